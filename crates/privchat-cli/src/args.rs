@@ -0,0 +1,473 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 命令行解析。全局选项写在子命令前面，子命令自己的选项写在后面。
+//! 选项不多，手写解析，不为此引参数库。
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use privchat_sdk::{parse_server_url, ServerEndpoint};
+
+pub const USAGE: &str = "\
+usage: privchat [--data-dir DIR] [--server URL]... [--json] <command> [args]
+
+session:
+  accounts                                   list local accounts
+  use <uid>                                  switch the active local account
+  login <username> [--device-id ID]
+  register <username> [--device-id ID]
+  authenticate <user_id> <token> [--device-id ID]
+  logout
+  session                                    print the stored session
+
+data:
+  channels [--limit N] [--offset N]
+  messages <channel_id> [--channel-type T] [--limit N] [--offset N]
+  send <channel_id> <text> [--channel-type T] [--wait SECS | --no-wait]
+  send-file <channel_id> <path> [--channel-type T] [--mime TYPE] [--wait SECS | --no-wait]
+  tail [--from SEQ]                          stream events with sequence ids
+
+sync:
+  sync-entities <entity_type> [--scope S]
+  sync-channel <channel_id> [--channel-type T]
+  repair <channel_id> <server_message_id> [--channel-type T]
+  sync-state
+
+--data-dir defaults to $PRIVCHAT_DATA_DIR, then ~/.privchat.
+--server defaults to $PRIVCHAT_SERVER (comma separated), then tcp://127.0.0.1:9001.
+--json prints one JSON document per result (one per line for `tail`).
+send/send-file wait up to 30 seconds (--wait SECS) for the message to be sent or to
+fail; --no-wait only queues it, and it goes out with the next session that comes online.
+login/register read the password from $PRIVCHAT_PASSWORD, then from piped stdin
+(first line), then prompt on the terminal without echo.";
+
+#[derive(Debug, Clone)]
+pub struct GlobalOptions {
+    pub data_dir: PathBuf,
+    pub endpoints: Vec<ServerEndpoint>,
+    pub json: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Accounts,
+    Use {
+        uid: String,
+    },
+    /// 密码不走命令行参数：argv 在进程列表和 shell 历史里都看得到，执行时另读。
+    Login {
+        username: String,
+        device_id: String,
+    },
+    Register {
+        username: String,
+        device_id: String,
+    },
+    Authenticate {
+        user_id: u64,
+        token: String,
+        device_id: String,
+    },
+    Logout,
+    Session,
+    Channels {
+        limit: u64,
+        offset: u64,
+    },
+    Messages {
+        channel_id: u64,
+        channel_type: Option<i32>,
+        limit: u64,
+        offset: u64,
+    },
+    Send {
+        channel_id: u64,
+        channel_type: Option<i32>,
+        text: String,
+        /// 等到已发送 / 失败的秒数；`None` 是 `--no-wait`，只入队。
+        wait_secs: Option<u64>,
+    },
+    SendFile {
+        channel_id: u64,
+        channel_type: Option<i32>,
+        path: PathBuf,
+        mime: Option<String>,
+        wait_secs: Option<u64>,
+    },
+    Tail {
+        from: Option<u64>,
+    },
+    SyncEntities {
+        entity_type: String,
+        scope: Option<String>,
+    },
+    SyncChannel {
+        channel_id: u64,
+        channel_type: Option<i32>,
+    },
+    Repair {
+        channel_id: u64,
+        channel_type: Option<i32>,
+        server_message_id: u64,
+    },
+    SyncState,
+}
+
+impl Command {
+    /// 只读本地库的命令不需要连服务端。
+    pub fn needs_network(&self) -> bool {
+        !matches!(
+            self,
+            Command::Accounts
+                | Command::Use { .. }
+                | Command::Session
+                | Command::Channels { .. }
+                | Command::Messages { .. }
+                | Command::SyncState
+        )
+    }
+}
+
+/// 不带值的子命令选项。
+const FLAGS: &[&str] = &["no-wait"];
+
+/// `send` / `send-file` 默认等多久：一次性的 CLI 进程退出时不会替它把发件箱发完。
+const DEFAULT_SEND_WAIT_SECS: u64 = 30;
+
+/// 子命令的剩余参数：位置参数按顺序取，`--name value` 按名取，[`FLAGS`] 里的不带值。
+struct Rest {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+    flags: HashSet<String>,
+}
+
+impl Rest {
+    fn split(args: Vec<String>) -> Result<Self, String> {
+        let mut positional = Vec::new();
+        let mut options = HashMap::new();
+        let mut flags = HashSet::new();
+        let mut it = args.into_iter();
+        while let Some(arg) = it.next() {
+            if let Some(name) = arg.strip_prefix("--") {
+                if FLAGS.contains(&name) {
+                    flags.insert(name.to_string());
+                    continue;
+                }
+                let value = it.next().ok_or_else(|| format!("--{name} needs a value"))?;
+                options.insert(name.to_string(), value);
+            } else {
+                positional.push(arg);
+            }
+        }
+        Ok(Self {
+            positional,
+            options,
+            flags,
+        })
+    }
+
+    fn take(&mut self, what: &str) -> Result<String, String> {
+        if self.positional.is_empty() {
+            return Err(format!("missing <{what}>"));
+        }
+        Ok(self.positional.remove(0))
+    }
+
+    fn take_num<T: std::str::FromStr>(&mut self, what: &str) -> Result<T, String> {
+        let raw = self.take(what)?;
+        raw.parse()
+            .map_err(|_| format!("<{what}>: not a number: {raw:?}"))
+    }
+
+    fn opt(&mut self, name: &str) -> Option<String> {
+        self.options.remove(name)
+    }
+
+    fn opt_num<T: std::str::FromStr>(&mut self, name: &str) -> Result<Option<T>, String> {
+        match self.options.remove(name) {
+            None => Ok(None),
+            Some(raw) => raw
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("--{name}: not a number: {raw:?}")),
+        }
+    }
+
+    fn flag(&mut self, name: &str) -> bool {
+        self.flags.remove(name)
+    }
+
+    /// `--wait SECS` / `--no-wait`，都没给时等 [`DEFAULT_SEND_WAIT_SECS`]。
+    fn send_wait(&mut self) -> Result<Option<u64>, String> {
+        let no_wait = self.flag("no-wait");
+        match (self.opt_num("wait")?, no_wait) {
+            (Some(_), true) => Err("--wait and --no-wait are exclusive".to_string()),
+            (Some(0), false) => {
+                Err("--wait: must be positive; use --no-wait to only queue".to_string())
+            }
+            (Some(secs), false) => Ok(Some(secs)),
+            (None, true) => Ok(None),
+            (None, false) => Ok(Some(DEFAULT_SEND_WAIT_SECS)),
+        }
+    }
+
+    /// 多余的参数一律报错：写错选项名时静默忽略，脚本会带着错误的默认值跑下去。
+    fn finish<T>(self, value: T) -> Result<T, String> {
+        if let Some(extra) = self.positional.first() {
+            return Err(format!("unexpected argument {extra:?}"));
+        }
+        if let Some(name) = self.options.keys().chain(self.flags.iter()).next() {
+            return Err(format!("unknown option --{name}"));
+        }
+        Ok(value)
+    }
+}
+
+pub fn parse<I, S>(args: I) -> Result<(GlobalOptions, Command), String>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let mut data_dir = std::env::var("PRIVCHAT_DATA_DIR").ok().map(PathBuf::from);
+    let mut server_urls: Vec<String> = Vec::new();
+    let mut json = false;
+
+    let mut it = args.into_iter().map(Into::into).peekable();
+    while let Some(arg) = it.peek() {
+        if !arg.starts_with("--") {
+            break;
+        }
+        let flag = it.next().unwrap_or_default();
+        match flag.as_str() {
+            "--json" => json = true,
+            "--help" => return Err(USAGE.to_string()),
+            "--data-dir" | "--server" => {
+                let value = it.next().ok_or_else(|| format!("{flag} needs a value"))?;
+                if flag == "--data-dir" {
+                    data_dir = Some(PathBuf::from(value));
+                } else {
+                    server_urls.push(value);
+                }
+            }
+            other => return Err(format!("unknown option {other}\n\n{USAGE}")),
+        }
+    }
+
+    if server_urls.is_empty() {
+        if let Ok(env) = std::env::var("PRIVCHAT_SERVER") {
+            server_urls = env
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+    }
+    let endpoints = if server_urls.is_empty() {
+        privchat_sdk::PrivchatConfig::default().endpoints
+    } else {
+        server_urls
+            .iter()
+            .map(|url| {
+                parse_server_url(url).ok_or_else(|| format!("unsupported server url {url:?}"))
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    let data_dir = match data_dir {
+        Some(dir) => dir,
+        None => std::env::var("HOME")
+            .map(|home| PathBuf::from(home).join(".privchat"))
+            .map_err(|_| "cannot locate a data dir: pass --data-dir".to_string())?,
+    };
+
+    let name = it
+        .next()
+        .ok_or_else(|| format!("missing command\n\n{USAGE}"))?;
+    let mut rest = Rest::split(it.collect())?;
+    let command = match name.as_str() {
+        "accounts" => Command::Accounts,
+        "use" => Command::Use {
+            uid: rest.take("uid")?,
+        },
+        "login" | "register" => {
+            let username = rest.take("username")?;
+            let device_id = rest.opt("device-id").unwrap_or_default();
+            if name == "login" {
+                Command::Login {
+                    username,
+                    device_id,
+                }
+            } else {
+                Command::Register {
+                    username,
+                    device_id,
+                }
+            }
+        }
+        "authenticate" => Command::Authenticate {
+            user_id: rest.take_num("user_id")?,
+            token: rest.take("token")?,
+            device_id: rest.opt("device-id").unwrap_or_default(),
+        },
+        "logout" => Command::Logout,
+        "session" => Command::Session,
+        "channels" => Command::Channels {
+            limit: rest.opt_num("limit")?.unwrap_or(50),
+            offset: rest.opt_num("offset")?.unwrap_or(0),
+        },
+        "messages" => Command::Messages {
+            channel_id: rest.take_num("channel_id")?,
+            channel_type: rest.opt_num("channel-type")?,
+            limit: rest.opt_num("limit")?.unwrap_or(50),
+            offset: rest.opt_num("offset")?.unwrap_or(0),
+        },
+        "send" => Command::Send {
+            channel_id: rest.take_num("channel_id")?,
+            text: rest.take("text")?,
+            channel_type: rest.opt_num("channel-type")?,
+            wait_secs: rest.send_wait()?,
+        },
+        "send-file" => Command::SendFile {
+            channel_id: rest.take_num("channel_id")?,
+            path: PathBuf::from(rest.take("path")?),
+            channel_type: rest.opt_num("channel-type")?,
+            mime: rest.opt("mime"),
+            wait_secs: rest.send_wait()?,
+        },
+        "tail" => Command::Tail {
+            from: rest.opt_num("from")?,
+        },
+        "sync-entities" => Command::SyncEntities {
+            entity_type: rest.take("entity_type")?,
+            scope: rest.opt("scope"),
+        },
+        "sync-channel" => Command::SyncChannel {
+            channel_id: rest.take_num("channel_id")?,
+            channel_type: rest.opt_num("channel-type")?,
+        },
+        "repair" => Command::Repair {
+            channel_id: rest.take_num("channel_id")?,
+            server_message_id: rest.take_num("server_message_id")?,
+            channel_type: rest.opt_num("channel-type")?,
+        },
+        "sync-state" => Command::SyncState,
+        "help" => return Err(USAGE.to_string()),
+        other => return Err(format!("unknown command {other:?}\n\n{USAGE}")),
+    };
+    let command = rest.finish(command)?;
+    Ok((
+        GlobalOptions {
+            data_dir,
+            endpoints,
+            json,
+        },
+        command,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_ok(args: &[&str]) -> (GlobalOptions, Command) {
+        parse(args.iter().copied()).unwrap()
+    }
+
+    #[test]
+    fn global_options_come_before_the_command() {
+        let (global, command) = parse_ok(&[
+            "--data-dir",
+            "/tmp/pc",
+            "--server",
+            "quic://im.example.com:9001",
+            "--json",
+            "messages",
+            "42",
+            "--limit",
+            "5",
+        ]);
+        assert_eq!(global.data_dir, PathBuf::from("/tmp/pc"));
+        assert_eq!(global.endpoints[0].host, "im.example.com");
+        assert!(global.json);
+        assert_eq!(
+            command,
+            Command::Messages {
+                channel_id: 42,
+                channel_type: None,
+                limit: 5,
+                offset: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_options_and_stray_arguments_are_rejected() {
+        assert!(parse(["--data-dir", "/tmp/pc", "channels", "--limt", "5"]).is_err());
+        assert!(parse(["--data-dir", "/tmp/pc", "sync-state", "now"]).is_err());
+        assert!(parse(["--data-dir", "/tmp/pc", "send", "not-a-number", "hi"]).is_err());
+    }
+
+    #[test]
+    fn passwords_are_not_taken_from_argv() {
+        let (_, command) = parse_ok(&["--data-dir", "/tmp/pc", "login", "alice"]);
+        assert_eq!(
+            command,
+            Command::Login {
+                username: "alice".to_string(),
+                device_id: String::new(),
+            }
+        );
+        assert!(parse(["--data-dir", "/tmp/pc", "login", "alice", "hunter2"]).is_err());
+    }
+
+    #[test]
+    fn local_reads_do_not_need_the_network() {
+        let (_, command) = parse_ok(&["--data-dir", "/tmp/pc", "channels"]);
+        assert!(!command.needs_network());
+        let (_, command) = parse_ok(&["--data-dir", "/tmp/pc", "send", "1", "hi"]);
+        assert!(command.needs_network());
+    }
+
+    #[test]
+    fn send_waits_for_the_outcome_unless_told_not_to() {
+        let wait_of = |args: &[&str]| match parse_ok(args).1 {
+            Command::Send { wait_secs, .. } | Command::SendFile { wait_secs, .. } => wait_secs,
+            other => panic!("not a send: {other:?}"),
+        };
+        assert_eq!(
+            wait_of(&["--data-dir", "/tmp/pc", "send", "1", "hi"]),
+            Some(DEFAULT_SEND_WAIT_SECS)
+        );
+        assert_eq!(
+            wait_of(&["--data-dir", "/tmp/pc", "send", "1", "hi", "--wait", "5"]),
+            Some(5)
+        );
+        assert_eq!(
+            wait_of(&[
+                "--data-dir",
+                "/tmp/pc",
+                "send-file",
+                "1",
+                "a.png",
+                "--no-wait"
+            ]),
+            None
+        );
+        assert!(parse(["--data-dir", "/tmp/pc", "send", "1", "hi", "--wait", "0"]).is_err());
+        assert!(parse([
+            "--data-dir",
+            "/tmp/pc",
+            "send",
+            "1",
+            "hi",
+            "--no-wait",
+            "--wait",
+            "5"
+        ])
+        .is_err());
+        assert!(parse(["--data-dir", "/tmp/pc", "channels", "--no-wait"]).is_err());
+    }
+}
@@ -0,0 +1,624 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 各子命令的实现。
+//!
+//! v1 facade 覆盖到的操作一律走 `ClientService`（spec §4）；同步、修复、账号列表这类
+//! 调试操作 facade 还没有，按 spec §13 经 `service.sdk()` 直通。
+
+use std::io::{IsTerminal, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use privchat_protocol::ContentMessageType;
+use privchat_sdk::client_service::{
+    AuthenticateRequest, ClientService, ClientServiceConfig, ListChannelsRequest,
+    ListMessagesRequest, LoginRequest, RegisterRequest, SdkError, SendTextRequest,
+};
+use privchat_sdk::{NewMessage, StoredMessage};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::args::{Command, GlobalOptions};
+use crate::output::{self, Output};
+
+/// 本地消息状态：2 = 已发送，3 = 失败。send 等的就是这两个终态。
+const STATUS_SENT: i32 = 2;
+const STATUS_FAILED: i32 = 3;
+
+/// login/register 读密码的环境变量，见 [`read_password`]。
+const PASSWORD_ENV: &str = "PRIVCHAT_PASSWORD";
+
+/// 没显式给 `--device-id` 时用的设备号，存在 data_dir 里，保证同一目录多次登录是同一台设备。
+const DEVICE_ID_FILE_NAME: &str = "cli-device-id";
+
+#[derive(Debug)]
+pub struct CliError {
+    /// SDK 错误码（`SdkError::sdk_code`）；CLI 自己的错误没有。
+    pub code: Option<u32>,
+    pub message: String,
+}
+
+impl From<SdkError> for CliError {
+    fn from(err: SdkError) -> Self {
+        Self {
+            code: Some(err.sdk_code()),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<String> for CliError {
+    fn from(message: String) -> Self {
+        Self {
+            code: None,
+            message,
+        }
+    }
+}
+
+impl From<std::io::Error> for CliError {
+    fn from(err: std::io::Error) -> Self {
+        Self::from(err.to_string())
+    }
+}
+
+type CliResult<T> = Result<T, CliError>;
+
+pub async fn run(global: GlobalOptions, command: Command) -> CliResult<()> {
+    let _instance = refuse_if_daemon_running(&global.data_dir)?;
+    let out = Output::new(global.json);
+    let device_id = load_or_create_device_id(&global.data_dir)?;
+    let service = ClientService::new(ClientServiceConfig {
+        endpoints: global.endpoints.clone(),
+        connection_timeout_secs: 10,
+        data_dir: global.data_dir.clone(),
+        device_id,
+        account_hint: None,
+    })?;
+
+    let result = dispatch(&service, &out, command).await;
+    let _ = service.shutdown().await;
+    result
+}
+
+/// 同一个 data_dir 上 daemon 和 CLI 不能同时开库。拿 daemon 的那把实例锁：daemon 在跑
+/// 就拿不到；拿到了就一直持有到命令结束，期间 daemon 也起不来。不看 socket 能不能连上
+/// ——daemon 刚起来还没 bind、或者 socket 文件被删了，探测都会漏掉。
+fn refuse_if_daemon_running(data_dir: &Path) -> CliResult<privchatd::InstanceLock> {
+    privchatd::InstanceLock::acquire(data_dir).map_err(|err| match err {
+        privchatd::instance_lock::LockError::AlreadyRunning(_) => CliError::from(format!(
+            "privchatd is serving {}; stop it before using the CLI on the same data dir",
+            data_dir.display()
+        )),
+        other => CliError::from(other.to_string()),
+    })
+}
+
+async fn dispatch(service: &ClientService, out: &Output, command: Command) -> CliResult<()> {
+    if command.needs_network() && !is_session_command(&command) {
+        go_online(service).await?;
+    }
+    match command {
+        Command::Accounts => {
+            let accounts = service.sdk().list_local_accounts().await?;
+            out.list(&accounts, output::account_line);
+        }
+        Command::Use { uid } => {
+            service.sdk().switch_local_account(uid.clone()).await?;
+            out.value(&serde_json::json!({ "current_uid": uid }), |_| {
+                format!("switched to {uid}")
+            });
+        }
+        Command::Login {
+            username,
+            device_id,
+        } => {
+            let password = read_password()?;
+            service.connect().await?;
+            let login = service
+                .login(LoginRequest {
+                    username,
+                    password,
+                    device_id,
+                })
+                .await?;
+            authenticate_after_login(service, &login).await?;
+            out.value(&login, output::login_line);
+        }
+        Command::Register {
+            username,
+            device_id,
+        } => {
+            let password = read_password()?;
+            service.connect().await?;
+            let login = service
+                .register(RegisterRequest {
+                    username,
+                    password,
+                    device_id,
+                })
+                .await?;
+            authenticate_after_login(service, &login).await?;
+            out.value(&login, output::login_line);
+        }
+        Command::Authenticate {
+            user_id,
+            token,
+            device_id,
+        } => {
+            service.connect().await?;
+            service
+                .authenticate(AuthenticateRequest {
+                    user_id,
+                    token,
+                    device_id,
+                })
+                .await?;
+            out.value(&serde_json::json!({ "user_id": user_id }), |_| {
+                format!("authenticated as {user_id}")
+            });
+        }
+        Command::Logout => {
+            // 远端注销是尽力而为：连不上也要把本地凭证清掉。
+            let _ = go_online(service).await;
+            service.logout().await?;
+            out.value(&serde_json::json!({ "logged_out": true }), |_| {
+                "logged out".to_string()
+            });
+        }
+        Command::Session => {
+            let snapshot = service.session_snapshot().await?;
+            out.value(&snapshot, output::session_line);
+        }
+        Command::Channels { limit, offset } => {
+            let channels = service
+                .list_channels(ListChannelsRequest { limit, offset })
+                .await?;
+            out.list(&channels, output::channel_line);
+        }
+        Command::Messages {
+            channel_id,
+            channel_type,
+            limit,
+            offset,
+        } => {
+            let messages = service
+                .list_messages(ListMessagesRequest {
+                    channel_id,
+                    channel_type,
+                    limit,
+                    offset,
+                    before_local_id: None,
+                })
+                .await?;
+            out.list(&messages, output::message_line);
+        }
+        Command::Send {
+            channel_id,
+            channel_type,
+            text,
+            wait_secs,
+        } => {
+            let message_id = service
+                .send_text(SendTextRequest {
+                    channel_id,
+                    channel_type,
+                    content: text,
+                })
+                .await?;
+            report_send(service, out, message_id, wait_secs).await?;
+        }
+        Command::SendFile {
+            channel_id,
+            channel_type,
+            path,
+            mime,
+            wait_secs,
+        } => {
+            let message_id = send_file(service, channel_id, channel_type, &path, mime).await?;
+            report_send(service, out, message_id, wait_secs).await?;
+        }
+        Command::Tail { from } => tail(service, out, from).await?,
+        Command::SyncEntities { entity_type, scope } => {
+            let applied = service
+                .sdk()
+                .sync_entities(entity_type.clone(), scope)
+                .await?;
+            out.value(
+                &serde_json::json!({ "entity_type": entity_type, "applied": applied }),
+                |_| format!("{entity_type}: {applied} applied"),
+            );
+        }
+        Command::SyncChannel {
+            channel_id,
+            channel_type,
+        } => {
+            let channel_type = resolve_channel_type(service, channel_id, channel_type).await?;
+            let applied = service.sdk().sync_channel(channel_id, channel_type).await?;
+            out.value(
+                &serde_json::json!({ "channel_id": channel_id, "applied": applied }),
+                |_| format!("channel {channel_id}: {applied} applied"),
+            );
+        }
+        Command::Repair {
+            channel_id,
+            channel_type,
+            server_message_id,
+        } => {
+            let channel_type = resolve_channel_type(service, channel_id, channel_type).await?;
+            let repaired = service
+                .sdk()
+                .repair_message_projection(channel_id, channel_type, server_message_id)
+                .await?;
+            out.value(
+                &serde_json::json!({
+                    "server_message_id": server_message_id,
+                    "local_message_id": repaired,
+                }),
+                |_| match repaired {
+                    Some(id) => format!("repaired as local message {id}"),
+                    None => format!("server has no message {server_message_id}"),
+                },
+            );
+        }
+        Command::SyncState => {
+            let state = service.sdk().sync_state().await?;
+            out.value(&state, |s| format!("{s:#?}"));
+        }
+    }
+    Ok(())
+}
+
+/// login/register/authenticate 自己负责建连，不走 [`go_online`] 的「用存下来的会话」逻辑。
+fn is_session_command(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::Login { .. }
+            | Command::Register { .. }
+            | Command::Authenticate { .. }
+            | Command::Logout
+    )
+}
+
+/// 用 data_dir 里存着的会话上线。没有会话就让用户先 login。
+async fn go_online(service: &ClientService) -> CliResult<()> {
+    let snapshot = service.session_snapshot().await?.ok_or_else(|| {
+        CliError::from("no stored session; run `privchat login` first".to_string())
+    })?;
+    service.connect().await?;
+    service
+        .authenticate(AuthenticateRequest {
+            user_id: snapshot.user_id,
+            token: snapshot.token,
+            device_id: snapshot.device_id,
+        })
+        .await?;
+    Ok(())
+}
+
+async fn authenticate_after_login(
+    service: &ClientService,
+    login: &privchat_sdk::client_service::LoginOutcome,
+) -> CliResult<()> {
+    service
+        .authenticate(AuthenticateRequest {
+            user_id: login.user_id,
+            token: login.token.clone(),
+            device_id: login.device_id.clone(),
+        })
+        .await?;
+    Ok(())
+}
+
+/// 密码来源依次是 `$PRIVCHAT_PASSWORD`、管道进来的 stdin 第一行、终端上关回显提示输入。
+/// 都不走 argv：命令行参数对同机其他用户的 `ps` 和 shell 历史都是明文。
+fn read_password() -> CliResult<String> {
+    if let Ok(password) = std::env::var(PASSWORD_ENV) {
+        if !password.is_empty() {
+            return Ok(password);
+        }
+    }
+    let stdin = std::io::stdin();
+    let password = if stdin.is_terminal() {
+        eprint!("password: ");
+        let _ = std::io::stderr().flush();
+        let echo = EchoOff::new(stdin.as_raw_fd());
+        let mut line = String::new();
+        let read = stdin.read_line(&mut line);
+        drop(echo);
+        eprintln!();
+        read?;
+        line
+    } else {
+        let mut line = String::new();
+        stdin.read_line(&mut line)?;
+        line
+    };
+    let password = password.trim_end_matches(['\r', '\n']).to_string();
+    if password.is_empty() {
+        return Err(CliError::from(format!(
+            "no password: set ${PASSWORD_ENV}, pipe it on stdin or run from a terminal"
+        )));
+    }
+    Ok(password)
+}
+
+/// 提示输入密码期间关掉终端回显，drop 时恢复原设置。
+struct EchoOff {
+    fd: RawFd,
+    saved: Option<libc::termios>,
+}
+
+impl EchoOff {
+    fn new(fd: RawFd) -> Self {
+        // SAFETY: termios 是纯数据结构，全零是合法的初值；tcgetattr 成功后才会用它。
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        // SAFETY: fd 是当前进程的 stdin，`saved` 指向栈上有效的 termios。
+        if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+            return Self { fd, saved: None };
+        }
+        let mut quiet = saved;
+        quiet.c_lflag &= !libc::ECHO;
+        quiet.c_lflag |= libc::ECHONL;
+        // SAFETY: 同上。
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &quiet) } != 0 {
+            return Self { fd, saved: None };
+        }
+        Self {
+            fd,
+            saved: Some(saved),
+        }
+    }
+}
+
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        if let Some(saved) = self.saved {
+            // SAFETY: 恢复的是 `new` 里从同一个 fd 读出的设置。
+            unsafe {
+                libc::tcsetattr(self.fd, libc::TCSANOW, &saved);
+            }
+        }
+    }
+}
+
+async fn resolve_channel_type(
+    service: &ClientService,
+    channel_id: u64,
+    explicit: Option<i32>,
+) -> CliResult<i32> {
+    if let Some(channel_type) = explicit {
+        return Ok(channel_type);
+    }
+    let channel = service.get_channel(channel_id).await?.ok_or_else(|| {
+        CliError::from(format!(
+            "channel {channel_id} is not in the local store; pass --channel-type"
+        ))
+    })?;
+    Ok(channel.channel_type)
+}
+
+/// 附件走产品路径：占位 + 「定稿并入队」同一事务，payload 留空让 outbox 自己读盘。
+async fn send_file(
+    service: &ClientService,
+    channel_id: u64,
+    channel_type: Option<i32>,
+    path: &Path,
+    mime: Option<String>,
+) -> CliResult<u64> {
+    let path = std::fs::canonicalize(path)
+        .map_err(|e| CliError::from(format!("{}: {e}", path.display())))?;
+    let channel_type = resolve_channel_type(service, channel_id, channel_type).await?;
+    let from_uid = service
+        .session_snapshot()
+        .await?
+        .map(|s| s.user_id)
+        .ok_or_else(|| CliError::from("no stored session".to_string()))?;
+    let mime = mime.unwrap_or_else(|| guess_mime(&path).to_string());
+    let (message_type, route_key) = attachment_kind(&mime);
+    let sdk = service.sdk();
+    let message_id = sdk
+        .create_local_attachment_placeholder(
+            NewMessage {
+                channel_id,
+                channel_type,
+                from_uid,
+                message_type: message_type as i32,
+                content: path.display().to_string(),
+                searchable_word: String::new(),
+                setting: 0,
+                extra: String::new(),
+                mime_type: Some(mime),
+                media_downloaded: true,
+                thumb_status: 0,
+            },
+            None,
+        )
+        .await?;
+    sdk.finalize_attachment_and_enqueue(
+        message_id,
+        path.display().to_string(),
+        0,
+        route_key.to_string(),
+        Vec::new(),
+    )
+    .await?;
+    Ok(message_id)
+}
+
+fn attachment_kind(mime: &str) -> (ContentMessageType, &'static str) {
+    if mime.starts_with("image/") {
+        (ContentMessageType::Image, "image")
+    } else if mime.starts_with("video/") {
+        (ContentMessageType::Video, "video")
+    } else {
+        (ContentMessageType::File, "file")
+    }
+}
+
+fn guess_mime(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    match ext.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "mp4" => "video/mp4",
+        "mov" => "video/quicktime",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct SendReport {
+    message_id: u64,
+    /// 没等（`--no-wait`）时为空。
+    message: Option<StoredMessage>,
+}
+
+/// 默认最多等 N 秒到终态，失败或超时返回非零退出码，方便脚本判断；`--no-wait` 只报告入队。
+async fn report_send(
+    service: &ClientService,
+    out: &Output,
+    message_id: u64,
+    wait_secs: Option<u64>,
+) -> CliResult<()> {
+    let Some(wait_secs) = wait_secs else {
+        out.value(
+            &SendReport {
+                message_id,
+                message: None,
+            },
+            |_| format!("queued local message {message_id}"),
+        );
+        return Ok(());
+    };
+    let deadline = Instant::now() + Duration::from_secs(wait_secs);
+    loop {
+        let row = service.sdk().get_message_by_id(message_id).await?;
+        let status = row.as_ref().map(|m| m.status);
+        if status == Some(STATUS_SENT)
+            || status == Some(STATUS_FAILED)
+            || Instant::now() >= deadline
+        {
+            let failed = status != Some(STATUS_SENT);
+            out.value(
+                &SendReport {
+                    message_id,
+                    message: row,
+                },
+                output::send_report_line,
+            );
+            if failed {
+                return Err(CliError::from(format!(
+                    "message {message_id} not sent (status {})",
+                    status.unwrap_or(-1)
+                )));
+            }
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+}
+
+impl SendReport {
+    pub(crate) fn summary(&self) -> String {
+        match &self.message {
+            Some(m) => format!(
+                "local message {} status={} server_message_id={}",
+                self.message_id,
+                m.status,
+                m.server_message_id.unwrap_or(0)
+            ),
+            None => format!("local message {} is gone", self.message_id),
+        }
+    }
+}
+
+/// 按序号打印事件直到 Ctrl-C。`--from` 给了就先补历史（只有本进程内的历史）。
+///
+/// 和 daemon 的事件泵同一个套路：广播只当唤醒信号，事件从 `events_since` 取，
+/// 这样才有 `sequence_id`，`Lagged` 也不丢。
+async fn tail(service: &ClientService, out: &Output, from: Option<u64>) -> CliResult<()> {
+    let mut wake = service.subscribe_events().into_receiver();
+    let mut cursor = from.unwrap_or_else(|| service.last_event_sequence_id());
+    loop {
+        for event in service.events_since(cursor, 256) {
+            cursor = event.sequence_id;
+            out.event(&event);
+        }
+        tokio::select! {
+            woke = wake.recv() => match woke {
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+fn load_or_create_device_id(data_dir: &Path) -> CliResult<String> {
+    let path: PathBuf = data_dir.join(DEVICE_ID_FILE_NAME);
+    if let Ok(existing) = std::fs::read_to_string(&path) {
+        let existing = existing.trim();
+        if !existing.is_empty() {
+            return Ok(existing.to_string());
+        }
+    }
+    std::fs::create_dir_all(data_dir)?;
+    let device_id = pseudo_uuid_v4_like();
+    std::fs::write(&path, format!("{device_id}\n"))?;
+    Ok(device_id)
+}
+
+fn pseudo_uuid_v4_like() -> String {
+    let ns = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let pid = std::process::id() as u128;
+    let x = ns ^ (pid << 32) ^ 0xa5a5_5a5a_dead_beef_u128;
+    let part1 = (x >> 96) as u32;
+    let part2 = (x >> 80) as u16;
+    let part3 = (((x >> 64) as u16) & 0x0fff) | 0x4000;
+    let part4 = (((x >> 48) as u16) & 0x3fff) | 0x8000;
+    let part5 = (x & 0x0000_0000_0000_ffff_ffff_ffff_u128) as u64;
+    format!("{part1:08x}-{part2:04x}-{part3:04x}-{part4:04x}-{part5:012x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attachment_route_follows_the_mime_family() {
+        assert_eq!(attachment_kind("image/png").1, "image");
+        assert_eq!(attachment_kind("video/mp4").1, "video");
+        assert_eq!(attachment_kind("application/pdf").1, "file");
+        assert_eq!(guess_mime(Path::new("/tmp/a.JPG")), "image/jpeg");
+        assert_eq!(
+            guess_mime(Path::new("/tmp/noext")),
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn device_id_is_stable_per_data_dir() {
+        let dir = std::env::temp_dir().join(format!("privchat-cli-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let first = load_or_create_device_id(&dir).unwrap();
+        let second = load_or_create_device_id(&dir).unwrap();
+        assert_eq!(first, second);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
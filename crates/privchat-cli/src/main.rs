@@ -0,0 +1,42 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! `privchat`：脚本和排障用的命令行客户端。进程内直接跑 SDK，会话存在 data_dir 里，
+//! 登录一次之后的命令都用存下来的会话上线。
+
+mod args;
+mod commands;
+mod output;
+
+use std::process::ExitCode;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let (global, command) = match args::parse(std::env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(msg) => {
+            eprintln!("{msg}");
+            return ExitCode::from(2);
+        }
+    };
+    let json = global.json;
+    match commands::run(global, command).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            if json {
+                eprintln!(
+                    "{}",
+                    serde_json::json!({ "error": { "code": err.code, "message": err.message } })
+                );
+            } else {
+                match err.code {
+                    Some(code) => eprintln!("privchat: {} (code {code:#010x})", err.message),
+                    None => eprintln!("privchat: {}", err.message),
+                }
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 输出：默认给人看的一行一条；`--json` 时每个结果一个 JSON 文档，`tail` 每行一条事件。
+
+use serde::Serialize;
+
+use privchat_sdk::client_service::LoginOutcome;
+use privchat_sdk::{
+    LocalAccountSummary, SequencedSdkEvent, SessionSnapshot, StoredChannel, StoredMessage,
+};
+
+use crate::commands::SendReport;
+
+pub struct Output {
+    json: bool,
+}
+
+impl Output {
+    pub fn new(json: bool) -> Self {
+        Self { json }
+    }
+
+    pub fn value<T: Serialize>(&self, value: &T, human: impl FnOnce(&T) -> String) {
+        if self.json {
+            print_json(value);
+        } else {
+            println!("{}", human(value));
+        }
+    }
+
+    /// JSON 模式下整个列表是一个数组，方便 `jq '.[]'`。
+    pub fn list<T: Serialize>(&self, items: &[T], human: impl Fn(&T) -> String) {
+        if self.json {
+            print_json(&items);
+        } else {
+            for item in items {
+                println!("{}", human(item));
+            }
+        }
+    }
+
+    pub fn event(&self, event: &SequencedSdkEvent) {
+        if self.json {
+            print_json(event);
+        } else {
+            println!(
+                "{}\t{}\t{:?}",
+                event.sequence_id, event.timestamp_ms, event.event
+            );
+        }
+    }
+}
+
+fn print_json<T: Serialize + ?Sized>(value: &T) {
+    match serde_json::to_string(value) {
+        Ok(line) => println!("{line}"),
+        Err(e) => eprintln!("privchat: cannot encode output: {e}"),
+    }
+}
+
+pub fn account_line(a: &LocalAccountSummary) -> String {
+    let marker = if a.is_active { '*' } else { ' ' };
+    // 展示优先级与 SDK 约定一致：display_name > username > uid。
+    let name = a
+        .display_name
+        .as_deref()
+        .or(a.username.as_deref())
+        .unwrap_or(a.uid.as_str());
+    format!("{marker} {}\t{name}", a.uid)
+}
+
+pub fn login_line(login: &LoginOutcome) -> String {
+    format!(
+        "logged in as {} (device {})",
+        login.user_id, login.device_id
+    )
+}
+
+/// 人读模式不打印 token；要 token 用 `--json`。
+pub fn session_line(snapshot: &Option<SessionSnapshot>) -> String {
+    match snapshot {
+        Some(s) => format!(
+            "user_id={} device_id={} bootstrap_completed={}",
+            s.user_id, s.device_id, s.bootstrap_completed
+        ),
+        None => "no stored session".to_string(),
+    }
+}
+
+pub fn channel_line(c: &StoredChannel) -> String {
+    let name = if c.channel_remark.is_empty() {
+        c.channel_name.as_str()
+    } else {
+        c.channel_remark.as_str()
+    };
+    format!(
+        "{}\ttype={}\tunread={}\t{}",
+        c.channel_id, c.channel_type, c.unread_count, name
+    )
+}
+
+pub fn message_line(m: &StoredMessage) -> String {
+    format!(
+        "{}\tserver={}\tpts={}\tfrom={}\tstatus={}\t{}",
+        m.message_id,
+        m.server_message_id.unwrap_or(0),
+        m.pts.unwrap_or(0),
+        m.from_uid,
+        m.status,
+        m.content.replace('\n', "\\n")
+    )
+}
+
+pub fn send_report_line(report: &SendReport) -> String {
+    report.summary()
+}
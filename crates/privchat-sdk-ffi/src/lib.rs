@@ -153,8 +153,6 @@ use privchat_protocol::rpc::{
     MessageRevokeResponse,
     MessageStatusCountRequest,
     MessageStatusCountResponse,
-    MessageStatusReadPtsRequest,
-    MessageStatusReadPtsResponse,
     QRCodeGenerateRequest,
     QRCodeGenerateResponse,
     QRCodeListRequest,
@@ -179,23 +177,44 @@ use privchat_protocol::rpc::{
     UserQRCodeResolveRequest,
     UserQRCodeResolveResponse,
 };
+use privchat_sdk::client_service::{
+    AuthenticateRequest as ServiceAuthenticateRequest, ClientService, ListChannelsRequest,
+    ListMessagesRequest, LoginRequest as ServiceLoginRequest, MarkReadByLocalMessageIdRequest,
+    MarkReadToPtsRequest, RegisterRequest as ServiceRegisterRequest, SendTextRequest,
+    SendTypingRequest,
+};
 use privchat_sdk::{
+    AccountEvent as SdkAccountEvent, AccountManager as SdkAccountManager,
+    ChannelFolder as SdkChannelFolder, ChannelFolderInput as SdkChannelFolderInput,
+    ChannelListCursor as SdkChannelListCursor, ChannelListFilter as SdkChannelListFilter,
+    ChannelListPage as SdkChannelListPage, ChannelListQuery as SdkChannelListQuery,
+    ChannelSortKey as SdkChannelSortKey, ChannelVisibility as SdkChannelVisibility,
     ConnectionState as SdkConnectionState, ContactCardMessageInput as SdkContactCardMessageInput,
-    Error as SdkError, LinkMessageInput as SdkLinkMessageInput,
-    LocalAccountSummary as SdkLocalAccountSummary, LocationMessageInput as SdkLocationMessageInput,
-    LoginResult as SdkLoginResult, MediaProcessOp as SdkMediaProcessOp,
-    MentionInput as SdkMentionInput, NetworkHint as SdkNetworkHint, NewMessage as SdkNewMessage,
-    PresenceStatus as SdkPresenceStatus, PrivchatConfig as SdkConfig, PrivchatSdk as InnerSdk,
+    Error as SdkError, LinkMessageInput as SdkLinkMessageInput, ListDiff as SdkListDiff,
+    LiveLocationMessageInput as SdkLiveLocationMessageInput,
+    LiveLocationView as SdkLiveLocationView, LiveQuery as SdkLiveQuery,
+    LiveQueryCancel as SdkLiveQueryCancel, LocalAccountSummary as SdkLocalAccountSummary,
+    LocationMessageInput as SdkLocationMessageInput, LoginResult as SdkLoginResult,
+    MediaProcessOp as SdkMediaProcessOp, MemberReadCursor as SdkMemberReadCursor,
+    MentionInput as SdkMentionInput, MessageReadReceipts as SdkMessageReadReceipts,
+    MessageRevision as SdkMessageRevision, NetworkHint as SdkNetworkHint,
+    NewMessage as SdkNewMessage, NotificationPrivacy as SdkNotificationPrivacy,
+    PinnedMessage as SdkPinnedMessage, PollMessageInput as SdkPollMessageInput,
+    PollView as SdkPollView, PresenceStatus as SdkPresenceStatus, PrivchatConfig as SdkConfig,
+    PrivchatSdk as InnerSdk, PushIngestor as SdkPushIngestor,
+    PushNotification as SdkPushNotification, PushPayload as SdkPushPayload,
     QueueMessage as SdkQueueMessage, SequencedSdkEvent as SdkSequencedSdkEvent,
     ServerEndpoint as SdkServerEndpoint, SessionSnapshot as SdkSessionSnapshot,
-    StoredBlacklistEntry as SdkStoredBlacklistEntry, StoredChannel as SdkStoredChannel,
-    StoredChannelExtra as SdkStoredChannelExtra, StoredChannelMember as SdkStoredChannelMember,
-    StoredFriend as SdkStoredFriend, StoredGroup as SdkStoredGroup,
-    StoredGroupMember as SdkStoredGroupMember, StoredMessage as SdkStoredMessage,
-    StoredMessageExtra as SdkStoredMessageExtra, StoredMessageReaction as SdkStoredMessageReaction,
-    StoredReminder as SdkStoredReminder, StoredUser as SdkStoredUser,
-    StructuredSendOptions as SdkStructuredSendOptions, TerminalReason as SdkTerminalReason,
-    TransportProtocol as SdkProtocol, TypingActionType as SdkTypingActionType,
+    Sticker as SdkSticker, StickerMessageInput as SdkStickerMessageInput,
+    StickerPackage as SdkStickerPackage, StoredBlacklistEntry as SdkStoredBlacklistEntry,
+    StoredChannel as SdkStoredChannel, StoredChannelExtra as SdkStoredChannelExtra,
+    StoredChannelMember as SdkStoredChannelMember, StoredFriend as SdkStoredFriend,
+    StoredGroup as SdkStoredGroup, StoredGroupMember as SdkStoredGroupMember,
+    StoredMessage as SdkStoredMessage, StoredMessageExtra as SdkStoredMessageExtra,
+    StoredMessageReaction as SdkStoredMessageReaction, StoredReminder as SdkStoredReminder,
+    StoredUser as SdkStoredUser, StructuredSendOptions as SdkStructuredSendOptions,
+    TerminalReason as SdkTerminalReason, TransportProtocol as SdkProtocol,
+    TypingActionType as SdkTypingActionType, UnreadAggregate as SdkUnreadAggregate,
     UnreadMentionCount as SdkUnreadMentionCount, UpsertBlacklistInput as SdkUpsertBlacklistInput,
     UpsertChannelExtraInput as SdkUpsertChannelExtraInput,
     UpsertChannelInput as SdkUpsertChannelInput,
@@ -267,6 +286,70 @@ pub struct HttpClientConfigView {
     scheme: String,
 }
 
+/// 某种网络下各类型的自动下载上限（字节）。0 = 不自动下载，`u64::MAX` = 不限。
+#[derive(Debug, Clone, Serialize, uniffi::Record)]
+pub struct AutoDownloadLimitsView {
+    image_max_bytes: u64,
+    video_max_bytes: u64,
+    voice_max_bytes: u64,
+    file_max_bytes: u64,
+}
+
+/// 媒体自动下载策略（按账号）。规则见 `privchat_sdk::auto_download`。
+#[derive(Debug, Clone, Serialize, uniffi::Record)]
+pub struct AutoDownloadPolicyView {
+    /// Wi-Fi / 以太网。
+    unmetered: AutoDownloadLimitsView,
+    /// 蜂窝；网络未知时也按它算。
+    metered: AutoDownloadLimitsView,
+    skip_muted_channels: bool,
+    voice_always: bool,
+}
+
+impl From<privchat_sdk::auto_download::AutoDownloadLimits> for AutoDownloadLimitsView {
+    fn from(v: privchat_sdk::auto_download::AutoDownloadLimits) -> Self {
+        Self {
+            image_max_bytes: v.image_max_bytes,
+            video_max_bytes: v.video_max_bytes,
+            voice_max_bytes: v.voice_max_bytes,
+            file_max_bytes: v.file_max_bytes,
+        }
+    }
+}
+
+impl From<AutoDownloadLimitsView> for privchat_sdk::auto_download::AutoDownloadLimits {
+    fn from(v: AutoDownloadLimitsView) -> Self {
+        Self {
+            image_max_bytes: v.image_max_bytes,
+            video_max_bytes: v.video_max_bytes,
+            voice_max_bytes: v.voice_max_bytes,
+            file_max_bytes: v.file_max_bytes,
+        }
+    }
+}
+
+impl From<privchat_sdk::auto_download::AutoDownloadPolicy> for AutoDownloadPolicyView {
+    fn from(v: privchat_sdk::auto_download::AutoDownloadPolicy) -> Self {
+        Self {
+            unmetered: v.unmetered.into(),
+            metered: v.metered.into(),
+            skip_muted_channels: v.skip_muted_channels,
+            voice_always: v.voice_always,
+        }
+    }
+}
+
+impl From<AutoDownloadPolicyView> for privchat_sdk::auto_download::AutoDownloadPolicy {
+    fn from(v: AutoDownloadPolicyView) -> Self {
+        Self {
+            unmetered: v.unmetered.into(),
+            metered: v.metered.into(),
+            skip_muted_channels: v.skip_muted_channels,
+            voice_always: v.voice_always,
+        }
+    }
+}
+
 /// Channel Transfer client→app reply (decoded from wire `TransferResponse`).
 /// See `02-server/CHANNEL_TRANSFER_SPEC.md` v2.0.
 #[derive(Debug, Clone, Serialize, uniffi::Record)]
@@ -345,6 +428,269 @@ pub struct SeenByEntry {
     pub read_at: Option<u64>,
 }
 
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct MemberReadCursor {
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub reader_id: u64,
+    pub read_pts: u64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct MessageReadReceipts {
+    pub message_id: u64,
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub pts: u64,
+    pub readers: Vec<MemberReadCursor>,
+    pub read_count: u32,
+    pub unknown_member_ids: Vec<u64>,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct UnreadTagTotal {
+    pub tag: String,
+    pub unread_count: u32,
+    pub badge_count: u32,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct UnreadFolderTotal {
+    pub folder_id: u64,
+    pub name: String,
+    pub unread_count: u32,
+    pub badge_count: u32,
+}
+
+/// 按会话偏好聚合的未读；`badge_count` 就是 launcher 角标该显示的数。
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct UnreadAggregate {
+    pub badge_count: u32,
+    pub total_unread: u32,
+    pub badge_channel_count: u32,
+    pub muted_unread: u32,
+    pub muted_mention_count: u32,
+    pub favourite_unread: u32,
+    pub low_priority_unread: u32,
+    pub tags: Vec<UnreadTagTotal>,
+    pub folders: Vec<UnreadFolderTotal>,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct AccountUnread {
+    pub uid: String,
+    pub aggregate: UnreadAggregate,
+    /// 这个账号取不到未读时的原因；此时 `aggregate` 为空且不计入 `total`
+    pub error: Option<String>,
+}
+
+/// 所有在线账号的未读，`total` 是各账号之和。
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct AccountsUnreadSummary {
+    pub total: UnreadAggregate,
+    pub accounts: Vec<AccountUnread>,
+}
+
+/// [`AccountManager`] 打开的一个账号实例；`slot_id` 用于关掉它、对应事件。
+#[derive(Clone, uniffi::Record)]
+pub struct OpenedAccount {
+    pub slot_id: u64,
+    pub client: Arc<PrivchatClient>,
+}
+
+/// 某个账号实例发出的事件。`uid` 为空表示该实例还没登录。
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct AccountEvent {
+    pub slot_id: u64,
+    pub uid: Option<String>,
+    pub event: SdkEvent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum ChannelVisibility {
+    Visible,
+    Hidden,
+    All,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum ChannelSortKey {
+    LastActivity,
+    Unread,
+}
+
+/// 会话列表筛选；各项之间是「与」，`None` / `false` 表示不按该项筛选。
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ChannelListFilter {
+    pub unread_only: bool,
+    pub mentions_only: bool,
+    pub favourite: Option<bool>,
+    pub low_priority: Option<bool>,
+    pub tag: Option<String>,
+    pub channel_type: Option<i32>,
+    pub visibility: ChannelVisibility,
+    pub muted: Option<bool>,
+    pub keyword: Option<String>,
+    pub folder_id: Option<u64>,
+}
+
+/// keyset 游标，原样传回 [`ChannelListQuery::after`] 即可。
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ChannelListCursor {
+    pub top: i32,
+    pub unread_count: i32,
+    pub last_msg_timestamp: i64,
+    pub channel_id: u64,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ChannelListQuery {
+    pub filter: ChannelListFilter,
+    pub sort: ChannelSortKey,
+    pub pinned_first: bool,
+    pub limit: u32,
+    pub after: Option<ChannelListCursor>,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ChannelListPage {
+    pub channels: Vec<StoredChannel>,
+    pub next_cursor: Option<ChannelListCursor>,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ChannelFolderEntry {
+    pub channel_id: u64,
+    pub channel_type: i32,
+}
+
+/// 用户自建的会话文件夹：手选的会话加上（`filter` 非空时）满足条件的会话。
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ChannelFolder {
+    pub folder_id: u64,
+    pub name: String,
+    pub sort_order: i32,
+    pub filter: ChannelListFilter,
+    pub channels: Vec<ChannelFolderEntry>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// `folder_id` 为 `None` 时新建，否则整体覆盖。
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ChannelFolderInput {
+    pub folder_id: Option<u64>,
+    pub name: String,
+    pub sort_order: i32,
+    pub filter: ChannelListFilter,
+    pub channels: Vec<ChannelFolderEntry>,
+}
+
+/// 活时间线的一条增量，按顺序应用到上一份窗口上。
+#[derive(Debug, Clone, uniffi::Enum)]
+pub enum TimelineDiff {
+    Reset { items: Vec<StoredMessage> },
+    Insert { index: u32, item: StoredMessage },
+    Update { index: u32, item: StoredMessage },
+    Remove { index: u32 },
+}
+
+/// 活会话列表的一条增量，按顺序应用到上一份列表上。
+#[derive(Debug, Clone, uniffi::Enum)]
+pub enum ChannelListDiff {
+    Reset { items: Vec<StoredChannel> },
+    Insert { index: u32, item: StoredChannel },
+    Update { index: u32, item: StoredChannel },
+    Remove { index: u32 },
+}
+
+/// 会话通知模式（会话偏好里的 `notification_mode`）：决定会话的未读怎么进角标、
+/// 算不算静音（会话列表 `muted` 筛选、自动下载跳过静音会话），以及推送响不响。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum ChannelNotificationMode {
+    /// 跟随会话自身的 `mute`：mute 或低优先级时只有 @ 进角标、只有 @ 才提醒。
+    Default,
+    /// 所有消息都进角标、都提醒，覆盖 `mute` 和低优先级。
+    All,
+    /// 只有 @ 进角标、只有 @ 才提醒。
+    MentionsOnly,
+    /// 不提醒，@ 也不进角标（仍计入 `muted_mention_count`）。
+    Off,
+}
+
+impl From<ChannelNotificationMode> for i32 {
+    fn from(mode: ChannelNotificationMode) -> Self {
+        use privchat_sdk::unread_badge::notification_mode;
+        match mode {
+            ChannelNotificationMode::Default => notification_mode::DEFAULT,
+            ChannelNotificationMode::All => notification_mode::ALL,
+            ChannelNotificationMode::MentionsOnly => notification_mode::MENTIONS_ONLY,
+            ChannelNotificationMode::Off => notification_mode::NONE,
+        }
+    }
+}
+
+impl From<i32> for ChannelNotificationMode {
+    /// 不认识的存量取值按 [`ChannelNotificationMode::Default`] 算，和角标规则一致。
+    fn from(mode: i32) -> Self {
+        use privchat_sdk::unread_badge::notification_mode;
+        match mode {
+            notification_mode::ALL => ChannelNotificationMode::All,
+            notification_mode::MENTIONS_ONLY => ChannelNotificationMode::MentionsOnly,
+            notification_mode::NONE => ChannelNotificationMode::Off,
+            _ => ChannelNotificationMode::Default,
+        }
+    }
+}
+
+/// 通知里露出多少内容；被隐藏的字段在 [`PushNotification`] 里为空。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum NotificationPrivacy {
+    Full,
+    HideContent,
+    HideAll,
+}
+
+/// 系统推送携带的一条消息，字段与长连接 push 相同；`timestamp` 是秒。
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct PushPayload {
+    pub server_message_id: u64,
+    pub local_message_id: u64,
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub from_uid: u64,
+    pub message_type: i32,
+    pub payload: Vec<u8>,
+    pub message_seq: i64,
+    pub timestamp: i64,
+    pub deleted: bool,
+}
+
+/// [`PushIngestor::ingest`] 落库后可以直接渲染的通知。
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct PushNotification {
+    pub message_id: u64,
+    pub server_message_id: u64,
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub title: Option<String>,
+    pub sender_name: Option<String>,
+    /// 正文或附件说明，不含类型占位；占位按 `message_kind` 由宿主本地化。
+    pub body: Option<String>,
+    pub message_kind: Option<String>,
+    /// 按通知模式和会话 `mute` 算的静音，与 `badge_count` 同一条规则。
+    pub channel_muted: bool,
+    pub notification_mode: ChannelNotificationMode,
+    /// 这条消息 @ 了当前账号。
+    pub mentions_me: bool,
+    pub from_self: bool,
+    /// 非自己发的，且会话没静音或静音但 @ 了自己；通知模式为 `Off` 时总是 `false`。
+    pub should_alert: bool,
+    pub channel_unread_count: i32,
+    pub badge_count: i32,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
 pub enum MediaProcessOp {
     Thumbnail,
@@ -839,6 +1185,36 @@ pub struct StickerInfoView {
     pub mime_type: String,
 }
 
+/// 本地表情目录里的一个表情。`local_path` 是预取到本地的图，还没下载时为空。
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct StickerView {
+    pub sticker_id: String,
+    pub package_id: String,
+    pub image_url: String,
+    pub alt_text: String,
+    pub emoji: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    pub mime_type: String,
+    pub local_path: Option<String>,
+}
+
+/// 本地表情目录里的一个包，带本账号的安装状态。
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct StickerPackageView {
+    pub package_id: String,
+    pub name: String,
+    pub thumbnail_url: String,
+    pub author: String,
+    pub description: String,
+    pub sticker_count: u64,
+    pub stickers: Vec<StickerView>,
+    pub installed: bool,
+    pub sort_order: i64,
+    pub installed_at: i64,
+    pub pending: bool,
+}
+
 #[derive(Debug, Clone, uniffi::Record)]
 pub struct SendMessageOptionsInput {
     pub in_reply_to_message_id: Option<u64>,
@@ -922,6 +1298,42 @@ pub struct ContactCardMessageInput {
     pub options: Option<StructuredSendOptionsInput>,
 }
 
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct StickerMessageInput {
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub from_uid: u64,
+    pub sticker_id: String,
+    pub options: Option<StructuredSendOptionsInput>,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct PollMessageInput {
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub from_uid: u64,
+    pub question: String,
+    pub poll_options: Vec<String>,
+    pub multi_select: bool,
+    pub anonymous: bool,
+    pub close_at: Option<i64>,
+    pub options: Option<StructuredSendOptionsInput>,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct LiveLocationMessageInput {
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub from_uid: u64,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub coordinate_system: Option<String>,
+    /// 起点的地点名，作为正文；为空时正文是起点坐标。
+    pub name: Option<String>,
+    pub duration_ms: i64,
+    pub options: Option<StructuredSendOptionsInput>,
+}
+
 #[derive(Debug, Clone, uniffi::Record)]
 pub struct GroupInfoView {
     pub group_id: u64,
@@ -1447,6 +1859,65 @@ pub enum MediaDownloadState {
     Failed { code: u32, message: String },
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum BackgroundTransferDirection {
+    Upload,
+    Download,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct HttpHeaderView {
+    pub name: String,
+    pub value: String,
+}
+
+/// 交给系统后台传输的一条请求。下载：响应体是 `[range_start, range_end)`，追加到
+/// `path` 末尾（`range_start == 0` 时覆盖）；上传：请求体是 `path` 的这一段。
+/// 跑完之后把 `transfer_id` 交回 `import_completed_transfer`。
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct BackgroundTransferView {
+    pub transfer_id: String,
+    pub direction: BackgroundTransferDirection,
+    pub message_id: u64,
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<HttpHeaderView>,
+    pub range_start: u64,
+    pub range_end: Option<u64>,
+    pub path: String,
+    pub expected_size: Option<u64>,
+    pub expected_sha256: Option<String>,
+}
+
+impl From<privchat_sdk::background_transfer::BackgroundTransfer> for BackgroundTransferView {
+    fn from(v: privchat_sdk::background_transfer::BackgroundTransfer) -> Self {
+        Self {
+            transfer_id: v.transfer_id,
+            direction: match v.direction {
+                privchat_sdk::bandwidth::TransferDirection::Upload => {
+                    BackgroundTransferDirection::Upload
+                }
+                privchat_sdk::bandwidth::TransferDirection::Download => {
+                    BackgroundTransferDirection::Download
+                }
+            },
+            message_id: v.message_id,
+            method: v.method,
+            url: v.url,
+            headers: v
+                .headers
+                .into_iter()
+                .map(|(name, value)| HttpHeaderView { name, value })
+                .collect(),
+            range_start: v.range_start,
+            range_end: v.range_end,
+            path: v.path.to_string_lossy().to_string(),
+            expected_size: v.expected_size,
+            expected_sha256: v.expected_sha256,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
 pub enum ForcedLogoutSource {
     ConnectAuth,
@@ -1590,6 +2061,19 @@ pub enum SdkEvent {
         server_message_id: u64,
         delivered_at: u64,
     },
+    /// 未读聚合变了（只在真正变化时发），宿主据此刷新 launcher 角标。
+    BadgeChanged {
+        aggregate: UnreadAggregate,
+    },
+    ChannelPrefsChanged {
+        channel_id: u64,
+        channel_type: i32,
+    },
+    PinnedMessagesChanged {
+        channel_id: u64,
+        channel_type: i32,
+    },
+    StickerCatalogChanged,
     MediaDownloadStateChanged {
         message_id: u64,
         state: MediaDownloadState,
@@ -1778,6 +2262,149 @@ pub struct AttachmentTransferStatsView {
     pub claims: u64,
     pub body_uploads: u64,
     pub thumbnail_uploads: u64,
+    /// 本次运行以来按网络类型累计的上传 / 下载字节。
+    pub bytes_by_network: Vec<NetworkTransferBytesView>,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct NetworkTransferBytesView {
+    pub network: NetworkHint,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+/// 限速 / 省流量配置。速率单位字节/秒，0 = 不限。
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct BandwidthPolicyView {
+    pub wifi_bytes_per_sec: u64,
+    pub ethernet_bytes_per_sec: u64,
+    pub cellular_bytes_per_sec: u64,
+    pub unknown_bytes_per_sec: u64,
+    /// 计费网络（蜂窝 / 未知）下缩小发送侧缩略图，并推迟首屏扫补。
+    pub data_saver: bool,
+}
+
+impl From<privchat_sdk::bandwidth::BandwidthPolicy> for BandwidthPolicyView {
+    fn from(v: privchat_sdk::bandwidth::BandwidthPolicy) -> Self {
+        Self {
+            wifi_bytes_per_sec: v.wifi_bytes_per_sec,
+            ethernet_bytes_per_sec: v.ethernet_bytes_per_sec,
+            cellular_bytes_per_sec: v.cellular_bytes_per_sec,
+            unknown_bytes_per_sec: v.unknown_bytes_per_sec,
+            data_saver: v.data_saver,
+        }
+    }
+}
+
+impl From<BandwidthPolicyView> for privchat_sdk::bandwidth::BandwidthPolicy {
+    fn from(v: BandwidthPolicyView) -> Self {
+        Self {
+            wifi_bytes_per_sec: v.wifi_bytes_per_sec,
+            ethernet_bytes_per_sec: v.ethernet_bytes_per_sec,
+            cellular_bytes_per_sec: v.cellular_bytes_per_sec,
+            unknown_bytes_per_sec: v.unknown_bytes_per_sec,
+            data_saver: v.data_saver,
+        }
+    }
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct MetricLabelView {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct CounterSampleView {
+    pub name: String,
+    pub labels: Vec<MetricLabelView>,
+    pub value: u64,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct GaugeSampleView {
+    pub name: String,
+    pub labels: Vec<MetricLabelView>,
+    pub value: f64,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct HistogramBucketView {
+    pub upper_bound: f64,
+    pub cumulative_count: u64,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct HistogramSampleView {
+    pub name: String,
+    pub labels: Vec<MetricLabelView>,
+    /// 累计计数，不含 `+Inf`（`+Inf` 就是 `count`）。
+    pub buckets: Vec<HistogramBucketView>,
+    pub count: u64,
+    pub sum: f64,
+}
+
+/// 见 [`PrivchatClient::metrics_snapshot`]。
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct MetricsSnapshotView {
+    pub captured_at_ms: i64,
+    pub counters: Vec<CounterSampleView>,
+    pub gauges: Vec<GaugeSampleView>,
+    pub histograms: Vec<HistogramSampleView>,
+}
+
+fn metric_labels_view(labels: Vec<privchat_sdk::metrics::MetricLabel>) -> Vec<MetricLabelView> {
+    labels
+        .into_iter()
+        .map(|l| MetricLabelView {
+            name: l.name,
+            value: l.value,
+        })
+        .collect()
+}
+
+impl From<privchat_sdk::metrics::MetricsSnapshot> for MetricsSnapshotView {
+    fn from(s: privchat_sdk::metrics::MetricsSnapshot) -> Self {
+        Self {
+            captured_at_ms: s.captured_at_ms,
+            counters: s
+                .counters
+                .into_iter()
+                .map(|c| CounterSampleView {
+                    name: c.name,
+                    labels: metric_labels_view(c.labels),
+                    value: c.value,
+                })
+                .collect(),
+            gauges: s
+                .gauges
+                .into_iter()
+                .map(|g| GaugeSampleView {
+                    name: g.name,
+                    labels: metric_labels_view(g.labels),
+                    value: g.value,
+                })
+                .collect(),
+            histograms: s
+                .histograms
+                .into_iter()
+                .map(|h| HistogramSampleView {
+                    name: h.name,
+                    labels: metric_labels_view(h.labels),
+                    buckets: h
+                        .buckets
+                        .into_iter()
+                        .map(|b| HistogramBucketView {
+                            upper_bound: b.upper_bound,
+                            cumulative_count: b.cumulative_count,
+                        })
+                        .collect(),
+                    count: h.count,
+                    sum: h.sum,
+                })
+                .collect(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, uniffi::Record)]
@@ -1812,6 +2439,40 @@ pub struct MessageContentRef {
     pub text: Option<String>,
 }
 
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct PollOptionView {
+    pub text: String,
+    pub vote_count: u32,
+    pub voted: bool,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct PollView {
+    pub question: String,
+    pub options: Vec<PollOptionView>,
+    pub multi_select: bool,
+    pub anonymous: bool,
+    pub close_at: Option<i64>,
+    pub closed: bool,
+    pub voter_count: u32,
+    pub vote_pending: bool,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct LiveLocationView {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub accuracy: Option<f64>,
+    pub heading: Option<f64>,
+    pub coordinate_system: Option<String>,
+    pub started_at: i64,
+    pub expires_at: i64,
+    pub updated_at: i64,
+    pub ended: bool,
+    pub stale: bool,
+    pub update_pending: bool,
+}
+
 #[derive(Debug, Clone, uniffi::Record)]
 pub struct MessageContentBody {
     pub kind: String,
@@ -1850,6 +2511,10 @@ pub struct MessageContentBody {
     pub money_amount_text: Option<String>,
     pub money_scene: Option<String>,
     pub money_type: Option<i32>,
+    pub revision_count: u32,
+    pub poll: Option<PollView>,
+    pub live_location: Option<LiveLocationView>,
+    pub sticker: Option<StickerView>,
 }
 
 #[derive(Debug, Clone, uniffi::Record)]
@@ -1972,6 +2637,29 @@ pub struct StoredMessageExtra {
     pub delivered_at: u64,
 }
 
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct MessageRevision {
+    pub message_id: u64,
+    pub version: u64,
+    pub content: String,
+    pub edited_at: i32,
+    pub editor_uid: Option<u64>,
+    /// 本地编辑、服务端还没确认；version 是临时的
+    pub pending: bool,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct PinnedMessage {
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub server_message_id: u64,
+    pub message_id: Option<u64>,
+    pub pinned_by: Option<u64>,
+    pub pinned_by_name: Option<String>,
+    pub pinned_at: i64,
+    pub pending: bool,
+}
+
 #[derive(Debug, Clone, uniffi::Record)]
 pub struct UpsertUserInput {
     pub user_id: u64,
@@ -2688,6 +3376,24 @@ fn map_sdk_event(v: privchat_sdk::SdkEvent) -> SdkEvent {
             server_message_id,
             delivered_at,
         },
+        privchat_sdk::SdkEvent::BadgeChanged { aggregate } => SdkEvent::BadgeChanged {
+            aggregate: map_unread_aggregate(aggregate),
+        },
+        privchat_sdk::SdkEvent::ChannelPrefsChanged {
+            channel_id,
+            channel_type,
+        } => SdkEvent::ChannelPrefsChanged {
+            channel_id,
+            channel_type,
+        },
+        privchat_sdk::SdkEvent::PinnedMessagesChanged {
+            channel_id,
+            channel_type,
+        } => SdkEvent::PinnedMessagesChanged {
+            channel_id,
+            channel_type,
+        },
+        privchat_sdk::SdkEvent::StickerCatalogChanged => SdkEvent::StickerCatalogChanged,
         privchat_sdk::SdkEvent::MediaDownloadStateChanged { message_id, state } => {
             SdkEvent::MediaDownloadStateChanged {
                 message_id,
@@ -3012,6 +3718,32 @@ fn sdk_event_to_json_value(event: &SdkEvent) -> serde_json::Value {
             "server_message_id": server_message_id,
             "delivered_at": delivered_at
         }),
+        SdkEvent::BadgeChanged { aggregate } => json!({
+            "type": "badge_changed",
+            "badge_count": aggregate.badge_count,
+            "total_unread": aggregate.total_unread,
+            "badge_channel_count": aggregate.badge_channel_count,
+            "muted_mention_count": aggregate.muted_mention_count
+        }),
+        SdkEvent::ChannelPrefsChanged {
+            channel_id,
+            channel_type,
+        } => json!({
+            "type": "channel_prefs_changed",
+            "channel_id": channel_id,
+            "channel_type": channel_type
+        }),
+        SdkEvent::PinnedMessagesChanged {
+            channel_id,
+            channel_type,
+        } => json!({
+            "type": "pinned_messages_changed",
+            "channel_id": channel_id,
+            "channel_type": channel_type
+        }),
+        SdkEvent::StickerCatalogChanged => json!({
+            "type": "sticker_catalog_changed"
+        }),
         SdkEvent::MediaDownloadStateChanged { message_id, state } => json!({
             "type": "media_download_state_changed",
             "message_id": message_id,
@@ -3220,6 +3952,44 @@ fn map_contact_card_message_input(v: ContactCardMessageInput) -> SdkContactCardM
     }
 }
 
+fn map_sticker_message_input(v: StickerMessageInput) -> SdkStickerMessageInput {
+    SdkStickerMessageInput {
+        channel_id: v.channel_id,
+        channel_type: v.channel_type,
+        from_uid: v.from_uid,
+        sticker_id: v.sticker_id,
+        options: map_structured_options(v.options),
+    }
+}
+
+fn map_poll_message_input(v: PollMessageInput) -> SdkPollMessageInput {
+    SdkPollMessageInput {
+        channel_id: v.channel_id,
+        channel_type: v.channel_type,
+        from_uid: v.from_uid,
+        question: v.question,
+        poll_options: v.poll_options,
+        multi_select: v.multi_select,
+        anonymous: v.anonymous,
+        close_at: v.close_at,
+        options: map_structured_options(v.options),
+    }
+}
+
+fn map_live_location_message_input(v: LiveLocationMessageInput) -> SdkLiveLocationMessageInput {
+    SdkLiveLocationMessageInput {
+        channel_id: v.channel_id,
+        channel_type: v.channel_type,
+        from_uid: v.from_uid,
+        latitude: v.latitude,
+        longitude: v.longitude,
+        coordinate_system: v.coordinate_system,
+        name: v.name,
+        duration_ms: v.duration_ms,
+        options: map_structured_options(v.options),
+    }
+}
+
 fn map_upsert_channel(v: UpsertChannelInput) -> SdkUpsertChannelInput {
     SdkUpsertChannelInput {
         channel_id: v.channel_id,
@@ -3349,6 +4119,47 @@ fn map_message_content(
         money_amount_text: v.money_amount_text,
         money_scene: v.money_scene,
         money_type: v.money_type,
+        revision_count: v.revision_count,
+        poll: v.poll.map(map_poll_view),
+        live_location: v.live_location.map(map_live_location_view),
+        sticker: v.sticker.map(map_sticker),
+    }
+}
+
+fn map_live_location_view(v: SdkLiveLocationView) -> LiveLocationView {
+    LiveLocationView {
+        latitude: v.latitude,
+        longitude: v.longitude,
+        accuracy: v.accuracy,
+        heading: v.heading,
+        coordinate_system: v.coordinate_system,
+        started_at: v.started_at,
+        expires_at: v.expires_at,
+        updated_at: v.updated_at,
+        ended: v.ended,
+        stale: v.stale,
+        update_pending: v.update_pending,
+    }
+}
+
+fn map_poll_view(v: SdkPollView) -> PollView {
+    PollView {
+        question: v.question,
+        options: v
+            .options
+            .into_iter()
+            .map(|o| PollOptionView {
+                text: o.text,
+                vote_count: o.vote_count,
+                voted: o.voted,
+            })
+            .collect(),
+        multi_select: v.multi_select,
+        anonymous: v.anonymous,
+        close_at: v.close_at,
+        closed: v.closed,
+        voter_count: v.voter_count,
+        vote_pending: v.vote_pending,
     }
 }
 
@@ -3415,6 +4226,9 @@ fn map_stored_channel(v: SdkStoredChannel) -> StoredChannel {
             thumb_status: 0,
             delivered: false,
             pts: None,
+            revision_count: 0,
+            poll: None,
+            live_location: None,
         };
         map_message_content(privchat_sdk::message_content::project_stored_message(
             &synthetic,
@@ -3478,6 +4292,300 @@ fn map_stored_message_extra(v: SdkStoredMessageExtra) -> StoredMessageExtra {
     }
 }
 
+fn map_member_read_cursor(v: SdkMemberReadCursor) -> MemberReadCursor {
+    MemberReadCursor {
+        channel_id: v.channel_id,
+        channel_type: v.channel_type,
+        reader_id: v.reader_id,
+        read_pts: v.read_pts,
+        updated_at: v.updated_at,
+    }
+}
+
+fn map_message_read_receipts(v: SdkMessageReadReceipts) -> MessageReadReceipts {
+    MessageReadReceipts {
+        message_id: v.message_id,
+        channel_id: v.channel_id,
+        channel_type: v.channel_type,
+        pts: v.pts,
+        read_count: v.readers.len() as u32,
+        readers: v.readers.into_iter().map(map_member_read_cursor).collect(),
+        unknown_member_ids: v.unknown_member_ids,
+    }
+}
+
+/// 冷缓存：还有成员没游标，且这个会话本地一条成员游标都没有。只缺几个人的游标时
+/// 不算——那些人多半就是没读，每次打开都去问服务端不值得。
+fn seen_by_cache_missed(receipts: &SdkMessageReadReceipts, known_cursors: usize) -> bool {
+    !receipts.unknown_member_ids.is_empty() && known_cursors == 0
+}
+
+fn seen_by_entries(receipts: SdkMessageReadReceipts) -> Vec<SeenByEntry> {
+    receipts
+        .readers
+        .into_iter()
+        .map(|r| SeenByEntry {
+            user_id: r.reader_id,
+            read_at: None,
+        })
+        .collect()
+}
+
+fn map_unread_aggregate(v: SdkUnreadAggregate) -> UnreadAggregate {
+    UnreadAggregate {
+        badge_count: v.badge_count,
+        total_unread: v.total_unread,
+        badge_channel_count: v.badge_channel_count,
+        muted_unread: v.muted_unread,
+        muted_mention_count: v.muted_mention_count,
+        favourite_unread: v.favourite_unread,
+        low_priority_unread: v.low_priority_unread,
+        tags: v
+            .tags
+            .into_iter()
+            .map(|t| UnreadTagTotal {
+                tag: t.tag,
+                unread_count: t.unread_count,
+                badge_count: t.badge_count,
+            })
+            .collect(),
+        folders: v
+            .folders
+            .into_iter()
+            .map(|f| UnreadFolderTotal {
+                folder_id: f.folder_id,
+                name: f.name,
+                unread_count: f.unread_count,
+                badge_count: f.badge_count,
+            })
+            .collect(),
+    }
+}
+
+fn map_channel_list_filter(v: ChannelListFilter) -> SdkChannelListFilter {
+    SdkChannelListFilter {
+        unread_only: v.unread_only,
+        mentions_only: v.mentions_only,
+        favourite: v.favourite,
+        low_priority: v.low_priority,
+        tag: v.tag,
+        channel_type: v.channel_type,
+        visibility: match v.visibility {
+            ChannelVisibility::Visible => SdkChannelVisibility::Visible,
+            ChannelVisibility::Hidden => SdkChannelVisibility::Hidden,
+            ChannelVisibility::All => SdkChannelVisibility::All,
+        },
+        muted: v.muted,
+        keyword: v.keyword,
+        folder_id: v.folder_id,
+    }
+}
+
+fn map_channel_list_filter_back(v: SdkChannelListFilter) -> ChannelListFilter {
+    ChannelListFilter {
+        unread_only: v.unread_only,
+        mentions_only: v.mentions_only,
+        favourite: v.favourite,
+        low_priority: v.low_priority,
+        tag: v.tag,
+        channel_type: v.channel_type,
+        visibility: match v.visibility {
+            SdkChannelVisibility::Visible => ChannelVisibility::Visible,
+            SdkChannelVisibility::Hidden => ChannelVisibility::Hidden,
+            SdkChannelVisibility::All => ChannelVisibility::All,
+        },
+        muted: v.muted,
+        keyword: v.keyword,
+        folder_id: v.folder_id,
+    }
+}
+
+fn map_channel_list_query(v: ChannelListQuery) -> SdkChannelListQuery {
+    SdkChannelListQuery {
+        filter: map_channel_list_filter(v.filter),
+        sort: match v.sort {
+            ChannelSortKey::LastActivity => SdkChannelSortKey::LastActivity,
+            ChannelSortKey::Unread => SdkChannelSortKey::Unread,
+        },
+        pinned_first: v.pinned_first,
+        limit: v.limit,
+        after: v.after.map(|c| SdkChannelListCursor {
+            top: c.top,
+            unread_count: c.unread_count,
+            last_msg_timestamp: c.last_msg_timestamp,
+            channel_id: c.channel_id,
+        }),
+    }
+}
+
+fn map_channel_list_page(v: SdkChannelListPage) -> ChannelListPage {
+    ChannelListPage {
+        channels: v.channels.into_iter().map(map_stored_channel).collect(),
+        next_cursor: v.next_cursor.map(|c| ChannelListCursor {
+            top: c.top,
+            unread_count: c.unread_count,
+            last_msg_timestamp: c.last_msg_timestamp,
+            channel_id: c.channel_id,
+        }),
+    }
+}
+
+fn map_timeline_diff(v: SdkListDiff<SdkStoredMessage>) -> TimelineDiff {
+    match v {
+        SdkListDiff::Reset { items } => TimelineDiff::Reset {
+            items: items.into_iter().map(map_stored_message).collect(),
+        },
+        SdkListDiff::Insert { index, item } => TimelineDiff::Insert {
+            index,
+            item: map_stored_message(item),
+        },
+        SdkListDiff::Update { index, item } => TimelineDiff::Update {
+            index,
+            item: map_stored_message(item),
+        },
+        SdkListDiff::Remove { index } => TimelineDiff::Remove { index },
+    }
+}
+
+fn map_channel_list_diff(v: SdkListDiff<SdkStoredChannel>) -> ChannelListDiff {
+    match v {
+        SdkListDiff::Reset { items } => ChannelListDiff::Reset {
+            items: items.into_iter().map(map_stored_channel).collect(),
+        },
+        SdkListDiff::Insert { index, item } => ChannelListDiff::Insert {
+            index,
+            item: map_stored_channel(item),
+        },
+        SdkListDiff::Update { index, item } => ChannelListDiff::Update {
+            index,
+            item: map_stored_channel(item),
+        },
+        SdkListDiff::Remove { index } => ChannelListDiff::Remove { index },
+    }
+}
+
+fn map_channel_folder(v: SdkChannelFolder) -> ChannelFolder {
+    ChannelFolder {
+        folder_id: v.folder_id,
+        name: v.name,
+        sort_order: v.sort_order,
+        filter: map_channel_list_filter_back(v.filter),
+        channels: v
+            .channels
+            .into_iter()
+            .map(|(channel_id, channel_type)| ChannelFolderEntry {
+                channel_id,
+                channel_type,
+            })
+            .collect(),
+        created_at: v.created_at,
+        updated_at: v.updated_at,
+    }
+}
+
+fn map_push_payload(v: PushPayload) -> SdkPushPayload {
+    SdkPushPayload {
+        server_message_id: v.server_message_id,
+        local_message_id: v.local_message_id,
+        channel_id: v.channel_id,
+        channel_type: v.channel_type,
+        from_uid: v.from_uid,
+        message_type: v.message_type,
+        payload: v.payload,
+        message_seq: v.message_seq,
+        timestamp: v.timestamp,
+        deleted: v.deleted,
+    }
+}
+
+fn map_push_notification(v: SdkPushNotification) -> PushNotification {
+    PushNotification {
+        should_alert: v.should_alert(),
+        message_id: v.message_id,
+        server_message_id: v.server_message_id,
+        channel_id: v.channel_id,
+        channel_type: v.channel_type,
+        title: v.title,
+        sender_name: v.sender_name,
+        body: v.body,
+        message_kind: v.message_kind,
+        channel_muted: v.channel_muted,
+        notification_mode: ChannelNotificationMode::from(v.notification_mode),
+        mentions_me: v.mentions_me,
+        from_self: v.from_self,
+        channel_unread_count: v.channel_unread_count,
+        badge_count: v.badge_count,
+    }
+}
+
+fn map_message_revision(v: SdkMessageRevision) -> MessageRevision {
+    MessageRevision {
+        message_id: v.message_id,
+        version: v.version,
+        content: v.content,
+        edited_at: v.edited_at,
+        editor_uid: v.editor_uid,
+        pending: v.pending,
+    }
+}
+
+fn map_pinned_message(v: SdkPinnedMessage) -> PinnedMessage {
+    PinnedMessage {
+        channel_id: v.channel_id,
+        channel_type: v.channel_type,
+        server_message_id: v.server_message_id,
+        message_id: v.message_id,
+        pinned_by: v.pinned_by,
+        pinned_by_name: v.pinned_by_name,
+        pinned_at: v.pinned_at,
+        pending: v.pending,
+    }
+}
+
+fn map_sticker(v: SdkSticker) -> StickerView {
+    StickerView {
+        sticker_id: v.sticker_id,
+        package_id: v.package_id,
+        image_url: v.image_url,
+        alt_text: v.alt_text,
+        emoji: v.emoji,
+        width: v.width,
+        height: v.height,
+        mime_type: v.mime_type,
+        local_path: v.local_path,
+    }
+}
+
+fn map_sticker_view(v: StickerView) -> SdkSticker {
+    SdkSticker {
+        sticker_id: v.sticker_id,
+        package_id: v.package_id,
+        image_url: v.image_url,
+        alt_text: v.alt_text,
+        emoji: v.emoji,
+        width: v.width,
+        height: v.height,
+        mime_type: v.mime_type,
+        local_path: None,
+    }
+}
+
+fn map_sticker_package(v: SdkStickerPackage) -> StickerPackageView {
+    StickerPackageView {
+        package_id: v.package_id,
+        name: v.name,
+        thumbnail_url: v.thumbnail_url,
+        author: v.author,
+        description: v.description,
+        sticker_count: v.sticker_count,
+        stickers: v.stickers.into_iter().map(map_sticker).collect(),
+        installed: v.installed,
+        sort_order: v.sort_order,
+        installed_at: v.installed_at,
+        pending: v.pending,
+    }
+}
+
 fn map_upsert_user(v: UpsertUserInput) -> SdkUpsertUserInput {
     SdkUpsertUserInput {
         user_id: v.user_id,
@@ -3825,6 +4933,10 @@ fn map_sticker_package_info(
 
 #[derive(uniffi::Object)]
 pub struct PrivchatClient {
+    /// v1 子集（连接、认证、会话/消息读取、发送、已读、typing、事件）一律走这里，
+    /// 组合逻辑只在 facade 里有一份。见 `docs/CLIENT_SERVICE_FACADE_SPEC.md` §13。
+    service: ClientService,
+    /// 还没提升进 facade 的直通方法用。与 `service` 是同一个 SDK 实例。
     inner: InnerSdk,
     event_rx: Arc<AsyncMutex<tokio::sync::broadcast::Receiver<privchat_sdk::SdkEvent>>>,
     config: Arc<StdMutex<PrivchatConfig>>,
@@ -3834,6 +4946,9 @@ pub struct PrivchatClient {
     typing_stopped_count: Arc<AtomicU64>,
     send_queue_enabled: Arc<AtomicBool>,
     disabled_channel_queues: Arc<AsyncMutex<HashSet<(u64, i32)>>>,
+    /// 本进程里回填过已读回执的会话。回填把服务端的已读成员落成游标，之后成员的已读
+    /// 推送会推进游标：本地对整份成员列表就是权威的，没游标的成员就是没读。
+    read_receipts_backfilled: Arc<AsyncMutex<HashSet<(u64, i32)>>>,
     lifecycle_hook_registered: Arc<AtomicBool>,
     transport_disconnect_listener_started: Arc<AtomicBool>,
     on_connection_state_changed_registered: Arc<AtomicBool>,
@@ -3850,8 +4965,14 @@ impl PrivchatClient {
     #[uniffi::constructor]
     pub fn new(config: PrivchatConfig) -> Result<Self, PrivchatFfiError> {
         eprintln!("[FFI] PrivchatClient::new");
-        let inner = InnerSdk::new(map_config(config.clone()));
-        let event_rx = Arc::new(AsyncMutex::new(inner.subscribe_events()));
+        let sdk = InnerSdk::new(map_config(config.clone()));
+        Ok(Self::from_sdk(sdk, config))
+    }
+
+    fn from_sdk(sdk: InnerSdk, config: PrivchatConfig) -> Self {
+        let service = ClientService::from_sdk(sdk);
+        let inner = service.sdk().clone();
+        let event_rx = Arc::new(AsyncMutex::new(service.subscribe_events().into_receiver()));
         let config = Arc::new(StdMutex::new(config));
         let app_in_background = Arc::new(AtomicBool::new(false));
         let typing_active_channels = Arc::new(AsyncMutex::new(HashSet::new()));
@@ -3859,6 +4980,7 @@ impl PrivchatClient {
         let typing_stopped_count = Arc::new(AtomicU64::new(0));
         let send_queue_enabled = Arc::new(AtomicBool::new(true));
         let disabled_channel_queues = Arc::new(AsyncMutex::new(HashSet::new()));
+        let read_receipts_backfilled = Arc::new(AsyncMutex::new(HashSet::new()));
         let lifecycle_hook_registered = Arc::new(AtomicBool::new(false));
         let transport_disconnect_listener_started = Arc::new(AtomicBool::new(false));
         let on_connection_state_changed_registered = Arc::new(AtomicBool::new(false));
@@ -3867,8 +4989,9 @@ impl PrivchatClient {
         let on_typing_indicator_registered = Arc::new(AtomicBool::new(false));
         let video_process_hook_registered = Arc::new(AtomicBool::new(false));
         let event_poll_count = Arc::new(AtomicU64::new(0));
-        let event_envelope_cursor = Arc::new(AtomicU64::new(inner.last_event_sequence_id()));
-        Ok(Self {
+        let event_envelope_cursor = Arc::new(AtomicU64::new(service.last_event_sequence_id()));
+        Self {
+            service,
             inner,
             event_rx,
             config,
@@ -3878,6 +5001,7 @@ impl PrivchatClient {
             typing_stopped_count,
             send_queue_enabled,
             disabled_channel_queues,
+            read_receipts_backfilled,
             lifecycle_hook_registered,
             transport_disconnect_listener_started,
             on_connection_state_changed_registered,
@@ -3887,7 +5011,7 @@ impl PrivchatClient {
             video_process_hook_registered,
             event_poll_count,
             event_envelope_cursor,
-        })
+        }
     }
 
     async fn require_current_user_id(&self) -> Result<u64, PrivchatFfiError> {
@@ -3916,38 +5040,32 @@ impl PrivchatClient {
         channel_type: i32,
         server_message_id: u64,
     ) -> Result<Option<u64>, PrivchatFfiError> {
-        let items = self.list_messages(channel_id, channel_type, 500, 0).await?;
-        Ok(items
-            .into_iter()
-            .find(|m| m.server_message_id == Some(server_message_id))
-            .map(|m| m.message_id))
+        self.inner
+            .get_message_id_by_server_message_id(
+                Some((channel_id, channel_type)),
+                server_message_id,
+            )
+            .await
+            .map_err(PrivchatFfiError::from)
     }
 
-    async fn resolve_channel_id_by_server_message_id(
+    async fn resolve_message_id_by_server_message_id(
         &self,
         server_message_id: u64,
     ) -> Result<u64, PrivchatFfiError> {
-        let channels = self.list_channels(1, 200).await?;
-        for ch in channels {
-            let messages = self
-                .list_messages(ch.channel_id, ch.channel_type, 1, 200)
-                .await?;
-            if messages
-                .iter()
-                .any(|m| m.server_message_id == Some(server_message_id))
-            {
-                return Ok(ch.channel_id);
-            }
-        }
-        Err(PrivchatFfiError::SdkError {
-            code: privchat_protocol::ErrorCode::ResourceNotFound as u32,
-            detail: format!("channel not found for server_message_id={server_message_id}"),
-        })
+        self.inner
+            .get_message_id_by_server_message_id(None, server_message_id)
+            .await
+            .map_err(PrivchatFfiError::from)?
+            .ok_or_else(|| PrivchatFfiError::SdkError {
+                code: privchat_protocol::ErrorCode::ResourceNotFound as u32,
+                detail: format!("message not found for server_message_id={server_message_id}"),
+            })
     }
 
     pub async fn connect(&self) -> Result<(), PrivchatFfiError> {
         eprintln!("[FFI] connect_async: enter");
-        let out = self.inner.connect().await.map_err(PrivchatFfiError::from);
+        let out = self.service.connect().await.map_err(PrivchatFfiError::from);
         eprintln!("[FFI] connect_async: done ok={}", out.is_ok());
         out
     }
@@ -3955,7 +5073,7 @@ impl PrivchatClient {
     pub async fn disconnect(&self) -> Result<(), PrivchatFfiError> {
         eprintln!("[FFI] disconnect_async: enter");
         let out = self
-            .inner
+            .service
             .disconnect()
             .await
             .map_err(PrivchatFfiError::from);
@@ -3989,7 +5107,7 @@ impl PrivchatClient {
 
     pub async fn connection_state(&self) -> Result<ConnectionState, PrivchatFfiError> {
         let state = self
-            .inner
+            .service
             .connection_state()
             .await
             .map_err(PrivchatFfiError::from)?;
@@ -4023,6 +5141,24 @@ impl PrivchatClient {
             .map_err(PrivchatFfiError::from)
     }
 
+    pub fn bandwidth_policy(&self) -> BandwidthPolicyView {
+        self.inner.bandwidth_policy().into()
+    }
+
+    pub fn set_bandwidth_policy(&self, policy: BandwidthPolicyView) {
+        self.inner.set_bandwidth_policy(policy.into());
+    }
+
+    pub fn segmented_attachment_uploads(&self) -> bool {
+        self.inner.segmented_attachment_uploads()
+    }
+
+    /// 大附件是否封成 v2（分段加密）。默认关：老版本的接收端和服务端打不开 v2，
+    /// 确认都已升级后再打开。只在内存里，每次启动推一次。
+    pub fn set_segmented_attachment_uploads(&self, enabled: bool) {
+        self.inner.set_segmented_attachment_uploads(enabled);
+    }
+
     pub async fn get_connection_state(&self) -> Result<ConnectionState, PrivchatFfiError> {
         self.connection_state().await
     }
@@ -4094,7 +5230,7 @@ impl PrivchatClient {
         let deadline = std::time::Instant::now() + timeout;
         loop {
             let cursor = self.event_envelope_cursor.load(Ordering::Acquire);
-            if let Some(evt) = self.inner.events_since(cursor, 1).into_iter().next() {
+            if let Some(evt) = self.service.events_since(cursor, 1).into_iter().next() {
                 self.event_envelope_cursor
                     .store(evt.sequence_id, Ordering::Release);
                 return Ok(Some(map_sequenced_sdk_event(evt)));
@@ -4200,7 +5336,7 @@ impl PrivchatClient {
     }
 
     pub fn event_stream_cursor(&self) -> u64 {
-        self.inner.last_event_sequence_id()
+        self.service.last_event_sequence_id()
     }
 
     pub fn recent_events(&self, limit: u64) -> Vec<SequencedSdkEvent> {
@@ -4222,8 +5358,8 @@ impl PrivchatClient {
         } else {
             limit.min(self.inner.event_history_limit() as u64) as usize
         };
-        self.inner
-            .events_since(sequence_id, cap)
+        self.service
+            .events_since(sequence_id, cap as u64)
             .into_iter()
             .map(map_sequenced_sdk_event)
             .collect()
@@ -4309,8 +5445,12 @@ impl PrivchatClient {
     ) -> Result<LoginResult, PrivchatFfiError> {
         eprintln!("[FFI] login_async: enter");
         let result = self
-            .inner
-            .login(username, password, device_id)
+            .service
+            .login(ServiceLoginRequest {
+                username,
+                password,
+                device_id,
+            })
             .await
             .map_err(PrivchatFfiError::from)?;
         eprintln!("[FFI] login_async: sdk login ok user_id={}", result.user_id);
@@ -4325,8 +5465,12 @@ impl PrivchatClient {
     ) -> Result<LoginResult, PrivchatFfiError> {
         eprintln!("[FFI] register_async: enter");
         let result = self
-            .inner
-            .register(username, password, device_id)
+            .service
+            .register(ServiceRegisterRequest {
+                username,
+                password,
+                device_id,
+            })
             .await
             .map_err(PrivchatFfiError::from)?;
         eprintln!(
@@ -4344,8 +5488,12 @@ impl PrivchatClient {
     ) -> Result<(), PrivchatFfiError> {
         eprintln!("[FFI] authenticate_async: enter user_id={user_id}");
         let out = self
-            .inner
-            .authenticate(user_id, token, device_id)
+            .service
+            .authenticate(ServiceAuthenticateRequest {
+                user_id,
+                token,
+                device_id,
+            })
             .await
             .map_err(PrivchatFfiError::from);
         eprintln!("[FFI] authenticate_async: done ok={}", out.is_ok());
@@ -4411,7 +5559,10 @@ impl PrivchatClient {
 
     pub async fn shutdown(&self) -> Result<(), PrivchatFfiError> {
         eprintln!("[FFI] shutdown_async: enter");
-        self.inner.shutdown().await;
+        self.service
+            .shutdown()
+            .await
+            .map_err(PrivchatFfiError::from)?;
         eprintln!("[FFI] shutdown_async: done");
         Ok(())
     }
@@ -4422,7 +5573,7 @@ impl PrivchatClient {
 
     pub async fn session_snapshot(&self) -> Result<Option<SessionSnapshot>, PrivchatFfiError> {
         let value = self
-            .inner
+            .service
             .session_snapshot()
             .await
             .map_err(PrivchatFfiError::from)?;
@@ -4523,11 +5674,16 @@ impl PrivchatClient {
         // 远端注销本来也不需要在这里设上界：`Command::RpcCall` 在 actor 里已经包了
         // 20s 超时；而「actor 太忙、命令排不上队」那种卡死，已经在宿主层解决——
         // 点退出立刻回登录页，收尾在后台跑完，用户不再等这条链。
-        let _ = self.auth_logout_remote().await;
-        let _ = self.clear_local_state().await;
-        self.disconnect().await
+        //
+        // 「远端尽力注销 + 清凭证 + 断开」这串组合在 `ClientService::logout` 里。
+        self.service
+            .logout()
+            .await
+            .map_err(PrivchatFfiError::from)
     }
 
+    /// 只停同步。附件传输要在挂起后继续跑，宿主另调 `export_background_transfers`
+    /// 交给系统——要不要交、交给哪个服务由宿主决定。
     pub fn enter_background(&self) {
         self.app_in_background.store(true, Ordering::Relaxed);
         self.stop_supervised_sync();
@@ -4807,13 +5963,13 @@ impl PrivchatClient {
         is_typing: bool,
         action_type: TypingActionType,
     ) -> Result<(), PrivchatFfiError> {
-        self.inner
-            .send_typing(
+        self.service
+            .send_typing(SendTypingRequest {
                 channel_id,
-                channel_type,
-                is_typing,
-                map_typing_action(action_type),
-            )
+                channel_type: Some(channel_type),
+                active: is_typing,
+                action: map_typing_action(action_type),
+            })
             .await
             .map_err(PrivchatFfiError::from)?;
         let mut active = self.typing_active_channels.lock().await;
@@ -5507,28 +6663,34 @@ impl PrivchatClient {
         })
     }
 
-    pub async fn mark_read_to_pts(
+    pub async fn mark_read_to_pts(
+        &self,
+        channel_id: u64,
+        read_pts: u64,
+    ) -> Result<u64, PrivchatFfiError> {
+        self.service
+            .mark_read_to_pts(MarkReadToPtsRequest {
+                channel_id,
+                read_pts,
+            })
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    /// 「读到这条本地消息为止」。facade 查出它的 pts 再走 [`Self::mark_read_to_pts`]；
+    /// 消息不在本地或还没有 pts 时报错，**不会**拿 server_message_id 顶替。
+    pub async fn mark_read_by_local_message_id(
         &self,
         channel_id: u64,
-        read_pts: u64,
+        local_message_id: u64,
     ) -> Result<u64, PrivchatFfiError> {
-        let resp: MessageStatusReadPtsResponse = rpc_call_typed(
-            &self.inner,
-            routes::message_status::READ_PTS,
-            &MessageStatusReadPtsRequest {
+        self.service
+            .mark_read_by_local_message_id(MarkReadByLocalMessageIdRequest {
                 channel_id,
-                read_pts,
-                last_read_message_id: None,
-                client_visible_pts: None,
-            },
-        )
-        .await?;
-        let channel_type = self.resolve_channel_type(channel_id).await;
-        let _ = self
-            .inner
-            .project_channel_read_cursor(channel_id, channel_type, resp.last_read_pts)
-            .await;
-        Ok(resp.last_read_pts)
+                local_message_id,
+            })
+            .await
+            .map_err(PrivchatFfiError::from)
     }
 
     pub async fn mark_read_to_pts_blocking(
@@ -7229,6 +8391,98 @@ impl PrivchatClient {
         })
     }
 
+    pub async fn list_sticker_packages(
+        &self,
+        installed_only: bool,
+    ) -> Result<Vec<StickerPackageView>, PrivchatFfiError> {
+        self.inner
+            .list_sticker_packages(installed_only)
+            .await
+            .map(|rows| rows.into_iter().map(map_sticker_package).collect())
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn refresh_sticker_packages(
+        &self,
+    ) -> Result<Vec<StickerPackageView>, PrivchatFfiError> {
+        self.inner
+            .refresh_sticker_packages()
+            .await
+            .map(|rows| rows.into_iter().map(map_sticker_package).collect())
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn refresh_sticker_package(
+        &self,
+        package_id: String,
+    ) -> Result<StickerPackageView, PrivchatFfiError> {
+        self.inner
+            .refresh_sticker_package(package_id)
+            .await
+            .map(map_sticker_package)
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn install_sticker_package(
+        &self,
+        package_id: String,
+    ) -> Result<(), PrivchatFfiError> {
+        self.inner
+            .install_sticker_package(package_id)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn uninstall_sticker_package(
+        &self,
+        package_id: String,
+    ) -> Result<(), PrivchatFfiError> {
+        self.inner
+            .uninstall_sticker_package(package_id)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn reorder_sticker_packages(
+        &self,
+        package_ids: Vec<String>,
+    ) -> Result<(), PrivchatFfiError> {
+        self.inner
+            .reorder_sticker_packages(package_ids)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn recent_stickers(&self) -> Result<Vec<StickerView>, PrivchatFfiError> {
+        self.inner
+            .recent_stickers()
+            .await
+            .map(|rows| rows.into_iter().map(map_sticker).collect())
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn favourite_stickers(&self) -> Result<Vec<StickerView>, PrivchatFfiError> {
+        self.inner
+            .favourite_stickers()
+            .await
+            .map(|rows| rows.into_iter().map(map_sticker).collect())
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn favourite_sticker(&self, sticker: StickerView) -> Result<bool, PrivchatFfiError> {
+        self.inner
+            .favourite_sticker(map_sticker_view(sticker))
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn unfavourite_sticker(&self, sticker_id: String) -> Result<bool, PrivchatFfiError> {
+        self.inner
+            .unfavourite_sticker(sticker_id)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
     pub async fn sync_submit_remote(
         &self,
         payload: SyncSubmitInput,
@@ -7495,6 +8749,10 @@ impl PrivchatClient {
             .map_err(PrivchatFfiError::from)
     }
 
+    /// 发送者一律取当前会话（见 `ClientService::send_text`），不接受调用方指定。
+    ///
+    /// **Deprecated**：`from_uid` 已不再使用，留在签名里只为不破坏已生成的绑定，
+    /// 传什么都一样（传 0 即可）；下一个不兼容版本移除。
     pub async fn send_message(
         &self,
         channel_id: u64,
@@ -7502,22 +8760,18 @@ impl PrivchatClient {
         from_uid: u64,
         content: String,
     ) -> Result<u64, PrivchatFfiError> {
-        self.enqueue_local_message(NewMessage {
-            channel_id,
-            channel_type,
-            from_uid,
-            message_type: 0,
-            content,
-            searchable_word: String::new(),
-            setting: 0,
-            extra: String::new(),
-            mime_type: None,
-            media_downloaded: false,
-            thumb_status: 0,
-        })
-        .await
+        let _ = from_uid;
+        self.service
+            .send_text(SendTextRequest {
+                channel_id,
+                channel_type: Some(channel_type),
+                content,
+            })
+            .await
+            .map_err(PrivchatFfiError::from)
     }
 
+    /// **Deprecated** `from_uid`：同 [`Self::send_message`]。
     pub async fn send_message_blocking(
         &self,
         channel_id: u64,
@@ -7645,6 +8899,82 @@ impl PrivchatClient {
             .map_err(PrivchatFfiError::from)
     }
 
+    pub async fn send_sticker_message(
+        &self,
+        input: StickerMessageInput,
+    ) -> Result<u64, PrivchatFfiError> {
+        self.inner
+            .send_sticker_message(map_sticker_message_input(input))
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn send_poll_message(
+        &self,
+        input: PollMessageInput,
+    ) -> Result<u64, PrivchatFfiError> {
+        self.inner
+            .send_poll_message(map_poll_message_input(input))
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn vote_poll(
+        &self,
+        message_id: u64,
+        option_indexes: Vec<u32>,
+    ) -> Result<(), PrivchatFfiError> {
+        self.inner
+            .vote_poll(message_id, option_indexes)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn retract_poll_vote(&self, message_id: u64) -> Result<(), PrivchatFfiError> {
+        self.inner
+            .retract_poll_vote(message_id)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn refresh_poll(&self, message_id: u64) -> Result<bool, PrivchatFfiError> {
+        self.inner
+            .refresh_poll(message_id)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn start_live_location(
+        &self,
+        input: LiveLocationMessageInput,
+    ) -> Result<u64, PrivchatFfiError> {
+        self.inner
+            .start_live_location(map_live_location_message_input(input))
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn update_live_location(
+        &self,
+        message_id: u64,
+        latitude: f64,
+        longitude: f64,
+        accuracy: Option<f64>,
+        heading: Option<f64>,
+    ) -> Result<(), PrivchatFfiError> {
+        self.inner
+            .update_live_location(message_id, latitude, longitude, accuracy, heading)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn stop_live_location(&self, message_id: u64) -> Result<(), PrivchatFfiError> {
+        self.inner
+            .stop_live_location(message_id)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
     pub async fn create_local_message(&self, input: NewMessage) -> Result<u64, PrivchatFfiError> {
         self.inner
             .create_local_message(map_new_message(input))
@@ -7683,8 +9013,14 @@ impl PrivchatClient {
         offset: u64,
     ) -> Result<Vec<StoredMessage>, PrivchatFfiError> {
         let out = self
-            .inner
-            .list_messages(channel_id, channel_type, limit as usize, offset as usize)
+            .service
+            .list_messages(ListMessagesRequest {
+                channel_id,
+                channel_type: Some(channel_type),
+                limit,
+                offset,
+                before_local_id: None,
+            })
             .await
             .map_err(PrivchatFfiError::from)?;
         Ok(out.into_iter().map(map_stored_message).collect())
@@ -7802,8 +9138,8 @@ impl PrivchatClient {
         channel_id: u64,
     ) -> Result<Option<StoredChannel>, PrivchatFfiError> {
         let out = self
-            .inner
-            .get_channel_by_id(channel_id)
+            .service
+            .get_channel(channel_id)
             .await
             .map_err(PrivchatFfiError::from)?;
         Ok(out.map(map_stored_channel))
@@ -7815,8 +9151,8 @@ impl PrivchatClient {
         offset: u64,
     ) -> Result<Vec<StoredChannel>, PrivchatFfiError> {
         let out = self
-            .inner
-            .list_channels(limit as usize, offset as usize)
+            .service
+            .list_channels(ListChannelsRequest { limit, offset })
             .await
             .map_err(PrivchatFfiError::from)?;
         Ok(out.into_iter().map(map_stored_channel).collect())
@@ -7996,6 +9332,43 @@ impl PrivchatClient {
             .map_err(PrivchatFfiError::from)
     }
 
+    pub async fn pin_message(&self, message_id: u64) -> Result<(), PrivchatFfiError> {
+        self.inner
+            .pin_message(message_id)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn unpin_message(&self, message_id: u64) -> Result<(), PrivchatFfiError> {
+        self.inner
+            .unpin_message(message_id)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn list_pinned_messages(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+    ) -> Result<Vec<PinnedMessage>, PrivchatFfiError> {
+        self.inner
+            .list_pinned_messages(channel_id, channel_type)
+            .await
+            .map(|rows| rows.into_iter().map(map_pinned_message).collect())
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn refresh_pinned_messages(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+    ) -> Result<bool, PrivchatFfiError> {
+        self.inner
+            .refresh_pinned_messages(channel_id, channel_type)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
     pub async fn get_message_extra(
         &self,
         message_id: u64,
@@ -8005,27 +9378,141 @@ impl PrivchatClient {
             .get_message_extra(message_id)
             .await
             .map_err(PrivchatFfiError::from)?;
-        Ok(out.map(map_stored_message_extra))
+        Ok(out.map(map_stored_message_extra))
+    }
+
+    pub async fn discard_pending_message_edit(
+        &self,
+        message_id: u64,
+    ) -> Result<bool, PrivchatFfiError> {
+        self.inner
+            .discard_pending_message_edit(message_id)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn list_message_revisions(
+        &self,
+        message_id: u64,
+    ) -> Result<Vec<MessageRevision>, PrivchatFfiError> {
+        let out = self
+            .inner
+            .list_message_revisions(message_id)
+            .await
+            .map_err(PrivchatFfiError::from)?;
+        Ok(out.into_iter().map(map_message_revision).collect())
+    }
+
+    pub async fn get_channel_unread_count(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+    ) -> Result<i32, PrivchatFfiError> {
+        self.inner
+            .get_channel_unread_count(channel_id, channel_type)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn get_total_unread_count(
+        &self,
+        exclude_muted: bool,
+    ) -> Result<i32, PrivchatFfiError> {
+        self.inner
+            .get_total_unread_count(exclude_muted)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    /// 带筛选、排序和 keyset 分页的会话列表，筛选在 SDK 的 SQL 里完成。
+    pub async fn query_channels(
+        &self,
+        query: ChannelListQuery,
+    ) -> Result<ChannelListPage, PrivchatFfiError> {
+        self.inner
+            .query_channels(map_channel_list_query(query))
+            .await
+            .map(map_channel_list_page)
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn upsert_channel_folder(
+        &self,
+        input: ChannelFolderInput,
+    ) -> Result<ChannelFolder, PrivchatFfiError> {
+        let input = SdkChannelFolderInput {
+            folder_id: input.folder_id,
+            name: input.name,
+            sort_order: input.sort_order,
+            filter: map_channel_list_filter(input.filter),
+            channels: input
+                .channels
+                .into_iter()
+                .map(|c| (c.channel_id, c.channel_type))
+                .collect(),
+        };
+        self.inner
+            .upsert_channel_folder(input)
+            .await
+            .map(map_channel_folder)
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn delete_channel_folder(&self, folder_id: u64) -> Result<bool, PrivchatFfiError> {
+        self.inner
+            .delete_channel_folder(folder_id)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn list_channel_folders(&self) -> Result<Vec<ChannelFolder>, PrivchatFfiError> {
+        self.inner
+            .list_channel_folders()
+            .await
+            .map(|folders| folders.into_iter().map(map_channel_folder).collect())
+            .map_err(PrivchatFfiError::from)
+    }
+
+    /// 订阅一个会话最新 `limit` 条消息的活窗口。从返回对象上循环 `next()` 取增量，
+    /// 第一批是整个窗口的 `Reset`。
+    pub async fn subscribe_timeline(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+        limit: u32,
+    ) -> Result<Arc<TimelineLiveQuery>, PrivchatFfiError> {
+        let query = self
+            .inner
+            .subscribe_timeline(channel_id, channel_type, limit as usize)
+            .await
+            .map_err(PrivchatFfiError::from)?;
+        Ok(Arc::new(TimelineLiveQuery {
+            cancel: query.canceller(),
+            inner: AsyncMutex::new(query),
+        }))
     }
 
-    pub async fn get_channel_unread_count(
+    /// 订阅会话列表第一页的活视图，筛选 / 排序同 `query_channels`（`after` 被忽略）。
+    pub async fn subscribe_channel_list(
         &self,
-        channel_id: u64,
-        channel_type: i32,
-    ) -> Result<i32, PrivchatFfiError> {
-        self.inner
-            .get_channel_unread_count(channel_id, channel_type)
+        query: ChannelListQuery,
+    ) -> Result<Arc<ChannelListLiveQuery>, PrivchatFfiError> {
+        let query = self
+            .inner
+            .subscribe_channel_list(map_channel_list_query(query))
             .await
-            .map_err(PrivchatFfiError::from)
+            .map_err(PrivchatFfiError::from)?;
+        Ok(Arc::new(ChannelListLiveQuery {
+            cancel: query.canceller(),
+            inner: AsyncMutex::new(query),
+        }))
     }
 
-    pub async fn get_total_unread_count(
-        &self,
-        exclude_muted: bool,
-    ) -> Result<i32, PrivchatFfiError> {
+    pub async fn get_unread_aggregate(&self) -> Result<UnreadAggregate, PrivchatFfiError> {
         self.inner
-            .get_total_unread_count(exclude_muted)
+            .get_unread_aggregate()
             .await
+            .map(map_unread_aggregate)
             .map_err(PrivchatFfiError::from)
     }
 
@@ -8070,14 +9557,34 @@ impl PrivchatClient {
         Ok(list.first().map(|m| m.message_id))
     }
 
+    pub async fn auto_download_policy(&self) -> Result<AutoDownloadPolicyView, PrivchatFfiError> {
+        self.inner
+            .auto_download_policy()
+            .await
+            .map(AutoDownloadPolicyView::from)
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn set_auto_download_policy(
+        &self,
+        policy: AutoDownloadPolicyView,
+    ) -> Result<(), PrivchatFfiError> {
+        self.inner
+            .set_auto_download_policy(policy.into())
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    /// 设置会话通知模式，各取值的含义见 [`ChannelNotificationMode`]。
+    /// 角标、会话列表的 `muted` 筛选、自动下载和推送提醒都按它算。
     pub async fn set_channel_notification_mode(
         &self,
         channel_id: u64,
         channel_type: i32,
-        mode: i32,
+        mode: ChannelNotificationMode,
     ) -> Result<(), PrivchatFfiError> {
         self.inner
-            .set_channel_notification_mode_pref(channel_id, channel_type, mode)
+            .set_channel_notification_mode_pref(channel_id, channel_type, mode.into())
             .await
             .map_err(PrivchatFfiError::from)
     }
@@ -8086,10 +9593,11 @@ impl PrivchatClient {
         &self,
         channel_id: u64,
         channel_type: i32,
-    ) -> Result<i32, PrivchatFfiError> {
+    ) -> Result<ChannelNotificationMode, PrivchatFfiError> {
         self.inner
             .channel_notification_mode_pref(channel_id, channel_type)
             .await
+            .map(ChannelNotificationMode::from)
             .map_err(PrivchatFfiError::from)
     }
 
@@ -8724,14 +10232,19 @@ impl PrivchatClient {
     }
 
     pub async fn own_last_read(&self, channel_id: u64) -> Result<u64, PrivchatFfiError> {
+        let channel_type = self
+            .get_channel_by_id(channel_id)
+            .await?
+            .map(|c| c.channel_type)
+            .unwrap_or(1);
         // Prefer channel extra browse_to as local "last read" cursor.
-        if let Ok(Some(extra)) = self.get_channel_extra(channel_id, 1).await {
+        if let Ok(Some(extra)) = self.get_channel_extra(channel_id, channel_type).await {
             if extra.browse_to > 0 {
                 return Ok(extra.browse_to);
             }
         }
         // Fallback to the newest server message id in local messages.
-        let list = self.list_messages(channel_id, 1, 1, 50).await?;
+        let list = self.list_messages(channel_id, channel_type, 1, 50).await?;
         Ok(list
             .into_iter()
             .filter_map(|m| m.server_message_id)
@@ -8739,54 +10252,125 @@ impl PrivchatClient {
             .unwrap_or(0))
     }
 
+    /// 本地成员游标判定；该成员本地还没有游标、且这个会话还没回填过时才回填一次。
+    /// 回填过的会话以本地为准：回填只落已读的人，没读的人仍然没有游标，每次都回填
+    /// 就等于每次都发 READ_LIST。
     pub async fn is_event_read_by(
         &self,
         server_message_id: u64,
         user_id: u64,
     ) -> Result<bool, PrivchatFfiError> {
-        let channel_id = self
-            .resolve_channel_id_by_server_message_id(server_message_id)
+        let message_id = self
+            .resolve_message_id_by_server_message_id(server_message_id)
             .await?;
-        let resp: MessageReadListResponse = rpc_call_typed(
-            &self.inner,
-            routes::message_status::READ_LIST,
-            &MessageReadListRequest {
-                message_id: server_message_id,
-                channel_id,
-            },
-        )
-        .await?;
-        Ok(resp
-            .readers
-            .into_iter()
-            .any(|entry| entry.user_id == user_id))
+        let mut receipts = self
+            .inner
+            .message_read_receipts(message_id)
+            .await
+            .map_err(PrivchatFfiError::from)?;
+        if receipts.unknown_member_ids.contains(&user_id)
+            && !self.read_receipts_backfilled_for(&receipts).await
+        {
+            receipts = self.backfill_read_receipts(message_id).await?;
+        }
+        Ok(receipts.readers.iter().any(|r| r.reader_id == user_id))
     }
 
+    /// 已读成员来自本地游标，`read_at` 恒为 None（游标只有位置，没有阅读时间）；
+    /// 要服务端的阅读时间用 `message_read_list`。
+    ///
+    /// 只读本地，不发网络；唯一例外是本地一条成员游标都没有（冷缓存），此时回填一次。
+    /// 要强制回填用 [`Self::refresh_seen_by_for_event`]。
     pub async fn seen_by_for_event(
         &self,
         server_message_id: u64,
     ) -> Result<Vec<SeenByEntry>, PrivchatFfiError> {
-        let channel_id = self
-            .resolve_channel_id_by_server_message_id(server_message_id)
+        let message_id = self
+            .resolve_message_id_by_server_message_id(server_message_id)
             .await?;
-        let resp: MessageReadListResponse = rpc_call_typed(
-            &self.inner,
-            routes::message_status::READ_LIST,
-            &MessageReadListRequest {
-                message_id: server_message_id,
-                channel_id,
-            },
-        )
-        .await?;
-        Ok(resp
-            .readers
-            .into_iter()
-            .filter_map(|entry| {
-                let user_id = entry.user_id;
-                let read_at = entry.read_at;
-                Some(SeenByEntry { user_id, read_at })
-            })
-            .collect())
+        let mut receipts = self
+            .inner
+            .message_read_receipts(message_id)
+            .await
+            .map_err(PrivchatFfiError::from)?;
+        if !receipts.unknown_member_ids.is_empty() {
+            let known = self
+                .inner
+                .list_member_read_cursors(receipts.channel_id, receipts.channel_type)
+                .await
+                .map_err(PrivchatFfiError::from)?;
+            if seen_by_cache_missed(&receipts, known.len())
+                && !self.read_receipts_backfilled_for(&receipts).await
+            {
+                receipts = self.backfill_read_receipts(message_id).await?;
+            }
+        }
+        Ok(seen_by_entries(receipts))
+    }
+
+    /// 同 [`Self::seen_by_for_event`]，但群里有成员本地还没有游标时总是发一次 READ_LIST
+    /// 回填（用户下拉刷新「谁看过」时用）。离线时退化为本地结果。
+    pub async fn refresh_seen_by_for_event(
+        &self,
+        server_message_id: u64,
+    ) -> Result<Vec<SeenByEntry>, PrivchatFfiError> {
+        let message_id = self
+            .resolve_message_id_by_server_message_id(server_message_id)
+            .await?;
+        let receipts = self.backfill_read_receipts(message_id).await?;
+        Ok(seen_by_entries(receipts))
+    }
+
+    /// 这条消息所在的会话本进程里回填过没有。
+    async fn read_receipts_backfilled_for(&self, receipts: &SdkMessageReadReceipts) -> bool {
+        self.read_receipts_backfilled
+            .lock()
+            .await
+            .contains(&(receipts.channel_id, receipts.channel_type))
+    }
+
+    /// 发一次 READ_LIST 回填，并记下这个会话已经回填过。
+    async fn backfill_read_receipts(
+        &self,
+        message_id: u64,
+    ) -> Result<SdkMessageReadReceipts, PrivchatFfiError> {
+        let receipts = self
+            .inner
+            .backfill_message_read_receipts(message_id)
+            .await
+            .map_err(PrivchatFfiError::from)?;
+        self.read_receipts_backfilled
+            .lock()
+            .await
+            .insert((receipts.channel_id, receipts.channel_type));
+        Ok(receipts)
+    }
+
+    pub async fn message_read_receipts(
+        &self,
+        message_id: u64,
+        backfill: bool,
+    ) -> Result<MessageReadReceipts, PrivchatFfiError> {
+        let out = if backfill {
+            self.inner.backfill_message_read_receipts(message_id).await
+        } else {
+            self.inner.message_read_receipts(message_id).await
+        };
+        out.map(map_message_read_receipts)
+            .map_err(PrivchatFfiError::from)
+    }
+
+    pub async fn list_member_read_cursors(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+    ) -> Result<Vec<MemberReadCursor>, PrivchatFfiError> {
+        let out = self
+            .inner
+            .list_member_read_cursors(channel_id, channel_type)
+            .await
+            .map_err(PrivchatFfiError::from)?;
+        Ok(out.into_iter().map(map_member_read_cursor).collect())
     }
 
     pub async fn paginate_back(
@@ -8825,16 +10409,19 @@ impl PrivchatClient {
         &self,
         keyword: String,
     ) -> Result<Vec<StoredChannel>, PrivchatFfiError> {
-        let needle = keyword.to_lowercase();
-        let items = self.list_channels(1, 500).await?;
-        Ok(items
-            .into_iter()
-            .filter(|c| {
-                c.channel_name.to_lowercase().contains(&needle)
-                    || c.channel_remark.to_lowercase().contains(&needle)
-                    || c.last_msg_content.to_lowercase().contains(&needle)
+        let page = self
+            .inner
+            .query_channels(SdkChannelListQuery {
+                filter: SdkChannelListFilter {
+                    keyword: Some(keyword),
+                    ..Default::default()
+                },
+                limit: 500,
+                ..Default::default()
             })
-            .collect())
+            .await
+            .map_err(PrivchatFfiError::from)?;
+        Ok(page.channels.into_iter().map(map_stored_channel).collect())
     }
 
     pub async fn search_messages(
@@ -8904,12 +10491,13 @@ impl PrivchatClient {
                 }
             })?;
 
-        if let Some((blob, cek)) = sealed {
+        if let Some((blob, cek, encryption_version)) = sealed {
             privchat_sdk::media_download::write_sealed_cache(
                 dir,
                 &format!("{file_name}.sealed"),
                 &blob,
                 &cek,
+                encryption_version,
             );
         }
 
@@ -8957,7 +10545,7 @@ impl PrivchatClient {
 
         // 把原始密文留在文件旁边：这份内容再发一次时原样上传，服务端按摘要认出
         // 「已经有了」，正文一个字节都不用传。
-        if let (Some((blob, cek)), Some(dir), Some(name)) =
+        if let (Some((blob, cek, encryption_version)), Some(dir), Some(name)) =
             (sealed, target.parent(), target.file_name())
         {
             privchat_sdk::media_download::write_sealed_cache(
@@ -8965,6 +10553,7 @@ impl PrivchatClient {
                 &format!("{}.sealed", name.to_string_lossy()),
                 &blob,
                 &cek,
+                encryption_version,
             );
         }
         Ok(target_path)
@@ -9012,7 +10601,39 @@ impl PrivchatClient {
             claims: s.claims,
             body_uploads: s.body_uploads,
             thumbnail_uploads: s.thumbnail_uploads,
+            bytes_by_network: s
+                .bytes_by_network
+                .into_iter()
+                .map(|b| NetworkTransferBytesView {
+                    network: map_sdk_network_hint(b.network),
+                    uploaded: b.uploaded,
+                    downloaded: b.downloaded,
+                })
+                .collect(),
+        }
+    }
+
+    /// 统一指标快照（RPC 延迟、重连、outbox、同步耗时、下载吞吐……）。
+    ///
+    /// FFI 层自己维护的 typing 计数也并进来（`privchat_ffi_typing_events_total`），
+    /// presence 在线/离线人数由 SDK 按缓存给出（`privchat_presence_cached_users`），
+    /// 宿主不必再分别去调 `get_typing_stats` / `get_presence_stats`。
+    pub async fn metrics_snapshot(&self) -> Result<MetricsSnapshotView, PrivchatFfiError> {
+        let mut view = MetricsSnapshotView::from(self.inner.metrics_snapshot().await?);
+        for (kind, counter) in [
+            ("started", &self.typing_started_count),
+            ("stopped", &self.typing_stopped_count),
+        ] {
+            view.counters.push(CounterSampleView {
+                name: "privchat_ffi_typing_events_total".to_string(),
+                labels: vec![MetricLabelView {
+                    name: "kind".to_string(),
+                    value: kind.to_string(),
+                }],
+                value: counter.load(Ordering::Relaxed),
+            });
         }
+        Ok(view)
     }
 
     pub fn get_attachment_target_dir(
@@ -9079,6 +10700,27 @@ impl PrivchatClient {
             .map_err(PrivchatFfiError::from)
     }
 
+    /// 边下边播：返回本地回环 URL，直接交给 AVPlayer / ExoPlayer。
+    /// 签名 URL 过期后再调一次即可刷新。
+    pub async fn media_stream_url(
+        &self,
+        message_id: u64,
+        file_id: u64,
+        mime: String,
+        filename_hint: Option<String>,
+        created_at_ms: i64,
+    ) -> Result<String, PrivchatFfiError> {
+        self.inner
+            .media_stream_url(message_id, file_id, mime, filename_hint, created_at_ms)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    /// 停掉本地流媒体代理，已发出的播放 URL 随之失效。
+    pub fn stop_media_stream_server(&self) {
+        self.inner.stop_media_stream_server();
+    }
+
     pub async fn pause_message_media_download(&self, message_id: u64) {
         self.inner.pause_message_media_download(message_id).await;
     }
@@ -9096,6 +10738,34 @@ impl PrivchatClient {
         map_media_download_state(state)
     }
 
+    /// App 即将挂起：停掉进程内的附件上传 / 下载，导出剩下的请求交给系统后台传输
+    /// （iOS 后台 `URLSession`、Android `WorkManager`）。
+    pub async fn export_background_transfers(&self) -> Vec<BackgroundTransferView> {
+        self.inner
+            .export_background_transfers()
+            .await
+            .into_iter()
+            .map(Into::into)
+            .collect()
+    }
+
+    /// 系统后台传输跑完一条：校验并接着走完（解密落盘 / 分片对齐后 complete）。
+    pub async fn import_completed_transfer(
+        &self,
+        transfer_id: String,
+    ) -> Result<(), PrivchatFfiError> {
+        self.inner
+            .import_completed_transfer(transfer_id)
+            .await
+            .map_err(PrivchatFfiError::from)
+    }
+
+    /// 回到前台：还没导入的传输收回进程内续传。系统那边的请求由宿主自己取消；
+    /// 系统报告某一条失败时也调它。
+    pub async fn reclaim_background_transfers(&self) -> u64 {
+        self.inner.reclaim_background_transfers().await as u64
+    }
+
     /// 解析本地已存在的附件路径 (含 Legacy 兼容)
     pub fn resolve_attachment_path(
         &self,
@@ -9273,6 +10943,196 @@ pub fn build_time() -> String {
         .to_string()
 }
 
+/// 通知扩展 / 后台 worker 用的推送落库入口：只开本地库，不连网，不需要 `PrivchatClient`。
+#[derive(uniffi::Object)]
+pub struct PushIngestor {
+    inner: SdkPushIngestor,
+}
+
+#[uniffi::export]
+impl PushIngestor {
+    /// `data_dir` 与 `PrivchatConfig.data_dir` 相同，`uid` 是要落库的账号。
+    #[uniffi::constructor]
+    pub fn open(data_dir: String, uid: String) -> Result<Self, PrivchatFfiError> {
+        let inner = SdkPushIngestor::open(data_dir, &uid)?;
+        Ok(Self { inner })
+    }
+
+    /// 已经落过（重复推送 / 主进程先收到）或撤回推送返回 `None`。
+    pub fn ingest(
+        &self,
+        payload: PushPayload,
+        privacy: NotificationPrivacy,
+    ) -> Result<Option<PushNotification>, PrivchatFfiError> {
+        let privacy = match privacy {
+            NotificationPrivacy::Full => SdkNotificationPrivacy::Full,
+            NotificationPrivacy::HideContent => SdkNotificationPrivacy::HideContent,
+            NotificationPrivacy::HideAll => SdkNotificationPrivacy::HideAll,
+        };
+        let notification = self.inner.ingest(&map_push_payload(payload), privacy)?;
+        Ok(notification.map(map_push_notification))
+    }
+}
+
+/// `PrivchatClient::subscribe_timeline` 返回的增量流。
+#[derive(uniffi::Object)]
+pub struct TimelineLiveQuery {
+    inner: AsyncMutex<SdkLiveQuery<SdkStoredMessage>>,
+    cancel: SdkLiveQueryCancel,
+}
+
+#[uniffi::export]
+impl TimelineLiveQuery {
+    /// 下一批增量；取消或 SDK 关闭后返回 `None`。
+    pub async fn next(&self) -> Option<Vec<TimelineDiff>> {
+        let batch = self.inner.lock().await.next().await?;
+        Some(batch.into_iter().map(map_timeline_diff).collect())
+    }
+
+    /// 停止维护，挂起中的 `next()` 随后返回 `None`。
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+}
+
+/// `PrivchatClient::subscribe_channel_list` 返回的增量流。
+#[derive(uniffi::Object)]
+pub struct ChannelListLiveQuery {
+    inner: AsyncMutex<SdkLiveQuery<SdkStoredChannel>>,
+    cancel: SdkLiveQueryCancel,
+}
+
+#[uniffi::export]
+impl ChannelListLiveQuery {
+    /// 下一批增量；取消或 SDK 关闭后返回 `None`。
+    pub async fn next(&self) -> Option<Vec<ChannelListDiff>> {
+        let batch = self.inner.lock().await.next().await?;
+        Some(batch.into_iter().map(map_channel_list_diff).collect())
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+}
+
+/// 同一进程里同时在线的多个账号。每个账号一个 `PrivchatClient`，共用一个数据目录；
+/// 后台账号照常收消息，前台账号只决定冷启动恢复谁。
+#[derive(uniffi::Object)]
+pub struct AccountManager {
+    inner: SdkAccountManager,
+    config: PrivchatConfig,
+    event_rx: AsyncMutex<tokio::sync::broadcast::Receiver<SdkAccountEvent>>,
+}
+
+#[uniffi::export]
+impl AccountManager {
+    /// `config.data_dir` 是所有账号共用的目录。
+    #[uniffi::constructor]
+    pub fn new(config: PrivchatConfig) -> Result<Self, PrivchatFfiError> {
+        let inner = SdkAccountManager::new(map_config(config.clone()))?;
+        let event_rx = AsyncMutex::new(inner.subscribe_events());
+        Ok(Self {
+            inner,
+            config,
+            event_rx,
+        })
+    }
+
+    /// 新开一个未登录的账号客户端，在它上面走 connect / login / authenticate。
+    /// 登录一个已在别的槽位上线的账号会失败；放弃登录时用 `remove_slot` 关掉。
+    pub fn add_account(&self) -> Result<OpenedAccount, PrivchatFfiError> {
+        let opened = self.inner.add_account()?;
+        Ok(self.map_opened_account(opened))
+    }
+
+    /// 打开本地已有的账号并恢复它保存的会话。
+    ///
+    /// 每次调用都返回一个新的客户端对象，底下是同一个账号实例。
+    pub fn open_account(&self, uid: String) -> Result<OpenedAccount, PrivchatFfiError> {
+        let opened = self.inner.open_account(uid)?;
+        Ok(self.map_opened_account(opened))
+    }
+
+    /// 已登录账号的 uid，按打开顺序。
+    pub fn accounts(&self) -> Vec<String> {
+        self.inner.accounts()
+    }
+
+    pub async fn remove_account(&self, uid: String) -> Result<(), PrivchatFfiError> {
+        Ok(self.inner.remove_account(&uid).await?)
+    }
+
+    /// 按槽位号关掉实例，还没登录的实例也可以。
+    pub async fn remove_slot(&self, slot_id: u64) -> Result<(), PrivchatFfiError> {
+        Ok(self.inner.remove_slot(slot_id).await?)
+    }
+
+    /// 所有账号实例的下一条事件，带槽位号和 uid；超时返回 `None`。
+    pub async fn next_account_event(&self, timeout_ms: u64) -> Option<AccountEvent> {
+        let mut rx = self.event_rx.lock().await;
+        let deadline =
+            std::time::Instant::now() + std::time::Duration::from_millis(timeout_ms.max(1));
+        loop {
+            match rx.try_recv() {
+                Ok(evt) => {
+                    return Some(AccountEvent {
+                        slot_id: evt.slot_id,
+                        uid: evt.uid,
+                        event: map_sdk_event(evt.event),
+                    })
+                }
+                // 落后的那段丢了就丢了，接着读后面的，不能因为一次 lag 就一直返回空。
+                Err(TryRecvError::Lagged(_)) => continue,
+                Err(TryRecvError::Closed) => return None,
+                Err(TryRecvError::Empty) => {
+                    let remain = deadline.saturating_duration_since(std::time::Instant::now());
+                    if remain.is_zero() {
+                        return None;
+                    }
+                    poll_wait(remain).await;
+                }
+            }
+        }
+    }
+
+    pub fn set_foreground(&self, uid: String) -> Result<(), PrivchatFfiError> {
+        Ok(self.inner.set_foreground(&uid)?)
+    }
+
+    pub fn foreground(&self) -> Result<Option<String>, PrivchatFfiError> {
+        Ok(self.inner.foreground()?)
+    }
+
+    pub async fn unread_summary(&self) -> Result<AccountsUnreadSummary, PrivchatFfiError> {
+        let summary = self.inner.unread_summary().await?;
+        Ok(AccountsUnreadSummary {
+            total: map_unread_aggregate(summary.total),
+            accounts: summary
+                .accounts
+                .into_iter()
+                .map(|a| AccountUnread {
+                    uid: a.uid,
+                    aggregate: map_unread_aggregate(a.aggregate),
+                    error: a.error,
+                })
+                .collect(),
+        })
+    }
+
+    pub async fn shutdown(&self) {
+        self.inner.shutdown().await;
+    }
+}
+
+impl AccountManager {
+    fn map_opened_account(&self, opened: privchat_sdk::OpenedAccount) -> OpenedAccount {
+        OpenedAccount {
+            slot_id: opened.slot_id,
+            client: Arc::new(PrivchatClient::from_sdk(opened.sdk, self.config.clone())),
+        }
+    }
+}
+
 // ───────────────────── R8.6b-rust QR decoder ─────────────────────
 //
 // Implementation lives in `crate::qr`. The `#[uniffi::export]` /
@@ -9390,10 +11250,23 @@ mod tests {
 
     use super::{
         map_sdk_event, metadata_input_extension, parse_read_list_entries, parse_read_list_user_ids,
-        PrivchatClient, PrivchatConfig, SdkEvent, ServerEndpoint, SyncPhase, SyncRunKind,
-        TransportProtocol,
+        seen_by_cache_missed, seen_by_entries, ChannelNotificationMode, PrivchatClient,
+        PrivchatConfig, SdkEvent, ServerEndpoint, SyncPhase, SyncRunKind, TransportProtocol,
     };
 
+    #[test]
+    fn notification_modes_round_trip_and_unknown_values_fall_back_to_default() {
+        for mode in [
+            ChannelNotificationMode::Default,
+            ChannelNotificationMode::All,
+            ChannelNotificationMode::MentionsOnly,
+            ChannelNotificationMode::Off,
+        ] {
+            assert_eq!(ChannelNotificationMode::from(i32::from(mode)), mode);
+        }
+        assert_eq!(ChannelNotificationMode::from(42), ChannelNotificationMode::Default);
+    }
+
     #[test]
     fn attachment_extension_requires_an_object() {
         assert!(metadata_input_extension(Some("[]")).is_err());
@@ -9459,6 +11332,47 @@ mod tests {
         assert!(parse_read_list_user_ids(raw).is_empty());
     }
 
+    fn receipts(readers: &[u64], unknown: &[u64]) -> privchat_sdk::MessageReadReceipts {
+        privchat_sdk::MessageReadReceipts {
+            message_id: 1,
+            channel_id: 7,
+            channel_type: 2,
+            pts: 40,
+            readers: readers
+                .iter()
+                .map(|&reader_id| privchat_sdk::MemberReadCursor {
+                    channel_id: 7,
+                    channel_type: 2,
+                    reader_id,
+                    read_pts: 40,
+                    updated_at: 0,
+                })
+                .collect(),
+            unknown_member_ids: unknown.to_vec(),
+        }
+    }
+
+    #[test]
+    fn seen_by_backfills_only_on_a_cold_cache() {
+        // 一条游标都没有：冷缓存，回填一次。
+        assert!(seen_by_cache_missed(&receipts(&[], &[2, 3]), 0));
+        // 已有游标、只缺几个人：本地结果就是答案，不发网络。
+        assert!(!seen_by_cache_missed(&receipts(&[2], &[3]), 1));
+        // 游标在但没越过本消息（readers 为空）：同样不算冷缓存。
+        assert!(!seen_by_cache_missed(&receipts(&[], &[3]), 1));
+        // 所有成员都有游标：没什么可回填的。
+        assert!(!seen_by_cache_missed(&receipts(&[2, 3], &[]), 0));
+    }
+
+    #[test]
+    fn seen_by_entries_come_from_local_readers_without_read_time() {
+        let entries = seen_by_entries(receipts(&[2, 5], &[9]));
+        let ids: Vec<u64> = entries.iter().map(|e| e.user_id).collect();
+        assert_eq!(ids, vec![2, 5]);
+        assert!(entries.iter().all(|e| e.read_at.is_none()));
+        assert!(seen_by_entries(receipts(&[], &[9])).is_empty());
+    }
+
     fn test_config() -> PrivchatConfig {
         PrivchatConfig {
             endpoints: vec![ServerEndpoint {
@@ -9616,7 +11530,7 @@ impl PrivchatClient {
     async fn resolve_attachment_bytes(
         &self,
         source_path: &str,
-    ) -> Result<(Vec<u8>, Option<(Vec<u8>, String)>), PrivchatFfiError> {
+    ) -> Result<(Vec<u8>, Option<(Vec<u8>, String, i32)>), PrivchatFfiError> {
         self.resolve_attachment_bytes_with_meta(source_path)
             .await
             .map(|(bytes, sealed, _)| (bytes, sealed))
@@ -9631,7 +11545,7 @@ impl PrivchatClient {
         &self,
         source_path: &str,
     ) -> Result<
-        (Vec<u8>, Option<(Vec<u8>, String)>, Option<FileGetUrlResponse>),
+        (Vec<u8>, Option<(Vec<u8>, String, i32)>, Option<FileGetUrlResponse>),
         PrivchatFfiError,
     > {
         let source = source_path.trim();
@@ -9651,7 +11565,7 @@ impl PrivchatClient {
             return Ok((bytes, None, None));
         }
 
-        // 加密附件（enc_v=1）在服务端存的是密文，CEK 只在 `file/get_url` 的响应里。
+        // 加密附件（enc_v=1/2）在服务端存的是密文，CEK 只在 `file/get_url` 的响应里。
         // 调用方直接给 URL 时没有这条信息，只能按明文处理。
         let mut encryption_version = 0;
         let mut cek: Option<String> = None;
@@ -9729,7 +11643,13 @@ impl PrivchatClient {
             code: privchat_protocol::ErrorCode::InternalError as u32,
             detail: format!("decrypt attachment failed: {e}"),
         })?;
-        let sealed = cek.filter(|_| encryption_version == 1).map(|c| (blob, c));
+        let sealed = cek
+            .filter(|_| {
+                encryption_version == privchat_sdk::attachment_crypto::ENCRYPTION_VERSION_WHOLE
+                    || encryption_version
+                        == privchat_sdk::attachment_crypto::ENCRYPTION_VERSION_SEGMENTED
+            })
+            .map(|c| (blob, c, encryption_version));
         Ok((plain, sealed, meta))
     }
 }
@@ -0,0 +1,409 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! `privchatd` 的 Rust 客户端。方法签名照抄 `ClientService`，只是把 `SdkError`
+//! 换成 [`ClientError`]（多了传输/协议层的失败）。
+//!
+//! 一条连接上可以并发发请求：后台读任务按 `id` 把应答交回调用方，按
+//! `subscription_id` 把事件分到各自的 [`EventSubscription`]。
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, oneshot, Mutex as AsyncMutex};
+
+use privchat_sdk::client_service::{
+    AuthenticateRequest, ListChannelsRequest, ListMessagesRequest, LoginOutcome, LoginRequest,
+    MarkReadByLocalMessageIdRequest, MarkReadToPtsRequest, RegisterRequest, SdkError,
+    SendTextRequest, SendTypingRequest,
+};
+use privchat_sdk::{
+    ConnectionState, SequencedSdkEvent, SessionSnapshot, StoredChannel, StoredMessage,
+};
+
+use crate::wire::{
+    codes, methods, read_frame, write_frame, EventsSinceParams, GetChannelParams, HelloParams,
+    HelloResult, RpcError, RpcRequest, RpcResponse, ServerFrame, SubscribeEventsParams,
+    SubscribeEventsResult, UnsubscribeEventsParams, PROTOCOL_VERSION,
+};
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ClientError {
+    /// daemon 端 facade 返回的错误，原样还原。
+    #[error(transparent)]
+    Sdk(SdkError),
+    /// RPC 层自有的错误（见 [`crate::wire::codes`]）。
+    #[error("rpc error {code:#010x}: {message}")]
+    Rpc { code: u32, message: String },
+    /// 连接断了或连不上。
+    #[error("transport: {0}")]
+    Transport(String),
+}
+
+impl ClientError {
+    /// 与 `SdkError::sdk_code` 同一套编号空间。
+    pub fn code(&self) -> u32 {
+        match self {
+            ClientError::Sdk(e) => e.sdk_code(),
+            ClientError::Rpc { code, .. } => *code,
+            ClientError::Transport(_) => codes::TRANSPORT_CLOSED,
+        }
+    }
+}
+
+impl From<RpcError> for ClientError {
+    fn from(err: RpcError) -> Self {
+        match err.sdk {
+            Some(sdk) => ClientError::Sdk(sdk),
+            None => ClientError::Rpc {
+                code: err.code,
+                message: err.message,
+            },
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+type Pending = HashMap<u64, oneshot::Sender<RpcResponse>>;
+type Sinks = HashMap<u64, mpsc::UnboundedSender<SequencedSdkEvent>>;
+
+struct Shared {
+    writer: AsyncMutex<OwnedWriteHalf>,
+    pending: StdMutex<Option<Pending>>,
+    sinks: StdMutex<Sinks>,
+    next_id: AtomicU64,
+}
+
+impl Shared {
+    /// 读任务退出时调用：挂着的请求全部以 `TRANSPORT_CLOSED` 失败，订阅流结束。
+    fn close(&self) {
+        self.pending.lock().expect("pending poisoned").take();
+        self.sinks.lock().expect("sinks poisoned").clear();
+    }
+}
+
+/// 克隆共享同一条连接。
+#[derive(Clone)]
+pub struct RpcClient {
+    shared: Arc<Shared>,
+    hello: Arc<HelloResult>,
+}
+
+impl RpcClient {
+    /// 连上 daemon 并完成 hello。版本不一致时返回 `VERSION_MISMATCH`。
+    pub async fn open(socket_path: &Path, client_name: &str) -> Result<Self> {
+        let stream = UnixStream::connect(socket_path).await.map_err(|e| {
+            ClientError::Transport(format!("connect {}: {e}", socket_path.display()))
+        })?;
+        let (mut reader, writer) = stream.into_split();
+        let shared = Arc::new(Shared {
+            writer: AsyncMutex::new(writer),
+            pending: StdMutex::new(Some(HashMap::new())),
+            sinks: StdMutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        });
+        let read_shared = shared.clone();
+        tokio::spawn(async move {
+            loop {
+                match read_frame::<_, ServerFrame>(&mut reader).await {
+                    Ok(Some(ServerFrame::Response(resp))) => {
+                        let waiter = read_shared
+                            .pending
+                            .lock()
+                            .expect("pending poisoned")
+                            .as_mut()
+                            .and_then(|p| p.remove(&resp.id));
+                        if let Some(waiter) = waiter {
+                            let _ = waiter.send(resp);
+                        }
+                    }
+                    Ok(Some(ServerFrame::Event(frame))) => {
+                        let mut sinks = read_shared.sinks.lock().expect("sinks poisoned");
+                        if let Some(sink) = sinks.get(&frame.subscription_id) {
+                            if sink.send(frame.event).is_err() {
+                                sinks.remove(&frame.subscription_id);
+                            }
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        tracing::warn!("privchat-sdk-rpc: read loop stopped: {e}");
+                        break;
+                    }
+                }
+            }
+            read_shared.close();
+        });
+
+        let mut client = Self {
+            shared,
+            hello: Arc::new(HelloResult {
+                protocol_version: 0,
+                daemon_version: String::new(),
+                methods: Vec::new(),
+            }),
+        };
+        let hello: HelloResult = client
+            .call(
+                methods::RPC_HELLO,
+                &HelloParams {
+                    protocol_version: PROTOCOL_VERSION,
+                    client_name: client_name.to_string(),
+                },
+            )
+            .await?;
+        client.hello = Arc::new(hello);
+        Ok(client)
+    }
+
+    /// hello 的结果：daemon 版本和它支持的方法。
+    pub fn hello(&self) -> &HelloResult {
+        &self.hello
+    }
+
+    /// 通用调用。typed 方法都走这里；新方法在 typed 包装出来之前也可以直接用。
+    pub async fn call<P, R>(&self, method: &str, params: &P) -> Result<R>
+    where
+        P: Serialize,
+        R: DeserializeOwned,
+    {
+        let params = serde_json::to_value(params).map_err(|e| ClientError::Rpc {
+            code: codes::INVALID_PARAMS,
+            message: e.to_string(),
+        })?;
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut pending = self.shared.pending.lock().expect("pending poisoned");
+            let Some(pending) = pending.as_mut() else {
+                return Err(ClientError::Transport("connection closed".into()));
+            };
+            pending.insert(id, tx);
+        }
+        let request = RpcRequest {
+            id,
+            method: method.to_string(),
+            account: None,
+            params,
+        };
+        let sent = {
+            let mut writer = self.shared.writer.lock().await;
+            write_frame(&mut *writer, &request).await
+        };
+        if let Err(e) = sent {
+            if let Some(p) = self
+                .shared
+                .pending
+                .lock()
+                .expect("pending poisoned")
+                .as_mut()
+            {
+                p.remove(&id);
+            }
+            return Err(ClientError::Transport(e.to_string()));
+        }
+        let resp = rx
+            .await
+            .map_err(|_| ClientError::Transport("connection closed".into()))?;
+        if let Some(err) = resp.error {
+            return Err(err.into());
+        }
+        serde_json::from_value(resp.result).map_err(|e| ClientError::Rpc {
+            code: codes::PROTOCOL_ERROR,
+            message: format!("{method}: bad result: {e}"),
+        })
+    }
+
+    // ---- lifecycle ----
+
+    pub async fn connect(&self) -> Result<()> {
+        self.call(methods::SESSION_CONNECT, &()).await
+    }
+
+    pub async fn disconnect(&self) -> Result<()> {
+        self.call(methods::SESSION_DISCONNECT, &()).await
+    }
+
+    pub async fn connection_state(&self) -> Result<ConnectionState> {
+        self.call(methods::SESSION_CONNECTION_STATE, &()).await
+    }
+
+    /// 关掉 daemon 里的 SDK；daemon 随后退出，这条连接也会断。
+    pub async fn shutdown(&self) -> Result<()> {
+        self.call(methods::LIFECYCLE_SHUTDOWN, &()).await
+    }
+
+    // ---- session ----
+
+    pub async fn login(&self, req: LoginRequest) -> Result<LoginOutcome> {
+        self.call(methods::SESSION_LOGIN, &req).await
+    }
+
+    pub async fn register(&self, req: RegisterRequest) -> Result<LoginOutcome> {
+        self.call(methods::SESSION_REGISTER, &req).await
+    }
+
+    pub async fn authenticate(&self, req: AuthenticateRequest) -> Result<()> {
+        self.call(methods::SESSION_AUTHENTICATE, &req).await
+    }
+
+    pub async fn logout(&self) -> Result<()> {
+        self.call(methods::SESSION_LOGOUT, &()).await
+    }
+
+    pub async fn session_snapshot(&self) -> Result<Option<SessionSnapshot>> {
+        self.call(methods::SESSION_SNAPSHOT, &()).await
+    }
+
+    // ---- local model ----
+
+    pub async fn list_channels(&self, req: ListChannelsRequest) -> Result<Vec<StoredChannel>> {
+        self.call(methods::CHANNELS_LIST, &req).await
+    }
+
+    pub async fn get_channel(&self, channel_id: u64) -> Result<Option<StoredChannel>> {
+        self.call(methods::CHANNELS_GET, &GetChannelParams { channel_id })
+            .await
+    }
+
+    pub async fn list_messages(&self, req: ListMessagesRequest) -> Result<Vec<StoredMessage>> {
+        self.call(methods::MESSAGES_LIST, &req).await
+    }
+
+    pub async fn send_text(&self, req: SendTextRequest) -> Result<u64> {
+        self.call(methods::MESSAGES_SEND_TEXT, &req).await
+    }
+
+    pub async fn mark_read_to_pts(&self, req: MarkReadToPtsRequest) -> Result<u64> {
+        self.call(methods::READ_MARK_TO_PTS, &req).await
+    }
+
+    pub async fn mark_read_by_local_message_id(
+        &self,
+        req: MarkReadByLocalMessageIdRequest,
+    ) -> Result<u64> {
+        self.call(methods::READ_MARK_BY_LOCAL_ID, &req).await
+    }
+
+    pub async fn send_typing(&self, req: SendTypingRequest) -> Result<()> {
+        self.call(methods::TYPING_SEND, &req).await
+    }
+
+    // ---- events ----
+
+    pub async fn last_event_sequence_id(&self) -> Result<u64> {
+        self.call(methods::EVENTS_LAST_SEQUENCE_ID, &()).await
+    }
+
+    pub async fn events_since(&self, cursor: u64, limit: u64) -> Result<Vec<SequencedSdkEvent>> {
+        self.call(methods::EVENTS_SINCE, &EventsSinceParams { cursor, limit })
+            .await
+    }
+
+    /// 从 `cursor` 之后开始收事件。游标由返回的 [`EventSubscription`] 自己记着，
+    /// 重连时拿 [`EventSubscription::cursor`] 续订即可。
+    pub async fn subscribe_events(&self, cursor: u64) -> Result<EventSubscription> {
+        let subscription_id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.shared
+            .sinks
+            .lock()
+            .expect("sinks poisoned")
+            .insert(subscription_id, tx);
+        let result: Result<SubscribeEventsResult> = self
+            .call(
+                methods::EVENTS_SUBSCRIBE,
+                &SubscribeEventsParams {
+                    subscription_id,
+                    cursor,
+                },
+            )
+            .await;
+        match result {
+            Ok(info) => Ok(EventSubscription {
+                subscription_id,
+                cursor,
+                missed_events: info.oldest_retained_sequence_id > 0
+                    && cursor + 1 < info.oldest_retained_sequence_id,
+                info,
+                rx,
+                client: self.clone(),
+            }),
+            Err(e) => {
+                self.shared
+                    .sinks
+                    .lock()
+                    .expect("sinks poisoned")
+                    .remove(&subscription_id);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// 一路事件订阅。drop 时会尽力通知 daemon 停推。
+pub struct EventSubscription {
+    subscription_id: u64,
+    cursor: u64,
+    missed_events: bool,
+    info: SubscribeEventsResult,
+    rx: mpsc::UnboundedReceiver<SequencedSdkEvent>,
+    client: RpcClient,
+}
+
+impl EventSubscription {
+    /// 下一条事件。连接断开后返回 `None`。
+    pub async fn recv(&mut self) -> Option<SequencedSdkEvent> {
+        let evt = self.rx.recv().await?;
+        self.cursor = evt.sequence_id;
+        Some(evt)
+    }
+
+    /// 最后交给调用方的事件序号。
+    pub fn cursor(&self) -> u64 {
+        self.cursor
+    }
+
+    /// 订阅时 daemon 报告的历史范围。
+    pub fn info(&self) -> &SubscribeEventsResult {
+        &self.info
+    }
+
+    /// 续订的游标已经落到 daemon 内存历史之外：中间那段事件补不回来了，
+    /// 调用方应当全量刷新本地视图，而不是假装连续。
+    pub fn missed_events(&self) -> bool {
+        self.missed_events
+    }
+}
+
+impl Drop for EventSubscription {
+    fn drop(&mut self) {
+        self.client
+            .shared
+            .sinks
+            .lock()
+            .expect("sinks poisoned")
+            .remove(&self.subscription_id);
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let client = self.client.clone();
+            let subscription_id = self.subscription_id;
+            handle.spawn(async move {
+                let _: Result<()> = client
+                    .call(
+                        methods::EVENTS_UNSUBSCRIBE,
+                        &UnsubscribeEventsParams { subscription_id },
+                    )
+                    .await;
+            });
+        }
+    }
+}
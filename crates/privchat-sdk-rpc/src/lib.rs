@@ -0,0 +1,29 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! `privchatd` 的控制 RPC：线上格式 + Rust 客户端。
+//!
+//! 方法集、payload、错误、事件语义全部从 `privchat_sdk::client_service` 派生
+//! （`docs/CLIENT_SERVICE_FACADE_SPEC.md` §14）：方法名与 facade 方法一一对应，
+//! payload 就是 `client_service::types` 里的请求类型，facade 层面的失败原样带着
+//! `SdkError` 过线。本 crate 只额外定义 RPC 层自己的错误码（[`wire::codes`]）。
+//!
+//! 与 `privchat-sdk-ffi` 是兄弟关系，**不**依赖它（spec §3）。
+
+pub mod client;
+pub mod wire;
+
+use std::path::{Path, PathBuf};
+
+pub use client::{ClientError, EventSubscription, RpcClient};
+pub use wire::{codes, methods, PROTOCOL_VERSION};
+
+/// daemon 默认监听的 socket 文件名，放在账号的 data_dir 下。
+pub const DEFAULT_SOCKET_FILE_NAME: &str = "privchatd.sock";
+
+/// 一个 data_dir 对应一个 daemon，socket 默认就在它下面；客户端据此找到 daemon。
+pub fn default_socket_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(DEFAULT_SOCKET_FILE_NAME)
+}
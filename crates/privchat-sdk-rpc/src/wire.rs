@@ -0,0 +1,337 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 线上格式。
+//!
+//! 帧 = 4 字节大端长度 + 一段 UTF-8 JSON。客户端只发 [`RpcRequest`]；daemon 发
+//! [`ServerFrame`]，其中应答按 `id` 配对，事件按 `subscription_id` 分流——两者可以
+//! 交错到达。
+//!
+//! 连接建立后第一帧必须是 [`methods::RPC_HELLO`]，版本对不上直接拒（`VERSION_MISMATCH`），
+//! 不做降级猜测。
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use privchat_sdk::client_service::SdkError;
+use privchat_sdk::SequencedSdkEvent;
+
+/// 线上协议版本。字段只增不改时不动它；改语义或删字段才加一。
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// 单帧上限。正常的最大负载是一页消息列表，远小于此；超过说明对端坏了。
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// v1 唯一接受的账号名（spec §9）。请求里不带 `account` 等同于它。
+pub const DEFAULT_ACCOUNT: &str = "default";
+
+/// 方法名与 `ClientService` 的方法一一对应（spec §14）。
+pub mod methods {
+    /// 握手：版本协商 + 方法清单。不是 facade 方法，是 RPC 层唯一的自有方法。
+    pub const RPC_HELLO: &str = "rpc.hello";
+
+    pub const SESSION_CONNECT: &str = "sdk.session.connect";
+    pub const SESSION_DISCONNECT: &str = "sdk.session.disconnect";
+    pub const SESSION_CONNECTION_STATE: &str = "sdk.session.connection_state";
+    pub const SESSION_LOGIN: &str = "sdk.session.login";
+    pub const SESSION_REGISTER: &str = "sdk.session.register";
+    pub const SESSION_AUTHENTICATE: &str = "sdk.session.authenticate";
+    pub const SESSION_LOGOUT: &str = "sdk.session.logout";
+    pub const SESSION_SNAPSHOT: &str = "sdk.session.snapshot";
+    /// 关掉 SDK 之后 daemon 也随之退出。
+    pub const LIFECYCLE_SHUTDOWN: &str = "sdk.lifecycle.shutdown";
+
+    pub const CHANNELS_LIST: &str = "sdk.channels.list";
+    pub const CHANNELS_GET: &str = "sdk.channels.get";
+    pub const MESSAGES_LIST: &str = "sdk.messages.list";
+    pub const MESSAGES_SEND_TEXT: &str = "sdk.messages.send_text";
+    pub const READ_MARK_TO_PTS: &str = "sdk.read.mark_to_pts";
+    pub const READ_MARK_BY_LOCAL_ID: &str = "sdk.read.mark_by_local_id";
+    pub const TYPING_SEND: &str = "sdk.typing.send";
+
+    pub const EVENTS_SUBSCRIBE: &str = "sdk.events.subscribe";
+    pub const EVENTS_UNSUBSCRIBE: &str = "sdk.events.unsubscribe";
+    pub const EVENTS_SINCE: &str = "sdk.events.since";
+    pub const EVENTS_LAST_SEQUENCE_ID: &str = "sdk.events.last_sequence_id";
+
+    /// daemon 在 hello 里报告的方法清单。
+    pub const ALL: &[&str] = &[
+        RPC_HELLO,
+        SESSION_CONNECT,
+        SESSION_DISCONNECT,
+        SESSION_CONNECTION_STATE,
+        SESSION_LOGIN,
+        SESSION_REGISTER,
+        SESSION_AUTHENTICATE,
+        SESSION_LOGOUT,
+        SESSION_SNAPSHOT,
+        LIFECYCLE_SHUTDOWN,
+        CHANNELS_LIST,
+        CHANNELS_GET,
+        MESSAGES_LIST,
+        MESSAGES_SEND_TEXT,
+        READ_MARK_TO_PTS,
+        READ_MARK_BY_LOCAL_ID,
+        TYPING_SEND,
+        EVENTS_SUBSCRIBE,
+        EVENTS_UNSUBSCRIBE,
+        EVENTS_SINCE,
+        EVENTS_LAST_SEQUENCE_ID,
+    ];
+}
+
+/// RPC 层自有的错误码（spec §14）。布局沿用 `privchat_sdk::error_codes`：
+/// 高 8 位是域，这里独占 `0x10`，不会和 SDK 的码撞。
+pub mod codes {
+    pub const DOMAIN_RPC: u32 = 0x10;
+
+    const fn code(detail: u32) -> u32 {
+        (DOMAIN_RPC << 24) | (detail & 0x00FF_FFFF)
+    }
+
+    /// 方法名不认识。消息里带着方法名。
+    pub const METHOD_NOT_IMPLEMENTED: u32 = code(1);
+    /// 方法存在但这个后端不支持（RpcBackend 缺口）。
+    pub const UNSUPPORTED_IN_RPC_BACKEND: u32 = code(2);
+    /// hello 里的协议版本对不上。
+    pub const VERSION_MISMATCH: u32 = code(3);
+    /// 帧或信封坏了，或者没先 hello。
+    pub const PROTOCOL_ERROR: u32 = code(4);
+    /// 连接断了，应答不会再来。只在客户端本地产生。
+    pub const TRANSPORT_CLOSED: u32 = code(5);
+    /// `account` 不是 `"default"`（spec §9）。
+    pub const UNSUPPORTED_ACCOUNT: u32 = code(6);
+    /// params 解不成该方法的请求类型。
+    pub const INVALID_PARAMS: u32 = code(7);
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcRequest {
+    pub id: u64,
+    pub method: String,
+    /// 预留（spec §9）。v1 只接受缺省或 `"default"`。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    #[serde(default)]
+    pub params: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: u32,
+    pub message: String,
+    /// facade 层面的失败原样带过来，客户端还原成 `SdkError`；RPC 层自有错误为空。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sdk: Option<SdkError>,
+}
+
+impl RpcError {
+    pub fn new(code: u32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            sdk: None,
+        }
+    }
+
+    pub fn from_sdk(err: SdkError) -> Self {
+        Self {
+            code: err.sdk_code(),
+            message: err.to_string(),
+            sdk: Some(err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcResponse {
+    pub id: u64,
+    #[serde(default)]
+    pub result: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+impl RpcResponse {
+    pub fn ok(id: u64, result: serde_json::Value) -> Self {
+        Self {
+            id,
+            result,
+            error: None,
+        }
+    }
+
+    pub fn err(id: u64, error: RpcError) -> Self {
+        Self {
+            id,
+            result: serde_json::Value::Null,
+            error: Some(error),
+        }
+    }
+}
+
+/// 订阅推送的一条事件。`event.sequence_id` 就是客户端该记的游标。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventFrame {
+    pub subscription_id: u64,
+    pub event: SequencedSdkEvent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ServerFrame {
+    Response(RpcResponse),
+    Event(EventFrame),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HelloParams {
+    pub protocol_version: u32,
+    /// 只用于 daemon 侧日志。
+    #[serde(default)]
+    pub client_name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HelloResult {
+    pub protocol_version: u32,
+    pub daemon_version: String,
+    pub methods: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetChannelParams {
+    pub channel_id: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventsSinceParams {
+    pub cursor: u64,
+    pub limit: u64,
+}
+
+/// 游标归客户端（spec §7）：daemon 从 `cursor` 之后开始推，断线重连时客户端带上
+/// 自己最后处理到的 `sequence_id` 即可续上。
+///
+/// `subscription_id` 由客户端挑，这样应答回来之前推过来的事件也能认领。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscribeEventsParams {
+    pub subscription_id: u64,
+    #[serde(default)]
+    pub cursor: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscribeEventsResult {
+    /// 订阅时 SDK 已产生的最大序号。
+    pub last_sequence_id: u64,
+    /// 订阅时内存历史里最老的序号（空为 0）。`cursor + 1` 比它小说明中间那段已经
+    /// 被挤出历史，客户端需要自己全量刷新。
+    pub oldest_retained_sequence_id: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnsubscribeEventsParams {
+    pub subscription_id: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FrameError {
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("frame of {0} bytes exceeds limit")]
+    TooLarge(usize),
+    #[error("decode: {0}")]
+    Decode(#[from] serde_json::Error),
+}
+
+pub async fn write_frame<W, T>(writer: &mut W, value: &T) -> Result<(), FrameError>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let body = serde_json::to_vec(value)?;
+    if body.len() > MAX_FRAME_LEN {
+        return Err(FrameError::TooLarge(body.len()));
+    }
+    writer.write_all(&(body.len() as u32).to_be_bytes()).await?;
+    writer.write_all(&body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// 读一帧。对端在帧边界上正常关闭时返回 `Ok(None)`。
+pub async fn read_frame<R, T>(reader: &mut R) -> Result<Option<T>, FrameError>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let mut len_buf = [0u8; 4];
+    match reader.read_exact(&mut len_buf).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(FrameError::TooLarge(len));
+    }
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn frames_round_trip_and_eof_is_clean() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        let req = RpcRequest {
+            id: 7,
+            method: methods::CHANNELS_GET.to_string(),
+            account: None,
+            params: serde_json::json!({ "channel_id": 42 }),
+        };
+        write_frame(&mut a, &req).await.unwrap();
+        drop(a);
+        let got: RpcRequest = read_frame(&mut b).await.unwrap().unwrap();
+        assert_eq!(got.id, 7);
+        assert_eq!(got.method, methods::CHANNELS_GET);
+        assert_eq!(got.params["channel_id"], 42);
+        assert!(read_frame::<_, RpcRequest>(&mut b).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn oversized_length_prefix_is_rejected_before_allocating() {
+        let (mut a, mut b) = tokio::io::duplex(64);
+        a.write_all(&(u32::MAX).to_be_bytes()).await.unwrap();
+        let err = read_frame::<_, RpcRequest>(&mut b).await.unwrap_err();
+        assert!(matches!(err, FrameError::TooLarge(_)));
+    }
+
+    #[test]
+    fn sdk_errors_survive_the_wire() {
+        let err = RpcError::from_sdk(SdkError::NotFound("message 5".into()));
+        let json = serde_json::to_string(&RpcResponse::err(1, err)).unwrap();
+        let back: RpcResponse = serde_json::from_str(&json).unwrap();
+        let back = back.error.unwrap();
+        assert_eq!(back.code, privchat_sdk::error_codes::NOT_FOUND);
+        assert!(matches!(back.sdk, Some(SdkError::NotFound(_))));
+    }
+
+    #[test]
+    fn rpc_codes_do_not_collide_with_sdk_domains() {
+        for code in [
+            codes::METHOD_NOT_IMPLEMENTED,
+            codes::VERSION_MISMATCH,
+            codes::UNSUPPORTED_ACCOUNT,
+        ] {
+            assert_eq!(code >> 24, codes::DOMAIN_RPC);
+        }
+    }
+}
@@ -0,0 +1,54 @@
+-- 消息编辑历史：每次编辑一行，(message_id, version) 唯一。
+--
+-- message_extra 只留最新一份 content_edit / edited_at，编辑是原地覆盖：界面拿不到
+-- 「改了什么」，乱序到达的旧编辑推送还会把更新的版本盖掉。这里把每个版本落成一行，
+-- 应用编辑时拿 version 和本表已有的最大版本比，不大于就丢弃（单调）。
+--
+-- version = 0 是首次编辑前的原文，由写入侧在第一次编辑时补进来；编辑次数只数 version > 0。
+-- message.content / message_extra.content_edit 仍然是最新版本，读路径不必 JOIN 本表。
+CREATE TABLE IF NOT EXISTS message_revision (
+    message_id  INTEGER NOT NULL,            -- message.id
+    version     INTEGER NOT NULL,
+    content     TEXT NOT NULL DEFAULT '',
+    edited_at   INTEGER NOT NULL DEFAULT 0,  -- 秒，与 message_extra.edited_at 同单位
+    editor_uid  INTEGER,                     -- 未知（老数据 / 服务端未下发）为 NULL
+    created_at  INTEGER NOT NULL DEFAULT 0,  -- 本地落库时间（毫秒）
+    PRIMARY KEY (message_id, version)
+);
+
+-- 已经编辑过的消息：原文已被覆盖，找不回来。仍按上面的约定补齐两行——
+-- version 0 占原文的位置（内容未知记空串，时间与作者取消息本身），当前的最新编辑记成
+-- version 1。少了 version 0，写入侧看到已有版本就不会再补，历史里永远缺原文那一格。
+-- created_at 是毫秒：原文取 message.created_at，编辑取 edited_at（秒）换算，
+-- 不能拿 extra_version 充数——那是扩展数据的版本号，不是时间。
+INSERT OR IGNORE INTO message_revision (message_id, version, content, edited_at, editor_uid, created_at)
+SELECT me.message_id, 0, '', m.created_at / 1000,
+       CASE WHEN m.from_uid > 0 THEN m.from_uid END, m.created_at
+FROM message_extra me
+JOIN message m ON m.id = me.message_id
+WHERE me.content_edit IS NOT NULL AND me.message_id IS NOT NULL;
+
+INSERT OR IGNORE INTO message_revision (message_id, version, content, edited_at, editor_uid, created_at)
+SELECT me.message_id, 1, me.content_edit, me.edited_at, NULL, me.edited_at * 1000
+FROM message_extra me
+WHERE me.content_edit IS NOT NULL AND me.message_id IS NOT NULL;
+
+-- 本地编辑在服务端确认之前不占版本号。
+--
+-- 本地编辑如果直接取「已有最大版本 + 1」写进 message_revision，会和服务端的版本号在
+-- 同一个空间里：离线改了一次、别的端也改了一次，两边拿到同一个版本号，后到的那个
+-- 被单调检查当成重复丢掉。所以本地编辑只记在这里（每条消息最多一份，后改的覆盖先改的），
+-- 带版本号的服务端编辑到了、内容与它一致才算确认，删掉这一行；在那之前界面显示的仍是
+-- 本地这一版。
+--
+-- base_version 是编辑时看到的最大服务端版本：服务端后来下发的版本超过 base_version + 1、
+-- 内容又不是本地这一版，说明在它之后已经有别的编辑落定（或者本地那次被拒了），
+-- 待确认的那份不能再挡着界面。
+CREATE TABLE IF NOT EXISTS message_revision_pending (
+    message_id   INTEGER PRIMARY KEY,         -- message.id
+    content      TEXT NOT NULL DEFAULT '',
+    edited_at    INTEGER NOT NULL DEFAULT 0,  -- 秒，与 message_revision.edited_at 同单位
+    editor_uid   INTEGER,
+    created_at   INTEGER NOT NULL DEFAULT 0,  -- 本地落库时间（毫秒）
+    base_version INTEGER NOT NULL DEFAULT 0
+);
@@ -0,0 +1,19 @@
+-- 每个成员在会话里读到哪儿（群「已读成员」本地缓存）。
+--
+-- 此前群消息的「谁看过」每次都发 message_status/READ_LIST 现查：离线看不了，滚一屏消息
+-- 就是一屏 RPC。channel_extra.peer_read_pts 只有一个值，是私聊「对方读到哪」，群里装不下
+-- 每个人的位置。SDK 本来就收到 channel_read_cursor（reader_id, last_read_pts）并发出
+-- PeerReadPtsAdvanced，这里把它按成员落下来：
+--
+--   seen_by(msg) = { reader | read_pts >= msg.pts, reader != msg.from_uid }
+--
+-- 游标只进不退（MAX），乱序到达的旧游标不会把人从「已读」里摘掉。群成员里没有游标行的人
+-- 才需要 RPC 回填——READ_LIST 里出现的人记成读到了这条消息的 pts。
+CREATE TABLE IF NOT EXISTS member_read_cursor (
+    channel_id   INTEGER NOT NULL,
+    channel_type INTEGER NOT NULL,
+    reader_id    INTEGER NOT NULL,
+    read_pts     INTEGER NOT NULL DEFAULT 0,
+    updated_at   INTEGER NOT NULL DEFAULT 0,  -- 本地最近一次推进游标的时间（毫秒）
+    PRIMARY KEY (channel_id, channel_type, reader_id)
+);
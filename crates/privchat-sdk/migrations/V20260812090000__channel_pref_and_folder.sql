@@ -0,0 +1,41 @@
+-- 会话列表查询（筛选 / 排序 / keyset 分页）与用户自建的会话文件夹。
+--
+-- 会话偏好（favourite / low_priority / notification_mode / tags）的权威副本在账号 kv 里，
+-- 是一个 JSON；想在 SQL 里按它筛选会话列表，只能先拉全量再在内存里过滤。这里给它建一个
+-- 镜像：写偏好时同步写入，首次查询前由 SDK 从 kv 整体回填。镜像丢了也不丢数据。
+CREATE TABLE IF NOT EXISTS channel_pref (
+    channel_id        INTEGER NOT NULL,
+    channel_type      INTEGER NOT NULL,
+    notification_mode INTEGER NOT NULL DEFAULT 0,
+    favourite         INTEGER NOT NULL DEFAULT 0,
+    low_priority      INTEGER NOT NULL DEFAULT 0,
+    updated_at        INTEGER NOT NULL DEFAULT 0,  -- 本地写入时间（毫秒）
+    PRIMARY KEY (channel_id, channel_type)
+);
+
+-- tags 拆成行，按 tag 筛选走索引而不是 LIKE 一个 JSON 串。
+CREATE TABLE IF NOT EXISTS channel_pref_tag (
+    channel_id   INTEGER NOT NULL,
+    channel_type INTEGER NOT NULL,
+    tag          TEXT NOT NULL,
+    PRIMARY KEY (channel_id, channel_type, tag)
+);
+CREATE INDEX IF NOT EXISTS idx_channel_pref_tag_tag ON channel_pref_tag(tag);
+
+-- 会话文件夹：手选的会话（channel_folder_member）加上可选的筛选条件（filter，
+-- ChannelListFilter 的 JSON；'{}' 表示只收手选的会话）。纯本地，不上服务端。
+CREATE TABLE IF NOT EXISTS channel_folder (
+    folder_id  INTEGER PRIMARY KEY AUTOINCREMENT,
+    name       TEXT NOT NULL DEFAULT '',
+    sort_order INTEGER NOT NULL DEFAULT 0,
+    filter     TEXT NOT NULL DEFAULT '{}',
+    created_at INTEGER NOT NULL DEFAULT 0,
+    updated_at INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS channel_folder_member (
+    folder_id    INTEGER NOT NULL,
+    channel_id   INTEGER NOT NULL,
+    channel_type INTEGER NOT NULL,
+    PRIMARY KEY (folder_id, channel_id, channel_type)
+);
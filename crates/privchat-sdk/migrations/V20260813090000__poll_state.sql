@@ -0,0 +1,18 @@
+-- 投票消息的本地可变状态（投票定义在消息 metadata 里，不在这里）。
+--
+-- 票数只认服务端：投票 RPC 的响应、message/poll/get、推送 / 同步的 message_poll 实体。
+-- 带版本号的按版本单调应用，乱序到达的旧票数不会覆盖新的。
+--
+-- 自己的选择分两份：my_votes 是界面上显示的（可能还在 outbox 里，vote_pending = 1），
+-- confirmed_votes 是服务端确认过的。命令被拒时 my_votes 退回 confirmed_votes。
+CREATE TABLE IF NOT EXISTS poll_state (
+    message_id      INTEGER PRIMARY KEY,            -- message.id
+    tallies         TEXT NOT NULL DEFAULT '[]',     -- 各选项票数，JSON 数组，按选项下标
+    voter_count     INTEGER NOT NULL DEFAULT 0,
+    my_votes        TEXT NOT NULL DEFAULT '[]',     -- JSON 数组，选项下标升序
+    confirmed_votes TEXT NOT NULL DEFAULT '[]',
+    vote_pending    INTEGER NOT NULL DEFAULT 0,
+    closed          INTEGER NOT NULL DEFAULT 0,
+    version         INTEGER NOT NULL DEFAULT 0,     -- 服务端票数版本；0 = 服务端没给
+    updated_at      INTEGER NOT NULL DEFAULT 0      -- 本地写入时间（毫秒）
+);
@@ -0,0 +1,15 @@
+-- 会话的置顶消息索引。
+--
+-- 用服务端消息 ID 做键：置顶的可能是很早的消息，本地不一定有那一行。本地有时
+-- message_extra.is_pinned 同步维护。行存在 = 当前置顶；取消置顶直接删行。
+CREATE TABLE IF NOT EXISTS message_pin (
+    channel_id        INTEGER NOT NULL,
+    channel_type      INTEGER NOT NULL,
+    server_message_id INTEGER NOT NULL,
+    pinned_by         INTEGER,                      -- 置顶人；老服务端的同步数据里可能没有
+    pinned_at         INTEGER NOT NULL DEFAULT 0,   -- 置顶时间（毫秒）
+    PRIMARY KEY (channel_type, channel_id, server_message_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_message_pin_channel_time
+    ON message_pin (channel_id, channel_type, pinned_at DESC);
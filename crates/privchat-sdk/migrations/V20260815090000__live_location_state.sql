@@ -0,0 +1,19 @@
+-- 实时位置共享的本地可变状态（起点和到期时间在消息 metadata 里，不在这里）。
+--
+-- 坐标更新改写同一行，不产生新消息。别人的位置只认服务端（推送 / 同步的
+-- message_live_location 实体），带版本号的按版本单调应用；stopped 只进不退。
+-- 自己的位置本地先写（update_pending = 1），出站队列按 last_sent_at 节流后发出。
+CREATE TABLE IF NOT EXISTS live_location_state (
+    message_id      INTEGER PRIMARY KEY,            -- message.id
+    latitude        REAL,                           -- 最新位置；NULL = 还没有更新，沿用起点
+    longitude       REAL,
+    accuracy        REAL,                           -- 水平精度（米）
+    heading         REAL,                           -- 朝向（度）
+    position_at     INTEGER NOT NULL DEFAULT 0,     -- 定位时间（毫秒）
+    expires_at      INTEGER NOT NULL DEFAULT 0,     -- 服务端改过的到期时间；0 = 沿用 metadata
+    stopped         INTEGER NOT NULL DEFAULT 0,
+    version         INTEGER NOT NULL DEFAULT 0,     -- 服务端位置版本；0 = 服务端没给
+    update_pending  INTEGER NOT NULL DEFAULT 0,
+    last_sent_at    INTEGER NOT NULL DEFAULT 0,     -- 自己的更新上次发出的时间（毫秒），节流用
+    updated_at      INTEGER NOT NULL DEFAULT 0      -- 本地写入时间（毫秒）
+);
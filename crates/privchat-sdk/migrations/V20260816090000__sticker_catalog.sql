@@ -0,0 +1,45 @@
+-- 表情包目录（按账号）。
+--
+-- 列表 / 详情接口拉到的包和表情都缓存在这里，installed 标出本账号装了哪些。
+-- 最近使用和收藏引用 sticker 行：包没装、或者是从消息里收藏的表情，一样缓存。
+CREATE TABLE IF NOT EXISTS sticker_package (
+    package_id    TEXT PRIMARY KEY,
+    name          TEXT NOT NULL DEFAULT '',
+    thumbnail_url TEXT NOT NULL DEFAULT '',
+    author        TEXT NOT NULL DEFAULT '',
+    description   TEXT NOT NULL DEFAULT '',
+    sticker_count INTEGER NOT NULL DEFAULT 0,
+    installed     INTEGER NOT NULL DEFAULT 0,
+    sort_order    INTEGER NOT NULL DEFAULT 0,   -- 已安装包的顺序，小的在前；只在本机
+    installed_at  INTEGER NOT NULL DEFAULT 0,   -- 安装时间（毫秒）
+    updated_at    INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX IF NOT EXISTS idx_sticker_package_installed
+    ON sticker_package (installed, sort_order);
+
+CREATE TABLE IF NOT EXISTS sticker (
+    sticker_id TEXT PRIMARY KEY,
+    package_id TEXT NOT NULL,
+    position   INTEGER NOT NULL DEFAULT -1,     -- 包内顺序；-1 = 不在包的表情列表里（只因最近使用 / 收藏留着）
+    image_url  TEXT NOT NULL DEFAULT '',
+    alt_text   TEXT NOT NULL DEFAULT '',
+    emoji      TEXT,
+    width      INTEGER NOT NULL DEFAULT 0,
+    height     INTEGER NOT NULL DEFAULT 0,
+    mime_type  TEXT NOT NULL DEFAULT '',
+    updated_at INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX IF NOT EXISTS idx_sticker_package_position
+    ON sticker (package_id, position);
+
+CREATE TABLE IF NOT EXISTS sticker_recent (
+    sticker_id TEXT PRIMARY KEY,
+    used_at    INTEGER NOT NULL                 -- 毫秒
+);
+
+CREATE TABLE IF NOT EXISTS sticker_favourite (
+    sticker_id TEXT PRIMARY KEY,
+    added_at   INTEGER NOT NULL                 -- 毫秒
+);
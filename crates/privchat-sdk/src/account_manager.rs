@@ -0,0 +1,313 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 一个进程里同时在线的多个账号。
+//!
+//! `PrivchatSdk` 只有一个当前 uid，`switch_local_account` 会拆掉旧会话；而且每条存储
+//! 命令都在执行时读 base 目录下的 `current_user` 文件解析账号，两个实例开在同一个
+//! `data_dir` 上会互相改对方的账号（sled 的 `global.kv` 一个进程也只能开一次）。
+//!
+//! [`AccountManager`] 的做法：
+//!
+//! - 每个账号一个完整的 `PrivchatSdk`（自己的 actor、transport、同步），共用一个 runtime；
+//! - 所有账号共用一份 [`LocalStore`] 句柄，但各自的「当前 uid」只在内存里
+//!   （[`LocalStore::scoped`]），登录、切号、退出都只影响自己；
+//! - `current_user` 文件只代表**前台**账号（冷启动恢复谁、账号列表里谁是活跃的），
+//!   由 [`AccountManager::set_foreground`] 写；后台账号照常收消息、落库、发事件；
+//! - 每个实例有一个槽位号（[`OpenedAccount::slot_id`]），还没登录的实例也能按它关掉；
+//!   同一个 uid 不能同时绑在两个槽位上，第二个登录会被拒绝；
+//! - 各账号的事件转发到一条总线上，带上槽位号和账号 uid（[`AccountEvent`]）；
+//! - [`AccountManager::unread_summary`] 把各账号的未读聚合加成总角标。
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::local_store::{LocalStore, UidScope};
+use crate::runtime::runtime_provider::RuntimeProvider;
+use crate::unread_badge::merge_unread;
+use crate::{Error, PrivchatConfig, PrivchatSdk, Result, SdkEvent, UnreadAggregate};
+
+const ACCOUNT_EVENT_CAPACITY: usize = 512;
+
+/// 总线上的一条事件：哪个账号发的。`uid` 为 `None` 表示该实例还没登录，
+/// 这时靠 `slot_id` 对应到 [`AccountManager::add_account`] 返回的实例。
+#[derive(Debug, Clone)]
+pub struct AccountEvent {
+    pub slot_id: u64,
+    pub uid: Option<String>,
+    pub event: SdkEvent,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountUnread {
+    pub uid: String,
+    pub aggregate: UnreadAggregate,
+    /// 这个账号取不到未读时的原因（比如还没 bootstrap）；此时 `aggregate` 为空，不计入总和。
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// 所有已登录账号的未读：`total` 是各账号之和（tag 按名字合并）。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountsUnreadSummary {
+    pub total: UnreadAggregate,
+    pub accounts: Vec<AccountUnread>,
+}
+
+/// [`AccountManager::add_account`] / [`AccountManager::open_account`] 打开的实例。
+#[derive(Clone)]
+pub struct OpenedAccount {
+    /// 管理器内唯一，不复用；[`AccountManager::remove_slot`] 和 [`AccountEvent`] 用它。
+    pub slot_id: u64,
+    pub sdk: PrivchatSdk,
+}
+
+struct AccountSlot {
+    slot_id: u64,
+    sdk: PrivchatSdk,
+    scope: UidScope,
+}
+
+impl AccountSlot {
+    fn uid(&self) -> Option<String> {
+        self.scope.lock().ok().and_then(|uid| uid.clone())
+    }
+}
+
+struct Inner {
+    config: PrivchatConfig,
+    runtime_provider: RuntimeProvider,
+    store: LocalStore,
+    slots: StdMutex<Vec<AccountSlot>>,
+    next_slot_id: AtomicU64,
+    event_tx: broadcast::Sender<AccountEvent>,
+}
+
+/// 多账号管理器。克隆共享同一组账号。
+#[derive(Clone)]
+pub struct AccountManager {
+    inner: Arc<Inner>,
+}
+
+impl AccountManager {
+    /// `config.data_dir` 是所有账号共用的 base 目录（为空时同 `PrivchatSdk` 的默认目录）。
+    pub fn new(config: PrivchatConfig) -> Result<Self> {
+        let store = if config.data_dir.trim().is_empty() {
+            LocalStore::open_default()?
+        } else {
+            LocalStore::open_at(PathBuf::from(&config.data_dir))?
+        };
+        let (event_tx, _) = broadcast::channel(ACCOUNT_EVENT_CAPACITY);
+        Ok(Self {
+            inner: Arc::new(Inner {
+                config,
+                runtime_provider: RuntimeProvider::new_owned(),
+                store,
+                slots: StdMutex::new(Vec::new()),
+                next_slot_id: AtomicU64::new(1),
+                event_tx,
+            }),
+        })
+    }
+
+    /// 新开一个还没登录的账号实例，调用方在它上面走 `connect` / `login` / `authenticate`。
+    /// 登录后它就以那个 uid 出现在 [`Self::accounts`] 里；登录一个已经在别的槽位上线的
+    /// 账号会返回 `InvalidState`。没登录成功的实例用 [`Self::remove_slot`] 关掉。
+    pub fn add_account(&self) -> Result<OpenedAccount> {
+        let mut slots = self.lock_slots()?;
+        self.open_slot(&mut slots, None)
+    }
+
+    /// 打开一个本地已有的账号（恢复它保存的会话，同 `PrivchatSdk` 冷启动）。
+    /// 已经开着就返回那个实例。查找和登记在同一把槽位锁下，并发打开同一个 uid
+    /// 只会开出一个槽位；uid 正被别的实例登录占着时返回 `InvalidState`。
+    pub fn open_account(&self, uid: String) -> Result<OpenedAccount> {
+        if uid.trim().is_empty() {
+            return Err(Error::InvalidArgument("uid is empty".to_string()));
+        }
+        let mut slots = self.lock_slots()?;
+        if let Some(opened) = Self::find_in(&slots, &uid) {
+            return Ok(opened);
+        }
+        self.open_slot(&mut slots, Some(uid))
+    }
+
+    fn lock_slots(&self) -> Result<std::sync::MutexGuard<'_, Vec<AccountSlot>>> {
+        self.inner
+            .slots
+            .lock()
+            .map_err(|_| Error::InvalidState("account slots lock poisoned".to_string()))
+    }
+
+    /// 调用方持有槽位锁，新槽位在锁内登记。
+    fn open_slot(
+        &self,
+        slots: &mut Vec<AccountSlot>,
+        uid: Option<String>,
+    ) -> Result<OpenedAccount> {
+        let (store, scope) = self.inner.store.scoped(uid)?;
+        let slot_id = self.inner.next_slot_id.fetch_add(1, Ordering::Relaxed);
+        let sdk = PrivchatSdk::build(
+            self.inner.config.clone(),
+            self.inner.runtime_provider.clone(),
+            Some(store),
+        );
+        let mut events = sdk.subscribe_events();
+        let event_tx = self.inner.event_tx.clone();
+        let forward_scope = scope.clone();
+        // 转发任务不持有 sdk：账号关闭（ShutdownStarted）或事件总线关闭就退出。
+        let _ = self.inner.runtime_provider.spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        let done = matches!(event, SdkEvent::ShutdownStarted);
+                        let uid = forward_scope.lock().ok().and_then(|uid| uid.clone());
+                        let _ = event_tx.send(AccountEvent {
+                            slot_id,
+                            uid,
+                            event,
+                        });
+                        if done {
+                            return;
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+        slots.push(AccountSlot {
+            slot_id,
+            sdk: sdk.clone(),
+            scope,
+        });
+        Ok(OpenedAccount { slot_id, sdk })
+    }
+
+    /// 已登录账号的 uid，按打开顺序。
+    pub fn accounts(&self) -> Vec<String> {
+        self.inner
+            .slots
+            .lock()
+            .map(|slots| slots.iter().filter_map(AccountSlot::uid).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn account(&self, uid: &str) -> Option<PrivchatSdk> {
+        self.find_slot(uid).map(|opened| opened.sdk)
+    }
+
+    fn find_slot(&self, uid: &str) -> Option<OpenedAccount> {
+        let slots = self.inner.slots.lock().ok()?;
+        Self::find_in(&slots, uid)
+    }
+
+    fn find_in(slots: &[AccountSlot], uid: &str) -> Option<OpenedAccount> {
+        slots
+            .iter()
+            .find(|slot| slot.uid().as_deref() == Some(uid))
+            .map(|slot| OpenedAccount {
+                slot_id: slot.slot_id,
+                sdk: slot.sdk.clone(),
+            })
+    }
+
+    /// 关掉一个账号的实例（不退出登录、不删数据）。它是前台账号时前台随之清空。
+    pub async fn remove_account(&self, uid: &str) -> Result<()> {
+        let slot_id = self
+            .find_slot(uid)
+            .map(|opened| opened.slot_id)
+            .ok_or_else(|| Error::NotFound(format!("account {uid} is not open")))?;
+        self.remove_slot(slot_id).await
+    }
+
+    /// 按槽位号关掉实例；还没登录（`add_account` 之后放弃了）的实例只能走这里。
+    pub async fn remove_slot(&self, slot_id: u64) -> Result<()> {
+        let slot = {
+            let mut slots = self.lock_slots()?;
+            let index = slots
+                .iter()
+                .position(|slot| slot.slot_id == slot_id)
+                .ok_or_else(|| Error::NotFound(format!("account slot {slot_id} is not open")))?;
+            slots.remove(index)
+        };
+        let uid = slot.uid();
+        slot.sdk.shutdown().await;
+        if let Some(uid) = uid {
+            if self.foreground()?.as_deref() == Some(uid.as_str()) {
+                self.inner.store.clear_foreground_uid()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 指定前台账号：只写 `current_user`，别的账号不受影响、继续在线。
+    pub fn set_foreground(&self, uid: &str) -> Result<()> {
+        if self.account(uid).is_none() {
+            return Err(Error::NotFound(format!("account {uid} is not open")));
+        }
+        self.inner.store.save_foreground_uid(uid)
+    }
+
+    pub fn foreground(&self) -> Result<Option<String>> {
+        self.inner.store.load_foreground_uid()
+    }
+
+    /// 所有账号的事件，带槽位号和账号 uid。
+    pub fn subscribe_events(&self) -> broadcast::Receiver<AccountEvent> {
+        self.inner.event_tx.subscribe()
+    }
+
+    /// 各已登录账号的未读聚合与总和。某个账号取不到（比如还没 bootstrap）时只在它自己的
+    /// `error` 里报告，不影响其他账号和总和。
+    pub async fn unread_summary(&self) -> Result<AccountsUnreadSummary> {
+        let open: Vec<(String, PrivchatSdk)> = {
+            let slots = self.lock_slots()?;
+            slots
+                .iter()
+                .filter_map(|slot| slot.uid().map(|uid| (uid, slot.sdk.clone())))
+                .collect()
+        };
+        let mut accounts = Vec::with_capacity(open.len());
+        for (uid, sdk) in open {
+            let account = match sdk.get_unread_aggregate().await {
+                Ok(aggregate) => AccountUnread {
+                    uid,
+                    aggregate,
+                    error: None,
+                },
+                Err(e) => AccountUnread {
+                    uid,
+                    aggregate: UnreadAggregate::default(),
+                    error: Some(e.to_string()),
+                },
+            };
+            accounts.push(account);
+        }
+        let total = merge_unread(
+            accounts
+                .iter()
+                .filter(|a| a.error.is_none())
+                .map(|a| &a.aggregate),
+        );
+        Ok(AccountsUnreadSummary { total, accounts })
+    }
+
+    /// 关掉所有账号实例。
+    pub async fn shutdown(&self) {
+        let slots = match self.inner.slots.lock() {
+            Ok(mut slots) => std::mem::take(&mut *slots),
+            Err(_) => return,
+        };
+        for slot in slots {
+            slot.sdk.shutdown().await;
+        }
+    }
+}
@@ -3,18 +3,34 @@
 //
 // Licensed under the Apache License, Version 2.0 (the "License").
 
-//! 附件加密 v1（ATTACHMENT_ENCRYPTION_SPEC）：AES-256-GCM 整文件加密。
+//! 附件加密（ATTACHMENT_ENCRYPTION_SPEC）。
 //!
+//! **v1**：AES-256-GCM 整文件加密。
 //! - `blob = nonce(12B) || ciphertext || tag(16B)`（aes-gcm 的 ciphertext 已含尾部 16B tag）。
-//! - `cek = base64url(no-pad)` 的 32 字节随机密钥。
 //! - 与 WebCrypto `AES-GCM` 字节兼容（同样 ct||tag），App/Web 互解。
-//! - **CEK 绝不进日志。**
+//! - 代价：加解密都要整份在内存里，没下载完之前一个字节也解不出来。
+//!
+//! **v2**：定长分段，每段独立认证（见 [`SegmentedLayout`]）。
+//! - `blob = header(24B) || seg_0 || seg_1 || … || seg_last`，每段 `ct || tag(16B)`。
+//! - `header = "PCA2" || segment_size(u32 BE) || salt(16B)`；header 整体作为每段的 AAD，
+//!   改段长或换 salt 都会让所有段认证失败。
+//! - 段密钥与 nonce 前缀都由 `HKDF-SHA256(cek, salt)` 派生；
+//!   `nonce = prefix(7B) || index(u32 BE) || last(1B)`。段号进 nonce → 段不能换位；
+//!   末段标记进 nonce → 从段边界截断也会被识破（最后剩下的那段不是「末段」）。
+//! - 末段明文长度严格小于 `segment_size`（整除时补一个空末段），所以只凭 blob 长度就能
+//!   算出明文长度和任意字节的位置，随机读不需要额外元数据。
+//!
+//! 两个版本共用：`cek = base64url(no-pad)` 的 32 字节随机密钥。**CEK 绝不进日志。**
 
-use aes_gcm::aead::{Aead, KeyInit};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
 use aes_gcm::{Aes256Gcm, Key, Nonce};
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
+use hkdf::Hkdf;
 use rand::RngCore;
+use sha2::{Digest, Sha256};
 
 pub const NONCE_LEN: usize = 12;
 pub const TAG_LEN: usize = 16;
@@ -22,6 +38,24 @@ pub const CEK_LEN: usize = 32;
 /// 最小密文 blob：12 nonce + 16 tag（空明文边界）。
 pub const MIN_BLOB_LEN: usize = NONCE_LEN + TAG_LEN;
 
+/// `encryption_version`：legacy 明文。
+pub const ENCRYPTION_VERSION_PLAIN: i32 = 0;
+/// `encryption_version`：整文件 AES-GCM。
+pub const ENCRYPTION_VERSION_WHOLE: i32 = 1;
+/// `encryption_version`：定长分段。
+pub const ENCRYPTION_VERSION_SEGMENTED: i32 = 2;
+
+pub const V2_MAGIC: &[u8; 4] = b"PCA2";
+pub const V2_SALT_LEN: usize = 16;
+pub const V2_HEADER_LEN: usize = V2_MAGIC.len() + 4 + V2_SALT_LEN;
+/// 默认段长。对齐分片上传的 base_unit，一段密文刚好落在一两个分片里。
+pub const V2_DEFAULT_SEGMENT_SIZE: u32 = 64 * 1024;
+/// 段长下限/上限。header 是不可信输入，不设上限的话一个坏 header 就能让解密端
+/// 按它去分配几个 GB 的缓冲。
+pub const V2_MIN_SEGMENT_SIZE: u32 = 1024;
+pub const V2_MAX_SEGMENT_SIZE: u32 = 16 * 1024 * 1024;
+const V2_NONCE_PREFIX_LEN: usize = 7;
+
 /// 加密明文 → `(blob, cek_base64url)`。CSPRNG 生成 cek + nonce。
 /// blob 直接上传对象存储；cek 走 file 表 / 鉴权后的 get_url 响应。
 pub fn encrypt_attachment(plaintext: &[u8]) -> Result<(Vec<u8>, String), String> {
@@ -54,12 +88,7 @@ pub fn decrypt_attachment(blob: &[u8], cek_b64: &str) -> Result<Vec<u8>, String>
             MIN_BLOB_LEN
         ));
     }
-    let cek = URL_SAFE_NO_PAD
-        .decode(cek_b64.as_bytes())
-        .map_err(|_| "cek is not valid base64url".to_string())?;
-    if cek.len() != CEK_LEN {
-        return Err(format!("cek must be {} bytes, got {}", CEK_LEN, cek.len()));
-    }
+    let cek = decode_cek(cek_b64)?;
     let (nonce_bytes, ct_with_tag) = blob.split_at(NONCE_LEN);
     let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&cek));
     cipher
@@ -67,10 +96,400 @@ pub fn decrypt_attachment(blob: &[u8], cek_b64: &str) -> Result<Vec<u8>, String>
         .map_err(|_| "attachment decrypt/auth failed".to_string())
 }
 
+fn decode_cek(cek_b64: &str) -> Result<[u8; CEK_LEN], String> {
+    let cek = URL_SAFE_NO_PAD
+        .decode(cek_b64.as_bytes())
+        .map_err(|_| "cek is not valid base64url".to_string())?;
+    cek.as_slice()
+        .try_into()
+        .map_err(|_| format!("cek must be {} bytes, got {}", CEK_LEN, cek.len()))
+}
+
+/// v2 blob 的几何：段长 + 明文总长。两者确定，每一段在 blob 里的位置就确定。
+///
+/// 明文 `P`、段长 `S`：`floor(P / S)` 个满段，外加一个明文长 `P mod S` 的末段
+/// （可能为空）。末段**总是存在**，它带着末段标记，截断检测靠的就是它。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentedLayout {
+    pub segment_size: u32,
+    pub plaintext_len: u64,
+}
+
+impl SegmentedLayout {
+    pub fn for_plaintext(segment_size: u32, plaintext_len: u64) -> Self {
+        Self {
+            segment_size,
+            plaintext_len,
+        }
+    }
+
+    /// 从 blob 总长反推明文长度。长度本身不可能出现（余数落在 `[S, S+16)`）时返回 Err——
+    /// 那只能是截断或拼接坏了。
+    pub fn from_blob_len(segment_size: u32, blob_len: u64) -> Result<Self, String> {
+        let body = blob_len
+            .checked_sub((V2_HEADER_LEN + TAG_LEN) as u64)
+            .ok_or_else(|| format!("segmented blob too short: {blob_len}"))?;
+        let stride = segment_size as u64 + TAG_LEN as u64;
+        let full = body / stride;
+        let rest = body % stride;
+        if rest >= segment_size as u64 {
+            return Err(format!(
+                "segmented blob length {blob_len} does not fit segment_size {segment_size}"
+            ));
+        }
+        Ok(Self {
+            segment_size,
+            plaintext_len: full * segment_size as u64 + rest,
+        })
+    }
+
+    pub fn blob_len(&self) -> u64 {
+        V2_HEADER_LEN as u64 + self.plaintext_len + self.segment_count() * TAG_LEN as u64
+    }
+
+    /// 段数（含末段）。
+    pub fn segment_count(&self) -> u64 {
+        self.plaintext_len / self.segment_size as u64 + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.segment_count() - 1
+    }
+
+    /// 第 `index` 段的明文区间 `[start, end)`。
+    pub fn plaintext_range(&self, index: u64) -> (u64, u64) {
+        let start = index * self.segment_size as u64;
+        let end = (start + self.segment_size as u64).min(self.plaintext_len);
+        (start, end)
+    }
+
+    /// 第 `index` 段的密文在 blob 里的区间 `[start, end)`（含 tag）。
+    pub fn ciphertext_range(&self, index: u64) -> (u64, u64) {
+        let (plain_start, plain_end) = self.plaintext_range(index);
+        let start = V2_HEADER_LEN as u64 + index * (self.segment_size as u64 + TAG_LEN as u64);
+        (start, start + (plain_end - plain_start) + TAG_LEN as u64)
+    }
+
+    /// 覆盖明文 `[start, end)` 需要的段号区间 `[first, last]`。`end` 会被夹到明文末尾。
+    /// 只想要其中几个字节也得解整段——认证的最小单位就是段。
+    pub fn segments_for(&self, start: u64, end: u64) -> (u64, u64) {
+        let end = end.min(self.plaintext_len);
+        let first = (start / self.segment_size as u64).min(self.last_index());
+        let last = if end <= start {
+            first
+        } else {
+            ((end - 1) / self.segment_size as u64).min(self.last_index())
+        };
+        (first, last)
+    }
+}
+
+/// v2 header 的解析结果。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentedHeader {
+    pub segment_size: u32,
+    pub salt: [u8; V2_SALT_LEN],
+}
+
+impl SegmentedHeader {
+    pub fn encode(&self) -> [u8; V2_HEADER_LEN] {
+        let mut out = [0u8; V2_HEADER_LEN];
+        out[..4].copy_from_slice(V2_MAGIC);
+        out[4..8].copy_from_slice(&self.segment_size.to_be_bytes());
+        out[8..].copy_from_slice(&self.salt);
+        out
+    }
+
+    pub fn parse(raw: &[u8]) -> Result<Self, String> {
+        if raw.len() < V2_HEADER_LEN {
+            return Err(format!(
+                "segmented header too short: {} < {}",
+                raw.len(),
+                V2_HEADER_LEN
+            ));
+        }
+        if &raw[..4] != V2_MAGIC {
+            return Err("not a segmented attachment blob (bad magic)".to_string());
+        }
+        let segment_size = u32::from_be_bytes([raw[4], raw[5], raw[6], raw[7]]);
+        if !(V2_MIN_SEGMENT_SIZE..=V2_MAX_SEGMENT_SIZE).contains(&segment_size) {
+            return Err(format!("segment_size out of range: {segment_size}"));
+        }
+        let mut salt = [0u8; V2_SALT_LEN];
+        salt.copy_from_slice(&raw[8..V2_HEADER_LEN]);
+        Ok(Self { segment_size, salt })
+    }
+}
+
+/// 一份 v2 blob 的段级加解密器：header + CEK 派生出段密钥和 nonce 前缀。
+///
+/// 流式接口和随机读都建立在它上面；只拿到 blob 某几段字节的调用方（比如按 Range
+/// 取数据的播放代理）也可以直接用它逐段解。
+pub struct SegmentedCipher {
+    header: SegmentedHeader,
+    header_bytes: [u8; V2_HEADER_LEN],
+    cipher: Aes256Gcm,
+    nonce_prefix: [u8; V2_NONCE_PREFIX_LEN],
+}
+
+impl SegmentedCipher {
+    pub fn new(cek_b64: &str, header: SegmentedHeader) -> Result<Self, String> {
+        let cek = decode_cek(cek_b64)?;
+        Ok(Self::with_key(&cek, header))
+    }
+
+    fn with_key(cek: &[u8; CEK_LEN], header: SegmentedHeader) -> Self {
+        let hk = Hkdf::<Sha256>::new(Some(&header.salt), cek);
+        let mut key = [0u8; CEK_LEN];
+        let mut nonce_prefix = [0u8; V2_NONCE_PREFIX_LEN];
+        // 输出长度都远小于 255*32，expand 不会失败。
+        hk.expand(b"privchat.attachment|v=2|segment_key", &mut key)
+            .expect("hkdf expand segment key");
+        hk.expand(b"privchat.attachment|v=2|nonce_prefix", &mut nonce_prefix)
+            .expect("hkdf expand nonce prefix");
+        Self {
+            header,
+            header_bytes: header.encode(),
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+            nonce_prefix,
+        }
+    }
+
+    pub fn header(&self) -> &SegmentedHeader {
+        &self.header
+    }
+
+    fn nonce(&self, index: u64, last: bool) -> Result<[u8; NONCE_LEN], String> {
+        let index = u32::try_from(index).map_err(|_| format!("segment index overflow: {index}"))?;
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..V2_NONCE_PREFIX_LEN].copy_from_slice(&self.nonce_prefix);
+        nonce[V2_NONCE_PREFIX_LEN..NONCE_LEN - 1].copy_from_slice(&index.to_be_bytes());
+        nonce[NONCE_LEN - 1] = last as u8;
+        Ok(nonce)
+    }
+
+    pub fn seal_segment(
+        &self,
+        index: u64,
+        last: bool,
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        let nonce = self.nonce(index, last)?;
+        self.cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &self.header_bytes,
+                },
+            )
+            .map_err(|_| "attachment segment encrypt failed".to_string())
+    }
+
+    /// 解一段。`last` 必须由调用方按 [`SegmentedLayout`] 判定，不能信数据本身。
+    pub fn open_segment(&self, index: u64, last: bool, segment: &[u8]) -> Result<Vec<u8>, String> {
+        let nonce = self.nonce(index, last)?;
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: segment,
+                    aad: &self.header_bytes,
+                },
+            )
+            .map_err(|_| format!("attachment segment {index} decrypt/auth failed"))
+    }
+}
+
+/// 流式封装的结果。`sha256` 是**密文**的摘要（秒传按最终上传字节判重），
+/// 边写边算，调用方不用再把 blob 读一遍。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamSealed {
+    pub cek: String,
+    pub plaintext_len: u64,
+    pub blob_len: u64,
+    pub sha256: String,
+}
+
+/// 把 `reader` 读到底，v2 分段加密写进 `writer`。内存占用是一段，不是整份文件。
+pub fn encrypt_attachment_stream<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+) -> Result<StreamSealed, String> {
+    encrypt_attachment_stream_with_segment_size(reader, writer, V2_DEFAULT_SEGMENT_SIZE)
+}
+
+pub fn encrypt_attachment_stream_with_segment_size<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    segment_size: u32,
+) -> Result<StreamSealed, String> {
+    if !(V2_MIN_SEGMENT_SIZE..=V2_MAX_SEGMENT_SIZE).contains(&segment_size) {
+        return Err(format!("segment_size out of range: {segment_size}"));
+    }
+    let mut cek = [0u8; CEK_LEN];
+    let mut salt = [0u8; V2_SALT_LEN];
+    let mut rng = rand::thread_rng();
+    rng.fill_bytes(&mut cek);
+    rng.fill_bytes(&mut salt);
+    let seg = SegmentedCipher::with_key(&cek, SegmentedHeader { segment_size, salt });
+
+    let mut hasher = Sha256::new();
+    let mut put = |bytes: &[u8]| -> Result<(), String> {
+        hasher.update(bytes);
+        writer
+            .write_all(bytes)
+            .map_err(|e| format!("write sealed attachment: {e}"))
+    };
+    put(&seg.header_bytes)?;
+
+    let mut buf = vec![0u8; segment_size as usize];
+    let mut index = 0u64;
+    let mut plaintext_len = 0u64;
+    let mut blob_len = V2_HEADER_LEN as u64;
+    loop {
+        let n = read_full(&mut reader, &mut buf).map_err(|e| format!("read attachment: {e}"))?;
+        // 读不满一段 = 到头了，这一段就是末段（可能是空的）。
+        let last = n < buf.len();
+        let sealed = seg.seal_segment(index, last, &buf[..n])?;
+        put(&sealed)?;
+        plaintext_len += n as u64;
+        blob_len += sealed.len() as u64;
+        if last {
+            break;
+        }
+        index += 1;
+    }
+    writer
+        .flush()
+        .map_err(|e| format!("flush sealed attachment: {e}"))?;
+    Ok(StreamSealed {
+        cek: URL_SAFE_NO_PAD.encode(cek),
+        plaintext_len,
+        blob_len,
+        sha256: hex::encode(hasher.finalize()),
+    })
+}
+
+/// 流式解 v2：逐段认证、逐段写出。返回写出的明文字节数。
+///
+/// 🔴 出错时 `writer` 里可能已经有前面几段的明文——那几段确实认证过，但整份文件
+/// 不完整。调用方必须写临时文件，成功后再改名。
+pub fn decrypt_attachment_stream<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    cek_b64: &str,
+) -> Result<u64, String> {
+    let mut header_raw = [0u8; V2_HEADER_LEN];
+    let n = read_full(&mut reader, &mut header_raw).map_err(|e| format!("read blob: {e}"))?;
+    let header = SegmentedHeader::parse(&header_raw[..n])?;
+    let seg = SegmentedCipher::new(cek_b64, header)?;
+
+    let stride = header.segment_size as usize + TAG_LEN;
+    let mut buf = vec![0u8; stride];
+    let mut index = 0u64;
+    let mut written = 0u64;
+    loop {
+        let n = read_full(&mut reader, &mut buf).map_err(|e| format!("read blob: {e}"))?;
+        if n < TAG_LEN {
+            // 连一个 tag 都凑不齐：末段没了（恰好截在段边界上也落在这里）。
+            return Err(format!("segmented blob truncated at segment {index}"));
+        }
+        let last = n < stride;
+        let plain = seg.open_segment(index, last, &buf[..n])?;
+        writer
+            .write_all(&plain)
+            .map_err(|e| format!("write decrypted attachment: {e}"))?;
+        written += plain.len() as u64;
+        if last {
+            break;
+        }
+        index += 1;
+    }
+    writer
+        .flush()
+        .map_err(|e| format!("flush decrypted attachment: {e}"))?;
+    Ok(written)
+}
+
+/// 随机读：只解覆盖明文 `[start, end)` 的那几段。`end` 超出明文长度时按明文末尾截断。
+///
+/// 给播放器拖进度条用——1 GB 的视频看第 40 分钟，不该先解前 39 分钟。
+pub fn decrypt_attachment_range<R: Read + Seek>(
+    reader: &mut R,
+    cek_b64: &str,
+    start: u64,
+    end: u64,
+) -> Result<Vec<u8>, String> {
+    let io = |e: std::io::Error| format!("read blob: {e}");
+    let blob_len = reader.seek(SeekFrom::End(0)).map_err(io)?;
+    reader.seek(SeekFrom::Start(0)).map_err(io)?;
+    let mut header_raw = [0u8; V2_HEADER_LEN];
+    reader.read_exact(&mut header_raw).map_err(io)?;
+    let header = SegmentedHeader::parse(&header_raw)?;
+    let seg = SegmentedCipher::new(cek_b64, header)?;
+    let layout = SegmentedLayout::from_blob_len(header.segment_size, blob_len)?;
+
+    let end = end.min(layout.plaintext_len);
+    if start >= end {
+        return Ok(Vec::new());
+    }
+    let (first, last) = layout.segments_for(start, end);
+    let mut out = Vec::with_capacity((end - start) as usize);
+    for index in first..=last {
+        let (ct_start, ct_end) = layout.ciphertext_range(index);
+        let mut ct = vec![0u8; (ct_end - ct_start) as usize];
+        reader.seek(SeekFrom::Start(ct_start)).map_err(io)?;
+        reader.read_exact(&mut ct).map_err(io)?;
+        let plain = seg.open_segment(index, index == layout.last_index(), &ct)?;
+        let (plain_start, _) = layout.plaintext_range(index);
+        let from = start.saturating_sub(plain_start) as usize;
+        let to = ((end - plain_start) as usize).min(plain.len());
+        out.extend_from_slice(&plain[from..to]);
+    }
+    Ok(out)
+}
+
+/// 内存版 v2 加密，语义同 [`encrypt_attachment`]。
+pub fn encrypt_attachment_v2(plaintext: &[u8]) -> Result<(Vec<u8>, String), String> {
+    let mut blob = Vec::with_capacity(
+        SegmentedLayout::for_plaintext(V2_DEFAULT_SEGMENT_SIZE, plaintext.len() as u64).blob_len()
+            as usize,
+    );
+    let sealed = encrypt_attachment_stream(plaintext, &mut blob)?;
+    Ok((blob, sealed.cek))
+}
+
+/// 内存版 v2 解密，语义同 [`decrypt_attachment`]。
+pub fn decrypt_attachment_v2(blob: &[u8], cek_b64: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(blob.len());
+    decrypt_attachment_stream(blob, &mut out, cek_b64)?;
+    Ok(out)
+}
+
+/// 读满 `buf` 或读到 EOF，返回实际读到的字节数。`read` 一次给不满不代表到头了。
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn require_cek(encryption_version: i32, cek: Option<&str>) -> Result<&str, String> {
+    cek.filter(|s| !s.is_empty())
+        .ok_or_else(|| format!("encryption_version={encryption_version} but cek missing"))
+}
+
 /// 下载完成后按加密信息把 blob 还原成明文（run_download / thumbnail 下载统一调用）。
 ///
 /// - `version=0`（或上层视为缺失时传 0）→ legacy 明文，原样返回。
-/// - `version=1` → `cek` **必须存在**，blob 校验 + AES-GCM 解密；缺 cek 或解密失败一律 Err，
+/// - `version=1/2` → `cek` **必须存在**，认证解密；缺 cek 或解密失败一律 Err，
 ///   **绝不 fallback 成明文**（否则会把密文当图片写入，UI 显示坏图并掩盖错误）。
 pub fn decrypt_downloaded_attachment_bytes(
     encryption_version: i32,
@@ -78,12 +497,43 @@ pub fn decrypt_downloaded_attachment_bytes(
     blob: &[u8],
 ) -> Result<Vec<u8>, String> {
     match encryption_version {
-        0 => Ok(blob.to_vec()),
-        1 => {
-            let cek = cek
-                .filter(|s| !s.is_empty())
-                .ok_or_else(|| "encryption_version=1 but cek missing".to_string())?;
-            decrypt_attachment(blob, cek)
+        ENCRYPTION_VERSION_PLAIN => Ok(blob.to_vec()),
+        ENCRYPTION_VERSION_WHOLE => decrypt_attachment(blob, require_cek(encryption_version, cek)?),
+        ENCRYPTION_VERSION_SEGMENTED => {
+            decrypt_attachment_v2(blob, require_cek(encryption_version, cek)?)
+        }
+        v => Err(format!("unsupported encryption_version: {v}")),
+    }
+}
+
+/// 同上，流式版：从 `reader`（下载好的 `.part`）解到 `writer`，返回明文字节数。
+///
+/// v2 全程只占一段内存；v1 格式本身做不到流式，只能整份读进来再解。
+pub fn decrypt_downloaded_attachment_stream<R: Read, W: Write>(
+    encryption_version: i32,
+    cek: Option<&str>,
+    mut reader: R,
+    mut writer: W,
+) -> Result<u64, String> {
+    match encryption_version {
+        ENCRYPTION_VERSION_PLAIN => {
+            std::io::copy(&mut reader, &mut writer).map_err(|e| format!("copy attachment: {e}"))
+        }
+        ENCRYPTION_VERSION_WHOLE => {
+            let cek = require_cek(encryption_version, cek)?;
+            let mut blob = Vec::new();
+            reader
+                .read_to_end(&mut blob)
+                .map_err(|e| format!("read blob: {e}"))?;
+            let plain = decrypt_attachment(&blob, cek)?;
+            writer
+                .write_all(&plain)
+                .and_then(|_| writer.flush())
+                .map_err(|e| format!("write decrypted attachment: {e}"))?;
+            Ok(plain.len() as u64)
+        }
+        ENCRYPTION_VERSION_SEGMENTED => {
+            decrypt_attachment_stream(reader, writer, require_cek(encryption_version, cek)?)
         }
         v => Err(format!("unsupported encryption_version: {v}")),
     }
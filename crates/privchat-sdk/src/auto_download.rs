@@ -0,0 +1,288 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 媒体自动下载策略。
+//!
+//! 之前只有缩略图会自动下载，主文件一律等用户点开。这里按账号存一份策略（账号 kv 里的
+//! [`POLICY_KV_KEY`]），入站消息落库时求值一次：放行的附件以后台优先级交给
+//! `DownloadManager`，和缩略图共用同一组有界槽位。
+//!
+//! | 维度 | 规则 |
+//! |---|---|
+//! | 网络 | Wi-Fi / 以太网用 [`AutoDownloadPolicy::unmetered`]，蜂窝和**未知**用 [`AutoDownloadPolicy::metered`]，离线一律不下 |
+//! | 类型 / 大小 | 每种网络下每个类型一个上限，0 = 不自动下载；大小未知的只在「不限」时放行 |
+//! | 会话 | [`AutoDownloadPolicy::skip_muted_channels`] 时静音会话一律不下（静音的判定与角标一致） |
+//! | 语音 | [`AutoDownloadPolicy::voice_always`] 时不看网络类型、大小和静音 |
+//!
+//! 网络变了（`set_network_hint`）会对已排上的后台下载重新求值：新网络不再放行的就地
+//! 停下（`.part` 留着），网络回到放行状态再按 Range 接着拉。
+//!
+//! 本模块是纯计算，不碰存储和网络；取数、排队、停/续在 actor 里。
+
+use serde::{Deserialize, Serialize};
+
+use crate::NetworkHint;
+use privchat_protocol::message::ContentMessageType;
+
+/// 账号 kv 里存策略的键。值是 [`AutoDownloadPolicy`] 的 JSON。
+pub const POLICY_KV_KEY: &str = "__auto_download_policy__";
+
+/// 只对这么新的消息自动下载。首次登录的历史回填、长时间离线后的补拉都会成批落库
+/// 旧消息，把它们全拉下来是拿用户流量换没人会看的文件。
+pub const RECENT_MESSAGE_WINDOW_MS: i64 = 48 * 60 * 60 * 1000;
+
+/// 参与自动下载的附件类型。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AutoDownloadKind {
+    Image,
+    Video,
+    Voice,
+    File,
+}
+
+impl AutoDownloadKind {
+    pub fn from_message_type(message_type: i32) -> Option<Self> {
+        let is = |t: ContentMessageType| i32::try_from(t.as_u32()).ok() == Some(message_type);
+        if is(ContentMessageType::Image) {
+            Some(Self::Image)
+        } else if is(ContentMessageType::Video) {
+            Some(Self::Video)
+        } else if is(ContentMessageType::Voice) {
+            Some(Self::Voice)
+        } else if is(ContentMessageType::File) {
+            Some(Self::File)
+        } else {
+            None
+        }
+    }
+}
+
+/// 一种网络下各类型的大小上限（字节）。0 = 不自动下载，[`u64::MAX`] = 不限。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AutoDownloadLimits {
+    pub image_max_bytes: u64,
+    pub video_max_bytes: u64,
+    pub voice_max_bytes: u64,
+    pub file_max_bytes: u64,
+}
+
+impl AutoDownloadLimits {
+    pub const NEVER: Self = Self {
+        image_max_bytes: 0,
+        video_max_bytes: 0,
+        voice_max_bytes: 0,
+        file_max_bytes: 0,
+    };
+
+    pub const UNLIMITED: Self = Self {
+        image_max_bytes: u64::MAX,
+        video_max_bytes: u64::MAX,
+        voice_max_bytes: u64::MAX,
+        file_max_bytes: u64::MAX,
+    };
+
+    fn max_bytes(&self, kind: AutoDownloadKind) -> u64 {
+        match kind {
+            AutoDownloadKind::Image => self.image_max_bytes,
+            AutoDownloadKind::Video => self.video_max_bytes,
+            AutoDownloadKind::Voice => self.voice_max_bytes,
+            AutoDownloadKind::File => self.file_max_bytes,
+        }
+    }
+}
+
+/// 按账号保存的自动下载策略。字段缺省时取 [`Default`]，旧版本写下的 JSON 照样能读。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutoDownloadPolicy {
+    /// Wi-Fi / 以太网。
+    pub unmetered: AutoDownloadLimits,
+    /// 蜂窝。网络未知也按它算：宿主没报网络时宁可少拉。
+    pub metered: AutoDownloadLimits,
+    /// 静音会话（mute / 只提醒 @ / 完全静音）里一律不自动下载。
+    pub skip_muted_channels: bool,
+    /// 语音不看网络类型、大小和静音：它很小，而且不下完就放不了。
+    pub voice_always: bool,
+}
+
+impl Default for AutoDownloadPolicy {
+    /// 蜂窝只拉 5 MiB 以内的图片，Wi-Fi 全拉，静音会话不拉，语音总是拉。
+    fn default() -> Self {
+        Self {
+            unmetered: AutoDownloadLimits::UNLIMITED,
+            metered: AutoDownloadLimits {
+                image_max_bytes: 5 * 1024 * 1024,
+                video_max_bytes: 0,
+                voice_max_bytes: u64::MAX,
+                file_max_bytes: 0,
+            },
+            skip_muted_channels: true,
+            voice_always: true,
+        }
+    }
+}
+
+impl AutoDownloadPolicy {
+    /// 从账号 kv 的原始值解出策略；没有或解不开都退回默认——坏掉的一条配置不该让
+    /// 自动下载整个停摆。
+    pub fn decode(raw: Option<&[u8]>) -> Self {
+        raw.and_then(|b| serde_json::from_slice(b).ok())
+            .unwrap_or_default()
+    }
+
+    /// 这份附件在 `network` 下该不该自动下载。`file_size == 0` 表示大小未知。
+    pub fn allows(
+        &self,
+        kind: AutoDownloadKind,
+        file_size: u64,
+        network: NetworkHint,
+        channel_muted: bool,
+    ) -> bool {
+        if network == NetworkHint::Offline {
+            return false;
+        }
+        if kind == AutoDownloadKind::Voice && self.voice_always {
+            return true;
+        }
+        if channel_muted && self.skip_muted_channels {
+            return false;
+        }
+        let limits = if network.is_unmetered() {
+            &self.unmetered
+        } else {
+            &self.metered
+        };
+        match limits.max_bytes(kind) {
+            0 => false,
+            u64::MAX => true,
+            max => file_size > 0 && file_size <= max,
+        }
+    }
+}
+
+/// 从一条入站消息里取出自动下载要的东西。不是附件，或者没有 `file_id`（legacy 明文
+/// 消息只有 URL，走不了 `file/get_url`）时返回 None。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AutoDownloadCandidate {
+    pub kind: AutoDownloadKind,
+    pub file_id: u64,
+    pub file_size: u64,
+    pub mime: String,
+    pub filename: Option<String>,
+}
+
+impl AutoDownloadCandidate {
+    pub fn from_message(message_type: i32, extra: &str) -> Option<Self> {
+        let kind = AutoDownloadKind::from_message_type(message_type)?;
+        let json: serde_json::Value = serde_json::from_str(extra).ok()?;
+        // 与缩略图字段同样兼容两种形态：`{"metadata": {...}}` 信封和扁平 JSON。
+        let scope = json.get("metadata").unwrap_or(&json);
+        let read_u64 = |name: &str| {
+            scope.get(name).and_then(|v| {
+                v.as_u64()
+                    .or_else(|| v.as_str().and_then(|s| s.parse::<u64>().ok()))
+            })
+        };
+        let read_str = |name: &str| {
+            scope
+                .get(name)
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+        };
+        let file_id = read_u64("file_id").filter(|id| *id > 0)?;
+        Some(Self {
+            kind,
+            file_id,
+            file_size: read_u64("file_size").unwrap_or(0),
+            mime: read_str("mime_type").unwrap_or_default(),
+            filename: read_str("filename"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test]
+    fn the_default_policy_saves_cellular_data() {
+        let policy = AutoDownloadPolicy::default();
+        let cell = NetworkHint::Cellular;
+        assert!(policy.allows(AutoDownloadKind::Image, 4 * MIB, cell, false));
+        assert!(!policy.allows(AutoDownloadKind::Image, 6 * MIB, cell, false));
+        assert!(!policy.allows(AutoDownloadKind::Video, MIB, cell, false));
+        assert!(!policy.allows(AutoDownloadKind::File, 1, cell, false));
+        assert!(policy.allows(AutoDownloadKind::Video, 500 * MIB, NetworkHint::Wifi, false));
+        assert!(policy.allows(AutoDownloadKind::File, 0, NetworkHint::Ethernet, false));
+    }
+
+    #[test]
+    fn unknown_network_is_treated_as_metered_and_offline_never_downloads() {
+        let policy = AutoDownloadPolicy::default();
+        assert!(!policy.allows(AutoDownloadKind::Video, MIB, NetworkHint::Unknown, false));
+        assert!(!policy.allows(AutoDownloadKind::Voice, 1, NetworkHint::Offline, false));
+    }
+
+    #[test]
+    fn muted_channels_are_skipped_but_voice_still_arrives() {
+        let policy = AutoDownloadPolicy::default();
+        assert!(!policy.allows(AutoDownloadKind::Image, MIB, NetworkHint::Wifi, true));
+        assert!(policy.allows(AutoDownloadKind::Voice, MIB, NetworkHint::Cellular, true));
+
+        let strict = AutoDownloadPolicy {
+            voice_always: false,
+            ..AutoDownloadPolicy::default()
+        };
+        assert!(!strict.allows(AutoDownloadKind::Voice, MIB, NetworkHint::Wifi, true));
+    }
+
+    #[test]
+    fn an_unknown_size_only_passes_an_unlimited_rule() {
+        let policy = AutoDownloadPolicy::default();
+        assert!(!policy.allows(AutoDownloadKind::Image, 0, NetworkHint::Cellular, false));
+        assert!(policy.allows(AutoDownloadKind::Image, 0, NetworkHint::Wifi, false));
+    }
+
+    #[test]
+    fn a_partial_or_corrupt_stored_policy_falls_back_field_by_field() {
+        let partial = AutoDownloadPolicy::decode(Some(br#"{"skip_muted_channels":false}"#));
+        assert!(!partial.skip_muted_channels);
+        assert_eq!(partial.metered, AutoDownloadPolicy::default().metered);
+        assert_eq!(
+            AutoDownloadPolicy::decode(Some(b"not json")),
+            AutoDownloadPolicy::default()
+        );
+        assert_eq!(
+            AutoDownloadPolicy::decode(None),
+            AutoDownloadPolicy::default()
+        );
+    }
+
+    #[test]
+    fn candidates_read_both_envelope_and_flat_metadata() {
+        let image = i32::try_from(ContentMessageType::Image.as_u32()).unwrap();
+        let flat =
+            r#"{"file_id":"77","file_size":1024,"mime_type":"image/jpeg","filename":"a.jpg"}"#;
+        let candidate = AutoDownloadCandidate::from_message(image, flat).expect("flat");
+        assert_eq!(candidate.file_id, 77);
+        assert_eq!(candidate.file_size, 1024);
+        assert_eq!(candidate.filename.as_deref(), Some("a.jpg"));
+
+        let envelope = r#"{"content":"","metadata":{"file_id":78,"mime_type":"image/png"}}"#;
+        let candidate = AutoDownloadCandidate::from_message(image, envelope).expect("envelope");
+        assert_eq!(candidate.file_id, 78);
+        assert_eq!(candidate.file_size, 0);
+
+        let text = i32::try_from(ContentMessageType::Text.as_u32()).unwrap();
+        assert_eq!(AutoDownloadCandidate::from_message(text, flat), None);
+        assert_eq!(
+            AutoDownloadCandidate::from_message(image, r#"{"file_url":"http://x"}"#),
+            None
+        );
+    }
+}
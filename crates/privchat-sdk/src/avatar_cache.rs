@@ -20,6 +20,7 @@ use std::sync::{Arc, Mutex as StdMutex};
 
 use tokio::sync::{broadcast, Semaphore};
 
+use crate::bandwidth::BandwidthLimiter;
 use crate::storage_actor::StorageHandle;
 use crate::{emit_sequenced_event, SdkEvent, SequencedSdkEvent};
 
@@ -47,15 +48,17 @@ pub(crate) fn avatar_cache_path(user_root: &Path, target_uid: u64) -> PathBuf {
 
 /// 下载 URL 到 dest：先写 `.part` 临时文件再 rename（原子换入）。
 /// 头像是 PUBLIC 类匿名可读文件，不带鉴权头；明文落盘（无附件加密信封）。
+/// 与附件共用同一个限速器：头像一批批灌进来时不该把聊天附件挤掉。
 pub(crate) async fn download_to_file(
     url: &str,
     dest: &Path,
+    bandwidth: &BandwidthLimiter,
 ) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let resp = reqwest::Client::new().get(url).send().await?;
     if !resp.status().is_success() {
         return Err(format!("HTTP {}", resp.status()).into());
     }
-    let bytes = resp.bytes().await?;
+    let bytes = bandwidth.read_body(resp).await?;
     if bytes.is_empty() {
         return Err("empty body".into());
     }
@@ -143,18 +146,24 @@ const MAX_CONCURRENT_AVATAR_CACHE_JOBS: usize = 3;
 pub(crate) struct AvatarCacheManager {
     inner: Arc<StdMutex<CacheState>>,
     limiter: Arc<Semaphore>,
+    bandwidth: BandwidthLimiter,
 }
 
 impl Default for AvatarCacheManager {
     fn default() -> Self {
+        Self::new(BandwidthLimiter::default())
+    }
+}
+
+impl AvatarCacheManager {
+    pub(crate) fn new(bandwidth: BandwidthLimiter) -> Self {
         Self {
             inner: Arc::new(StdMutex::new(CacheState::default())),
             limiter: Arc::new(Semaphore::new(MAX_CONCURRENT_AVATAR_CACHE_JOBS)),
+            bandwidth,
         }
     }
-}
 
-impl AvatarCacheManager {
     #[cfg(test)]
     pub(crate) fn inflight_len(&self) -> usize {
         self.inner
@@ -193,6 +202,7 @@ impl AvatarCacheManager {
         }
         let mgr = self.clone();
         let limiter = self.limiter.clone();
+        let bandwidth = self.bandwidth.clone();
         let owner_uid = self_uid.to_string();
         let url = url.to_string();
         tokio::spawn(async move {
@@ -205,7 +215,7 @@ impl AvatarCacheManager {
                     return;
                 }
             };
-            let ok = run_ensure(&storage, &sinks, &owner_uid, user_id, &url).await;
+            let ok = run_ensure(&storage, &sinks, &bandwidth, &owner_uid, user_id, &url).await;
             drop(permit);
             if let Ok(mut st) = mgr.inner.lock() {
                 st.inflight.remove(&key);
@@ -221,6 +231,7 @@ impl AvatarCacheManager {
 async fn run_ensure(
     storage: &StorageHandle,
     sinks: &AvatarEventSinks,
+    bandwidth: &BandwidthLimiter,
     owner_uid: &str,
     user_id: u64,
     url: &str,
@@ -256,7 +267,7 @@ async fn run_ensure(
     let dest = avatar_cache_path(&paths.user_root, user_id);
     // 走到这里说明本地缺失或已过期（换头像 ⇒ cached_url != url）：下载并原子覆盖
     // 同一路径（download_to_file 内部 `.part` → rename 覆盖）。
-    if let Err(e) = download_to_file(url, &dest).await {
+    if let Err(e) = download_to_file(url, &dest, bandwidth).await {
         eprintln!("[SDK.avatar] download failed user_id={user_id} url={url}: {e}");
         return false;
     }
@@ -309,6 +320,7 @@ async fn run_ensure(
 /// `avatar_local_path` / `avatar_cached_url` 保持不变。返回 `(local_path, cached_url)`。
 pub(crate) async fn recache_user_avatar(
     storage: &StorageHandle,
+    bandwidth: &BandwidthLimiter,
     user_id: u64,
     url: &str,
 ) -> crate::Result<(String, String)> {
@@ -320,7 +332,7 @@ pub(crate) async fn recache_user_avatar(
     }
     let paths = storage.get_storage_paths().await?;
     let dest = avatar_cache_path(&paths.user_root, user_id);
-    download_to_file(url, &dest)
+    download_to_file(url, &dest, bandwidth)
         .await
         .map_err(|e| crate::Error::Storage(format!("recache download failed: {e}")))?;
     let dest_str = dest.to_string_lossy().to_string();
@@ -347,11 +359,18 @@ mod tests {
             hex::encode(rand)
         ));
         let storage = StorageHandle::start_at(dir).expect("start storage");
-        assert!(recache_user_avatar(&storage, 42, "").await.is_err());
-        assert!(recache_user_avatar(&storage, 42, "   ").await.is_err());
-        assert!(recache_user_avatar(&storage, 42, "ftp://x/a.png")
+        let bandwidth = BandwidthLimiter::default();
+        assert!(recache_user_avatar(&storage, &bandwidth, 42, "")
+            .await
+            .is_err());
+        assert!(recache_user_avatar(&storage, &bandwidth, 42, "   ")
             .await
             .is_err());
+        assert!(
+            recache_user_avatar(&storage, &bandwidth, 42, "ftp://x/a.png")
+                .await
+                .is_err()
+        );
     }
 
     #[test]
@@ -0,0 +1,529 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 把进行中的传输交给平台的后台传输服务。
+//!
+//! App 一被挂起，进程内的 reqwest 请求就跟着停了：大附件的上传、下载在后台永远
+//! 走不完。iOS 的后台 `URLSession`、Android 的 `WorkManager` 能在 App 挂起甚至被杀
+//! 之后继续跑 HTTP 请求，但它们只认「URL + 头 + 文件」，不认我们的状态机。
+//!
+//! 所以交接分两半：
+//!
+//! - **导出**（`PrivchatSdk::export_background_transfers`）：停掉进程内的传输，把剩下
+//!   的字节描述成一组 [`BackgroundTransfer`]——签名 URL、请求头、字节区间、本地文件、
+//!   期望的大小和摘要。下载是一条「从 `.part` 现有长度拉到结尾、追加进 `.part`」；
+//!   上传是缺口切出来的若干条分片 PUT，请求体是密文缓存里对应区间的字节。
+//! - **导入**（`PrivchatSdk::import_completed_transfer`）：平台报告某一条跑完了。下载
+//!   校验整份 `.part` 并解密落盘，和进程内下载完成是同一条路；上传分片由服务端按
+//!   `X-Chunk-SHA256` 校验过，这边只需在全部导入后让上传循环去 `/status` 对齐、
+//!   `complete`。
+//!
+//! 进程在导入前被杀也不丢：下载的 `.part` 拉满了，下次发起下载会直接校验收尾；上传
+//! 会话本来就落了盘，outbox 重试时 `/status` 会告诉它只剩 `complete`。
+//!
+//! 回到前台时 `PrivchatSdk::reclaim_background_transfers` 把还没导入的收回进程内，
+//! 从各自的断点续上。平台那边同时在跑的请求由宿主自己取消。
+//!
+//! 🔴 交出去的上传不在 actor 里等：在路上的分片落地后上传循环就退出，把 actor 让出来
+//! （导入下载要写库，得经过 actor）。登记留在表里，outbox 照旧留着那条命令，drain 时
+//! 跳过交接中的；最后一片导入或 reclaim 之后登记才摘掉，再踢一次 drain，上传循环从
+//! `/status` 对齐接着传、`complete`。平台那边有一片失败、宿主又没 reclaim，就一直等
+//! 到 reclaim——不自己到点收回，免得和平台那边还在跑的分片抢着传。
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::bandwidth::TransferDirection;
+use crate::resumable_upload::{chunk_digest, split_gap, Gap};
+
+/// 交给平台的一条传输。
+///
+/// 下载：`GET url`，带上 `headers`，响应体是对象的 `[range_start, range_end)`，**追加**
+/// 到 `path` 末尾（`range_start == 0` 时覆盖）。`range_start == range_end` 表示已经拉满，
+/// 不用发请求，直接导入。
+///
+/// 上传：`PUT url`，带上 `headers`，请求体是 `path` 文件的 `[range_start, range_end)`。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackgroundTransfer {
+    /// 导入时原样传回。
+    pub transfer_id: String,
+    pub direction: TransferDirection,
+    /// 这条传输属于哪条消息（本地 id）。
+    pub message_id: u64,
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub range_start: u64,
+    /// 区间终点（不含）。服务端没给大小的下载为 `None`，一直拉到结尾。
+    pub range_end: Option<u64>,
+    pub path: PathBuf,
+    /// 下载：整个对象的大小 / SHA-256（十六进制）。上传：这一片的。
+    pub expected_size: Option<u64>,
+    pub expected_sha256: Option<String>,
+}
+
+pub(crate) fn download_transfer_id(message_id: u64) -> String {
+    format!("dl:{message_id}")
+}
+
+fn upload_transfer_id(local_message_id: &str, offset: u64) -> String {
+    format!("ul:{local_message_id}:{offset}")
+}
+
+/// 这个 id 是不是上传分片（否则是下载）。
+pub(crate) fn is_upload_transfer_id(transfer_id: &str) -> bool {
+    parse_upload_transfer_id(transfer_id).is_some()
+}
+
+/// `ul:{local_message_id}:{offset}` → `(local_message_id, offset)`。
+fn parse_upload_transfer_id(transfer_id: &str) -> Option<(&str, u64)> {
+    let rest = transfer_id.strip_prefix("ul:")?;
+    let (local_message_id, offset) = rest.rsplit_once(':')?;
+    Some((local_message_id, offset.parse().ok()?))
+}
+
+/// 一次分片上传交接时要用到的会话参数。
+#[derive(Debug, Clone)]
+pub(crate) struct UploadEndpoint {
+    /// `.../files`，分片端点是 `{base}/chunk?offset=N`。
+    pub base: String,
+    pub token: String,
+    /// 密文缓存：分片按 offset 从这里读。
+    pub sealed_cache: PathBuf,
+    pub total: u64,
+    /// 交给平台时每片的大小。平台的请求不受 `ChunkSizer` 调度，取计划里的上限，片数最少。
+    pub piece_size: u32,
+}
+
+/// 进程内正在跑的分片上传。
+///
+/// `State::upload_in_chunks` 进来时登记、出去时（[`UploadRegistration`] drop）注销，
+/// 期间随服务端确认更新缺口。交接中退出的不注销，留到最后一片导入或 reclaim。
+/// `PrivchatSdk` 持有同一份，导出 / 导入不必经过 actor。
+#[derive(Clone, Default)]
+pub(crate) struct UploadRegistry {
+    uploads: Arc<Mutex<HashMap<String, ActiveUpload>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+struct ActiveUpload {
+    /// 同一条消息先后两次上传（重试）各有一个号，旧的注销不能删掉新的。
+    upload_id: u64,
+    endpoint: UploadEndpoint,
+    missing: Vec<Gap>,
+    /// 已交给平台、还没导入的分片：offset → len。
+    outstanding: BTreeMap<u64, u64>,
+    /// 上传循环还持有登记。交接中退出后为 `false`，收回时连登记一起摘掉。
+    attached: bool,
+    control: Arc<UploadControl>,
+}
+
+#[derive(Default)]
+struct UploadControl {
+    handed_off: AtomicBool,
+}
+
+/// 上传循环持有的登记凭据。
+pub(crate) struct UploadRegistration {
+    registry: UploadRegistry,
+    local_message_id: String,
+    upload_id: u64,
+    control: Arc<UploadControl>,
+}
+
+impl UploadRegistration {
+    /// 以服务端确认过的为准更新剩余缺口。
+    pub(crate) fn set_missing(&self, gaps: &[Gap]) {
+        let mut uploads = self
+            .registry
+            .uploads
+            .lock()
+            .expect("upload registry poisoned");
+        if let Some(upload) = uploads.get_mut(&self.local_message_id) {
+            if upload.upload_id == self.upload_id {
+                upload.missing = gaps.to_vec();
+            }
+        }
+    }
+
+    /// 已交给平台：上传循环不再领新片，在路上的落地后退出。
+    pub(crate) fn is_handed_off(&self) -> bool {
+        self.control.handed_off.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for UploadRegistration {
+    fn drop(&mut self) {
+        let mut uploads = self
+            .registry
+            .uploads
+            .lock()
+            .expect("upload registry poisoned");
+        let Some(upload) = uploads
+            .get_mut(&self.local_message_id)
+            .filter(|upload| upload.upload_id == self.upload_id)
+        else {
+            return;
+        };
+        // 锁里再看一次：和收回抢的话，要么这里看到已收回、照常注销，要么收回看到
+        // 还挂着、只清标志，不会两边都不摘。
+        if upload.control.handed_off.load(Ordering::Acquire) {
+            upload.attached = false;
+        } else {
+            uploads.remove(&self.local_message_id);
+        }
+    }
+}
+
+impl UploadRegistry {
+    pub(crate) fn register(
+        &self,
+        local_message_id: &str,
+        endpoint: UploadEndpoint,
+        missing: &[Gap],
+    ) -> UploadRegistration {
+        let upload_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let control = Arc::new(UploadControl::default());
+        self.uploads
+            .lock()
+            .expect("upload registry poisoned")
+            .insert(
+                local_message_id.to_string(),
+                ActiveUpload {
+                    upload_id,
+                    endpoint,
+                    missing: missing.to_vec(),
+                    outstanding: BTreeMap::new(),
+                    attached: true,
+                    control: control.clone(),
+                },
+            );
+        UploadRegistration {
+            registry: self.clone(),
+            local_message_id: local_message_id.to_string(),
+            upload_id,
+            control,
+        }
+    }
+
+    /// 把全部进行中的上传交给平台，返回每一片的描述。
+    ///
+    /// 已经交出去的再导出一次，给出的是同一批还没导入的分片（幂等）。读密文缓存
+    /// 失败的上传不交接，留在进程内照常跑。没有缺口（只差 `complete`）的也不交接：
+    /// 平台帮不上忙，回到前台一个请求的事。
+    pub(crate) fn hand_off(&self) -> Vec<BackgroundTransfer> {
+        // 快照出来再读盘求摘要：大文件要读好一阵，不能一直占着锁。
+        let snapshot = {
+            let uploads = self.uploads.lock().expect("upload registry poisoned");
+            uploads
+                .iter()
+                .map(|(local_message_id, upload)| {
+                    let pieces = if upload.control.handed_off.load(Ordering::Acquire) {
+                        upload
+                            .outstanding
+                            .iter()
+                            .map(|(&offset, &len)| Gap { offset, len })
+                            .collect()
+                    } else {
+                        pieces_of(&upload.endpoint, &upload.missing)
+                    };
+                    (
+                        local_message_id.clone(),
+                        upload.upload_id,
+                        upload.endpoint.clone(),
+                        pieces,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut out = Vec::new();
+        for (local_message_id, upload_id, endpoint, pieces) in snapshot {
+            if pieces.is_empty() {
+                continue;
+            }
+            let descriptors = match describe_upload(&local_message_id, &endpoint, &pieces) {
+                Ok(descriptors) => descriptors,
+                Err(e) => {
+                    eprintln!(
+                        "[SDK.upload] 不交接 local_message_id={local_message_id}: 读密文缓存失败 {e}"
+                    );
+                    continue;
+                }
+            };
+            let mut uploads = self.uploads.lock().expect("upload registry poisoned");
+            // 读盘期间上传可能已经结束或换了一次。
+            let Some(upload) = uploads
+                .get_mut(&local_message_id)
+                .filter(|upload| upload.upload_id == upload_id)
+            else {
+                continue;
+            };
+            if !upload.control.handed_off.swap(true, Ordering::AcqRel) {
+                upload.outstanding = pieces.iter().map(|g| (g.offset, g.len)).collect();
+            }
+            out.extend(descriptors);
+        }
+        out
+    }
+
+    /// 这条消息的上传是不是交给了平台、还没收回。outbox drain 跳过这样的命令。
+    pub(crate) fn is_handed_off(&self, local_message_id: &str) -> bool {
+        self.uploads
+            .lock()
+            .expect("upload registry poisoned")
+            .get(local_message_id)
+            .is_some_and(|upload| upload.control.handed_off.load(Ordering::Acquire))
+    }
+
+    /// 平台报告一片传完。返回这个 id 认不认得。
+    ///
+    /// 最后一片导入后收回整条上传：下一次 drain 时上传循环去 `/status` 对齐——哪片
+    /// 其实没到，服务端的 `missing` 会说，进程内补传；都到了就 `complete`。
+    pub(crate) fn import(&self, transfer_id: &str) -> bool {
+        let Some((local_message_id, offset)) = parse_upload_transfer_id(transfer_id) else {
+            return false;
+        };
+        let mut uploads = self.uploads.lock().expect("upload registry poisoned");
+        let Some(upload) = uploads.get_mut(local_message_id) else {
+            return false;
+        };
+        if upload.outstanding.remove(&offset).is_none() {
+            return false;
+        }
+        if upload.outstanding.is_empty() {
+            let detached = reclaim(upload);
+            if detached {
+                uploads.remove(local_message_id);
+            }
+        }
+        true
+    }
+
+    /// 回到前台：全部收回进程内。返回收回了几条上传。
+    pub(crate) fn reclaim_all(&self) -> usize {
+        let mut uploads = self.uploads.lock().expect("upload registry poisoned");
+        let mut reclaimed = 0;
+        uploads.retain(|_, upload| {
+            if !upload.control.handed_off.load(Ordering::Acquire) {
+                return true;
+            }
+            reclaimed += 1;
+            !reclaim(upload)
+        });
+        reclaimed
+    }
+}
+
+/// 收回一条上传：剩下的分片不再等导入。返回上传循环是否已经退出（登记该摘掉了）；
+/// 没退出的照常往下传，在路上的落地后去 `/status` 对齐。
+fn reclaim(upload: &mut ActiveUpload) -> bool {
+    upload.outstanding.clear();
+    upload.control.handed_off.store(false, Ordering::Release);
+    !upload.attached
+}
+
+fn pieces_of(endpoint: &UploadEndpoint, missing: &[Gap]) -> Vec<Gap> {
+    missing
+        .iter()
+        .flat_map(|gap| split_gap(*gap, endpoint.piece_size, endpoint.total))
+        .collect()
+}
+
+fn describe_upload(
+    local_message_id: &str,
+    endpoint: &UploadEndpoint,
+    pieces: &[Gap],
+) -> std::io::Result<Vec<BackgroundTransfer>> {
+    use std::os::unix::fs::FileExt;
+    let file = std::fs::File::open(&endpoint.sealed_cache)?;
+    let message_id = local_message_id.parse().unwrap_or(0);
+    pieces
+        .iter()
+        .map(|piece| {
+            let mut buf = vec![0u8; piece.len as usize];
+            file.read_exact_at(&mut buf, piece.offset)?;
+            let digest = chunk_digest(&buf);
+            Ok(BackgroundTransfer {
+                transfer_id: upload_transfer_id(local_message_id, piece.offset),
+                direction: TransferDirection::Upload,
+                message_id,
+                method: "PUT".to_string(),
+                url: format!("{}/chunk?offset={}", endpoint.base, piece.offset),
+                headers: vec![
+                    ("X-Upload-Token".to_string(), endpoint.token.clone()),
+                    ("X-Chunk-SHA256".to_string(), digest.clone()),
+                ],
+                range_start: piece.offset,
+                range_end: Some(piece.offset + piece.len),
+                path: endpoint.sealed_cache.clone(),
+                expected_size: Some(piece.len),
+                expected_sha256: Some(digest),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tmp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "privchat-bg-transfer-{}-{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn endpoint(sealed_cache: PathBuf, total: u64) -> UploadEndpoint {
+        UploadEndpoint {
+            base: "https://files.example/files".to_string(),
+            token: "tok".to_string(),
+            sealed_cache,
+            total,
+            piece_size: 4,
+        }
+    }
+
+    #[test]
+    fn upload_pieces_cover_the_missing_gaps_with_their_own_digests() {
+        let dir = tmp_dir();
+        let cache = dir.join("body.sealed");
+        std::fs::write(&cache, b"0123456789").unwrap();
+        let registry = UploadRegistry::default();
+        let registration = registry.register(
+            "42",
+            endpoint(cache.clone(), 10),
+            &[Gap { offset: 2, len: 8 }],
+        );
+
+        let pieces = registry.hand_off();
+        assert!(registration.is_handed_off());
+        let ranges = pieces
+            .iter()
+            .map(|p| (p.range_start, p.range_end))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, vec![(2, Some(6)), (6, Some(10))]);
+        assert_eq!(pieces[0].url, "https://files.example/files/chunk?offset=2");
+        assert_eq!(pieces[0].message_id, 42);
+        assert_eq!(
+            pieces[1].expected_sha256.as_deref(),
+            Some(chunk_digest(b"6789").as_str())
+        );
+        assert!(pieces[1]
+            .headers
+            .contains(&("X-Chunk-SHA256".to_string(), chunk_digest(b"6789"))));
+
+        // 再导出一次是同一批。
+        assert_eq!(registry.hand_off(), pieces);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn the_upload_is_released_once_every_piece_is_imported() {
+        let dir = tmp_dir();
+        let cache = dir.join("body.sealed");
+        std::fs::write(&cache, b"01234567").unwrap();
+        let registry = UploadRegistry::default();
+        let registration = registry.register("7", endpoint(cache, 8), &[Gap { offset: 0, len: 8 }]);
+        let pieces = registry.hand_off();
+        assert_eq!(pieces.len(), 2);
+
+        // 交接后上传循环退出：登记留着，导入照认。
+        drop(registration);
+        assert!(registry.is_handed_off("7"));
+        assert!(registry.import(&pieces[0].transfer_id));
+        assert!(registry.is_handed_off("7"));
+        // 同一片导入两次不算数。
+        assert!(!registry.import(&pieces[0].transfer_id));
+        assert!(registry.import(&pieces[1].transfer_id));
+        // 最后一片导入后登记摘掉，drain 重新接手这条上传。
+        assert!(!registry.is_handed_off("7"));
+        assert!(registry.hand_off().is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn a_handoff_stays_put_until_it_is_reclaimed() {
+        let dir = tmp_dir();
+        let cache = dir.join("body.sealed");
+        std::fs::write(&cache, b"01234567").unwrap();
+        let registry = UploadRegistry::default();
+        let registration = registry.register("8", endpoint(cache, 8), &[Gap { offset: 0, len: 8 }]);
+        let pieces = registry.hand_off();
+        assert_eq!(pieces.len(), 2);
+        drop(registration);
+
+        // 宿主既不导入也不 reclaim：不会自己到点收回，再导出还是同一批。
+        assert_eq!(registry.hand_off(), pieces);
+        assert_eq!(registry.reclaim_all(), 1);
+        assert!(!registry.is_handed_off("8"));
+        // 迟到的导入认不得。
+        assert!(!registry.import(&pieces[0].transfer_id));
+        assert_eq!(registry.reclaim_all(), 0);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn reclaiming_a_running_upload_keeps_its_registration() {
+        let dir = tmp_dir();
+        let cache = dir.join("body.sealed");
+        std::fs::write(&cache, b"01234567").unwrap();
+        let registry = UploadRegistry::default();
+        let registration = registry.register("10", endpoint(cache, 8), &[Gap { offset: 0, len: 8 }]);
+        let pieces = registry.hand_off();
+        assert!(registration.is_handed_off());
+
+        // 在路上的分片还没落地就收回：上传循环接着跑，登记不动。
+        assert_eq!(registry.reclaim_all(), 1);
+        assert!(!registration.is_handed_off());
+        assert_eq!(registry.hand_off(), pieces);
+        drop(registration);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn only_upload_pieces_are_upload_transfer_ids() {
+        assert!(is_upload_transfer_id(&upload_transfer_id("5", 0)));
+        assert!(!is_upload_transfer_id(&download_transfer_id(5)));
+    }
+
+    #[test]
+    fn a_finished_upload_is_no_longer_exported_and_an_old_guard_keeps_the_new_one() {
+        let dir = tmp_dir();
+        let cache = dir.join("body.sealed");
+        std::fs::write(&cache, b"01234567").unwrap();
+        let registry = UploadRegistry::default();
+        let first = registry.register(
+            "9",
+            endpoint(cache.clone(), 8),
+            &[Gap { offset: 0, len: 8 }],
+        );
+        let second = registry.register("9", endpoint(cache, 8), &[Gap { offset: 4, len: 4 }]);
+        drop(first);
+        assert_eq!(registry.hand_off().len(), 1);
+        drop(second);
+        assert!(registry.hand_off().is_empty());
+        assert!(!registry.import("ul:9:4"));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn transfer_ids_round_trip() {
+        assert_eq!(
+            parse_upload_transfer_id(&upload_transfer_id("123", 65536)),
+            Some(("123", 65536))
+        );
+        assert_eq!(parse_upload_transfer_id(&download_transfer_id(123)), None);
+    }
+}
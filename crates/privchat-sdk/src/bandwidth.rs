@@ -0,0 +1,316 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 传输限速、省流量模式与按网络类型的字节记账。
+//!
+//! 上传（分片 / 整传）、`DownloadManager` 里的下载（主文件、缩略图、自动下载）和头像
+//! 下载共用**一个**令牌桶：要限的是这条链路的总吞吐，各管各的等于没限。速率按
+//! [`NetworkHint`] 分档，宿主报网络变化时换档（见 [`BandwidthLimiter::set_network`]）。
+//!
+//! 令牌允许欠账：一次取走的字节可以超过桶里剩的，调用方按欠额睡够再继续。这样分片上传
+//! 可以先付整片再发，下载按收到的每个 chunk 事后付，两种节奏都不用把请求拆小。
+//!
+//! 省流量模式（[`BandwidthPolicy::data_saver`]）只在计费网络下生效——蜂窝和**未知**，与
+//! 自动下载同一口径：接收端向服务端要小一档的缩略图，首屏扫补推迟到非计费网络或关掉省流量之后。
+//!
+//! 配置只在内存里，和网络提示一样由宿主在启动时推一次；记账从本次运行开始算，不落盘。
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::NetworkHint;
+
+/// 传输方向，记账用。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferDirection {
+    Upload,
+    Download,
+}
+
+/// 各网络类型下的速率上限（字节/秒），0 = 不限。默认全部不限、省流量关。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BandwidthPolicy {
+    pub wifi_bytes_per_sec: u64,
+    pub ethernet_bytes_per_sec: u64,
+    pub cellular_bytes_per_sec: u64,
+    /// 宿主没报网络类型时用这一档。
+    pub unknown_bytes_per_sec: u64,
+    pub data_saver: bool,
+}
+
+impl BandwidthPolicy {
+    /// `network` 下的速率上限，0 = 不限。离线时没有传输可限。
+    pub fn rate_for(&self, network: NetworkHint) -> u64 {
+        match network {
+            NetworkHint::Wifi => self.wifi_bytes_per_sec,
+            NetworkHint::Ethernet => self.ethernet_bytes_per_sec,
+            NetworkHint::Cellular => self.cellular_bytes_per_sec,
+            NetworkHint::Unknown => self.unknown_bytes_per_sec,
+            NetworkHint::Offline => 0,
+        }
+    }
+}
+
+/// 某一种网络类型上的累计字节。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkTransferBytes {
+    pub network: NetworkHint,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+const NETWORKS: [NetworkHint; 5] = [
+    NetworkHint::Unknown,
+    NetworkHint::Offline,
+    NetworkHint::Wifi,
+    NetworkHint::Cellular,
+    NetworkHint::Ethernet,
+];
+
+fn network_index(network: NetworkHint) -> usize {
+    match network {
+        NetworkHint::Unknown => 0,
+        NetworkHint::Offline => 1,
+        NetworkHint::Wifi => 2,
+        NetworkHint::Cellular => 3,
+        NetworkHint::Ethernet => 4,
+    }
+}
+
+/// 令牌桶。`tokens` 可以是负数：那是欠账，取的人按欠额睡。
+#[derive(Debug)]
+struct TokenBucket {
+    /// 字节/秒，0 = 不限。
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(now: Instant) -> Self {
+        Self {
+            rate: 0,
+            tokens: 0.0,
+            last: now,
+        }
+    }
+
+    /// 换档时清零：旧档的欠账不带进新档（从蜂窝切到 Wi-Fi 不该还在还蜂窝的账），
+    /// 旧档攒下的余额也不带（否则一换档就是一次突发）。
+    fn set_rate(&mut self, rate: u64, now: Instant) {
+        if rate != self.rate {
+            self.rate = rate;
+            self.tokens = 0.0;
+            self.last = now;
+        }
+    }
+
+    /// 记一笔 `bytes`，返回调用方要等多久才算付清。桶容量是一秒的量：闲置再久，
+    /// 突发也不超过一秒。
+    fn take(&mut self, bytes: u64, now: Instant) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        let rate = self.rate as f64;
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    policy: BandwidthPolicy,
+    network: NetworkHint,
+    bucket: TokenBucket,
+    /// `[上传, 下载]`，按 [`network_index`] 排。
+    bytes: [[u64; 2]; NETWORKS.len()],
+}
+
+/// 共享限速器。Clone 共享同一个桶。
+#[derive(Debug, Clone)]
+pub(crate) struct BandwidthLimiter {
+    inner: Arc<Mutex<LimiterState>>,
+}
+
+impl Default for BandwidthLimiter {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(LimiterState {
+                policy: BandwidthPolicy::default(),
+                network: NetworkHint::Unknown,
+                bucket: TokenBucket::new(Instant::now()),
+                bytes: [[0; 2]; NETWORKS.len()],
+            })),
+        }
+    }
+}
+
+impl BandwidthLimiter {
+    fn lock(&self) -> std::sync::MutexGuard<'_, LimiterState> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn policy(&self) -> BandwidthPolicy {
+        self.lock().policy
+    }
+
+    pub(crate) fn set_policy(&self, policy: BandwidthPolicy) {
+        let mut state = self.lock();
+        state.policy = policy;
+        let rate = policy.rate_for(state.network);
+        state.bucket.set_rate(rate, Instant::now());
+    }
+
+    /// actor 里网络提示一变就调。
+    pub(crate) fn set_network(&self, network: NetworkHint) {
+        let mut state = self.lock();
+        state.network = network;
+        let rate = state.policy.rate_for(network);
+        state.bucket.set_rate(rate, Instant::now());
+    }
+
+    /// 省流量此刻是否生效：开了，且当前是计费网络。
+    pub(crate) fn data_saver_active(&self) -> bool {
+        let state = self.lock();
+        state.policy.data_saver && !state.network.is_unmetered()
+    }
+
+    /// 记账并按当前档位限速。上传在发出之前调（先付），下载每收到一块调（后付）。
+    pub(crate) async fn acquire(&self, direction: TransferDirection, bytes: u64) {
+        let column = match direction {
+            TransferDirection::Upload => 0,
+            TransferDirection::Download => 1,
+        };
+        let wait = {
+            let mut state = self.lock();
+            let row = network_index(state.network);
+            let total = &mut state.bytes[row][column];
+            *total = total.saturating_add(bytes);
+            state.bucket.take(bytes, Instant::now())
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// 读完整个响应体，每收到一个 chunk 付一次账。限速落在读的过程中：
+    /// 先 `resp.bytes()` 整份读完再补一次 acquire，字节早已按线速灌进来，等于没限。
+    pub(crate) async fn read_body(&self, mut resp: reqwest::Response) -> reqwest::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            self.acquire(TransferDirection::Download, chunk.len() as u64)
+                .await;
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    /// 本次运行以来各网络类型上的字节数；一个字节都没有的网络类型不列。
+    pub(crate) fn bytes_by_network(&self) -> Vec<NetworkTransferBytes> {
+        let state = self.lock();
+        NETWORKS
+            .iter()
+            .zip(state.bytes.iter())
+            .filter(|(_, [up, down])| *up > 0 || *down > 0)
+            .map(|(network, [up, down])| NetworkTransferBytes {
+                network: *network,
+                uploaded: *up,
+                downloaded: *down,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_unlimited_bucket_never_waits() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(now);
+        assert_eq!(bucket.take(u64::MAX / 2, now), Duration::ZERO);
+    }
+
+    #[test]
+    fn debt_is_paid_off_at_the_configured_rate() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(now);
+        bucket.set_rate(1000, now);
+        // 空桶一次取 2000：欠两秒。
+        assert_eq!(bucket.take(2000, now), Duration::from_secs(2));
+        // 两秒之后账还清，再取 500 又欠半秒。
+        let later = now + Duration::from_secs(2);
+        assert_eq!(bucket.take(500, later), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn idle_time_buys_at_most_one_second_of_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(now);
+        bucket.set_rate(1000, now);
+        let later = now + Duration::from_secs(60);
+        assert_eq!(bucket.take(1000, later), Duration::ZERO);
+        assert_eq!(bucket.take(1000, later), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn switching_rate_forgives_the_old_debt() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(now);
+        bucket.set_rate(100, now);
+        assert_eq!(bucket.take(10_000, now), Duration::from_secs(100));
+        bucket.set_rate(10_000, now);
+        assert_eq!(bucket.take(5_000, now), Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn bytes_are_booked_under_the_network_they_moved_on() {
+        let limiter = BandwidthLimiter::default();
+        limiter.set_network(NetworkHint::Cellular);
+        limiter.acquire(TransferDirection::Download, 300).await;
+        limiter.acquire(TransferDirection::Upload, 20).await;
+        limiter.set_network(NetworkHint::Wifi);
+        limiter.acquire(TransferDirection::Download, 7).await;
+        assert_eq!(
+            limiter.bytes_by_network(),
+            vec![
+                NetworkTransferBytes {
+                    network: NetworkHint::Wifi,
+                    uploaded: 0,
+                    downloaded: 7,
+                },
+                NetworkTransferBytes {
+                    network: NetworkHint::Cellular,
+                    uploaded: 20,
+                    downloaded: 300,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn data_saver_only_applies_on_metered_networks() {
+        let limiter = BandwidthLimiter::default();
+        limiter.set_policy(BandwidthPolicy {
+            data_saver: true,
+            ..BandwidthPolicy::default()
+        });
+        assert!(limiter.data_saver_active(), "未知网络按计费算");
+        limiter.set_network(NetworkHint::Wifi);
+        assert!(!limiter.data_saver_active());
+        limiter.set_network(NetworkHint::Cellular);
+        assert!(limiter.data_saver_active());
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 会话列表查询：筛选、排序、keyset 分页，以及用户自建的会话文件夹。
+//!
+//! `list_channels(limit, offset)` 只有一种排序，筛选（只看未读、收藏、某个 tag …）原来都在
+//! FFI 里把整页拉上来再在内存里过滤：分页和筛选互相打架（第 2 页过滤完可能是空的），
+//! 也没法做「未读优先」。这里只定义查询的形状，SQL 在 `local_store.rs`。
+//!
+//! 会话偏好（`favourite` / `low_priority` / `notification_mode` / `tags`）的权威副本仍是账号
+//! kv；SQLite 里的 `channel_pref` / `channel_pref_tag` 是它的镜像，写偏好时同步写入，
+//! 首次查询前整体回填一次，只为让筛选能在一条 SQL 里完成。
+//!
+//! 分页用 keyset 而不是 offset：新消息会把会话顶到前面，offset 翻页会重复或漏掉会话；
+//! 游标记住上一页最后一行的排序键，下一页从它之后继续。
+
+use serde::{Deserialize, Serialize};
+
+use crate::StoredChannel;
+
+/// 隐藏（`channel.is_deleted`）会话是否参与查询。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChannelVisibility {
+    /// 只看未隐藏的，零消息私聊同样不出现（与 `list_channels` 一致）。
+    #[default]
+    Visible,
+    /// 只看被隐藏的。
+    Hidden,
+    /// 都看。
+    All,
+}
+
+/// 会话列表筛选条件。各条件之间是「与」；`None` / `false` 表示不按该项筛选。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelListFilter {
+    #[serde(default)]
+    pub unread_only: bool,
+    /// 只看有未读 @ 的会话。
+    #[serde(default)]
+    pub mentions_only: bool,
+    #[serde(default)]
+    pub favourite: Option<bool>,
+    #[serde(default)]
+    pub low_priority: Option<bool>,
+    #[serde(default)]
+    pub tag: Option<String>,
+    #[serde(default)]
+    pub channel_type: Option<i32>,
+    #[serde(default)]
+    pub visibility: ChannelVisibility,
+    /// 按实际的静音状态筛：`channel.mute` 与会话通知模式合起来算，规则同
+    /// [`crate::unread_badge`]。
+    #[serde(default)]
+    pub muted: Option<bool>,
+    /// 在会话名、备注、最后一条消息预览里做不区分大小写的子串匹配。
+    #[serde(default)]
+    pub keyword: Option<String>,
+    /// 只看某个文件夹里的会话。文件夹自身的筛选条件里的这一项会被忽略。
+    #[serde(default)]
+    pub folder_id: Option<u64>,
+}
+
+impl ChannelListFilter {
+    /// 没有任何筛选条件（文件夹用它区分「只有手选会话」和「按条件收录」）。
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// 排序键。置顶（`top`）是否优先由 [`ChannelListQuery::pinned_first`] 决定。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChannelSortKey {
+    /// 最后一条消息的时间，新的在前。
+    #[default]
+    LastActivity,
+    /// 未读数多的在前，同未读数再按最后活动时间。
+    Unread,
+}
+
+/// 一页的游标：上一页最后一行的排序键。对宿主是不透明的，原样传回即可。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelListCursor {
+    pub top: i32,
+    pub unread_count: i32,
+    pub last_msg_timestamp: i64,
+    pub channel_id: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelListQuery {
+    pub filter: ChannelListFilter,
+    pub sort: ChannelSortKey,
+    pub pinned_first: bool,
+    /// 每页条数，取值范围 1..=500。
+    pub limit: u32,
+    /// `None` 取第一页；之后传上一页的 [`ChannelListPage::next_cursor`]。
+    pub after: Option<ChannelListCursor>,
+}
+
+impl Default for ChannelListQuery {
+    fn default() -> Self {
+        Self {
+            filter: ChannelListFilter::default(),
+            sort: ChannelSortKey::default(),
+            pinned_first: true,
+            limit: 50,
+            after: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelListPage {
+    pub channels: Vec<StoredChannel>,
+    /// 还有下一页时为 `Some`。
+    pub next_cursor: Option<ChannelListCursor>,
+}
+
+/// 用户自建的会话文件夹，按账号存在本地库里，不上服务端。
+///
+/// 文件夹收录 `channels` 里手选的会话，外加（`filter` 非空时）所有满足 `filter` 的会话。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelFolder {
+    pub folder_id: u64,
+    pub name: String,
+    pub sort_order: i32,
+    pub filter: ChannelListFilter,
+    /// `(channel_id, channel_type)`。
+    pub channels: Vec<(u64, i32)>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// 新建（`folder_id` 为 `None`）或整体覆盖一个文件夹。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelFolderInput {
+    pub folder_id: Option<u64>,
+    pub name: String,
+    pub sort_order: i32,
+    pub filter: ChannelListFilter,
+    pub channels: Vec<(u64, i32)>,
+}
+
+/// 写入 `channel_pref` 镜像的一行。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ChannelPrefRow {
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub notification_mode: i32,
+    pub favourite: bool,
+    pub low_priority: bool,
+    pub tags: Vec<String>,
+}
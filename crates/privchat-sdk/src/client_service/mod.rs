@@ -0,0 +1,461 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 高层 facade（`docs/CLIENT_SERVICE_FACADE_SPEC.md`）。
+//!
+//! FFI、`privchatd`、控制 RPC 都是它的兄弟消费方：凡是 v1 覆盖到的操作，它们都走这里，
+//! 不再各自拼 `PrivchatSdk` 的调用。**组合逻辑只许有一份**（spec §4）——`logout` 的
+//! 「远端尽力注销 + 清凭证 + 断开」、`mark_read_to_pts` 的「RPC + 本地游标投影」、
+//! `send_text` 的「补 from_uid + 入队」都只在这里写。
+//!
+//! 错误统一是 [`crate::Error`]（spec §6 里的 `SdkError`），不另起错误枚举。
+//! 事件是轮询/流模型（spec §7）：游标归调用方，facade 不替任何人记「读到哪了」。
+
+pub mod types;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{
+    ConnectionState, Error, NewMessage, PrivchatConfig, PrivchatSdk, Result, SdkEvent,
+    SequencedSdkEvent, SessionSnapshot, StoredChannel, StoredMessage,
+};
+use privchat_protocol::rpc::routes;
+use privchat_protocol::rpc::{MessageStatusReadPtsRequest, MessageStatusReadPtsResponse};
+
+pub use crate::Error as SdkError;
+pub use types::*;
+
+/// `auth/logout` 没有参数；服务端只看连接上的身份。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AuthLogoutRequest {}
+
+/// [`ClientService::subscribe_events`] 的返回值。每次订阅都是独立的接收端。
+///
+/// 落后太多会收到 `Lagged(n)`：这时用 [`ClientService::events_since`] 按自己的游标补。
+pub struct EventStream {
+    rx: broadcast::Receiver<SdkEvent>,
+}
+
+impl EventStream {
+    pub async fn recv(&mut self) -> std::result::Result<SdkEvent, broadcast::error::RecvError> {
+        self.rx.recv().await
+    }
+
+    pub fn try_recv(&mut self) -> std::result::Result<SdkEvent, broadcast::error::TryRecvError> {
+        self.rx.try_recv()
+    }
+
+    /// 交出底层 receiver。给已经围绕 `broadcast::Receiver` 写好轮询逻辑的绑定层用。
+    pub fn into_receiver(self) -> broadcast::Receiver<SdkEvent> {
+        self.rx
+    }
+}
+
+/// 一个实例 = 一个本地账号上下文（spec §9）。克隆共享同一个 SDK。
+#[derive(Clone)]
+pub struct ClientService {
+    sdk: PrivchatSdk,
+    default_device_id: String,
+}
+
+impl ClientService {
+    /// 建实例，**不连接**（spec §11.1）。
+    pub fn new(config: ClientServiceConfig) -> Result<Self> {
+        if let Some(hint) = config.account_hint.as_deref() {
+            if hint != "default" {
+                return Err(Error::InvalidArgument(format!(
+                    "account_hint {hint:?} is not supported; v1 only accepts \"default\""
+                )));
+            }
+        }
+        if let Some(bad) = config
+            .endpoints
+            .iter()
+            .find(|e| e.host.trim().is_empty() || e.port == 0)
+        {
+            return Err(Error::InvalidArgument(format!(
+                "bad endpoint {}:{}",
+                bad.host, bad.port
+            )));
+        }
+        let sdk = PrivchatSdk::new(PrivchatConfig {
+            endpoints: config.endpoints,
+            connection_timeout_secs: u64::from(config.connection_timeout_secs),
+            data_dir: config.data_dir.to_string_lossy().to_string(),
+        });
+        Ok(Self {
+            sdk,
+            default_device_id: config.device_id,
+        })
+    }
+
+    /// 包一个已经存在的 SDK。绑定层还有大量未提升的直通方法要用同一个实例（spec §13），
+    /// 不能各建一个。
+    pub fn from_sdk(sdk: PrivchatSdk) -> Self {
+        Self {
+            sdk,
+            default_device_id: String::new(),
+        }
+    }
+
+    /// 底层 SDK。只给 v1 还没覆盖的操作用；覆盖到的必须走 facade（spec §4）。
+    pub fn sdk(&self) -> &PrivchatSdk {
+        &self.sdk
+    }
+
+    fn device_id_or_default(&self, device_id: String) -> String {
+        if device_id.is_empty() {
+            self.default_device_id.clone()
+        } else {
+            device_id
+        }
+    }
+
+    // ---- lifecycle ----
+
+    pub async fn connect(&self) -> Result<()> {
+        self.sdk.connect().await
+    }
+
+    pub async fn disconnect(&self) -> Result<()> {
+        self.sdk.disconnect().await
+    }
+
+    pub async fn connection_state(&self) -> Result<ConnectionState> {
+        self.sdk.connection_state().await
+    }
+
+    /// 终态：返回后这个实例只剩 drop。重复调用无害。
+    pub async fn shutdown(&self) -> Result<()> {
+        self.sdk.shutdown().await;
+        Ok(())
+    }
+
+    // ---- auth & session ----
+
+    /// 只拿凭证，不替调用方 `authenticate`（spec §11.4）。
+    pub async fn login(&self, req: LoginRequest) -> Result<LoginOutcome> {
+        let device_id = self.device_id_or_default(req.device_id);
+        self.sdk.login(req.username, req.password, device_id).await
+    }
+
+    pub async fn register(&self, req: RegisterRequest) -> Result<LoginOutcome> {
+        let device_id = self.device_id_or_default(req.device_id);
+        self.sdk.register(req.username, req.password, device_id).await
+    }
+
+    pub async fn authenticate(&self, req: AuthenticateRequest) -> Result<()> {
+        let device_id = self.device_id_or_default(req.device_id);
+        self.sdk.authenticate(req.user_id, req.token, device_id).await
+    }
+
+    /// 退出登录是**本地动作**：远端注销尽力而为，失败不拦着；清的是凭证不是本地库。
+    pub async fn logout(&self) -> Result<()> {
+        let _ = self
+            .sdk
+            .rpc_call_typed::<_, bool>(routes::auth::LOGOUT, &AuthLogoutRequest {})
+            .await;
+        let _ = self.sdk.clear_local_state().await;
+        self.sdk.disconnect().await
+    }
+
+    pub async fn session_snapshot(&self) -> Result<Option<SessionSnapshot>> {
+        self.sdk.session_snapshot().await
+    }
+
+    // ---- channels & messages (read) ----
+
+    pub async fn list_channels(&self, req: ListChannelsRequest) -> Result<Vec<StoredChannel>> {
+        self.sdk
+            .list_channels(req.limit as usize, req.offset as usize)
+            .await
+    }
+
+    pub async fn list_messages(&self, req: ListMessagesRequest) -> Result<Vec<StoredMessage>> {
+        let channel_type = match req.channel_type {
+            Some(t) => t,
+            None => self.resolve_channel_type(req.channel_id).await?,
+        };
+        match req.before_local_id {
+            Some(before) => {
+                self.sdk
+                    .list_messages_before(
+                        req.channel_id,
+                        channel_type,
+                        before,
+                        req.limit as usize,
+                        req.offset as usize,
+                    )
+                    .await
+            }
+            None => {
+                self.sdk
+                    .list_messages(
+                        req.channel_id,
+                        channel_type,
+                        req.limit as usize,
+                        req.offset as usize,
+                    )
+                    .await
+            }
+        }
+    }
+
+    pub async fn get_channel(&self, channel_id: u64) -> Result<Option<StoredChannel>> {
+        self.sdk.get_channel_by_id(channel_id).await
+    }
+
+    // ---- outbound ----
+
+    /// 写本地消息并入 outbox，立刻返回本地主键；发送与回执由队列负责。
+    /// `from_uid` 由 facade 按当前会话填，调用方不传（spec §11.11）。
+    pub async fn send_text(&self, req: SendTextRequest) -> Result<u64> {
+        if req.content.is_empty() {
+            return Err(Error::InvalidArgument("content is empty".to_string()));
+        }
+        let from_uid = self.current_user_id().await?;
+        let channel_type = match req.channel_type {
+            Some(t) => t,
+            None => self.resolve_channel_type(req.channel_id).await?,
+        };
+        self.sdk
+            .create_local_message_queued(
+                NewMessage {
+                    channel_id: req.channel_id,
+                    channel_type,
+                    from_uid,
+                    message_type: 0,
+                    content: req.content,
+                    searchable_word: String::new(),
+                    setting: 0,
+                    extra: String::new(),
+                    mime_type: None,
+                    media_downloaded: false,
+                    thumb_status: 0,
+                },
+                None,
+                "message",
+                Vec::new(),
+                None,
+            )
+            .await
+    }
+
+    /// 已读的唯一原语。返回服务端确认后的游标。
+    pub async fn mark_read_to_pts(&self, req: MarkReadToPtsRequest) -> Result<u64> {
+        if req.read_pts == 0 {
+            return Err(Error::InvalidArgument("read_pts must be > 0".to_string()));
+        }
+        let resp: MessageStatusReadPtsResponse = self
+            .sdk
+            .rpc_call_typed(
+                routes::message_status::READ_PTS,
+                &MessageStatusReadPtsRequest {
+                    channel_id: req.channel_id,
+                    read_pts: req.read_pts,
+                    last_read_message_id: None,
+                    client_visible_pts: None,
+                },
+            )
+            .await?;
+        // 投影失败不影响结果：服务端已经确认，下一轮同步会把本地游标补齐。
+        if let Ok(channel_type) = self.resolve_channel_type(req.channel_id).await {
+            let _ = self
+                .sdk
+                .project_channel_read_cursor(req.channel_id, channel_type, resp.last_read_pts)
+                .await;
+        }
+        Ok(resp.last_read_pts)
+    }
+
+    /// 「滚过了这条就算读到这条」的兼容入口：查出这条消息的 pts，再走 [`Self::mark_read_to_pts`]。
+    pub async fn mark_read_by_local_message_id(
+        &self,
+        req: MarkReadByLocalMessageIdRequest,
+    ) -> Result<u64> {
+        let read_pts = self
+            .pts_by_local_message_id(req.channel_id, req.local_message_id)
+            .await?;
+        self.mark_read_to_pts(MarkReadToPtsRequest {
+            channel_id: req.channel_id,
+            read_pts,
+        })
+        .await
+    }
+
+    /// 尽力而为；失败照样返回，给调用方记日志。
+    pub async fn send_typing(&self, req: SendTypingRequest) -> Result<()> {
+        let channel_type = match req.channel_type {
+            Some(t) => t,
+            None => self.resolve_channel_type(req.channel_id).await?,
+        };
+        self.sdk
+            .send_typing(req.channel_id, channel_type, req.active, req.action)
+            .await
+    }
+
+    // ---- events ----
+
+    pub fn subscribe_events(&self) -> EventStream {
+        EventStream {
+            rx: self.sdk.subscribe_events(),
+        }
+    }
+
+    /// SDK 已经产出的最新序号。**不是**任何调用方的已读游标。
+    pub fn last_event_sequence_id(&self) -> u64 {
+        self.sdk.last_event_sequence_id()
+    }
+
+    pub fn events_since(&self, cursor: u64, limit: u64) -> Vec<SequencedSdkEvent> {
+        self.sdk
+            .events_since(cursor, usize::try_from(limit).unwrap_or(usize::MAX))
+    }
+
+    // ---- internal helpers ----
+
+    async fn resolve_channel_type(&self, channel_id: u64) -> Result<i32> {
+        self.sdk
+            .get_channel_by_id(channel_id)
+            .await?
+            .map(|ch| ch.channel_type)
+            .ok_or_else(|| Error::NotFound(format!("channel {channel_id}")))
+    }
+
+    async fn current_user_id(&self) -> Result<u64> {
+        self.sdk
+            .session_snapshot()
+            .await?
+            .map(|snap| snap.user_id)
+            .ok_or_else(|| Error::SessionNotReady {
+                state: "no session; login/authenticate required".to_string(),
+            })
+    }
+
+    async fn pts_by_local_message_id(&self, channel_id: u64, local_message_id: u64) -> Result<u64> {
+        let message = self
+            .sdk
+            .get_message_by_id(local_message_id)
+            .await?
+            .filter(|m| m.channel_id == channel_id)
+            .ok_or_else(|| {
+                Error::NotFound(format!(
+                    "message {local_message_id} in channel {channel_id}"
+                ))
+            })?;
+        resolve_pts_field(&message).ok_or_else(|| {
+            Error::NotFound(format!("message {local_message_id} has no pts yet"))
+        })
+    }
+}
+
+/// 「server_message_id / local_message_id 都不是 pts」这条规则只在这里执行（spec §8）。
+/// 还没拿到 pts（发送中、老数据）就是 `None`，调用方不得拿别的 id 顶上。
+fn resolve_pts_field(message: &StoredMessage) -> Option<u64> {
+    message.pts.filter(|pts| *pts > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(pts: Option<u64>) -> StoredMessage {
+        StoredMessage {
+            message_id: 7,
+            server_message_id: Some(9_001),
+            local_message_id: Some(42),
+            channel_id: 1,
+            channel_type: 1,
+            from_uid: 2,
+            message_type: 0,
+            content: "hi".to_string(),
+            status: 0,
+            created_at: 0,
+            updated_at: 0,
+            extra: String::new(),
+            revoked: false,
+            revoked_by: None,
+            mime_type: None,
+            media_downloaded: false,
+            thumb_status: 0,
+            delivered: false,
+            pts,
+            revision_count: 0,
+            poll: None,
+            live_location: None,
+        }
+    }
+
+    #[test]
+    fn pts_never_falls_back_to_message_ids() {
+        assert_eq!(resolve_pts_field(&message(Some(15))), Some(15));
+        assert_eq!(resolve_pts_field(&message(Some(0))), None);
+        assert_eq!(resolve_pts_field(&message(None)), None);
+    }
+
+    #[test]
+    fn only_the_default_account_hint_is_accepted() {
+        let config = |hint: Option<&str>| ClientServiceConfig {
+            endpoints: PrivchatConfig::default().endpoints,
+            connection_timeout_secs: 1,
+            data_dir: std::env::temp_dir().join("privchat-client-service-hint"),
+            device_id: String::new(),
+            account_hint: hint.map(str::to_string),
+        };
+        assert!(matches!(
+            ClientService::new(config(Some("work"))),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn mark_read_by_local_id_reports_missing_messages_as_not_found() {
+        let dir = std::env::temp_dir().join(format!(
+            "privchat-client-service-{}-{}",
+            std::process::id(),
+            chrono::Utc::now().timestamp_micros()
+        ));
+        let store = crate::local_store::LocalStore::open_at(dir.clone()).expect("open store");
+        store
+            .save_login(
+                "10001",
+                &crate::LoginResult {
+                    user_id: 10001,
+                    token: "token".to_string(),
+                    device_id: "dev".to_string(),
+                    refresh_token: None,
+                    expires_at: 0,
+                },
+            )
+            .expect("seed login");
+        drop(store);
+        let service = ClientService::new(ClientServiceConfig {
+            endpoints: PrivchatConfig::default().endpoints,
+            connection_timeout_secs: 1,
+            data_dir: dir.clone(),
+            device_id: "dev".to_string(),
+            account_hint: Some("default".to_string()),
+        })
+        .expect("create service");
+        service
+            .sdk()
+            .set_current_uid("10001".to_string())
+            .await
+            .expect("select account");
+
+        let err = service
+            .mark_read_by_local_message_id(MarkReadByLocalMessageIdRequest {
+                channel_id: 1,
+                local_message_id: 404,
+            })
+            .await
+            .expect_err("missing message");
+        assert!(matches!(err, Error::NotFound(_)), "got {err:?}");
+
+        let _ = service.shutdown().await;
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
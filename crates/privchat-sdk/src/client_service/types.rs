@@ -0,0 +1,109 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! `ClientService` 的请求/响应类型（spec §5、§11）。
+//!
+//! 这些类型是 facade 自己的契约：按调用方想做的操作来定形状，**不**照搬
+//! `privchat_protocol::rpc::*` 的 wire 结构。Phase D 的控制 RPC 直接拿它们当 payload，
+//! 所以全部可 serde；新增字段一律 `Option` 或 `#[serde(default)]`（spec §12）。
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ServerEndpoint, TypingActionType};
+
+/// 登录/注册的结果与 SDK 的 [`crate::LoginResult`] 字段完全一致，按 spec §5 直接复用。
+pub use crate::LoginResult as LoginOutcome;
+
+/// spec §9。一个 `ClientService` 对应一个本地账号上下文。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientServiceConfig {
+    pub endpoints: Vec<ServerEndpoint>,
+    pub connection_timeout_secs: u32,
+    pub data_dir: PathBuf,
+    /// 请求里 `device_id` 留空时用它补。
+    #[serde(default)]
+    pub device_id: String,
+    /// 预留。v1 只接受 `None` 或 `"default"`。
+    #[serde(default)]
+    pub account_hint: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub device_id: String,
+}
+
+/// 与 [`LoginRequest`] 同形（spec §11.5）。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterRequest {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub device_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthenticateRequest {
+    pub user_id: u64,
+    pub token: String,
+    #[serde(default)]
+    pub device_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListChannelsRequest {
+    pub limit: u64,
+    #[serde(default)]
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListMessagesRequest {
+    pub channel_id: u64,
+    /// `None` 时由 facade 按本地会话行补。
+    #[serde(default)]
+    pub channel_type: Option<i32>,
+    pub limit: u64,
+    #[serde(default)]
+    pub offset: u64,
+    /// 翻页游标：只要本地主键小于它的消息，在 SQL 里过滤后再分页。
+    #[serde(default)]
+    pub before_local_id: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendTextRequest {
+    pub channel_id: u64,
+    #[serde(default)]
+    pub channel_type: Option<i32>,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarkReadToPtsRequest {
+    pub channel_id: u64,
+    pub read_pts: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarkReadByLocalMessageIdRequest {
+    pub channel_id: u64,
+    /// 本地库主键（`StoredMessage::message_id`）。**不是** pts。
+    pub local_message_id: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendTypingRequest {
+    pub channel_id: u64,
+    #[serde(default)]
+    pub channel_type: Option<i32>,
+    pub active: bool,
+    pub action: TypingActionType,
+}
@@ -33,6 +33,9 @@ const fn code(domain: u32, detail: u32) -> u32 {
 
 pub const TRANSPORT_FAILURE: u32 = code(domain::TRANSPORT, 1);
 pub const NETWORK_DISCONNECTED: u32 = code(domain::TRANSPORT, 2);
+/// 下载到的附件和服务端给的大小 / 摘要对不上（或解密认证失败），自动从头重下一次后
+/// 仍然不对。内容坏了，不是网络断了：再点一次下载之前宿主可以先提示。
+pub const MEDIA_INTEGRITY_MISMATCH: u32 = code(domain::TRANSPORT, 3);
 pub const AUTH_FAILURE: u32 = code(domain::AUTH, 1);
 pub const STORAGE_FAILURE: u32 = code(domain::STORAGE, 1);
 pub const SERIALIZATION_FAILURE: u32 = code(domain::SERIALIZATION, 1);
@@ -41,6 +44,10 @@ pub const INVALID_STATE: u32 = code(domain::STATE, 1);
 pub const ATTACHMENT_SOURCE_MISSING: u32 = code(domain::STATE, 2);
 /// 会话尚未鉴权（连接中/重连中）。可重试的时序状态，不是参数/配置错误。
 pub const SESSION_NOT_READY: u32 = code(domain::STATE, 3);
+/// 调用方要的资源本地不存在（见 `client_service::mark_read_by_local_message_id`）。
+pub const NOT_FOUND: u32 = code(domain::STATE, 4);
+/// 参数不合法：改参数，不要重试。
+pub const INVALID_ARGUMENT: u32 = code(domain::STATE, 5);
 pub const ACTOR_CLOSED: u32 = code(domain::ACTOR, 1);
 pub const SHUTDOWN: u32 = code(domain::SHUTDOWN, 1);
 pub const INTERNAL_UNKNOWN: u32 = code(domain::INTERNAL, 1);
@@ -55,6 +55,11 @@ use privchat_protocol::rpc::sync::{
     GetDifferenceResponse, GroupMemberSyncPayload, GroupSyncPayload, MessageStatusSyncPayload,
     MessageSyncPayload, ServerCommit, SyncEntityItem,
 };
+use privchat_protocol::rpc::{MessageReadListRequest, MessageReadListResponse};
+use privchat_protocol::rpc::{
+    StickerPackageDetailRequest, StickerPackageDetailResponse, StickerPackageListRequest,
+    StickerPackageListResponse,
+};
 use privchat_protocol::MessagePayloadEnvelope;
 use privchat_protocol::{
     decode_message, encode_message, AuthType, AuthorizationRequest, AuthorizationResponse,
@@ -82,22 +87,64 @@ const REPAIR_TIMEOUT_MS: u64 = 15_000;
 const REPAIR_BACKOFF_BASE_MS: u64 = 2_000;
 const REPAIR_BACKOFF_MAX_SHIFT: u32 = 6;
 
+pub mod account_manager;
 pub mod attachment_crypto;
+pub mod auto_download;
 mod avatar_cache;
+pub mod background_transfer;
+pub mod bandwidth;
 pub mod canonical_inbound;
+pub mod channel_query;
+pub mod client_service;
 pub mod error_codes;
+pub mod live_location;
+pub mod live_query;
 mod local_store;
+pub mod media_blob;
 pub mod media_download;
 pub mod media_store;
+pub mod media_stream;
+pub mod message_pin;
+pub mod metrics;
+pub mod poll;
+pub mod push_ingest;
 mod receive_pipeline;
 pub mod resumable_upload;
 mod runtime;
+pub mod sticker;
 mod storage_actor;
 mod sync_commit_applier;
 mod sync_coordinator;
 mod task;
+pub mod trace_recorder;
+pub mod unread_badge;
+pub mod video_processor;
+pub use account_manager::{
+    AccountEvent, AccountManager, AccountUnread, AccountsUnreadSummary, OpenedAccount,
+};
+pub use channel_query::{
+    ChannelFolder, ChannelFolderInput, ChannelListCursor, ChannelListFilter, ChannelListPage,
+    ChannelListQuery, ChannelSortKey, ChannelVisibility,
+};
+use live_location::{
+    LiveLocationCommand, LiveLocationSettle, LiveLocationUpdate, LiveLocationUpdateRequest,
+};
+pub use live_location::{
+    LiveLocationPosition, LiveLocationState, LiveLocationView, LIVE_LOCATION_MESSAGE_TYPE,
+};
+pub use live_query::{ListDiff, LiveQuery, LiveQueryCancel};
+use local_store::OutboxRpcCommand;
+pub use message_pin::PinnedMessage;
+use message_pin::{MessagePinCommand, RemotePin};
+pub use poll::{
+    PollDefinition, PollOptionView, PollTally, PollTallyUpdate, PollView, POLL_MESSAGE_TYPE,
+};
+use poll::{PollGetRequest, PollVoteCommand, PollVoteRequest};
+pub use push_ingest::{NotificationPrivacy, PushIngestor, PushNotification, PushPayload};
 use receive_pipeline::ReceivePipeline;
 use runtime::runtime_provider::RuntimeProvider;
+use sticker::{RemoteStickerInstall, StickerInstallCommand, StickerInstallRequest};
+pub use sticker::{Sticker, StickerPackage, STICKER_MESSAGE_TYPE};
 use storage_actor::StorageHandle;
 use sync_commit_applier::SyncCommitApplier;
 use sync_coordinator::SyncCoordinator;
@@ -106,6 +153,7 @@ pub use sync_coordinator::{
     CriticalFailureCode, Readiness, SyncPhase, SyncRunKind, SyncStateSnapshot,
 };
 use task::task_registry::TaskRegistry;
+pub use unread_badge::{UnreadAggregate, UnreadFolderTotal, UnreadTagTotal};
 
 /// 下载票据：下载前由 `file/get_url` 解析（file_id 路径），或由 legacy file_url 构造
 /// （`encryption_version=0, cek=None`）。DownloadManager / run_download 只认这个，不关心来源。
@@ -114,6 +162,23 @@ pub struct ResolvedFileDownload {
     pub url: String,
     pub encryption_version: i32,
     pub cek: Option<String>,
+    /// 票据对应的服务端 file_id；legacy 票据没有。下载完成后用它登记本地 blob 库的
+    /// file_id 索引，见 [`media_blob`]。
+    pub file_id: Option<u64>,
+    /// 服务端给出的存储对象大小 / SHA-256（小写 hex）。给了就在下载完成后校验，
+    /// 见 [`media_download`] 的完整性检查；没给（老服务端、legacy 票据）就不校验。
+    pub expected_size: Option<u64>,
+    pub expected_sha256: Option<String>,
+}
+
+/// `file/get_url` 响应里可选的存储对象校验信息。描述的是**存储对象本身**（v0 明文、
+/// v1/v2 密文），与上传时 `request_upload_token` 报的 `file_size` / `sha256` 同一口径。
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct StoredObjectDigest {
+    file_size: Option<u64>,
+    #[serde(alias = "file_hash")]
+    sha256: Option<String>,
 }
 
 impl ResolvedFileDownload {
@@ -123,7 +188,35 @@ impl ResolvedFileDownload {
             url,
             encryption_version: 0,
             cek: None,
+            file_id: None,
+            expected_size: None,
+            expected_sha256: None,
+        }
+    }
+
+    /// 由 `file/get_url` 的原始响应构造；`file_url` 为空返回 None。校验字段解不出来
+    /// 就当服务端没给，不影响下载本身。
+    fn from_get_url(
+        file_id: u64,
+        raw: serde_json::Value,
+    ) -> std::result::Result<Option<Self>, serde_json::Error> {
+        let digest: StoredObjectDigest =
+            serde_json::from_value(raw.clone()).unwrap_or_default();
+        let resp: FileGetUrlResponse = serde_json::from_value(raw)?;
+        if resp.file_url.trim().is_empty() {
+            return Ok(None);
         }
+        Ok(Some(Self {
+            url: resp.file_url,
+            encryption_version: resp.encryption_version,
+            cek: resp.cek,
+            file_id: Some(file_id),
+            expected_size: digest.file_size.filter(|size| *size > 0),
+            expected_sha256: digest
+                .sha256
+                .map(|sha| sha.trim().to_ascii_lowercase())
+                .filter(|sha| sha.len() == 64 && sha.bytes().all(|b| b.is_ascii_hexdigit())),
+        }))
     }
 }
 
@@ -356,7 +449,9 @@ fn default_device_name(os: &str) -> String {
     format!("privchat-{os}")
 }
 
-fn parse_server_url(url: &str) -> Option<ServerEndpoint> {
+/// `quic://` / `tcp://` / `ws://` / `wss://` 形式的地址 → [`ServerEndpoint`]。
+/// 缺端口时按 9001；前缀不认识返回 `None`。
+pub fn parse_server_url(url: &str) -> Option<ServerEndpoint> {
     if url.starts_with("quic://") {
         parse_url_parts(url, "quic://", TransportProtocol::Quic, false)
     } else if url.starts_with("tcp://") {
@@ -473,6 +568,11 @@ impl NetworkHint {
     fn is_online(self) -> bool {
         !matches!(self, NetworkHint::Offline)
     }
+
+    /// Wi-Fi / 以太网。蜂窝和未知都按计费算：宿主没报网络时宁可省着用。
+    pub(crate) fn is_unmetered(self) -> bool {
+        matches!(self, NetworkHint::Wifi | NetworkHint::Ethernet)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -481,6 +581,9 @@ pub enum MediaDownloadState {
     Downloading { bytes: u64, total: Option<u64> },
     Paused { bytes: u64, total: Option<u64> },
     Done { path: String },
+    /// 内容和服务端给的大小 / 摘要对不上时 `code` 是
+    /// [`error_codes::MEDIA_INTEGRITY_MISMATCH`]（已自动从头重下过一次）；
+    /// 其余是网络 / 本地 I/O 错误。
     Failed { code: u32, message: String },
 }
 
@@ -645,6 +748,25 @@ pub enum SdkEvent {
         server_message_id: u64,
         delivered_at: u64,
     },
+    /// 未读聚合（角标）变了。只在结果和上一次不同时发，宿主可以直接拿它刷 launcher 角标，
+    /// 不必每次 TimelineUpdated 都回头拉一遍会话列表。
+    BadgeChanged {
+        aggregate: UnreadAggregate,
+    },
+    /// 会话偏好（收藏、低优先级、通知模式、tag）写入成功。会话列表活查询据此重查。
+    ChannelPrefsChanged {
+        channel_id: u64,
+        channel_type: i32,
+    },
+    /// 会话的置顶列表变了（自己置顶 / 取消、服务端确认、同步、重拉列表）。
+    /// 宿主据此重读 `list_pinned_messages`。
+    PinnedMessagesChanged {
+        channel_id: u64,
+        channel_type: i32,
+    },
+    /// 本地表情目录变了（装 / 卸、排序、同步、拉列表或详情、预取完图、最近使用、收藏）。
+    /// 宿主据此重读 `list_sticker_packages` / `recent_stickers` / `favourite_stickers`。
+    StickerCatalogChanged,
     MediaDownloadStateChanged {
         message_id: u64,
         state: MediaDownloadState,
@@ -740,8 +862,17 @@ struct GroupSettingsCache {
     extra: serde_json::Map<String, serde_json::Value>,
 }
 
+const CHANNEL_PREFS_KEY_PREFIX: &str = "__channel_prefs__:";
+
 fn channel_prefs_key(channel_id: u64, channel_type: i32) -> String {
-    format!("__channel_prefs__:{channel_id}:{channel_type}")
+    format!("{CHANNEL_PREFS_KEY_PREFIX}{channel_id}:{channel_type}")
+}
+
+fn parse_channel_prefs_key(key: &str) -> Option<(u64, i32)> {
+    let (channel_id, channel_type) = key
+        .strip_prefix(CHANNEL_PREFS_KEY_PREFIX)?
+        .split_once(':')?;
+    Some((channel_id.parse().ok()?, channel_type.parse().ok()?))
 }
 
 fn group_settings_key(group_id: u64) -> String {
@@ -753,6 +884,22 @@ fn decode_channel_prefs(raw: Option<Vec<u8>>) -> ChannelPrefsState {
         .unwrap_or_default()
 }
 
+/// `channel_pref` 镜像里的一行（见 [`channel_query`]）。
+fn channel_pref_row(
+    channel_id: u64,
+    channel_type: i32,
+    state: &ChannelPrefsState,
+) -> channel_query::ChannelPrefRow {
+    channel_query::ChannelPrefRow {
+        channel_id,
+        channel_type,
+        notification_mode: state.notification_mode,
+        favourite: state.favourite,
+        low_priority: state.low_priority,
+        tags: state.tags.clone(),
+    }
+}
+
 fn decode_group_settings_cache(raw: Option<Vec<u8>>) -> GroupSettingsCache {
     raw.and_then(|b| serde_json::from_slice::<GroupSettingsCache>(&b).ok())
         .unwrap_or_default()
@@ -1006,8 +1153,9 @@ impl AttachmentUploadIo for LiveAttachmentUploadIo<'_> {
                 session,
                 &self.mime_type,
                 self.sealed_cache,
-                &payload.blob,
+                payload.size,
                 payload.cek_b64.clone(),
+                payload.encryption_version,
                 &self.local_message_id,
             )
             .await
@@ -1035,13 +1183,32 @@ impl AttachmentUploadIo for LiveAttachmentUploadIo<'_> {
                 &token.token,
                 &self.filename,
                 &self.mime_type,
-                payload.blob.clone(),
+                // 只有整包路径会到这里，大小在分片阈值以内，整份读进来没问题。
+                std::fs::read(self.sealed_cache)
+                    .map_err(|e| Error::Storage(format!("read sealed cache failed: {e}")))?,
                 payload.cek_b64.clone(),
+                payload.encryption_version,
             )
             .await
     }
 }
 
+/// 省流量生效时，接收端向服务端要的缩略图长边，见 [`ThumbnailUrlRequest`]。
+const DATA_SAVER_THUMBNAIL_MAX_EDGE: u32 = 200;
+
+/// 缩略图的 `file/get_url` 请求：协议的 [`FileGetUrlRequest`] 平铺一层，带上想要的长边。
+///
+/// 发送端生成的缩略图照旧是 320 长边，其他接收端不受某一台设备省流量的影响；
+/// 省流量生效的接收端要一份小一档的变体。认得 `max_edge` 的服务端回缩小后的票据
+/// （大小 / 摘要对应那份变体），不认得的忽略多出的字段，照常回原图。
+#[derive(Serialize)]
+struct ThumbnailUrlRequest {
+    #[serde(flatten)]
+    base: FileGetUrlRequest,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_edge: Option<u32>,
+}
+
 /// 超过这个大小走分片（RESUMABLE_UPLOAD_SPEC §2.4）。
 ///
 /// 阈值是客户端常量，不进协议：它只影响「这次拆不拆」，拆错了也只是效率问题。
@@ -1128,13 +1295,19 @@ trait AttachmentUploadIo {
     }
 }
 
-/// 一次封装的产物：要上传的密文、解它的 CEK、以及秒传按的摘要。
+/// 一次封装的产物：密文的大小、解它的 CEK、以及秒传按的摘要。
+///
+/// 密文本身只在封装缓存文件里（`seal_once` 的 `cache_path`），上传从那里读。
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct SealedPayload {
-    blob: Vec<u8>,
+    /// 密文字节数。
+    size: u64,
     cek_b64: String,
     /// 密文（不是明文）的 SHA-256：服务端按最终上传字节判重。
     sha256: String,
+    /// 密文的格式：1 = 整文件 GCM，2 = 分段。随 complete/upload 报给服务端，
+    /// 再经 `file/get_url` 交给接收端。
+    encryption_version: i32,
 }
 
 /// 封装缓存的伴生元数据：密文本身在旁边那个文件里。
@@ -1145,6 +1318,15 @@ struct SealedPayload {
 struct SealedCacheMeta {
     cek: String,
     sha256: String,
+    /// 分段格式之前写下的缓存没有这个字段，它们都是 v1。
+    #[serde(default = "SealedCacheMeta::legacy_encryption_version")]
+    encryption_version: i32,
+}
+
+impl SealedCacheMeta {
+    fn legacy_encryption_version() -> i32 {
+        crate::attachment_crypto::ENCRYPTION_VERSION_WHOLE
+    }
 }
 
 /// [`State::build_attachment_wire_content`] 的输入。
@@ -1165,6 +1347,8 @@ struct AttachmentWireInput<'a> {
     mime_type: &'a str,
     width: Option<u32>,
     height: Option<u32>,
+    /// 视频时长（秒），内置处理器探测到的。`extra` 里有的以 `extra` 为准。
+    duration: Option<u32>,
     message_type: i32,
     /// 本地行的 `extra`：Voice/Video 的时长等 typed metadata 从这里合并进来。
     extra: &'a str,
@@ -1891,6 +2075,49 @@ pub struct ContactCardMessageInput {
     pub options: StructuredSendOptions,
 }
 
+/// 表情消息只引用本地目录里的表情（包里的、最近使用的、收藏的），不上传文件。
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StickerMessageInput {
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub from_uid: u64,
+    pub sticker_id: String,
+    pub options: StructuredSendOptions,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PollMessageInput {
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub from_uid: u64,
+    pub question: String,
+    /// 2..=[`poll::MAX_POLL_OPTIONS`] 个互不相同的选项。
+    pub poll_options: Vec<String>,
+    pub multi_select: bool,
+    pub anonymous: bool,
+    /// 截止时间（毫秒）。
+    pub close_at: Option<i64>,
+    pub options: StructuredSendOptions,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LiveLocationMessageInput {
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub from_uid: u64,
+    /// 起点坐标。
+    pub latitude: f64,
+    pub longitude: f64,
+    pub coordinate_system: Option<String>,
+    /// 起点的地点名，作为消息正文（会话预览、搜索）；为空时正文是起点坐标。
+    #[serde(default)]
+    pub name: Option<String>,
+    /// 共享时长（毫秒），
+    /// [`live_location::MIN_LIVE_LOCATION_DURATION_MS`]..=[`live_location::MAX_LIVE_LOCATION_DURATION_MS`]。
+    pub duration_ms: i64,
+    pub options: StructuredSendOptions,
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct UpsertRemoteMessageInput {
     pub server_message_id: u64,
@@ -1928,7 +2155,7 @@ pub struct UpsertRemoteMessageResult {
     pub inserted_new: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct StoredMessage {
     pub message_id: u64,
     pub server_message_id: Option<u64>,
@@ -1954,6 +2181,15 @@ pub struct StoredMessage {
     pub delivered: bool,
     /// per-channel 消息序号（用于 read cursor 投影: pts <= peer_read_pts）
     pub pts: Option<u64>,
+    /// 编辑次数（message_revision 中 version > 0 的行数），0 = 未编辑
+    #[serde(default)]
+    pub revision_count: u32,
+    /// 投票消息的本地票数与自己的选择；不是投票或还没有票数时为 None
+    #[serde(default)]
+    pub poll: Option<poll::PollTally>,
+    /// 实时位置消息的最新位置与结束状态；不是实时位置或还没有更新时为 None
+    #[serde(default)]
+    pub live_location: Option<LiveLocationState>,
 }
 
 /// 出站队列可否排空的**纯判据**。
@@ -2112,7 +2348,7 @@ pub struct UpsertChannelInput {
     pub peer_user_id: Option<u64>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct StoredChannel {
     pub channel_id: u64,
     pub channel_type: i32,
@@ -2195,6 +2431,56 @@ pub struct StoredMessageExtra {
     pub delivered_at: u64,
 }
 
+/// 消息的一个编辑版本（message_revision 表一行）。version 0 是首次编辑前的原文。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageRevision {
+    pub message_id: u64,
+    pub version: u64,
+    pub content: String,
+    /// 秒，与 `StoredMessageExtra::edited_at` 同单位
+    pub edited_at: i32,
+    pub editor_uid: Option<u64>,
+    /// 本地编辑、服务端还没确认；此时 version 是临时的，确认后以服务端分配的为准
+    #[serde(default)]
+    pub pending: bool,
+}
+
+/// 应用一次服务端编辑的输入。`version` 为 None 时取「已有最大版本 + 1」（服务端未
+/// 下发版本号的老推送），与最新一版内容、时间都相同则视为重复同步；给了版本号则必须
+/// 大于已有最大版本，否则整条丢弃。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageRevisionInput {
+    pub version: Option<u64>,
+    pub content: String,
+    pub edited_at: i32,
+    pub editor_uid: Option<u64>,
+}
+
+/// 某个成员在会话里的已读游标（member_read_cursor 表一行）。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemberReadCursor {
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub reader_id: u64,
+    pub read_pts: u64,
+    /// 本地最近一次推进游标的时间（毫秒），不是服务端的阅读时间
+    pub updated_at: i64,
+}
+
+/// 一条消息的已读情况，全部由本地游标算出。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageReadReceipts {
+    pub message_id: u64,
+    pub channel_id: u64,
+    pub channel_type: i32,
+    /// 消息的 pts；未发出的消息为 0，此时没有人能读到它
+    pub pts: u64,
+    /// 游标已越过本消息的成员（不含发送者）
+    pub readers: Vec<MemberReadCursor>,
+    /// 群成员里本地还没有游标的人（不含发送者和自己）；非空时才值得发 RPC 回填
+    pub unknown_member_ids: Vec<u64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpsertUserInput {
     pub user_id: u64,
@@ -2587,6 +2873,12 @@ pub enum Error {
     /// `current: New` 这种内部状态名甩给用户。
     #[error("session not ready (current: {state})")]
     SessionNotReady { state: String },
+    /// 调用方要的东西本地没有（消息不存在、消息还没有 pts……）。见 `client_service`。
+    #[error("not found: {0}")]
+    NotFound(String),
+    /// 参数本身不合法。与 [`Error::InvalidState`] 区分：换个时机重试也没用，要改参数。
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
 }
 
 /// 认证错误分层语义（spec: TOKEN_REFRESH_SPEC §2.1）。
@@ -2709,6 +3001,8 @@ impl Error {
             Error::InvalidState(_) => error_codes::INVALID_STATE,
             Error::AttachmentSourceMissing { .. } => error_codes::ATTACHMENT_SOURCE_MISSING,
             Error::SessionNotReady { .. } => error_codes::SESSION_NOT_READY,
+            Error::NotFound(_) => error_codes::NOT_FOUND,
+            Error::InvalidArgument(_) => error_codes::INVALID_ARGUMENT,
             Error::Server { code, .. } => *code,
         }
     }
@@ -2730,6 +3024,8 @@ impl Error {
             // 专用码：UI 据此提示「源文件已不存在，请重新选择」，而不是笼统的失败。
             Error::AttachmentSourceMissing { .. } => ErrorCode::AttachmentSourceMissing as u32,
             Error::SessionNotReady { .. } => ErrorCode::SessionNotReady as u32,
+            Error::NotFound(_) => ErrorCode::ResourceNotFound as u32,
+            Error::InvalidArgument(_) => ErrorCode::InvalidParams as u32,
             Error::Server { code, .. } => *code,
         }
     }
@@ -2952,6 +3248,12 @@ enum Command {
         message_id: u64,
         resp: oneshot::Sender<Result<Option<StoredMessage>>>,
     },
+    GetMessageIdByServerMessageId {
+        /// `None` 时不限会话（server_message_id 全局唯一）。
+        channel: Option<(u64, i32)>,
+        server_message_id: u64,
+        resp: oneshot::Sender<Result<Option<u64>>>,
+    },
     ListMessages {
         channel_id: u64,
         channel_type: i32,
@@ -2959,6 +3261,14 @@ enum Command {
         offset: usize,
         resp: oneshot::Sender<Result<Vec<StoredMessage>>>,
     },
+    ListMessagesBefore {
+        channel_id: u64,
+        channel_type: i32,
+        before_local_id: u64,
+        limit: usize,
+        offset: usize,
+        resp: oneshot::Sender<Result<Vec<StoredMessage>>>,
+    },
     ListMessagesAround {
         channel_id: u64,
         channel_type: i32,
@@ -3061,6 +3371,11 @@ enum Command {
         outcome: ThumbnailDownloadOutcome,
         resp: oneshot::Sender<()>,
     },
+    CompleteAutoDownload {
+        key: media_download::MediaTaskKey,
+        result: std::result::Result<String, String>,
+        resp: oneshot::Sender<()>,
+    },
     FinalizeLocalAttachment {
         message_id: u64,
         content: String,
@@ -3118,6 +3433,99 @@ enum Command {
         message_id: u64,
         resp: oneshot::Sender<Result<Option<StoredMessageExtra>>>,
     },
+    DiscardPendingMessageEdit {
+        message_id: u64,
+        resp: oneshot::Sender<Result<bool>>,
+    },
+    ListMessageRevisions {
+        message_id: u64,
+        resp: oneshot::Sender<Result<Vec<MessageRevision>>>,
+    },
+    VotePoll {
+        message_id: u64,
+        option_indexes: Vec<u32>,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    PinMessage {
+        message_id: u64,
+        pinned: bool,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    ListPinnedMessages {
+        channel_id: u64,
+        channel_type: i32,
+        resp: oneshot::Sender<Result<Vec<PinnedMessage>>>,
+    },
+    RefreshPinnedMessages {
+        channel_id: u64,
+        channel_type: i32,
+        resp: oneshot::Sender<Result<bool>>,
+    },
+    ListStickerPackages {
+        installed_only: bool,
+        resp: oneshot::Sender<Result<Vec<StickerPackage>>>,
+    },
+    RefreshStickerPackages {
+        resp: oneshot::Sender<Result<Vec<StickerPackage>>>,
+    },
+    RefreshStickerPackage {
+        package_id: String,
+        resp: oneshot::Sender<Result<StickerPackage>>,
+    },
+    InstallStickerPackage {
+        package_id: String,
+        installed: bool,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    ReorderStickerPackages {
+        package_ids: Vec<String>,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    ListRecentStickers {
+        resp: oneshot::Sender<Result<Vec<Sticker>>>,
+    },
+    ListFavouriteStickers {
+        resp: oneshot::Sender<Result<Vec<Sticker>>>,
+    },
+    FavouriteSticker {
+        sticker: Sticker,
+        resp: oneshot::Sender<Result<bool>>,
+    },
+    UnfavouriteSticker {
+        sticker_id: String,
+        resp: oneshot::Sender<Result<bool>>,
+    },
+    PrepareStickerSend {
+        sticker_id: String,
+        resp: oneshot::Sender<Result<Sticker>>,
+    },
+    CompleteStickerPackageLoad {
+        key: media_download::MediaTaskKey,
+        package: StickerPackage,
+    },
+    CompleteStickerPrefetch {
+        key: media_download::MediaTaskKey,
+    },
+    RefreshPoll {
+        message_id: u64,
+        resp: oneshot::Sender<Result<bool>>,
+    },
+    /// `position` 为空表示结束共享。
+    UpdateLiveLocation {
+        message_id: u64,
+        position: Option<LiveLocationPosition>,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    ListMemberReadCursors {
+        channel_id: u64,
+        channel_type: i32,
+        resp: oneshot::Sender<Result<Vec<MemberReadCursor>>>,
+    },
+    MessageReadReceipts {
+        message_id: u64,
+        backfill: bool,
+        resp: oneshot::Sender<Result<MessageReadReceipts>>,
+    },
     ProjectChannelReadCursor {
         channel_id: u64,
         channel_type: i32,
@@ -3138,6 +3546,24 @@ enum Command {
         exclude_muted: bool,
         resp: oneshot::Sender<Result<i32>>,
     },
+    GetUnreadAggregate {
+        resp: oneshot::Sender<Result<UnreadAggregate>>,
+    },
+    QueryChannels {
+        query: ChannelListQuery,
+        resp: oneshot::Sender<Result<ChannelListPage>>,
+    },
+    UpsertChannelFolder {
+        input: ChannelFolderInput,
+        resp: oneshot::Sender<Result<ChannelFolder>>,
+    },
+    DeleteChannelFolder {
+        folder_id: u64,
+        resp: oneshot::Sender<Result<bool>>,
+    },
+    ListChannelFolders {
+        resp: oneshot::Sender<Result<Vec<ChannelFolder>>>,
+    },
     UpsertUser {
         input: UpsertUserInput,
         resp: oneshot::Sender<Result<()>>,
@@ -3336,11 +3762,229 @@ enum Command {
     WipeCurrentUserFull {
         resp: oneshot::Sender<Result<()>>,
     },
+    /// 开始录制 trace（见 [`trace_recorder`]）。返回解密用的 key（base64url）。
+    StartTraceRecording {
+        path: PathBuf,
+        resp: oneshot::Sender<Result<String>>,
+    },
+    /// 停止录制并刷盘。返回写入的记录数；没有在录时为 0。
+    StopTraceRecording {
+        resp: oneshot::Sender<Result<u64>>,
+    },
+    /// 回放前选定账号。只给从未建立会话的全新实例用：不要求本地已有该账号的登录记录。
+    BeginTraceReplay {
+        uid: String,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    /// 回放一条入站帧。与 `InboundFrame` 走同一条 `handle_inbound_frame`，但不比对 epoch，
+    /// 并且等处理完才应答——回放需要逐条同步。
+    ReplayInboundFrame {
+        biz_type: u8,
+        data: Vec<u8>,
+        resp: oneshot::Sender<Result<usize>>,
+    },
+    /// 采样 outbox 深度/最老等待时长写进指标注册表（见 [`PrivchatSdk::metrics_snapshot`]）。
+    SampleOutboxMetrics {
+        resp: oneshot::Sender<Result<()>>,
+    },
     Shutdown {
         resp: oneshot::Sender<()>,
     },
 }
 
+impl Command {
+    /// 命令名，只供 trace 录制用。**刻意不带参数**：参数里有密码、token 和消息正文。
+    fn trace_name(&self) -> &'static str {
+        match self {
+            Command::Connect { .. } => "Connect",
+            Command::Disconnect { .. } => "Disconnect",
+            Command::IsConnected { .. } => "IsConnected",
+            Command::GetConnectionState { .. } => "GetConnectionState",
+            Command::GetSessionStatus { .. } => "GetSessionStatus",
+            #[cfg(test)]
+            Command::SetSessionStateForTest { .. } => "SetSessionStateForTest",
+            Command::GetLastTerminalReason { .. } => "GetLastTerminalReason",
+            Command::GetCurrentAccessToken { .. } => "GetCurrentAccessToken",
+            Command::Ping { .. } => "Ping",
+            Command::SetNetworkHint { .. } => "SetNetworkHint",
+            Command::InboundFrame { .. } => "InboundFrame",
+            Command::InboundDisconnected { .. } => "InboundDisconnected",
+            Command::SetVideoProcessHook { .. } => "SetVideoProcessHook",
+            Command::SetLinkPreviewHook { .. } => "SetLinkPreviewHook",
+            Command::Register { .. } => "Register",
+            Command::Login { .. } => "Login",
+            Command::Authenticate { .. } => "Authenticate",
+            Command::SyncEntities { .. } => "SyncEntities",
+            Command::SyncChannel { .. } => "SyncChannel",
+            Command::SyncAllChannels { .. } => "SyncAllChannels",
+            Command::BatchGetPresence { .. } => "BatchGetPresence",
+            Command::SendTyping { .. } => "SendTyping",
+            Command::Subscribe { .. } => "Subscribe",
+            Command::Unsubscribe { .. } => "Unsubscribe",
+            Command::RpcCall { .. } => "RpcCall",
+            Command::Transfer { .. } => "Transfer",
+            Command::RunBootstrapSync { .. } => "RunBootstrapSync",
+            Command::EnsureSynced { .. } => "EnsureSynced",
+            Command::GetSyncState { .. } => "GetSyncState",
+            Command::IsBootstrapCompleted { .. } => "IsBootstrapCompleted",
+            Command::GetSessionSnapshot { .. } => "GetSessionSnapshot",
+            Command::ClearLocalState { .. } => "ClearLocalState",
+            Command::EnqueueOutboundMessage { .. } => "EnqueueOutboundMessage",
+            Command::PeekOutboundMessages { .. } => "PeekOutboundMessages",
+            Command::AckOutboundMessages { .. } => "AckOutboundMessages",
+            Command::EnqueueOutboundAttachment { .. } => "EnqueueOutboundAttachment",
+            Command::PeekOutboundFiles { .. } => "PeekOutboundFiles",
+            Command::AckOutboundFiles { .. } => "AckOutboundFiles",
+            Command::KickOutboundDrain => "KickOutboundDrain",
+            Command::CreateLocalMessage { .. } => "CreateLocalMessage",
+            Command::CreateLocalMessageQueued { .. } => "CreateLocalMessageQueued",
+            Command::GetMessageById { .. } => "GetMessageById",
+            Command::GetMessageIdByServerMessageId { .. } => "GetMessageIdByServerMessageId",
+            Command::ListMessages { .. } => "ListMessages",
+            Command::ListMessagesBefore { .. } => "ListMessagesBefore",
+            Command::ListMessagesAround { .. } => "ListMessagesAround",
+            Command::QueryTimelineSnapshot { .. } => "QueryTimelineSnapshot",
+            Command::SetMessageCachePolicy { .. } => "SetMessageCachePolicy",
+            Command::UpsertChannel { .. } => "UpsertChannel",
+            Command::GetChannelById { .. } => "GetChannelById",
+            Command::ListChannels { .. } => "ListChannels",
+            Command::UpsertChannelExtra { .. } => "UpsertChannelExtra",
+            Command::GetChannelExtra { .. } => "GetChannelExtra",
+            Command::MarkMessageSent { .. } => "MarkMessageSent",
+            Command::FetchChannelHistory { .. } => "FetchChannelHistory",
+            Command::FetchMessagesAround { .. } => "FetchMessagesAround",
+            Command::RepairMessageProjection { .. } => "RepairMessageProjection",
+            Command::UpdateMessageStatus { .. } => "UpdateMessageStatus",
+            Command::UpdateThumbStatus { .. } => "UpdateThumbStatus",
+            Command::UpdateMediaDownloaded { .. } => "UpdateMediaDownloaded",
+            Command::UpdateMediaDownloadedScoped { .. } => "UpdateMediaDownloadedScoped",
+            Command::CompleteThumbnailDownload { .. } => "CompleteThumbnailDownload",
+            Command::CompleteAutoDownload { .. } => "CompleteAutoDownload",
+            Command::FinalizeLocalAttachment { .. } => "FinalizeLocalAttachment",
+            Command::FinalizeAttachmentAndEnqueue { .. } => "FinalizeAttachmentAndEnqueue",
+            Command::CreateLocalAttachmentPlaceholder { .. } => "CreateLocalAttachmentPlaceholder",
+            Command::SetMessageRevoke { .. } => "SetMessageRevoke",
+            Command::DeleteMessageLocal { .. } => "DeleteMessageLocal",
+            Command::SetChannelHidden { .. } => "SetChannelHidden",
+            Command::DeleteChannelLocal { .. } => "DeleteChannelLocal",
+            Command::EditMessage { .. } => "EditMessage",
+            Command::SetMessagePinned { .. } => "SetMessagePinned",
+            Command::GetMessageExtra { .. } => "GetMessageExtra",
+            Command::DiscardPendingMessageEdit { .. } => "DiscardPendingMessageEdit",
+            Command::ListMessageRevisions { .. } => "ListMessageRevisions",
+            Command::VotePoll { .. } => "VotePoll",
+            Command::PinMessage { .. } => "PinMessage",
+            Command::ListPinnedMessages { .. } => "ListPinnedMessages",
+            Command::RefreshPinnedMessages { .. } => "RefreshPinnedMessages",
+            Command::ListStickerPackages { .. } => "ListStickerPackages",
+            Command::RefreshStickerPackages { .. } => "RefreshStickerPackages",
+            Command::RefreshStickerPackage { .. } => "RefreshStickerPackage",
+            Command::InstallStickerPackage { .. } => "InstallStickerPackage",
+            Command::ReorderStickerPackages { .. } => "ReorderStickerPackages",
+            Command::ListRecentStickers { .. } => "ListRecentStickers",
+            Command::ListFavouriteStickers { .. } => "ListFavouriteStickers",
+            Command::FavouriteSticker { .. } => "FavouriteSticker",
+            Command::UnfavouriteSticker { .. } => "UnfavouriteSticker",
+            Command::PrepareStickerSend { .. } => "PrepareStickerSend",
+            Command::CompleteStickerPackageLoad { .. } => "CompleteStickerPackageLoad",
+            Command::CompleteStickerPrefetch { .. } => "CompleteStickerPrefetch",
+            Command::RefreshPoll { .. } => "RefreshPoll",
+            Command::UpdateLiveLocation { .. } => "UpdateLiveLocation",
+            Command::ListMemberReadCursors { .. } => "ListMemberReadCursors",
+            Command::MessageReadReceipts { .. } => "MessageReadReceipts",
+            Command::ProjectChannelReadCursor { .. } => "ProjectChannelReadCursor",
+            Command::GetPeerReadPts { .. } => "GetPeerReadPts",
+            Command::GetChannelUnreadCount { .. } => "GetChannelUnreadCount",
+            Command::GetTotalUnreadCount { .. } => "GetTotalUnreadCount",
+            Command::GetUnreadAggregate { .. } => "GetUnreadAggregate",
+            Command::QueryChannels { .. } => "QueryChannels",
+            Command::UpsertChannelFolder { .. } => "UpsertChannelFolder",
+            Command::DeleteChannelFolder { .. } => "DeleteChannelFolder",
+            Command::ListChannelFolders { .. } => "ListChannelFolders",
+            Command::UpsertUser { .. } => "UpsertUser",
+            Command::UpdateUserAlias { .. } => "UpdateUserAlias",
+            Command::GetUserById { .. } => "GetUserById",
+            Command::ListUsersByIds { .. } => "ListUsersByIds",
+            Command::UpsertFriend { .. } => "UpsertFriend",
+            Command::DeleteFriend { .. } => "DeleteFriend",
+            Command::ListFriends { .. } => "ListFriends",
+            Command::ListFriendRequests { .. } => "ListFriendRequests",
+            Command::UpsertBlacklistEntry { .. } => "UpsertBlacklistEntry",
+            Command::DeleteBlacklistEntry { .. } => "DeleteBlacklistEntry",
+            Command::ListBlacklistEntries { .. } => "ListBlacklistEntries",
+            Command::UpsertGroup { .. } => "UpsertGroup",
+            Command::GetGroupById { .. } => "GetGroupById",
+            Command::ListGroups { .. } => "ListGroups",
+            Command::UpsertGroupMember { .. } => "UpsertGroupMember",
+            Command::RecacheAvatar { .. } => "RecacheAvatar",
+            Command::DeleteGroupMember { .. } => "DeleteGroupMember",
+            Command::ListGroupMembers { .. } => "ListGroupMembers",
+            Command::UpsertChannelMember { .. } => "UpsertChannelMember",
+            Command::ListChannelMembers { .. } => "ListChannelMembers",
+            Command::DeleteChannelMember { .. } => "DeleteChannelMember",
+            Command::UpsertMessageReaction { .. } => "UpsertMessageReaction",
+            Command::ListMessageReactions { .. } => "ListMessageReactions",
+            Command::RecordMention { .. } => "RecordMention",
+            Command::GetUnreadMentionCount { .. } => "GetUnreadMentionCount",
+            Command::ListUnreadMentionMessageIds { .. } => "ListUnreadMentionMessageIds",
+            Command::MarkMentionRead { .. } => "MarkMentionRead",
+            Command::MarkAllMentionsRead { .. } => "MarkAllMentionsRead",
+            Command::GetAllUnreadMentionCounts { .. } => "GetAllUnreadMentionCounts",
+            Command::UpsertReminder { .. } => "UpsertReminder",
+            Command::ListPendingReminders { .. } => "ListPendingReminders",
+            Command::MarkReminderDone { .. } => "MarkReminderDone",
+            Command::KvPut { .. } => "KvPut",
+            Command::KvGet { .. } => "KvGet",
+            Command::GetUserStoragePaths { .. } => "GetUserStoragePaths",
+            Command::ListLocalAccounts { .. } => "ListLocalAccounts",
+            Command::SetCurrentUid { .. } => "SetCurrentUid",
+            Command::SwitchLocalAccount { .. } => "SwitchLocalAccount",
+            Command::EnsureMessageThumbnail { .. } => "EnsureMessageThumbnail",
+            Command::SetLocalAccountDisplayName { .. } => "SetLocalAccountDisplayName",
+            Command::WipeCurrentUserFull { .. } => "WipeCurrentUserFull",
+            Command::StartTraceRecording { .. } => "StartTraceRecording",
+            Command::StopTraceRecording { .. } => "StopTraceRecording",
+            Command::BeginTraceReplay { .. } => "BeginTraceReplay",
+            Command::ReplayInboundFrame { .. } => "ReplayInboundFrame",
+            Command::SampleOutboxMetrics { .. } => "SampleOutboxMetrics",
+            Command::Shutdown { .. } => "Shutdown",
+        }
+    }
+
+    /// 跑完之后未读聚合可能变了（新消息、本地建 / 删消息、已读推进、同步、会话偏好、
+    /// 文件夹）。宁可多标：
+    /// 标脏只换来 repair tick 上一次本地重算，结果没变不会发事件。
+    fn affects_unread_badge(&self) -> bool {
+        match self {
+            Command::KvPut { key, .. } => key.starts_with(CHANNEL_PREFS_KEY_PREFIX),
+            Command::InboundFrame { .. }
+            | Command::ReplayInboundFrame { .. }
+            | Command::SyncEntities { .. }
+            | Command::SyncChannel { .. }
+            | Command::SyncAllChannels { .. }
+            | Command::RunBootstrapSync { .. }
+            | Command::EnsureSynced { .. }
+            | Command::ClearLocalState { .. }
+            | Command::UpsertChannel { .. }
+            | Command::SetChannelHidden { .. }
+            | Command::DeleteChannelLocal { .. }
+            | Command::ProjectChannelReadCursor { .. }
+            | Command::RecordMention { .. }
+            | Command::MarkMentionRead { .. }
+            | Command::MarkAllMentionsRead { .. }
+            | Command::CreateLocalMessage { .. }
+            | Command::CreateLocalMessageQueued { .. }
+            | Command::CreateLocalAttachmentPlaceholder { .. }
+            | Command::DeleteMessageLocal { .. }
+            | Command::UpsertChannelFolder { .. }
+            | Command::DeleteChannelFolder { .. }
+            | Command::SetCurrentUid { .. }
+            | Command::SwitchLocalAccount { .. } => true,
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 enum SessionState {
     New,
@@ -3731,6 +4375,8 @@ fn timeline_reason_for_message(realtime: bool) -> &'static str {
 struct State {
     /// 见 [`PrivchatSdk::attachment_transfer_stats`]：正文到底传没传字节。
     attachment_transfers: Arc<AttachmentTransferCounters>,
+    /// 见 [`PrivchatSdk::metrics_snapshot`]。与 SDK 句柄共享同一个注册表。
+    metrics: Arc<metrics::MetricsRegistry>,
     config: PrivchatConfig,
     /// 共享句柄。`TransportClient::request_with_options` 取的是 `&self`，
     /// 内部又是 `Arc<Transport>`——所以后台任务可以**自己**发请求，不必占用 actor。
@@ -3751,6 +4397,13 @@ struct State {
     /// The single receiver-side media coordinator. Payload and thumbnail work
     /// share its account/session scope, cancellation and concurrency budget.
     download_manager: media_download::DownloadManager,
+    /// 与 SDK 句柄共享的限速器，见 [`bandwidth`]。网络提示变化时在这里换档。
+    bandwidth: bandwidth::BandwidthLimiter,
+    /// 大附件能不能封成 v2（分段），与 SDK 句柄共享。见
+    /// [`PrivchatSdk::set_segmented_attachment_uploads`]。
+    segmented_uploads: Arc<AtomicBool>,
+    /// 进行中的分片上传，与 SDK 句柄共享，交给后台传输时从这里导出。见 [`background_transfer`]。
+    uploads: background_transfer::UploadRegistry,
     /// Receiver workers report typed outcomes back through the actor. A weak
     /// sender avoids keeping the actor alive after every public SDK handle drops.
     actor_tx: mpsc::WeakSender<Command>,
@@ -3867,8 +4520,31 @@ struct State {
     repair_backoff: HashMap<(i32, u64, u64), (u32, std::time::Instant)>,
     /// AVATAR_CACHE_SPEC P1: user 头像本地缓存管理器（in-flight/verified 去重）。
     avatar_cache: avatar_cache::AvatarCacheManager,
+    /// 入站帧录制（见 [`trace_recorder`]）。默认 `None`，宿主显式开启才录。
+    trace_recorder: Option<trace_recorder::TraceRecorder>,
+    /// 上一次发出去的未读聚合，连同它属于哪个 uid：切账号后第一次聚合一定算「变了」。
+    last_unread_aggregate: Option<(String, UnreadAggregate)>,
+    /// 有可能改变未读的命令跑过。repair tick 看到它才重算，顺带把一串连续的入站消息
+    /// 合并成一次 BadgeChanged。
+    badge_dirty: bool,
+    /// `channel_pref` 镜像已经从账号 kv 回填过的 uid。之后的偏好写入走 KvPut 同步写镜像。
+    channel_prefs_mirrored_uid: Option<String>,
+    /// 按策略排上的后台主文件下载，完成（或失败）时移除。网络变了靠它重新求值：
+    /// 不再放行的从 DownloadManager 里摘掉但留在这里，网络回来再重新提交。
+    auto_downloads: HashMap<media_download::MediaTaskKey, PendingAutoDownload>,
 }
 
+/// 一条按自动下载策略排上的主文件下载。
+#[derive(Debug, Clone)]
+struct PendingAutoDownload {
+    candidate: auto_download::AutoDownloadCandidate,
+    channel_muted: bool,
+    created_at_ms: i64,
+}
+
+/// 同时挂着的自动下载上限。超出的直接不排：它们只是「提前下好」，用户点开时照常下载。
+const AUTO_DOWNLOAD_PENDING_LIMIT: usize = 256;
+
 impl State {
     /// AVATAR_CACHE_SPEC P1: upsert_user 落库后触发头像本地缓存。
     ///
@@ -4182,6 +4858,10 @@ impl State {
             // 与 resume 无关的本地附件错误：不该把它当成需要重建/重试的同步失败。
             Error::AttachmentSourceMissing { .. } => ResumeFailureClass::FatalProtocolError,
             Error::SessionNotReady { .. } => ResumeFailureClass::RetryableTemporaryError,
+            // 同步路径不产生这两种；真出现了也只是调用方的问题，不值得重建。
+            Error::NotFound(_) | Error::InvalidArgument(_) => {
+                ResumeFailureClass::FatalProtocolError
+            }
             Error::InvalidState(message) => {
                 let lowered = message.to_ascii_lowercase();
                 if lowered.contains("session_ready rejected")
@@ -5210,7 +5890,7 @@ impl State {
     fn should_log_unsupported_entity_skip(entity_type: &str) -> bool {
         !matches!(
             entity_type,
-            "user_block" | "channel_extra" | "channel_unread"
+            "user_block" | "channel_extra" | "channel_unread" | sticker::SYNC_ENTITY_TYPE
         )
     }
 
@@ -5563,9 +6243,18 @@ impl State {
         commit: &privchat_protocol::rpc::sync::ServerCommit,
     ) -> (String, SyncEntityItem) {
         match commit.message_type.as_str() {
-            "message.revoke" | "message_extra" | "message_ext" => {
+            "message.revoke" | "message_extra" | "message_ext" | "message.pin"
+            | "message.unpin" => {
                 let mut payload = commit.content.clone();
                 if let Some(obj) = payload.as_object_mut() {
+                    if let Some(pinned) = match commit.message_type.as_str() {
+                        "message.pin" => Some(true),
+                        "message.unpin" => Some(false),
+                        _ => None,
+                    } {
+                        obj.entry("is_pinned".to_string())
+                            .or_insert_with(|| serde_json::json!(pinned));
+                    }
                     obj.entry("channel_id".to_string())
                         .or_insert_with(|| serde_json::json!(commit.channel_id));
                     obj.entry("channel_type".to_string())
@@ -5583,6 +6272,36 @@ impl State {
                     },
                 )
             }
+            "message.poll"
+            | "message_poll"
+            | "poll"
+            | "message.live_location"
+            | "message_live_location"
+            | "live_location" => {
+                let mut payload = commit.content.clone();
+                if let Some(obj) = payload.as_object_mut() {
+                    obj.entry("channel_id".to_string())
+                        .or_insert_with(|| serde_json::json!(commit.channel_id));
+                    obj.entry("channel_type".to_string())
+                        .or_insert_with(|| serde_json::json!(i32::from(commit.channel_type)));
+                    obj.entry("message_id".to_string())
+                        .or_insert_with(|| serde_json::json!(commit.server_msg_id));
+                }
+                let entity_type = if commit.message_type.contains("poll") {
+                    "message_poll"
+                } else {
+                    "message_live_location"
+                };
+                (
+                    entity_type.to_string(),
+                    SyncEntityItem {
+                        entity_id: commit.server_msg_id.to_string(),
+                        version: commit.pts,
+                        deleted: false,
+                        payload: Some(payload),
+                    },
+                )
+            }
             "message_reaction" | "reaction" | "message.reaction" => {
                 let mut payload = commit.content.clone();
                 let entity_id = if let Some(obj) = payload.as_object_mut() {
@@ -5814,6 +6533,44 @@ impl State {
         }
     }
 
+    /// 实时位置推送（`live_location_updated` / `live_location_stopped`）转成
+    /// `message_live_location` 实体，和同步下来的位置走同一条应用路径。
+    fn push_message_to_live_location_item(push: &PushMessageRequest) -> Option<SyncEntityItem> {
+        let payload_json: serde_json::Value = serde_json::from_slice(&push.payload).ok()?;
+        let notification_type =
+            Self::json_field_string(&payload_json, &["metadata", "notification_type"])?;
+        let stopped = match notification_type.as_str() {
+            "live_location_updated" => false,
+            "live_location_stopped" => true,
+            _ => return None,
+        };
+        let mut payload = payload_json.get("metadata")?.clone();
+        let server_message_id = Self::json_field_u64(&payload, &["server_message_id"])
+            .or_else(|| Self::json_field_u64(&payload, &["message_id"]))
+            .filter(|&id| id > 0)?;
+        // 只作接收管线的去重键：没有版本号时位置推送的 message_seq 可能都是 0，
+        // 必须按定位时间区分，否则第二次更新起全被当成重复丢掉。
+        let version = Self::json_field_u64(&payload, &["version"]).unwrap_or_else(|| {
+            u64::from(push.message_seq)
+                .max(Self::json_field_u64(&payload, &["position_at"]).unwrap_or(0))
+                .max(u64::from(push.timestamp) * 1000)
+        });
+        let obj = payload.as_object_mut()?;
+        obj.insert(
+            "message_id".to_string(),
+            serde_json::json!(server_message_id),
+        );
+        if stopped {
+            obj.insert("stopped".to_string(), serde_json::json!(true));
+        }
+        Some(SyncEntityItem {
+            entity_id: server_message_id.to_string(),
+            version,
+            deleted: false,
+            payload: Some(payload),
+        })
+    }
+
     /// 识别 `friend.request.*` 三类在线 hint topic：
     /// - `friend.request.received`：作为 target 收到新申请；
     /// - `friend.request.sent`：作为 requester 自己其他设备的"我发出了申请" hint；
@@ -5875,7 +6632,13 @@ impl State {
         for item in batch.items {
             if !matches!(
                 item.entity_type.as_str(),
-                "friend" | "user" | "group" | "group_member" | "channel" | "channel_read_cursor"
+                "friend"
+                    | "user"
+                    | "group"
+                    | "group_member"
+                    | "channel"
+                    | "channel_read_cursor"
+                    | sticker::SYNC_ENTITY_TYPE
             ) {
                 tracing::warn!(
                     entity_type = item.entity_type,
@@ -6905,6 +7668,17 @@ impl State {
                             media_download::DownloadPriority::Background,
                         );
                     }
+                    if !from_self && upserted.inserted_new {
+                        self.maybe_schedule_auto_download(
+                            message_id,
+                            channel_id,
+                            channel_type,
+                            timestamp,
+                            message_type,
+                            &extra_for_thumb,
+                        )
+                        .await;
+                    }
 
                     // NewMessage is immutable. Realtime delivery and anti-entropy may overlap,
                     // so replaying an already materialized server message must not trigger a
@@ -6998,16 +7772,52 @@ impl State {
                     {
                         let edited_at = Self::json_get_i32(&payload, &["edited_at"])
                             .unwrap_or((now_ms / 1000) as i32);
+                        // 带版本号的编辑按版本单调应用：乱序到达的旧编辑在存储层被丢弃。
+                        // 不带版本号的老服务端退化为「追加一版」，与此前的覆盖语义一致；
+                        // 同一份编辑重复同步（内容和 edited_at 都没变）不再追加。
                         self.storage
-                            .edit_message(message_id, &content_edit, edited_at)
+                            .apply_message_revision(
+                                message_id,
+                                MessageRevisionInput {
+                                    version: Self::json_get_u64(
+                                        &payload,
+                                        &["edit_version", "content_version", "revision"],
+                                    ),
+                                    content: content_edit,
+                                    edited_at,
+                                    editor_uid: Self::json_get_u64(
+                                        &payload,
+                                        &["editor_uid", "edited_by", "editor"],
+                                    ),
+                                },
+                            )
                             .await?;
                     }
                     if payload.get("is_pinned").is_some() || payload.get("pinned").is_some() {
                         let is_pinned = Self::json_get_bool(&payload, &["is_pinned", "pinned"])
                             .unwrap_or(false);
-                        self.storage
-                            .set_message_pinned(message_id, is_pinned)
-                            .await?;
+                        if channel_id > 0 {
+                            let pin = RemotePin {
+                                server_message_id: raw_message_id,
+                                pinned_by: Self::json_get_u64(&payload, &["pinned_by", "pin_by"]),
+                                pinned_at: Self::json_get_i64(&payload, &["pinned_at"])
+                                    .unwrap_or(now_ms),
+                            };
+                            if self
+                                .storage
+                                .apply_remote_pin(channel_id, channel_type, pin, is_pinned)
+                                .await?
+                            {
+                                emitted.push(SdkEvent::PinnedMessagesChanged {
+                                    channel_id,
+                                    channel_type,
+                                });
+                            }
+                        } else {
+                            self.storage
+                                .set_message_pinned(message_id, is_pinned)
+                                .await?;
+                        }
                     }
                     emitted.push(SdkEvent::SyncEntityChanged {
                         entity_type: "message_extra".to_string(),
@@ -7093,13 +7903,13 @@ impl State {
                     });
                 }
             }
-            "mention" | "message_mention" => {
+            "message_poll" | "poll" => {
                 for item in items {
                     let payload = item
                         .payload
                         .clone()
                         .unwrap_or_else(|| serde_json::json!({}));
-                    let message_id =
+                    let server_message_id =
                         Self::json_get_u64(&payload, &["message_id", "server_message_id", "id"])
                             .or_else(|| Self::parse_entity_id_u64(&item.entity_id))
                             .unwrap_or(0);
@@ -7113,15 +7923,129 @@ impl State {
                     let channel_id = Self::json_get_u64(&payload, &["channel_id"])
                         .or(scoped_channel.map(|v| v.1))
                         .unwrap_or(0);
-                    let mentioned_user_id =
-                        Self::json_get_u64(&payload, &["mentioned_user_id", "uid", "user_id"])
-                            .unwrap_or(0);
-                    if message_id == 0 || channel_id == 0 || mentioned_user_id == 0 {
+                    let Some(update) = PollTallyUpdate::from_payload(&payload) else {
+                        continue;
+                    };
+                    if server_message_id == 0 || channel_id == 0 {
                         continue;
                     }
-                    self.storage
-                        .record_mention(MentionInput {
-                            message_id,
+                    // 票数挂在本地消息上；投票消息本身还没同步下来时先不记，
+                    // 消息到了以后打开详情会 refresh。
+                    let Some(message_id) = self
+                        .storage
+                        .get_message_id_by_server_message_id(
+                            channel_id,
+                            channel_type,
+                            server_message_id,
+                        )
+                        .await?
+                    else {
+                        continue;
+                    };
+                    if !self.storage.apply_poll_update(message_id, update).await? {
+                        continue;
+                    }
+                    emitted.push(SdkEvent::SyncEntityChanged {
+                        entity_type: "message_poll".to_string(),
+                        entity_id: item.entity_id.clone(),
+                        deleted: item.deleted,
+                    });
+                    emitted.push(SdkEvent::TimelineUpdated {
+                        channel_id,
+                        channel_type,
+                        message_id,
+                        reason: "poll_sync".to_string(),
+                    });
+                }
+            }
+            "message_live_location" | "live_location" => {
+                for item in items {
+                    let payload = item
+                        .payload
+                        .clone()
+                        .unwrap_or_else(|| serde_json::json!({}));
+                    let server_message_id =
+                        Self::json_get_u64(&payload, &["message_id", "server_message_id", "id"])
+                            .or_else(|| Self::parse_entity_id_u64(&item.entity_id))
+                            .unwrap_or(0);
+                    let scoped_channel = Self::parse_channel_scope(scope);
+                    let channel_type = Self::parse_protocol_channel_type(
+                        &payload,
+                        &["channel_type", "type", "conversation_type"],
+                    )
+                    .or(scoped_channel.map(|v| v.0))
+                    .unwrap_or(1);
+                    let channel_id = Self::json_get_u64(&payload, &["channel_id"])
+                        .or(scoped_channel.map(|v| v.1))
+                        .unwrap_or(0);
+                    let Some(update) = LiveLocationUpdate::from_payload(&payload) else {
+                        continue;
+                    };
+                    if server_message_id == 0 || channel_id == 0 {
+                        continue;
+                    }
+                    // 位置挂在本地消息上；共享消息本身还没同步下来时这次位置就不记了，
+                    // 共享期间下一次更新很快会再来。
+                    let Some(message_id) = self
+                        .storage
+                        .get_message_id_by_server_message_id(
+                            channel_id,
+                            channel_type,
+                            server_message_id,
+                        )
+                        .await?
+                    else {
+                        continue;
+                    };
+                    if !self
+                        .storage
+                        .apply_live_location_update(message_id, update)
+                        .await?
+                    {
+                        continue;
+                    }
+                    emitted.push(SdkEvent::SyncEntityChanged {
+                        entity_type: "message_live_location".to_string(),
+                        entity_id: item.entity_id.clone(),
+                        deleted: item.deleted,
+                    });
+                    emitted.push(SdkEvent::TimelineUpdated {
+                        channel_id,
+                        channel_type,
+                        message_id,
+                        reason: "live_location_sync".to_string(),
+                    });
+                }
+            }
+            "mention" | "message_mention" => {
+                for item in items {
+                    let payload = item
+                        .payload
+                        .clone()
+                        .unwrap_or_else(|| serde_json::json!({}));
+                    let message_id =
+                        Self::json_get_u64(&payload, &["message_id", "server_message_id", "id"])
+                            .or_else(|| Self::parse_entity_id_u64(&item.entity_id))
+                            .unwrap_or(0);
+                    let scoped_channel = Self::parse_channel_scope(scope);
+                    let channel_type = Self::parse_protocol_channel_type(
+                        &payload,
+                        &["channel_type", "type", "conversation_type"],
+                    )
+                    .or(scoped_channel.map(|v| v.0))
+                    .unwrap_or(1);
+                    let channel_id = Self::json_get_u64(&payload, &["channel_id"])
+                        .or(scoped_channel.map(|v| v.1))
+                        .unwrap_or(0);
+                    let mentioned_user_id =
+                        Self::json_get_u64(&payload, &["mentioned_user_id", "uid", "user_id"])
+                            .unwrap_or(0);
+                    if message_id == 0 || channel_id == 0 || mentioned_user_id == 0 {
+                        continue;
+                    }
+                    self.storage
+                        .record_mention(MentionInput {
+                            message_id,
                             channel_id,
                             channel_type,
                             mentioned_user_id,
@@ -7335,6 +8259,11 @@ impl State {
                             .storage
                             .save_peer_read_pts(channel_id, channel_type, read_pts)
                             .await;
+                        // 按成员留一份：群「谁看过」本地算，不再逐条 READ_LIST。
+                        let _ = self
+                            .storage
+                            .save_member_read_pts(channel_id, channel_type, reader_id, read_pts)
+                            .await;
                         emitted.push(SdkEvent::PeerReadPtsAdvanced {
                             channel_id,
                             channel_type,
@@ -7364,6 +8293,41 @@ impl State {
                     });
                 }
             }
+            sticker::SYNC_ENTITY_TYPE => {
+                let installs: Vec<RemoteStickerInstall> = items
+                    .iter()
+                    .filter_map(|item| {
+                        RemoteStickerInstall::from_sync_item(
+                            &item.entity_id,
+                            item.deleted,
+                            item.payload.as_ref().unwrap_or(&serde_json::Value::Null),
+                        )
+                    })
+                    .collect();
+                let changed = self.storage.apply_remote_sticker_installs(installs).await?;
+                for install in &changed {
+                    emitted.push(SdkEvent::SyncEntityChanged {
+                        entity_type: sticker::SYNC_ENTITY_TYPE.to_string(),
+                        entity_id: install.package_id.clone(),
+                        deleted: !install.installed,
+                    });
+                }
+                if !changed.is_empty() {
+                    let (newly_installed, removed): (Vec<_>, Vec<_>) =
+                        changed.into_iter().partition(|install| install.installed);
+                    for install in &removed {
+                        self.remove_sticker_assets(&install.package_id).await;
+                    }
+                    self.load_installed_sticker_packages(
+                        newly_installed
+                            .into_iter()
+                            .map(|install| install.package_id)
+                            .collect(),
+                    )
+                    .await;
+                    emitted.push(SdkEvent::StickerCatalogChanged);
+                }
+            }
             _ => {}
         }
         Ok(emitted)
@@ -7686,6 +8650,30 @@ impl State {
         Self::drop_attachment_sealed_caches_at(&self.storage, msg).await;
     }
 
+    /// 本地删掉的消息，连同它们的媒体目录一起删，再回收 blob 库里已经没人引用的内容。
+    ///
+    /// 消息目录里的正文是 blob 库的硬链接（见 `media_blob`）：删目录只是减引用，
+    /// 同一份内容还被别的消息用着就留着。放到阻塞线程里做，不占 actor。
+    async fn release_message_media(&self, messages: &[StoredMessage]) {
+        let Ok(paths) = self.storage.get_storage_paths().await else {
+            return;
+        };
+        let user_root = PathBuf::from(&paths.user_root);
+        let dirs: Vec<PathBuf> = messages
+            .iter()
+            .map(|m| media_store::get_message_dir(&user_root, m.message_id as i64, m.created_at))
+            .collect();
+        tokio::task::spawn_blocking(move || {
+            for dir in &dirs {
+                let _ = std::fs::remove_dir_all(dir);
+            }
+            let removed = crate::media_blob::collect_garbage(&user_root, SEALED_CACHE_RETENTION);
+            if removed > 0 {
+                eprintln!("[SDK.media] released {removed} unreferenced blob(s)");
+            }
+        });
+    }
+
     async fn drop_attachment_sealed_caches_at(storage: &StorageHandle, msg: &StoredMessage) {
         let Ok(paths) = storage.get_storage_paths().await else {
             return;
@@ -7755,6 +8743,10 @@ impl State {
                 }
             };
             let local_message_id = self.ensure_local_message_id(message_id).await?;
+            // 剩下的分片在平台手里：命令原样留着，不记重试；导入完或收回时会再踢一次。
+            if self.uploads.is_handed_off(&local_message_id.to_string()) {
+                continue;
+            }
             eprintln!(
                 "[SDK.actor] drain_attachment_outbox_once: processing message_id={} payload_len={} created_at={}",
                 message_id,
@@ -7801,6 +8793,10 @@ impl State {
                         });
                     processed += 1;
                 }
+                Err(_) if self.uploads.is_handed_off(&local_message_id.to_string()) => {
+                    // 上传循环交接后退出了，不是失败。
+                    continue;
+                }
                 Err(e) => {
                     // Reconciliation for "server committed but response lost":
                     // sync/push may land slightly later than request timeout; poll briefly.
@@ -7947,6215 +8943,7983 @@ impl State {
         drained += self
             .drain_attachment_outbox_once(OUTBOUND_DRAIN_BATCH_SIZE)
             .await?;
+        drained += self
+            .drain_rpc_outbox_once(OUTBOUND_DRAIN_BATCH_SIZE)
+            .await?;
         Ok(drained)
     }
 
-    fn connect_timeout_total(&self) -> Duration {
-        let per = self.config.connection_timeout_secs.max(1);
-        let endpoints = self.config.endpoints.len().max(1) as u64;
-        // Allow sequential fallback across endpoints plus a small scheduling buffer.
-        Duration::from_secs(per.saturating_mul(endpoints).saturating_add(2))
-    }
-
-    async fn connect(&mut self) -> Result<()> {
-        if actor_logs_enabled() {
-            eprintln!(
-                "[SDK.actor] connect: enter (has_transport={}, endpoints={})",
-                self.transport.is_some(),
-                self.config.endpoints.len()
-            );
-        }
-        if let Some(transport) = self.transport.as_ref() {
-            if transport.is_connected().await {
-                if actor_logs_enabled() {
-                    eprintln!("[SDK.actor] connect: already connected");
+    /// RPC 类出站命令（`message_id` 为空），按 `command_type` 分派。
+    ///
+    /// 分派函数只在「可以重试」时返回错误；服务端明确拒绝的命令由它自己收尾
+    /// （删命令 + 退回本地状态），不会卡在队列里。
+    async fn drain_rpc_outbox_once(&mut self, limit: usize) -> Result<usize> {
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let commands = self.storage.outbox_peek_rpc(limit, now_ms).await?;
+        let mut processed = 0usize;
+        for command in commands {
+            let outcome = match command.command_type.as_str() {
+                poll::VOTE_COMMAND => self.run_poll_vote_command(&command).await,
+                message_pin::PIN_COMMAND => self.run_message_pin_command(&command).await,
+                sticker::INSTALL_COMMAND => self.run_sticker_install_command(&command).await,
+                live_location::UPDATE_COMMAND => self.run_live_location_command(&command).await,
+                other => {
+                    // 更新的版本写进来、又降级回来的命令：这里永远发不出去，留着只会
+                    // 每轮都扫到它。
+                    tracing::warn!(
+                        command_type = other,
+                        command_id = %command.command_id,
+                        "unknown outbox command type; dropping"
+                    );
+                    self.storage.outbox_rpc_drop(command.id).await
                 }
-                return Ok(());
-            }
-            if actor_logs_enabled() {
-                eprintln!("[SDK.actor] connect: found stale transport, rebuilding");
-            }
-            self.transport = None;
-        }
-        let endpoints = self.config.endpoints.clone();
-        let mut last_err = None;
-        for ep in endpoints {
-            if actor_logs_enabled() {
-                match ep.protocol {
-                    TransportProtocol::WebSocket => {
-                        eprintln!(
-                            "[SDK.actor] connect: trying {:?} {}:{} tls={}",
-                            ep.protocol, ep.host, ep.port, ep.use_tls
-                        );
-                    }
-                    _ => {
-                        eprintln!(
-                            "[SDK.actor] connect: trying {:?} {}:{}",
-                            ep.protocol, ep.host, ep.port
+            };
+            match outcome {
+                Ok(()) => processed += 1,
+                Err(e) => {
+                    let next_at = self.outbox_next_attempt_at(command.retry_count);
+                    if let Err(backoff_err) = self
+                        .storage
+                        .outbox_rpc_bump_retry(command.id, next_at, &e.to_string())
+                        .await
+                    {
+                        tracing::warn!(
+                            command_id = %command.command_id,
+                            error = %backoff_err,
+                            "backoff not persisted; the command may retry immediately"
                         );
                     }
-                }
-            }
-            match timeout(self.timeout(), self.connect_one(&ep)).await {
-                Ok(Ok((c, events))) => {
-                    self.transport = Some(Arc::new(c));
-                    *self.transport_events.lock().await = Some(events);
-                    if actor_logs_enabled() {
-                        eprintln!("[SDK.actor] connect: success");
-                    }
-                    return Ok(());
-                }
-                Ok(Err(e)) => {
-                    eprintln!("[SDK.actor] connect: endpoint failed: {e}");
-                    last_err = Some(e);
-                }
-                Err(_) => {
-                    let e = Error::Transport(format!(
-                        "endpoint {:?} {}:{} timeout",
-                        ep.protocol, ep.host, ep.port
-                    ));
-                    eprintln!("[SDK.actor] connect: endpoint failed: {e}");
-                    last_err = Some(e);
+                    self.pending_events.push(SdkEvent::OutboundQueueUpdated {
+                        kind: "rpc".to_string(),
+                        action: format!("deferred:{e}"),
+                        message_id: None,
+                    });
+                    break;
                 }
             }
         }
-        eprintln!("[SDK.actor] connect: all endpoints failed");
-        Err(last_err.unwrap_or_else(|| Error::Transport("no endpoint".into())))
+        Ok(processed)
     }
 
-    async fn try_auto_reconnect(
-        &mut self,
-        inbound_task: &mut Option<tokio::task::JoinHandle<()>>,
-        event_tx: &broadcast::Sender<SdkEvent>,
-        event_history: &Arc<StdMutex<VecDeque<SequencedSdkEvent>>>,
-        event_seq: &Arc<AtomicU64>,
-        event_history_limit: usize,
-    ) -> Result<SessionState> {
-        if realtime_trace_enabled() {
-            eprintln!(
-                "[SDK_RECONNECT_BEGIN] old_epoch={} state_before={:?} uid={:?}",
-                self.inbound_epoch, self.session_state, self.current_uid
-            );
-        }
-        tracing::debug!(
-            state_before = ?self.session_state,
-            current_uid_present = self.current_uid.is_some(),
-            "auto reconnect started"
-        );
-        match timeout(self.connect_timeout_total(), self.connect()).await {
-            Ok(Ok(())) => {
-                self.session_state = SessionState::Connected;
-            }
-            Ok(Err(e)) => {
-                tracing::warn!(error = %e, "auto reconnect transport failed");
-                return Err(e);
-            }
-            Err(_) => {
-                let e = Error::Transport("reconnect timeout".to_string());
-                tracing::warn!(error = %e, "auto reconnect timed out");
-                return Err(e);
+    async fn run_poll_vote_command(&mut self, command: &OutboxRpcCommand) -> Result<()> {
+        let vote: PollVoteCommand = match serde_json::from_slice(&command.payload) {
+            Ok(vote) => vote,
+            Err(e) => {
+                tracing::warn!(
+                    command_id = %command.command_id,
+                    error = %e,
+                    "undecodable poll vote command; dropping"
+                );
+                return self.storage.outbox_rpc_drop(command.id).await;
             }
-        }
-        let uid = match self.current_uid.clone() {
-            Some(v) => v,
-            None => {
-                tracing::debug!("auto reconnect has no persisted user");
-                return Ok(SessionState::Connected);
+        };
+        let request = PollVoteRequest {
+            server_message_id: vote.server_message_id,
+            channel_id: vote.channel_id,
+            channel_type: vote.channel_type,
+            option_indexes: vote.option_indexes.clone(),
+            command_id: command.command_id.clone(),
+        };
+        let (accepted, update) = match self
+            .rpc_call_typed::<_, Option<PollTallyUpdate>>(poll::ROUTE_VOTE, &request)
+            .await
+        {
+            Ok(update) => (true, update),
+            Err(e) if e.is_retryable() => return Err(e),
+            Err(e) => {
+                tracing::warn!(
+                    message_id = vote.message_id,
+                    error = %e,
+                    "poll vote rejected; reverting the local choice"
+                );
+                (false, None)
             }
         };
-        let snapshot = match self.storage.load_session(uid.clone()).await {
-            Ok(Some(v)) => v,
-            Ok(None) => {
-                tracing::debug!(user_id = %uid, "auto reconnect has no session snapshot");
-                return Ok(SessionState::Connected);
-            }
+        let (channel_id, channel_type, message_id) =
+            (vote.channel_id, vote.channel_type, vote.message_id);
+        self.storage
+            .poll_vote_settled(command.id, vote, accepted, update)
+            .await?;
+        self.pending_events.push(SdkEvent::TimelineUpdated {
+            channel_id,
+            channel_type,
+            message_id,
+            reason: if accepted {
+                "poll_vote_sent".to_string()
+            } else {
+                "poll_vote_rejected".to_string()
+            },
+        });
+        Ok(())
+    }
+
+    async fn record_poll_vote(&mut self, message_id: u64, option_indexes: Vec<u32>) -> Result<()> {
+        let message = self
+            .storage
+            .get_message_by_id(message_id)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("message {message_id}")))?;
+        let definition = poll::definition_from_message(&message)
+            .ok_or_else(|| Error::InvalidArgument(format!("message {message_id} is not a poll")))?;
+        let server_message_id = message
+            .server_message_id
+            .filter(|&id| id > 0)
+            .ok_or_else(|| Error::InvalidState("poll message is not sent yet".to_string()))?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        if message.poll.as_ref().is_some_and(|tally| tally.closed)
+            || definition.is_past_close(now_ms)
+        {
+            return Err(Error::InvalidState("poll is closed".to_string()));
+        }
+        let option_indexes = definition.validate_choice(&option_indexes)?;
+        // 与消息命令的 `msg:{snowflake}` 同理：重试不变，服务端按它去重。
+        let command_id = format!("{}:{}", poll::VOTE_COMMAND, self.next_local_message_id()?);
+        self.storage
+            .poll_record_vote(
+                command_id,
+                PollVoteCommand {
+                    message_id,
+                    server_message_id,
+                    channel_id: message.channel_id,
+                    channel_type: message.channel_type,
+                    option_indexes,
+                },
+            )
+            .await?;
+        self.pending_events.push(SdkEvent::TimelineUpdated {
+            channel_id: message.channel_id,
+            channel_type: message.channel_type,
+            message_id,
+            reason: "poll_vote".to_string(),
+        });
+        Ok(())
+    }
+
+    async fn refresh_poll(&mut self, message_id: u64) -> Result<bool> {
+        let message = self
+            .storage
+            .get_message_by_id(message_id)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("message {message_id}")))?;
+        if message.message_type != POLL_MESSAGE_TYPE {
+            return Err(Error::InvalidArgument(format!(
+                "message {message_id} is not a poll"
+            )));
+        }
+        let server_message_id = message
+            .server_message_id
+            .filter(|&id| id > 0)
+            .ok_or_else(|| Error::InvalidState("poll message is not sent yet".to_string()))?;
+        let update: PollTallyUpdate = self
+            .rpc_call_typed(
+                poll::ROUTE_GET,
+                &PollGetRequest {
+                    server_message_id,
+                    channel_id: message.channel_id,
+                    channel_type: message.channel_type,
+                },
+            )
+            .await?;
+        let changed = self.storage.apply_poll_update(message_id, update).await?;
+        if changed {
+            self.pending_events.push(SdkEvent::TimelineUpdated {
+                channel_id: message.channel_id,
+                channel_type: message.channel_type,
+                message_id,
+                reason: "poll_refresh".to_string(),
+            });
+        }
+        Ok(changed)
+    }
+
+    async fn run_live_location_command(&mut self, command: &OutboxRpcCommand) -> Result<()> {
+        let update: LiveLocationCommand = match serde_json::from_slice(&command.payload) {
+            Ok(update) => update,
             Err(e) => {
-                tracing::warn!(user_id = %uid, error = %e, "auto reconnect session load failed");
-                return Ok(SessionState::Connected);
+                tracing::warn!(
+                    command_id = %command.command_id,
+                    error = %e,
+                    "undecodable live location command; dropping"
+                );
+                return self.storage.outbox_rpc_drop(command.id).await;
             }
         };
-
-        tracing::debug!(user_id = %snapshot.user_id, "restoring persisted session");
-        let user_id = snapshot.user_id;
-        let device_id = snapshot.device_id.clone();
-        let bootstrap_completed = snapshot.bootstrap_completed;
-        let has_access_token = !snapshot.token.is_empty();
-        tracing::debug!(
-            user_id = %user_id,
-            device_id = %device_id,
-            has_access_token,
-            state_before = ?self.session_state,
-            "authenticating restored session"
-        );
-        let mut driver = ActorAuthenticateRetryDriver {
-            state: self,
-            inbound_task,
-            event_tx,
-            event_history,
-            event_seq,
-            event_history_limit,
-            user_id,
-            token: snapshot.token,
-            device_id,
+        let request = LiveLocationUpdateRequest {
+            server_message_id: update.server_message_id,
+            channel_id: update.channel_id,
+            channel_type: update.channel_type,
+            position: update.position.clone(),
+            stop: update.stop,
+            command_id: command.command_id.clone(),
         };
-        let auth_result = execute_authenticate_with_retry(&mut driver, false).await;
-        drop(driver);
-        match auth_result {
-            Ok(()) => {
-                self.bootstrap_completed = bootstrap_completed;
-                tracing::debug!(user_id = %user_id, "persisted session restored");
-                Ok(SessionState::Authenticated)
-            }
+        let (outcome, response) = match self
+            .rpc_call_typed::<_, Option<LiveLocationUpdate>>(live_location::ROUTE_UPDATE, &request)
+            .await
+        {
+            Ok(response) => (LiveLocationSettle::Accepted, response),
+            Err(e) if e.is_retryable() => return Err(e),
             Err(e) => {
-                // A failed authentication must leave neither a live unauthenticated
-                // transport nor stale logical state behind. Use the same lifecycle
-                // teardown as the retry path rather than assigning transport directly.
-                eprintln!("[SDK.actor] monitor: restore auth failed: {e} (dropping transport)");
-                dismantle_auth_transport(
-                    self,
-                    inbound_task,
-                    event_tx,
-                    event_history,
-                    event_seq,
-                    event_history_limit,
-                )
-                .await;
-                Err(e)
+                let outcome = LiveLocationSettle::for_rejection(&update, &e);
+                tracing::warn!(
+                    message_id = update.message_id,
+                    error = %e,
+                    ?outcome,
+                    "live location update rejected"
+                );
+                (outcome, None)
             }
-        }
+        };
+        let (channel_id, channel_type, message_id) =
+            (update.channel_id, update.channel_type, update.message_id);
+        self.storage
+            .live_location_settled(command.id, update, outcome, response)
+            .await?;
+        self.pending_events.push(SdkEvent::TimelineUpdated {
+            channel_id,
+            channel_type,
+            message_id,
+            reason: match outcome {
+                LiveLocationSettle::Accepted => "live_location_sent".to_string(),
+                LiveLocationSettle::Dropped => "live_location_dropped".to_string(),
+                LiveLocationSettle::Ended => "live_location_rejected".to_string(),
+            },
+        });
+        Ok(())
     }
 
-    async fn disconnect(&mut self) -> Result<()> {
-        if let Some(transport) = self.transport.as_ref() {
-            transport
-                .disconnect()
-                .await
-                .map_err(|e| Error::Transport(format!("disconnect: {e}")))?;
+    /// 记下自己的一次位置（`position` 为空 = 结束共享）并入队。返回命令的
+    /// `next_attempt_at`，节流推迟时大于当前时间。
+    async fn record_live_location(
+        &mut self,
+        message_id: u64,
+        position: Option<LiveLocationPosition>,
+    ) -> Result<i64> {
+        let message = self
+            .storage
+            .get_message_by_id(message_id)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("message {message_id}")))?;
+        let definition = live_location::definition_from_message(&message).ok_or_else(|| {
+            Error::InvalidArgument(format!("message {message_id} is not a live location"))
+        })?;
+        let self_uid = self
+            .current_uid
+            .as_deref()
+            .and_then(|uid| uid.parse::<u64>().ok());
+        if self_uid != Some(message.from_uid) {
+            return Err(Error::InvalidArgument(
+                "only the sharer can update a live location".to_string(),
+            ));
         }
-        self.transport = None;
-        Ok(())
+        let Some(server_message_id) = message.server_message_id.filter(|&id| id > 0) else {
+            return Err(Error::InvalidState(
+                "live location message is not sent yet".to_string(),
+            ));
+        };
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let view =
+            live_location::live_location_view(definition, message.live_location.as_ref(), now_ms);
+        if view.ended {
+            // 结束是幂等的：已经结束（含到期）的共享再结束一次什么也不做。
+            return match position {
+                None => Ok(0),
+                Some(_) => Err(Error::InvalidState(
+                    "live location sharing has ended".to_string(),
+                )),
+            };
+        }
+        let position = position
+            .map(|p| LiveLocationPosition { at: now_ms, ..p }.validated())
+            .transpose()?;
+        let stop = position.is_none();
+        let command_id = format!(
+            "{}:{}",
+            live_location::UPDATE_COMMAND,
+            self.next_local_message_id()?
+        );
+        let next_attempt_at = self
+            .storage
+            .live_location_record(
+                command_id,
+                LiveLocationCommand {
+                    message_id,
+                    server_message_id,
+                    channel_id: message.channel_id,
+                    channel_type: message.channel_type,
+                    position,
+                    stop,
+                },
+            )
+            .await?;
+        self.pending_events.push(SdkEvent::TimelineUpdated {
+            channel_id: message.channel_id,
+            channel_type: message.channel_type,
+            message_id,
+            reason: if stop {
+                "live_location_stop".to_string()
+            } else {
+                "live_location_update".to_string()
+            },
+        });
+        Ok(next_attempt_at)
     }
 
-    async fn is_connected(&self) -> bool {
-        match self.transport.as_ref() {
-            Some(transport) => transport.is_connected().await,
-            None => false,
+    async fn record_message_pin(&mut self, message_id: u64, pinned: bool) -> Result<()> {
+        let message = self
+            .storage
+            .get_message_by_id(message_id)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("message {message_id}")))?;
+        if message.channel_type != 2 {
+            return Err(Error::InvalidArgument(
+                "message pinning is only supported in group channels".to_string(),
+            ));
         }
+        let server_message_id = message
+            .server_message_id
+            .filter(|&id| id > 0)
+            .ok_or_else(|| Error::InvalidState("message is not sent yet".to_string()))?;
+        let operator_id = self
+            .current_uid
+            .as_deref()
+            .and_then(|uid| uid.parse::<u64>().ok());
+        let command_id = format!(
+            "{}:{}",
+            message_pin::PIN_COMMAND,
+            self.next_local_message_id()?
+        );
+        self.storage
+            .message_pin_record(
+                command_id,
+                MessagePinCommand {
+                    message_id,
+                    server_message_id,
+                    channel_id: message.channel_id,
+                    channel_type: message.channel_type,
+                    pinned,
+                },
+                operator_id,
+            )
+            .await?;
+        self.invalidate_channel_cache_with_reason(
+            message.channel_id,
+            message.channel_type,
+            "pin_message",
+        );
+        self.pending_events.push(SdkEvent::PinnedMessagesChanged {
+            channel_id: message.channel_id,
+            channel_type: message.channel_type,
+        });
+        self.pending_events.push(SdkEvent::TimelineUpdated {
+            channel_id: message.channel_id,
+            channel_type: message.channel_type,
+            message_id,
+            reason: if pinned {
+                "pin".to_string()
+            } else {
+                "unpin".to_string()
+            },
+        });
+        Ok(())
     }
 
-    async fn probe_connection(&mut self) -> bool {
-        let transport = match self.transport.as_mut() {
-            Some(transport) => transport,
-            None => return false,
-        };
-        if !transport.is_connected().await {
-            return false;
-        }
-        let payload = match encode_message(&PingRequest {
-            timestamp: chrono::Utc::now().timestamp_millis(),
-        }) {
-            Ok(v) => v,
+    async fn run_message_pin_command(&mut self, command: &OutboxRpcCommand) -> Result<()> {
+        let pin: MessagePinCommand = match serde_json::from_slice(&command.payload) {
+            Ok(pin) => pin,
             Err(e) => {
-                eprintln!("[SDK.actor] monitor: encode ping failed: {e}");
-                return false;
+                tracing::warn!(
+                    command_id = %command.command_id,
+                    error = %e,
+                    "undecodable message pin command; dropping"
+                );
+                return self.storage.outbox_rpc_drop(command.id).await;
             }
         };
-        let options = RequestOptions::new()
-            .biz_type(MessageType::PingRequest as u8)
-            .timeout(Duration::from_secs(2));
-        match transport
-            .request_with_options(Bytes::from(payload), options)
+        let operator_id = self
+            .current_uid
+            .as_deref()
+            .and_then(|uid| uid.parse::<u64>().ok())
+            .unwrap_or(0);
+        let request = privchat_protocol::rpc::message::pin::MessagePinRequest {
+            group_id: pin.channel_id,
+            channel_id: pin.channel_id,
+            message_id: pin.server_message_id,
+            pinned: pin.pinned,
+            operator_id,
+        };
+        let confirmed = match self
+            .rpc_call_typed::<_, privchat_protocol::rpc::message::pin::MessagePinResponse>(
+                routes::message::PIN,
+                &request,
+            )
             .await
         {
-            Ok(raw) => match decode_message::<PongResponse>(&raw) {
-                Ok(_) => true,
-                Err(e) => {
-                    eprintln!("[SDK.actor] monitor: decode pong failed: {e}");
-                    true
-                }
-            },
+            Ok(resp) if resp.success => Some(RemotePin {
+                server_message_id: pin.server_message_id,
+                pinned_by: resp.pinned_by.or(Some(operator_id).filter(|&id| id > 0)),
+                pinned_at: resp
+                    .pinned_at
+                    .map(|at| at as i64)
+                    .unwrap_or_else(|| chrono::Utc::now().timestamp_millis()),
+            }),
+            Ok(_) => None,
+            Err(e) if e.is_retryable() => return Err(e),
             Err(e) => {
-                eprintln!("[SDK.actor] monitor: ping request failed: {e}");
-                false
+                tracing::warn!(
+                    message_id = pin.message_id,
+                    error = %e,
+                    "message pin rejected; reloading the server pin list"
+                );
+                None
+            }
+        };
+        let accepted = confirmed.is_some();
+        let (channel_id, channel_type, message_id) =
+            (pin.channel_id, pin.channel_type, pin.message_id);
+        self.storage
+            .message_pin_settled(command.id, pin, confirmed)
+            .await?;
+        if !accepted {
+            // 被拒时本地已经显示的置顶 / 取消置顶是错的，以服务端列表为准。拉不到就等下次
+            // sync_channel 或宿主 refresh。
+            if let Err(e) = self.refresh_pinned_messages(channel_id, channel_type).await {
+                tracing::warn!(
+                    channel_id,
+                    error = %e,
+                    "reloading pinned messages after a rejected pin failed"
+                );
             }
         }
+        self.pending_events.push(SdkEvent::PinnedMessagesChanged {
+            channel_id,
+            channel_type,
+        });
+        self.pending_events.push(SdkEvent::TimelineUpdated {
+            channel_id,
+            channel_type,
+            message_id,
+            reason: if accepted {
+                "pin_sent".to_string()
+            } else {
+                "pin_rejected".to_string()
+            },
+        });
+        Ok(())
     }
 
-    async fn connect_one(
-        &self,
-        ep: &ServerEndpoint,
-    ) -> Result<(TransportClient, msgtrans::ClientEvents)> {
-        if actor_logs_enabled() {
-            eprintln!("[SDK.actor] connect_one: begin");
+    async fn refresh_pinned_messages(
+        &mut self,
+        channel_id: u64,
+        channel_type: i32,
+    ) -> Result<bool> {
+        if channel_type != 2 {
+            return Err(Error::InvalidArgument(
+                "message pinning is only supported in group channels".to_string(),
+            ));
         }
-        let timeout = self.timeout();
-        let target = Self::resolve_target(&ep.host, ep.port).await?;
-        let mut client = match ep.protocol {
-            TransportProtocol::Quic => {
-                let mut cfg = QuicClientConfig::new(&target)
-                    .map_err(|e| Error::Transport(format!("quic config: {e}")))?
-                    .connect_timeout(timeout)
-                    .server_name(ep.host.clone());
-                if quic_accept_self_signed_for_testing_enabled() {
-                    warn_quic_insecure_verification_disabled_once();
-                    cfg = cfg.danger_skip_verification();
-                }
-                TransportClientBuilder::new()
-                    .protocol(cfg)
-                    .build()
-                    .await
-                    .map_err(|e| Error::Transport(format!("quic build: {e}")))?
-            }
-            TransportProtocol::Tcp => {
-                let cfg = TcpClientConfig::new(&target)
-                    .map_err(|e| Error::Transport(format!("tcp config: {e}")))?
-                    .connect_timeout(timeout);
-                TransportClientBuilder::new()
-                    .protocol(cfg)
-                    .build()
-                    .await
-                    .map_err(|e| Error::Transport(format!("tcp build: {e}")))?
-            }
-            TransportProtocol::WebSocket => {
-                let path = ep.path.as_deref().unwrap_or("/");
-                let url = if ep.use_tls {
-                    format!("wss://{}:{}{}", ep.host, ep.port, path)
-                } else {
-                    format!("ws://{}:{}{}", ep.host, ep.port, path)
-                };
-                let cfg = WebSocketClientConfig::new(&url)
-                    .map_err(|e| Error::Transport(format!("ws config: {e}")))?
-                    .connect_timeout(timeout)
-                    // msgtrans 2.0: TLS 行为改为 ClientTls 枚举(旧 verify_tls
-                    // 是从未接线的假开关);use_tls=false 的端点走 ws:// 本就
-                    // 不触发 TLS,这里映射为 Insecure 仅保语义完整。
-                    .tls(if ep.use_tls {
-                        msgtrans::ClientTls::SystemRoots
-                    } else {
-                        msgtrans::ClientTls::Insecure
-                    });
-                TransportClientBuilder::new()
-                    .protocol(cfg)
-                    .build()
-                    .await
-                    .map_err(|e| Error::Transport(format!("ws build: {e}")))?
-            }
+        let user_id = self
+            .current_uid
+            .as_deref()
+            .and_then(|uid| uid.parse::<u64>().ok())
+            .unwrap_or(0);
+        let resp: privchat_protocol::rpc::message::pin::MessagePinListResponse = self
+            .rpc_call_typed(
+                routes::message::PIN_LIST,
+                &privchat_protocol::rpc::message::pin::MessagePinListRequest {
+                    group_id: channel_id,
+                    user_id,
+                },
+            )
+            .await?;
+        let pins: Vec<RemotePin> = resp
+            .items
+            .into_iter()
+            .filter(|item| item.channel_id == 0 || item.channel_id == channel_id)
+            .map(|item| RemotePin {
+                server_message_id: item.message_id,
+                pinned_by: Some(item.pinned_by).filter(|&id| id > 0),
+                pinned_at: item.pinned_at as i64,
+            })
+            .collect();
+        let changed = self
+            .storage
+            .replace_channel_pins(channel_id, channel_type, pins)
+            .await?;
+        if changed {
+            self.invalidate_channel_cache_with_reason(
+                channel_id,
+                channel_type,
+                "refresh_pinned_messages",
+            );
+            self.pending_events.push(SdkEvent::PinnedMessagesChanged {
+                channel_id,
+                channel_type,
+            });
+        }
+        Ok(changed)
+    }
+
+    /// 表情目录读出来时补上已经预取到本地的图。取不到账号目录就都当没下载。
+    async fn with_sticker_assets(&self, stickers: &mut [Sticker]) {
+        let Ok(paths) = self.storage.get_storage_paths().await else {
+            return;
         };
-        // 先取事件流再 connect：事件流是有界队列，连接期间的事件会排队而不是丢失。
-        let events = client
-            .events()
-            .await
-            .map_err(|e| Error::Transport(format!("events: {e}")))?;
-        client
-            .connect()
-            .await
-            .map_err(|e| Error::Transport(format!("connect: {e}")))?;
-        if actor_logs_enabled() {
-            eprintln!("[SDK.actor] connect_one: connected");
+        for sticker in stickers {
+            sticker.local_path = sticker::local_asset(&paths.user_root, sticker);
         }
-        Ok((client, events))
     }
 
-    async fn login(
-        &mut self,
-        username: String,
-        password: String,
-        device_id: String,
-    ) -> Result<LoginResult> {
-        if actor_logs_enabled() {
-            eprintln!("[SDK.actor] login: enter");
+    async fn with_package_assets(&self, packages: &mut [StickerPackage]) {
+        for package in packages {
+            self.with_sticker_assets(&mut package.stickers).await;
         }
-        let timeout = self.timeout();
-        let os = std::env::consts::OS.to_string();
-        let device_name = default_device_name(&os);
-        let device_model = default_device_model();
-        let manufacturer = default_manufacturer();
-        let app_id = default_app_id(&os);
-        let req = RpcRequest {
-            route: "account/auth/login".to_string(),
-            body: serde_json::to_vec(&AuthLoginRequest {
-                username,
-                password,
-                device_id: device_id.clone(),
-                device_info: Some(DeviceInfo {
-                    device_id: device_id.clone(),
-                    device_type: DeviceType::from_str(&os),
-                    app_id: app_id.clone(),
-                    push_token: None,
-                    push_channel: None,
-                    device_name,
-                    device_model,
-                    os_version: Some(os),
-                    app_version: Some("0.1.0".to_string()),
-                    manufacturer,
-                    device_fingerprint: None,
-                }),
-            })
-            .map_err(|e| Error::Serialization(format!("encode login body: {e}")))?,
-        };
+    }
 
-        let payload = encode_message(&req)
-            .map_err(|e| Error::Serialization(format!("encode rpc request: {e}")))?;
-        let raw = self
-            .request_bytes(
-                Bytes::from(payload),
-                MessageType::RpcRequest as u8,
-                timeout,
-                "rpc login",
+    async fn list_sticker_packages(&self, installed_only: bool) -> Result<Vec<StickerPackage>> {
+        let mut packages = self.storage.list_sticker_packages(installed_only).await?;
+        self.with_package_assets(&mut packages).await;
+        Ok(packages)
+    }
+
+    /// 拉服务端的表情包列表缓存到本地，返回本地目录（已安装的在前）。
+    async fn refresh_sticker_packages(&mut self) -> Result<Vec<StickerPackage>> {
+        let resp: StickerPackageListResponse = self
+            .rpc_call_typed(routes::sticker::PACKAGE_LIST, &StickerPackageListRequest {})
+            .await?;
+        let packages: Vec<StickerPackage> = resp
+            .packages
+            .into_iter()
+            .map(StickerPackage::from)
+            .collect();
+        self.storage.cache_sticker_packages(packages).await?;
+        self.pending_events.push(SdkEvent::StickerCatalogChanged);
+        let packages = self.list_sticker_packages(false).await?;
+        for package in packages.iter().filter(|p| p.installed) {
+            self.submit_sticker_prefetch(package);
+        }
+        Ok(packages)
+    }
+
+    /// 拉一个包的详情（含表情列表）缓存到本地；已安装的顺带预取表情图。
+    async fn refresh_sticker_package(&mut self, package_id: String) -> Result<StickerPackage> {
+        let resp: StickerPackageDetailResponse = self
+            .rpc_call_typed(
+                routes::sticker::PACKAGE_DETAIL,
+                &StickerPackageDetailRequest {
+                    package_id: package_id.clone(),
+                },
             )
             .await?;
-
-        let rpc_resp: RpcResponse = decode_message(&raw)
-            .map_err(|e| Error::Serialization(format!("decode rpc response: {e}")))?;
-        if rpc_resp.code != 0 {
-            return Err(Error::Auth(rpc_resp.message));
+        self.storage
+            .cache_sticker_packages(vec![StickerPackage::from(resp.package)])
+            .await?;
+        self.pending_events.push(SdkEvent::StickerCatalogChanged);
+        let mut package = self
+            .storage
+            .get_sticker_package(package_id.clone())
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("sticker package {package_id}")))?;
+        if package.installed {
+            self.submit_sticker_prefetch(&package);
+        }
+        self.with_sticker_assets(&mut package.stickers).await;
+        Ok(package)
+    }
+
+    /// 新装上的包（自己装的、同步下来的）交给预取：还没有表情列表的，预取任务先拉一次
+    /// 详情。都在 `DownloadManager` 里跑，不占 actor；尽力而为，失败等下次打开表情面板时
+    /// 宿主 refresh。
+    async fn load_installed_sticker_packages(&mut self, package_ids: Vec<String>) {
+        for package_id in package_ids {
+            match self.storage.get_sticker_package(package_id.clone()).await {
+                Ok(Some(package)) => {
+                    self.submit_sticker_prefetch(&package);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::warn!(%package_id, error = %e, "read installed sticker package failed");
+                }
+            }
         }
-        let body = rpc_resp
-            .data
-            .ok_or_else(|| Error::Serialization("empty rpc data".into()))?;
-        let auth: AuthResponse = serde_json::from_slice(&body)
-            .map_err(|e| Error::Serialization(format!("decode auth response: {e}")))?;
+    }
 
-        let out = LoginResult {
-            user_id: auth.user_id,
-            token: auth.token,
-            device_id: auth.device_id,
-            refresh_token: auth.refresh_token,
-            expires_at: auth.expires_at,
-        };
-        let uid = out.user_id.to_string();
-        self.storage.save_login(uid.clone(), out.clone()).await?;
-        self.storage.flush_user(uid.clone()).await?;
-        self.current_uid = Some(uid);
-        self.download_manager.cancel_all_scoped();
-        self.session_epoch += 1;
-        if let Err(e) = self.replay_prelogin_inbound_frames().await {
-            eprintln!("[SDK.inbound] replay after login failed: {e}");
+    /// 卸掉的包：停掉它的预取，删掉 `{user_root}/stickers/{package_id}/`。尽力而为。
+    async fn remove_sticker_assets(&self, package_id: &str) {
+        if let Some(owner_uid) = self.current_uid.clone() {
+            self.download_manager
+                .abort_background(&media_download::MediaTaskKey::sticker_assets(
+                    owner_uid,
+                    self.session_epoch,
+                    package_id,
+                ));
         }
-        if actor_logs_enabled() {
-            eprintln!("[SDK.actor] login: success user_id={}", out.user_id);
+        let Ok(paths) = self.storage.get_storage_paths().await else {
+            return;
+        };
+        let dir = sticker::package_dir(&paths.user_root, package_id);
+        let removed = tokio::task::spawn_blocking(move || match std::fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            other => other,
+        })
+        .await;
+        if let Ok(Err(error)) = removed {
+            tracing::warn!(%package_id, %error, "remove sticker assets failed");
         }
-        Ok(out)
     }
 
-    async fn register(
-        &mut self,
-        username: String,
-        password: String,
-        device_id: String,
-    ) -> Result<LoginResult> {
-        let timeout = self.timeout();
-        let os = std::env::consts::OS.to_string();
-        let device_name = default_device_name(&os);
-        let device_model = default_device_model();
-        let manufacturer = default_manufacturer();
-        let app_id = default_app_id(&os);
-        let req = RpcRequest {
-            route: routes::account_user::REGISTER.to_string(),
-            body: serde_json::to_vec(&UserRegisterRequest {
-                username,
-                password,
-                nickname: None,
-                phone: None,
-                email: None,
-                device_id: device_id.clone(),
-                device_info: Some(DeviceInfo {
-                    device_id: device_id.clone(),
-                    device_type: DeviceType::from_str(&os),
-                    app_id,
-                    push_token: None,
-                    push_channel: None,
-                    device_name,
-                    device_model,
-                    os_version: Some(os),
-                    app_version: Some("0.1.0".to_string()),
-                    manufacturer,
-                    device_fingerprint: None,
-                }),
-            })
-            .map_err(|e| Error::Serialization(format!("encode register body: {e}")))?,
-        };
-        let payload = encode_message(&req)
-            .map_err(|e| Error::Serialization(format!("encode register request: {e}")))?;
-        let raw = self
-            .request_bytes(
-                Bytes::from(payload),
-                MessageType::RpcRequest as u8,
-                timeout,
-                "rpc register",
+    /// 本地先改、命令进 outbox，不等服务端：离线也装得上。从别人的表情消息里「添加这个包」
+    /// 时本地可能还没见过它，详情由预取任务去拉。
+    async fn record_sticker_install(&mut self, package_id: String, installed: bool) -> Result<()> {
+        let command_id = format!(
+            "{}:{}",
+            sticker::INSTALL_COMMAND,
+            self.next_local_message_id()?
+        );
+        self.storage
+            .sticker_install_record(
+                command_id,
+                StickerInstallCommand {
+                    package_id: package_id.clone(),
+                    installed,
+                    previous: None,
+                },
             )
             .await?;
-        let rpc_resp: RpcResponse = decode_message(&raw)
-            .map_err(|e| Error::Serialization(format!("decode register response: {e}")))?;
-        if rpc_resp.code != 0 {
-            return Err(Error::Auth(rpc_resp.message));
-        }
-        let body = rpc_resp
-            .data
-            .ok_or_else(|| Error::Serialization("empty register data".into()))?;
-        let auth: AuthResponse = serde_json::from_slice(&body)
-            .map_err(|e| Error::Serialization(format!("decode register auth response: {e}")))?;
-        let out = LoginResult {
-            user_id: auth.user_id,
-            token: auth.token,
-            device_id: auth.device_id,
-            refresh_token: auth.refresh_token,
-            expires_at: auth.expires_at,
-        };
-        let uid = out.user_id.to_string();
-        self.storage.save_login(uid.clone(), out.clone()).await?;
-        self.storage.flush_user(uid.clone()).await?;
-        self.current_uid = Some(uid);
-        self.download_manager.cancel_all_scoped();
-        self.session_epoch += 1;
-        if let Err(e) = self.replay_prelogin_inbound_frames().await {
-            eprintln!("[SDK.inbound] replay after register failed: {e}");
+        self.pending_events.push(SdkEvent::StickerCatalogChanged);
+        if installed {
+            self.load_installed_sticker_packages(vec![package_id]).await;
+        } else {
+            self.remove_sticker_assets(&package_id).await;
         }
-        Ok(out)
+        Ok(())
     }
 
-    async fn authenticate(&mut self, user_id: u64, token: String, device_id: String) -> Result<()> {
-        if actor_logs_enabled() {
-            eprintln!("[SDK.actor] authenticate: enter user_id={user_id}");
-        }
-        // ConnAuth gets a short, request-only deadline. Retrying a silent
-        // connection after five seconds is safer than holding startup for the
-        // general 30-second RPC timeout. Token persistence below is deliberately
-        // outside this transport deadline.
-        let timeout = auth_request_timeout(self.timeout());
-        let token_for_persist = token.clone();
-        let device_id_for_persist = device_id.clone();
-        let os = std::env::consts::OS.to_string();
-        let app_id = default_app_id(&os);
-        let app_package = default_app_package(&os);
-        let device_name = default_device_name(&os);
-        let device_model = default_device_model();
-        let manufacturer = default_manufacturer();
-        let req = AuthorizationRequest {
-            auth_type: AuthType::JWT,
-            auth_token: token,
-            client_info: ClientInfo {
-                client_type: default_client_type(&os),
-                version: "0.1.0".to_string(),
-                os: os.clone(),
-                os_version: os.clone(),
-                device_model: device_model.clone(),
-                app_package,
-            },
-            device_info: DeviceInfo {
-                device_id,
-                device_type: DeviceType::from_str(std::env::consts::OS),
-                app_id,
-                push_token: None,
-                push_channel: None,
-                device_name,
-                device_model,
-                os_version: Some(os),
-                app_version: Some("0.1.0".to_string()),
-                manufacturer,
-                device_fingerprint: None,
-            },
-            protocol_version: "1.0".to_string(),
-            properties: HashMap::from([
-                ("user_id".to_string(), user_id.to_string()),
-                (
-                    "client_timestamp".to_string(),
-                    chrono::Utc::now().timestamp_millis().to_string(),
-                ),
-            ]),
+    async fn run_sticker_install_command(&mut self, command: &OutboxRpcCommand) -> Result<()> {
+        let install: StickerInstallCommand = match serde_json::from_slice(&command.payload) {
+            Ok(install) => install,
+            Err(e) => {
+                tracing::warn!(
+                    command_id = %command.command_id,
+                    error = %e,
+                    "undecodable sticker install command; dropping"
+                );
+                return self.storage.outbox_rpc_drop(command.id).await;
+            }
         };
-        let payload = encode_message(&req)
-            .map_err(|e| Error::Serialization(format!("encode auth request: {e}")))?;
-        let raw = self
-            .request_bytes(
-                Bytes::from(payload),
-                MessageType::AuthorizationRequest as u8,
-                timeout,
-                "auth request",
-            )
-            .await?;
-        let auth_resp: AuthorizationResponse = decode_message(&raw)
-            .map_err(|e| Error::Serialization(format!("decode auth response: {e}")))?;
-        if !auth_resp.success {
-            // 把服务端 error_code（u32）塞进 message 前缀 `[<code>] ...`，供上层 auth_kind() 解出。
-            // 0 表示未携带码，归 Transient。
-            let code = auth_resp.error_code.unwrap_or(0);
-            let message = auth_resp
-                .error_message
-                .unwrap_or_else(|| "authorization failed".to_string());
-            return Err(Error::Auth(format!("[{}] {}", code, message)));
-        }
-        let uid = user_id.to_string();
-        // 换人了：旧账号的会话作用域状态一律作废（与 SwitchLocalAccount 的 ④ 同一套清理）。
-        //
-        // 最要命的一项是 sync_coordinator 的 readiness。不重置的话新账号沿用上一个账号的
-        // 「已就绪」：宿主看到 Ready 就跳过 bootstrap，而本地优先读又被 bootstrap gate 挡回
-        // （`run_bootstrap_sync required before local-first operations`）——两头落空，界面上
-        // 就永远停在**上一个账号**的会话列表，直到冷启动才恢复正常。真机上表现为切/加账号后
-        // 看到别人的会话标题和消息预览，属于跨账号数据泄露。
-        //
-        // 「添加账号 → 登录另一个身份」走的是裸 authenticate，不经过 SwitchLocalAccount，
-        // 所以那套清理必须在这里也做一遍。
-        self.reset_if_owner_changed(&uid, chrono::Utc::now().timestamp_millis());
-        // authenticate() 的职责只是用当前 access_token 握手；不应覆盖 login/register 写入的
-        // refresh_token 或过期时间。若该用户已有会话，只原子刷新 access_token；否则才走
-        // save_login（用于外部认证首次 handshake，无 refresh_token）。
-        let existing_session = self.storage.load_session(uid.clone()).await.ok().flatten();
-        if let Some(existing) = existing_session.as_ref() {
-            // 冷启动 restore：从持久化 snapshot 恢复 bootstrap_completed，与自动重连路径
-            // （restore_persisted_session）保持一致。否则每次冷启动 bootstrap_completed 停留在
-            // false → ① 本地优先读（get_channels 等）被 current_uid_required gate 拒绝，UI 只能
-            // 等网络 sync 才出会话；② 后续 run_bootstrap_sync 被迫走全量而非增量 resume。
-            // full_rebuild_required() 仍是安全网：本地 store 需重建时即便标记为 true 也会走全量。
-            self.bootstrap_completed = existing.bootstrap_completed;
-        } else {
-            // 没有快照 ≠ 没同步过。退出登录只删凭证，账号的 bootstrap 水位仍在盘上，
-            // 只是读不进快照里了。不在这里补读，同一个账号重新登录就会被当成新账号
-            // 跑一遍全量 bootstrap——本地库明明还是全的。
-            self.bootstrap_completed = self
-                .storage
-                .load_bootstrap_completed(uid.clone())
-                .await
-                .unwrap_or(false);
-        }
-        if existing_session.is_some() {
-            self.storage
-                .update_access_token(uid.clone(), token_for_persist, None)
-                .await?;
-            // update_access_token 不写 K_CUR_UID；与 save_login 分支保持一致，确保磁盘 current_uid
-            // 与本次 authenticate 的 uid 对齐，避免后续 with_uid! 命令读到 None。
-            self.storage.save_current_uid(uid.clone()).await?;
+        let route = if install.installed {
+            sticker::ROUTE_INSTALL
         } else {
-            self.storage
-                .save_login(
-                    uid.clone(),
-                    LoginResult {
-                        user_id,
-                        token: token_for_persist,
-                        device_id: device_id_for_persist,
-                        refresh_token: None,
-                        expires_at: 0,
-                    },
-                )
-                .await?;
-        }
-        self.storage.flush_user(uid.clone()).await?;
-        self.current_uid = Some(uid);
-        self.download_manager.cancel_all_scoped();
-        self.session_epoch += 1;
-        if actor_logs_enabled() {
-            eprintln!("[SDK.actor] authenticate: success");
+            sticker::ROUTE_UNINSTALL
+        };
+        let request = StickerInstallRequest {
+            package_id: install.package_id.clone(),
+            command_id: command.command_id.clone(),
+        };
+        let accepted = match self
+            .rpc_call_typed::<_, serde_json::Value>(route, &request)
+            .await
+        {
+            Ok(_) => true,
+            Err(e) if e.is_retryable() => return Err(e),
+            Err(e) if sticker::route_unavailable(&e) => {
+                tracing::warn!(
+                    package_id = %install.package_id,
+                    error = %e,
+                    "server has no sticker install route; keeping the install local"
+                );
+                true
+            }
+            Err(e) => {
+                tracing::warn!(
+                    package_id = %install.package_id,
+                    error = %e,
+                    "sticker package install rejected; reverting the local state"
+                );
+                false
+            }
+        };
+        let package_id = install.package_id.clone();
+        if self
+            .storage
+            .sticker_install_settled(command.id, install, accepted)
+            .await?
+        {
+            self.pending_events.push(SdkEvent::StickerCatalogChanged);
+            // 退回之后装着的接着预取，没装的删掉图。
+            match self.storage.get_sticker_package(package_id.clone()).await {
+                Ok(Some(package)) if package.installed => {
+                    self.submit_sticker_prefetch(&package);
+                }
+                _ => self.remove_sticker_assets(&package_id).await,
+            }
         }
         Ok(())
     }
 
-    async fn sync_entities(&mut self, entity_type: String, scope: Option<String>) -> Result<usize> {
-        let timeout = self.timeout();
-        let entity_type_for_apply = entity_type.clone();
-        let scope_for_apply = scope.clone();
-        let persist_cursor =
-            Self::should_persist_sync_cursor(&entity_type_for_apply, scope_for_apply.as_deref());
-        self.last_sync_entity_events.clear();
-        let mut since_version = if persist_cursor {
-            self.load_sync_since_version(&entity_type_for_apply, scope_for_apply.as_deref())
-                .await
-                .unwrap_or(0)
-        } else {
-            0
+    /// 把一个已安装包里还没下载的表情图交给 `DownloadManager`，后台优先级、一个包一个任务。
+    /// 还没有表情列表的包，任务先拉一次详情交回 actor 落库。省流量生效时只拉详情、不预取
+    /// 图（发送、打开面板时宿主照常按 URL 显示）。
+    fn submit_sticker_prefetch(&self, package: &StickerPackage) -> bool {
+        if !package.stickers.is_empty() && self.bandwidth.data_saver_active() {
+            return false;
+        }
+        let Some(owner_uid) = self.current_uid.clone() else {
+            return false;
         };
-        let mut total_queued = 0usize;
-        let mut total_dropped = 0usize;
-        let mut fetched_pages = 0usize;
-        let mut restarted_full_sync = false;
-        let final_next_version = loop {
-            fetched_pages += 1;
-            if fetched_pages > 64 {
-                return Err(Error::InvalidState(
-                    "sync_entities exceeded max paging iterations".to_string(),
-                ));
-            }
-
-            let req = privchat_protocol::rpc::sync::SyncEntitiesRequest {
-                entity_type: entity_type_for_apply.clone(),
-                since_version: Some(since_version),
-                scope: scope_for_apply.clone(),
-                limit: Some(200),
-            };
-            let request = RpcRequest {
-                route: privchat_protocol::rpc::routes::entity::SYNC_ENTITIES.to_string(),
-                body: serde_json::to_vec(&req)
-                    .map_err(|e| Error::Serialization(format!("encode sync_entities body: {e}")))?,
-            };
-            let payload = encode_message(&request)
-                .map_err(|e| Error::Serialization(format!("encode sync_entities rpc: {e}")))?;
-            let raw = self
-                .request_bytes(
-                    Bytes::from(payload),
-                    MessageType::RpcRequest as u8,
-                    timeout,
-                    "rpc sync_entities",
-                )
-                .await?;
-            let rpc_resp: RpcResponse = decode_message(&raw)
-                .map_err(|e| Error::Serialization(format!("decode sync_entities rpc: {e}")))?;
-            if rpc_resp.code != 0 {
-                let data_preview = rpc_resp
-                    .data
-                    .as_ref()
-                    .map(|v| {
-                        let s = String::from_utf8_lossy(v);
-                        if s.len() > 512 {
-                            format!("{}...", &s[..512])
-                        } else {
-                            s.into_owned()
+        let key = media_download::MediaTaskKey::sticker_assets(
+            owner_uid,
+            self.session_epoch,
+            &package.package_id,
+        );
+        let storage = self.storage.clone();
+        let metrics = self.metrics.clone();
+        let bandwidth = self.bandwidth.clone();
+        let actor_tx = self.actor_tx.clone();
+        let transport = self.transport.clone();
+        let timeout = self.timeout();
+        let package_id = package.package_id.clone();
+        let stickers = package.stickers.clone();
+        let task_key = key.clone();
+        self.download_manager.submit(
+            key,
+            media_download::DownloadPriority::Background,
+            async move {
+                let stickers = if stickers.is_empty() {
+                    let Some(transport) = transport.as_ref() else {
+                        return;
+                    };
+                    let request = StickerPackageDetailRequest {
+                        package_id: package_id.clone(),
+                    };
+                    let detail: StickerPackageDetailResponse = match State::rpc_call_typed_detached(
+                        transport,
+                        routes::sticker::PACKAGE_DETAIL,
+                        &request,
+                        timeout,
+                    )
+                    .await
+                    {
+                        Ok(detail) => detail,
+                        Err(error) => {
+                            tracing::warn!(%package_id, %error, "loading sticker package failed");
+                            return;
                         }
-                    })
-                    .unwrap_or_else(|| "<none>".to_string());
-                if Self::should_log_unsupported_entity_skip(&entity_type_for_apply)
-                    || !matches!(rpc_resp.code, 10100)
-                {
-                    eprintln!(
-                        "[SDK.actor] sync_entities rpc rejected: entity_type={} scope={:?} since_version={} code={} message={} data={}",
-                        entity_type_for_apply,
-                        scope_for_apply,
-                        since_version,
-                        rpc_resp.code,
-                        rpc_resp.message,
-                        data_preview
-                    );
+                    };
+                    let package = StickerPackage::from(detail.package);
+                    let stickers = package.stickers.clone();
+                    if let Some(actor_tx) = actor_tx.upgrade() {
+                        let _ = actor_tx
+                            .send(Command::CompleteStickerPackageLoad {
+                                key: task_key.clone(),
+                                package,
+                            })
+                            .await;
+                    }
+                    if bandwidth.data_saver_active() {
+                        return;
+                    }
+                    stickers
+                } else {
+                    stickers
+                };
+                let Ok(paths) = storage
+                    .get_storage_paths_for_uid(task_key.owner_uid.clone())
+                    .await
+                else {
+                    return;
+                };
+                let mut downloaded = 0usize;
+                for sticker in &stickers {
+                    if sticker.image_url.is_empty()
+                        || sticker::local_asset(&paths.user_root, sticker).is_some()
+                    {
+                        continue;
+                    }
+                    let target = sticker::asset_path(&paths.user_root, sticker);
+                    let (Some(dir), Some(filename)) = (
+                        target.parent(),
+                        target.file_name().and_then(|name| name.to_str()),
+                    ) else {
+                        continue;
+                    };
+                    if let Err(error) = std::fs::create_dir_all(dir) {
+                        tracing::warn!(%error, "create sticker asset dir failed");
+                        return;
+                    }
+                    let ticket = ResolvedFileDownload::legacy_url(sticker.image_url.clone());
+                    match media_download::download_detached(
+                        &ticket, dir, filename, &metrics, &bandwidth,
+                    )
+                    .await
+                    {
+                        Ok(_) => downloaded += 1,
+                        Err(error) => {
+                            tracing::warn!(
+                                sticker_id = %sticker.sticker_id,
+                                %error,
+                                "sticker asset prefetch failed"
+                            );
+                        }
+                    }
                 }
-                return Err(Self::sync_rpc_rejection(
-                    &format!(
-                        "sync_entities entity_type={} scope={:?} since_version={}",
-                        entity_type_for_apply, scope_for_apply, since_version
-                    ),
-                    rpc_resp.code,
-                    rpc_resp.message,
-                ));
-            }
-            let body = rpc_resp
-                .data
-                .ok_or_else(|| Error::Serialization("empty sync_entities data".into()))?;
-            let response: privchat_protocol::rpc::sync::SyncEntitiesResponse =
-                serde_json::from_slice(&body).map_err(|e| {
-                    Error::Serialization(format!("decode sync_entities response: {e}"))
-                })?;
-            if let Some(min_version) = response.min_version {
-                if since_version < min_version && since_version > 0 && !restarted_full_sync {
-                    restarted_full_sync = true;
-                    since_version = 0;
-                    total_queued = 0;
-                    total_dropped = 0;
-                    continue;
-                }
-            }
-            let stats = self.receive_pipeline.enqueue(
-                entity_type_for_apply.clone(),
-                scope_for_apply.clone(),
-                response.items,
-            );
-            total_queued += stats.queued_items;
-            total_dropped += stats.dropped_duplicates;
-
-            // 每页报一次，宿主用它在「本族区间」内往前爬。
-            //
-            // 没有这个事件，首屏进度条会在第一族上停 9 秒不动：整轮同步六成时间花在
-            // 联系人那一族，而族级事件要等它整个跑完才发。页事件让用户看得出还在动。
-            self.emit_sync_page_progress(&entity_type_for_apply, fetched_pages);
-
-            if !response.has_more {
-                break response.next_version;
-            }
-            if response.next_version <= since_version {
-                return Err(Error::InvalidState(format!(
-                    "sync_entities paging stalled: entity_type={} scope={:?} since={} next={}",
-                    entity_type_for_apply, scope_for_apply, since_version, response.next_version
-                )));
-            }
-            since_version = response.next_version;
-        };
-
-        let mut applied = 0usize;
-        let mut entity_events: Vec<SdkEvent> = Vec::new();
-        for batch in SyncCommitApplier::drain_batches(&mut self.receive_pipeline) {
-            let count = batch.items.len();
-            match self
-                .apply_sync_entities(
-                    &batch.entity_type,
-                    batch.scope.as_deref(),
-                    &batch.items,
-                    false,
-                )
-                .await
-            {
-                Ok(events) => {
-                    applied += count;
-                    entity_events.extend(events);
+                if downloaded == 0 {
+                    return;
                 }
-                Err(err) => {
-                    SyncCommitApplier::requeue_front(&mut self.receive_pipeline, batch);
-                    return Err(err);
+                if let Some(actor_tx) = actor_tx.upgrade() {
+                    let _ = actor_tx
+                        .send(Command::CompleteStickerPrefetch { key: task_key })
+                        .await;
                 }
-            }
-        }
-        if persist_cursor {
-            self.save_sync_next_version(
-                &entity_type_for_apply,
-                scope_for_apply.as_deref(),
-                final_next_version,
-            )
-            .await?;
-        }
-        self.last_sync_queued = total_queued;
-        self.last_sync_dropped_duplicates = total_dropped;
-        self.invalidate_cache_for_events(&entity_events);
-        self.last_sync_entity_events = entity_events;
-        if scope_for_apply.is_none() {
-            self.clear_resume_repair_key(Self::resume_repair_entity_key(&entity_type_for_apply))
-                .await;
-        }
-        Ok(applied)
+            },
+        )
     }
 
-    async fn send_session_ready(&mut self) -> Result<()> {
-        let timeout = self.timeout();
-        let ready_req = privchat_protocol::rpc::sync::SessionReadyRequest {};
-        let ready_rpc = RpcRequest {
-            route: routes::sync::SESSION_READY.to_string(),
-            body: serde_json::to_vec(&ready_req)
-                .map_err(|e| Error::Serialization(format!("encode session_ready body: {e}")))?,
-        };
-        let payload = encode_message(&ready_rpc)
-            .map_err(|e| Error::Serialization(format!("encode session_ready rpc: {e}")))?;
-        let raw = self
-            .request_bytes(
-                Bytes::from(payload),
-                MessageType::RpcRequest as u8,
-                timeout,
-                "rpc session_ready",
-            )
-            .await?;
-        let rpc_resp: RpcResponse = decode_message(&raw)
-            .map_err(|e| Error::Serialization(format!("decode session_ready rpc: {e}")))?;
-        if rpc_resp.code != 0 {
-            return Err(Self::sync_rpc_rejection(
-                "session_ready",
-                rpc_resp.code,
-                rpc_resp.message,
-            ));
+    /// 预取任务拉到了一个包的详情：落库。只认当前会话的。
+    async fn apply_sticker_package_load(
+        &mut self,
+        key: media_download::MediaTaskKey,
+        package: StickerPackage,
+    ) {
+        if self.current_uid.as_deref() != Some(key.owner_uid.as_str())
+            || self.session_epoch != key.session_epoch
+        {
+            return;
         }
-        let body = rpc_resp
-            .data
-            .ok_or_else(|| Error::Serialization("empty session_ready data".into()))?;
-        let ready_ok: privchat_protocol::rpc::sync::SessionReadyResponse =
-            serde_json::from_slice(&body)
-                .map_err(|e| Error::Serialization(format!("decode session_ready response: {e}")))?;
-        if !ready_ok {
-            return Err(Error::InvalidState(
-                "session_ready rejected by server".to_string(),
-            ));
+        let package_id = package.package_id.clone();
+        match self.storage.cache_sticker_packages(vec![package]).await {
+            Ok(()) => self.pending_events.push(SdkEvent::StickerCatalogChanged),
+            Err(e) => {
+                tracing::warn!(%package_id, error = %e, "caching loaded sticker package failed");
+            }
         }
-        Ok(())
     }
 
-    async fn get_difference(
-        &mut self,
-        channel_id: u64,
-        channel_type: i32,
-        last_pts: u64,
-        limit: Option<u32>,
-    ) -> Result<GetDifferenceResponse> {
-        let timeout = self.timeout();
-        let req = GetDifferenceRequest {
-            channel_id,
-            channel_type: u8::try_from(channel_type).unwrap_or(1),
-            last_pts,
-            limit,
-        };
-        let request = RpcRequest {
-            route: routes::sync::GET_DIFFERENCE.to_string(),
-            body: serde_json::to_vec(&req)
-                .map_err(|e| Error::Serialization(format!("encode get_difference body: {e}")))?,
-        };
-        let payload = encode_message(&request)
-            .map_err(|e| Error::Serialization(format!("encode get_difference rpc: {e}")))?;
-        let raw = self
-            .request_bytes(
-                Bytes::from(payload),
-                MessageType::RpcRequest as u8,
-                timeout,
-                "rpc get_difference",
-            )
-            .await?;
-        let rpc_resp: RpcResponse = decode_message(&raw)
-            .map_err(|e| Error::Serialization(format!("decode get_difference rpc: {e}")))?;
-        if rpc_resp.code != 0 {
-            return Err(Self::sync_rpc_rejection(
-                &format!(
-                    "get_difference channel_id={} channel_type={} last_pts={}",
-                    channel_id, channel_type, last_pts
-                ),
-                rpc_resp.code,
-                rpc_resp.message,
-            ));
+    /// 预取下完了一个包里的新图。只认当前会话的。
+    fn apply_sticker_prefetch_outcome(&mut self, key: media_download::MediaTaskKey) {
+        if self.current_uid.as_deref() == Some(key.owner_uid.as_str())
+            && self.session_epoch == key.session_epoch
+        {
+            self.pending_events.push(SdkEvent::StickerCatalogChanged);
         }
-        let body = rpc_resp
-            .data
-            .ok_or_else(|| Error::Serialization("empty get_difference data".into()))?;
-        serde_json::from_slice(&body)
-            .map_err(|e| Error::Serialization(format!("decode get_difference response: {e}")))
     }
 
-    async fn get_channel_pts(&mut self, channel_id: u64, channel_type: i32) -> Result<u64> {
-        let timeout = self.timeout();
-        let req = GetChannelPtsRequest {
-            channel_id,
-            channel_type: u8::try_from(channel_type).unwrap_or(1),
-        };
-        let request = RpcRequest {
-            route: routes::sync::GET_CHANNEL_PTS.to_string(),
-            body: serde_json::to_vec(&req)
-                .map_err(|e| Error::Serialization(format!("encode get_channel_pts body: {e}")))?,
-        };
-        let payload = encode_message(&request)
-            .map_err(|e| Error::Serialization(format!("encode get_channel_pts rpc: {e}")))?;
-        let raw = self
-            .request_bytes(
-                Bytes::from(payload),
-                MessageType::RpcRequest as u8,
-                timeout,
-                "rpc get_channel_pts",
-            )
+    /// 发表情消息前：表情必须在本地目录里（包里的、最近使用的、收藏的），顺带记一次使用。
+    async fn prepare_sticker_send(&mut self, sticker_id: String) -> Result<Sticker> {
+        let sticker = self
+            .storage
+            .get_sticker(sticker_id.clone())
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("sticker {sticker_id}")))?;
+        self.storage
+            .record_sticker_use(sticker.clone(), chrono::Utc::now().timestamp_millis())
             .await?;
-        let rpc_resp: RpcResponse = decode_message(&raw)
-            .map_err(|e| Error::Serialization(format!("decode get_channel_pts rpc: {e}")))?;
-        if rpc_resp.code != 0 {
-            return Err(Self::sync_rpc_rejection(
-                &format!(
-                    "get_channel_pts channel_id={} channel_type={}",
-                    channel_id, channel_type
-                ),
-                rpc_resp.code,
-                rpc_resp.message,
-            ));
-        }
-        let body = rpc_resp
-            .data
-            .ok_or_else(|| Error::Serialization("empty get_channel_pts data".into()))?;
-        let resp: GetChannelPtsResponse = serde_json::from_slice(&body)
-            .map_err(|e| Error::Serialization(format!("decode get_channel_pts response: {e}")))?;
-        Ok(resp.current_pts)
+        self.pending_events.push(SdkEvent::StickerCatalogChanged);
+        Ok(sticker)
     }
 
-    async fn batch_get_channel_pts(
-        &mut self,
-        channels: Vec<(u64, i32)>,
-    ) -> Result<BatchGetChannelPtsResponse> {
-        self.rpc_call_typed(
-            routes::sync::BATCH_GET_CHANNEL_PTS,
-            &BatchGetChannelPtsRequest {
-                channels: channels
-                    .into_iter()
-                    .map(|(channel_id, channel_type)| ChannelIdentifier {
-                        channel_id,
-                        channel_type: u8::try_from(channel_type).unwrap_or(1),
-                    })
-                    .collect(),
-            },
-        )
-        .await
+    fn connect_timeout_total(&self) -> Duration {
+        let per = self.config.connection_timeout_secs.max(1);
+        let endpoints = self.config.endpoints.len().max(1) as u64;
+        // Allow sequential fallback across endpoints plus a small scheduling buffer.
+        Duration::from_secs(per.saturating_mul(endpoints).saturating_add(2))
     }
 
-    /// wire message_type 字符串 → 本地 i32（history/around/hydrate 共用，防三处漂移）。
-    fn wire_message_type_to_i32(t: &str) -> i32 {
-        let ct = match t {
-            "image" => ContentMessageType::Image,
-            "voice" => ContentMessageType::Voice,
-            "video" => ContentMessageType::Video,
-            // 注：普通音频文件（mp3/wav/...）作为 File 消息发送，不再有独立 Audio 类型
-            "file" | "audio" => ContentMessageType::File,
-            // RP-12：资金卡片（服务端注入）历史/冷同步必须保留类型，否则落成 Text→渲染原始 JSON
-            "red_packet" => ContentMessageType::RedPacket,
-            "money_transfer" => ContentMessageType::MoneyTransfer,
-            "system" => ContentMessageType::System,
-            _ => ContentMessageType::Text,
-        };
-        i32::try_from(ct.as_u32()).unwrap_or(0)
+    async fn connect(&mut self) -> Result<()> {
+        if actor_logs_enabled() {
+            eprintln!(
+                "[SDK.actor] connect: enter (has_transport={}, endpoints={})",
+                self.transport.is_some(),
+                self.config.endpoints.len()
+            );
+        }
+        if let Some(transport) = self.transport.as_ref() {
+            if transport.is_connected().await {
+                if actor_logs_enabled() {
+                    eprintln!("[SDK.actor] connect: already connected");
+                }
+                return Ok(());
+            }
+            if actor_logs_enabled() {
+                eprintln!("[SDK.actor] connect: found stale transport, rebuilding");
+            }
+            self.transport = None;
+        }
+        let endpoints = self.config.endpoints.clone();
+        let mut last_err = None;
+        for ep in endpoints {
+            if actor_logs_enabled() {
+                match ep.protocol {
+                    TransportProtocol::WebSocket => {
+                        eprintln!(
+                            "[SDK.actor] connect: trying {:?} {}:{} tls={}",
+                            ep.protocol, ep.host, ep.port, ep.use_tls
+                        );
+                    }
+                    _ => {
+                        eprintln!(
+                            "[SDK.actor] connect: trying {:?} {}:{}",
+                            ep.protocol, ep.host, ep.port
+                        );
+                    }
+                }
+            }
+            match timeout(self.timeout(), self.connect_one(&ep)).await {
+                Ok(Ok((c, events))) => {
+                    self.transport = Some(Arc::new(c));
+                    *self.transport_events.lock().await = Some(events);
+                    if actor_logs_enabled() {
+                        eprintln!("[SDK.actor] connect: success");
+                    }
+                    return Ok(());
+                }
+                Ok(Err(e)) => {
+                    eprintln!("[SDK.actor] connect: endpoint failed: {e}");
+                    last_err = Some(e);
+                }
+                Err(_) => {
+                    let e = Error::Transport(format!(
+                        "endpoint {:?} {}:{} timeout",
+                        ep.protocol, ep.host, ep.port
+                    ));
+                    eprintln!("[SDK.actor] connect: endpoint failed: {e}");
+                    last_err = Some(e);
+                }
+            }
+        }
+        eprintln!("[SDK.actor] connect: all endpoints failed");
+        Err(last_err.unwrap_or_else(|| Error::Transport("no endpoint".into())))
     }
 
-    /// 单条历史消息落库（SDK-HISTORY-2 核心）：status=2 + 真实 pts(=message_seq)。
-    /// get/around 回填共用；upsert 的乱序回退 guard 由 order_seq 保证幂等。
-    /// history / around 响应的一条消息 → 落库入参。
-    ///
-    /// 一处定义,三条回填路径共用(store_history_item / channel hydrate /
-    /// bootstrap 内联 hydrate)。此前是三份手抄,`extra` 在其中两份里被写成空串,
-    /// 于是同一条图片消息「翻页翻到」和「hydrate 拉到」的行内容不一样——这种分叉
-    /// 只要还存在三份,就一定会再次发生。
-    fn history_item_to_upsert_input(
-        item: &MessageHistoryItem,
-        channel_type: i32,
-    ) -> UpsertRemoteMessageInput {
-        let canonical = crate::canonical_inbound::CanonicalInboundMessage::from_history_item(
-            item,
-            if channel_type == 0 { 1 } else { channel_type },
-            Self::wire_message_type_to_i32,
+    async fn try_auto_reconnect(
+        &mut self,
+        inbound_task: &mut Option<tokio::task::JoinHandle<()>>,
+        event_tx: &broadcast::Sender<SdkEvent>,
+        event_history: &Arc<StdMutex<VecDeque<SequencedSdkEvent>>>,
+        event_seq: &Arc<AtomicU64>,
+        event_history_limit: usize,
+    ) -> Result<SessionState> {
+        if realtime_trace_enabled() {
+            eprintln!(
+                "[SDK_RECONNECT_BEGIN] old_epoch={} state_before={:?} uid={:?}",
+                self.inbound_epoch, self.session_state, self.current_uid
+            );
+        }
+        tracing::debug!(
+            state_before = ?self.session_state,
+            current_uid_present = self.current_uid.is_some(),
+            "auto reconnect started"
         );
-        let mime_type = Self::extract_mime_type_from_json(&canonical.content, &canonical.extra);
-        // status=2：history 返回的都是服务端已确认的消息。
-        canonical.to_upsert_input(2, mime_type)
+        match timeout(self.connect_timeout_total(), self.connect()).await {
+            Ok(Ok(())) => {
+                self.session_state = SessionState::Connected;
+            }
+            Ok(Err(e)) => {
+                tracing::warn!(error = %e, "auto reconnect transport failed");
+                return Err(e);
+            }
+            Err(_) => {
+                let e = Error::Transport("reconnect timeout".to_string());
+                tracing::warn!(error = %e, "auto reconnect timed out");
+                return Err(e);
+            }
+        }
+        let uid = match self.current_uid.clone() {
+            Some(v) => v,
+            None => {
+                tracing::debug!("auto reconnect has no persisted user");
+                return Ok(SessionState::Connected);
+            }
+        };
+        let snapshot = match self.storage.load_session(uid.clone()).await {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                tracing::debug!(user_id = %uid, "auto reconnect has no session snapshot");
+                return Ok(SessionState::Connected);
+            }
+            Err(e) => {
+                tracing::warn!(user_id = %uid, error = %e, "auto reconnect session load failed");
+                return Ok(SessionState::Connected);
+            }
+        };
+
+        tracing::debug!(user_id = %snapshot.user_id, "restoring persisted session");
+        let user_id = snapshot.user_id;
+        let device_id = snapshot.device_id.clone();
+        let bootstrap_completed = snapshot.bootstrap_completed;
+        let has_access_token = !snapshot.token.is_empty();
+        tracing::debug!(
+            user_id = %user_id,
+            device_id = %device_id,
+            has_access_token,
+            state_before = ?self.session_state,
+            "authenticating restored session"
+        );
+        let mut driver = ActorAuthenticateRetryDriver {
+            state: self,
+            inbound_task,
+            event_tx,
+            event_history,
+            event_seq,
+            event_history_limit,
+            user_id,
+            token: snapshot.token,
+            device_id,
+        };
+        let auth_result = execute_authenticate_with_retry(&mut driver, false).await;
+        drop(driver);
+        match auth_result {
+            Ok(()) => {
+                self.bootstrap_completed = bootstrap_completed;
+                tracing::debug!(user_id = %user_id, "persisted session restored");
+                Ok(SessionState::Authenticated)
+            }
+            Err(e) => {
+                // A failed authentication must leave neither a live unauthenticated
+                // transport nor stale logical state behind. Use the same lifecycle
+                // teardown as the retry path rather than assigning transport directly.
+                eprintln!("[SDK.actor] monitor: restore auth failed: {e} (dropping transport)");
+                dismantle_auth_transport(
+                    self,
+                    inbound_task,
+                    event_tx,
+                    event_history,
+                    event_seq,
+                    event_history_limit,
+                )
+                .await;
+                Err(e)
+            }
+        }
     }
 
-    async fn store_history_items<'a, I>(&self, items: I, channel_type: i32) -> Result<Vec<u64>>
-    where
-        I: IntoIterator<Item = &'a MessageHistoryItem>,
-    {
-        let inputs = items
-            .into_iter()
-            .map(|item| Self::history_item_to_upsert_input(item, channel_type))
-            .collect();
-        self.storage
-            .batch_upsert_remote_messages(inputs)
-            .await?
-            .into_iter()
-            .map(|result| result.map(|stored| stored.message_id))
-            .collect()
+    async fn disconnect(&mut self) -> Result<()> {
+        if let Some(transport) = self.transport.as_ref() {
+            transport
+                .disconnect()
+                .await
+                .map_err(|e| Error::Transport(format!("disconnect: {e}")))?;
+        }
+        self.transport = None;
+        Ok(())
     }
 
-    /// 回填式拉取频道历史：RPC → 逐条落库 → 返回响应（spec §6：get 结果必须回填，
-    /// UI 随后从本地库重查渲染）。不更新 last_message（向前翻页不改会话预览）。
-    async fn fetch_and_store_channel_history(
-        &mut self,
-        channel_id: u64,
-        channel_type: i32,
-        before_server_message_id: Option<u64>,
-        limit: Option<u32>,
-    ) -> Result<MessageHistoryResponse> {
-        let req = MessageHistoryGetRequest {
-            user_id: 0,
-            channel_id,
-            before_server_message_id,
-            limit,
-        };
-        let resp: MessageHistoryResponse = self
-            .rpc_call_typed(routes::message_history::GET, &req)
-            .await?;
-        let normalized_channel_type = if channel_type == 0 { 1 } else { channel_type };
-        self.store_history_items(resp.messages.iter(), normalized_channel_type)
-            .await?;
-        Ok(resp)
+    async fn is_connected(&self) -> bool {
+        match self.transport.as_ref() {
+            Some(transport) => transport.is_connected().await,
+            None => false,
+        }
     }
 
-    /// jump-to-message 上下文：RPC around → before/anchor/after 全部回填 → 返回响应。
-    /// search 命中（snippet 投影）不落库；点击后走本方法拿完整消息（spec §4/§5/§6 边界）。
-    async fn fetch_and_store_messages_around(
-        &mut self,
-        channel_id: u64,
-        channel_type: i32,
-        message_id: u64,
-        before_limit: Option<u32>,
-        after_limit: Option<u32>,
-    ) -> Result<MessageHistoryAroundResponse> {
-        let req = MessageHistoryAroundRequest {
-            channel_id,
-            message_id,
-            before_limit,
-            after_limit,
+    async fn probe_connection(&mut self) -> bool {
+        let transport = match self.transport.as_mut() {
+            Some(transport) => transport,
+            None => return false,
         };
-        let resp: MessageHistoryAroundResponse = self
-            .rpc_call_typed(routes::message_history::AROUND, &req)
-            .await?;
-        let normalized_channel_type = if channel_type == 0 { 1 } else { channel_type };
-        self.store_history_items(
-            resp.before_messages
-                .iter()
-                .chain(std::iter::once(&resp.anchor_message))
-                .chain(resp.after_messages.iter()),
-            normalized_channel_type,
-        )
-        .await?;
-        Ok(resp)
-    }
-
-    /// 把一条损坏的投影排进 repair 队列。**立即返回，不发网络。**
-    ///
-    /// 调用它的是读路径（打开会话、上滑翻页）——那里绝不能等一串 around 请求：
-    /// 坏数据还没修好之前会话就先卡住，用户付出的代价比问题本身大。真正的修复由
-    /// actor 的 repair tick 取队列执行。
-    ///
-    /// 三道闸门，少一道就会变成「每次读都打一遍服务端」：
-    /// - **singleflight**：`repair_seen` 保证同一条消息只排一次。反复读到同一条坏行
-    ///   是常态（打开、上滑、切回来），每次都发请求是拿用户流量换同一个答案。
-    /// - **有界**：队列超过上限就丢弃，留给下一次读取重新发现——堆积没有意义，
-    ///   因为损坏是从数据本身发现的，不会丢。
-    /// - **退避**：失败多半是离线或服务端不稳，退避期内直接跳过。不需要额外的
-    ///   「网络恢复唤醒」：读路径本身就是唤醒信号。
-    fn enqueue_projection_repair(
-        &mut self,
-        channel_id: u64,
-        channel_type: i32,
-        server_message_id: u64,
-    ) {
-        let key = (channel_type, channel_id, server_message_id);
-        if let Some((_, until)) = self.repair_backoff.get(&key) {
-            if std::time::Instant::now() < *until {
-                return;
-            }
-        }
-        if !self.repair_seen.insert(key) {
-            return;
+        if !transport.is_connected().await {
+            return false;
         }
-        if self.repair_queue.len() >= REPAIR_QUEUE_LIMIT {
-            self.repair_seen.remove(&key);
-            return;
+        let payload = match encode_message(&PingRequest {
+            timestamp: chrono::Utc::now().timestamp_millis(),
+        }) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("[SDK.actor] monitor: encode ping failed: {e}");
+                return false;
+            }
+        };
+        let options = RequestOptions::new()
+            .biz_type(MessageType::PingRequest as u8)
+            .timeout(Duration::from_secs(2));
+        match transport
+            .request_with_options(Bytes::from(payload), options)
+            .await
+        {
+            Ok(raw) => match decode_message::<PongResponse>(&raw) {
+                Ok(_) => true,
+                Err(e) => {
+                    eprintln!("[SDK.actor] monitor: decode pong failed: {e}");
+                    true
+                }
+            },
+            Err(e) => {
+                eprintln!("[SDK.actor] monitor: ping request failed: {e}");
+                false
+            }
         }
-        self.repair_queue.push_back(key);
     }
 
-    /// Submit a thumbnail to the existing receiver-side [`DownloadManager`].
-    ///
-    /// This method is deliberately synchronous: message materialization and UI
-    /// visibility only register work. Ticket resolution, HTTP and disk IO run in
-    /// the manager's bounded account/session-scoped task.
-    fn submit_thumbnail_download(
+    async fn connect_one(
         &self,
-        message_id: u64,
-        channel_id: u64,
-        channel_type: i32,
-        created_at_ms: i64,
-        extra: &str,
-        priority: media_download::DownloadPriority,
-    ) {
-        let Some(owner_uid) = self.current_uid.clone() else {
-            return;
-        };
-        let key = media_download::MediaTaskKey::thumbnail(
-            owner_uid.clone(),
-            self.session_epoch,
-            message_id,
-        );
-        let storage = self.storage.clone();
-        let transport = self.transport.clone();
+        ep: &ServerEndpoint,
+    ) -> Result<(TransportClient, msgtrans::ClientEvents)> {
+        if actor_logs_enabled() {
+            eprintln!("[SDK.actor] connect_one: begin");
+        }
         let timeout = self.timeout();
-        let content = extra.to_string();
-        let thumbnail_file_id = Self::extract_thumbnail_file_id(extra);
-        let actor_tx = self.actor_tx.clone();
-        let session_epoch = self.session_epoch;
-
-        let _ = self.download_manager.submit(key, priority, async move {
-            let paths = match storage.get_storage_paths_for_uid(owner_uid.clone()).await {
-                Ok(paths) => paths,
-                Err(error) => {
-                    tracing::warn!(message_id, %error, "resolve thumbnail owner storage failed");
-                    return;
-                }
-            };
-            let ticket = if let Some(file_id) = thumbnail_file_id {
-                let Some(transport) = transport.as_ref() else {
-                    return;
-                };
-                let mut resolved = None;
-                for attempt in 0..3u64 {
-                    resolved =
-                        State::resolve_thumbnail_ticket_detached(transport, file_id, timeout).await;
-                    if resolved.is_some() {
-                        break;
-                    }
-                    if attempt < 2 {
-                        tokio::time::sleep(Duration::from_millis(500 * (1 << attempt))).await;
-                    }
+        let target = Self::resolve_target(&ep.host, ep.port).await?;
+        let mut client = match ep.protocol {
+            TransportProtocol::Quic => {
+                let mut cfg = QuicClientConfig::new(&target)
+                    .map_err(|e| Error::Transport(format!("quic config: {e}")))?
+                    .connect_timeout(timeout)
+                    .server_name(ep.host.clone());
+                if quic_accept_self_signed_for_testing_enabled() {
+                    warn_quic_insecure_verification_disabled_once();
+                    cfg = cfg.danger_skip_verification();
                 }
-                let Some(ticket) = resolved else {
-                    tracing::warn!(
-                        message_id,
-                        file_id,
-                        "thumbnail ticket unavailable after retry"
-                    );
-                    return;
+                TransportClientBuilder::new()
+                    .protocol(cfg)
+                    .build()
+                    .await
+                    .map_err(|e| Error::Transport(format!("quic build: {e}")))?
+            }
+            TransportProtocol::Tcp => {
+                let cfg = TcpClientConfig::new(&target)
+                    .map_err(|e| Error::Transport(format!("tcp config: {e}")))?
+                    .connect_timeout(timeout);
+                TransportClientBuilder::new()
+                    .protocol(cfg)
+                    .build()
+                    .await
+                    .map_err(|e| Error::Transport(format!("tcp build: {e}")))?
+            }
+            TransportProtocol::WebSocket => {
+                let path = ep.path.as_deref().unwrap_or("/");
+                let url = if ep.use_tls {
+                    format!("wss://{}:{}{}", ep.host, ep.port, path)
+                } else {
+                    format!("ws://{}:{}{}", ep.host, ep.port, path)
                 };
-                Some(ticket)
-            } else {
-                None
-            };
-            let Some(outcome) = State::run_auto_download_thumbnail(
-                &content,
-                ticket,
-                &paths.user_root,
-                message_id,
-                created_at_ms,
-                channel_id,
-                channel_type,
-            )
-            .await
-            else {
-                return;
-            };
-            let Some(actor_tx) = actor_tx.upgrade() else {
-                return;
-            };
-            let (resp_tx, resp_rx) = oneshot::channel();
-            if actor_tx
-                .send(Command::CompleteThumbnailDownload {
-                    owner_uid,
-                    session_epoch,
-                    message_id,
-                    channel_id,
-                    channel_type,
-                    outcome,
-                    resp: resp_tx,
-                })
-                .await
-                .is_ok()
-            {
-                // Keep the DownloadManager singleflight entry until the actor has
-                // either committed or rejected this exact session's outcome.
-                let _ = resp_rx.await;
+                let cfg = WebSocketClientConfig::new(&url)
+                    .map_err(|e| Error::Transport(format!("ws config: {e}")))?
+                    .connect_timeout(timeout)
+                    // msgtrans 2.0: TLS 行为改为 ClientTls 枚举(旧 verify_tls
+                    // 是从未接线的假开关);use_tls=false 的端点走 ws:// 本就
+                    // 不触发 TLS,这里映射为 Insecure 仅保语义完整。
+                    .tls(if ep.use_tls {
+                        msgtrans::ClientTls::SystemRoots
+                    } else {
+                        msgtrans::ClientTls::Insecure
+                    });
+                TransportClientBuilder::new()
+                    .protocol(cfg)
+                    .build()
+                    .await
+                    .map_err(|e| Error::Transport(format!("ws build: {e}")))?
             }
-        });
+        };
+        // 先取事件流再 connect：事件流是有界队列，连接期间的事件会排队而不是丢失。
+        let events = client
+            .events()
+            .await
+            .map_err(|e| Error::Transport(format!("events: {e}")))?;
+        client
+            .connect()
+            .await
+            .map_err(|e| Error::Transport(format!("connect: {e}")))?;
+        if actor_logs_enabled() {
+            eprintln!("[SDK.actor] connect_one: connected");
+        }
+        Ok((client, events))
     }
 
-    /// Commit a receiver worker outcome only if it still belongs to the active
-    /// account session. Keeping this check in the main actor closes the same-UID
-    /// relogin race that a storage-only UID guard cannot distinguish.
-    async fn apply_thumbnail_download_outcome(
+    async fn login(
         &mut self,
-        owner_uid: &str,
-        session_epoch: u64,
-        message_id: u64,
-        channel_id: u64,
-        channel_type: i32,
-        outcome: ThumbnailDownloadOutcome,
-    ) -> bool {
-        if self.current_uid.as_deref() != Some(owner_uid) || self.session_epoch != session_epoch {
-            return false;
+        username: String,
+        password: String,
+        device_id: String,
+    ) -> Result<LoginResult> {
+        if actor_logs_enabled() {
+            eprintln!("[SDK.actor] login: enter");
         }
-        let thumb_status = match outcome {
-            ThumbnailDownloadOutcome::Ready => 1,
-            ThumbnailDownloadOutcome::ConfirmedAbsent => 3,
-            ThumbnailDownloadOutcome::Failed => 2,
-        };
-        let applied = match self
-            .storage
-            .update_thumb_status_scoped(owner_uid.to_string(), message_id, thumb_status)
-            .await
-        {
-            Ok(applied) => applied,
-            Err(error) => {
-                tracing::warn!(message_id, %error, "commit thumbnail outcome failed");
-                false
-            }
+        let timeout = self.timeout();
+        let os = std::env::consts::OS.to_string();
+        let device_name = default_device_name(&os);
+        let device_model = default_device_model();
+        let manufacturer = default_manufacturer();
+        let app_id = default_app_id(&os);
+        let req = RpcRequest {
+            route: "account/auth/login".to_string(),
+            body: serde_json::to_vec(&AuthLoginRequest {
+                username,
+                password,
+                device_id: device_id.clone(),
+                device_info: Some(DeviceInfo {
+                    device_id: device_id.clone(),
+                    device_type: DeviceType::from_str(&os),
+                    app_id: app_id.clone(),
+                    push_token: None,
+                    push_channel: None,
+                    device_name,
+                    device_model,
+                    os_version: Some(os),
+                    app_version: Some("0.1.0".to_string()),
+                    manufacturer,
+                    device_fingerprint: None,
+                }),
+            })
+            .map_err(|e| Error::Serialization(format!("encode login body: {e}")))?,
         };
-        if applied && outcome == ThumbnailDownloadOutcome::Ready {
-            let event = SdkEvent::TimelineUpdated {
-                channel_id,
-                channel_type,
-                message_id,
-                reason: "thumbnail_ready".to_string(),
-            };
-            if let (Some(tx), Some(history), Some(seq)) =
-                (&self.event_tx, &self.event_history, &self.event_seq)
-            {
-                emit_sequenced_event(tx, history, seq, self.event_history_limit, event);
-            } else if let Some(tx) = &self.event_tx {
-                let _ = tx.send(event);
-            }
-        }
-        applied
-    }
 
-    /// 处理一批排队的 repair。由 actor tick 调用，每次最多 [`REPAIR_BATCH_LIMIT`] 条。
-    ///
-    /// 修好后**只发一次** TimelineUpdated：投影是原地更新的，message.id 不变、
-    /// 未读不动、cursor 不动 —— repair 不是「收到新消息」。
-    async fn drain_projection_repairs(&mut self) {
-        for _ in 0..REPAIR_BATCH_LIMIT {
-            let Some(key) = self.repair_queue.pop_front() else {
-                return;
-            };
-            let (channel_type, channel_id, server_message_id) = key;
-            let outcome = tokio::time::timeout(
-                Duration::from_millis(REPAIR_TIMEOUT_MS),
-                self.repair_message_projection(channel_id, channel_type, server_message_id),
+        let payload = encode_message(&req)
+            .map_err(|e| Error::Serialization(format!("encode rpc request: {e}")))?;
+        let raw = self
+            .request_bytes(
+                Bytes::from(payload),
+                MessageType::RpcRequest as u8,
+                timeout,
+                "rpc login",
             )
-            .await;
-            self.repair_seen.remove(&key);
+            .await?;
 
-            match outcome {
-                Ok(Ok(Some(message_id))) => {
-                    self.repair_backoff.remove(&key);
-                    self.invalidate_channel_cache_with_reason(
-                        channel_id,
-                        channel_type,
-                        "repair_message_projection",
-                    );
-                    if let (Some(tx), Some(history), Some(seq)) = (
-                        self.event_tx.as_ref(),
-                        self.event_history.as_ref(),
-                        self.event_seq.as_ref(),
-                    ) {
-                        emit_sequenced_event(
-                            tx,
-                            history,
-                            seq,
-                            self.event_history_limit,
-                            SdkEvent::TimelineUpdated {
-                                channel_id,
-                                channel_type,
-                                message_id,
-                                reason: "message_projection_repaired".to_string(),
-                            },
-                        );
-                    }
-                }
-                // 服务端也没有这条：不是可重试失败，别再排它。
-                Ok(Ok(None)) => {
-                    self.repair_backoff.remove(&key);
-                }
-                Ok(Err(e)) => self.note_repair_failure(key, &e.to_string()),
-                Err(_) => self.note_repair_failure(key, "repair timed out"),
-            }
+        let rpc_resp: RpcResponse = decode_message(&raw)
+            .map_err(|e| Error::Serialization(format!("decode rpc response: {e}")))?;
+        if rpc_resp.code != 0 {
+            return Err(Error::Auth(rpc_resp.message));
         }
-    }
+        let body = rpc_resp
+            .data
+            .ok_or_else(|| Error::Serialization("empty rpc data".into()))?;
+        let auth: AuthResponse = serde_json::from_slice(&body)
+            .map_err(|e| Error::Serialization(format!("decode auth response: {e}")))?;
 
-    fn note_repair_failure(&mut self, key: (i32, u64, u64), reason: &str) {
-        let attempts = self.repair_backoff.get(&key).map(|(n, _)| *n).unwrap_or(0) + 1;
-        let delay =
-            REPAIR_BACKOFF_BASE_MS.saturating_mul(1u64 << attempts.min(REPAIR_BACKOFF_MAX_SHIFT));
-        self.repair_backoff.insert(
-            key,
-            (
-                attempts,
-                std::time::Instant::now() + Duration::from_millis(delay),
-            ),
-        );
-        tracing::warn!(
-            server_message_id = key.2,
-            channel_id = key.1,
-            attempts,
-            delay_ms = delay,
-            reason,
-            "投影 repair 失败,退避后由下一次读取重新发现"
-        );
+        let out = LoginResult {
+            user_id: auth.user_id,
+            token: auth.token,
+            device_id: auth.device_id,
+            refresh_token: auth.refresh_token,
+            expires_at: auth.expires_at,
+        };
+        let uid = out.user_id.to_string();
+        self.storage.save_login(uid.clone(), out.clone()).await?;
+        self.storage.flush_user(uid.clone()).await?;
+        self.current_uid = Some(uid);
+        self.download_manager.cancel_all_scoped();
+        self.session_epoch += 1;
+        if let Err(e) = self.replay_prelogin_inbound_frames().await {
+            eprintln!("[SDK.inbound] replay after login failed: {e}");
+        }
+        if actor_logs_enabled() {
+            eprintln!("[SDK.actor] login: success user_id={}", out.user_id);
+        }
+        Ok(out)
     }
 
-    /// 按 `server_message_id` 定向修复一条投影损坏的消息。
-    ///
-    /// 「投影损坏」指本地这一行是某条旧代码或半截写入留下的:metadata 丢了(图片
-    /// 永远加载不出来)、时间戳单位错了、pts 缺失。这类行不该靠删掉整段会话历史
-    /// 再全量重拉来救——那会连带丢掉本地已下载的媒体、已读位置和 gap 水位,代价
-    /// 远大于问题本身,而且用户会看到会话「闪空」。
-    ///
-    /// 修复路径就是一条:按 server_message_id 走 around 拿回权威消息 → 重新跑
-    /// canonical projection → 原地 upsert(`server_message_id` 唯一,天然幂等)。
-    ///
-    /// 返回本地 message id;消息在服务端也不存在时返回 None。
-    pub async fn repair_message_projection(
+    async fn register(
         &mut self,
-        channel_id: u64,
-        channel_type: i32,
-        server_message_id: u64,
-    ) -> Result<Option<u64>> {
-        let normalized_channel_type = if channel_type == 0 { 1 } else { channel_type };
-        let resp = self
-            .fetch_and_store_messages_around(
-                channel_id,
-                normalized_channel_type,
-                server_message_id,
-                Some(0),
-                Some(0),
+        username: String,
+        password: String,
+        device_id: String,
+    ) -> Result<LoginResult> {
+        let timeout = self.timeout();
+        let os = std::env::consts::OS.to_string();
+        let device_name = default_device_name(&os);
+        let device_model = default_device_model();
+        let manufacturer = default_manufacturer();
+        let app_id = default_app_id(&os);
+        let req = RpcRequest {
+            route: routes::account_user::REGISTER.to_string(),
+            body: serde_json::to_vec(&UserRegisterRequest {
+                username,
+                password,
+                nickname: None,
+                phone: None,
+                email: None,
+                device_id: device_id.clone(),
+                device_info: Some(DeviceInfo {
+                    device_id: device_id.clone(),
+                    device_type: DeviceType::from_str(&os),
+                    app_id,
+                    push_token: None,
+                    push_channel: None,
+                    device_name,
+                    device_model,
+                    os_version: Some(os),
+                    app_version: Some("0.1.0".to_string()),
+                    manufacturer,
+                    device_fingerprint: None,
+                }),
+            })
+            .map_err(|e| Error::Serialization(format!("encode register body: {e}")))?,
+        };
+        let payload = encode_message(&req)
+            .map_err(|e| Error::Serialization(format!("encode register request: {e}")))?;
+        let raw = self
+            .request_bytes(
+                Bytes::from(payload),
+                MessageType::RpcRequest as u8,
+                timeout,
+                "rpc register",
             )
             .await?;
-        if resp.anchor_message.message_id != server_message_id {
-            tracing::warn!(
-                server_message_id,
-                channel_id,
-                "repair: 服务端没有返回这条 anchor,跳过"
-            );
-            return Ok(None);
+        let rpc_resp: RpcResponse = decode_message(&raw)
+            .map_err(|e| Error::Serialization(format!("decode register response: {e}")))?;
+        if rpc_resp.code != 0 {
+            return Err(Error::Auth(rpc_resp.message));
         }
-        // fetch_and_store_messages_around 内部已按 canonical projection 落库,
-        // 这里只把本地 id 查回来给调用方。
-        Ok(self
-            .storage
-            .get_message_id_by_server_message_id(
-                channel_id,
-                normalized_channel_type,
-                server_message_id,
-            )
-            .await?)
-    }
-
-    async fn hydrate_channel_messages_from_history(
-        &mut self,
-        channel_id: u64,
-        channel_type: i32,
-        limit: u32,
-    ) -> Result<usize> {
-        let req = MessageHistoryGetRequest {
-            user_id: 0,
-            channel_id,
-            before_server_message_id: None,
-            limit: Some(limit),
+        let body = rpc_resp
+            .data
+            .ok_or_else(|| Error::Serialization("empty register data".into()))?;
+        let auth: AuthResponse = serde_json::from_slice(&body)
+            .map_err(|e| Error::Serialization(format!("decode register auth response: {e}")))?;
+        let out = LoginResult {
+            user_id: auth.user_id,
+            token: auth.token,
+            device_id: auth.device_id,
+            refresh_token: auth.refresh_token,
+            expires_at: auth.expires_at,
         };
-        let resp: MessageHistoryResponse = self
-            .rpc_call_typed(routes::message_history::GET, &req)
-            .await?;
-        if resp.messages.is_empty() {
-            return Ok(0);
+        let uid = out.user_id.to_string();
+        self.storage.save_login(uid.clone(), out.clone()).await?;
+        self.storage.flush_user(uid.clone()).await?;
+        self.current_uid = Some(uid);
+        self.download_manager.cancel_all_scoped();
+        self.session_epoch += 1;
+        if let Err(e) = self.replay_prelogin_inbound_frames().await {
+            eprintln!("[SDK.inbound] replay after register failed: {e}");
         }
+        Ok(out)
+    }
 
-        let normalized_channel_type = if channel_type == 0 { 1 } else { channel_type };
-        let message_ids = self
-            .store_history_items(resp.messages.iter(), normalized_channel_type)
+    async fn authenticate(&mut self, user_id: u64, token: String, device_id: String) -> Result<()> {
+        if actor_logs_enabled() {
+            eprintln!("[SDK.actor] authenticate: enter user_id={user_id}");
+        }
+        // ConnAuth gets a short, request-only deadline. Retrying a silent
+        // connection after five seconds is safer than holding startup for the
+        // general 30-second RPC timeout. Token persistence below is deliberately
+        // outside this transport deadline.
+        let timeout = auth_request_timeout(self.timeout());
+        let token_for_persist = token.clone();
+        let device_id_for_persist = device_id.clone();
+        let os = std::env::consts::OS.to_string();
+        let app_id = default_app_id(&os);
+        let app_package = default_app_package(&os);
+        let device_name = default_device_name(&os);
+        let device_model = default_device_model();
+        let manufacturer = default_manufacturer();
+        let req = AuthorizationRequest {
+            auth_type: AuthType::JWT,
+            auth_token: token,
+            client_info: ClientInfo {
+                client_type: default_client_type(&os),
+                version: "0.1.0".to_string(),
+                os: os.clone(),
+                os_version: os.clone(),
+                device_model: device_model.clone(),
+                app_package,
+            },
+            device_info: DeviceInfo {
+                device_id,
+                device_type: DeviceType::from_str(std::env::consts::OS),
+                app_id,
+                push_token: None,
+                push_channel: None,
+                device_name,
+                device_model,
+                os_version: Some(os),
+                app_version: Some("0.1.0".to_string()),
+                manufacturer,
+                device_fingerprint: None,
+            },
+            protocol_version: "1.0".to_string(),
+            properties: HashMap::from([
+                ("user_id".to_string(), user_id.to_string()),
+                (
+                    "client_timestamp".to_string(),
+                    chrono::Utc::now().timestamp_millis().to_string(),
+                ),
+            ]),
+        };
+        let payload = encode_message(&req)
+            .map_err(|e| Error::Serialization(format!("encode auth request: {e}")))?;
+        let raw = self
+            .request_bytes(
+                Bytes::from(payload),
+                MessageType::AuthorizationRequest as u8,
+                timeout,
+                "auth request",
+            )
             .await?;
-        let mut applied = 0usize;
-        for (item, message_id) in resp.messages.into_iter().zip(message_ids) {
-            let timestamp_ms = i64::try_from(item.timestamp).unwrap_or(i64::MAX);
-            let _ = self
-                .update_channel_last_message(
-                    item.channel_id,
-                    normalized_channel_type,
-                    &item.content,
-                    timestamp_ms,
-                    message_id,
-                    Some(item.sender_id),
-                    false,
+        let auth_resp: AuthorizationResponse = decode_message(&raw)
+            .map_err(|e| Error::Serialization(format!("decode auth response: {e}")))?;
+        if !auth_resp.success {
+            // 把服务端 error_code（u32）塞进 message 前缀 `[<code>] ...`，供上层 auth_kind() 解出。
+            // 0 表示未携带码，归 Transient。
+            let code = auth_resp.error_code.unwrap_or(0);
+            let message = auth_resp
+                .error_message
+                .unwrap_or_else(|| "authorization failed".to_string());
+            return Err(Error::Auth(format!("[{}] {}", code, message)));
+        }
+        let uid = user_id.to_string();
+        // 换人了：旧账号的会话作用域状态一律作废（与 SwitchLocalAccount 的 ④ 同一套清理）。
+        //
+        // 最要命的一项是 sync_coordinator 的 readiness。不重置的话新账号沿用上一个账号的
+        // 「已就绪」：宿主看到 Ready 就跳过 bootstrap，而本地优先读又被 bootstrap gate 挡回
+        // （`run_bootstrap_sync required before local-first operations`）——两头落空，界面上
+        // 就永远停在**上一个账号**的会话列表，直到冷启动才恢复正常。真机上表现为切/加账号后
+        // 看到别人的会话标题和消息预览，属于跨账号数据泄露。
+        //
+        // 「添加账号 → 登录另一个身份」走的是裸 authenticate，不经过 SwitchLocalAccount，
+        // 所以那套清理必须在这里也做一遍。
+        self.reset_if_owner_changed(&uid, chrono::Utc::now().timestamp_millis());
+        // authenticate() 的职责只是用当前 access_token 握手；不应覆盖 login/register 写入的
+        // refresh_token 或过期时间。若该用户已有会话，只原子刷新 access_token；否则才走
+        // save_login（用于外部认证首次 handshake，无 refresh_token）。
+        let existing_session = self.storage.load_session(uid.clone()).await.ok().flatten();
+        if let Some(existing) = existing_session.as_ref() {
+            // 冷启动 restore：从持久化 snapshot 恢复 bootstrap_completed，与自动重连路径
+            // （restore_persisted_session）保持一致。否则每次冷启动 bootstrap_completed 停留在
+            // false → ① 本地优先读（get_channels 等）被 current_uid_required gate 拒绝，UI 只能
+            // 等网络 sync 才出会话；② 后续 run_bootstrap_sync 被迫走全量而非增量 resume。
+            // full_rebuild_required() 仍是安全网：本地 store 需重建时即便标记为 true 也会走全量。
+            self.bootstrap_completed = existing.bootstrap_completed;
+        } else {
+            // 没有快照 ≠ 没同步过。退出登录只删凭证，账号的 bootstrap 水位仍在盘上，
+            // 只是读不进快照里了。不在这里补读，同一个账号重新登录就会被当成新账号
+            // 跑一遍全量 bootstrap——本地库明明还是全的。
+            self.bootstrap_completed = self
+                .storage
+                .load_bootstrap_completed(uid.clone())
+                .await
+                .unwrap_or(false);
+        }
+        if existing_session.is_some() {
+            self.storage
+                .update_access_token(uid.clone(), token_for_persist, None)
+                .await?;
+            // update_access_token 不写 K_CUR_UID；与 save_login 分支保持一致，确保磁盘 current_uid
+            // 与本次 authenticate 的 uid 对齐，避免后续 with_uid! 命令读到 None。
+            self.storage.save_current_uid(uid.clone()).await?;
+        } else {
+            self.storage
+                .save_login(
+                    uid.clone(),
+                    LoginResult {
+                        user_id,
+                        token: token_for_persist,
+                        device_id: device_id_for_persist,
+                        refresh_token: None,
+                        expires_at: 0,
+                    },
                 )
-                .await;
-            applied += 1;
+                .await?;
         }
-        Ok(applied)
+        self.storage.flush_user(uid.clone()).await?;
+        self.current_uid = Some(uid);
+        self.download_manager.cancel_all_scoped();
+        self.session_epoch += 1;
+        if actor_logs_enabled() {
+            eprintln!("[SDK.actor] authenticate: success");
+        }
+        Ok(())
     }
 
-    async fn defer_canonical_mutation_if_target_missing(
-        &self,
-        commit: &ServerCommit,
-    ) -> Result<bool> {
-        let resolution = commit.resolve_canonical_event();
-        let Some(event) = resolution.event else {
-            return Ok(false);
-        };
-        let target_server_message_id = match &event {
-            CanonicalTimelineEvent::Revoke(event) => event.target_server_message_id,
-            CanonicalTimelineEvent::ReactionChange(event) => event.target_server_message_id,
-            CanonicalTimelineEvent::NewMessage(_) => return Ok(false),
-        };
-        if self
-            .storage
-            .get_message_id_by_server_message_id(
-                commit.channel_id,
-                i32::from(commit.channel_type),
-                target_server_message_id,
-            )
-            .await?
-            .is_some()
-        {
-            return Ok(false);
-        }
-        let Some(event_id) = commit.event_id else {
-            tracing::warn!(
-                channel_id = commit.channel_id,
-                target_server_message_id,
-                "cannot persist out-of-order legacy mutation without event_id"
-            );
-            return Ok(false);
+    async fn sync_entities(&mut self, entity_type: String, scope: Option<String>) -> Result<usize> {
+        let timeout = self.timeout();
+        let entity_type_for_apply = entity_type.clone();
+        let scope_for_apply = scope.clone();
+        let persist_cursor =
+            Self::should_persist_sync_cursor(&entity_type_for_apply, scope_for_apply.as_deref());
+        self.last_sync_entity_events.clear();
+        let mut since_version = if persist_cursor {
+            self.load_sync_since_version(&entity_type_for_apply, scope_for_apply.as_deref())
+                .await
+                .unwrap_or(0)
+        } else {
+            0
         };
-        self.storage
-            .put_pending_timeline_mutation(PendingTimelineMutation {
-                channel_id: commit.channel_id,
-                channel_type: i32::from(commit.channel_type),
-                target_server_message_id,
-                event_id,
-                pts: commit.pts,
-                canonical_event: event.encode_fb().map_err(|e| {
-                    Error::Serialization(format!("encode pending canonical mutation: {e}"))
-                })?,
-            })
-            .await?;
-        Ok(true)
-    }
+        let mut total_queued = 0usize;
+        let mut total_dropped = 0usize;
+        let mut fetched_pages = 0usize;
+        let mut restarted_full_sync = false;
+        let final_next_version = loop {
+            fetched_pages += 1;
+            if fetched_pages > 64 {
+                return Err(Error::InvalidState(
+                    "sync_entities exceeded max paging iterations".to_string(),
+                ));
+            }
 
-    async fn replay_pending_timeline_mutations(
-        &mut self,
-        channel_id: u64,
-        channel_type: i32,
-        target_server_message_id: u64,
-        bump_unread_on_incoming: bool,
-    ) -> Result<usize> {
-        let pending = self
-            .storage
-            .list_pending_timeline_mutations(channel_id, channel_type, target_server_message_id)
-            .await?;
-        let mut applied = 0usize;
-        for mutation in pending {
-            let event = CanonicalTimelineEvent::decode_fb(&mutation.canonical_event)
-                .map_err(|e| Error::Serialization(format!("decode pending mutation: {e}")))?;
-            let sender_id = match &event {
-                CanonicalTimelineEvent::Revoke(event) => event.revoked_by,
-                CanonicalTimelineEvent::ReactionChange(event) => event.actor_id,
-                CanonicalTimelineEvent::NewMessage(_) => 0,
+            let req = privchat_protocol::rpc::sync::SyncEntitiesRequest {
+                entity_type: entity_type_for_apply.clone(),
+                since_version: Some(since_version),
+                scope: scope_for_apply.clone(),
+                limit: Some(200),
             };
-            let commit = ServerCommit {
-                event_id: Some(mutation.event_id),
-                pts: mutation.pts,
-                server_msg_id: mutation.event_id,
-                local_message_id: None,
-                channel_id,
-                channel_type: u8::try_from(channel_type).unwrap_or(1),
-                message_type: String::new(),
-                content: serde_json::Value::Null,
-                server_timestamp: 0,
-                sender_id,
-                sender_info: None,
-                event_schema_version: Some(privchat_protocol::CANONICAL_TIMELINE_EVENT_SCHEMA_V1),
-                canonical_event: Some(mutation.canonical_event.clone()),
+            let request = RpcRequest {
+                route: privchat_protocol::rpc::routes::entity::SYNC_ENTITIES.to_string(),
+                body: serde_json::to_vec(&req)
+                    .map_err(|e| Error::Serialization(format!("encode sync_entities body: {e}")))?,
             };
-            let (entity_type, item) = Self::sync_item_from_difference_commit(&commit);
-            let scope = Some(format!("{channel_type}:{channel_id}"));
-            let count = self
-                .enqueue_and_apply_sync_items(
-                    entity_type.clone(),
-                    scope.clone(),
-                    vec![item],
-                    bump_unread_on_incoming,
+            let payload = encode_message(&request)
+                .map_err(|e| Error::Serialization(format!("encode sync_entities rpc: {e}")))?;
+            let raw = self
+                .request_bytes(
+                    Bytes::from(payload),
+                    MessageType::RpcRequest as u8,
+                    timeout,
+                    "rpc sync_entities",
                 )
                 .await?;
-            self.storage
-                .delete_pending_timeline_mutation(mutation)
-                .await?;
-            self.queue_last_sync_events(entity_type, scope, count);
-            applied += count;
-        }
-        Ok(applied)
-    }
+            let rpc_resp: RpcResponse = decode_message(&raw)
+                .map_err(|e| Error::Serialization(format!("decode sync_entities rpc: {e}")))?;
+            if rpc_resp.code != 0 {
+                let data_preview = rpc_resp
+                    .data
+                    .as_ref()
+                    .map(|v| {
+                        let s = String::from_utf8_lossy(v);
+                        if s.len() > 512 {
+                            format!("{}...", &s[..512])
+                        } else {
+                            s.into_owned()
+                        }
+                    })
+                    .unwrap_or_else(|| "<none>".to_string());
+                if Self::should_log_unsupported_entity_skip(&entity_type_for_apply)
+                    || !matches!(rpc_resp.code, 10100)
+                {
+                    eprintln!(
+                        "[SDK.actor] sync_entities rpc rejected: entity_type={} scope={:?} since_version={} code={} message={} data={}",
+                        entity_type_for_apply,
+                        scope_for_apply,
+                        since_version,
+                        rpc_resp.code,
+                        rpc_resp.message,
+                        data_preview
+                    );
+                }
+                return Err(Self::sync_rpc_rejection(
+                    &format!(
+                        "sync_entities entity_type={} scope={:?} since_version={}",
+                        entity_type_for_apply, scope_for_apply, since_version
+                    ),
+                    rpc_resp.code,
+                    rpc_resp.message,
+                ));
+            }
+            let body = rpc_resp
+                .data
+                .ok_or_else(|| Error::Serialization("empty sync_entities data".into()))?;
+            let response: privchat_protocol::rpc::sync::SyncEntitiesResponse =
+                serde_json::from_slice(&body).map_err(|e| {
+                    Error::Serialization(format!("decode sync_entities response: {e}"))
+                })?;
+            if let Some(min_version) = response.min_version {
+                if since_version < min_version && since_version > 0 && !restarted_full_sync {
+                    restarted_full_sync = true;
+                    since_version = 0;
+                    total_queued = 0;
+                    total_dropped = 0;
+                    continue;
+                }
+            }
+            let stats = self.receive_pipeline.enqueue(
+                entity_type_for_apply.clone(),
+                scope_for_apply.clone(),
+                response.items,
+            );
+            total_queued += stats.queued_items;
+            total_dropped += stats.dropped_duplicates;
+            self.metrics.inc_counter(
+                metrics::RECEIVE_QUEUED_ITEMS_TOTAL,
+                &[],
+                stats.queued_items as u64,
+            );
+            self.metrics.inc_counter(
+                metrics::RECEIVE_DROPPED_DUPLICATES_TOTAL,
+                &[],
+                stats.dropped_duplicates as u64,
+            );
 
-    async fn resume_channel_difference(
-        &mut self,
-        channel_id: u64,
-        channel_type: i32,
-    ) -> Result<usize> {
-        let scope = Some(format!("{channel_type}:{channel_id}"));
-        let mut last_pts = self
-            .storage
-            .max_message_pts(channel_id, channel_type)
-            .await?;
-        if let Some(cursor_pts) = self.load_resume_channel_pts(channel_id, channel_type).await {
-            last_pts = last_pts.max(cursor_pts);
-        }
-        let mut total_applied = 0usize;
-        let mut pages = 0usize;
-        loop {
-            pages += 1;
-            if pages > 64 {
+            // 每页报一次，宿主用它在「本族区间」内往前爬。
+            //
+            // 没有这个事件，首屏进度条会在第一族上停 9 秒不动：整轮同步六成时间花在
+            // 联系人那一族，而族级事件要等它整个跑完才发。页事件让用户看得出还在动。
+            self.emit_sync_page_progress(&entity_type_for_apply, fetched_pages);
+
+            if !response.has_more {
+                break response.next_version;
+            }
+            if response.next_version <= since_version {
                 return Err(Error::InvalidState(format!(
-                    "resume_channel_difference exceeded max paging iterations: channel_id={} channel_type={}",
-                    channel_id, channel_type
+                    "sync_entities paging stalled: entity_type={} scope={:?} since={} next={}",
+                    entity_type_for_apply, scope_for_apply, since_version, response.next_version
                 )));
             }
-            let response = match self
-                .get_difference(channel_id, channel_type, last_pts, Some(200))
+            since_version = response.next_version;
+        };
+
+        let mut applied = 0usize;
+        let mut entity_events: Vec<SdkEvent> = Vec::new();
+        for batch in SyncCommitApplier::drain_batches(&mut self.receive_pipeline) {
+            let count = batch.items.len();
+            match self
+                .apply_sync_entities(
+                    &batch.entity_type,
+                    batch.scope.as_deref(),
+                    &batch.items,
+                    false,
+                )
                 .await
             {
-                Ok(resp) => resp,
+                Ok(events) => {
+                    applied += count;
+                    entity_events.extend(events);
+                }
                 Err(err) => {
-                    if Self::classify_resume_error(&err)
-                        == ResumeFailureClass::ChannelResyncRequired
-                    {
-                        let current_pts = self.get_channel_pts(channel_id, channel_type).await?;
-                        self.save_resume_channel_pts(channel_id, channel_type, current_pts)
-                            .await?;
-                        let recovered = self
-                            .hydrate_channel_messages_from_history(channel_id, channel_type, 100)
-                            .await
-                            .unwrap_or(0);
-                        return Ok(total_applied + recovered);
-                    }
+                    SyncCommitApplier::requeue_front(&mut self.receive_pipeline, batch);
                     return Err(err);
                 }
-            };
-            if response.commits.is_empty() {
-                break;
-            }
-            for commit in response.commits.iter() {
-                if self
-                    .defer_canonical_mutation_if_target_missing(commit)
-                    .await?
-                {
-                    continue;
-                }
-                let (entity_type, item) = Self::sync_item_from_difference_commit(commit);
-                let materializes_message = entity_type == "message" && !item.deleted;
-                let applied = self
-                    .enqueue_and_apply_sync_items(
-                        entity_type.clone(),
-                        scope.clone(),
-                        vec![item],
-                        false,
-                    )
-                    .await?;
-                total_applied += applied;
-                self.queue_last_sync_events(entity_type, scope.clone(), applied);
-                if materializes_message {
-                    total_applied += self
-                        .replay_pending_timeline_mutations(
-                            commit.channel_id,
-                            i32::from(commit.channel_type),
-                            commit.server_msg_id,
-                            false,
-                        )
-                        .await?;
-                }
-            }
-            let next_last_pts = response
-                .commits
-                .iter()
-                .map(|commit| commit.pts)
-                .max()
-                .unwrap_or(last_pts);
-            if next_last_pts <= last_pts {
-                break;
-            }
-            last_pts = next_last_pts;
-            self.save_resume_channel_pts(channel_id, channel_type, last_pts)
-                .await?;
-            if !response.has_more {
-                break;
             }
         }
-        self.reconcile_channel_unread_after_difference(channel_id, channel_type)
+        if persist_cursor {
+            self.save_sync_next_version(
+                &entity_type_for_apply,
+                scope_for_apply.as_deref(),
+                final_next_version,
+            )
             .await?;
-        Ok(total_applied)
-    }
-
-    async fn reconcile_channel_unread_after_difference(
-        &mut self,
-        channel_id: u64,
-        channel_type: i32,
-    ) -> Result<()> {
-        let Some(channel) = self.storage.get_channel_by_id(channel_id).await? else {
-            return Ok(());
-        };
-        let Some(extra) = self
-            .storage
-            .get_channel_extra(channel_id, channel_type)
-            .await?
-        else {
-            return Ok(());
+        }
+        self.last_sync_queued = total_queued;
+        self.last_sync_dropped_duplicates = total_dropped;
+        self.invalidate_cache_for_events(&entity_events);
+        self.last_sync_entity_events = entity_events;
+        if scope_for_apply.is_none() {
+            self.clear_resume_repair_key(Self::resume_repair_entity_key(&entity_type_for_apply))
+                .await;
+        }
+        Ok(applied)
+    }
+
+    async fn send_session_ready(&mut self) -> Result<()> {
+        let timeout = self.timeout();
+        let ready_req = privchat_protocol::rpc::sync::SessionReadyRequest {};
+        let ready_rpc = RpcRequest {
+            route: routes::sync::SESSION_READY.to_string(),
+            body: serde_json::to_vec(&ready_req)
+                .map_err(|e| Error::Serialization(format!("encode session_ready body: {e}")))?,
         };
-        let max_pts = self
-            .storage
-            .max_message_pts(channel_id, channel_type)
+        let payload = encode_message(&ready_rpc)
+            .map_err(|e| Error::Serialization(format!("encode session_ready rpc: {e}")))?;
+        let raw = self
+            .request_bytes(
+                Bytes::from(payload),
+                MessageType::RpcRequest as u8,
+                timeout,
+                "rpc session_ready",
+            )
             .await?;
-        if max_pts == 0 || extra.keep_pts < max_pts {
-            return Ok(());
+        let rpc_resp: RpcResponse = decode_message(&raw)
+            .map_err(|e| Error::Serialization(format!("decode session_ready rpc: {e}")))?;
+        if rpc_resp.code != 0 {
+            return Err(Self::sync_rpc_rejection(
+                "session_ready",
+                rpc_resp.code,
+                rpc_resp.message,
+            ));
         }
-        let exact_unread = self
-            .storage
-            .count_materialized_unread(channel_id, channel_type)
-            .await?;
-        if channel.unread_count == exact_unread {
-            return Ok(());
+        let body = rpc_resp
+            .data
+            .ok_or_else(|| Error::Serialization("empty session_ready data".into()))?;
+        let ready_ok: privchat_protocol::rpc::sync::SessionReadyResponse =
+            serde_json::from_slice(&body)
+                .map_err(|e| Error::Serialization(format!("decode session_ready response: {e}")))?;
+        if !ready_ok {
+            return Err(Error::InvalidState(
+                "session_ready rejected by server".to_string(),
+            ));
         }
-        self.storage
-            .upsert_channel(UpsertChannelInput {
-                channel_id: channel.channel_id,
-                channel_type: channel.channel_type,
-                channel_name: channel.channel_name,
-                channel_remark: channel.channel_remark,
-                avatar: channel.avatar,
-                unread_count: exact_unread,
-                top: channel.top,
-                mute: channel.mute,
-                last_msg_timestamp: channel.last_msg_timestamp,
-                last_local_message_id: channel.last_local_message_id,
-                last_msg_content: channel.last_msg_content,
-                version: channel.version,
-                peer_user_id: channel.peer_user_id,
-            })
-            .await?;
         Ok(())
     }
 
-    /// Bounded repair for pushes lost while the transport still looked healthy.
-    /// 一页 anti-entropy 的结果。
-    ///
-    /// 只返回「修了几条」是不够的：0 既可能是「这一页没东西修」，也可能是
-    /// 「整个账号都收敛了」，两者语义天差地别。据此判定 Converged 会在扫到
-    /// 第一页干净数据时就过早宣布收敛完成。
-    async fn run_anti_entropy_once(&mut self) -> Result<AntiEntropyPage> {
-        // This runs inline on the SDK actor. Keep each slice small enough that
-        // a channel/message command arriving just after the tick is not trapped
-        // behind a large scan. Throughput comes from repeated ticks, not a burst.
-        const PAGE_SIZE: usize = 25;
-        const WIFI_DIFFERENCE_BUDGET: usize = 1;
-        const CELLULAR_DIFFERENCE_BUDGET: usize = 1;
-
-        if self.session_state != SessionState::Authenticated || !self.bootstrap_completed {
-            return Ok(AntiEntropyPage::idle());
-        }
-        let (after_channel_id, after_channel_type) = self.load_anti_entropy_cursor().await;
-        // 从头开始扫的这一轮，是否已经走完一整圈
-        let mut cycle_completed = false;
-        let mut channels = self
-            .storage
-            .list_channel_identifiers_after(after_channel_id, after_channel_type, PAGE_SIZE)
+    async fn get_difference(
+        &mut self,
+        channel_id: u64,
+        channel_type: i32,
+        last_pts: u64,
+        limit: Option<u32>,
+    ) -> Result<GetDifferenceResponse> {
+        let timeout = self.timeout();
+        let req = GetDifferenceRequest {
+            channel_id,
+            channel_type: u8::try_from(channel_type).unwrap_or(1),
+            last_pts,
+            limit,
+        };
+        let request = RpcRequest {
+            route: routes::sync::GET_DIFFERENCE.to_string(),
+            body: serde_json::to_vec(&req)
+                .map_err(|e| Error::Serialization(format!("encode get_difference body: {e}")))?,
+        };
+        let payload = encode_message(&request)
+            .map_err(|e| Error::Serialization(format!("encode get_difference rpc: {e}")))?;
+        let raw = self
+            .request_bytes(
+                Bytes::from(payload),
+                MessageType::RpcRequest as u8,
+                timeout,
+                "rpc get_difference",
+            )
             .await?;
-        if channels.is_empty() && after_channel_id != 0 {
-            // 游标走到末尾又绕回开头 = 完整扫过一圈
-            cycle_completed = true;
-            self.save_anti_entropy_cursor(0, -1).await?;
-            channels = self
-                .storage
-                .list_channel_identifiers_after(0, -1, PAGE_SIZE)
-                .await?;
-        }
-        if channels.is_empty() {
-            // 一个频道都没有：也算走完一圈
-            return Ok(AntiEntropyPage {
-                page_scanned: 0,
-                stale_found: 0,
-                channels_repaired: 0,
-                messages_applied: 0,
-                deferred: 0,
-                unknown_channels: 0,
-                cycle_completed: true,
-            });
+        let rpc_resp: RpcResponse = decode_message(&raw)
+            .map_err(|e| Error::Serialization(format!("decode get_difference rpc: {e}")))?;
+        if rpc_resp.code != 0 {
+            return Err(Self::sync_rpc_rejection(
+                &format!(
+                    "get_difference channel_id={} channel_type={} last_pts={}",
+                    channel_id, channel_type, last_pts
+                ),
+                rpc_resp.code,
+                rpc_resp.message,
+            ));
         }
+        let body = rpc_resp
+            .data
+            .ok_or_else(|| Error::Serialization("empty get_difference data".into()))?;
+        serde_json::from_slice(&body)
+            .map_err(|e| Error::Serialization(format!("decode get_difference response: {e}")))
+    }
 
-        let remote = self.batch_get_channel_pts(channels.clone()).await?;
-        let remote_pts: HashMap<(u64, i32), u64> = remote
-            .channel_pts_map
-            .into_iter()
-            .map(|row| {
-                (
-                    (row.channel_id, i32::from(row.channel_type)),
-                    row.current_pts,
-                )
-            })
-            .collect();
-        let budget = if self.network_hint == NetworkHint::Cellular {
-            CELLULAR_DIFFERENCE_BUDGET
-        } else {
-            WIFI_DIFFERENCE_BUDGET
+    async fn get_channel_pts(&mut self, channel_id: u64, channel_type: i32) -> Result<u64> {
+        let timeout = self.timeout();
+        let req = GetChannelPtsRequest {
+            channel_id,
+            channel_type: u8::try_from(channel_type).unwrap_or(1),
         };
-        let mut observations = Vec::with_capacity(channels.len());
-        for (channel_id, channel_type) in channels.iter().copied() {
-            let materialized_pts = self
-                .storage
-                .max_message_pts(channel_id, channel_type)
-                .await?;
-            let local_pts = self
-                .load_resume_channel_pts(channel_id, channel_type)
-                .await
-                .unwrap_or(0)
-                .max(materialized_pts);
-            observations.push(AntiEntropyObservation {
-                key: (channel_id, channel_type),
-                local_pts,
-                server_pts: remote_pts.get(&(channel_id, channel_type)).copied(),
-            });
-        }
-        let plan = plan_anti_entropy_page(&observations, budget);
-        let mut messages_applied = 0usize;
-        for (channel_id, channel_type) in plan.repair.iter().copied() {
-            messages_applied += self
-                .resume_channel_difference(channel_id, channel_type)
-                .await?;
+        let request = RpcRequest {
+            route: routes::sync::GET_CHANNEL_PTS.to_string(),
+            body: serde_json::to_vec(&req)
+                .map_err(|e| Error::Serialization(format!("encode get_channel_pts body: {e}")))?,
+        };
+        let payload = encode_message(&request)
+            .map_err(|e| Error::Serialization(format!("encode get_channel_pts rpc: {e}")))?;
+        let raw = self
+            .request_bytes(
+                Bytes::from(payload),
+                MessageType::RpcRequest as u8,
+                timeout,
+                "rpc get_channel_pts",
+            )
+            .await?;
+        let rpc_resp: RpcResponse = decode_message(&raw)
+            .map_err(|e| Error::Serialization(format!("decode get_channel_pts rpc: {e}")))?;
+        if rpc_resp.code != 0 {
+            return Err(Self::sync_rpc_rejection(
+                &format!(
+                    "get_channel_pts channel_id={} channel_type={}",
+                    channel_id, channel_type
+                ),
+                rpc_resp.code,
+                rpc_resp.message,
+            ));
         }
-        let channels_repaired = plan.repair.len();
+        let body = rpc_resp
+            .data
+            .ok_or_else(|| Error::Serialization("empty get_channel_pts data".into()))?;
+        let resp: GetChannelPtsResponse = serde_json::from_slice(&body)
+            .map_err(|e| Error::Serialization(format!("decode get_channel_pts response: {e}")))?;
+        Ok(resp.current_pts)
+    }
 
-        if plan.deferred > 0 {
-            if let Some((channel_id, channel_type)) = plan.last_consumed {
-                self.save_anti_entropy_cursor(channel_id, channel_type)
-                    .await?;
-            }
-        } else if channels.len() < PAGE_SIZE {
-            // 已消费末页，当前周期完整结束。
-            cycle_completed = true;
-            self.save_anti_entropy_cursor(0, -1).await?;
-        } else if let Some((channel_id, channel_type)) = channels.last().copied() {
-            self.save_anti_entropy_cursor(channel_id, channel_type)
-                .await?;
-        }
-        tracing::debug!(
-            scanned = plan.consumed,
-            difference_calls = channels_repaired,
-            channels_repaired,
-            messages_applied,
-            stale_found = plan.stale_found,
-            unknown_channels = plan.unknown_channels,
-            deferred = plan.deferred,
-            cycle_completed,
-            "anti-entropy channel page completed"
+    async fn batch_get_channel_pts(
+        &mut self,
+        channels: Vec<(u64, i32)>,
+    ) -> Result<BatchGetChannelPtsResponse> {
+        self.rpc_call_typed(
+            routes::sync::BATCH_GET_CHANNEL_PTS,
+            &BatchGetChannelPtsRequest {
+                channels: channels
+                    .into_iter()
+                    .map(|(channel_id, channel_type)| ChannelIdentifier {
+                        channel_id,
+                        channel_type: u8::try_from(channel_type).unwrap_or(1),
+                    })
+                    .collect(),
+            },
+        )
+        .await
+    }
+
+    /// wire message_type 字符串 → 本地 i32（history/around/hydrate 共用，防三处漂移）。
+    fn wire_message_type_to_i32(t: &str) -> i32 {
+        let ct = match t {
+            "image" => ContentMessageType::Image,
+            "voice" => ContentMessageType::Voice,
+            "video" => ContentMessageType::Video,
+            // 注：普通音频文件（mp3/wav/...）作为 File 消息发送，不再有独立 Audio 类型
+            "file" | "audio" => ContentMessageType::File,
+            // RP-12：资金卡片（服务端注入）历史/冷同步必须保留类型，否则落成 Text→渲染原始 JSON
+            "red_packet" => ContentMessageType::RedPacket,
+            "money_transfer" => ContentMessageType::MoneyTransfer,
+            "system" => ContentMessageType::System,
+            _ => ContentMessageType::Text,
+        };
+        i32::try_from(ct.as_u32()).unwrap_or(0)
+    }
+
+    /// 单条历史消息落库（SDK-HISTORY-2 核心）：status=2 + 真实 pts(=message_seq)。
+    /// get/around 回填共用；upsert 的乱序回退 guard 由 order_seq 保证幂等。
+    /// history / around 响应的一条消息 → 落库入参。
+    ///
+    /// 一处定义,三条回填路径共用(store_history_item / channel hydrate /
+    /// bootstrap 内联 hydrate)。此前是三份手抄,`extra` 在其中两份里被写成空串,
+    /// 于是同一条图片消息「翻页翻到」和「hydrate 拉到」的行内容不一样——这种分叉
+    /// 只要还存在三份,就一定会再次发生。
+    fn history_item_to_upsert_input(
+        item: &MessageHistoryItem,
+        channel_type: i32,
+    ) -> UpsertRemoteMessageInput {
+        let canonical = crate::canonical_inbound::CanonicalInboundMessage::from_history_item(
+            item,
+            if channel_type == 0 { 1 } else { channel_type },
+            Self::wire_message_type_to_i32,
         );
-        Ok(AntiEntropyPage {
-            page_scanned: plan.consumed,
-            stale_found: plan.stale_found,
-            unknown_channels: plan.unknown_channels,
-            channels_repaired,
-            messages_applied,
-            deferred: plan.deferred,
-            cycle_completed,
-        })
+        let mime_type = Self::extract_mime_type_from_json(&canonical.content, &canonical.extra);
+        // status=2：history 返回的都是服务端已确认的消息。
+        canonical.to_upsert_input(2, mime_type)
     }
 
-    async fn execute_resume_sync(&mut self) -> Result<()> {
-        if self.session_state != SessionState::Authenticated {
-            let err =
-                Error::InvalidState("run_resume_sync requires authenticated state".to_string());
-            let _ = self
-                .handle_resume_failure(ResumeFailureTarget::Global, &err)
-                .await;
-            return Err(err);
-        }
-        if !self.bootstrap_completed {
-            return Ok(());
+    async fn store_history_items<'a, I>(&self, items: I, channel_type: i32) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = &'a MessageHistoryItem>,
+    {
+        let inputs = items
+            .into_iter()
+            .map(|item| Self::history_item_to_upsert_input(item, channel_type))
+            .collect();
+        self.storage
+            .batch_upsert_remote_messages(inputs)
+            .await?
+            .into_iter()
+            .map(|result| result.map(|stored| stored.message_id))
+            .collect()
+    }
+
+    /// 回填式拉取频道历史：RPC → 逐条落库 → 返回响应（spec §6：get 结果必须回填，
+    /// UI 随后从本地库重查渲染）。不更新 last_message（向前翻页不改会话预览）。
+    async fn fetch_and_store_channel_history(
+        &mut self,
+        channel_id: u64,
+        channel_type: i32,
+        before_server_message_id: Option<u64>,
+        limit: Option<u32>,
+    ) -> Result<MessageHistoryResponse> {
+        let req = MessageHistoryGetRequest {
+            user_id: 0,
+            channel_id,
+            before_server_message_id,
+            limit,
+        };
+        let resp: MessageHistoryResponse = self
+            .rpc_call_typed(routes::message_history::GET, &req)
+            .await?;
+        let normalized_channel_type = if channel_type == 0 { 1 } else { channel_type };
+        self.store_history_items(resp.messages.iter(), normalized_channel_type)
+            .await?;
+        Ok(resp)
+    }
+
+    /// jump-to-message 上下文：RPC around → before/anchor/after 全部回填 → 返回响应。
+    /// search 命中（snippet 投影）不落库；点击后走本方法拿完整消息（spec §4/§5/§6 边界）。
+    async fn fetch_and_store_messages_around(
+        &mut self,
+        channel_id: u64,
+        channel_type: i32,
+        message_id: u64,
+        before_limit: Option<u32>,
+        after_limit: Option<u32>,
+    ) -> Result<MessageHistoryAroundResponse> {
+        let req = MessageHistoryAroundRequest {
+            channel_id,
+            message_id,
+            before_limit,
+            after_limit,
+        };
+        let resp: MessageHistoryAroundResponse = self
+            .rpc_call_typed(routes::message_history::AROUND, &req)
+            .await?;
+        let normalized_channel_type = if channel_type == 0 { 1 } else { channel_type };
+        self.store_history_items(
+            resp.before_messages
+                .iter()
+                .chain(std::iter::once(&resp.anchor_message))
+                .chain(resp.after_messages.iter()),
+            normalized_channel_type,
+        )
+        .await?;
+        Ok(resp)
+    }
+
+    /// 已读回执：先用本地成员游标算；`backfill` 且群里还有成员没有游标时，才发一次
+    /// READ_LIST，把名单里出现的人记成读到了本消息的 pts，再重算。RPC 失败（离线、
+    /// 超时）不算错，返回本地结果——回执是展示数据，不值得让调用方报错。
+    async fn message_read_receipts(
+        &mut self,
+        message_id: u64,
+        backfill: bool,
+    ) -> Result<MessageReadReceipts> {
+        let receipts = self
+            .storage
+            .get_message_read_receipts(message_id)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("message.id={message_id}")))?;
+        if !backfill || receipts.unknown_member_ids.is_empty() {
+            return Ok(receipts);
         }
-        // P0-12 单轮化：同一连接世代内一轮成功后，30s 内的重复触发直接跳过。
-        // 冷启动（connect + bootstrap 双入口）和重连（retry driver / connect /
-        // cmd_authenticate 三入口）都会连续触发多轮全家桶；连接期间的增量由
-        // push 实时覆盖，重复轮次只放大服务端压力。debounce 而非严格单次：
-        // 前台回归等超过窗口的触发仍作为安全网执行。
-        if let Some((epoch, at)) = self.last_resume_synced {
-            if epoch == self.inbound_epoch && at.elapsed() < Duration::from_secs(30) {
-                eprintln!(
-                    "[SDK.actor] resume sync skipped: epoch {} already synced {}ms ago",
-                    epoch,
-                    at.elapsed().as_millis()
-                );
-                return Ok(());
+        let Some(server_message_id) = self
+            .storage
+            .get_message_by_id(message_id)
+            .await?
+            .and_then(|m| m.server_message_id)
+        else {
+            return Ok(receipts);
+        };
+        let resp: MessageReadListResponse = match self
+            .rpc_call_typed(
+                routes::message_status::READ_LIST,
+                &MessageReadListRequest {
+                    message_id: server_message_id,
+                    channel_id: receipts.channel_id,
+                },
+            )
+            .await
+        {
+            Ok(resp) => resp,
+            Err(e) => {
+                if inbound_logs_enabled() {
+                    eprintln!(
+                        "[SDK.read] read receipt backfill skipped: message_id={} error={}",
+                        message_id, e
+                    );
+                }
+                return Ok(receipts);
+            }
+        };
+        let mut advanced = false;
+        for reader in resp.readers {
+            if receipts.unknown_member_ids.contains(&reader.user_id) {
+                advanced |= self
+                    .storage
+                    .save_member_read_pts(
+                        receipts.channel_id,
+                        receipts.channel_type,
+                        reader.user_id,
+                        receipts.pts,
+                    )
+                    .await?;
             }
         }
-        let mut stats = ResumeRunStats::default();
-        self.resume_run_id = self.resume_run_id.wrapping_add(1);
-        let run_id = self.resume_run_id;
-        println!("[SDK.resume] run={run_id} phase=2 begin");
-        self.queue_resume_started();
-        if let Err(err) = self.send_session_ready().await {
-            let _ = self
-                .handle_resume_failure(ResumeFailureTarget::Global, &err)
-                .await;
-            return Err(err);
+        if !advanced {
+            return Ok(receipts);
         }
+        self.storage
+            .get_message_read_receipts(message_id)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("message.id={message_id}")))
+    }
 
-        let entity_order = ["friend", "group", "channel", "user", "channel_read_cursor"];
-        for entity_type in entity_order {
-            match self.sync_entities(entity_type.to_string(), None).await {
-                Ok(applied) => {
-                    stats.entity_types_synced += 1;
-                    self.queue_sync_summary_event(entity_type.to_string(), None, applied);
-                }
-                Err(e) if Self::is_unsupported_entity_error(&e) => {
-                    self.log_unsupported_sync_skip("resume sync", entity_type, None, &e);
+    /// 按会话偏好算一次未读聚合，规则见 [`unread_badge`]。
+    async fn compute_unread_aggregate(&self, uid: &str) -> Result<UnreadAggregate> {
+        let user_id = uid
+            .parse::<u64>()
+            .map_err(|_| Error::InvalidState(format!("current uid is not numeric: {uid}")))?;
+        let channels = self.storage.list_channel_unread_rows().await?;
+        let mut prefs: HashMap<(u64, i32), ChannelPrefsState> = HashMap::new();
+        for (key, raw) in self
+            .storage
+            .kv_scan_prefix(CHANNEL_PREFS_KEY_PREFIX.to_string())
+            .await?
+        {
+            if let Some(channel) = parse_channel_prefs_key(&key) {
+                prefs.insert(channel, decode_channel_prefs(Some(raw)));
+            }
+        }
+        let mentions: HashMap<(u64, i32), u32> = self
+            .storage
+            .get_all_unread_mention_counts(user_id)
+            .await?
+            .into_iter()
+            .map(|m| ((m.channel_id, m.channel_type), m.unread_count.max(0) as u32))
+            .collect();
+        let (folders, mut folder_ids) = self.channel_folder_memberships().await?;
+        let inputs = channels.into_iter().map(|row| {
+            let key = (row.channel_id, row.channel_type);
+            let pref = prefs.remove(&key).unwrap_or_default();
+            unread_badge::ChannelUnreadInput {
+                unread_count: row.unread_count.max(0) as u32,
+                mention_count: mentions.get(&key).copied().unwrap_or(0),
+                channel_muted: row.mute,
+                notification_mode: pref.notification_mode,
+                favourite: pref.favourite,
+                low_priority: pref.low_priority,
+                tags: pref.tags,
+                folder_ids: folder_ids.remove(&key).unwrap_or_default(),
+            }
+        });
+        Ok(unread_badge::aggregate_unread(inputs, folders))
+    }
+
+    /// 各文件夹（计数为 0，按 `sort_order`）以及每个会话被哪些文件夹收录。收录规则与
+    /// 按 `folder_id` 查会话列表相同，直接走同一条查询；调用方保证 `channel_pref` 镜像已回填。
+    async fn channel_folder_memberships(
+        &self,
+    ) -> Result<(
+        Vec<unread_badge::UnreadFolderTotal>,
+        HashMap<(u64, i32), Vec<u64>>,
+    )> {
+        let mut totals = Vec::new();
+        let mut members: HashMap<(u64, i32), Vec<u64>> = HashMap::new();
+        for folder in self.storage.list_channel_folders().await? {
+            let mut query = ChannelListQuery {
+                filter: ChannelListFilter {
+                    folder_id: Some(folder.folder_id),
+                    ..Default::default()
+                },
+                pinned_first: false,
+                limit: 500,
+                ..Default::default()
+            };
+            loop {
+                let page = self.storage.query_channels(query.clone()).await?;
+                for channel in &page.channels {
+                    members
+                        .entry((channel.channel_id, channel.channel_type))
+                        .or_default()
+                        .push(folder.folder_id);
                 }
-                Err(e) => {
-                    let handling = self
-                        .handle_resume_failure(
-                            ResumeFailureTarget::EntityType(entity_type.to_string()),
-                            &e,
-                        )
-                        .await;
-                    if handling == ResumeFailureHandling::Abort {
-                        return Err(e);
-                    }
+                match page.next_cursor {
+                    Some(cursor) => query.after = Some(cursor),
+                    None => break,
                 }
             }
+            totals.push(unread_badge::UnreadFolderTotal {
+                folder_id: folder.folder_id,
+                name: folder.name,
+                ..Default::default()
+            });
         }
+        Ok((totals, members))
+    }
 
-        // ── Phase 2 到此为止（spec SDK_SYNC_RESUME_SPEC §Startup Phases）──
-        //
-        // 上面几个实体族是**自包含列表投影**：会话/好友/群列表带着渲染该行所需的
-        // 名称、头像、类型，请求数固定为实体族个数，与账号大小无关。到这里主界面
-        // 就能正确显示了，立刻宣布 CriticalReady 让用户可用。
-        //
-        // 原先排在这之后的两段被移出关键路径：
-        //   1. 逐群 sync_entities("group_member", group_id) —— 请求数 O(群数)
-        //   2. 逐频道 resume_channel_difference        —— 请求数 O(频道数 × 分页)
-        // 它们让上线时延随账号大小线性增长（实测百来个会话就要挂几分钟），
-        // 现在交给 Phase 3 后台收敛：先 batch_get_channel_pts 批量比对，只修 stale。
-        // Phase 2 完成的信号就是 readiness → Ready：ensure_synced 收尾时
-        // `sync_coordinator.complete()` 会置位并发 SyncStateChanged，宿主据此撤横幅。
-        // 不另造 CriticalReady 事件——uniffi 绑定是手工维护的，多一个变体就要同步
-        // 三个平台的绑定文件，而 readiness 已经把这件事说清楚了。
-        //
-        // 旧 `ResumeSyncCompleted` 保持原义：在 Phase 3 收敛完成时才发（见 repair_tick）。
-        //
-        // 开启 Phase 3。这里只置标志，实际收敛由 actor loop 的 repair_tick 驱动，
-        // 一轮修一小批（有界预算），不阻塞命令处理，也不霸占 actor。
-        // println! 而非 eprintln!：Android 的 logcat 收不到 stderr，
-        // 而这些打点的用途正是生产排查。
-        println!(
-            "[SDK.resume] run={run_id} phase=2 done entity_types={} (critical path complete)",
-            stats.entity_types_synced
-        );
-        // stats 交给 Phase 3：ResumeSyncCompleted 只在真正全量收敛后发一次。
-        // 在这里发过一次是漏删——那会让宿主以为整轮结束，而收敛才刚开始。
-        self.convergence_run = Some(stats);
-        const CONVERGENCE_START_GRACE_MS: i64 = 5_000;
-        self.sync_coordinator.schedule_convergence(
-            chrono::Utc::now().timestamp_millis(),
-            CONVERGENCE_START_GRACE_MS,
-        );
-        Ok(())
+    /// 重算未读聚合；和上一次发出去的不同才发 [`SdkEvent::BadgeChanged`]。
+    async fn refresh_unread_aggregate(&mut self) -> Result<UnreadAggregate> {
+        self.badge_dirty = false;
+        let uid = self.current_uid_required()?;
+        // 文件夹的条件收录要按偏好筛选，读的是 channel_pref 镜像。
+        self.ensure_channel_prefs_mirrored().await?;
+        let aggregate = self.compute_unread_aggregate(&uid).await?;
+        let changed = match &self.last_unread_aggregate {
+            Some((last_uid, last)) => last_uid != &uid || last != &aggregate,
+            None => true,
+        };
+        if changed {
+            self.last_unread_aggregate = Some((uid, aggregate.clone()));
+            let event = SdkEvent::BadgeChanged {
+                aggregate: aggregate.clone(),
+            };
+            if let (Some(tx), Some(history), Some(seq)) =
+                (&self.event_tx, &self.event_history, &self.event_seq)
+            {
+                emit_sequenced_event(tx, history, seq, self.event_history_limit, event);
+            } else if let Some(tx) = &self.event_tx {
+                let _ = tx.send(event);
+            }
+        }
+        Ok(aggregate)
     }
 
-    async fn full_rebuild_required(&self) -> bool {
-        self.storage
-            .kv_get(Self::resume_repair_full_rebuild_key())
-            .await
-            .ok()
-            .flatten()
-            .is_some()
+    /// 会话列表查询按偏好筛选前，确保当前账号的 `channel_pref` 镜像已从 kv 整体回填。
+    async fn ensure_channel_prefs_mirrored(&mut self) -> Result<()> {
+        let uid = self.current_uid_required()?;
+        if self.channel_prefs_mirrored_uid.as_deref() == Some(uid.as_str()) {
+            return Ok(());
+        }
+        let prefs = self
+            .storage
+            .kv_scan_prefix(CHANNEL_PREFS_KEY_PREFIX.to_string())
+            .await?
+            .into_iter()
+            .filter_map(|(key, raw)| {
+                let (channel_id, channel_type) = parse_channel_prefs_key(&key)?;
+                let state = decode_channel_prefs(Some(raw));
+                Some(channel_pref_row(channel_id, channel_type, &state))
+            })
+            .collect();
+        self.storage.replace_channel_prefs(prefs).await?;
+        self.channel_prefs_mirrored_uid = Some(uid);
+        Ok(())
     }
 
-    /// [bump_unread_on_incoming]:仅真正的实时推送传 true。resume/difference 回填的
-    /// 历史消息必须传 false——它们已包含在 channel 冷启动同步的服务端未读基线里,
-    /// 再按实时消息 bump 会把未读恰好双算(2026-07-24 事故:系统消息 11→22、DM 1→2)。
-    async fn enqueue_and_apply_sync_items(
+    /// 把一条损坏的投影排进 repair 队列。**立即返回，不发网络。**
+    ///
+    /// 调用它的是读路径（打开会话、上滑翻页）——那里绝不能等一串 around 请求：
+    /// 坏数据还没修好之前会话就先卡住，用户付出的代价比问题本身大。真正的修复由
+    /// actor 的 repair tick 取队列执行。
+    ///
+    /// 三道闸门，少一道就会变成「每次读都打一遍服务端」：
+    /// - **singleflight**：`repair_seen` 保证同一条消息只排一次。反复读到同一条坏行
+    ///   是常态（打开、上滑、切回来），每次都发请求是拿用户流量换同一个答案。
+    /// - **有界**：队列超过上限就丢弃，留给下一次读取重新发现——堆积没有意义，
+    ///   因为损坏是从数据本身发现的，不会丢。
+    /// - **退避**：失败多半是离线或服务端不稳，退避期内直接跳过。不需要额外的
+    ///   「网络恢复唤醒」：读路径本身就是唤醒信号。
+    fn enqueue_projection_repair(
         &mut self,
-        entity_type: String,
-        scope: Option<String>,
-        items: Vec<SyncEntityItem>,
-        bump_unread_on_incoming: bool,
-    ) -> Result<usize> {
-        self.last_sync_entity_events.clear();
-        let stats = self
-            .receive_pipeline
-            .enqueue(entity_type.clone(), scope.clone(), items);
-        self.last_sync_queued = stats.queued_items;
-        self.last_sync_dropped_duplicates = stats.dropped_duplicates;
-
-        let mut applied = 0usize;
-        let mut entity_events: Vec<SdkEvent> = Vec::new();
-        for batch in SyncCommitApplier::drain_batches(&mut self.receive_pipeline) {
-            let count = batch.items.len();
-            match self
-                .apply_sync_entities(
-                    &batch.entity_type,
-                    batch.scope.as_deref(),
-                    &batch.items,
-                    bump_unread_on_incoming,
-                )
-                .await
-            {
-                Ok(events) => {
-                    applied += count;
-                    entity_events.extend(events);
-                }
-                Err(err) => {
-                    SyncCommitApplier::requeue_front(&mut self.receive_pipeline, batch);
-                    return Err(err);
-                }
+        channel_id: u64,
+        channel_type: i32,
+        server_message_id: u64,
+    ) {
+        let key = (channel_type, channel_id, server_message_id);
+        if let Some((_, until)) = self.repair_backoff.get(&key) {
+            if std::time::Instant::now() < *until {
+                return;
             }
         }
-        self.invalidate_cache_for_events(&entity_events);
-        self.last_sync_entity_events = entity_events;
-        Ok(applied)
+        if !self.repair_seen.insert(key) {
+            return;
+        }
+        if self.repair_queue.len() >= REPAIR_QUEUE_LIMIT {
+            self.repair_seen.remove(&key);
+            return;
+        }
+        self.repair_queue.push_back(key);
     }
 
-    /// P1-05：room 广播去重。返回 true 表示这条 (channel_id, server_message_id) 最近
-    /// 已见过（应丢弃）。None id 一律放行（无法去重）。每 channel 有界 FIFO（256）。
-    fn room_message_is_duplicate(
-        &mut self,
+    /// Submit a thumbnail to the existing receiver-side [`DownloadManager`].
+    ///
+    /// This method is deliberately synchronous: message materialization and UI
+    /// visibility only register work. Ticket resolution, HTTP and disk IO run in
+    /// the manager's bounded account/session-scoped task.
+    fn submit_thumbnail_download(
+        &self,
+        message_id: u64,
         channel_id: u64,
-        server_message_id: Option<u64>,
-    ) -> bool {
-        const ROOM_DEDUP_WINDOW: usize = 256;
-        let Some(id) = server_message_id else {
-            return false;
+        channel_type: i32,
+        created_at_ms: i64,
+        extra: &str,
+        priority: media_download::DownloadPriority,
+    ) {
+        let Some(owner_uid) = self.current_uid.clone() else {
+            return;
         };
-        let seen = self.room_seen_msg_ids.entry(channel_id).or_default();
-        if seen.contains(&id) {
-            return true;
-        }
-        seen.push_back(id);
-        if seen.len() > ROOM_DEDUP_WINDOW {
-            seen.pop_front();
-        }
-        false
-    }
+        let key = media_download::MediaTaskKey::thumbnail(
+            owner_uid.clone(),
+            self.session_epoch,
+            message_id,
+        );
+        let storage = self.storage.clone();
+        let transport = self.transport.clone();
+        let timeout = self.timeout();
+        let bandwidth = self.bandwidth.clone();
+        let content = extra.to_string();
+        let thumbnail_file_id = Self::extract_thumbnail_file_id(extra);
+        let actor_tx = self.actor_tx.clone();
+        let session_epoch = self.session_epoch;
 
-    async fn handle_inbound_frame(&mut self, biz_type: u8, data: Vec<u8>) -> Result<usize> {
-        if inbound_logs_enabled() {
-            eprintln!(
-                "[SDK.inbound] frame biz_type={} len={}",
-                biz_type,
-                data.len()
-            );
-        }
-        let message_type = MessageType::from(biz_type);
-        if inbound_logs_enabled() {
-            eprintln!("[SDK.inbound] frame message_type={:?}", message_type);
-        }
-        Self::log_inbound_decoded(message_type, &data);
-        // Do not drop server push frames during login->authenticate gap.
-        // current_uid is set by login, while session_state may still be LoggedIn.
-        // If we gate strictly by Authenticated, login notice pushes are lost and only
-        // channel preview is updated by sync, leaving message table empty.
-        if self.current_uid.is_none() {
-            match message_type {
-                MessageType::SendMessageRequest
-                | MessageType::PushMessageRequest
-                | MessageType::PushBatchRequest
-                | MessageType::PublishRequest => {
-                    if self.pending_prelogin_inbound_frames.len() >= 256 {
-                        let _ = self.pending_prelogin_inbound_frames.remove(0);
-                    }
-                    self.pending_prelogin_inbound_frames.push((biz_type, data));
-                    if realtime_trace_enabled() {
-                        eprintln!(
-                        "[SDK_INBOUND_PRELOGIN_BUFFER] biz_type={} current_uid=None queue_len={} (push 帧卡在登录前缓冲；若不 replay 即收不到)",
-                        biz_type,
-                        self.pending_prelogin_inbound_frames.len()
-                    );
-                    }
+        let _ = self.download_manager.submit(key, priority, async move {
+            let paths = match storage.get_storage_paths_for_uid(owner_uid.clone()).await {
+                Ok(paths) => paths,
+                Err(error) => {
+                    tracing::warn!(message_id, %error, "resolve thumbnail owner storage failed");
+                    return;
                 }
-                _ => {
-                    if inbound_logs_enabled() {
-                        eprintln!(
-                            "[SDK.inbound] skip frame before login message_type={:?} biz_type={}",
-                            message_type, biz_type
-                        );
+            };
+            let ticket = if let Some(file_id) = thumbnail_file_id {
+                let Some(transport) = transport.as_ref() else {
+                    return;
+                };
+                // 排队期间省流量可能刚生效或刚关掉：按真正去要的那一刻算。
+                let max_edge = bandwidth
+                    .data_saver_active()
+                    .then_some(DATA_SAVER_THUMBNAIL_MAX_EDGE);
+                let mut resolved = None;
+                for attempt in 0..3u64 {
+                    resolved = State::resolve_thumbnail_ticket_detached(
+                        transport, file_id, max_edge, timeout,
+                    )
+                    .await;
+                    if resolved.is_some() {
+                        break;
+                    }
+                    if attempt < 2 {
+                        tokio::time::sleep(Duration::from_millis(500 * (1 << attempt))).await;
                     }
                 }
-            }
-            return Ok(0);
-        }
-        let mut message_items = Vec::new();
-        let mut read_cursor_items = Vec::new();
-        let mut direct_applied = 0usize;
-        // (channel_id, channel_type, server_message_id, delivered_at)
-        let mut delivery_receipts: Vec<(u64, i32, u64, u64)> = Vec::new();
-        match message_type {
-            MessageType::SendMessageRequest => {
-                let req: SendMessageRequest = decode_message(&data).map_err(|e| {
-                    Error::Serialization(format!("decode send message request: {e}"))
-                })?;
-                let current_user_id = self
-                    .current_uid
-                    .as_ref()
-                    .and_then(|v| v.parse::<u64>().ok());
-                // Accept self-echo SendMessageRequest as a reconciliation signal.
-                // In some transport paths the outbound request can be committed on server
-                // while response correlation is dropped; processing self-echo lets local
-                // status converge to sent instead of remaining failed.
-                if inbound_logs_enabled() && current_user_id == Some(req.from_uid) {
-                    eprintln!(
-                        "[SDK.inbound] process self SendMessageRequest echo: from_uid={} local_message_id={}",
-                        req.from_uid, req.local_message_id
+                let Some(ticket) = resolved else {
+                    tracing::warn!(
+                        message_id,
+                        file_id,
+                        "thumbnail ticket unavailable after retry"
                     );
-                }
-                let channel_type = self
-                    .storage
-                    .get_channel_by_id(req.channel_id)
-                    .await?
-                    .map(|ch| ch.channel_type as u8)
-                    .unwrap_or(1);
-                if let Some(item) = Self::send_message_to_sync_item(req, channel_type) {
-                    if inbound_logs_enabled() {
-                        eprintln!(
-                            "[SDK.inbound] mapped SendMessageRequest -> sync item channel_type={}",
-                            channel_type
-                        );
-                    }
-                    message_items.push(item);
-                }
+                    return;
+                };
+                Some(ticket)
+            } else {
+                None
+            };
+            let Some(outcome) = State::run_auto_download_thumbnail(
+                &content,
+                ticket,
+                &paths.user_root,
+                message_id,
+                created_at_ms,
+                channel_id,
+                channel_type,
+                &bandwidth,
+            )
+            .await
+            else {
+                return;
+            };
+            let Some(actor_tx) = actor_tx.upgrade() else {
+                return;
+            };
+            let (resp_tx, resp_rx) = oneshot::channel();
+            if actor_tx
+                .send(Command::CompleteThumbnailDownload {
+                    owner_uid,
+                    session_epoch,
+                    message_id,
+                    channel_id,
+                    channel_type,
+                    outcome,
+                    resp: resp_tx,
+                })
+                .await
+                .is_ok()
+            {
+                // Keep the DownloadManager singleflight entry until the actor has
+                // either committed or rejected this exact session's outcome.
+                let _ = resp_rx.await;
             }
-            MessageType::PushMessageRequest => {
-                let req: PushMessageRequest = decode_message(&data)
-                    .map_err(|e| Error::Serialization(format!("decode push message: {e}")))?;
-                if self.skip_inbound_materialization_for_load_testing {
-                    return Ok(0);
-                }
-                if realtime_trace_enabled() {
-                    eprintln!(
-                    "[SDK_MESSAGE_EVENT_DECODED] biz=Push channel_id={} server_message_id={} from_uid={} msg_type={} payload_len={}",
-                    req.channel_id, req.server_message_id, req.from_uid, req.message_type, req.payload.len()
-                );
+        });
+    }
+
+    /// 当前账号的自动下载策略（账号 kv，见 [`auto_download`]）。
+    async fn load_auto_download_policy(&self) -> auto_download::AutoDownloadPolicy {
+        let raw = self
+            .storage
+            .kv_get(auto_download::POLICY_KV_KEY.to_string())
+            .await
+            .ok()
+            .flatten();
+        auto_download::AutoDownloadPolicy::decode(raw.as_deref())
+    }
+
+    /// 会话算不算静音，规则与角标一致（[`unread_badge::channel_is_muted`]）：会话偏好里
+    /// 的通知模式优先，「只提醒 @」「不提醒」算静音、「所有消息」不算，默认时跟随
+    /// `channel.mute`。
+    async fn channel_muted_for_auto_download(&self, channel_id: u64, channel_type: i32) -> bool {
+        let channel_muted = matches!(
+            self.storage.get_channel_by_id(channel_id).await,
+            Ok(Some(channel)) if channel.mute != 0
+        );
+        let raw = self
+            .storage
+            .kv_get(channel_prefs_key(channel_id, channel_type))
+            .await
+            .ok()
+            .flatten();
+        unread_badge::channel_is_muted(decode_channel_prefs(raw).notification_mode, channel_muted)
+    }
+
+    /// 入站附件落库后按策略决定要不要后台先把主文件拉下来。只排队，不等下载。
+    async fn maybe_schedule_auto_download(
+        &mut self,
+        message_id: u64,
+        channel_id: u64,
+        channel_type: i32,
+        created_at_ms: i64,
+        message_type: i32,
+        extra: &str,
+    ) {
+        let Some(candidate) =
+            auto_download::AutoDownloadCandidate::from_message(message_type, extra)
+        else {
+            return;
+        };
+        if chrono::Utc::now().timestamp_millis() - created_at_ms
+            > auto_download::RECENT_MESSAGE_WINDOW_MS
+        {
+            return;
+        }
+        let Some(owner_uid) = self.current_uid.clone() else {
+            return;
+        };
+        if self.auto_downloads.len() >= AUTO_DOWNLOAD_PENDING_LIMIT {
+            return;
+        }
+        let policy = self.load_auto_download_policy().await;
+        let channel_muted = self
+            .channel_muted_for_auto_download(channel_id, channel_type)
+            .await;
+        if !policy.allows(
+            candidate.kind,
+            candidate.file_size,
+            self.network_hint,
+            channel_muted,
+        ) {
+            return;
+        }
+        let key =
+            media_download::MediaTaskKey::payload(owner_uid, self.session_epoch, message_id);
+        let pending = PendingAutoDownload {
+            candidate,
+            channel_muted,
+            created_at_ms,
+        };
+        if self.submit_auto_download(key.clone(), &pending) {
+            self.auto_downloads.insert(key, pending);
+        }
+    }
+
+    /// 把一条自动下载交给 [`DownloadManager`](media_download::DownloadManager)，后台优先级。
+    ///
+    /// 和缩略图一样：票据、HTTP、落盘都在 manager 的有界任务里，actor 只登记。
+    /// 完成后经 [`Command::CompleteAutoDownload`] 回到 actor 提交，旧会话的结果在那里丢弃。
+    fn submit_auto_download(
+        &self,
+        key: media_download::MediaTaskKey,
+        pending: &PendingAutoDownload,
+    ) -> bool {
+        let Some(transport) = self.transport.clone() else {
+            return false;
+        };
+        let storage = self.storage.clone();
+        let metrics = self.metrics.clone();
+        let bandwidth = self.bandwidth.clone();
+        let timeout = self.timeout();
+        let actor_tx = self.actor_tx.clone();
+        let candidate = pending.candidate.clone();
+        let created_at_ms = pending.created_at_ms;
+        let task_key = key.clone();
+        self.download_manager
+            .submit(key, media_download::DownloadPriority::Background, async move {
+                let result = async {
+                    let paths = storage
+                        .get_storage_paths_for_uid(task_key.owner_uid.clone())
+                        .await
+                        .map_err(|e| format!("resolve owner storage: {e}"))?;
+                    // file/get_url 对主文件和缩略图是同一个接口。
+                    let ticket = State::resolve_thumbnail_ticket_detached(
+                        &transport,
+                        candidate.file_id,
+                        None,
+                        timeout,
+                    )
+                    .await
+                    .ok_or_else(|| "file/get_url returned no ticket".to_string())?;
+                    let dir = media_store::get_message_dir(
+                        &paths.user_root,
+                        task_key.message_id as i64,
+                        created_at_ms,
+                    );
+                    std::fs::create_dir_all(&dir).map_err(|e| format!("create dir: {e}"))?;
+                    let filename = media_store::payload_filename_with_fallback(
+                        &candidate.mime,
+                        candidate.filename.as_deref(),
+                    );
+                    media_download::download_detached(
+                        &ticket, &dir, &filename, &metrics, &bandwidth,
+                    )
+                    .await
+                    .map(|path| path.to_string_lossy().to_string())
                 }
-                if let Some(count) = self.apply_entity_invalidation_push(&req).await? {
-                    direct_applied += count;
-                } else if let Some(count) = self.apply_canonical_timeline_push(&req).await? {
-                    direct_applied += count;
-                } else if let Some(peer_uid) = Self::push_message_to_friend_event(&req) {
-                    // F-sync.2: 转 entity_type="friend"，让 SDK 走 entity sync
-                    // 把 pending/rejected/recalled 状态从 server 拉到本地 friend 表。
-                    self.pending_events.push(SdkEvent::SyncEntityChanged {
-                        entity_type: "friend".to_string(),
-                        entity_id: peer_uid.to_string(),
-                        deleted: false,
-                    });
-                } else if let Some(status_item) = Self::push_message_to_status_sync_item(&req) {
-                    read_cursor_items.push(status_item);
-                } else if let Some(receipt) = Self::push_message_to_delivery_receipt(&req) {
-                    delivery_receipts.push(receipt);
-                } else {
-                    // deleted=true 时 push_message_to_sync_item 会透传给 SyncEntityItem.deleted，
-                    // 进而触发 "message" 实体处理里的 set_message_revoke 路径
-                    message_items.push(Self::push_message_to_sync_item(req));
+                .await;
+                let Some(actor_tx) = actor_tx.upgrade() else {
+                    return;
+                };
+                let (resp_tx, resp_rx) = oneshot::channel();
+                if actor_tx
+                    .send(Command::CompleteAutoDownload {
+                        key: task_key,
+                        result,
+                        resp: resp_tx,
+                    })
+                    .await
+                    .is_ok()
+                {
+                    // 同缩略图：actor 提交之前不让出 singleflight 条目。
+                    let _ = resp_rx.await;
                 }
+            })
+    }
+
+    /// 自动下载的结果。只认当前会话的；成功时标记已下载并发 `Done`，界面据此直接显示。
+    /// 失败不发事件——用户没点过它，没有「下载失败」可展示，点开时照常前台下载。
+    async fn apply_auto_download_outcome(
+        &mut self,
+        key: media_download::MediaTaskKey,
+        result: std::result::Result<String, String>,
+    ) {
+        self.auto_downloads.remove(&key);
+        if self.current_uid.as_deref() != Some(key.owner_uid.as_str())
+            || self.session_epoch != key.session_epoch
+        {
+            return;
+        }
+        let path = match result {
+            Ok(path) => path,
+            Err(error) => {
+                tracing::warn!(message_id = key.message_id, %error, "auto-download failed");
+                return;
             }
-            MessageType::PushBatchRequest => {
-                let req: PushBatchRequest = decode_message(&data)
-                    .map_err(|e| Error::Serialization(format!("decode push batch: {e}")))?;
-                if self.skip_inbound_materialization_for_load_testing {
-                    return Ok(0);
-                }
-                for push in req.messages {
-                    if let Some(count) = self.apply_entity_invalidation_push(&push).await? {
-                        direct_applied += count;
-                    } else if let Some(count) = self.apply_canonical_timeline_push(&push).await? {
-                        direct_applied += count;
-                    } else if let Some(peer_uid) = Self::push_message_to_friend_event(&push) {
-                        self.pending_events.push(SdkEvent::SyncEntityChanged {
-                            entity_type: "friend".to_string(),
-                            entity_id: peer_uid.to_string(),
-                            deleted: false,
-                        });
-                    } else if let Some(status_item) = Self::push_message_to_status_sync_item(&push)
-                    {
-                        read_cursor_items.push(status_item);
-                    } else if let Some(receipt) = Self::push_message_to_delivery_receipt(&push) {
-                        delivery_receipts.push(receipt);
-                    } else {
-                        message_items.push(Self::push_message_to_sync_item(push));
-                    }
-                }
-            }
-            MessageType::PublishRequest => {
-                let req: PublishRequest = decode_message(&data)
-                    .map_err(|e| Error::Serialization(format!("decode publish request: {e}")))?;
-                if let Ok(push) = decode_message::<PushMessageRequest>(&req.payload) {
-                    if self.skip_inbound_materialization_for_load_testing {
-                        return Ok(0);
-                    }
-                    // IM 场景：payload 是 PushMessageRequest（私聊/群聊消息走 sync pipeline）
-                    if inbound_logs_enabled() {
-                        eprintln!(
-                            "[SDK.inbound] publish payload decoded as PushMessageRequest channel_id={}",
-                            push.channel_id
-                        );
-                    }
-                    if let Some(count) = self.apply_entity_invalidation_push(&push).await? {
-                        direct_applied += count;
-                    } else if let Some(count) = self.apply_canonical_timeline_push(&push).await? {
-                        direct_applied += count;
-                    } else if let Some(peer_uid) = Self::push_message_to_friend_event(&push) {
-                        self.pending_events.push(SdkEvent::SyncEntityChanged {
-                            entity_type: "friend".to_string(),
-                            entity_id: peer_uid.to_string(),
-                            deleted: false,
-                        });
-                    } else if let Some(status_item) = Self::push_message_to_status_sync_item(&push)
-                    {
-                        read_cursor_items.push(status_item);
-                    } else if let Some(receipt) = Self::push_message_to_delivery_receipt(&push) {
-                        delivery_receipts.push(receipt);
-                    } else {
-                        message_items.push(Self::push_message_to_sync_item(push));
-                    }
-                } else if let Ok(batch) = decode_message::<PushBatchRequest>(&req.payload) {
-                    if self.skip_inbound_materialization_for_load_testing {
-                        return Ok(0);
-                    }
-                    if inbound_logs_enabled() {
-                        eprintln!(
-                            "[SDK.inbound] publish payload decoded as PushBatchRequest count={}",
-                            batch.messages.len()
-                        );
-                    }
-                    for push in batch.messages {
-                        if let Some(count) = self.apply_entity_invalidation_push(&push).await? {
-                            direct_applied += count;
-                        } else if let Some(count) =
-                            self.apply_canonical_timeline_push(&push).await?
-                        {
-                            direct_applied += count;
-                        } else if let Some(peer_uid) = Self::push_message_to_friend_event(&push) {
-                            self.pending_events.push(SdkEvent::SyncEntityChanged {
-                                entity_type: "friend".to_string(),
-                                entity_id: peer_uid.to_string(),
-                                deleted: false,
-                            });
-                        } else if let Some(status_item) =
-                            Self::push_message_to_status_sync_item(&push)
-                        {
-                            read_cursor_items.push(status_item);
-                        } else if let Some(receipt) = Self::push_message_to_delivery_receipt(&push)
-                        {
-                            delivery_receipts.push(receipt);
-                        } else {
-                            message_items.push(Self::push_message_to_sync_item(push));
-                        }
-                    }
-                } else {
-                    // Room 场景：payload 是原始内容（纯网络，不走本地数据库）
-                    if inbound_logs_enabled() {
-                        eprintln!(
-                            "[SDK.inbound] room publish channel_id={} topic={:?} payload_len={}",
-                            req.channel_id,
-                            req.topic,
-                            req.payload.len()
-                        );
-                    }
-                    // P1-05：去重 replay/live 重叠窗口的重复帧。presence_changed 是无 id
-                    // 的状态帧，不参与去重（每次都应用最新态）。
-                    if req.topic.as_deref() == Some("presence_changed") {
-                        self.apply_presence_changed_payload(&req.payload);
-                    } else if self.room_message_is_duplicate(req.channel_id, req.server_message_id)
-                    {
-                        if inbound_logs_enabled() {
-                            eprintln!(
-                                "[SDK.inbound] drop duplicate room publish channel_id={} server_message_id={:?}",
-                                req.channel_id, req.server_message_id
-                            );
-                        }
-                        return Ok(0);
-                    }
-                    self.pending_events
-                        .push(SdkEvent::SubscriptionMessageReceived {
-                            channel_id: req.channel_id,
-                            topic: req.topic,
-                            payload: req.payload,
-                            publisher: req.publisher,
-                            server_message_id: req.server_message_id,
-                            timestamp: req.timestamp,
-                        });
-                }
-            }
-            _ => {
-                if inbound_logs_enabled() {
-                    eprintln!(
-                        "[SDK.inbound] ignore inbound message_type={:?} biz_type={}",
-                        message_type, biz_type
-                    );
-                }
-                return Ok(0);
-            }
-        }
-
-        let mut applied = direct_applied;
-        if !message_items.is_empty() {
-            let n = message_items.len();
-            let before = applied;
-            applied += self
-                .enqueue_and_apply_sync_items("message".to_string(), None, message_items, true)
-                .await?;
-            if realtime_trace_enabled() {
-                eprintln!(
-                    "[SDK_LOCAL_STORE_APPLY] message_items={} applied={} uid={:?}",
-                    n,
-                    applied - before,
-                    self.current_uid
-                );
-            }
-        }
-        if !read_cursor_items.is_empty() {
-            applied += self
-                .enqueue_and_apply_sync_items(
-                    "channel_read_cursor".to_string(),
-                    None,
-                    read_cursor_items,
-                    true,
-                )
-                .await?;
-        }
-        // Process delivery receipts: persist + emit events
-        for (channel_id, channel_type, server_message_id, delivered_at) in delivery_receipts {
-            let local_message_id = self
-                .storage
-                .mark_message_delivered(server_message_id, delivered_at)
-                .await
-                .unwrap_or(None);
-            if let Some(message_id) = local_message_id {
-                self.pending_events.push(SdkEvent::MessageDelivered {
-                    channel_id,
-                    channel_type,
-                    message_id,
-                    server_message_id,
-                    delivered_at,
-                });
-                if inbound_logs_enabled() {
-                    eprintln!(
-                        "[SDK.delivered] message_delivered: channel_id={} message_id={} server_message_id={} delivered_at={}",
-                        channel_id, message_id, server_message_id, delivered_at
-                    );
-                }
+        };
+        if let Err(error) = self
+            .storage
+            .update_media_downloaded(key.message_id, true)
+            .await
+        {
+            tracing::warn!(message_id = key.message_id, %error, "mark auto-download failed");
+        }
+        let event = SdkEvent::MediaDownloadStateChanged {
+            message_id: key.message_id,
+            state: MediaDownloadState::Done { path },
+        };
+        if let (Some(tx), Some(history), Some(seq)) =
+            (&self.event_tx, &self.event_history, &self.event_seq)
+        {
+            emit_sequenced_event(tx, history, seq, self.event_history_limit, event);
+        } else if let Some(tx) = &self.event_tx {
+            let _ = tx.send(event);
+        }
+    }
+
+    /// 网络变了：已排上的自动下载按新网络重新求值。不再放行的从 DownloadManager
+    /// 摘掉（`.part` 留着），重新放行且不在跑的再提交一次，从断点接着拉。
+    ///
+    /// 用户已经点开、由前台下载接手的不再归这里管：网络策略只约束「提前下好」，
+    /// 不能掐掉用户自己要的下载。
+    async fn reevaluate_auto_downloads(&mut self) {
+        let owner_uid = self.current_uid.clone();
+        let session_epoch = self.session_epoch;
+        let download_manager = &self.download_manager;
+        self.auto_downloads.retain(|key, _| {
+            owner_uid.as_deref() == Some(key.owner_uid.as_str())
+                && key.session_epoch == session_epoch
+                && !download_manager.is_user_initiated(key)
+        });
+        if self.auto_downloads.is_empty() {
+            return;
+        }
+        let policy = self.load_auto_download_policy().await;
+        let pending: Vec<_> = self
+            .auto_downloads
+            .iter()
+            .map(|(key, pending)| (key.clone(), pending.clone()))
+            .collect();
+        for (key, pending) in pending {
+            let allowed = policy.allows(
+                pending.candidate.kind,
+                pending.candidate.file_size,
+                self.network_hint,
+                pending.channel_muted,
+            );
+            if !allowed {
+                self.download_manager.abort_background(&key);
+            } else if !self.download_manager.is_tracked(&key) {
+                self.submit_auto_download(key, &pending);
             }
         }
-        Ok(applied)
     }
 
-    async fn replay_prelogin_inbound_frames(&mut self) -> Result<usize> {
-        if self.current_uid.is_none() || self.pending_prelogin_inbound_frames.is_empty() {
-            return Ok(0);
-        }
-        let pending = std::mem::take(&mut self.pending_prelogin_inbound_frames);
-        let count = pending.len();
-        let mut applied = 0usize;
-        for (biz_type, data) in pending {
-            applied += self.handle_inbound_frame(biz_type, data).await?;
+    /// Commit a receiver worker outcome only if it still belongs to the active
+    /// account session. Keeping this check in the main actor closes the same-UID
+    /// relogin race that a storage-only UID guard cannot distinguish.
+    async fn apply_thumbnail_download_outcome(
+        &mut self,
+        owner_uid: &str,
+        session_epoch: u64,
+        message_id: u64,
+        channel_id: u64,
+        channel_type: i32,
+        outcome: ThumbnailDownloadOutcome,
+    ) -> bool {
+        if self.current_uid.as_deref() != Some(owner_uid) || self.session_epoch != session_epoch {
+            return false;
         }
-        if realtime_trace_enabled() {
-            eprintln!(
-                "[SDK_INBOUND_PRELOGIN_REPLAY] count={} applied={} current_uid={:?}",
-                count, applied, self.current_uid
-            );
+        let thumb_status = match outcome {
+            ThumbnailDownloadOutcome::Ready => 1,
+            ThumbnailDownloadOutcome::ConfirmedAbsent => 3,
+            ThumbnailDownloadOutcome::Failed => 2,
+        };
+        let applied = match self
+            .storage
+            .update_thumb_status_scoped(owner_uid.to_string(), message_id, thumb_status)
+            .await
+        {
+            Ok(applied) => applied,
+            Err(error) => {
+                tracing::warn!(message_id, %error, "commit thumbnail outcome failed");
+                false
+            }
+        };
+        if applied && outcome == ThumbnailDownloadOutcome::Ready {
+            let event = SdkEvent::TimelineUpdated {
+                channel_id,
+                channel_type,
+                message_id,
+                reason: "thumbnail_ready".to_string(),
+            };
+            if let (Some(tx), Some(history), Some(seq)) =
+                (&self.event_tx, &self.event_history, &self.event_seq)
+            {
+                emit_sequenced_event(tx, history, seq, self.event_history_limit, event);
+            } else if let Some(tx) = &self.event_tx {
+                let _ = tx.send(event);
+            }
         }
-        Ok(applied)
+        applied
     }
 
-    const BOOTSTRAP_ENTITY_ORDER: [&str; 6] = [
-        "friend",
-        "group",
-        "channel",
-        "user",
-        "channel_read_cursor",
-        "user_block",
-    ];
-
-    async fn execute_bootstrap_sync(&mut self) -> Result<()> {
-        if self.session_state != SessionState::Authenticated {
-            return Err(Error::InvalidState(
-                "run_bootstrap_sync requires authenticated state".to_string(),
-            ));
-        }
+    /// 处理一批排队的 repair。由 actor tick 调用，每次最多 [`REPAIR_BATCH_LIMIT`] 条。
+    ///
+    /// 修好后**只发一次** TimelineUpdated：投影是原地更新的，message.id 不变、
+    /// 未读不动、cursor 不动 —— repair 不是「收到新消息」。
+    async fn drain_projection_repairs(&mut self) {
+        for _ in 0..REPAIR_BATCH_LIMIT {
+            let Some(key) = self.repair_queue.pop_front() else {
+                return;
+            };
+            let (channel_type, channel_id, server_message_id) = key;
+            let outcome = tokio::time::timeout(
+                Duration::from_millis(REPAIR_TIMEOUT_MS),
+                self.repair_message_projection(channel_id, channel_type, server_message_id),
+            )
+            .await;
+            self.repair_seen.remove(&key);
 
-        if self.bootstrap_completed && !self.full_rebuild_required().await {
-            match self.execute_resume_sync().await {
-                Ok(()) => return Ok(()),
-                Err(err) => {
-                    if !self.full_rebuild_required().await {
-                        return Err(err);
-                    }
-                    eprintln!(
-                        "[SDK.actor] bootstrap sync escalate to full rebuild after resume failure: {}",
-                        err
+            match outcome {
+                Ok(Ok(Some(message_id))) => {
+                    self.repair_backoff.remove(&key);
+                    self.invalidate_channel_cache_with_reason(
+                        channel_id,
+                        channel_type,
+                        "repair_message_projection",
                     );
+                    if let (Some(tx), Some(history), Some(seq)) = (
+                        self.event_tx.as_ref(),
+                        self.event_history.as_ref(),
+                        self.event_seq.as_ref(),
+                    ) {
+                        emit_sequenced_event(
+                            tx,
+                            history,
+                            seq,
+                            self.event_history_limit,
+                            SdkEvent::TimelineUpdated {
+                                channel_id,
+                                channel_type,
+                                message_id,
+                                reason: "message_projection_repaired".to_string(),
+                            },
+                        );
+                    }
+                }
+                // 服务端也没有这条：不是可重试失败，别再排它。
+                Ok(Ok(None)) => {
+                    self.repair_backoff.remove(&key);
                 }
+                Ok(Err(e)) => self.note_repair_failure(key, &e.to_string()),
+                Err(_) => self.note_repair_failure(key, "repair timed out"),
             }
         }
+    }
 
-        let core_entities = ["friend", "group", "channel", "user", "channel_read_cursor"];
-        let optional_entities = ["user_block", "channel_extra", "channel_unread"];
-        if actor_logs_enabled() {
-            eprintln!(
-                "[SDK.actor] bootstrap sync plan core={:?} optional={:?}",
-                core_entities, optional_entities
-            );
-        }
-        for entity_type in Self::BOOTSTRAP_ENTITY_ORDER {
-            match self.sync_entities(entity_type.to_string(), None).await {
-                Ok(count) => {
-                    if actor_logs_enabled() {
-                        eprintln!(
-                            "[SDK.actor] bootstrap sync entity={} count={}",
-                            entity_type, count
-                        );
-                    }
-                    // 每族报一次完成，宿主据此画首屏进度条。首次登录要等十几秒，中间
-                    // 没有信号的话用户分不出「在同步」和「卡死」。
-                    //
-                    // 必须无条件发，不能只在 count > 0 时发：某族本来就没有增量时
-                    // count = 0，但它确实已经同步过了，跳过会让进度永远差这一格。
-                    self.queue_sync_summary_event(entity_type.to_string(), None, count);
-                }
-                Err(e) if Self::is_unsupported_entity_error(&e) => {
-                    self.log_unsupported_sync_skip("bootstrap sync", entity_type, None, &e);
-                    // 服务端不支持的族也算走过了，否则老服务端上进度永远到不了 100%。
-                    self.queue_sync_summary_event(entity_type.to_string(), None, 0);
-                }
-                Err(e) => return Err(e),
-            }
-        }
-
-        // Full rosters are not a readiness dependency. The authoritative `user`
-        // projection already covers referenced identities; roster membership is
-        // hydrated on demand by member/settings surfaces. Iterating every group
-        // here makes first login O(group_count) and traps conversation reads.
-        self.bootstrap_completed = true;
-        if let Some(uid) = &self.current_uid {
-            self.storage
-                .set_bootstrap_completed(uid.clone(), true)
-                .await?;
-        }
-        self.clear_resume_repair_key(Self::resume_repair_full_rebuild_key())
-            .await;
-
-        // Notify server that bootstrap is finished and this session is ready
-        // to receive catch-up and realtime pushes. This endpoint is idempotent.
-        self.send_session_ready().await?;
-
-        self.hydrate_system_channel_messages_from_history().await?;
-
-        Ok(())
-    }
-
-    /// 有没有「已请求但还没被 actor 处理」的账号切换。
-    fn switch_is_pending(&self) -> bool {
-        self.switch_requested.load(Ordering::SeqCst) > self.switch_processed.load(Ordering::SeqCst)
+    fn note_repair_failure(&mut self, key: (i32, u64, u64), reason: &str) {
+        let attempts = self.repair_backoff.get(&key).map(|(n, _)| *n).unwrap_or(0) + 1;
+        let delay =
+            REPAIR_BACKOFF_BASE_MS.saturating_mul(1u64 << attempts.min(REPAIR_BACKOFF_MAX_SHIFT));
+        self.repair_backoff.insert(
+            key,
+            (
+                attempts,
+                std::time::Instant::now() + Duration::from_millis(delay),
+            ),
+        );
+        tracing::warn!(
+            server_message_id = key.2,
+            channel_id = key.1,
+            attempts,
+            delay_ms = delay,
+            reason,
+            "投影 repair 失败,退避后由下一次读取重新发现"
+        );
     }
 
-    /// 等到有账号切换排队为止。
+    /// 按 `server_message_id` 定向修复一条投影损坏的消息。
     ///
-    /// 先注册唤醒、再读计数器：顺序反了就会漏掉「读完之后、注册之前」那一瞬间到达的
-    /// 请求——正是裸 `notify_waiters()` 丢信号的那个窗口。注册在前，那一声必然落在
-    /// 已注册的等待者身上；而即使它仍然落空，循环回来重读计数器也能看到事实。
-    async fn wait_for_switch_request(
-        requested: Arc<AtomicU64>,
-        processed: Arc<AtomicU64>,
-        wakeup: Arc<tokio::sync::Notify>,
-    ) {
-        loop {
-            let notified = wakeup.notified();
-            tokio::pin!(notified);
-            notified.as_mut().enable();
-            if requested.load(Ordering::SeqCst) > processed.load(Ordering::SeqCst) {
-                return;
-            }
-            notified.await;
+    /// 「投影损坏」指本地这一行是某条旧代码或半截写入留下的:metadata 丢了(图片
+    /// 永远加载不出来)、时间戳单位错了、pts 缺失。这类行不该靠删掉整段会话历史
+    /// 再全量重拉来救——那会连带丢掉本地已下载的媒体、已读位置和 gap 水位,代价
+    /// 远大于问题本身,而且用户会看到会话「闪空」。
+    ///
+    /// 修复路径就是一条:按 server_message_id 走 around 拿回权威消息 → 重新跑
+    /// canonical projection → 原地 upsert(`server_message_id` 唯一,天然幂等)。
+    ///
+    /// 返回本地 message id;消息在服务端也不存在时返回 None。
+    pub async fn repair_message_projection(
+        &mut self,
+        channel_id: u64,
+        channel_type: i32,
+        server_message_id: u64,
+    ) -> Result<Option<u64>> {
+        let normalized_channel_type = if channel_type == 0 { 1 } else { channel_type };
+        let resp = self
+            .fetch_and_store_messages_around(
+                channel_id,
+                normalized_channel_type,
+                server_message_id,
+                Some(0),
+                Some(0),
+            )
+            .await?;
+        if resp.anchor_message.message_id != server_message_id {
+            tracing::warn!(
+                server_message_id,
+                channel_id,
+                "repair: 服务端没有返回这条 anchor,跳过"
+            );
+            return Ok(None);
         }
+        // fetch_and_store_messages_around 内部已按 canonical projection 落库,
+        // 这里只把本地 id 查回来给调用方。
+        Ok(self
+            .storage
+            .get_message_id_by_server_message_id(
+                channel_id,
+                normalized_channel_type,
+                server_message_id,
+            )
+            .await?)
     }
 
-    /// 跑一轮同步（如果闸门允许）。
-    ///
-    /// 返回值是**这一轮到底跑没跑**。以前它返回 `Result<()>`，被闸门挡掉时也是 Ok——
-    /// 调用方无从分辨「同步完成了」和「什么都没发生」，于是 `run_bootstrap_sync` 会
-    /// 在 bootstrap 根本没跑的情况下报成功，接着每一个 local-first 操作都失败。
-    ///
-    /// `explicit` = 宿主主动要求（不是自动触发）。它能解除退避窗口：退避压的是自动
-    /// 重试的空转，不该把用户点下的那一次也一起吞掉。
-    async fn ensure_synced_inner<F>(&mut self, explicit: bool, mut emit: F) -> Result<bool>
-    where
-        F: FnMut(SdkEvent),
-    {
-        if self.session_state != SessionState::Authenticated {
-            return Err(Error::InvalidState(
-                "ensure_synced requires authenticated state".to_string(),
-            ));
+    async fn hydrate_channel_messages_from_history(
+        &mut self,
+        channel_id: u64,
+        channel_type: i32,
+        limit: u32,
+    ) -> Result<usize> {
+        let req = MessageHistoryGetRequest {
+            user_id: 0,
+            channel_id,
+            before_server_message_id: None,
+            limit: Some(limit),
+        };
+        let resp: MessageHistoryResponse = self
+            .rpc_call_typed(routes::message_history::GET, &req)
+            .await?;
+        if resp.messages.is_empty() {
+            return Ok(0);
         }
 
-        // 已经有切换在排队就根本别开这一轮：开了也是马上让出，白白把闸门开关一遍。
-        if self.switch_is_pending() {
-            if actor_logs_enabled() {
-                eprintln!("[SDK.actor] ensure_synced skipped: an account switch is pending");
-            }
-            return Ok(false);
+        let normalized_channel_type = if channel_type == 0 { 1 } else { channel_type };
+        let message_ids = self
+            .store_history_items(resp.messages.iter(), normalized_channel_type)
+            .await?;
+        let mut applied = 0usize;
+        for (item, message_id) in resp.messages.into_iter().zip(message_ids) {
+            let timestamp_ms = i64::try_from(item.timestamp).unwrap_or(i64::MAX);
+            let _ = self
+                .update_channel_last_message(
+                    item.channel_id,
+                    normalized_channel_type,
+                    &item.content,
+                    timestamp_ms,
+                    message_id,
+                    Some(item.sender_id),
+                    false,
+                )
+                .await;
+            applied += 1;
         }
+        Ok(applied)
+    }
 
-        let kind = if self.bootstrap_completed && !self.full_rebuild_required().await {
-            SyncRunKind::Resume
-        } else {
-            SyncRunKind::Bootstrap
+    async fn defer_canonical_mutation_if_target_missing(
+        &self,
+        commit: &ServerCommit,
+    ) -> Result<bool> {
+        let resolution = commit.resolve_canonical_event();
+        let Some(event) = resolution.event else {
+            return Ok(false);
         };
-        let now_ms = chrono::Utc::now().timestamp_millis();
-        if explicit {
-            // 宿主主动要的这一次不该被上一次失败排下的退避窗口吞掉。
-            self.sync_coordinator.note_explicit_request();
-        }
-        // 退避/合并/终态都在协调器里判定。sync 有多个触发源（重连成功、connect
-        // 成功、token 刷新后重认证、显式命令），它们只有共同经过这一道闸门才收
-        // 敛得住——在任何单个调用点 sleep 都会被下一个触发源绕开。
-        if let Err(rejection) = self.sync_coordinator.begin(kind, now_ms) {
-            if actor_logs_enabled() {
-                eprintln!("[SDK.actor] ensure_synced skipped: {rejection:?}");
-            }
+        let target_server_message_id = match &event {
+            CanonicalTimelineEvent::Revoke(event) => event.target_server_message_id,
+            CanonicalTimelineEvent::ReactionChange(event) => event.target_server_message_id,
+            CanonicalTimelineEvent::NewMessage(_) => return Ok(false),
+        };
+        if self
+            .storage
+            .get_message_id_by_server_message_id(
+                commit.channel_id,
+                i32::from(commit.channel_type),
+                target_server_message_id,
+            )
+            .await?
+            .is_some()
+        {
             return Ok(false);
         }
-        // 这一轮属于哪个账号/会话世代。切账号会 bump 它。
-        let generation = self.sync_coordinator.generation();
-        emit(SdkEvent::SyncStateChanged {
-            state: self.sync_coordinator.snapshot(),
-        });
-
-        // 同步跑起来，同时盯着「有账号切换在排队」这个铃。actor 在这里内联 await，
-        // 期间不处理命令——不盯着的话，一次卡住的同步会把切换一起堵死，用户点了
-        // 切换要等十几秒才有反应。铃响就让出：这一轮的结果已经不属于任何人了。
-        let result = tokio::select! {
-            biased;
-            _ = Self::wait_for_switch_request(
-                self.switch_requested.clone(),
-                self.switch_processed.clone(),
-                self.switch_wakeup.clone(),
-            ) => None,
-            r = async {
-                #[cfg(test)]
-                if let Some(stall) = self.sync_stall_for_test {
-                    tokio::time::sleep(stall).await;
-                }
-                match kind {
-                    SyncRunKind::Bootstrap => self.execute_bootstrap_sync().await,
-                    SyncRunKind::Resume => self.execute_resume_sync().await,
-                }
-            } => Some(r),
-        };
-
-        let Some(result) = result else {
-            // 主动放弃，不是失败：不能记 attempt/退避（那会让新账号背上上一个账号的
-            // 债），但必须把闸门撤下来，否则 begin() 会永远挡住后面每一个触发源。
-            self.sync_coordinator
-                .abandon(chrono::Utc::now().timestamp_millis());
-            // 后台收敛也必须停：那一轮属于正被切走的账号，继续跑会把上一个账号的
-            // 频道差异写进新账号的会话里。
-            self.convergence_run = None;
-            if actor_logs_enabled() {
-                eprintln!("[SDK.actor] ensure_synced yielded to a pending account switch");
-            }
+        let Some(event_id) = commit.event_id else {
+            tracing::warn!(
+                channel_id = commit.channel_id,
+                target_server_message_id,
+                "cannot persist out-of-order legacy mutation without event_id"
+            );
             return Ok(false);
         };
-
-        // 跑的过程中账号被切走了：这一轮的结果属于**上一个** owner，既不能写进
-        // 协调器（会污染新账号的 attempt/退避/终态），也不能发事件（UI 会拿旧账号
-        // 的同步结果去更新新账号）。静默丢弃，新账号自己的那一轮会照常跑。
-        //
-        // 正常情况下上面的 select 已经先一步让出（切换 API 投命令前会按铃），所以这里
-        // 兜的是漏网的那一格：铃响时这一轮恰好还没开始 await（notify_waiters 只唤醒
-        // **已经在等**的人，早响的一声会丢），同步照跑完，回来时世代已经变了。
-        // 那时这份结果属于上一个 owner，写进协调器会污染新账号的 attempt/退避，
-        // 发事件会让 UI 拿旧账号的同步结果去更新新账号。丢弃即可，新账号自己会跑。
-        if self.sync_coordinator.generation() != generation {
-            if actor_logs_enabled() {
-                eprintln!(
-                    "[SDK.actor] ensure_synced result dropped: generation {generation} -> {} (account switched mid-flight)",
-                    self.sync_coordinator.generation()
-                );
-            }
-            return Ok(false);
-        }
-
-        let now_ms = chrono::Utc::now().timestamp_millis();
-        match &result {
-            Ok(()) => self.sync_coordinator.complete(kind, now_ms),
-            Err(error) => self.sync_coordinator.fail(
-                kind,
-                error.is_auth_terminal(),
-                Some(error.protocol_code()),
-                error.to_string(),
-                now_ms,
-            ),
-        }
-        emit(SdkEvent::SyncStateChanged {
-            state: self.sync_coordinator.snapshot(),
-        });
-        result.map(|()| true)
-    }
-
-    /// 自动触发的同步（重连成功 / connect / token 刷新后重认证）。
-    async fn ensure_synced<F>(&mut self, emit: F) -> Result<()>
-    where
-        F: FnMut(SdkEvent),
-    {
-        self.ensure_synced_inner(false, emit).await.map(|_| ())
-    }
-
-    /// 宿主显式要求的同步。返回「是否真的跑了」——调用方要靠它区分
-    /// 「同步完成」和「被闸门挡住、什么都没发生」。
-    async fn ensure_synced_explicit<F>(&mut self, emit: F) -> Result<bool>
-    where
-        F: FnMut(SdkEvent),
-    {
-        self.ensure_synced_inner(true, emit).await
+        self.storage
+            .put_pending_timeline_mutation(PendingTimelineMutation {
+                channel_id: commit.channel_id,
+                channel_type: i32::from(commit.channel_type),
+                target_server_message_id,
+                event_id,
+                pts: commit.pts,
+                canonical_event: event.encode_fb().map_err(|e| {
+                    Error::Serialization(format!("encode pending canonical mutation: {e}"))
+                })?,
+            })
+            .await?;
+        Ok(true)
     }
 
-    async fn hydrate_system_channel_messages_from_history(&mut self) -> Result<()> {
-        let channels = self.storage.list_channels(200, 0).await?;
-        for channel in channels {
-            if channel.channel_type != 1 && channel.channel_type != 0 {
-                continue;
-            }
-            let normalized_channel_type = if channel.channel_type == 0 {
-                1
-            } else {
-                channel.channel_type
+    async fn replay_pending_timeline_mutations(
+        &mut self,
+        channel_id: u64,
+        channel_type: i32,
+        target_server_message_id: u64,
+        bump_unread_on_incoming: bool,
+    ) -> Result<usize> {
+        let pending = self
+            .storage
+            .list_pending_timeline_mutations(channel_id, channel_type, target_server_message_id)
+            .await?;
+        let mut applied = 0usize;
+        for mutation in pending {
+            let event = CanonicalTimelineEvent::decode_fb(&mutation.canonical_event)
+                .map_err(|e| Error::Serialization(format!("decode pending mutation: {e}")))?;
+            let sender_id = match &event {
+                CanonicalTimelineEvent::Revoke(event) => event.revoked_by,
+                CanonicalTimelineEvent::ReactionChange(event) => event.actor_id,
+                CanonicalTimelineEvent::NewMessage(_) => 0,
             };
-            let is_system_channel = channel.channel_name == "1"
-                || channel.channel_remark == "1"
-                || channel.channel_name == "__system_1__";
-            if !is_system_channel {
-                continue;
-            }
-            eprintln!(
-                "[SDK.actor] bootstrap hydrate system channel={} channel_type={} last_msg='{}'",
-                channel.channel_id, normalized_channel_type, channel.last_msg_content
-            );
-            let existing = self
-                .storage
-                .list_messages(channel.channel_id, normalized_channel_type, 1, 0)
-                .await?;
-            if !existing.is_empty() {
-                eprintln!(
-                    "[SDK.actor] bootstrap hydrate skip channel={} existing_local_messages={}",
-                    channel.channel_id,
-                    existing.len()
-                );
-                continue;
-            }
-
-            let req = MessageHistoryGetRequest {
-                user_id: 0,
-                channel_id: channel.channel_id,
-                before_server_message_id: None,
-                limit: Some(20),
+            let commit = ServerCommit {
+                event_id: Some(mutation.event_id),
+                pts: mutation.pts,
+                server_msg_id: mutation.event_id,
+                local_message_id: None,
+                channel_id,
+                channel_type: u8::try_from(channel_type).unwrap_or(1),
+                message_type: String::new(),
+                content: serde_json::Value::Null,
+                server_timestamp: 0,
+                sender_id,
+                sender_info: None,
+                event_schema_version: Some(privchat_protocol::CANONICAL_TIMELINE_EVENT_SCHEMA_V1),
+                canonical_event: Some(mutation.canonical_event.clone()),
             };
-            let resp: MessageHistoryResponse = self
-                .rpc_call_typed(routes::message_history::GET, &req)
+            let (entity_type, item) = Self::sync_item_from_difference_commit(&commit);
+            let scope = Some(format!("{channel_type}:{channel_id}"));
+            let count = self
+                .enqueue_and_apply_sync_items(
+                    entity_type.clone(),
+                    scope.clone(),
+                    vec![item],
+                    bump_unread_on_incoming,
+                )
                 .await?;
-            eprintln!(
-                "[SDK.actor] bootstrap hydrate history channel={} messages={}",
-                channel.channel_id,
-                resp.messages.len()
-            );
-            if resp.messages.is_empty() {
-                continue;
-            }
-
-            for item in resp.messages {
-                let timestamp_ms = i64::try_from(item.timestamp).unwrap_or(i64::MAX);
-                let message_id = self
-                    .storage
-                    .upsert_remote_message_with_result(Self::history_item_to_upsert_input(
-                        &item,
-                        normalized_channel_type,
-                    ))
-                    .await?
-                    .message_id;
-                // Bootstrap history hydration should not bump unread —
-                // Bootstrap history hydration should not bump unread.
-                // Unread is derived locally from message timeline + read cursor projection.
-                let _ = self
-                    .update_channel_last_message(
-                        item.channel_id,
-                        normalized_channel_type,
-                        &item.content,
-                        timestamp_ms,
-                        message_id,
-                        Some(item.sender_id),
-                        false,
-                    )
-                    .await;
-            }
+            self.storage
+                .delete_pending_timeline_mutation(mutation)
+                .await?;
+            self.queue_last_sync_events(entity_type, scope, count);
+            applied += count;
         }
-        Ok(())
+        Ok(applied)
     }
 
-    async fn sync_channel(&mut self, channel_id: u64, channel_type: i32) -> Result<usize> {
-        // Keep channel-targeted sync aligned with the existing minimum set:
-        // channel state + timeline + read cursor + group member hydration.
-        // `user` remains a collection-scoped entity family and should not be fetched with a
-        // channel scope, otherwise the SDK just emits unsupported/no-op requests.
-        // Timeline recovery must keep using `get_difference`; `message` is not a sync_entities
-        // family in the approved existing-data sync model.
+    async fn resume_channel_difference(
+        &mut self,
+        channel_id: u64,
+        channel_type: i32,
+    ) -> Result<usize> {
         let scope = Some(format!("{channel_type}:{channel_id}"));
-        let mut total = 0usize;
-        total += self
-            .sync_entities("channel".to_string(), scope.clone())
+        let mut last_pts = self
+            .storage
+            .max_message_pts(channel_id, channel_type)
             .await?;
-        match self
-            .sync_entities("channel_read_cursor".to_string(), scope.clone())
-            .await
-        {
-            Ok(v) => total += v,
-            Err(e) if Self::is_unsupported_entity_error(&e) => {
-                self.log_unsupported_sync_skip("sync_channel", "channel_read_cursor", scope, &e);
-            }
-            Err(e) => return Err(e),
+        if let Some(cursor_pts) = self.load_resume_channel_pts(channel_id, channel_type).await {
+            last_pts = last_pts.max(cursor_pts);
         }
-        total += self
-            .resume_channel_difference(channel_id, channel_type)
-            .await?;
-        if channel_type == 2 {
-            match self
-                .sync_entities("group_member".to_string(), Some(channel_id.to_string()))
-                .await
-            {
-                Ok(v) => total += v,
-                Err(e) if Self::is_unsupported_entity_error(&e) => {
-                    self.log_unsupported_sync_skip(
-                        "sync_channel",
-                        "group_member",
-                        Some(channel_id.to_string()),
-                        &e,
-                    );
-                }
-                Err(e) => return Err(e),
-            }
-        }
-        self.clear_resume_repair_key(Self::resume_repair_channel_key(channel_id, channel_type))
-            .await;
-        Ok(total)
-    }
-
-    async fn sync_all_channels(&mut self) -> Result<usize> {
-        let mut total = 0usize;
-        total += self.sync_entities("friend".to_string(), None).await?;
-        total += self.sync_entities("group".to_string(), None).await?;
-        total += self.sync_entities("channel".to_string(), None).await?;
-        total += self.sync_entities("user".to_string(), None).await?;
-        match self
-            .sync_entities("channel_read_cursor".to_string(), None)
-            .await
-        {
-            Ok(v) => total += v,
-            Err(e) if Self::is_unsupported_entity_error(&e) => {
-                self.log_unsupported_sync_skip(
-                    "sync_all_channels",
-                    "channel_read_cursor",
-                    None,
-                    &e,
-                );
-            }
-            Err(e) => return Err(e),
-        }
-        let mut group_offset = 0usize;
-        let group_page_size = 500usize;
+        let mut total_applied = 0usize;
+        let mut pages = 0usize;
         loop {
-            let groups = self
-                .storage
-                .list_groups(group_page_size, group_offset)
-                .await?;
-            if groups.is_empty() {
+            pages += 1;
+            if pages > 64 {
+                return Err(Error::InvalidState(format!(
+                    "resume_channel_difference exceeded max paging iterations: channel_id={} channel_type={}",
+                    channel_id, channel_type
+                )));
+            }
+            let response = match self
+                .get_difference(channel_id, channel_type, last_pts, Some(200))
+                .await
+            {
+                Ok(resp) => resp,
+                Err(err) => {
+                    if Self::classify_resume_error(&err)
+                        == ResumeFailureClass::ChannelResyncRequired
+                    {
+                        let current_pts = self.get_channel_pts(channel_id, channel_type).await?;
+                        self.save_resume_channel_pts(channel_id, channel_type, current_pts)
+                            .await?;
+                        let recovered = self
+                            .hydrate_channel_messages_from_history(channel_id, channel_type, 100)
+                            .await
+                            .unwrap_or(0);
+                        return Ok(total_applied + recovered);
+                    }
+                    return Err(err);
+                }
+            };
+            if response.commits.is_empty() {
                 break;
             }
-            for group in groups.iter() {
-                match self
-                    .sync_entities("group_member".to_string(), Some(group.group_id.to_string()))
-                    .await
+            for commit in response.commits.iter() {
+                if self
+                    .defer_canonical_mutation_if_target_missing(commit)
+                    .await?
                 {
-                    Ok(v) => total += v,
-                    Err(e) if Self::is_unsupported_entity_error(&e) => {
-                        self.log_unsupported_sync_skip(
-                            "sync_all_channels",
-                            "group_member",
-                            Some(group.group_id.to_string()),
-                            &e,
-                        );
-                    }
-                    Err(e) => return Err(e),
+                    continue;
+                }
+                let (entity_type, item) = Self::sync_item_from_difference_commit(commit);
+                let materializes_message = entity_type == "message" && !item.deleted;
+                let applied = self
+                    .enqueue_and_apply_sync_items(
+                        entity_type.clone(),
+                        scope.clone(),
+                        vec![item],
+                        false,
+                    )
+                    .await?;
+                total_applied += applied;
+                self.queue_last_sync_events(entity_type, scope.clone(), applied);
+                if materializes_message {
+                    total_applied += self
+                        .replay_pending_timeline_mutations(
+                            commit.channel_id,
+                            i32::from(commit.channel_type),
+                            commit.server_msg_id,
+                            false,
+                        )
+                        .await?;
                 }
             }
-            if groups.len() < group_page_size {
+            let next_last_pts = response
+                .commits
+                .iter()
+                .map(|commit| commit.pts)
+                .max()
+                .unwrap_or(last_pts);
+            if next_last_pts <= last_pts {
+                break;
+            }
+            last_pts = next_last_pts;
+            self.save_resume_channel_pts(channel_id, channel_type, last_pts)
+                .await?;
+            if !response.has_more {
                 break;
             }
-            group_offset += group_page_size;
-        }
-        Ok(total)
-    }
-
-    async fn batch_get_presence(&mut self, user_ids: Vec<u64>) -> Result<Vec<PresenceStatus>> {
-        if user_ids.is_empty() {
-            return Ok(Vec::new());
         }
-        let timeout = self.timeout();
-        let req = PresenceBatchStatusRequest { user_ids };
-        let request = RpcRequest {
-            route: routes::presence::STATUS_GET.to_string(),
-            body: serde_json::to_vec(&req).map_err(|e| {
-                Error::Serialization(format!("encode batch_get_presence body: {e}"))
-            })?,
-        };
-        let payload = encode_message(&request)
-            .map_err(|e| Error::Serialization(format!("encode batch_get_presence rpc: {e}")))?;
-        let raw = self
-            .request_bytes(
-                Bytes::from(payload),
-                MessageType::RpcRequest as u8,
-                timeout,
-                "rpc batch_get_presence",
-            )
+        self.reconcile_channel_unread_after_difference(channel_id, channel_type)
             .await?;
-        let rpc_resp: RpcResponse = decode_message(&raw)
-            .map_err(|e| Error::Serialization(format!("decode batch_get_presence rpc: {e}")))?;
-        if rpc_resp.code != 0 {
-            return Err(Error::Auth(rpc_resp.message));
-        }
-        let body = rpc_resp
-            .data
-            .ok_or_else(|| Error::Serialization("empty batch_get_presence data".into()))?;
-        let response: PresenceBatchStatusResponse = serde_json::from_slice(&body).map_err(|e| {
-            Error::Serialization(format!("decode batch_get_presence response: {e}"))
-        })?;
-        Ok(self.cache_presence_response(response))
+        Ok(total_applied)
     }
 
-    async fn send_typing(
+    async fn reconcile_channel_unread_after_difference(
         &mut self,
         channel_id: u64,
         channel_type: i32,
-        is_typing: bool,
-        action_type: TypingActionType,
     ) -> Result<()> {
-        let channel_type = u8::try_from(channel_type)
-            .map_err(|_| Error::InvalidState(format!("invalid channel_type: {channel_type}")))?;
-        let timeout = self.timeout();
-        let req = TypingIndicatorRequest {
-            channel_id,
-            channel_type,
-            is_typing,
-            action_type: action_type.into_proto(),
+        let Some(channel) = self.storage.get_channel_by_id(channel_id).await? else {
+            return Ok(());
         };
-        let request = RpcRequest {
-            route: routes::presence::TYPING.to_string(),
-            body: serde_json::to_vec(&req)
-                .map_err(|e| Error::Serialization(format!("encode send_typing body: {e}")))?,
+        let Some(extra) = self
+            .storage
+            .get_channel_extra(channel_id, channel_type)
+            .await?
+        else {
+            return Ok(());
         };
-        let payload = encode_message(&request)
-            .map_err(|e| Error::Serialization(format!("encode send_typing rpc: {e}")))?;
-        let raw = self
-            .request_bytes(
-                Bytes::from(payload),
-                MessageType::RpcRequest as u8,
-                timeout,
-                "rpc send_typing",
-            )
+        let max_pts = self
+            .storage
+            .max_message_pts(channel_id, channel_type)
             .await?;
-        let rpc_resp: RpcResponse = decode_message(&raw)
-            .map_err(|e| Error::Serialization(format!("decode send_typing rpc: {e}")))?;
-        if rpc_resp.code != 0 {
-            return Err(Error::Auth(rpc_resp.message));
+        if max_pts == 0 || extra.keep_pts < max_pts {
+            return Ok(());
+        }
+        let exact_unread = self
+            .storage
+            .count_materialized_unread(channel_id, channel_type)
+            .await?;
+        if channel.unread_count == exact_unread {
+            return Ok(());
         }
+        self.storage
+            .upsert_channel(UpsertChannelInput {
+                channel_id: channel.channel_id,
+                channel_type: channel.channel_type,
+                channel_name: channel.channel_name,
+                channel_remark: channel.channel_remark,
+                avatar: channel.avatar,
+                unread_count: exact_unread,
+                top: channel.top,
+                mute: channel.mute,
+                last_msg_timestamp: channel.last_msg_timestamp,
+                last_local_message_id: channel.last_local_message_id,
+                last_msg_content: channel.last_msg_content,
+                version: channel.version,
+                peer_user_id: channel.peer_user_id,
+            })
+            .await?;
         Ok(())
     }
 
-    /// 订阅频道事件（typing / presence 等状态事件通过此通道接收）
-    /// token: 可选，Room 类型订阅时传入业务 API 签发的 ticket（JWT）
-    async fn subscribe_channel(
-        &mut self,
-        channel_id: u64,
-        channel_type: u8,
-        token: Option<String>,
-    ) -> Result<()> {
-        let timeout = self.timeout();
-        let req = SubscribeRequest {
-            setting: 0,
-            local_message_id: 0,
-            channel_id,
-            channel_type,
-            action: 1, // SUBSCRIBE
-            param: token.clone().unwrap_or_default(),
-        };
-        let payload = encode_message(&req)
-            .map_err(|e| Error::Serialization(format!("encode subscribe_channel: {e}")))?;
-        let raw = self
-            .request_bytes(
-                Bytes::from(payload),
-                MessageType::SubscribeRequest as u8,
-                timeout,
-                "subscribe_channel",
-            )
-            .await?;
-        let resp: SubscribeResponse = decode_message(&raw)
-            .map_err(|e| Error::Serialization(format!("decode subscribe_channel resp: {e}")))?;
-        if resp.reason_code != 0 {
-            return Err(Error::Auth(format!(
-                "subscribe_channel failed: reason_code={}",
-                resp.reason_code
-            )));
-        }
-        // 记入 desired-subscription 注册表，供重连后 replay（presence/typing 恢复的基础）。
-        self.active_subscriptions
-            .insert((channel_id, channel_type), token);
-        Ok(())
-    }
-
-    /// Channel Transfer client→app RPC. See `02-server/CHANNEL_TRANSFER_SPEC.md`.
-    /// `request_id` is generated locally (snowflake → string) and matched at the
-    /// transport layer; the wire `TransferResponse` is decoded into `TransferReply`.
-    async fn transfer_channel(
-        &mut self,
-        channel_id: u64,
-        route: String,
-        body: Vec<u8>,
-        timeout_ms: u64,
-    ) -> Result<TransferReply> {
-        let request_id = self
-            .snowflake
-            .next_id()
-            .map_err(|e| Error::Storage(format!("generate transfer request_id failed: {e:?}")))?
-            .to_string();
-        let req = TransferRequest {
-            request_id,
-            channel_id,
-            route: route.clone(),
-            body,
-        };
-        let payload = encode_message(&req)
-            .map_err(|e| Error::Serialization(format!("encode transfer_channel: {e}")))?;
-        let timeout = Duration::from_millis(timeout_ms.max(1));
-        let raw = self
-            .request_bytes(
-                Bytes::from(payload),
-                MessageType::TransferRequest as u8,
-                timeout,
-                "transfer_channel",
-            )
-            .await?;
-        let resp: TransferResponse = decode_message(&raw)
-            .map_err(|e| Error::Serialization(format!("decode transfer_channel resp: {e}")))?;
-        Ok(TransferReply {
-            request_id: resp.request_id,
-            channel_id: resp.channel_id,
-            code: resp.code,
-            message: resp.message,
-            data: resp.data.unwrap_or_default(),
-        })
-    }
+    /// Bounded repair for pushes lost while the transport still looked healthy.
+    /// 一页 anti-entropy 的结果。
+    ///
+    /// 只返回「修了几条」是不够的：0 既可能是「这一页没东西修」，也可能是
+    /// 「整个账号都收敛了」，两者语义天差地别。据此判定 Converged 会在扫到
+    /// 第一页干净数据时就过早宣布收敛完成。
+    async fn run_anti_entropy_once(&mut self) -> Result<AntiEntropyPage> {
+        // This runs inline on the SDK actor. Keep each slice small enough that
+        // a channel/message command arriving just after the tick is not trapped
+        // behind a large scan. Throughput comes from repeated ticks, not a burst.
+        const PAGE_SIZE: usize = 25;
+        const WIFI_DIFFERENCE_BUDGET: usize = 1;
+        const CELLULAR_DIFFERENCE_BUDGET: usize = 1;
 
-    /// 取消订阅频道事件
-    async fn unsubscribe_channel(&mut self, channel_id: u64, channel_type: u8) -> Result<()> {
-        let timeout = self.timeout();
-        let req = SubscribeRequest {
-            setting: 0,
-            local_message_id: 0,
-            channel_id,
-            channel_type,
-            action: 2, // UNSUBSCRIBE
-            param: String::new(),
-        };
-        let payload = encode_message(&req)
-            .map_err(|e| Error::Serialization(format!("encode unsubscribe_channel: {e}")))?;
-        let raw = self
-            .request_bytes(
-                Bytes::from(payload),
-                MessageType::SubscribeRequest as u8,
-                timeout,
-                "unsubscribe_channel",
-            )
-            .await?;
-        let resp: SubscribeResponse = decode_message(&raw)
-            .map_err(|e| Error::Serialization(format!("decode unsubscribe_channel resp: {e}")))?;
-        if resp.reason_code != 0 {
-            return Err(Error::Auth(format!(
-                "unsubscribe_channel failed: reason_code={}",
-                resp.reason_code
-            )));
+        if self.session_state != SessionState::Authenticated || !self.bootstrap_completed {
+            return Ok(AntiEntropyPage::idle());
         }
-        // 从 desired-subscription 注册表移除：重连后不再 replay 该频道。
-        self.active_subscriptions
-            .remove(&(channel_id, channel_type));
-        Ok(())
-    }
-
-    /// 重连后重放所有活跃订阅（desired subscriptions）。服务端 subscribe_manager 随旧会话清空，
-    /// 不重放则 presence_changed / typing / room 广播全断。best-effort：单个失败不影响其它。
-    /// 注意直接发 SubscribeRequest，不复用 subscribe_channel（避免借用 active_subscriptions 同时改它）。
-    async fn replay_subscriptions(&mut self) {
-        if self.active_subscriptions.is_empty() {
-            return;
+        let (after_channel_id, after_channel_type) = self.load_anti_entropy_cursor().await;
+        // 从头开始扫的这一轮，是否已经走完一整圈
+        let mut cycle_completed = false;
+        let mut channels = self
+            .storage
+            .list_channel_identifiers_after(after_channel_id, after_channel_type, PAGE_SIZE)
+            .await?;
+        if channels.is_empty() && after_channel_id != 0 {
+            // 游标走到末尾又绕回开头 = 完整扫过一圈
+            cycle_completed = true;
+            self.save_anti_entropy_cursor(0, -1).await?;
+            channels = self
+                .storage
+                .list_channel_identifiers_after(0, -1, PAGE_SIZE)
+                .await?;
         }
-        let subs: Vec<((u64, u8), Option<String>)> = self
-            .active_subscriptions
-            .iter()
-            .map(|(k, v)| (*k, v.clone()))
-            .collect();
-        if realtime_trace_enabled() {
-            eprintln!(
-                "[SDK_RESUBSCRIBE] replaying {} active subscriptions after reconnect",
-                subs.len()
-            );
+        if channels.is_empty() {
+            // 一个频道都没有：也算走完一圈
+            return Ok(AntiEntropyPage {
+                page_scanned: 0,
+                stale_found: 0,
+                channels_repaired: 0,
+                messages_applied: 0,
+                deferred: 0,
+                unknown_channels: 0,
+                cycle_completed: true,
+            });
         }
-        let timeout = self.timeout();
-        for ((channel_id, channel_type), token) in subs {
-            let req = SubscribeRequest {
-                setting: 0,
-                local_message_id: 0,
-                channel_id,
-                channel_type,
-                action: 1, // SUBSCRIBE
-                param: token.unwrap_or_default(),
-            };
-            let Ok(payload) = encode_message(&req) else {
-                continue;
-            };
-            if let Err(e) = self
-                .request_bytes(
-                    Bytes::from(payload),
-                    MessageType::SubscribeRequest as u8,
-                    timeout,
-                    "replay_subscribe",
+
+        let remote = self.batch_get_channel_pts(channels.clone()).await?;
+        let remote_pts: HashMap<(u64, i32), u64> = remote
+            .channel_pts_map
+            .into_iter()
+            .map(|row| {
+                (
+                    (row.channel_id, i32::from(row.channel_type)),
+                    row.current_pts,
                 )
+            })
+            .collect();
+        let budget = if self.network_hint == NetworkHint::Cellular {
+            CELLULAR_DIFFERENCE_BUDGET
+        } else {
+            WIFI_DIFFERENCE_BUDGET
+        };
+        let mut observations = Vec::with_capacity(channels.len());
+        for (channel_id, channel_type) in channels.iter().copied() {
+            let materialized_pts = self
+                .storage
+                .max_message_pts(channel_id, channel_type)
+                .await?;
+            let local_pts = self
+                .load_resume_channel_pts(channel_id, channel_type)
                 .await
-            {
-                eprintln!(
-                    "[SDK_RESUBSCRIBE] replay failed channel_id={} type={} err={}",
-                    channel_id, channel_type, e
-                );
+                .unwrap_or(0)
+                .max(materialized_pts);
+            observations.push(AntiEntropyObservation {
+                key: (channel_id, channel_type),
+                local_pts,
+                server_pts: remote_pts.get(&(channel_id, channel_type)).copied(),
+            });
+        }
+        let plan = plan_anti_entropy_page(&observations, budget);
+        let mut messages_applied = 0usize;
+        for (channel_id, channel_type) in plan.repair.iter().copied() {
+            messages_applied += self
+                .resume_channel_difference(channel_id, channel_type)
+                .await?;
+        }
+        let channels_repaired = plan.repair.len();
+
+        if plan.deferred > 0 {
+            if let Some((channel_id, channel_type)) = plan.last_consumed {
+                self.save_anti_entropy_cursor(channel_id, channel_type)
+                    .await?;
             }
+        } else if channels.len() < PAGE_SIZE {
+            // 已消费末页，当前周期完整结束。
+            cycle_completed = true;
+            self.save_anti_entropy_cursor(0, -1).await?;
+        } else if let Some((channel_id, channel_type)) = channels.last().copied() {
+            self.save_anti_entropy_cursor(channel_id, channel_type)
+                .await?;
         }
+        tracing::debug!(
+            scanned = plan.consumed,
+            difference_calls = channels_repaired,
+            channels_repaired,
+            messages_applied,
+            stale_found = plan.stale_found,
+            unknown_channels = plan.unknown_channels,
+            deferred = plan.deferred,
+            cycle_completed,
+            "anti-entropy channel page completed"
+        );
+        Ok(AntiEntropyPage {
+            page_scanned: plan.consumed,
+            stale_found: plan.stale_found,
+            unknown_channels: plan.unknown_channels,
+            channels_repaired,
+            messages_applied,
+            deferred: plan.deferred,
+            cycle_completed,
+        })
     }
 
-    fn build_send_message_request_with_content(
-        &self,
-        message: &StoredMessage,
-        local_message_id: u64,
-        content: String,
-    ) -> Result<privchat_protocol::protocol::SendMessageRequest> {
-        let message_type = u32::try_from(message.message_type).map_err(|_| {
-            Error::InvalidState(format!("invalid message_type: {}", message.message_type))
-        })?;
-        // Build envelope in legacy (Value-based) shape to keep the existing
-        // flexible content-detection logic intact, then bridge to the typed
-        // wire envelope at the FlatBuffers encode boundary below.
-        let extra_envelope = Self::decode_legacy_message_envelope(&message.extra)
-            .and_then(|value| serde_json::from_value::<LocalMessagePayloadEnvelope>(value).ok());
-        let mut envelope = extra_envelope.unwrap_or_else(|| LocalMessagePayloadEnvelope {
-            content: content.clone(),
-            metadata: serde_json::from_str::<serde_json::Value>(&message.extra)
-                .ok()
-                .and_then(|value| if value.is_null() { None } else { Some(value) }),
-            reply_to_message_id: None,
-            mentioned_user_ids: None,
-            message_source: None,
-        });
-
-        if let Some(content_json) = Self::decode_legacy_message_envelope(&content) {
-            if let Ok(parsed_envelope) =
-                serde_json::from_value::<LocalMessagePayloadEnvelope>(content_json)
-            {
-                envelope = parsed_envelope;
-            }
-        } else if let Ok(content_json) = serde_json::from_str::<serde_json::Value>(&content) {
-            if content_json
-                .get("file_id")
-                .or_else(|| content_json.get("thumbnail_file_id"))
-                .is_some()
-            {
-                // Backward compatibility: attachment callers may still pass raw metadata json.
-                envelope.content = Self::attachment_caption(&message.extra).unwrap_or_default();
-                envelope.metadata = Some(content_json);
-            }
+    async fn execute_resume_sync(&mut self) -> Result<()> {
+        if self.session_state != SessionState::Authenticated {
+            let err =
+                Error::InvalidState("run_resume_sync requires authenticated state".to_string());
+            let _ = self
+                .handle_resume_failure(ResumeFailureTarget::Global, &err)
+                .await;
+            return Err(err);
         }
-
-        // Wire boundary: bridge the legacy Value-based envelope to the typed
-        // FlatBuffers envelope and encode as binary.
-        let content_type =
-            ContentMessageType::from_u32(message_type).unwrap_or(ContentMessageType::Text);
-        let typed_envelope = MessagePayloadEnvelope::from_legacy(&envelope, content_type);
-        let payload = privchat_protocol::encode_message(&typed_envelope)
-            .map_err(|e| Error::Serialization(format!("encode send payload: {e}")))?;
-
-        Ok(privchat_protocol::protocol::SendMessageRequest {
-            setting: privchat_protocol::protocol::MessageSetting {
-                need_receipt: true,
-                signal: 0,
-            },
-            client_seq: 1,
-            local_message_id,
-            stream_no: format!("stream_{local_message_id}"),
-            channel_id: message.channel_id,
-            message_type,
-            expire: 3600,
-            from_uid: message.from_uid,
-            topic: "chat".to_string(),
-            payload,
-        })
-    }
-
-    async fn direct_send_message(
-        &mut self,
-        req: privchat_protocol::protocol::SendMessageRequest,
-    ) -> Result<SendMessageResponse> {
-        if req.local_message_id == 0 {
-            return Err(Error::InvalidState(
-                "local_message_id must be non-zero".to_string(),
-            ));
+        if !self.bootstrap_completed {
+            return Ok(());
         }
-        let request_data = encode_message(&req)
-            .map_err(|e| Error::Serialization(format!("encode send request: {e}")))?;
-        let timeout = self.timeout();
-        let transport = match self.transport.as_mut() {
-            Some(t) => t,
-            None => {
-                let transition = self.apply_transport_health(false);
-                self.push_connection_transition_event(transition);
-                return Err(self.network_disconnected_error());
+        // P0-12 单轮化：同一连接世代内一轮成功后，30s 内的重复触发直接跳过。
+        // 冷启动（connect + bootstrap 双入口）和重连（retry driver / connect /
+        // cmd_authenticate 三入口）都会连续触发多轮全家桶；连接期间的增量由
+        // push 实时覆盖，重复轮次只放大服务端压力。debounce 而非严格单次：
+        // 前台回归等超过窗口的触发仍作为安全网执行。
+        if let Some((epoch, at)) = self.last_resume_synced {
+            if epoch == self.inbound_epoch && at.elapsed() < Duration::from_secs(30) {
+                eprintln!(
+                    "[SDK.actor] resume sync skipped: epoch {} already synced {}ms ago",
+                    epoch,
+                    at.elapsed().as_millis()
+                );
+                return Ok(());
             }
-        };
-        let opt = RequestOptions::new()
-            .biz_type(MessageType::SendMessageRequest as u8)
-            .timeout(timeout);
-        let raw = transport
-            .request_with_options(Bytes::from(request_data), opt)
-            .await
-            .map_err(|e| self.handle_transport_request_error("direct send message", e))?;
-        let resp: SendMessageResponse = decode_message(&raw)
-            .map_err(|e| Error::Serialization(format!("decode send response: {e}")))?;
-        if resp.reason_code != 0 {
-            return Err(Error::Server {
-                code: resp.reason_code,
-                message: format!("send message failed: reason_code={}", resp.reason_code),
-            });
         }
-        if resp.server_message_id == 0 {
-            return Err(Error::Serialization(
-                "send message response missing server_message_id".to_string(),
-            ));
+        let mut stats = ResumeRunStats::default();
+        self.resume_run_id = self.resume_run_id.wrapping_add(1);
+        let run_id = self.resume_run_id;
+        println!("[SDK.resume] run={run_id} phase=2 begin");
+        self.queue_resume_started();
+        if let Err(err) = self.send_session_ready().await {
+            let _ = self
+                .handle_resume_failure(ResumeFailureTarget::Global, &err)
+                .await;
+            return Err(err);
         }
-        Ok(resp)
-    }
 
-    /// Extract mime_type from content or extra JSON.
-    /// Tries content first, then extra. Looks for "mime_type" key in metadata or flat format.
-    fn extract_mime_type_from_json(content: &str, extra: &str) -> Option<String> {
-        for src in [content, extra] {
-            if src.is_empty() {
-                continue;
-            }
-            if let Ok(json) = serde_json::from_str::<serde_json::Value>(src) {
-                // Envelope: {"metadata":{"mime_type":"image/jpeg"}}
-                if let Some(mime) = json
-                    .get("metadata")
-                    .and_then(|m| m.get("mime_type"))
-                    .and_then(|v| v.as_str())
-                    .filter(|s| !s.is_empty())
-                {
-                    return Some(mime.to_string());
+        let entity_order = [
+            "friend",
+            "group",
+            "channel",
+            "user",
+            "channel_read_cursor",
+            sticker::SYNC_ENTITY_TYPE,
+        ];
+        for entity_type in entity_order {
+            match self.sync_entities(entity_type.to_string(), None).await {
+                Ok(applied) => {
+                    stats.entity_types_synced += 1;
+                    self.queue_sync_summary_event(entity_type.to_string(), None, applied);
                 }
-                // Flat: {"mime_type":"image/jpeg"}
-                if let Some(mime) = json
-                    .get("mime_type")
-                    .and_then(|v| v.as_str())
-                    .filter(|s| !s.is_empty())
-                {
-                    return Some(mime.to_string());
+                Err(e) if Self::is_unsupported_entity_error(&e) => {
+                    self.log_unsupported_sync_skip("resume sync", entity_type, None, &e);
+                }
+                Err(e) => {
+                    let handling = self
+                        .handle_resume_failure(
+                            ResumeFailureTarget::EntityType(entity_type.to_string()),
+                            &e,
+                        )
+                        .await;
+                    if handling == ResumeFailureHandling::Abort {
+                        return Err(e);
+                    }
                 }
             }
         }
-        None
-    }
 
-    /// Extract thumbnail_url from message content JSON (supports envelope and flat formats).
-    fn extract_thumbnail_url(content: &str) -> Option<String> {
-        let json: serde_json::Value = serde_json::from_str(content).ok()?;
-        // Envelope format: {"content":"...","metadata":{"thumbnail_url":"..."}}
-        if let Some(url) = json
-            .get("metadata")
-            .and_then(|m| m.get("thumbnail_url"))
-            .and_then(|v| v.as_str())
-        {
-            if !url.is_empty() {
-                return Some(url.to_string());
-            }
-        }
-        // Flat format: {"thumbnail_url":"..."}
-        if let Some(url) = json.get("thumbnail_url").and_then(|v| v.as_str()) {
-            if !url.is_empty() {
-                return Some(url.to_string());
-            }
-        }
-        None
+        // ── Phase 2 到此为止（spec SDK_SYNC_RESUME_SPEC §Startup Phases）──
+        //
+        // 上面几个实体族是**自包含列表投影**：会话/好友/群列表带着渲染该行所需的
+        // 名称、头像、类型，请求数固定为实体族个数，与账号大小无关。到这里主界面
+        // 就能正确显示了，立刻宣布 CriticalReady 让用户可用。
+        //
+        // 原先排在这之后的两段被移出关键路径：
+        //   1. 逐群 sync_entities("group_member", group_id) —— 请求数 O(群数)
+        //   2. 逐频道 resume_channel_difference        —— 请求数 O(频道数 × 分页)
+        // 它们让上线时延随账号大小线性增长（实测百来个会话就要挂几分钟），
+        // 现在交给 Phase 3 后台收敛：先 batch_get_channel_pts 批量比对，只修 stale。
+        // Phase 2 完成的信号就是 readiness → Ready：ensure_synced 收尾时
+        // `sync_coordinator.complete()` 会置位并发 SyncStateChanged，宿主据此撤横幅。
+        // 不另造 CriticalReady 事件——uniffi 绑定是手工维护的，多一个变体就要同步
+        // 三个平台的绑定文件，而 readiness 已经把这件事说清楚了。
+        //
+        // 旧 `ResumeSyncCompleted` 保持原义：在 Phase 3 收敛完成时才发（见 repair_tick）。
+        //
+        // 开启 Phase 3。这里只置标志，实际收敛由 actor loop 的 repair_tick 驱动，
+        // 一轮修一小批（有界预算），不阻塞命令处理，也不霸占 actor。
+        // println! 而非 eprintln!：Android 的 logcat 收不到 stderr，
+        // 而这些打点的用途正是生产排查。
+        println!(
+            "[SDK.resume] run={run_id} phase=2 done entity_types={} (critical path complete)",
+            stats.entity_types_synced
+        );
+        // stats 交给 Phase 3：ResumeSyncCompleted 只在真正全量收敛后发一次。
+        // 在这里发过一次是漏删——那会让宿主以为整轮结束，而收敛才刚开始。
+        self.convergence_run = Some(stats);
+        const CONVERGENCE_START_GRACE_MS: i64 = 5_000;
+        self.sync_coordinator.schedule_convergence(
+            chrono::Utc::now().timestamp_millis(),
+            CONVERGENCE_START_GRACE_MS,
+        );
+        Ok(())
     }
 
-    /// 从消息内容 JSON 解析缩略图的协议权威 `thumbnail_file_id`（envelope `metadata` 或 flat）。
-    /// Scheme B：接收端按此 file_id 走 `file/get_url` 拿 signed_url + cek 下载解密。
-    fn extract_thumbnail_file_id(content: &str) -> Option<u64> {
-        let json: serde_json::Value = serde_json::from_str(content).ok()?;
-        let read = |scope: &serde_json::Value| -> Option<u64> {
-            scope.get("thumbnail_file_id").and_then(|v| {
-                v.as_u64()
-                    .or_else(|| v.as_str().and_then(|s| s.parse::<u64>().ok()))
-            })
-        };
-        read(json.get("metadata").unwrap_or(&json))
-            .or_else(|| read(&json))
-            .filter(|id| *id > 0)
+    async fn full_rebuild_required(&self) -> bool {
+        self.storage
+            .kv_get(Self::resume_repair_full_rebuild_key())
+            .await
+            .ok()
+            .flatten()
+            .is_some()
     }
 
-    /// 在 DownloadManager worker 内按 `thumbnail_file_id` 调 `file/get_url`，拿
-    /// signed_url + encryption_version + cek。失败返回 None（缩略图静默不下载，不阻塞）。
-    /// CEK 只来自此处，绝不取自消息 metadata。
-    /// 由 [`media_download::DownloadManager`] 的受控任务调用，不占 actor。
-    async fn resolve_thumbnail_ticket_detached(
-        transport: &TransportClient,
-        thumbnail_file_id: u64,
-        timeout: Duration,
-    ) -> Option<ResolvedFileDownload> {
-        let req = FileGetUrlRequest {
-            file_id: thumbnail_file_id,
-            user_id: 0,
-        };
-        let resp: FileGetUrlResponse =
-            Self::rpc_call_typed_detached(transport, routes::file::GET_URL, &req, timeout)
-                .await
-                .ok()?;
-        if resp.file_url.trim().is_empty() {
-            return None;
-        }
-        Some(ResolvedFileDownload {
-            url: resp.file_url,
-            encryption_version: resp.encryption_version,
-            cek: resp.cek,
-        })
-    }
+    /// [bump_unread_on_incoming]:仅真正的实时推送传 true。resume/difference 回填的
+    /// 历史消息必须传 false——它们已包含在 channel 冷启动同步的服务端未读基线里,
+    /// 再按实时消息 bump 会把未读恰好双算(2026-07-24 事故:系统消息 11→22、DM 1→2)。
+    async fn enqueue_and_apply_sync_items(
+        &mut self,
+        entity_type: String,
+        scope: Option<String>,
+        items: Vec<SyncEntityItem>,
+        bump_unread_on_incoming: bool,
+    ) -> Result<usize> {
+        self.last_sync_entity_events.clear();
+        let stats = self
+            .receive_pipeline
+            .enqueue(entity_type.clone(), scope.clone(), items);
+        self.last_sync_queued = stats.queued_items;
+        self.last_sync_dropped_duplicates = stats.dropped_duplicates;
 
-    /// Download one thumbnail inside DownloadManager's tracked task.
-    ///
-    /// Scheme B：缩略图也是独立 file。`ticket` 是 caller 用 `thumbnail_file_id` 经
-    /// `file/get_url` 解析出的下载票据（v1 加密：url + encryption_version + cek）。
-    /// 没有 `thumbnail_file_id` 时 `ticket=None`，退回消息里的 legacy 明文 `thumbnail_url`（v0）。
-    /// **CEK 只来自 get_url ticket，绝不取自消息 metadata。**
-    #[allow(clippy::too_many_arguments)]
-    async fn run_auto_download_thumbnail(
-        content: &str,
-        ticket: Option<ResolvedFileDownload>,
-        user_root: &Path,
-        message_id: u64,
-        created_at_ms: i64,
-        channel_id: u64,
-        channel_type: i32,
-    ) -> Option<ThumbnailDownloadOutcome> {
-        let (thumb_url, thumb_enc_version, thumb_cek) = match ticket {
-            // v1：get_url 解析的票据，密文 blob，用票据里的 cek 解密。
-            Some(t) if t.url.starts_with("http") => (t.url, t.encryption_version, t.cek),
-            // legacy：消息里只有明文 thumbnail_url（旧 v0 附件）。
-            _ => match Self::extract_thumbnail_url(content) {
-                Some(url) if url.starts_with("http") => (url, 0, None),
-                _ => {
-                    // thumb_status=3 是**终态**：写下去之后永不重试，UI 从此渲染
-                    // 静态占位符。所以它只能表示「已经看清楚了,这条消息确实没有
-                    // 缩略图」,不能表示「我没看到缩略图字段」。
-                    //
-                    // 这两者的区别就是上一次事故:history 回填丢了 metadata,extra
-                    // 是空串,于是这里判定「没有缩略图」并永久标记——整段历史的图片
-                    // 从此是灰块,重开 App 也不会好。
-                    //
-                    // 现在的规则:metadata 解析不出来时停在 0(未知/待重试),留给下
-                    // 一次投影或定向 repair 去补;只有确实解析出了 metadata 而其中
-                    // 没有缩略图字段,才允许进终态。
-                    // 许可条件是「服务端明确说了没有缩略图」,不是「metadata 能解析」。
-                    // 后者会漏掉最要命的一种:metadata 里有 thumbnail_file_id,只是这次
-                    // file/get_url 因网络/token/服务端抖动没拿到票据——那是可重试失败,
-                    // 写成终态就等于一次抖动永久毁掉一张图。
-                    let explicit_absence =
-                        crate::canonical_inbound::CanonicalInboundMessage::from_sync_entity(
-                            0,
-                            0,
-                            channel_id,
-                            channel_type,
-                            0,
-                            0,
-                            String::new(),
-                            content.to_string(),
-                            0,
-                            0,
-                        )
-                        .server_says_no_thumbnail();
-                    if explicit_absence {
-                        return Some(ThumbnailDownloadOutcome::ConfirmedAbsent);
-                    } else {
-                        tracing::warn!(
-                            message_id,
-                            channel_id,
-                            "拿不到缩略图票据,但服务端未明确表示没有缩略图:保持待重试,不写终态"
-                        );
-                    }
-                    return None;
-                }
-            },
-        };
-        let dir = media_store::get_message_dir(user_root, message_id as i64, created_at_ms);
-        let thumb_path = dir.join(Self::thumb_filename_for_url(&thumb_url));
-        let webp_path = dir.join(media_store::THUMB_FILENAME);
-        let png_path = dir.join(media_store::THUMB_PNG_FILENAME);
-        if thumb_path.exists() || webp_path.exists() || png_path.exists() {
-            return Some(ThumbnailDownloadOutcome::Ready);
-        }
-        let mut downloaded = None;
-        for attempt in 0..3u64 {
-            match Self::do_download_thumbnail(
-                &thumb_url,
-                &dir,
-                &thumb_path,
-                thumb_enc_version,
-                thumb_cek.as_deref(),
-            )
-            .await
+        let mut applied = 0usize;
+        let mut entity_events: Vec<SdkEvent> = Vec::new();
+        for batch in SyncCommitApplier::drain_batches(&mut self.receive_pipeline) {
+            let count = batch.items.len();
+            match self
+                .apply_sync_entities(
+                    &batch.entity_type,
+                    batch.scope.as_deref(),
+                    &batch.items,
+                    bump_unread_on_incoming,
+                )
+                .await
             {
-                Ok(()) => {
-                    downloaded = Some(Ok(()));
-                    break;
-                }
-                Err(error) if attempt < 2 => {
-                    tracing::warn!(message_id, attempt, %error, "thumbnail download retrying");
-                    tokio::time::sleep(Duration::from_millis(500 * (1 << attempt))).await;
+                Ok(events) => {
+                    applied += count;
+                    entity_events.extend(events);
                 }
-                Err(error) => {
-                    downloaded = Some(Err(error));
-                    break;
+                Err(err) => {
+                    SyncCommitApplier::requeue_front(&mut self.receive_pipeline, batch);
+                    return Err(err);
                 }
             }
         }
-        match downloaded.expect("thumbnail retry loop must produce an outcome") {
-            Ok(()) => Some(ThumbnailDownloadOutcome::Ready),
-            Err(e) => {
-                eprintln!(
-                    "[SDK.thumb] auto-download failed message_id={} url={}: {}",
-                    message_id, thumb_url, e
-                );
-                Some(ThumbnailDownloadOutcome::Failed)
-            }
-        }
-    }
-
-    /// Pick canonical thumbnail filename based on the remote URL extension,
-    /// so the file name on disk matches the actual bytes (webp vs png).
-    fn thumb_filename_for_url(url: &str) -> &'static str {
-        let path = url.split(['?', '#']).next().unwrap_or(url);
-        let ext = path
-            .rsplit('/')
-            .next()
-            .and_then(|name| name.rsplit('.').next())
-            .map(str::to_ascii_lowercase)
-            .unwrap_or_default();
-        match ext.as_str() {
-            "png" => media_store::THUMB_PNG_FILENAME,
-            _ => media_store::THUMB_FILENAME,
-        }
+        self.invalidate_cache_for_events(&entity_events);
+        self.last_sync_entity_events = entity_events;
+        Ok(applied)
     }
 
-    async fn do_download_thumbnail(
-        url: &str,
-        dir: &Path,
-        thumb_path: &Path,
-        encryption_version: i32,
-        cek: Option<&str>,
-    ) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let resp = reqwest::Client::builder()
-            .timeout(Duration::from_secs(30))
-            .build()?
-            .get(url)
-            .send()
-            .await?;
-        if !resp.status().is_success() {
-            return Err(format!("HTTP {}", resp.status()).into());
+    /// P1-05：room 广播去重。返回 true 表示这条 (channel_id, server_message_id) 最近
+    /// 已见过（应丢弃）。None id 一律放行（无法去重）。每 channel 有界 FIFO（256）。
+    fn room_message_is_duplicate(
+        &mut self,
+        channel_id: u64,
+        server_message_id: Option<u64>,
+    ) -> bool {
+        const ROOM_DEDUP_WINDOW: usize = 256;
+        let Some(id) = server_message_id else {
+            return false;
+        };
+        let seen = self.room_seen_msg_ids.entry(channel_id).or_default();
+        if seen.contains(&id) {
+            return true;
         }
-        let bytes = resp.bytes().await?;
-        // 附件加密 v1：缩略图 blob 同样是 nonce||ct||tag，用 file/get_url 票据里的 cek
-        // 本地解密后再落盘；v0（legacy 明文）原样写入。解密失败直接报错，绝不写密文当图片。
-        let plaintext = crate::attachment_crypto::decrypt_downloaded_attachment_bytes(
-            encryption_version,
-            cek,
-            &bytes,
-        )?;
-        std::fs::create_dir_all(dir)?;
-        let part_path = thumb_path.with_extension(format!(
-            "{}.part",
-            thumb_path
-                .extension()
-                .and_then(|ext| ext.to_str())
-                .unwrap_or("thumb")
-        ));
-        {
-            let mut file = std::fs::File::create(&part_path)?;
-            file.write_all(&plaintext)?;
-            file.sync_all()?;
+        seen.push_back(id);
+        if seen.len() > ROOM_DEDUP_WINDOW {
+            seen.pop_front();
         }
-        std::fs::rename(&part_path, thumb_path)?;
-        eprintln!(
-            "[SDK.thumb] auto-download ok: {} ({} bytes, enc_v={})",
-            thumb_path.display(),
-            plaintext.len(),
-            encryption_version
-        );
-        Ok(())
+        false
     }
 
-    /// 存储层 / 上传层使用的文件分类字符串，严格对齐服务端 `FileType`：
-    /// `"image"` / `"video"` / `"voice"` / `"file"`。
-    ///
-    /// 规则：发送入口（`message_type`）决定分类，MIME 不反推消息类型。
-    /// Voice 消息直接返回 `"voice"`，服务端据此走独立的尺寸限额与 /voices/ 存储目录。
-    fn guess_file_type(message_type: i32, filename: &str, mime: &str) -> &'static str {
-        let image_type = privchat_protocol::message::ContentMessageType::Image as i32;
-        let video_type = privchat_protocol::message::ContentMessageType::Video as i32;
-        let voice_type = privchat_protocol::message::ContentMessageType::Voice as i32;
-        if message_type == image_type {
-            return "image";
-        }
-        if message_type == video_type {
-            return "video";
-        }
-        if message_type == voice_type {
-            return "voice";
-        }
-        if mime.starts_with("image/") {
-            return "image";
-        }
-        if mime.starts_with("video/") {
-            return "video";
+    async fn handle_inbound_frame(&mut self, biz_type: u8, data: Vec<u8>) -> Result<usize> {
+        if inbound_logs_enabled() {
+            eprintln!(
+                "[SDK.inbound] frame biz_type={} len={}",
+                biz_type,
+                data.len()
+            );
         }
-        let ext = filename
-            .rsplit('.')
-            .next()
-            .map(|s| s.to_ascii_lowercase())
-            .unwrap_or_default();
-        match ext.as_str() {
-            "jpg" | "jpeg" | "png" | "gif" | "webp" | "bmp" | "heic" => "image",
-            "mp4" | "mov" | "mkv" | "avi" | "webm" => "video",
-            _ => "file",
+        let message_type = MessageType::from(biz_type);
+        if inbound_logs_enabled() {
+            eprintln!("[SDK.inbound] frame message_type={:?}", message_type);
         }
-    }
-
-    /// 附件的说明文字，写在本地元数据里（protocol 的 `LocalAttachmentMetadata::caption`）。
-    ///
-    /// `None` = 用户没写；wire 层据此发空正文。
-    ///
-    /// 🔴 **一个字都不改**：不 trim、不折叠空白。用户正文归用户，SDK 擅自修剪
-    /// 会跟不修剪的另一端（TS）在同一条消息上产出不同字节。只有空串算「没写」。
-    fn attachment_caption(extra: &str) -> Option<String> {
-        serde_json::from_str::<serde_json::Value>(extra)
-            .ok()?
-            .get("caption")?
-            .as_str()
-            .filter(|s| !s.is_empty())
-            .map(str::to_string)
-    }
-
-    fn default_mime_for_message_type(message_type: i32) -> &'static str {
-        use privchat_protocol::message::ContentMessageType;
-        let image_type = ContentMessageType::Image as i32;
-        let video_type = ContentMessageType::Video as i32;
-        let voice_type = ContentMessageType::Voice as i32;
-        if message_type == image_type {
-            "image/jpeg"
-        } else if message_type == video_type {
-            "video/mp4"
-        } else if message_type == voice_type {
-            "audio/mp4"
-        } else {
-            "application/octet-stream"
-        }
-    }
-
-    /// 从 extra JSON 中按优先级读 u64：先找顶层 `key`，再找 `metadata.key`。
-    fn pick_u64_from_extra(extra: &str, key: &str) -> Option<u64> {
-        let value = serde_json::from_str::<serde_json::Value>(extra).ok()?;
-        value
-            .get(key)
-            .or_else(|| value.get("metadata").and_then(|m| m.get(key)))
-            .and_then(|v| v.as_u64())
-    }
-
-    /// 语音消息：协议要求 `VoiceMetadata.duration` 必填。
-    ///
-    /// 上传完成后 SDK 会用 attachment_content 重写 message.content，如果不把发送侧
-    /// 采样的时长搬进去，接收端 VoiceMetadata 解析就会拿到 duration=0。录制不足 1 秒
-    /// 时兜底为 1，与客户端约定保持一致。
-    fn merge_voice_metadata(extra: &str, attachment_content: &mut serde_json::Value) {
-        let duration_secs = Self::pick_u64_from_extra(extra, "duration")
-            .map(|v| v.min(u32::MAX as u64) as u32)
-            .unwrap_or(1);
-        if let Some(obj) = attachment_content.as_object_mut() {
-            obj.insert(
-                "duration".to_string(),
-                serde_json::Value::from(duration_secs),
-            );
+        Self::log_inbound_decoded(message_type, &data);
+        // Do not drop server push frames during login->authenticate gap.
+        // current_uid is set by login, while session_state may still be LoggedIn.
+        // If we gate strictly by Authenticated, login notice pushes are lost and only
+        // channel preview is updated by sync, leaving message table empty.
+        if self.current_uid.is_none() {
+            match message_type {
+                MessageType::SendMessageRequest
+                | MessageType::PushMessageRequest
+                | MessageType::PushBatchRequest
+                | MessageType::PublishRequest => {
+                    if self.pending_prelogin_inbound_frames.len() >= 256 {
+                        let _ = self.pending_prelogin_inbound_frames.remove(0);
+                    }
+                    self.pending_prelogin_inbound_frames.push((biz_type, data));
+                    if realtime_trace_enabled() {
+                        eprintln!(
+                        "[SDK_INBOUND_PRELOGIN_BUFFER] biz_type={} current_uid=None queue_len={} (push 帧卡在登录前缓冲；若不 replay 即收不到)",
+                        biz_type,
+                        self.pending_prelogin_inbound_frames.len()
+                    );
+                    }
+                }
+                _ => {
+                    if inbound_logs_enabled() {
+                        eprintln!(
+                            "[SDK.inbound] skip frame before login message_type={:?} biz_type={}",
+                            message_type, biz_type
+                        );
+                    }
+                }
+            }
+            return Ok(0);
         }
-    }
-
-    /// 视频消息：协议 `VideoMetadata` 要求 duration / width / height 必填，缩略图三件套可选。
-    ///
-    /// 与语音不同，视频的 UI（气泡比例、首帧渲染）依赖完整的尺寸信息，因此独立成函数处理，
-    /// 避免和语音的单字段逻辑混用。未抽帧的视频允许缺省 thumbnail_*，由客户端播放器自行处理。
-    fn merge_video_metadata(extra: &str, attachment_content: &mut serde_json::Value) {
-        let Some(obj) = attachment_content.as_object_mut() else {
-            return;
-        };
-        let insert_u32 =
-            |obj: &mut serde_json::Map<String, serde_json::Value>, key: &str, v: u64| {
-                obj.insert(
-                    key.to_string(),
-                    serde_json::Value::from(v.min(u32::MAX as u64) as u32),
+        let mut message_items = Vec::new();
+        let mut read_cursor_items = Vec::new();
+        let mut live_location_items = Vec::new();
+        let mut direct_applied = 0usize;
+        // (channel_id, channel_type, server_message_id, delivered_at)
+        let mut delivery_receipts: Vec<(u64, i32, u64, u64)> = Vec::new();
+        match message_type {
+            MessageType::SendMessageRequest => {
+                let req: SendMessageRequest = decode_message(&data).map_err(|e| {
+                    Error::Serialization(format!("decode send message request: {e}"))
+                })?;
+                let current_user_id = self
+                    .current_uid
+                    .as_ref()
+                    .and_then(|v| v.parse::<u64>().ok());
+                // Accept self-echo SendMessageRequest as a reconciliation signal.
+                // In some transport paths the outbound request can be committed on server
+                // while response correlation is dropped; processing self-echo lets local
+                // status converge to sent instead of remaining failed.
+                if inbound_logs_enabled() && current_user_id == Some(req.from_uid) {
+                    eprintln!(
+                        "[SDK.inbound] process self SendMessageRequest echo: from_uid={} local_message_id={}",
+                        req.from_uid, req.local_message_id
+                    );
+                }
+                let channel_type = self
+                    .storage
+                    .get_channel_by_id(req.channel_id)
+                    .await?
+                    .map(|ch| ch.channel_type as u8)
+                    .unwrap_or(1);
+                if let Some(item) = Self::send_message_to_sync_item(req, channel_type) {
+                    if inbound_logs_enabled() {
+                        eprintln!(
+                            "[SDK.inbound] mapped SendMessageRequest -> sync item channel_type={}",
+                            channel_type
+                        );
+                    }
+                    message_items.push(item);
+                }
+            }
+            MessageType::PushMessageRequest => {
+                let req: PushMessageRequest = decode_message(&data)
+                    .map_err(|e| Error::Serialization(format!("decode push message: {e}")))?;
+                if self.skip_inbound_materialization_for_load_testing {
+                    return Ok(0);
+                }
+                if realtime_trace_enabled() {
+                    eprintln!(
+                    "[SDK_MESSAGE_EVENT_DECODED] biz=Push channel_id={} server_message_id={} from_uid={} msg_type={} payload_len={}",
+                    req.channel_id, req.server_message_id, req.from_uid, req.message_type, req.payload.len()
                 );
-            };
-        if let Some(duration) = Self::pick_u64_from_extra(extra, "duration") {
-            insert_u32(obj, "duration", duration);
-        }
-        if let Some(width) = Self::pick_u64_from_extra(extra, "width") {
-            insert_u32(obj, "width", width);
-        }
-        if let Some(height) = Self::pick_u64_from_extra(extra, "height") {
-            insert_u32(obj, "height", height);
-        }
-        if let Some(tw) = Self::pick_u64_from_extra(extra, "thumbnail_width") {
-            insert_u32(obj, "thumbnail_width", tw);
-        }
-        if let Some(th) = Self::pick_u64_from_extra(extra, "thumbnail_height") {
-            insert_u32(obj, "thumbnail_height", th);
-        }
-    }
-
-    /// 补全 channel 的 last_message_* 元数据字段。
-    ///
-    /// **重要**：本函数**不再改写 `last_msg_content`**（参见架构归正）。
-    /// content 字段保持为消息的原始体（TEXT = 纯文本 / 其它 = 结构化 JSON），
-    /// 预览文案由 UI 层基于 `last_message_type` + `last_message_is_revoked` + i18n 渲染。
-    ///
-    /// 行为：通过 `last_local_message_id` 查本地消息表，取出 `message_type` 与
-    /// 撤回标记后写到 channel 的相应字段。查不到时保持 None / false。
-    async fn materialize_channel_preview(&self, mut channel: StoredChannel) -> StoredChannel {
-        if channel.last_local_message_id > 0 {
-            if let Ok(Some(message)) = self
-                .storage
-                .get_message_by_id(channel.last_local_message_id)
-                .await
-            {
-                if message.channel_id == channel.channel_id
-                    && message.channel_type == channel.channel_type
-                {
-                    channel.last_message_type = Some(message.message_type);
-                    // StoredMessage 暂无显式 revoked 字段；客户端通过 message_type==System
-                    // + content/extra 中的 topic / metadata 推断（spec/SYSTEM_MESSAGE_SPEC §3）。
-                    // 这里保守置 false，未来如需精确从 StoredMessageExtra.revoke 派生再补。
-                    channel.last_message_is_revoked = false;
+                }
+                if let Some(count) = self.apply_entity_invalidation_push(&req).await? {
+                    direct_applied += count;
+                } else if let Some(count) = self.apply_canonical_timeline_push(&req).await? {
+                    direct_applied += count;
+                } else if let Some(peer_uid) = Self::push_message_to_friend_event(&req) {
+                    // F-sync.2: 转 entity_type="friend"，让 SDK 走 entity sync
+                    // 把 pending/rejected/recalled 状态从 server 拉到本地 friend 表。
+                    self.pending_events.push(SdkEvent::SyncEntityChanged {
+                        entity_type: "friend".to_string(),
+                        entity_id: peer_uid.to_string(),
+                        deleted: false,
+                    });
+                } else if let Some(item) = Self::push_message_to_live_location_item(&req) {
+                    live_location_items.push(item);
+                } else if let Some(status_item) = Self::push_message_to_status_sync_item(&req) {
+                    read_cursor_items.push(status_item);
+                } else if let Some(receipt) = Self::push_message_to_delivery_receipt(&req) {
+                    delivery_receipts.push(receipt);
+                } else {
+                    // deleted=true 时 push_message_to_sync_item 会透传给 SyncEntityItem.deleted，
+                    // 进而触发 "message" 实体处理里的 set_message_revoke 路径
+                    message_items.push(Self::push_message_to_sync_item(req));
                 }
             }
+            MessageType::PushBatchRequest => {
+                let req: PushBatchRequest = decode_message(&data)
+                    .map_err(|e| Error::Serialization(format!("decode push batch: {e}")))?;
+                if self.skip_inbound_materialization_for_load_testing {
+                    return Ok(0);
+                }
+                for push in req.messages {
+                    if let Some(count) = self.apply_entity_invalidation_push(&push).await? {
+                        direct_applied += count;
+                    } else if let Some(count) = self.apply_canonical_timeline_push(&push).await? {
+                        direct_applied += count;
+                    } else if let Some(peer_uid) = Self::push_message_to_friend_event(&push) {
+                        self.pending_events.push(SdkEvent::SyncEntityChanged {
+                            entity_type: "friend".to_string(),
+                            entity_id: peer_uid.to_string(),
+                            deleted: false,
+                        });
+                    } else if let Some(item) = Self::push_message_to_live_location_item(&push) {
+                        live_location_items.push(item);
+                    } else if let Some(status_item) = Self::push_message_to_status_sync_item(&push)
+                    {
+                        read_cursor_items.push(status_item);
+                    } else if let Some(receipt) = Self::push_message_to_delivery_receipt(&push) {
+                        delivery_receipts.push(receipt);
+                    } else {
+                        message_items.push(Self::push_message_to_sync_item(push));
+                    }
+                }
+            }
+            MessageType::PublishRequest => {
+                let req: PublishRequest = decode_message(&data)
+                    .map_err(|e| Error::Serialization(format!("decode publish request: {e}")))?;
+                if let Ok(push) = decode_message::<PushMessageRequest>(&req.payload) {
+                    if self.skip_inbound_materialization_for_load_testing {
+                        return Ok(0);
+                    }
+                    // IM 场景：payload 是 PushMessageRequest（私聊/群聊消息走 sync pipeline）
+                    if inbound_logs_enabled() {
+                        eprintln!(
+                            "[SDK.inbound] publish payload decoded as PushMessageRequest channel_id={}",
+                            push.channel_id
+                        );
+                    }
+                    if let Some(count) = self.apply_entity_invalidation_push(&push).await? {
+                        direct_applied += count;
+                    } else if let Some(count) = self.apply_canonical_timeline_push(&push).await? {
+                        direct_applied += count;
+                    } else if let Some(peer_uid) = Self::push_message_to_friend_event(&push) {
+                        self.pending_events.push(SdkEvent::SyncEntityChanged {
+                            entity_type: "friend".to_string(),
+                            entity_id: peer_uid.to_string(),
+                            deleted: false,
+                        });
+                    } else if let Some(item) = Self::push_message_to_live_location_item(&push) {
+                        live_location_items.push(item);
+                    } else if let Some(status_item) = Self::push_message_to_status_sync_item(&push)
+                    {
+                        read_cursor_items.push(status_item);
+                    } else if let Some(receipt) = Self::push_message_to_delivery_receipt(&push) {
+                        delivery_receipts.push(receipt);
+                    } else {
+                        message_items.push(Self::push_message_to_sync_item(push));
+                    }
+                } else if let Ok(batch) = decode_message::<PushBatchRequest>(&req.payload) {
+                    if self.skip_inbound_materialization_for_load_testing {
+                        return Ok(0);
+                    }
+                    if inbound_logs_enabled() {
+                        eprintln!(
+                            "[SDK.inbound] publish payload decoded as PushBatchRequest count={}",
+                            batch.messages.len()
+                        );
+                    }
+                    for push in batch.messages {
+                        if let Some(count) = self.apply_entity_invalidation_push(&push).await? {
+                            direct_applied += count;
+                        } else if let Some(count) =
+                            self.apply_canonical_timeline_push(&push).await?
+                        {
+                            direct_applied += count;
+                        } else if let Some(peer_uid) = Self::push_message_to_friend_event(&push) {
+                            self.pending_events.push(SdkEvent::SyncEntityChanged {
+                                entity_type: "friend".to_string(),
+                                entity_id: peer_uid.to_string(),
+                                deleted: false,
+                            });
+                        } else if let Some(item) = Self::push_message_to_live_location_item(&push) {
+                            live_location_items.push(item);
+                        } else if let Some(status_item) =
+                            Self::push_message_to_status_sync_item(&push)
+                        {
+                            read_cursor_items.push(status_item);
+                        } else if let Some(receipt) = Self::push_message_to_delivery_receipt(&push)
+                        {
+                            delivery_receipts.push(receipt);
+                        } else {
+                            message_items.push(Self::push_message_to_sync_item(push));
+                        }
+                    }
+                } else {
+                    // Room 场景：payload 是原始内容（纯网络，不走本地数据库）
+                    if inbound_logs_enabled() {
+                        eprintln!(
+                            "[SDK.inbound] room publish channel_id={} topic={:?} payload_len={}",
+                            req.channel_id,
+                            req.topic,
+                            req.payload.len()
+                        );
+                    }
+                    // P1-05：去重 replay/live 重叠窗口的重复帧。presence_changed 是无 id
+                    // 的状态帧，不参与去重（每次都应用最新态）。
+                    if req.topic.as_deref() == Some("presence_changed") {
+                        self.apply_presence_changed_payload(&req.payload);
+                    } else if self.room_message_is_duplicate(req.channel_id, req.server_message_id)
+                    {
+                        if inbound_logs_enabled() {
+                            eprintln!(
+                                "[SDK.inbound] drop duplicate room publish channel_id={} server_message_id={:?}",
+                                req.channel_id, req.server_message_id
+                            );
+                        }
+                        return Ok(0);
+                    }
+                    self.pending_events
+                        .push(SdkEvent::SubscriptionMessageReceived {
+                            channel_id: req.channel_id,
+                            topic: req.topic,
+                            payload: req.payload,
+                            publisher: req.publisher,
+                            server_message_id: req.server_message_id,
+                            timestamp: req.timestamp,
+                        });
+                }
+            }
+            _ => {
+                if inbound_logs_enabled() {
+                    eprintln!(
+                        "[SDK.inbound] ignore inbound message_type={:?} biz_type={}",
+                        message_type, biz_type
+                    );
+                }
+                return Ok(0);
+            }
         }
-        channel
-    }
-
-    fn guess_mime_type(filename: &str) -> &'static str {
-        let ext = filename
-            .rsplit('.')
-            .next()
-            .map(|s| s.to_ascii_lowercase())
-            .unwrap_or_default();
-        match ext.as_str() {
-            "jpg" | "jpeg" => "image/jpeg",
-            "png" => "image/png",
-            "gif" => "image/gif",
-            "webp" => "image/webp",
-            "heic" => "image/heic",
-            "mp4" => "video/mp4",
-            "mov" => "video/quicktime",
-            "mkv" => "video/x-matroska",
-            "mp3" => "audio/mpeg",
-            "wav" => "audio/wav",
-            "aac" => "audio/aac",
-            "m4a" => "audio/mp4",
-            "ogg" => "audio/ogg",
-            "pdf" => "application/pdf",
-            "txt" => "text/plain",
-            "json" => "application/json",
-            "zip" => "application/zip",
-            _ => "application/octet-stream",
-        }
-    }
-
-    /// 解码图片并应用 EXIF orientation，返回的尺寸/像素均为「显示方向」。
-    ///
-    /// `image` crate 默认不会消费 EXIF orientation：`img.width()/height()` 给的是
-    /// 传感器原始像素方向。真机横拍照片常带 orientation=6/8（旋转 90°），不处理会让
-    /// metadata 宽高反置（web 按 metadata 定气泡比例 → 比例错）且缩略图转向错。
-    /// 这里读出 orientation 并 apply 到解码结果，保证所有下游消费者拿到的是显示方向。
-    fn decode_image_oriented(source_path: &std::path::Path) -> Result<image::DynamicImage> {
-        let reader = image::ImageReader::open(source_path)
-            .map_err(|e| Error::Storage(format!("open image failed: {e}")))?;
-        let mut decoder = reader
-            .into_decoder()
-            .map_err(|e| Error::Storage(format!("decode image failed: {e}")))?;
-        let orientation = image::ImageDecoder::orientation(&mut decoder)
-            .unwrap_or(image::metadata::Orientation::NoTransforms);
-        let mut img = image::DynamicImage::from_decoder(decoder)
-            .map_err(|e| Error::Storage(format!("decode image failed: {e}")))?;
-        img.apply_orientation(orientation);
-        Ok(img)
-    }
-
-    fn generate_image_thumbnail_sync(
-        source_path: &std::path::Path,
-        output_path: &std::path::Path,
-        max_edge: u32,
-        quality: u8,
-    ) -> Result<(u32, u32, u64)> {
-        let img = Self::decode_image_oriented(source_path)?;
-        let (w, h) = (img.width(), img.height());
-        let (tw, th) = if w >= h {
-            let nw = w.min(max_edge).max(1);
-            let nh = ((h as u64) * (nw as u64) / (w as u64)).max(1) as u32;
-            (nw, nh)
-        } else {
-            let nh = h.min(max_edge).max(1);
-            let nw = ((w as u64) * (nh as u64) / (h as u64)).max(1) as u32;
-            (nw, nh)
-        };
-        let thumb = img.resize_exact(tw, th, image::imageops::FilterType::Triangle);
-        // WebP supports RGBA, so preserve alpha channel for PNG/WebP sources.
-        let rgba = thumb.to_rgba8();
-        if let Some(parent) = output_path.parent() {
-            std::fs::create_dir_all(parent)
-                .map_err(|e| Error::Storage(format!("create thumb dir failed: {e}")))?;
-        }
-        let file = std::fs::File::create(output_path)
-            .map_err(|e| Error::Storage(format!("create thumb failed: {e}")))?;
-        let mut writer = std::io::BufWriter::new(file);
-        let _ = quality; // lossless WebP; quality unused but kept for API compat
-        let encoder = image::codecs::webp::WebPEncoder::new_lossless(&mut writer);
-        encoder
-            .encode(
-                rgba.as_raw(),
-                thumb.width(),
-                thumb.height(),
-                image::ExtendedColorType::Rgba8,
-            )
-            .map_err(|e| Error::Storage(format!("encode thumb webp failed: {e}")))?;
-        writer
-            .flush()
-            .map_err(|e| Error::Storage(format!("flush thumb failed: {e}")))?;
-        let file_size = output_path
-            .metadata()
-            .map(|m| m.len())
-            .map_err(|e| Error::Storage(format!("stat thumb failed: {e}")))?;
-        Ok((thumb.width(), thumb.height(), file_size))
-    }
 
-    async fn request_upload_token(
-        &mut self,
-        user_id: u64,
-        filename: String,
-        file_size: i64,
-        mime_type: String,
-        file_type: String,
-        // **最终待上传 blob** 的 SHA-256（加密之后那串字节）。
-        // `None` = 不参与秒传，照常完整上传。
-        sha256: Option<&str>,
-    ) -> Result<FileRequestUploadTokenResponse> {
-        let payload = FileRequestUploadTokenRequest {
-            user_id,
-            filename: Some(filename),
-            file_size,
-            mime_type,
-            file_type,
-            business_type: "message".to_string(),
-            sha256: sha256.map(str::to_string),
-            transform_version: 0,
-        };
-        let response: FileRequestUploadTokenResponse = self
-            .rpc_call_typed(routes::file::REQUEST_UPLOAD_TOKEN, &payload)
-            .await?;
-        if response.token.trim().is_empty() {
-            return Err(Error::Serialization(
-                "decode file/request_upload_token response: missing token".to_string(),
-            ));
+        let mut applied = direct_applied;
+        if !message_items.is_empty() {
+            let n = message_items.len();
+            let before = applied;
+            applied += self
+                .enqueue_and_apply_sync_items("message".to_string(), None, message_items, true)
+                .await?;
+            if realtime_trace_enabled() {
+                eprintln!(
+                    "[SDK_LOCAL_STORE_APPLY] message_items={} applied={} uid={:?}",
+                    n,
+                    applied - before,
+                    self.current_uid
+                );
+            }
         }
-        if response.upload_url.trim().is_empty() {
-            return Err(Error::Serialization(
-                "decode file/request_upload_token response: missing upload_url".to_string(),
-            ));
+        if !read_cursor_items.is_empty() {
+            applied += self
+                .enqueue_and_apply_sync_items(
+                    "channel_read_cursor".to_string(),
+                    None,
+                    read_cursor_items,
+                    true,
+                )
+                .await?;
         }
-        Ok(response)
-    }
-
-    /// 申请分片 token（RESUMABLE_UPLOAD_SPEC §2）。
-    async fn request_chunked_upload_token(
-        &mut self,
-        filename: String,
-        file_size: i64,
-        mime_type: String,
-        file_type: String,
-        sha256: &str,
-        force_upload: bool,
-    ) -> Result<FileRequestChunkedUploadTokenResponse> {
-        let payload = FileRequestChunkedUploadTokenRequest {
-            file_type,
-            business_type: "message".to_string(),
-            file_size,
-            file_hash: sha256.to_string(),
-            mime_type,
-            filename: Some(filename),
-            transform_version: 0,
-            force_upload,
-        };
-        self.rpc_call_typed(routes::file::REQUEST_CHUNKED_UPLOAD_TOKEN, &payload)
-            .await
-    }
-
-    /// 明文 → **最终待上传 blob**：加密一次，算一次摘要，之后都用它。
-    ///
-    /// 🔴 顺序不能反。秒传按「最终上传字节」判重，而加密用的是随机 CEK/nonce——
-    /// 预检之后再加密一次，字节就变了、摘要也变了，本来就不该命中。
-    /// 所以这里产出的 blob 必须留住：上传用它，重试也用它。
-    fn seal_for_upload(data: &[u8]) -> Result<(Vec<u8>, String, String)> {
-        // 附件加密 v1（ATTACHMENT_ENCRYPTION_SPEC）：整文件 AES-256-GCM，
-        // 密文 blob = nonce||ct||tag。对象存储只存密文。CEK 不进日志。
-        let (blob, cek_b64) = crate::attachment_crypto::encrypt_attachment(data)
-            .map_err(|e| Error::Serialization(format!("attachment encrypt failed: {e}")))?;
-        let sha256 = Self::sha256_hex(&blob);
-        Ok((blob, cek_b64, sha256))
+        if !live_location_items.is_empty() {
+            applied += self
+                .enqueue_and_apply_sync_items(
+                    "message_live_location".to_string(),
+                    None,
+                    live_location_items,
+                    true,
+                )
+                .await?;
+        }
+        // Process delivery receipts: persist + emit events
+        for (channel_id, channel_type, server_message_id, delivered_at) in delivery_receipts {
+            let local_message_id = self
+                .storage
+                .mark_message_delivered(server_message_id, delivered_at)
+                .await
+                .unwrap_or(None);
+            if let Some(message_id) = local_message_id {
+                self.pending_events.push(SdkEvent::MessageDelivered {
+                    channel_id,
+                    channel_type,
+                    message_id,
+                    server_message_id,
+                    delivered_at,
+                });
+                if inbound_logs_enabled() {
+                    eprintln!(
+                        "[SDK.delivered] message_delivered: channel_id={} message_id={} server_message_id={} delivered_at={}",
+                        channel_id, message_id, server_message_id, delivered_at
+                    );
+                }
+            }
+        }
+        Ok(applied)
     }
 
-    fn sha256_hex(bytes: &[u8]) -> String {
-        use sha2::Digest as _;
-        let mut hasher = <sha2::Sha256 as sha2::Digest>::new();
-        hasher.update(bytes);
-        hex::encode(hasher.finalize())
+    async fn replay_prelogin_inbound_frames(&mut self) -> Result<usize> {
+        if self.current_uid.is_none() || self.pending_prelogin_inbound_frames.is_empty() {
+            return Ok(0);
+        }
+        let pending = std::mem::take(&mut self.pending_prelogin_inbound_frames);
+        let count = pending.len();
+        let mut applied = 0usize;
+        for (biz_type, data) in pending {
+            applied += self.handle_inbound_frame(biz_type, data).await?;
+        }
+        if realtime_trace_enabled() {
+            eprintln!(
+                "[SDK_INBOUND_PRELOGIN_REPLAY] count={} applied={} current_uid={:?}",
+                count, applied, self.current_uid
+            );
+        }
+        Ok(applied)
     }
 
-    /// 一次附件发送要按什么次序跟服务端打交道。
-    ///
-    /// 抽出来是为了**能被测到**：这段的价值全在次序和入参上——第一次带摘要、
-    /// claim 没成时第二次**不带**摘要、两次上传用的是同一个已封装的 blob。
-    /// 写在发送函数里的话，这些只能靠读代码确认。
-    ///
-    /// 三个动作由 `io` 提供：生产是真实的 RPC/HTTP，测试是记录调用的假实现。
-    /// 这里不碰网络，也不碰文件。
-    async fn plan_attachment_upload<Io: AttachmentUploadIo>(
-        io: &mut Io,
-        // 🔴 封装好的 payload 从外面传进来，这里**没有**重新封装的能力——
-        // 再封一次就是另一串密文：摘要对不上，秒传落空，服务端多存一份。
-        payload: &SealedPayload,
-    ) -> Result<(UploadedFileInfo, String)> {
-        let sha256 = payload.sha256.as_str();
+    const BOOTSTRAP_ENTITY_ORDER: [&str; 7] = [
+        "friend",
+        "group",
+        "channel",
+        "user",
+        "channel_read_cursor",
+        "user_block",
+        sticker::SYNC_ENTITY_TYPE,
+    ];
 
-        // 大文件走分片（RESUMABLE_UPLOAD_SPEC）：独立 RPC、独立端点，与整包互不影响。
-        if let Some(threshold) = io.chunked_threshold() {
-            if payload.blob.len() > threshold {
-                return Self::plan_chunked_upload(io, payload).await;
-            }
+    async fn execute_bootstrap_sync(&mut self) -> Result<()> {
+        if self.session_state != SessionState::Authenticated {
+            return Err(Error::InvalidState(
+                "run_bootstrap_sync requires authenticated state".to_string(),
+            ));
         }
 
-        // 第一次带摘要，给服务端说「这串字节我已经有了」的机会。
-        let token = io.prepare(Some(sha256), payload.blob.len()).await?;
-
-        if token.already_exists {
-            match io.claim(&token.token, sha256).await {
-                Ok(info) => {
-                    io.on_upload_finished();
-                    return Ok((info, token.token));
-                }
-                // 🔴 秒传没成 → 照常上传，不是发送失败。预检说「有」而 claim 拿不到
-                // 会正常发生：那份内容的记录里没有一条是这个人现在读得到的，或者
-                // 候选在这中间失效了。
-                Err(e) if Self::claim_miss_should_reupload(&e) => {
-                    eprintln!("[SDK.actor] claim missed, falling back to a normal upload: {e}");
-                    // 🔴 第二次**不带**摘要。带着的话服务端还会说「已经有了」，
-                    // 又绕回 claim —— 转成死循环。
-                    let token = io.prepare(None, payload.blob.len()).await?;
-                    let info = io.upload(&token, payload).await?;
-                    io.on_upload_finished();
-                    return Ok((info, token.token));
+        if self.bootstrap_completed && !self.full_rebuild_required().await {
+            match self.execute_resume_sync().await {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    if !self.full_rebuild_required().await {
+                        return Err(err);
+                    }
+                    eprintln!(
+                        "[SDK.actor] bootstrap sync escalate to full rebuild after resume failure: {}",
+                        err
+                    );
                 }
-                Err(e) => return Err(e),
             }
         }
 
-        let info = io.upload(&token, payload).await?;
-        io.on_upload_finished();
-        Ok((info, token.token))
-    }
-
-    /// 分片路径的编排（RESUMABLE_UPLOAD_SPEC §2.1 / §3.3.1）。
-    ///
-    /// - 命中 → claim；claim 没成 → **`force_upload=true` 再申请一次**（跳过预检，否则
-    ///   预检又命中、又回 claim_token，永远进不了实体上传），同一条消息只置一次。
-    /// - 传字节途中会话没了（过期 / 被清）→ 丢掉本地记录，重新申请一次；此时若已经
-    ///   完成过，预检会命中 → claim 拿到 file_id。
-    async fn plan_chunked_upload<Io: AttachmentUploadIo>(
-        io: &mut Io,
-        payload: &SealedPayload,
-    ) -> Result<(UploadedFileInfo, String)> {
-        let sha256 = payload.sha256.as_str();
-        let size = payload.blob.len();
-
-        let mut prepared = io.prepare_chunked(sha256, size, false).await?;
-        if let ChunkedPrepared::Claim { claim_token } = &prepared {
-            match io.claim(claim_token, sha256).await {
-                Ok(info) => {
-                    io.on_upload_finished();
-                    return Ok((info, claim_token.clone()));
+        let core_entities = ["friend", "group", "channel", "user", "channel_read_cursor"];
+        let optional_entities = [
+            "user_block",
+            "channel_extra",
+            "channel_unread",
+            sticker::SYNC_ENTITY_TYPE,
+        ];
+        if actor_logs_enabled() {
+            eprintln!(
+                "[SDK.actor] bootstrap sync plan core={:?} optional={:?}",
+                core_entities, optional_entities
+            );
+        }
+        for entity_type in Self::BOOTSTRAP_ENTITY_ORDER {
+            match self.sync_entities(entity_type.to_string(), None).await {
+                Ok(count) => {
+                    if actor_logs_enabled() {
+                        eprintln!(
+                            "[SDK.actor] bootstrap sync entity={} count={}",
+                            entity_type, count
+                        );
+                    }
+                    // 每族报一次完成，宿主据此画首屏进度条。首次登录要等十几秒，中间
+                    // 没有信号的话用户分不出「在同步」和「卡死」。
+                    //
+                    // 必须无条件发，不能只在 count > 0 时发：某族本来就没有增量时
+                    // count = 0，但它确实已经同步过了，跳过会让进度永远差这一格。
+                    self.queue_sync_summary_event(entity_type.to_string(), None, count);
                 }
-                Err(e) if Self::claim_miss_should_reupload(&e) => {
-                    eprintln!("[SDK.actor] claim missed, forcing a chunked upload: {e}");
-                    prepared = io.prepare_chunked(sha256, size, true).await?;
+                Err(e) if Self::is_unsupported_entity_error(&e) => {
+                    self.log_unsupported_sync_skip("bootstrap sync", entity_type, None, &e);
+                    // 服务端不支持的族也算走过了，否则老服务端上进度永远到不了 100%。
+                    self.queue_sync_summary_event(entity_type.to_string(), None, 0);
                 }
                 Err(e) => return Err(e),
             }
         }
-        let session = match prepared {
-            ChunkedPrepared::Session(s) => s,
-            ChunkedPrepared::Claim { .. } => {
-                return Err(Error::Transport(
-                    "force_upload=true 仍返回秒传命中，服务端违反协议".to_string(),
-                ))
-            }
-        };
 
-        match io.upload_chunked(&session, payload).await {
-            Ok(info) => {
-                io.on_upload_finished();
-                Ok((info, session.upload_token))
-            }
-            Err(Error::UploadSessionGone) => {
-                eprintln!("[SDK.actor] chunked session gone, re-preparing once");
-                io.on_upload_finished();
-                match io.prepare_chunked(sha256, size, false).await? {
-                    ChunkedPrepared::Claim { claim_token } => {
-                        let info = io.claim(&claim_token, sha256).await?;
-                        io.on_upload_finished();
-                        Ok((info, claim_token))
-                    }
-                    ChunkedPrepared::Session(again) => {
-                        let info = io.upload_chunked(&again, payload).await?;
-                        io.on_upload_finished();
-                        Ok((info, again.upload_token))
-                    }
-                }
-            }
-            Err(e) => Err(e),
+        // Full rosters are not a readiness dependency. The authoritative `user`
+        // projection already covers referenced identities; roster membership is
+        // hydrated on demand by member/settings surfaces. Iterating every group
+        // here makes first login O(group_count) and traps conversation reads.
+        self.bootstrap_completed = true;
+        if let Some(uid) = &self.current_uid {
+            self.storage
+                .set_bootstrap_completed(uid.clone(), true)
+                .await?;
         }
-    }
+        self.clear_resume_repair_key(Self::resume_repair_full_rebuild_key())
+            .await;
 
-    /// claim 失败是不是「服务端拿不到那份内容」这一种——只有它该退回整传。
-    ///
-    /// 服务端把「没有这份内容」和「有但你无权」说成同一句话（否则这个接口就是
-    /// 文件存在性探测器），所以两者都落在这里，退回整传对两者都是对的：真无权的
-    /// 人传自己的字节，本来就该被允许。
-    ///
-    /// 其余错误各有含义，不能一并退回：Validation 是参数不对（传一遍还是错），
-    /// Forbidden 是这次操作本身不被允许，ServiceUnavailable 是瞬时竞争、该重试。
-    fn claim_miss_should_reupload(e: &Error) -> bool {
-        matches!(
-            e,
-            Error::Server { code, .. }
-                if *code == ErrorCode::ResourceNotFound as u32
-        )
+        // Notify server that bootstrap is finished and this session is ready
+        // to receive catch-up and realtime pushes. This endpoint is idempotent.
+        self.send_session_ready().await?;
+
+        self.hydrate_system_channel_messages_from_history().await?;
+
+        Ok(())
     }
 
-    /// 一条 outbox 任务只封装**一次**，结果落盘；重试读回同一份。
-    ///
-    /// 🔴 不这样做的话，秒传对「重试」这个最常见的重复来源完全无效：加密用随机
-    /// CEK/nonce，重新封装出来的字节不同、摘要不同，预检必然 miss，于是同一条
-    /// 逻辑消息在服务端留下第二份物理文件。上一次上传如果其实成功了、只是响应
-    /// 丢了，那第二份就永远没人引用。
-    ///
-    /// 缓存随这条消息的托管目录一起存在，发送成功后由调用方删掉。
-    fn seal_once(
-        cache_path: &std::path::Path,
-        plaintext: &[u8],
-    ) -> Result<(Vec<u8>, String, String)> {
-        let meta_path = cache_path.with_extension("sealed.json");
+    /// 有没有「已请求但还没被 actor 处理」的账号切换。
+    fn switch_is_pending(&self) -> bool {
+        self.switch_requested.load(Ordering::SeqCst) > self.switch_processed.load(Ordering::SeqCst)
+    }
 
-        // 🔴 metadata 是**提交标记**：它存在，才代表旁边那个 blob 写完了。
-        // 而且必须重算摘要——两个文件分两次 rename，中间崩一次就可能凑出
-        // 「新 blob + 旧 CEK/摘要」。那种组合每次上传都会被服务端拒，而且自己
-        // 好不了：每次重试都读回同一份对不上的缓存。
-        if let (Ok(blob), Ok(meta_raw)) = (
-            std::fs::read(cache_path),
-            std::fs::read_to_string(&meta_path),
-        ) {
-            if let Ok(meta) = serde_json::from_str::<SealedCacheMeta>(&meta_raw) {
-                // 摘要对上只说明**字节**没坏。CEK 坏了而摘要还对（元数据被改、
-                // 或两个文件来自不同轮次）的话，传上去的密文没人解得开，服务端
-                // 拒了之后下一轮又读回同一份——永久失败。
-                //
-                // 解一次就能判：AES-GCM 的认证标签在密钥不对时直接失败。这只发生
-                // 在重试路径（首次封装根本不读缓存），多这一次解密换的是不会卡死。
-                if !blob.is_empty()
-                    && Self::sha256_hex(&blob) == meta.sha256
-                    && crate::attachment_crypto::decrypt_attachment(&blob, &meta.cek)
-                        .is_ok_and(|decoded| decoded == plaintext)
-                {
-                    return Ok((blob, meta.cek, meta.sha256));
-                }
+    /// 等到有账号切换排队为止。
+    ///
+    /// 先注册唤醒、再读计数器：顺序反了就会漏掉「读完之后、注册之前」那一瞬间到达的
+    /// 请求——正是裸 `notify_waiters()` 丢信号的那个窗口。注册在前，那一声必然落在
+    /// 已注册的等待者身上；而即使它仍然落空，循环回来重读计数器也能看到事实。
+    async fn wait_for_switch_request(
+        requested: Arc<AtomicU64>,
+        processed: Arc<AtomicU64>,
+        wakeup: Arc<tokio::sync::Notify>,
+    ) {
+        loop {
+            let notified = wakeup.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if requested.load(Ordering::SeqCst) > processed.load(Ordering::SeqCst) {
+                return;
             }
+            notified.await;
         }
-
-        let (blob, cek_b64, sha256) = Self::seal_for_upload(plaintext)?;
-
-        // 顺序不能变：先撤掉旧标记，再写 blob，最后立标记。
-        // 反过来的话，新 blob 落地而旧 metadata 还在的那一瞬间，缓存看着是完整的。
-        let _ = std::fs::remove_file(&meta_path);
-        let tmp = cache_path.with_extension("sealed.tmp");
-        std::fs::write(&tmp, &blob)
-            .and_then(|_| std::fs::rename(&tmp, cache_path))
-            .map_err(|e| Error::Storage(format!("write sealed cache failed: {e}")))?;
-        let meta = SealedCacheMeta {
-            cek: cek_b64.clone(),
-            sha256: sha256.clone(),
-        };
-        let meta_tmp = meta_path.with_extension("tmp");
-        std::fs::write(
-            &meta_tmp,
-            serde_json::to_string(&meta)
-                .map_err(|e| Error::Serialization(format!("encode sealed meta: {e}")))?,
-        )
-        .and_then(|_| std::fs::rename(&meta_tmp, &meta_path))
-        .map_err(|e| Error::Storage(format!("write sealed meta failed: {e}")))?;
-        Ok((blob, cek_b64, sha256))
-    }
-
-    /// 删掉封装缓存。发送真正完成之后才调用——在那之前任何一次重试都还需要它。
-    fn drop_sealed_cache(cache_path: &std::path::Path) {
-        let _ = std::fs::remove_file(cache_path);
-        let _ = std::fs::remove_file(cache_path.with_extension("sealed.json"));
     }
 
-    /// 分片上传：查缺口 → 逐片传 → complete（RESUMABLE_UPLOAD_SPEC §3 / §5 / §6）。
+    /// 跑一轮同步（如果闸门允许）。
     ///
-    /// 🔴 **每一轮都以服务端的 `missing` 为准**。本地记账只是乐观估计，一旦对不上
-    /// （重试、上次崩溃、换了设备），必须听服务端的。
+    /// 返回值是**这一轮到底跑没跑**。以前它返回 `Result<()>`，被闸门挡掉时也是 Ok——
+    /// 调用方无从分辨「同步完成了」和「什么都没发生」，于是 `run_bootstrap_sync` 会
+    /// 在 bootstrap 根本没跑的情况下报成功，接着每一个 local-first 操作都失败。
     ///
-    /// 🔴 分片按 offset 从**密文缓存文件**读（决策 13），不再切内存里的整个 blob；
-    /// 缓存读不到时才退回内存 blob（老路径的兜底，不该发生）。
-    async fn upload_in_chunks(
-        &self,
-        session: &ChunkedSession,
-        mime_type: &str,
-        sealed_cache: &std::path::Path,
-        blob: &[u8],
-        cek_b64: String,
-        local_message_id: &str,
-    ) -> Result<UploadedFileInfo> {
-        use crate::resumable_upload::{ChunkTimer, ChunkVerdict, ResumableUpload, CHUNK_RETRIES};
+    /// `explicit` = 宿主主动要求（不是自动触发）。它能解除退避窗口：退避压的是自动
+    /// 重试的空转，不该把用户点下的那一次也一起吞掉。
+    async fn ensure_synced_inner<F>(&mut self, explicit: bool, mut emit: F) -> Result<bool>
+    where
+        F: FnMut(SdkEvent),
+    {
+        if self.session_state != SessionState::Authenticated {
+            return Err(Error::InvalidState(
+                "ensure_synced requires authenticated state".to_string(),
+            ));
+        }
 
-        let base = session.upload_url.trim_end_matches('/').to_string();
-        let token = session.upload_token.as_str();
-        let client = reqwest::Client::new();
-        let total = blob.len() as u64;
-        let plan = crate::resumable_upload::UploadPlan::for_base_unit(session.base_unit);
+        // 已经有切换在排队就根本别开这一轮：开了也是马上让出，白白把闸门开关一遍。
+        if self.switch_is_pending() {
+            if actor_logs_enabled() {
+                eprintln!("[SDK.actor] ensure_synced skipped: an account switch is pending");
+            }
+            return Ok(false);
+        }
 
-        // 先问一次：可能是续传（上次断在半路），也可能一片都还没传，也可能已经完成。
-        let status = self.fetch_upload_status(&client, &base, token).await?;
-        let (missing, completed) = match status {
-            UploadStatusOutcome::Status { missing, completed, .. } => (missing, completed),
-            UploadStatusOutcome::SessionGone => return Err(Error::UploadSessionGone),
+        let kind = if self.bootstrap_completed && !self.full_rebuild_required().await {
+            SyncRunKind::Resume
+        } else {
+            SyncRunKind::Bootstrap
         };
-        if completed {
-            // 上次 complete 成功但回执没到：直接再要一次结果。
-            return self.complete_upload(&client, &base, token, mime_type, cek_b64).await;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        if explicit {
+            // 宿主主动要的这一次不该被上一次失败排下的退避窗口吞掉。
+            self.sync_coordinator.note_explicit_request();
         }
-        let mut up = ResumableUpload::from_missing(total, plan, &missing);
-        self.emit_upload_progress(local_message_id, up.progress());
+        // 退避/合并/终态都在协调器里判定。sync 有多个触发源（重连成功、connect
+        // 成功、token 刷新后重认证、显式命令），它们只有共同经过这一道闸门才收
+        // 敛得住——在任何单个调用点 sleep 都会被下一个触发源绕开。
+        if let Err(rejection) = self.sync_coordinator.begin(kind, now_ms) {
+            if actor_logs_enabled() {
+                eprintln!("[SDK.actor] ensure_synced skipped: {rejection:?}");
+            }
+            return Ok(false);
+        }
+        // 这一轮属于哪个账号/会话世代。切账号会 bump 它。
+        let generation = self.sync_coordinator.generation();
+        emit(SdkEvent::SyncStateChanged {
+            state: self.sync_coordinator.snapshot(),
+        });
 
-        let file = std::fs::File::open(sealed_cache).ok();
-        let read_piece = |offset: u64, len: u64| -> Result<Vec<u8>> {
-            let mut buf = vec![0u8; len as usize];
-            if let Some(f) = file.as_ref() {
-                use std::os::unix::fs::FileExt;
-                if f.read_exact_at(&mut buf, offset).is_ok() {
-                    return Ok(buf);
+        let started = Instant::now();
+        // 同步跑起来，同时盯着「有账号切换在排队」这个铃。actor 在这里内联 await，
+        // 期间不处理命令——不盯着的话，一次卡住的同步会把切换一起堵死，用户点了
+        // 切换要等十几秒才有反应。铃响就让出：这一轮的结果已经不属于任何人了。
+        let result = tokio::select! {
+            biased;
+            _ = Self::wait_for_switch_request(
+                self.switch_requested.clone(),
+                self.switch_processed.clone(),
+                self.switch_wakeup.clone(),
+            ) => None,
+            r = async {
+                #[cfg(test)]
+                if let Some(stall) = self.sync_stall_for_test {
+                    tokio::time::sleep(stall).await;
                 }
-            }
-            let start = offset as usize;
-            let end = start + len as usize;
-            if end > blob.len() {
-                return Err(Error::Storage("sealed blob shorter than the session total".to_string()));
-            }
-            Ok(blob[start..end].to_vec())
+                match kind {
+                    SyncRunKind::Bootstrap => self.execute_bootstrap_sync().await,
+                    SyncRunKind::Resume => self.execute_resume_sync().await,
+                }
+            } => Some(r),
         };
 
-        // 🔴 失败预算是「自上次有进展以来」的，不是每片各算一份。
-        let mut failures_since_progress = 0u32;
-
-        while let Some(chunk) = up.next_chunk() {
-            let bytes = read_piece(chunk.offset, chunk.len)?;
-            let digest = crate::resumable_upload::chunk_digest(&bytes);
+        let Some(result) = result else {
+            // 主动放弃，不是失败：不能记 attempt/退避（那会让新账号背上上一个账号的
+            // 债），但必须把闸门撤下来，否则 begin() 会永远挡住后面每一个触发源。
+            self.sync_coordinator
+                .abandon(chrono::Utc::now().timestamp_millis());
+            // 后台收敛也必须停：那一轮属于正被切走的账号，继续跑会把上一个账号的
+            // 频道差异写进新账号的会话里。
+            self.convergence_run = None;
+            if actor_logs_enabled() {
+                eprintln!("[SDK.actor] ensure_synced yielded to a pending account switch");
+            }
+            return Ok(false);
+        };
 
-            let timer = ChunkTimer::start();
-            match self
-                .put_chunk(&client, &base, token, chunk.offset, bytes, &digest)
-                .await
-            {
-                ChunkVerdict::Ok => {
-                    up.on_chunk_ok(chunk, timer.elapsed());
-                    self.emit_upload_progress(local_message_id, up.progress());
-                    failures_since_progress = 0;
-                }
-                ChunkVerdict::RetryChunk => {
-                    failures_since_progress += 1;
-                    if failures_since_progress > CHUNK_RETRIES {
-                        return Err(Error::Transport(format!(
-                            "分片上传连续 {failures_since_progress} 次没有进展（offset={}）",
-                            chunk.offset
-                        )));
-                    }
-                    up.on_chunk_failed();
-                    tokio::time::sleep(crate::resumable_upload::retry_delay(
-                        failures_since_progress,
-                    ))
-                    .await;
-                }
-                ChunkVerdict::Resync => {
-                    failures_since_progress += 1;
-                    if failures_since_progress > CHUNK_RETRIES {
-                        return Err(Error::Transport(
-                            "反复与服务端区间对不齐，交回重试".to_string(),
-                        ));
-                    }
-                    match self.fetch_upload_status(&client, &base, token).await? {
-                        UploadStatusOutcome::Status { missing, completed: true, .. } if missing.is_empty() => {
-                            break;
-                        }
-                        UploadStatusOutcome::Status { missing, .. } => up.resync_missing(&missing),
-                        UploadStatusOutcome::SessionGone => return Err(Error::UploadSessionGone),
-                    }
-                    self.emit_upload_progress(local_message_id, up.progress());
-                }
-                ChunkVerdict::StartOver => return Err(Error::UploadSessionGone),
-                ChunkVerdict::Fatal => {
-                    return Err(Error::Transport(format!(
-                        "分片上传被服务端终局拒绝（offset={}）",
-                        chunk.offset
-                    )));
-                }
+        // 跑的过程中账号被切走了：这一轮的结果属于**上一个** owner，既不能写进
+        // 协调器（会污染新账号的 attempt/退避/终态），也不能发事件（UI 会拿旧账号
+        // 的同步结果去更新新账号）。静默丢弃，新账号自己的那一轮会照常跑。
+        //
+        // 正常情况下上面的 select 已经先一步让出（切换 API 投命令前会按铃），所以这里
+        // 兜的是漏网的那一格：铃响时这一轮恰好还没开始 await（notify_waiters 只唤醒
+        // **已经在等**的人，早响的一声会丢），同步照跑完，回来时世代已经变了。
+        // 那时这份结果属于上一个 owner，写进协调器会污染新账号的 attempt/退避，
+        // 发事件会让 UI 拿旧账号的同步结果去更新新账号。丢弃即可，新账号自己会跑。
+        if self.sync_coordinator.generation() != generation {
+            if actor_logs_enabled() {
+                eprintln!(
+                    "[SDK.actor] ensure_synced result dropped: generation {generation} -> {} (account switched mid-flight)",
+                    self.sync_coordinator.generation()
+                );
             }
+            return Ok(false);
         }
 
-        let info = self.complete_upload(&client, &base, token, mime_type, cek_b64).await?;
-        self.attachment_transfers
-            .body_uploads
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        Ok(info)
+        // 让出和跨世代丢弃的那几轮不计：它们的耗时不代表同步本身有多慢。
+        self.metrics.observe(
+            metrics::SYNC_DURATION_SECONDS,
+            &[
+                (
+                    "kind",
+                    match kind {
+                        SyncRunKind::Bootstrap => "bootstrap",
+                        SyncRunKind::Resume => "resume",
+                    },
+                ),
+                ("outcome", if result.is_ok() { "ok" } else { "error" }),
+            ],
+            metrics::SYNC_BUCKETS,
+            started.elapsed().as_secs_f64(),
+        );
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        match &result {
+            Ok(()) => self.sync_coordinator.complete(kind, now_ms),
+            Err(error) => self.sync_coordinator.fail(
+                kind,
+                error.is_auth_terminal(),
+                Some(error.protocol_code()),
+                error.to_string(),
+                now_ms,
+            ),
+        }
+        emit(SdkEvent::SyncStateChanged {
+            state: self.sync_coordinator.snapshot(),
+        });
+        result.map(|()| true)
     }
 
-    /// 查进度：服务端**同时**回已收与缺失，客户端直接用 `missing`。
-    async fn fetch_upload_status(
-        &self,
-        client: &reqwest::Client,
-        base: &str,
-        token: &str,
-    ) -> Result<UploadStatusOutcome> {
-        let resp = match self
-            .with_upload_token(client.get(format!("{base}/status")), token)
-            .send()
-            .await
-        {
-            Ok(r) => r,
-            Err(e) => return Err(Error::Transport(format!("查询上传进度失败: {e}"))),
-        };
-        let status = resp.status();
-        let json: serde_json::Value = resp
-            .json()
-            .await
-            .map_err(|e| Error::Serialization(format!("上传进度响应无法解析: {e}")))?;
-        let code = json["code"].as_u64().unwrap_or(u64::MAX) as u32;
-        if code == 20613 {
-            return Ok(UploadStatusOutcome::SessionGone);
-        }
-        if status.is_server_error() {
-            return Err(Error::Transport(format!("上传进度查询服务端错误: {status}")));
-        }
-        if code != 0 {
-            return Err(Error::Transport(format!(
-                "上传进度查询失败: code={code} {}",
-                json["message"].as_str().unwrap_or("")
-            )));
-        }
-        let parse = |key: &str| -> Result<Vec<(u64, u64)>> {
-            let Some(arr) = json["data"][key].as_array() else {
-                return Err(Error::Serialization(format!("上传进度响应缺少 {key}")));
-            };
-            arr.iter()
-                .map(|r| {
-                    match (r["offset"].as_u64(), r["length"].as_u64()) {
-                        (Some(o), Some(l)) => Ok((o, l)),
-                        _ => Err(Error::Serialization("上传进度响应里的区间格式不对".to_string())),
-                    }
-                })
-                .collect()
-        };
-        Ok(UploadStatusOutcome::Status {
-            received: parse("received")?,
-            missing: parse("missing")?,
-            completed: json["data"]["completed"].as_bool().unwrap_or(false),
-        })
+    /// 自动触发的同步（重连成功 / connect / token 刷新后重认证）。
+    async fn ensure_synced<F>(&mut self, emit: F) -> Result<()>
+    where
+        F: FnMut(SdkEvent),
+    {
+        self.ensure_synced_inner(false, emit).await.map(|_| ())
     }
 
-    async fn put_chunk(
-        &self,
-        client: &reqwest::Client,
-        base: &str,
-        token: &str,
-        offset: u64,
-        bytes: Vec<u8>,
-        digest: &str,
-    ) -> crate::resumable_upload::ChunkVerdict {
-        use crate::resumable_upload::{chunk_verdict, ChunkVerdict};
-        let resp = self
-            .with_upload_token(client.put(format!("{base}/chunk?offset={offset}")), token)
-            .header("X-Chunk-SHA256", digest)
-            .body(bytes)
-            .send()
-            .await;
-        let Ok(resp) = resp else {
-            // 连不上/超时：这一片值得再试，别把网络抖动当成协议失败。
-            return ChunkVerdict::RetryChunk;
-        };
-        // 🔴 先取 HTTP 状态再消费 body：未知码/解析失败时靠它区分「瞬时 5xx 可重试」
-        // 与「客户端 4xx 终局」，与 TS SDK 逐字一致。
-        let is_server_error = resp.status().is_server_error();
-        let code = resp
-            .json::<serde_json::Value>()
-            .await
-            .ok()
-            .and_then(|j| j["code"].as_u64())
-            .map(|c| c as u32);
-        chunk_verdict(code, is_server_error)
+    /// 宿主显式要求的同步。返回「是否真的跑了」——调用方要靠它区分
+    /// 「同步完成」和「被闸门挡住、什么都没发生」。
+    async fn ensure_synced_explicit<F>(&mut self, emit: F) -> Result<bool>
+    where
+        F: FnMut(SdkEvent),
+    {
+        self.ensure_synced_inner(true, emit).await
     }
 
-    async fn complete_upload(
-        &self,
-        client: &reqwest::Client,
-        base: &str,
-        token: &str,
-        mime_type: &str,
-        cek_b64: String,
-    ) -> Result<UploadedFileInfo> {
-        let resp = self
-            .with_upload_token(client.post(format!("{base}/complete")), token)
-            .json(&serde_json::json!({ "cek": cek_b64, "encryption_version": 1 }))
-            .send()
-            .await
-            .map_err(|e| Error::Transport(format!("complete request failed: {e}")))?;
-        let status = resp.status();
-        let envelope = resp
-            .json::<serde_json::Value>()
-            .await
-            .map_err(|e| Error::Serialization(format!("decode complete response: {e}")))?;
-        let code = envelope["code"].as_u64().unwrap_or(0) as u32;
-        if code == 20613 {
-            return Err(Error::UploadSessionGone);
-        }
-        if !status.is_success() {
-            return Err(Error::Transport(format!(
-                "complete failed: status={status} code={code} message={}",
-                envelope["message"].as_str().unwrap_or("")
-            )));
+    async fn hydrate_system_channel_messages_from_history(&mut self) -> Result<()> {
+        let channels = self.storage.list_channels(200, 0).await?;
+        for channel in channels {
+            if channel.channel_type != 1 && channel.channel_type != 0 {
+                continue;
+            }
+            let normalized_channel_type = if channel.channel_type == 0 {
+                1
+            } else {
+                channel.channel_type
+            };
+            let is_system_channel = channel.channel_name == "1"
+                || channel.channel_remark == "1"
+                || channel.channel_name == "__system_1__";
+            if !is_system_channel {
+                continue;
+            }
+            eprintln!(
+                "[SDK.actor] bootstrap hydrate system channel={} channel_type={} last_msg='{}'",
+                channel.channel_id, normalized_channel_type, channel.last_msg_content
+            );
+            let existing = self
+                .storage
+                .list_messages(channel.channel_id, normalized_channel_type, 1, 0)
+                .await?;
+            if !existing.is_empty() {
+                eprintln!(
+                    "[SDK.actor] bootstrap hydrate skip channel={} existing_local_messages={}",
+                    channel.channel_id,
+                    existing.len()
+                );
+                continue;
+            }
+
+            let req = MessageHistoryGetRequest {
+                user_id: 0,
+                channel_id: channel.channel_id,
+                before_server_message_id: None,
+                limit: Some(20),
+            };
+            let resp: MessageHistoryResponse = self
+                .rpc_call_typed(routes::message_history::GET, &req)
+                .await?;
+            eprintln!(
+                "[SDK.actor] bootstrap hydrate history channel={} messages={}",
+                channel.channel_id,
+                resp.messages.len()
+            );
+            if resp.messages.is_empty() {
+                continue;
+            }
+
+            for item in resp.messages {
+                let timestamp_ms = i64::try_from(item.timestamp).unwrap_or(i64::MAX);
+                let message_id = self
+                    .storage
+                    .upsert_remote_message_with_result(Self::history_item_to_upsert_input(
+                        &item,
+                        normalized_channel_type,
+                    ))
+                    .await?
+                    .message_id;
+                // Bootstrap history hydration should not bump unread —
+                // Bootstrap history hydration should not bump unread.
+                // Unread is derived locally from message timeline + read cursor projection.
+                let _ = self
+                    .update_channel_last_message(
+                        item.channel_id,
+                        normalized_channel_type,
+                        &item.content,
+                        timestamp_ms,
+                        message_id,
+                        Some(item.sender_id),
+                        false,
+                    )
+                    .await;
+            }
         }
-        Self::parse_uploaded_file_info(&envelope, mime_type)
+        Ok(())
     }
 
-    /// 单凭据（决策 7）：分片端点只认 `X-Upload-Token`。
-    fn with_upload_token(
-        &self,
-        req: reqwest::RequestBuilder,
-        upload_token: &str,
-    ) -> reqwest::RequestBuilder {
-        req.header("X-Upload-Token", upload_token)
+    async fn sync_channel(&mut self, channel_id: u64, channel_type: i32) -> Result<usize> {
+        // Keep channel-targeted sync aligned with the existing minimum set:
+        // channel state + timeline + read cursor + group member hydration.
+        // `user` remains a collection-scoped entity family and should not be fetched with a
+        // channel scope, otherwise the SDK just emits unsupported/no-op requests.
+        // Timeline recovery must keep using `get_difference`; `message` is not a sync_entities
+        // family in the approved existing-data sync model.
+        let scope = Some(format!("{channel_type}:{channel_id}"));
+        let mut total = 0usize;
+        total += self
+            .sync_entities("channel".to_string(), scope.clone())
+            .await?;
+        match self
+            .sync_entities("channel_read_cursor".to_string(), scope.clone())
+            .await
+        {
+            Ok(v) => total += v,
+            Err(e) if Self::is_unsupported_entity_error(&e) => {
+                self.log_unsupported_sync_skip("sync_channel", "channel_read_cursor", scope, &e);
+            }
+            Err(e) => return Err(e),
+        }
+        total += self
+            .resume_channel_difference(channel_id, channel_type)
+            .await?;
+        if channel_type == 2 {
+            match self
+                .sync_entities("group_member".to_string(), Some(channel_id.to_string()))
+                .await
+            {
+                Ok(v) => total += v,
+                Err(e) if Self::is_unsupported_entity_error(&e) => {
+                    self.log_unsupported_sync_skip(
+                        "sync_channel",
+                        "group_member",
+                        Some(channel_id.to_string()),
+                        &e,
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+            // 置顶列表不是 sync_entities 的实体族，单独拉；拉不到不影响这次同步的其它部分。
+            if let Err(e) = self.refresh_pinned_messages(channel_id, channel_type).await {
+                tracing::warn!(channel_id, error = %e, "sync_channel: pinned messages not refreshed");
+            }
+        }
+        self.clear_resume_repair_key(Self::resume_repair_channel_key(channel_id, channel_type))
+            .await;
+        Ok(total)
     }
 
-    async fn upload_file_bytes(
-        &self,
-        upload_url: &str,
-        upload_token: &str,
-        filename: &str,
-        mime_type: &str,
-        // 🔴 已经封好的那个 blob，**不在这里再加密一次**：重新加密会产出另一串字节，
-        // 与 prepare 时报的摘要对不上，服务端会拒；重试同理必须复用同一个 blob。
-        blob: Vec<u8>,
-        cek_b64: String,
-    ) -> Result<UploadedFileInfo> {
-        let part = reqwest::multipart::Part::bytes(blob)
-            .file_name(filename.to_string())
-            .mime_str(mime_type)
-            .map_err(|e| Error::Serialization(format!("invalid mime_type for upload part: {e}")))?;
-        let form = reqwest::multipart::Form::new()
-            .part("file", part)
-            .text("encryption_version", "1")
-            .text("cek", cek_b64);
-        let response = reqwest::Client::new()
-            .post(upload_url)
-            .header("X-Upload-Token", upload_token)
-            .multipart(form)
-            .send()
+    async fn sync_all_channels(&mut self) -> Result<usize> {
+        let mut total = 0usize;
+        total += self.sync_entities("friend".to_string(), None).await?;
+        total += self.sync_entities("group".to_string(), None).await?;
+        total += self.sync_entities("channel".to_string(), None).await?;
+        total += self.sync_entities("user".to_string(), None).await?;
+        match self
+            .sync_entities("channel_read_cursor".to_string(), None)
             .await
-            .map_err(|e| Error::Transport(format!("upload request failed: {e}")))?;
-        if !response.status().is_success() {
-            let status = response.status();
-            let text = response.text().await.unwrap_or_else(|_| String::new());
-            return Err(Error::Transport(format!(
-                "upload failed: status={} body={}",
-                status, text
-            )));
+        {
+            Ok(v) => total += v,
+            Err(e) if Self::is_unsupported_entity_error(&e) => {
+                self.log_unsupported_sync_skip(
+                    "sync_all_channels",
+                    "channel_read_cursor",
+                    None,
+                    &e,
+                );
+            }
+            Err(e) => return Err(e),
         }
-        let envelope = response
-            .json::<serde_json::Value>()
-            .await
-            .map_err(|e| Error::Serialization(format!("decode upload response: {e}")))?;
-        let info = Self::parse_uploaded_file_info(&envelope, mime_type)?;
-        // 传成了才计：失败的那次会重试，算进去等于把同一份内容数很多遍。
-        // 缩略图走同一个接口，靠规范文件名区分（见 media_store::payload_filename）。
-        let counter = if filename.starts_with("thumb") {
-            &self.attachment_transfers.thumbnail_uploads
-        } else {
-            &self.attachment_transfers.body_uploads
-        };
-        counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        Ok(info)
-    }
-
-    /// 把上传 / 秒传取用的响应解析成同一个结构。
-    ///
-    /// 两条路径的响应形状在服务端就是逐字段一致的，这里也只写一份——
-    /// 写两份解析，迟早有一份先于另一份腐坏。
-    fn parse_uploaded_file_info(
-        envelope: &serde_json::Value,
-        fallback_mime: &str,
-    ) -> Result<UploadedFileInfo> {
-        // server `SERVICE_RESPONSE_ENVELOPE_SPEC` v1.1：HTTP 接口统一 `{code, message, data}`；
-        // RPC 直接给业务对象。两种都收。
-        let code = envelope.get("code").and_then(|v| v.as_u64()).unwrap_or(0);
-        if code != 0 {
-            let message = envelope
-                .get("message")
-                .and_then(|v| v.as_str())
-                .unwrap_or("upload failed");
-            return Err(Error::Transport(format!(
-                "upload failed: code={code} message={message}"
-            )));
+        let mut group_offset = 0usize;
+        let group_page_size = 500usize;
+        loop {
+            let groups = self
+                .storage
+                .list_groups(group_page_size, group_offset)
+                .await?;
+            if groups.is_empty() {
+                break;
+            }
+            for group in groups.iter() {
+                match self
+                    .sync_entities("group_member".to_string(), Some(group.group_id.to_string()))
+                    .await
+                {
+                    Ok(v) => total += v,
+                    Err(e) if Self::is_unsupported_entity_error(&e) => {
+                        self.log_unsupported_sync_skip(
+                            "sync_all_channels",
+                            "group_member",
+                            Some(group.group_id.to_string()),
+                            &e,
+                        );
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            if groups.len() < group_page_size {
+                break;
+            }
+            group_offset += group_page_size;
         }
-        let value = envelope.get("data").cloned().unwrap_or_else(|| envelope.clone());
-        let file_id = value
-            .get("file_id")
-            .and_then(|v| {
-                v.as_str()
-                    .map(|s| s.to_string())
-                    .or_else(|| v.as_u64().map(|n| n.to_string()))
-                    .or_else(|| v.as_i64().map(|n| n.to_string()))
-            })
-            .ok_or_else(|| Error::Serialization("upload response missing file_id".to_string()))?;
-        let storage_source_id = value
-            .get("storage_source_id")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(0) as u32;
-        let file_url = value
-            .get("file_url")
-            .and_then(|v| v.as_str())
-            .unwrap_or_default()
-            .to_string();
-        let thumbnail_url = value
-            .get("thumbnail_url")
-            .and_then(|v| v.as_str())
-            .map(|s| s.to_string());
-        let file_size = value.get("file_size").and_then(|v| v.as_u64()).unwrap_or(0);
-        let original_size = value.get("original_size").and_then(|v| v.as_u64());
-        let width = value
-            .get("width")
-            .and_then(|v| v.as_u64())
-            .map(|n| n as u32);
-        let height = value
-            .get("height")
-            .and_then(|v| v.as_u64())
-            .map(|n| n as u32);
-        let resp_mime = value
-            .get("mime_type")
-            .and_then(|v| v.as_str())
-            .unwrap_or(fallback_mime)
-            .to_string();
-        Ok(UploadedFileInfo {
-            file_id,
-            storage_source_id,
-            file_url,
-            thumbnail_url,
-            file_size,
-            original_size,
-            width,
-            height,
-            mime_type: resp_mime,
-        })
+        Ok(total)
     }
 
-    /// 秒传命中：拿 token 换**自己的** file_id，一个字节都不传。
-    async fn claim_existing_file(&mut self, token: &str, sha256: &str) -> Result<UploadedFileInfo> {
-        let value: serde_json::Value = self
-            .rpc_call_json(
-                "file/claim_existing".to_string(),
-                serde_json::json!({ "token": token, "sha256": sha256 }),
+    async fn batch_get_presence(&mut self, user_ids: Vec<u64>) -> Result<Vec<PresenceStatus>> {
+        if user_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let timeout = self.timeout();
+        let req = PresenceBatchStatusRequest { user_ids };
+        let request = RpcRequest {
+            route: routes::presence::STATUS_GET.to_string(),
+            body: serde_json::to_vec(&req).map_err(|e| {
+                Error::Serialization(format!("encode batch_get_presence body: {e}"))
+            })?,
+        };
+        let payload = encode_message(&request)
+            .map_err(|e| Error::Serialization(format!("encode batch_get_presence rpc: {e}")))?;
+        let raw = self
+            .request_bytes(
+                Bytes::from(payload),
+                MessageType::RpcRequest as u8,
+                timeout,
+                "rpc batch_get_presence",
             )
             .await?;
-        let info = Self::parse_uploaded_file_info(&value, "application/octet-stream")?;
-        // 成功才计：失败的 claim 会退回整传，算进去会让「省了多少带宽」虚高。
-        self.attachment_transfers
-            .claims
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        Ok(info)
+        let rpc_resp: RpcResponse = decode_message(&raw)
+            .map_err(|e| Error::Serialization(format!("decode batch_get_presence rpc: {e}")))?;
+        if rpc_resp.code != 0 {
+            return Err(Error::Auth(rpc_resp.message));
+        }
+        let body = rpc_resp
+            .data
+            .ok_or_else(|| Error::Serialization("empty batch_get_presence data".into()))?;
+        let response: PresenceBatchStatusResponse = serde_json::from_slice(&body).map_err(|e| {
+            Error::Serialization(format!("decode batch_get_presence response: {e}"))
+        })?;
+        Ok(self.cache_presence_response(response))
     }
 
-    /// 发一份附件：**封装一次** → 预检 → 命中就换 id，未命中才传那同一个 blob。
-    ///
-    /// 顺序是整件事的关键：秒传按「最终上传字节」判重，而加密用随机 CEK/nonce，
-    /// 所以必须先封装、对封装结果求摘要，再拿这个摘要去预检。预检之后重新加密，
-    /// 字节就变了，命中率恒为 0。
-    async fn send_one_attachment(
+    async fn send_typing(
         &mut self,
-        user_id: u64,
-        filename: String,
-        mime_type: String,
-        file_type: String,
-        plaintext: Vec<u8>,
-        // 封装结果缓存到哪儿。同一条 outbox 任务重试时读回它，绝不重新封装。
-        sealed_cache: &std::path::Path,
-        // 进度事件归到哪条消息名下。UI 靠它更新对应的气泡。
-        local_message_id: &str,
-    ) -> Result<(UploadedFileInfo, String)> {
-        // 封装一次，之后的分支都用它。
-        let (blob, cek_b64, sha256) = Self::seal_once(sealed_cache, &plaintext)?;
-        let payload = SealedPayload {
-            blob,
-            cek_b64,
-            sha256,
+        channel_id: u64,
+        channel_type: i32,
+        is_typing: bool,
+        action_type: TypingActionType,
+    ) -> Result<()> {
+        let channel_type = u8::try_from(channel_type)
+            .map_err(|_| Error::InvalidState(format!("invalid channel_type: {channel_type}")))?;
+        let timeout = self.timeout();
+        let req = TypingIndicatorRequest {
+            channel_id,
+            channel_type,
+            is_typing,
+            action_type: action_type.into_proto(),
         };
-        let server_identity = self.server_identity_for_uploads();
-        let mut io = LiveAttachmentUploadIo {
-            state: self,
-            user_id,
-            filename,
-            mime_type,
-            file_type,
-            local_message_id: local_message_id.to_string(),
-            sealed_cache,
-            // 服务器身份用「这次上传要打到哪个 base」——切环境（本地/生产）之后
-            // 旧会话在新服务器上根本不存在，必须作废重来。
-            server_identity,
+        let request = RpcRequest {
+            route: routes::presence::TYPING.to_string(),
+            body: serde_json::to_vec(&req)
+                .map_err(|e| Error::Serialization(format!("encode send_typing body: {e}")))?,
         };
-        Self::plan_attachment_upload(&mut io, &payload).await
+        let payload = encode_message(&request)
+            .map_err(|e| Error::Serialization(format!("encode send_typing rpc: {e}")))?;
+        let raw = self
+            .request_bytes(
+                Bytes::from(payload),
+                MessageType::RpcRequest as u8,
+                timeout,
+                "rpc send_typing",
+            )
+            .await?;
+        let rpc_resp: RpcResponse = decode_message(&raw)
+            .map_err(|e| Error::Serialization(format!("decode send_typing rpc: {e}")))?;
+        if rpc_resp.code != 0 {
+            return Err(Error::Auth(rpc_resp.message));
+        }
+        Ok(())
     }
 
-    async fn upload_callback(
+    /// 订阅频道事件（typing / presence 等状态事件通过此通道接收）
+    /// token: 可选，Room 类型订阅时传入业务 API 签发的 ticket（JWT）
+    async fn subscribe_channel(
         &mut self,
-        user_id: u64,
-        token: &str,
-        uploaded: &UploadedFileInfo,
+        channel_id: u64,
+        channel_type: u8,
+        token: Option<String>,
     ) -> Result<()> {
-        let payload = serde_json::json!({
-            "token": token,
-            "file_id": uploaded.file_id,
-            "file_url": uploaded.file_url,
-            "thumbnail_url": uploaded.thumbnail_url,
-            "file_size": uploaded.file_size,
-            "original_size": uploaded.original_size,
-            "mime_type": uploaded.mime_type,
-            "width": uploaded.width,
-            "height": uploaded.height,
-            "user_id": user_id,
-            "status": "uploaded",
-        });
-        let value: serde_json::Value = self
-            .rpc_call_json(routes::file::UPLOAD_CALLBACK.to_string(), payload)
+        let timeout = self.timeout();
+        let req = SubscribeRequest {
+            setting: 0,
+            local_message_id: 0,
+            channel_id,
+            channel_type,
+            action: 1, // SUBSCRIBE
+            param: token.clone().unwrap_or_default(),
+        };
+        let payload = encode_message(&req)
+            .map_err(|e| Error::Serialization(format!("encode subscribe_channel: {e}")))?;
+        let raw = self
+            .request_bytes(
+                Bytes::from(payload),
+                MessageType::SubscribeRequest as u8,
+                timeout,
+                "subscribe_channel",
+            )
             .await?;
-        if let Some(ok) = value.as_bool() {
-            if ok {
-                return Ok(());
-            }
-            return Err(Error::Transport("upload callback rejected".to_string()));
+        let resp: SubscribeResponse = decode_message(&raw)
+            .map_err(|e| Error::Serialization(format!("decode subscribe_channel resp: {e}")))?;
+        if resp.reason_code != 0 {
+            return Err(Error::Auth(format!(
+                "subscribe_channel failed: reason_code={}",
+                resp.reason_code
+            )));
         }
+        // 记入 desired-subscription 注册表，供重连后 replay（presence/typing 恢复的基础）。
+        self.active_subscriptions
+            .insert((channel_id, channel_type), token);
         Ok(())
     }
 
-    /// 附件消息的 wire content —— **唯一**一处构造。
-    ///
-    /// 两条路径都走它：本地文件上传完之后，和复用一份服务端已有附件时。分成两份写
-    /// 的话，接收端能不能渲染就取决于发送方走了哪条路——而那种分叉只会在用户手里
-    /// 被发现。
-    /// 消息里该显示的文件名。
-    ///
-    /// 上传用的名字是规范化过的 `payload.{ext}`——那是磁盘布局，不是内容。用户看见的
-    /// 应该是这份文件本来的名字，它由客户端在建占位符时写进 `extra.file_name`
-    /// （见 protocol 的 `LocalAttachmentMetadata`）。没有就退回上传名。
-    ///
-    /// 🔴 只取最后一段：名字可能来自远端消息，不许带路径。
-    fn wire_display_filename(extra: &str, upload_filename: &str) -> String {
-        serde_json::from_str::<serde_json::Value>(extra)
-            .ok()
-            .and_then(|v| {
-                v.get("file_name")
-                    .and_then(|n| n.as_str())
-                    .map(|s| s.rsplit(['/', '\\']).next().unwrap_or(s).trim().to_string())
-            })
-            .filter(|s| !s.is_empty() && s != "." && s != "..")
-            .unwrap_or_else(|| upload_filename.to_string())
-    }
-
-    fn build_attachment_wire_content(input: AttachmentWireInput<'_>) -> serde_json::Value {
-        // 图片消息协议必须带缩略图引用（server 校验拒绝缺失）。缩略图未产出时把
-        // 原图 file 引用为缩略图（接收端按缩略图链路下载原图渲染），与 TS SDK 一致；
-        // 视频/文件不强制。
-        let (thumbnail_file_id, thumbnail_url) = match input.thumbnail {
-            Some((id, url)) => (Some(id), url.to_string()),
-            None if input.file_type == "image" => {
-                (Some(input.file_id), input.file_url.to_string())
-            }
-            None => (None, String::new()),
+    /// Channel Transfer client→app RPC. See `02-server/CHANNEL_TRANSFER_SPEC.md`.
+    /// `request_id` is generated locally (snowflake → string) and matched at the
+    /// transport layer; the wire `TransferResponse` is decoded into `TransferReply`.
+    async fn transfer_channel(
+        &mut self,
+        channel_id: u64,
+        route: String,
+        body: Vec<u8>,
+        timeout_ms: u64,
+    ) -> Result<TransferReply> {
+        let request_id = self
+            .snowflake
+            .next_id()
+            .map_err(|e| Error::Storage(format!("generate transfer request_id failed: {e:?}")))?
+            .to_string();
+        let req = TransferRequest {
+            request_id,
+            channel_id,
+            route: route.clone(),
+            body,
         };
+        let payload = encode_message(&req)
+            .map_err(|e| Error::Serialization(format!("encode transfer_channel: {e}")))?;
+        let timeout = Duration::from_millis(timeout_ms.max(1));
+        let raw = self
+            .request_bytes(
+                Bytes::from(payload),
+                MessageType::TransferRequest as u8,
+                timeout,
+                "transfer_channel",
+            )
+            .await?;
+        let resp: TransferResponse = decode_message(&raw)
+            .map_err(|e| Error::Serialization(format!("decode transfer_channel resp: {e}")))?;
+        Ok(TransferReply {
+            request_id: resp.request_id,
+            channel_id: resp.channel_id,
+            code: resp.code,
+            message: resp.message,
+            data: resp.data.unwrap_or_default(),
+        })
+    }
 
-        let mut content = if let Some(thumb_file_id) = thumbnail_file_id {
-            // Scheme B：缩略图也是独立 file，接收端走 thumbnail_file_id -> file/get_url -> cek
-            // 统一下载解密。**CEK 永不进消息 metadata**。thumbnail_url 仅作 legacy 明文 fallback。
-            serde_json::json!({
-                "file_type": input.file_type,
-                "file_id": input.file_id,
-                "thumbnail_file_id": thumb_file_id,
-                "filename": input.filename,
-                "mime_type": input.mime_type,
-                "storage_source_id": input.storage_source_id,
-                "file_size": input.file_size,
-                "file_url": input.file_url,
-                "thumbnail_url": thumbnail_url,
-                // 原图尺寸（客户端加密前解码得到）。加密后服务端拿不到密文图片尺寸，
-                // 必须客户端带上，否则接收端 UI 没法按原比例渲染气泡（只能正方形兜底）。
-                "width": input.width.unwrap_or(0),
-                "height": input.height.unwrap_or(0),
-            })
-        } else {
-            serde_json::json!({
-                "file_type": input.file_type,
-                "file_id": input.file_id,
-                "filename": input.filename,
-                "mime_type": input.mime_type,
-                "storage_source_id": input.storage_source_id,
-                "file_size": input.file_size,
-                "file_url": input.file_url,
-                "thumbnail_url": input.main_thumbnail_url,
-                "width": input.width.unwrap_or(0),
-                "height": input.height.unwrap_or(0),
-            })
+    /// 取消订阅频道事件
+    async fn unsubscribe_channel(&mut self, channel_id: u64, channel_type: u8) -> Result<()> {
+        let timeout = self.timeout();
+        let req = SubscribeRequest {
+            setting: 0,
+            local_message_id: 0,
+            channel_id,
+            channel_type,
+            action: 2, // UNSUBSCRIBE
+            param: String::new(),
         };
-
-        // 按消息类型独立合并 metadata：Voice 与 Video 的协议形态不同，不共用逻辑。
-        if input.message_type == (privchat_protocol::message::ContentMessageType::Voice as i32) {
-            Self::merge_voice_metadata(input.extra, &mut content);
-        } else if input.message_type
-            == (privchat_protocol::message::ContentMessageType::Video as i32)
-        {
-            Self::merge_video_metadata(input.extra, &mut content);
+        let payload = encode_message(&req)
+            .map_err(|e| Error::Serialization(format!("encode unsubscribe_channel: {e}")))?;
+        let raw = self
+            .request_bytes(
+                Bytes::from(payload),
+                MessageType::SubscribeRequest as u8,
+                timeout,
+                "unsubscribe_channel",
+            )
+            .await?;
+        let resp: SubscribeResponse = decode_message(&raw)
+            .map_err(|e| Error::Serialization(format!("decode unsubscribe_channel resp: {e}")))?;
+        if resp.reason_code != 0 {
+            return Err(Error::Auth(format!(
+                "unsubscribe_channel failed: reason_code={}",
+                resp.reason_code
+            )));
         }
-
-        content
+        // 从 desired-subscription 注册表移除：重连后不再 replay 该频道。
+        self.active_subscriptions
+            .remove(&(channel_id, channel_type));
+        Ok(())
     }
 
-    /// 这次发送该用哪份封装缓存。
-    ///
-    /// 来源是接手后的成品时，用**源目录**里那份——它是收附件时留下的原始密文。
-    /// 复用它，秒传预检就能命中，一个字节都不用传；重新封装则会产出另一串字节，
-    /// 按定义就是另一个物理文件。
-    ///
-    /// 缓存过期或从来没有（老消息、下载时没留）就退回本条消息自己的目录，照常封装
-    /// 上传——功能不受影响，只是这次省不掉。
-    /// 封装缓存只有**两个文件都在**才算数：`seal_once` 拿 metadata 当提交标记，
-    /// 缺一个就当没有——缺了还用，就会拿半份缓存去上传。
-    fn committed_sealed_cache(path: std::path::PathBuf) -> Option<std::path::PathBuf> {
-        if path.exists() && path.with_extension("sealed.json").exists() {
-            Some(path)
-        } else {
-            None
+    /// 重连后重放所有活跃订阅（desired subscriptions）。服务端 subscribe_manager 随旧会话清空，
+    /// 不重放则 presence_changed / typing / room 广播全断。best-effort：单个失败不影响其它。
+    /// 注意直接发 SubscribeRequest，不复用 subscribe_channel（避免借用 active_subscriptions 同时改它）。
+    async fn replay_subscriptions(&mut self) {
+        if self.active_subscriptions.is_empty() {
+            return;
+        }
+        let subs: Vec<((u64, u8), Option<String>)> = self
+            .active_subscriptions
+            .iter()
+            .map(|(k, v)| (*k, v.clone()))
+            .collect();
+        if realtime_trace_enabled() {
+            eprintln!(
+                "[SDK_RESUBSCRIBE] replaying {} active subscriptions after reconnect",
+                subs.len()
+            );
+        }
+        let timeout = self.timeout();
+        for ((channel_id, channel_type), token) in subs {
+            let req = SubscribeRequest {
+                setting: 0,
+                local_message_id: 0,
+                channel_id,
+                channel_type,
+                action: 1, // SUBSCRIBE
+                param: token.unwrap_or_default(),
+            };
+            let Ok(payload) = encode_message(&req) else {
+                continue;
+            };
+            if let Err(e) = self
+                .request_bytes(
+                    Bytes::from(payload),
+                    MessageType::SubscribeRequest as u8,
+                    timeout,
+                    "replay_subscribe",
+                )
+                .await
+            {
+                eprintln!(
+                    "[SDK_RESUBSCRIBE] replay failed channel_id={} type={} err={}",
+                    channel_id, channel_type, e
+                );
+            }
         }
     }
 
-    fn sealed_cache_for_send(
-        content: &str,
-        user_root: &std::path::Path,
-        files_dir: &std::path::Path,
-        cache_name: &str,
-    ) -> std::path::PathBuf {
-        let own = files_dir.join(cache_name);
-        let raw = content.strip_prefix("file://").unwrap_or(content);
-
-        // 🔴 收到的附件带着**原始密文**的旁挂缓存（下载时留下的 `{文件名}.sealed`）。
-        // 认它，重发才可能秒传：重新封装会用新的随机 CEK/nonce，密文一变摘要就变，
-        // 服务端认不出这是同一份内容，只能整传。
-        //
-        // 下载落点不一定在托管目录（App 转发一条还没下载过的附件时落在 cache/），
-        // 所以判据是「旁边有没有这份缓存」，不是「路径在不在托管树里」。
-        //
-        // 🔴 只有主文件能认它：旁挂缓存装的是**这个文件**的密文，缩略图是另一份内容。
-        // 缩略图先于主文件上传，若也认这条路径，会拿缩略图的密文覆盖掉主文件的缓存，
-        // 秒传反而永远打不中。
-        if cache_name == "body.sealed" {
-            let sidecar = std::path::Path::new(raw).file_name().and_then(|name| {
-                let p = std::path::Path::new(raw)
-                    .with_file_name(format!("{}.sealed", name.to_string_lossy()));
-                Self::committed_sealed_cache(p)
-            });
-            if let Some(sidecar) = sidecar {
-                return sidecar;
+    fn build_send_message_request_with_content(
+        &self,
+        message: &StoredMessage,
+        local_message_id: u64,
+        content: String,
+    ) -> Result<privchat_protocol::protocol::SendMessageRequest> {
+        let message_type = u32::try_from(message.message_type).map_err(|_| {
+            Error::InvalidState(format!("invalid message_type: {}", message.message_type))
+        })?;
+        // Build envelope in legacy (Value-based) shape to keep the existing
+        // flexible content-detection logic intact, then bridge to the typed
+        // wire envelope at the FlatBuffers encode boundary below.
+        let extra_envelope = Self::decode_legacy_message_envelope(&message.extra)
+            .and_then(|value| serde_json::from_value::<LocalMessagePayloadEnvelope>(value).ok());
+        let mut envelope = extra_envelope.unwrap_or_else(|| LocalMessagePayloadEnvelope {
+            content: content.clone(),
+            metadata: serde_json::from_str::<serde_json::Value>(&message.extra)
+                .ok()
+                .and_then(|value| if value.is_null() { None } else { Some(value) }),
+            reply_to_message_id: None,
+            mentioned_user_ids: None,
+            message_source: None,
+        });
+
+        if let Some(content_json) = Self::decode_legacy_message_envelope(&content) {
+            if let Ok(parsed_envelope) =
+                serde_json::from_value::<LocalMessagePayloadEnvelope>(content_json)
+            {
+                envelope = parsed_envelope;
+            }
+        } else if let Ok(content_json) = serde_json::from_str::<serde_json::Value>(&content) {
+            if content_json
+                .get("file_id")
+                .or_else(|| content_json.get("thumbnail_file_id"))
+                .is_some()
+            {
+                // Backward compatibility: attachment callers may still pass raw metadata json.
+                envelope.content = Self::attachment_caption(&message.extra).unwrap_or_default();
+                envelope.metadata = Some(content_json);
             }
         }
 
-        let Some(source) = Self::managed_source_path(content, user_root) else {
-            return own;
-        };
-        let Some(dir) = source.parent() else {
-            return own;
-        };
-        Self::committed_sealed_cache(dir.join(cache_name)).unwrap_or(own)
-    }
-
-    /// 这份文件是不是**接手后的成品**（收到的附件、被转发的原件）。
-    ///
-    /// 托管附件落在 `{user_root}/files/{yyyymm}/{message_id}/`（见
-    /// `media_store::get_message_dir`）。用户刚从相册选的文件在这之外。
-    ///
-    /// 判据用路径而不是另存一份标记：这个区分本身就是真的，不需要再维护一份可能
-    /// 与事实不符的元数据。
-    fn managed_source_path(content: &str, user_root: &std::path::Path) -> Option<std::path::PathBuf> {
-        let path = content.strip_prefix("file://").unwrap_or(content);
-        let path = std::path::Path::new(path);
-        path.starts_with(user_root.join("files")).then(|| path.to_path_buf())
-    }
+        // Wire boundary: bridge the legacy Value-based envelope to the typed
+        // FlatBuffers envelope and encode as binary.
+        let content_type =
+            ContentMessageType::from_u32(message_type).unwrap_or(ContentMessageType::Text);
+        let typed_envelope = MessagePayloadEnvelope::from_legacy(&envelope, content_type);
+        let payload = privchat_protocol::encode_message(&typed_envelope)
+            .map_err(|e| Error::Serialization(format!("encode send payload: {e}")))?;
 
-    fn source_is_already_managed(content: &str, user_root: &std::path::Path) -> bool {
-        Self::managed_source_path(content, user_root).is_some()
+        Ok(privchat_protocol::protocol::SendMessageRequest {
+            setting: privchat_protocol::protocol::MessageSetting {
+                need_receipt: true,
+                signal: 0,
+            },
+            client_seq: 1,
+            local_message_id,
+            stream_no: format!("stream_{local_message_id}"),
+            channel_id: message.channel_id,
+            message_type,
+            expire: 3600,
+            from_uid: message.from_uid,
+            topic: "chat".to_string(),
+            payload,
+        })
     }
 
-    async fn process_outbound_file(
+    async fn direct_send_message(
         &mut self,
-        message: &StoredMessage,
-        local_message_id: u64,
-        payload: Vec<u8>,
+        req: privchat_protocol::protocol::SendMessageRequest,
     ) -> Result<SendMessageResponse> {
-        // 命令里不带文件字节。附件由 SDK 托管在本地，`message.content` 就是它的
-        // 路径——把几十上百 MB 复制进 SQLite 只是同一份数据存两遍，还要跟着事务
-        // 一起写。空 payload 因此是**正常情况**：从托管路径读。
-        //
-        // 旧 sled 队列里的项内嵌了字节，搬过来后仍然带着，按原样发。
-        let payload = if payload.is_empty() {
-            let path = message
-                .content
-                .strip_prefix("file://")
-                .unwrap_or(&message.content);
-            // 异步读：单 actor 跑着收消息、同步和事件发布，几十上百 MB 的
-            // 附件用同步读会把这些一起卡住。
-            tokio::fs::read(path).await.map_err(|e| {
-                Error::InvalidState(format!("attachment source unreadable at {path}: {e}"))
-            })?
-        } else {
-            payload
-        };
-        if payload.is_empty() {
+        if req.local_message_id == 0 {
             return Err(Error::InvalidState(
-                "attachment payload is empty".to_string(),
+                "local_message_id must be non-zero".to_string(),
             ));
         }
-        // Extract original filename from content (before server overwrites it)
-        let original_filename = std::path::Path::new(&message.content)
-            .file_name()
-            .and_then(|v| v.to_str())
-            .map(|s| s.to_string())
-            .filter(|s| !s.is_empty() && !s.starts_with('['));
-
-        // Determine MIME from original filename (best effort)
-        let mime_type = original_filename
-            .as_deref()
-            .map(|n| Self::guess_mime_type(n).to_string())
-            .unwrap_or_else(|| {
-                Self::default_mime_for_message_type(message.message_type).to_string()
+        let request_data = encode_message(&req)
+            .map_err(|e| Error::Serialization(format!("encode send request: {e}")))?;
+        let timeout = self.timeout();
+        let transport = match self.transport.as_mut() {
+            Some(t) => t,
+            None => {
+                let transition = self.apply_transport_health(false);
+                self.push_connection_transition_event(transition);
+                return Err(self.network_disconnected_error());
+            }
+        };
+        let opt = RequestOptions::new()
+            .biz_type(MessageType::SendMessageRequest as u8)
+            .timeout(timeout);
+        let raw = transport
+            .request_with_options(Bytes::from(request_data), opt)
+            .await
+            .map_err(|e| self.handle_transport_request_error("direct send message", e))?;
+        let resp: SendMessageResponse = decode_message(&raw)
+            .map_err(|e| Error::Serialization(format!("decode send response: {e}")))?;
+        if resp.reason_code != 0 {
+            return Err(Error::Server {
+                code: resp.reason_code,
+                message: format!("send message failed: reason_code={}", resp.reason_code),
             });
+        }
+        if resp.server_message_id == 0 {
+            return Err(Error::Serialization(
+                "send message response missing server_message_id".to_string(),
+            ));
+        }
+        Ok(resp)
+    }
 
-        // Canonical payload filename: payload.{ext} (Spec §7.5 v2)
-        let filename =
-            media_store::payload_filename_with_fallback(&mime_type, original_filename.as_deref());
-
-        let file_type =
-            Self::guess_file_type(message.message_type, &filename, &mime_type).to_string();
-
-        let storage_paths = self.storage.get_storage_paths().await?;
-        let user_root = PathBuf::from(&storage_paths.user_root);
-        let files_dir =
-            media_store::get_message_dir(&user_root, message.message_id as i64, message.created_at);
-        std::fs::create_dir_all(&files_dir)
-            .map_err(|e| Error::Storage(format!("create files dir failed: {e}")))?;
-
-        let body_path = files_dir.join(&filename);
-        let meta_path = files_dir.join(media_store::META_FILENAME);
-        std::fs::write(&body_path, &payload)
-            .map_err(|e| Error::Storage(format!("write body file failed: {e}")))?;
-        let mut upload_payload = payload;
-        let mut upload_filename = filename.clone();
-        let mut body_size = upload_payload.len() as u64;
-
-        let mut source_width = None;
-        let mut source_height = None;
-        let mut thumb_upload: Option<(PathBuf, String, String)> = None;
-        if file_type == "image" {
-            if let Ok(img) = Self::decode_image_oriented(&body_path) {
-                source_width = Some(img.width());
-                source_height = Some(img.height());
+    /// Extract mime_type from content or extra JSON.
+    /// Tries content first, then extra. Looks for "mime_type" key in metadata or flat format.
+    fn extract_mime_type_from_json(content: &str, extra: &str) -> Option<String> {
+        for src in [content, extra] {
+            if src.is_empty() {
+                continue;
             }
-            let canonical_thumb = files_dir.join(media_store::THUMB_FILENAME);
-            let managed_thumb = Self::managed_source_path(&message.content, &user_root)
-                .and_then(|source| source.parent().map(|dir| dir.join(media_store::THUMB_FILENAME)))
-                .filter(|path| path.exists());
-            // 🔴 **同一个路径不能拷给自己**。
-            //
-            // 重试同一条消息时，`managed_source_path` 解析出来的就是这条消息自己的
-            // 目录，于是 source 与 canonical 是同一个文件——`fs::copy` 会先把目标
-            // 截断，结果 `thumb.webp` 变成 0 字节。第一次发送留下的有效缩略图就这样
-            // 被自己的重试毁掉，而且毁得很隐蔽：meta.json 还记着上一次的真实大小，
-            // 磁盘上却是空文件，之后解码失败、正文密文根本来不及生成。
-            //
-            // 复用之前还要验一遍能不能解码：上一次可能正好死在写缩略图的中途。
-            // 复用不了就当作没有，走下面的重新生成——那条路本来就允许「没有缩略图」。
-            let reused = managed_thumb.and_then(|source_thumb| {
-                if source_thumb != canonical_thumb {
-                    if let Err(e) = std::fs::copy(&source_thumb, &canonical_thumb) {
-                        eprintln!("[SDK.media] 复制托管缩略图失败，改为重新生成: {e}");
-                        return None;
-                    }
-                }
-                let size = std::fs::metadata(&canonical_thumb).ok()?.len();
-                if size == 0 {
-                    eprintln!("[SDK.media] 现有缩略图是空文件，重新生成");
-                    return None;
+            if let Ok(json) = serde_json::from_str::<serde_json::Value>(src) {
+                // Envelope: {"metadata":{"mime_type":"image/jpeg"}}
+                if let Some(mime) = json
+                    .get("metadata")
+                    .and_then(|m| m.get("mime_type"))
+                    .and_then(|v| v.as_str())
+                    .filter(|s| !s.is_empty())
+                {
+                    return Some(mime.to_string());
                 }
-                match Self::decode_image_oriented(&canonical_thumb) {
-                    Ok(img) => Some((img.width(), img.height(), size)),
-                    Err(e) => {
-                        eprintln!("[SDK.media] 现有缩略图解不开，重新生成: {e}");
-                        None
-                    }
+                // Flat: {"mime_type":"image/jpeg"}
+                if let Some(mime) = json
+                    .get("mime_type")
+                    .and_then(|v| v.as_str())
+                    .filter(|s| !s.is_empty())
+                {
+                    return Some(mime.to_string());
                 }
-            });
+            }
+        }
+        None
+    }
 
-            let (w, h, size) = if let Some(dims) = reused {
-                dims
-            } else {
-                // 托管来源但没有现成缩略图时**照样生成**，不报错。
-                //
-                // 「没有缩略图」是正常状态：自动下载可能失败，`thumb_status=3`
-                // 本身就是正式态，老消息也可能压根没有独立缩略图。在这里硬报错，
-                // 这类转发就永久发不出去了。
-                //
-                // 而且生成缩略图不算「二次处理」——被转发的那个文件一个字节都没动，
-                // 缩略图是协议要求的另一件小产物。要避免的是对主文件重新压缩。
-                Self::generate_image_thumbnail_sync(&body_path, &canonical_thumb, 320, 85)?
-            };
-            let _ = self
-                .storage
-                .update_thumb_status(message.message_id, 1)
-                .await;
-            thumb_upload = Some((
-                canonical_thumb,
-                "image/webp".to_string(),
-                media_store::THUMB_FILENAME.to_string(),
-            ));
-            let meta = MediaMeta {
-                source: MediaSourceMeta {
-                    original_filename: original_filename.clone().unwrap_or_default(),
-                    mime: mime_type.clone(),
-                    width: source_width,
-                    height: source_height,
-                    file_size: Some(body_size),
-                },
-                thumbnail: Some(MediaThumbnailMeta {
-                    width: Some(w),
-                    height: Some(h),
-                    file_size: Some(size),
-                    mime: Some("image/webp".to_string()),
-                }),
-                processing: Some(MediaProcessingMeta {
-                    strategy: Some("client_preprocess".to_string()),
-                    created_at: Some(chrono::Utc::now().timestamp()),
-                }),
-            };
-            std::fs::write(
-                &meta_path,
-                serde_json::to_string(&meta)
-                    .map_err(|e| Error::Serialization(format!("encode media meta failed: {e}")))?,
-            )
-            .map_err(|e| Error::Storage(format!("write media meta failed: {e}")))?;
-        } else if file_type == "video" {
-            // 🔴 来源已经在托管目录里 = 这是**接手后的成品**（收到的附件、转发的
-            // 原件），不再做第二次处理。转发一次压一次，几跳之后画质就没了，而且
-            // 每压一次字节都变，本来还能命中的秒传也一起失效。
-            //
-            // 判据是路径而不是另存一份标记：用户刚从相册选的文件在托管目录外，
-            // 收到并落盘的在里面，这个区分本身就是真的。
-            let source_is_managed = Self::source_is_already_managed(&message.content, &user_root);
-            if source_is_managed {
-                eprintln!(
-                    "[SDK.actor] process_outbound_file: source already managed, skipping re-compress"
-                );
+    /// Extract thumbnail_url from message content JSON (supports envelope and flat formats).
+    fn extract_thumbnail_url(content: &str) -> Option<String> {
+        let json: serde_json::Value = serde_json::from_str(content).ok()?;
+        // Envelope format: {"content":"...","metadata":{"thumbnail_url":"..."}}
+        if let Some(url) = json
+            .get("metadata")
+            .and_then(|m| m.get("thumbnail_url"))
+            .and_then(|v| v.as_str())
+        {
+            if !url.is_empty() {
+                return Some(url.to_string());
             }
-            if let Some(hook) = self.video_process_hook.as_ref().filter(|_| !source_is_managed) {
-                // Hook writes compressed output in place to `payload.{ext}`.
-                // On failure the implementation must leave the file untouched.
-                let compressed_ok =
-                    hook(MediaProcessOp::Compress, &body_path, &meta_path, &body_path)
-                        .map_err(|e| Error::Storage(format!("video compress hook failed: {e}")))?;
-                if compressed_ok {
-                    upload_payload = std::fs::read(&body_path).map_err(|e| {
-                        Error::Storage(format!("read compressed video failed: {e}"))
-                    })?;
-                    body_size = upload_payload.len() as u64;
-                    upload_filename = filename.clone();
-                }
+        }
+        // Flat format: {"thumbnail_url":"..."}
+        if let Some(url) = json.get("thumbnail_url").and_then(|v| v.as_str()) {
+            if !url.is_empty() {
+                return Some(url.to_string());
             }
+        }
+        None
+    }
 
-            let canonical_thumb = files_dir.join(media_store::THUMB_FILENAME);
-            let thumb_scratch = files_dir.join("thumb.src.jpg");
-            let mut hook_used = false;
-            if source_is_managed {
-                if let Some(source_thumb) = Self::managed_source_path(&message.content, &user_root)
-                    .and_then(|source| source.parent().map(|dir| dir.join(media_store::THUMB_FILENAME)))
-                    .filter(|path| path.exists())
-                {
-                    std::fs::copy(&source_thumb, &canonical_thumb)
-                        .map_err(|e| Error::Storage(format!("copy managed video thumbnail failed: {e}")))?;
-                    hook_used = true;
-                    let _ = self.storage.update_thumb_status(message.message_id, 1).await;
-                    thumb_upload = Some((
-                        canonical_thumb.clone(),
-                        "image/webp".to_string(),
-                        media_store::THUMB_FILENAME.to_string(),
-                    ));
-                } else {
-                    // A video can be sent without a thumbnail. Do not create
-                    // a new one from an already managed artifact.
-                    hook_used = true;
+    /// 从消息内容 JSON 解析缩略图的协议权威 `thumbnail_file_id`（envelope `metadata` 或 flat）。
+    /// Scheme B：接收端按此 file_id 走 `file/get_url` 拿 signed_url + cek 下载解密。
+    fn extract_thumbnail_file_id(content: &str) -> Option<u64> {
+        let json: serde_json::Value = serde_json::from_str(content).ok()?;
+        let read = |scope: &serde_json::Value| -> Option<u64> {
+            scope.get("thumbnail_file_id").and_then(|v| {
+                v.as_u64()
+                    .or_else(|| v.as_str().and_then(|s| s.parse::<u64>().ok()))
+            })
+        };
+        read(json.get("metadata").unwrap_or(&json))
+            .or_else(|| read(&json))
+            .filter(|id| *id > 0)
+    }
+
+    /// 在 DownloadManager worker 内按 `thumbnail_file_id` 调 `file/get_url`，拿
+    /// signed_url + encryption_version + cek。失败返回 None（缩略图静默不下载，不阻塞）。
+    /// CEK 只来自此处，绝不取自消息 metadata。
+    /// 由 [`media_download::DownloadManager`] 的受控任务调用，不占 actor。
+    async fn resolve_thumbnail_ticket_detached(
+        transport: &TransportClient,
+        thumbnail_file_id: u64,
+        max_edge: Option<u32>,
+        timeout: Duration,
+    ) -> Option<ResolvedFileDownload> {
+        let req = ThumbnailUrlRequest {
+            base: FileGetUrlRequest {
+                file_id: thumbnail_file_id,
+                user_id: 0,
+            },
+            max_edge,
+        };
+        let raw: serde_json::Value =
+            Self::rpc_call_typed_detached(transport, routes::file::GET_URL, &req, timeout)
+                .await
+                .ok()?;
+        ResolvedFileDownload::from_get_url(thumbnail_file_id, raw)
+            .ok()
+            .flatten()
+    }
+
+    /// Download one thumbnail inside DownloadManager's tracked task.
+    ///
+    /// Scheme B：缩略图也是独立 file。`ticket` 是 caller 用 `thumbnail_file_id` 经
+    /// `file/get_url` 解析出的下载票据（v1 加密：url + encryption_version + cek）。
+    /// 没有 `thumbnail_file_id` 时 `ticket=None`，退回消息里的 legacy 明文 `thumbnail_url`（v0）。
+    /// **CEK 只来自 get_url ticket，绝不取自消息 metadata。**
+    #[allow(clippy::too_many_arguments)]
+    async fn run_auto_download_thumbnail(
+        content: &str,
+        ticket: Option<ResolvedFileDownload>,
+        user_root: &Path,
+        message_id: u64,
+        created_at_ms: i64,
+        channel_id: u64,
+        channel_type: i32,
+        bandwidth: &bandwidth::BandwidthLimiter,
+    ) -> Option<ThumbnailDownloadOutcome> {
+        let ticket = match ticket {
+            // v1：get_url 解析的票据，密文 blob，用票据里的 cek 解密。
+            Some(t) if t.url.starts_with("http") => t,
+            // legacy：消息里只有明文 thumbnail_url（旧 v0 附件）。
+            _ => match Self::extract_thumbnail_url(content) {
+                Some(url) if url.starts_with("http") => ResolvedFileDownload::legacy_url(url),
+                _ => {
+                    // thumb_status=3 是**终态**：写下去之后永不重试，UI 从此渲染
+                    // 静态占位符。所以它只能表示「已经看清楚了,这条消息确实没有
+                    // 缩略图」,不能表示「我没看到缩略图字段」。
+                    //
+                    // 这两者的区别就是上一次事故:history 回填丢了 metadata,extra
+                    // 是空串,于是这里判定「没有缩略图」并永久标记——整段历史的图片
+                    // 从此是灰块,重开 App 也不会好。
+                    //
+                    // 现在的规则:metadata 解析不出来时停在 0(未知/待重试),留给下
+                    // 一次投影或定向 repair 去补;只有确实解析出了 metadata 而其中
+                    // 没有缩略图字段,才允许进终态。
+                    // 许可条件是「服务端明确说了没有缩略图」,不是「metadata 能解析」。
+                    // 后者会漏掉最要命的一种:metadata 里有 thumbnail_file_id,只是这次
+                    // file/get_url 因网络/token/服务端抖动没拿到票据——那是可重试失败,
+                    // 写成终态就等于一次抖动永久毁掉一张图。
+                    let explicit_absence =
+                        crate::canonical_inbound::CanonicalInboundMessage::from_sync_entity(
+                            0,
+                            0,
+                            channel_id,
+                            channel_type,
+                            0,
+                            0,
+                            String::new(),
+                            content.to_string(),
+                            0,
+                            0,
+                        )
+                        .server_says_no_thumbnail();
+                    if explicit_absence {
+                        return Some(ThumbnailDownloadOutcome::ConfirmedAbsent);
+                    } else {
+                        tracing::warn!(
+                            message_id,
+                            channel_id,
+                            "拿不到缩略图票据,但服务端未明确表示没有缩略图:保持待重试,不写终态"
+                        );
+                    }
+                    return None;
                 }
-            }
-            // Host (Kotlin/iOS) may have pre-generated thumb.webp during the send-prep
-            // loading modal. If present, trust it: set thumb_status=1 and skip both the
-            // sync hook and Plan 2 async path.
-            if canonical_thumb.exists()
-                && std::fs::metadata(&canonical_thumb)
-                    .map(|m| m.len() > 0)
-                    .unwrap_or(false)
+            },
+        };
+        let dir = media_store::get_message_dir(user_root, message_id as i64, created_at_ms);
+        let thumb_url = ticket.url.clone();
+        let thumb_path = dir.join(Self::thumb_filename_for_url(&thumb_url));
+        let webp_path = dir.join(media_store::THUMB_FILENAME);
+        let png_path = dir.join(media_store::THUMB_PNG_FILENAME);
+        if thumb_path.exists() || webp_path.exists() || png_path.exists() {
+            return Some(ThumbnailDownloadOutcome::Ready);
+        }
+        let mut downloaded = None;
+        for attempt in 0..3u64 {
+            match Self::do_download_thumbnail(&ticket, &dir, &thumb_path, bandwidth)
+            .await
             {
-                hook_used = true;
-                let _ = self
-                    .storage
-                    .update_thumb_status(message.message_id, 1)
-                    .await;
-                thumb_upload = Some((
-                    canonical_thumb.clone(),
-                    "image/webp".to_string(),
-                    media_store::THUMB_FILENAME.to_string(),
-                ));
+                Ok(()) => {
+                    downloaded = Some(Ok(()));
+                    break;
+                }
+                Err(error) if attempt < 2 => {
+                    tracing::warn!(message_id, attempt, %error, "thumbnail download retrying");
+                    tokio::time::sleep(Duration::from_millis(500 * (1 << attempt))).await;
+                }
+                Err(error) => {
+                    downloaded = Some(Err(error));
+                    break;
+                }
             }
-            if !hook_used {
-                if let Some(hook) = self.video_process_hook.as_ref() {
-                    // Hook outputs JPEG; Rust re-encodes to canonical WebP (Spec §FILE_STORAGE).
-                    let ok = hook(
-                        MediaProcessOp::Thumbnail,
-                        &body_path,
-                        &meta_path,
-                        &thumb_scratch,
-                    )
-                    .map_err(|e| Error::Storage(format!("video thumbnail hook failed: {e}")))?;
-                    if ok && thumb_scratch.exists() {
-                        match Self::generate_image_thumbnail_sync(
-                            &thumb_scratch,
-                            &canonical_thumb,
-                            320,
-                            85,
-                        ) {
-                            Ok(_) => {
-                                hook_used = true;
-                                let _ = self
-                                    .storage
-                                    .update_thumb_status(message.message_id, 1)
-                                    .await;
-                                thumb_upload = Some((
-                                    canonical_thumb.clone(),
-                                    "image/webp".to_string(),
-                                    media_store::THUMB_FILENAME.to_string(),
-                                ));
-                            }
-                            Err(e) => {
-                                eprintln!("[SDK.video] re-encode thumbnail to webp failed: {e}");
-                            }
-                        }
-                        let _ = std::fs::remove_file(&thumb_scratch);
-                    }
-                }
-            }
-            // Plan 2: no sync hook registered → issue an async media job to the host
-            // (Kotlin/iOS) and block on a oneshot. `submit_media_job_result` bypasses
-            // the actor command channel because the actor is blocked here.
-            if !hook_used && self.event_tx.is_some() {
-                const VIDEO_THUMBNAIL_TIMEOUT_MS: u64 = 8_000;
-                let job_id = self
-                    .snowflake
-                    .next_id()
-                    .map(|id| id.to_string())
-                    .unwrap_or_else(|_| {
-                        chrono::Utc::now()
-                            .timestamp_nanos_opt()
-                            .unwrap_or(0)
-                            .to_string()
-                    });
-                let (job_tx, job_rx) = oneshot::channel::<MediaJobResult>();
-                {
-                    let mut locked = self
-                        .pending_media_jobs
-                        .lock()
-                        .expect("pending_media_jobs poisoned");
-                    locked.insert(job_id.clone(), job_tx);
-                }
-                let event = SdkEvent::MediaJobRequested {
-                    job_id: job_id.clone(),
-                    job_kind: "video_thumbnail".to_string(),
-                    source_path: body_path.display().to_string(),
-                    output_path: thumb_scratch.display().to_string(),
-                    mime_type: mime_type.clone(),
-                    message_id: message.message_id,
-                    timeout_ms: VIDEO_THUMBNAIL_TIMEOUT_MS,
-                };
-                if let (Some(tx), Some(history), Some(seq)) = (
-                    self.event_tx.as_ref(),
-                    self.event_history.as_ref(),
-                    self.event_seq.as_ref(),
-                ) {
-                    emit_sequenced_event(tx, history, seq, self.event_history_limit, event);
-                }
-                let wait =
-                    tokio::time::timeout(Duration::from_millis(VIDEO_THUMBNAIL_TIMEOUT_MS), job_rx)
-                        .await;
-                // Clear the entry on any exit path — host may submit after timeout.
-                if let Ok(mut locked) = self.pending_media_jobs.lock() {
-                    locked.remove(&job_id);
-                }
-                match wait {
-                    Ok(Ok(result)) if result.ok => {
-                        let out = result
-                            .output_path
-                            .as_deref()
-                            .map(std::path::PathBuf::from)
-                            .unwrap_or_else(|| thumb_scratch.clone());
-                        if out.exists() {
-                            match Self::generate_image_thumbnail_sync(
-                                &out,
-                                &canonical_thumb,
-                                320,
-                                85,
-                            ) {
-                                Ok(_) => {
-                                    hook_used = true;
-                                    let _ = self
-                                        .storage
-                                        .update_thumb_status(message.message_id, 1)
-                                        .await;
-                                    thumb_upload = Some((
-                                        canonical_thumb.clone(),
-                                        "image/webp".to_string(),
-                                        media_store::THUMB_FILENAME.to_string(),
-                                    ));
-                                }
-                                Err(e) => {
-                                    eprintln!(
-                                        "[SDK.video] plan2 re-encode thumbnail to webp failed: {e}"
-                                    );
-                                }
-                            }
-                        } else {
-                            eprintln!(
-                                "[SDK.video] plan2 host reported ok but {} missing",
-                                out.display()
-                            );
-                        }
-                        let _ = std::fs::remove_file(&thumb_scratch);
-                    }
-                    Ok(Ok(result)) => {
-                        eprintln!(
-                            "[SDK.video] plan2 host failed job {job_id}: {}",
-                            result.error.unwrap_or_else(|| "unknown".to_string())
-                        );
-                    }
-                    Ok(Err(_)) => {
-                        eprintln!("[SDK.video] plan2 job {job_id} sender dropped");
-                    }
-                    Err(_) => {
-                        eprintln!(
-                            "[SDK.video] plan2 job {job_id} timed out after {}ms",
-                            VIDEO_THUMBNAIL_TIMEOUT_MS
-                        );
-                    }
-                }
-            }
-            if !hook_used {
-                let _ = self
-                    .storage
-                    .update_thumb_status(message.message_id, 3)
-                    .await;
+        }
+        match downloaded.expect("thumbnail retry loop must produce an outcome") {
+            Ok(()) => Some(ThumbnailDownloadOutcome::Ready),
+            Err(e) => {
+                eprintln!(
+                    "[SDK.thumb] auto-download failed message_id={} url={}: {}",
+                    message_id, thumb_url, e
+                );
+                Some(ThumbnailDownloadOutcome::Failed)
             }
+        }
+    }
 
-            let (thumb_w, thumb_h, thumb_size, thumb_mime) = match thumb_upload.as_ref() {
-                Some((path, mime, _)) => {
-                    let size = std::fs::metadata(path)
-                        .map(|m| m.len())
-                        .map_err(|e| Error::Storage(format!("stat video thumb failed: {e}")))?;
-                    (None, None, Some(size), Some(mime.clone()))
-                }
-                None => (None, None, None, None),
-            };
-            let meta = MediaMeta {
-                source: MediaSourceMeta {
-                    original_filename: original_filename.clone().unwrap_or_default(),
-                    mime: mime_type.clone(),
-                    width: None,
-                    height: None,
-                    file_size: Some(body_size),
-                },
-                thumbnail: if thumb_upload.is_some() {
-                    Some(MediaThumbnailMeta {
-                        width: thumb_w,
-                        height: thumb_h,
-                        file_size: thumb_size,
-                        mime: thumb_mime,
-                    })
-                } else {
-                    None
-                },
-                processing: Some(MediaProcessingMeta {
-                    strategy: Some("client_preprocess".to_string()),
-                    created_at: Some(chrono::Utc::now().timestamp()),
-                }),
-            };
-            std::fs::write(
-                &meta_path,
-                serde_json::to_string(&meta)
-                    .map_err(|e| Error::Serialization(format!("encode media meta failed: {e}")))?,
-            )
-            .map_err(|e| Error::Storage(format!("write media meta failed: {e}")))?;
-        } else {
-            let meta = MediaMeta {
-                source: MediaSourceMeta {
-                    original_filename: original_filename.clone().unwrap_or_default(),
-                    mime: mime_type.clone(),
-                    width: None,
-                    height: None,
-                    file_size: Some(body_size),
-                },
-                thumbnail: None,
-                processing: Some(MediaProcessingMeta {
-                    strategy: Some("client_preprocess".to_string()),
-                    created_at: Some(chrono::Utc::now().timestamp()),
-                }),
-            };
-            std::fs::write(
-                &meta_path,
-                serde_json::to_string(&meta)
-                    .map_err(|e| Error::Serialization(format!("encode media meta failed: {e}")))?,
-            )
-            .map_err(|e| Error::Storage(format!("write media meta failed: {e}")))?;
+    /// Pick canonical thumbnail filename based on the remote URL extension,
+    /// so the file name on disk matches the actual bytes (webp vs png).
+    fn thumb_filename_for_url(url: &str) -> &'static str {
+        let path = url.split(['?', '#']).next().unwrap_or(url);
+        let ext = path
+            .rsplit('/')
+            .next()
+            .and_then(|name| name.rsplit('.').next())
+            .map(str::to_ascii_lowercase)
+            .unwrap_or_default();
+        match ext.as_str() {
+            "png" => media_store::THUMB_PNG_FILENAME,
+            _ => media_store::THUMB_FILENAME,
         }
+    }
 
+    async fn do_download_thumbnail(
+        ticket: &ResolvedFileDownload,
+        dir: &Path,
+        thumb_path: &Path,
+        bandwidth: &bandwidth::BandwidthLimiter,
+    ) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let encryption_version = ticket.encryption_version;
+        let resp = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?
+            .get(&ticket.url)
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            return Err(format!("HTTP {}", resp.status()).into());
+        }
+        let bytes = bandwidth.read_body(resp).await?;
+        // 服务端给了大小 / 摘要就先比对：v0 缩略图没有别的校验，截断的图片会被当成
+        // 完好的落盘。对不上算一次失败，调用方的重试循环会整张重拉。
+        media_download::verify_stored_bytes(ticket, &bytes)?;
+        // 附件加密 v1：缩略图 blob 同样是 nonce||ct||tag，用 file/get_url 票据里的 cek
+        // 本地解密后再落盘；v0（legacy 明文）原样写入。解密失败直接报错，绝不写密文当图片。
+        let plaintext = crate::attachment_crypto::decrypt_downloaded_attachment_bytes(
+            encryption_version,
+            ticket.cek.as_deref(),
+            &bytes,
+        )?;
+        std::fs::create_dir_all(dir)?;
+        let part_path = thumb_path.with_extension(format!(
+            "{}.part",
+            thumb_path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .unwrap_or("thumb")
+        ));
+        {
+            let mut file = std::fs::File::create(&part_path)?;
+            file.write_all(&plaintext)?;
+            file.sync_all()?;
+        }
+        std::fs::rename(&part_path, thumb_path)?;
         eprintln!(
-            "[SDK.actor] process_outbound_file: message_id={} file_type={} mime={} body_size={} has_thumb={}",
-            message.message_id, file_type, mime_type, body_size, thumb_upload.is_some()
+            "[SDK.thumb] auto-download ok: {} ({} bytes, enc_v={})",
+            thumb_path.display(),
+            plaintext.len(),
+            encryption_version
         );
+        Ok(())
+    }
 
-        let uploaded_thumbnail = if let Some((thumb_path, thumb_mime, thumb_name)) = thumb_upload {
-            let thumb_size = std::fs::metadata(&thumb_path)
-                .map(|m| m.len() as i64)
-                .map_err(|e| Error::Storage(format!("stat thumb file failed: {e}")))?;
-            eprintln!(
-                "[SDK.actor] process_outbound_file: uploading thumbnail size={}",
-                thumb_size
-            );
-            let thumb_bytes = std::fs::read(&thumb_path)
-                .map_err(|e| Error::Storage(format!("read thumb file failed: {e}")))?;
-            let (uploaded_thumb, thumb_token) = self
-                .send_one_attachment(
-                    message.from_uid,
-                    thumb_name.clone(),
-                    thumb_mime.clone(),
-                    "image".to_string(),
-                    thumb_bytes,
-                    &Self::sealed_cache_for_send(
-                        &message.content,
-                        &user_root,
-                        &files_dir,
-                        "thumb.sealed",
-                    ),
-                    &message.message_id.to_string(),
-                )
-                .await?;
-            self.upload_callback(message.from_uid, &thumb_token, &uploaded_thumb)
-                .await?;
-            Some(uploaded_thumb)
-        } else {
-            None
-        };
+    /// 存储层 / 上传层使用的文件分类字符串，严格对齐服务端 `FileType`：
+    /// `"image"` / `"video"` / `"voice"` / `"file"`。
+    ///
+    /// 规则：发送入口（`message_type`）决定分类，MIME 不反推消息类型。
+    /// Voice 消息直接返回 `"voice"`，服务端据此走独立的尺寸限额与 /voices/ 存储目录。
+    fn guess_file_type(message_type: i32, filename: &str, mime: &str) -> &'static str {
+        let image_type = privchat_protocol::message::ContentMessageType::Image as i32;
+        let video_type = privchat_protocol::message::ContentMessageType::Video as i32;
+        let voice_type = privchat_protocol::message::ContentMessageType::Voice as i32;
+        if message_type == image_type {
+            return "image";
+        }
+        if message_type == video_type {
+            return "video";
+        }
+        if message_type == voice_type {
+            return "voice";
+        }
+        if mime.starts_with("image/") {
+            return "image";
+        }
+        if mime.starts_with("video/") {
+            return "video";
+        }
+        let ext = filename
+            .rsplit('.')
+            .next()
+            .map(|s| s.to_ascii_lowercase())
+            .unwrap_or_default();
+        match ext.as_str() {
+            "jpg" | "jpeg" | "png" | "gif" | "webp" | "bmp" | "heic" => "image",
+            "mp4" | "mov" | "mkv" | "avi" | "webm" => "video",
+            _ => "file",
+        }
+    }
 
-        eprintln!(
-            "[SDK.actor] process_outbound_file: requesting upload token for main file size={}",
-            upload_payload.len()
-        );
-        let (uploaded, main_token) = self
-            .send_one_attachment(
-                message.from_uid,
-                upload_filename.clone(),
-                mime_type.clone(),
-                file_type.clone(),
-                upload_payload,
-                &Self::sealed_cache_for_send(
-                    &message.content,
-                    &user_root,
-                    &files_dir,
-                    "body.sealed",
-                ),
-                &message.message_id.to_string(),
-            )
-            .await?;
-        eprintln!("[SDK.actor] process_outbound_file: upload callback");
-        self.upload_callback(message.from_uid, &main_token, &uploaded)
-            .await?;
+    /// 附件的说明文字，写在本地元数据里（protocol 的 `LocalAttachmentMetadata::caption`）。
+    ///
+    /// `None` = 用户没写；wire 层据此发空正文。
+    ///
+    /// 🔴 **一个字都不改**：不 trim、不折叠空白。用户正文归用户，SDK 擅自修剪
+    /// 会跟不修剪的另一端（TS）在同一条消息上产出不同字节。只有空串算「没写」。
+    fn attachment_caption(extra: &str) -> Option<String> {
+        serde_json::from_str::<serde_json::Value>(extra)
+            .ok()?
+            .get("caption")?
+            .as_str()
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+    }
 
-        let uploaded_file_id = uploaded.file_id.parse::<u64>().map_err(|_| {
-            Error::Serialization(format!(
-                "upload response invalid numeric file_id: {}",
-                uploaded.file_id
-            ))
-        })?;
-        let thumbnail_file_id_u64 = uploaded_thumbnail
-            .as_ref()
-            .map(|uploaded| {
-                uploaded.file_id.parse::<u64>().map_err(|_| {
-                    Error::Serialization(format!(
-                        "upload response invalid numeric thumbnail_file_id: {}",
-                        uploaded.file_id
-                    ))
-                })
-            })
-            .transpose()?;
+    fn default_mime_for_message_type(message_type: i32) -> &'static str {
+        use privchat_protocol::message::ContentMessageType;
+        let image_type = ContentMessageType::Image as i32;
+        let video_type = ContentMessageType::Video as i32;
+        let voice_type = ContentMessageType::Voice as i32;
+        if message_type == image_type {
+            "image/jpeg"
+        } else if message_type == video_type {
+            "video/mp4"
+        } else if message_type == voice_type {
+            "audio/mp4"
+        } else {
+            "application/octet-stream"
+        }
+    }
 
-        let attachment_content = Self::build_attachment_wire_content(AttachmentWireInput {
-            file_type: &file_type,
-            file_id: uploaded_file_id,
-            storage_source_id: uploaded.storage_source_id,
-            file_size: uploaded.file_size,
-            file_url: &uploaded.file_url,
-            main_thumbnail_url: uploaded.thumbnail_url.as_deref(),
-            thumbnail: thumbnail_file_id_u64.map(|id| {
-                (
-                    id,
-                    uploaded_thumbnail
-                        .as_ref()
-                        .map(|v| v.file_url.as_str())
-                        .unwrap_or_default(),
-                )
-            }),
-            // 传的是 payload.{ext}，显示的是它本来的名字。
-            filename: &Self::wire_display_filename(&message.extra, &upload_filename),
-            mime_type: &mime_type,
-            width: source_width,
-            height: source_height,
-            message_type: message.message_type,
-            extra: &message.extra,
-        });
-        let content = serde_json::to_string(&attachment_content)
-            .map_err(|e| Error::Serialization(format!("encode attachment content: {e}")))?;
-        let req = self.build_send_message_request_with_content(
-            message,
-            local_message_id,
-            content.clone(),
-        )?;
-        // 🔴 这里**不删**封装缓存。`direct_send_message` 返回成功只说明服务端收下了，
-        // 本地那笔 `outbox_ack_sent` 还没提交；它要是失败，outbox 行留着、下一轮重来，
-        // 而缓存已经没了 → 重新随机加密 → 又一份物理文件。清理放在 drain 确认之后。
-        let resp = self.direct_send_message(req).await?;
-        // 把带 width/height/file_id/thumbnail 的最终 content 回写发送端本地行。
-        // 否则本地行停在入队时的初始 content（无尺寸），发送端自己的气泡读不到宽高、
-        // 退化竖向默认 150×200（接收端拿的是 wire content，所以一直正常）。best-effort：
-        // 回写失败不影响已发出的消息，重进会话时下次读到的仍是这份 content。
-        if let Err(err) = self
-            .storage
-            .update_message_content(message.message_id, &content)
-            .await
-        {
-            eprintln!(
-                "[SDK.actor] update_message_content after send failed: message_id={} err={err}",
-                message.message_id
+    /// 从 extra JSON 中按优先级读 u64：先找顶层 `key`，再找 `metadata.key`。
+    fn pick_u64_from_extra(extra: &str, key: &str) -> Option<u64> {
+        let value = serde_json::from_str::<serde_json::Value>(extra).ok()?;
+        value
+            .get(key)
+            .or_else(|| value.get("metadata").and_then(|m| m.get(key)))
+            .and_then(|v| v.as_u64())
+    }
+
+    /// 语音消息：协议要求 `VoiceMetadata.duration` 必填。
+    ///
+    /// 上传完成后 SDK 会用 attachment_content 重写 message.content，如果不把发送侧
+    /// 采样的时长搬进去，接收端 VoiceMetadata 解析就会拿到 duration=0。录制不足 1 秒
+    /// 时兜底为 1，与客户端约定保持一致。
+    fn merge_voice_metadata(extra: &str, attachment_content: &mut serde_json::Value) {
+        let duration_secs = Self::pick_u64_from_extra(extra, "duration")
+            .map(|v| v.min(u32::MAX as u64) as u32)
+            .unwrap_or(1);
+        if let Some(obj) = attachment_content.as_object_mut() {
+            obj.insert(
+                "duration".to_string(),
+                serde_json::Value::from(duration_secs),
             );
         }
-        Ok(resp)
     }
 
-    async fn rpc_call_json(
-        &mut self,
-        route: String,
-        body: serde_json::Value,
-    ) -> Result<serde_json::Value> {
-        let timeout = self.timeout();
-        let body_bytes = serde_json::to_vec(&body)
-            .map_err(|e| Error::Serialization(format!("encode rpc body: {e}")))?;
-        let request_context = format!("rpc call route={route}");
-        let request = RpcRequest {
-            route,
-            body: body_bytes,
+    /// 视频消息：协议 `VideoMetadata` 要求 duration / width / height 必填，缩略图三件套可选。
+    ///
+    /// 与语音不同，视频的 UI（气泡比例、首帧渲染）依赖完整的尺寸信息，因此独立成函数处理，
+    /// 避免和语音的单字段逻辑混用。未抽帧的视频允许缺省 thumbnail_*，由客户端播放器自行处理。
+    fn merge_video_metadata(extra: &str, attachment_content: &mut serde_json::Value) {
+        let Some(obj) = attachment_content.as_object_mut() else {
+            return;
         };
-        let payload = encode_message(&request)
-            .map_err(|e| Error::Serialization(format!("encode rpc request: {e}")))?;
-        let raw = self
-            .request_bytes(
-                Bytes::from(payload),
-                MessageType::RpcRequest as u8,
-                timeout,
-                &request_context,
-            )
-            .await?;
-        let rpc_resp: RpcResponse = decode_message(&raw)
-            .map_err(|e| Error::Serialization(format!("decode rpc response: {e}")))?;
-        if rpc_resp.code != 0 {
-            // 认证段 (10000-10099) 单独归为 Error::Auth（非 retryable），
-            // 其它 code 交给 is_retryable_server_code 按段判定。
-            let code_u32 = rpc_resp.code as u32;
-            if (10000..10100).contains(&code_u32) {
-                return Err(Error::Auth(format!(
-                    "[{}] {}",
-                    rpc_resp.code, rpc_resp.message
-                )));
-            }
-            return Err(Error::Server {
-                code: code_u32,
-                message: rpc_resp.message,
-            });
+        let insert_u32 =
+            |obj: &mut serde_json::Map<String, serde_json::Value>, key: &str, v: u64| {
+                obj.insert(
+                    key.to_string(),
+                    serde_json::Value::from(v.min(u32::MAX as u64) as u32),
+                );
+            };
+        if let Some(duration) = Self::pick_u64_from_extra(extra, "duration") {
+            insert_u32(obj, "duration", duration);
         }
-        match rpc_resp.data {
-            Some(bytes) if !bytes.is_empty() => serde_json::from_slice(&bytes)
-                .map_err(|e| Error::Serialization(format!("decode rpc data: {e}"))),
-            _ => Ok(serde_json::Value::Null),
+        if let Some(width) = Self::pick_u64_from_extra(extra, "width") {
+            insert_u32(obj, "width", width);
+        }
+        if let Some(height) = Self::pick_u64_from_extra(extra, "height") {
+            insert_u32(obj, "height", height);
+        }
+        if let Some(tw) = Self::pick_u64_from_extra(extra, "thumbnail_width") {
+            insert_u32(obj, "thumbnail_width", tw);
+        }
+        if let Some(th) = Self::pick_u64_from_extra(extra, "thumbnail_height") {
+            insert_u32(obj, "thumbnail_height", th);
         }
     }
 
-    /// 不依赖 actor 状态的一次 RPC。供**后台任务**使用。
+    /// 补全 channel 的 last_message_* 元数据字段。
     ///
-    /// 与 [`State::rpc_call_typed`] 的区别：不做连接健康对账。后台请求超时本来就不构成
-    /// 断线证据（只有 `TransportError::Connection` 才是），而健康对账要 `&mut State`——
-    /// 把它一起搬出去就等于让后台工作去裁决连接状态，那是本末倒置。
-    async fn rpc_call_typed_detached<Req, Resp>(
-        transport: &TransportClient,
-        route: &str,
-        body: &Req,
-        timeout: Duration,
-    ) -> Result<Resp>
-    where
-        Req: Serialize,
-        Resp: DeserializeOwned,
-    {
-        let body_bytes = serde_json::to_vec(body)
-            .map_err(|e| Error::Serialization(format!("encode {route} body: {e}")))?;
-        let request = RpcRequest {
-            route: route.to_string(),
-            body: body_bytes,
-        };
-        let payload = encode_message(&request)
-            .map_err(|e| Error::Serialization(format!("encode rpc request: {e}")))?;
-        let opt = RequestOptions::new()
-            .biz_type(MessageType::RpcRequest as u8)
-            .timeout(timeout);
-        let raw = transport
-            .request_with_options(Bytes::from(payload), opt)
-            .await
-            .map_err(|e| match e {
-                TransportError::Timeout { .. } => Error::RequestUnanswered {
-                    context: route.to_string(),
-                },
-                other => Error::Transport(format!("{route}: {other}")),
-            })?;
-        let rpc_resp: RpcResponse = decode_message(&raw)
-            .map_err(|e| Error::Serialization(format!("decode rpc response: {e}")))?;
-        if rpc_resp.code != 0 {
-            return Err(Error::Server {
-                code: rpc_resp.code as u32,
-                message: rpc_resp.message,
-            });
+    /// **重要**：本函数**不再改写 `last_msg_content`**（参见架构归正）。
+    /// content 字段保持为消息的原始体（TEXT = 纯文本 / 其它 = 结构化 JSON），
+    /// 预览文案由 UI 层基于 `last_message_type` + `last_message_is_revoked` + i18n 渲染。
+    ///
+    /// 行为：通过 `last_local_message_id` 查本地消息表，取出 `message_type` 与
+    /// 撤回标记后写到 channel 的相应字段。查不到时保持 None / false。
+    async fn materialize_channel_preview(&self, mut channel: StoredChannel) -> StoredChannel {
+        if channel.last_local_message_id > 0 {
+            if let Ok(Some(message)) = self
+                .storage
+                .get_message_by_id(channel.last_local_message_id)
+                .await
+            {
+                if message.channel_id == channel.channel_id
+                    && message.channel_type == channel.channel_type
+                {
+                    channel.last_message_type = Some(message.message_type);
+                    // StoredMessage 暂无显式 revoked 字段；客户端通过 message_type==System
+                    // + content/extra 中的 topic / metadata 推断（spec/SYSTEM_MESSAGE_SPEC §3）。
+                    // 这里保守置 false，未来如需精确从 StoredMessageExtra.revoke 派生再补。
+                    channel.last_message_is_revoked = false;
+                }
+            }
         }
-        match rpc_resp.data {
-            Some(bytes) if !bytes.is_empty() => serde_json::from_slice(&bytes)
-                .map_err(|e| Error::Serialization(format!("decode rpc data: {e}"))),
-            _ => Err(Error::Serialization(format!("{route}: empty data"))),
+        channel
+    }
+
+    fn guess_mime_type(filename: &str) -> &'static str {
+        let ext = filename
+            .rsplit('.')
+            .next()
+            .map(|s| s.to_ascii_lowercase())
+            .unwrap_or_default();
+        match ext.as_str() {
+            "jpg" | "jpeg" => "image/jpeg",
+            "png" => "image/png",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            "heic" => "image/heic",
+            "mp4" => "video/mp4",
+            "mov" => "video/quicktime",
+            "mkv" => "video/x-matroska",
+            "mp3" => "audio/mpeg",
+            "wav" => "audio/wav",
+            "aac" => "audio/aac",
+            "m4a" => "audio/mp4",
+            "ogg" => "audio/ogg",
+            "pdf" => "application/pdf",
+            "txt" => "text/plain",
+            "json" => "application/json",
+            "zip" => "application/zip",
+            _ => "application/octet-stream",
         }
     }
 
-    async fn rpc_call_typed<Req, Resp>(&mut self, route: &str, body: &Req) -> Result<Resp>
-    where
-        Req: Serialize,
-        Resp: DeserializeOwned,
-    {
-        let value = self
-            .rpc_call_json(
-                route.to_string(),
-                serde_json::to_value(body)
-                    .map_err(|e| Error::Serialization(format!("encode {route} body: {e}")))?,
+    /// 解码图片并应用 EXIF orientation，返回的尺寸/像素均为「显示方向」。
+    ///
+    /// `image` crate 默认不会消费 EXIF orientation：`img.width()/height()` 给的是
+    /// 传感器原始像素方向。真机横拍照片常带 orientation=6/8（旋转 90°），不处理会让
+    /// metadata 宽高反置（web 按 metadata 定气泡比例 → 比例错）且缩略图转向错。
+    /// 这里读出 orientation 并 apply 到解码结果，保证所有下游消费者拿到的是显示方向。
+    fn decode_image_oriented(source_path: &std::path::Path) -> Result<image::DynamicImage> {
+        let reader = image::ImageReader::open(source_path)
+            .map_err(|e| Error::Storage(format!("open image failed: {e}")))?;
+        let mut decoder = reader
+            .into_decoder()
+            .map_err(|e| Error::Storage(format!("decode image failed: {e}")))?;
+        let orientation = image::ImageDecoder::orientation(&mut decoder)
+            .unwrap_or(image::metadata::Orientation::NoTransforms);
+        let mut img = image::DynamicImage::from_decoder(decoder)
+            .map_err(|e| Error::Storage(format!("decode image failed: {e}")))?;
+        img.apply_orientation(orientation);
+        Ok(img)
+    }
+
+    /// 视频处理回调：注册了的用注册的；没注册时，开了 `video-processor` feature 就用
+    /// 内置实现（只抽帧、不压缩，见 [`video_processor`]）。
+    fn effective_video_process_hook(&self) -> Option<VideoProcessHook> {
+        #[cfg(feature = "video-processor")]
+        if self.video_process_hook.is_none() {
+            return video_processor::default_hook();
+        }
+        self.video_process_hook.clone()
+    }
+
+    fn generate_image_thumbnail_sync(
+        source_path: &std::path::Path,
+        output_path: &std::path::Path,
+        max_edge: u32,
+        quality: u8,
+    ) -> Result<(u32, u32, u64)> {
+        let img = Self::decode_image_oriented(source_path)?;
+        let (w, h) = (img.width(), img.height());
+        let (tw, th) = if w >= h {
+            let nw = w.min(max_edge).max(1);
+            let nh = ((h as u64) * (nw as u64) / (w as u64)).max(1) as u32;
+            (nw, nh)
+        } else {
+            let nh = h.min(max_edge).max(1);
+            let nw = ((w as u64) * (nh as u64) / (h as u64)).max(1) as u32;
+            (nw, nh)
+        };
+        let thumb = img.resize_exact(tw, th, image::imageops::FilterType::Triangle);
+        // WebP supports RGBA, so preserve alpha channel for PNG/WebP sources.
+        let rgba = thumb.to_rgba8();
+        if let Some(parent) = output_path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| Error::Storage(format!("create thumb dir failed: {e}")))?;
+        }
+        let file = std::fs::File::create(output_path)
+            .map_err(|e| Error::Storage(format!("create thumb failed: {e}")))?;
+        let mut writer = std::io::BufWriter::new(file);
+        let _ = quality; // lossless WebP; quality unused but kept for API compat
+        let encoder = image::codecs::webp::WebPEncoder::new_lossless(&mut writer);
+        encoder
+            .encode(
+                rgba.as_raw(),
+                thumb.width(),
+                thumb.height(),
+                image::ExtendedColorType::Rgba8,
             )
+            .map_err(|e| Error::Storage(format!("encode thumb webp failed: {e}")))?;
+        writer
+            .flush()
+            .map_err(|e| Error::Storage(format!("flush thumb failed: {e}")))?;
+        let file_size = output_path
+            .metadata()
+            .map(|m| m.len())
+            .map_err(|e| Error::Storage(format!("stat thumb failed: {e}")))?;
+        Ok((thumb.width(), thumb.height(), file_size))
+    }
+
+    async fn request_upload_token(
+        &mut self,
+        user_id: u64,
+        filename: String,
+        file_size: i64,
+        mime_type: String,
+        file_type: String,
+        // **最终待上传 blob** 的 SHA-256（加密之后那串字节）。
+        // `None` = 不参与秒传，照常完整上传。
+        sha256: Option<&str>,
+    ) -> Result<FileRequestUploadTokenResponse> {
+        let payload = FileRequestUploadTokenRequest {
+            user_id,
+            filename: Some(filename),
+            file_size,
+            mime_type,
+            file_type,
+            business_type: "message".to_string(),
+            sha256: sha256.map(str::to_string),
+            transform_version: 0,
+        };
+        let response: FileRequestUploadTokenResponse = self
+            .rpc_call_typed(routes::file::REQUEST_UPLOAD_TOKEN, &payload)
             .await?;
-        serde_json::from_value::<Resp>(value)
-            .map_err(|e| Error::Serialization(format!("decode {route} response: {e}")))
+        if response.token.trim().is_empty() {
+            return Err(Error::Serialization(
+                "decode file/request_upload_token response: missing token".to_string(),
+            ));
+        }
+        if response.upload_url.trim().is_empty() {
+            return Err(Error::Serialization(
+                "decode file/request_upload_token response: missing upload_url".to_string(),
+            ));
+        }
+        Ok(response)
     }
 
-    async fn update_channel_last_message(
+    /// 申请分片 token（RESUMABLE_UPLOAD_SPEC §2）。
+    async fn request_chunked_upload_token(
         &mut self,
-        channel_id: u64,
-        channel_type: i32,
-        content: &str,
-        timestamp_ms: i64,
-        message_id: u64,
-        from_uid: Option<u64>,
-        bump_unread: bool,
-    ) -> Result<()> {
-        let existing = self.storage.get_channel_by_id(channel_id).await?;
-        let unread_before = existing.as_ref().map(|c| c.unread_count).unwrap_or(0);
-        let prev_last_local_message_id = existing
-            .as_ref()
-            .map(|c| c.last_local_message_id)
-            .unwrap_or(0);
-        let prev_last_msg_content = existing
-            .as_ref()
-            .map(|c| c.last_msg_content.clone())
-            .unwrap_or_default();
-        let prev_last_msg_timestamp = existing.as_ref().map(|c| c.last_msg_timestamp).unwrap_or(0);
-        // 只有「这条消息就地建出新会话行」时才由这里定对端；已存在的行不碰
-        // （upsert SQL 用 COALESCE 保留已存值）。
-        let mut new_channel_peer_user_id: Option<u64> = None;
-        let (channel_name, channel_remark, avatar, top, mute, unread_count) =
-            if let Some(c) = existing {
-                (
-                    c.channel_name,
-                    c.channel_remark,
-                    c.avatar,
-                    c.top,
-                    c.mute,
-                    if bump_unread {
-                        c.unread_count.saturating_add(1)
-                    } else {
-                        c.unread_count
-                    },
-                )
-            } else {
-                let current_uid = self
-                    .current_uid
-                    .as_ref()
-                    .and_then(|v| v.parse::<u64>().ok())
-                    .unwrap_or_default();
-                // channel_type 客户端约定：1=DM，2=群，其它=房间。
-                //
-                // **名字一律留空**，DM 和群一样。名字的真源是 user / group 实体，
-                // 这里知道的只有 uid——把 uid 写进 channel_name 就是把一串数字写成了
-                // 「名字」：列表查询看到非空的 channel_name 就直接拿去显示，用户看到的
-                // 是 `100000007`。邀请码注册的新用户第一眼看到的就是这个（欢迎消息
-                // 先于对端 user 实体到达，本地会话行由这条消息就地建出来）。
-                //
-                // 群那条分支早就踩过同一个坑（见下方保留的注释），DM 这条一直没跟上。
-                // 服务端也是这个约定：`channel_service` 的自包含投影在名字缺失时发空串，
-                // 注释写着「宁可显示加载中也不显示一串数字」。
-                //
-                // 对端身份改存到 `peer_user_id` 结构化字段——那才是它该待的地方，
-                // 列表查询据此 JOIN user 表拿真名，user 实体一到名字自然就对了。
-                let inferred_peer_user_id = if channel_type == 1 {
-                    match from_uid {
-                        Some(uid) if uid > 0 && uid != current_uid => Some(uid),
-                        _ => None,
-                    }
-                } else {
-                    // 群/房间的名字来自 group 实体（entity sync），这里绝不能拿 channel_id
-                    // 当名字：它会被写进 channel.channel_name，而频道列表查询对群会优先取
-                    // channel_name，于是真正的群名（即便随后同步到）被永久盖住，标题卡在裸 id。
-                    // 留空，交给查询回落到 group.name / 成员名。
-                    None
-                };
-                new_channel_peer_user_id = inferred_peer_user_id;
-                (
-                    String::new(),
-                    String::new(),
-                    String::new(),
-                    0,
-                    0,
-                    if bump_unread { 1 } else { 0 },
-                )
-            };
-        if inbound_logs_enabled() {
-            eprintln!(
-                "[SDK.unread] update_channel_last_message: channel_id={} channel_type={} bump_unread={} unread_before={} unread_after={} message_id={} from_uid={:?}",
-                channel_id,
-                channel_type,
-                bump_unread,
-                unread_before,
-                unread_count,
-                message_id,
-                from_uid
-            );
-        }
-        // 预览反乱序守卫（MESSAGE_HISTORY spec §2.5）：预览选择必须与显示排序同构
-        // （pending 最新端；已确认按 pts）。到达顺序 ≠ pts 顺序（例：登录通知 pts=2 先经
-        // push 到达，欢迎消息 pts=1 后经 sync 补齐——此前预览被"到达序"覆盖成旧消息）。
-        // 仅当新行不older于当前预览行时才覆盖预览字段；unread 计数不受影响照常更新。
-        let preview_should_update =
-            if prev_last_local_message_id == 0 || prev_last_local_message_id == message_id {
-                true
-            } else {
-                let new_row = self
-                    .storage
-                    .get_message_by_id(message_id)
-                    .await
-                    .ok()
-                    .flatten();
-                match new_row {
-                    None => true, // 新行尚未落库（调用时序差异）：保守覆盖，下次调用自愈
-                    Some(nr) => {
-                        if nr.server_message_id.unwrap_or(0) == 0 {
-                            true // pending 自发消息 = 时间线最新端
-                        } else {
-                            match self
-                                .storage
-                                .get_message_by_id(prev_last_local_message_id)
-                                .await
-                                .ok()
-                                .flatten()
-                            {
-                                None => true, // 旧预览行已不存在（被清理），覆盖
-                                Some(or) => {
-                                    if or.server_message_id.unwrap_or(0) == 0 {
-                                        // 旧预览是 pending：收到的 server 消息按时间更新，允许覆盖
-                                        // （pending ack 回填 pts 后由后续更新自然归位）
-                                        true
-                                    } else {
-                                        nr.pts.unwrap_or(0) >= or.pts.unwrap_or(0)
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            };
-
-        let existing_version = self
-            .storage
-            .get_channel_by_id(channel_id)
-            .await?
-            .map(|c| c.version)
-            .unwrap_or(0);
-        self.storage
-            .upsert_channel(UpsertChannelInput {
-                channel_id,
-                channel_type,
-                channel_name,
-                channel_remark,
-                avatar,
-                unread_count,
-                top,
-                mute,
-                last_msg_timestamp: if preview_should_update {
-                    timestamp_ms
-                } else {
-                    prev_last_msg_timestamp
-                },
-                last_local_message_id: if preview_should_update {
-                    message_id
-                } else {
-                    prev_last_local_message_id
-                },
-                last_msg_content: if preview_should_update {
-                    content.to_string()
-                } else {
-                    prev_last_msg_content.clone()
-                },
-                // Timeline preview updates must not mint a synthetic entity version.
-                // Keep the existing channel entity version so later sync_entities(channel)
-                // payloads can still apply top/mute/name changes from the server.
-                version: existing_version,
-                // 已存在的行不触碰对端（upsert SQL 用 COALESCE 保留已存值）；
-                // 新建的 DM 行在这里把对端存进结构化字段，列表查询据此 JOIN user 拿真名。
-                peer_user_id: new_channel_peer_user_id,
-            })
-            .await?;
-        Ok(())
+        filename: String,
+        file_size: i64,
+        mime_type: String,
+        file_type: String,
+        sha256: &str,
+        force_upload: bool,
+    ) -> Result<FileRequestChunkedUploadTokenResponse> {
+        let payload = FileRequestChunkedUploadTokenRequest {
+            file_type,
+            business_type: "message".to_string(),
+            file_size,
+            file_hash: sha256.to_string(),
+            mime_type,
+            filename: Some(filename),
+            transform_version: 0,
+            force_upload,
+        };
+        self.rpc_call_typed(routes::file::REQUEST_CHUNKED_UPLOAD_TOKEN, &payload)
+            .await
     }
-}
-
-/// 附件正文传输计数（诊断用；也是「秒传到底有没有省下带宽」的唯一可信判据）。
-#[derive(Debug, Default)]
-pub struct AttachmentTransferCounters {
-    /// 秒传取用**成功**次数：换到自己的 file_id，正文零字节。
-    pub claims: AtomicU64,
-    /// **主文件**字节传上去并被服务端接收的次数。
-    pub body_uploads: AtomicU64,
-    /// **缩略图**字节传上去并被服务端接收的次数。缩略图是本地重新生成的派生物，
-    /// 每次生成都是新字节，所以它不会命中秒传——这是已知代价，单独计数免得
-    /// 被算进「主文件没省下带宽」。
-    pub thumbnail_uploads: AtomicU64,
-}
-
-/// [`PrivchatSdk::attachment_transfer_stats`] 的快照。
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct AttachmentTransferStats {
-    pub claims: u64,
-    pub body_uploads: u64,
-    pub thumbnail_uploads: u64,
-}
 
-#[derive(Clone)]
-pub struct PrivchatSdk {
-    /// 附件正文的传输计数：秒传命中一次 +1 claim，真传字节一次 +1 upload。
+    /// 明文文件 → **最终待上传 blob**（写进 `dest`）：加密一次，算一次摘要，之后都用它。
     ///
-    /// 这是唯一能回答「这次转发到底传没传字节」的地方——服务端也按内容哈希复用
-    /// 物理路径，所以「两条记录指向同一个 file_url」**不能**证明没上传。
-    attachment_transfers: Arc<AttachmentTransferCounters>,
-    tx: mpsc::Sender<Command>,
-    event_tx: broadcast::Sender<SdkEvent>,
-    event_seq: Arc<AtomicU64>,
-    event_history: Arc<StdMutex<VecDeque<SequencedSdkEvent>>>,
-    event_history_limit: usize,
-    _runtime_provider: RuntimeProvider,
-    task_registry: TaskRegistry,
-    shutting_down: Arc<AtomicBool>,
-    supervised_sync_running: Arc<AtomicBool>,
-    /// 首屏扫补是否已在跑。宿主每次进入 SYNC_READY 都会踢一脚，重连/切前台会踢很多次；
-    /// 没有这道闸，几百个会话的扫补会被并发起好几轮，互相抢 actor。
-    first_screen_sweep_running: Arc<AtomicBool>,
-    /// 见 [State::switch_requested]：切换账号前先记一笔并叫醒 actor，让正在跑的同步让出。
-    switch_requested: Arc<AtomicU64>,
-    switch_wakeup: Arc<tokio::sync::Notify>,
-    /// Interactive timeline reads preempt Phase 3 convergence. A pending notify
-    /// permit survives the small check/start race around the background pass.
-    foreground_wakeup: Arc<tokio::sync::Notify>,
-    startup_error: Arc<StdMutex<Option<Error>>>,
-    snowflake: Arc<snowflake_me::Snowflake>,
-    presence_cache: Arc<StdMutex<HashMap<u64, PresenceStatus>>>,
-    typing_throttle: Arc<StdMutex<HashMap<(u64, bool, u8), std::time::Instant>>>,
-    data_dir: Arc<String>,
-    /// file queue 的路由键（构造期由 endpoint 固化）。附件首发与重试必须用同一个键，
-    /// 才会落到同一条有序队列。
-    file_route_key: Arc<Option<String>>,
-    download_manager: media_download::DownloadManager,
-    pending_media_jobs: Arc<StdMutex<HashMap<String, oneshot::Sender<MediaJobResult>>>>,
-}
-
-impl PrivchatSdk {
-    /// 附件正文的传输计数。
+    /// 🔴 顺序不能反。秒传按「最终上传字节」判重，而加密用的是随机 CEK/nonce——
+    /// 预检之后再加密一次，字节就变了、摘要也变了，本来就不该命中。
+    /// 所以这里产出的 blob 必须留住：上传用它，重试也用它。
     ///
-    /// 🔴 「秒传省了带宽」只能在这里证明：服务端**也**按内容哈希复用物理路径，
-    /// 所以「两条记录指向同一个 file_url」并不意味着客户端没上传。
-    pub fn attachment_transfer_stats(&self) -> AttachmentTransferStats {
-        AttachmentTransferStats {
-            claims: self
-                .attachment_transfers
-                .claims
-                .load(std::sync::atomic::Ordering::Relaxed),
-            body_uploads: self
-                .attachment_transfers
-                .body_uploads
-                .load(std::sync::atomic::Ordering::Relaxed),
-            thumbnail_uploads: self
-                .attachment_transfers
-                .thumbnail_uploads
-                .load(std::sync::atomic::Ordering::Relaxed),
+    /// `segmented` 时走分片的大文件封成 v2（分段）：边读边加密，内存里只有一段，
+    /// 接收端也可以边下边解、随机读。v2 要服务端和对端都认得，没打开时一律 v1。
+    /// 小文件总是 v1——一段就装得下，分段只多一个 header，而 v1 是所有端（含 Web）
+    /// 都认的格式。
+    fn seal_into(
+        source: &std::path::Path,
+        dest: &std::path::Path,
+        segmented: bool,
+    ) -> Result<SealedPayload> {
+        use crate::attachment_crypto as crypto;
+        let source_len = std::fs::metadata(source)
+            .map_err(|e| Error::Storage(format!("stat attachment failed: {e}")))?
+            .len();
+        // 对象存储只存密文。CEK 不进日志。
+        if segmented && source_len > CHUNKED_UPLOAD_THRESHOLD as u64 {
+            let reader = std::io::BufReader::new(
+                std::fs::File::open(source)
+                    .map_err(|e| Error::Storage(format!("open attachment failed: {e}")))?,
+            );
+            let mut writer = std::io::BufWriter::new(
+                std::fs::File::create(dest)
+                    .map_err(|e| Error::Storage(format!("write sealed cache failed: {e}")))?,
+            );
+            let sealed = crypto::encrypt_attachment_stream(reader, &mut writer)
+                .map_err(|e| Error::Serialization(format!("attachment encrypt failed: {e}")))?;
+            writer
+                .into_inner()
+                .map_err(|e| Error::Storage(format!("write sealed cache failed: {e}")))?;
+            return Ok(SealedPayload {
+                size: sealed.blob_len,
+                cek_b64: sealed.cek,
+                sha256: sealed.sha256,
+                encryption_version: crypto::ENCRYPTION_VERSION_SEGMENTED,
+            });
         }
+        let plaintext = std::fs::read(source)
+            .map_err(|e| Error::Storage(format!("read attachment failed: {e}")))?;
+        let (blob, cek_b64) = crypto::encrypt_attachment(&plaintext)
+            .map_err(|e| Error::Serialization(format!("attachment encrypt failed: {e}")))?;
+        drop(plaintext);
+        let sha256 = Self::sha256_hex(&blob);
+        std::fs::write(dest, &blob)
+            .map_err(|e| Error::Storage(format!("write sealed cache failed: {e}")))?;
+        Ok(SealedPayload {
+            size: blob.len() as u64,
+            cek_b64,
+            sha256,
+            encryption_version: crypto::ENCRYPTION_VERSION_WHOLE,
+        })
     }
 
-    fn is_timeline_like_event(event: &SdkEvent) -> bool {
-        matches!(
-            event,
-            SdkEvent::TimelineUpdated { .. }
-                | SdkEvent::MessageSendStatusChanged { .. }
-                | SdkEvent::SyncEntityChanged { .. }
-                | SdkEvent::SyncChannelApplied { .. }
-                | SdkEvent::SyncAllChannelsApplied { .. }
-                | SdkEvent::TypingSent { .. }
-        )
+    fn sha256_hex(bytes: &[u8]) -> String {
+        use sha2::Digest as _;
+        let mut hasher = <sha2::Sha256 as sha2::Digest>::new();
+        hasher.update(bytes);
+        hex::encode(hasher.finalize())
     }
 
-    fn is_network_like_event(event: &SdkEvent) -> bool {
-        matches!(
-            event,
-            SdkEvent::ConnectionStateChanged { .. }
-                | SdkEvent::NetworkHintChanged { .. }
-                | SdkEvent::ResumeSyncStarted
-                | SdkEvent::ResumeSyncCompleted { .. }
-                | SdkEvent::ResumeSyncFailed { .. }
-                | SdkEvent::ResumeSyncEscalated { .. }
-                | SdkEvent::ForcedLogout { .. }
-        )
-    }
+    /// 一次附件发送要按什么次序跟服务端打交道。
+    ///
+    /// 抽出来是为了**能被测到**：这段的价值全在次序和入参上——第一次带摘要、
+    /// claim 没成时第二次**不带**摘要、两次上传用的是同一个已封装的 blob。
+    /// 写在发送函数里的话，这些只能靠读代码确认。
+    ///
+    /// 三个动作由 `io` 提供：生产是真实的 RPC/HTTP，测试是记录调用的假实现。
+    /// 这里不碰网络，也不碰文件。
+    async fn plan_attachment_upload<Io: AttachmentUploadIo>(
+        io: &mut Io,
+        // 🔴 封装好的 payload 从外面传进来，这里**没有**重新封装的能力——
+        // 再封一次就是另一串密文：摘要对不上，秒传落空，服务端多存一份。
+        payload: &SealedPayload,
+    ) -> Result<(UploadedFileInfo, String)> {
+        let sha256 = payload.sha256.as_str();
 
-    pub fn new(config: PrivchatConfig) -> Self {
-        Self::with_runtime(config, RuntimeProvider::new_owned())
+        // 大文件走分片（RESUMABLE_UPLOAD_SPEC）：独立 RPC、独立端点，与整包互不影响。
+        if let Some(threshold) = io.chunked_threshold() {
+            if payload.size > threshold as u64 {
+                return Self::plan_chunked_upload(io, payload).await;
+            }
+        }
+
+        // 第一次带摘要，给服务端说「这串字节我已经有了」的机会。
+        let token = io.prepare(Some(sha256), payload.size as usize).await?;
+
+        if token.already_exists {
+            match io.claim(&token.token, sha256).await {
+                Ok(info) => {
+                    io.on_upload_finished();
+                    return Ok((info, token.token));
+                }
+                // 🔴 秒传没成 → 照常上传，不是发送失败。预检说「有」而 claim 拿不到
+                // 会正常发生：那份内容的记录里没有一条是这个人现在读得到的，或者
+                // 候选在这中间失效了。
+                Err(e) if Self::claim_miss_should_reupload(&e) => {
+                    eprintln!("[SDK.actor] claim missed, falling back to a normal upload: {e}");
+                    // 🔴 第二次**不带**摘要。带着的话服务端还会说「已经有了」，
+                    // 又绕回 claim —— 转成死循环。
+                    let token = io.prepare(None, payload.size as usize).await?;
+                    let info = io.upload(&token, payload).await?;
+                    io.on_upload_finished();
+                    return Ok((info, token.token));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let info = io.upload(&token, payload).await?;
+        io.on_upload_finished();
+        Ok((info, token.token))
     }
 
-    pub fn with_runtime(config: PrivchatConfig, runtime_provider: RuntimeProvider) -> Self {
-        let configured_data_dir = config.data_dir.clone();
-        let data_dir_for_self = configured_data_dir.clone();
-        // 附件 file queue 的路由键在构造期固化：首发与重试必须落到同一条有序队列。
-        let file_route_key = config.endpoints.first().map(Self::endpoint_route_key);
-        let (tx, mut rx) = mpsc::channel::<Command>(64);
-        let actor_cmd_tx = tx.clone();
-        let (event_tx, _) = broadcast::channel::<SdkEvent>(256);
-        let actor_event_tx = event_tx.clone();
-        let event_seq = Arc::new(AtomicU64::new(0));
-        let actor_event_seq = event_seq.clone();
-        let event_history = Arc::new(StdMutex::new(VecDeque::new()));
-        let actor_event_history = event_history.clone();
-        let event_history_limit = DEFAULT_EVENT_HISTORY_LIMIT;
-        let task_registry = TaskRegistry::new();
-        let startup_error = Arc::new(StdMutex::new(None));
-        let actor_startup_error = startup_error.clone();
-        let presence_cache = Arc::new(StdMutex::new(HashMap::new()));
-        let actor_presence_cache = presence_cache.clone();
-        let pending_media_jobs: Arc<StdMutex<HashMap<String, oneshot::Sender<MediaJobResult>>>> =
-            Arc::new(StdMutex::new(HashMap::new()));
-        let actor_pending_media_jobs = pending_media_jobs.clone();
-        let download_manager = media_download::DownloadManager::new();
-        let actor_download_manager = download_manager.clone();
-        // CODEX-8：worker 位取自持久化 installation id（稳定设备身份），替代 pid/启动毫秒的
-        // 临时派生 —— 重启后 worker 位不漂移；配合服务端 (sender, device, local_message_id)
-        // 幂等命名空间，雪花碰撞面收敛到单设备内（由毫秒+序列保证唯一）。
-        let (machine_id, data_center_id) = stable_snowflake_worker_bits(&config.data_dir);
-        let snowflake = snowflake_me::Snowflake::builder()
-            .machine_id(&|| Ok(machine_id))
-            .data_center_id(&|| Ok(data_center_id))
-            .finalize()
-            .map(Arc::new)
-            .unwrap_or_else(|_| {
-                // Fallback: use defaults
-                Arc::new(
-                    snowflake_me::Snowflake::builder()
-                        .finalize()
-                        .expect("default snowflake must work"),
-                )
-            });
-        // 账号切换的「让出」通道：计数器记事实，Notify 负责叫醒。见 State::switch_requested。
-        let switch_requested_sdk = Arc::new(AtomicU64::new(0));
-        let attachment_transfers_sdk = Arc::new(AttachmentTransferCounters::default());
-        let attachment_transfers_actor = attachment_transfers_sdk.clone();
-        let switch_processed_sdk = Arc::new(AtomicU64::new(0));
-        let switch_wakeup_sdk = Arc::new(tokio::sync::Notify::new());
-        let switch_requested_actor = switch_requested_sdk.clone();
-        let switch_processed_actor = switch_processed_sdk.clone();
-        let switch_wakeup_actor = switch_wakeup_sdk.clone();
-        let foreground_wakeup_sdk = Arc::new(tokio::sync::Notify::new());
-        let foreground_wakeup_actor = foreground_wakeup_sdk.clone();
-        let actor_snowflake = snowflake.clone();
-        let actor_task = runtime_provider.spawn(async move {
-            if actor_logs_enabled() {
-                eprintln!("[SDK.actor] loop: started");
-                if configured_data_dir.trim().is_empty() {
-                    eprintln!("[SDK.actor] storage base: <default>");
-                } else {
-                    eprintln!("[SDK.actor] storage base: {}", configured_data_dir);
+    /// 分片路径的编排（RESUMABLE_UPLOAD_SPEC §2.1 / §3.3.1）。
+    ///
+    /// - 命中 → claim；claim 没成 → **`force_upload=true` 再申请一次**（跳过预检，否则
+    ///   预检又命中、又回 claim_token，永远进不了实体上传），同一条消息只置一次。
+    /// - 传字节途中会话没了（过期 / 被清）→ 丢掉本地记录，重新申请一次；此时若已经
+    ///   完成过，预检会命中 → claim 拿到 file_id。
+    async fn plan_chunked_upload<Io: AttachmentUploadIo>(
+        io: &mut Io,
+        payload: &SealedPayload,
+    ) -> Result<(UploadedFileInfo, String)> {
+        let sha256 = payload.sha256.as_str();
+        let size = payload.size as usize;
+
+        let mut prepared = io.prepare_chunked(sha256, size, false).await?;
+        if let ChunkedPrepared::Claim { claim_token } = &prepared {
+            match io.claim(claim_token, sha256).await {
+                Ok(info) => {
+                    io.on_upload_finished();
+                    return Ok((info, claim_token.clone()));
+                }
+                Err(e) if Self::claim_miss_should_reupload(&e) => {
+                    eprintln!("[SDK.actor] claim missed, forcing a chunked upload: {e}");
+                    prepared = io.prepare_chunked(sha256, size, true).await?;
                 }
+                Err(e) => return Err(e),
             }
-            let storage = match if configured_data_dir.trim().is_empty() {
-                StorageHandle::start()
-            } else {
-                StorageHandle::start_at(PathBuf::from(configured_data_dir))
-            } {
-                Ok(s) => s,
-                Err(e) => {
-                    if let Ok(mut locked) = actor_startup_error.lock() {
-                        *locked = Some(Error::Storage(format!("storage init failed: {e}")));
+        }
+        let session = match prepared {
+            ChunkedPrepared::Session(s) => s,
+            ChunkedPrepared::Claim { .. } => {
+                return Err(Error::Transport(
+                    "force_upload=true 仍返回秒传命中，服务端违反协议".to_string(),
+                ))
+            }
+        };
+
+        match io.upload_chunked(&session, payload).await {
+            Ok(info) => {
+                io.on_upload_finished();
+                Ok((info, session.upload_token))
+            }
+            Err(Error::UploadSessionGone) => {
+                eprintln!("[SDK.actor] chunked session gone, re-preparing once");
+                io.on_upload_finished();
+                match io.prepare_chunked(sha256, size, false).await? {
+                    ChunkedPrepared::Claim { claim_token } => {
+                        let info = io.claim(&claim_token, sha256).await?;
+                        io.on_upload_finished();
+                        Ok((info, claim_token))
+                    }
+                    ChunkedPrepared::Session(again) => {
+                        let info = io.upload_chunked(&again, payload).await?;
+                        io.on_upload_finished();
+                        Ok((info, again.upload_token))
                     }
-                    eprintln!("[SDK.actor] storage init failed: {e}");
-                    return;
                 }
-            };
-            let snowflake = actor_snowflake;
-            let current_uid = storage.load_current_uid().await.ok().flatten();
-            let saved = if let Some(uid) = &current_uid {
-                storage.load_session(uid.clone()).await.ok().flatten()
-            } else {
-                None
-            };
-            let mut state = State {
-                attachment_transfers: attachment_transfers_actor,
-                config,
-                transport: None,
-                transport_events: Arc::new(tokio::sync::Mutex::new(None)),
-                session_state: SessionState::New,
-                bootstrap_completed: saved
-                    .as_ref()
-                    .map(|s| s.bootstrap_completed)
-                    .unwrap_or(false),
-                sync_coordinator: SyncCoordinator::new(),
-                snowflake,
-                storage: storage.clone(),
-                download_manager: actor_download_manager,
-                actor_tx: actor_cmd_tx.downgrade(),
-                skip_inbound_materialization_for_load_testing:
-                    SKIP_INBOUND_MATERIALIZATION_FOR_LOAD_TESTING.load(Ordering::SeqCst),
-                current_uid,
-                session_epoch: 0,
-                should_auto_reconnect: false,
-                reconnect_attempt: 0,
-                next_reconnect_at: None,
-                auth_terminal_fired: false,
-                inbound_epoch: 0,
-                last_resume_synced: None,
-                last_anti_entropy_at: Instant::now(),
-                convergence_run: None,
-                resume_run_id: 0,
-                anti_entropy_jitter: Duration::from_secs(rand::random::<u64>() % 16),
-                room_seen_msg_ids: HashMap::new(),
-                last_terminal_reason: None,
-                network_hint: NetworkHint::Unknown,
-                receive_pipeline: ReceivePipeline::default(),
-                last_sync_queued: 0,
-                last_sync_dropped_duplicates: 0,
-                last_sync_entity_events: Vec::new(),
-                video_process_hook: None,
-                link_preview_hook: None,
-                last_tmp_cleanup_day: None,
-                pending_events: Vec::new(),
-                message_cache_policy: MessageCachePolicy::default(),
-                channel_message_cache: HashMap::new(),
-                channel_cache_generation: HashMap::new(),
-                switch_requested: switch_requested_actor,
-                switch_processed: switch_processed_actor,
-                switch_wakeup: switch_wakeup_actor,
-                #[cfg(test)]
-                sync_stall_for_test: None,
-                channel_cache_lru: VecDeque::new(),
-                channel_cache_total_bytes: 0,
-                cache_debug_log: std::env::var("PRIVCHAT_CACHE_LOG").ok().as_deref() == Some("1"),
-                cache_hit_count: 0,
-                cache_miss_count: 0,
-                pending_prelogin_inbound_frames: Vec::new(),
-                active_subscriptions: HashMap::new(),
-                presence_cache: actor_presence_cache,
-                event_tx: Some(actor_event_tx.clone()),
-                event_history: Some(actor_event_history.clone()),
-                event_seq: Some(actor_event_seq.clone()),
-                event_history_limit: event_history_limit,
-                pending_media_jobs: actor_pending_media_jobs,
-                repair_queue: VecDeque::new(),
-                repair_seen: HashSet::new(),
-                repair_backoff: HashMap::new(),
-                avatar_cache: avatar_cache::AvatarCacheManager::default(),
-            };
-            let mut inbound_task: Option<tokio::task::JoinHandle<()>> = None;
-            let mut health_tick = interval(Duration::from_secs(15));
-            #[allow(unused_mut)]
-            let mut health_tick_enabled = true;
-            // repair tick 比 health tick 快:损坏的图片/顺序应当尽快自愈,但仍然是
-            // **后台**节奏——读路径只入队,一次 tick 最多处理 REPAIR_BATCH_LIMIT 条。
-            let mut repair_tick = interval(Duration::from_secs(2));
-            // Burst（默认）会把错过的 tick 补齐：后台收敛一轮跑几秒（429 会话账号每页
-            // 要 200 次本地读 + 一次批量 RPC + 最多 8 个 difference），错过的 tick 于是
-            // 连续就绪，actor 再也回不到命令处理 —— 宿主的 ensure_synced 永远收不到应答，
-            // 界面卡在「同步中」。Skip：迟到的 tick 直接丢，只保留下一次。
-            repair_tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
-            health_tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
-            loop {
-                // Backoff driver: fires when `next_reconnect_at` is due. When no retry
-                // is armed we pin a `pending()` so this arm is inert but keeps its type
-                // inside `select!`.
-                // New = 传输断开;Connected + 有本地账号 = token-refresh handoff 期间的
-                // 未认证保活态(见 AccessTokenRefreshNeeded 发射点)——两者都允许重试驱动。
-                let awaiting_reauth = state.session_state == SessionState::Connected
-                    && state.current_uid.is_some();
-                // 系统 reachability(network_hint)只是加速信号,不是永久闸门:模拟器/
-                // 挂起恢复后系统常给出 Offline 假信号且再无恢复回调,若据此一票否决,
-                // 重连永不发起(2026-07-24 实测:sim 常亮整夜「网络已断开」,实际网络
-                // 通畅)。Offline 期间降频到 ≥60s 做真实连接探测,连上即翻回 Online。
-                // ⚠️ 用 next_reconnect_at 的**绝对时刻**,绝不在此按 `now` 现算 deadline。
-                // 历史 bug(2026-07-24 生产三修):OFFLINE 分支曾写 `at.max(now + 60s)`,而 15s
-                // 的 health_tick 每次醒来都重进 select! 用新的 `now` 重算这个 max → 60s 倒计时
-                // 被永久往后推(15s<60s,retry_sleep 永远等不到截止点就被下轮重置)→ 断网后
-                // **重试永不触发**,横幅永久卡「网络已断开」。离线降频改到 schedule_next_reconnect
-                // 的**设置时**烘进 next_reconnect_at(稳定绝对值),这里只做纯读取。
-                let retry_deadline = if state.should_auto_reconnect
-                    && (state.session_state == SessionState::New || awaiting_reauth)
-                {
-                    state.next_reconnect_at
-                } else {
-                    None
-                };
-                let retry_sleep = async move {
-                    match retry_deadline {
-                        Some(at) => {
-                            let now = Instant::now();
-                            let dur = at.saturating_duration_since(now);
-                            sleep(dur).await;
-                        }
-                        None => std::future::pending::<()>().await,
-                    }
-                };
-                tokio::pin!(retry_sleep);
+            }
+            Err(e) => Err(e),
+        }
+    }
 
-                // sync 退避到期的唤醒。
-                //
-                // 只有退避门禁是不够的：门禁挡住了热循环，但如果连接一直是好的、
-                // 没有新的外部触发（重连成功 / connect / token 刷新）再来敲门，
-                // 一次临时 sync 失败就会永远停在 Retrying——退避到期了也没人来跑。
-                // 谁持有 deadline 谁就得负责叫醒，这里把它接进 actor 的定时器。
-                //
-                // 与上面 next_reconnect_at 的区别：那个存的是 Instant，这里存的是
-                // **绝对 epoch ms**，所以每轮按 now 重算剩余时长是安全的——目标时刻
-                // 本身不会移动，不存在被 health_tick 反复推迟的那类饿死。
-                let sync_retry_deadline = if state.session_state == SessionState::Authenticated {
-                    state.sync_coordinator.next_retry_at_ms()
-                } else {
-                    None
-                };
-                let sync_retry_sleep = async move {
-                    match sync_retry_deadline {
-                        Some(at_ms) => {
-                            let now_ms = chrono::Utc::now().timestamp_millis();
-                            let remaining = (at_ms - now_ms).max(0) as u64;
-                            sleep(Duration::from_millis(remaining)).await;
-                        }
-                        None => std::future::pending::<()>().await,
+    /// claim 失败是不是「服务端拿不到那份内容」这一种——只有它该退回整传。
+    ///
+    /// 服务端把「没有这份内容」和「有但你无权」说成同一句话（否则这个接口就是
+    /// 文件存在性探测器），所以两者都落在这里，退回整传对两者都是对的：真无权的
+    /// 人传自己的字节，本来就该被允许。
+    ///
+    /// 其余错误各有含义，不能一并退回：Validation 是参数不对（传一遍还是错），
+    /// Forbidden 是这次操作本身不被允许，ServiceUnavailable 是瞬时竞争、该重试。
+    fn claim_miss_should_reupload(e: &Error) -> bool {
+        matches!(
+            e,
+            Error::Server { code, .. }
+                if *code == ErrorCode::ResourceNotFound as u32
+        )
+    }
+
+    /// 一条 outbox 任务只封装**一次**，结果落盘；重试读回同一份。
+    ///
+    /// 🔴 不这样做的话，秒传对「重试」这个最常见的重复来源完全无效：加密用随机
+    /// CEK/nonce，重新封装出来的字节不同、摘要不同，预检必然 miss，于是同一条
+    /// 逻辑消息在服务端留下第二份物理文件。上一次上传如果其实成功了、只是响应
+    /// 丢了，那第二份就永远没人引用。
+    ///
+    /// 缓存随这条消息的托管目录一起存在，发送成功后由调用方删掉。
+    ///
+    /// 明文从 `source` 读、密文直接写进 `cache_path`，整份密文不经过内存（v2 时
+    /// 明文也不）。`segmented` 见 [`State::seal_into`]。
+    fn seal_once(
+        cache_path: &std::path::Path,
+        source: &std::path::Path,
+        segmented: bool,
+    ) -> Result<SealedPayload> {
+        let meta_path = cache_path.with_extension("sealed.json");
+
+        if let Some(cached) = Self::reusable_sealed_cache(cache_path, &meta_path, source, segmented)
+        {
+            return Ok(cached);
+        }
+
+        // 顺序不能变：先撤掉旧标记，再写 blob，最后立标记。
+        // 反过来的话，新 blob 落地而旧 metadata 还在的那一瞬间，缓存看着是完整的。
+        let _ = std::fs::remove_file(&meta_path);
+        let tmp = cache_path.with_extension("sealed.tmp");
+        let sealed = match Self::seal_into(source, &tmp, segmented).and_then(|sealed| {
+            std::fs::rename(&tmp, cache_path)
+                .map(|_| sealed)
+                .map_err(|e| Error::Storage(format!("write sealed cache failed: {e}")))
+        }) {
+            Ok(sealed) => sealed,
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp);
+                return Err(e);
+            }
+        };
+        let meta = SealedCacheMeta {
+            cek: sealed.cek_b64.clone(),
+            sha256: sealed.sha256.clone(),
+            encryption_version: sealed.encryption_version,
+        };
+        let meta_tmp = meta_path.with_extension("tmp");
+        std::fs::write(
+            &meta_tmp,
+            serde_json::to_string(&meta)
+                .map_err(|e| Error::Serialization(format!("encode sealed meta: {e}")))?,
+        )
+        .and_then(|_| std::fs::rename(&meta_tmp, &meta_path))
+        .map_err(|e| Error::Storage(format!("write sealed meta failed: {e}")))?;
+        Ok(sealed)
+    }
+
+    /// 已落盘的封装缓存还能不能直接用；不能就返回 `None`，由调用方重封。
+    ///
+    /// 🔴 metadata 是**提交标记**：它存在，才代表旁边那个 blob 写完了。
+    /// 而且必须重算摘要——两个文件分两次 rename，中间崩一次就可能凑出
+    /// 「新 blob + 旧 CEK/摘要」。那种组合每次上传都会被服务端拒，而且自己
+    /// 好不了：每次重试都读回同一份对不上的缓存。
+    fn reusable_sealed_cache(
+        cache_path: &std::path::Path,
+        meta_path: &std::path::Path,
+        source: &std::path::Path,
+        segmented: bool,
+    ) -> Option<SealedPayload> {
+        use sha2::Digest as _;
+        let meta: SealedCacheMeta =
+            serde_json::from_str(&std::fs::read_to_string(meta_path).ok()?).ok()?;
+        // v2 开关关掉之后（服务端或对端回退了），之前封好的 v2 缓存不能再发出去。
+        if meta.encryption_version == crate::attachment_crypto::ENCRYPTION_VERSION_SEGMENTED
+            && !segmented
+        {
+            return None;
+        }
+        let size = std::fs::metadata(cache_path).ok()?.len();
+        if size == 0 || crate::media_blob::hash_file(cache_path).ok()? != meta.sha256 {
+            return None;
+        }
+        // 摘要对上只说明**字节**没坏。CEK 坏了而摘要还对（元数据被改、
+        // 或两个文件来自不同轮次）的话，传上去的密文没人解得开，服务端
+        // 拒了之后下一轮又读回同一份——永久失败。
+        //
+        // 解一次就能判：AES-GCM 的认证标签在密钥不对时直接失败。这只发生
+        // 在重试路径（首次封装根本不读缓存），多这一次解密换的是不会卡死。
+        // 流式解进摘要里，和明文文件的摘要比：v2 全程只占一段内存。
+        let mut decoded = <sha2::Sha256 as sha2::Digest>::new();
+        crate::attachment_crypto::decrypt_downloaded_attachment_stream(
+            meta.encryption_version,
+            Some(&meta.cek),
+            std::io::BufReader::new(std::fs::File::open(cache_path).ok()?),
+            &mut decoded,
+        )
+        .ok()?;
+        if hex::encode(decoded.finalize()) != crate::media_blob::hash_file(source).ok()? {
+            return None;
+        }
+        Some(SealedPayload {
+            size,
+            cek_b64: meta.cek,
+            sha256: meta.sha256,
+            encryption_version: meta.encryption_version,
+        })
+    }
+
+    /// 删掉封装缓存。发送真正完成之后才调用——在那之前任何一次重试都还需要它。
+    fn drop_sealed_cache(cache_path: &std::path::Path) {
+        let _ = std::fs::remove_file(cache_path);
+        let _ = std::fs::remove_file(cache_path.with_extension("sealed.json"));
+    }
+
+    /// 分片上传：查缺口 → 并发传分片 → complete（RESUMABLE_UPLOAD_SPEC §3 / §5 / §6）。
+    ///
+    /// 同时在路上的分片最多 `max_parallel_parts` 个，乱序落地由 `ResumableUpload`
+    /// 记账；进度事件只在某一片被服务端确认之后才发。
+    ///
+    /// 🔴 **每一轮都以服务端的 `missing` 为准**。本地记账只是乐观估计，一旦对不上
+    /// （重试、上次崩溃、换了设备），必须听服务端的。
+    ///
+    /// 🔴 分片按 offset 从**密文缓存文件**读（决策 13），整份密文从不进内存。
+    async fn upload_in_chunks(
+        &self,
+        session: &ChunkedSession,
+        mime_type: &str,
+        sealed_cache: &std::path::Path,
+        total: u64,
+        cek_b64: String,
+        encryption_version: i32,
+        local_message_id: &str,
+    ) -> Result<UploadedFileInfo> {
+        use crate::resumable_upload::{
+            ChunkTimer, ChunkVerdict, Gap, ResumableUpload, CHUNK_RETRIES,
+        };
+
+        let base = session.upload_url.trim_end_matches('/').to_string();
+        let token = session.upload_token.as_str();
+        let client = reqwest::Client::new();
+        let plan = crate::resumable_upload::UploadPlan::for_base_unit(session.base_unit);
+
+        // 先问一次：可能是续传（上次断在半路），也可能一片都还没传，也可能已经完成。
+        let status = self.fetch_upload_status(&client, &base, token).await?;
+        let (missing, completed) = match status {
+            UploadStatusOutcome::Status { missing, completed, .. } => (missing, completed),
+            UploadStatusOutcome::SessionGone => return Err(Error::UploadSessionGone),
+        };
+        if completed {
+            // 上次 complete 成功但回执没到：直接再要一次结果。
+            return self
+                .complete_upload(&client, &base, token, mime_type, cek_b64, encryption_version)
+                .await;
+        }
+        let mut up = ResumableUpload::from_missing(total, plan, &missing);
+        self.emit_upload_progress(local_message_id, up.progress());
+        // 登记到退出为止：App 进后台时从这里导出剩下的分片，见 [`background_transfer`]。
+        let registration = self.uploads.register(
+            local_message_id,
+            background_transfer::UploadEndpoint {
+                base: base.clone(),
+                token: token.to_string(),
+                sealed_cache: sealed_cache.to_path_buf(),
+                total,
+                piece_size: plan.max_request_size,
+            },
+            up.gaps(),
+        );
+
+        let file = std::fs::File::open(sealed_cache)
+            .map_err(|e| Error::Storage(format!("open sealed cache failed: {e}")))?;
+        let read_piece = |offset: u64, len: u64| -> Result<Vec<u8>> {
+            use std::os::unix::fs::FileExt;
+            let mut buf = vec![0u8; len as usize];
+            file.read_exact_at(&mut buf, offset).map_err(|e| {
+                Error::Storage(format!("sealed cache shorter than the session total: {e}"))
+            })?;
+            Ok(buf)
+        };
+
+        // 🔴 失败预算是「自上次有进展以来」的，不是每片各算一份。
+        //
+        // 并发时一次断网会让在路上的几片**一起**失败，那是一次失败不是四次：每片带着
+        // 发出时的失败计数（epoch），只有发出之后还没人记过失败的那片才记一次。
+        let mut failures_since_progress = 0u32;
+        // 要对齐时先停止发新片，等在路上的全部落地再查 status——否则刚拿到的
+        // `missing` 马上就被那几片的结论推翻。
+        let mut resync_pending = false;
+        let mut parts: tokio::task::JoinSet<(Gap, u32, std::time::Duration, ChunkVerdict)> =
+            tokio::task::JoinSet::new();
+
+        loop {
+            // 剩下的分片交给了平台：不再领新片，在路上的落地后就退出，把 actor 让出来。
+            // 登记留着；全部导入或收回之后 drain 重新发起，从 `/status` 对齐接着传。
+            if registration.is_handed_off() {
+                if parts.is_empty() {
+                    return Err(Error::Transport(
+                        "剩下的分片已交给平台的后台传输".to_string(),
+                    ));
+                }
+            } else if !resync_pending {
+                while let Some(part) = up.claim_part() {
+                    let bytes = read_piece(part.offset, part.len)?;
+                    let digest = crate::resumable_upload::chunk_digest(&bytes);
+                    let client = client.clone();
+                    let base = base.clone();
+                    let token = token.to_string();
+                    let epoch = failures_since_progress;
+                    let bandwidth = self.bandwidth.clone();
+                    parts.spawn(async move {
+                        // 先付后发：并发的几片排同一个桶，限的是整条链路。计时从付清之后
+                        // 开始，限速的等待不该被 ChunkSizer 当成链路慢。
+                        bandwidth
+                            .acquire(bandwidth::TransferDirection::Upload, part.len)
+                            .await;
+                        let timer = ChunkTimer::start();
+                        let verdict =
+                            Self::put_chunk(&client, &base, &token, part.offset, bytes, &digest)
+                                .await;
+                        (part, epoch, timer.elapsed(), verdict)
+                    });
+                }
+            }
+
+            let Some(joined) = parts.join_next().await else {
+                if !resync_pending {
+                    // 没有在路上的，也领不到新的：全部确认完了。
+                    break;
+                }
+                resync_pending = false;
+                match self.fetch_upload_status(&client, &base, token).await? {
+                    UploadStatusOutcome::Status { missing, completed: true, .. } if missing.is_empty() => {
+                        break;
                     }
-                };
-                tokio::pin!(sync_retry_sleep);
+                    UploadStatusOutcome::Status { missing, .. } => up.resync_missing(&missing),
+                    UploadStatusOutcome::SessionGone => return Err(Error::UploadSessionGone),
+                }
+                registration.set_missing(up.gaps());
+                self.emit_upload_progress(local_message_id, up.progress());
+                continue;
+            };
+            let (part, epoch, elapsed, verdict) = joined
+                .map_err(|e| Error::Transport(format!("分片上传任务异常退出: {e}")))?;
 
-                tokio::select! {
-                    _ = &mut sync_retry_sleep => {
-                        if actor_logs_enabled() {
-                            eprintln!("[SDK.actor] sync retry deadline reached");
+            match verdict {
+                ChunkVerdict::Ok => {
+                    up.on_chunk_ok(part, elapsed);
+                    registration.set_missing(up.gaps());
+                    self.emit_upload_progress(local_message_id, up.progress());
+                    failures_since_progress = 0;
+                }
+                ChunkVerdict::RetryChunk => {
+                    up.on_part_failed(part);
+                    if epoch == failures_since_progress {
+                        failures_since_progress += 1;
+                        if failures_since_progress > CHUNK_RETRIES {
+                            return Err(Error::Transport(format!(
+                                "分片上传连续 {failures_since_progress} 次没有进展（offset={}）",
+                                part.offset
+                            )));
                         }
-                        let _ = state.ensure_synced(|event| {
-                            emit_sequenced_event(
-                                &actor_event_tx,
-                                &actor_event_history,
-                                &actor_event_seq,
-                                event_history_limit,
-                                event,
-                            );
-                        }).await;
+                        tokio::time::sleep(crate::resumable_upload::retry_delay(
+                            failures_since_progress,
+                        ))
+                        .await;
                     }
-                    _ = repair_tick.tick() => {
-                        if state.session_state != SessionState::Shutdown
-                            && !state.repair_queue.is_empty()
-                        {
-                            state.drain_projection_repairs().await;
-                        }
-                        // Phase 3 后台收敛：一次一小批 stale 频道（run_anti_entropy_once
-                        // 内部用 batch_get_channel_pts 批量比对 + WiFi/蜂窝预算）。
-                        // 失败只退避重试，**不动 readiness** —— 用户此刻能正常收发。
-                        //
-                        // 命令优先：队列里有待处理命令时不开新一轮。收敛是后台工作，
-                        // 让宿主等应答（进而卡住界面）是本末倒置。
-                        if !rx.is_empty()
-                            && state.session_state == SessionState::Authenticated
-                            && state.convergence_run.is_some()
-                        {
-                            continue;
+                }
+                ChunkVerdict::Resync => {
+                    up.on_part_failed(part);
+                    if epoch == failures_since_progress {
+                        failures_since_progress += 1;
+                        if failures_since_progress > CHUNK_RETRIES {
+                            return Err(Error::Transport(
+                                "反复与服务端区间对不齐，交回重试".to_string(),
+                            ));
                         }
-                        if state.session_state == SessionState::Authenticated
-                            && state.convergence_run.is_some()
-                            && state.sync_coordinator.convergence_retry_ready(
-                                chrono::Utc::now().timestamp_millis(),
-                            )
-                        {
-                            // A conversation open is foreground work. If it arrives while a
-                            // batch/difference RPC is in flight, cancel this idempotent pass and
-                            // return to the command loop instead of making the UI wait for the
-                            // background network timeout. `notify_one` retains a permit across
-                            // the check/start race above.
-                            let convergence = run_preemptible_background(
-                                &foreground_wakeup_actor,
-                                state.run_anti_entropy_once(),
-                            )
-                            .await;
-                            let Some(convergence) = convergence else {
-                                continue;
-                            };
-                            match convergence {
-                                // 只有「完整扫过一圈 + 没有留下未修的 stale」才算收敛。
-                                // 用「本页修了 0 条」判定会在扫到第一页干净数据时就
-                                // 过早宣布完成，后面的频道再也不会被检查。
-                                Ok(page) if page.is_converged() => {
-                                    if let Some(stats) = state.convergence_run.take() {
-                                        println!(
-                                            "[SDK.resume] run={} phase=3 converged scanned={} channels_repaired={} messages_applied={}",
-                                            state.resume_run_id,
-                                            page.page_scanned,
-                                            page.channels_repaired,
-                                            page.messages_applied
-                                        );
-                                        state.sync_coordinator.set_convergence(
-                                            crate::sync_coordinator::Convergence::Converged,
-                                            chrono::Utc::now().timestamp_millis(),
-                                        );
-                                        // 全量收敛完成才发 ResumeSyncCompleted —— 保持它
-                                        // 「这一轮全做完了」的原始语义。
-                                        state.queue_resume_completed(stats);
-                                    }
-                                }
-                                Ok(page) => {
-                                    // unknown 不为零也要出声：那是异常规模指标
-                                    if page.stale_found > 0
-                                        || page.deferred > 0
-                                        || page.unknown_channels > 0
-                                    {
-                                        println!(
-                                            "[SDK.resume] run={} phase=3 page scanned={} stale={} channels_repaired={} messages_applied={} deferred={} unknown={}",
-                                            state.resume_run_id,
-                                            page.page_scanned,
-                                            page.stale_found,
-                                            page.channels_repaired,
-                                            page.messages_applied,
-                                            page.deferred,
-                                            page.unknown_channels
-                                        );
-                                    }
-                                    state.sync_coordinator.set_convergence(
-                                        if page.stale_found > 0 {
-                                            crate::sync_coordinator::Convergence::Repairing
-                                        } else {
-                                            crate::sync_coordinator::Convergence::Scanning
-                                        },
-                                        chrono::Utc::now().timestamp_millis(),
-                                    );
-                                }
-                                Err(err) => {
-                                    let now_ms = chrono::Utc::now().timestamp_millis();
-                                    let (attempt, next_retry_at_ms) =
-                                        state.sync_coordinator.backoff_convergence(now_ms);
-                                    tracing::warn!(
-                                        error = %err,
-                                        attempt,
-                                        next_retry_at_ms,
-                                        "convergence pass failed; backing off"
-                                    );
-                                }
-                            }
-                            for event in state.take_pending_events() {
-                                emit_sequenced_event(
-                                    &actor_event_tx,
-                                    &actor_event_history,
-                                    &actor_event_seq,
-                                    event_history_limit,
-                                    event,
-                                );
-                            }
-                            // 让调度器有机会把排队的命令喂进来
-                            tokio::task::yield_now().await;
-                        }
-                        continue;
                     }
-                    _ = health_tick.tick(), if health_tick_enabled => {
-                        if state.session_state == SessionState::Shutdown {
-                            continue;
-                        }
-
-                        // ── 活性看门狗（2026-07-24 生产事故三修根治）──────────────────
-                        // 断网→联网后横幅永久卡「网络已断开」的架构根因:重试驱动可能走进
-                        // inert 死态——retry 守卫兜底(session 短暂非 New 时)会把
-                        // next_reconnect_at 置 None,而唯一复活入口(SetNetworkHint
-                        // offline→online)依赖 iOS reachability 的恢复回调,该回调在挂起/
-                        // 长断网后常常**根本不投递** → 驱动永不再武装 → 永久卡死。
-                        //
-                        // 修:15s health_tick 无条件充当看门狗——只要有「想在线」的会话
-                        // (should_auto_reconnect + 传输断开 New / 待重认证 Connected+uid)
-                        // 而驱动已 inert(next_reconnect_at==None),立即重新武装。这样
-                        // 恢复不再依赖任何系统回调:OFFLINE 期驱动仍每≤60s 做真实 TCP 探测,
-                        // 网络一旦真回来,下一次 try_auto_reconnect 成功即把 hint 复位并认证。
-                        // 活性看门狗:想在线(should_auto_reconnect)+ 传输断开(New)/待重认证,
-                        // 而重连驱动 inert(next_reconnect_at 空)→ 重新武装。honor should_auto_reconnect
-                        // 是必须的:手动/后台 Disconnect 也把 session 设成 New 且保留 uid,唯一区分
-                        // 「想重连」vs「显式离线」的信号就是这个标志,不能绕过。
-                        let wants_online = state.should_auto_reconnect
-                            && (matches!(state.session_state, SessionState::New)
-                                || (state.session_state == SessionState::Connected
-                                    && state.current_uid.is_some()));
-                        if wants_online && state.next_reconnect_at.is_none() {
-                            eprintln!(
-                                "[SDK.actor] liveness watchdog: retry driver inert (state={:?}, hint={:?}); re-arming reconnect",
-                                state.session_state, state.network_hint
-                            );
-                            state.reconnect_attempt = 0;
-                            state.next_reconnect_at = Some(Instant::now());
-                        }
-
-                        // ⚠️ 这里曾是 `if !network_hint.is_online() { continue; }` —— 一个
-                        // 假 Offline 就把整个 tick 尾部(连接探测 / anti-entropy / **出站队列
-                        // 排空**)全部跳过。后果(2026-07-26 生产实测):inbound 推送走的是活着的
-                        // transport、不看 hint,所以**用户能正常收消息**,但自己发的消息因为
-                        // outbox 永远排不空而卡在「发送中」,既不成功也不失败。
-                        // 原则同前:reachability 只是提示,绝不做硬闸门——各操作自身的前置条件
-                        // (session_state / bootstrap_completed / 队列非空)才是真正的门槛,真断网
-                        // 时它们各自失败即可,代价只是一次廉价的探测。
+                    resync_pending = true;
+                }
+                // 提前返回时 `parts` 被丢弃，还在路上的请求随之中止。
+                ChunkVerdict::StartOver => return Err(Error::UploadSessionGone),
+                ChunkVerdict::Fatal => {
+                    return Err(Error::Transport(format!(
+                        "分片上传被服务端终局拒绝（offset={}）",
+                        part.offset
+                    )));
+                }
+            }
+        }
 
-                        // Probe live connections for silent drops (NAT/proxy timeouts).
-                        // apply_transport_health will arm the reconnect driver on loss.
-                        if matches!(
-                            state.session_state,
-                            SessionState::Connected | SessionState::LoggedIn | SessionState::Authenticated
-                        ) {
-                            let is_connected = state.probe_connection().await;
-                            if !is_connected {
-                                stop_inbound_task(&mut inbound_task).await;
-                                if let Some((from, to)) = state.apply_transport_health(false) {
-                                    emit_sequenced_event(
-                                        &actor_event_tx,
-                                        &actor_event_history,
-                                        &actor_event_seq,
-                                        event_history_limit,
-                                        SdkEvent::ConnectionStateChanged { from, to },
-                                    );
-                                }
-                            }
-                        }
+        let info = self
+            .complete_upload(&client, &base, token, mime_type, cek_b64, encryption_version)
+            .await?;
+        self.attachment_transfers
+            .body_uploads
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        Ok(info)
+    }
 
-                        let _ = state.cleanup_tmp_dirs_if_needed().await;
+    /// 查进度：服务端**同时**回已收与缺失，客户端直接用 `missing`。
+    async fn fetch_upload_status(
+        &self,
+        client: &reqwest::Client,
+        base: &str,
+        token: &str,
+    ) -> Result<UploadStatusOutcome> {
+        let resp = match Self::with_upload_token(client.get(format!("{base}/status")), token)
+            .send()
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => return Err(Error::Transport(format!("查询上传进度失败: {e}"))),
+        };
+        let status = resp.status();
+        let json: serde_json::Value = resp
+            .json()
+            .await
+            .map_err(|e| Error::Serialization(format!("上传进度响应无法解析: {e}")))?;
+        let code = json["code"].as_u64().unwrap_or(u64::MAX) as u32;
+        if code == 20613 {
+            return Ok(UploadStatusOutcome::SessionGone);
+        }
+        if status.is_server_error() {
+            return Err(Error::Transport(format!("上传进度查询服务端错误: {status}")));
+        }
+        if code != 0 {
+            return Err(Error::Transport(format!(
+                "上传进度查询失败: code={code} {}",
+                json["message"].as_str().unwrap_or("")
+            )));
+        }
+        let parse = |key: &str| -> Result<Vec<(u64, u64)>> {
+            let Some(arr) = json["data"][key].as_array() else {
+                return Err(Error::Serialization(format!("上传进度响应缺少 {key}")));
+            };
+            arr.iter()
+                .map(|r| {
+                    match (r["offset"].as_u64(), r["length"].as_u64()) {
+                        (Some(o), Some(l)) => Ok((o, l)),
+                        _ => Err(Error::Serialization("上传进度响应里的区间格式不对".to_string())),
+                    }
+                })
+                .collect()
+        };
+        Ok(UploadStatusOutcome::Status {
+            received: parse("received")?,
+            missing: parse("missing")?,
+            completed: json["data"]["completed"].as_bool().unwrap_or(false),
+        })
+    }
 
-                        if state.session_state == SessionState::Authenticated
-                            && state.bootstrap_completed
-                            && state.last_anti_entropy_at.elapsed()
-                                >= Duration::from_secs(60) + state.anti_entropy_jitter
-                        {
-                            // Advance before RPC so a transient failure cannot turn the
-                            // 15s health tick into an unbounded retry loop.
-                            state.last_anti_entropy_at = Instant::now();
-                            if let Err(err) = state.run_anti_entropy_once().await {
-                                tracing::warn!(error = %err, "anti-entropy pass failed");
-                            }
-                        }
+    async fn put_chunk(
+        client: &reqwest::Client,
+        base: &str,
+        token: &str,
+        offset: u64,
+        bytes: Vec<u8>,
+        digest: &str,
+    ) -> crate::resumable_upload::ChunkVerdict {
+        use crate::resumable_upload::{chunk_verdict, ChunkVerdict};
+        let resp =
+            Self::with_upload_token(client.put(format!("{base}/chunk?offset={offset}")), token)
+                .header("X-Chunk-SHA256", digest)
+                .body(bytes)
+                .send()
+                .await;
+        let Ok(resp) = resp else {
+            // 连不上/超时：这一片值得再试，别把网络抖动当成协议失败。
+            return ChunkVerdict::RetryChunk;
+        };
+        // 🔴 先取 HTTP 状态再消费 body：未知码/解析失败时靠它区分「瞬时 5xx 可重试」
+        // 与「客户端 4xx 终局」，与 TS SDK 逐字一致。
+        let is_server_error = resp.status().is_server_error();
+        let code = resp
+            .json::<serde_json::Value>()
+            .await
+            .ok()
+            .and_then(|j| j["code"].as_u64())
+            .map(|c| c as u32);
+        chunk_verdict(code, is_server_error)
+    }
 
-                        if state.should_process_outbound_queue() {
-                            let _ = state.drain_outbound_queues().await;
-                        }
-                        for event in state.take_pending_events() {
-                            emit_sequenced_event(
-                                &actor_event_tx,
-                                &actor_event_history,
-                                &actor_event_seq,
-                                event_history_limit,
-                                event,
-                            );
-                        }
-                    }
-                    _ = &mut retry_sleep => {
-                        // Retry driver: the deadline fired. Guard again because state
-                        // may have changed between pin and wake.
-                        let awaiting_reauth = state.session_state == SessionState::Connected
-                            && state.current_uid.is_some();
-                        if (state.session_state != SessionState::New && !awaiting_reauth)
-                            || !state.should_auto_reconnect
-                        {
-                            state.next_reconnect_at = None;
-                            continue;
-                        }
-                        let attempt_n = state.reconnect_attempt.saturating_add(1);
-                        eprintln!("[SDK.actor] auto_reconnect_attempt #{attempt_n}");
-                        let from = state.session_state.as_connection_state();
-                        match state
-                            .try_auto_reconnect(
-                                &mut inbound_task,
-                                &actor_event_tx,
-                                &actor_event_history,
-                                &actor_event_seq,
-                                event_history_limit,
-                            )
-                            .await
-                        {
-                            Ok(next) => {
-                                state.session_state = next;
-                                state.reset_reconnect_backoff();
-                                state.sync_coordinator.note_network_available();
-                                // 握手 + 认证全通了，解除 Terminal 闸门，后续如果再过期还能再触发一次。
-                                state.auth_terminal_fired = false;
-                                // 真实连接成功是网络在线的最强证据:覆盖系统 reachability 的
-                                // Offline 假信号(否则 hint 卡 Offline 会继续压制其它路径)。
-                                if !state.network_hint.is_online() {
-                                    eprintln!("[SDK.actor] probe connected while hint=Offline; reset hint to Unknown");
-                                    state.network_hint = NetworkHint::Unknown;
-                                }
-                                eprintln!("[SDK.actor] auto_reconnect_result ok attempt=#{attempt_n}");
-                                // [硬化] 重连成功后必须：① 重启 inbound task（订阅新 transport + bump epoch）
-                                start_inbound_task(&mut state, actor_cmd_tx.clone(), &mut inbound_task)
-                                    .await;
-                                if realtime_trace_enabled() { eprintln!(
-                                    "[SDK_RECONNECT_OK] new_epoch={} state={:?} uid={:?}",
-                                    state.inbound_epoch, state.session_state, state.current_uid
-                                ); }
-                                // ② 防御性 replay：若有 push 帧在登录前缓冲（current_uid 恢复后），立即重放，
-                                //    避免 message/presence/typing 卡在 prelogin 队列里收不到。
-                                if let Err(err) = state.replay_prelogin_inbound_frames().await {
-                                    eprintln!("[SDK.actor] prelogin replay failed after reconnect: {err}");
-                                }
-                                // 重连后重放活跃订阅：恢复 presence_changed / typing / room 广播。
-                                state.replay_subscriptions().await;
-                                if state.session_state == SessionState::Authenticated
-                                    && state.bootstrap_completed
-                                {
-                                    // ③ resume sync 把重连/断网期间漏掉的增量补回来
-                                    if let Err(err) = state.ensure_synced(|event| {
-                                        emit_sequenced_event(
-                                            &actor_event_tx,
-                                            &actor_event_history,
-                                            &actor_event_seq,
-                                            event_history_limit,
-                                            event,
-                                        );
-                                    }).await {
-                                        eprintln!("[SDK.actor] resume sync failed after reconnect: {err}");
-                                    }
-                                }
-                                emit_sequenced_event(
-                                    &actor_event_tx,
-                                    &actor_event_history,
-                                    &actor_event_seq,
-                                    event_history_limit,
-                                    SdkEvent::ConnectionStateChanged {
-                                        from,
-                                        to: state.session_state.as_connection_state(),
-                                    },
-                                );
-                            }
-                            Err(e) => {
-                                eprintln!("[SDK.actor] auto_reconnect_result fail attempt=#{attempt_n}");
-                                if e.is_auth_terminal() {
-                                    // Terminal 认证错误（token 撤销/设备不匹配等）由
-                                    // trigger_forced_logout 统一收口：停 inbound、断 transport、
-                                    // 清状态、发 ForcedLogout。继续自动重连只会把过期 token 反复
-                                    // 投给服务端打满日志。
-                                    trigger_forced_logout(
-                                        &mut state,
-                                        &mut inbound_task,
-                                        &actor_event_tx,
-                                        &actor_event_history,
-                                        &actor_event_seq,
-                                        event_history_limit,
-                                        &e,
-                                        ForcedLogoutSource::ConnectAuth,
-                                    )
-                                    .await;
-                                } else if matches!(
-                                    e.auth_kind(),
-                                    Some(AuthErrorKind::Recoverable)
-                                ) {
-                                    // Recoverable（10002 AccessTokenExpired 等）：
-                                    // 停 auto-reconnect，避免拿过期 token 反复打服务端。
-                                    //
-                                    // 关键：try_auto_reconnect 失败时已 disconnect 释放 transport，
-                                    // state 仍是 New。如果直接 emit 事件让 host refresh + authenticate，
-                                    // 但 SessionState=New 下 Action::Authenticate 会被状态机拒绝
-                                    // （spec TOKEN_REFRESH_SPEC §7：New/Shutdown 报错）。
-                                    //
-                                    // 所以在 emit 事件**之前**主动 connect 一次重建 transport，把 state
-                                    // 转回 Connected。host 收到事件后调 sdk.authenticate(uid, newToken,
-                                    // deviceId) 直接走 Connected→Authenticated 即可，不需要先 connect
-                                    // （否则 host 的 connect 又触发 try_auto_reconnect 用旧 token 撞
-                                    // 10002 死循环）。
-                                    //
-                                    // 若 connect 也失败（网络真断了）则保持 New + emit 事件，host recover
-                                    // 会因 sdk.authenticate 报 InvalidState 失败；下次 retry / 网络恢复
-                                    // 还会再 emit，最终自愈。
-                                    let code = e.auth_error_code().unwrap_or(0);
-                                    let message = e.to_string();
-                                    eprintln!(
-                                        "[SDK.actor] auto_reconnect: recoverable auth error code={code}; reconnect transport then emit AccessTokenRefreshNeeded"
-                                    );
-                                    let pre_state = state.session_state.as_connection_state();
-                                    if let Ok(()) = timeout(state.connect_timeout_total(), state.connect()).await
-                                        .unwrap_or(Err(Error::Transport("reconnect timeout".to_string())))
-                                    {
-                                        if let Ok(next_state) = state.session_state.can(Action::Connect) {
-                                            state.session_state = next_state;
-                                            emit_sequenced_event(
-                                                &actor_event_tx,
-                                                &actor_event_history,
-                                                &actor_event_seq,
-                                                event_history_limit,
-                                                SdkEvent::ConnectionStateChanged {
-                                                    from: pre_state,
-                                                    to: state.session_state.as_connection_state(),
-                                                },
-                                            );
-                                        }
-                                    } else {
-                                        eprintln!(
-                                            "[SDK.actor] auto_reconnect recoverable: post-fail reconnect failed; emit event but host authenticate likely InvalidState"
-                                        );
-                                    }
-                                    // 活性兜底(2026-07-24 生产事故):这里曾 should_auto_reconnect=false
-                                    // 永久关闭重连,把恢复责任完全交给宿主的一次性 refresh —— 宿主那一次
-                                    // 失败(如撞上服务端发版窗口)就死锁在「网络已断开」,且唯一救活入口
-                                    // 是前台/网络回调,常亮设备永远等不到。改为降频保活:60s 一轮继续
-                                    // 探测,每轮撞 10002 会再 emit 一次事件(宿主侧 mutex 去重),无论
-                                    // 宿主死活 SDK 自身永远有心跳式重试;宿主 refresh 成功后 authenticate
-                                    // 会 reset backoff 自然接管。
-                                    state.reconnect_attempt = state.reconnect_attempt.saturating_add(1);
-                                    state.next_reconnect_at =
-                                        Some(Instant::now() + Duration::from_secs(60));
-                                    eprintln!(
-                                        "[SDK.actor] auto_reconnect: token-refresh handoff to host; keep-alive retry in 60s"
-                                    );
-                                    emit_sequenced_event(
-                                        &actor_event_tx,
-                                        &actor_event_history,
-                                        &actor_event_seq,
-                                        event_history_limit,
-                                        SdkEvent::AccessTokenRefreshNeeded { code, message },
-                                    );
-                                } else {
-                                    state.schedule_next_reconnect();
-                                }
-                            }
-                        }
-                    }
-                    cmd = rx.recv() => {
-                        let Some(cmd) = cmd else { break; };
-                        match cmd {
-                    Command::Connect { resp } => {
-                        if actor_logs_enabled() {
-                            eprintln!("[SDK.actor] loop: cmd connect");
-                        }
-                        {
-                            // ⚠️ 这里**不再**用 network_hint 做硬闸门(2026-07-25 生产事故):
-                            // 系统 reachability 断线/挂起后常卡 Offline 且不再投递恢复回调,
-                            // 据此一票否决会让宿主的「前台/网络恢复/用户点重连」全部空转。
-                            // 显式 Connect 本身就是「我要上线」的强意图信号:直接尝试,真断网
-                            // 自然失败并由重试驱动接管;成功则下方把 hint 复位(真实连接=真源)。
-                            if !state.network_hint.is_online() {
-                                eprintln!(
-                                    "[SDK.actor] connect requested while hint=Offline; attempting anyway (hint is advisory)"
-                                );
-                            }
-                            // A user-driven Connect expresses the intent to stay online —
-                            // enable auto-reconnect up-front so retry fires even if this
-                            // first attempt fails (e.g. server temporarily down).
-                            state.should_auto_reconnect = true;
-                            let from_state = state.session_state.as_connection_state();
-                            let had_local_session = state.current_uid.is_some();
-                            let transport_connected = state.is_connected().await;
-                            let plan = plan_connect(
-                                state.session_state,
-                                had_local_session,
-                                transport_connected,
-                            );
-                            let already_ready =
-                                matches!(&plan, Ok(ConnectPlan::AlreadyReady));
-                            let mut transition_from = from_state;
+    async fn complete_upload(
+        &self,
+        client: &reqwest::Client,
+        base: &str,
+        token: &str,
+        mime_type: &str,
+        cek_b64: String,
+        encryption_version: i32,
+    ) -> Result<UploadedFileInfo> {
+        let resp = Self::with_upload_token(client.post(format!("{base}/complete")), token)
+            .json(&serde_json::json!({ "cek": cek_b64, "encryption_version": encryption_version }))
+            .send()
+            .await
+            .map_err(|e| Error::Transport(format!("complete request failed: {e}")))?;
+        let status = resp.status();
+        let envelope = resp
+            .json::<serde_json::Value>()
+            .await
+            .map_err(|e| Error::Serialization(format!("decode complete response: {e}")))?;
+        let code = envelope["code"].as_u64().unwrap_or(0) as u32;
+        if code == 20613 {
+            return Err(Error::UploadSessionGone);
+        }
+        if !status.is_success() {
+            return Err(Error::Transport(format!(
+                "complete failed: status={status} code={code} message={}",
+                envelope["message"].as_str().unwrap_or("")
+            )));
+        }
+        Self::parse_uploaded_file_info(&envelope, mime_type)
+    }
 
-                            // Queries are observers. Explicit connect is the intent boundary
-                            // that reconciles a stale logical session with transport reality.
-                            if !transport_connected {
-                                if let Some((from, to)) = state.apply_transport_health(false) {
-                                    stop_inbound_task(&mut inbound_task).await;
-                                    emit_sequenced_event(
-                                        &actor_event_tx,
-                                        &actor_event_history,
-                                        &actor_event_seq,
-                                        event_history_limit,
-                                        SdkEvent::ConnectionStateChanged { from, to },
-                                    );
-                                    transition_from = to;
-                                }
-                            }
-
-                            // 关键不变量：宿主在已登录场景下点 connect()（前台 fast-track / 网络
-                            // 恢复 / 后台 disconnect 后回前台），final state 必须**仍是** Authenticated，
-                            // 否则任何要 authenticated session 的 RPC（markRead / sendMessage /
-                            // search 等）会立刻挂"current: Connected"。
-                            //
-                            // 触发场景：
-                            //  1) SYNC_READY 透明 fast-track：state=Authenticated，transport 还没掉
-                            //  2) 后台超时 disconnect 后回前台：state=New，transport=None，但 storage
-                            //     里的 session snapshot 还在
-                            //  3) 网络抖动：state=New（health probe 降级过），transport=None
-                            //
-                            // 三种场景都用 try_auto_reconnect 单步法解决：它 atomic 地 connect +
-                            // load session + re-authenticate，自然收敛到 Authenticated（有 snapshot）
-                            // 或 Connected（无 snapshot：冷启动 / 已 logged out / forced）。
-                            //
-                            // 之前用过"先转 Connected 再尝试 auto-restore"两步法，transport 偶尔在
-                            // `state.connect()` 内部短路没真正重建 → auto-restore race 到失败 → 卡 Connected。
-                            let result: Result<()> = match plan {
-                                Err(e) => Err(e),
-                                Ok(ConnectPlan::AlreadyReady) => Ok(()),
-                                Ok(ConnectPlan::RestorePersistedSession) => {
-                                    match state
-                                        .try_auto_reconnect(
-                                            &mut inbound_task,
-                                            &actor_event_tx,
-                                            &actor_event_history,
-                                            &actor_event_seq,
-                                            event_history_limit,
-                                        )
-                                        .await
-                                    {
-                                        Ok(next) => {
-                                            state.session_state = next;
-                                            Ok(())
-                                        }
-                                        Err(e) => Err(e),
-                                    }
-                                }
-                                Ok(ConnectPlan::ConnectTransportOnly) => {
-                                    // 冷启动且无本地 session（用户没登录过）/ logged out / forced：
-                                    // 走简单 connect+transition；不 authenticate（没 session 可用）。
-                                    match state.session_state.can(Action::Connect) {
-                                        Ok(next_state) => {
-                                            match timeout(state.connect_timeout_total(), state.connect()).await
-                                            {
-                                                Ok(r) => {
-                                                    if r.is_ok() {
-                                                        state.session_state = next_state;
-                                                    }
-                                                    r
-                                                }
-                                                Err(_) => Err(Error::Transport("connect timeout".to_string())),
-                                            }
-                                        }
-                                        Err(e) => Err(e),
-                                    }
-                                }
-                            };
-
-                            if result.is_ok() {
-                                state.reset_reconnect_backoff();
-                                if !already_ready {
-                                    state.sync_coordinator.note_network_available();
-                                }
-                                // 连上了就是网络可达的铁证:复位系统 reachability 的 Offline
-                                // 假信号,否则 hint 会继续压制退避节奏与其它路径。
-                                if !state.network_hint.is_online() {
-                                    eprintln!("[SDK.actor] connect ok while hint=Offline; reset hint to Unknown");
-                                    state.network_hint = NetworkHint::Unknown;
-                                }
-                                // 用户主动 Connect 成功 = 新的登录回合。清 Terminal 闸门
-                                // 和上一轮 terminal reason，让后续 Authenticate 若再遇到
-                                // Terminal 错可以再次触发 ForcedLogout。
-                                state.auth_terminal_fired = false;
-                                state.last_terminal_reason = None;
-                                if !already_ready {
-                                    start_inbound_task(
-                                        &mut state,
-                                        actor_cmd_tx.clone(),
-                                        &mut inbound_task,
-                                    )
-                                    .await;
-                                    if realtime_trace_enabled() { eprintln!(
-                                        "[SDK_RECONNECT_OK] (connect-path) new_epoch={} state={:?} uid={:?}",
-                                        state.inbound_epoch, state.session_state, state.current_uid
-                                    ); }
-                                    // [硬化] 防御性 replay：push 帧可能在登录前缓冲，current_uid 恢复后立即重放。
-                                    if let Err(err) = state.replay_prelogin_inbound_frames().await {
-                                        eprintln!("[SDK.actor] connect: prelogin replay failed: {err}");
-                                    }
-                                    // 重连后重放活跃订阅：恢复 presence_changed / typing / room 广播。
-                                    state.replay_subscriptions().await;
-                                }
+    /// 单凭据（决策 7）：分片端点只认 `X-Upload-Token`。
+    fn with_upload_token(
+        req: reqwest::RequestBuilder,
+        upload_token: &str,
+    ) -> reqwest::RequestBuilder {
+        req.header("X-Upload-Token", upload_token)
+    }
 
-                                // 若 try_auto_reconnect 把 session 拉回 Authenticated 且
-                                // bootstrap 早已完成，触发一次 resume_sync 把背景期间漏掉的
-                                // 增量同步上来（与 retry driver 的成功分支一致）。
-                                // 入口可能是 Authenticated（fast-track）或 New（后台 disconnect 后回前台），
-                                // 只要本地有 session 且当前已 Authenticated，就跑 resume_sync。
-                                // 先把「连接已就绪」告诉宿主，再跑 resume sync。
-                                //
-                                // 顺序反过来会让状态事件被整轮同步挡住：会话多的账号 ensure_synced
-                                // 要跑很久，期间 UI 收不到 Authenticated，状态条一直停在「服务器
-                                // 连接中」——而连接其实早就建好、数据也正在回来。连接态是既成事实，
-                                // 不该等数据层动作完成才通知；同步进度本来就有 resume_sync_* 事件
-                                // 单独播报。
-                                let to_state = state.session_state.as_connection_state();
-                                if transition_from != to_state {
-                                    emit_sequenced_event(
-                                        &actor_event_tx,
-                                        &actor_event_history,
-                                        &actor_event_seq,
-                                        event_history_limit,
-                                        SdkEvent::ConnectionStateChanged {
-                                            from: transition_from,
-                                            to: to_state,
-                                        },
-                                    );
-                                }
+    async fn upload_file_bytes(
+        &self,
+        upload_url: &str,
+        upload_token: &str,
+        filename: &str,
+        mime_type: &str,
+        // 🔴 已经封好的那个 blob，**不在这里再加密一次**：重新加密会产出另一串字节，
+        // 与 prepare 时报的摘要对不上，服务端会拒；重试同理必须复用同一个 blob。
+        blob: Vec<u8>,
+        cek_b64: String,
+        encryption_version: i32,
+    ) -> Result<UploadedFileInfo> {
+        self.bandwidth
+            .acquire(bandwidth::TransferDirection::Upload, blob.len() as u64)
+            .await;
+        let part = reqwest::multipart::Part::bytes(blob)
+            .file_name(filename.to_string())
+            .mime_str(mime_type)
+            .map_err(|e| Error::Serialization(format!("invalid mime_type for upload part: {e}")))?;
+        let form = reqwest::multipart::Form::new()
+            .part("file", part)
+            .text("encryption_version", encryption_version.to_string())
+            .text("cek", cek_b64);
+        let response = reqwest::Client::new()
+            .post(upload_url)
+            .header("X-Upload-Token", upload_token)
+            .multipart(form)
+            .send()
+            .await
+            .map_err(|e| Error::Transport(format!("upload request failed: {e}")))?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_else(|_| String::new());
+            return Err(Error::Transport(format!(
+                "upload failed: status={} body={}",
+                status, text
+            )));
+        }
+        let envelope = response
+            .json::<serde_json::Value>()
+            .await
+            .map_err(|e| Error::Serialization(format!("decode upload response: {e}")))?;
+        let info = Self::parse_uploaded_file_info(&envelope, mime_type)?;
+        // 传成了才计：失败的那次会重试，算进去等于把同一份内容数很多遍。
+        // 缩略图走同一个接口，靠规范文件名区分（见 media_store::payload_filename）。
+        let counter = if filename.starts_with("thumb") {
+            &self.attachment_transfers.thumbnail_uploads
+        } else {
+            &self.attachment_transfers.body_uploads
+        };
+        counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        Ok(info)
+    }
 
-                                // 连接已就绪，先答复宿主再跑 resume sync。
-                                //
-                                // 把 resp.send 留到同步之后，宿主的 connect() 就要挂到整轮
-                                // 同步结束才返回：会话多的账号能挂几十秒，而宿主往往在 connect()
-                                // 前后切状态条文案（「连接中」→「同步中」→清空），于是状态条
-                                // 卡死在「服务器连接中」——连接其实早就建好了。
-                                // connect 的语义是「连接建立成功」，不该捎带数据层的耗时。
-                                let _ = resp.send(Ok(()));
+    /// 把上传 / 秒传取用的响应解析成同一个结构。
+    ///
+    /// 两条路径的响应形状在服务端就是逐字段一致的，这里也只写一份——
+    /// 写两份解析，迟早有一份先于另一份腐坏。
+    fn parse_uploaded_file_info(
+        envelope: &serde_json::Value,
+        fallback_mime: &str,
+    ) -> Result<UploadedFileInfo> {
+        // server `SERVICE_RESPONSE_ENVELOPE_SPEC` v1.1：HTTP 接口统一 `{code, message, data}`；
+        // RPC 直接给业务对象。两种都收。
+        let code = envelope.get("code").and_then(|v| v.as_u64()).unwrap_or(0);
+        if code != 0 {
+            let message = envelope
+                .get("message")
+                .and_then(|v| v.as_str())
+                .unwrap_or("upload failed");
+            return Err(Error::Transport(format!(
+                "upload failed: code={code} message={message}"
+            )));
+        }
+        let value = envelope.get("data").cloned().unwrap_or_else(|| envelope.clone());
+        let file_id = value
+            .get("file_id")
+            .and_then(|v| {
+                v.as_str()
+                    .map(|s| s.to_string())
+                    .or_else(|| v.as_u64().map(|n| n.to_string()))
+                    .or_else(|| v.as_i64().map(|n| n.to_string()))
+            })
+            .ok_or_else(|| Error::Serialization("upload response missing file_id".to_string()))?;
+        let storage_source_id = value
+            .get("storage_source_id")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0) as u32;
+        let file_url = value
+            .get("file_url")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_string();
+        let thumbnail_url = value
+            .get("thumbnail_url")
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string());
+        let file_size = value.get("file_size").and_then(|v| v.as_u64()).unwrap_or(0);
+        let original_size = value.get("original_size").and_then(|v| v.as_u64());
+        let width = value
+            .get("width")
+            .and_then(|v| v.as_u64())
+            .map(|n| n as u32);
+        let height = value
+            .get("height")
+            .and_then(|v| v.as_u64())
+            .map(|n| n as u32);
+        let resp_mime = value
+            .get("mime_type")
+            .and_then(|v| v.as_str())
+            .unwrap_or(fallback_mime)
+            .to_string();
+        Ok(UploadedFileInfo {
+            file_id,
+            storage_source_id,
+            file_url,
+            thumbnail_url,
+            file_size,
+            original_size,
+            width,
+            height,
+            mime_type: resp_mime,
+        })
+    }
 
-                                if had_local_session
+    /// 秒传命中：拿 token 换**自己的** file_id，一个字节都不传。
+    async fn claim_existing_file(&mut self, token: &str, sha256: &str) -> Result<UploadedFileInfo> {
+        let value: serde_json::Value = self
+            .rpc_call_json(
+                "file/claim_existing".to_string(),
+                serde_json::json!({ "token": token, "sha256": sha256 }),
+            )
+            .await?;
+        let info = Self::parse_uploaded_file_info(&value, "application/octet-stream")?;
+        // 成功才计：失败的 claim 会退回整传，算进去会让「省了多少带宽」虚高。
+        self.attachment_transfers
+            .claims
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        Ok(info)
+    }
+
+    /// 发一份附件：**封装一次** → 预检 → 命中就换 id，未命中才传那同一个 blob。
+    ///
+    /// 顺序是整件事的关键：秒传按「最终上传字节」判重，而加密用随机 CEK/nonce，
+    /// 所以必须先封装、对封装结果求摘要，再拿这个摘要去预检。预检之后重新加密，
+    /// 字节就变了，命中率恒为 0。
+    async fn send_one_attachment(
+        &mut self,
+        user_id: u64,
+        filename: String,
+        mime_type: String,
+        file_type: String,
+        // 明文在哪儿。从文件流式封装，整份内容不进内存。
+        source: &std::path::Path,
+        // 封装结果缓存到哪儿。同一条 outbox 任务重试时读回它，绝不重新封装。
+        sealed_cache: &std::path::Path,
+        // 进度事件归到哪条消息名下。UI 靠它更新对应的气泡。
+        local_message_id: &str,
+    ) -> Result<(UploadedFileInfo, String)> {
+        // 封装一次，之后的分支都用它。
+        let segmented = self.segmented_uploads.load(Ordering::Relaxed);
+        let payload = Self::seal_once(sealed_cache, source, segmented)?;
+        let server_identity = self.server_identity_for_uploads();
+        let mut io = LiveAttachmentUploadIo {
+            state: self,
+            user_id,
+            filename,
+            mime_type,
+            file_type,
+            local_message_id: local_message_id.to_string(),
+            sealed_cache,
+            // 服务器身份用「这次上传要打到哪个 base」——切环境（本地/生产）之后
+            // 旧会话在新服务器上根本不存在，必须作废重来。
+            server_identity,
+        };
+        Self::plan_attachment_upload(&mut io, &payload).await
+    }
+
+    async fn upload_callback(
+        &mut self,
+        user_id: u64,
+        token: &str,
+        uploaded: &UploadedFileInfo,
+    ) -> Result<()> {
+        let payload = serde_json::json!({
+            "token": token,
+            "file_id": uploaded.file_id,
+            "file_url": uploaded.file_url,
+            "thumbnail_url": uploaded.thumbnail_url,
+            "file_size": uploaded.file_size,
+            "original_size": uploaded.original_size,
+            "mime_type": uploaded.mime_type,
+            "width": uploaded.width,
+            "height": uploaded.height,
+            "user_id": user_id,
+            "status": "uploaded",
+        });
+        let value: serde_json::Value = self
+            .rpc_call_json(routes::file::UPLOAD_CALLBACK.to_string(), payload)
+            .await?;
+        if let Some(ok) = value.as_bool() {
+            if ok {
+                return Ok(());
+            }
+            return Err(Error::Transport("upload callback rejected".to_string()));
+        }
+        Ok(())
+    }
+
+    /// 附件消息的 wire content —— **唯一**一处构造。
+    ///
+    /// 两条路径都走它：本地文件上传完之后，和复用一份服务端已有附件时。分成两份写
+    /// 的话，接收端能不能渲染就取决于发送方走了哪条路——而那种分叉只会在用户手里
+    /// 被发现。
+    /// 消息里该显示的文件名。
+    ///
+    /// 上传用的名字是规范化过的 `payload.{ext}`——那是磁盘布局，不是内容。用户看见的
+    /// 应该是这份文件本来的名字，它由客户端在建占位符时写进 `extra.file_name`
+    /// （见 protocol 的 `LocalAttachmentMetadata`）。没有就退回上传名。
+    ///
+    /// 🔴 只取最后一段：名字可能来自远端消息，不许带路径。
+    fn wire_display_filename(extra: &str, upload_filename: &str) -> String {
+        serde_json::from_str::<serde_json::Value>(extra)
+            .ok()
+            .and_then(|v| {
+                v.get("file_name")
+                    .and_then(|n| n.as_str())
+                    .map(|s| s.rsplit(['/', '\\']).next().unwrap_or(s).trim().to_string())
+            })
+            .filter(|s| !s.is_empty() && s != "." && s != "..")
+            .unwrap_or_else(|| upload_filename.to_string())
+    }
+
+    fn build_attachment_wire_content(input: AttachmentWireInput<'_>) -> serde_json::Value {
+        // 图片消息协议必须带缩略图引用（server 校验拒绝缺失）。缩略图未产出时把
+        // 原图 file 引用为缩略图（接收端按缩略图链路下载原图渲染），与 TS SDK 一致；
+        // 视频/文件不强制。
+        let (thumbnail_file_id, thumbnail_url) = match input.thumbnail {
+            Some((id, url)) => (Some(id), url.to_string()),
+            None if input.file_type == "image" => {
+                (Some(input.file_id), input.file_url.to_string())
+            }
+            None => (None, String::new()),
+        };
+
+        let mut content = if let Some(thumb_file_id) = thumbnail_file_id {
+            // Scheme B：缩略图也是独立 file，接收端走 thumbnail_file_id -> file/get_url -> cek
+            // 统一下载解密。**CEK 永不进消息 metadata**。thumbnail_url 仅作 legacy 明文 fallback。
+            serde_json::json!({
+                "file_type": input.file_type,
+                "file_id": input.file_id,
+                "thumbnail_file_id": thumb_file_id,
+                "filename": input.filename,
+                "mime_type": input.mime_type,
+                "storage_source_id": input.storage_source_id,
+                "file_size": input.file_size,
+                "file_url": input.file_url,
+                "thumbnail_url": thumbnail_url,
+                // 原图尺寸（客户端加密前解码得到）。加密后服务端拿不到密文图片尺寸，
+                // 必须客户端带上，否则接收端 UI 没法按原比例渲染气泡（只能正方形兜底）。
+                "width": input.width.unwrap_or(0),
+                "height": input.height.unwrap_or(0),
+            })
+        } else {
+            serde_json::json!({
+                "file_type": input.file_type,
+                "file_id": input.file_id,
+                "filename": input.filename,
+                "mime_type": input.mime_type,
+                "storage_source_id": input.storage_source_id,
+                "file_size": input.file_size,
+                "file_url": input.file_url,
+                "thumbnail_url": input.main_thumbnail_url,
+                "width": input.width.unwrap_or(0),
+                "height": input.height.unwrap_or(0),
+            })
+        };
+
+        // 按消息类型独立合并 metadata：Voice 与 Video 的协议形态不同，不共用逻辑。
+        if input.message_type == (privchat_protocol::message::ContentMessageType::Voice as i32) {
+            Self::merge_voice_metadata(input.extra, &mut content);
+        } else if input.message_type
+            == (privchat_protocol::message::ContentMessageType::Video as i32)
+        {
+            if let (Some(duration), Some(obj)) = (input.duration, content.as_object_mut()) {
+                obj.insert("duration".to_string(), serde_json::Value::from(duration));
+            }
+            Self::merge_video_metadata(input.extra, &mut content);
+        }
+
+        content
+    }
+
+    /// 这次发送该用哪份封装缓存。
+    ///
+    /// 来源是接手后的成品时，用**源目录**里那份——它是收附件时留下的原始密文。
+    /// 复用它，秒传预检就能命中，一个字节都不用传；重新封装则会产出另一串字节，
+    /// 按定义就是另一个物理文件。
+    ///
+    /// 缓存过期或从来没有（老消息、下载时没留）就退回本条消息自己的目录，照常封装
+    /// 上传——功能不受影响，只是这次省不掉。
+    /// 封装缓存只有**两个文件都在**才算数：`seal_once` 拿 metadata 当提交标记，
+    /// 缺一个就当没有——缺了还用，就会拿半份缓存去上传。
+    fn committed_sealed_cache(path: std::path::PathBuf) -> Option<std::path::PathBuf> {
+        if path.exists() && path.with_extension("sealed.json").exists() {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    fn sealed_cache_for_send(
+        content: &str,
+        user_root: &std::path::Path,
+        files_dir: &std::path::Path,
+        cache_name: &str,
+    ) -> std::path::PathBuf {
+        let own = files_dir.join(cache_name);
+        let raw = content.strip_prefix("file://").unwrap_or(content);
+
+        // 🔴 收到的附件带着**原始密文**的旁挂缓存（下载时留下的 `{文件名}.sealed`）。
+        // 认它，重发才可能秒传：重新封装会用新的随机 CEK/nonce，密文一变摘要就变，
+        // 服务端认不出这是同一份内容，只能整传。
+        //
+        // 下载落点不一定在托管目录（App 转发一条还没下载过的附件时落在 cache/），
+        // 所以判据是「旁边有没有这份缓存」，不是「路径在不在托管树里」。
+        //
+        // 🔴 只有主文件能认它：旁挂缓存装的是**这个文件**的密文，缩略图是另一份内容。
+        // 缩略图先于主文件上传，若也认这条路径，会拿缩略图的密文覆盖掉主文件的缓存，
+        // 秒传反而永远打不中。
+        if cache_name == "body.sealed" {
+            let sidecar = std::path::Path::new(raw).file_name().and_then(|name| {
+                let p = std::path::Path::new(raw)
+                    .with_file_name(format!("{}.sealed", name.to_string_lossy()));
+                Self::committed_sealed_cache(p)
+            });
+            if let Some(sidecar) = sidecar {
+                return sidecar;
+            }
+        }
+
+        let Some(source) = Self::managed_source_path(content, user_root) else {
+            return own;
+        };
+        let Some(dir) = source.parent() else {
+            return own;
+        };
+        Self::committed_sealed_cache(dir.join(cache_name)).unwrap_or(own)
+    }
+
+    /// 这份文件是不是**接手后的成品**（收到的附件、被转发的原件）。
+    ///
+    /// 托管附件落在 `{user_root}/files/{yyyymm}/{message_id}/`（见
+    /// `media_store::get_message_dir`）。用户刚从相册选的文件在这之外。
+    ///
+    /// 判据用路径而不是另存一份标记：这个区分本身就是真的，不需要再维护一份可能
+    /// 与事实不符的元数据。
+    fn managed_source_path(content: &str, user_root: &std::path::Path) -> Option<std::path::PathBuf> {
+        let path = content.strip_prefix("file://").unwrap_or(content);
+        let path = std::path::Path::new(path);
+        path.starts_with(user_root.join("files")).then(|| path.to_path_buf())
+    }
+
+    fn source_is_already_managed(content: &str, user_root: &std::path::Path) -> bool {
+        Self::managed_source_path(content, user_root).is_some()
+    }
+
+    /// 把发送来源放到这条消息的托管正文位置 `body_path`。
+    ///
+    /// 来源就是 `body_path` 本身（重试，或者 App 直接写进了托管目录）时什么都不做——
+    /// 拷给自己会先把目标截断。否则拷到临时名再改名盖过去：重试时 `body_path` 可能
+    /// 已经是 blob 库的硬链接（见 `media_blob`），原地写会把所有引用同一份内容的
+    /// 消息一起改掉。
+    ///
+    /// 异步拷：单 actor 跑着收消息、同步和事件发布，几十上百 MB 的附件同步拷会把
+    /// 这些一起卡住。
+    async fn stage_outbound_body(
+        source: &std::path::Path,
+        body_path: &std::path::Path,
+    ) -> std::io::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+        let from = tokio::fs::metadata(source).await?;
+        if let Ok(to) = tokio::fs::metadata(body_path).await {
+            if from.dev() == to.dev() && from.ino() == to.ino() {
+                return Ok(());
+            }
+        }
+        let tmp = body_path.with_extension("staging.tmp");
+        let staged = match tokio::fs::copy(source, &tmp).await {
+            Ok(_) => tokio::fs::rename(&tmp, body_path).await,
+            Err(e) => Err(e),
+        };
+        if staged.is_err() {
+            let _ = tokio::fs::remove_file(&tmp).await;
+        }
+        staged
+    }
+
+    async fn process_outbound_file(
+        &mut self,
+        message: &StoredMessage,
+        local_message_id: u64,
+        payload: Vec<u8>,
+    ) -> Result<SendMessageResponse> {
+        // 命令里不带文件字节。附件由 SDK 托管在本地，`message.content` 就是它的
+        // 路径——把几十上百 MB 复制进 SQLite 只是同一份数据存两遍，还要跟着事务
+        // 一起写。空 payload 因此是**正常情况**：从托管路径读。
+        //
+        // 旧 sled 队列里的项内嵌了字节，搬过来后仍然带着，按原样发。
+        //
+        // 🔴 文件本身**不读进内存**：拷进托管目录、从那里流式封装。几十上百 MB 的
+        // 视频整份读进来，内存里就同时有明文和密文两份。
+        // Extract original filename from content (before server overwrites it)
+        let original_filename = std::path::Path::new(&message.content)
+            .file_name()
+            .and_then(|v| v.to_str())
+            .map(|s| s.to_string())
+            .filter(|s| !s.is_empty() && !s.starts_with('['));
+
+        // Determine MIME from original filename (best effort)
+        let mime_type = original_filename
+            .as_deref()
+            .map(|n| Self::guess_mime_type(n).to_string())
+            .unwrap_or_else(|| {
+                Self::default_mime_for_message_type(message.message_type).to_string()
+            });
+
+        // Canonical payload filename: payload.{ext} (Spec §7.5 v2)
+        let filename =
+            media_store::payload_filename_with_fallback(&mime_type, original_filename.as_deref());
+
+        let file_type =
+            Self::guess_file_type(message.message_type, &filename, &mime_type).to_string();
+
+        let storage_paths = self.storage.get_storage_paths().await?;
+        let user_root = PathBuf::from(&storage_paths.user_root);
+        let files_dir =
+            media_store::get_message_dir(&user_root, message.message_id as i64, message.created_at);
+        std::fs::create_dir_all(&files_dir)
+            .map_err(|e| Error::Storage(format!("create files dir failed: {e}")))?;
+
+        let body_path = files_dir.join(&filename);
+        let meta_path = files_dir.join(media_store::META_FILENAME);
+        if payload.is_empty() {
+            let source = message
+                .content
+                .strip_prefix("file://")
+                .unwrap_or(&message.content);
+            Self::stage_outbound_body(std::path::Path::new(source), &body_path)
+                .await
+                .map_err(|e| {
+                    Error::InvalidState(format!("attachment source unreadable at {source}: {e}"))
+                })?;
+        } else {
+            // 🔴 先 unlink 再写：重试时这里可能已经是 blob 库的硬链接（见 `media_blob`），
+            // 原地写会把所有引用同一份内容的消息一起改掉。
+            let _ = std::fs::remove_file(&body_path);
+            std::fs::write(&body_path, &payload)
+                .map_err(|e| Error::Storage(format!("write body file failed: {e}")))?;
+        }
+        let mut body_size = std::fs::metadata(&body_path)
+            .map_err(|e| Error::Storage(format!("stat body file failed: {e}")))?
+            .len();
+        if body_size == 0 {
+            return Err(Error::InvalidState(
+                "attachment payload is empty".to_string(),
+            ));
+        }
+        let mut upload_filename = filename.clone();
+
+        let mut source_width = None;
+        let mut source_height = None;
+        let mut video_duration = None;
+        let mut thumb_upload: Option<(PathBuf, String, String)> = None;
+        if file_type == "image" {
+            if let Ok(img) = Self::decode_image_oriented(&body_path) {
+                source_width = Some(img.width());
+                source_height = Some(img.height());
+            }
+            let canonical_thumb = files_dir.join(media_store::THUMB_FILENAME);
+            let managed_thumb = Self::managed_source_path(&message.content, &user_root)
+                .and_then(|source| source.parent().map(|dir| dir.join(media_store::THUMB_FILENAME)))
+                .filter(|path| path.exists());
+            // 🔴 **同一个路径不能拷给自己**。
+            //
+            // 重试同一条消息时，`managed_source_path` 解析出来的就是这条消息自己的
+            // 目录，于是 source 与 canonical 是同一个文件——`fs::copy` 会先把目标
+            // 截断，结果 `thumb.webp` 变成 0 字节。第一次发送留下的有效缩略图就这样
+            // 被自己的重试毁掉，而且毁得很隐蔽：meta.json 还记着上一次的真实大小，
+            // 磁盘上却是空文件，之后解码失败、正文密文根本来不及生成。
+            //
+            // 复用之前还要验一遍能不能解码：上一次可能正好死在写缩略图的中途。
+            // 复用不了就当作没有，走下面的重新生成——那条路本来就允许「没有缩略图」。
+            let reused = managed_thumb.and_then(|source_thumb| {
+                if source_thumb != canonical_thumb {
+                    if let Err(e) = std::fs::copy(&source_thumb, &canonical_thumb) {
+                        eprintln!("[SDK.media] 复制托管缩略图失败，改为重新生成: {e}");
+                        return None;
+                    }
+                }
+                let size = std::fs::metadata(&canonical_thumb).ok()?.len();
+                if size == 0 {
+                    eprintln!("[SDK.media] 现有缩略图是空文件，重新生成");
+                    return None;
+                }
+                match Self::decode_image_oriented(&canonical_thumb) {
+                    Ok(img) => Some((img.width(), img.height(), size)),
+                    Err(e) => {
+                        eprintln!("[SDK.media] 现有缩略图解不开，重新生成: {e}");
+                        None
+                    }
+                }
+            });
+
+            let (w, h, size) = if let Some(dims) = reused {
+                dims
+            } else {
+                // 托管来源但没有现成缩略图时**照样生成**，不报错。
+                //
+                // 「没有缩略图」是正常状态：自动下载可能失败，`thumb_status=3`
+                // 本身就是正式态，老消息也可能压根没有独立缩略图。在这里硬报错，
+                // 这类转发就永久发不出去了。
+                //
+                // 而且生成缩略图不算「二次处理」——被转发的那个文件一个字节都没动，
+                // 缩略图是协议要求的另一件小产物。要避免的是对主文件重新压缩。
+                Self::generate_image_thumbnail_sync(&body_path, &canonical_thumb, 320, 85)?
+            };
+            let _ = self
+                .storage
+                .update_thumb_status(message.message_id, 1)
+                .await;
+            thumb_upload = Some((
+                canonical_thumb,
+                "image/webp".to_string(),
+                media_store::THUMB_FILENAME.to_string(),
+            ));
+            let meta = MediaMeta {
+                source: MediaSourceMeta {
+                    original_filename: original_filename.clone().unwrap_or_default(),
+                    mime: mime_type.clone(),
+                    width: source_width,
+                    height: source_height,
+                    file_size: Some(body_size),
+                },
+                thumbnail: Some(MediaThumbnailMeta {
+                    width: Some(w),
+                    height: Some(h),
+                    file_size: Some(size),
+                    mime: Some("image/webp".to_string()),
+                }),
+                processing: Some(MediaProcessingMeta {
+                    strategy: Some("client_preprocess".to_string()),
+                    created_at: Some(chrono::Utc::now().timestamp()),
+                }),
+            };
+            std::fs::write(
+                &meta_path,
+                serde_json::to_string(&meta)
+                    .map_err(|e| Error::Serialization(format!("encode media meta failed: {e}")))?,
+            )
+            .map_err(|e| Error::Storage(format!("write media meta failed: {e}")))?;
+        } else if file_type == "video" {
+            // 🔴 来源已经在托管目录里 = 这是**接手后的成品**（收到的附件、转发的
+            // 原件），不再做第二次处理。转发一次压一次，几跳之后画质就没了，而且
+            // 每压一次字节都变，本来还能命中的秒传也一起失效。
+            //
+            // 判据是路径而不是另存一份标记：用户刚从相册选的文件在托管目录外，
+            // 收到并落盘的在里面，这个区分本身就是真的。
+            let source_is_managed = Self::source_is_already_managed(&message.content, &user_root);
+            if source_is_managed {
+                eprintln!(
+                    "[SDK.actor] process_outbound_file: source already managed, skipping re-compress"
+                );
+            }
+            let video_hook = self.effective_video_process_hook();
+            if let Some(hook) = video_hook.as_ref().filter(|_| !source_is_managed) {
+                // Hook writes compressed output in place to `payload.{ext}`.
+                // On failure the implementation must leave the file untouched.
+                let compressed_ok =
+                    hook(MediaProcessOp::Compress, &body_path, &meta_path, &body_path)
+                        .map_err(|e| Error::Storage(format!("video compress hook failed: {e}")))?;
+                if compressed_ok {
+                    body_size = std::fs::metadata(&body_path)
+                        .map_err(|e| Error::Storage(format!("stat compressed video failed: {e}")))?
+                        .len();
+                    upload_filename = filename.clone();
+                }
+            }
+            // 压缩之后再探测：尺寸可能变了。宿主在 extra 里带了的，合并时以宿主为准。
+            if let Some(probe) = video_processor::probe(&body_path) {
+                source_width = probe.width;
+                source_height = probe.height;
+                video_duration = probe.duration_secs;
+            }
+
+            let canonical_thumb = files_dir.join(media_store::THUMB_FILENAME);
+            let thumb_scratch = files_dir.join("thumb.src.jpg");
+            let mut hook_used = false;
+            if source_is_managed {
+                if let Some(source_thumb) = Self::managed_source_path(&message.content, &user_root)
+                    .and_then(|source| source.parent().map(|dir| dir.join(media_store::THUMB_FILENAME)))
+                    .filter(|path| path.exists())
+                {
+                    std::fs::copy(&source_thumb, &canonical_thumb)
+                        .map_err(|e| Error::Storage(format!("copy managed video thumbnail failed: {e}")))?;
+                    hook_used = true;
+                    let _ = self.storage.update_thumb_status(message.message_id, 1).await;
+                    thumb_upload = Some((
+                        canonical_thumb.clone(),
+                        "image/webp".to_string(),
+                        media_store::THUMB_FILENAME.to_string(),
+                    ));
+                } else {
+                    // A video can be sent without a thumbnail. Do not create
+                    // a new one from an already managed artifact.
+                    hook_used = true;
+                }
+            }
+            // Host (Kotlin/iOS) may have pre-generated thumb.webp during the send-prep
+            // loading modal. If present, trust it: set thumb_status=1 and skip both the
+            // sync hook and Plan 2 async path.
+            if canonical_thumb.exists()
+                && std::fs::metadata(&canonical_thumb)
+                    .map(|m| m.len() > 0)
+                    .unwrap_or(false)
+            {
+                hook_used = true;
+                let _ = self
+                    .storage
+                    .update_thumb_status(message.message_id, 1)
+                    .await;
+                thumb_upload = Some((
+                    canonical_thumb.clone(),
+                    "image/webp".to_string(),
+                    media_store::THUMB_FILENAME.to_string(),
+                ));
+            }
+            if !hook_used {
+                if let Some(hook) = video_hook.as_ref() {
+                    // Hook outputs JPEG; Rust re-encodes to canonical WebP (Spec §FILE_STORAGE).
+                    let ok = hook(
+                        MediaProcessOp::Thumbnail,
+                        &body_path,
+                        &meta_path,
+                        &thumb_scratch,
+                    )
+                    .map_err(|e| Error::Storage(format!("video thumbnail hook failed: {e}")))?;
+                    if ok && thumb_scratch.exists() {
+                        match Self::generate_image_thumbnail_sync(
+                            &thumb_scratch,
+                            &canonical_thumb,
+                            320,
+                            85,
+                        ) {
+                            Ok(_) => {
+                                hook_used = true;
+                                let _ = self
+                                    .storage
+                                    .update_thumb_status(message.message_id, 1)
+                                    .await;
+                                thumb_upload = Some((
+                                    canonical_thumb.clone(),
+                                    "image/webp".to_string(),
+                                    media_store::THUMB_FILENAME.to_string(),
+                                ));
+                            }
+                            Err(e) => {
+                                eprintln!("[SDK.video] re-encode thumbnail to webp failed: {e}");
+                            }
+                        }
+                        let _ = std::fs::remove_file(&thumb_scratch);
+                    }
+                }
+            }
+            // Plan 2: no sync hook registered → issue an async media job to the host
+            // (Kotlin/iOS) and block on a oneshot. `submit_media_job_result` bypasses
+            // the actor command channel because the actor is blocked here.
+            if !hook_used && self.event_tx.is_some() {
+                const VIDEO_THUMBNAIL_TIMEOUT_MS: u64 = 8_000;
+                let job_id = self
+                    .snowflake
+                    .next_id()
+                    .map(|id| id.to_string())
+                    .unwrap_or_else(|_| {
+                        chrono::Utc::now()
+                            .timestamp_nanos_opt()
+                            .unwrap_or(0)
+                            .to_string()
+                    });
+                let (job_tx, job_rx) = oneshot::channel::<MediaJobResult>();
+                {
+                    let mut locked = self
+                        .pending_media_jobs
+                        .lock()
+                        .expect("pending_media_jobs poisoned");
+                    locked.insert(job_id.clone(), job_tx);
+                }
+                let event = SdkEvent::MediaJobRequested {
+                    job_id: job_id.clone(),
+                    job_kind: "video_thumbnail".to_string(),
+                    source_path: body_path.display().to_string(),
+                    output_path: thumb_scratch.display().to_string(),
+                    mime_type: mime_type.clone(),
+                    message_id: message.message_id,
+                    timeout_ms: VIDEO_THUMBNAIL_TIMEOUT_MS,
+                };
+                if let (Some(tx), Some(history), Some(seq)) = (
+                    self.event_tx.as_ref(),
+                    self.event_history.as_ref(),
+                    self.event_seq.as_ref(),
+                ) {
+                    emit_sequenced_event(tx, history, seq, self.event_history_limit, event);
+                }
+                let wait =
+                    tokio::time::timeout(Duration::from_millis(VIDEO_THUMBNAIL_TIMEOUT_MS), job_rx)
+                        .await;
+                // Clear the entry on any exit path — host may submit after timeout.
+                if let Ok(mut locked) = self.pending_media_jobs.lock() {
+                    locked.remove(&job_id);
+                }
+                match wait {
+                    Ok(Ok(result)) if result.ok => {
+                        let out = result
+                            .output_path
+                            .as_deref()
+                            .map(std::path::PathBuf::from)
+                            .unwrap_or_else(|| thumb_scratch.clone());
+                        if out.exists() {
+                            match Self::generate_image_thumbnail_sync(
+                                &out,
+                                &canonical_thumb,
+                                320,
+                                85,
+                            ) {
+                                Ok(_) => {
+                                    hook_used = true;
+                                    let _ = self
+                                        .storage
+                                        .update_thumb_status(message.message_id, 1)
+                                        .await;
+                                    thumb_upload = Some((
+                                        canonical_thumb.clone(),
+                                        "image/webp".to_string(),
+                                        media_store::THUMB_FILENAME.to_string(),
+                                    ));
+                                }
+                                Err(e) => {
+                                    eprintln!(
+                                        "[SDK.video] plan2 re-encode thumbnail to webp failed: {e}"
+                                    );
+                                }
+                            }
+                        } else {
+                            eprintln!(
+                                "[SDK.video] plan2 host reported ok but {} missing",
+                                out.display()
+                            );
+                        }
+                        let _ = std::fs::remove_file(&thumb_scratch);
+                    }
+                    Ok(Ok(result)) => {
+                        eprintln!(
+                            "[SDK.video] plan2 host failed job {job_id}: {}",
+                            result.error.unwrap_or_else(|| "unknown".to_string())
+                        );
+                    }
+                    Ok(Err(_)) => {
+                        eprintln!("[SDK.video] plan2 job {job_id} sender dropped");
+                    }
+                    Err(_) => {
+                        eprintln!(
+                            "[SDK.video] plan2 job {job_id} timed out after {}ms",
+                            VIDEO_THUMBNAIL_TIMEOUT_MS
+                        );
+                    }
+                }
+            }
+            if !hook_used {
+                let _ = self
+                    .storage
+                    .update_thumb_status(message.message_id, 3)
+                    .await;
+            }
+
+            let (thumb_w, thumb_h, thumb_size, thumb_mime) = match thumb_upload.as_ref() {
+                Some((path, mime, _)) => {
+                    let size = std::fs::metadata(path)
+                        .map(|m| m.len())
+                        .map_err(|e| Error::Storage(format!("stat video thumb failed: {e}")))?;
+                    (None, None, Some(size), Some(mime.clone()))
+                }
+                None => (None, None, None, None),
+            };
+            let meta = MediaMeta {
+                source: MediaSourceMeta {
+                    original_filename: original_filename.clone().unwrap_or_default(),
+                    mime: mime_type.clone(),
+                    width: source_width,
+                    height: source_height,
+                    file_size: Some(body_size),
+                },
+                thumbnail: if thumb_upload.is_some() {
+                    Some(MediaThumbnailMeta {
+                        width: thumb_w,
+                        height: thumb_h,
+                        file_size: thumb_size,
+                        mime: thumb_mime,
+                    })
+                } else {
+                    None
+                },
+                processing: Some(MediaProcessingMeta {
+                    strategy: Some("client_preprocess".to_string()),
+                    created_at: Some(chrono::Utc::now().timestamp()),
+                }),
+            };
+            std::fs::write(
+                &meta_path,
+                serde_json::to_string(&meta)
+                    .map_err(|e| Error::Serialization(format!("encode media meta failed: {e}")))?,
+            )
+            .map_err(|e| Error::Storage(format!("write media meta failed: {e}")))?;
+        } else {
+            let meta = MediaMeta {
+                source: MediaSourceMeta {
+                    original_filename: original_filename.clone().unwrap_or_default(),
+                    mime: mime_type.clone(),
+                    width: None,
+                    height: None,
+                    file_size: Some(body_size),
+                },
+                thumbnail: None,
+                processing: Some(MediaProcessingMeta {
+                    strategy: Some("client_preprocess".to_string()),
+                    created_at: Some(chrono::Utc::now().timestamp()),
+                }),
+            };
+            std::fs::write(
+                &meta_path,
+                serde_json::to_string(&meta)
+                    .map_err(|e| Error::Serialization(format!("encode media meta failed: {e}")))?,
+            )
+            .map_err(|e| Error::Storage(format!("write media meta failed: {e}")))?;
+        }
+
+        eprintln!(
+            "[SDK.actor] process_outbound_file: message_id={} file_type={} mime={} body_size={} has_thumb={}",
+            message.message_id, file_type, mime_type, body_size, thumb_upload.is_some()
+        );
+
+        let uploaded_thumbnail = if let Some((thumb_path, thumb_mime, thumb_name)) = thumb_upload {
+            let thumb_size = std::fs::metadata(&thumb_path)
+                .map(|m| m.len() as i64)
+                .map_err(|e| Error::Storage(format!("stat thumb file failed: {e}")))?;
+            eprintln!(
+                "[SDK.actor] process_outbound_file: uploading thumbnail size={}",
+                thumb_size
+            );
+            let (uploaded_thumb, thumb_token) = self
+                .send_one_attachment(
+                    message.from_uid,
+                    thumb_name.clone(),
+                    thumb_mime.clone(),
+                    "image".to_string(),
+                    &thumb_path,
+                    &Self::sealed_cache_for_send(
+                        &message.content,
+                        &user_root,
+                        &files_dir,
+                        "thumb.sealed",
+                    ),
+                    &message.message_id.to_string(),
+                )
+                .await?;
+            self.upload_callback(message.from_uid, &thumb_token, &uploaded_thumb)
+                .await?;
+            Some(uploaded_thumb)
+        } else {
+            None
+        };
+
+        eprintln!(
+            "[SDK.actor] process_outbound_file: requesting upload token for main file size={}",
+            body_size
+        );
+        let body_cache =
+            Self::sealed_cache_for_send(&message.content, &user_root, &files_dir, "body.sealed");
+        // 来源路径上找不到密文，但同样的明文之前发过或收过：blob 库里那份封装缓存
+        // 链过来，`seal_once` 照常校验后复用，秒传预检就能命中。
+        if Self::committed_sealed_cache(body_cache.clone()).is_none() {
+            match crate::media_blob::hash_file(&body_path) {
+                Ok(digest) => crate::media_blob::link_sealed_for(&user_root, &digest, &body_cache),
+                Err(e) => eprintln!("[SDK.media] 明文摘要算不出，跳过封装缓存复用: {e}"),
+            }
+        }
+        let (uploaded, main_token) = self
+            .send_one_attachment(
+                message.from_uid,
+                upload_filename.clone(),
+                mime_type.clone(),
+                file_type.clone(),
+                &body_path,
+                &body_cache,
+                &message.message_id.to_string(),
+            )
+            .await?;
+        eprintln!("[SDK.actor] process_outbound_file: upload callback");
+        self.upload_callback(message.from_uid, &main_token, &uploaded)
+            .await?;
+
+        let uploaded_file_id = uploaded.file_id.parse::<u64>().map_err(|_| {
+            Error::Serialization(format!(
+                "upload response invalid numeric file_id: {}",
+                uploaded.file_id
+            ))
+        })?;
+        let thumbnail_file_id_u64 = uploaded_thumbnail
+            .as_ref()
+            .map(|uploaded| {
+                uploaded.file_id.parse::<u64>().map_err(|_| {
+                    Error::Serialization(format!(
+                        "upload response invalid numeric thumbnail_file_id: {}",
+                        uploaded.file_id
+                    ))
+                })
+            })
+            .transpose()?;
+
+        let attachment_content = Self::build_attachment_wire_content(AttachmentWireInput {
+            file_type: &file_type,
+            file_id: uploaded_file_id,
+            storage_source_id: uploaded.storage_source_id,
+            file_size: uploaded.file_size,
+            file_url: &uploaded.file_url,
+            main_thumbnail_url: uploaded.thumbnail_url.as_deref(),
+            thumbnail: thumbnail_file_id_u64.map(|id| {
+                (
+                    id,
+                    uploaded_thumbnail
+                        .as_ref()
+                        .map(|v| v.file_url.as_str())
+                        .unwrap_or_default(),
+                )
+            }),
+            // 传的是 payload.{ext}，显示的是它本来的名字。
+            filename: &Self::wire_display_filename(&message.extra, &upload_filename),
+            mime_type: &mime_type,
+            width: source_width,
+            height: source_height,
+            duration: video_duration,
+            message_type: message.message_type,
+            extra: &message.extra,
+        });
+        let content = serde_json::to_string(&attachment_content)
+            .map_err(|e| Error::Serialization(format!("encode attachment content: {e}")))?;
+        let req = self.build_send_message_request_with_content(
+            message,
+            local_message_id,
+            content.clone(),
+        )?;
+        // 🔴 这里**不删**封装缓存。`direct_send_message` 返回成功只说明服务端收下了，
+        // 本地那笔 `outbox_ack_sent` 还没提交；它要是失败，outbox 行留着、下一轮重来，
+        // 而缓存已经没了 → 重新随机加密 → 又一份物理文件。清理放在 drain 确认之后。
+        let resp = self.direct_send_message(req).await?;
+        // 发出去的正文收进 blob 库：之后再发同一份明文、或者收到别人转发回来的同一个
+        // file_id，都不用再封装 / 再下载。best-effort。
+        if let Err(e) =
+            crate::media_blob::adopt(&user_root, &body_path, Some(uploaded_file_id), Some(&body_cache))
+        {
+            eprintln!("[SDK.media] blob store adopt after send failed: {e}");
+        }
+        // 把带 width/height/file_id/thumbnail 的最终 content 回写发送端本地行。
+        // 否则本地行停在入队时的初始 content（无尺寸），发送端自己的气泡读不到宽高、
+        // 退化竖向默认 150×200（接收端拿的是 wire content，所以一直正常）。best-effort：
+        // 回写失败不影响已发出的消息，重进会话时下次读到的仍是这份 content。
+        if let Err(err) = self
+            .storage
+            .update_message_content(message.message_id, &content)
+            .await
+        {
+            eprintln!(
+                "[SDK.actor] update_message_content after send failed: message_id={} err={err}",
+                message.message_id
+            );
+        }
+        Ok(resp)
+    }
+
+    async fn rpc_call_json(
+        &mut self,
+        route: String,
+        body: serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        let timeout = self.timeout();
+        let body_bytes = serde_json::to_vec(&body)
+            .map_err(|e| Error::Serialization(format!("encode rpc body: {e}")))?;
+        let request_context = format!("rpc call route={route}");
+        let route_name = route.clone();
+        let request = RpcRequest {
+            route,
+            body: body_bytes,
+        };
+        let payload = encode_message(&request)
+            .map_err(|e| Error::Serialization(format!("encode rpc request: {e}")))?;
+        let started = Instant::now();
+        let raw = match self
+            .request_bytes(
+                Bytes::from(payload),
+                MessageType::RpcRequest as u8,
+                timeout,
+                &request_context,
+            )
+            .await
+        {
+            Ok(raw) => raw,
+            Err(e) => {
+                self.metrics.inc_counter(
+                    metrics::RPC_ERRORS_TOTAL,
+                    &[("route", route_name.as_str()), ("kind", "transport")],
+                    1,
+                );
+                return Err(e);
+            }
+        };
+        self.metrics.observe(
+            metrics::RPC_DURATION_SECONDS,
+            &[("route", route_name.as_str())],
+            metrics::LATENCY_BUCKETS,
+            started.elapsed().as_secs_f64(),
+        );
+        let rpc_resp: RpcResponse = match decode_message(&raw) {
+            Ok(resp) => resp,
+            Err(e) => {
+                self.metrics.inc_counter(
+                    metrics::RPC_ERRORS_TOTAL,
+                    &[("route", route_name.as_str()), ("kind", "decode")],
+                    1,
+                );
+                return Err(Error::Serialization(format!("decode rpc response: {e}")));
+            }
+        };
+        if let Some(recorder) = self.trace_recorder.as_mut() {
+            recorder.record_rpc_response(
+                &route_name,
+                i64::from(rpc_resp.code),
+                rpc_resp.data.as_deref(),
+            );
+        }
+        if rpc_resp.code != 0 {
+            // 认证段 (10000-10099) 单独归为 Error::Auth（非 retryable），
+            // 其它 code 交给 is_retryable_server_code 按段判定。
+            let code_u32 = rpc_resp.code as u32;
+            let is_auth = (10000..10100).contains(&code_u32);
+            self.metrics.inc_counter(
+                metrics::RPC_ERRORS_TOTAL,
+                &[
+                    ("route", route_name.as_str()),
+                    ("kind", if is_auth { "auth" } else { "server" }),
+                ],
+                1,
+            );
+            if is_auth {
+                return Err(Error::Auth(format!(
+                    "[{}] {}",
+                    rpc_resp.code, rpc_resp.message
+                )));
+            }
+            return Err(Error::Server {
+                code: code_u32,
+                message: rpc_resp.message,
+            });
+        }
+        match rpc_resp.data {
+            Some(bytes) if !bytes.is_empty() => serde_json::from_slice(&bytes)
+                .map_err(|e| Error::Serialization(format!("decode rpc data: {e}"))),
+            _ => Ok(serde_json::Value::Null),
+        }
+    }
+
+    /// 不依赖 actor 状态的一次 RPC。供**后台任务**使用。
+    ///
+    /// 与 [`State::rpc_call_typed`] 的区别：不做连接健康对账。后台请求超时本来就不构成
+    /// 断线证据（只有 `TransportError::Connection` 才是），而健康对账要 `&mut State`——
+    /// 把它一起搬出去就等于让后台工作去裁决连接状态，那是本末倒置。
+    async fn rpc_call_typed_detached<Req, Resp>(
+        transport: &TransportClient,
+        route: &str,
+        body: &Req,
+        timeout: Duration,
+    ) -> Result<Resp>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let body_bytes = serde_json::to_vec(body)
+            .map_err(|e| Error::Serialization(format!("encode {route} body: {e}")))?;
+        let request = RpcRequest {
+            route: route.to_string(),
+            body: body_bytes,
+        };
+        let payload = encode_message(&request)
+            .map_err(|e| Error::Serialization(format!("encode rpc request: {e}")))?;
+        let opt = RequestOptions::new()
+            .biz_type(MessageType::RpcRequest as u8)
+            .timeout(timeout);
+        let raw = transport
+            .request_with_options(Bytes::from(payload), opt)
+            .await
+            .map_err(|e| match e {
+                TransportError::Timeout { .. } => Error::RequestUnanswered {
+                    context: route.to_string(),
+                },
+                other => Error::Transport(format!("{route}: {other}")),
+            })?;
+        let rpc_resp: RpcResponse = decode_message(&raw)
+            .map_err(|e| Error::Serialization(format!("decode rpc response: {e}")))?;
+        if rpc_resp.code != 0 {
+            return Err(Error::Server {
+                code: rpc_resp.code as u32,
+                message: rpc_resp.message,
+            });
+        }
+        match rpc_resp.data {
+            Some(bytes) if !bytes.is_empty() => serde_json::from_slice(&bytes)
+                .map_err(|e| Error::Serialization(format!("decode rpc data: {e}"))),
+            _ => Err(Error::Serialization(format!("{route}: empty data"))),
+        }
+    }
+
+    async fn rpc_call_typed<Req, Resp>(&mut self, route: &str, body: &Req) -> Result<Resp>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let value = self
+            .rpc_call_json(
+                route.to_string(),
+                serde_json::to_value(body)
+                    .map_err(|e| Error::Serialization(format!("encode {route} body: {e}")))?,
+            )
+            .await?;
+        serde_json::from_value::<Resp>(value)
+            .map_err(|e| Error::Serialization(format!("decode {route} response: {e}")))
+    }
+
+    async fn update_channel_last_message(
+        &mut self,
+        channel_id: u64,
+        channel_type: i32,
+        content: &str,
+        timestamp_ms: i64,
+        message_id: u64,
+        from_uid: Option<u64>,
+        bump_unread: bool,
+    ) -> Result<()> {
+        let existing = self.storage.get_channel_by_id(channel_id).await?;
+        let unread_before = existing.as_ref().map(|c| c.unread_count).unwrap_or(0);
+        let prev_last_local_message_id = existing
+            .as_ref()
+            .map(|c| c.last_local_message_id)
+            .unwrap_or(0);
+        let prev_last_msg_content = existing
+            .as_ref()
+            .map(|c| c.last_msg_content.clone())
+            .unwrap_or_default();
+        let prev_last_msg_timestamp = existing.as_ref().map(|c| c.last_msg_timestamp).unwrap_or(0);
+        // 只有「这条消息就地建出新会话行」时才由这里定对端；已存在的行不碰
+        // （upsert SQL 用 COALESCE 保留已存值）。
+        let mut new_channel_peer_user_id: Option<u64> = None;
+        let (channel_name, channel_remark, avatar, top, mute, unread_count) =
+            if let Some(c) = existing {
+                (
+                    c.channel_name,
+                    c.channel_remark,
+                    c.avatar,
+                    c.top,
+                    c.mute,
+                    if bump_unread {
+                        c.unread_count.saturating_add(1)
+                    } else {
+                        c.unread_count
+                    },
+                )
+            } else {
+                let current_uid = self
+                    .current_uid
+                    .as_ref()
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .unwrap_or_default();
+                // channel_type 客户端约定：1=DM，2=群，其它=房间。
+                //
+                // **名字一律留空**，DM 和群一样。名字的真源是 user / group 实体，
+                // 这里知道的只有 uid——把 uid 写进 channel_name 就是把一串数字写成了
+                // 「名字」：列表查询看到非空的 channel_name 就直接拿去显示，用户看到的
+                // 是 `100000007`。邀请码注册的新用户第一眼看到的就是这个（欢迎消息
+                // 先于对端 user 实体到达，本地会话行由这条消息就地建出来）。
+                //
+                // 群那条分支早就踩过同一个坑（见下方保留的注释），DM 这条一直没跟上。
+                // 服务端也是这个约定：`channel_service` 的自包含投影在名字缺失时发空串，
+                // 注释写着「宁可显示加载中也不显示一串数字」。
+                //
+                // 对端身份改存到 `peer_user_id` 结构化字段——那才是它该待的地方，
+                // 列表查询据此 JOIN user 表拿真名，user 实体一到名字自然就对了。
+                let inferred_peer_user_id = if channel_type == 1 {
+                    match from_uid {
+                        Some(uid) if uid > 0 && uid != current_uid => Some(uid),
+                        _ => None,
+                    }
+                } else {
+                    // 群/房间的名字来自 group 实体（entity sync），这里绝不能拿 channel_id
+                    // 当名字：它会被写进 channel.channel_name，而频道列表查询对群会优先取
+                    // channel_name，于是真正的群名（即便随后同步到）被永久盖住，标题卡在裸 id。
+                    // 留空，交给查询回落到 group.name / 成员名。
+                    None
+                };
+                new_channel_peer_user_id = inferred_peer_user_id;
+                (
+                    String::new(),
+                    String::new(),
+                    String::new(),
+                    0,
+                    0,
+                    if bump_unread { 1 } else { 0 },
+                )
+            };
+        if inbound_logs_enabled() {
+            eprintln!(
+                "[SDK.unread] update_channel_last_message: channel_id={} channel_type={} bump_unread={} unread_before={} unread_after={} message_id={} from_uid={:?}",
+                channel_id,
+                channel_type,
+                bump_unread,
+                unread_before,
+                unread_count,
+                message_id,
+                from_uid
+            );
+        }
+        // 预览反乱序守卫（MESSAGE_HISTORY spec §2.5）：预览选择必须与显示排序同构
+        // （pending 最新端；已确认按 pts）。到达顺序 ≠ pts 顺序（例：登录通知 pts=2 先经
+        // push 到达，欢迎消息 pts=1 后经 sync 补齐——此前预览被"到达序"覆盖成旧消息）。
+        // 仅当新行不older于当前预览行时才覆盖预览字段；unread 计数不受影响照常更新。
+        let preview_should_update =
+            if prev_last_local_message_id == 0 || prev_last_local_message_id == message_id {
+                true
+            } else {
+                let new_row = self
+                    .storage
+                    .get_message_by_id(message_id)
+                    .await
+                    .ok()
+                    .flatten();
+                match new_row {
+                    None => true, // 新行尚未落库（调用时序差异）：保守覆盖，下次调用自愈
+                    Some(nr) => {
+                        if nr.server_message_id.unwrap_or(0) == 0 {
+                            true // pending 自发消息 = 时间线最新端
+                        } else {
+                            match self
+                                .storage
+                                .get_message_by_id(prev_last_local_message_id)
+                                .await
+                                .ok()
+                                .flatten()
+                            {
+                                None => true, // 旧预览行已不存在（被清理），覆盖
+                                Some(or) => {
+                                    if or.server_message_id.unwrap_or(0) == 0 {
+                                        // 旧预览是 pending：收到的 server 消息按时间更新，允许覆盖
+                                        // （pending ack 回填 pts 后由后续更新自然归位）
+                                        true
+                                    } else {
+                                        nr.pts.unwrap_or(0) >= or.pts.unwrap_or(0)
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            };
+
+        let existing_version = self
+            .storage
+            .get_channel_by_id(channel_id)
+            .await?
+            .map(|c| c.version)
+            .unwrap_or(0);
+        self.storage
+            .upsert_channel(UpsertChannelInput {
+                channel_id,
+                channel_type,
+                channel_name,
+                channel_remark,
+                avatar,
+                unread_count,
+                top,
+                mute,
+                last_msg_timestamp: if preview_should_update {
+                    timestamp_ms
+                } else {
+                    prev_last_msg_timestamp
+                },
+                last_local_message_id: if preview_should_update {
+                    message_id
+                } else {
+                    prev_last_local_message_id
+                },
+                last_msg_content: if preview_should_update {
+                    content.to_string()
+                } else {
+                    prev_last_msg_content.clone()
+                },
+                // Timeline preview updates must not mint a synthetic entity version.
+                // Keep the existing channel entity version so later sync_entities(channel)
+                // payloads can still apply top/mute/name changes from the server.
+                version: existing_version,
+                // 已存在的行不触碰对端（upsert SQL 用 COALESCE 保留已存值）；
+                // 新建的 DM 行在这里把对端存进结构化字段，列表查询据此 JOIN user 拿真名。
+                peer_user_id: new_channel_peer_user_id,
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+/// 附件正文传输计数（诊断用；也是「秒传到底有没有省下带宽」的唯一可信判据）。
+#[derive(Debug, Default)]
+pub struct AttachmentTransferCounters {
+    /// 秒传取用**成功**次数：换到自己的 file_id，正文零字节。
+    pub claims: AtomicU64,
+    /// **主文件**字节传上去并被服务端接收的次数。
+    pub body_uploads: AtomicU64,
+    /// **缩略图**字节传上去并被服务端接收的次数。缩略图是本地重新生成的派生物，
+    /// 每次生成都是新字节，所以它不会命中秒传——这是已知代价，单独计数免得
+    /// 被算进「主文件没省下带宽」。
+    pub thumbnail_uploads: AtomicU64,
+}
+
+/// [`PrivchatSdk::attachment_transfer_stats`] 的快照。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttachmentTransferStats {
+    pub claims: u64,
+    pub body_uploads: u64,
+    pub thumbnail_uploads: u64,
+    /// 本次运行以来按网络类型累计的上传 / 下载字节（附件、缩略图、头像）。
+    pub bytes_by_network: Vec<bandwidth::NetworkTransferBytes>,
+}
+
+#[derive(Clone)]
+pub struct PrivchatSdk {
+    /// 附件正文的传输计数：秒传命中一次 +1 claim，真传字节一次 +1 upload。
+    ///
+    /// 这是唯一能回答「这次转发到底传没传字节」的地方——服务端也按内容哈希复用
+    /// 物理路径，所以「两条记录指向同一个 file_url」**不能**证明没上传。
+    attachment_transfers: Arc<AttachmentTransferCounters>,
+    /// RPC 延迟、重连、同步耗时、下载吞吐等统一计数。见 [`metrics`]。
+    pub(crate) metrics: Arc<metrics::MetricsRegistry>,
+    tx: mpsc::Sender<Command>,
+    event_tx: broadcast::Sender<SdkEvent>,
+    event_seq: Arc<AtomicU64>,
+    event_history: Arc<StdMutex<VecDeque<SequencedSdkEvent>>>,
+    event_history_limit: usize,
+    _runtime_provider: RuntimeProvider,
+    task_registry: TaskRegistry,
+    shutting_down: Arc<AtomicBool>,
+    supervised_sync_running: Arc<AtomicBool>,
+    /// 首屏扫补是否已在跑。宿主每次进入 SYNC_READY 都会踢一脚，重连/切前台会踢很多次；
+    /// 没有这道闸，几百个会话的扫补会被并发起好几轮，互相抢 actor。
+    first_screen_sweep_running: Arc<AtomicBool>,
+    /// 见 [State::switch_requested]：切换账号前先记一笔并叫醒 actor，让正在跑的同步让出。
+    switch_requested: Arc<AtomicU64>,
+    switch_wakeup: Arc<tokio::sync::Notify>,
+    /// Interactive timeline reads preempt Phase 3 convergence. A pending notify
+    /// permit survives the small check/start race around the background pass.
+    foreground_wakeup: Arc<tokio::sync::Notify>,
+    startup_error: Arc<StdMutex<Option<Error>>>,
+    snowflake: Arc<snowflake_me::Snowflake>,
+    presence_cache: Arc<StdMutex<HashMap<u64, PresenceStatus>>>,
+    typing_throttle: Arc<StdMutex<HashMap<(u64, bool, u8), std::time::Instant>>>,
+    data_dir: Arc<String>,
+    /// file queue 的路由键（构造期由 endpoint 固化）。附件首发与重试必须用同一个键，
+    /// 才会落到同一条有序队列。
+    file_route_key: Arc<Option<String>>,
+    download_manager: media_download::DownloadManager,
+    /// 上传、下载、头像共用的限速器与字节记账。见 [`bandwidth`]。
+    pub(crate) bandwidth: bandwidth::BandwidthLimiter,
+    /// 见 [`State::segmented_uploads`]。
+    segmented_uploads: Arc<AtomicBool>,
+    /// 见 [`State::uploads`]。
+    uploads: background_transfer::UploadRegistry,
+    /// 首屏扫补被省流量推迟时记下的参数，换到非计费网络或关掉省流量时补起。
+    deferred_first_screen_hydration: Arc<StdMutex<Option<(u32, usize)>>>,
+    /// 本地流媒体代理，第一次要播放 URL 时才起。见 [`media_stream`]。
+    media_stream: Arc<StdMutex<Option<Arc<media_stream::MediaStreamServer>>>>,
+    pending_media_jobs: Arc<StdMutex<HashMap<String, oneshot::Sender<MediaJobResult>>>>,
+}
+
+impl PrivchatSdk {
+    /// 附件正文的传输计数。
+    ///
+    /// 🔴 「秒传省了带宽」只能在这里证明：服务端**也**按内容哈希复用物理路径，
+    /// 所以「两条记录指向同一个 file_url」并不意味着客户端没上传。
+    pub fn attachment_transfer_stats(&self) -> AttachmentTransferStats {
+        AttachmentTransferStats {
+            claims: self
+                .attachment_transfers
+                .claims
+                .load(std::sync::atomic::Ordering::Relaxed),
+            body_uploads: self
+                .attachment_transfers
+                .body_uploads
+                .load(std::sync::atomic::Ordering::Relaxed),
+            thumbnail_uploads: self
+                .attachment_transfers
+                .thumbnail_uploads
+                .load(std::sync::atomic::Ordering::Relaxed),
+            bytes_by_network: self.bandwidth.bytes_by_network(),
+        }
+    }
+
+    /// 统一指标快照：RPC 延迟（按 route）、重连、outbox 深度与最老等待时长、
+    /// 每种同步的耗时、接收管线去重、下载吞吐，以及附件/canonical 这些原有计数、
+    /// presence 缓存里的在线/离线人数。
+    ///
+    /// outbox 两个 gauge 和 presence 人数在这里现采（presence 只数缓存，不发 RPC）；actor 已经停了就沿用上一次的值，快照照常返回。
+    pub async fn metrics_snapshot(&self) -> Result<metrics::MetricsSnapshot> {
+        if self.ensure_running().is_ok() {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            if self
+                .tx
+                .send(Command::SampleOutboxMetrics { resp: resp_tx })
+                .await
+                .is_ok()
+            {
+                if let Ok(Err(e)) = resp_rx.await {
+                    eprintln!("[SDK.metrics] sample outbox failed: {e}");
+                }
+            }
+        }
+        let attachments = self.attachment_transfer_stats();
+        for (kind, value) in [
+            ("claim", attachments.claims),
+            ("body", attachments.body_uploads),
+            ("thumbnail", attachments.thumbnail_uploads),
+        ] {
+            self.metrics
+                .mirror_counter(metrics::ATTACHMENT_TRANSFERS_TOTAL, &[("kind", kind)], value);
+        }
+        self.metrics.mirror_counter(
+            metrics::CANONICAL_LEGACY_MISMATCH_TOTAL,
+            &[],
+            CANONICAL_LEGACY_MISMATCH_COUNT.load(Ordering::Relaxed),
+        );
+        self.metrics.mirror_counter(
+            metrics::CANONICAL_DECODE_ERRORS_TOTAL,
+            &[],
+            CANONICAL_DECODE_ERROR_COUNT.load(Ordering::Relaxed),
+        );
+        let (online, offline) = self
+            .presence_cache
+            .lock()
+            .map(|locked| {
+                let online = locked.values().filter(|p| p.is_online).count();
+                (online, locked.len() - online)
+            })
+            .unwrap_or_default();
+        for (state, value) in [("online", online), ("offline", offline)] {
+            self.metrics.set_gauge(
+                metrics::PRESENCE_CACHED_USERS,
+                &[("state", state)],
+                value as f64,
+            );
+        }
+        Ok(self.metrics.snapshot())
+    }
+
+    /// [`Self::metrics_snapshot`] 的 Prometheus 文本格式，给桌面端/daemon 的 scrape 端点用。
+    #[cfg(feature = "prometheus")]
+    pub async fn metrics_prometheus_text(&self) -> Result<String> {
+        let snapshot = self.metrics_snapshot().await?;
+        Ok(metrics::render_prometheus(&snapshot))
+    }
+
+    fn is_timeline_like_event(event: &SdkEvent) -> bool {
+        matches!(
+            event,
+            SdkEvent::TimelineUpdated { .. }
+                | SdkEvent::MessageSendStatusChanged { .. }
+                | SdkEvent::SyncEntityChanged { .. }
+                | SdkEvent::SyncChannelApplied { .. }
+                | SdkEvent::SyncAllChannelsApplied { .. }
+                | SdkEvent::TypingSent { .. }
+        )
+    }
+
+    fn is_network_like_event(event: &SdkEvent) -> bool {
+        matches!(
+            event,
+            SdkEvent::ConnectionStateChanged { .. }
+                | SdkEvent::NetworkHintChanged { .. }
+                | SdkEvent::ResumeSyncStarted
+                | SdkEvent::ResumeSyncCompleted { .. }
+                | SdkEvent::ResumeSyncFailed { .. }
+                | SdkEvent::ResumeSyncEscalated { .. }
+                | SdkEvent::ForcedLogout { .. }
+        )
+    }
+
+    pub fn new(config: PrivchatConfig) -> Self {
+        Self::with_runtime(config, RuntimeProvider::new_owned())
+    }
+
+    pub fn with_runtime(config: PrivchatConfig, runtime_provider: RuntimeProvider) -> Self {
+        Self::build(config, runtime_provider, None)
+    }
+
+    /// `shared_store` 为 `Some` 时 storage actor 直接用它，不再按 `data_dir` 自己开库
+    /// （[`account_manager`] 让几个账号共用一个 base 目录）。
+    pub(crate) fn build(
+        config: PrivchatConfig,
+        runtime_provider: RuntimeProvider,
+        shared_store: Option<local_store::LocalStore>,
+    ) -> Self {
+        let configured_data_dir = config.data_dir.clone();
+        let data_dir_for_self = configured_data_dir.clone();
+        // 附件 file queue 的路由键在构造期固化：首发与重试必须落到同一条有序队列。
+        let file_route_key = config.endpoints.first().map(Self::endpoint_route_key);
+        let (tx, mut rx) = mpsc::channel::<Command>(64);
+        let actor_cmd_tx = tx.clone();
+        let (event_tx, _) = broadcast::channel::<SdkEvent>(256);
+        let actor_event_tx = event_tx.clone();
+        let event_seq = Arc::new(AtomicU64::new(0));
+        let actor_event_seq = event_seq.clone();
+        let event_history = Arc::new(StdMutex::new(VecDeque::new()));
+        let actor_event_history = event_history.clone();
+        let event_history_limit = DEFAULT_EVENT_HISTORY_LIMIT;
+        let task_registry = TaskRegistry::new();
+        let startup_error = Arc::new(StdMutex::new(None));
+        let actor_startup_error = startup_error.clone();
+        let presence_cache = Arc::new(StdMutex::new(HashMap::new()));
+        let actor_presence_cache = presence_cache.clone();
+        let pending_media_jobs: Arc<StdMutex<HashMap<String, oneshot::Sender<MediaJobResult>>>> =
+            Arc::new(StdMutex::new(HashMap::new()));
+        let actor_pending_media_jobs = pending_media_jobs.clone();
+        let download_manager = media_download::DownloadManager::new();
+        let actor_download_manager = download_manager.clone();
+        // CODEX-8：worker 位取自持久化 installation id（稳定设备身份），替代 pid/启动毫秒的
+        // 临时派生 —— 重启后 worker 位不漂移；配合服务端 (sender, device, local_message_id)
+        // 幂等命名空间，雪花碰撞面收敛到单设备内（由毫秒+序列保证唯一）。
+        let (machine_id, data_center_id) = stable_snowflake_worker_bits(&config.data_dir);
+        let snowflake = snowflake_me::Snowflake::builder()
+            .machine_id(&|| Ok(machine_id))
+            .data_center_id(&|| Ok(data_center_id))
+            .finalize()
+            .map(Arc::new)
+            .unwrap_or_else(|_| {
+                // Fallback: use defaults
+                Arc::new(
+                    snowflake_me::Snowflake::builder()
+                        .finalize()
+                        .expect("default snowflake must work"),
+                )
+            });
+        // 账号切换的「让出」通道：计数器记事实，Notify 负责叫醒。见 State::switch_requested。
+        let switch_requested_sdk = Arc::new(AtomicU64::new(0));
+        let attachment_transfers_sdk = Arc::new(AttachmentTransferCounters::default());
+        let attachment_transfers_actor = attachment_transfers_sdk.clone();
+        let metrics_sdk = Arc::new(metrics::MetricsRegistry::new());
+        let metrics_actor = metrics_sdk.clone();
+        let bandwidth_sdk = bandwidth::BandwidthLimiter::default();
+        let bandwidth_actor = bandwidth_sdk.clone();
+        let segmented_uploads_sdk = Arc::new(AtomicBool::new(false));
+        let segmented_uploads_actor = segmented_uploads_sdk.clone();
+        let uploads_sdk = background_transfer::UploadRegistry::default();
+        let uploads_actor = uploads_sdk.clone();
+        let switch_processed_sdk = Arc::new(AtomicU64::new(0));
+        let switch_wakeup_sdk = Arc::new(tokio::sync::Notify::new());
+        let switch_requested_actor = switch_requested_sdk.clone();
+        let switch_processed_actor = switch_processed_sdk.clone();
+        let switch_wakeup_actor = switch_wakeup_sdk.clone();
+        let foreground_wakeup_sdk = Arc::new(tokio::sync::Notify::new());
+        let foreground_wakeup_actor = foreground_wakeup_sdk.clone();
+        let actor_snowflake = snowflake.clone();
+        let actor_task = runtime_provider.spawn(async move {
+            if actor_logs_enabled() {
+                eprintln!("[SDK.actor] loop: started");
+                if configured_data_dir.trim().is_empty() {
+                    eprintln!("[SDK.actor] storage base: <default>");
+                } else {
+                    eprintln!("[SDK.actor] storage base: {}", configured_data_dir);
+                }
+            }
+            let storage = match if let Some(store) = shared_store {
+                StorageHandle::start_with(store)
+            } else if configured_data_dir.trim().is_empty() {
+                StorageHandle::start()
+            } else {
+                StorageHandle::start_at(PathBuf::from(configured_data_dir))
+            } {
+                Ok(s) => s,
+                Err(e) => {
+                    if let Ok(mut locked) = actor_startup_error.lock() {
+                        *locked = Some(Error::Storage(format!("storage init failed: {e}")));
+                    }
+                    eprintln!("[SDK.actor] storage init failed: {e}");
+                    return;
+                }
+            };
+            let snowflake = actor_snowflake;
+            let current_uid = storage.load_current_uid().await.ok().flatten();
+            let saved = if let Some(uid) = &current_uid {
+                storage.load_session(uid.clone()).await.ok().flatten()
+            } else {
+                None
+            };
+            let mut state = State {
+                attachment_transfers: attachment_transfers_actor,
+                metrics: metrics_actor,
+                config,
+                transport: None,
+                transport_events: Arc::new(tokio::sync::Mutex::new(None)),
+                session_state: SessionState::New,
+                bootstrap_completed: saved
+                    .as_ref()
+                    .map(|s| s.bootstrap_completed)
+                    .unwrap_or(false),
+                sync_coordinator: SyncCoordinator::new(),
+                snowflake,
+                storage: storage.clone(),
+                download_manager: actor_download_manager,
+                bandwidth: bandwidth_actor.clone(),
+                segmented_uploads: segmented_uploads_actor,
+                uploads: uploads_actor,
+                actor_tx: actor_cmd_tx.downgrade(),
+                skip_inbound_materialization_for_load_testing:
+                    SKIP_INBOUND_MATERIALIZATION_FOR_LOAD_TESTING.load(Ordering::SeqCst),
+                current_uid,
+                session_epoch: 0,
+                should_auto_reconnect: false,
+                reconnect_attempt: 0,
+                next_reconnect_at: None,
+                auth_terminal_fired: false,
+                inbound_epoch: 0,
+                last_resume_synced: None,
+                last_anti_entropy_at: Instant::now(),
+                convergence_run: None,
+                resume_run_id: 0,
+                anti_entropy_jitter: Duration::from_secs(rand::random::<u64>() % 16),
+                room_seen_msg_ids: HashMap::new(),
+                last_terminal_reason: None,
+                network_hint: NetworkHint::Unknown,
+                receive_pipeline: ReceivePipeline::default(),
+                last_sync_queued: 0,
+                last_sync_dropped_duplicates: 0,
+                last_sync_entity_events: Vec::new(),
+                video_process_hook: None,
+                link_preview_hook: None,
+                last_tmp_cleanup_day: None,
+                pending_events: Vec::new(),
+                message_cache_policy: MessageCachePolicy::default(),
+                channel_message_cache: HashMap::new(),
+                channel_cache_generation: HashMap::new(),
+                switch_requested: switch_requested_actor,
+                switch_processed: switch_processed_actor,
+                switch_wakeup: switch_wakeup_actor,
+                #[cfg(test)]
+                sync_stall_for_test: None,
+                channel_cache_lru: VecDeque::new(),
+                channel_cache_total_bytes: 0,
+                cache_debug_log: std::env::var("PRIVCHAT_CACHE_LOG").ok().as_deref() == Some("1"),
+                cache_hit_count: 0,
+                cache_miss_count: 0,
+                pending_prelogin_inbound_frames: Vec::new(),
+                active_subscriptions: HashMap::new(),
+                presence_cache: actor_presence_cache,
+                event_tx: Some(actor_event_tx.clone()),
+                event_history: Some(actor_event_history.clone()),
+                event_seq: Some(actor_event_seq.clone()),
+                event_history_limit: event_history_limit,
+                pending_media_jobs: actor_pending_media_jobs,
+                repair_queue: VecDeque::new(),
+                repair_seen: HashSet::new(),
+                repair_backoff: HashMap::new(),
+                avatar_cache: avatar_cache::AvatarCacheManager::new(bandwidth_actor),
+                trace_recorder: None,
+                last_unread_aggregate: None,
+                badge_dirty: false,
+                channel_prefs_mirrored_uid: None,
+                auto_downloads: HashMap::new(),
+            };
+            let mut inbound_task: Option<tokio::task::JoinHandle<()>> = None;
+            let mut health_tick = interval(Duration::from_secs(15));
+            #[allow(unused_mut)]
+            let mut health_tick_enabled = true;
+            // repair tick 比 health tick 快:损坏的图片/顺序应当尽快自愈,但仍然是
+            // **后台**节奏——读路径只入队,一次 tick 最多处理 REPAIR_BATCH_LIMIT 条。
+            let mut repair_tick = interval(Duration::from_secs(2));
+            // Burst（默认）会把错过的 tick 补齐：后台收敛一轮跑几秒（429 会话账号每页
+            // 要 200 次本地读 + 一次批量 RPC + 最多 8 个 difference），错过的 tick 于是
+            // 连续就绪，actor 再也回不到命令处理 —— 宿主的 ensure_synced 永远收不到应答，
+            // 界面卡在「同步中」。Skip：迟到的 tick 直接丢，只保留下一次。
+            repair_tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            health_tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            loop {
+                // Backoff driver: fires when `next_reconnect_at` is due. When no retry
+                // is armed we pin a `pending()` so this arm is inert but keeps its type
+                // inside `select!`.
+                // New = 传输断开;Connected + 有本地账号 = token-refresh handoff 期间的
+                // 未认证保活态(见 AccessTokenRefreshNeeded 发射点)——两者都允许重试驱动。
+                let awaiting_reauth = state.session_state == SessionState::Connected
+                    && state.current_uid.is_some();
+                // 系统 reachability(network_hint)只是加速信号,不是永久闸门:模拟器/
+                // 挂起恢复后系统常给出 Offline 假信号且再无恢复回调,若据此一票否决,
+                // 重连永不发起(2026-07-24 实测:sim 常亮整夜「网络已断开」,实际网络
+                // 通畅)。Offline 期间降频到 ≥60s 做真实连接探测,连上即翻回 Online。
+                // ⚠️ 用 next_reconnect_at 的**绝对时刻**,绝不在此按 `now` 现算 deadline。
+                // 历史 bug(2026-07-24 生产三修):OFFLINE 分支曾写 `at.max(now + 60s)`,而 15s
+                // 的 health_tick 每次醒来都重进 select! 用新的 `now` 重算这个 max → 60s 倒计时
+                // 被永久往后推(15s<60s,retry_sleep 永远等不到截止点就被下轮重置)→ 断网后
+                // **重试永不触发**,横幅永久卡「网络已断开」。离线降频改到 schedule_next_reconnect
+                // 的**设置时**烘进 next_reconnect_at(稳定绝对值),这里只做纯读取。
+                let retry_deadline = if state.should_auto_reconnect
+                    && (state.session_state == SessionState::New || awaiting_reauth)
+                {
+                    state.next_reconnect_at
+                } else {
+                    None
+                };
+                let retry_sleep = async move {
+                    match retry_deadline {
+                        Some(at) => {
+                            let now = Instant::now();
+                            let dur = at.saturating_duration_since(now);
+                            sleep(dur).await;
+                        }
+                        None => std::future::pending::<()>().await,
+                    }
+                };
+                tokio::pin!(retry_sleep);
+
+                // sync 退避到期的唤醒。
+                //
+                // 只有退避门禁是不够的：门禁挡住了热循环，但如果连接一直是好的、
+                // 没有新的外部触发（重连成功 / connect / token 刷新）再来敲门，
+                // 一次临时 sync 失败就会永远停在 Retrying——退避到期了也没人来跑。
+                // 谁持有 deadline 谁就得负责叫醒，这里把它接进 actor 的定时器。
+                //
+                // 与上面 next_reconnect_at 的区别：那个存的是 Instant，这里存的是
+                // **绝对 epoch ms**，所以每轮按 now 重算剩余时长是安全的——目标时刻
+                // 本身不会移动，不存在被 health_tick 反复推迟的那类饿死。
+                let sync_retry_deadline = if state.session_state == SessionState::Authenticated {
+                    state.sync_coordinator.next_retry_at_ms()
+                } else {
+                    None
+                };
+                let sync_retry_sleep = async move {
+                    match sync_retry_deadline {
+                        Some(at_ms) => {
+                            let now_ms = chrono::Utc::now().timestamp_millis();
+                            let remaining = (at_ms - now_ms).max(0) as u64;
+                            sleep(Duration::from_millis(remaining)).await;
+                        }
+                        None => std::future::pending::<()>().await,
+                    }
+                };
+                tokio::pin!(sync_retry_sleep);
+
+                tokio::select! {
+                    _ = &mut sync_retry_sleep => {
+                        if actor_logs_enabled() {
+                            eprintln!("[SDK.actor] sync retry deadline reached");
+                        }
+                        state.badge_dirty = true;
+                        let _ = state.ensure_synced(|event| {
+                            emit_sequenced_event(
+                                &actor_event_tx,
+                                &actor_event_history,
+                                &actor_event_seq,
+                                event_history_limit,
+                                event,
+                            );
+                        }).await;
+                    }
+                    _ = repair_tick.tick() => {
+                        if state.session_state != SessionState::Shutdown
+                            && !state.repair_queue.is_empty()
+                        {
+                            state.drain_projection_repairs().await;
+                        }
+                        // 未读聚合在这里统一重算：2s 节奏本身就是合并窗口，一阵入站消息
+                        // 只换来一次 BadgeChanged。bootstrap 前重算会失败，忽略即可——
+                        // bootstrap 命令本身会再次标脏。
+                        if state.badge_dirty && state.session_state != SessionState::Shutdown {
+                            let _ = state.refresh_unread_aggregate().await;
+                        }
+                        // Phase 3 后台收敛：一次一小批 stale 频道（run_anti_entropy_once
+                        // 内部用 batch_get_channel_pts 批量比对 + WiFi/蜂窝预算）。
+                        // 失败只退避重试，**不动 readiness** —— 用户此刻能正常收发。
+                        //
+                        // 命令优先：队列里有待处理命令时不开新一轮。收敛是后台工作，
+                        // 让宿主等应答（进而卡住界面）是本末倒置。
+                        if !rx.is_empty()
+                            && state.session_state == SessionState::Authenticated
+                            && state.convergence_run.is_some()
+                        {
+                            continue;
+                        }
+                        if state.session_state == SessionState::Authenticated
+                            && state.convergence_run.is_some()
+                            && state.sync_coordinator.convergence_retry_ready(
+                                chrono::Utc::now().timestamp_millis(),
+                            )
+                        {
+                            // A conversation open is foreground work. If it arrives while a
+                            // batch/difference RPC is in flight, cancel this idempotent pass and
+                            // return to the command loop instead of making the UI wait for the
+                            // background network timeout. `notify_one` retains a permit across
+                            // the check/start race above.
+                            let convergence = run_preemptible_background(
+                                &foreground_wakeup_actor,
+                                state.run_anti_entropy_once(),
+                            )
+                            .await;
+                            let Some(convergence) = convergence else {
+                                continue;
+                            };
+                            if matches!(&convergence, Ok(page) if page.messages_applied > 0) {
+                                state.badge_dirty = true;
+                            }
+                            match convergence {
+                                // 只有「完整扫过一圈 + 没有留下未修的 stale」才算收敛。
+                                // 用「本页修了 0 条」判定会在扫到第一页干净数据时就
+                                // 过早宣布完成，后面的频道再也不会被检查。
+                                Ok(page) if page.is_converged() => {
+                                    if let Some(stats) = state.convergence_run.take() {
+                                        println!(
+                                            "[SDK.resume] run={} phase=3 converged scanned={} channels_repaired={} messages_applied={}",
+                                            state.resume_run_id,
+                                            page.page_scanned,
+                                            page.channels_repaired,
+                                            page.messages_applied
+                                        );
+                                        state.sync_coordinator.set_convergence(
+                                            crate::sync_coordinator::Convergence::Converged,
+                                            chrono::Utc::now().timestamp_millis(),
+                                        );
+                                        // 全量收敛完成才发 ResumeSyncCompleted —— 保持它
+                                        // 「这一轮全做完了」的原始语义。
+                                        state.queue_resume_completed(stats);
+                                    }
+                                }
+                                Ok(page) => {
+                                    // unknown 不为零也要出声：那是异常规模指标
+                                    if page.stale_found > 0
+                                        || page.deferred > 0
+                                        || page.unknown_channels > 0
+                                    {
+                                        println!(
+                                            "[SDK.resume] run={} phase=3 page scanned={} stale={} channels_repaired={} messages_applied={} deferred={} unknown={}",
+                                            state.resume_run_id,
+                                            page.page_scanned,
+                                            page.stale_found,
+                                            page.channels_repaired,
+                                            page.messages_applied,
+                                            page.deferred,
+                                            page.unknown_channels
+                                        );
+                                    }
+                                    state.sync_coordinator.set_convergence(
+                                        if page.stale_found > 0 {
+                                            crate::sync_coordinator::Convergence::Repairing
+                                        } else {
+                                            crate::sync_coordinator::Convergence::Scanning
+                                        },
+                                        chrono::Utc::now().timestamp_millis(),
+                                    );
+                                }
+                                Err(err) => {
+                                    let now_ms = chrono::Utc::now().timestamp_millis();
+                                    let (attempt, next_retry_at_ms) =
+                                        state.sync_coordinator.backoff_convergence(now_ms);
+                                    tracing::warn!(
+                                        error = %err,
+                                        attempt,
+                                        next_retry_at_ms,
+                                        "convergence pass failed; backing off"
+                                    );
+                                }
+                            }
+                            for event in state.take_pending_events() {
+                                emit_sequenced_event(
+                                    &actor_event_tx,
+                                    &actor_event_history,
+                                    &actor_event_seq,
+                                    event_history_limit,
+                                    event,
+                                );
+                            }
+                            // 让调度器有机会把排队的命令喂进来
+                            tokio::task::yield_now().await;
+                        }
+                        continue;
+                    }
+                    _ = health_tick.tick(), if health_tick_enabled => {
+                        if state.session_state == SessionState::Shutdown {
+                            continue;
+                        }
+
+                        // ── 活性看门狗（2026-07-24 生产事故三修根治）──────────────────
+                        // 断网→联网后横幅永久卡「网络已断开」的架构根因:重试驱动可能走进
+                        // inert 死态——retry 守卫兜底(session 短暂非 New 时)会把
+                        // next_reconnect_at 置 None,而唯一复活入口(SetNetworkHint
+                        // offline→online)依赖 iOS reachability 的恢复回调,该回调在挂起/
+                        // 长断网后常常**根本不投递** → 驱动永不再武装 → 永久卡死。
+                        //
+                        // 修:15s health_tick 无条件充当看门狗——只要有「想在线」的会话
+                        // (should_auto_reconnect + 传输断开 New / 待重认证 Connected+uid)
+                        // 而驱动已 inert(next_reconnect_at==None),立即重新武装。这样
+                        // 恢复不再依赖任何系统回调:OFFLINE 期驱动仍每≤60s 做真实 TCP 探测,
+                        // 网络一旦真回来,下一次 try_auto_reconnect 成功即把 hint 复位并认证。
+                        // 活性看门狗:想在线(should_auto_reconnect)+ 传输断开(New)/待重认证,
+                        // 而重连驱动 inert(next_reconnect_at 空)→ 重新武装。honor should_auto_reconnect
+                        // 是必须的:手动/后台 Disconnect 也把 session 设成 New 且保留 uid,唯一区分
+                        // 「想重连」vs「显式离线」的信号就是这个标志,不能绕过。
+                        let wants_online = state.should_auto_reconnect
+                            && (matches!(state.session_state, SessionState::New)
+                                || (state.session_state == SessionState::Connected
+                                    && state.current_uid.is_some()));
+                        if wants_online && state.next_reconnect_at.is_none() {
+                            eprintln!(
+                                "[SDK.actor] liveness watchdog: retry driver inert (state={:?}, hint={:?}); re-arming reconnect",
+                                state.session_state, state.network_hint
+                            );
+                            state.reconnect_attempt = 0;
+                            state.next_reconnect_at = Some(Instant::now());
+                        }
+
+                        // ⚠️ 这里曾是 `if !network_hint.is_online() { continue; }` —— 一个
+                        // 假 Offline 就把整个 tick 尾部(连接探测 / anti-entropy / **出站队列
+                        // 排空**)全部跳过。后果(2026-07-26 生产实测):inbound 推送走的是活着的
+                        // transport、不看 hint,所以**用户能正常收消息**,但自己发的消息因为
+                        // outbox 永远排不空而卡在「发送中」,既不成功也不失败。
+                        // 原则同前:reachability 只是提示,绝不做硬闸门——各操作自身的前置条件
+                        // (session_state / bootstrap_completed / 队列非空)才是真正的门槛,真断网
+                        // 时它们各自失败即可,代价只是一次廉价的探测。
+
+                        // Probe live connections for silent drops (NAT/proxy timeouts).
+                        // apply_transport_health will arm the reconnect driver on loss.
+                        if matches!(
+                            state.session_state,
+                            SessionState::Connected | SessionState::LoggedIn | SessionState::Authenticated
+                        ) {
+                            let is_connected = state.probe_connection().await;
+                            if !is_connected {
+                                stop_inbound_task(&mut inbound_task).await;
+                                if let Some((from, to)) = state.apply_transport_health(false) {
+                                    emit_sequenced_event(
+                                        &actor_event_tx,
+                                        &actor_event_history,
+                                        &actor_event_seq,
+                                        event_history_limit,
+                                        SdkEvent::ConnectionStateChanged { from, to },
+                                    );
+                                }
+                            }
+                        }
+
+                        let _ = state.cleanup_tmp_dirs_if_needed().await;
+
+                        if state.session_state == SessionState::Authenticated
+                            && state.bootstrap_completed
+                            && state.last_anti_entropy_at.elapsed()
+                                >= Duration::from_secs(60) + state.anti_entropy_jitter
+                        {
+                            // Advance before RPC so a transient failure cannot turn the
+                            // 15s health tick into an unbounded retry loop.
+                            state.last_anti_entropy_at = Instant::now();
+                            if let Err(err) = state.run_anti_entropy_once().await {
+                                tracing::warn!(error = %err, "anti-entropy pass failed");
+                            }
+                        }
+
+                        if state.should_process_outbound_queue() {
+                            let _ = state.drain_outbound_queues().await;
+                        }
+                        for event in state.take_pending_events() {
+                            emit_sequenced_event(
+                                &actor_event_tx,
+                                &actor_event_history,
+                                &actor_event_seq,
+                                event_history_limit,
+                                event,
+                            );
+                        }
+                    }
+                    _ = &mut retry_sleep => {
+                        // Retry driver: the deadline fired. Guard again because state
+                        // may have changed between pin and wake.
+                        let awaiting_reauth = state.session_state == SessionState::Connected
+                            && state.current_uid.is_some();
+                        if (state.session_state != SessionState::New && !awaiting_reauth)
+                            || !state.should_auto_reconnect
+                        {
+                            state.next_reconnect_at = None;
+                            continue;
+                        }
+                        let attempt_n = state.reconnect_attempt.saturating_add(1);
+                        eprintln!("[SDK.actor] auto_reconnect_attempt #{attempt_n}");
+                        let from = state.session_state.as_connection_state();
+                        match state
+                            .try_auto_reconnect(
+                                &mut inbound_task,
+                                &actor_event_tx,
+                                &actor_event_history,
+                                &actor_event_seq,
+                                event_history_limit,
+                            )
+                            .await
+                        {
+                            Ok(next) => {
+                                state.session_state = next;
+                                state.reset_reconnect_backoff();
+                                state.sync_coordinator.note_network_available();
+                                // 握手 + 认证全通了，解除 Terminal 闸门，后续如果再过期还能再触发一次。
+                                state.auth_terminal_fired = false;
+                                // 真实连接成功是网络在线的最强证据:覆盖系统 reachability 的
+                                // Offline 假信号(否则 hint 卡 Offline 会继续压制其它路径)。
+                                if !state.network_hint.is_online() {
+                                    eprintln!("[SDK.actor] probe connected while hint=Offline; reset hint to Unknown");
+                                    state.network_hint = NetworkHint::Unknown;
+                                    state.bandwidth.set_network(NetworkHint::Unknown);
+                                }
+                                eprintln!("[SDK.actor] auto_reconnect_result ok attempt=#{attempt_n}");
+                                state.metrics.inc_counter(
+                                    metrics::RECONNECT_ATTEMPTS_TOTAL,
+                                    &[("outcome", "ok")],
+                                    1,
+                                );
+                                // [硬化] 重连成功后必须：① 重启 inbound task（订阅新 transport + bump epoch）
+                                start_inbound_task(&mut state, actor_cmd_tx.clone(), &mut inbound_task)
+                                    .await;
+                                if realtime_trace_enabled() { eprintln!(
+                                    "[SDK_RECONNECT_OK] new_epoch={} state={:?} uid={:?}",
+                                    state.inbound_epoch, state.session_state, state.current_uid
+                                ); }
+                                // ② 防御性 replay：若有 push 帧在登录前缓冲（current_uid 恢复后），立即重放，
+                                //    避免 message/presence/typing 卡在 prelogin 队列里收不到。
+                                if let Err(err) = state.replay_prelogin_inbound_frames().await {
+                                    eprintln!("[SDK.actor] prelogin replay failed after reconnect: {err}");
+                                }
+                                // 重连后重放活跃订阅：恢复 presence_changed / typing / room 广播。
+                                state.replay_subscriptions().await;
+                                if state.session_state == SessionState::Authenticated
+                                    && state.bootstrap_completed
+                                {
+                                    // ③ resume sync 把重连/断网期间漏掉的增量补回来
+                                    if let Err(err) = state.ensure_synced(|event| {
+                                        emit_sequenced_event(
+                                            &actor_event_tx,
+                                            &actor_event_history,
+                                            &actor_event_seq,
+                                            event_history_limit,
+                                            event,
+                                        );
+                                    }).await {
+                                        eprintln!("[SDK.actor] resume sync failed after reconnect: {err}");
+                                    }
+                                }
+                                emit_sequenced_event(
+                                    &actor_event_tx,
+                                    &actor_event_history,
+                                    &actor_event_seq,
+                                    event_history_limit,
+                                    SdkEvent::ConnectionStateChanged {
+                                        from,
+                                        to: state.session_state.as_connection_state(),
+                                    },
+                                );
+                            }
+                            Err(e) => {
+                                eprintln!("[SDK.actor] auto_reconnect_result fail attempt=#{attempt_n}");
+                                state.metrics.inc_counter(
+                                    metrics::RECONNECT_ATTEMPTS_TOTAL,
+                                    &[("outcome", "fail")],
+                                    1,
+                                );
+                                if e.is_auth_terminal() {
+                                    // Terminal 认证错误（token 撤销/设备不匹配等）由
+                                    // trigger_forced_logout 统一收口：停 inbound、断 transport、
+                                    // 清状态、发 ForcedLogout。继续自动重连只会把过期 token 反复
+                                    // 投给服务端打满日志。
+                                    trigger_forced_logout(
+                                        &mut state,
+                                        &mut inbound_task,
+                                        &actor_event_tx,
+                                        &actor_event_history,
+                                        &actor_event_seq,
+                                        event_history_limit,
+                                        &e,
+                                        ForcedLogoutSource::ConnectAuth,
+                                    )
+                                    .await;
+                                } else if matches!(
+                                    e.auth_kind(),
+                                    Some(AuthErrorKind::Recoverable)
+                                ) {
+                                    // Recoverable（10002 AccessTokenExpired 等）：
+                                    // 停 auto-reconnect，避免拿过期 token 反复打服务端。
+                                    //
+                                    // 关键：try_auto_reconnect 失败时已 disconnect 释放 transport，
+                                    // state 仍是 New。如果直接 emit 事件让 host refresh + authenticate，
+                                    // 但 SessionState=New 下 Action::Authenticate 会被状态机拒绝
+                                    // （spec TOKEN_REFRESH_SPEC §7：New/Shutdown 报错）。
+                                    //
+                                    // 所以在 emit 事件**之前**主动 connect 一次重建 transport，把 state
+                                    // 转回 Connected。host 收到事件后调 sdk.authenticate(uid, newToken,
+                                    // deviceId) 直接走 Connected→Authenticated 即可，不需要先 connect
+                                    // （否则 host 的 connect 又触发 try_auto_reconnect 用旧 token 撞
+                                    // 10002 死循环）。
+                                    //
+                                    // 若 connect 也失败（网络真断了）则保持 New + emit 事件，host recover
+                                    // 会因 sdk.authenticate 报 InvalidState 失败；下次 retry / 网络恢复
+                                    // 还会再 emit，最终自愈。
+                                    let code = e.auth_error_code().unwrap_or(0);
+                                    let message = e.to_string();
+                                    eprintln!(
+                                        "[SDK.actor] auto_reconnect: recoverable auth error code={code}; reconnect transport then emit AccessTokenRefreshNeeded"
+                                    );
+                                    let pre_state = state.session_state.as_connection_state();
+                                    if let Ok(()) = timeout(state.connect_timeout_total(), state.connect()).await
+                                        .unwrap_or(Err(Error::Transport("reconnect timeout".to_string())))
+                                    {
+                                        if let Ok(next_state) = state.session_state.can(Action::Connect) {
+                                            state.session_state = next_state;
+                                            emit_sequenced_event(
+                                                &actor_event_tx,
+                                                &actor_event_history,
+                                                &actor_event_seq,
+                                                event_history_limit,
+                                                SdkEvent::ConnectionStateChanged {
+                                                    from: pre_state,
+                                                    to: state.session_state.as_connection_state(),
+                                                },
+                                            );
+                                        }
+                                    } else {
+                                        eprintln!(
+                                            "[SDK.actor] auto_reconnect recoverable: post-fail reconnect failed; emit event but host authenticate likely InvalidState"
+                                        );
+                                    }
+                                    // 活性兜底(2026-07-24 生产事故):这里曾 should_auto_reconnect=false
+                                    // 永久关闭重连,把恢复责任完全交给宿主的一次性 refresh —— 宿主那一次
+                                    // 失败(如撞上服务端发版窗口)就死锁在「网络已断开」,且唯一救活入口
+                                    // 是前台/网络回调,常亮设备永远等不到。改为降频保活:60s 一轮继续
+                                    // 探测,每轮撞 10002 会再 emit 一次事件(宿主侧 mutex 去重),无论
+                                    // 宿主死活 SDK 自身永远有心跳式重试;宿主 refresh 成功后 authenticate
+                                    // 会 reset backoff 自然接管。
+                                    state.reconnect_attempt = state.reconnect_attempt.saturating_add(1);
+                                    state.next_reconnect_at =
+                                        Some(Instant::now() + Duration::from_secs(60));
+                                    eprintln!(
+                                        "[SDK.actor] auto_reconnect: token-refresh handoff to host; keep-alive retry in 60s"
+                                    );
+                                    emit_sequenced_event(
+                                        &actor_event_tx,
+                                        &actor_event_history,
+                                        &actor_event_seq,
+                                        event_history_limit,
+                                        SdkEvent::AccessTokenRefreshNeeded { code, message },
+                                    );
+                                } else {
+                                    state.schedule_next_reconnect();
+                                }
+                            }
+                        }
+                    }
+                    cmd = rx.recv() => {
+                        let Some(cmd) = cmd else { break; };
+                        // 入站帧在下面按 epoch 过滤后单独录整帧，这里只录命令名。
+                        if let Some(recorder) = state.trace_recorder.as_mut() {
+                            if !matches!(cmd, Command::InboundFrame { .. }) {
+                                recorder.record_command(cmd.trace_name());
+                            }
+                        }
+                        if cmd.affects_unread_badge() {
+                            state.badge_dirty = true;
+                        }
+                        match cmd {
+                    Command::Connect { resp } => {
+                        if actor_logs_enabled() {
+                            eprintln!("[SDK.actor] loop: cmd connect");
+                        }
+                        {
+                            // ⚠️ 这里**不再**用 network_hint 做硬闸门(2026-07-25 生产事故):
+                            // 系统 reachability 断线/挂起后常卡 Offline 且不再投递恢复回调,
+                            // 据此一票否决会让宿主的「前台/网络恢复/用户点重连」全部空转。
+                            // 显式 Connect 本身就是「我要上线」的强意图信号:直接尝试,真断网
+                            // 自然失败并由重试驱动接管;成功则下方把 hint 复位(真实连接=真源)。
+                            if !state.network_hint.is_online() {
+                                eprintln!(
+                                    "[SDK.actor] connect requested while hint=Offline; attempting anyway (hint is advisory)"
+                                );
+                            }
+                            // A user-driven Connect expresses the intent to stay online —
+                            // enable auto-reconnect up-front so retry fires even if this
+                            // first attempt fails (e.g. server temporarily down).
+                            state.should_auto_reconnect = true;
+                            let from_state = state.session_state.as_connection_state();
+                            let had_local_session = state.current_uid.is_some();
+                            let transport_connected = state.is_connected().await;
+                            let plan = plan_connect(
+                                state.session_state,
+                                had_local_session,
+                                transport_connected,
+                            );
+                            let already_ready =
+                                matches!(&plan, Ok(ConnectPlan::AlreadyReady));
+                            let mut transition_from = from_state;
+
+                            // Queries are observers. Explicit connect is the intent boundary
+                            // that reconciles a stale logical session with transport reality.
+                            if !transport_connected {
+                                if let Some((from, to)) = state.apply_transport_health(false) {
+                                    stop_inbound_task(&mut inbound_task).await;
+                                    emit_sequenced_event(
+                                        &actor_event_tx,
+                                        &actor_event_history,
+                                        &actor_event_seq,
+                                        event_history_limit,
+                                        SdkEvent::ConnectionStateChanged { from, to },
+                                    );
+                                    transition_from = to;
+                                }
+                            }
+
+                            // 关键不变量：宿主在已登录场景下点 connect()（前台 fast-track / 网络
+                            // 恢复 / 后台 disconnect 后回前台），final state 必须**仍是** Authenticated，
+                            // 否则任何要 authenticated session 的 RPC（markRead / sendMessage /
+                            // search 等）会立刻挂"current: Connected"。
+                            //
+                            // 触发场景：
+                            //  1) SYNC_READY 透明 fast-track：state=Authenticated，transport 还没掉
+                            //  2) 后台超时 disconnect 后回前台：state=New，transport=None，但 storage
+                            //     里的 session snapshot 还在
+                            //  3) 网络抖动：state=New（health probe 降级过），transport=None
+                            //
+                            // 三种场景都用 try_auto_reconnect 单步法解决：它 atomic 地 connect +
+                            // load session + re-authenticate，自然收敛到 Authenticated（有 snapshot）
+                            // 或 Connected（无 snapshot：冷启动 / 已 logged out / forced）。
+                            //
+                            // 之前用过"先转 Connected 再尝试 auto-restore"两步法，transport 偶尔在
+                            // `state.connect()` 内部短路没真正重建 → auto-restore race 到失败 → 卡 Connected。
+                            let result: Result<()> = match plan {
+                                Err(e) => Err(e),
+                                Ok(ConnectPlan::AlreadyReady) => Ok(()),
+                                Ok(ConnectPlan::RestorePersistedSession) => {
+                                    match state
+                                        .try_auto_reconnect(
+                                            &mut inbound_task,
+                                            &actor_event_tx,
+                                            &actor_event_history,
+                                            &actor_event_seq,
+                                            event_history_limit,
+                                        )
+                                        .await
+                                    {
+                                        Ok(next) => {
+                                            state.session_state = next;
+                                            Ok(())
+                                        }
+                                        Err(e) => Err(e),
+                                    }
+                                }
+                                Ok(ConnectPlan::ConnectTransportOnly) => {
+                                    // 冷启动且无本地 session（用户没登录过）/ logged out / forced：
+                                    // 走简单 connect+transition；不 authenticate（没 session 可用）。
+                                    match state.session_state.can(Action::Connect) {
+                                        Ok(next_state) => {
+                                            match timeout(state.connect_timeout_total(), state.connect()).await
+                                            {
+                                                Ok(r) => {
+                                                    if r.is_ok() {
+                                                        state.session_state = next_state;
+                                                    }
+                                                    r
+                                                }
+                                                Err(_) => Err(Error::Transport("connect timeout".to_string())),
+                                            }
+                                        }
+                                        Err(e) => Err(e),
+                                    }
+                                }
+                            };
+
+                            if result.is_ok() {
+                                state.reset_reconnect_backoff();
+                                if !already_ready {
+                                    state.sync_coordinator.note_network_available();
+                                }
+                                // 连上了就是网络可达的铁证:复位系统 reachability 的 Offline
+                                // 假信号,否则 hint 会继续压制退避节奏与其它路径。
+                                if !state.network_hint.is_online() {
+                                    eprintln!("[SDK.actor] connect ok while hint=Offline; reset hint to Unknown");
+                                    state.network_hint = NetworkHint::Unknown;
+                                    state.bandwidth.set_network(NetworkHint::Unknown);
+                                }
+                                // 用户主动 Connect 成功 = 新的登录回合。清 Terminal 闸门
+                                // 和上一轮 terminal reason，让后续 Authenticate 若再遇到
+                                // Terminal 错可以再次触发 ForcedLogout。
+                                state.auth_terminal_fired = false;
+                                state.last_terminal_reason = None;
+                                if !already_ready {
+                                    start_inbound_task(
+                                        &mut state,
+                                        actor_cmd_tx.clone(),
+                                        &mut inbound_task,
+                                    )
+                                    .await;
+                                    if realtime_trace_enabled() { eprintln!(
+                                        "[SDK_RECONNECT_OK] (connect-path) new_epoch={} state={:?} uid={:?}",
+                                        state.inbound_epoch, state.session_state, state.current_uid
+                                    ); }
+                                    // [硬化] 防御性 replay：push 帧可能在登录前缓冲，current_uid 恢复后立即重放。
+                                    if let Err(err) = state.replay_prelogin_inbound_frames().await {
+                                        eprintln!("[SDK.actor] connect: prelogin replay failed: {err}");
+                                    }
+                                    // 重连后重放活跃订阅：恢复 presence_changed / typing / room 广播。
+                                    state.replay_subscriptions().await;
+                                }
+
+                                // 若 try_auto_reconnect 把 session 拉回 Authenticated 且
+                                // bootstrap 早已完成，触发一次 resume_sync 把背景期间漏掉的
+                                // 增量同步上来（与 retry driver 的成功分支一致）。
+                                // 入口可能是 Authenticated（fast-track）或 New（后台 disconnect 后回前台），
+                                // 只要本地有 session 且当前已 Authenticated，就跑 resume_sync。
+                                // 先把「连接已就绪」告诉宿主，再跑 resume sync。
+                                //
+                                // 顺序反过来会让状态事件被整轮同步挡住：会话多的账号 ensure_synced
+                                // 要跑很久，期间 UI 收不到 Authenticated，状态条一直停在「服务器
+                                // 连接中」——而连接其实早就建好、数据也正在回来。连接态是既成事实，
+                                // 不该等数据层动作完成才通知；同步进度本来就有 resume_sync_* 事件
+                                // 单独播报。
+                                let to_state = state.session_state.as_connection_state();
+                                if transition_from != to_state {
+                                    emit_sequenced_event(
+                                        &actor_event_tx,
+                                        &actor_event_history,
+                                        &actor_event_seq,
+                                        event_history_limit,
+                                        SdkEvent::ConnectionStateChanged {
+                                            from: transition_from,
+                                            to: to_state,
+                                        },
+                                    );
+                                }
+
+                                // 连接已就绪，先答复宿主再跑 resume sync。
+                                //
+                                // 把 resp.send 留到同步之后，宿主的 connect() 就要挂到整轮
+                                // 同步结束才返回：会话多的账号能挂几十秒，而宿主往往在 connect()
+                                // 前后切状态条文案（「连接中」→「同步中」→清空），于是状态条
+                                // 卡死在「服务器连接中」——连接其实早就建好了。
+                                // connect 的语义是「连接建立成功」，不该捎带数据层的耗时。
+                                let _ = resp.send(Ok(()));
+
+                                if had_local_session
                                     && state.session_state == SessionState::Authenticated
                                     && state.bootstrap_completed
                                 {
@@ -14262,7 +17026,9 @@ impl PrivchatSdk {
                     Command::SetNetworkHint { hint, resp } => {
                         let old_hint = state.network_hint;
                         state.network_hint = hint;
+                        state.bandwidth.set_network(hint);
                         if old_hint != hint {
+                            state.reevaluate_auto_downloads().await;
                             emit_sequenced_event(
                                 &actor_event_tx,
                                 &actor_event_history,
@@ -14313,6 +17079,9 @@ impl PrivchatSdk {
                             "[SDK_PUSH_FRAME_RECEIVED] frame_epoch={} current_epoch={} biz_type={} payload_len={} uid={:?}",
                             epoch, state.inbound_epoch, biz_type, data.len(), state.current_uid
                         ); }
+                        if let Some(recorder) = state.trace_recorder.as_mut() {
+                            recorder.record_inbound_frame(biz_type, &data);
+                        }
                         match state.handle_inbound_frame(biz_type, data).await {
                             Ok(applied) if applied > 0 => {
                                 for evt in state.last_sync_entity_events.clone() {
@@ -14833,6 +17602,14 @@ impl PrivchatSdk {
                                     "[SDK.media] pruned {removed} expired sealed cache(s)"
                                 );
                             }
+                            // blob 库同一个窗口：过期的封装缓存和没人引用的内容。
+                            let released = crate::media_blob::collect_garbage(
+                                &paths.user_root,
+                                SEALED_CACHE_RETENTION,
+                            );
+                            if released > 0 {
+                                eprintln!("[SDK.media] released {released} unreferenced blob(s)");
+                            }
                         }
                         // 显式路径：解除退避窗口，并且要知道这一轮到底跑没跑。
                         //
@@ -15249,9 +18026,62 @@ impl PrivchatSdk {
                             let _ = resp.send(result);
                         }
                     }
-                    Command::GetMessageById { message_id, resp } => {
+                    Command::GetMessageById { message_id, resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.storage.get_message_by_id(message_id).await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::GetMessageIdByServerMessageId {
+                        channel,
+                        server_message_id,
+                        resp,
+                    } => {
+                        let result = match (state.current_uid_required(), channel) {
+                            (Err(e), _) => Err(e),
+                            (Ok(_), Some((channel_id, channel_type))) => {
+                                state
+                                    .storage
+                                    .get_message_id_by_server_message_id(
+                                        channel_id,
+                                        channel_type,
+                                        server_message_id,
+                                    )
+                                    .await
+                            }
+                            (Ok(_), None) => {
+                                state
+                                    .storage
+                                    .find_message_id_by_server_message_id(server_message_id)
+                                    .await
+                            }
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::ListMessagesBefore {
+                        channel_id,
+                        channel_type,
+                        before_local_id,
+                        limit,
+                        offset,
+                        resp,
+                    } => {
+                        // 带游标的分页不走时间线快照缓存：缓存按 (limit, offset) 切片，
+                        // 切完再按游标筛会把整页筛空。
                         let result = match state.current_uid_required() {
-                            Ok(_) => state.storage.get_message_by_id(message_id).await,
+                            Ok(_) => {
+                                state
+                                    .storage
+                                    .list_messages_before(
+                                        channel_id,
+                                        channel_type,
+                                        before_local_id,
+                                        limit,
+                                        offset,
+                                    )
+                                    .await
+                            }
                             Err(e) => Err(e),
                         };
                         let _ = resp.send(result);
@@ -15673,6 +18503,10 @@ impl PrivchatSdk {
                             .await;
                         let _ = resp.send(());
                     }
+                    Command::CompleteAutoDownload { key, result, resp } => {
+                        state.apply_auto_download_outcome(key, result).await;
+                        let _ = resp.send(());
+                    }
                     Command::CreateLocalAttachmentPlaceholder { input, local_message_id, resp } => {
                         let channel_id = input.channel_id;
                         let channel_type = input.channel_type;
@@ -15876,6 +18710,7 @@ impl PrivchatSdk {
                             Err(e) => Err(e),
                         };
                         if let Ok(Some(stored)) = &result {
+                            state.release_message_media(std::slice::from_ref(stored)).await;
                             state.invalidate_channel_cache_with_reason(
                                 stored.channel_id,
                                 stored.channel_type,
@@ -15912,6 +18747,9 @@ impl PrivchatSdk {
                             Ok(_) => state.storage.delete_channel_local(channel_id).await,
                             Err(e) => Err(e),
                         };
+                        if let Ok(removed) = &result {
+                            state.release_message_media(removed).await;
+                        }
                         let _ = resp.send(result);
                     }
                     Command::EditMessage {
@@ -15956,54 +18794,333 @@ impl PrivchatSdk {
                         }
                         let _ = resp.send(result);
                     }
-                    Command::SetMessagePinned {
-                        message_id,
-                        is_pinned,
+                    Command::SetMessagePinned {
+                        message_id,
+                        is_pinned,
+                        resp,
+                    } => {
+                        let message_ctx = match state.current_uid_required() {
+                            Ok(_) => state.storage.get_message_by_id(message_id).await.ok().flatten(),
+                            Err(_) => None,
+                        };
+                        let result = match state.current_uid_required() {
+                            Ok(_) => {
+                                state
+                                    .storage
+                                    .set_message_pinned(message_id, is_pinned)
+                                    .await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        if result.is_ok() {
+                            if let Some(msg) = &message_ctx {
+                                state.invalidate_channel_cache_with_reason(
+                                    msg.channel_id,
+                                    msg.channel_type,
+                                    "set_message_pinned",
+                                );
+                            }
+                            emit_sequenced_event(
+                                &actor_event_tx,
+                                &actor_event_history,
+                                &actor_event_seq,
+                                event_history_limit,
+                                SdkEvent::TimelineUpdated {
+                                    channel_id: message_ctx.as_ref().map(|m| m.channel_id).unwrap_or(0),
+                                    channel_type: message_ctx.as_ref().map(|m| m.channel_type).unwrap_or(0),
+                                    message_id,
+                                    reason: if is_pinned {
+                                        "pin".to_string()
+                                    } else {
+                                        "unpin".to_string()
+                                    },
+                                },
+                            );
+                            if let Some(msg) = &message_ctx {
+                                emit_sequenced_event(
+                                    &actor_event_tx,
+                                    &actor_event_history,
+                                    &actor_event_seq,
+                                    event_history_limit,
+                                    SdkEvent::PinnedMessagesChanged {
+                                        channel_id: msg.channel_id,
+                                        channel_type: msg.channel_type,
+                                    },
+                                );
+                            }
+                        }
+                        let _ = resp.send(result);
+                    }
+                    Command::GetMessageExtra { message_id, resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.storage.get_message_extra(message_id).await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::DiscardPendingMessageEdit { message_id, resp } => {
+                        let message_ctx = match state.current_uid_required() {
+                            Ok(_) => state.storage.get_message_by_id(message_id).await.ok().flatten(),
+                            Err(_) => None,
+                        };
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.storage.discard_pending_message_edit(message_id).await,
+                            Err(e) => Err(e),
+                        };
+                        if let (Ok(true), Some(msg)) = (&result, &message_ctx) {
+                            state.invalidate_channel_cache_with_reason(
+                                msg.channel_id,
+                                msg.channel_type,
+                                "discard_pending_message_edit",
+                            );
+                            emit_sequenced_event(
+                                &actor_event_tx,
+                                &actor_event_history,
+                                &actor_event_seq,
+                                event_history_limit,
+                                SdkEvent::TimelineUpdated {
+                                    channel_id: msg.channel_id,
+                                    channel_type: msg.channel_type,
+                                    message_id,
+                                    reason: "edit_rejected".to_string(),
+                                },
+                            );
+                        }
+                        let _ = resp.send(result);
+                    }
+                    Command::ListMessageRevisions { message_id, resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.storage.list_message_revisions(message_id).await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::VotePoll {
+                        message_id,
+                        option_indexes,
+                        resp,
+                    } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.record_poll_vote(message_id, option_indexes).await,
+                            Err(e) => Err(e),
+                        };
+                        if result.is_ok() {
+                            let _ = actor_cmd_tx.try_send(Command::KickOutboundDrain);
+                        }
+                        let _ = resp.send(result);
+                    }
+                    Command::RefreshPoll { message_id, resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.refresh_poll(message_id).await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::UpdateLiveLocation {
+                        message_id,
+                        position,
+                        resp,
+                    } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.record_live_location(message_id, position).await,
+                            Err(e) => Err(e),
+                        };
+                        if let Ok(next_attempt_at) = result {
+                            // 节流推迟的更新到点再敲一次出站队列，不必等 15s 的 health tick。
+                            let delay_ms = next_attempt_at - chrono::Utc::now().timestamp_millis();
+                            if delay_ms > 0 {
+                                let kick_tx = actor_cmd_tx.clone();
+                                tokio::spawn(async move {
+                                    sleep(Duration::from_millis(delay_ms as u64)).await;
+                                    let _ = kick_tx.try_send(Command::KickOutboundDrain);
+                                });
+                            } else {
+                                let _ = actor_cmd_tx.try_send(Command::KickOutboundDrain);
+                            }
+                        }
+                        let _ = resp.send(result.map(|_| ()));
+                    }
+                    Command::PinMessage {
+                        message_id,
+                        pinned,
+                        resp,
+                    } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.record_message_pin(message_id, pinned).await,
+                            Err(e) => Err(e),
+                        };
+                        if result.is_ok() {
+                            let _ = actor_cmd_tx.try_send(Command::KickOutboundDrain);
+                        }
+                        let _ = resp.send(result);
+                    }
+                    Command::ListPinnedMessages {
+                        channel_id,
+                        channel_type,
+                        resp,
+                    } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => {
+                                state
+                                    .storage
+                                    .list_pinned_messages(channel_id, channel_type)
+                                    .await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::RefreshPinnedMessages {
+                        channel_id,
+                        channel_type,
+                        resp,
+                    } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => {
+                                state
+                                    .refresh_pinned_messages(channel_id, channel_type)
+                                    .await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::ListStickerPackages {
+                        installed_only,
+                        resp,
+                    } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.list_sticker_packages(installed_only).await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::RefreshStickerPackages { resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.refresh_sticker_packages().await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::RefreshStickerPackage { package_id, resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.refresh_sticker_package(package_id).await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::InstallStickerPackage {
+                        package_id,
+                        installed,
+                        resp,
+                    } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.record_sticker_install(package_id, installed).await,
+                            Err(e) => Err(e),
+                        };
+                        if result.is_ok() {
+                            let _ = actor_cmd_tx.try_send(Command::KickOutboundDrain);
+                        }
+                        let _ = resp.send(result);
+                    }
+                    Command::ReorderStickerPackages { package_ids, resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.storage.reorder_sticker_packages(package_ids).await,
+                            Err(e) => Err(e),
+                        };
+                        if result.is_ok() {
+                            state.pending_events.push(SdkEvent::StickerCatalogChanged);
+                        }
+                        let _ = resp.send(result);
+                    }
+                    Command::ListRecentStickers { resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => match state.storage.list_recent_stickers().await {
+                                Ok(mut stickers) => {
+                                    state.with_sticker_assets(&mut stickers).await;
+                                    Ok(stickers)
+                                }
+                                Err(e) => Err(e),
+                            },
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::ListFavouriteStickers { resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => match state.storage.list_favourite_stickers().await {
+                                Ok(mut stickers) => {
+                                    state.with_sticker_assets(&mut stickers).await;
+                                    Ok(stickers)
+                                }
+                                Err(e) => Err(e),
+                            },
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::FavouriteSticker { sticker, resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.storage.set_sticker_favourite(sticker, true).await,
+                            Err(e) => Err(e),
+                        };
+                        if matches!(result, Ok(true)) {
+                            state.pending_events.push(SdkEvent::StickerCatalogChanged);
+                        }
+                        let _ = resp.send(result);
+                    }
+                    Command::UnfavouriteSticker { sticker_id, resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => {
+                                let sticker = Sticker {
+                                    sticker_id,
+                                    ..Default::default()
+                                };
+                                state.storage.set_sticker_favourite(sticker, false).await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        if matches!(result, Ok(true)) {
+                            state.pending_events.push(SdkEvent::StickerCatalogChanged);
+                        }
+                        let _ = resp.send(result);
+                    }
+                    Command::PrepareStickerSend { sticker_id, resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.prepare_sticker_send(sticker_id).await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::CompleteStickerPackageLoad { key, package } => {
+                        state.apply_sticker_package_load(key, package).await;
+                    }
+                    Command::CompleteStickerPrefetch { key } => {
+                        state.apply_sticker_prefetch_outcome(key);
+                    }
+                    Command::ListMemberReadCursors {
+                        channel_id,
+                        channel_type,
                         resp,
                     } => {
-                        let message_ctx = match state.current_uid_required() {
-                            Ok(_) => state.storage.get_message_by_id(message_id).await.ok().flatten(),
-                            Err(_) => None,
-                        };
                         let result = match state.current_uid_required() {
                             Ok(_) => {
                                 state
                                     .storage
-                                    .set_message_pinned(message_id, is_pinned)
+                                    .list_member_read_cursors(channel_id, channel_type)
                                     .await
                             }
                             Err(e) => Err(e),
                         };
-                        if result.is_ok() {
-                            if let Some(msg) = &message_ctx {
-                                state.invalidate_channel_cache_with_reason(
-                                    msg.channel_id,
-                                    msg.channel_type,
-                                    "set_message_pinned",
-                                );
-                            }
-                            emit_sequenced_event(
-                                &actor_event_tx,
-                                &actor_event_history,
-                                &actor_event_seq,
-                                event_history_limit,
-                                SdkEvent::TimelineUpdated {
-                                    channel_id: message_ctx.as_ref().map(|m| m.channel_id).unwrap_or(0),
-                                    channel_type: message_ctx.as_ref().map(|m| m.channel_type).unwrap_or(0),
-                                    message_id,
-                                    reason: if is_pinned {
-                                        "pin".to_string()
-                                    } else {
-                                        "unpin".to_string()
-                                    },
-                                },
-                            );
-                        }
                         let _ = resp.send(result);
                     }
-                    Command::GetMessageExtra { message_id, resp } => {
+                    Command::MessageReadReceipts {
+                        message_id,
+                        backfill,
+                        resp,
+                    } => {
                         let result = match state.current_uid_required() {
-                            Ok(_) => state.storage.get_message_extra(message_id).await,
+                            Ok(_) => state.message_read_receipts(message_id, backfill).await,
                             Err(e) => Err(e),
                         };
                         let _ = resp.send(result);
@@ -16076,6 +19193,47 @@ impl PrivchatSdk {
                         };
                         let _ = resp.send(result);
                     }
+                    Command::GetUnreadAggregate { resp } => {
+                        let _ = resp.send(state.refresh_unread_aggregate().await);
+                    }
+                    Command::QueryChannels { query, resp } => {
+                        let result = match state.ensure_channel_prefs_mirrored().await {
+                            Ok(()) => match state.storage.query_channels(query).await {
+                                Ok(mut page) => {
+                                    let channels = std::mem::take(&mut page.channels);
+                                    for channel in channels {
+                                        page.channels
+                                            .push(state.materialize_channel_preview(channel).await);
+                                    }
+                                    Ok(page)
+                                }
+                                Err(e) => Err(e),
+                            },
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::UpsertChannelFolder { input, resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.storage.upsert_channel_folder(input).await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::DeleteChannelFolder { folder_id, resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.storage.delete_channel_folder(folder_id).await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::ListChannelFolders { resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.storage.list_channel_folders().await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
                     Command::UpsertUser { input, resp } => {
                         let result = match state.current_uid_required() {
                             Ok(_) => {
@@ -16133,14 +19291,207 @@ impl PrivchatSdk {
                         resp,
                     } => {
                         let result = match state.current_uid_required() {
-                            Ok(_) => state.storage.list_friends(limit, offset).await,
+                            Ok(_) => state.storage.list_friends(limit, offset).await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::ListFriendRequests {
+                        outgoing,
+                        statuses,
+                        limit,
+                        offset,
+                        resp,
+                    } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => {
+                                state
+                                    .storage
+                                    .list_friend_requests(outgoing, statuses, limit, offset)
+                                    .await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::UpsertBlacklistEntry { input, resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.storage.upsert_blacklist_entry(input).await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::DeleteBlacklistEntry {
+                        blocked_user_id,
+                        resp,
+                    } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => {
+                                state
+                                    .storage
+                                    .delete_blacklist_entry(blocked_user_id)
+                                    .await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::ListBlacklistEntries {
+                        limit,
+                        offset,
+                        resp,
+                    } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => {
+                                state
+                                    .storage
+                                    .list_blacklist_entries(limit, offset)
+                                    .await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::UpsertGroup { input, resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.storage.upsert_group(input).await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::GetGroupById { group_id, resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.storage.get_group_by_id(group_id).await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::ListGroups {
+                        limit,
+                        offset,
+                        resp,
+                    } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.storage.list_groups(limit, offset).await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::UpsertGroupMember { input, resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.storage.upsert_group_member(input).await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::RecacheAvatar { user_id, url, resp } => {
+                        match state.current_uid_required() {
+                            Ok(_) => {
+                                // 下载可能慢 → spawn，避免阻塞 actor loop；完成后回 oneshot。
+                                let storage = state.storage.clone();
+                                let bandwidth = state.bandwidth.clone();
+                                tokio::spawn(async move {
+                                    let r = avatar_cache::recache_user_avatar(
+                                        &storage, &bandwidth, user_id, &url,
+                                    )
+                                    .await;
+                                    let _ = resp.send(r);
+                                });
+                            }
+                            Err(e) => {
+                                let _ = resp.send(Err(e));
+                            }
+                        }
+                    }
+                    Command::DeleteGroupMember {
+                        group_id,
+                        user_id,
+                        resp,
+                    } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => {
+                                state
+                                    .storage
+                                    .delete_group_member(group_id, user_id)
+                                    .await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::ListGroupMembers {
+                        group_id,
+                        limit,
+                        offset,
+                        resp,
+                    } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => {
+                                state
+                                    .storage
+                                    .list_group_members(group_id, limit, offset)
+                                    .await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::UpsertChannelMember { input, resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.storage.upsert_channel_member(input).await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::ListChannelMembers {
+                        channel_id,
+                        channel_type,
+                        limit,
+                        offset,
+                        resp,
+                    } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => {
+                                state
+                                    .storage
+                                    .list_channel_members(
+                                        channel_id,
+                                        channel_type,
+                                        limit,
+                                        offset,
+                                    )
+                                    .await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::DeleteChannelMember {
+                        channel_id,
+                        channel_type,
+                        member_uid,
+                        resp,
+                    } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => {
+                                state
+                                    .storage
+                                    .delete_channel_member(channel_id, channel_type, member_uid)
+                                    .await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::UpsertMessageReaction { input, resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.storage.upsert_message_reaction(input).await,
                             Err(e) => Err(e),
                         };
                         let _ = resp.send(result);
                     }
-                    Command::ListFriendRequests {
-                        outgoing,
-                        statuses,
+                    Command::ListMessageReactions {
+                        message_id,
                         limit,
                         offset,
                         resp,
@@ -16149,1167 +19500,1908 @@ impl PrivchatSdk {
                             Ok(_) => {
                                 state
                                     .storage
-                                    .list_friend_requests(outgoing, statuses, limit, offset)
+                                    .list_message_reactions(message_id, limit, offset)
                                     .await
                             }
                             Err(e) => Err(e),
                         };
                         let _ = resp.send(result);
                     }
-                    Command::UpsertBlacklistEntry { input, resp } => {
+                    Command::RecordMention { input, resp } => {
                         let result = match state.current_uid_required() {
-                            Ok(_) => state.storage.upsert_blacklist_entry(input).await,
+                            Ok(_) => state.storage.record_mention(input).await,
                             Err(e) => Err(e),
                         };
                         let _ = resp.send(result);
                     }
-                    Command::DeleteBlacklistEntry {
-                        blocked_user_id,
+                    Command::GetUnreadMentionCount {
+                        channel_id,
+                        channel_type,
+                        user_id,
                         resp,
                     } => {
                         let result = match state.current_uid_required() {
                             Ok(_) => {
                                 state
                                     .storage
-                                    .delete_blacklist_entry(blocked_user_id)
+                                    .get_unread_mention_count(channel_id, channel_type, user_id)
                                     .await
                             }
                             Err(e) => Err(e),
                         };
                         let _ = resp.send(result);
                     }
-                    Command::ListBlacklistEntries {
+                    Command::ListUnreadMentionMessageIds {
+                        channel_id,
+                        channel_type,
+                        user_id,
                         limit,
-                        offset,
                         resp,
                     } => {
                         let result = match state.current_uid_required() {
                             Ok(_) => {
                                 state
                                     .storage
-                                    .list_blacklist_entries(limit, offset)
+                                    .list_unread_mention_message_ids(channel_id, channel_type, user_id, limit)
                                     .await
                             }
                             Err(e) => Err(e),
                         };
                         let _ = resp.send(result);
                     }
-                    Command::UpsertGroup { input, resp } => {
+                    Command::MarkMentionRead {
+                        message_id,
+                        user_id,
+                        resp,
+                    } => {
                         let result = match state.current_uid_required() {
-                            Ok(_) => state.storage.upsert_group(input).await,
+                            Ok(_) => {
+                                state
+                                    .storage
+                                    .mark_mention_read(message_id, user_id)
+                                    .await
+                            }
                             Err(e) => Err(e),
                         };
                         let _ = resp.send(result);
                     }
-                    Command::GetGroupById { group_id, resp } => {
+                    Command::MarkAllMentionsRead {
+                        channel_id,
+                        channel_type,
+                        user_id,
+                        resp,
+                    } => {
                         let result = match state.current_uid_required() {
-                            Ok(_) => state.storage.get_group_by_id(group_id).await,
+                            Ok(_) => {
+                                state
+                                    .storage
+                                    .mark_all_mentions_read(channel_id, channel_type, user_id)
+                                    .await
+                            }
                             Err(e) => Err(e),
                         };
                         let _ = resp.send(result);
                     }
-                    Command::ListGroups {
-                        limit,
-                        offset,
-                        resp,
-                    } => {
+                    Command::GetAllUnreadMentionCounts { user_id, resp } => {
                         let result = match state.current_uid_required() {
-                            Ok(_) => state.storage.list_groups(limit, offset).await,
+                            Ok(_) => {
+                                state
+                                    .storage
+                                    .get_all_unread_mention_counts(user_id)
+                                    .await
+                            }
                             Err(e) => Err(e),
                         };
                         let _ = resp.send(result);
                     }
-                    Command::UpsertGroupMember { input, resp } => {
+                    Command::UpsertReminder { input, resp } => {
                         let result = match state.current_uid_required() {
-                            Ok(_) => state.storage.upsert_group_member(input).await,
+                            Ok(_) => state.storage.upsert_reminder(input).await,
                             Err(e) => Err(e),
                         };
                         let _ = resp.send(result);
                     }
-                    Command::RecacheAvatar { user_id, url, resp } => {
-                        match state.current_uid_required() {
-                            Ok(_) => {
-                                // 下载可能慢 → spawn，避免阻塞 actor loop；完成后回 oneshot。
-                                let storage = state.storage.clone();
-                                tokio::spawn(async move {
-                                    let r =
-                                        avatar_cache::recache_user_avatar(&storage, user_id, &url)
-                                            .await;
-                                    let _ = resp.send(r);
-                                });
-                            }
-                            Err(e) => {
-                                let _ = resp.send(Err(e));
-                            }
-                        }
-                    }
-                    Command::DeleteGroupMember {
-                        group_id,
-                        user_id,
+                    Command::ListPendingReminders {
+                        uid: reminder_uid,
+                        limit,
+                        offset,
                         resp,
                     } => {
                         let result = match state.current_uid_required() {
                             Ok(_) => {
                                 state
                                     .storage
-                                    .delete_group_member(group_id, user_id)
+                                    .list_pending_reminders(reminder_uid, limit, offset)
                                     .await
                             }
                             Err(e) => Err(e),
                         };
                         let _ = resp.send(result);
                     }
-                    Command::ListGroupMembers {
-                        group_id,
-                        limit,
-                        offset,
+                    Command::MarkReminderDone {
+                        reminder_id,
+                        done,
                         resp,
                     } => {
                         let result = match state.current_uid_required() {
                             Ok(_) => {
                                 state
                                     .storage
-                                    .list_group_members(group_id, limit, offset)
+                                    .mark_reminder_done(reminder_id, done)
                                     .await
                             }
                             Err(e) => Err(e),
                         };
                         let _ = resp.send(result);
                     }
-                    Command::UpsertChannelMember { input, resp } => {
+                    Command::KvPut { key, value, resp } => {
+                        let pref = parse_channel_prefs_key(&key).map(|(channel_id, channel_type)| {
+                            let state = decode_channel_prefs(Some(value.clone()));
+                            channel_pref_row(channel_id, channel_type, &state)
+                        });
                         let result = match state.current_uid_required() {
-                            Ok(_) => state.storage.upsert_channel_member(input).await,
+                            Ok(_) => state.storage.kv_put(key, value).await,
                             Err(e) => Err(e),
                         };
+                        if let (Ok(()), Some(pref)) = (&result, pref) {
+                            let event = SdkEvent::ChannelPrefsChanged {
+                                channel_id: pref.channel_id,
+                                channel_type: pref.channel_type,
+                            };
+                            // kv 是权威副本；镜像写失败就让下一次查询整体回填。
+                            if let Err(e) = state.storage.upsert_channel_pref(pref).await {
+                                tracing::warn!(error = %e, "mirror channel prefs failed");
+                                state.channel_prefs_mirrored_uid = None;
+                            }
+                            if let (Some(tx), Some(history), Some(seq)) =
+                                (&state.event_tx, &state.event_history, &state.event_seq)
+                            {
+                                emit_sequenced_event(
+                                    tx,
+                                    history,
+                                    seq,
+                                    state.event_history_limit,
+                                    event,
+                                );
+                            } else if let Some(tx) = &state.event_tx {
+                                let _ = tx.send(event);
+                            }
+                        }
                         let _ = resp.send(result);
                     }
-                    Command::ListChannelMembers {
-                        channel_id,
-                        channel_type,
-                        limit,
-                        offset,
-                        resp,
-                    } => {
+                    Command::KvGet { key, resp } => {
                         let result = match state.current_uid_required() {
-                            Ok(_) => {
-                                state
-                                    .storage
-                                    .list_channel_members(
-                                        channel_id,
-                                        channel_type,
-                                        limit,
-                                        offset,
-                                    )
-                                    .await
+                            Ok(_) => state.storage.kv_get(key).await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::GetUserStoragePaths { resp } => {
+                        let result = match state.current_uid_required() {
+                            Ok(_) => state.storage.get_storage_paths().await.map(|paths| {
+                                UserStoragePaths {
+                                    user_root: paths.user_root.display().to_string(),
+                                    db_path: paths.db_path.display().to_string(),
+                                    kv_path: paths.kv_path.display().to_string(),
+                                    queue_root: paths.queue_root.display().to_string(),
+                                    normal_queue_path: paths
+                                        .normal_queue_path
+                                        .display()
+                                        .to_string(),
+                                    file_queue_paths: paths
+                                        .file_queue_paths
+                                        .iter()
+                                        .map(|v| v.display().to_string())
+                                        .collect(),
+                                    media_root: paths.media_root.display().to_string(),
+                                }
+                            }),
+                            Err(e) => Err(e),
+                        };
+                        let _ = resp.send(result);
+                    }
+                    Command::ListLocalAccounts { resp } => {
+                        let result = state.storage.list_local_accounts().await.map(
+                            |(active_uid, entries)| {
+                                entries
+                                    .into_iter()
+                                    .map(|entry| LocalAccountSummary {
+                                        is_active: active_uid
+                                            .as_ref()
+                                            .map(|uid| uid == &entry.uid)
+                                            .unwrap_or(false),
+                                        uid: entry.uid,
+                                        created_at: entry.created_at,
+                                        last_login_at: entry.last_login_at,
+                                        display_name: entry.display_name.clone(),
+                                        username: entry.username.clone(),
+                                        login_mode: entry.login_mode.clone(),
+                                        login_identifier: entry.login_identifier.clone(),
+                                    })
+                                    .collect::<Vec<_>>()
+                            },
+                        );
+                        let _ = resp.send(result);
+                    }
+                    Command::SetCurrentUid { uid, resp } => {
+                        // 只能在「还没有活跃会话」时用来选定账号。对一个正在运行的
+                        // actor 改 uid 不等于切换账号：transport / inbound / 订阅 /
+                        // 缓存 全都还属于上一个账号，改完就是跨账号状态污染。
+                        // 活跃会话要换账号走 SwitchLocalAccount。
+                        let result = if state.current_uid.as_deref() == Some(uid.as_str()) {
+                            // 已经就是这个账号：幂等成功，不是错误。
+                            //
+                            // 宿主冷启动时会无条件调一次本命令来选定账号，而 Activity
+                            // 重建并不杀进程——session 很可能还停在 Authenticated。
+                            // 之前不分青红皂白按「活跃会话」拒掉，于是每次启动都死在
+                            // 这一行：宿主报「切换账号失败」并停在登录页，重新登录还是
+                            // 同一条路，用户只能卸载重装。
+                            //
+                            // 上面那条防护要防的是**换到别的账号**，同一个 uid 不存在
+                            // 跨账号污染，没有理由拒绝。
+                            Ok(())
+                        } else if matches!(
+                            state.session_state,
+                            SessionState::Connected
+                                | SessionState::LoggedIn
+                                | SessionState::Authenticated
+                        ) {
+                            Err(Error::InvalidState(
+                                "set_current_uid on an active session; use switch_local_account"
+                                    .to_string(),
+                            ))
+                        } else {
+                            match state.storage.list_local_accounts().await {
+                            Ok((_, entries)) => {
+                                if !entries.iter().any(|entry| entry.uid == uid) {
+                                    Err(Error::InvalidState(format!(
+                                        "local account not found: {uid}"
+                                    )))
+                                } else {
+                                    let saved = state.storage.save_current_uid(uid.clone()).await;
+                                    let loaded = state.storage.load_session(uid.clone()).await;
+                                    match (saved, loaded) {
+                                        (Ok(()), Ok(snapshot)) => {
+                                            // 水位单独读，不跟凭证绑定。
+                                            //
+                                            // 退出登录只删凭证，盘上的 bootstrap 水位还在；
+                                            // 但快照要求 access_token 存在，于是这里以前
+                                            // 一律 `unwrap_or(false)` —— 再登录同一个账号
+                                            // 就把整份本地库当没同步过重跑全量，用户盯着
+                                            // 「数据初始化中」十几秒。退出登录不是注销账号。
+                                            let completed = match snapshot {
+                                                Some(s) => s.bootstrap_completed,
+                                                None => state
+                                                    .storage
+                                                    .load_bootstrap_completed(uid.clone())
+                                                    .await
+                                                    .unwrap_or(false),
+                                            };
+                                            state.current_uid = Some(uid);
+                                            state.bootstrap_completed = completed;
+                                            Ok(())
+                                        }
+                                        (Err(e), _) => Err(e),
+                                        (_, Err(e)) => Err(e),
+                                    }
+                                }
+                            }
+                                Err(e) => Err(e),
                             }
-                            Err(e) => Err(e),
                         };
                         let _ = resp.send(result);
                     }
-                    Command::DeleteChannelMember {
-                        channel_id,
-                        channel_type,
-                        member_uid,
-                        resp,
-                    } => {
-                        let result = match state.current_uid_required() {
-                            Ok(_) => {
-                                state
-                                    .storage
-                                    .delete_channel_member(channel_id, channel_type, member_uid)
-                                    .await
+                    // 原子账号切换：停旧会话 → 作废在途 → 清会话作用域状态 →
+                    // 装载新账号。全程在这一个命令里完成，App 不再拼接
+                    // setCurrentUid + shutdown + quickEnter 这三步——那三步之间
+                    // 的窗口正是跨账号污染的来源。
+                    Command::SwitchLocalAccount { uid, resp } => {
+                        if actor_logs_enabled() {
+                            eprintln!("[SDK.actor] loop: cmd switch_local_account uid={uid}");
+                        }
+                        // 这条请求已被取走：销账。计数器只表达「还在排队的切换」，
+                        // 不销账的话每一轮同步开始时都会看到一个早已处理完的请求，
+                        // 于是永远开不了工。
+                        state.switch_processed.fetch_add(1, Ordering::SeqCst);
+                        let now_ms = chrono::Utc::now().timestamp_millis();
+                        let from_state = state.session_state.as_connection_state();
+                        // 顺序是这条命令的全部要害：**所有可能失败的 IO 都排在拆除之前**。
+                        //
+                        // 反过来写（先拆旧会话、再存 uid / 读 session）会留下「半切换」：
+                        // 旧会话已经销毁、全局 active uid 可能已指向新账号、而
+                        // state.current_uid 还是旧的，且失败路径不发事件——UI 以为旧账号
+                        // 一切正常，实际底下什么都没有了。原子的意思是「要么整件事发生，
+                        // 要么什么都没发生」，不是「按顺序做完这几件事」。
+                        //
+                        // 现在的次序：① 校验目标存在 → ② 读目标 session → ③ 落盘 active
+                        // uid。这三步任一失败都直接返回，旧会话**一根汗毛都没动**。全部
+                        // 成功之后才 ④ 拆旧会话 ⑤ 提交内存状态——这两步不可失败。
+                        // 残留风险只剩「③ 与 ⑤ 之间进程崩溃」，那种情况下重启会按新 uid
+                        // 启动，本来就是用户要的结果。
+                        let result = async {
+                            // ① 目标账号必须存在。
+                            let (_, entries) = state.storage.list_local_accounts().await?;
+                            if !entries.iter().any(|entry| entry.uid == uid) {
+                                return Err(Error::InvalidState(format!(
+                                    "local account not found: {uid}"
+                                )));
                             }
+                            // ② 先把目标 session 读出来（只读，失败不影响任何现存状态）。
+                            let snapshot = state.storage.load_session(uid.clone()).await?;
+                            // ③ 落盘 active uid。到这里为止全部可回退。
+                            state.storage.save_current_uid(uid.clone()).await?;
+                            Ok(snapshot)
+                        }
+                        .await
+                        .map(|snapshot| {
+                            // ④ 停旧会话：撤销重连意图 → 停 inbound → 断 transport。
+                            //    reset_session_scoped_state 已把 should_auto_reconnect 置 false，
+                            //    所以这里断开不会被自动重连拉回来。
+                            state.reset_session_scoped_state(now_ms);
+                            (snapshot,)
+                        });
+                        let result = match result {
                             Err(e) => Err(e),
-                        };
-                        let _ = resp.send(result);
-                    }
-                    Command::UpsertMessageReaction { input, resp } => {
-                        let result = match state.current_uid_required() {
-                            Ok(_) => state.storage.upsert_message_reaction(input).await,
-                            Err(e) => Err(e),
-                        };
-                        let _ = resp.send(result);
-                    }
-                    Command::ListMessageReactions {
-                        message_id,
-                        limit,
-                        offset,
-                        resp,
-                    } => {
-                        let result = match state.current_uid_required() {
-                            Ok(_) => {
-                                state
-                                    .storage
-                                    .list_message_reactions(message_id, limit, offset)
-                                    .await
+                            Ok((snapshot,)) => {
+                                stop_inbound_task(&mut inbound_task).await;
+                                if let Err(e) = state.disconnect().await {
+                                    // 断开失败不阻断切换：目标是不再使用这条 transport，
+                                    // 而它已经被丢弃（state.transport = None）。
+                                    eprintln!(
+                                        "[SDK.actor] switch_local_account: disconnect old session failed: {e}"
+                                    );
+                                }
+                                state.transport = None;
+
+                                // ⑤ 提交内存状态。
+                                state.current_uid = Some(uid.clone());
+                                state.session_epoch += 1;
+                                state.bootstrap_completed =
+                                    snapshot.map(|s| s.bootstrap_completed).unwrap_or(false);
+                                state.session_state = SessionState::New;
+                                Ok(())
                             }
-                            Err(e) => Err(e),
-                        };
-                        let _ = resp.send(result);
-                    }
-                    Command::RecordMention { input, resp } => {
-                        let result = match state.current_uid_required() {
-                            Ok(_) => state.storage.record_mention(input).await,
-                            Err(e) => Err(e),
                         };
-                        let _ = resp.send(result);
-                    }
-                    Command::GetUnreadMentionCount {
-                        channel_id,
-                        channel_type,
-                        user_id,
-                        resp,
-                    } => {
-                        let result = match state.current_uid_required() {
-                            Ok(_) => {
-                                state
-                                    .storage
-                                    .get_unread_mention_count(channel_id, channel_type, user_id)
-                                    .await
+                        // ③ 只在成功后发一次状态事件。失败不发：否则 UI 会收到一个
+                        //    「切过去了」的假象，而实际仍停在旧账号。
+                        if result.is_ok() {
+                            let to_state = state.session_state.as_connection_state();
+                            if from_state != to_state {
+                                emit_sequenced_event(
+                                    &actor_event_tx,
+                                    &actor_event_history,
+                                    &actor_event_seq,
+                                    event_history_limit,
+                                    SdkEvent::ConnectionStateChanged {
+                                        from: from_state,
+                                        to: to_state,
+                                    },
+                                );
                             }
-                            Err(e) => Err(e),
-                        };
+                        }
                         let _ = resp.send(result);
                     }
-                    Command::ListUnreadMentionMessageIds {
-                        channel_id,
-                        channel_type,
-                        user_id,
-                        limit,
+                    Command::SetLocalAccountDisplayName {
+                        uid,
+                        display_name,
+                        username,
+                        login_mode,
+                        login_identifier,
                         resp,
                     } => {
-                        let result = match state.current_uid_required() {
-                            Ok(_) => {
-                                state
-                                    .storage
-                                    .list_unread_mention_message_ids(channel_id, channel_type, user_id, limit)
-                                    .await
-                            }
-                            Err(e) => Err(e),
-                        };
+                        let result = state
+                            .storage
+                            .save_account_display_name(
+                                uid,
+                                display_name,
+                                username,
+                                login_mode,
+                                login_identifier,
+                            )
+                            .await;
                         let _ = resp.send(result);
                     }
-                    Command::MarkMentionRead {
-                        message_id,
-                        user_id,
-                        resp,
-                    } => {
-                        let result = match state.current_uid_required() {
-                            Ok(_) => {
-                                state
-                                    .storage
-                                    .mark_mention_read(message_id, user_id)
-                                    .await
+                    // 「滚到哪儿就取哪儿的缩略图」。
+                    //
+                    // 缩略图的自动下载本来只挂在消息**入站**（push / sync）那条路径上，
+                    // 历史翻页拉回来的消息从来不触发——用户看到的就是「历史图片必须点
+                    // 一下才加载」。UI 在气泡进入可视区时调这里，语义是 ensure：已经有
+                    // 了就什么都不做，没有才去取。
+                    Command::EnsureMessageThumbnail { message_id, resp } => {
+                        let result = async {
+                            let Some(msg) = state.storage.get_message_by_id(message_id).await? else {
+                                return Ok(());
+                            };
+                            // thumb_status: 1=已下载, 3=协议层确无缩略图（终态）。
+                            if msg.thumb_status == 1 {
+                                return Ok(());
                             }
-                            Err(e) => Err(e),
-                        };
+                            if msg.thumb_status == 3 {
+                                // 3 是终态，但历史上有一批 3 是**误判**写下的：早先
+                                // history 回填丢了 metadata，extra 是空串，当时的代码
+                                // 把「我没看到缩略图字段」当成了「确实没有缩略图」。
+                                // 写入侧已经修好，这些行却永远停在灰块上。
+                                //
+                                // 判据不另写一份，就用写入侧那条：现在再看一次，服务端
+                                // 是否**明确**说了没有缩略图。它说了，3 就是对的；没说，
+                                // 这条 3 就是当年那次误判，退回 0 重新走一遍。
+                                //
+                                // 放在这里而不是启动扫全表：用户滑到哪修到哪，代价只落
+                                // 在真正被看到的那几条上。
+                                let says_none =
+                                    crate::canonical_inbound::CanonicalInboundMessage::from_sync_entity(
+                                        0,
+                                        0,
+                                        msg.channel_id,
+                                        msg.channel_type,
+                                        0,
+                                        0,
+                                        String::new(),
+                                        msg.extra.clone(),
+                                        0,
+                                        0,
+                                    )
+                                    .server_says_no_thumbnail();
+                                if says_none {
+                                    return Ok(());
+                                }
+                                tracing::info!(
+                                    message_id = msg.message_id,
+                                    "thumb_status=3 是早期误判（服务端未明确表示没有缩略图）：退回待重试"
+                                );
+                                state.storage.update_thumb_status(msg.message_id, 0).await?;
+                            }
+
+                            // Missing metadata is projection repair work, not a reason to
+                            // block this UI command on `message/history/around`. Queue the
+                            // existing repair path and return; its TimelineUpdated event
+                            // causes the visible bubble to submit again with fresh metadata.
+                            if State::extract_thumbnail_file_id(&msg.extra).is_none()
+                                && State::extract_thumbnail_url(&msg.extra).is_none()
+                            {
+                                if let Some(server_id) = msg.server_message_id.filter(|id| *id != 0)
+                                {
+                                    state.enqueue_projection_repair(
+                                        msg.channel_id,
+                                        msg.channel_type,
+                                        server_id,
+                                    );
+                                }
+                                return Ok(());
+                            }
+                            state.submit_thumbnail_download(
+                                msg.message_id,
+                                msg.channel_id,
+                                msg.channel_type,
+                                msg.created_at,
+                                &msg.extra,
+                                media_download::DownloadPriority::Visible,
+                            );
+                            Ok(())
+                        }
+                        .await;
                         let _ = resp.send(result);
                     }
-                    Command::MarkAllMentionsRead {
-                        channel_id,
-                        channel_type,
-                        user_id,
-                        resp,
-                    } => {
-                        let result = match state.current_uid_required() {
-                            Ok(_) => {
-                                state
-                                    .storage
-                                    .mark_all_mentions_read(channel_id, channel_type, user_id)
-                                    .await
+                    Command::WipeCurrentUserFull { resp } => {
+                        state.download_manager.cancel_all_scoped();
+                        let from_state = state.session_state.as_connection_state();
+                        state.should_auto_reconnect = false;
+                        state.reset_reconnect_backoff();
+                        state.bootstrap_completed = false;
+                        state.clear_presence_cache();
+                        state.session_state = SessionState::Connected;
+                        let result = match state.current_uid.clone() {
+                            Some(uid) => {
+                                let clear_uid = state.storage.clear_current_uid().await;
+                                let wipe = state.storage.wipe_user_full(uid).await;
+                                state.current_uid = None;
+                                state.session_epoch += 1;
+                                wipe.and(clear_uid)
                             }
-                            Err(e) => Err(e),
+                            None => Ok(()),
                         };
+                        if result.is_ok() {
+                            emit_sequenced_event(
+                                &actor_event_tx,
+                                &actor_event_history,
+                                &actor_event_seq,
+                                event_history_limit,
+                                SdkEvent::ConnectionStateChanged {
+                                    from: from_state,
+                                    to: state.session_state.as_connection_state(),
+                                },
+                            );
+                        }
                         let _ = resp.send(result);
                     }
-                    Command::GetAllUnreadMentionCounts { user_id, resp } => {
-                        let result = match state.current_uid_required() {
-                            Ok(_) => {
-                                state
-                                    .storage
-                                    .get_all_unread_mention_counts(user_id)
-                                    .await
+                    Command::StartTraceRecording { path, resp } => {
+                        // 重复开始 = 先把上一份收尾，免得两份 trace 交错写进同一个 recorder。
+                        if let Some(previous) = state.trace_recorder.take() {
+                            if let Err(e) = previous.finish() {
+                                eprintln!("[SDK.trace] finish previous trace failed: {e}");
                             }
-                            Err(e) => Err(e),
-                        };
+                        }
+                        let result = trace_recorder::TraceRecorder::start(
+                            &path,
+                            state.current_uid.clone(),
+                        )
+                        .map(|(recorder, key)| {
+                            state.trace_recorder = Some(recorder);
+                            key
+                        });
                         let _ = resp.send(result);
                     }
-                    Command::UpsertReminder { input, resp } => {
-                        let result = match state.current_uid_required() {
-                            Ok(_) => state.storage.upsert_reminder(input).await,
-                            Err(e) => Err(e),
+                    Command::StopTraceRecording { resp } => {
+                        let result = match state.trace_recorder.take() {
+                            Some(recorder) => recorder.finish(),
+                            None => Ok(0),
                         };
                         let _ = resp.send(result);
                     }
-                    Command::ListPendingReminders {
-                        uid: reminder_uid,
-                        limit,
-                        offset,
-                        resp,
-                    } => {
-                        let result = match state.current_uid_required() {
-                            Ok(_) => {
-                                state
-                                    .storage
-                                    .list_pending_reminders(reminder_uid, limit, offset)
-                                    .await
+                    Command::BeginTraceReplay { uid, resp } => {
+                        // 回放必须落在一个干净、离线的实例上：有 transport 的话线上帧会和
+                        // 回放帧交错，复现出来的就不是录下来的那个次序了。
+                        let result = if state.session_state != SessionState::New
+                            || state.transport.is_some()
+                        {
+                            Err(Error::InvalidState(
+                                "trace replay requires a fresh, never-connected sdk".to_string(),
+                            ))
+                        } else if state
+                            .current_uid
+                            .as_deref()
+                            .is_some_and(|current| current != uid)
+                        {
+                            Err(Error::InvalidState(format!(
+                                "trace replay for {uid} on an sdk bound to another account"
+                            )))
+                        } else {
+                            match state.storage.save_current_uid(uid.clone()).await {
+                                Ok(()) => {
+                                    state.current_uid = Some(uid);
+                                    Ok(())
+                                }
+                                Err(e) => Err(e),
                             }
-                            Err(e) => Err(e),
                         };
                         let _ = resp.send(result);
                     }
-                    Command::MarkReminderDone {
-                        reminder_id,
-                        done,
+                    Command::ReplayInboundFrame {
+                        biz_type,
+                        data,
                         resp,
                     } => {
-                        let result = match state.current_uid_required() {
-                            Ok(_) => {
-                                state
-                                    .storage
-                                    .mark_reminder_done(reminder_id, done)
-                                    .await
-                            }
-                            Err(e) => Err(e),
-                        };
-                        let _ = resp.send(result);
-                    }
-                    Command::KvPut { key, value, resp } => {
-                        let result = match state.current_uid_required() {
-                            Ok(_) => state.storage.kv_put(key, value).await,
-                            Err(e) => Err(e),
-                        };
-                        let _ = resp.send(result);
-                    }
-                    Command::KvGet { key, resp } => {
-                        let result = match state.current_uid_required() {
-                            Ok(_) => state.storage.kv_get(key).await,
-                            Err(e) => Err(e),
-                        };
-                        let _ = resp.send(result);
-                    }
-                    Command::GetUserStoragePaths { resp } => {
-                        let result = match state.current_uid_required() {
-                            Ok(_) => state.storage.get_storage_paths().await.map(|paths| {
-                                UserStoragePaths {
-                                    user_root: paths.user_root.display().to_string(),
-                                    db_path: paths.db_path.display().to_string(),
-                                    kv_path: paths.kv_path.display().to_string(),
-                                    queue_root: paths.queue_root.display().to_string(),
-                                    normal_queue_path: paths
-                                        .normal_queue_path
-                                        .display()
-                                        .to_string(),
-                                    file_queue_paths: paths
-                                        .file_queue_paths
-                                        .iter()
-                                        .map(|v| v.display().to_string())
-                                        .collect(),
-                                    media_root: paths.media_root.display().to_string(),
+                        let result = state.handle_inbound_frame(biz_type, data).await;
+                        if let Ok(applied) = result {
+                            if applied > 0 {
+                                for evt in state.last_sync_entity_events.clone() {
+                                    emit_sequenced_event(
+                                        &actor_event_tx,
+                                        &actor_event_history,
+                                        &actor_event_seq,
+                                        event_history_limit,
+                                        evt,
+                                    );
                                 }
-                            }),
-                            Err(e) => Err(e),
-                        };
-                        let _ = resp.send(result);
-                    }
-                    Command::ListLocalAccounts { resp } => {
-                        let result = state.storage.list_local_accounts().await.map(
-                            |(active_uid, entries)| {
-                                entries
-                                    .into_iter()
-                                    .map(|entry| LocalAccountSummary {
-                                        is_active: active_uid
-                                            .as_ref()
-                                            .map(|uid| uid == &entry.uid)
-                                            .unwrap_or(false),
-                                        uid: entry.uid,
-                                        created_at: entry.created_at,
-                                        last_login_at: entry.last_login_at,
-                                        display_name: entry.display_name.clone(),
-                                        username: entry.username.clone(),
-                                        login_mode: entry.login_mode.clone(),
-                                        login_identifier: entry.login_identifier.clone(),
-                                    })
-                                    .collect::<Vec<_>>()
-                            },
-                        );
+                            }
+                        }
                         let _ = resp.send(result);
                     }
-                    Command::SetCurrentUid { uid, resp } => {
-                        // 只能在「还没有活跃会话」时用来选定账号。对一个正在运行的
-                        // actor 改 uid 不等于切换账号：transport / inbound / 订阅 /
-                        // 缓存 全都还属于上一个账号，改完就是跨账号状态污染。
-                        // 活跃会话要换账号走 SwitchLocalAccount。
-                        let result = if state.current_uid.as_deref() == Some(uid.as_str()) {
-                            // 已经就是这个账号：幂等成功，不是错误。
-                            //
-                            // 宿主冷启动时会无条件调一次本命令来选定账号，而 Activity
-                            // 重建并不杀进程——session 很可能还停在 Authenticated。
-                            // 之前不分青红皂白按「活跃会话」拒掉，于是每次启动都死在
-                            // 这一行：宿主报「切换账号失败」并停在登录页，重新登录还是
-                            // 同一条路，用户只能卸载重装。
-                            //
-                            // 上面那条防护要防的是**换到别的账号**，同一个 uid 不存在
-                            // 跨账号污染，没有理由拒绝。
+                    Command::SampleOutboxMetrics { resp } => {
+                        // 没登录就没有 outbox 可看：保留上一次的值，不报错。
+                        let result = if state.current_uid.is_none() {
                             Ok(())
-                        } else if matches!(
-                            state.session_state,
-                            SessionState::Connected
-                                | SessionState::LoggedIn
-                                | SessionState::Authenticated
-                        ) {
-                            Err(Error::InvalidState(
-                                "set_current_uid on an active session; use switch_local_account"
-                                    .to_string(),
-                            ))
                         } else {
-                            match state.storage.list_local_accounts().await {
-                            Ok((_, entries)) => {
-                                if !entries.iter().any(|entry| entry.uid == uid) {
-                                    Err(Error::InvalidState(format!(
-                                        "local account not found: {uid}"
-                                    )))
-                                } else {
-                                    let saved = state.storage.save_current_uid(uid.clone()).await;
-                                    let loaded = state.storage.load_session(uid.clone()).await;
-                                    match (saved, loaded) {
-                                        (Ok(()), Ok(snapshot)) => {
-                                            // 水位单独读，不跟凭证绑定。
-                                            //
-                                            // 退出登录只删凭证，盘上的 bootstrap 水位还在；
-                                            // 但快照要求 access_token 存在，于是这里以前
-                                            // 一律 `unwrap_or(false)` —— 再登录同一个账号
-                                            // 就把整份本地库当没同步过重跑全量，用户盯着
-                                            // 「数据初始化中」十几秒。退出登录不是注销账号。
-                                            let completed = match snapshot {
-                                                Some(s) => s.bootstrap_completed,
-                                                None => state
-                                                    .storage
-                                                    .load_bootstrap_completed(uid.clone())
-                                                    .await
-                                                    .unwrap_or(false),
-                                            };
-                                            state.current_uid = Some(uid);
-                                            state.bootstrap_completed = completed;
-                                            Ok(())
-                                        }
-                                        (Err(e), _) => Err(e),
-                                        (_, Err(e)) => Err(e),
-                                    }
-                                }
-                            }
-                                Err(e) => Err(e),
-                            }
+                            state.storage.outbox_stats().await.map(|(depth, oldest)| {
+                                let age_secs = oldest
+                                    .map(|created_at| {
+                                        (chrono::Utc::now().timestamp_millis() - created_at).max(0)
+                                            as f64
+                                            / 1000.0
+                                    })
+                                    .unwrap_or(0.0);
+                                state.metrics.set_gauge(metrics::OUTBOX_DEPTH, &[], depth as f64);
+                                state.metrics.set_gauge(
+                                    metrics::OUTBOX_OLDEST_AGE_SECONDS,
+                                    &[],
+                                    age_secs,
+                                );
+                            })
                         };
                         let _ = resp.send(result);
                     }
-                    // 原子账号切换：停旧会话 → 作废在途 → 清会话作用域状态 →
-                    // 装载新账号。全程在这一个命令里完成，App 不再拼接
-                    // setCurrentUid + shutdown + quickEnter 这三步——那三步之间
-                    // 的窗口正是跨账号污染的来源。
-                    Command::SwitchLocalAccount { uid, resp } => {
-                        if actor_logs_enabled() {
-                            eprintln!("[SDK.actor] loop: cmd switch_local_account uid={uid}");
+                    Command::Shutdown { resp } => {
+                        if actor_logs_enabled() {
+                            eprintln!("[SDK.actor] loop: cmd shutdown");
+                        }
+                        if let Some(recorder) = state.trace_recorder.take() {
+                            if let Err(e) = recorder.finish() {
+                                eprintln!("[SDK.trace] finish trace on shutdown failed: {e}");
+                            }
+                        }
+                        state.should_auto_reconnect = false;
+                        state.reset_reconnect_backoff();
+                        state.download_manager.cancel_all_scoped();
+                        stop_inbound_task(&mut inbound_task).await;
+                        emit_sequenced_event(
+                            &actor_event_tx,
+                            &actor_event_history,
+                            &actor_event_seq,
+                            event_history_limit,
+                            SdkEvent::ShutdownStarted,
+                        );
+                        let from_state = state.session_state.as_connection_state();
+                        if let Ok(next_state) = state.session_state.can(Action::Shutdown) {
+                            state.session_state = next_state;
+                        }
+                        state.clear_presence_cache();
+                        emit_sequenced_event(
+                            &actor_event_tx,
+                            &actor_event_history,
+                            &actor_event_seq,
+                            event_history_limit,
+                            SdkEvent::ConnectionStateChanged {
+                                from: from_state,
+                                to: state.session_state.as_connection_state(),
+                            },
+                        );
+                        state.transport = None;
+                        state.storage.shutdown();
+                        emit_sequenced_event(
+                            &actor_event_tx,
+                            &actor_event_history,
+                            &actor_event_seq,
+                            event_history_limit,
+                            SdkEvent::ShutdownCompleted,
+                        );
+                        let _ = resp.send(());
+                        break;
+                    }
                         }
-                        // 这条请求已被取走：销账。计数器只表达「还在排队的切换」，
-                        // 不销账的话每一轮同步开始时都会看到一个早已处理完的请求，
-                        // 于是永远开不了工。
-                        state.switch_processed.fetch_add(1, Ordering::SeqCst);
-                        let now_ms = chrono::Utc::now().timestamp_millis();
-                        let from_state = state.session_state.as_connection_state();
-                        // 顺序是这条命令的全部要害：**所有可能失败的 IO 都排在拆除之前**。
-                        //
-                        // 反过来写（先拆旧会话、再存 uid / 读 session）会留下「半切换」：
-                        // 旧会话已经销毁、全局 active uid 可能已指向新账号、而
-                        // state.current_uid 还是旧的，且失败路径不发事件——UI 以为旧账号
-                        // 一切正常，实际底下什么都没有了。原子的意思是「要么整件事发生，
-                        // 要么什么都没发生」，不是「按顺序做完这几件事」。
-                        //
-                        // 现在的次序：① 校验目标存在 → ② 读目标 session → ③ 落盘 active
-                        // uid。这三步任一失败都直接返回，旧会话**一根汗毛都没动**。全部
-                        // 成功之后才 ④ 拆旧会话 ⑤ 提交内存状态——这两步不可失败。
-                        // 残留风险只剩「③ 与 ⑤ 之间进程崩溃」，那种情况下重启会按新 uid
-                        // 启动，本来就是用户要的结果。
-                        let result = async {
-                            // ① 目标账号必须存在。
-                            let (_, entries) = state.storage.list_local_accounts().await?;
-                            if !entries.iter().any(|entry| entry.uid == uid) {
-                                return Err(Error::InvalidState(format!(
-                                    "local account not found: {uid}"
-                                )));
-                            }
-                            // ② 先把目标 session 读出来（只读，失败不影响任何现存状态）。
-                            let snapshot = state.storage.load_session(uid.clone()).await?;
-                            // ③ 落盘 active uid。到这里为止全部可回退。
-                            state.storage.save_current_uid(uid.clone()).await?;
-                            Ok(snapshot)
+                        for event in state.take_pending_events() {
+                            emit_sequenced_event(
+                                &actor_event_tx,
+                                &actor_event_history,
+                                &actor_event_seq,
+                                event_history_limit,
+                                event,
+                            );
                         }
-                        .await
-                        .map(|snapshot| {
-                            // ④ 停旧会话：撤销重连意图 → 停 inbound → 断 transport。
-                            //    reset_session_scoped_state 已把 should_auto_reconnect 置 false，
-                            //    所以这里断开不会被自动重连拉回来。
-                            state.reset_session_scoped_state(now_ms);
-                            (snapshot,)
-                        });
-                        let result = match result {
-                            Err(e) => Err(e),
-                            Ok((snapshot,)) => {
-                                stop_inbound_task(&mut inbound_task).await;
-                                if let Err(e) = state.disconnect().await {
-                                    // 断开失败不阻断切换：目标是不再使用这条 transport，
-                                    // 而它已经被丢弃（state.transport = None）。
-                                    eprintln!(
-                                        "[SDK.actor] switch_local_account: disconnect old session failed: {e}"
-                                    );
-                                }
-                                state.transport = None;
+                    }
+                }
+            }
+            stop_inbound_task(&mut inbound_task).await;
+            if actor_logs_enabled() {
+                eprintln!("[SDK.actor] loop: receiver closed");
+            }
+        });
+        task_registry.track(actor_task);
+
+        Self {
+            attachment_transfers: attachment_transfers_sdk,
+            metrics: metrics_sdk,
+            tx,
+            event_tx,
+            event_seq,
+            event_history,
+            event_history_limit,
+            _runtime_provider: runtime_provider,
+            task_registry,
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            supervised_sync_running: Arc::new(AtomicBool::new(false)),
+            first_screen_sweep_running: Arc::new(AtomicBool::new(false)),
+            switch_requested: switch_requested_sdk,
+            switch_wakeup: switch_wakeup_sdk,
+            foreground_wakeup: foreground_wakeup_sdk,
+            startup_error,
+            snowflake,
+            presence_cache,
+            typing_throttle: Arc::new(StdMutex::new(HashMap::new())),
+            data_dir: Arc::new(data_dir_for_self),
+            file_route_key: Arc::new(file_route_key),
+            download_manager,
+            bandwidth: bandwidth_sdk,
+            segmented_uploads: segmented_uploads_sdk,
+            uploads: uploads_sdk,
+            deferred_first_screen_hydration: Arc::new(StdMutex::new(None)),
+            media_stream: Arc::new(StdMutex::new(None)),
+            pending_media_jobs,
+        }
+    }
+
+    fn actor_channel_error(&self) -> Error {
+        if let Ok(locked) = self.startup_error.lock() {
+            if let Some(err) = locked.clone() {
+                return err;
+            }
+        }
+        if self.shutting_down.load(Ordering::Acquire) {
+            Error::Shutdown
+        } else {
+            Error::ActorClosed
+        }
+    }
+
+    fn ensure_running(&self) -> Result<()> {
+        if self.shutting_down.load(Ordering::Acquire) {
+            return Err(Error::Shutdown);
+        }
+        Ok(())
+    }
+
+    pub async fn connect(&self) -> Result<()> {
+        self.ensure_running()?;
+        if actor_logs_enabled() {
+            eprintln!("[SDK.api] connect: send");
+        }
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::Connect { resp: resp_tx })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        let out = resp_rx.await.map_err(|_| self.actor_channel_error())?;
+        if actor_logs_enabled() {
+            eprintln!("[SDK.api] connect: recv");
+        }
+        out
+    }
+
+    pub async fn disconnect(&self) -> Result<()> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::Disconnect { resp: resp_tx })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        let out = resp_rx.await.map_err(|_| self.actor_channel_error())?;
+        if out.is_ok() {
+            self.clear_presence_cache();
+        }
+        out
+    }
+
+    pub async fn is_connected(&self) -> Result<bool> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::IsConnected { resp: resp_tx })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    /// 读取会话快照（精确阶段 + 账号 + 会话世代）。宿主对账连接状态时用它，
+    /// 不要用 [`connection_state`]——那个值不带身份，无法防串号。
+    pub async fn session_status(&self) -> Result<SessionStatus> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::GetSessionStatus { resp: resp_tx })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    pub async fn connection_state(&self) -> Result<ConnectionState> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::GetConnectionState { resp: resp_tx })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    /// 读取最近一次 Terminal 认证错误原因（ForcedLogout 快照）。
+    /// `Connect` 成功后清空，可用于宿主冷启动诊断 / debug UI。
+    pub async fn last_terminal_reason(&self) -> Result<Option<TerminalReason>> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::GetLastTerminalReason { resp: resp_tx })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    /// 读取当前 access_token——SDK 内部 refresh 后拿到的就是最新权威值。
+    ///
+    /// 典型用法：宿主需要拿 access_token 调其它业务 HTTP API 时，**每次临用临取**，
+    /// 不要在宿主侧缓存；订阅 `SdkEvent::TokenRefreshed` 仅用于 UI 状态更新，
+    /// 真正要使用 token 时再调一次本方法，保证拿到的是最新值。
+    ///
+    /// 未登录时返回 `Ok(None)`。
+    pub async fn get_current_access_token(&self) -> Result<Option<String>> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::GetCurrentAccessToken { resp: resp_tx })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<SdkEvent> {
+        self.event_tx.subscribe()
+    }
+
+    /// Publish an event through the sequenced event bus.
+    /// Used by auxiliary tasks (media download, etc.) that live outside the actor loop
+    /// but need to emit on the same broadcast+history channel that consumers subscribe to.
+    pub fn emit_event(&self, event: SdkEvent) {
+        emit_sequenced_event(
+            &self.event_tx,
+            &self.event_history,
+            &self.event_seq,
+            self.event_history_limit,
+            event,
+        );
+    }
+
+    /// Configured data directory (may be empty if the caller relied on the default).
+    pub fn data_dir(&self) -> &str {
+        self.data_dir.as_str()
+    }
+
+    /// Start a Telegram-style streaming download for `message_id`.
+    ///
+    /// Resolves the canonical target directory via [`media_store`] and dispatches
+    /// to the embedded [`DownloadManager`](media_download::DownloadManager).
+    /// Emits [`SdkEvent::MediaDownloadStateChanged`] at ~5Hz plus on transitions.
+    pub async fn start_message_media_download(
+        &self,
+        message_id: u64,
+        download_url: String,
+        mime: String,
+        filename_hint: Option<String>,
+        created_at_ms: i64,
+    ) -> Result<()> {
+        let status = self.session_status().await?;
+        let owner_uid = status.account_uid.ok_or_else(|| {
+            Error::InvalidState("session is empty; login/authenticate required".to_string())
+        })?;
+        let uid = owner_uid
+            .parse::<u64>()
+            .map_err(|_| Error::InvalidState("active account uid is invalid".to_string()))?;
+        let key =
+            media_download::MediaTaskKey::payload(owner_uid, status.session_epoch, message_id);
+        let root = std::path::Path::new(self.data_dir.as_str());
+        let target_dir =
+            media_store::ensure_attachment_dir(root, uid, message_id as i64, created_at_ms)
+                .map_err(|e| Error::Storage(format!("ensure attachment dir failed: {e}")))?;
+        let payload_filename =
+            media_store::payload_filename_with_fallback(&mime, filename_hint.as_deref());
+        self.download_manager
+            .start(
+                self.clone(),
+                key,
+                download_url,
+                target_dir,
+                payload_filename,
+            )
+            .await
+            .map_err(Error::InvalidState)
+    }
+
+    /// Start a streaming download for an attachment-encrypted (v1) message.
+    ///
+    /// Resolves the canonical download ticket via [`file/get_url`](Self::resolve_file_download)
+    /// (signed URL + `encryption_version` + `cek`) and dispatches it. On completion
+    /// the blob is AES-GCM decrypted before becoming the on-disk file. Legacy
+    /// plaintext messages (no `file_id`) should keep using
+    /// [`start_message_media_download`](Self::start_message_media_download).
+    pub async fn start_message_media_download_by_file_id(
+        &self,
+        message_id: u64,
+        file_id: u64,
+        mime: String,
+        filename_hint: Option<String>,
+        created_at_ms: i64,
+    ) -> Result<()> {
+        let status = self.session_status().await?;
+        let owner_uid = status.account_uid.ok_or_else(|| {
+            Error::InvalidState("session is empty; login/authenticate required".to_string())
+        })?;
+        let uid = owner_uid
+            .parse::<u64>()
+            .map_err(|_| Error::InvalidState("active account uid is invalid".to_string()))?;
+        let key =
+            media_download::MediaTaskKey::payload(owner_uid, status.session_epoch, message_id);
+        let ticket = self.resolve_file_download(file_id).await?;
+        let root = std::path::Path::new(self.data_dir.as_str());
+        let target_dir =
+            media_store::ensure_attachment_dir(root, uid, message_id as i64, created_at_ms)
+                .map_err(|e| Error::Storage(format!("ensure attachment dir failed: {e}")))?;
+        let payload_filename =
+            media_store::payload_filename_with_fallback(&mime, filename_hint.as_deref());
+        self.download_manager
+            .start_with_ticket(self.clone(), key, ticket, target_dir, payload_filename)
+            .await
+            .map_err(Error::InvalidState)
+    }
+
+    /// 边下边播：返回一个本地回环 URL（`http://127.0.0.1:{port}/media/{message_id}?token=...`），
+    /// 直接交给播放器。
+    ///
+    /// 代理按播放器的 Range 去签名 URL 拉对应的密文块、当场解密（v2 分段格式随机
+    /// 访问；v1 只能整份拉完再出），拉到的块留在本地，全部到齐后成品落在
+    /// [`start_message_media_download_by_file_id`](Self::start_message_media_download_by_file_id)
+    /// 同一个路径，并照常发 [`SdkEvent::MediaDownloadStateChanged`] `Done`。
+    ///
+    /// 代理第一次调用时才起；签名 URL 过期后再调一次即可刷新，已缓存的块不作废。
+    pub async fn media_stream_url(
+        &self,
+        message_id: u64,
+        file_id: u64,
+        mime: String,
+        filename_hint: Option<String>,
+        created_at_ms: i64,
+    ) -> Result<String> {
+        let status = self.session_status().await?;
+        let owner_uid = status.account_uid.ok_or_else(|| {
+            Error::InvalidState("session is empty; login/authenticate required".to_string())
+        })?;
+        let uid = owner_uid
+            .parse::<u64>()
+            .map_err(|_| Error::InvalidState("active account uid is invalid".to_string()))?;
+        let key =
+            media_download::MediaTaskKey::payload(owner_uid, status.session_epoch, message_id);
+        let ticket = self.resolve_file_download(file_id).await?;
+        let root = std::path::Path::new(self.data_dir.as_str());
+        let target_dir =
+            media_store::ensure_attachment_dir(root, uid, message_id as i64, created_at_ms)
+                .map_err(|e| Error::Storage(format!("ensure attachment dir failed: {e}")))?;
+        let payload_filename =
+            media_store::payload_filename_with_fallback(&mime, filename_hint.as_deref());
+        let server = self.ensure_media_stream_server()?;
+        Ok(server
+            .register(media_stream::StreamSource {
+                key,
+                ticket,
+                final_path: target_dir.join(payload_filename),
+                mime,
+            })
+            .await)
+    }
+
+    /// 停掉本地流媒体代理。已发出去的 URL 随之失效；下次 `media_stream_url` 会换端口
+    /// 和 token 重新起。已缓存但未到齐的块丢弃（`.stream` 留在磁盘上，下次从头拉）。
+    pub fn stop_media_stream_server(&self) {
+        if let Ok(mut slot) = self.media_stream.lock() {
+            slot.take();
+        }
+    }
+
+    fn ensure_media_stream_server(&self) -> Result<Arc<media_stream::MediaStreamServer>> {
+        let mut slot = self
+            .media_stream
+            .lock()
+            .map_err(|_| Error::InvalidState("media stream server poisoned".to_string()))?;
+        if let Some(server) = slot.as_ref() {
+            return Ok(server.clone());
+        }
+        // 回调持有 sdk 的克隆，与 `self.media_stream` 成环；代理随
+        // `stop_media_stream_server` / `shutdown` 一起 drop，环在那里断开。
+        let sdk = self.clone();
+        let on_complete: media_stream::CompletionHook = Arc::new(move |key, path| {
+            let sdk = sdk.clone();
+            sdk.runtime_provider().clone().spawn(async move {
+                if let Err(e) = sdk.update_media_downloaded_scoped(&key, true).await {
+                    eprintln!(
+                        "[SDK.media_stream] update_media_downloaded failed message_id={}: {e}",
+                        key.message_id
+                    );
+                }
+                sdk.emit_event(SdkEvent::MediaDownloadStateChanged {
+                    message_id: key.message_id,
+                    state: MediaDownloadState::Done {
+                        path: path.to_string_lossy().to_string(),
+                    },
+                });
+            });
+        });
+        let server = media_stream::MediaStreamServer::start(
+            self.runtime_provider().handle(),
+            self.bandwidth.clone(),
+            self.metrics.clone(),
+            on_complete,
+        )
+        .map(Arc::new)
+        .map_err(|e| Error::Transport(format!("start media stream server failed: {e}")))?;
+        *slot = Some(server.clone());
+        Ok(server)
+    }
+
+    pub async fn pause_message_media_download(&self, message_id: u64) {
+        if let Ok(status) = self.session_status().await {
+            if let Some(uid) = status.account_uid {
+                let key =
+                    media_download::MediaTaskKey::payload(uid, status.session_epoch, message_id);
+                self.download_manager.pause(self, &key).await;
+            }
+        }
+    }
+
+    pub async fn resume_message_media_download(&self, message_id: u64) {
+        if let Ok(status) = self.session_status().await {
+            if let Some(uid) = status.account_uid {
+                let key =
+                    media_download::MediaTaskKey::payload(uid, status.session_epoch, message_id);
+                self.download_manager.resume(self, &key).await;
+            }
+        }
+    }
+
+    pub async fn cancel_message_media_download(&self, message_id: u64) {
+        if let Ok(status) = self.session_status().await {
+            if let Some(uid) = status.account_uid {
+                let key =
+                    media_download::MediaTaskKey::payload(uid, status.session_epoch, message_id);
+                self.download_manager.cancel(self, &key).await;
+            }
+        }
+    }
+
+    pub async fn get_media_download_state(&self, message_id: u64) -> MediaDownloadState {
+        let Ok(status) = self.session_status().await else {
+            return MediaDownloadState::Idle;
+        };
+        let Some(uid) = status.account_uid else {
+            return MediaDownloadState::Idle;
+        };
+        let key = media_download::MediaTaskKey::payload(uid, status.session_epoch, message_id);
+        self.download_manager.get_state(&key).await
+    }
+
+    /// App 要被挂起了：停掉进程内的正文下载和分片上传，导出剩下的字节，交给平台的
+    /// 后台传输服务（见 [`background_transfer`]）。
+    ///
+    /// 下载会先收到一次 `Paused`；上传在路上的分片落地后退出，消息留在 outbox 里，等
+    /// 全部分片导入或 [`reclaim_background_transfers`](Self::reclaim_background_transfers)
+    /// 之后再接着发。重复调用给出同一批还没导入的描述。
+    pub async fn export_background_transfers(
+        &self,
+    ) -> Vec<background_transfer::BackgroundTransfer> {
+        let mut transfers = Vec::new();
+        for download in self.download_manager.hand_off().await {
+            let descriptor = download.descriptor();
+            self.emit_event(SdkEvent::MediaDownloadStateChanged {
+                message_id: descriptor.message_id,
+                state: MediaDownloadState::Paused {
+                    bytes: descriptor.range_start,
+                    total: descriptor.range_end,
+                },
+            });
+            transfers.push(descriptor);
+        }
+        transfers.extend(self.uploads.hand_off());
+        transfers
+    }
+
+    /// 平台报告 `transfer_id` 那条传输跑完了。
+    ///
+    /// 下载：校验 `.part` 的大小 / SHA-256，解密落盘，置下载标记，发 `Done`——与进程内
+    /// 下载完成同一条路。校验不过发 `Failed`（[`error_codes::MEDIA_INTEGRITY_MISMATCH`]），
+    /// `.part` 已删，重新发起下载会从头拉。进程重启过、不认得这个 id 的返回
+    /// [`Error::NotFound`]；拉满的 `.part` 还在，重新发起下载会直接收尾。
+    ///
+    /// 上传分片：记下这一片；最后一片导入后 outbox 重新发起，上传循环去 `/status`
+    /// 对齐再 `complete`。分片本身服务端已按摘要校验过。进程重启过的同样返回 `Ok`：
+    /// outbox 重试会沿用落盘的会话，`/status` 会说明哪些已经到了。
+    pub async fn import_completed_transfer(&self, transfer_id: String) -> Result<()> {
+        if background_transfer::is_upload_transfer_id(&transfer_id) {
+            if self.uploads.import(&transfer_id) {
+                // 还有没导入的分片时 drain 照样跳过它，多踢一次无妨。
+                let _ = self.tx.try_send(Command::KickOutboundDrain);
+            }
+            return Ok(());
+        }
+        let download = self
+            .download_manager
+            .take_handed_off(&transfer_id)
+            .ok_or_else(|| Error::NotFound(format!("background transfer {transfer_id}")))?;
+        let message_id = download.key.message_id;
+        // 整份校验 + 解密是同步读写，放到 blocking 线程池上做，不占 runtime 的 worker。
+        let key = download.key.clone();
+        let completed = tokio::task::spawn_blocking(move || download.complete())
+            .await
+            .map_err(|e| Error::Storage(format!("finish background transfer: {e}")))?;
+        match completed {
+            Ok(path) => {
+                if let Err(e) = self.update_media_downloaded_scoped(&key, true).await
+                {
+                    eprintln!(
+                        "[SDK.media] update_media_downloaded failed message_id={message_id}: {e}"
+                    );
+                }
+                self.emit_event(SdkEvent::MediaDownloadStateChanged {
+                    message_id,
+                    state: MediaDownloadState::Done {
+                        path: path.to_string_lossy().to_string(),
+                    },
+                });
+                Ok(())
+            }
+            Err(media_download::FinalizeError::Integrity(e)) => {
+                self.emit_event(SdkEvent::MediaDownloadStateChanged {
+                    message_id,
+                    state: MediaDownloadState::Failed {
+                        code: error_codes::MEDIA_INTEGRITY_MISMATCH,
+                        message: e.clone(),
+                    },
+                });
+                Err(Error::Transport(e))
+            }
+            Err(media_download::FinalizeError::Io(e)) => {
+                self.emit_event(SdkEvent::MediaDownloadStateChanged {
+                    message_id,
+                    state: MediaDownloadState::Failed {
+                        code: ErrorCode::InternalError as u32,
+                        message: e.clone(),
+                    },
+                });
+                Err(Error::Storage(e))
+            }
+        }
+    }
+
+    /// 回到前台：还没导入的传输收回进程内，从各自的断点续上。返回收回了几条。
+    /// 平台那边还在跑的请求由宿主自己取消。
+    pub async fn reclaim_background_transfers(&self) -> usize {
+        let uploads = self.uploads.reclaim_all();
+        if uploads > 0 {
+            // 交接时退出的上传循环由 drain 重新发起。
+            let _ = self.tx.try_send(Command::KickOutboundDrain);
+        }
+        self.download_manager.reclaim_handed_off(self).await + uploads
+    }
+
+    pub(crate) fn runtime_provider(&self) -> &RuntimeProvider {
+        &self._runtime_provider
+    }
+
+    /// SDK 自己那个 Tokio 运行时的句柄。
+    ///
+    /// 🔴 FFI 的 async 函数是被**宿主**（Kotlin 协程 / Swift）轮询的，那个上下文里
+    /// 没有 Tokio reactor：直接在里面 `reqwest ... .await` 会在运行期报
+    /// 「there is no reactor running」。要做 I/O 的地方把 future 交给这个句柄跑。
+    pub fn runtime_handle(&self) -> tokio::runtime::Handle {
+        self._runtime_provider.handle().clone()
+    }
+
+    pub fn last_event_sequence_id(&self) -> u64 {
+        self.event_seq.load(Ordering::Acquire)
+    }
+
+    pub fn event_history_limit(&self) -> usize {
+        self.event_history_limit
+    }
+
+    pub fn recent_events(&self, limit: usize) -> Vec<SequencedSdkEvent> {
+        let capped = limit.min(self.event_history_limit);
+        if capped == 0 {
+            return vec![];
+        }
+        let locked = self.event_history.lock().expect("event history poisoned");
+        locked
+            .iter()
+            .rev()
+            .take(capped)
+            .cloned()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect()
+    }
+
+    pub fn events_since(&self, from_sequence_id: u64, limit: usize) -> Vec<SequencedSdkEvent> {
+        let capped = limit.min(self.event_history_limit);
+        if capped == 0 {
+            return vec![];
+        }
+        let locked = self.event_history.lock().expect("event history poisoned");
+        locked
+            .iter()
+            .filter(|evt| evt.sequence_id > from_sequence_id)
+            .take(capped)
+            .cloned()
+            .collect()
+    }
+
+    pub fn recent_timeline_events(&self, limit: usize) -> Vec<SequencedSdkEvent> {
+        let capped = limit.min(self.event_history_limit);
+        if capped == 0 {
+            return vec![];
+        }
+        let locked = self.event_history.lock().expect("event history poisoned");
+        locked
+            .iter()
+            .rev()
+            .filter(|evt| Self::is_timeline_like_event(&evt.event))
+            .take(capped)
+            .cloned()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect()
+    }
+
+    pub fn recent_network_events(&self, limit: usize) -> Vec<SequencedSdkEvent> {
+        let capped = limit.min(self.event_history_limit);
+        if capped == 0 {
+            return vec![];
+        }
+        let locked = self.event_history.lock().expect("event history poisoned");
+        locked
+            .iter()
+            .rev()
+            .filter(|evt| Self::is_network_like_event(&evt.event))
+            .take(capped)
+            .cloned()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect()
+    }
+
+    pub fn timeline_events_since(
+        &self,
+        from_sequence_id: u64,
+        limit: usize,
+    ) -> Vec<SequencedSdkEvent> {
+        let capped = limit.min(self.event_history_limit);
+        if capped == 0 {
+            return vec![];
+        }
+        let locked = self.event_history.lock().expect("event history poisoned");
+        locked
+            .iter()
+            .filter(|evt| {
+                evt.sequence_id > from_sequence_id && Self::is_timeline_like_event(&evt.event)
+            })
+            .take(capped)
+            .cloned()
+            .collect()
+    }
+
+    pub fn network_events_since(
+        &self,
+        from_sequence_id: u64,
+        limit: usize,
+    ) -> Vec<SequencedSdkEvent> {
+        let capped = limit.min(self.event_history_limit);
+        if capped == 0 {
+            return vec![];
+        }
+        let locked = self.event_history.lock().expect("event history poisoned");
+        locked
+            .iter()
+            .filter(|evt| {
+                evt.sequence_id > from_sequence_id && Self::is_network_like_event(&evt.event)
+            })
+            .take(capped)
+            .cloned()
+            .collect()
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::Ping { resp: resp_tx })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    pub async fn set_network_hint(&self, hint: NetworkHint) -> Result<()> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::SetNetworkHint {
+                hint,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())??;
+        self.resume_deferred_first_screen_hydration();
+        Ok(())
+    }
+
+    /// 当前的限速 / 省流量配置。
+    pub fn bandwidth_policy(&self) -> bandwidth::BandwidthPolicy {
+        self.bandwidth.policy()
+    }
+
+    /// 设置限速 / 省流量（见 [`bandwidth`]）。立即生效，正在传的也按新档位走；
+    /// 只在内存里，宿主每次启动推一次。
+    pub fn set_bandwidth_policy(&self, policy: bandwidth::BandwidthPolicy) {
+        self.bandwidth.set_policy(policy);
+        self.resume_deferred_first_screen_hydration();
+    }
+
+    /// 大附件是否封成 v2（分段加密）。
+    pub fn segmented_attachment_uploads(&self) -> bool {
+        self.segmented_uploads.load(Ordering::Relaxed)
+    }
+
+    /// 打开后，超过分片阈值的附件封成 v2（分段加密）：发送端边读边加密，接收端
+    /// 边下边解、可以随机读。默认关——老版本的接收端和服务端只认 v1，收到 v2
+    /// 打不开。宿主确认服务端和对端都已升级后再打开。
+    ///
+    /// 只影响之后的封装；关掉之后，已封好的 v2 缓存会在下次发送时重封成 v1。
+    /// 只在内存里，宿主每次启动推一次。
+    pub fn set_segmented_attachment_uploads(&self, enabled: bool) {
+        self.segmented_uploads.store(enabled, Ordering::Relaxed);
+    }
+
+    /// 被省流量推迟的首屏扫补，省流量不再生效时补起。
+    fn resume_deferred_first_screen_hydration(&self) {
+        if self.bandwidth.data_saver_active() {
+            return;
+        }
+        let deferred = self
+            .deferred_first_screen_hydration
+            .lock()
+            .ok()
+            .and_then(|mut deferred| deferred.take());
+        if let Some((limit, max_channels)) = deferred {
+            self.start_first_screen_hydration(limit, max_channels);
+        }
+    }
+
+    pub async fn set_video_process_hook(&self, hook: Option<VideoProcessHook>) -> Result<()> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::SetVideoProcessHook {
+                hook,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    /// Plan 2 媒体作业回传。宿主（Kotlin/iOS）收到 `SdkEvent::MediaJobRequested`
+    /// 处理完成后调用此接口。直接操作共享 `pending_media_jobs` 表、不经 actor
+    /// 命令通道——此时 actor 正阻塞在同一 oneshot rx 上。
+    ///
+    /// 已过期（超时被 actor 丢弃）或 `job_id` 未知时返回 `Err`。
+    pub fn submit_media_job_result(&self, job_id: String, result: MediaJobResult) -> Result<()> {
+        let sender = {
+            let mut locked = self
+                .pending_media_jobs
+                .lock()
+                .expect("pending_media_jobs poisoned");
+            locked.remove(&job_id)
+        };
+        match sender {
+            Some(tx) => tx
+                .send(result)
+                .map_err(|_| Error::InvalidState(format!("media job {job_id} receiver dropped"))),
+            None => Err(Error::InvalidState(format!(
+                "media job {job_id} not pending (expired or unknown)"
+            ))),
+        }
+    }
+
+    /// 注册 / 清除网址预览回调。未注册时发送 Link 消息仅带 URL，客户端显示空白缩略图。
+    pub async fn set_link_preview_hook(&self, hook: Option<LinkPreviewHook>) -> Result<()> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::SetLinkPreviewHook {
+                hook,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    pub async fn login(
+        &self,
+        username: String,
+        password: String,
+        device_id: String,
+    ) -> Result<LoginResult> {
+        self.ensure_running()?;
+        if actor_logs_enabled() {
+            eprintln!("[SDK.api] login: send");
+        }
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::Login {
+                username,
+                password,
+                device_id,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        let out = resp_rx.await.map_err(|_| self.actor_channel_error())?;
+        if actor_logs_enabled() {
+            eprintln!("[SDK.api] login: recv");
+        }
+        out
+    }
+
+    pub async fn register(
+        &self,
+        username: String,
+        password: String,
+        device_id: String,
+    ) -> Result<LoginResult> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::Register {
+                username,
+                password,
+                device_id,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    pub async fn authenticate(&self, user_id: u64, token: String, device_id: String) -> Result<()> {
+        self.ensure_running()?;
+        if actor_logs_enabled() {
+            eprintln!("[SDK.api] authenticate: send");
+        }
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::Authenticate {
+                user_id,
+                token,
+                device_id,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        let out = resp_rx.await.map_err(|_| self.actor_channel_error())?;
+        if actor_logs_enabled() {
+            eprintln!("[SDK.api] authenticate: recv");
+        }
+        out
+    }
+
+    pub async fn shutdown(&self) {
+        if self.shutting_down.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        self.supervised_sync_running.store(false, Ordering::Release);
+        self.stop_media_stream_server();
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let _ = self.tx.send(Command::Shutdown { resp: resp_tx }).await;
+        let _ = resp_rx.await;
+        self.task_registry.shutdown().await;
+    }
+
+    pub async fn sync_entities(&self, entity_type: String, scope: Option<String>) -> Result<usize> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::SyncEntities {
+                entity_type,
+                scope,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
 
-                                // ⑤ 提交内存状态。
-                                state.current_uid = Some(uid.clone());
-                                state.session_epoch += 1;
-                                state.bootstrap_completed =
-                                    snapshot.map(|s| s.bootstrap_completed).unwrap_or(false);
-                                state.session_state = SessionState::New;
-                                Ok(())
-                            }
-                        };
-                        // ③ 只在成功后发一次状态事件。失败不发：否则 UI 会收到一个
-                        //    「切过去了」的假象，而实际仍停在旧账号。
-                        if result.is_ok() {
-                            let to_state = state.session_state.as_connection_state();
-                            if from_state != to_state {
-                                emit_sequenced_event(
-                                    &actor_event_tx,
-                                    &actor_event_history,
-                                    &actor_event_seq,
-                                    event_history_limit,
-                                    SdkEvent::ConnectionStateChanged {
-                                        from: from_state,
-                                        to: to_state,
-                                    },
-                                );
-                            }
-                        }
-                        let _ = resp.send(result);
-                    }
-                    Command::SetLocalAccountDisplayName {
-                        uid,
-                        display_name,
-                        username,
-                        login_mode,
-                        login_identifier,
-                        resp,
-                    } => {
-                        let result = state
-                            .storage
-                            .save_account_display_name(
-                                uid,
-                                display_name,
-                                username,
-                                login_mode,
-                                login_identifier,
-                            )
-                            .await;
-                        let _ = resp.send(result);
-                    }
-                    // 「滚到哪儿就取哪儿的缩略图」。
-                    //
-                    // 缩略图的自动下载本来只挂在消息**入站**（push / sync）那条路径上，
-                    // 历史翻页拉回来的消息从来不触发——用户看到的就是「历史图片必须点
-                    // 一下才加载」。UI 在气泡进入可视区时调这里，语义是 ensure：已经有
-                    // 了就什么都不做，没有才去取。
-                    Command::EnsureMessageThumbnail { message_id, resp } => {
-                        let result = async {
-                            let Some(msg) = state.storage.get_message_by_id(message_id).await? else {
-                                return Ok(());
-                            };
-                            // thumb_status: 1=已下载, 3=协议层确无缩略图（终态）。
-                            if msg.thumb_status == 1 {
-                                return Ok(());
-                            }
-                            if msg.thumb_status == 3 {
-                                // 3 是终态，但历史上有一批 3 是**误判**写下的：早先
-                                // history 回填丢了 metadata，extra 是空串，当时的代码
-                                // 把「我没看到缩略图字段」当成了「确实没有缩略图」。
-                                // 写入侧已经修好，这些行却永远停在灰块上。
-                                //
-                                // 判据不另写一份，就用写入侧那条：现在再看一次，服务端
-                                // 是否**明确**说了没有缩略图。它说了，3 就是对的；没说，
-                                // 这条 3 就是当年那次误判，退回 0 重新走一遍。
-                                //
-                                // 放在这里而不是启动扫全表：用户滑到哪修到哪，代价只落
-                                // 在真正被看到的那几条上。
-                                let says_none =
-                                    crate::canonical_inbound::CanonicalInboundMessage::from_sync_entity(
-                                        0,
-                                        0,
-                                        msg.channel_id,
-                                        msg.channel_type,
-                                        0,
-                                        0,
-                                        String::new(),
-                                        msg.extra.clone(),
-                                        0,
-                                        0,
-                                    )
-                                    .server_says_no_thumbnail();
-                                if says_none {
-                                    return Ok(());
-                                }
-                                tracing::info!(
-                                    message_id = msg.message_id,
-                                    "thumb_status=3 是早期误判（服务端未明确表示没有缩略图）：退回待重试"
-                                );
-                                state.storage.update_thumb_status(msg.message_id, 0).await?;
-                            }
+    pub async fn sync_channel(&self, channel_id: u64, channel_type: i32) -> Result<usize> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::SyncChannel {
+                channel_id,
+                channel_type,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
 
-                            // Missing metadata is projection repair work, not a reason to
-                            // block this UI command on `message/history/around`. Queue the
-                            // existing repair path and return; its TimelineUpdated event
-                            // causes the visible bubble to submit again with fresh metadata.
-                            if State::extract_thumbnail_file_id(&msg.extra).is_none()
-                                && State::extract_thumbnail_url(&msg.extra).is_none()
-                            {
-                                if let Some(server_id) = msg.server_message_id.filter(|id| *id != 0)
-                                {
-                                    state.enqueue_projection_repair(
-                                        msg.channel_id,
-                                        msg.channel_type,
-                                        server_id,
-                                    );
-                                }
-                                return Ok(());
-                            }
-                            state.submit_thumbnail_download(
-                                msg.message_id,
-                                msg.channel_id,
-                                msg.channel_type,
-                                msg.created_at,
-                                &msg.extra,
-                                media_download::DownloadPriority::Visible,
-                            );
-                            Ok(())
-                        }
-                        .await;
-                        let _ = resp.send(result);
-                    }
-                    Command::WipeCurrentUserFull { resp } => {
-                        state.download_manager.cancel_all_scoped();
-                        let from_state = state.session_state.as_connection_state();
-                        state.should_auto_reconnect = false;
-                        state.reset_reconnect_backoff();
-                        state.bootstrap_completed = false;
-                        state.clear_presence_cache();
-                        state.session_state = SessionState::Connected;
-                        let result = match state.current_uid.clone() {
-                            Some(uid) => {
-                                let clear_uid = state.storage.clear_current_uid().await;
-                                let wipe = state.storage.wipe_user_full(uid).await;
-                                state.current_uid = None;
-                                state.session_epoch += 1;
-                                wipe.and(clear_uid)
-                            }
-                            None => Ok(()),
-                        };
-                        if result.is_ok() {
-                            emit_sequenced_event(
-                                &actor_event_tx,
-                                &actor_event_history,
-                                &actor_event_seq,
-                                event_history_limit,
-                                SdkEvent::ConnectionStateChanged {
-                                    from: from_state,
-                                    to: state.session_state.as_connection_state(),
-                                },
-                            );
-                        }
-                        let _ = resp.send(result);
-                    }
-                    Command::Shutdown { resp } => {
-                        if actor_logs_enabled() {
-                            eprintln!("[SDK.actor] loop: cmd shutdown");
-                        }
-                        state.should_auto_reconnect = false;
-                        state.reset_reconnect_backoff();
-                        state.download_manager.cancel_all_scoped();
-                        stop_inbound_task(&mut inbound_task).await;
-                        emit_sequenced_event(
-                            &actor_event_tx,
-                            &actor_event_history,
-                            &actor_event_seq,
-                            event_history_limit,
-                            SdkEvent::ShutdownStarted,
-                        );
-                        let from_state = state.session_state.as_connection_state();
-                        if let Ok(next_state) = state.session_state.can(Action::Shutdown) {
-                            state.session_state = next_state;
-                        }
-                        state.clear_presence_cache();
-                        emit_sequenced_event(
-                            &actor_event_tx,
-                            &actor_event_history,
-                            &actor_event_seq,
-                            event_history_limit,
-                            SdkEvent::ConnectionStateChanged {
-                                from: from_state,
-                                to: state.session_state.as_connection_state(),
-                            },
-                        );
-                        state.transport = None;
-                        state.storage.shutdown();
-                        emit_sequenced_event(
-                            &actor_event_tx,
-                            &actor_event_history,
-                            &actor_event_seq,
-                            event_history_limit,
-                            SdkEvent::ShutdownCompleted,
-                        );
-                        let _ = resp.send(());
-                        break;
-                    }
-                        }
-                        for event in state.take_pending_events() {
-                            emit_sequenced_event(
-                                &actor_event_tx,
-                                &actor_event_history,
-                                &actor_event_seq,
-                                event_history_limit,
-                                event,
-                            );
-                        }
-                    }
+    pub async fn sync_all_channels(&self) -> Result<usize> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::SyncAllChannels { resp: resp_tx })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    pub async fn batch_get_presence(&self, user_ids: Vec<u64>) -> Result<Vec<PresenceStatus>> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::BatchGetPresence {
+                user_ids,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    pub async fn get_presence(&self, user_id: u64) -> Result<Option<PresenceStatus>> {
+        let mut out = self.batch_get_presence(vec![user_id]).await?;
+        Ok(out.pop())
+    }
+
+    pub fn batch_get_cached_presence(&self, user_ids: Vec<u64>) -> Vec<PresenceStatus> {
+        let mut out = self
+            .presence_cache
+            .lock()
+            .ok()
+            .map(|locked| {
+                user_ids
+                    .iter()
+                    .filter_map(|user_id| locked.get(user_id).cloned())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        out.sort_by_key(|v| v.user_id);
+        out
+    }
+
+    pub fn get_cached_presence(&self, user_id: u64) -> Option<PresenceStatus> {
+        self.presence_cache
+            .lock()
+            .ok()
+            .and_then(|locked| locked.get(&user_id).cloned())
+    }
+
+    pub fn clear_presence_cache(&self) {
+        if let Ok(mut locked) = self.presence_cache.lock() {
+            locked.clear();
+        }
+    }
+
+    pub async fn send_typing(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+        is_typing: bool,
+        action_type: TypingActionType,
+    ) -> Result<()> {
+        self.ensure_running()?;
+
+        // 1 秒节流：如果同一频道在 1 秒内已经上报过相同状态，则直接跳过
+        let key = (channel_id, is_typing, action_type.clone() as u8);
+        let now = std::time::Instant::now();
+        {
+            let mut locked = self.typing_throttle.lock().unwrap();
+            if let Some(last_sent) = locked.get(&key) {
+                if now.duration_since(*last_sent).as_millis() < 1000 {
+                    return Ok(());
                 }
             }
-            stop_inbound_task(&mut inbound_task).await;
-            if actor_logs_enabled() {
-                eprintln!("[SDK.actor] loop: receiver closed");
-            }
-        });
-        task_registry.track(actor_task);
-
-        Self {
-            attachment_transfers: attachment_transfers_sdk,
-            tx,
-            event_tx,
-            event_seq,
-            event_history,
-            event_history_limit,
-            _runtime_provider: runtime_provider,
-            task_registry,
-            shutting_down: Arc::new(AtomicBool::new(false)),
-            supervised_sync_running: Arc::new(AtomicBool::new(false)),
-            first_screen_sweep_running: Arc::new(AtomicBool::new(false)),
-            switch_requested: switch_requested_sdk,
-            switch_wakeup: switch_wakeup_sdk,
-            foreground_wakeup: foreground_wakeup_sdk,
-            startup_error,
-            snowflake,
-            presence_cache,
-            typing_throttle: Arc::new(StdMutex::new(HashMap::new())),
-            data_dir: Arc::new(data_dir_for_self),
-            file_route_key: Arc::new(file_route_key),
-            download_manager,
-            pending_media_jobs,
+            locked.insert(key, now);
         }
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::SendTyping {
+                channel_id,
+                channel_type,
+                is_typing,
+                action_type,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    fn actor_channel_error(&self) -> Error {
-        if let Ok(locked) = self.startup_error.lock() {
-            if let Some(err) = locked.clone() {
-                return err;
+    /// 订阅频道事件（进入聊天页面时调用，接收 typing / presence 等状态事件）
+    /// channel_type: 0=Private, 1=Group, 2=Room
+    /// token: 可选，Room 类型订阅时传入业务 API 签发的 ticket（JWT）
+    pub async fn subscribe_channel(
+        &self,
+        channel_id: u64,
+        channel_type: u8,
+        token: Option<String>,
+    ) -> Result<()> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::Subscribe {
+                channel_id,
+                channel_type,
+                token,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    /// 取消订阅频道事件（离开聊天页面时调用）
+    /// channel_type: 0=Private, 1=Group, 2=Room
+    pub async fn unsubscribe_channel(&self, channel_id: u64, channel_type: u8) -> Result<()> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::Unsubscribe {
+                channel_id,
+                channel_type,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    /// Channel Transfer client→app RPC. Sends a wire `TransferRequest` (biz_type=19),
+    /// awaits the matching `TransferResponse` (biz_type=20), and returns it decoded
+    /// as `TransferReply`. `timeout_ms` is the per-call wire timeout; default 5000ms
+    /// when zero. See `02-server/CHANNEL_TRANSFER_SPEC.md` v2.0 and
+    /// `07-application/BOT_INTERACTION_SPEC.md` for routes (e.g. `bot/menu/get`).
+    pub async fn transfer(
+        &self,
+        channel_id: u64,
+        route: String,
+        body: Vec<u8>,
+        timeout_ms: u64,
+    ) -> Result<TransferReply> {
+        self.ensure_running()?;
+        let timeout_ms = if timeout_ms == 0 { 5000 } else { timeout_ms };
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::Transfer {
+                channel_id,
+                route,
+                body,
+                timeout_ms,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    pub async fn rpc_call(&self, route: String, body_json: String) -> Result<String> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::RpcCall {
+                route,
+                body_json,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    pub async fn rpc_call_typed<Req, Resp>(&self, route: &str, req: &Req) -> Result<Resp>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let body_json = serde_json::to_string(req)
+            .map_err(|e| Error::Serialization(format!("encode {route} body: {e}")))?;
+        let raw = self.rpc_call(route.to_string(), body_json).await?;
+        self.apply_rpc_side_effects(route, &raw).await?;
+        serde_json::from_str(&raw)
+            .map_err(|e| Error::Serialization(format!("decode {route} response: {e}; raw={raw}")))
+    }
+
+    /// 下载前按 file_id 调 `file/get_url` 解析出下载票据（含解密所需 cek/version）。
+    /// 附件加密 v1：消息只带 file_id，下载权威信息（signed_url + cek + version）走此 RPC，
+    /// 不依赖消息里存的 file_url（那只作 legacy fallback）。CEK 不进日志。
+    pub async fn resolve_file_download(&self, file_id: u64) -> Result<ResolvedFileDownload> {
+        let payload = FileGetUrlRequest {
+            file_id,
+            user_id: 0, // 服务端按鉴权上下文填充
+        };
+        let raw: serde_json::Value = self.rpc_call_typed(routes::file::GET_URL, &payload).await?;
+        ResolvedFileDownload::from_get_url(file_id, raw)
+            .map_err(|e| Error::Serialization(format!("decode file/get_url response: {e}")))?
+            .ok_or_else(|| {
+                Error::Serialization("decode file/get_url response: missing file_url".to_string())
+            })
+    }
+
+    /// AVATAR_CACHE_SPEC §8: 头像上传前客户端预处理。
+    ///
+    /// decode（白名单 jpeg/png/webp，gif/损坏格式直接 Err，不消耗上传流量）→
+    /// 中心裁剪正方形 → 边长 >480 缩放到 480x480（≤480 不放大）→ 编码 PNG
+    /// 写临时文件，返回处理后路径。App 选图后先过它再走上传管道。
+    pub async fn prepare_avatar_image(&self, src_path: String) -> Result<String> {
+        // 直接同步调用:uniffi 的 async 桥在自己的 foreign executor 上 poll 本 future,
+        // 没有 Tokio runtime 上下文,spawn_blocking 会 panic「no reactor running」。
+        // 头像预处理是一次性 CPU 工作(≤480 图,数十 ms),App 已在协程里调用,
+        // 短暂阻塞该协程线程可接受。
+        // 输出写 data_dir/tmp（app 沙箱内保证可写）；Android 上 std temp_dir =
+        // /data/local/tmp 无写权限，不能用（见 prepare_avatar_image_sync doc）。
+        let out_dir = std::path::Path::new(self.data_dir()).join("tmp");
+        avatar_cache::prepare_avatar_image_sync(std::path::Path::new(&src_path), &out_dir)
+            .map(|p| p.to_string_lossy().to_string())
+    }
+
+    async fn apply_rpc_side_effects(&self, route: &str, raw: &str) -> Result<()> {
+        if route == routes::friend::PENDING {
+            let parsed = serde_json::from_str::<FriendPendingResponse>(raw).map_err(|e| {
+                Error::Serialization(format!(
+                    "decode {} side-effect response: {e}; raw={raw}",
+                    routes::friend::PENDING
+                ))
+            })?;
+            let now_ms = chrono::Utc::now().timestamp_millis();
+            for item in parsed.requests {
+                self.upsert_user(UpsertUserInput {
+                    user_id: item.user.user_id,
+                    username: Some(item.user.username),
+                    nickname: Some(item.user.nickname),
+                    alias: None,
+                    avatar: item.user.avatar_url.unwrap_or_default(),
+                    user_type: item.user.user_type as i32,
+                    is_deleted: false,
+                    channel_id: String::new(),
+                    version: 0,
+                    updated_at: now_ms,
+                })
+                .await?;
             }
+            return Ok(());
         }
-        if self.shutting_down.load(Ordering::Acquire) {
-            Error::Shutdown
-        } else {
-            Error::ActorClosed
+
+        if route == routes::friend::ACCEPT {
+            // Keep local entities fresh so direct-channel title can resolve from user table.
+            let channel_id = serde_json::from_str::<u64>(raw).map_err(|e| {
+                Error::Serialization(format!(
+                    "decode {} side-effect response: {e}; raw={raw}",
+                    routes::friend::ACCEPT
+                ))
+            })?;
+            if channel_id > 0 {
+                let _ = self.sync_channel(channel_id, 1).await;
+            }
+            let _ = self.sync_entities("friend".to_string(), None).await;
+            let _ = self.sync_entities("user".to_string(), None).await;
         }
-    }
 
-    fn ensure_running(&self) -> Result<()> {
-        if self.shutting_down.load(Ordering::Acquire) {
-            return Err(Error::Shutdown);
-        }
         Ok(())
     }
 
-    pub async fn connect(&self) -> Result<()> {
-        self.ensure_running()?;
-        if actor_logs_enabled() {
-            eprintln!("[SDK.api] connect: send");
-        }
-        let (resp_tx, resp_rx) = oneshot::channel();
-        self.tx
-            .send(Command::Connect { resp: resp_tx })
-            .await
-            .map_err(|_| self.actor_channel_error())?;
-        let out = resp_rx.await.map_err(|_| self.actor_channel_error())?;
-        if actor_logs_enabled() {
-            eprintln!("[SDK.api] connect: recv");
-        }
-        out
-    }
-
-    pub async fn disconnect(&self) -> Result<()> {
+    pub async fn is_bootstrap_completed(&self) -> Result<bool> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::Disconnect { resp: resp_tx })
+            .send(Command::IsBootstrapCompleted { resp: resp_tx })
             .await
             .map_err(|_| self.actor_channel_error())?;
-        let out = resp_rx.await.map_err(|_| self.actor_channel_error())?;
-        if out.is_ok() {
-            self.clear_presence_cache();
-        }
-        out
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn is_connected(&self) -> Result<bool> {
+    pub async fn run_bootstrap_sync(&self) -> Result<()> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::IsConnected { resp: resp_tx })
+            .send(Command::RunBootstrapSync { resp: resp_tx })
             .await
             .map_err(|_| self.actor_channel_error())?;
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// 读取会话快照（精确阶段 + 账号 + 会话世代）。宿主对账连接状态时用它，
-    /// 不要用 [`connection_state`]——那个值不带身份，无法防串号。
-    pub async fn session_status(&self) -> Result<SessionStatus> {
+    pub async fn ensure_synced(&self) -> Result<()> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::GetSessionStatus { resp: resp_tx })
+            .send(Command::EnsureSynced { resp: resp_tx })
             .await
             .map_err(|_| self.actor_channel_error())?;
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn connection_state(&self) -> Result<ConnectionState> {
+    pub async fn sync_state(&self) -> Result<SyncStateSnapshot> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::GetConnectionState { resp: resp_tx })
+            .send(Command::GetSyncState { resp: resp_tx })
             .await
             .map_err(|_| self.actor_channel_error())?;
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// 读取最近一次 Terminal 认证错误原因（ForcedLogout 快照）。
-    /// `Connect` 成功后清空，可用于宿主冷启动诊断 / debug UI。
-    pub async fn last_terminal_reason(&self) -> Result<Option<TerminalReason>> {
+    pub async fn session_snapshot(&self) -> Result<Option<SessionSnapshot>> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::GetLastTerminalReason { resp: resp_tx })
+            .send(Command::GetSessionSnapshot { resp: resp_tx })
             .await
             .map_err(|_| self.actor_channel_error())?;
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// 读取当前 access_token——SDK 内部 refresh 后拿到的就是最新权威值。
-    ///
-    /// 典型用法：宿主需要拿 access_token 调其它业务 HTTP API 时，**每次临用临取**，
-    /// 不要在宿主侧缓存；订阅 `SdkEvent::TokenRefreshed` 仅用于 UI 状态更新，
-    /// 真正要使用 token 时再调一次本方法，保证拿到的是最新值。
-    ///
-    /// 未登录时返回 `Ok(None)`。
-    pub async fn get_current_access_token(&self) -> Result<Option<String>> {
+    pub async fn clear_local_state(&self) -> Result<()> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::GetCurrentAccessToken { resp: resp_tx })
+            .send(Command::ClearLocalState { resp: resp_tx })
             .await
             .map_err(|_| self.actor_channel_error())?;
-        resp_rx.await.map_err(|_| self.actor_channel_error())?
-    }
-
-    pub fn subscribe_events(&self) -> broadcast::Receiver<SdkEvent> {
-        self.event_tx.subscribe()
-    }
-
-    /// Publish an event through the sequenced event bus.
-    /// Used by auxiliary tasks (media download, etc.) that live outside the actor loop
-    /// but need to emit on the same broadcast+history channel that consumers subscribe to.
-    pub fn emit_event(&self, event: SdkEvent) {
-        emit_sequenced_event(
-            &self.event_tx,
-            &self.event_history,
-            &self.event_seq,
-            self.event_history_limit,
-            event,
-        );
-    }
-
-    /// Configured data directory (may be empty if the caller relied on the default).
-    pub fn data_dir(&self) -> &str {
-        self.data_dir.as_str()
-    }
-
-    /// Start a Telegram-style streaming download for `message_id`.
-    ///
-    /// Resolves the canonical target directory via [`media_store`] and dispatches
-    /// to the embedded [`DownloadManager`](media_download::DownloadManager).
-    /// Emits [`SdkEvent::MediaDownloadStateChanged`] at ~5Hz plus on transitions.
-    pub async fn start_message_media_download(
-        &self,
-        message_id: u64,
-        download_url: String,
-        mime: String,
-        filename_hint: Option<String>,
-        created_at_ms: i64,
-    ) -> Result<()> {
-        let status = self.session_status().await?;
-        let owner_uid = status.account_uid.ok_or_else(|| {
-            Error::InvalidState("session is empty; login/authenticate required".to_string())
-        })?;
-        let uid = owner_uid
-            .parse::<u64>()
-            .map_err(|_| Error::InvalidState("active account uid is invalid".to_string()))?;
-        let key =
-            media_download::MediaTaskKey::payload(owner_uid, status.session_epoch, message_id);
-        let root = std::path::Path::new(self.data_dir.as_str());
-        let target_dir =
-            media_store::ensure_attachment_dir(root, uid, message_id as i64, created_at_ms)
-                .map_err(|e| Error::Storage(format!("ensure attachment dir failed: {e}")))?;
-        let payload_filename =
-            media_store::payload_filename_with_fallback(&mime, filename_hint.as_deref());
-        self.download_manager
-            .start(
-                self.clone(),
-                key,
-                download_url,
-                target_dir,
-                payload_filename,
-            )
-            .await
-            .map_err(Error::InvalidState)
-    }
-
-    /// Start a streaming download for an attachment-encrypted (v1) message.
-    ///
-    /// Resolves the canonical download ticket via [`file/get_url`](Self::resolve_file_download)
-    /// (signed URL + `encryption_version` + `cek`) and dispatches it. On completion
-    /// the blob is AES-GCM decrypted before becoming the on-disk file. Legacy
-    /// plaintext messages (no `file_id`) should keep using
-    /// [`start_message_media_download`](Self::start_message_media_download).
-    pub async fn start_message_media_download_by_file_id(
-        &self,
-        message_id: u64,
-        file_id: u64,
-        mime: String,
-        filename_hint: Option<String>,
-        created_at_ms: i64,
-    ) -> Result<()> {
-        let status = self.session_status().await?;
-        let owner_uid = status.account_uid.ok_or_else(|| {
-            Error::InvalidState("session is empty; login/authenticate required".to_string())
-        })?;
-        let uid = owner_uid
-            .parse::<u64>()
-            .map_err(|_| Error::InvalidState("active account uid is invalid".to_string()))?;
-        let key =
-            media_download::MediaTaskKey::payload(owner_uid, status.session_epoch, message_id);
-        let ticket = self.resolve_file_download(file_id).await?;
-        let root = std::path::Path::new(self.data_dir.as_str());
-        let target_dir =
-            media_store::ensure_attachment_dir(root, uid, message_id as i64, created_at_ms)
-                .map_err(|e| Error::Storage(format!("ensure attachment dir failed: {e}")))?;
-        let payload_filename =
-            media_store::payload_filename_with_fallback(&mime, filename_hint.as_deref());
-        self.download_manager
-            .start_with_ticket(self.clone(), key, ticket, target_dir, payload_filename)
-            .await
-            .map_err(Error::InvalidState)
-    }
-
-    pub async fn pause_message_media_download(&self, message_id: u64) {
-        if let Ok(status) = self.session_status().await {
-            if let Some(uid) = status.account_uid {
-                let key =
-                    media_download::MediaTaskKey::payload(uid, status.session_epoch, message_id);
-                self.download_manager.pause(self, &key).await;
-            }
-        }
-    }
-
-    pub async fn resume_message_media_download(&self, message_id: u64) {
-        if let Ok(status) = self.session_status().await {
-            if let Some(uid) = status.account_uid {
-                let key =
-                    media_download::MediaTaskKey::payload(uid, status.session_epoch, message_id);
-                self.download_manager.resume(self, &key).await;
-            }
-        }
-    }
-
-    pub async fn cancel_message_media_download(&self, message_id: u64) {
-        if let Ok(status) = self.session_status().await {
-            if let Some(uid) = status.account_uid {
-                let key =
-                    media_download::MediaTaskKey::payload(uid, status.session_epoch, message_id);
-                self.download_manager.cancel(self, &key).await;
-            }
-        }
-    }
-
-    pub async fn get_media_download_state(&self, message_id: u64) -> MediaDownloadState {
-        let Ok(status) = self.session_status().await else {
-            return MediaDownloadState::Idle;
-        };
-        let Some(uid) = status.account_uid else {
-            return MediaDownloadState::Idle;
-        };
-        let key = media_download::MediaTaskKey::payload(uid, status.session_epoch, message_id);
-        self.download_manager.get_state(&key).await
-    }
-
-    pub(crate) fn runtime_provider(&self) -> &RuntimeProvider {
-        &self._runtime_provider
-    }
-
-    /// SDK 自己那个 Tokio 运行时的句柄。
-    ///
-    /// 🔴 FFI 的 async 函数是被**宿主**（Kotlin 协程 / Swift）轮询的，那个上下文里
-    /// 没有 Tokio reactor：直接在里面 `reqwest ... .await` 会在运行期报
-    /// 「there is no reactor running」。要做 I/O 的地方把 future 交给这个句柄跑。
-    pub fn runtime_handle(&self) -> tokio::runtime::Handle {
-        self._runtime_provider.handle().clone()
-    }
-
-    pub fn last_event_sequence_id(&self) -> u64 {
-        self.event_seq.load(Ordering::Acquire)
-    }
-
-    pub fn event_history_limit(&self) -> usize {
-        self.event_history_limit
-    }
-
-    pub fn recent_events(&self, limit: usize) -> Vec<SequencedSdkEvent> {
-        let capped = limit.min(self.event_history_limit);
-        if capped == 0 {
-            return vec![];
-        }
-        let locked = self.event_history.lock().expect("event history poisoned");
-        locked
-            .iter()
-            .rev()
-            .take(capped)
-            .cloned()
-            .collect::<Vec<_>>()
-            .into_iter()
-            .rev()
-            .collect()
-    }
-
-    pub fn events_since(&self, from_sequence_id: u64, limit: usize) -> Vec<SequencedSdkEvent> {
-        let capped = limit.min(self.event_history_limit);
-        if capped == 0 {
-            return vec![];
-        }
-        let locked = self.event_history.lock().expect("event history poisoned");
-        locked
-            .iter()
-            .filter(|evt| evt.sequence_id > from_sequence_id)
-            .take(capped)
-            .cloned()
-            .collect()
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub fn recent_timeline_events(&self, limit: usize) -> Vec<SequencedSdkEvent> {
-        let capped = limit.min(self.event_history_limit);
-        if capped == 0 {
-            return vec![];
-        }
-        let locked = self.event_history.lock().expect("event history poisoned");
-        locked
-            .iter()
-            .rev()
-            .filter(|evt| Self::is_timeline_like_event(&evt.event))
-            .take(capped)
-            .cloned()
-            .collect::<Vec<_>>()
-            .into_iter()
-            .rev()
-            .collect()
+    pub fn is_supervised_sync_running(&self) -> bool {
+        self.supervised_sync_running.load(Ordering::Acquire)
     }
 
-    pub fn recent_network_events(&self, limit: usize) -> Vec<SequencedSdkEvent> {
-        let capped = limit.min(self.event_history_limit);
-        if capped == 0 {
-            return vec![];
+    pub fn start_supervised_sync(&self, interval_secs: u64) -> Result<()> {
+        self.ensure_running()?;
+        if self
+            .supervised_sync_running
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return Err(Error::InvalidState(
+                "supervised sync already running".to_string(),
+            ));
         }
-        let locked = self.event_history.lock().expect("event history poisoned");
-        locked
-            .iter()
-            .rev()
-            .filter(|evt| Self::is_network_like_event(&evt.event))
-            .take(capped)
-            .cloned()
-            .collect::<Vec<_>>()
-            .into_iter()
-            .rev()
-            .collect()
+
+        let sdk = self.clone();
+        let running = self.supervised_sync_running.clone();
+        let interval = Duration::from_secs(interval_secs.max(5));
+        let handle = self._runtime_provider.spawn(async move {
+            while running.load(Ordering::Acquire) && !sdk.shutting_down.load(Ordering::Acquire) {
+                let _ = sdk.sync_all_channels().await;
+                tokio::time::sleep(interval).await;
+            }
+            running.store(false, Ordering::Release);
+        });
+        let _ = self.task_registry.track(handle);
+        Ok(())
     }
 
-    pub fn timeline_events_since(
-        &self,
-        from_sequence_id: u64,
-        limit: usize,
-    ) -> Vec<SequencedSdkEvent> {
-        let capped = limit.min(self.event_history_limit);
-        if capped == 0 {
-            return vec![];
-        }
-        let locked = self.event_history.lock().expect("event history poisoned");
-        locked
-            .iter()
-            .filter(|evt| {
-                evt.sequence_id > from_sequence_id && Self::is_timeline_like_event(&evt.event)
-            })
-            .take(capped)
-            .cloned()
-            .collect()
+    pub fn stop_supervised_sync(&self) {
+        self.supervised_sync_running.store(false, Ordering::Release);
     }
 
-    pub fn network_events_since(
-        &self,
-        from_sequence_id: u64,
-        limit: usize,
-    ) -> Vec<SequencedSdkEvent> {
-        let capped = limit.min(self.event_history_limit);
-        if capped == 0 {
-            return vec![];
-        }
-        let locked = self.event_history.lock().expect("event history poisoned");
-        locked
-            .iter()
-            .filter(|evt| {
-                evt.sequence_id > from_sequence_id && Self::is_network_like_event(&evt.event)
+    pub async fn enqueue_outbound_message(&self, message_id: u64, payload: Vec<u8>) -> Result<u64> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::EnqueueOutboundMessage {
+                message_id,
+                payload,
+                resp: resp_tx,
             })
-            .take(capped)
-            .cloned()
-            .collect()
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn ping(&self) -> Result<()> {
+    pub async fn peek_outbound_messages(&self, limit: usize) -> Result<Vec<QueueMessage>> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::Ping { resp: resp_tx })
+            .send(Command::PeekOutboundMessages {
+                limit,
+                resp: resp_tx,
+            })
             .await
             .map_err(|_| self.actor_channel_error())?;
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn set_network_hint(&self, hint: NetworkHint) -> Result<()> {
+    pub async fn ack_outbound_messages(&self, message_ids: Vec<u64>) -> Result<usize> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::SetNetworkHint {
-                hint,
+            .send(Command::AckOutboundMessages {
+                message_ids,
                 resp: resp_tx,
             })
             .await
@@ -17317,12 +21409,20 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn set_video_process_hook(&self, hook: Option<VideoProcessHook>) -> Result<()> {
+    /// 把一条已有的附件消息重新排进 outbox（重试用）。
+    ///
+    /// payload 留空：附件字节留在托管路径上，drain 发送时自己读。
+    pub async fn enqueue_outbound_attachment(
+        &self,
+        message_id: u64,
+        route_key: String,
+    ) -> Result<u64> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::SetVideoProcessHook {
-                hook,
+            .send(Command::EnqueueOutboundAttachment {
+                message_id,
+                route_key,
                 resp: resp_tx,
             })
             .await
@@ -17330,36 +21430,25 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// Plan 2 媒体作业回传。宿主（Kotlin/iOS）收到 `SdkEvent::MediaJobRequested`
-    /// 处理完成后调用此接口。直接操作共享 `pending_media_jobs` 表、不经 actor
-    /// 命令通道——此时 actor 正阻塞在同一 oneshot rx 上。
-    ///
-    /// 已过期（超时被 actor 丢弃）或 `job_id` 未知时返回 `Err`。
-    pub fn submit_media_job_result(&self, job_id: String, result: MediaJobResult) -> Result<()> {
-        let sender = {
-            let mut locked = self
-                .pending_media_jobs
-                .lock()
-                .expect("pending_media_jobs poisoned");
-            locked.remove(&job_id)
-        };
-        match sender {
-            Some(tx) => tx
-                .send(result)
-                .map_err(|_| Error::InvalidState(format!("media job {job_id} receiver dropped"))),
-            None => Err(Error::InvalidState(format!(
-                "media job {job_id} not pending (expired or unknown)"
-            ))),
-        }
+    pub async fn peek_outbound_files(&self, limit: usize) -> Result<Vec<QueueMessage>> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::PeekOutboundFiles {
+                limit,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// 注册 / 清除网址预览回调。未注册时发送 Link 消息仅带 URL，客户端显示空白缩略图。
-    pub async fn set_link_preview_hook(&self, hook: Option<LinkPreviewHook>) -> Result<()> {
+    pub async fn ack_outbound_files(&self, message_ids: Vec<u64>) -> Result<usize> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::SetLinkPreviewHook {
-                hook,
+            .send(Command::AckOutboundFiles {
+                message_ids,
                 resp: resp_tx,
             })
             .await
@@ -17367,46 +21456,150 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn login(
-        &self,
-        username: String,
-        password: String,
-        device_id: String,
-    ) -> Result<LoginResult> {
+    pub fn generate_local_message_id(&self) -> Result<u64> {
+        self.snowflake
+            .next_id()
+            .map_err(|e| Error::Storage(format!("generate local_message_id failed: {e:?}")))
+    }
+
+    pub async fn create_local_message(&self, input: NewMessage) -> Result<u64> {
+        self.create_local_message_with_id(input, None).await
+    }
+
+    pub async fn send_link_message(&self, input: LinkMessageInput) -> Result<u64> {
+        let url = input.url.trim().to_string();
+        if url.is_empty() {
+            return Err(Error::InvalidState("url is empty".to_string()));
+        }
+        let title = input.title.and_then(non_empty_trimmed);
+        let description = input.description.and_then(non_empty_trimmed);
+        let display_content = title.clone().unwrap_or_else(|| url.clone());
+        let metadata = MessageMetadata::Link(LinkMetadata {
+            url,
+            title,
+            description,
+            thumbnail_file_id: input.thumbnail_file_id,
+        });
+        self.send_structured_message(
+            input.channel_id,
+            input.channel_type,
+            input.from_uid,
+            ContentMessageType::Link,
+            display_content,
+            metadata,
+            input.options,
+        )
+        .await
+    }
+
+    pub async fn send_location_message(&self, input: LocationMessageInput) -> Result<u64> {
+        let name = input.name.and_then(non_empty_trimmed);
+        let address = input.address.and_then(non_empty_trimmed);
+        let display_content = name
+            .clone()
+            .or_else(|| address.clone())
+            .unwrap_or_else(|| format!("{},{}", input.latitude, input.longitude));
+        let metadata = MessageMetadata::Location(LocationMetadata {
+            latitude: input.latitude,
+            longitude: input.longitude,
+            coordinate_system: input.coordinate_system.and_then(non_empty_trimmed),
+            name,
+            address,
+            poi_id: input.poi_id.and_then(non_empty_trimmed),
+            poi_source: input.poi_source.and_then(non_empty_trimmed),
+            thumbnail_file_id: input.thumbnail_file_id,
+        });
+        self.send_structured_message(
+            input.channel_id,
+            input.channel_type,
+            input.from_uid,
+            ContentMessageType::Location,
+            display_content,
+            metadata,
+            input.options,
+        )
+        .await
+    }
+
+    pub async fn send_contact_card_message(&self, input: ContactCardMessageInput) -> Result<u64> {
+        let metadata = MessageMetadata::ContactCard(ContactCardMetadata {
+            user_id: input.user_id,
+        });
+        self.send_structured_message(
+            input.channel_id,
+            input.channel_type,
+            input.from_uid,
+            ContentMessageType::ContactCard,
+            String::new(),
+            metadata,
+            input.options,
+        )
+        .await
+    }
+
+    /// 发表情。描述文字（没有就用 emoji）作为正文，表情本身放在 metadata 里；
+    /// 顺带记进最近使用。
+    pub async fn send_sticker_message(&self, input: StickerMessageInput) -> Result<u64> {
         self.ensure_running()?;
-        if actor_logs_enabled() {
-            eprintln!("[SDK.api] login: send");
-        }
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::Login {
-                username,
-                password,
-                device_id,
+            .send(Command::PrepareStickerSend {
+                sticker_id: input.sticker_id,
                 resp: resp_tx,
             })
             .await
             .map_err(|_| self.actor_channel_error())?;
-        let out = resp_rx.await.map_err(|_| self.actor_channel_error())?;
-        if actor_logs_enabled() {
-            eprintln!("[SDK.api] login: recv");
-        }
-        out
+        let sticker = resp_rx.await.map_err(|_| self.actor_channel_error())??;
+        let display_content = sticker.display_text();
+        let metadata = serde_json::to_value(&sticker)
+            .map_err(|e| Error::Serialization(format!("encode sticker: {e}")))?;
+        self.send_structured_value(
+            input.channel_id,
+            input.channel_type,
+            input.from_uid,
+            STICKER_MESSAGE_TYPE,
+            display_content,
+            metadata,
+            input.options,
+        )
+        .await
     }
 
-    pub async fn register(
-        &self,
-        username: String,
-        password: String,
-        device_id: String,
-    ) -> Result<LoginResult> {
+    /// 发起投票。题目作为正文（会话预览、搜索），定义放在 metadata 里。
+    pub async fn send_poll_message(&self, input: PollMessageInput) -> Result<u64> {
+        let definition = PollDefinition::normalized(
+            &input.question,
+            &input.poll_options,
+            input.multi_select,
+            input.anonymous,
+            input.close_at,
+        )?;
+        let display_content = definition.question.clone();
+        let metadata = serde_json::to_value(&definition)
+            .map_err(|e| Error::Serialization(format!("encode poll definition: {e}")))?;
+        self.send_structured_value(
+            input.channel_id,
+            input.channel_type,
+            input.from_uid,
+            POLL_MESSAGE_TYPE,
+            display_content,
+            metadata,
+            input.options,
+        )
+        .await
+    }
+
+    /// 投票或改票：多选投票可一次给多个选项下标，空列表等同撤票。
+    ///
+    /// 离线可用：自己的选择先记在本地（投影里 `vote_pending`），命令进出站队列，
+    /// 连上后发出；同一投票还没发出的旧选择被这次覆盖。票数等服务端回来再变。
+    pub async fn vote_poll(&self, message_id: u64, option_indexes: Vec<u32>) -> Result<()> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::Register {
-                username,
-                password,
-                device_id,
+            .send(Command::VotePoll {
+                message_id,
+                option_indexes,
                 resp: resp_tx,
             })
             .await
@@ -17414,46 +21607,87 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn authenticate(&self, user_id: u64, token: String, device_id: String) -> Result<()> {
+    pub async fn retract_poll_vote(&self, message_id: u64) -> Result<()> {
+        self.vote_poll(message_id, Vec::new()).await
+    }
+
+    /// 向服务端要一次票数（打开投票详情、或投票消息比票数推送晚到时）。
+    /// 返回本地票数是否变了；变了同样发 `TimelineUpdated`。
+    pub async fn refresh_poll(&self, message_id: u64) -> Result<bool> {
         self.ensure_running()?;
-        if actor_logs_enabled() {
-            eprintln!("[SDK.api] authenticate: send");
-        }
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::Authenticate {
-                user_id,
-                token,
-                device_id,
+            .send(Command::RefreshPoll {
+                message_id,
                 resp: resp_tx,
             })
             .await
             .map_err(|_| self.actor_channel_error())?;
-        let out = resp_rx.await.map_err(|_| self.actor_channel_error())?;
-        if actor_logs_enabled() {
-            eprintln!("[SDK.api] authenticate: recv");
-        }
-        out
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn shutdown(&self) {
-        if self.shutting_down.swap(true, Ordering::AcqRel) {
-            return;
+    /// 开始共享实时位置：发一条实时位置消息，之后的坐标用 [`Self::update_live_location`]
+    /// 改写这条消息，不再发新消息。起点坐标和到期时间放在 metadata 里。
+    pub async fn start_live_location(&self, input: LiveLocationMessageInput) -> Result<u64> {
+        let started_at = chrono::Utc::now().timestamp_millis();
+        let start = LiveLocationPosition {
+            latitude: input.latitude,
+            longitude: input.longitude,
+            at: started_at,
+            ..Default::default()
         }
-        self.supervised_sync_running.store(false, Ordering::Release);
-        let (resp_tx, resp_rx) = oneshot::channel();
-        let _ = self.tx.send(Command::Shutdown { resp: resp_tx }).await;
-        let _ = resp_rx.await;
-        self.task_registry.shutdown().await;
+        .validated()?;
+        let definition = live_location::LiveLocationDefinition {
+            latitude: start.latitude,
+            longitude: start.longitude,
+            coordinate_system: input.coordinate_system.and_then(non_empty_trimmed),
+            started_at,
+            expires_at: live_location::expires_at_for(started_at, input.duration_ms)?,
+        };
+        // 正文给会话预览和搜索用，同普通位置消息：有地点名用地点名，没有就是起点坐标。
+        let display_content = input
+            .name
+            .and_then(non_empty_trimmed)
+            .unwrap_or_else(|| format!("{},{}", start.latitude, start.longitude));
+        let metadata = serde_json::to_value(&definition)
+            .map_err(|e| Error::Serialization(format!("encode live location definition: {e}")))?;
+        self.send_structured_value(
+            input.channel_id,
+            input.channel_type,
+            input.from_uid,
+            LIVE_LOCATION_MESSAGE_TYPE,
+            display_content,
+            metadata,
+            input.options,
+        )
+        .await
     }
 
-    pub async fn sync_entities(&self, entity_type: String, scope: Option<String>) -> Result<usize> {
+    /// 推一次最新位置（只有发起人能推，消息须已发出）。
+    ///
+    /// 本地位置立即更新（投影里 `update_pending`），命令进出站队列；离上次发出不到
+    /// [`live_location::MIN_UPDATE_INTERVAL_MS`] 时推迟发出，推迟期间的更新只留最新一次。
+    /// 已结束或已到期的共享返回 `InvalidState`。
+    pub async fn update_live_location(
+        &self,
+        message_id: u64,
+        latitude: f64,
+        longitude: f64,
+        accuracy: Option<f64>,
+        heading: Option<f64>,
+    ) -> Result<()> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::SyncEntities {
-                entity_type,
-                scope,
+            .send(Command::UpdateLiveLocation {
+                message_id,
+                position: Some(LiveLocationPosition {
+                    latitude,
+                    longitude,
+                    accuracy,
+                    heading,
+                    at: 0,
+                }),
                 resp: resp_tx,
             })
             .await
@@ -17461,13 +21695,14 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn sync_channel(&self, channel_id: u64, channel_type: i32) -> Result<usize> {
+    /// 提前结束共享。立即发出、不节流，并替换还没发出的位置更新；已结束的再调一次什么也不做。
+    pub async fn stop_live_location(&self, message_id: u64) -> Result<()> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::SyncChannel {
-                channel_id,
-                channel_type,
+            .send(Command::UpdateLiveLocation {
+                message_id,
+                position: None,
                 resp: resp_tx,
             })
             .await
@@ -17475,22 +21710,130 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn sync_all_channels(&self) -> Result<usize> {
+    async fn send_structured_message(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+        from_uid: u64,
+        content_type: ContentMessageType,
+        display_content: String,
+        metadata: MessageMetadata,
+        options: StructuredSendOptions,
+    ) -> Result<u64> {
+        debug_assert!(
+            matches!(
+                (&content_type, &metadata),
+                (ContentMessageType::Link, MessageMetadata::Link(_))
+                    | (ContentMessageType::Location, MessageMetadata::Location(_))
+                    | (
+                        ContentMessageType::ContactCard,
+                        MessageMetadata::ContactCard(_)
+                    )
+            ),
+            "structured message type and protocol metadata must match",
+        );
+        self.send_structured_value(
+            channel_id,
+            channel_type,
+            from_uid,
+            i32::try_from(content_type.as_u32()).unwrap_or(0),
+            display_content,
+            metadata.to_inner_json_value(),
+            options,
+        )
+        .await
+    }
+
+    /// 结构化消息的公共部分：metadata 已经是信封里的 JSON。协议 `MessageMetadata`
+    /// 里没有的类型（投票、实时位置）直接走这里。
+    async fn send_structured_value(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+        from_uid: u64,
+        message_type: i32,
+        display_content: String,
+        metadata_value: serde_json::Value,
+        options: StructuredSendOptions,
+    ) -> Result<u64> {
+        let envelope = LocalMessagePayloadEnvelope {
+            content: display_content.clone(),
+            metadata: Some(metadata_value),
+            reply_to_message_id: options.in_reply_to_message_id.map(|id| id.to_string()),
+            mentioned_user_ids: if options.mentioned_user_ids.is_empty() {
+                None
+            } else {
+                Some(options.mentioned_user_ids)
+            },
+            message_source: None,
+        };
+        let extra = serde_json::to_string(&envelope).map_err(|e| {
+            Error::Serialization(format!("encode structured message envelope: {e}"))
+        })?;
+        // 建消息与入队命令是一个事务：分开做的话第二步失败就留下一条
+        // 「幽灵消息」——UI 永远显示发送中，却没有命令负责把它发出去。
+        self.create_local_message_queued(
+            NewMessage {
+                channel_id,
+                channel_type,
+                from_uid,
+                message_type,
+                content: display_content.clone(),
+                searchable_word: display_content,
+                setting: 0,
+                extra,
+                mime_type: None,
+                media_downloaded: false,
+                thumb_status: 0,
+            },
+            None,
+            "message",
+            Vec::new(),
+            None,
+        )
+        .await
+    }
+
+    /// 建消息并入队出站命令，**一个事务**（MESSAGE_SPEC §8.2）。
+    ///
+    /// 发送入口应当用它，而不是 `create_local_message` + `enqueue_outbound_*`：
+    /// 后者是两个事务，第二步失败会留下永远发送中的幽灵消息。
+    pub async fn create_local_message_queued(
+        &self,
+        input: NewMessage,
+        local_message_id: Option<u64>,
+        command_type: &str,
+        payload: Vec<u8>,
+        route_key: Option<String>,
+    ) -> Result<u64> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::SyncAllChannels { resp: resp_tx })
+            .send(Command::CreateLocalMessageQueued {
+                input,
+                local_message_id,
+                command_type: command_type.to_string(),
+                payload,
+                route_key,
+                resp: resp_tx,
+            })
             .await
             .map_err(|_| self.actor_channel_error())?;
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn batch_get_presence(&self, user_ids: Vec<u64>) -> Result<Vec<PresenceStatus>> {
+    pub async fn create_local_message_with_id(
+        &self,
+        input: NewMessage,
+        local_message_id: Option<u64>,
+    ) -> Result<u64> {
         self.ensure_running()?;
+        let input = State::normalize_new_message(input);
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::BatchGetPresence {
-                user_ids,
+            .send(Command::CreateLocalMessage {
+                input,
+                local_message_id,
                 resp: resp_tx,
             })
             .await
@@ -17498,69 +21841,71 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn get_presence(&self, user_id: u64) -> Result<Option<PresenceStatus>> {
-        let mut out = self.batch_get_presence(vec![user_id]).await?;
-        Ok(out.pop())
-    }
-
-    pub fn batch_get_cached_presence(&self, user_ids: Vec<u64>) -> Vec<PresenceStatus> {
-        let mut out = self
-            .presence_cache
-            .lock()
-            .ok()
-            .map(|locked| {
-                user_ids
-                    .iter()
-                    .filter_map(|user_id| locked.get(user_id).cloned())
-                    .collect::<Vec<_>>()
-            })
-            .unwrap_or_default();
-        out.sort_by_key(|v| v.user_id);
-        out
-    }
-
-    pub fn get_cached_presence(&self, user_id: u64) -> Option<PresenceStatus> {
-        self.presence_cache
-            .lock()
-            .ok()
-            .and_then(|locked| locked.get(&user_id).cloned())
-    }
+    /// 重发一条失败的消息。**消息生命周期编排属于 Core**（SDK_LAYERED spec §4.1），
+    /// FFI 只做薄委托，不得维护第二套重试状态机。
+    ///
+    /// 分流依据是消息类型与其持久化阶段：
+    /// - 附件（image/video/voice/file）→ 回到 **file queue**，从上传阶段重来。此前不分
+    ///   类型一律进普通队列，送出去的是「本地路径当 content、metadata 为空」的消息，
+    ///   服务端必 20006，用户点多少次重试都发不出去（2026-07-26 生产阻断）。
+    /// - 其余 → 普通队列（drain 时从本地库重读消息，payload 不透明）。
+    ///
+    /// 附件源文件已不在时返回 [`Error::AttachmentSourceMissing`]，让上层提示重新选择，
+    /// 而不是排一条注定被拒的消息。
+    pub async fn retry_message(&self, message_id: u64) -> Result<u64> {
+        let msg = self
+            .get_message_by_id(message_id)
+            .await?
+            .ok_or_else(|| Error::InvalidState(format!("message not found: {message_id}")))?;
 
-    pub fn clear_presence_cache(&self) {
-        if let Ok(mut locked) = self.presence_cache.lock() {
-            locked.clear();
+        if is_attachment_message_type(msg.message_type) {
+            let path = attachment_local_path(&msg.content)
+                .ok_or(Error::AttachmentSourceMissing { message_id })?;
+            // 只证明源文件此刻可读，**不把字节读进来**：drain 会在真正发送时
+            // 从这条托管路径读盘（payload 为空即走该分支）。把几十上百 MB 复制
+            // 进 outbox 的 BLOB 列，等于同一份数据存两遍，还要跟着事务一起写。
+            match std::fs::File::open(&path).and_then(|f| f.metadata()) {
+                Ok(meta) if meta.len() > 0 => {}
+                Ok(_) => return Err(Error::AttachmentSourceMissing { message_id }),
+                Err(e) => {
+                    // 路径存在但读不出来（权限/占用）同样是「没有可重传的源」。
+                    tracing::warn!(error = %e, path = %path, "attachment retry: source unreadable");
+                    return Err(Error::AttachmentSourceMissing { message_id });
+                }
+            }
+            let route_key = self.file_route_key.as_ref().clone().ok_or_else(|| {
+                Error::InvalidState("no endpoint configured for attachment retry".to_string())
+            })?;
+            return self
+                .enqueue_outbound_attachment(message_id, route_key)
+                .await;
         }
-    }
 
-    pub async fn send_typing(
-        &self,
-        channel_id: u64,
-        channel_type: i32,
-        is_typing: bool,
-        action_type: TypingActionType,
-    ) -> Result<()> {
-        self.ensure_running()?;
+        self.enqueue_outbound_message(message_id, Vec::new()).await
+    }
 
-        // 1 秒节流：如果同一频道在 1 秒内已经上报过相同状态，则直接跳过
-        let key = (channel_id, is_typing, action_type.clone() as u8);
-        let now = std::time::Instant::now();
-        {
-            let mut locked = self.typing_throttle.lock().unwrap();
-            if let Some(last_sent) = locked.get(&key) {
-                if now.duration_since(*last_sent).as_millis() < 1000 {
-                    return Ok(());
+    /// file queue 的路由键：同一 endpoint 的附件共享一条有序队列。
+    fn endpoint_route_key(ep: &ServerEndpoint) -> String {
+        let scheme = match ep.protocol {
+            TransportProtocol::Quic => "quic",
+            TransportProtocol::Tcp => "tcp",
+            TransportProtocol::WebSocket => {
+                if ep.use_tls {
+                    "wss"
+                } else {
+                    "ws"
                 }
             }
-            locked.insert(key, now);
-        }
+        };
+        format!("{scheme}://{}:{}", ep.host, ep.port)
+    }
 
+    pub async fn get_message_by_id(&self, message_id: u64) -> Result<Option<StoredMessage>> {
+        self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::SendTyping {
-                channel_id,
-                channel_type,
-                is_typing,
-                action_type,
+            .send(Command::GetMessageById {
+                message_id,
                 resp: resp_tx,
             })
             .await
@@ -17568,22 +21913,18 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// 订阅频道事件（进入聊天页面时调用，接收 typing / presence 等状态事件）
-    /// channel_type: 0=Private, 1=Group, 2=Room
-    /// token: 可选，Room 类型订阅时传入业务 API 签发的 ticket（JWT）
-    pub async fn subscribe_channel(
+    /// 按 server_message_id 查本地主键；`channel` 为 `None` 时不限会话。本地没有返回 None。
+    pub async fn get_message_id_by_server_message_id(
         &self,
-        channel_id: u64,
-        channel_type: u8,
-        token: Option<String>,
-    ) -> Result<()> {
+        channel: Option<(u64, i32)>,
+        server_message_id: u64,
+    ) -> Result<Option<u64>> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::Subscribe {
-                channel_id,
-                channel_type,
-                token,
+            .send(Command::GetMessageIdByServerMessageId {
+                channel,
+                server_message_id,
                 resp: resp_tx,
             })
             .await
@@ -17591,15 +21932,22 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// 取消订阅频道事件（离开聊天页面时调用）
-    /// channel_type: 0=Private, 1=Group, 2=Room
-    pub async fn unsubscribe_channel(&self, channel_id: u64, channel_type: u8) -> Result<()> {
+    pub async fn list_messages(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<StoredMessage>> {
         self.ensure_running()?;
+        self.foreground_wakeup.notify_one();
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::Unsubscribe {
+            .send(Command::ListMessages {
                 channel_id,
                 channel_type,
+                limit,
+                offset,
                 resp: resp_tx,
             })
             .await
@@ -17607,27 +21955,26 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// Channel Transfer client→app RPC. Sends a wire `TransferRequest` (biz_type=19),
-    /// awaits the matching `TransferResponse` (biz_type=20), and returns it decoded
-    /// as `TransferReply`. `timeout_ms` is the per-call wire timeout; default 5000ms
-    /// when zero. See `02-server/CHANNEL_TRANSFER_SPEC.md` v2.0 and
-    /// `07-application/BOT_INTERACTION_SPEC.md` for routes (e.g. `bot/menu/get`).
-    pub async fn transfer(
+    /// 同 [`Self::list_messages`]，只返回本地主键小于 `before_local_id` 的消息；
+    /// 过滤在 SQL 里完成，`limit`/`offset` 针对筛选后的结果。
+    pub async fn list_messages_before(
         &self,
         channel_id: u64,
-        route: String,
-        body: Vec<u8>,
-        timeout_ms: u64,
-    ) -> Result<TransferReply> {
+        channel_type: i32,
+        before_local_id: u64,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<StoredMessage>> {
         self.ensure_running()?;
-        let timeout_ms = if timeout_ms == 0 { 5000 } else { timeout_ms };
+        self.foreground_wakeup.notify_one();
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::Transfer {
+            .send(Command::ListMessagesBefore {
                 channel_id,
-                route,
-                body,
-                timeout_ms,
+                channel_type,
+                before_local_id,
+                limit,
+                offset,
                 resp: resp_tx,
             })
             .await
@@ -17635,13 +21982,26 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn rpc_call(&self, route: String, body_json: String) -> Result<String> {
+    /// 以 anchor（server_message_id）为轴按显示排序读取本地上下文窗口
+    /// （spec §5：around 回填后 UI 从本地重查渲染）。anchor 本地不存在返回空。
+    pub async fn list_messages_around(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+        anchor_server_message_id: u64,
+        before_limit: usize,
+        after_limit: usize,
+    ) -> Result<Vec<StoredMessage>> {
         self.ensure_running()?;
+        self.foreground_wakeup.notify_one();
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::RpcCall {
-                route,
-                body_json,
+            .send(Command::ListMessagesAround {
+                channel_id,
+                channel_type,
+                anchor_server_message_id,
+                before_limit,
+                after_limit,
                 resp: resp_tx,
             })
             .await
@@ -17649,203 +22009,142 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn rpc_call_typed<Req, Resp>(&self, route: &str, req: &Req) -> Result<Resp>
-    where
-        Req: Serialize,
-        Resp: DeserializeOwned,
-    {
-        let body_json = serde_json::to_string(req)
-            .map_err(|e| Error::Serialization(format!("encode {route} body: {e}")))?;
-        let raw = self.rpc_call(route.to_string(), body_json).await?;
-        self.apply_rpc_side_effects(route, &raw).await?;
-        serde_json::from_str(&raw)
-            .map_err(|e| Error::Serialization(format!("decode {route} response: {e}; raw={raw}")))
-    }
-
-    /// 下载前按 file_id 调 `file/get_url` 解析出下载票据（含解密所需 cek/version）。
-    /// 附件加密 v1：消息只带 file_id，下载权威信息（signed_url + cek + version）走此 RPC，
-    /// 不依赖消息里存的 file_url（那只作 legacy fallback）。CEK 不进日志。
-    pub async fn resolve_file_download(&self, file_id: u64) -> Result<ResolvedFileDownload> {
-        let payload = FileGetUrlRequest {
-            file_id,
-            user_id: 0, // 服务端按鉴权上下文填充
-        };
-        let resp: FileGetUrlResponse = self.rpc_call_typed(routes::file::GET_URL, &payload).await?;
-        if resp.file_url.trim().is_empty() {
-            return Err(Error::Serialization(
-                "decode file/get_url response: missing file_url".to_string(),
-            ));
-        }
-        Ok(ResolvedFileDownload {
-            url: resp.file_url,
-            encryption_version: resp.encryption_version,
-            cek: resp.cek,
-        })
-    }
-
-    /// AVATAR_CACHE_SPEC §8: 头像上传前客户端预处理。
-    ///
-    /// decode（白名单 jpeg/png/webp，gif/损坏格式直接 Err，不消耗上传流量）→
-    /// 中心裁剪正方形 → 边长 >480 缩放到 480x480（≤480 不放大）→ 编码 PNG
-    /// 写临时文件，返回处理后路径。App 选图后先过它再走上传管道。
-    pub async fn prepare_avatar_image(&self, src_path: String) -> Result<String> {
-        // 直接同步调用:uniffi 的 async 桥在自己的 foreign executor 上 poll 本 future,
-        // 没有 Tokio runtime 上下文,spawn_blocking 会 panic「no reactor running」。
-        // 头像预处理是一次性 CPU 工作(≤480 图,数十 ms),App 已在协程里调用,
-        // 短暂阻塞该协程线程可接受。
-        // 输出写 data_dir/tmp（app 沙箱内保证可写）；Android 上 std temp_dir =
-        // /data/local/tmp 无写权限，不能用（见 prepare_avatar_image_sync doc）。
-        let out_dir = std::path::Path::new(self.data_dir()).join("tmp");
-        avatar_cache::prepare_avatar_image_sync(std::path::Path::new(&src_path), &out_dir)
-            .map(|p| p.to_string_lossy().to_string())
-    }
-
-    async fn apply_rpc_side_effects(&self, route: &str, raw: &str) -> Result<()> {
-        if route == routes::friend::PENDING {
-            let parsed = serde_json::from_str::<FriendPendingResponse>(raw).map_err(|e| {
-                Error::Serialization(format!(
-                    "decode {} side-effect response: {e}; raw={raw}",
-                    routes::friend::PENDING
-                ))
-            })?;
-            let now_ms = chrono::Utc::now().timestamp_millis();
-            for item in parsed.requests {
-                self.upsert_user(UpsertUserInput {
-                    user_id: item.user.user_id,
-                    username: Some(item.user.username),
-                    nickname: Some(item.user.nickname),
-                    alias: None,
-                    avatar: item.user.avatar_url.unwrap_or_default(),
-                    user_type: item.user.user_type as i32,
-                    is_deleted: false,
-                    channel_id: String::new(),
-                    version: 0,
-                    updated_at: now_ms,
-                })
-                .await?;
-            }
-            return Ok(());
-        }
-
-        if route == routes::friend::ACCEPT {
-            // Keep local entities fresh so direct-channel title can resolve from user table.
-            let channel_id = serde_json::from_str::<u64>(raw).map_err(|e| {
-                Error::Serialization(format!(
-                    "decode {} side-effect response: {e}; raw={raw}",
-                    routes::friend::ACCEPT
-                ))
-            })?;
-            if channel_id > 0 {
-                let _ = self.sync_channel(channel_id, 1).await;
-            }
-            let _ = self.sync_entities("friend".to_string(), None).await;
-            let _ = self.sync_entities("user".to_string(), None).await;
-        }
-
-        Ok(())
-    }
-
-    pub async fn is_bootstrap_completed(&self) -> Result<bool> {
+    pub async fn query_timeline_snapshot(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+        limit: usize,
+        offset: usize,
+    ) -> Result<TimelineSnapshot> {
         self.ensure_running()?;
+        self.foreground_wakeup.notify_one();
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::IsBootstrapCompleted { resp: resp_tx })
+            .send(Command::QueryTimelineSnapshot {
+                channel_id,
+                channel_type,
+                limit,
+                offset,
+                resp: resp_tx,
+            })
             .await
             .map_err(|_| self.actor_channel_error())?;
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn run_bootstrap_sync(&self) -> Result<()> {
-        self.ensure_running()?;
-        let (resp_tx, resp_rx) = oneshot::channel();
-        self.tx
-            .send(Command::RunBootstrapSync { resp: resp_tx })
-            .await
-            .map_err(|_| self.actor_channel_error())?;
-        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    /// 订阅一个会话最新 `limit` 条消息的活窗口（排序同 [`Self::list_messages`]）。
+    /// 第一批增量是当前窗口的 `Reset`，之后消息新增、状态变化、下载完成时推增量。
+    /// 见 [`live_query`]。
+    pub async fn subscribe_timeline(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+        limit: usize,
+    ) -> Result<LiveQuery<StoredMessage>> {
+        if limit == 0 {
+            return Err(Error::InvalidArgument(
+                "live timeline limit must be positive".to_string(),
+            ));
+        }
+        // 先订阅再拿快照：两者之间的变化至多触发一次多余的重查，不会漏。
+        let events = self.subscribe_events();
+        let initial = self
+            .list_messages(channel_id, channel_type, limit, 0)
+            .await?;
+        let sdk = self.clone();
+        let fetch = move || {
+            let sdk = sdk.clone();
+            async move { sdk.list_messages(channel_id, channel_type, limit, 0).await }
+        };
+        Ok(self.spawn_live_query(
+            events,
+            initial,
+            fetch,
+            |m: &StoredMessage| m.message_id,
+            live_query::timeline_event_relevant(channel_id, channel_type),
+        ))
     }
 
-    pub async fn ensure_synced(&self) -> Result<()> {
-        self.ensure_running()?;
-        let (resp_tx, resp_rx) = oneshot::channel();
-        self.tx
-            .send(Command::EnsureSynced { resp: resp_tx })
-            .await
-            .map_err(|_| self.actor_channel_error())?;
-        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    /// 订阅会话列表的第一页（`query.after` 被忽略）。筛选 / 排序同 [`Self::query_channels`]，
+    /// 会话被顶到前面表现为一次 `Remove` + `Insert`。
+    pub async fn subscribe_channel_list(
+        &self,
+        mut query: ChannelListQuery,
+    ) -> Result<LiveQuery<StoredChannel>> {
+        query.after = None;
+        let events = self.subscribe_events();
+        let initial = self.query_channels(query.clone()).await?.channels;
+        let sdk = self.clone();
+        let fetch = move || {
+            let sdk = sdk.clone();
+            let query = query.clone();
+            async move { sdk.query_channels(query).await.map(|page| page.channels) }
+        };
+        Ok(self.spawn_live_query(
+            events,
+            initial,
+            fetch,
+            |c: &StoredChannel| (c.channel_id, c.channel_type),
+            live_query::channel_list_event_relevant,
+        ))
     }
 
-    pub async fn sync_state(&self) -> Result<SyncStateSnapshot> {
-        self.ensure_running()?;
-        let (resp_tx, resp_rx) = oneshot::channel();
-        self.tx
-            .send(Command::GetSyncState { resp: resp_tx })
-            .await
-            .map_err(|_| self.actor_channel_error())?;
-        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    fn spawn_live_query<T, K, KeyFn, Fetch, Fut, Relevant>(
+        &self,
+        events: broadcast::Receiver<SdkEvent>,
+        initial: Vec<T>,
+        fetch: Fetch,
+        key: KeyFn,
+        relevant: Relevant,
+    ) -> LiveQuery<T>
+    where
+        T: Clone + PartialEq + Send + Sync + 'static,
+        K: Eq + std::hash::Hash + Send + 'static,
+        KeyFn: Fn(&T) -> K + Send + Sync + 'static,
+        Fetch: Fn() -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<Vec<T>>> + Send + 'static,
+        Relevant: Fn(&SdkEvent, &[T]) -> bool + Send + Sync + 'static,
+    {
+        let (query, tx, cancel) = live_query::live_query_channel(initial.clone());
+        let maintain =
+            live_query::maintain_live_query(events, tx, cancel, initial, fetch, key, relevant);
+        let handle = self._runtime_provider.spawn(maintain);
+        let _ = self.task_registry.track(handle);
+        query
     }
 
-    pub async fn session_snapshot(&self) -> Result<Option<SessionSnapshot>> {
+    pub async fn set_message_cache_policy(&self, policy: MessageCachePolicy) -> Result<()> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::GetSessionSnapshot { resp: resp_tx })
+            .send(Command::SetMessageCachePolicy {
+                policy,
+                resp: resp_tx,
+            })
             .await
             .map_err(|_| self.actor_channel_error())?;
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn clear_local_state(&self) -> Result<()> {
+    pub async fn upsert_channel(&self, input: UpsertChannelInput) -> Result<()> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::ClearLocalState { resp: resp_tx })
+            .send(Command::UpsertChannel {
+                input,
+                resp: resp_tx,
+            })
             .await
             .map_err(|_| self.actor_channel_error())?;
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub fn is_supervised_sync_running(&self) -> bool {
-        self.supervised_sync_running.load(Ordering::Acquire)
-    }
-
-    pub fn start_supervised_sync(&self, interval_secs: u64) -> Result<()> {
-        self.ensure_running()?;
-        if self
-            .supervised_sync_running
-            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
-            .is_err()
-        {
-            return Err(Error::InvalidState(
-                "supervised sync already running".to_string(),
-            ));
-        }
-
-        let sdk = self.clone();
-        let running = self.supervised_sync_running.clone();
-        let interval = Duration::from_secs(interval_secs.max(5));
-        let handle = self._runtime_provider.spawn(async move {
-            while running.load(Ordering::Acquire) && !sdk.shutting_down.load(Ordering::Acquire) {
-                let _ = sdk.sync_all_channels().await;
-                tokio::time::sleep(interval).await;
-            }
-            running.store(false, Ordering::Release);
-        });
-        let _ = self.task_registry.track(handle);
-        Ok(())
-    }
-
-    pub fn stop_supervised_sync(&self) {
-        self.supervised_sync_running.store(false, Ordering::Release);
-    }
-
-    pub async fn enqueue_outbound_message(&self, message_id: u64, payload: Vec<u8>) -> Result<u64> {
+    pub async fn get_channel_by_id(&self, channel_id: u64) -> Result<Option<StoredChannel>> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::EnqueueOutboundMessage {
-                message_id,
-                payload,
+            .send(Command::GetChannelById {
+                channel_id,
                 resp: resp_tx,
             })
             .await
@@ -17853,12 +22152,13 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn peek_outbound_messages(&self, limit: usize) -> Result<Vec<QueueMessage>> {
+    pub async fn list_channels(&self, limit: usize, offset: usize) -> Result<Vec<StoredChannel>> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::PeekOutboundMessages {
+            .send(Command::ListChannels {
                 limit,
+                offset,
                 resp: resp_tx,
             })
             .await
@@ -17866,12 +22166,12 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn ack_outbound_messages(&self, message_ids: Vec<u64>) -> Result<usize> {
+    pub async fn upsert_channel_extra(&self, input: UpsertChannelExtraInput) -> Result<()> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::AckOutboundMessages {
-                message_ids,
+            .send(Command::UpsertChannelExtra {
+                input,
                 resp: resp_tx,
             })
             .await
@@ -17879,20 +22179,17 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// 把一条已有的附件消息重新排进 outbox（重试用）。
-    ///
-    /// payload 留空：附件字节留在托管路径上，drain 发送时自己读。
-    pub async fn enqueue_outbound_attachment(
+    pub async fn get_channel_extra(
         &self,
-        message_id: u64,
-        route_key: String,
-    ) -> Result<u64> {
+        channel_id: u64,
+        channel_type: i32,
+    ) -> Result<Option<StoredChannelExtra>> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::EnqueueOutboundAttachment {
-                message_id,
-                route_key,
+            .send(Command::GetChannelExtra {
+                channel_id,
+                channel_type,
                 resp: resp_tx,
             })
             .await
@@ -17900,12 +22197,19 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn peek_outbound_files(&self, limit: usize) -> Result<Vec<QueueMessage>> {
+    pub async fn mark_message_sent(
+        &self,
+        message_id: u64,
+        server_message_id: u64,
+        message_seq: u32,
+    ) -> Result<()> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::PeekOutboundFiles {
-                limit,
+            .send(Command::MarkMessageSent {
+                message_id,
+                server_message_id,
+                message_seq,
                 resp: resp_tx,
             })
             .await
@@ -17913,12 +22217,23 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn ack_outbound_files(&self, message_ids: Vec<u64>) -> Result<usize> {
+    /// 回填式拉取频道历史（spec §3/§6）：结果已写入本地库，UI 应随后从本地重查渲染。
+    pub async fn fetch_channel_history(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+        before_server_message_id: Option<u64>,
+        limit: Option<u32>,
+    ) -> Result<privchat_protocol::rpc::message::history::MessageHistoryResponse> {
         self.ensure_running()?;
+        self.foreground_wakeup.notify_one();
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::AckOutboundFiles {
-                message_ids,
+            .send(Command::FetchChannelHistory {
+                channel_id,
+                channel_type,
+                before_server_message_id,
+                limit,
                 resp: resp_tx,
             })
             .await
@@ -17926,308 +22241,337 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub fn generate_local_message_id(&self) -> Result<u64> {
-        self.snowflake
-            .next_id()
-            .map_err(|e| Error::Storage(format!("generate local_message_id failed: {e:?}")))
-    }
-
-    pub async fn create_local_message(&self, input: NewMessage) -> Result<u64> {
-        self.create_local_message_with_id(input, None).await
-    }
-
-    pub async fn send_link_message(&self, input: LinkMessageInput) -> Result<u64> {
-        let url = input.url.trim().to_string();
-        if url.is_empty() {
-            return Err(Error::InvalidState("url is empty".to_string()));
+    /// SDK-HISTORY-5（MESSAGE_HISTORY spec §2.5 / §2.5.1）：上滑加载更早历史。
+    ///
+    /// **架构（Telegram 式，读本地 / get 只补缺口）**：聊天页渲染读本地库为真源；本地翻到
+    /// 最早时用 `message/history/get` 回填（带真实 pts，SDK-HISTORY-2 已做 upsert），再从
+    /// 本地库重查返回。**gap 水位（has_more_before）由 SDK 持久化在 KV**——已到顶的会话
+    /// 不再空打网络，部分缓存的会话据此触发回填；跨会话/重启有效。主窗口只向更早**连续**
+    /// 扩展，绝不制造/渲染不连续区间（around 孤岛由 §5 独立处理）。
+    ///
+    /// [before_server_message_id] = 当前已显示最早一条的 server_message_id（翻页游标）。
+    /// 返回本次回填的更早消息（本地重查、显示序 DESC）+ has_more_before（服务端是否还有更早）。
+    pub async fn load_older_history(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+        before_server_message_id: u64,
+        limit: u32,
+    ) -> Result<OlderHistoryPage> {
+        let gap_key = format!("__hist_gap__:{channel_type}:{channel_id}");
+        // 读持久化 gap 态：不存在 → 视为未知（允许探一次）；has_more_before=false → 已到顶。
+        let persisted = self
+            .kv_get_local(gap_key.clone())
+            .await?
+            .and_then(|b| serde_json::from_slice::<HistGapState>(&b).ok());
+        if matches!(&persisted, Some(s) if !s.has_more_before) {
+            return Ok(OlderHistoryPage {
+                messages: Vec::new(),
+                has_more_before: false,
+            });
         }
-        let title = input.title.and_then(non_empty_trimmed);
-        let description = input.description.and_then(non_empty_trimmed);
-        let display_content = title.clone().unwrap_or_else(|| url.clone());
-        let metadata = MessageMetadata::Link(LinkMetadata {
-            url,
-            title,
-            description,
-            thumbnail_file_id: input.thumbnail_file_id,
-        });
-        self.send_structured_message(
-            input.channel_id,
-            input.channel_type,
-            input.from_uid,
-            ContentMessageType::Link,
-            display_content,
-            metadata,
-            input.options,
-        )
-        .await
-    }
-
-    pub async fn send_location_message(&self, input: LocationMessageInput) -> Result<u64> {
-        let name = input.name.and_then(non_empty_trimmed);
-        let address = input.address.and_then(non_empty_trimmed);
-        let display_content = name
-            .clone()
-            .or_else(|| address.clone())
-            .unwrap_or_else(|| format!("{},{}", input.latitude, input.longitude));
-        let metadata = MessageMetadata::Location(LocationMetadata {
-            latitude: input.latitude,
-            longitude: input.longitude,
-            coordinate_system: input.coordinate_system.and_then(non_empty_trimmed),
-            name,
-            address,
-            poi_id: input.poi_id.and_then(non_empty_trimmed),
-            poi_source: input.poi_source.and_then(non_empty_trimmed),
-            thumbnail_file_id: input.thumbnail_file_id,
-        });
-        self.send_structured_message(
-            input.channel_id,
-            input.channel_type,
-            input.from_uid,
-            ContentMessageType::Location,
-            display_content,
-            metadata,
-            input.options,
-        )
-        .await
-    }
-
-    pub async fn send_contact_card_message(&self, input: ContactCardMessageInput) -> Result<u64> {
-        let metadata = MessageMetadata::ContactCard(ContactCardMetadata {
-            user_id: input.user_id,
-        });
-        self.send_structured_message(
-            input.channel_id,
-            input.channel_type,
-            input.from_uid,
-            ContentMessageType::ContactCard,
-            String::new(),
-            metadata,
-            input.options,
-        )
-        .await
+        // 网络回填：SDK 内部按 server_message_id upsert 本地库（带真实 pts）。
+        let resp = self
+            .fetch_channel_history(
+                channel_id,
+                channel_type,
+                Some(before_server_message_id),
+                Some(limit),
+            )
+            .await?;
+        // 持久化新 gap 水位（服务端是否还有更早）。
+        let new_state = HistGapState {
+            has_more_before: resp.has_more,
+        };
+        let _ = self
+            .kv_put_local(gap_key, serde_json::to_vec(&new_state).unwrap_or_default())
+            .await;
+        // 从本地库重读更早窗口（复用规范 StoredMessage 映射）；过滤掉 anchor 及更新的。
+        let window = self
+            .list_messages_around(
+                channel_id,
+                channel_type,
+                before_server_message_id,
+                limit as usize,
+                0,
+            )
+            .await?;
+        let older: Vec<StoredMessage> = window
+            .into_iter()
+            .filter(|m| {
+                m.server_message_id
+                    .map(|id| id < before_server_message_id)
+                    .unwrap_or(false)
+            })
+            .collect();
+        Ok(OlderHistoryPage {
+            messages: older,
+            has_more_before: resp.has_more,
+        })
     }
 
-    async fn send_structured_message(
+    /// 打开会话（SDK-HISTORY-7）：本地为渲染真源，本地为空时补一次**最新**窗口。
+    ///
+    /// 这个入口此前根本不存在——`list_messages` 是纯本地读，打开一个本地没有消息的会话
+    /// 只会显示「暂无聊天内容」，而上滑翻页救不了它：翻页需要一个已存在的锚点往前翻，
+    /// 一条都没有时连起点都没有。
+    ///
+    /// 「没补过」和「补过、结果是空」必须分开记（[HistHydratedState]）。只看「本地是否为空」
+    /// 的话，一个真正空的会话每次打开都会白打一次网络，永远收敛不了。
+    ///
+    /// 空会话就返回空。**不注入任何占位/问候消息**——那是伪造历史。
+    pub async fn open_conversation(
         &self,
         channel_id: u64,
         channel_type: i32,
-        from_uid: u64,
-        content_type: ContentMessageType,
-        display_content: String,
-        metadata: MessageMetadata,
-        options: StructuredSendOptions,
-    ) -> Result<u64> {
-        debug_assert!(
-            matches!(
-                (&content_type, &metadata),
-                (ContentMessageType::Link, MessageMetadata::Link(_))
-                    | (ContentMessageType::Location, MessageMetadata::Location(_))
-                    | (
-                        ContentMessageType::ContactCard,
-                        MessageMetadata::ContactCard(_)
-                    )
-            ),
-            "structured message type and protocol metadata must match",
-        );
-        let metadata_value = metadata.to_inner_json_value();
-        let envelope = LocalMessagePayloadEnvelope {
-            content: display_content.clone(),
-            metadata: Some(metadata_value.clone()),
-            reply_to_message_id: options.in_reply_to_message_id.map(|id| id.to_string()),
-            mentioned_user_ids: if options.mentioned_user_ids.is_empty() {
-                None
-            } else {
-                Some(options.mentioned_user_ids)
-            },
-            message_source: None,
+        limit: u32,
+    ) -> Result<OpenConversationPage> {
+        let gap_key = format!("__hist_gap__:{channel_type}:{channel_id}");
+        let read_has_more = |raw: Option<Vec<u8>>| -> bool {
+            raw.and_then(|b| serde_json::from_slice::<HistGapState>(&b).ok())
+                .map(|s| s.has_more_before)
+                // 没有水位记录 = 未知，按「可能还有」处理：宁可多给一次上滑机会，
+                // 也不要谎称到顶把用户挡在门外。
+                .unwrap_or(true)
         };
-        let extra = serde_json::to_string(&envelope).map_err(|e| {
-            Error::Serialization(format!("encode structured message envelope: {e}"))
-        })?;
-        // 建消息与入队命令是一个事务：分开做的话第二步失败就留下一条
-        // 「幽灵消息」——UI 永远显示发送中，却没有命令负责把它发出去。
-        self.create_local_message_queued(
-            NewMessage {
-                channel_id,
-                channel_type,
-                from_uid,
-                message_type: i32::try_from(content_type.as_u32()).unwrap_or(0),
-                content: display_content.clone(),
-                searchable_word: display_content,
-                setting: 0,
-                extra,
-                mime_type: None,
-                media_downloaded: false,
-                thumb_status: 0,
-            },
-            None,
-            "message",
-            Vec::new(),
-            None,
-        )
-        .await
+
+        // 1. 先读本地。UI can publish this cache before awaiting this method, but local
+        // rows do not prove that the latest window was hydrated: a realtime push may have
+        // inserted exactly one newest row on a fresh device.
+        let local = self
+            .list_messages(channel_id, channel_type, limit as usize, 0)
+            .await?;
+
+        // 2. Hydration is state-driven, not row-count-driven. This also distinguishes a
+        // genuinely empty hydrated conversation from a conversation never fetched.
+        let hydrated_key = format!("__hist_hydrated__:{channel_type}:{channel_id}");
+        let hydrated = self
+            .kv_get_local(hydrated_key.clone())
+            .await?
+            .and_then(|b| serde_json::from_slice::<HistHydratedState>(&b).ok())
+            .map(|s| s.hydrated)
+            .unwrap_or(false);
+        if !should_hydrate_latest_window(hydrated, local.len()) {
+            return Ok(OpenConversationPage {
+                messages: local,
+                has_more_before: read_has_more(self.kv_get_local(gap_key).await?),
+                fetched_from_server: false,
+            });
+        }
+
+        // 3. 从没补过 → 拉最新一页，即使本地已有 realtime 消息。`before = None` 即「最新」，
+        //    与上滑翻页复用同一个 RPC（回填带真实 pts，SDK-HISTORY-2 已做 upsert）。
+        let resp = match self
+            .fetch_channel_history(channel_id, channel_type, None, Some(limit))
+            .await
+        {
+            Ok(resp) => resp,
+            // Local-first means a transient network failure must not erase an already
+            // rendered cache. Do not mark hydrated; the next open will retry.
+            Err(_) if !local.is_empty() => {
+                return Ok(OpenConversationPage {
+                    messages: local,
+                    has_more_before: read_has_more(self.kv_get_local(gap_key).await?),
+                    fetched_from_server: false,
+                });
+            }
+            Err(error) => return Err(error),
+        };
+
+        // 只有网络这一步成功了才落 hydrated：失败就落的话，一次超时会让这个会话
+        // 永远显示「暂无聊天内容」，再也不会重试。
+        let _ = self
+            .kv_put_local(
+                hydrated_key,
+                serde_json::to_vec(&HistHydratedState { hydrated: true }).unwrap_or_default(),
+            )
+            .await;
+        let _ = self
+            .kv_put_local(
+                gap_key,
+                serde_json::to_vec(&HistGapState {
+                    has_more_before: resp.has_more,
+                })
+                .unwrap_or_default(),
+            )
+            .await;
+
+        // 4. 从本地库重读（回填已 upsert）。本地始终是渲染真源，不直接渲染 RPC 响应。
+        let messages = self
+            .list_messages(channel_id, channel_type, limit as usize, 0)
+            .await?;
+        Ok(OpenConversationPage {
+            messages,
+            has_more_before: resp.has_more,
+            fetched_from_server: true,
+        })
     }
 
-    /// 建消息并入队出站命令，**一个事务**（MESSAGE_SPEC §8.2）。
+    /// 这个会话补过首屏了吗（KV，重启后仍然有效）。
+    async fn is_first_screen_hydrated(&self, channel_id: u64, channel_type: i32) -> bool {
+        self.kv_get_local(format!("__hist_hydrated__:{channel_type}:{channel_id}"))
+            .await
+            .ok()
+            .flatten()
+            .and_then(|b| serde_json::from_slice::<HistHydratedState>(&b).ok())
+            .map(|s| s.hydrated)
+            .unwrap_or(false)
+    }
+
+    /// 给**所有未隐藏会话**补首屏（SDK-HISTORY-7 §15.9）。
     ///
-    /// 发送入口应当用它，而不是 `create_local_message` + `enqueue_outbound_*`：
-    /// 后者是两个事务，第二步失败会留下永远发送中的幽灵消息。
-    pub async fn create_local_message_queued(
+    /// `open_conversation` 只在用户点进某个会话时才补，于是新设备/重装后的会话列表是这样的：
+    /// 每个会话都空着，点一个补一个，不点就永远空着。用户看到的是「先空一下再刷出消息」，
+    /// 而没点过的会话在离线时什么都没有。换设备本来就该把该看的先备齐。
+    ///
+    /// 几条硬约束：
+    /// - **绝不在启动关键路径上**（SDK_SYNC_RESUME_SPEC Phase 2）。调用方必须在
+    ///   `CriticalReady` / `SYNC_READY` 之后再起它，失败也不得影响任何全局可用状态。
+    /// - **按 `list_channels` 的顺序**（置顶 → 最近活跃），用户最可能打开的先补。
+    /// - **逐个补、补一个歇一下**：SDK 是单 actor，RPC 在 actor 循环里串行执行。不歇的话
+    ///   几百个会话会把 actor 占满，发消息、退出登录这些命令排不上队——那正是把退出登录
+    ///   卡死的同一个坑。
+    /// - **已补过的直接跳过**，只读本地 KV，不打网络；所以重复调用是廉价且幂等的。
+    /// - **单个会话失败不中断整轮**，也不标记 hydrated，下次自然重试。
+    ///
+    /// `max_channels = 0` 表示不限。
+    pub async fn hydrate_conversation_first_screens(
         &self,
-        input: NewMessage,
-        local_message_id: Option<u64>,
-        command_type: &str,
-        payload: Vec<u8>,
-        route_key: Option<String>,
-    ) -> Result<u64> {
+        limit: u32,
+        max_channels: usize,
+    ) -> Result<FirstScreenSweepReport> {
+        /// 两次网络补齐之间的间隔。给 actor 留出处理用户操作的缝隙。
+        const PACE: Duration = Duration::from_millis(80);
+        /// 每次向本地库要多少个会话。
+        const PAGE: usize = 200;
+
         self.ensure_running()?;
-        let (resp_tx, resp_rx) = oneshot::channel();
-        self.tx
-            .send(Command::CreateLocalMessageQueued {
-                input,
-                local_message_id,
-                command_type: command_type.to_string(),
-                payload,
-                route_key,
-                resp: resp_tx,
-            })
-            .await
-            .map_err(|_| self.actor_channel_error())?;
-        resp_rx.await.map_err(|_| self.actor_channel_error())?
-    }
+        let mut report = FirstScreenSweepReport::default();
+        let mut offset = 0usize;
+
+        loop {
+            if self.shutting_down.load(Ordering::Acquire) {
+                report.cancelled = true;
+                break;
+            }
+            let channels = self.list_channels(PAGE, offset).await?;
+            if channels.is_empty() {
+                break;
+            }
+            offset += channels.len();
+
+            for channel in channels {
+                if self.shutting_down.load(Ordering::Acquire) {
+                    report.cancelled = true;
+                    return Ok(report);
+                }
+                if max_channels != 0 && report.scanned >= max_channels {
+                    return Ok(report);
+                }
+                report.scanned += 1;
 
-    pub async fn create_local_message_with_id(
-        &self,
-        input: NewMessage,
-        local_message_id: Option<u64>,
-    ) -> Result<u64> {
-        self.ensure_running()?;
-        let input = State::normalize_new_message(input);
-        let (resp_tx, resp_rx) = oneshot::channel();
-        self.tx
-            .send(Command::CreateLocalMessage {
-                input,
-                local_message_id,
-                resp: resp_tx,
-            })
-            .await
-            .map_err(|_| self.actor_channel_error())?;
-        resp_rx.await.map_err(|_| self.actor_channel_error())?
+                if self
+                    .is_first_screen_hydrated(channel.channel_id, channel.channel_type)
+                    .await
+                {
+                    report.skipped += 1;
+                    continue;
+                }
+
+                match self
+                    .open_conversation(channel.channel_id, channel.channel_type, limit)
+                    .await
+                {
+                    Ok(_) => report.hydrated += 1,
+                    // 一个会话补不上（没权限/服务端报错/网络抖动）不该让其余会话陪葬。
+                    Err(error) => {
+                        report.failed += 1;
+                        tracing::debug!(
+                            channel_id = channel.channel_id,
+                            error = %error,
+                            "first-screen hydration failed; will retry later",
+                        );
+                    }
+                }
+                tokio::time::sleep(PACE).await;
+            }
+        }
+        Ok(report)
     }
 
-    /// 重发一条失败的消息。**消息生命周期编排属于 Core**（SDK_LAYERED spec §4.1），
-    /// FFI 只做薄委托，不得维护第二套重试状态机。
+    /// 起一轮首屏扫补，**立刻返回**（SDK-HISTORY-7 §15.9）。
     ///
-    /// 分流依据是消息类型与其持久化阶段：
-    /// - 附件（image/video/voice/file）→ 回到 **file queue**，从上传阶段重来。此前不分
-    ///   类型一律进普通队列，送出去的是「本地路径当 content、metadata 为空」的消息，
-    ///   服务端必 20006，用户点多少次重试都发不出去（2026-07-26 生产阻断）。
-    /// - 其余 → 普通队列（drain 时从本地库重读消息，payload 不透明）。
+    /// 宿主要的就是即发即忘，而且这条路径**不能**让调用方 await：
+    /// uniffi 的 async 桥在它自己的 foreign executor 上 poll future，那里没有 Tokio
+    /// runtime 上下文，`tokio::time::sleep` 会直接 panic「no reactor running」——
+    /// 真机实测第一版就是这么失败的（`hydrateConversationFirstScreens failed:
+    /// there is no reactor running`）。扫补必须跑在 SDK 自己的 runtime 上。
     ///
-    /// 附件源文件已不在时返回 [`Error::AttachmentSourceMissing`]，让上层提示重新选择，
-    /// 而不是排一条注定被拒的消息。
-    pub async fn retry_message(&self, message_id: u64) -> Result<u64> {
-        let msg = self
-            .get_message_by_id(message_id)
-            .await?
-            .ok_or_else(|| Error::InvalidState(format!("message not found: {message_id}")))?;
-
-        if is_attachment_message_type(msg.message_type) {
-            let path = attachment_local_path(&msg.content)
-                .ok_or(Error::AttachmentSourceMissing { message_id })?;
-            // 只证明源文件此刻可读，**不把字节读进来**：drain 会在真正发送时
-            // 从这条托管路径读盘（payload 为空即走该分支）。把几十上百 MB 复制
-            // 进 outbox 的 BLOB 列，等于同一份数据存两遍，还要跟着事务一起写。
-            match std::fs::File::open(&path).and_then(|f| f.metadata()) {
-                Ok(meta) if meta.len() > 0 => {}
-                Ok(_) => return Err(Error::AttachmentSourceMissing { message_id }),
-                Err(e) => {
-                    // 路径存在但读不出来（权限/占用）同样是「没有可重传的源」。
-                    tracing::warn!(error = %e, path = %path, "attachment retry: source unreadable");
-                    return Err(Error::AttachmentSourceMissing { message_id });
-                }
+    /// 返回 `false` = 已经有一轮在跑，这次不重复起。宿主每次进 SYNC_READY 都会踢一脚，
+    /// 重连/切前台会踢很多次，没有这道闸就是几百个会话被并发扫好几轮。
+    ///
+    /// 省流量生效时（见 [`bandwidth`]）也返回 `false`：参数记下，换到非计费网络或
+    /// 关掉省流量时自动补起这一轮。
+    pub fn start_first_screen_hydration(&self, limit: u32, max_channels: usize) -> bool {
+        if self.shutting_down.load(Ordering::Acquire) {
+            return false;
+        }
+        if self.bandwidth.data_saver_active() {
+            if let Ok(mut deferred) = self.deferred_first_screen_hydration.lock() {
+                *deferred = Some((limit, max_channels));
             }
-            let route_key = self.file_route_key.as_ref().clone().ok_or_else(|| {
-                Error::InvalidState("no endpoint configured for attachment retry".to_string())
-            })?;
-            return self
-                .enqueue_outbound_attachment(message_id, route_key)
-                .await;
+            return false;
+        }
+        if self
+            .first_screen_sweep_running
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return false;
         }
 
-        self.enqueue_outbound_message(message_id, Vec::new()).await
-    }
-
-    /// file queue 的路由键：同一 endpoint 的附件共享一条有序队列。
-    fn endpoint_route_key(ep: &ServerEndpoint) -> String {
-        let scheme = match ep.protocol {
-            TransportProtocol::Quic => "quic",
-            TransportProtocol::Tcp => "tcp",
-            TransportProtocol::WebSocket => {
-                if ep.use_tls {
-                    "wss"
-                } else {
-                    "ws"
+        let sdk = self.clone();
+        let running = self.first_screen_sweep_running.clone();
+        let handle = self._runtime_provider.spawn(async move {
+            match sdk
+                .hydrate_conversation_first_screens(limit, max_channels)
+                .await
+            {
+                Ok(report) => tracing::info!(
+                    scanned = report.scanned,
+                    hydrated = report.hydrated,
+                    skipped = report.skipped,
+                    failed = report.failed,
+                    cancelled = report.cancelled,
+                    "first-screen hydration sweep finished",
+                ),
+                // 扫补是尽力而为的后台工作：整轮失败也不得影响任何已可用状态。
+                Err(error) => {
+                    tracing::warn!(error = %error, "first-screen hydration sweep aborted")
                 }
             }
-        };
-        format!("{scheme}://{}:{}", ep.host, ep.port)
-    }
-
-    pub async fn get_message_by_id(&self, message_id: u64) -> Result<Option<StoredMessage>> {
-        self.ensure_running()?;
-        let (resp_tx, resp_rx) = oneshot::channel();
-        self.tx
-            .send(Command::GetMessageById {
-                message_id,
-                resp: resp_tx,
-            })
-            .await
-            .map_err(|_| self.actor_channel_error())?;
-        resp_rx.await.map_err(|_| self.actor_channel_error())?
-    }
-
-    pub async fn list_messages(
-        &self,
-        channel_id: u64,
-        channel_type: i32,
-        limit: usize,
-        offset: usize,
-    ) -> Result<Vec<StoredMessage>> {
-        self.ensure_running()?;
-        self.foreground_wakeup.notify_one();
-        let (resp_tx, resp_rx) = oneshot::channel();
-        self.tx
-            .send(Command::ListMessages {
-                channel_id,
-                channel_type,
-                limit,
-                offset,
-                resp: resp_tx,
-            })
-            .await
-            .map_err(|_| self.actor_channel_error())?;
-        resp_rx.await.map_err(|_| self.actor_channel_error())?
+            running.store(false, Ordering::Release);
+        });
+        let _ = self.task_registry.track(handle);
+        true
     }
 
-    /// 以 anchor（server_message_id）为轴按显示排序读取本地上下文窗口
-    /// （spec §5：around 回填后 UI 从本地重查渲染）。anchor 本地不存在返回空。
-    pub async fn list_messages_around(
+    /// jump-to-message 上下文（spec §5）：before/anchor/after 完整消息已回填本地库。
+    pub async fn fetch_messages_around(
         &self,
         channel_id: u64,
         channel_type: i32,
-        anchor_server_message_id: u64,
-        before_limit: usize,
-        after_limit: usize,
-    ) -> Result<Vec<StoredMessage>> {
+        message_id: u64,
+        before_limit: Option<u32>,
+        after_limit: Option<u32>,
+    ) -> Result<privchat_protocol::rpc::message::history::MessageHistoryAroundResponse> {
         self.ensure_running()?;
-        self.foreground_wakeup.notify_one();
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::ListMessagesAround {
+            .send(Command::FetchMessagesAround {
                 channel_id,
                 channel_type,
-                anchor_server_message_id,
+                message_id,
                 before_limit,
                 after_limit,
                 resp: resp_tx,
@@ -18237,48 +22581,32 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn query_timeline_snapshot(
+    /// 定向修复一条投影损坏的消息（MESSAGE_PROJECTION_SPEC §2.4）。
+    ///
+    /// 「投影损坏」= 本地这一行是旧代码或半截写入留下的：metadata 丢了（图片永远
+    /// 加载不出来）、时间单位错了、pts 缺失。修法是按 server_message_id 走
+    /// `message/history/around` 拿回权威消息，重新跑 canonical projection，原地
+    /// upsert。
+    ///
+    /// 契约：
+    /// - `message.id` 不变（upsert 按 server_message_id 命中既有行）；
+    /// - 不增加未读、不推进 sync cursor —— 这不是「收到新消息」；
+    /// - 成功后发 `TimelineUpdated{reason="message_projection_repaired"}`，宿主据此重查。
+    ///
+    /// 返回本地 message id；服务端也没有这条消息时返回 None。
+    pub async fn repair_message_projection(
         &self,
         channel_id: u64,
         channel_type: i32,
-        limit: usize,
-        offset: usize,
-    ) -> Result<TimelineSnapshot> {
+        server_message_id: u64,
+    ) -> Result<Option<u64>> {
         self.ensure_running()?;
-        self.foreground_wakeup.notify_one();
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::QueryTimelineSnapshot {
+            .send(Command::RepairMessageProjection {
                 channel_id,
                 channel_type,
-                limit,
-                offset,
-                resp: resp_tx,
-            })
-            .await
-            .map_err(|_| self.actor_channel_error())?;
-        resp_rx.await.map_err(|_| self.actor_channel_error())?
-    }
-
-    pub async fn set_message_cache_policy(&self, policy: MessageCachePolicy) -> Result<()> {
-        self.ensure_running()?;
-        let (resp_tx, resp_rx) = oneshot::channel();
-        self.tx
-            .send(Command::SetMessageCachePolicy {
-                policy,
-                resp: resp_tx,
-            })
-            .await
-            .map_err(|_| self.actor_channel_error())?;
-        resp_rx.await.map_err(|_| self.actor_channel_error())?
-    }
-
-    pub async fn upsert_channel(&self, input: UpsertChannelInput) -> Result<()> {
-        self.ensure_running()?;
-        let (resp_tx, resp_rx) = oneshot::channel();
-        self.tx
-            .send(Command::UpsertChannel {
-                input,
+                server_message_id,
                 resp: resp_tx,
             })
             .await
@@ -18286,26 +22614,41 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn get_channel_by_id(&self, channel_id: u64) -> Result<Option<StoredChannel>> {
-        self.ensure_running()?;
-        let (resp_tx, resp_rx) = oneshot::channel();
-        self.tx
-            .send(Command::GetChannelById {
-                channel_id,
-                resp: resp_tx,
-            })
-            .await
-            .map_err(|_| self.actor_channel_error())?;
-        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    /// 云端历史搜索（spec §4）。scope 由 channel_id 推导：Some=CHANNEL / None=GLOBAL。
+    /// 命中是 snippet 投影，**不落本地库**；点击命中后调 fetch_messages_around。
+    /// 服务端限频 300ms/user——调用方（UI）应 debounce 300–500ms 且忽略过期结果。
+    pub async fn search_message_history(
+        &self,
+        query: &str,
+        channel_id: Option<u64>,
+        cursor: Option<String>,
+        limit: Option<u32>,
+    ) -> Result<privchat_protocol::rpc::message::history::MessageHistorySearchResponse> {
+        let req = privchat_protocol::rpc::message::history::MessageHistorySearchRequest {
+            query: query.to_string(),
+            scope: if channel_id.is_some() {
+                "CHANNEL".to_string()
+            } else {
+                "GLOBAL".to_string()
+            },
+            channel_id,
+            cursor,
+            limit,
+        };
+        self.rpc_call_typed(
+            privchat_protocol::rpc::routes::message_history::SEARCH,
+            &req,
+        )
+        .await
     }
 
-    pub async fn list_channels(&self, limit: usize, offset: usize) -> Result<Vec<StoredChannel>> {
+    pub async fn update_message_status(&self, message_id: u64, status: i32) -> Result<()> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::ListChannels {
-                limit,
-                offset,
+            .send(Command::UpdateMessageStatus {
+                message_id,
+                status,
                 resp: resp_tx,
             })
             .await
@@ -18313,12 +22656,13 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn upsert_channel_extra(&self, input: UpsertChannelExtraInput) -> Result<()> {
+    pub async fn update_thumb_status(&self, message_id: u64, thumb_status: i32) -> Result<()> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::UpsertChannelExtra {
-                input,
+            .send(Command::UpdateThumbStatus {
+                message_id,
+                thumb_status,
                 resp: resp_tx,
             })
             .await
@@ -18326,17 +22670,13 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn get_channel_extra(
-        &self,
-        channel_id: u64,
-        channel_type: i32,
-    ) -> Result<Option<StoredChannelExtra>> {
+    pub async fn update_media_downloaded(&self, message_id: u64, downloaded: bool) -> Result<()> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::GetChannelExtra {
-                channel_id,
-                channel_type,
+            .send(Command::UpdateMediaDownloaded {
+                message_id,
+                downloaded,
                 resp: resp_tx,
             })
             .await
@@ -18344,19 +22684,19 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn mark_message_sent(
+    async fn update_media_downloaded_scoped(
         &self,
-        message_id: u64,
-        server_message_id: u64,
-        message_seq: u32,
+        key: &media_download::MediaTaskKey,
+        downloaded: bool,
     ) -> Result<()> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::MarkMessageSent {
-                message_id,
-                server_message_id,
-                message_seq,
+            .send(Command::UpdateMediaDownloadedScoped {
+                owner_uid: key.owner_uid.clone(),
+                session_epoch: key.session_epoch,
+                message_id: key.message_id,
+                downloaded,
                 resp: resp_tx,
             })
             .await
@@ -18364,23 +22704,20 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// 回填式拉取频道历史（spec §3/§6）：结果已写入本地库，UI 应随后从本地重查渲染。
-    pub async fn fetch_channel_history(
+    /// 本地发送附件的占位 INSERT：拿 DB 自增 id，但不 emit 任何事件。
+    /// 调用方必须在文件写盘后调用 `finalize_local_attachment`，由 finalize 负责 emit，
+    /// 这样 UI 只会看到一次完整态的气泡，不会有"空内容 → 有内容"的闪动。
+    pub async fn create_local_attachment_placeholder(
         &self,
-        channel_id: u64,
-        channel_type: i32,
-        before_server_message_id: Option<u64>,
-        limit: Option<u32>,
-    ) -> Result<privchat_protocol::rpc::message::history::MessageHistoryResponse> {
+        input: NewMessage,
+        local_message_id: Option<u64>,
+    ) -> Result<u64> {
         self.ensure_running()?;
-        self.foreground_wakeup.notify_one();
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::FetchChannelHistory {
-                channel_id,
-                channel_type,
-                before_server_message_id,
-                limit,
+            .send(Command::CreateLocalAttachmentPlaceholder {
+                input,
+                local_message_id,
                 resp: resp_tx,
             })
             .await
@@ -18388,330 +22725,191 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// SDK-HISTORY-5（MESSAGE_HISTORY spec §2.5 / §2.5.1）：上滑加载更早历史。
-    ///
-    /// **架构（Telegram 式，读本地 / get 只补缺口）**：聊天页渲染读本地库为真源；本地翻到
-    /// 最早时用 `message/history/get` 回填（带真实 pts，SDK-HISTORY-2 已做 upsert），再从
-    /// 本地库重查返回。**gap 水位（has_more_before）由 SDK 持久化在 KV**——已到顶的会话
-    /// 不再空打网络，部分缓存的会话据此触发回填；跨会话/重启有效。主窗口只向更早**连续**
-    /// 扩展，绝不制造/渲染不连续区间（around 孤岛由 §5 独立处理）。
-    ///
-    /// [before_server_message_id] = 当前已显示最早一条的 server_message_id（翻页游标）。
-    /// 返回本次回填的更早消息（本地重查、显示序 DESC）+ has_more_before（服务端是否还有更早）。
-    pub async fn load_older_history(
-        &self,
-        channel_id: u64,
-        channel_type: i32,
-        before_server_message_id: u64,
-        limit: u32,
-    ) -> Result<OlderHistoryPage> {
-        let gap_key = format!("__hist_gap__:{channel_type}:{channel_id}");
-        // 读持久化 gap 态：不存在 → 视为未知（允许探一次）；has_more_before=false → 已到顶。
-        let persisted = self
-            .kv_get_local(gap_key.clone())
-            .await?
-            .and_then(|b| serde_json::from_slice::<HistGapState>(&b).ok());
-        if matches!(&persisted, Some(s) if !s.has_more_before) {
-            return Ok(OlderHistoryPage {
-                messages: Vec::new(),
-                has_more_before: false,
-            });
-        }
-        // 网络回填：SDK 内部按 server_message_id upsert 本地库（带真实 pts）。
-        let resp = self
-            .fetch_channel_history(
-                channel_id,
-                channel_type,
-                Some(before_server_message_id),
-                Some(limit),
-            )
-            .await?;
-        // 持久化新 gap 水位（服务端是否还有更早）。
-        let new_state = HistGapState {
-            has_more_before: resp.has_more,
-        };
-        let _ = self
-            .kv_put_local(gap_key, serde_json::to_vec(&new_state).unwrap_or_default())
-            .await;
-        // 从本地库重读更早窗口（复用规范 StoredMessage 映射）；过滤掉 anchor 及更新的。
-        let window = self
-            .list_messages_around(
-                channel_id,
-                channel_type,
-                before_server_message_id,
-                limit as usize,
-                0,
-            )
-            .await?;
-        let older: Vec<StoredMessage> = window
-            .into_iter()
-            .filter(|m| {
-                m.server_message_id
-                    .map(|id| id < before_server_message_id)
-                    .unwrap_or(false)
-            })
-            .collect();
-        Ok(OlderHistoryPage {
-            messages: older,
-            has_more_before: resp.has_more,
-        })
-    }
-
-    /// 打开会话（SDK-HISTORY-7）：本地为渲染真源，本地为空时补一次**最新**窗口。
-    ///
-    /// 这个入口此前根本不存在——`list_messages` 是纯本地读，打开一个本地没有消息的会话
-    /// 只会显示「暂无聊天内容」，而上滑翻页救不了它：翻页需要一个已存在的锚点往前翻，
-    /// 一条都没有时连起点都没有。
-    ///
-    /// 「没补过」和「补过、结果是空」必须分开记（[HistHydratedState]）。只看「本地是否为空」
-    /// 的话，一个真正空的会话每次打开都会白打一次网络，永远收敛不了。
-    ///
-    /// 空会话就返回空。**不注入任何占位/问候消息**——那是伪造历史。
-    pub async fn open_conversation(
-        &self,
-        channel_id: u64,
-        channel_type: i32,
-        limit: u32,
-    ) -> Result<OpenConversationPage> {
-        let gap_key = format!("__hist_gap__:{channel_type}:{channel_id}");
-        let read_has_more = |raw: Option<Vec<u8>>| -> bool {
-            raw.and_then(|b| serde_json::from_slice::<HistGapState>(&b).ok())
-                .map(|s| s.has_more_before)
-                // 没有水位记录 = 未知，按「可能还有」处理：宁可多给一次上滑机会，
-                // 也不要谎称到顶把用户挡在门外。
-                .unwrap_or(true)
-        };
-
-        // 1. 先读本地。UI can publish this cache before awaiting this method, but local
-        // rows do not prove that the latest window was hydrated: a realtime push may have
-        // inserted exactly one newest row on a fresh device.
-        let local = self
-            .list_messages(channel_id, channel_type, limit as usize, 0)
-            .await?;
-
-        // 2. Hydration is state-driven, not row-count-driven. This also distinguishes a
-        // genuinely empty hydrated conversation from a conversation never fetched.
-        let hydrated_key = format!("__hist_hydrated__:{channel_type}:{channel_id}");
-        let hydrated = self
-            .kv_get_local(hydrated_key.clone())
-            .await?
-            .and_then(|b| serde_json::from_slice::<HistHydratedState>(&b).ok())
-            .map(|s| s.hydrated)
-            .unwrap_or(false);
-        if !should_hydrate_latest_window(hydrated, local.len()) {
-            return Ok(OpenConversationPage {
-                messages: local,
-                has_more_before: read_has_more(self.kv_get_local(gap_key).await?),
-                fetched_from_server: false,
-            });
-        }
-
-        // 3. 从没补过 → 拉最新一页，即使本地已有 realtime 消息。`before = None` 即「最新」，
-        //    与上滑翻页复用同一个 RPC（回填带真实 pts，SDK-HISTORY-2 已做 upsert）。
-        let resp = match self
-            .fetch_channel_history(channel_id, channel_type, None, Some(limit))
-            .await
-        {
-            Ok(resp) => resp,
-            // Local-first means a transient network failure must not erase an already
-            // rendered cache. Do not mark hydrated; the next open will retry.
-            Err(_) if !local.is_empty() => {
-                return Ok(OpenConversationPage {
-                    messages: local,
-                    has_more_before: read_has_more(self.kv_get_local(gap_key).await?),
-                    fetched_from_server: false,
-                });
-            }
-            Err(error) => return Err(error),
-        };
-
-        // 只有网络这一步成功了才落 hydrated：失败就落的话，一次超时会让这个会话
-        // 永远显示「暂无聊天内容」，再也不会重试。
-        let _ = self
-            .kv_put_local(
-                hydrated_key,
-                serde_json::to_vec(&HistHydratedState { hydrated: true }).unwrap_or_default(),
-            )
-            .await;
-        let _ = self
-            .kv_put_local(
-                gap_key,
-                serde_json::to_vec(&HistGapState {
-                    has_more_before: resp.has_more,
-                })
-                .unwrap_or_default(),
-            )
-            .await;
-
-        // 4. 从本地库重读（回填已 upsert）。本地始终是渲染真源，不直接渲染 RPC 响应。
-        let messages = self
-            .list_messages(channel_id, channel_type, limit as usize, 0)
-            .await?;
-        Ok(OpenConversationPage {
-            messages,
-            has_more_before: resp.has_more,
-            fetched_from_server: true,
-        })
-    }
-
-    /// 这个会话补过首屏了吗（KV，重启后仍然有效）。
-    async fn is_first_screen_hydrated(&self, channel_id: u64, channel_type: i32) -> bool {
-        self.kv_get_local(format!("__hist_hydrated__:{channel_type}:{channel_id}"))
+    /// 本地发送附件：文件写盘完毕后调用，一次性写回 content / thumb_status /
+    /// media_downloaded，并 emit TimelineUpdated 让 UI 刷新气泡。
+    pub async fn finalize_local_attachment(
+        &self,
+        message_id: u64,
+        content: String,
+        thumb_status: i32,
+    ) -> Result<()> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::FinalizeLocalAttachment {
+                message_id,
+                content,
+                thumb_status,
+                resp: resp_tx,
+            })
             .await
-            .ok()
-            .flatten()
-            .and_then(|b| serde_json::from_slice::<HistHydratedState>(&b).ok())
-            .map(|s| s.hydrated)
-            .unwrap_or(false)
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// 给**所有未隐藏会话**补首屏（SDK-HISTORY-7 §15.9）。
-    ///
-    /// `open_conversation` 只在用户点进某个会话时才补，于是新设备/重装后的会话列表是这样的：
-    /// 每个会话都空着，点一个补一个，不点就永远空着。用户看到的是「先空一下再刷出消息」，
-    /// 而没点过的会话在离线时什么都没有。换设备本来就该把该看的先备齐。
-    ///
-    /// 几条硬约束：
-    /// - **绝不在启动关键路径上**（SDK_SYNC_RESUME_SPEC Phase 2）。调用方必须在
-    ///   `CriticalReady` / `SYNC_READY` 之后再起它，失败也不得影响任何全局可用状态。
-    /// - **按 `list_channels` 的顺序**（置顶 → 最近活跃），用户最可能打开的先补。
-    /// - **逐个补、补一个歇一下**：SDK 是单 actor，RPC 在 actor 循环里串行执行。不歇的话
-    ///   几百个会话会把 actor 占满，发消息、退出登录这些命令排不上队——那正是把退出登录
-    ///   卡死的同一个坑。
-    /// - **已补过的直接跳过**，只读本地 KV，不打网络；所以重复调用是廉价且幂等的。
-    /// - **单个会话失败不中断整轮**，也不标记 hydrated，下次自然重试。
+    /// 附件定稿 + 入队命令，**一个事务**。
     ///
-    /// `max_channels = 0` 表示不限。
-    pub async fn hydrate_conversation_first_screens(
+    /// 附件链路应当用它，而不是 `finalize_local_attachment` + `send_attachment_*`：
+    /// 后者是两步，中间崩溃会留下一条已完成、已显示、却没人负责发送的附件。
+    pub async fn finalize_attachment_and_enqueue(
         &self,
-        limit: u32,
-        max_channels: usize,
-    ) -> Result<FirstScreenSweepReport> {
-        /// 两次网络补齐之间的间隔。给 actor 留出处理用户操作的缝隙。
-        const PACE: Duration = Duration::from_millis(80);
-        /// 每次向本地库要多少个会话。
-        const PAGE: usize = 200;
-
+        message_id: u64,
+        content: String,
+        thumb_status: i32,
+        route_key: String,
+        payload: Vec<u8>,
+    ) -> Result<()> {
         self.ensure_running()?;
-        let mut report = FirstScreenSweepReport::default();
-        let mut offset = 0usize;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::FinalizeAttachmentAndEnqueue {
+                message_id,
+                content,
+                thumb_status,
+                route_key,
+                payload,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
 
-        loop {
-            if self.shutting_down.load(Ordering::Acquire) {
-                report.cancelled = true;
-                break;
-            }
-            let channels = self.list_channels(PAGE, offset).await?;
-            if channels.is_empty() {
-                break;
-            }
-            offset += channels.len();
+    pub async fn set_message_revoke(
+        &self,
+        message_id: u64,
+        revoked: bool,
+        revoker: Option<u64>,
+    ) -> Result<()> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::SetMessageRevoke {
+                message_id,
+                revoked,
+                revoker,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
 
-            for channel in channels {
-                if self.shutting_down.load(Ordering::Acquire) {
-                    report.cancelled = true;
-                    return Ok(report);
-                }
-                if max_channels != 0 && report.scanned >= max_channels {
-                    return Ok(report);
-                }
-                report.scanned += 1;
+    pub async fn delete_message_local(&self, message_id: u64) -> Result<Option<StoredMessage>> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::DeleteMessageLocal {
+                message_id,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
 
-                if self
-                    .is_first_screen_hydrated(channel.channel_id, channel.channel_type)
-                    .await
-                {
-                    report.skipped += 1;
-                    continue;
-                }
+    /// 设置本地 channel 隐藏标记（不触达服务端）。返回 true 表示命中行被更新。
+    pub async fn set_channel_hidden(&self, channel_id: u64, hidden: bool) -> Result<bool> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::SetChannelHidden {
+                channel_id,
+                hidden,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
 
-                match self
-                    .open_conversation(channel.channel_id, channel.channel_type, limit)
-                    .await
-                {
-                    Ok(_) => report.hydrated += 1,
-                    // 一个会话补不上（没权限/服务端报错/网络抖动）不该让其余会话陪葬。
-                    Err(error) => {
-                        report.failed += 1;
-                        tracing::debug!(
-                            channel_id = channel.channel_id,
-                            error = %error,
-                            "first-screen hydration failed; will retry later",
-                        );
-                    }
-                }
-                tokio::time::sleep(PACE).await;
-            }
-        }
-        Ok(report)
+    /// 本地删除 channel：隐藏 + 清空所有相关消息。不触达服务端。
+    /// 这些消息的媒体目录一并删掉，blob 库里只被它们引用的内容随之回收。
+    pub async fn delete_channel_local(&self, channel_id: u64) -> Result<Vec<StoredMessage>> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::DeleteChannelLocal {
+                channel_id,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// 起一轮首屏扫补，**立刻返回**（SDK-HISTORY-7 §15.9）。
-    ///
-    /// 宿主要的就是即发即忘，而且这条路径**不能**让调用方 await：
-    /// uniffi 的 async 桥在它自己的 foreign executor 上 poll future，那里没有 Tokio
-    /// runtime 上下文，`tokio::time::sleep` 会直接 panic「no reactor running」——
-    /// 真机实测第一版就是这么失败的（`hydrateConversationFirstScreens failed:
-    /// there is no reactor running`）。扫补必须跑在 SDK 自己的 runtime 上。
-    ///
-    /// 返回 `false` = 已经有一轮在跑，这次不重复起。宿主每次进 SYNC_READY 都会踢一脚，
-    /// 重连/切前台会踢很多次，没有这道闸就是几百个会话被并发扫好几轮。
-    pub fn start_first_screen_hydration(&self, limit: u32, max_channels: usize) -> bool {
-        if self.shutting_down.load(Ordering::Acquire) {
-            return false;
-        }
-        if self
-            .first_screen_sweep_running
-            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
-            .is_err()
-        {
-            return false;
-        }
+    pub async fn edit_message(
+        &self,
+        message_id: u64,
+        content: String,
+        edited_at: i32,
+    ) -> Result<()> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::EditMessage {
+                message_id,
+                content,
+                edited_at,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
 
-        let sdk = self.clone();
-        let running = self.first_screen_sweep_running.clone();
-        let handle = self._runtime_provider.spawn(async move {
-            match sdk
-                .hydrate_conversation_first_screens(limit, max_channels)
-                .await
-            {
-                Ok(report) => tracing::info!(
-                    scanned = report.scanned,
-                    hydrated = report.hydrated,
-                    skipped = report.skipped,
-                    failed = report.failed,
-                    cancelled = report.cancelled,
-                    "first-screen hydration sweep finished",
-                ),
-                // 扫补是尽力而为的后台工作：整轮失败也不得影响任何已可用状态。
-                Err(error) => {
-                    tracing::warn!(error = %error, "first-screen hydration sweep aborted")
-                }
-            }
-            running.store(false, Ordering::Release);
-        });
-        let _ = self.task_registry.track(handle);
-        true
+    pub async fn set_message_pinned(&self, message_id: u64, is_pinned: bool) -> Result<()> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::SetMessagePinned {
+                message_id,
+                is_pinned,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    /// 置顶一条已发出的群消息。离线可用：本地索引先改（`pending`），命令进出站队列，
+    /// 连上后发出；同一消息还没发出的置顶 / 取消置顶被这次覆盖。
+    ///
+    /// 与 [`Self::set_message_pinned`] 的区别：那个只改本地、不通知服务端。
+    pub async fn pin_message(&self, message_id: u64) -> Result<()> {
+        self.pin_message_with(message_id, true).await
     }
 
-    /// jump-to-message 上下文（spec §5）：before/anchor/after 完整消息已回填本地库。
-    pub async fn fetch_messages_around(
+    pub async fn unpin_message(&self, message_id: u64) -> Result<()> {
+        self.pin_message_with(message_id, false).await
+    }
+
+    async fn pin_message_with(&self, message_id: u64, pinned: bool) -> Result<()> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::PinMessage {
+                message_id,
+                pinned,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    /// 本地的置顶列表，按置顶时间倒序，带置顶人。不走网络。
+    pub async fn list_pinned_messages(
         &self,
         channel_id: u64,
         channel_type: i32,
-        message_id: u64,
-        before_limit: Option<u32>,
-        after_limit: Option<u32>,
-    ) -> Result<privchat_protocol::rpc::message::history::MessageHistoryAroundResponse> {
+    ) -> Result<Vec<PinnedMessage>> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::FetchMessagesAround {
+            .send(Command::ListPinnedMessages {
                 channel_id,
                 channel_type,
-                message_id,
-                before_limit,
-                after_limit,
                 resp: resp_tx,
             })
             .await
@@ -18719,32 +22917,19 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// 定向修复一条投影损坏的消息（MESSAGE_PROJECTION_SPEC §2.4）。
-    ///
-    /// 「投影损坏」= 本地这一行是旧代码或半截写入留下的：metadata 丢了（图片永远
-    /// 加载不出来）、时间单位错了、pts 缺失。修法是按 server_message_id 走
-    /// `message/history/around` 拿回权威消息，重新跑 canonical projection，原地
-    /// upsert。
-    ///
-    /// 契约：
-    /// - `message.id` 不变（upsert 按 server_message_id 命中既有行）；
-    /// - 不增加未读、不推进 sync cursor —— 这不是「收到新消息」；
-    /// - 成功后发 `TimelineUpdated{reason="message_projection_repaired"}`，宿主据此重查。
-    ///
-    /// 返回本地 message id；服务端也没有这条消息时返回 None。
-    pub async fn repair_message_projection(
+    /// 用服务端的置顶列表替换本地的（自己还没发出的置顶保持不动）。
+    /// 返回本地列表是否变了；变了同样发 `PinnedMessagesChanged`。
+    pub async fn refresh_pinned_messages(
         &self,
         channel_id: u64,
         channel_type: i32,
-        server_message_id: u64,
-    ) -> Result<Option<u64>> {
+    ) -> Result<bool> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::RepairMessageProjection {
+            .send(Command::RefreshPinnedMessages {
                 channel_id,
                 channel_type,
-                server_message_id,
                 resp: resp_tx,
             })
             .await
@@ -18752,41 +22937,14 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// 云端历史搜索（spec §4）。scope 由 channel_id 推导：Some=CHANNEL / None=GLOBAL。
-    /// 命中是 snippet 投影，**不落本地库**；点击命中后调 fetch_messages_around。
-    /// 服务端限频 300ms/user——调用方（UI）应 debounce 300–500ms 且忽略过期结果。
-    pub async fn search_message_history(
-        &self,
-        query: &str,
-        channel_id: Option<u64>,
-        cursor: Option<String>,
-        limit: Option<u32>,
-    ) -> Result<privchat_protocol::rpc::message::history::MessageHistorySearchResponse> {
-        let req = privchat_protocol::rpc::message::history::MessageHistorySearchRequest {
-            query: query.to_string(),
-            scope: if channel_id.is_some() {
-                "CHANNEL".to_string()
-            } else {
-                "GLOBAL".to_string()
-            },
-            channel_id,
-            cursor,
-            limit,
-        };
-        self.rpc_call_typed(
-            privchat_protocol::rpc::routes::message_history::SEARCH,
-            &req,
-        )
-        .await
-    }
-
-    pub async fn update_message_status(&self, message_id: u64, status: i32) -> Result<()> {
+    /// 本地表情目录：已安装的按用户排的顺序在前；`installed_only = false` 时再跟上其余
+    /// 拉过的包。不走网络，离线可用。
+    pub async fn list_sticker_packages(&self, installed_only: bool) -> Result<Vec<StickerPackage>> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::UpdateMessageStatus {
-                message_id,
-                status,
+            .send(Command::ListStickerPackages {
+                installed_only,
                 resp: resp_tx,
             })
             .await
@@ -18794,27 +22952,24 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn update_thumb_status(&self, message_id: u64, thumb_status: i32) -> Result<()> {
+    /// 拉服务端的表情包列表缓存到本地，返回同 `list_sticker_packages(false)`。
+    pub async fn refresh_sticker_packages(&self) -> Result<Vec<StickerPackage>> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::UpdateThumbStatus {
-                message_id,
-                thumb_status,
-                resp: resp_tx,
-            })
+            .send(Command::RefreshStickerPackages { resp: resp_tx })
             .await
             .map_err(|_| self.actor_channel_error())?;
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn update_media_downloaded(&self, message_id: u64, downloaded: bool) -> Result<()> {
+    /// 拉一个包的详情（含表情列表）缓存到本地。已安装的包顺带在后台预取表情图。
+    pub async fn refresh_sticker_package(&self, package_id: String) -> Result<StickerPackage> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::UpdateMediaDownloaded {
-                message_id,
-                downloaded,
+            .send(Command::RefreshStickerPackage {
+                package_id,
                 resp: resp_tx,
             })
             .await
@@ -18822,19 +22977,29 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    async fn update_media_downloaded_scoped(
+    /// 安装表情包，排到最前面。离线可用：本地先装上（`pending`），命令进出站队列，
+    /// 连上后发出并同步到别的设备；被服务端拒绝时退回原来的样子。本地还没见过的包
+    /// 先只记安装状态，详情和表情图在后台拉。
+    pub async fn install_sticker_package(&self, package_id: String) -> Result<()> {
+        self.install_sticker_package_with(package_id, true).await
+    }
+
+    /// 卸载表情包，同样离线可用；预取到本地的表情图一并删掉。
+    pub async fn uninstall_sticker_package(&self, package_id: String) -> Result<()> {
+        self.install_sticker_package_with(package_id, false).await
+    }
+
+    async fn install_sticker_package_with(
         &self,
-        key: &media_download::MediaTaskKey,
-        downloaded: bool,
+        package_id: String,
+        installed: bool,
     ) -> Result<()> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::UpdateMediaDownloadedScoped {
-                owner_uid: key.owner_uid.clone(),
-                session_epoch: key.session_epoch,
-                message_id: key.message_id,
-                downloaded,
+            .send(Command::InstallStickerPackage {
+                package_id,
+                installed,
                 resp: resp_tx,
             })
             .await
@@ -18842,20 +23007,13 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// 本地发送附件的占位 INSERT：拿 DB 自增 id，但不 emit 任何事件。
-    /// 调用方必须在文件写盘后调用 `finalize_local_attachment`，由 finalize 负责 emit，
-    /// 这样 UI 只会看到一次完整态的气泡，不会有"空内容 → 有内容"的闪动。
-    pub async fn create_local_attachment_placeholder(
-        &self,
-        input: NewMessage,
-        local_message_id: Option<u64>,
-    ) -> Result<u64> {
+    /// 调整已安装包的顺序：列出的在前，没列出的按原顺序跟在后面。顺序只在本机。
+    pub async fn reorder_sticker_packages(&self, package_ids: Vec<String>) -> Result<()> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::CreateLocalAttachmentPlaceholder {
-                input,
-                local_message_id,
+            .send(Command::ReorderStickerPackages {
+                package_ids,
                 resp: resp_tx,
             })
             .await
@@ -18863,69 +23021,36 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// 本地发送附件：文件写盘完毕后调用，一次性写回 content / thumb_status /
-    /// media_downloaded，并 emit TimelineUpdated 让 UI 刷新气泡。
-    pub async fn finalize_local_attachment(
-        &self,
-        message_id: u64,
-        content: String,
-        thumb_status: i32,
-    ) -> Result<()> {
+    /// 最近使用的表情，新的在前，最多 [`sticker::RECENT_LIMIT`] 个。只在本机。
+    pub async fn recent_stickers(&self) -> Result<Vec<Sticker>> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::FinalizeLocalAttachment {
-                message_id,
-                content,
-                thumb_status,
-                resp: resp_tx,
-            })
+            .send(Command::ListRecentStickers { resp: resp_tx })
             .await
             .map_err(|_| self.actor_channel_error())?;
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// 附件定稿 + 入队命令，**一个事务**。
-    ///
-    /// 附件链路应当用它，而不是 `finalize_local_attachment` + `send_attachment_*`：
-    /// 后者是两步，中间崩溃会留下一条已完成、已显示、却没人负责发送的附件。
-    pub async fn finalize_attachment_and_enqueue(
-        &self,
-        message_id: u64,
-        content: String,
-        thumb_status: i32,
-        route_key: String,
-        payload: Vec<u8>,
-    ) -> Result<()> {
+    /// 收藏的表情，新收藏的在前。只在本机。
+    pub async fn favourite_stickers(&self) -> Result<Vec<Sticker>> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::FinalizeAttachmentAndEnqueue {
-                message_id,
-                content,
-                thumb_status,
-                route_key,
-                payload,
-                resp: resp_tx,
-            })
+            .send(Command::ListFavouriteStickers { resp: resp_tx })
             .await
             .map_err(|_| self.actor_channel_error())?;
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn set_message_revoke(
-        &self,
-        message_id: u64,
-        revoked: bool,
-        revoker: Option<u64>,
-    ) -> Result<()> {
+    /// 收藏一个表情，可以来自没装的包（比如别人发的表情消息，见 [`sticker::sticker_from_message`]）。
+    /// 返回是否有变化。
+    pub async fn favourite_sticker(&self, sticker: Sticker) -> Result<bool> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::SetMessageRevoke {
-                message_id,
-                revoked,
-                revoker,
+            .send(Command::FavouriteSticker {
+                sticker,
                 resp: resp_tx,
             })
             .await
@@ -18933,12 +23058,12 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn delete_message_local(&self, message_id: u64) -> Result<Option<StoredMessage>> {
+    pub async fn unfavourite_sticker(&self, sticker_id: String) -> Result<bool> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::DeleteMessageLocal {
-                message_id,
+            .send(Command::UnfavouriteSticker {
+                sticker_id,
                 resp: resp_tx,
             })
             .await
@@ -18946,14 +23071,12 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// 设置本地 channel 隐藏标记（不触达服务端）。返回 true 表示命中行被更新。
-    pub async fn set_channel_hidden(&self, channel_id: u64, hidden: bool) -> Result<bool> {
+    pub async fn get_message_extra(&self, message_id: u64) -> Result<Option<StoredMessageExtra>> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::SetChannelHidden {
-                channel_id,
-                hidden,
+            .send(Command::GetMessageExtra {
+                message_id,
                 resp: resp_tx,
             })
             .await
@@ -18961,13 +23084,18 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// 本地删除 channel：隐藏 + 清空所有相关消息。不触达服务端；附件文件由调用方清理。
-    pub async fn delete_channel_local(&self, channel_id: u64) -> Result<Vec<StoredMessage>> {
+    /// 会话里每个成员读到的 pts（来自 channel_read_cursor 同步与回执回填）。
+    pub async fn list_member_read_cursors(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+    ) -> Result<Vec<MemberReadCursor>> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::DeleteChannelLocal {
+            .send(Command::ListMemberReadCursors {
                 channel_id,
+                channel_type,
                 resp: resp_tx,
             })
             .await
@@ -18975,19 +23103,27 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn edit_message(
+    /// 一条消息的「谁看过」，纯本地计算，离线可用，不发网络。
+    pub async fn message_read_receipts(&self, message_id: u64) -> Result<MessageReadReceipts> {
+        self.read_receipts(message_id, false).await
+    }
+
+    /// 同 [`Self::message_read_receipts`]，但群里有成员本地还没有游标时发一次 READ_LIST
+    /// 回填。已知游标的成员不会因此再查；离线时退化为本地结果。
+    pub async fn backfill_message_read_receipts(
         &self,
         message_id: u64,
-        content: String,
-        edited_at: i32,
-    ) -> Result<()> {
+    ) -> Result<MessageReadReceipts> {
+        self.read_receipts(message_id, true).await
+    }
+
+    async fn read_receipts(&self, message_id: u64, backfill: bool) -> Result<MessageReadReceipts> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::EditMessage {
+            .send(Command::MessageReadReceipts {
                 message_id,
-                content,
-                edited_at,
+                backfill,
                 resp: resp_tx,
             })
             .await
@@ -18995,13 +23131,14 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn set_message_pinned(&self, message_id: u64, is_pinned: bool) -> Result<()> {
+    /// 服务端拒绝了 `edit_message` 的那次编辑时调用：丢掉待确认的本地编辑，界面退回
+    /// 最新的服务端版本（没有就退回原文）。没有待确认编辑时返回 false。
+    pub async fn discard_pending_message_edit(&self, message_id: u64) -> Result<bool> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::SetMessagePinned {
+            .send(Command::DiscardPendingMessageEdit {
                 message_id,
-                is_pinned,
                 resp: resp_tx,
             })
             .await
@@ -19009,11 +23146,12 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn get_message_extra(&self, message_id: u64) -> Result<Option<StoredMessageExtra>> {
+    /// 消息编辑历史，按版本升序：version 0 是原文，之后每次编辑一条。未编辑过返回空。
+    pub async fn list_message_revisions(&self, message_id: u64) -> Result<Vec<MessageRevision>> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::GetMessageExtra {
+            .send(Command::ListMessageRevisions {
                 message_id,
                 resp: resp_tx,
             })
@@ -19042,18 +23180,91 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    /// Get the locally persisted peer read pts for cold start.
-    pub async fn get_peer_read_pts(
-        &self,
-        channel_id: u64,
-        channel_type: i32,
-    ) -> Result<Option<u64>> {
+    /// Get the locally persisted peer read pts for cold start.
+    pub async fn get_peer_read_pts(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+    ) -> Result<Option<u64>> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::GetPeerReadPts {
+                channel_id,
+                channel_type,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    pub async fn get_channel_unread_count(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+    ) -> Result<i32> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::GetChannelUnreadCount {
+                channel_id,
+                channel_type,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    pub async fn get_total_unread_count(&self, exclude_muted: bool) -> Result<i32> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::GetTotalUnreadCount {
+                exclude_muted,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    /// 按筛选条件、排序键和 keyset 游标取一页会话，筛选与排序都在 SQL 里完成。
+    /// 见 [`channel_query`]。
+    pub async fn query_channels(&self, query: ChannelListQuery) -> Result<ChannelListPage> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::QueryChannels {
+                query,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    /// 新建（`input.folder_id` 为 `None`）或整体覆盖一个会话文件夹，按账号存在本地。
+    pub async fn upsert_channel_folder(&self, input: ChannelFolderInput) -> Result<ChannelFolder> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::UpsertChannelFolder {
+                input,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    pub async fn delete_channel_folder(&self, folder_id: u64) -> Result<bool> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::GetPeerReadPts {
-                channel_id,
-                channel_type,
+            .send(Command::DeleteChannelFolder {
+                folder_id,
                 resp: resp_tx,
             })
             .await
@@ -19061,32 +23272,23 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn get_channel_unread_count(
-        &self,
-        channel_id: u64,
-        channel_type: i32,
-    ) -> Result<i32> {
+    pub async fn list_channel_folders(&self) -> Result<Vec<ChannelFolder>> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::GetChannelUnreadCount {
-                channel_id,
-                channel_type,
-                resp: resp_tx,
-            })
+            .send(Command::ListChannelFolders { resp: resp_tx })
             .await
             .map_err(|_| self.actor_channel_error())?;
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
-    pub async fn get_total_unread_count(&self, exclude_muted: bool) -> Result<i32> {
+    /// 按会话偏好聚合的未读：角标数、各 tag 合计、静音但被 @ 的计数。规则见
+    /// [`unread_badge`]。之后的变化通过 [`SdkEvent::BadgeChanged`] 推送。
+    pub async fn get_unread_aggregate(&self) -> Result<UnreadAggregate> {
         self.ensure_running()?;
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(Command::GetTotalUnreadCount {
-                exclude_muted,
-                resp: resp_tx,
-            })
+            .send(Command::GetUnreadAggregate { resp: resp_tx })
             .await
             .map_err(|_| self.actor_channel_error())?;
         resp_rx.await.map_err(|_| self.actor_channel_error())?
@@ -19622,6 +23824,31 @@ impl PrivchatSdk {
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
 
+    /// 当前账号的媒体自动下载策略。没设过时是 [`AutoDownloadPolicy::default`](auto_download::AutoDownloadPolicy)。
+    pub async fn auto_download_policy(&self) -> Result<auto_download::AutoDownloadPolicy> {
+        let raw = self
+            .kv_get_local(auto_download::POLICY_KV_KEY.to_string())
+            .await?;
+        Ok(auto_download::AutoDownloadPolicy::decode(raw.as_deref()))
+    }
+
+    /// 保存媒体自动下载策略（按账号）。对之后落库的消息生效；已经排上的后台下载在下一次
+    /// 网络变化时按新策略重新求值。
+    pub async fn set_auto_download_policy(
+        &self,
+        policy: auto_download::AutoDownloadPolicy,
+    ) -> Result<()> {
+        self.kv_put_local(
+            auto_download::POLICY_KV_KEY.to_string(),
+            serde_json::to_vec(&policy).map_err(|e| {
+                Error::Serialization(format!("encode auto-download policy failed: {e}"))
+            })?,
+        )
+        .await
+    }
+
+    /// `mode` 取 [`unread_badge::notification_mode`] 里的值（FFI 上是
+    /// `ChannelNotificationMode`），不认识的值按 `DEFAULT` 算。
     pub async fn set_channel_notification_mode_pref(
         &self,
         channel_id: u64,
@@ -19834,6 +24061,60 @@ impl PrivchatSdk {
             .map_err(|_| self.actor_channel_error())?;
         resp_rx.await.map_err(|_| self.actor_channel_error())?
     }
+
+    /// 开始把入站帧、RPC 应答和命令流录进 `path`（见 [`trace_recorder`]）。
+    ///
+    /// 返回解密这份 trace 的 key（base64url）。key 只在这里出现一次，SDK 不保存它：
+    /// 宿主要和 trace 文件**分开**交给排查的人。
+    pub async fn start_trace_recording(&self, path: String) -> Result<String> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::StartTraceRecording {
+                path: PathBuf::from(path),
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    /// 停止录制并刷盘，返回写入的记录数（含 header）。没有在录时返回 0。
+    pub async fn stop_trace_recording(&self) -> Result<u64> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::StopTraceRecording { resp: resp_tx })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    /// 回放前选定账号。只给 [`trace_recorder::replay_trace`] 用。
+    pub async fn begin_trace_replay(&self, uid: String) -> Result<()> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::BeginTraceReplay { uid, resp: resp_tx })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
+
+    /// 回放一条录下来的入站帧，返回落库条数。只给 [`trace_recorder::replay_trace`] 用。
+    pub async fn replay_inbound_frame(&self, biz_type: u8, data: Vec<u8>) -> Result<usize> {
+        self.ensure_running()?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(Command::ReplayInboundFrame {
+                biz_type,
+                data,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| self.actor_channel_error())?;
+        resp_rx.await.map_err(|_| self.actor_channel_error())?
+    }
 }
 
 #[cfg(test)]
@@ -20503,6 +24784,7 @@ mod tests {
         config.data_dir = dir.display().to_string();
         let state = State {
             attachment_transfers: Arc::new(crate::AttachmentTransferCounters::default()),
+            metrics: Arc::new(crate::metrics::MetricsRegistry::new()),
             config,
             transport: None,
             transport_events: Arc::new(tokio::sync::Mutex::new(None)),
@@ -20518,6 +24800,9 @@ mod tests {
             ),
             storage,
             download_manager: crate::media_download::DownloadManager::new(),
+            bandwidth: crate::bandwidth::BandwidthLimiter::default(),
+            segmented_uploads: Arc::new(AtomicBool::new(false)),
+            uploads: crate::background_transfer::UploadRegistry::default(),
             actor_tx: {
                 let (tx, _rx) = tokio::sync::mpsc::channel::<Command>(1);
                 tx.downgrade()
@@ -20567,6 +24852,11 @@ mod tests {
             pending_media_jobs: Arc::new(StdMutex::new(HashMap::new())),
             active_subscriptions: HashMap::new(),
             avatar_cache: crate::avatar_cache::AvatarCacheManager::default(),
+            trace_recorder: None,
+            last_unread_aggregate: None,
+            badge_dirty: false,
+            channel_prefs_mirrored_uid: None,
+            auto_downloads: HashMap::new(),
             repair_queue: std::collections::VecDeque::new(),
             repair_seen: std::collections::HashSet::new(),
             repair_backoff: std::collections::HashMap::new(),
@@ -21789,6 +26079,103 @@ mod tests {
         let _ = std::fs::remove_dir_all(dir);
     }
 
+    #[tokio::test(flavor = "current_thread")]
+    async fn unread_aggregate_follows_channel_prefs_and_emits_only_on_change() {
+        let (mut state, dir) = new_seeded_state("unread-aggregate").await;
+        let (event_tx, mut event_rx) = tokio::sync::broadcast::channel::<SdkEvent>(16);
+        state.event_tx = Some(event_tx);
+        for (channel_id, unread_count, mute) in [(94_001, 5, 0), (94_002, 8, 1), (94_003, 4, 0)] {
+            state
+                .storage
+                .upsert_channel(UpsertChannelInput {
+                    channel_id,
+                    channel_type: 2,
+                    channel_name: format!("room-{channel_id}"),
+                    channel_remark: String::new(),
+                    avatar: String::new(),
+                    unread_count,
+                    top: 0,
+                    mute,
+                    last_msg_timestamp: 0,
+                    last_local_message_id: 0,
+                    last_msg_content: String::new(),
+                    version: 1,
+                    peer_user_id: None,
+                })
+                .await
+                .expect("seed channel");
+        }
+        state
+            .storage
+            .record_mention(super::MentionInput {
+                message_id: 1,
+                channel_id: 94_002,
+                channel_type: 2,
+                mentioned_user_id: 10001,
+                sender_id: 20_001,
+                is_mention_all: false,
+                created_at: 1,
+            })
+            .await
+            .expect("record mention");
+        // 不提醒：连 @ 也不进角标，哪怕同时是低优先级。
+        let prefs = super::ChannelPrefsState {
+            notification_mode: crate::unread_badge::notification_mode::NONE,
+            low_priority: true,
+            tags: vec!["work".to_string()],
+            ..Default::default()
+        };
+        state
+            .storage
+            .kv_put(
+                channel_prefs_key(94_003, 2),
+                serde_json::to_vec(&prefs).expect("encode prefs"),
+            )
+            .await
+            .expect("put prefs");
+        let folder = state
+            .storage
+            .upsert_channel_folder(crate::channel_query::ChannelFolderInput {
+                name: "rooms".to_string(),
+                channels: vec![(94_001, 2), (94_003, 2)],
+                ..Default::default()
+            })
+            .await
+            .expect("create folder");
+
+        let aggregate = state.refresh_unread_aggregate().await.expect("aggregate");
+        assert_eq!(
+            aggregate.folders,
+            vec![crate::UnreadFolderTotal {
+                folder_id: folder.folder_id,
+                name: "rooms".to_string(),
+                unread_count: 5 + 4,
+                badge_count: 5,
+            }]
+        );
+        assert_eq!(aggregate.badge_count, 5 + 1);
+        assert_eq!(aggregate.total_unread, 5 + 8 + 4);
+        assert_eq!(aggregate.muted_mention_count, 1);
+        assert_eq!(aggregate.tags.len(), 1);
+        assert_eq!(aggregate.tags[0].unread_count, 4);
+        assert_eq!(aggregate.tags[0].badge_count, 0);
+        match event_rx.try_recv() {
+            Ok(SdkEvent::BadgeChanged { aggregate: sent }) => assert_eq!(sent, aggregate),
+            other => panic!("expected BadgeChanged, got {other:?}"),
+        }
+
+        state
+            .refresh_unread_aggregate()
+            .await
+            .expect("aggregate again");
+        assert!(
+            event_rx.try_recv().is_err(),
+            "unchanged aggregate must not re-emit"
+        );
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn replayed_canonical_message_does_not_emit_duplicate_timeline_update() {
         use privchat_protocol::{
@@ -21968,6 +26355,7 @@ mod tests {
                 "user",
                 "channel_read_cursor",
                 "user_block",
+                "sticker_package",
             ]
         );
         assert!(!State::BOOTSTRAP_ENTITY_ORDER.contains(&"group_member"));
@@ -22374,6 +26762,41 @@ mod tests {
         assert!(matches!(err, Error::Shutdown));
     }
 
+    #[tokio::test(flavor = "current_thread")]
+    async fn metrics_snapshot_counts_cached_presence() {
+        let sdk = PrivchatSdk::new(PrivchatConfig::default());
+        sdk.presence_cache.lock().expect("presence cache").extend(
+            [(1, true), (2, false), (3, true)].map(|(user_id, is_online)| {
+                (
+                    user_id,
+                    PresenceStatus {
+                        user_id,
+                        is_online,
+                        last_seen_at: 0,
+                        device_count: 0,
+                        version: 1,
+                    },
+                )
+            }),
+        );
+        let snapshot = sdk.metrics_snapshot().await.expect("snapshot");
+        let cached = |state: &str| {
+            snapshot
+                .gauges
+                .iter()
+                .find(|g| {
+                    g.name == crate::metrics::PRESENCE_CACHED_USERS
+                        && g.labels
+                            .iter()
+                            .any(|l| l.name == "state" && l.value == state)
+                })
+                .map(|g| g.value)
+        };
+        assert_eq!(cached("online"), Some(2.0));
+        assert_eq!(cached("offline"), Some(1.0));
+        sdk.shutdown().await;
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn network_hint_offline_emits_event_and_blocks_connect() {
         // 指向一个确定关闭的端口。默认配置指的是 127.0.0.1:9001——开发机上
@@ -23713,6 +28136,123 @@ mod tests {
         let _ = std::fs::remove_dir_all(dir);
     }
 
+    #[tokio::test(flavor = "current_thread")]
+    async fn live_queries_follow_real_sdk_events() {
+        let (sdk, dir) = new_seeded_sdk("live-query-events").await;
+        let channel = |channel_id: u64, last_msg_timestamp: i64| UpsertChannelInput {
+            channel_id,
+            channel_type: 2,
+            channel_name: format!("live-{channel_id}"),
+            channel_remark: String::new(),
+            avatar: String::new(),
+            unread_count: 0,
+            top: 0,
+            mute: 0,
+            last_msg_timestamp,
+            last_local_message_id: 0,
+            last_msg_content: String::new(),
+            version: 1,
+            peer_user_id: None,
+        };
+        sdk.upsert_channel(channel(97301, 1_710_600_000_000))
+            .await
+            .expect("seed channel");
+
+        let mut timeline = sdk
+            .subscribe_timeline(97301, 2, 20)
+            .await
+            .expect("subscribe timeline");
+        let mut channels = sdk
+            .subscribe_channel_list(crate::ChannelListQuery::default())
+            .await
+            .expect("subscribe channel list");
+        fn next<T>(batch: Option<Vec<crate::ListDiff<T>>>) -> Vec<crate::ListDiff<T>> {
+            batch.expect("live query still open")
+        }
+        let first = next(
+            tokio::time::timeout(Duration::from_secs(2), timeline.next())
+                .await
+                .expect("timeline snapshot in time"),
+        );
+        assert!(matches!(
+            first.as_slice(),
+            [crate::ListDiff::Reset { items }] if items.is_empty()
+        ));
+        let first = next(
+            tokio::time::timeout(Duration::from_secs(2), channels.next())
+                .await
+                .expect("channel snapshot in time"),
+        );
+        assert!(matches!(
+            first.as_slice(),
+            [crate::ListDiff::Reset { items }]
+                if items.iter().any(|c| c.channel_id == 97301)
+        ));
+
+        // 本地建消息走 actor 发出的 TimelineUpdated，窗口收到一次插入。
+        let message_id = sdk
+            .create_local_message(NewMessage {
+                channel_id: 97301,
+                channel_type: 2,
+                from_uid: 10001,
+                message_type: 0,
+                content: "hello live".to_string(),
+                searchable_word: String::new(),
+                setting: 0,
+                extra: "{}".to_string(),
+                mime_type: None,
+                media_downloaded: false,
+                thumb_status: 0,
+            })
+            .await
+            .expect("create local message");
+        let inserted = next(
+            tokio::time::timeout(Duration::from_secs(2), timeline.next())
+                .await
+                .expect("timeline insert in time"),
+        );
+        assert!(matches!(
+            inserted.as_slice(),
+            [crate::ListDiff::Insert { index: 0, item }] if item.message_id == message_id
+        ));
+
+        // 新会话写进存储后，下一次相关事件把它带进列表。
+        sdk.upsert_channel(channel(97302, 1_710_700_000_000))
+            .await
+            .expect("seed second channel");
+        sdk.emit_event(SdkEvent::TimelineUpdated {
+            channel_id: 97302,
+            channel_type: 2,
+            message_id: 0,
+            reason: "test".to_string(),
+        });
+        let mut seen_new_channel = false;
+        while !seen_new_channel {
+            let batch = next(
+                tokio::time::timeout(Duration::from_secs(2), channels.next())
+                    .await
+                    .expect("channel list change in time"),
+            );
+            seen_new_channel = batch.iter().any(|diff| match diff {
+                crate::ListDiff::Insert { item, .. } => item.channel_id == 97302,
+                crate::ListDiff::Reset { items } => items.iter().any(|c| c.channel_id == 97302),
+                _ => false,
+            });
+        }
+
+        // 取消后句柄收尾；丢弃句柄同样结束维护任务，不影响 SDK 关闭。
+        timeline.cancel();
+        while tokio::time::timeout(Duration::from_secs(2), timeline.next())
+            .await
+            .expect("timeline closes after cancel")
+            .is_some()
+        {}
+        drop(channels);
+
+        sdk.shutdown().await;
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn sdk_channel_methods_prefer_materialized_local_message() {
         let (sdk, dir) = new_seeded_sdk("sdk-channel-materialized-message").await;
@@ -24877,8 +29417,9 @@ mod ack_before_cache_release_tests {
         );
         std::fs::create_dir_all(&dir).expect("create message dir");
         let cache = dir.join("body.sealed");
-        let (first_blob, _, first_sha) =
-            State::seal_once(&cache, b"the picture bytes").expect("seal");
+        let source = dir.join("payload.png");
+        std::fs::write(&source, b"the picture bytes").expect("write source");
+        let first = State::seal_once(&cache, &source, false).expect("seal");
 
         // ---- ack 失败 ----
         arm_ack_failure(&paths.db_path, &uid.to_string());
@@ -24900,10 +29441,15 @@ mod ack_before_cache_release_tests {
         );
 
         // ---- 重试：必须是同一串密文 ----
-        let (retry_blob, _, retry_sha) =
-            State::seal_once(&cache, b"the picture bytes").expect("reseal");
-        assert_eq!(retry_blob, first_blob, "🔴 重试必须上传同一串字节");
-        assert_eq!(retry_sha, first_sha);
+        let sealed_bytes = std::fs::read(&cache).expect("read cache");
+        let retry = State::seal_once(&cache, &source, false).expect("reseal");
+        assert_eq!(
+            std::fs::read(&cache).expect("read cache"),
+            sealed_bytes,
+            "🔴 重试必须上传同一串字节"
+        );
+        assert_eq!(retry.cek_b64, first.cek_b64);
+        assert_eq!(retry.sha256, first.sha256);
 
         // ---- ack 成功之后才轮到清理 ----
         disarm_ack_failure(&paths.db_path, &uid.to_string());
@@ -25073,8 +29619,8 @@ mod attachment_upload_plan_tests {
         /// 每次 prepare 收到的 (摘要, 字节数)。`None` = 这次不参与秒传。
         prepares: Vec<(Option<String>, usize)>,
         claims: usize,
-        /// 每次 upload 的 (token, 上传的 blob, 上传的 CEK)。
-        uploads: Vec<(String, Vec<u8>, String)>,
+        /// 每次 upload 的 (token, 上传密文的摘要, 上传的 CEK)。
+        uploads: Vec<(String, String, String)>,
         already_exists: bool,
         claim_result: Option<Error>,
     }
@@ -25133,7 +29679,7 @@ mod attachment_upload_plan_tests {
         ) -> Result<UploadedFileInfo> {
             self.uploads.push((
                 token.token.clone(),
-                payload.blob.clone(),
+                payload.sha256.clone(),
                 payload.cek_b64.clone(),
             ));
             Ok(uploaded())
@@ -25143,9 +29689,10 @@ mod attachment_upload_plan_tests {
     /// 一次封装的产物。整条链路只有它，两条分支都得用它。
     fn sealed() -> SealedPayload {
         SealedPayload {
-            blob: b"sealed!!".to_vec(),
+            size: 8,
             cek_b64: "cek-1".to_string(),
             sha256: SHA.to_string(),
+            encryption_version: 1,
         }
     }
 
@@ -25164,7 +29711,11 @@ mod attachment_upload_plan_tests {
         assert_eq!(io.claims, 0);
         assert_eq!(
             io.uploads,
-            vec![("token-1".to_string(), payload.blob.clone(), payload.cek_b64)],
+            vec![(
+                "token-1".to_string(),
+                payload.sha256.clone(),
+                payload.cek_b64
+            )],
             "传的必须是封装出来的那串字节"
         );
     }
@@ -25215,7 +29766,7 @@ mod attachment_upload_plan_tests {
             io.uploads,
             vec![(
                 "token-2".to_string(),
-                payload.blob.clone(),
+                payload.sha256.clone(),
                 payload.cek_b64.clone(),
             )],
             "🔴 用第二张 token 传第一次封装的字节和 CEK；第一张已被 claim 消费过"
@@ -25327,9 +29878,10 @@ mod chunked_upload_plan_tests {
 
     fn sealed() -> SealedPayload {
         SealedPayload {
-            blob: b"sealed!!".to_vec(),
+            size: 8,
             cek_b64: "cek-1".to_string(),
             sha256: SHA.to_string(),
+            encryption_version: 1,
         }
     }
 
@@ -25459,6 +30011,17 @@ mod seal_once_tests {
         dir
     }
 
+    /// 把明文写到缓存旁边，再从那个文件封装——生产路径也是从托管正文文件封的。
+    fn seal(cache: &std::path::Path, plaintext: &[u8], segmented: bool) -> Result<SealedPayload> {
+        let source = cache.with_extension("plain");
+        std::fs::write(&source, plaintext).expect("write plaintext");
+        State::seal_once(cache, &source, segmented)
+    }
+
+    fn read(path: &std::path::Path) -> Vec<u8> {
+        std::fs::read(path).expect("read sealed cache")
+    }
+
     /// 🔴 这是秒传能不能对「重试」生效的全部关键。
     ///
     /// 每次重试重新加密的话，随机 CEK/nonce 会产出另一串字节、另一个摘要，预检
@@ -25470,12 +30033,13 @@ mod seal_once_tests {
         let cache = dir.join("body.sealed");
         let plaintext = b"the same picture, sent twice".to_vec();
 
-        let (blob1, cek1, sha1) = State::seal_once(&cache, &plaintext).expect("first seal");
-        let (blob2, cek2, sha2) = State::seal_once(&cache, &plaintext).expect("retry");
+        let first = seal(&cache, &plaintext, false).expect("first seal");
+        let first_bytes = read(&cache);
+        let retry = seal(&cache, &plaintext, false).expect("retry");
 
-        assert_eq!(blob1, blob2, "🔴 重试必须上传同一串字节");
-        assert_eq!(sha1, sha2, "🔴 摘要不同就等于服务端多一份物理文件");
-        assert_eq!(cek1, cek2, "🔴 CEK 换了，接收端就解不开这份密文");
+        assert_eq!(first_bytes, read(&cache), "🔴 重试必须上传同一串字节");
+        assert_eq!(first.sha256, retry.sha256, "🔴 摘要不同就等于服务端多一份物理文件");
+        assert_eq!(first.cek_b64, retry.cek_b64, "🔴 CEK 换了，接收端就解不开这份密文");
     }
 
     /// 不带缓存时每次封装都是新的——这正是为什么必须缓存。
@@ -25484,11 +30048,11 @@ mod seal_once_tests {
         let dir = tmp_dir();
         let plaintext = b"the same picture, sent twice".to_vec();
 
-        let (_, _, sha_a) = State::seal_once(&dir.join("a.sealed"), &plaintext).expect("seal a");
-        let (_, _, sha_b) = State::seal_once(&dir.join("b.sealed"), &plaintext).expect("seal b");
+        let a = seal(&dir.join("a.sealed"), &plaintext, false).expect("seal a");
+        let b = seal(&dir.join("b.sealed"), &plaintext, false).expect("seal b");
 
         assert_ne!(
-            sha_a, sha_b,
+            a.sha256, b.sha256,
             "随机 CEK/nonce 下同一份明文封两次本来就是两串不同的字节"
         );
     }
@@ -25502,21 +30066,21 @@ mod seal_once_tests {
         let dir = tmp_dir();
         let cache = dir.join("body.sealed");
         let plaintext = b"a payload long enough to truncate".to_vec();
-        let (good_blob, _, good_sha) = State::seal_once(&cache, &plaintext).expect("first seal");
+        let good = seal(&cache, &plaintext, false).expect("first seal");
 
         // 写到一半崩了：blob 短了一截，metadata 还是完整的。
-        std::fs::write(&cache, &good_blob[..good_blob.len() / 2]).expect("truncate blob");
+        let blob = read(&cache);
+        std::fs::write(&cache, &blob[..blob.len() / 2]).expect("truncate blob");
 
-        let (blob, _, sha) = State::seal_once(&cache, &plaintext).expect("reseal");
+        let resealed = seal(&cache, &plaintext, false).expect("reseal");
         assert_eq!(
-            sha,
-            State::sha256_hex(&blob),
-            "🔴 返回的摘要必须真的对应返回的字节"
+            resealed.sha256,
+            State::sha256_hex(&read(&cache)),
+            "🔴 返回的摘要必须真的对应缓存里的字节"
         );
-        assert_ne!(sha, good_sha, "截断的缓存不能被当成有效结果沿用");
+        assert_ne!(resealed.sha256, good.sha256, "截断的缓存不能被当成有效结果沿用");
         assert_eq!(
-            blob.len(),
-            good_blob.len(),
+            resealed.size, good.size,
             "重新封装出来的应该是完整密文，不是那半截"
         );
     }
@@ -25532,17 +30096,21 @@ mod seal_once_tests {
         let cache = dir.join("body.sealed");
         let meta_path = cache.with_extension("sealed.json");
 
-        let (_, _, first_sha) = State::seal_once(&cache, b"first").expect("first seal");
+        let first = seal(&cache, b"first", false).expect("first seal");
         let stale_meta = std::fs::read_to_string(&meta_path).expect("read meta");
         // 换了 blob，metadata 留在上一轮。
         State::drop_sealed_cache(&cache);
-        let (_, _, second_sha) = State::seal_once(&cache, b"second").expect("second seal");
-        assert_ne!(first_sha, second_sha);
+        let second = seal(&cache, b"second", false).expect("second seal");
+        assert_ne!(first.sha256, second.sha256);
         std::fs::write(&meta_path, &stale_meta).expect("restore stale meta");
 
-        let (blob, _, sha) = State::seal_once(&cache, b"second").expect("reseal");
-        assert_eq!(sha, State::sha256_hex(&blob), "🔴 摘要必须对应真实字节");
-        assert_ne!(sha, first_sha, "🔴 不能沿用那份对不上的旧 metadata");
+        let resealed = seal(&cache, b"second", false).expect("reseal");
+        assert_eq!(
+            resealed.sha256,
+            State::sha256_hex(&read(&cache)),
+            "🔴 摘要必须对应真实字节"
+        );
+        assert_ne!(resealed.sha256, first.sha256, "🔴 不能沿用那份对不上的旧 metadata");
     }
 
     /// CEK 坏了但摘要还对：必须识破。
@@ -25557,40 +30125,57 @@ mod seal_once_tests {
         let meta_path = cache.with_extension("sealed.json");
         let plaintext = b"a payload sealed under one key".to_vec();
 
-        let (_, good_cek, good_sha) = State::seal_once(&cache, &plaintext).expect("first seal");
+        let good = seal(&cache, &plaintext, false).expect("first seal");
 
         // 换成另一次封装的 CEK：blob 一个字节没动，摘要照旧对得上。
         let other_dir = tmp_dir();
-        let (_, other_cek, _) =
-            State::seal_once(&other_dir.join("other.sealed"), &plaintext).expect("other seal");
-        assert_ne!(good_cek, other_cek);
+        let other = seal(&other_dir.join("other.sealed"), &plaintext, false).expect("other seal");
+        assert_ne!(good.cek_b64, other.cek_b64);
         std::fs::write(
             &meta_path,
-            serde_json::json!({ "cek": other_cek, "sha256": good_sha }).to_string(),
+            serde_json::json!({ "cek": other.cek_b64, "sha256": good.sha256 }).to_string(),
         )
         .expect("swap cek");
 
-        let (blob, cek, sha) = State::seal_once(&cache, &plaintext).expect("reseal");
-        assert_ne!(sha, good_sha, "🔴 CEK 对不上就必须重新封装，不能沿用");
-        assert_eq!(sha, State::sha256_hex(&blob));
+        let resealed = seal(&cache, &plaintext, false).expect("reseal");
+        assert_ne!(resealed.sha256, good.sha256, "🔴 CEK 对不上就必须重新封装，不能沿用");
+        let blob = read(&cache);
+        assert_eq!(resealed.sha256, State::sha256_hex(&blob));
         assert_eq!(
-            crate::attachment_crypto::decrypt_attachment(&blob, &cek).expect("decrypt"),
+            crate::attachment_crypto::decrypt_attachment(&blob, &resealed.cek_b64)
+                .expect("decrypt"),
             plaintext,
             "重新封装的结果必须自洽：这个 CEK 能解开这份密文"
         );
     }
 
+    /// 密文和 CEK 都对，但解出来的不是这次要发的明文（来源文件换了内容）：重封。
+    #[test]
+    fn a_cache_for_different_plaintext_is_resealed() {
+        let dir = tmp_dir();
+        let cache = dir.join("body.sealed");
+        let old = seal(&cache, b"what was picked first", false).expect("first seal");
+
+        let resealed = seal(&cache, b"what is being sent now", false).expect("reseal");
+        assert_ne!(resealed.sha256, old.sha256, "🔴 传上去的必须是这次的内容");
+        assert_eq!(
+            crate::attachment_crypto::decrypt_attachment(&read(&cache), &resealed.cek_b64)
+                .expect("decrypt"),
+            b"what is being sent now"
+        );
+    }
+
     /// metadata 是提交标记：它不在，缓存就不算数。
     #[test]
     fn a_cache_without_its_commit_marker_is_ignored() {
         let dir = tmp_dir();
         let cache = dir.join("body.sealed");
         let plaintext = b"payload".to_vec();
-        let (_, _, good_sha) = State::seal_once(&cache, &plaintext).expect("first seal");
+        let good = seal(&cache, &plaintext, false).expect("first seal");
 
         std::fs::remove_file(cache.with_extension("sealed.json")).expect("drop marker");
-        let (_, _, sha) = State::seal_once(&cache, &plaintext).expect("reseal");
-        assert_ne!(sha, good_sha, "没有 CEK 的密文传上去没人能解开");
+        let resealed = seal(&cache, &plaintext, false).expect("reseal");
+        assert_ne!(resealed.sha256, good.sha256, "没有 CEK 的密文传上去没人能解开");
         assert!(
             cache.with_extension("sealed.json").exists(),
             "重新封装之后标记要补齐"
@@ -25602,13 +30187,91 @@ mod seal_once_tests {
     fn the_cache_is_dropped_once_the_send_completes() {
         let dir = tmp_dir();
         let cache = dir.join("body.sealed");
-        State::seal_once(&cache, b"payload").expect("seal");
+        seal(&cache, b"payload", false).expect("seal");
         assert!(cache.exists() && cache.with_extension("sealed.json").exists());
 
         State::drop_sealed_cache(&cache);
         assert!(!cache.exists(), "🔴 密文副本不能永久留在磁盘上");
         assert!(!cache.with_extension("sealed.json").exists());
     }
+
+    /// 打开分段之后：大文件封成 v2，小文件保持 v1；重试读回时版本跟着缓存走。
+    #[test]
+    fn large_payloads_are_sealed_segmented_when_allowed_and_retries_keep_the_version() {
+        let dir = tmp_dir();
+        let small = seal(&dir.join("small.sealed"), b"tiny", true).expect("seal small");
+        assert_eq!(small.encryption_version, 1);
+
+        let cache = dir.join("body.sealed");
+        let plaintext: Vec<u8> = (0..CHUNKED_UPLOAD_THRESHOLD + 4096)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let first = seal(&cache, &plaintext, true).expect("first seal");
+        assert_eq!(first.encryption_version, 2);
+        let blob = read(&cache);
+        assert_eq!(first.sha256, State::sha256_hex(&blob));
+        assert_eq!(first.size, blob.len() as u64);
+        assert_eq!(
+            crate::attachment_crypto::decrypt_attachment_v2(&blob, &first.cek_b64)
+                .expect("decrypt v2"),
+            plaintext
+        );
+
+        let retry = seal(&cache, &plaintext, true).expect("retry");
+        assert_eq!(retry, first, "🔴 v2 缓存同样必须原样读回");
+    }
+
+    /// 🔴 没打开分段时大文件也封成 v1：老版本的接收端和服务端打不开 v2。
+    #[test]
+    fn large_payloads_stay_whole_until_segmented_uploads_are_enabled() {
+        let dir = tmp_dir();
+        let cache = dir.join("body.sealed");
+        let plaintext: Vec<u8> = (0..CHUNKED_UPLOAD_THRESHOLD + 4096)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let sealed = seal(&cache, &plaintext, false).expect("seal");
+        assert_eq!(sealed.encryption_version, 1);
+        assert_eq!(
+            crate::attachment_crypto::decrypt_attachment(&read(&cache), &sealed.cek_b64)
+                .expect("decrypt v1"),
+            plaintext
+        );
+    }
+
+    /// 分段关掉之后（服务端或对端回退了），之前封好的 v2 缓存不能再发出去：重封成 v1。
+    #[test]
+    fn a_segmented_cache_is_resealed_whole_once_segmented_uploads_are_disabled() {
+        let dir = tmp_dir();
+        let cache = dir.join("body.sealed");
+        let plaintext: Vec<u8> = (0..CHUNKED_UPLOAD_THRESHOLD + 4096)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let segmented = seal(&cache, &plaintext, true).expect("seal v2");
+        assert_eq!(segmented.encryption_version, 2);
+
+        let whole = seal(&cache, &plaintext, false).expect("reseal");
+        assert_eq!(whole.encryption_version, 1, "🔴 关掉之后一律 v1");
+        assert_ne!(whole.sha256, segmented.sha256);
+        assert_eq!(whole.sha256, State::sha256_hex(&read(&cache)));
+    }
+
+    /// 分段格式之前写下的缓存没有 `encryption_version`：必须按 v1 读回，不能丢弃重封。
+    #[test]
+    fn a_cache_written_before_segmented_sealing_reads_back_as_v1() {
+        let dir = tmp_dir();
+        let cache = dir.join("body.sealed");
+        let plaintext = b"sealed by an older build".to_vec();
+        let first = seal(&cache, &plaintext, false).expect("first seal");
+        std::fs::write(
+            cache.with_extension("sealed.json"),
+            serde_json::json!({ "cek": first.cek_b64, "sha256": first.sha256 }).to_string(),
+        )
+        .expect("write legacy meta");
+
+        let retry = seal(&cache, &plaintext, false).expect("retry");
+        assert_eq!(retry, first);
+        assert_eq!(retry.encryption_version, 1);
+    }
 }
 
 /// 附件消息发出去的 wire 正文里到底有什么。
@@ -25639,6 +30302,9 @@ mod attachment_wire_caption_tests {
             thumb_status: 1,
             delivered: false,
             pts: None,
+            revision_count: 0,
+            poll: None,
+            live_location: None,
         }
     }
 
@@ -25779,6 +30445,34 @@ mod wire_display_filename_tests {
     }
 }
 
+#[cfg(test)]
+mod thumbnail_url_request_tests {
+    use super::*;
+
+    /// 边长提示平铺在 get_url 请求体里；不省流量时请求体与协议原样一致。
+    #[test]
+    fn the_edge_hint_is_flattened_and_omitted_when_unset() {
+        let request = |max_edge| ThumbnailUrlRequest {
+            base: FileGetUrlRequest {
+                file_id: 42,
+                user_id: 0,
+            },
+            max_edge,
+        };
+        let hinted = serde_json::to_value(request(Some(DATA_SAVER_THUMBNAIL_MAX_EDGE))).unwrap();
+        assert_eq!(hinted["file_id"], 42);
+        assert_eq!(hinted["max_edge"], 200);
+        assert_eq!(
+            serde_json::to_value(request(None)).unwrap(),
+            serde_json::to_value(FileGetUrlRequest {
+                file_id: 42,
+                user_id: 0,
+            })
+            .unwrap()
+        );
+    }
+}
+
 #[cfg(test)]
 mod attachment_wire_equivalence_tests {
     use super::*;
@@ -25880,6 +30574,7 @@ mod attachment_wire_equivalence_tests {
             mime_type: "image/png",
             width,
             height,
+            duration: None,
             message_type,
             extra,
         });
@@ -25923,6 +30618,7 @@ mod attachment_wire_equivalence_tests {
             mime_type: "image/png",
             width,
             height,
+            duration: None,
             message_type: privchat_protocol::message::ContentMessageType::Image as i32,
             extra: "",
         });
@@ -25961,6 +30657,7 @@ mod attachment_wire_equivalence_tests {
             mime_type: "audio/amr",
             width: None,
             height: None,
+            duration: None,
             message_type: voice,
             extra: r#"{"duration":7}"#,
         });
@@ -25995,6 +30692,8 @@ mod attachment_wire_equivalence_tests {
             // 🔴 extra 里的尺寸必须**覆盖**这里传进来的：视频的权威尺寸来自抽帧那步。
             width: Some(1280),
             height: Some(720),
+            // 内置处理器探测到的时长同理：extra 里有就以 extra 为准。
+            duration: Some(5),
             message_type: video,
             extra,
         });
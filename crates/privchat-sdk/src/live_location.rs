@@ -0,0 +1,437 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 实时位置共享消息。
+//!
+//! 共享本身是**一条**消息（`message_type` = [`LIVE_LOCATION_MESSAGE_TYPE`]）：起点坐标、坐标系、
+//! 开始时间和到期时间放在消息 metadata 里（[`LiveLocationDefinition`]），发出后不变。
+//! 之后的坐标更新不产生新消息，而是改写本地 `live_location_state` 表里挂在这条消息上的
+//! 最新位置（[`LiveLocationState`]），时间线照常收到 `TimelineUpdated`。
+//!
+//! - 自己的更新：本地先写最新位置（`update_pending`），再作为 outbox 里的 RPC 类命令发出
+//!   （`coalesce_key` = `live_location:{message_id}`）。发得太勤的更新不丢，只是推迟到
+//!   上次发出后 [`MIN_UPDATE_INTERVAL_MS`] 再发，排队期间再来的更新覆盖它——服务端只
+//!   收到节流后的最新位置。结束共享同样走这条命令，不节流，并覆盖排着的更新。服务端拒绝
+//!   某一次更新只丢这一次；只有共享在服务端已经不存在（消息删了、无权再推、已结束）时
+//!   本地才跟着结束；
+//! - 别人的更新：推送 / 同步里的 `message_live_location` 实体，带版本号的按版本单调应用；
+//!   结束只进不退。
+//!
+//! 到期不需要谁来通知：`expires_at` 过了就是结束，投影按当前时间算。位置超过
+//! [`STALE_AFTER_MS`] 没更新时投影标 `stale`，UI 据此把位置画成灰色。
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use privchat_protocol::message::ContentMessageType;
+use privchat_protocol::rpc::routes;
+use privchat_protocol::ErrorCode;
+
+use crate::{Error, Result, StoredMessage};
+
+/// 实时位置消息的 `message_type`。
+pub const LIVE_LOCATION_MESSAGE_TYPE: i32 = ContentMessageType::LiveLocation as i32;
+/// 共享时长下限 / 上限（毫秒）。
+pub const MIN_LIVE_LOCATION_DURATION_MS: i64 = 60 * 1000;
+pub const MAX_LIVE_LOCATION_DURATION_MS: i64 = 8 * 60 * 60 * 1000;
+/// 两次发出坐标更新之间至少隔多久。
+pub const MIN_UPDATE_INTERVAL_MS: i64 = 5 * 1000;
+/// 最新位置超过这么久没更新就算过时。
+pub const STALE_AFTER_MS: i64 = 2 * 60 * 1000;
+
+pub(crate) const UPDATE_COMMAND: &str = "live_location";
+pub(crate) const ROUTE_UPDATE: &str = routes::message_live_location::UPDATE;
+
+/// 同一个共享的未发更新互相覆盖。
+pub(crate) fn update_coalesce_key(message_id: u64) -> String {
+    format!("{UPDATE_COMMAND}:{message_id}")
+}
+
+/// 共享的定义，即消息 metadata 的形状。
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LiveLocationDefinition {
+    /// 起点坐标。
+    pub latitude: f64,
+    pub longitude: f64,
+    #[serde(default)]
+    pub coordinate_system: Option<String>,
+    /// 开始 / 到期时间（毫秒）。
+    pub started_at: i64,
+    pub expires_at: i64,
+}
+
+impl LiveLocationDefinition {
+    /// 从消息 metadata 里认出共享定义；缺坐标或到期时间就不是。
+    pub(crate) fn from_value(value: &Value) -> Option<Self> {
+        let obj = value.as_object()?;
+        Some(Self {
+            latitude: obj.get("latitude")?.as_f64()?,
+            longitude: obj.get("longitude")?.as_f64()?,
+            coordinate_system: obj
+                .get("coordinate_system")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            started_at: obj.get("started_at").and_then(Value::as_i64).unwrap_or(0),
+            expires_at: obj.get("expires_at")?.as_i64()?,
+        })
+    }
+}
+
+/// 共享消息的定义：在消息信封的 metadata 里（`extra`），老数据可能直接在 `content` 里。
+pub(crate) fn definition_from_message(message: &StoredMessage) -> Option<LiveLocationDefinition> {
+    if message.message_type != LIVE_LOCATION_MESSAGE_TYPE {
+        return None;
+    }
+    [message.extra.as_str(), message.content.as_str()]
+        .iter()
+        .find_map(|raw| {
+            let value: Value = serde_json::from_str(raw).ok()?;
+            value
+                .get("metadata")
+                .and_then(LiveLocationDefinition::from_value)
+                .or_else(|| LiveLocationDefinition::from_value(&value))
+        })
+}
+
+/// 校验共享时长，返回到期时间。
+pub(crate) fn expires_at_for(started_at: i64, duration_ms: i64) -> Result<i64> {
+    if !(MIN_LIVE_LOCATION_DURATION_MS..=MAX_LIVE_LOCATION_DURATION_MS).contains(&duration_ms) {
+        return Err(Error::InvalidArgument(format!(
+            "live location duration must be {MIN_LIVE_LOCATION_DURATION_MS}..={MAX_LIVE_LOCATION_DURATION_MS} ms, got {duration_ms}"
+        )));
+    }
+    Ok(started_at + duration_ms)
+}
+
+/// 一个位置点。
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LiveLocationPosition {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// 水平精度（米）。
+    #[serde(default)]
+    pub accuracy: Option<f64>,
+    /// 朝向（度，正北为 0，顺时针）。
+    #[serde(default)]
+    pub heading: Option<f64>,
+    /// 定位时间（毫秒）。
+    pub at: i64,
+}
+
+impl LiveLocationPosition {
+    /// 经纬度必须是有限值且在范围内；精度、朝向给了就得合理，朝向规整到 [0, 360)。
+    pub(crate) fn validated(mut self) -> Result<Self> {
+        if !self.latitude.is_finite() || !(-90.0..=90.0).contains(&self.latitude) {
+            return Err(Error::InvalidArgument(format!(
+                "latitude out of range: {}",
+                self.latitude
+            )));
+        }
+        if !self.longitude.is_finite() || !(-180.0..=180.0).contains(&self.longitude) {
+            return Err(Error::InvalidArgument(format!(
+                "longitude out of range: {}",
+                self.longitude
+            )));
+        }
+        if self.accuracy.is_some_and(|a| !a.is_finite() || a < 0.0) {
+            return Err(Error::InvalidArgument("accuracy must be >= 0".to_string()));
+        }
+        if let Some(heading) = self.heading {
+            if !heading.is_finite() {
+                return Err(Error::InvalidArgument("heading must be finite".to_string()));
+            }
+            self.heading = Some(heading.rem_euclid(360.0));
+        }
+        Ok(self)
+    }
+}
+
+/// 共享在本地的可变状态。
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LiveLocationState {
+    /// 最新位置；还没有任何更新时为空，投影回落到定义里的起点。
+    pub position: Option<LiveLocationPosition>,
+    /// 服务端改过的到期时间（提前结束、延长）；0 表示沿用定义。
+    pub expires_at: i64,
+    pub stopped: bool,
+    /// 服务端位置版本；0 表示服务端没给版本。
+    pub version: u64,
+    /// 自己的更新还在出站队列里。
+    pub update_pending: bool,
+}
+
+/// 服务端下发的一次位置：更新 RPC 的响应、推送 / 同步的 `message_live_location`。
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LiveLocationUpdate {
+    #[serde(default)]
+    pub position: Option<LiveLocationPosition>,
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+    #[serde(default)]
+    pub stopped: bool,
+    #[serde(default)]
+    pub version: u64,
+}
+
+impl LiveLocationUpdate {
+    /// 从同步实体的 payload 里取位置；既没有坐标也没有结束标记就不是一次位置更新。
+    pub(crate) fn from_payload(payload: &Value) -> Option<Self> {
+        let f64_field = |keys: &[&str]| keys.iter().find_map(|k| payload.get(*k)?.as_f64());
+        let i64_field = |keys: &[&str]| keys.iter().find_map(|k| payload.get(*k)?.as_i64());
+        let position = match (
+            f64_field(&["latitude", "lat"]),
+            f64_field(&["longitude", "lng", "lon"]),
+        ) {
+            (Some(latitude), Some(longitude)) => LiveLocationPosition {
+                latitude,
+                longitude,
+                accuracy: f64_field(&["accuracy"]),
+                heading: f64_field(&["heading"]),
+                at: i64_field(&["position_at", "updated_at", "at"]).unwrap_or(0),
+            }
+            .validated()
+            .ok(),
+            _ => None,
+        };
+        let stopped = payload
+            .get("stopped")
+            .or_else(|| payload.get("is_stopped"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        if position.is_none() && !stopped {
+            return None;
+        }
+        Some(Self {
+            position,
+            expires_at: i64_field(&["expires_at"]).filter(|&at| at > 0),
+            stopped,
+            version: payload.get("version").and_then(Value::as_u64).unwrap_or(0),
+        })
+    }
+}
+
+/// outbox 里一条共享命令的 payload：一次坐标更新，或结束共享（`position` 为空）。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct LiveLocationCommand {
+    /// 本地消息行 id。
+    pub message_id: u64,
+    pub server_message_id: u64,
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub position: Option<LiveLocationPosition>,
+    pub stop: bool,
+}
+
+/// 一条共享命令发出后的结果。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LiveLocationSettle {
+    /// 服务端接受。
+    Accepted,
+    /// 这一次更新被拒（坐标不合法之类），共享本身还在：丢掉它，后面的更新照发。
+    Dropped,
+    /// 共享在服务端已经结束，本地跟着结束。
+    Ended,
+}
+
+impl LiveLocationSettle {
+    /// 不可重试的拒绝怎么收尾：结束命令被拒也按结束算，免得本地永远停在「共享中」。
+    pub(crate) fn for_rejection(command: &LiveLocationCommand, error: &Error) -> Self {
+        if command.stop || rejection_ends_share(error) {
+            Self::Ended
+        } else {
+            Self::Dropped
+        }
+    }
+}
+
+/// 哪些拒绝说明共享已经不存在：消息没了、不再有权推送、服务端已结束。
+fn rejection_ends_share(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::Server { code, .. }
+            if *code == ErrorCode::ResourceNotFound as u32
+                || *code == ErrorCode::PermissionDenied as u32
+                || *code == ErrorCode::OperationNotAllowed as u32
+    )
+}
+
+/// `routes::message_live_location::UPDATE` 请求。`command_id` 供服务端去重，重试不变。
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct LiveLocationUpdateRequest {
+    pub server_message_id: u64,
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub position: Option<LiveLocationPosition>,
+    pub stop: bool,
+    pub command_id: String,
+}
+
+/// 投影给 UI 的实时位置：定义 + 本地最新位置。
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct LiveLocationView {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub accuracy: Option<f64>,
+    pub heading: Option<f64>,
+    pub coordinate_system: Option<String>,
+    pub started_at: i64,
+    pub expires_at: i64,
+    /// 最新位置的定位时间（毫秒）；还没有更新时是开始时间。
+    pub updated_at: i64,
+    /// 已结束：手动结束或已过到期时间。
+    pub ended: bool,
+    /// 还在共享，但位置超过 [`STALE_AFTER_MS`] 没更新。
+    pub stale: bool,
+    pub update_pending: bool,
+}
+
+pub(crate) fn live_location_view(
+    definition: LiveLocationDefinition,
+    state: Option<&LiveLocationState>,
+    now_ms: i64,
+) -> LiveLocationView {
+    let empty = LiveLocationState::default();
+    let state = state.unwrap_or(&empty);
+    let start = LiveLocationPosition {
+        latitude: definition.latitude,
+        longitude: definition.longitude,
+        accuracy: None,
+        heading: None,
+        at: definition.started_at,
+    };
+    let position = state.position.clone().unwrap_or(start);
+    let expires_at = if state.expires_at > 0 {
+        state.expires_at
+    } else {
+        definition.expires_at
+    };
+    let ended = state.stopped || now_ms >= expires_at;
+    LiveLocationView {
+        latitude: position.latitude,
+        longitude: position.longitude,
+        accuracy: position.accuracy,
+        heading: position.heading,
+        coordinate_system: definition.coordinate_system,
+        started_at: definition.started_at,
+        expires_at,
+        updated_at: position.at,
+        ended,
+        stale: !ended && now_ms - position.at > STALE_AFTER_MS,
+        update_pending: state.update_pending,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definition() -> LiveLocationDefinition {
+        LiveLocationDefinition {
+            latitude: 31.2,
+            longitude: 121.5,
+            coordinate_system: Some("gcj02".to_string()),
+            started_at: 1_000,
+            expires_at: 1_000 + 15 * 60 * 1000,
+        }
+    }
+
+    #[test]
+    fn positions_and_durations_are_validated() {
+        let ok = LiveLocationPosition {
+            latitude: 10.0,
+            longitude: -20.0,
+            heading: Some(-90.0),
+            ..Default::default()
+        }
+        .validated()
+        .expect("valid position");
+        assert_eq!(ok.heading, Some(270.0));
+        let far_north = LiveLocationPosition {
+            latitude: 91.0,
+            ..Default::default()
+        };
+        assert!(far_north.validated().is_err());
+        let nan = LiveLocationPosition {
+            longitude: f64::NAN,
+            ..Default::default()
+        };
+        assert!(nan.validated().is_err());
+        assert!(expires_at_for(0, 1_000).is_err());
+        assert_eq!(
+            expires_at_for(5, MIN_LIVE_LOCATION_DURATION_MS).unwrap(),
+            60_005
+        );
+    }
+
+    #[test]
+    fn view_tracks_latest_position_staleness_and_expiry() {
+        let view = live_location_view(definition(), None, 2_000);
+        assert_eq!((view.latitude, view.updated_at), (31.2, 1_000));
+        assert!(!view.ended && !view.stale);
+
+        let state = LiveLocationState {
+            position: Some(LiveLocationPosition {
+                latitude: 31.3,
+                longitude: 121.6,
+                at: 60_000,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let view = live_location_view(definition(), Some(&state), 60_000 + STALE_AFTER_MS + 1);
+        assert_eq!(view.latitude, 31.3);
+        assert!(view.stale && !view.ended);
+
+        let view = live_location_view(definition(), Some(&state), definition().expires_at);
+        assert!(view.ended && !view.stale);
+    }
+
+    #[test]
+    fn payloads_without_a_position_or_stop_are_ignored() {
+        assert!(LiveLocationUpdate::from_payload(&serde_json::json!({"message_id": 1})).is_none());
+        let stop = LiveLocationUpdate::from_payload(&serde_json::json!({"stopped": true}))
+            .expect("stop update");
+        assert!(stop.stopped && stop.position.is_none());
+        let moved = LiveLocationUpdate::from_payload(&serde_json::json!({
+            "lat": 1.5, "lng": 2.5, "position_at": 9, "version": 3
+        }))
+        .expect("position update");
+        assert_eq!(moved.position.map(|p| (p.latitude, p.at)), Some((1.5, 9)));
+        assert_eq!(moved.version, 3);
+    }
+
+    #[test]
+    fn only_terminal_rejections_end_the_share() {
+        let update = LiveLocationCommand {
+            message_id: 1,
+            server_message_id: 2,
+            channel_id: 3,
+            channel_type: 1,
+            position: Some(LiveLocationPosition::default()),
+            stop: false,
+        };
+        let server = |code: ErrorCode| Error::Server {
+            code: code as u32,
+            message: String::new(),
+        };
+        assert_eq!(
+            LiveLocationSettle::for_rejection(&update, &server(ErrorCode::InvalidParams)),
+            LiveLocationSettle::Dropped
+        );
+        assert_eq!(
+            LiveLocationSettle::for_rejection(&update, &server(ErrorCode::ResourceNotFound)),
+            LiveLocationSettle::Ended
+        );
+        let stop = LiveLocationCommand {
+            position: None,
+            stop: true,
+            ..update
+        };
+        assert_eq!(
+            LiveLocationSettle::for_rejection(&stop, &server(ErrorCode::InvalidParams)),
+            LiveLocationSettle::Ended
+        );
+    }
+}
@@ -0,0 +1,421 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 活查询：在 SDK 里维护一个时间线窗口 / 会话列表视图，存储变化时推送有序增量。
+//!
+//! 宿主原来收到 `TimelineUpdated` / `SyncEntityChanged` 就整页重拉 `list_messages` /
+//! `list_channels`：大群里一条消息就是几百行过一遍 FFI，界面再自己比对哪行变了。
+//! 这里把「重查 + 比对」收进 SDK：
+//!
+//! - 订阅时先拿一次快照，作为第一批增量里的 [`ListDiff::Reset`]；
+//! - 之后只在相关事件到达时重查（一小段时间内的连续事件合并成一次），和上一份结果
+//!   按主键比对，推出 `Remove` / `Insert` / `Update`；
+//! - 增量按顺序应用到上一份列表上就得到新列表；改动比整页还多时直接发 `Reset`。
+//!
+//! 事件总线落后（`Lagged`）时同样重查一次，结果仍然正确，只是可能多一次比对。
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::{broadcast, mpsc, watch};
+
+use crate::{Result, SdkEvent, StoredChannel, StoredMessage};
+
+/// 连续事件的合并窗口：一次 sync 落下几十条消息时只重查一次。
+const COALESCE_WINDOW: Duration = Duration::from_millis(50);
+/// 宿主来不及消费时最多积压的批次；满了维护任务就等着，不会无限占内存。
+pub(crate) const LIVE_QUERY_BUFFER: usize = 16;
+
+/// 一条列表增量。按顺序应用：`index` 指的是应用到这一条时列表里的位置。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ListDiff<T> {
+    /// 整体替换。
+    Reset {
+        items: Vec<T>,
+    },
+    Insert {
+        index: u32,
+        item: T,
+    },
+    /// 同一主键的行内容变了（状态、预览、未读……）。
+    Update {
+        index: u32,
+        item: T,
+    },
+    Remove {
+        index: u32,
+    },
+}
+
+/// 取消一个活查询。可以克隆后交给别的线程；取消后 [`LiveQuery::next`] 返回 `None`。
+#[derive(Debug, Clone)]
+pub struct LiveQueryCancel(Arc<watch::Sender<bool>>);
+
+impl LiveQueryCancel {
+    pub fn cancel(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+/// 一个活查询的订阅句柄。丢弃句柄等同于取消。
+pub struct LiveQuery<T> {
+    rx: mpsc::Receiver<Vec<ListDiff<T>>>,
+    cancel: LiveQueryCancel,
+}
+
+impl<T> LiveQuery<T> {
+    /// 下一批增量。第一批总是一个 `Reset`；查询被取消或 SDK 关闭后返回 `None`。
+    pub async fn next(&mut self) -> Option<Vec<ListDiff<T>>> {
+        self.rx.recv().await
+    }
+
+    pub fn canceller(&self) -> LiveQueryCancel {
+        self.cancel.clone()
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+}
+
+/// 建一个活查询：先把初始快照作为 `Reset` 放进去，返回句柄和交给维护任务的两端。
+pub(crate) fn live_query_channel<T>(
+    initial: Vec<T>,
+) -> (
+    LiveQuery<T>,
+    mpsc::Sender<Vec<ListDiff<T>>>,
+    watch::Receiver<bool>,
+) {
+    let (tx, rx) = mpsc::channel(LIVE_QUERY_BUFFER);
+    let _ = tx.try_send(vec![ListDiff::Reset { items: initial }]);
+    let (cancel_tx, cancel_rx) = watch::channel(false);
+    let query = LiveQuery {
+        rx,
+        cancel: LiveQueryCancel(Arc::new(cancel_tx)),
+    };
+    (query, tx, cancel_rx)
+}
+
+/// 把 `old` 变成 `new` 的有序增量。主键在各自列表里必须唯一。
+pub(crate) fn diff_by_key<T, K, F>(old: &[T], new: &[T], key: F) -> Vec<ListDiff<T>>
+where
+    T: Clone + PartialEq,
+    K: Eq + Hash,
+    F: Fn(&T) -> K,
+{
+    let new_keys: HashSet<K> = new.iter().map(&key).collect();
+    let mut ops = Vec::new();
+    // 先从后往前删掉新列表里没有的行，前面行的下标不受影响。
+    for (index, item) in old.iter().enumerate().rev() {
+        if !new_keys.contains(&key(item)) {
+            ops.push(ListDiff::Remove {
+                index: index as u32,
+            });
+        }
+    }
+    let mut current: Vec<&T> = old
+        .iter()
+        .filter(|item| new_keys.contains(&key(item)))
+        .collect();
+    for (index, item) in new.iter().enumerate() {
+        let item_key = key(item);
+        match current.get(index) {
+            Some(existing) if key(existing) == item_key => {
+                if *existing != item {
+                    current[index] = item;
+                    ops.push(ListDiff::Update {
+                        index: index as u32,
+                        item: item.clone(),
+                    });
+                }
+            }
+            _ => {
+                // 前 index 个位置已经和新列表对齐，同一主键只可能出现在更后面：
+                // 那是一次移动，拆成删除 + 插入。
+                let moved_from = current
+                    .iter()
+                    .skip(index + 1)
+                    .position(|existing| key(existing) == item_key)
+                    .map(|offset| offset + index + 1);
+                if let Some(from) = moved_from {
+                    current.remove(from);
+                    ops.push(ListDiff::Remove { index: from as u32 });
+                }
+                current.insert(index, item);
+                ops.push(ListDiff::Insert {
+                    index: index as u32,
+                    item: item.clone(),
+                });
+            }
+        }
+    }
+    if ops.len() > new.len().max(1) {
+        return vec![ListDiff::Reset {
+            items: new.to_vec(),
+        }];
+    }
+    ops
+}
+
+/// 时间线窗口关心的事件。
+pub(crate) fn timeline_event_relevant(
+    channel_id: u64,
+    channel_type: i32,
+) -> impl Fn(&SdkEvent, &[StoredMessage]) -> bool + Send + Sync + 'static {
+    move |event, window| match event {
+        SdkEvent::TimelineUpdated {
+            channel_id: c,
+            channel_type: t,
+            ..
+        }
+        | SdkEvent::MessageDelivered {
+            channel_id: c,
+            channel_type: t,
+            ..
+        }
+        | SdkEvent::SyncChannelApplied {
+            channel_id: c,
+            channel_type: t,
+            ..
+        } => *c == channel_id && *t == channel_type,
+        SdkEvent::MessageSendStatusChanged { message_id, .. }
+        | SdkEvent::MediaDownloadStateChanged { message_id, .. } => {
+            window.iter().any(|m| m.message_id == *message_id)
+        }
+        SdkEvent::SyncAllChannelsApplied { .. }
+        | SdkEvent::ResumeSyncCompleted { .. }
+        | SdkEvent::BootstrapCompleted { .. } => true,
+        _ => false,
+    }
+}
+
+/// 会话列表视图关心的事件：任一会话的消息、实体（名字 / 头像）、未读、偏好变化。
+pub(crate) fn channel_list_event_relevant(event: &SdkEvent, _view: &[StoredChannel]) -> bool {
+    matches!(
+        event,
+        SdkEvent::TimelineUpdated { .. }
+            | SdkEvent::MessageSendStatusChanged { .. }
+            | SdkEvent::SyncEntityChanged { .. }
+            | SdkEvent::SyncEntitiesApplied { .. }
+            | SdkEvent::SyncChannelApplied { .. }
+            | SdkEvent::SyncAllChannelsApplied { .. }
+            | SdkEvent::BadgeChanged { .. }
+            | SdkEvent::ChannelPrefsChanged { .. }
+            | SdkEvent::ResumeSyncCompleted { .. }
+            | SdkEvent::BootstrapCompleted { .. }
+    )
+}
+
+/// 活查询的维护循环：等相关事件 → 合并 → 重查 → 比对 → 推增量。
+/// 订阅方取消或丢弃句柄、SDK 关闭时退出。
+pub(crate) async fn maintain_live_query<T, K, KeyFn, Fetch, Fut, Relevant>(
+    mut events: broadcast::Receiver<SdkEvent>,
+    tx: mpsc::Sender<Vec<ListDiff<T>>>,
+    mut cancel: watch::Receiver<bool>,
+    mut current: Vec<T>,
+    fetch: Fetch,
+    key: KeyFn,
+    relevant: Relevant,
+) where
+    T: Clone + PartialEq,
+    K: Eq + Hash,
+    KeyFn: Fn(&T) -> K,
+    Fetch: Fn() -> Fut,
+    Fut: Future<Output = Result<Vec<T>>>,
+    Relevant: Fn(&SdkEvent, &[T]) -> bool,
+{
+    loop {
+        tokio::select! {
+            _ = tx.closed() => return,
+            _ = cancel.changed() => return,
+            event = events.recv() => match event {
+                Ok(SdkEvent::ShutdownStarted) | Err(RecvError::Closed) => return,
+                Ok(event) if relevant(&event, &current) => {}
+                Ok(_) => continue,
+                Err(RecvError::Lagged(_)) => {}
+            },
+        }
+        tokio::time::sleep(COALESCE_WINDOW).await;
+        loop {
+            match events.try_recv() {
+                Ok(SdkEvent::ShutdownStarted) | Err(TryRecvError::Closed) => return,
+                Ok(_) | Err(TryRecvError::Lagged(_)) => {}
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+        let next = match fetch().await {
+            Ok(next) => next,
+            Err(e) => {
+                tracing::debug!(error = %e, "live query refresh failed; waiting for next change");
+                continue;
+            }
+        };
+        let diffs = diff_by_key(&current, &next, &key);
+        current = next;
+        if !diffs.is_empty() && tx.send(diffs).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(
+        mut list: Vec<(u32, &'static str)>,
+        diffs: &[ListDiff<(u32, &'static str)>],
+    ) -> Vec<(u32, &'static str)> {
+        for diff in diffs {
+            match diff {
+                ListDiff::Reset { items } => list = items.clone(),
+                ListDiff::Insert { index, item } => list.insert(*index as usize, *item),
+                ListDiff::Update { index, item } => list[*index as usize] = *item,
+                ListDiff::Remove { index } => {
+                    list.remove(*index as usize);
+                }
+            }
+        }
+        list
+    }
+
+    #[test]
+    fn diffs_replay_into_the_new_list() {
+        let old = vec![(1, "a"), (2, "b"), (3, "c"), (4, "d"), (5, "e")];
+        let new = vec![(6, "f"), (1, "a"), (3, "c2"), (2, "b"), (5, "e")];
+        let diffs = diff_by_key(&old, &new, |item| item.0);
+        assert!(!matches!(diffs.first(), Some(ListDiff::Reset { .. })));
+        assert_eq!(apply(old, &diffs), new);
+    }
+
+    #[test]
+    fn a_new_message_on_top_is_a_single_insert() {
+        let old = vec![(2, "b"), (1, "a")];
+        let new = vec![(3, "c"), (2, "b"), (1, "a")];
+        assert_eq!(
+            diff_by_key(&old, &new, |item| item.0),
+            vec![ListDiff::Insert {
+                index: 0,
+                item: (3, "c")
+            }]
+        );
+        assert!(diff_by_key(&new, &new, |item| item.0).is_empty());
+    }
+
+    #[test]
+    fn wholesale_changes_collapse_into_a_reset() {
+        let old = vec![(1, "a"), (2, "b"), (3, "c")];
+        let new = vec![(4, "d"), (5, "e")];
+        assert_eq!(
+            diff_by_key(&old, &new, |item| item.0),
+            vec![ListDiff::Reset { items: new.clone() }]
+        );
+    }
+
+    type Rows = Arc<std::sync::Mutex<Vec<(u32, &'static str)>>>;
+
+    fn spawn_maintain(
+        events: broadcast::Receiver<SdkEvent>,
+        rows: &Rows,
+        initial: Vec<(u32, &'static str)>,
+    ) -> (
+        LiveQuery<(u32, &'static str)>,
+        tokio::task::JoinHandle<()>,
+    ) {
+        let (query, tx, cancel) = live_query_channel(initial.clone());
+        let rows = rows.clone();
+        let fetch = move || {
+            let rows = rows.clone();
+            async move { Ok(rows.lock().unwrap().clone()) }
+        };
+        let task = tokio::spawn(maintain_live_query(
+            events,
+            tx,
+            cancel,
+            initial,
+            fetch,
+            |item: &(u32, &'static str)| item.0,
+            |event: &SdkEvent, _: &[(u32, &'static str)]| {
+                matches!(event, SdkEvent::BootstrapCompleted { .. })
+            },
+        ));
+        (query, task)
+    }
+
+    async fn next_batch(
+        query: &mut LiveQuery<(u32, &'static str)>,
+    ) -> Option<Vec<ListDiff<(u32, &'static str)>>> {
+        tokio::time::timeout(Duration::from_secs(2), query.next())
+            .await
+            .expect("live query batch in time")
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn a_change_between_subscribe_and_snapshot_still_arrives() {
+        let (events_tx, events) = broadcast::channel(16);
+        let rows: Rows = Arc::new(std::sync::Mutex::new(vec![(1, "a")]));
+        let initial = rows.lock().unwrap().clone();
+        // 快照拿完、维护任务还没起来时落下一条新消息：事件已经在订阅上了。
+        rows.lock().unwrap().insert(0, (2, "b"));
+        events_tx
+            .send(SdkEvent::BootstrapCompleted { user_id: 10001 })
+            .unwrap();
+        let (mut query, task) = spawn_maintain(events, &rows, initial.clone());
+
+        let first = next_batch(&mut query).await.expect("initial reset");
+        assert_eq!(first, vec![ListDiff::Reset { items: initial }]);
+        let second = next_batch(&mut query).await.expect("missed change");
+        assert_eq!(
+            second,
+            vec![ListDiff::Insert {
+                index: 0,
+                item: (2, "b")
+            }]
+        );
+
+        // 无关事件不触发重查，哪怕数据已经变了。
+        rows.lock().unwrap()[0] = (2, "b2");
+        events_tx.send(SdkEvent::ResumeSyncStarted).unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), query.next())
+                .await
+                .is_err()
+        );
+        events_tx
+            .send(SdkEvent::BootstrapCompleted { user_id: 10001 })
+            .unwrap();
+        assert_eq!(
+            next_batch(&mut query).await.expect("update"),
+            vec![ListDiff::Update {
+                index: 0,
+                item: (2, "b2")
+            }]
+        );
+
+        query.cancel();
+        assert_eq!(next_batch(&mut query).await, None);
+        tokio::time::timeout(Duration::from_secs(2), task)
+            .await
+            .expect("maintain task ends after cancel")
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn dropping_the_handle_ends_the_maintain_task() {
+        let (_events_tx, events) = broadcast::channel(16);
+        let rows: Rows = Arc::new(std::sync::Mutex::new(vec![(1, "a")]));
+        let (query, task) = spawn_maintain(events, &rows, vec![(1, "a")]);
+        drop(query);
+        tokio::time::timeout(Duration::from_secs(2), task)
+            .await
+            .expect("maintain task ends after the handle is dropped")
+            .unwrap();
+    }
+}
@@ -17,7 +17,7 @@
 
 use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Weak};
 
 fn non_blank(value: Option<&str>) -> Option<&str> {
     value.map(str::trim).filter(|value| !value.is_empty())
@@ -43,12 +43,29 @@ use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
 use hkdf::Hkdf;
 use rand::RngCore;
 use refinery::embed_migrations;
+use rusqlite::types::Value as SqlValue;
 use rusqlite::{params, Connection, OptionalExtension};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
+use crate::channel_query::{
+    ChannelFolder, ChannelFolderInput, ChannelListCursor, ChannelListFilter, ChannelListPage,
+    ChannelListQuery, ChannelPrefRow, ChannelSortKey, ChannelVisibility,
+};
+use crate::live_location::{
+    self, LiveLocationCommand, LiveLocationPosition, LiveLocationSettle, LiveLocationState,
+    LiveLocationUpdate,
+};
+use crate::message_pin::{self, MessagePinCommand, PinnedMessage, RemotePin};
+use crate::poll::{self, PollTally, PollTallyUpdate, PollVoteCommand};
+use crate::push_ingest::PushNotificationContext;
+use crate::sticker::{
+    self, RemoteStickerInstall, Sticker, StickerInstallCommand, StickerInstallState, StickerPackage,
+};
+use crate::unread_badge::{notification_mode, ChannelUnreadRow};
 use crate::{
-    Error, LoginResult, MentionInput, NewMessage, PendingTimelineMutation, Result, SessionSnapshot,
+    Error, LoginResult, MemberReadCursor, MentionInput, MessageReadReceipts, MessageRevision,
+    MessageRevisionInput, NewMessage, PendingTimelineMutation, Result, SessionSnapshot,
     StoredBlacklistEntry, StoredChannel, StoredChannelExtra, StoredChannelMember, StoredFriend,
     StoredGroup, StoredGroupMember, StoredMessage, StoredMessageExtra, StoredMessageReaction,
     StoredReminder, StoredUser, UnreadMentionCount, UpsertBlacklistInput, UpsertChannelExtraInput,
@@ -95,6 +112,275 @@ const UPSERT_USER_SQL: &str = "INSERT INTO user (
         updated_at=excluded.updated_at
      WHERE excluded.version >= user.version";
 
+/// 表情行，列顺序与 [`LocalStore::sticker_row`] 一一对应。
+const STICKER_SELECT: &str = "SELECT sticker_id, package_id, image_url, alt_text, emoji,
+        width, height, mime_type
+     FROM sticker";
+
+/// 会话列表行：`list_channels` 与 `query_channels` 共用的列和 JOIN，各自拼 WHERE / ORDER BY。
+/// 列顺序与 [`channel_list_row`] 一一对应。
+const CHANNEL_LIST_SELECT: &str = "SELECT
+                    c.channel_id,
+                    c.channel_type,
+                    CASE
+                        -- DM 标题：只用真实身份，**任何分支都不回退 uid**
+                        -- （spec CLIENT_GLOBAL_STATE_AND_IDENTITY_STORE_SPEC §27.5）。
+                        -- 名字拿不到就返回空串，由 UI 显示 typed loading 占位，等 user
+                        -- 实体到达后自然刷新——宁可显示「加载中」，也不给用户看一串数字。
+                        WHEN c.channel_type = 1 THEN COALESCE(
+                            (
+                                SELECT COALESCE(
+                                    NULLIF(u.alias, ''),
+                                    NULLIF(u.nickname, ''),
+                                    NULLIF(u.username, '')
+                                )
+                                FROM (
+                                    SELECT COALESCE(
+                                        c.peer_user_id,
+                                        -- 存量兼容：老版本把对端 uid 写进了 channel_name。
+                                        -- 这里只拿它**反推对端身份**去 JOIN，绝不当名字显示。
+                                        CASE
+                                            WHEN c.channel_name GLOB '[0-9]*' AND c.channel_name <> ''
+                                            THEN CAST(c.channel_name AS INTEGER)
+                                            ELSE NULL
+                                        END
+                                    ) AS peer_user_id
+                                ) peer
+                                LEFT JOIN friend f ON f.user_id = peer.peer_user_id
+                                LEFT JOIN \"user\" u ON u.user_id = COALESCE(f.user_id, peer.peer_user_id)
+                                WHERE peer.peer_user_id IS NOT NULL
+                                LIMIT 1
+                            ),
+                            CASE
+                                WHEN NULLIF(c.channel_name, '') IN ('1', '__system_1__') THEN NULL
+                                -- 纯数字的 channel_name 是存量脏数据（老版本写进去的 uid），
+                                -- 不是名字。当成「没有名字」处理。
+                                WHEN c.channel_name GLOB '[0-9]*' THEN NULL
+                                ELSE NULLIF(c.channel_name, '')
+                            END,
+                            ''
+                        )
+                        WHEN c.channel_type = 2 THEN COALESCE(
+                            -- 群名以 group 实体为权威（见上方单条查询同注释）。
+                            (
+                                SELECT NULLIF(g.name, '')
+                                FROM \"group\" g
+                                WHERE g.group_id = c.channel_id
+                                LIMIT 1
+                            ),
+                            NULLIF(c.channel_name, ''),
+                            (
+                                SELECT NULLIF(group_concat(name_part, '、'), '')
+                                FROM (
+                                    SELECT COALESCE(
+                                        NULLIF(gm.alias, ''),
+                                        NULLIF(u.alias, ''),
+                                        NULLIF(u.nickname, ''),
+                                        NULLIF(u.username, ''),
+                                        CAST(gm.user_id AS TEXT)
+                                    ) AS name_part
+                                    FROM group_member gm
+                                    LEFT JOIN \"user\" u ON u.user_id = gm.user_id
+                                    WHERE gm.group_id = c.channel_id
+                                      AND gm.status = 0
+                                    ORDER BY gm.role ASC, gm.joined_at ASC, gm.user_id ASC
+                                    LIMIT 3
+                                )
+                            ),
+                            CAST(c.channel_id AS TEXT)
+                        )
+                        ELSE c.channel_name
+                    END AS resolved_channel_name,
+                    c.channel_remark,
+                    c.avatar,
+                    unread_count, top, mute,
+                    -- 排序键是**最后一条消息的时间**，没有消息就没有时间（spec §16）。
+                    -- 原来兜底到 c.updated_at，而那是「这行本地被写过」的时间：新装或全量
+                    -- 同步会把所有频道的 updated_at 刷成「现在」，于是一堆从来没说过话的
+                    -- 会话集体排到列表最上面、且没有预览——看起来完全像「聊天记录没加载
+                    -- 出来」。生产实测：11 个空 DM 的时间戳落在同一个 25ms 窗口里。
+                    COALESCE(lm.created_at, NULLIF(c.last_msg_timestamp, 0), 0)
+                        AS resolved_last_msg_timestamp,
+                    COALESCE(lm.id, c.last_local_message_id) AS resolved_last_local_message_id,
+                    COALESCE(lm.content, c.last_msg_content, '') AS resolved_last_msg_content,
+                    c.version,
+                    c.updated_at,
+                    CASE WHEN c.channel_type = 1 THEN COALESCE(
+                        c.peer_user_id,
+                        CASE
+                            WHEN c.channel_name GLOB '[0-9]*' AND c.channel_name <> ''
+                            THEN CAST(c.channel_name AS INTEGER)
+                            ELSE NULL
+                        END
+                    ) ELSE NULL END AS peer_user_id,
+                    COALESCE(
+                        (SELECT g.member_count FROM \"group\" g WHERE g.group_id = c.channel_id),
+                        0
+                    ) AS resolved_member_count,
+                    (SELECT u2.user_type FROM \"user\" u2 WHERE u2.user_id = CASE WHEN c.channel_type = 1 THEN COALESCE(
+                        c.peer_user_id,
+                        CASE WHEN c.channel_name GLOB '[0-9]*' AND c.channel_name <> '' THEN CAST(c.channel_name AS INTEGER) ELSE NULL END
+                    ) ELSE NULL END LIMIT 1) AS peer_user_type,
+                    (SELECT NULLIF(u2.username, '') FROM \"user\" u2 WHERE u2.user_id = CASE WHEN c.channel_type = 1 THEN COALESCE(
+                        c.peer_user_id,
+                        CASE WHEN c.channel_name GLOB '[0-9]*' AND c.channel_name <> '' THEN CAST(c.channel_name AS INTEGER) ELSE NULL END
+                    ) ELSE NULL END LIMIT 1) AS peer_username,
+                    (SELECT NULLIF(u2.avatar, '') FROM \"user\" u2 WHERE u2.user_id = CASE WHEN c.channel_type = 1 THEN COALESCE(
+                        c.peer_user_id,
+                        CASE WHEN c.channel_name GLOB '[0-9]*' AND c.channel_name <> '' THEN CAST(c.channel_name AS INTEGER) ELSE NULL END
+                    ) ELSE NULL END LIMIT 1) AS peer_avatar_url,
+                    -- 最后一条消息是否已撤回。原来这两个字段在行映射里被写死成 false，
+                    -- SQL 压根没查——于是撤回消息的会话在列表里没有任何预览：内容被
+                    -- 服务端清空了（spec 的占位契约），而客户端又不知道该显示「已撤回」。
+                    COALESCE((SELECT me.revoke FROM message_extra me WHERE me.message_id = lm.id), 0)
+                        AS resolved_last_msg_revoked
+                 FROM channel c
+                 -- P1-17：last message 三个字段一次取齐（原来每行 3 个相关子查询各扫
+                 -- 一遍索引，低端机上列表刷新的主要成本；且并发写入下三个子查询可能
+                 -- 取到不同消息）。timeline 优先语义不变。
+                 LEFT JOIN message lm ON lm.id = (
+                     SELECT m.id
+                     FROM message m
+                     WHERE m.channel_id = c.channel_id
+                       AND m.channel_type = c.channel_type
+                     -- 同 get_channel_by_id：预览行选择与显示排序同构（spec §2.5）
+                     ORDER BY
+                         CASE WHEN COALESCE(m.server_message_id, 0) <= 0 THEN 1 ELSE 0 END DESC,
+                         m.pts DESC,
+                         m.server_message_id DESC,
+                         m.id DESC
+                     LIMIT 1
+                 )";
+
+fn channel_list_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<StoredChannel> {
+    Ok(StoredChannel {
+        channel_id: row.get::<_, i64>(0)? as u64,
+        channel_type: row.get::<_, i32>(1)?,
+        channel_name: row.get::<_, String>(2)?,
+        channel_remark: row.get::<_, String>(3)?,
+        avatar: row.get::<_, String>(4)?,
+        unread_count: row.get::<_, i32>(5)?,
+        top: row.get::<_, i32>(6)?,
+        mute: row.get::<_, i32>(7)?,
+        last_msg_timestamp: row.get::<_, Option<i64>>(8)?.unwrap_or_default(),
+        last_local_message_id: row.get::<_, Option<i64>>(9)?.unwrap_or_default() as u64,
+        last_msg_content: row.get::<_, String>(10)?,
+        version: row.get::<_, i64>(11)?,
+        updated_at: row.get::<_, i64>(12)?,
+        peer_user_id: row.get::<_, Option<i64>>(13)?.map(|v| v as u64),
+        member_count: row.get::<_, i64>(14)?,
+        peer_user_type: row.get::<_, Option<i32>>(15)?,
+        peer_username: row.get::<_, Option<String>>(16)?,
+        peer_avatar_url: row.get::<_, Option<String>>(17)?,
+        last_message_type: None,
+        last_message_is_revoked: row.get::<_, i32>(18).unwrap_or(0) != 0,
+    })
+}
+
+/// 会话列表实际返回的未读数，与 [`LocalStore::resolve_channel_unread_on_read`] 同一判据：
+/// 本地已读位置（`channel_extra.keep_pts`）覆盖到最新一条消息时为 0，否则就是
+/// `channel.unread_count`。未读筛选、未读排序和游标都按它算，否则列表显示 0 的会话
+/// 还会留在「只看未读」里。需要 `CHANNEL_LIST_SELECT` 的 `c` 别名。
+const RESOLVED_UNREAD_SQL: &str = "(CASE
+        WHEN (SELECT ce.keep_pts FROM channel_extra ce
+              WHERE ce.channel_id = c.channel_id AND ce.channel_type = c.channel_type
+              LIMIT 1)
+             >= NULLIF((SELECT MAX(m.pts) FROM message m
+                        WHERE m.channel_id = c.channel_id AND m.channel_type = c.channel_type), 0)
+        THEN 0
+        ELSE MAX(COALESCE(c.unread_count, 0), 0)
+    END)";
+
+/// 把 [`ChannelListFilter`]（不含 `folder_id`）翻译成 WHERE 子句，参数按占位符顺序追加。
+/// 需要 `CHANNEL_LIST_SELECT` 的 `c` 和 `channel_pref` 的 `p` 两个别名。
+fn push_channel_filter_sql(
+    filter: &ChannelListFilter,
+    uid_i64: i64,
+    clauses: &mut Vec<String>,
+    args: &mut Vec<SqlValue>,
+) {
+    match filter.visibility {
+        ChannelVisibility::Visible => clauses.push(
+            // 与 list_channels 同一判据：零消息私聊不进列表。
+            "(COALESCE(c.is_deleted, 0) = 0
+              AND (c.channel_type <> 1 OR resolved_last_msg_timestamp > 0))"
+                .to_string(),
+        ),
+        ChannelVisibility::Hidden => clauses.push("COALESCE(c.is_deleted, 0) <> 0".to_string()),
+        ChannelVisibility::All => {}
+    }
+    if filter.unread_only {
+        clauses.push(format!("{RESOLVED_UNREAD_SQL} > 0"));
+    }
+    if filter.mentions_only {
+        clauses.push(
+            "EXISTS (
+                SELECT 1 FROM mention mn
+                WHERE mn.channel_id = c.channel_id AND mn.channel_type = c.channel_type
+                  AND mn.mentioned_user_id = ? AND mn.is_read = 0
+            )"
+            .to_string(),
+        );
+        args.push(SqlValue::Integer(uid_i64));
+    }
+    if let Some(favourite) = filter.favourite {
+        clauses.push("COALESCE(p.favourite, 0) = ?".to_string());
+        args.push(SqlValue::Integer(favourite.into()));
+    }
+    if let Some(low_priority) = filter.low_priority {
+        clauses.push("COALESCE(p.low_priority, 0) = ?".to_string());
+        args.push(SqlValue::Integer(low_priority.into()));
+    }
+    if let Some(tag) = filter.tag.as_ref() {
+        clauses.push(
+            "EXISTS (
+                SELECT 1 FROM channel_pref_tag pt
+                WHERE pt.channel_id = c.channel_id AND pt.channel_type = c.channel_type
+                  AND pt.tag = ?
+            )"
+            .to_string(),
+        );
+        args.push(SqlValue::Text(tag.clone()));
+    }
+    if let Some(channel_type) = filter.channel_type {
+        clauses.push("c.channel_type = ?".to_string());
+        args.push(SqlValue::Integer(channel_type.into()));
+    }
+    if let Some(muted) = filter.muted {
+        // 与 unread_badge::channel_is_muted 同一规则。
+        clauses.push(format!(
+            "(CASE COALESCE(p.notification_mode, 0)
+                WHEN {} THEN 0
+                WHEN {} THEN 1
+                WHEN {} THEN 1
+                ELSE COALESCE(c.mute, 0) <> 0
+              END) = ?",
+            notification_mode::ALL,
+            notification_mode::MENTIONS_ONLY,
+            notification_mode::NONE
+        ));
+        args.push(SqlValue::Integer(muted.into()));
+    }
+    if let Some(keyword) = filter.keyword.as_deref().map(str::trim) {
+        if !keyword.is_empty() {
+            let escaped = keyword
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            let pattern = format!("%{escaped}%");
+            clauses.push(
+                "(resolved_channel_name LIKE ? ESCAPE '\\'
+                  OR c.channel_remark LIKE ? ESCAPE '\\'
+                  OR resolved_last_msg_content LIKE ? ESCAPE '\\')"
+                    .to_string(),
+            );
+            for _ in 0..3 {
+                args.push(SqlValue::Text(pattern.clone()));
+            }
+        }
+    }
+}
+
 const K_SCHEMA_VERSION: &[u8] = b"schema_version";
 const K_DEVICE_ID: &[u8] = b"device_id";
 const K_INSTALL_SECRET: &[u8] = b"install_secret";
@@ -131,6 +417,16 @@ struct EncryptedBlob {
     nonce: Vec<u8>,
 }
 
+/// 编辑消息时要读的那条消息的列：原文记成 version 0，频道信息写 message_extra。
+struct RevisionTarget {
+    message_id: u64,
+    channel_id: i64,
+    channel_type: i32,
+    original: String,
+    from_uid: i64,
+    created_at: i64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LocalAccountEntry {
     pub uid: String,
@@ -281,8 +577,16 @@ pub struct LocalStore {
     /// 本进程已经搬过旧 sled 队列的用户。搬运本身幂等，这里只是免得每次
     /// `ensure_user_storage` 都去扫一遍队列目录。
     queues_migrated: Arc<Mutex<HashSet<String>>>,
+    /// 多账号并发时每个账号自己的「当前 uid」（见 [`LocalStore::scoped`]）。`None` 时
+    /// 当前 uid 就是 base 目录下的 `current_user` 文件。
+    uid_scope: Option<UidScope>,
+    /// 同一 base 目录上开出来的所有 scope（弱引用），用来拒绝两个账号实例绑同一个 uid。
+    uid_scopes: Arc<Mutex<Vec<Weak<Mutex<Option<String>>>>>>,
 }
 
+/// 一个账号 actor 的当前 uid，只存在内存里；账号管理器持有同一份来给事件标账号。
+pub(crate) type UidScope = Arc<Mutex<Option<String>>>;
+
 /// 旧 sled 队列搬运的结果。`remaining > 0` 表示还有项没搬走，本进程后续
 /// 触发必须继续尝试——记为完成会把它们晾到下次重启。
 #[derive(Debug, Clone, Copy)]
@@ -294,6 +598,17 @@ pub struct SledQueueMigration {
     pub quarantined: usize,
 }
 
+/// outbox 里一条 RPC 类命令（`message_id` 为空）。`payload` 按 `command_type` 解释。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboxRpcCommand {
+    /// outbox 行 id。
+    pub id: i64,
+    pub command_id: String,
+    pub command_type: String,
+    pub payload: Vec<u8>,
+    pub retry_count: i64,
+}
+
 /// A guard that returns the connection to the cache when dropped
 pub struct ConnGuard<'a> {
     conn: Option<Connection>,
@@ -338,9 +653,39 @@ impl LocalStore {
             global_db: Arc::new(Mutex::new(None)),
             sqlite_conns: Arc::new(Mutex::new(HashMap::new())),
             queues_migrated: Arc::new(Mutex::new(HashSet::new())),
+            uid_scope: None,
+            uid_scopes: Arc::new(Mutex::new(Vec::new())),
         })
     }
 
+    /// 同一 base 目录上再开一个账号：共享 sled / SQLite 句柄（sled 一个进程只能开一次），
+    /// 但当前 uid 只在返回的 scope 里，读写都不碰 `current_user` 文件，
+    /// 几个账号的 storage actor 因此可以同时跑。
+    ///
+    /// 带 uid 开时与 [`Self::save_current_uid`] 一样在 `uid_scopes` 锁下先查后绑：
+    /// 这个 uid 已经绑在别的 scope 上就返回 `InvalidState`。
+    pub(crate) fn scoped(&self, uid: Option<String>) -> Result<(Self, UidScope)> {
+        let mut scopes = self
+            .uid_scopes
+            .lock()
+            .map_err(|_| Error::Storage("lock uid scopes failed".to_string()))?;
+        scopes.retain(|weak| weak.strong_count() > 0);
+        let scope = Arc::new(Mutex::new(None));
+        if let Some(uid) = &uid {
+            Self::check_uid_not_bound(&scopes, &scope, uid)?;
+        }
+        *scope
+            .lock()
+            .map_err(|_| Error::Storage("lock uid scope failed".to_string()))? = uid;
+        scopes.push(Arc::downgrade(&scope));
+        drop(scopes);
+        let store = Self {
+            uid_scope: Some(scope.clone()),
+            ..self.clone()
+        };
+        Ok((store, scope))
+    }
+
     pub fn open_default() -> Result<Self> {
         let base = std::env::var("PRIVCHAT_DATA_DIR")
             .map(PathBuf::from)
@@ -582,6 +927,7 @@ impl LocalStore {
     }
 
     pub fn save_login(&self, uid: &str, login: &LoginResult) -> Result<()> {
+        self.ensure_uid_not_open_elsewhere(uid)?;
         let now = chrono::Utc::now().timestamp_millis();
         let bootstrap_completed = self.load_bootstrap_completed(uid)?;
         let install = self.get_install_state()?;
@@ -767,6 +1113,7 @@ impl LocalStore {
         access_token: &str,
         expires_at: Option<u64>,
     ) -> Result<()> {
+        self.ensure_uid_not_open_elsewhere(uid)?;
         let master_key = self.load_master_key(uid)?;
         let blob =
             self.encrypt_user_blob(uid, "access_token", &master_key, access_token.as_bytes())?;
@@ -1121,7 +1468,10 @@ impl LocalStore {
         // `save_login`, still cleared by `clear_session` / `wipe_user_full`)
         // to keep the patch surface minimal and avoid migration. It is now
         // dead-read; a follow-up cleanup PR can remove it if desired.
-        let active_uid = self.load_current_uid()?;
+        //
+        // With several accounts running (`LocalStore::scoped`) "active" is the
+        // foreground account, i.e. still the file.
+        let active_uid = self.load_foreground_uid()?;
         let mut out = Vec::new();
         for item in accounts.iter() {
             let (k, v) = item.map_err(|e| Error::Storage(format!("iterate accounts: {e}")))?;
@@ -1796,40 +2146,19 @@ impl LocalStore {
                 COALESCE(me.revoke, 0), me.revoker,
                 m.mime_type, m.media_downloaded, m.thumb_status,
                 COALESCE(me.delivered, 0),
-                m.pts
+                m.pts,
+                (SELECT COUNT(*) FROM message_revision mr WHERE mr.message_id = m.id AND mr.version > 0)
+                    + (SELECT COUNT(*) FROM message_revision_pending mp WHERE mp.message_id = m.id),
+                ps.tallies, ps.voter_count, ps.my_votes, ps.vote_pending, ps.closed, ps.version,
+                ll.latitude, ll.longitude, ll.accuracy, ll.heading, ll.position_at,
+                ll.expires_at, ll.stopped, ll.version, ll.update_pending
              FROM message m
              LEFT JOIN message_extra me ON me.message_id = m.id
+             LEFT JOIN poll_state ps ON ps.message_id = m.id
+             LEFT JOIN live_location_state ll ON ll.message_id = m.id
              WHERE m.id = ?1 LIMIT 1",
             params![message_id as i64],
-            |row| {
-                Ok(StoredMessage {
-                    message_id: row.get::<_, i64>(0)? as u64,
-                    server_message_id: row.get::<_, Option<i64>>(1)?.map(|v| v as u64),
-                    local_message_id: row
-                        .get::<_, Option<i64>>(11)?
-                        .filter(|&v| v > 0)
-                        .map(|v| v as u64),
-                    channel_id: row.get::<_, i64>(2)? as u64,
-                    channel_type: row.get::<_, i32>(3)?,
-                    from_uid: row.get::<_, i64>(4)? as u64,
-                    message_type: row.get::<_, i32>(5)?,
-                    content: row.get::<_, String>(6)?,
-                    status: row.get::<_, i32>(7)?,
-                    created_at: row.get::<_, i64>(8)?,
-                    updated_at: row.get::<_, i64>(9)?,
-                    extra: row.get::<_, String>(10)?,
-                    revoked: row.get::<_, i32>(12)? != 0,
-                    revoked_by: row.get::<_, Option<i64>>(13)?.map(|v| v as u64),
-                    mime_type: row.get::<_, Option<String>>(14)?,
-                    media_downloaded: row.get::<_, i32>(15).unwrap_or(0) != 0,
-                    thumb_status: row.get::<_, i32>(16).unwrap_or(0),
-                    delivered: row.get::<_, i32>(17).unwrap_or(0) != 0,
-                    pts: row
-                        .get::<_, Option<i64>>(18)?
-                        .filter(|&v| v > 0)
-                        .map(|v| v as u64),
-                })
-            },
+            Self::stored_message_from_row,
         )
         .optional()
         .map_err(|e| Error::Storage(format!("get message by id: {e}")))
@@ -1854,6 +2183,23 @@ impl LocalStore {
         .map_err(|e| Error::Storage(format!("get message id by server_message_id: {e}")))
     }
 
+    /// 不限会话按 server_message_id 查本地主键。server_message_id 全局唯一
+    /// （撤回同样只按它定位），调用方手里没有会话时用这个，不要逐会话扫消息页。
+    pub fn find_message_id_by_server_message_id(
+        &self,
+        uid: &str,
+        server_message_id: u64,
+    ) -> Result<Option<u64>> {
+        let conn = self.conn_for_user(uid)?;
+        conn.query_row(
+            "SELECT id FROM message WHERE server_message_id = ?1 LIMIT 1",
+            params![server_message_id as i64],
+            |row| Ok(row.get::<_, i64>(0)? as u64),
+        )
+        .optional()
+        .map_err(|e| Error::Storage(format!("find message id by server_message_id: {e}")))
+    }
+
     pub fn set_message_revoke_by_server_message_id(
         &self,
         uid: &str,
@@ -1908,8 +2254,11 @@ impl LocalStore {
         self.get_message_by_id(uid, message_id as u64)
     }
 
-    /// message JOIN message_extra 标准 19 列 → StoredMessage（list_messages /
-    /// list_messages_around 共用；列序固定，新增查询照此 SELECT 列序）。
+    /// message JOIN message_extra 标准 35 列 → StoredMessage（get_message_by_id /
+    /// list_messages / list_messages_around 共用；列序固定，新增查询照此 SELECT 列序）。
+    /// 第 20 列是编辑次数子查询（message_revision 主键索引，逐行 COUNT 不扫表）；
+    /// 第 21-26 列是 LEFT JOIN poll_state，不是投票或还没有票数时全为 NULL；
+    /// 第 27-35 列是 LEFT JOIN live_location_state，同理。
     fn stored_message_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<StoredMessage> {
         Ok(StoredMessage {
             message_id: row.get::<_, i64>(0)? as u64,
@@ -1937,6 +2286,9 @@ impl LocalStore {
                 .get::<_, Option<i64>>(18)?
                 .filter(|&v| v > 0)
                 .map(|v| v as u64),
+            revision_count: row.get::<_, i64>(19)?.max(0) as u32,
+            poll: Self::poll_tally_from_columns(row, 20)?,
+            live_location: Self::live_location_from_columns(row, 26)?,
         })
     }
 
@@ -1947,6 +2299,22 @@ impl LocalStore {
         channel_type: i32,
         limit: usize,
         offset: usize,
+    ) -> Result<Vec<StoredMessage>> {
+        self.list_messages_before(uid, channel_id, channel_type, None, limit, offset)
+    }
+
+    /// 同 [`Self::list_messages`]，但只要本地主键小于 `before_local_id` 的行。
+    ///
+    /// 🔴 过滤必须在 SQL 里做：放到 LIMIT/OFFSET 之后再筛，整页可能被筛空，
+    /// 调用方会误以为已经翻到头。
+    pub fn list_messages_before(
+        &self,
+        uid: &str,
+        channel_id: u64,
+        channel_type: i32,
+        before_local_id: Option<u64>,
+        limit: usize,
+        offset: usize,
     ) -> Result<Vec<StoredMessage>> {
         let conn = self.conn_for_user(uid)?;
         let mut stmt = conn
@@ -1957,10 +2325,18 @@ impl LocalStore {
                     COALESCE(me.revoke, 0), me.revoker,
                     m.mime_type, m.media_downloaded, m.thumb_status,
                     COALESCE(me.delivered, 0),
-                    m.pts
+                    m.pts,
+                    (SELECT COUNT(*) FROM message_revision mr WHERE mr.message_id = m.id AND mr.version > 0)
+                    + (SELECT COUNT(*) FROM message_revision_pending mp WHERE mp.message_id = m.id),
+                    ps.tallies, ps.voter_count, ps.my_votes, ps.vote_pending, ps.closed, ps.version,
+                    ll.latitude, ll.longitude, ll.accuracy, ll.heading, ll.position_at,
+                    ll.expires_at, ll.stopped, ll.version, ll.update_pending
                  FROM message m
                  LEFT JOIN message_extra me ON me.message_id = m.id
+                 LEFT JOIN poll_state ps ON ps.message_id = m.id
+                 LEFT JOIN live_location_state ll ON ll.message_id = m.id
                  WHERE m.channel_id = ?1 AND m.channel_type = ?2
+                   AND (?5 IS NULL OR m.id < ?5)
                  ORDER BY
                      CASE WHEN COALESCE(m.server_message_id, 0) <= 0 THEN 1 ELSE 0 END DESC,
                      m.pts DESC,
@@ -1971,7 +2347,13 @@ impl LocalStore {
             .map_err(|e| Error::Storage(format!("prepare list messages: {e}")))?;
         let rows = stmt
             .query_map(
-                params![channel_id as i64, channel_type, limit as i64, offset as i64],
+                params![
+                    channel_id as i64,
+                    channel_type,
+                    limit as i64,
+                    offset as i64,
+                    before_local_id.map(|id| id as i64)
+                ],
                 Self::stored_message_from_row,
             )
             .map_err(|e| Error::Storage(format!("query list messages: {e}")))?;
@@ -2030,10 +2412,17 @@ impl LocalStore {
                     m.mime_type, m.media_downloaded, m.thumb_status,
                     COALESCE(me.delivered, 0),
                     m.pts,
+                    (SELECT COUNT(*) FROM message_revision mr WHERE mr.message_id = m.id AND mr.version > 0)
+                    + (SELECT COUNT(*) FROM message_revision_pending mp WHERE mp.message_id = m.id),
+                    ps.tallies, ps.voter_count, ps.my_votes, ps.vote_pending, ps.closed, ps.version,
+                    ll.latitude, ll.longitude, ll.accuracy, ll.heading, ll.position_at,
+                    ll.expires_at, ll.stopped, ll.version, ll.update_pending,
                     CASE WHEN COALESCE(m.server_message_id, 0) <= 0 THEN 1 ELSE 0 END AS k1,
                     COALESCE(m.pts, 0) AS k2, COALESCE(m.server_message_id, 0) AS k3, m.id AS k4
              FROM message m
              LEFT JOIN message_extra me ON me.message_id = m.id
+             LEFT JOIN poll_state ps ON ps.message_id = m.id
+             LEFT JOIN live_location_state ll ON ll.message_id = m.id
              WHERE m.channel_id = ?1 AND m.channel_type = ?2 AND ?3 >= 0";
 
         let read_rows = |sql: &str, limit: usize| -> Result<Vec<StoredMessage>> {
@@ -2482,139 +2871,8 @@ impl LocalStore {
         let conn = self.conn_for_user(uid)?;
         let uid_i64 = uid.parse::<i64>().unwrap_or_default();
         let mut stmt = conn
-            .prepare(
-                "SELECT
-                    c.channel_id,
-                    c.channel_type,
-                    CASE
-                        -- DM 标题：只用真实身份，**任何分支都不回退 uid**
-                        -- （spec CLIENT_GLOBAL_STATE_AND_IDENTITY_STORE_SPEC §27.5）。
-                        -- 名字拿不到就返回空串，由 UI 显示 typed loading 占位，等 user
-                        -- 实体到达后自然刷新——宁可显示「加载中」，也不给用户看一串数字。
-                        WHEN c.channel_type = 1 THEN COALESCE(
-                            (
-                                SELECT COALESCE(
-                                    NULLIF(u.alias, ''),
-                                    NULLIF(u.nickname, ''),
-                                    NULLIF(u.username, '')
-                                )
-                                FROM (
-                                    SELECT COALESCE(
-                                        c.peer_user_id,
-                                        -- 存量兼容：老版本把对端 uid 写进了 channel_name。
-                                        -- 这里只拿它**反推对端身份**去 JOIN，绝不当名字显示。
-                                        CASE
-                                            WHEN c.channel_name GLOB '[0-9]*' AND c.channel_name <> ''
-                                            THEN CAST(c.channel_name AS INTEGER)
-                                            ELSE NULL
-                                        END
-                                    ) AS peer_user_id
-                                ) peer
-                                LEFT JOIN friend f ON f.user_id = peer.peer_user_id
-                                LEFT JOIN \"user\" u ON u.user_id = COALESCE(f.user_id, peer.peer_user_id)
-                                WHERE peer.peer_user_id IS NOT NULL
-                                LIMIT 1
-                            ),
-                            CASE
-                                WHEN NULLIF(c.channel_name, '') IN ('1', '__system_1__') THEN NULL
-                                -- 纯数字的 channel_name 是存量脏数据（老版本写进去的 uid），
-                                -- 不是名字。当成「没有名字」处理。
-                                WHEN c.channel_name GLOB '[0-9]*' THEN NULL
-                                ELSE NULLIF(c.channel_name, '')
-                            END,
-                            ''
-                        )
-                        WHEN c.channel_type = 2 THEN COALESCE(
-                            -- 群名以 group 实体为权威（见上方单条查询同注释）。
-                            (
-                                SELECT NULLIF(g.name, '')
-                                FROM \"group\" g
-                                WHERE g.group_id = c.channel_id
-                                LIMIT 1
-                            ),
-                            NULLIF(c.channel_name, ''),
-                            (
-                                SELECT NULLIF(group_concat(name_part, '、'), '')
-                                FROM (
-                                    SELECT COALESCE(
-                                        NULLIF(gm.alias, ''),
-                                        NULLIF(u.alias, ''),
-                                        NULLIF(u.nickname, ''),
-                                        NULLIF(u.username, ''),
-                                        CAST(gm.user_id AS TEXT)
-                                    ) AS name_part
-                                    FROM group_member gm
-                                    LEFT JOIN \"user\" u ON u.user_id = gm.user_id
-                                    WHERE gm.group_id = c.channel_id
-                                      AND gm.status = 0
-                                    ORDER BY gm.role ASC, gm.joined_at ASC, gm.user_id ASC
-                                    LIMIT 3
-                                )
-                            ),
-                            CAST(c.channel_id AS TEXT)
-                        )
-                        ELSE c.channel_name
-                    END AS resolved_channel_name,
-                    c.channel_remark,
-                    c.avatar,
-                    unread_count, top, mute,
-                    -- 排序键是**最后一条消息的时间**，没有消息就没有时间（spec §16）。
-                    -- 原来兜底到 c.updated_at，而那是「这行本地被写过」的时间：新装或全量
-                    -- 同步会把所有频道的 updated_at 刷成「现在」，于是一堆从来没说过话的
-                    -- 会话集体排到列表最上面、且没有预览——看起来完全像「聊天记录没加载
-                    -- 出来」。生产实测：11 个空 DM 的时间戳落在同一个 25ms 窗口里。
-                    COALESCE(lm.created_at, NULLIF(c.last_msg_timestamp, 0), 0)
-                        AS resolved_last_msg_timestamp,
-                    COALESCE(lm.id, c.last_local_message_id) AS resolved_last_local_message_id,
-                    COALESCE(lm.content, c.last_msg_content, '') AS resolved_last_msg_content,
-                    c.version,
-                    c.updated_at,
-                    CASE WHEN c.channel_type = 1 THEN COALESCE(
-                        c.peer_user_id,
-                        CASE
-                            WHEN c.channel_name GLOB '[0-9]*' AND c.channel_name <> ''
-                            THEN CAST(c.channel_name AS INTEGER)
-                            ELSE NULL
-                        END
-                    ) ELSE NULL END AS peer_user_id,
-                    COALESCE(
-                        (SELECT g.member_count FROM \"group\" g WHERE g.group_id = c.channel_id),
-                        0
-                    ) AS resolved_member_count,
-                    (SELECT u2.user_type FROM \"user\" u2 WHERE u2.user_id = CASE WHEN c.channel_type = 1 THEN COALESCE(
-                        c.peer_user_id,
-                        CASE WHEN c.channel_name GLOB '[0-9]*' AND c.channel_name <> '' THEN CAST(c.channel_name AS INTEGER) ELSE NULL END
-                    ) ELSE NULL END LIMIT 1) AS peer_user_type,
-                    (SELECT NULLIF(u2.username, '') FROM \"user\" u2 WHERE u2.user_id = CASE WHEN c.channel_type = 1 THEN COALESCE(
-                        c.peer_user_id,
-                        CASE WHEN c.channel_name GLOB '[0-9]*' AND c.channel_name <> '' THEN CAST(c.channel_name AS INTEGER) ELSE NULL END
-                    ) ELSE NULL END LIMIT 1) AS peer_username,
-                    (SELECT NULLIF(u2.avatar, '') FROM \"user\" u2 WHERE u2.user_id = CASE WHEN c.channel_type = 1 THEN COALESCE(
-                        c.peer_user_id,
-                        CASE WHEN c.channel_name GLOB '[0-9]*' AND c.channel_name <> '' THEN CAST(c.channel_name AS INTEGER) ELSE NULL END
-                    ) ELSE NULL END LIMIT 1) AS peer_avatar_url,
-                    -- 最后一条消息是否已撤回。原来这两个字段在行映射里被写死成 false，
-                    -- SQL 压根没查——于是撤回消息的会话在列表里没有任何预览：内容被
-                    -- 服务端清空了（spec 的占位契约），而客户端又不知道该显示「已撤回」。
-                    COALESCE((SELECT me.revoke FROM message_extra me WHERE me.message_id = lm.id), 0)
-                        AS resolved_last_msg_revoked
-                 FROM channel c
-                 -- P1-17：last message 三个字段一次取齐（原来每行 3 个相关子查询各扫
-                 -- 一遍索引，低端机上列表刷新的主要成本；且并发写入下三个子查询可能
-                 -- 取到不同消息）。timeline 优先语义不变。
-                 LEFT JOIN message lm ON lm.id = (
-                     SELECT m.id
-                     FROM message m
-                     WHERE m.channel_id = c.channel_id
-                       AND m.channel_type = c.channel_type
-                     -- 同 get_channel_by_id：预览行选择与显示排序同构（spec §2.5）
-                     ORDER BY
-                         CASE WHEN COALESCE(m.server_message_id, 0) <= 0 THEN 1 ELSE 0 END DESC,
-                         m.pts DESC,
-                         m.server_message_id DESC,
-                         m.id DESC
-                     LIMIT 1
-                 )
+            .prepare(&format!(
+                "{CHANNEL_LIST_SELECT}
                  -- 零消息 DM 不进会话列表（spec MESSAGE_HISTORY §16）。
                  --
                  -- 好友申请通过时 ensureDirectChannel 会建出 DM，它可能一条消息都没有。
@@ -2629,34 +2887,11 @@ impl LocalStore {
                  WHERE COALESCE(c.is_deleted, 0) = 0
                    AND (c.channel_type <> 1 OR resolved_last_msg_timestamp > 0)
                  ORDER BY c.top DESC, resolved_last_msg_timestamp DESC, c.channel_id DESC
-                 LIMIT ?1 OFFSET ?2",
-            )
+                 LIMIT ?1 OFFSET ?2"
+            ))
             .map_err(|e| Error::Storage(format!("prepare list channels: {e}")))?;
         let rows = stmt
-            .query_map(params![limit as i64, offset as i64], |row| {
-                Ok(StoredChannel {
-                    channel_id: row.get::<_, i64>(0)? as u64,
-                    channel_type: row.get::<_, i32>(1)?,
-                    channel_name: row.get::<_, String>(2)?,
-                    channel_remark: row.get::<_, String>(3)?,
-                    avatar: row.get::<_, String>(4)?,
-                    unread_count: row.get::<_, i32>(5)?,
-                    top: row.get::<_, i32>(6)?,
-                    mute: row.get::<_, i32>(7)?,
-                    last_msg_timestamp: row.get::<_, Option<i64>>(8)?.unwrap_or_default(),
-                    last_local_message_id: row.get::<_, Option<i64>>(9)?.unwrap_or_default() as u64,
-                    last_msg_content: row.get::<_, String>(10)?,
-                    version: row.get::<_, i64>(11)?,
-                    updated_at: row.get::<_, i64>(12)?,
-                    peer_user_id: row.get::<_, Option<i64>>(13)?.map(|v| v as u64),
-                    member_count: row.get::<_, i64>(14)?,
-                    peer_user_type: row.get::<_, Option<i32>>(15)?,
-                    peer_username: row.get::<_, Option<String>>(16)?,
-                    peer_avatar_url: row.get::<_, Option<String>>(17)?,
-                    last_message_type: None,
-                    last_message_is_revoked: row.get::<_, i32>(18).unwrap_or(0) != 0,
-                })
-            })
+            .query_map(params![limit as i64, offset as i64], channel_list_row)
             .map_err(|e| Error::Storage(format!("query list channels: {e}")))?;
 
         let mut out = Vec::new();
@@ -2669,6 +2904,343 @@ impl LocalStore {
         Ok(out)
     }
 
+    /// 带筛选、排序和 keyset 分页的会话列表，见 [`crate::channel_query`]。
+    ///
+    /// 会话偏好从 `channel_pref` 镜像里读；镜像由调用方（actor）保证已回填。
+    pub fn query_channels(&self, uid: &str, query: &ChannelListQuery) -> Result<ChannelListPage> {
+        let conn = self.conn_for_user(uid)?;
+        let uid_i64 = uid.parse::<i64>().unwrap_or_default();
+        let mut clauses: Vec<String> = Vec::new();
+        let mut args: Vec<SqlValue> = Vec::new();
+        push_channel_filter_sql(&query.filter, uid_i64, &mut clauses, &mut args);
+        if let Some(folder_id) = query.filter.folder_id {
+            let folder = Self::load_channel_folder(&conn, folder_id)?
+                .ok_or_else(|| Error::NotFound(format!("channel folder {folder_id}")))?;
+            args.push(SqlValue::Integer(folder_id as i64));
+            let members = "EXISTS (
+                SELECT 1 FROM channel_folder_member fm
+                WHERE fm.folder_id = ? AND fm.channel_id = c.channel_id
+                  AND fm.channel_type = c.channel_type
+            )";
+            let folder_filter = ChannelListFilter {
+                folder_id: None,
+                ..folder.filter
+            };
+            if folder_filter.is_empty() {
+                clauses.push(members.to_string());
+            } else {
+                let mut folder_clauses = Vec::new();
+                push_channel_filter_sql(&folder_filter, uid_i64, &mut folder_clauses, &mut args);
+                clauses.push(format!("({members} OR ({}))", folder_clauses.join(" AND ")));
+            }
+        }
+
+        // 排序键从左到右：置顶、（未读）、最后活动时间、channel_id。全部 DESC，
+        // 于是「在游标之后」就是整组键按行值比较小于游标。
+        let mut sort_keys: Vec<(&str, SqlValue)> = Vec::new();
+        let cursor = query.after.clone().unwrap_or_default();
+        if query.pinned_first {
+            sort_keys.push(("COALESCE(c.top, 0)", SqlValue::Integer(cursor.top.into())));
+        }
+        if query.sort == ChannelSortKey::Unread {
+            sort_keys.push((
+                RESOLVED_UNREAD_SQL,
+                SqlValue::Integer(cursor.unread_count.into()),
+            ));
+        }
+        sort_keys.push((
+            "resolved_last_msg_timestamp",
+            SqlValue::Integer(cursor.last_msg_timestamp),
+        ));
+        sort_keys.push(("c.channel_id", SqlValue::Integer(cursor.channel_id as i64)));
+        let key_exprs: Vec<&str> = sort_keys.iter().map(|(expr, _)| *expr).collect();
+        if query.after.is_some() {
+            let placeholders = vec!["?"; key_exprs.len()].join(", ");
+            clauses.push(format!("({}) < ({placeholders})", key_exprs.join(", ")));
+            args.extend(sort_keys.into_iter().map(|(_, value)| value));
+        }
+        let order_by = key_exprs
+            .iter()
+            .map(|expr| format!("{expr} DESC"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let where_sql = if clauses.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", clauses.join(" AND "))
+        };
+        let limit = query.limit.clamp(1, 500) as usize;
+        // 多取一行判断是否还有下一页。
+        args.push(SqlValue::Integer(limit as i64 + 1));
+
+        let mut stmt = conn
+            .prepare(&format!(
+                "{CHANNEL_LIST_SELECT}
+                 LEFT JOIN channel_pref p
+                    ON p.channel_id = c.channel_id AND p.channel_type = c.channel_type
+                 {where_sql}
+                 ORDER BY {order_by}
+                 LIMIT ?"
+            ))
+            .map_err(|e| Error::Storage(format!("prepare query channels: {e}")))?;
+        let rows = stmt
+            .query_map(rusqlite::params_from_iter(args), channel_list_row)
+            .map_err(|e| Error::Storage(format!("query channels: {e}")))?;
+        let mut channels = Vec::new();
+        for row in rows {
+            channels
+                .push(row.map_err(|e| Error::Storage(format!("decode query channels row: {e}")))?);
+        }
+        let has_more = channels.len() > limit;
+        channels.truncate(limit);
+        // 自愈后的未读数与排序键 RESOLVED_UNREAD_SQL 是同一个值，游标直接取它。
+        for channel in &mut channels {
+            channel.unread_count = self.resolve_channel_unread_on_read(&conn, uid_i64, channel)?;
+        }
+        let next_cursor = if has_more {
+            channels.last().map(|last| ChannelListCursor {
+                top: last.top,
+                unread_count: last.unread_count,
+                last_msg_timestamp: last.last_msg_timestamp,
+                channel_id: last.channel_id,
+            })
+        } else {
+            None
+        };
+        Ok(ChannelListPage {
+            channels,
+            next_cursor,
+        })
+    }
+
+    /// 把一条会话偏好写进 `channel_pref` 镜像（tags 整体替换）。
+    pub(crate) fn upsert_channel_pref(&self, uid: &str, pref: &ChannelPrefRow) -> Result<()> {
+        let mut conn = self.conn_for_user(uid)?;
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("upsert channel pref begin tx: {e}")))?;
+        Self::write_channel_pref(&tx, pref)?;
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("upsert channel pref commit: {e}")))
+    }
+
+    /// 用账号 kv 里的全部会话偏好整体重建 `channel_pref` 镜像。
+    pub(crate) fn replace_channel_prefs(&self, uid: &str, prefs: &[ChannelPrefRow]) -> Result<()> {
+        let mut conn = self.conn_for_user(uid)?;
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("replace channel prefs begin tx: {e}")))?;
+        tx.execute_batch("DELETE FROM channel_pref; DELETE FROM channel_pref_tag;")
+            .map_err(|e| Error::Storage(format!("clear channel prefs: {e}")))?;
+        for pref in prefs {
+            Self::write_channel_pref(&tx, pref)?;
+        }
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("replace channel prefs commit: {e}")))
+    }
+
+    fn write_channel_pref(conn: &Connection, pref: &ChannelPrefRow) -> Result<()> {
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        conn.execute(
+            "INSERT INTO channel_pref (
+                channel_id, channel_type, notification_mode, favourite, low_priority, updated_at
+             ) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(channel_id, channel_type) DO UPDATE SET
+                notification_mode = excluded.notification_mode,
+                favourite = excluded.favourite,
+                low_priority = excluded.low_priority,
+                updated_at = excluded.updated_at",
+            params![
+                pref.channel_id as i64,
+                pref.channel_type,
+                pref.notification_mode,
+                pref.favourite as i32,
+                pref.low_priority as i32,
+                now_ms
+            ],
+        )
+        .map_err(|e| Error::Storage(format!("write channel pref: {e}")))?;
+        conn.execute(
+            "DELETE FROM channel_pref_tag WHERE channel_id = ?1 AND channel_type = ?2",
+            params![pref.channel_id as i64, pref.channel_type],
+        )
+        .map_err(|e| Error::Storage(format!("clear channel pref tags: {e}")))?;
+        for tag in pref.tags.iter().filter(|tag| !tag.is_empty()) {
+            conn.execute(
+                "INSERT OR IGNORE INTO channel_pref_tag (channel_id, channel_type, tag)
+                 VALUES (?1, ?2, ?3)",
+                params![pref.channel_id as i64, pref.channel_type, tag],
+            )
+            .map_err(|e| Error::Storage(format!("write channel pref tag: {e}")))?;
+        }
+        Ok(())
+    }
+
+    /// 新建或整体覆盖一个会话文件夹（手选会话一并替换）。
+    pub fn upsert_channel_folder(
+        &self,
+        uid: &str,
+        input: &ChannelFolderInput,
+    ) -> Result<ChannelFolder> {
+        let filter = serde_json::to_string(&input.filter)
+            .map_err(|e| Error::Serialization(format!("encode channel folder filter: {e}")))?;
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("upsert channel folder begin tx: {e}")))?;
+        let folder_id = match input.folder_id {
+            Some(folder_id) => {
+                let updated = tx
+                    .execute(
+                        "UPDATE channel_folder
+                         SET name = ?1, sort_order = ?2, filter = ?3, updated_at = ?4
+                         WHERE folder_id = ?5",
+                        params![
+                            input.name,
+                            input.sort_order,
+                            filter,
+                            now_ms,
+                            folder_id as i64
+                        ],
+                    )
+                    .map_err(|e| Error::Storage(format!("update channel folder: {e}")))?;
+                if updated == 0 {
+                    return Err(Error::NotFound(format!("channel folder {folder_id}")));
+                }
+                folder_id
+            }
+            None => {
+                tx.execute(
+                    "INSERT INTO channel_folder (name, sort_order, filter, created_at, updated_at)
+                     VALUES (?1, ?2, ?3, ?4, ?4)",
+                    params![input.name, input.sort_order, filter, now_ms],
+                )
+                .map_err(|e| Error::Storage(format!("insert channel folder: {e}")))?;
+                tx.last_insert_rowid() as u64
+            }
+        };
+        tx.execute(
+            "DELETE FROM channel_folder_member WHERE folder_id = ?1",
+            params![folder_id as i64],
+        )
+        .map_err(|e| Error::Storage(format!("clear channel folder members: {e}")))?;
+        for (channel_id, channel_type) in &input.channels {
+            tx.execute(
+                "INSERT OR IGNORE INTO channel_folder_member (folder_id, channel_id, channel_type)
+                 VALUES (?1, ?2, ?3)",
+                params![folder_id as i64, *channel_id as i64, channel_type],
+            )
+            .map_err(|e| Error::Storage(format!("insert channel folder member: {e}")))?;
+        }
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("upsert channel folder commit: {e}")))?;
+        Self::load_channel_folder(&conn, folder_id)?
+            .ok_or_else(|| Error::NotFound(format!("channel folder {folder_id}")))
+    }
+
+    /// 删除文件夹；不存在时返回 `false`。文件夹里的会话本身不受影响。
+    pub fn delete_channel_folder(&self, uid: &str, folder_id: u64) -> Result<bool> {
+        let mut conn = self.conn_for_user(uid)?;
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("delete channel folder begin tx: {e}")))?;
+        tx.execute(
+            "DELETE FROM channel_folder_member WHERE folder_id = ?1",
+            params![folder_id as i64],
+        )
+        .map_err(|e| Error::Storage(format!("delete channel folder members: {e}")))?;
+        let deleted = tx
+            .execute(
+                "DELETE FROM channel_folder WHERE folder_id = ?1",
+                params![folder_id as i64],
+            )
+            .map_err(|e| Error::Storage(format!("delete channel folder: {e}")))?;
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("delete channel folder commit: {e}")))?;
+        Ok(deleted > 0)
+    }
+
+    /// 按 `sort_order`、`folder_id` 升序。
+    pub fn list_channel_folders(&self, uid: &str) -> Result<Vec<ChannelFolder>> {
+        let conn = self.conn_for_user(uid)?;
+        let folder_ids = {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT folder_id FROM channel_folder ORDER BY sort_order ASC, folder_id ASC",
+                )
+                .map_err(|e| Error::Storage(format!("prepare list channel folders: {e}")))?;
+            let rows = stmt
+                .query_map([], |row| row.get::<_, i64>(0))
+                .map_err(|e| Error::Storage(format!("query list channel folders: {e}")))?;
+            let mut out = Vec::new();
+            for row in rows {
+                out.push(
+                    row.map_err(|e| Error::Storage(format!("decode channel folder id: {e}")))?,
+                );
+            }
+            out
+        };
+        let mut out = Vec::with_capacity(folder_ids.len());
+        for folder_id in folder_ids {
+            if let Some(folder) = Self::load_channel_folder(&conn, folder_id as u64)? {
+                out.push(folder);
+            }
+        }
+        Ok(out)
+    }
+
+    fn load_channel_folder(conn: &Connection, folder_id: u64) -> Result<Option<ChannelFolder>> {
+        let row = conn
+            .query_row(
+                "SELECT name, sort_order, filter, created_at, updated_at
+                 FROM channel_folder WHERE folder_id = ?1",
+                params![folder_id as i64],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, i32>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, i64>(3)?,
+                        row.get::<_, i64>(4)?,
+                    ))
+                },
+            )
+            .optional()
+            .map_err(|e| Error::Storage(format!("load channel folder: {e}")))?;
+        let Some((name, sort_order, filter, created_at, updated_at)) = row else {
+            return Ok(None);
+        };
+        let mut stmt = conn
+            .prepare(
+                "SELECT channel_id, channel_type FROM channel_folder_member
+                 WHERE folder_id = ?1
+                 ORDER BY channel_id ASC, channel_type ASC",
+            )
+            .map_err(|e| Error::Storage(format!("prepare channel folder members: {e}")))?;
+        let rows = stmt
+            .query_map(params![folder_id as i64], |row| {
+                Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i32>(1)?))
+            })
+            .map_err(|e| Error::Storage(format!("query channel folder members: {e}")))?;
+        let mut channels = Vec::new();
+        for row in rows {
+            channels.push(
+                row.map_err(|e| Error::Storage(format!("decode channel folder member: {e}")))?,
+            );
+        }
+        Ok(Some(ChannelFolder {
+            folder_id,
+            name,
+            sort_order,
+            // 解不开（比如将来的版本写入了不认识的字段组合）就退化成只收手选的会话。
+            filter: serde_json::from_str(&filter).unwrap_or_default(),
+            channels,
+            created_at,
+            updated_at,
+        }))
+    }
+
     /// Stable, lightweight pagination for anti-entropy. Unlike the UI channel
     /// list this ordering does not change when new messages update previews.
     pub fn list_channel_identifiers_after(
@@ -2749,6 +3321,9 @@ impl LocalStore {
                         thumb_status: 0,
                         delivered: false,
                         pts: None,
+                        revision_count: 0,
+                        poll: None,
+                        live_location: None,
                     })
                 })
                 .map_err(|e| Error::Storage(format!("query channel messages: {e}")))?;
@@ -2779,6 +3354,39 @@ impl LocalStore {
             params![channel_id as i64],
         )
         .map_err(|e| Error::Storage(format!("delete_channel_local message_reaction: {e}")))?;
+        tx.execute(
+            "DELETE FROM message_revision
+             WHERE message_id IN (SELECT id FROM message WHERE channel_id = ?1)",
+            params![channel_id as i64],
+        )
+        .map_err(|e| Error::Storage(format!("delete_channel_local message_revision: {e}")))?;
+        tx.execute(
+            "DELETE FROM message_revision_pending
+             WHERE message_id IN (SELECT id FROM message WHERE channel_id = ?1)",
+            params![channel_id as i64],
+        )
+        .map_err(|e| {
+            Error::Storage(format!(
+                "delete_channel_local message_revision_pending: {e}"
+            ))
+        })?;
+        tx.execute(
+            "DELETE FROM poll_state
+             WHERE message_id IN (SELECT id FROM message WHERE channel_id = ?1)",
+            params![channel_id as i64],
+        )
+        .map_err(|e| Error::Storage(format!("delete_channel_local poll_state: {e}")))?;
+        tx.execute(
+            "DELETE FROM live_location_state
+             WHERE message_id IN (SELECT id FROM message WHERE channel_id = ?1)",
+            params![channel_id as i64],
+        )
+        .map_err(|e| Error::Storage(format!("delete_channel_local live_location_state: {e}")))?;
+        tx.execute(
+            "DELETE FROM message_pin WHERE channel_id = ?1",
+            params![channel_id as i64],
+        )
+        .map_err(|e| Error::Storage(format!("delete_channel_local message_pin: {e}")))?;
         tx.execute(
             "DELETE FROM mention WHERE channel_id = ?1",
             params![channel_id as i64],
@@ -2789,6 +3397,13 @@ impl LocalStore {
             params![channel_id as i64],
         )
         .map_err(|e| Error::Storage(format!("delete_channel_local reminder: {e}")))?;
+        // 成员游标只服务于本会话的已读成员；会话删了还留着，重新加入时旧位置会把
+        // 新消息错判成「已读」。
+        tx.execute(
+            "DELETE FROM member_read_cursor WHERE channel_id = ?1",
+            params![channel_id as i64],
+        )
+        .map_err(|e| Error::Storage(format!("delete_channel_local member_read_cursor: {e}")))?;
         tx.execute(
             "DELETE FROM message WHERE channel_id = ?1",
             params![channel_id as i64],
@@ -2799,6 +3414,7 @@ impl LocalStore {
         Ok(messages)
     }
 
+    /// 改这里的判据要同步改 `RESOLVED_UNREAD_SQL`（会话列表的未读筛选 / 排序用它）。
     fn resolve_channel_unread_on_read(
         &self,
         conn: &Connection,
@@ -2978,17 +3594,141 @@ impl LocalStore {
         Ok(pts.filter(|&v| v > 0).map(|v| v as u64))
     }
 
-    pub fn upsert_user(&self, uid: &str, input: &UpsertUserInput) -> Result<()> {
+    /// 推进某成员的已读游标（单调取 MAX）。返回游标是否真的前进了。
+    pub fn save_member_read_pts(
+        &self,
+        uid: &str,
+        channel_id: u64,
+        channel_type: i32,
+        reader_id: u64,
+        read_pts: u64,
+    ) -> Result<bool> {
+        if read_pts == 0 {
+            return Ok(false);
+        }
         let conn = self.conn_for_user(uid)?;
-        Self::execute_upsert_user(&conn, input)?;
-        Ok(())
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let changed = conn
+            .execute(
+                "INSERT INTO member_read_cursor (channel_id, channel_type, reader_id, read_pts, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT(channel_id, channel_type, reader_id) DO UPDATE SET
+                    read_pts = excluded.read_pts,
+                    updated_at = excluded.updated_at
+                 WHERE excluded.read_pts > member_read_cursor.read_pts",
+                params![
+                    channel_id as i64,
+                    channel_type,
+                    reader_id as i64,
+                    read_pts as i64,
+                    now_ms
+                ],
+            )
+            .map_err(|e| Error::Storage(format!("save_member_read_pts: {e}")))?;
+        Ok(changed > 0)
     }
 
-    /// Apply one authoritative user-sync page in one SQLite transaction.
-    ///
-    /// A fresh large account can contain thousands of related users. Committing
-    /// every row separately monopolizes the DB actor for minutes and prevents
-    /// channels/messages from being read after `CriticalReady`.
+    pub fn list_member_read_cursors(
+        &self,
+        uid: &str,
+        channel_id: u64,
+        channel_type: i32,
+    ) -> Result<Vec<MemberReadCursor>> {
+        let conn = self.conn_for_user(uid)?;
+        let mut stmt = conn
+            .prepare(
+                "SELECT channel_id, channel_type, reader_id, read_pts, updated_at
+                 FROM member_read_cursor
+                 WHERE channel_id = ?1 AND channel_type = ?2
+                 ORDER BY read_pts DESC, reader_id ASC",
+            )
+            .map_err(|e| Error::Storage(format!("prepare list member_read_cursor: {e}")))?;
+        let rows = stmt
+            .query_map(params![channel_id as i64, channel_type], |row| {
+                Ok(MemberReadCursor {
+                    channel_id: row.get::<_, i64>(0)? as u64,
+                    channel_type: row.get::<_, i32>(1)?,
+                    reader_id: row.get::<_, i64>(2)? as u64,
+                    read_pts: row.get::<_, i64>(3)? as u64,
+                    updated_at: row.get::<_, i64>(4)?,
+                })
+            })
+            .map_err(|e| Error::Storage(format!("query list member_read_cursor: {e}")))?;
+        let mut out = Vec::new();
+        for row in rows {
+            out.push(
+                row.map_err(|e| Error::Storage(format!("decode member_read_cursor row: {e}")))?,
+            );
+        }
+        Ok(out)
+    }
+
+    /// 本地算一条消息的「谁看过」：游标 >= 消息 pts 的成员就是已读。群会话顺带列出
+    /// group_member 里还没有游标的人，调用方据此决定要不要发 RPC 回填。
+    /// 消息不存在返回 None。
+    pub fn get_message_read_receipts(
+        &self,
+        uid: &str,
+        message_id: u64,
+    ) -> Result<Option<MessageReadReceipts>> {
+        let Some(message) = self.get_message_by_id(uid, message_id)? else {
+            return Ok(None);
+        };
+        let pts = message.pts.unwrap_or(0);
+        let mut receipts = MessageReadReceipts {
+            message_id,
+            channel_id: message.channel_id,
+            channel_type: message.channel_type,
+            pts,
+            readers: Vec::new(),
+            unknown_member_ids: Vec::new(),
+        };
+        if pts == 0 {
+            return Ok(Some(receipts));
+        }
+        let self_uid = uid.parse::<u64>().unwrap_or(0);
+        let cursors =
+            self.list_member_read_cursors(uid, message.channel_id, message.channel_type)?;
+        let known: HashSet<u64> = cursors.iter().map(|c| c.reader_id).collect();
+        receipts.readers = cursors
+            .into_iter()
+            .filter(|c| c.read_pts >= pts && c.reader_id != message.from_uid)
+            .collect();
+        if message.channel_type == 2 {
+            let conn = self.conn_for_user(uid)?;
+            let mut stmt = conn
+                .prepare(
+                    "SELECT user_id FROM group_member
+                     WHERE group_id = ?1 AND status = 0
+                     ORDER BY user_id ASC",
+                )
+                .map_err(|e| Error::Storage(format!("prepare read receipt members: {e}")))?;
+            let members = stmt
+                .query_map(params![message.channel_id as i64], |row| {
+                    row.get::<_, i64>(0).map(|v| v as u64)
+                })
+                .map_err(|e| Error::Storage(format!("query read receipt members: {e}")))?
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|e| Error::Storage(format!("decode read receipt member: {e}")))?;
+            receipts.unknown_member_ids = members
+                .into_iter()
+                .filter(|id| *id != message.from_uid && *id != self_uid && !known.contains(id))
+                .collect();
+        }
+        Ok(Some(receipts))
+    }
+
+    pub fn upsert_user(&self, uid: &str, input: &UpsertUserInput) -> Result<()> {
+        let conn = self.conn_for_user(uid)?;
+        Self::execute_upsert_user(&conn, input)?;
+        Ok(())
+    }
+
+    /// Apply one authoritative user-sync page in one SQLite transaction.
+    ///
+    /// A fresh large account can contain thousands of related users. Committing
+    /// every row separately monopolizes the DB actor for minutes and prevents
+    /// channels/messages from being read after `CriticalReady`.
     pub fn batch_upsert_users(&self, uid: &str, inputs: &[UpsertUserInput]) -> Result<()> {
         if inputs.is_empty() {
             return Ok(());
@@ -4310,6 +5050,22 @@ impl LocalStore {
         })
     }
 
+    /// 还没送达的出站命令数，以及其中最老一条的 `created_at`（毫秒）。指标用。
+    pub fn outbox_stats(&self, uid: &str) -> Result<(u64, Option<i64>)> {
+        let conn = self.conn_for_user(uid)?;
+        conn.query_row(
+            "SELECT COUNT(*), MIN(created_at) FROM outbox WHERE status = 'pending'",
+            [],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?.max(0) as u64,
+                    row.get::<_, Option<i64>>(1)?,
+                ))
+            },
+        )
+        .map_err(|e| Error::Storage(format!("outbox stats: {e}")))
+    }
+
     /// 到期的出站命令，最老的先来。
     /// `(local_message_id, command_type, channel_id, payload, route_key, retry_count)`
     pub fn outbox_peek(
@@ -4483,6 +5239,108 @@ impl LocalStore {
         Ok(())
     }
 
+    /// 入队一条 RPC 类命令（在调用方的事务里）。带 `coalesce_key` 时先删掉同一目标
+    /// 还没发出的旧命令：离线时改了几次，只发最后一次。
+    fn outbox_insert_rpc(
+        tx: &Connection,
+        command_id: &str,
+        command_type: &str,
+        coalesce_key: Option<&str>,
+        channel_id: Option<u64>,
+        payload: &[u8],
+        now_ms: i64,
+    ) -> Result<()> {
+        if let Some(key) = coalesce_key {
+            tx.execute(
+                "DELETE FROM outbox
+                 WHERE coalesce_key = ?1 AND message_id IS NULL AND status = 'pending'",
+                params![key],
+            )
+            .map_err(|e| Error::Storage(format!("outbox coalesce: {e}")))?;
+        }
+        tx.execute(
+            "INSERT INTO outbox
+                 (command_id, command_type, message_id, coalesce_key, channel_id, payload,
+                  status, retry_count, next_attempt_at, created_at, updated_at)
+             VALUES (?1, ?2, NULL, ?3, ?4, ?5, 'pending', 0, 0, ?6, ?6)",
+            params![
+                command_id,
+                command_type,
+                coalesce_key,
+                channel_id.map(|v| v as i64),
+                payload,
+                now_ms
+            ],
+        )
+        .map_err(|e| Error::Storage(format!("outbox insert rpc command: {e}")))?;
+        Ok(())
+    }
+
+    /// 到期的 RPC 类出站命令，最老的先来。
+    pub fn outbox_peek_rpc(
+        &self,
+        uid: &str,
+        limit: usize,
+        now_ms: i64,
+    ) -> Result<Vec<OutboxRpcCommand>> {
+        let conn = self.conn_for_user(uid)?;
+        let mut stmt = conn
+            .prepare(
+                "SELECT id, command_id, command_type, payload, retry_count
+                 FROM outbox
+                 WHERE message_id IS NULL AND status = 'pending' AND next_attempt_at <= ?1
+                 ORDER BY created_at ASC, id ASC
+                 LIMIT ?2",
+            )
+            .map_err(|e| Error::Storage(format!("outbox peek rpc prepare: {e}")))?;
+        let rows = stmt
+            .query_map(params![now_ms, limit as i64], |row| {
+                Ok(OutboxRpcCommand {
+                    id: row.get(0)?,
+                    command_id: row.get(1)?,
+                    command_type: row.get(2)?,
+                    payload: row.get(3)?,
+                    retry_count: row.get(4)?,
+                })
+            })
+            .map_err(|e| Error::Storage(format!("outbox peek rpc query: {e}")))?
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| Error::Storage(format!("outbox peek rpc collect: {e}")))?;
+        Ok(rows)
+    }
+
+    /// RPC 类命令发送失败：记一次重试与下次时间。行保留。
+    pub fn outbox_rpc_bump_retry(
+        &self,
+        uid: &str,
+        outbox_id: i64,
+        next_attempt_at: i64,
+        last_error: &str,
+    ) -> Result<()> {
+        let conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        conn.execute(
+            "UPDATE outbox
+             SET retry_count = retry_count + 1, next_attempt_at = ?2,
+                 last_error = ?3, updated_at = ?4
+             WHERE id = ?1",
+            params![outbox_id, next_attempt_at, last_error, now_ms],
+        )
+        .map_err(|e| Error::Storage(format!("outbox rpc bump retry: {e}")))?;
+        Ok(())
+    }
+
+    /// 丢弃一条 RPC 类命令（无法解析、或没有本地状态要收尾的类型）。
+    pub fn outbox_rpc_drop(&self, uid: &str, outbox_id: i64) -> Result<()> {
+        let conn = self.conn_for_user(uid)?;
+        conn.execute(
+            "DELETE FROM outbox WHERE id = ?1 AND message_id IS NULL",
+            params![outbox_id],
+        )
+        .map_err(|e| Error::Storage(format!("outbox rpc drop: {e}")))?;
+        Ok(())
+    }
+
     /// 附件上传成功后回写最终 payload（含 file_id / 尺寸），保持行不动。
     /// 回写消息 content（不动 edited_at/status，避免误标「已编辑」）。
     ///
@@ -4664,6 +5522,30 @@ impl LocalStore {
             params![message_id as i64],
         )
         .map_err(|e| Error::Storage(format!("delete_message_local message_reaction: {e}")))?;
+        tx.execute(
+            "DELETE FROM message_revision WHERE message_id = ?1",
+            params![message_id as i64],
+        )
+        .map_err(|e| Error::Storage(format!("delete_message_local message_revision: {e}")))?;
+        tx.execute(
+            "DELETE FROM message_revision_pending WHERE message_id = ?1",
+            params![message_id as i64],
+        )
+        .map_err(|e| {
+            Error::Storage(format!(
+                "delete_message_local message_revision_pending: {e}"
+            ))
+        })?;
+        tx.execute(
+            "DELETE FROM poll_state WHERE message_id = ?1",
+            params![message_id as i64],
+        )
+        .map_err(|e| Error::Storage(format!("delete_message_local poll_state: {e}")))?;
+        tx.execute(
+            "DELETE FROM live_location_state WHERE message_id = ?1",
+            params![message_id as i64],
+        )
+        .map_err(|e| Error::Storage(format!("delete_message_local live_location_state: {e}")))?;
         tx.execute(
             "DELETE FROM mention WHERE message_id = ?1",
             params![message_id as i64],
@@ -4679,6 +5561,9 @@ impl LocalStore {
         Ok(Some(stored))
     }
 
+    /// 本地编辑：编辑者是当前账号，记成待确认的一版（`message_revision_pending`），
+    /// **不占版本号**——版本号只由服务端分配，否则离线编辑和别的端的编辑会撞号。
+    /// 同一条消息再改一次覆盖上一份待确认的；界面立即显示新内容。
     pub fn edit_message(
         &self,
         uid: &str,
@@ -4686,1220 +5571,3287 @@ impl LocalStore {
         content: &str,
         edited_at: i32,
     ) -> Result<()> {
-        let conn = self.conn_for_user(uid)?;
+        let mut conn = self.conn_for_user(uid)?;
         let now_ms = chrono::Utc::now().timestamp_millis();
-        let updated = conn
-            .execute(
-                "UPDATE message SET content = ?1, updated_at = ?2 WHERE id = ?3",
-                params![content, now_ms, message_id as i64],
-            )
-            .map_err(|e| Error::Storage(format!("update message content: {e}")))?;
-        if updated == 0 {
-            return Err(Error::Storage(format!(
-                "edit message failed: message.id={} not found",
-                message_id
-            )));
-        }
-        let (channel_id, channel_type): (i64, i32) = conn
-            .query_row(
-                "SELECT channel_id, channel_type FROM message WHERE id = ?1",
-                params![message_id as i64],
-                |r| Ok((r.get(0)?, r.get(1)?)),
-            )
-            .map_err(|e| Error::Storage(format!("query message for edit: {e}")))?;
-        conn.execute(
-            "INSERT INTO message_extra (
-                message_id, channel_id, channel_type, content_edit, edited_at, extra_version
-             ) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("edit_message begin tx: {e}")))?;
+        let target = Self::revision_target(&tx, message_id)?;
+        Self::ensure_original_revision(&tx, &target, now_ms)?;
+        tx.execute(
+            "INSERT INTO message_revision_pending (
+                message_id, content, edited_at, editor_uid, created_at, base_version
+             ) VALUES (
+                ?1, ?2, ?3, ?4, ?5,
+                (SELECT COALESCE(MAX(version), 0) FROM message_revision WHERE message_id = ?1)
+             )
              ON CONFLICT(message_id) DO UPDATE SET
-                content_edit=excluded.content_edit,
-                edited_at=excluded.edited_at,
-                extra_version=excluded.extra_version",
+                content = excluded.content,
+                edited_at = excluded.edited_at,
+                editor_uid = excluded.editor_uid,
+                created_at = excluded.created_at,
+                base_version = excluded.base_version",
             params![
                 message_id as i64,
-                channel_id,
-                channel_type,
                 content,
                 edited_at,
+                uid.parse::<i64>().ok(),
                 now_ms
             ],
         )
-        .map_err(|e| Error::Storage(format!("upsert message_extra edit: {e}")))?;
+        .map_err(|e| Error::Storage(format!("upsert pending message revision: {e}")))?;
+        Self::project_message_edit(&tx, &target, content, edited_at, now_ms)?;
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("edit_message commit: {e}")))?;
         Ok(())
     }
 
-    pub fn set_message_pinned(&self, uid: &str, message_id: u64, is_pinned: bool) -> Result<()> {
-        let conn = self.conn_for_user(uid)?;
-        let (channel_id, channel_type): (i64, i32) = conn
+    /// 应用一次服务端的编辑并记入 message_revision。返回生效的版本号；`input.version`
+    /// 不大于已有最大版本（乱序到达的旧编辑、重复推送）时什么都不写，返回 None。
+    /// 不带版本号的编辑与最新一版内容、时间都相同时同样当成重复推送，不再追加一版。
+    /// 第一次编辑前先把原文记成 version 0，历史里才看得出改了什么。
+    ///
+    /// 本地有待确认的编辑时：内容一致就是它被确认了，删掉待确认的那份；不一致说明是
+    /// 别的编辑，照样记进历史。这版比本地那份新（`edited_at` 更晚，或版本已经越过
+    /// 本地编辑时看到的版本 + 1）就顶掉待确认的那份、界面改显示它，否则界面继续显示
+    /// 本地那一版。
+    pub fn apply_message_revision(
+        &self,
+        uid: &str,
+        message_id: u64,
+        input: &MessageRevisionInput,
+    ) -> Result<Option<u64>> {
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("apply_message_revision begin tx: {e}")))?;
+        let target = Self::revision_target(&tx, message_id)?;
+        let latest: Option<(i64, String, i32)> = tx
             .query_row(
-                "SELECT channel_id, channel_type FROM message WHERE id = ?1",
+                "SELECT version, content, edited_at FROM message_revision
+                 WHERE message_id = ?1 ORDER BY version DESC LIMIT 1",
                 params![message_id as i64],
-                |r| Ok((r.get(0)?, r.get(1)?)),
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
             )
-            .map_err(|e| Error::Storage(format!("query message for pin: {e}")))?;
-        let now_ms = chrono::Utc::now().timestamp_millis();
-        conn.execute(
-            "INSERT INTO message_extra (
-                message_id, channel_id, channel_type, is_pinned, extra_version
-             ) VALUES (?1, ?2, ?3, ?4, ?5)
-             ON CONFLICT(message_id) DO UPDATE SET
-                is_pinned=excluded.is_pinned,
-                extra_version=excluded.extra_version",
+            .optional()
+            .map_err(|e| Error::Storage(format!("query latest message revision: {e}")))?;
+        let version = match (input.version, &latest) {
+            (Some(v), Some((latest, _, _))) if v as i64 <= *latest => return Ok(None),
+            (Some(0), None) => return Ok(None),
+            (Some(v), _) => v,
+            // 只补了原文（version 0）时没有可比的编辑
+            (None, Some((latest, content, edited_at)))
+                if *latest > 0 && *content == input.content && *edited_at == input.edited_at =>
+            {
+                return Ok(None)
+            }
+            (None, Some((latest, _, _))) => *latest as u64 + 1,
+            (None, None) => 1,
+        };
+        Self::ensure_original_revision(&tx, &target, now_ms)?;
+        tx.execute(
+            "INSERT INTO message_revision (
+                message_id, version, content, edited_at, editor_uid, created_at
+             ) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
             params![
                 message_id as i64,
-                channel_id,
-                channel_type,
-                if is_pinned { 1 } else { 0 },
+                version as i64,
+                input.content,
+                input.edited_at,
+                input.editor_uid.map(|v| v as i64),
                 now_ms
             ],
         )
-        .map_err(|e| Error::Storage(format!("set message pinned: {e}")))?;
-        Ok(())
-    }
-
-    pub fn get_message_extra(
-        &self,
-        uid: &str,
-        message_id: u64,
-    ) -> Result<Option<StoredMessageExtra>> {
-        let conn = self.conn_for_user(uid)?;
-        conn.query_row(
-            "SELECT message_id, channel_id, channel_type, readed, readed_count, unread_count, revoke,
-                    revoker, extra_version, is_mutual_deleted, content_edit, edited_at, need_upload, is_pinned,
-                    delivered, delivered_at
-             FROM message_extra WHERE message_id = ?1",
-            params![message_id as i64],
-            |row| {
-                Ok(StoredMessageExtra {
-                    message_id: row.get::<_, i64>(0)? as u64,
-                    channel_id: row.get::<_, i64>(1)? as u64,
-                    channel_type: row.get::<_, i32>(2)?,
-                    readed: row.get::<_, i32>(3)?,
-                    readed_count: row.get::<_, i32>(4)?,
-                    unread_count: row.get::<_, i32>(5)?,
-                    revoke: row.get::<_, i16>(6)? != 0,
-                    revoker: row.get::<_, Option<i64>>(7)?.map(|v| v as u64),
-                    extra_version: row.get::<_, i64>(8)?,
-                    is_mutual_deleted: row.get::<_, i16>(9)? != 0,
-                    content_edit: row.get::<_, Option<String>>(10)?,
-                    edited_at: row.get::<_, i32>(11)?,
-                    need_upload: row.get::<_, i16>(12)? != 0,
-                    is_pinned: row.get::<_, i32>(13)? != 0,
-                    delivered: row.get::<_, i32>(14)? != 0,
-                    delivered_at: row.get::<_, i64>(15)? as u64,
-                })
-            },
-        )
-        .optional()
-        .map_err(|e| Error::Storage(format!("get message extra: {e}")))
-    }
-
-    /// Mark a message as delivered (only upgrades 0→1, never downgrades).
-    /// Returns the local SQLite `message.id` when the row was actually updated.
-    pub fn mark_message_delivered(
-        &self,
-        uid: &str,
-        server_message_id: u64,
-        delivered_at: u64,
-    ) -> Result<Option<u64>> {
-        let conn = self.conn_for_user(uid)?;
-        // Resolve local message_id from server_message_id
-        let message_row: Option<(i64, i64, i32)> = conn
+        .map_err(|e| Error::Storage(format!("insert message revision: {e}")))?;
+        let pending: Option<(String, i32, i64)> = tx
             .query_row(
-                "SELECT id, channel_id, channel_type FROM message WHERE server_message_id = ?1 LIMIT 1",
-                params![server_message_id as i64],
+                "SELECT content, edited_at, base_version FROM message_revision_pending
+                 WHERE message_id = ?1",
+                params![message_id as i64],
                 |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
             )
             .optional()
-            .map_err(|e| Error::Storage(format!("mark_delivered lookup: {e}")))?;
-        let Some((message_id, channel_id, channel_type)) = message_row else {
-            return Ok(None);
+            .map_err(|e| Error::Storage(format!("query pending message revision: {e}")))?;
+        let keep_pending = match pending {
+            Some((content, _, _)) if content == input.content => false,
+            Some((_, edited_at, base_version)) => {
+                input.edited_at <= edited_at && version as i64 <= base_version + 1
+            }
+            None => false,
         };
-        let now_ms = chrono::Utc::now().timestamp_millis();
-        let updated = conn
-            .execute(
-                "INSERT INTO message_extra (
-                    message_id, channel_id, channel_type, delivered, delivered_at, extra_version
-                 ) VALUES (?1, ?2, ?3, 1, ?4, ?5)
-                 ON CONFLICT(message_id) DO UPDATE SET
-                    delivered = 1,
-                    delivered_at = excluded.delivered_at,
-                    extra_version = excluded.extra_version
-                 WHERE message_extra.delivered = 0",
-                params![
-                    message_id,
-                    channel_id,
-                    channel_type,
-                    delivered_at as i64,
-                    now_ms
-                ],
+        if !keep_pending {
+            tx.execute(
+                "DELETE FROM message_revision_pending WHERE message_id = ?1",
+                params![message_id as i64],
             )
-            .map_err(|e| Error::Storage(format!("mark_delivered upsert: {e}")))?;
-        Ok((updated > 0).then_some(message_id as u64))
+            .map_err(|e| Error::Storage(format!("settle pending message revision: {e}")))?;
+            Self::project_message_edit(&tx, &target, &input.content, input.edited_at, now_ms)?;
+        }
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("apply_message_revision commit: {e}")))?;
+        Ok(Some(version))
     }
 
-    /// 基于 read cursor 精确投影本地读状态：
-    /// - message.pts <= last_read_pts 视为已读
-    /// - message.pts > last_read_pts 且非自己发送 视为未读
-    pub fn project_channel_read_cursor(
-        &self,
-        uid: &str,
-        channel_id: u64,
-        channel_type: i32,
-        last_read_pts: u64,
-    ) -> Result<()> {
+    /// 服务端拒绝了本地编辑：丢掉待确认的那份，界面退回最新的服务端版本；一次都没被
+    /// 服务端编辑过的消息退回原文，编辑标记一并清掉。没有待确认编辑时返回 false。
+    pub fn discard_pending_message_edit(&self, uid: &str, message_id: u64) -> Result<bool> {
         let mut conn = self.conn_for_user(uid)?;
         let now_ms = chrono::Utc::now().timestamp_millis();
-        let uid_num = uid.parse::<u64>().unwrap_or(0);
         let tx = conn
             .transaction()
-            .map_err(|e| Error::Storage(format!("project channel cursor begin tx: {e}")))?;
-        let existing_keep_pts: u64 = tx
+            .map_err(|e| Error::Storage(format!("discard_pending_message_edit begin tx: {e}")))?;
+        let removed = tx
+            .execute(
+                "DELETE FROM message_revision_pending WHERE message_id = ?1",
+                params![message_id as i64],
+            )
+            .map_err(|e| Error::Storage(format!("discard pending message revision: {e}")))?;
+        if removed == 0 {
+            return Ok(false);
+        }
+        let target = Self::revision_target(&tx, message_id)?;
+        let latest: Option<(i64, String, i32)> = tx
             .query_row(
-                "SELECT keep_pts
-                 FROM channel_extra
-                 WHERE channel_id = ?1 AND channel_type = ?2
-                 LIMIT 1",
-                params![channel_id as i64, channel_type],
-                |r| Ok(r.get::<_, i64>(0)? as u64),
+                "SELECT version, content, edited_at FROM message_revision
+                 WHERE message_id = ?1 ORDER BY version DESC LIMIT 1",
+                params![message_id as i64],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
             )
             .optional()
-            .map_err(|e| Error::Storage(format!("project channel cursor query keep_pts: {e}")))?
-            .unwrap_or(0);
-        let effective_read_pts = existing_keep_pts.max(last_read_pts);
-
-        if effective_read_pts == existing_keep_pts {
-            tx.commit()
-                .map_err(|e| Error::Storage(format!("project channel cursor commit tx: {e}")))?;
-            return Ok(());
+            .map_err(|e| Error::Storage(format!("query latest message revision: {e}")))?;
+        match latest {
+            Some((version, content, edited_at)) if version > 0 => {
+                Self::project_message_edit(&tx, &target, &content, edited_at, now_ms)?;
+            }
+            latest => {
+                // 只有本地编辑时补进来的原文：历史清空，正文和 message_extra 退回未编辑。
+                let original = latest.map(|(_, content, _)| content).unwrap_or(target.original);
+                tx.execute(
+                    "DELETE FROM message_revision WHERE message_id = ?1",
+                    params![message_id as i64],
+                )
+                .map_err(|e| Error::Storage(format!("discard original message revision: {e}")))?;
+                tx.execute(
+                    "UPDATE message SET content = ?1, updated_at = ?2 WHERE id = ?3",
+                    params![original, now_ms, message_id as i64],
+                )
+                .map_err(|e| Error::Storage(format!("restore message content: {e}")))?;
+                tx.execute(
+                    "UPDATE message_extra SET content_edit = NULL, edited_at = 0, extra_version = ?1
+                     WHERE message_id = ?2",
+                    params![now_ms, message_id as i64],
+                )
+                .map_err(|e| Error::Storage(format!("clear message_extra edit: {e}")))?;
+            }
         }
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("discard_pending_message_edit commit: {e}")))?;
+        Ok(true)
+    }
+
+    /// 编辑要用到的那条消息的列。
+    fn revision_target(tx: &Connection, message_id: u64) -> Result<RevisionTarget> {
+        tx.query_row(
+            "SELECT channel_id, channel_type, content, from_uid, created_at
+             FROM message WHERE id = ?1",
+            params![message_id as i64],
+            |r| {
+                Ok(RevisionTarget {
+                    message_id,
+                    channel_id: r.get(0)?,
+                    channel_type: r.get(1)?,
+                    original: r.get(2)?,
+                    from_uid: r.get(3)?,
+                    created_at: r.get(4)?,
+                })
+            },
+        )
+        .optional()
+        .map_err(|e| Error::Storage(format!("query message for edit: {e}")))?
+        .ok_or_else(|| {
+            Error::Storage(format!(
+                "edit message failed: message.id={} not found",
+                message_id
+            ))
+        })
+    }
 
+    /// 第一次编辑（本地或服务端）前把原文记成 version 0。已经有过版本的不动。
+    fn ensure_original_revision(
+        tx: &Connection,
+        target: &RevisionTarget,
+        now_ms: i64,
+    ) -> Result<()> {
         tx.execute(
-            "INSERT INTO channel_extra (
-                channel_id, channel_type, browse_to, keep_pts, keep_offset_y,
-                draft, draft_updated_at, version
-            ) VALUES (?1, ?2, ?3, ?3, 0, '', 0, ?4)
-            ON CONFLICT(channel_id, channel_type) DO UPDATE SET
-                browse_to = MAX(channel_extra.browse_to, excluded.browse_to),
-                keep_pts = MAX(channel_extra.keep_pts, excluded.keep_pts),
-                version = excluded.version",
+            "INSERT INTO message_revision (
+                message_id, version, content, edited_at, editor_uid, created_at
+             )
+             SELECT ?1, 0, ?2, ?3, ?4, ?5
+             WHERE NOT EXISTS (SELECT 1 FROM message_revision WHERE message_id = ?1)",
             params![
-                channel_id as i64,
-                channel_type,
-                effective_read_pts as i64,
+                target.message_id as i64,
+                target.original,
+                // message.created_at 是毫秒，revision 的 edited_at 与 message_extra 一样用秒
+                target.created_at / 1000,
+                Some(target.from_uid).filter(|&v| v > 0),
                 now_ms
             ],
         )
-        .map_err(|e| Error::Storage(format!("project channel cursor upsert channel_extra: {e}")))?;
+        .map_err(|e| Error::Storage(format!("insert original message revision: {e}")))?;
+        Ok(())
+    }
 
+    /// 把一版内容投影到 message.content / message_extra（读路径只看这两处）。
+    fn project_message_edit(
+        tx: &Connection,
+        target: &RevisionTarget,
+        content: &str,
+        edited_at: i32,
+        now_ms: i64,
+    ) -> Result<()> {
+        tx.execute(
+            "UPDATE message SET content = ?1, updated_at = ?2 WHERE id = ?3",
+            params![content, now_ms, target.message_id as i64],
+        )
+        .map_err(|e| Error::Storage(format!("update message content: {e}")))?;
         tx.execute(
             "INSERT INTO message_extra (
-                message_id, channel_id, channel_type, readed, readed_count, unread_count, extra_version
-            )
-            SELECT
-                m.id AS message_id,
-                m.channel_id,
-                m.channel_type,
-                1 AS readed,
-                1 AS readed_count,
-                0 AS unread_count,
-                ?5 AS extra_version
-            FROM message m
-            WHERE m.channel_id = ?1
-              AND m.channel_type = ?2
-              AND COALESCE(m.pts, 0) > ?3
-              AND COALESCE(m.pts, 0) <= ?4
-            ON CONFLICT(message_id) DO UPDATE SET
-                readed = 1,
-                readed_count = CASE
-                    WHEN message_extra.readed_count < 1 THEN 1
-                    ELSE message_extra.readed_count
-                END,
-                unread_count = 0,
-                extra_version = excluded.extra_version",
+                message_id, channel_id, channel_type, content_edit, edited_at, extra_version
+             ) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(message_id) DO UPDATE SET
+                content_edit=excluded.content_edit,
+                edited_at=excluded.edited_at,
+                extra_version=excluded.extra_version",
             params![
-                channel_id as i64,
-                channel_type,
-                existing_keep_pts as i64,
-                effective_read_pts as i64,
+                target.message_id as i64,
+                target.channel_id,
+                target.channel_type,
+                content,
+                edited_at,
                 now_ms
             ],
         )
-        .map_err(|e| Error::Storage(format!("project channel cursor upsert message_extra: {e}")))?;
-
-        let newly_read_unread_count: i64 = tx
-            .query_row(
-                "SELECT COUNT(1)
-                 FROM message m
-                 WHERE m.channel_id = ?1
-                   AND m.channel_type = ?2
-                   AND m.from_uid != ?3
-                   AND COALESCE(m.pts, 0) > ?4
-                   AND COALESCE(m.pts, 0) <= ?5",
-                params![
-                    channel_id as i64,
-                    channel_type,
-                    uid_num as i64,
-                    existing_keep_pts as i64,
-                    effective_read_pts as i64
-                ],
-                |r| r.get(0),
-            )
-            .map_err(|e| {
-                Error::Storage(format!(
-                    "project channel cursor query newly read count: {e}"
-                ))
-            })?;
-
-        let existing_unread: Option<i64> = tx
-            .query_row(
-                "SELECT unread_count
-                 FROM channel
-                 WHERE channel_id = ?1
-                 LIMIT 1",
-                params![channel_id as i64],
-                |r| r.get::<_, i64>(0),
-            )
-            .optional()
-            .map_err(|e| {
-                Error::Storage(format!("project channel cursor query existing unread: {e}"))
-            })?;
-
-        // 会话行只能由 channel 实体同步创建（CLIENT_UI_SPEC §3.1：会话列表主数据源是
-        // channel 表）。read cursor 是读状态的 projection（READ_STATUS_SPEC §6），不是会话
-        // 的来源——这里曾是 `INSERT ... ON CONFLICT`，给尚无 channel 实体的频道凭空造出
-        // 只有 id/type/unread 的裸行，列表上就是一条无名无头像的会话。
-        //
-        // 频道实体还没到就不投影 unread：读水位已经落在 channel_extra.keep_pts，实体到达时
-        // upsert_channel 会带上服务端的 unread baseline，不丢状态。
-        if let Some(current) = existing_unread {
-            let unread_total = std::cmp::max(0, current.saturating_sub(newly_read_unread_count));
-            tx.execute(
-                "UPDATE channel
-                    SET unread_count = ?2,
-                        updated_at = ?3
-                  WHERE channel_id = ?1",
-                params![channel_id as i64, unread_total as i32, now_ms],
-            )
-            .map_err(|e| Error::Storage(format!("project channel cursor update channel: {e}")))?;
-        }
-        tx.commit()
-            .map_err(|e| Error::Storage(format!("project channel cursor commit tx: {e}")))?;
+        .map_err(|e| Error::Storage(format!("upsert message_extra edit: {e}")))?;
         Ok(())
     }
 
-    pub fn get_channel_unread_count(
+    /// 编辑历史，按版本升序；未编辑过的消息返回空。本地还没确认的那一版排在最后，
+    /// `pending = true`，版本号是临时的（已有最大版本 + 1），确认后以服务端分配的为准。
+    pub fn list_message_revisions(
         &self,
         uid: &str,
-        channel_id: u64,
-        channel_type: i32,
-    ) -> Result<i32> {
+        message_id: u64,
+    ) -> Result<Vec<MessageRevision>> {
         let conn = self.conn_for_user(uid)?;
-        let from_channel = conn
-            .query_row(
-                "SELECT unread_count FROM channel WHERE channel_id = ?1 LIMIT 1",
-                params![channel_id as i64],
-                |r| r.get::<_, i32>(0),
-            )
-            .optional()
-            .map_err(|e| Error::Storage(format!("query channel unread_count: {e}")))?;
-        if let Some(v) = from_channel {
-            return Ok(v);
+        let mut stmt = conn
+            .prepare(
+                "SELECT message_id, version, content, edited_at, editor_uid, 0
+                 FROM message_revision WHERE message_id = ?1
+                 UNION ALL
+                 SELECT p.message_id,
+                        (SELECT COALESCE(MAX(version), 0) + 1 FROM message_revision
+                         WHERE message_id = p.message_id),
+                        p.content, p.edited_at, p.editor_uid, 1
+                 FROM message_revision_pending p WHERE p.message_id = ?1
+                 ORDER BY 6 ASC, 2 ASC",
+            )
+            .map_err(|e| Error::Storage(format!("prepare list message_revision: {e}")))?;
+        let rows = stmt
+            .query_map(params![message_id as i64], |row| {
+                Ok(MessageRevision {
+                    message_id: row.get::<_, i64>(0)? as u64,
+                    version: row.get::<_, i64>(1)? as u64,
+                    content: row.get::<_, String>(2)?,
+                    edited_at: row.get::<_, i32>(3)?,
+                    editor_uid: row.get::<_, Option<i64>>(4)?.map(|v| v as u64),
+                    pending: row.get(5)?,
+                })
+            })
+            .map_err(|e| Error::Storage(format!("query list message_revision: {e}")))?;
+        let mut out = Vec::new();
+        for row in rows {
+            out.push(row.map_err(|e| Error::Storage(format!("decode message_revision row: {e}")))?);
         }
-        let from_extra: i64 = conn
-            .query_row(
-                "SELECT COALESCE(SUM(unread_count), 0)
-                 FROM message_extra
-                 WHERE channel_id = ?1 AND channel_type = ?2",
-                params![channel_id as i64, channel_type],
-                |r| r.get(0),
-            )
-            .map_err(|e| Error::Storage(format!("query message_extra unread_count: {e}")))?;
-        Ok(from_extra as i32)
+        Ok(out)
     }
 
-    pub fn count_materialized_unread(
-        &self,
-        uid: &str,
-        channel_id: u64,
-        channel_type: i32,
-    ) -> Result<i32> {
-        let conn = self.conn_for_user(uid)?;
-        let uid_num = uid.parse::<u64>().unwrap_or(0);
-        let keep_pts: i64 = conn
-            .query_row(
-                "SELECT keep_pts
-                 FROM channel_extra
-                 WHERE channel_id = ?1 AND channel_type = ?2
-                 LIMIT 1",
-                params![channel_id as i64, channel_type],
-                |r| r.get::<_, i64>(0),
-            )
-            .optional()
-            .map_err(|e| Error::Storage(format!("query channel keep_pts: {e}")))?
-            .unwrap_or(0);
-        let count: i64 = conn
-            .query_row(
-                "SELECT COUNT(1)
-                 FROM message m
-                 WHERE m.channel_id = ?1
-                   AND m.channel_type = ?2
-                   AND m.from_uid != ?3
-                   AND COALESCE(m.pts, 0) > ?4",
-                params![channel_id as i64, channel_type, uid_num as i64, keep_pts],
-                |r| r.get(0),
-            )
-            .map_err(|e| Error::Storage(format!("count materialized unread: {e}")))?;
-        Ok(count as i32)
+    /// `stored_message_from_row` 里 LEFT JOIN poll_state 的 6 列（从 `start` 起）。
+    fn poll_tally_from_columns(
+        row: &rusqlite::Row<'_>,
+        start: usize,
+    ) -> rusqlite::Result<Option<PollTally>> {
+        let Some(tallies) = row.get::<_, Option<String>>(start)? else {
+            return Ok(None);
+        };
+        let my_votes = row.get::<_, Option<String>>(start + 2)?.unwrap_or_default();
+        Ok(Some(PollTally {
+            counts: serde_json::from_str(&tallies).unwrap_or_default(),
+            voter_count: row.get::<_, Option<i64>>(start + 1)?.unwrap_or(0).max(0) as u32,
+            my_votes: serde_json::from_str(&my_votes).unwrap_or_default(),
+            vote_pending: row.get::<_, Option<i64>>(start + 3)?.unwrap_or(0) != 0,
+            closed: row.get::<_, Option<i64>>(start + 4)?.unwrap_or(0) != 0,
+            version: row.get::<_, Option<i64>>(start + 5)?.unwrap_or(0).max(0) as u64,
+        }))
     }
 
-    pub fn get_total_unread_count(&self, uid: &str, exclude_muted: bool) -> Result<i32> {
-        let conn = self.conn_for_user(uid)?;
-        let sql = if exclude_muted {
-            "SELECT COALESCE(SUM(unread_count), 0) FROM channel WHERE mute = 0"
-        } else {
-            "SELECT COALESCE(SUM(unread_count), 0) FROM channel"
-        };
-        let total: i64 = conn
-            .query_row(sql, [], |r| r.get(0))
-            .map_err(|e| Error::Storage(format!("get total unread count: {e}")))?;
-        Ok(total as i32)
+    /// `(本地状态, 服务端确认过的自己的选择)`。
+    fn read_poll_state(
+        conn: &Connection,
+        message_id: u64,
+    ) -> Result<Option<(PollTally, Vec<u32>)>> {
+        conn.query_row(
+            "SELECT tallies, voter_count, my_votes, vote_pending, closed, version, confirmed_votes
+             FROM poll_state WHERE message_id = ?1",
+            params![message_id as i64],
+            |row| {
+                let tally = Self::poll_tally_from_columns(row, 0)?.unwrap_or_default();
+                let confirmed: String = row.get(6)?;
+                Ok((tally, serde_json::from_str(&confirmed).unwrap_or_default()))
+            },
+        )
+        .optional()
+        .map_err(|e| Error::Storage(format!("read poll_state: {e}")))
     }
 
-    pub fn save_current_uid(&self, uid: &str) -> Result<()> {
-        std::fs::write(self.current_user_file(), uid.as_bytes())
-            .map_err(|e| Error::Storage(format!("write current uid: {e}")))?;
+    fn write_poll_state(
+        conn: &Connection,
+        message_id: u64,
+        tally: &PollTally,
+        confirmed: &[u32],
+        now_ms: i64,
+    ) -> Result<()> {
+        let encode = |v: &[u32]| serde_json::to_string(v).unwrap_or_else(|_| "[]".to_string());
+        conn.execute(
+            "INSERT INTO poll_state (
+                message_id, tallies, voter_count, my_votes, confirmed_votes,
+                vote_pending, closed, version, updated_at
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            ON CONFLICT(message_id) DO UPDATE SET
+                tallies = excluded.tallies,
+                voter_count = excluded.voter_count,
+                my_votes = excluded.my_votes,
+                confirmed_votes = excluded.confirmed_votes,
+                vote_pending = excluded.vote_pending,
+                closed = excluded.closed,
+                version = excluded.version,
+                updated_at = excluded.updated_at",
+            params![
+                message_id as i64,
+                encode(&tally.counts),
+                tally.voter_count as i64,
+                encode(&tally.my_votes),
+                encode(confirmed),
+                tally.vote_pending as i64,
+                tally.closed as i64,
+                tally.version as i64,
+                now_ms
+            ],
+        )
+        .map_err(|e| Error::Storage(format!("write poll_state: {e}")))?;
         Ok(())
     }
 
-    pub fn load_current_uid(&self) -> Result<Option<String>> {
-        let path = self.current_user_file();
-        if !path.exists() {
-            return Ok(None);
+    /// 把一次服务端票数并进本地状态（不落库）。版本比本地旧的丢弃；截止只进不退。
+    /// 自己的选择还在出站队列里时，界面上的选择不被服务端的旧值盖掉。
+    fn merge_poll_update(
+        tally: &mut PollTally,
+        confirmed: &mut Vec<u32>,
+        update: &PollTallyUpdate,
+    ) -> bool {
+        if update.version > 0 && update.version < tally.version {
+            return false;
         }
-        let raw = std::fs::read_to_string(path)
-            .map_err(|e| Error::Storage(format!("read current uid: {e}")))?;
-        let uid = raw.trim();
-        if uid.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(uid.to_string()))
+        tally.counts = update.counts.clone();
+        tally.voter_count = update.voter_count;
+        tally.closed |= update.closed;
+        tally.version = tally.version.max(update.version);
+        if let Some(mine) = &update.my_votes {
+            let mut mine = mine.clone();
+            mine.sort_unstable();
+            mine.dedup();
+            if !tally.vote_pending {
+                tally.my_votes = mine.clone();
+            }
+            *confirmed = mine;
         }
+        true
     }
 
-    pub fn clear_current_uid(&self) -> Result<()> {
-        let path = self.current_user_file();
-        if path.exists() {
-            std::fs::remove_file(path)
-                .map_err(|e| Error::Storage(format!("clear current uid: {e}")))?;
+    /// 应用服务端下发的票数。返回本地状态是否有变化（没变就不必刷新时间线）。
+    pub fn apply_poll_update(
+        &self,
+        uid: &str,
+        message_id: u64,
+        update: &PollTallyUpdate,
+    ) -> Result<bool> {
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("apply poll update begin tx: {e}")))?;
+        let current = Self::read_poll_state(&tx, message_id)?;
+        let (mut tally, mut confirmed) = current.clone().unwrap_or_default();
+        if !Self::merge_poll_update(&mut tally, &mut confirmed, update) {
+            return Ok(false);
         }
-        Ok(())
+        if current.as_ref() == Some(&(tally.clone(), confirmed.clone())) {
+            return Ok(false);
+        }
+        Self::write_poll_state(&tx, message_id, &tally, &confirmed, now_ms)?;
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("apply poll update commit: {e}")))?;
+        Ok(true)
     }
 
-    #[allow(dead_code)]
-    pub fn wipe_user_full(&self, uid: &str) -> Result<()> {
-        if let Ok(mut guard) = self.account_dbs.lock() {
-            let _ = guard.remove(uid);
-        }
-        let paths = self.storage_paths(uid);
-        if paths.user_root.exists() {
-            std::fs::remove_dir_all(&paths.user_root)
-                .map_err(|e| Error::Storage(format!("remove user dir: {e}")))?;
-        }
-        let global_db = self.open_global_db()?;
-        let accounts = global_db
-            .open_tree(GLOBAL_TREE_ACCOUNTS)
-            .map_err(|e| Error::Storage(format!("open accounts tree: {e}")))?;
-        accounts
-            .remove(Self::k_acct_last_login(uid).as_bytes())
-            .map_err(|e| Error::Storage(format!("remove account last_login_at: {e}")))?;
-        accounts
-            .remove(Self::k_acct_created_at(uid).as_bytes())
-            .map_err(|e| Error::Storage(format!("remove account created_at: {e}")))?;
-        if let Some(active_uid) = get_string(&accounts, K_ACTIVE_UID)? {
-            if active_uid == uid {
-                accounts
-                    .remove(K_ACTIVE_UID)
-                    .map_err(|e| Error::Storage(format!("remove active uid: {e}")))?;
-            }
-        }
-        global_db
-            .flush()
-            .map_err(|e| Error::Storage(format!("flush global db: {e}")))?;
-        if self
-            .load_current_uid()?
-            .as_ref()
-            .map(|v| v == uid)
-            .unwrap_or(false)
-        {
-            self.clear_current_uid()?;
-        }
+    /// 记下自己的一次投票（空选择 = 撤票）并入队投票命令：**同一事务**。
+    /// 同一投票还没发出的旧命令被替换掉。
+    pub fn poll_record_vote(
+        &self,
+        uid: &str,
+        command_id: &str,
+        command: &PollVoteCommand,
+    ) -> Result<()> {
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let payload = serde_json::to_vec(command)
+            .map_err(|e| Error::Serialization(format!("encode poll vote command: {e}")))?;
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("poll record vote begin tx: {e}")))?;
+        let (mut tally, confirmed) =
+            Self::read_poll_state(&tx, command.message_id)?.unwrap_or_default();
+        tally.my_votes = command.option_indexes.clone();
+        tally.vote_pending = true;
+        Self::write_poll_state(&tx, command.message_id, &tally, &confirmed, now_ms)?;
+        Self::outbox_insert_rpc(
+            &tx,
+            command_id,
+            poll::VOTE_COMMAND,
+            Some(&poll::vote_coalesce_key(command.message_id)),
+            Some(command.channel_id),
+            &payload,
+            now_ms,
+        )?;
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("poll record vote commit: {e}")))?;
         Ok(())
     }
 
-    /// The send time to persist, in milliseconds.
+    /// 投票命令有了结果：删除命令、收尾本地状态，**同一事务**。
     ///
-    /// Guards the one thing a caller can get wrong here that no reader can
-    /// detect afterwards: a seconds-resolution value. The wire carries both —
-    /// `PushMessageRequest.timestamp` is a u32 of seconds (a millisecond epoch
-    /// does not fit in u32 at all), while history and sync carry milliseconds —
-    /// and a seconds value stored as milliseconds lands in 1970, which puts a
-    /// message received today before the entire conversation. The threshold is
-    /// 10^11 ms = 1973: every real millisecond timestamp is above it and every
-    /// plausible seconds one is below.
-    fn sent_at_ms(timestamp: i64, fallback_ms: i64) -> i64 {
-        const MIN_PLAUSIBLE_MS: i64 = 100_000_000_000;
-        match timestamp {
-            t if t >= MIN_PLAUSIBLE_MS => t,
-            t if t > 0 => t.saturating_mul(1_000),
-            // No usable time at all: local arrival is a worse answer than the
-            // real one, but a far better one than 1970.
-            _ => fallback_ms,
+    /// - 接受（`accepted`）：命令里的选择成为确认过的选择，响应里带的票数一并写入；
+    /// - 拒绝：界面上的选择退回上次确认的。
+    ///
+    /// 同一投票后面又排了一条命令时（理论上 actor 串行不会发生）`vote_pending` 保持。
+    pub fn poll_vote_settled(
+        &self,
+        uid: &str,
+        outbox_id: i64,
+        command: &PollVoteCommand,
+        accepted: bool,
+        update: Option<&PollTallyUpdate>,
+    ) -> Result<()> {
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("poll vote settled begin tx: {e}")))?;
+        tx.execute("DELETE FROM outbox WHERE id = ?1", params![outbox_id])
+            .map_err(|e| Error::Storage(format!("poll vote settled delete command: {e}")))?;
+        let still_pending: bool = tx
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM outbox WHERE coalesce_key = ?1)",
+                params![poll::vote_coalesce_key(command.message_id)],
+                |row| row.get(0),
+            )
+            .map_err(|e| Error::Storage(format!("poll vote settled check queue: {e}")))?;
+        let (mut tally, mut confirmed) =
+            Self::read_poll_state(&tx, command.message_id)?.unwrap_or_default();
+        tally.vote_pending = still_pending;
+        if accepted {
+            confirmed = command.option_indexes.clone();
+            if let Some(update) = update {
+                Self::merge_poll_update(&mut tally, &mut confirmed, update);
+            }
         }
-    }
-
-    /// 仅 crate 内可见：测试要用外部连接打开这个加密库（例如装一个
-    /// 故意让事务失败的 trigger）。派生规则本身不变。
-    pub(crate) fn derive_encryption_key(uid: &str) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(b"privchat_sdk_encryption_key_v1");
-        hasher.update(uid.as_bytes());
-        let result = hasher.finalize();
-        hex::encode(result)
-    }
-
-    pub fn kv_put(&self, uid: &str, key: &str, value: &[u8]) -> Result<()> {
-        let tree = self.account_tree(uid, ACCOUNT_TREE_KV)?;
-        tree.insert(key.as_bytes(), value)
-            .map_err(|e| Error::Storage(format!("sled kv put: {e}")))?;
+        if !still_pending {
+            tally.my_votes = confirmed.clone();
+        }
+        Self::write_poll_state(&tx, command.message_id, &tally, &confirmed, now_ms)?;
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("poll vote settled commit: {e}")))?;
         Ok(())
     }
 
-    pub fn kv_get(&self, uid: &str, key: &str) -> Result<Option<Vec<u8>>> {
-        let tree = self.account_tree(uid, ACCOUNT_TREE_KV)?;
-        let v = tree
-            .get(key.as_bytes())
-            .map_err(|e| Error::Storage(format!("sled kv get: {e}")))?;
-        Ok(v.map(|x| x.to_vec()))
-    }
-
-    pub fn kv_delete(&self, uid: &str, key: &str) -> Result<()> {
-        let tree = self.account_tree(uid, ACCOUNT_TREE_KV)?;
-        tree.remove(key.as_bytes())
-            .map_err(|e| Error::Storage(format!("sled kv delete: {e}")))?;
-        Ok(())
+    /// `stored_message_from_row` 里 LEFT JOIN live_location_state 的 9 列（从 `start` 起）。
+    fn live_location_from_columns(
+        row: &rusqlite::Row<'_>,
+        start: usize,
+    ) -> rusqlite::Result<Option<LiveLocationState>> {
+        let Some(position_at) = row.get::<_, Option<i64>>(start + 4)? else {
+            return Ok(None);
+        };
+        let latitude = row.get::<_, Option<f64>>(start)?;
+        let longitude = row.get::<_, Option<f64>>(start + 1)?;
+        let accuracy = row.get::<_, Option<f64>>(start + 2)?;
+        let heading = row.get::<_, Option<f64>>(start + 3)?;
+        Ok(Some(LiveLocationState {
+            position: latitude
+                .zip(longitude)
+                .map(|(latitude, longitude)| LiveLocationPosition {
+                    latitude,
+                    longitude,
+                    accuracy,
+                    heading,
+                    at: position_at,
+                }),
+            expires_at: row.get::<_, Option<i64>>(start + 5)?.unwrap_or(0),
+            stopped: row.get::<_, Option<i64>>(start + 6)?.unwrap_or(0) != 0,
+            version: row.get::<_, Option<i64>>(start + 7)?.unwrap_or(0).max(0) as u64,
+            update_pending: row.get::<_, Option<i64>>(start + 8)?.unwrap_or(0) != 0,
+        }))
     }
 
-    fn pending_timeline_mutation_key(mutation: &PendingTimelineMutation) -> String {
-        format!(
-            "{PENDING_TIMELINE_MUTATION_PREFIX}:{}:{:020}:{:020}:{:020}",
-            mutation.channel_type,
-            mutation.channel_id,
-            mutation.target_server_message_id,
-            mutation.event_id
+    /// `(本地状态, 自己的更新上次发出的时间)`。
+    fn read_live_location_state(
+        conn: &Connection,
+        message_id: u64,
+    ) -> Result<Option<(LiveLocationState, i64)>> {
+        conn.query_row(
+            "SELECT latitude, longitude, accuracy, heading, position_at,
+                    expires_at, stopped, version, update_pending, last_sent_at
+             FROM live_location_state WHERE message_id = ?1",
+            params![message_id as i64],
+            |row| {
+                let state = Self::live_location_from_columns(row, 0)?.unwrap_or_default();
+                Ok((state, row.get(9)?))
+            },
         )
+        .optional()
+        .map_err(|e| Error::Storage(format!("read live_location_state: {e}")))
     }
 
-    fn pending_timeline_mutation_prefix(
-        channel_id: u64,
-        channel_type: i32,
-        target_server_message_id: u64,
-    ) -> String {
-        format!(
-            "{PENDING_TIMELINE_MUTATION_PREFIX}:{channel_type}:{channel_id:020}:{target_server_message_id:020}:"
+    fn write_live_location_state(
+        conn: &Connection,
+        message_id: u64,
+        state: &LiveLocationState,
+        last_sent_at: i64,
+        now_ms: i64,
+    ) -> Result<()> {
+        let position = state.position.as_ref();
+        conn.execute(
+            "INSERT INTO live_location_state (
+                message_id, latitude, longitude, accuracy, heading, position_at,
+                expires_at, stopped, version, update_pending, last_sent_at, updated_at
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+            ON CONFLICT(message_id) DO UPDATE SET
+                latitude = excluded.latitude,
+                longitude = excluded.longitude,
+                accuracy = excluded.accuracy,
+                heading = excluded.heading,
+                position_at = excluded.position_at,
+                expires_at = excluded.expires_at,
+                stopped = excluded.stopped,
+                version = excluded.version,
+                update_pending = excluded.update_pending,
+                last_sent_at = excluded.last_sent_at,
+                updated_at = excluded.updated_at",
+            params![
+                message_id as i64,
+                position.map(|p| p.latitude),
+                position.map(|p| p.longitude),
+                position.and_then(|p| p.accuracy),
+                position.and_then(|p| p.heading),
+                position.map(|p| p.at).unwrap_or(0),
+                state.expires_at,
+                state.stopped as i64,
+                state.version as i64,
+                state.update_pending as i64,
+                last_sent_at,
+                now_ms
+            ],
         )
+        .map_err(|e| Error::Storage(format!("write live_location_state: {e}")))?;
+        Ok(())
     }
 
-    fn encode_pending_timeline_mutation(mutation: &PendingTimelineMutation) -> Vec<u8> {
-        let mut value = Vec::with_capacity(17 + mutation.canonical_event.len());
-        value.push(1);
-        value.extend_from_slice(&mutation.event_id.to_be_bytes());
-        value.extend_from_slice(&mutation.pts.to_be_bytes());
-        value.extend_from_slice(&mutation.canonical_event);
-        value
-    }
-
-    fn decode_pending_timeline_mutation(
-        channel_id: u64,
-        channel_type: i32,
-        target_server_message_id: u64,
-        value: &[u8],
-    ) -> Result<PendingTimelineMutation> {
-        if value.len() < 18 || value[0] != 1 {
-            return Err(Error::Storage(
-                "invalid pending timeline mutation record".to_string(),
-            ));
+    /// 把一次服务端位置并进本地状态（不落库）。版本比本地旧的丢弃；结束只进不退；
+    /// 自己的更新还在出站队列里时，本地位置不被服务端的旧位置盖掉。
+    fn merge_live_location_update(
+        state: &mut LiveLocationState,
+        update: &LiveLocationUpdate,
+    ) -> bool {
+        if update.version > 0 && update.version < state.version {
+            return false;
         }
-        let event_id = u64::from_be_bytes(value[1..9].try_into().map_err(|_| {
-            Error::Storage("invalid pending timeline mutation event_id".to_string())
-        })?);
-        let pts =
-            u64::from_be_bytes(value[9..17].try_into().map_err(|_| {
-                Error::Storage("invalid pending timeline mutation pts".to_string())
-            })?);
-        Ok(PendingTimelineMutation {
-            channel_id,
-            channel_type,
-            target_server_message_id,
-            event_id,
-            pts,
-            canonical_event: value[17..].to_vec(),
-        })
+        if let Some(position) = &update.position {
+            let newer = match &state.position {
+                Some(current) => position.at >= current.at,
+                None => true,
+            };
+            if newer && !state.update_pending {
+                state.position = Some(position.clone());
+            }
+        }
+        if let Some(expires_at) = update.expires_at {
+            state.expires_at = expires_at;
+        }
+        state.stopped |= update.stopped;
+        state.version = state.version.max(update.version);
+        true
     }
 
-    pub fn put_pending_timeline_mutation(
+    /// 应用服务端下发的位置。返回本地状态是否有变化（没变就不必刷新时间线）。
+    pub fn apply_live_location_update(
         &self,
         uid: &str,
-        mutation: &PendingTimelineMutation,
-    ) -> Result<()> {
-        let tree = self.account_tree(uid, ACCOUNT_TREE_KV)?;
-        tree.insert(
-            Self::pending_timeline_mutation_key(mutation).as_bytes(),
-            Self::encode_pending_timeline_mutation(mutation),
-        )
-        .map_err(|e| Error::Storage(format!("put pending timeline mutation: {e}")))?;
-        tree.flush()
-            .map_err(|e| Error::Storage(format!("flush pending timeline mutation: {e}")))?;
-        Ok(())
+        message_id: u64,
+        update: &LiveLocationUpdate,
+    ) -> Result<bool> {
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("apply live location begin tx: {e}")))?;
+        let current = Self::read_live_location_state(&tx, message_id)?;
+        let (mut state, last_sent_at) = current.clone().unwrap_or_default();
+        if !Self::merge_live_location_update(&mut state, update) {
+            return Ok(false);
+        }
+        if current.as_ref().map(|(s, _)| s) == Some(&state) {
+            return Ok(false);
+        }
+        Self::write_live_location_state(&tx, message_id, &state, last_sent_at, now_ms)?;
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("apply live location commit: {e}")))?;
+        Ok(true)
     }
 
-    pub fn list_pending_timeline_mutations(
+    /// 记下自己的一次位置更新（或结束共享）并入队命令：**同一事务**。
+    ///
+    /// 同一共享还没发出的旧命令被替换掉。更新离上次发出不到
+    /// [`live_location::MIN_UPDATE_INTERVAL_MS`] 时命令推迟到那一刻；结束不推迟。
+    /// 返回命令的 `next_attempt_at`（0 = 立即可发）。
+    pub fn live_location_record(
         &self,
         uid: &str,
-        channel_id: u64,
-        channel_type: i32,
-        target_server_message_id: u64,
-    ) -> Result<Vec<PendingTimelineMutation>> {
-        let tree = self.account_tree(uid, ACCOUNT_TREE_KV)?;
-        let prefix = Self::pending_timeline_mutation_prefix(
-            channel_id,
-            channel_type,
-            target_server_message_id,
-        );
-        let mut rows = Vec::new();
-        let mut corrupt_keys = Vec::new();
-        for entry in tree.scan_prefix(prefix.as_bytes()) {
-            let (key, value) = entry
-                .map_err(|e| Error::Storage(format!("scan pending timeline mutation: {e}")))?;
-            match Self::decode_pending_timeline_mutation(
-                channel_id,
-                channel_type,
-                target_server_message_id,
-                value.as_ref(),
-            ) {
-                Ok(row) => rows.push(row),
-                Err(_) => corrupt_keys.push(key),
+        command_id: &str,
+        command: &LiveLocationCommand,
+    ) -> Result<i64> {
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let payload = serde_json::to_vec(command)
+            .map_err(|e| Error::Serialization(format!("encode live location command: {e}")))?;
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("live location record begin tx: {e}")))?;
+        let (mut state, last_sent_at) =
+            Self::read_live_location_state(&tx, command.message_id)?.unwrap_or_default();
+        if state.stopped {
+            return Err(Error::InvalidState(
+                "live location sharing has ended".to_string(),
+            ));
+        }
+        let mut next_attempt_at = 0;
+        if command.stop {
+            state.stopped = true;
+        } else {
+            state.position = command.position.clone();
+            if last_sent_at > 0 {
+                next_attempt_at = last_sent_at + live_location::MIN_UPDATE_INTERVAL_MS;
             }
         }
-        for key in corrupt_keys {
-            let _ = tree.remove(key);
+        if next_attempt_at <= now_ms {
+            next_attempt_at = 0;
         }
-        rows.sort_by_key(|row| (row.pts, row.event_id));
-        Ok(rows)
+        state.update_pending = true;
+        Self::write_live_location_state(&tx, command.message_id, &state, last_sent_at, now_ms)?;
+        Self::outbox_insert_rpc(
+            &tx,
+            command_id,
+            live_location::UPDATE_COMMAND,
+            Some(&live_location::update_coalesce_key(command.message_id)),
+            Some(command.channel_id),
+            &payload,
+            now_ms,
+        )?;
+        if next_attempt_at > 0 {
+            tx.execute(
+                "UPDATE outbox SET next_attempt_at = ?1 WHERE command_id = ?2",
+                params![next_attempt_at, command_id],
+            )
+            .map_err(|e| Error::Storage(format!("live location record throttle: {e}")))?;
+        }
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("live location record commit: {e}")))?;
+        Ok(next_attempt_at)
     }
 
-    pub fn delete_pending_timeline_mutation(
+    /// 共享命令有了结果：删除命令、收尾本地状态，**同一事务**。
+    ///
+    /// 接受时记下发出时间（下一次更新据此节流），响应里带的状态一并并入；
+    /// 共享已在服务端结束（消息已删、无权推送）时本地同样标为结束；只是这一次被拒时
+    /// 丢掉这次更新，共享照旧。
+    pub fn live_location_settled(
         &self,
         uid: &str,
-        mutation: &PendingTimelineMutation,
+        outbox_id: i64,
+        command: &LiveLocationCommand,
+        outcome: LiveLocationSettle,
+        update: Option<&LiveLocationUpdate>,
     ) -> Result<()> {
-        let tree = self.account_tree(uid, ACCOUNT_TREE_KV)?;
-        tree.remove(Self::pending_timeline_mutation_key(mutation).as_bytes())
-            .map_err(|e| Error::Storage(format!("delete pending timeline mutation: {e}")))?;
-        tree.flush()
-            .map_err(|e| Error::Storage(format!("flush pending timeline mutation delete: {e}")))?;
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("live location settled begin tx: {e}")))?;
+        tx.execute("DELETE FROM outbox WHERE id = ?1", params![outbox_id])
+            .map_err(|e| Error::Storage(format!("live location settled delete command: {e}")))?;
+        let still_pending: bool = tx
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM outbox WHERE coalesce_key = ?1)",
+                params![live_location::update_coalesce_key(command.message_id)],
+                |row| row.get(0),
+            )
+            .map_err(|e| Error::Storage(format!("live location settled check queue: {e}")))?;
+        let (mut state, mut last_sent_at) =
+            Self::read_live_location_state(&tx, command.message_id)?.unwrap_or_default();
+        state.update_pending = still_pending;
+        match outcome {
+            LiveLocationSettle::Accepted => {
+                last_sent_at = now_ms;
+                if let Some(update) = update {
+                    Self::merge_live_location_update(&mut state, update);
+                }
+            }
+            LiveLocationSettle::Dropped => {}
+            LiveLocationSettle::Ended => state.stopped = true,
+        }
+        Self::write_live_location_state(&tx, command.message_id, &state, last_sent_at, now_ms)?;
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("live location settled commit: {e}")))?;
         Ok(())
     }
 
-    fn open_db(path: &Path) -> Result<sled::Db> {
-        sled::open(path)
-            .map_err(|e| Error::Storage(format!("open sled db {}: {e}", path.display())))
+    /// 本地直接改置顶状态（不发服务端）。有服务端消息 ID 时置顶索引一并维护。
+    pub fn set_message_pinned(&self, uid: &str, message_id: u64, is_pinned: bool) -> Result<()> {
+        let conn = self.conn_for_user(uid)?;
+        let (channel_id, channel_type, server_message_id): (i64, i32, Option<i64>) = conn
+            .query_row(
+                "SELECT channel_id, channel_type, server_message_id FROM message WHERE id = ?1",
+                params![message_id as i64],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )
+            .map_err(|e| Error::Storage(format!("query message for pin: {e}")))?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        match server_message_id.filter(|&id| id > 0) {
+            Some(server_message_id) => {
+                let pin = RemotePin {
+                    server_message_id: server_message_id as u64,
+                    pinned_by: None,
+                    pinned_at: now_ms,
+                };
+                Self::write_message_pin(
+                    &conn,
+                    channel_id as u64,
+                    channel_type,
+                    &pin,
+                    is_pinned,
+                    now_ms,
+                )?;
+            }
+            None => Self::set_extra_pinned(
+                &conn,
+                message_id,
+                channel_id,
+                channel_type,
+                is_pinned,
+                now_ms,
+            )?,
+        }
+        Ok(())
     }
 
-    /// 旧 sled 出站队列的读写口 —— **只给测试造旧数据用**。
-    ///
-    /// 生产代码里已经没有这条路了：命令都在 SQLite `outbox`，升级后唯一还会
-    /// 碰 sled 队列的是 `migrate_sled_queues_into_outbox`，而它直接开 db 文件，
-    /// 不经过这里。把这几个方法留在 `LocalStore` 的公开表面上，等于给新代码留
-    /// 了一条「再写一次旧队列」的入口——那正是一个数据库两套真相的老问题。
-    #[cfg(test)]
-    pub(crate) fn test_seed_legacy_queue(
-        &self,
-        uid: &str,
-        kind: LegacyQueueKind,
+    fn set_extra_pinned(
+        conn: &Connection,
         message_id: u64,
-        payload: &[u8],
+        channel_id: i64,
+        channel_type: i32,
+        is_pinned: bool,
+        now_ms: i64,
     ) -> Result<()> {
-        let paths = self.ensure_user_storage(uid)?;
-        let db = Self::open_db(self.legacy_queue_path(&paths, kind))?;
-        db.insert(message_id.to_be_bytes(), payload)
-            .map_err(|e| Error::Storage(format!("seed legacy queue: {e}")))?;
-        db.flush()
-            .map_err(|e| Error::Storage(format!("seed legacy queue flush: {e}")))?;
+        conn.execute(
+            "INSERT INTO message_extra (
+                message_id, channel_id, channel_type, is_pinned, extra_version
+             ) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(message_id) DO UPDATE SET
+                is_pinned=excluded.is_pinned,
+                extra_version=excluded.extra_version",
+            params![
+                message_id as i64,
+                channel_id,
+                channel_type,
+                if is_pinned { 1 } else { 0 },
+                now_ms
+            ],
+        )
+        .map_err(|e| Error::Storage(format!("set message pinned: {e}")))?;
         Ok(())
     }
 
-    #[cfg(test)]
-    pub(crate) fn test_legacy_queue_len(&self, uid: &str, kind: LegacyQueueKind) -> Result<usize> {
-        let paths = self.ensure_user_storage(uid)?;
-        let path = self.legacy_queue_path(&paths, kind);
-        if !path.exists() {
-            return Ok(0);
+    /// 写一条置顶（`pinned = false` 删行），本地有这条消息时同步 `message_extra.is_pinned`。
+    /// 返回索引是否有变化。
+    fn write_message_pin(
+        conn: &Connection,
+        channel_id: u64,
+        channel_type: i32,
+        pin: &RemotePin,
+        pinned: bool,
+        now_ms: i64,
+    ) -> Result<bool> {
+        let current: Option<(Option<i64>, i64)> = conn
+            .query_row(
+                "SELECT pinned_by, pinned_at FROM message_pin
+                 WHERE channel_type = ?1 AND channel_id = ?2 AND server_message_id = ?3",
+                params![
+                    channel_type,
+                    channel_id as i64,
+                    pin.server_message_id as i64
+                ],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(|e| Error::Storage(format!("read message_pin: {e}")))?;
+        let changed = if pinned {
+            // 没带置顶人 / 时间的（老服务端的同步、本地直接改）不抹掉已知的值。
+            let pinned_by = pin
+                .pinned_by
+                .map(|v| v as i64)
+                .or(current.and_then(|(by, _)| by));
+            let pinned_at = match current {
+                Some((_, at)) if pin.pinned_by.is_none() && at > 0 => at,
+                _ => pin.pinned_at,
+            };
+            if current == Some((pinned_by, pinned_at)) {
+                false
+            } else {
+                conn.execute(
+                    "INSERT INTO message_pin (
+                        channel_id, channel_type, server_message_id, pinned_by, pinned_at
+                     ) VALUES (?1, ?2, ?3, ?4, ?5)
+                     ON CONFLICT(channel_type, channel_id, server_message_id) DO UPDATE SET
+                        pinned_by = excluded.pinned_by,
+                        pinned_at = excluded.pinned_at",
+                    params![
+                        channel_id as i64,
+                        channel_type,
+                        pin.server_message_id as i64,
+                        pinned_by,
+                        pinned_at
+                    ],
+                )
+                .map_err(|e| Error::Storage(format!("upsert message_pin: {e}")))?;
+                true
+            }
+        } else {
+            conn.execute(
+                "DELETE FROM message_pin
+                 WHERE channel_type = ?1 AND channel_id = ?2 AND server_message_id = ?3",
+                params![
+                    channel_type,
+                    channel_id as i64,
+                    pin.server_message_id as i64
+                ],
+            )
+            .map_err(|e| Error::Storage(format!("delete message_pin: {e}")))?
+                > 0
+        };
+        let local_message_id =
+            Self::pin_local_message_id(conn, channel_id, channel_type, pin.server_message_id)?;
+        if let Some(message_id) = local_message_id {
+            Self::set_extra_pinned(
+                conn,
+                message_id,
+                channel_id as i64,
+                channel_type,
+                pinned,
+                now_ms,
+            )?;
         }
-        Ok(Self::open_db(path)?.len())
+        Ok(changed)
     }
 
-    #[cfg(test)]
-    fn legacy_queue_path<'a>(&self, paths: &'a StoragePaths, kind: LegacyQueueKind) -> &'a Path {
-        match kind {
-            LegacyQueueKind::Normal => paths.normal_queue_path.as_path(),
-            LegacyQueueKind::File => paths
-                .file_queue_paths
-                .first()
-                .expect("legacy file queue path"),
-        }
+    fn pin_local_message_id(
+        conn: &Connection,
+        channel_id: u64,
+        channel_type: i32,
+        server_message_id: u64,
+    ) -> Result<Option<u64>> {
+        conn.query_row(
+            "SELECT id FROM message
+             WHERE server_message_id = ?1 AND channel_id = ?2 AND channel_type = ?3
+             LIMIT 1",
+            params![server_message_id as i64, channel_id as i64, channel_type],
+            |row| Ok(row.get::<_, i64>(0)? as u64),
+        )
+        .optional()
+        .map_err(|e| Error::Storage(format!("query message for pin: {e}")))
     }
-}
 
-/// 旧队列种类，仅测试用（见 `test_seed_legacy_queue`）。
-#[cfg(test)]
-#[derive(Debug, Clone, Copy)]
-pub(crate) enum LegacyQueueKind {
-    Normal,
-    File,
-}
+    fn message_pin_pending(conn: &Connection, message_id: u64) -> Result<bool> {
+        conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM outbox WHERE coalesce_key = ?1)",
+            params![message_pin::pin_coalesce_key(message_id)],
+            |row| row.get(0),
+        )
+        .map_err(|e| Error::Storage(format!("check pending pin command: {e}")))
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::{
-        get_string, resolve_group_member_display_name, LegacyQueueKind, LocalStore,
-        GLOBAL_TREE_ACCOUNTS, K_ACTIVE_UID,
-    };
-    use crate::{
-        LoginResult, NewMessage, PendingTimelineMutation, UpsertChannelExtraInput,
-        UpsertChannelInput, UpsertGroupInput, UpsertRemoteMessageInput, UpsertUserInput,
-    };
-    use rand::RngCore;
-    use rusqlite::params;
-    use std::path::PathBuf;
+    /// 自己置顶 / 取消置顶：改本地索引并入队置顶命令，**同一事务**。
+    /// 同一消息还没发出的旧命令被替换掉。
+    pub fn message_pin_record(
+        &self,
+        uid: &str,
+        command_id: &str,
+        command: &MessagePinCommand,
+        pinned_by: Option<u64>,
+    ) -> Result<()> {
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let payload = serde_json::to_vec(command)
+            .map_err(|e| Error::Serialization(format!("encode message pin command: {e}")))?;
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("message pin record begin tx: {e}")))?;
+        let pin = RemotePin {
+            server_message_id: command.server_message_id,
+            pinned_by,
+            pinned_at: now_ms,
+        };
+        Self::write_message_pin(
+            &tx,
+            command.channel_id,
+            command.channel_type,
+            &pin,
+            command.pinned,
+            now_ms,
+        )?;
+        Self::outbox_insert_rpc(
+            &tx,
+            command_id,
+            message_pin::PIN_COMMAND,
+            Some(&message_pin::pin_coalesce_key(command.message_id)),
+            Some(command.channel_id),
+            &payload,
+            now_ms,
+        )?;
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("message pin record commit: {e}")))?;
+        Ok(())
+    }
 
-    fn test_store() -> LocalStore {
-        let mut rand_bytes = [0u8; 6];
-        rand::thread_rng().fill_bytes(&mut rand_bytes);
-        let dir = PathBuf::from(format!(
-            "/tmp/privchat-rust-test-{}-{}",
-            chrono::Utc::now().timestamp_micros(),
-            hex::encode(rand_bytes)
-        ));
-        LocalStore::open_at(dir).expect("open test store")
+    /// 置顶命令有了结果：删除命令；服务端接受时按它给的置顶人 / 时间落定，**同一事务**。
+    /// 被拒时本地状态不动，调用方重拉服务端列表。
+    pub fn message_pin_settled(
+        &self,
+        uid: &str,
+        outbox_id: i64,
+        command: &MessagePinCommand,
+        confirmed: Option<&RemotePin>,
+    ) -> Result<()> {
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("message pin settled begin tx: {e}")))?;
+        tx.execute("DELETE FROM outbox WHERE id = ?1", params![outbox_id])
+            .map_err(|e| Error::Storage(format!("message pin settled delete command: {e}")))?;
+        if let Some(pin) = confirmed {
+            if !Self::message_pin_pending(&tx, command.message_id)? {
+                Self::write_message_pin(
+                    &tx,
+                    command.channel_id,
+                    command.channel_type,
+                    pin,
+                    command.pinned,
+                    now_ms,
+                )?;
+            }
+        }
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("message pin settled commit: {e}")))?;
+        Ok(())
     }
 
-    /// 退出登录不是注销账号：凭证清掉了，bootstrap 水位必须还读得出来。
-    ///
-    /// 这条水位决定下次登录走增量还是全量。它一直单独存在 META 树里、`clear_session`
-    /// 也从不删它，可唯一的读法要求 access_token 还在——于是退出后它就"消失"了，
-    /// 同一个账号重新登录被当成没同步过，750 人的群连同 761 个会话重跑一遍全量，
-    /// 用户盯着「数据初始化中」十几秒。真机实测 15.9s，修完 0.5s。
-    #[test]
-    fn logging_out_keeps_the_bootstrap_watermark() {
-        let store = test_store();
-        let uid = "100000156";
-        store
-            .save_login(
-                uid,
-                &LoginResult {
-                    user_id: 100_000_156,
-                    token: "token".to_string(),
-                    device_id: "device".to_string(),
-                    refresh_token: None,
-                    expires_at: 0,
-                },
-            )
-            .expect("save login");
-        store
-            .set_bootstrap_completed(uid, true)
-            .expect("mark bootstrap completed");
-
-        store.clear_session(uid).expect("logout clears credentials");
+    /// 同步 / 推送来的一条置顶变化。自己对这条消息的命令还没发出时跳过。
+    /// 返回索引是否有变化。
+    pub fn apply_remote_pin(
+        &self,
+        uid: &str,
+        channel_id: u64,
+        channel_type: i32,
+        pin: &RemotePin,
+        pinned: bool,
+    ) -> Result<bool> {
+        let conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let local_message_id =
+            Self::pin_local_message_id(&conn, channel_id, channel_type, pin.server_message_id)?;
+        if let Some(message_id) = local_message_id {
+            if Self::message_pin_pending(&conn, message_id)? {
+                return Ok(false);
+            }
+        }
+        Self::write_message_pin(&conn, channel_id, channel_type, pin, pinned, now_ms)
+    }
 
-        // 凭证没了（这正是退出登录该做的）……
-        assert!(
-            store.load_session(uid).expect("load session").is_none(),
-            "logout must drop the credentials",
-        );
-        // ……但水位还在，所以再登录只做增量。
-        assert!(
-            store
-                .load_bootstrap_completed(uid)
-                .expect("load bootstrap watermark"),
-            "logout must not look like a fresh account",
-        );
+    /// 用服务端的置顶列表替换一个会话的本地索引。自己还没发出的置顶 / 取消置顶保持本地状态。
+    /// 返回索引是否有变化。
+    pub fn replace_channel_pins(
+        &self,
+        uid: &str,
+        channel_id: u64,
+        channel_type: i32,
+        pins: &[RemotePin],
+    ) -> Result<bool> {
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("replace channel pins begin tx: {e}")))?;
+        // 本地有未发命令的消息（按服务端消息 ID）：这些不动。
+        let pending: HashSet<u64> = {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT m.server_message_id FROM outbox o
+                     JOIN message m ON o.coalesce_key = ?1 || m.id
+                     WHERE m.channel_id = ?2 AND m.channel_type = ?3
+                       AND m.server_message_id IS NOT NULL",
+                )
+                .map_err(|e| Error::Storage(format!("prepare pending pins: {e}")))?;
+            let rows = stmt
+                .query_map(
+                    params![
+                        format!("{}:", message_pin::PIN_COMMAND),
+                        channel_id as i64,
+                        channel_type
+                    ],
+                    |row| Ok(row.get::<_, i64>(0)? as u64),
+                )
+                .map_err(|e| Error::Storage(format!("query pending pins: {e}")))?;
+            rows.collect::<std::result::Result<_, _>>()
+                .map_err(|e| Error::Storage(format!("read pending pins: {e}")))?
+        };
+        let existing: Vec<u64> = {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT server_message_id FROM message_pin
+                     WHERE channel_id = ?1 AND channel_type = ?2",
+                )
+                .map_err(|e| Error::Storage(format!("prepare channel pins: {e}")))?;
+            let rows = stmt
+                .query_map(params![channel_id as i64, channel_type], |row| {
+                    Ok(row.get::<_, i64>(0)? as u64)
+                })
+                .map_err(|e| Error::Storage(format!("query channel pins: {e}")))?;
+            rows.collect::<std::result::Result<_, _>>()
+                .map_err(|e| Error::Storage(format!("read channel pins: {e}")))?
+        };
+        let mut changed = false;
+        for server_message_id in existing {
+            if pending.contains(&server_message_id)
+                || pins
+                    .iter()
+                    .any(|pin| pin.server_message_id == server_message_id)
+            {
+                continue;
+            }
+            let stale = RemotePin {
+                server_message_id,
+                pinned_by: None,
+                pinned_at: 0,
+            };
+            changed |=
+                Self::write_message_pin(&tx, channel_id, channel_type, &stale, false, now_ms)?;
+        }
+        for pin in pins {
+            if pending.contains(&pin.server_message_id) {
+                continue;
+            }
+            changed |= Self::write_message_pin(&tx, channel_id, channel_type, pin, true, now_ms)?;
+        }
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("replace channel pins commit: {e}")))?;
+        Ok(changed)
     }
 
-    /// DM 标题**任何情况下不得回退到 uid**
-    /// （spec CLIENT_GLOBAL_STATE_AND_IDENTITY_STORE_SPEC §27.5）。
-    ///
-    /// 2026-08-08 生产反馈：邀请码注册的新用户进主界面，和邀请人的会话标题是一串
-    /// `100000007`。链路是——补全链绑定邀请码时服务端才建好友和 DM，欢迎消息先于对端
-    /// user 实体到达，本地会话行由这条消息就地建出来，名字位置被填进了 uid。
-    /// 服务端其实是按约定发空串的（"宁可显示加载中也不显示一串数字"），是客户端自己
-    /// 一层层把 uid 补了回去。
-    #[test]
-    fn a_dm_without_a_known_peer_shows_no_name_rather_than_the_uid() {
-        let store = test_store();
-        let uid = "100000200";
-        // 对端 user 实体还没同步到本地——这正是新注册用户进主界面那一刻的状态。
-        store
-            .upsert_channel(
-                uid,
-                &UpsertChannelInput {
-                    channel_id: 4381,
-                    channel_type: 1,
-                    channel_name: String::new(),
-                    channel_remark: String::new(),
-                    avatar: String::new(),
-                    unread_count: 1,
-                    top: 0,
-                    mute: 0,
-                    last_msg_timestamp: 1,
-                    last_local_message_id: 1,
-                    last_msg_content: "hi".to_string(),
-                    version: 1,
-                    peer_user_id: Some(100_000_007),
+    /// 一个会话的置顶，按置顶时间倒序。
+    pub fn list_pinned_messages(
+        &self,
+        uid: &str,
+        channel_id: u64,
+        channel_type: i32,
+    ) -> Result<Vec<PinnedMessage>> {
+        let conn = self.conn_for_user(uid)?;
+        let mut stmt = conn
+            .prepare(
+                "SELECT
+                    p.server_message_id, m.id, p.pinned_by, p.pinned_at,
+                    NULLIF(TRIM(gm.alias), ''), NULLIF(TRIM(u.alias), ''),
+                    NULLIF(TRIM(u.nickname), ''), NULLIF(TRIM(u.username), ''),
+                    EXISTS(SELECT 1 FROM outbox o WHERE o.coalesce_key = ?3 || m.id)
+                 FROM message_pin AS p
+                 LEFT JOIN message AS m
+                    ON m.server_message_id = p.server_message_id
+                   AND m.channel_id = p.channel_id AND m.channel_type = p.channel_type
+                 LEFT JOIN \"user\" AS u ON u.user_id = p.pinned_by AND u.is_deleted = 0
+                 LEFT JOIN group_member AS gm
+                    ON p.channel_type = 2 AND gm.group_id = p.channel_id
+                   AND gm.user_id = p.pinned_by
+                 WHERE p.channel_id = ?1 AND p.channel_type = ?2
+                 ORDER BY p.pinned_at DESC, p.server_message_id DESC",
+            )
+            .map_err(|e| Error::Storage(format!("prepare list pinned messages: {e}")))?;
+        let rows = stmt
+            .query_map(
+                params![
+                    channel_id as i64,
+                    channel_type,
+                    format!("{}:", message_pin::PIN_COMMAND)
+                ],
+                |row| {
+                    let pinned_by = row.get::<_, Option<i64>>(2)?.map(|v| v as u64);
+                    let group_alias = row.get::<_, Option<String>>(4)?;
+                    let user_alias = row.get::<_, Option<String>>(5)?;
+                    let nickname = row.get::<_, Option<String>>(6)?;
+                    let username = row.get::<_, Option<String>>(7)?;
+                    Ok(PinnedMessage {
+                        channel_id,
+                        channel_type,
+                        server_message_id: row.get::<_, i64>(0)? as u64,
+                        message_id: row.get::<_, Option<i64>>(1)?.map(|v| v as u64),
+                        pinned_by,
+                        pinned_by_name: pinned_by.map(|user_id| {
+                            resolve_group_member_display_name(
+                                group_alias.as_deref(),
+                                user_alias.as_deref(),
+                                nickname.as_deref(),
+                                username.as_deref(),
+                                user_id,
+                            )
+                        }),
+                        pinned_at: row.get(3)?,
+                        pending: row.get::<_, Option<bool>>(8)?.unwrap_or(false),
+                    })
                 },
             )
-            .expect("upsert channel");
-
-        let listed = store.list_channels(uid, 10, 0).expect("list channels");
-        let dm = listed.iter().find(|c| c.channel_id == 4381).expect("dm row");
-        assert_eq!(dm.channel_name, "", "名字未知时留空，绝不返回 uid");
-        assert!(
-            !dm.channel_name.contains("100000007"),
-            "对端 uid 绝不能出现在标题里，实际='{}'",
-            dm.channel_name
-        );
+            .map_err(|e| Error::Storage(format!("query pinned messages: {e}")))?;
+        rows.collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| Error::Storage(format!("read pinned messages: {e}")))
     }
 
-    /// 对端 user 实体一到，标题立刻是真名——不需要重启，也不需要额外的全量同步。
-    #[test]
-    fn the_dm_title_becomes_the_real_name_once_the_peer_lands() {
-        let store = test_store();
-        let uid = "100000200";
-        store
-            .upsert_channel(
-                uid,
-                &UpsertChannelInput {
-                    channel_id: 4381,
-                    channel_type: 1,
-                    channel_name: String::new(),
-                    channel_remark: String::new(),
-                    avatar: String::new(),
-                    unread_count: 1,
-                    top: 0,
-                    mute: 0,
-                    last_msg_timestamp: 1,
-                    last_local_message_id: 1,
-                    last_msg_content: "hi".to_string(),
-                    version: 1,
-                    peer_user_id: Some(100_000_007),
-                },
+    /// 缓存服务端给的包元数据（列表或详情），安装状态不动。
+    ///
+    /// `stickers` 非空时整包替换表情列表：不在新列表里、但被最近使用 / 收藏引用的表情
+    /// 留着（`position = -1`），只是不再算在包里。
+    pub fn cache_sticker_packages(&self, uid: &str, packages: &[StickerPackage]) -> Result<()> {
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("cache sticker packages begin tx: {e}")))?;
+        for package in packages {
+            tx.execute(
+                "INSERT INTO sticker_package (
+                    package_id, name, thumbnail_url, author, description, sticker_count, updated_at
+                 ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                 ON CONFLICT(package_id) DO UPDATE SET
+                    name = excluded.name,
+                    thumbnail_url = excluded.thumbnail_url,
+                    author = excluded.author,
+                    description = excluded.description,
+                    sticker_count = excluded.sticker_count,
+                    updated_at = excluded.updated_at",
+                params![
+                    package.package_id,
+                    package.name,
+                    package.thumbnail_url,
+                    package.author,
+                    package.description,
+                    package.sticker_count as i64,
+                    now_ms
+                ],
             )
-            .expect("upsert channel");
-        store
-            .upsert_user(
-                uid,
-                &UpsertUserInput {
-                    user_id: 100_000_007,
-                    username: Some("fujie95".to_string()),
-                    nickname: Some("福姐九五".to_string()),
-                    alias: None,
-                    avatar: String::new(),
-                    user_type: 0,
-                    is_deleted: false,
-                    channel_id: String::new(),
-                    version: 1,
-                    updated_at: 1,
-                },
+            .map_err(|e| Error::Storage(format!("upsert sticker_package: {e}")))?;
+            if package.stickers.is_empty() {
+                continue;
+            }
+            tx.execute(
+                "UPDATE sticker SET position = -1 WHERE package_id = ?1",
+                params![package.package_id],
             )
-            .expect("upsert user");
+            .map_err(|e| Error::Storage(format!("reset sticker positions: {e}")))?;
+            for (position, sticker) in package.stickers.iter().enumerate() {
+                Self::upsert_sticker(&tx, sticker, Some(position as i64), now_ms)?;
+            }
+            tx.execute(
+                "DELETE FROM sticker
+                 WHERE package_id = ?1 AND position < 0
+                   AND sticker_id NOT IN (SELECT sticker_id FROM sticker_recent)
+                   AND sticker_id NOT IN (SELECT sticker_id FROM sticker_favourite)",
+                params![package.package_id],
+            )
+            .map_err(|e| Error::Storage(format!("delete stale stickers: {e}")))?;
+        }
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("cache sticker packages commit: {e}")))?;
+        Ok(())
+    }
 
-        let listed = store.list_channels(uid, 10, 0).expect("list channels");
-        let dm = listed.iter().find(|c| c.channel_id == 4381).expect("dm row");
-        assert_eq!(dm.channel_name, "福姐九五");
+    /// `position` 为 `None`（最近使用、收藏带进来的）时保留已知的包内顺序。
+    fn upsert_sticker(
+        conn: &Connection,
+        sticker: &Sticker,
+        position: Option<i64>,
+        now_ms: i64,
+    ) -> Result<()> {
+        conn.execute(
+            "INSERT INTO sticker (
+                sticker_id, package_id, position, image_url, alt_text, emoji,
+                width, height, mime_type, updated_at
+             ) VALUES (?1, ?2, COALESCE(?3, -1), ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+             ON CONFLICT(sticker_id) DO UPDATE SET
+                package_id = excluded.package_id,
+                position = COALESCE(?3, sticker.position),
+                image_url = excluded.image_url,
+                alt_text = excluded.alt_text,
+                emoji = excluded.emoji,
+                width = excluded.width,
+                height = excluded.height,
+                mime_type = excluded.mime_type,
+                updated_at = excluded.updated_at",
+            params![
+                sticker.sticker_id,
+                sticker.package_id,
+                position,
+                sticker.image_url,
+                sticker.alt_text,
+                sticker.emoji,
+                sticker.width,
+                sticker.height,
+                sticker.mime_type,
+                now_ms
+            ],
+        )
+        .map_err(|e| Error::Storage(format!("upsert sticker: {e}")))?;
+        Ok(())
     }
 
-    /// 存量脏数据：老版本把对端 uid 写进了 `channel_name`。
-    /// 它只能用来**反推对端身份**去 JOIN，绝不能当名字显示。
-    #[test]
-    fn a_legacy_row_with_a_uid_in_the_name_column_still_never_shows_it() {
-        let store = test_store();
-        let uid = "100000200";
-        store
-            .upsert_channel(
-                uid,
-                &UpsertChannelInput {
-                    channel_id: 4382,
-                    channel_type: 1,
-                    // 老版本的写法：uid 当名字
-                    channel_name: "100000007".to_string(),
-                    channel_remark: String::new(),
-                    avatar: String::new(),
-                    unread_count: 0,
-                    top: 0,
-                    mute: 0,
-                    last_msg_timestamp: 1,
-                    last_local_message_id: 1,
-                    last_msg_content: String::new(),
-                    version: 1,
-                    peer_user_id: None,
+    fn sticker_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Sticker> {
+        Ok(Sticker {
+            sticker_id: row.get(0)?,
+            package_id: row.get(1)?,
+            image_url: row.get(2)?,
+            alt_text: row.get(3)?,
+            emoji: row.get(4)?,
+            width: row.get(5)?,
+            height: row.get(6)?,
+            mime_type: row.get(7)?,
+            local_path: None,
+        })
+    }
+
+    fn query_stickers(
+        conn: &Connection,
+        sql: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<Sticker>> {
+        let mut stmt = conn
+            .prepare(sql)
+            .map_err(|e| Error::Storage(format!("prepare stickers: {e}")))?;
+        let rows = stmt
+            .query_map(params, Self::sticker_row)
+            .map_err(|e| Error::Storage(format!("query stickers: {e}")))?;
+        rows.collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| Error::Storage(format!("read stickers: {e}")))
+    }
+
+    fn load_sticker_package(conn: &Connection, package_id: &str) -> Result<Option<StickerPackage>> {
+        let package = conn
+            .query_row(
+                "SELECT package_id, name, thumbnail_url, author, description, sticker_count,
+                        installed, sort_order, installed_at,
+                        EXISTS(SELECT 1 FROM outbox WHERE coalesce_key = ?2 || package_id)
+                 FROM sticker_package WHERE package_id = ?1",
+                params![package_id, format!("{}:", sticker::INSTALL_COMMAND)],
+                |row| {
+                    Ok(StickerPackage {
+                        package_id: row.get(0)?,
+                        name: row.get(1)?,
+                        thumbnail_url: row.get(2)?,
+                        author: row.get(3)?,
+                        description: row.get(4)?,
+                        sticker_count: row.get::<_, i64>(5)?.max(0) as u64,
+                        stickers: Vec::new(),
+                        installed: row.get(6)?,
+                        sort_order: row.get(7)?,
+                        installed_at: row.get(8)?,
+                        pending: row.get(9)?,
+                    })
                 },
             )
-            .expect("upsert channel");
+            .optional()
+            .map_err(|e| Error::Storage(format!("read sticker_package: {e}")))?;
+        let Some(mut package) = package else {
+            return Ok(None);
+        };
+        package.stickers = Self::query_stickers(
+            conn,
+            &format!("{STICKER_SELECT} WHERE package_id = ?1 AND position >= 0 ORDER BY position"),
+            params![package_id],
+        )?;
+        Ok(Some(package))
+    }
 
-        let before = store.list_channels(uid, 10, 0).expect("list channels");
-        let dm = before.iter().find(|c| c.channel_id == 4382).expect("dm row");
-        assert_eq!(dm.channel_name, "", "存量 uid 名字当成「没有名字」");
+    /// 一个包，带包内表情。本地没有缓存过这个包时为 `None`。
+    pub fn get_sticker_package(
+        &self,
+        uid: &str,
+        package_id: &str,
+    ) -> Result<Option<StickerPackage>> {
+        let conn = self.conn_for_user(uid)?;
+        Self::load_sticker_package(&conn, package_id)
+    }
 
-        // 但它仍然要能反推出对端，等 user 实体到了标题就对了。
-        store
-            .upsert_user(
-                uid,
-                &UpsertUserInput {
-                    user_id: 100_000_007,
-                    username: Some("fujie95".to_string()),
-                    nickname: Some("福姐九五".to_string()),
-                    alias: None,
-                    avatar: String::new(),
-                    user_type: 0,
-                    is_deleted: false,
-                    channel_id: String::new(),
-                    version: 1,
-                    updated_at: 1,
-                },
-            )
-            .expect("upsert user");
-        let after = store.list_channels(uid, 10, 0).expect("list channels");
-        let dm = after.iter().find(|c| c.channel_id == 4382).expect("dm row");
-        assert_eq!(dm.channel_name, "福姐九五", "存量行也要能自愈");
+    /// 已安装的包按 `sort_order`；`installed_only = false` 时再跟上其余缓存过的包（按名字）。
+    pub fn list_sticker_packages(
+        &self,
+        uid: &str,
+        installed_only: bool,
+    ) -> Result<Vec<StickerPackage>> {
+        let conn = self.conn_for_user(uid)?;
+        let ids: Vec<String> = {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT package_id FROM sticker_package
+                     WHERE installed = 1 OR ?1 = 0
+                     ORDER BY installed DESC,
+                              CASE WHEN installed = 1 THEN sort_order ELSE 0 END ASC,
+                              name ASC, package_id ASC",
+                )
+                .map_err(|e| Error::Storage(format!("prepare sticker packages: {e}")))?;
+            let rows = stmt
+                .query_map(params![installed_only], |row| row.get(0))
+                .map_err(|e| Error::Storage(format!("query sticker packages: {e}")))?;
+            rows.collect::<std::result::Result<_, _>>()
+                .map_err(|e| Error::Storage(format!("read sticker packages: {e}")))?
+        };
+        let mut packages = Vec::with_capacity(ids.len());
+        for package_id in ids {
+            if let Some(package) = Self::load_sticker_package(&conn, &package_id)? {
+                packages.push(package);
+            }
+        }
+        Ok(packages)
     }
 
-    #[test]
-    fn group_member_display_name_uses_the_frozen_priority() {
-        assert_eq!(
-            resolve_group_member_display_name(
-                Some(" group alias "),
-                Some("user alias"),
-                Some("nickname"),
-                Some("username"),
-                7,
-            ),
-            "group alias"
-        );
-        assert_eq!(
-            resolve_group_member_display_name(
-                None,
-                Some("user alias"),
-                Some("nickname"),
-                Some("username"),
-                7,
-            ),
-            "user alias"
-        );
-        assert_eq!(
-            resolve_group_member_display_name(None, None, Some("nickname"), None, 7),
-            "nickname"
-        );
-        assert_eq!(
-            resolve_group_member_display_name(None, None, None, Some("username"), 7),
-            "username"
-        );
-        assert_eq!(
-            resolve_group_member_display_name(None, None, None, None, 7),
-            "7"
-        );
+    /// 写一个包的安装状态。新装的排在最前面。返回是否有变化。
+    fn write_sticker_install(
+        conn: &Connection,
+        package_id: &str,
+        installed: bool,
+        installed_at: i64,
+        now_ms: i64,
+    ) -> Result<bool> {
+        let current: Option<bool> = conn
+            .query_row(
+                "SELECT installed FROM sticker_package WHERE package_id = ?1",
+                params![package_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| Error::Storage(format!("read sticker install: {e}")))?;
+        if current == Some(installed) {
+            return Ok(false);
+        }
+        if installed {
+            let top: i64 = conn
+                .query_row(
+                    "SELECT COALESCE(MIN(sort_order), 0) FROM sticker_package WHERE installed = 1",
+                    [],
+                    |row| row.get(0),
+                )
+                .map_err(|e| Error::Storage(format!("read sticker sort order: {e}")))?;
+            conn.execute(
+                "INSERT INTO sticker_package (package_id, installed, sort_order, installed_at, updated_at)
+                 VALUES (?1, 1, ?2, ?3, ?4)
+                 ON CONFLICT(package_id) DO UPDATE SET
+                    installed = 1,
+                    sort_order = excluded.sort_order,
+                    installed_at = excluded.installed_at,
+                    updated_at = excluded.updated_at",
+                params![package_id, top - 1, installed_at, now_ms],
+            )
+            .map_err(|e| Error::Storage(format!("install sticker package: {e}")))?;
+        } else {
+            conn.execute(
+                "UPDATE sticker_package
+                 SET installed = 0, sort_order = 0, installed_at = 0, updated_at = ?2
+                 WHERE package_id = ?1",
+                params![package_id, now_ms],
+            )
+            .map_err(|e| Error::Storage(format!("uninstall sticker package: {e}")))?;
+        }
+        Ok(true)
     }
 
-    #[test]
-    fn force_set_user_avatar_cache_bypasses_avatar_gate() {
-        // CLIENT_GLOBAL_STATE §4.3 P2：自己上传头像后，SDK user 行 avatar 列可能还没同步到
-        // 新 url ⇒ 门控版 set_user_avatar_cache 不写；force 版必须强制写三列。
-        let store = test_store();
-        let uid = "30001";
-        let user_id = 42u64;
-        store
-            .upsert_user(
-                uid,
-                &crate::UpsertUserInput {
-                    user_id,
-                    username: Some("self".to_string()),
-                    nickname: Some("Self".to_string()),
-                    alias: None,
-                    avatar: "https://cdn/old.png".to_string(),
-                    user_type: 0,
-                    is_deleted: false,
-                    channel_id: "c-self".to_string(),
-                    version: 1,
-                    updated_at: 1,
+    /// 一个包现在在本机的安装状态；本地库里还没有这个包时是没装。
+    fn read_sticker_install(conn: &Connection, package_id: &str) -> Result<StickerInstallState> {
+        let state = conn
+            .query_row(
+                "SELECT installed, sort_order, installed_at FROM sticker_package
+                 WHERE package_id = ?1",
+                params![package_id],
+                |row| {
+                    Ok(StickerInstallState {
+                        installed: row.get(0)?,
+                        sort_order: row.get(1)?,
+                        installed_at: row.get(2)?,
+                    })
                 },
             )
-            .expect("upsert user");
-
-        // 门控版：avatar 列 != new_url ⇒ 不写（这正是自己上传后 ensure 失效的根因）。
-        let gated = store
-            .set_user_avatar_cache(
-                uid,
-                user_id,
-                "https://cdn/new.png",
-                "/root/avatars/users/42.img",
-            )
-            .expect("gated set");
-        assert!(!gated, "gated set 应因 avatar 列不匹配而不写");
+            .optional()
+            .map_err(|e| Error::Storage(format!("read sticker install: {e}")))?;
+        Ok(state.unwrap_or_default())
+    }
 
-        // 强制版：显式命令，avatar / avatar_local_path / avatar_cached_url 三列一起对齐。
-        let forced = store
-            .force_set_user_avatar_cache(
-                uid,
-                user_id,
-                "https://cdn/new.png",
-                "/root/avatars/users/42.img",
-            )
-            .expect("force set");
-        assert!(forced, "force set 应写入");
+    /// 按原样退回一个包的安装状态（位置、安装时间都是原来的）。返回是否有变化。
+    fn restore_sticker_install(
+        conn: &Connection,
+        package_id: &str,
+        state: StickerInstallState,
+        now_ms: i64,
+    ) -> Result<bool> {
+        if Self::read_sticker_install(conn, package_id)? == state {
+            return Ok(false);
+        }
+        conn.execute(
+            "INSERT INTO sticker_package (package_id, installed, sort_order, installed_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(package_id) DO UPDATE SET
+                installed = excluded.installed,
+                sort_order = excluded.sort_order,
+                installed_at = excluded.installed_at,
+                updated_at = excluded.updated_at",
+            params![
+                package_id,
+                state.installed,
+                state.sort_order,
+                state.installed_at,
+                now_ms
+            ],
+        )
+        .map_err(|e| Error::Storage(format!("restore sticker install: {e}")))?;
+        Ok(true)
+    }
 
-        let cache = store
-            .get_user_avatar_cache(uid, user_id)
-            .expect("get cache")
-            .expect("row exists");
-        assert_eq!(cache.avatar_cached_url, "https://cdn/new.png");
-        assert_eq!(cache.avatar_local_path, "/root/avatars/users/42.img");
-        let user = store
-            .get_user_by_id(uid, user_id)
-            .expect("get user")
-            .expect("user exists");
-        assert_eq!(user.avatar, "https://cdn/new.png");
+    fn sticker_install_pending(conn: &Connection, package_id: &str) -> Result<bool> {
+        conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM outbox WHERE coalesce_key = ?1)",
+            params![sticker::install_coalesce_key(package_id)],
+            |row| row.get(0),
+        )
+        .map_err(|e| Error::Storage(format!("check pending sticker command: {e}")))
     }
 
-    #[test]
-    fn account_kv_path_uses_dot_kv_suffix() {
-        let store = test_store();
-        let paths = store.storage_paths("20001");
-        assert_eq!(
-            paths.kv_path.file_name().and_then(|v| v.to_str()),
-            Some("account.kv")
-        );
+    /// 自己装 / 卸一个包：改本地状态并入队命令，**同一事务**。同一个包还没发出的旧命令
+    /// 被替换掉。本地还没见过的包（从别人的表情消息里添加）先记下安装状态，元数据等拉
+    /// 详情，离线也装得上。
+    ///
+    /// 命令里记下被拒时要退回的状态（[`StickerInstallCommand::previous`]）。
+    pub fn sticker_install_record(
+        &self,
+        uid: &str,
+        command_id: &str,
+        command: &StickerInstallCommand,
+    ) -> Result<()> {
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let coalesce_key = sticker::install_coalesce_key(&command.package_id);
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("sticker install record begin tx: {e}")))?;
+        // 要替换的旧命令记着服务端最后认可的状态，沿用它的；否则就是现在这一份。
+        let replaced: Option<Vec<u8>> = tx
+            .query_row(
+                "SELECT payload FROM outbox
+                 WHERE coalesce_key = ?1 AND message_id IS NULL AND status = 'pending'
+                 ORDER BY id DESC LIMIT 1",
+                params![coalesce_key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| Error::Storage(format!("read replaced sticker command: {e}")))?;
+        let previous = match replaced
+            .and_then(|payload| serde_json::from_slice::<StickerInstallCommand>(&payload).ok())
+            .and_then(|replaced| replaced.previous)
+        {
+            Some(previous) => previous,
+            None => Self::read_sticker_install(&tx, &command.package_id)?,
+        };
+        let payload = serde_json::to_vec(&StickerInstallCommand {
+            previous: Some(previous),
+            ..command.clone()
+        })
+        .map_err(|e| Error::Serialization(format!("encode sticker install command: {e}")))?;
+        Self::write_sticker_install(&tx, &command.package_id, command.installed, now_ms, now_ms)?;
+        Self::outbox_insert_rpc(
+            &tx,
+            command_id,
+            sticker::INSTALL_COMMAND,
+            Some(&coalesce_key),
+            None,
+            &payload,
+            now_ms,
+        )?;
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("sticker install record commit: {e}")))?;
+        Ok(())
     }
 
-    #[test]
-    fn db_path_migrates_messages_db_to_privchat_db() {
-        let store = test_store();
-        let uid = "20000";
-        let paths = store.storage_paths(uid);
-        std::fs::create_dir_all(&paths.user_root).expect("create user root");
-        let legacy = paths.user_root.join("messages.db");
-        let conn = rusqlite::Connection::open(&legacy).expect("open legacy db");
-        let key = LocalStore::derive_encryption_key(uid);
-        conn.pragma_update(None, "key", &key)
-            .expect("set legacy db key");
-        conn.execute("CREATE TABLE t(id INTEGER PRIMARY KEY)", [])
-            .expect("init legacy db");
-        drop(conn);
+    /// 装 / 卸命令有了结果：删除命令；被服务端拒绝、且这个包没有更新的命令排着时，
+    /// 按命令里记下的原样退回（原来的位置、安装时间），**同一事务**。返回本地状态是否
+    /// 有变化。
+    pub fn sticker_install_settled(
+        &self,
+        uid: &str,
+        outbox_id: i64,
+        command: &StickerInstallCommand,
+        accepted: bool,
+    ) -> Result<bool> {
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("sticker install settled begin tx: {e}")))?;
+        tx.execute("DELETE FROM outbox WHERE id = ?1", params![outbox_id])
+            .map_err(|e| Error::Storage(format!("sticker install settled delete command: {e}")))?;
+        let changed = if accepted || Self::sticker_install_pending(&tx, &command.package_id)? {
+            false
+        } else if let Some(previous) = command.previous {
+            Self::restore_sticker_install(&tx, &command.package_id, previous, now_ms)?
+        } else {
+            // 老版本入队的命令没记原状态：只能把装 / 卸反过来。
+            Self::write_sticker_install(
+                &tx,
+                &command.package_id,
+                !command.installed,
+                now_ms,
+                now_ms,
+            )?
+        };
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("sticker install settled commit: {e}")))?;
+        Ok(changed)
+    }
 
-        let out = store.ensure_user_storage(uid).expect("ensure user storage");
-        assert_eq!(
-            out.db_path.file_name().and_then(|v| v.to_str()),
-            Some("privchat.db")
-        );
-        assert!(out.db_path.exists());
-        assert!(!legacy.exists());
+    /// 同步下来的装 / 卸。自己对某个包的命令还没发出时跳过那个包；本地还不认识的包先记下
+    /// 安装状态，元数据等拉详情。返回实际变了的那些。
+    pub fn apply_remote_sticker_installs(
+        &self,
+        uid: &str,
+        installs: &[RemoteStickerInstall],
+    ) -> Result<Vec<RemoteStickerInstall>> {
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("apply sticker installs begin tx: {e}")))?;
+        let mut changed = Vec::new();
+        for install in installs {
+            if Self::sticker_install_pending(&tx, &install.package_id)? {
+                continue;
+            }
+            if Self::write_sticker_install(
+                &tx,
+                &install.package_id,
+                install.installed,
+                install.installed_at.unwrap_or(now_ms),
+                now_ms,
+            )? {
+                changed.push(install.clone());
+            }
+        }
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("apply sticker installs commit: {e}")))?;
+        Ok(changed)
     }
 
-    #[test]
-    fn session_roundtrip_uses_encrypted_account_store() {
-        let store = test_store();
-        let uid = "20002";
-        let login = LoginResult {
-            user_id: uid.parse().expect("uid"),
-            token: "token-secret-value".to_string(),
-            device_id: "device-a".to_string(),
-            refresh_token: Some("refresh-secret-value".to_string()),
-            expires_at: 0,
+    /// 调整已安装包的顺序：`package_ids` 里的排在前面，没列出的已安装包按原顺序跟在后面。
+    /// 没装的 ID 忽略。
+    pub fn reorder_sticker_packages(&self, uid: &str, package_ids: &[String]) -> Result<()> {
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("reorder sticker packages begin tx: {e}")))?;
+        let installed: Vec<String> = {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT package_id FROM sticker_package
+                     WHERE installed = 1 ORDER BY sort_order ASC, installed_at DESC",
+                )
+                .map_err(|e| Error::Storage(format!("prepare installed stickers: {e}")))?;
+            let rows = stmt
+                .query_map([], |row| row.get(0))
+                .map_err(|e| Error::Storage(format!("query installed stickers: {e}")))?;
+            rows.collect::<std::result::Result<_, _>>()
+                .map_err(|e| Error::Storage(format!("read installed stickers: {e}")))?
         };
-        store.save_login(uid, &login).expect("save login");
-        let snap = store
-            .load_session(uid)
-            .expect("load session")
-            .expect("session exists");
-        assert_eq!(snap.user_id, login.user_id);
-        assert_eq!(snap.token, login.token);
-        assert_eq!(snap.device_id, login.device_id);
-
-        let account_db = store.open_account_db(uid).expect("open account db");
-        let auth = account_db.open_tree("auth").expect("open auth tree");
-        let token_enc = auth.get("access_token_enc").expect("get access enc");
-        assert!(token_enc.is_some());
-        let plain = token_enc.expect("access enc exists");
-        assert_ne!(plain.as_ref(), login.token.as_bytes());
+        let mut order: Vec<&String> = Vec::with_capacity(installed.len());
+        for id in package_ids.iter().chain(installed.iter()) {
+            if installed.contains(id) && !order.contains(&id) {
+                order.push(id);
+            }
+        }
+        for (sort_order, package_id) in order.into_iter().enumerate() {
+            tx.execute(
+                "UPDATE sticker_package SET sort_order = ?2, updated_at = ?3 WHERE package_id = ?1",
+                params![package_id, sort_order as i64, now_ms],
+            )
+            .map_err(|e| Error::Storage(format!("reorder sticker package: {e}")))?;
+        }
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("reorder sticker packages commit: {e}")))?;
+        Ok(())
     }
 
-    // R8.7-impl — see privchat-sdk/docs/ACCOUNT_ACTIVE_AUDIT.md.
-    //
-    // `list_local_accounts` derives the active uid from `current_user_file`
-    // (single source of truth), not the legacy `K_ACTIVE_UID` sled key.
-    // These three tests lock that contract down:
-    //
-    //   1. After `save_login`, list reports the logged-in uid as active.
-    //   2. After `save_current_uid` (the path `set_current_uid` uses), list
-    //      reports the newly-current uid as active.
-    //   3. When the file says B but sled K_ACTIVE_UID still says A (the
-    //      drift scenario this round fixes), list trusts the file.
-    #[test]
-    fn list_local_accounts_after_save_login_reports_active() {
-        let store = test_store();
-        let uid = "30001";
-        let login = LoginResult {
-            user_id: uid.parse().expect("uid"),
-            token: "t".to_string(),
-            device_id: "d".to_string(),
-            refresh_token: None,
-            expires_at: 0,
-        };
-        store.save_login(uid, &login).expect("save login");
-        let (active, entries) = store.list_local_accounts().expect("list");
-        assert_eq!(
-            active.as_deref(),
-            Some(uid),
-            "active uid should match login"
-        );
-        assert!(
-            entries.iter().any(|e| e.uid == uid),
-            "logged-in uid should appear in entries"
-        );
+    pub fn get_sticker(&self, uid: &str, sticker_id: &str) -> Result<Option<Sticker>> {
+        let conn = self.conn_for_user(uid)?;
+        Ok(Self::query_stickers(
+            &conn,
+            &format!("{STICKER_SELECT} WHERE sticker_id = ?1"),
+            params![sticker_id],
+        )?
+        .pop())
     }
 
-    #[test]
-    fn list_local_accounts_follows_save_current_uid() {
-        let store = test_store();
-        // Two accounts, both fully logged in so both have entries.
-        let uid_a = "30010";
-        let uid_b = "30011";
-        for uid in [uid_a, uid_b] {
-            let login = LoginResult {
-                user_id: uid.parse().expect("uid"),
-                token: format!("t-{uid}"),
-                device_id: format!("d-{uid}"),
-                refresh_token: None,
-                expires_at: 0,
-            };
-            store.save_login(uid, &login).expect("save login");
-        }
-        // After both save_login calls, K_ACTIVE_UID=B and file=B (each
-        // save_login overwrites both). Switch the file pointer to A.
-        store.save_current_uid(uid_a).expect("save current uid");
-        let (active, entries) = store.list_local_accounts().expect("list");
-        assert_eq!(
-            active.as_deref(),
-            Some(uid_a),
-            "active should track the file, not the most-recent save_login"
-        );
-        assert_eq!(entries.len(), 2, "both accounts should remain listed");
+    /// 记一次使用：表情顶到最近使用的最前面，只留 [`sticker::RECENT_LIMIT`] 个。
+    pub fn record_sticker_use(&self, uid: &str, sticker: &Sticker, used_at: i64) -> Result<()> {
+        let mut conn = self.conn_for_user(uid)?;
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("record sticker use begin tx: {e}")))?;
+        Self::upsert_sticker(&tx, sticker, None, used_at)?;
+        tx.execute(
+            "INSERT INTO sticker_recent (sticker_id, used_at) VALUES (?1, ?2)
+             ON CONFLICT(sticker_id) DO UPDATE SET used_at = MAX(used_at, excluded.used_at)",
+            params![sticker.sticker_id, used_at],
+        )
+        .map_err(|e| Error::Storage(format!("upsert sticker_recent: {e}")))?;
+        tx.execute(
+            "DELETE FROM sticker_recent WHERE sticker_id NOT IN (
+                SELECT sticker_id FROM sticker_recent
+                ORDER BY used_at DESC, sticker_id ASC LIMIT ?1
+             )",
+            params![sticker::RECENT_LIMIT as i64],
+        )
+        .map_err(|e| Error::Storage(format!("trim sticker_recent: {e}")))?;
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("record sticker use commit: {e}")))?;
+        Ok(())
     }
 
-    #[test]
-    fn list_local_accounts_prefers_file_when_sled_drifts() {
-        let store = test_store();
-        let uid_a = "30020";
-        let uid_b = "30021";
-        for uid in [uid_a, uid_b] {
-            let login = LoginResult {
-                user_id: uid.parse().expect("uid"),
-                token: format!("t-{uid}"),
-                device_id: format!("d-{uid}"),
-                refresh_token: None,
-                expires_at: 0,
-            };
-            store.save_login(uid, &login).expect("save login");
-        }
-
-        // Construct an explicit drift: force the sled K_ACTIVE_UID back to
-        // A while leaving the file at B (state after second save_login).
-        // This mirrors the bug shape the audit identified — a `set_current_uid`-
-        // like path that updated only one of the two stores.
-        let global_db = store.open_global_db().expect("open global");
-        let accounts = global_db
-            .open_tree(GLOBAL_TREE_ACCOUNTS)
-            .expect("open accounts tree");
-        accounts
-            .insert(K_ACTIVE_UID, uid_a.as_bytes())
-            .expect("force K_ACTIVE_UID=A");
-        global_db.flush().expect("flush sled drift");
-
-        // Re-verify the drift is in place: sled says A, file should say B.
-        let sled_active = get_string(&accounts, K_ACTIVE_UID).expect("read sled");
-        assert_eq!(sled_active.as_deref(), Some(uid_a));
-        let file_active = store.load_current_uid().expect("read file");
-        assert_eq!(file_active.as_deref(), Some(uid_b));
+    /// 最近使用，新的在前。
+    pub fn list_recent_stickers(&self, uid: &str) -> Result<Vec<Sticker>> {
+        let conn = self.conn_for_user(uid)?;
+        Self::query_stickers(
+            &conn,
+            &format!(
+                "{STICKER_SELECT} JOIN sticker_recent r USING (sticker_id)
+                 ORDER BY r.used_at DESC, sticker_id ASC"
+            ),
+            [],
+        )
+    }
 
-        // The contract: list trusts the file.
-        let (active, _) = store.list_local_accounts().expect("list");
-        assert_eq!(
-            active.as_deref(),
-            Some(uid_b),
-            "drift must be resolved in favor of the file (current_user_file)"
-        );
+    /// 收藏 / 取消收藏。收藏时表情本身一并缓存（可能来自一条消息、不在任何已装的包里）。
+    /// 返回是否有变化。
+    pub fn set_sticker_favourite(
+        &self,
+        uid: &str,
+        sticker: &Sticker,
+        favourite: bool,
+    ) -> Result<bool> {
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("set sticker favourite begin tx: {e}")))?;
+        let changed = if favourite {
+            Self::upsert_sticker(&tx, sticker, None, now_ms)?;
+            tx.execute(
+                "INSERT OR IGNORE INTO sticker_favourite (sticker_id, added_at) VALUES (?1, ?2)",
+                params![sticker.sticker_id, now_ms],
+            )
+            .map_err(|e| Error::Storage(format!("insert sticker_favourite: {e}")))?
+                > 0
+        } else {
+            tx.execute(
+                "DELETE FROM sticker_favourite WHERE sticker_id = ?1",
+                params![sticker.sticker_id],
+            )
+            .map_err(|e| Error::Storage(format!("delete sticker_favourite: {e}")))?
+                > 0
+        };
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("set sticker favourite commit: {e}")))?;
+        Ok(changed)
     }
 
-    #[test]
-    fn load_session_migrates_legacy_sqlite_row() {
-        let store = test_store();
-        let uid = "20003";
-        let conn = store.conn_for_user(uid).expect("open user db");
-        conn.execute(
-            "INSERT OR REPLACE INTO auth_session(id, user_id, token, device_id, bootstrap_completed, updated_at)
-             VALUES(1, ?1, ?2, ?3, 1, ?4)",
-            params![
-                uid.parse::<u64>().expect("uid parse"),
-                "legacy-token",
-                "legacy-device",
-                chrono::Utc::now().timestamp_millis()
-            ],
+    /// 收藏的表情，新收藏的在前。
+    pub fn list_favourite_stickers(&self, uid: &str) -> Result<Vec<Sticker>> {
+        let conn = self.conn_for_user(uid)?;
+        Self::query_stickers(
+            &conn,
+            &format!(
+                "{STICKER_SELECT} JOIN sticker_favourite f USING (sticker_id)
+                 ORDER BY f.added_at DESC, sticker_id ASC"
+            ),
+            [],
         )
-        .expect("insert legacy session");
-
-        let snap = store
-            .load_session(uid)
-            .expect("load session")
-            .expect("session exists");
-        assert_eq!(snap.token, "legacy-token");
-        assert!(snap.bootstrap_completed);
+    }
 
-        let remain: i64 = conn
-            .query_row("SELECT COUNT(1) FROM auth_session", [], |r| r.get(0))
-            .expect("count auth_session");
-        assert_eq!(remain, 0);
+    pub fn get_message_extra(
+        &self,
+        uid: &str,
+        message_id: u64,
+    ) -> Result<Option<StoredMessageExtra>> {
+        let conn = self.conn_for_user(uid)?;
+        conn.query_row(
+            "SELECT message_id, channel_id, channel_type, readed, readed_count, unread_count, revoke,
+                    revoker, extra_version, is_mutual_deleted, content_edit, edited_at, need_upload, is_pinned,
+                    delivered, delivered_at
+             FROM message_extra WHERE message_id = ?1",
+            params![message_id as i64],
+            |row| {
+                Ok(StoredMessageExtra {
+                    message_id: row.get::<_, i64>(0)? as u64,
+                    channel_id: row.get::<_, i64>(1)? as u64,
+                    channel_type: row.get::<_, i32>(2)?,
+                    readed: row.get::<_, i32>(3)?,
+                    readed_count: row.get::<_, i32>(4)?,
+                    unread_count: row.get::<_, i32>(5)?,
+                    revoke: row.get::<_, i16>(6)? != 0,
+                    revoker: row.get::<_, Option<i64>>(7)?.map(|v| v as u64),
+                    extra_version: row.get::<_, i64>(8)?,
+                    is_mutual_deleted: row.get::<_, i16>(9)? != 0,
+                    content_edit: row.get::<_, Option<String>>(10)?,
+                    edited_at: row.get::<_, i32>(11)?,
+                    need_upload: row.get::<_, i16>(12)? != 0,
+                    is_pinned: row.get::<_, i32>(13)? != 0,
+                    delivered: row.get::<_, i32>(14)? != 0,
+                    delivered_at: row.get::<_, i64>(15)? as u64,
+                })
+            },
+        )
+        .optional()
+        .map_err(|e| Error::Storage(format!("get message extra: {e}")))
     }
 
-    #[test]
-    fn message_lifecycle_uses_message_id_pk() {
-        let store = test_store();
-        let uid = "10003";
-        let input = NewMessage {
-            channel_id: 100,
-            channel_type: 1,
-            from_uid: 200,
-            message_type: 1,
-            content: "hello".to_string(),
-            searchable_word: "hello".to_string(),
-            setting: 0,
-            extra: "{}".to_string(),
-            mime_type: None,
-            media_downloaded: false,
-            thumb_status: 0,
+    /// Mark a message as delivered (only upgrades 0→1, never downgrades).
+    /// Returns the local SQLite `message.id` when the row was actually updated.
+    pub fn mark_message_delivered(
+        &self,
+        uid: &str,
+        server_message_id: u64,
+        delivered_at: u64,
+    ) -> Result<Option<u64>> {
+        let conn = self.conn_for_user(uid)?;
+        // Resolve local message_id from server_message_id
+        let message_row: Option<(i64, i64, i32)> = conn
+            .query_row(
+                "SELECT id, channel_id, channel_type FROM message WHERE server_message_id = ?1 LIMIT 1",
+                params![server_message_id as i64],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )
+            .optional()
+            .map_err(|e| Error::Storage(format!("mark_delivered lookup: {e}")))?;
+        let Some((message_id, channel_id, channel_type)) = message_row else {
+            return Ok(None);
         };
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let updated = conn
+            .execute(
+                "INSERT INTO message_extra (
+                    message_id, channel_id, channel_type, delivered, delivered_at, extra_version
+                 ) VALUES (?1, ?2, ?3, 1, ?4, ?5)
+                 ON CONFLICT(message_id) DO UPDATE SET
+                    delivered = 1,
+                    delivered_at = excluded.delivered_at,
+                    extra_version = excluded.extra_version
+                 WHERE message_extra.delivered = 0",
+                params![
+                    message_id,
+                    channel_id,
+                    channel_type,
+                    delivered_at as i64,
+                    now_ms
+                ],
+            )
+            .map_err(|e| Error::Storage(format!("mark_delivered upsert: {e}")))?;
+        Ok((updated > 0).then_some(message_id as u64))
+    }
 
-        let message_id = store
-            .create_local_message(uid, &input, 0)
-            .expect("create local message");
-        let msg = store
+    /// 基于 read cursor 精确投影本地读状态：
+    /// - message.pts <= last_read_pts 视为已读
+    /// - message.pts > last_read_pts 且非自己发送 视为未读
+    pub fn project_channel_read_cursor(
+        &self,
+        uid: &str,
+        channel_id: u64,
+        channel_type: i32,
+        last_read_pts: u64,
+    ) -> Result<()> {
+        let mut conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let uid_num = uid.parse::<u64>().unwrap_or(0);
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::Storage(format!("project channel cursor begin tx: {e}")))?;
+        let existing_keep_pts: u64 = tx
+            .query_row(
+                "SELECT keep_pts
+                 FROM channel_extra
+                 WHERE channel_id = ?1 AND channel_type = ?2
+                 LIMIT 1",
+                params![channel_id as i64, channel_type],
+                |r| Ok(r.get::<_, i64>(0)? as u64),
+            )
+            .optional()
+            .map_err(|e| Error::Storage(format!("project channel cursor query keep_pts: {e}")))?
+            .unwrap_or(0);
+        let effective_read_pts = existing_keep_pts.max(last_read_pts);
+
+        if effective_read_pts == existing_keep_pts {
+            tx.commit()
+                .map_err(|e| Error::Storage(format!("project channel cursor commit tx: {e}")))?;
+            return Ok(());
+        }
+
+        tx.execute(
+            "INSERT INTO channel_extra (
+                channel_id, channel_type, browse_to, keep_pts, keep_offset_y,
+                draft, draft_updated_at, version
+            ) VALUES (?1, ?2, ?3, ?3, 0, '', 0, ?4)
+            ON CONFLICT(channel_id, channel_type) DO UPDATE SET
+                browse_to = MAX(channel_extra.browse_to, excluded.browse_to),
+                keep_pts = MAX(channel_extra.keep_pts, excluded.keep_pts),
+                version = excluded.version",
+            params![
+                channel_id as i64,
+                channel_type,
+                effective_read_pts as i64,
+                now_ms
+            ],
+        )
+        .map_err(|e| Error::Storage(format!("project channel cursor upsert channel_extra: {e}")))?;
+
+        tx.execute(
+            "INSERT INTO message_extra (
+                message_id, channel_id, channel_type, readed, readed_count, unread_count, extra_version
+            )
+            SELECT
+                m.id AS message_id,
+                m.channel_id,
+                m.channel_type,
+                1 AS readed,
+                1 AS readed_count,
+                0 AS unread_count,
+                ?5 AS extra_version
+            FROM message m
+            WHERE m.channel_id = ?1
+              AND m.channel_type = ?2
+              AND COALESCE(m.pts, 0) > ?3
+              AND COALESCE(m.pts, 0) <= ?4
+            ON CONFLICT(message_id) DO UPDATE SET
+                readed = 1,
+                readed_count = CASE
+                    WHEN message_extra.readed_count < 1 THEN 1
+                    ELSE message_extra.readed_count
+                END,
+                unread_count = 0,
+                extra_version = excluded.extra_version",
+            params![
+                channel_id as i64,
+                channel_type,
+                existing_keep_pts as i64,
+                effective_read_pts as i64,
+                now_ms
+            ],
+        )
+        .map_err(|e| Error::Storage(format!("project channel cursor upsert message_extra: {e}")))?;
+
+        let newly_read_unread_count: i64 = tx
+            .query_row(
+                "SELECT COUNT(1)
+                 FROM message m
+                 WHERE m.channel_id = ?1
+                   AND m.channel_type = ?2
+                   AND m.from_uid != ?3
+                   AND COALESCE(m.pts, 0) > ?4
+                   AND COALESCE(m.pts, 0) <= ?5",
+                params![
+                    channel_id as i64,
+                    channel_type,
+                    uid_num as i64,
+                    existing_keep_pts as i64,
+                    effective_read_pts as i64
+                ],
+                |r| r.get(0),
+            )
+            .map_err(|e| {
+                Error::Storage(format!(
+                    "project channel cursor query newly read count: {e}"
+                ))
+            })?;
+
+        let existing_unread: Option<i64> = tx
+            .query_row(
+                "SELECT unread_count
+                 FROM channel
+                 WHERE channel_id = ?1
+                 LIMIT 1",
+                params![channel_id as i64],
+                |r| r.get::<_, i64>(0),
+            )
+            .optional()
+            .map_err(|e| {
+                Error::Storage(format!("project channel cursor query existing unread: {e}"))
+            })?;
+
+        // 会话行只能由 channel 实体同步创建（CLIENT_UI_SPEC §3.1：会话列表主数据源是
+        // channel 表）。read cursor 是读状态的 projection（READ_STATUS_SPEC §6），不是会话
+        // 的来源——这里曾是 `INSERT ... ON CONFLICT`，给尚无 channel 实体的频道凭空造出
+        // 只有 id/type/unread 的裸行，列表上就是一条无名无头像的会话。
+        //
+        // 频道实体还没到就不投影 unread：读水位已经落在 channel_extra.keep_pts，实体到达时
+        // upsert_channel 会带上服务端的 unread baseline，不丢状态。
+        if let Some(current) = existing_unread {
+            let unread_total = std::cmp::max(0, current.saturating_sub(newly_read_unread_count));
+            tx.execute(
+                "UPDATE channel
+                    SET unread_count = ?2,
+                        updated_at = ?3
+                  WHERE channel_id = ?1",
+                params![channel_id as i64, unread_total as i32, now_ms],
+            )
+            .map_err(|e| Error::Storage(format!("project channel cursor update channel: {e}")))?;
+        }
+        tx.commit()
+            .map_err(|e| Error::Storage(format!("project channel cursor commit tx: {e}")))?;
+        Ok(())
+    }
+
+    pub fn get_channel_unread_count(
+        &self,
+        uid: &str,
+        channel_id: u64,
+        channel_type: i32,
+    ) -> Result<i32> {
+        let conn = self.conn_for_user(uid)?;
+        let from_channel = conn
+            .query_row(
+                "SELECT unread_count FROM channel WHERE channel_id = ?1 LIMIT 1",
+                params![channel_id as i64],
+                |r| r.get::<_, i32>(0),
+            )
+            .optional()
+            .map_err(|e| Error::Storage(format!("query channel unread_count: {e}")))?;
+        if let Some(v) = from_channel {
+            return Ok(v);
+        }
+        let from_extra: i64 = conn
+            .query_row(
+                "SELECT COALESCE(SUM(unread_count), 0)
+                 FROM message_extra
+                 WHERE channel_id = ?1 AND channel_type = ?2",
+                params![channel_id as i64, channel_type],
+                |r| r.get(0),
+            )
+            .map_err(|e| Error::Storage(format!("query message_extra unread_count: {e}")))?;
+        Ok(from_extra as i32)
+    }
+
+    pub fn count_materialized_unread(
+        &self,
+        uid: &str,
+        channel_id: u64,
+        channel_type: i32,
+    ) -> Result<i32> {
+        let conn = self.conn_for_user(uid)?;
+        let uid_num = uid.parse::<u64>().unwrap_or(0);
+        let keep_pts: i64 = conn
+            .query_row(
+                "SELECT keep_pts
+                 FROM channel_extra
+                 WHERE channel_id = ?1 AND channel_type = ?2
+                 LIMIT 1",
+                params![channel_id as i64, channel_type],
+                |r| r.get::<_, i64>(0),
+            )
+            .optional()
+            .map_err(|e| Error::Storage(format!("query channel keep_pts: {e}")))?
+            .unwrap_or(0);
+        let count: i64 = conn
+            .query_row(
+                "SELECT COUNT(1)
+                 FROM message m
+                 WHERE m.channel_id = ?1
+                   AND m.channel_type = ?2
+                   AND m.from_uid != ?3
+                   AND COALESCE(m.pts, 0) > ?4",
+                params![channel_id as i64, channel_type, uid_num as i64, keep_pts],
+                |r| r.get(0),
+            )
+            .map_err(|e| Error::Storage(format!("count materialized unread: {e}")))?;
+        Ok(count as i32)
+    }
+
+    pub fn get_total_unread_count(&self, uid: &str, exclude_muted: bool) -> Result<i32> {
+        let conn = self.conn_for_user(uid)?;
+        let sql = if exclude_muted {
+            "SELECT COALESCE(SUM(unread_count), 0) FROM channel WHERE mute = 0"
+        } else {
+            "SELECT COALESCE(SUM(unread_count), 0) FROM channel"
+        };
+        let total: i64 = conn
+            .query_row(sql, [], |r| r.get(0))
+            .map_err(|e| Error::Storage(format!("get total unread count: {e}")))?;
+        Ok(total as i32)
+    }
+
+    /// 角标聚合用：所有可见会话的未读与 mute。隐藏的会话（`is_deleted = 1`）不进角标。
+    pub(crate) fn list_channel_unread_rows(&self, uid: &str) -> Result<Vec<ChannelUnreadRow>> {
+        let conn = self.conn_for_user(uid)?;
+        let mut stmt = conn
+            .prepare(
+                "SELECT channel_id, channel_type, COALESCE(unread_count, 0), COALESCE(mute, 0)
+                 FROM channel
+                 WHERE COALESCE(is_deleted, 0) = 0",
+            )
+            .map_err(|e| Error::Storage(format!("prepare channel unread rows: {e}")))?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok(ChannelUnreadRow {
+                    channel_id: row.get::<_, i64>(0)? as u64,
+                    channel_type: row.get::<_, i32>(1)?,
+                    unread_count: row.get::<_, i32>(2)?,
+                    mute: row.get::<_, i32>(3)? != 0,
+                })
+            })
+            .map_err(|e| Error::Storage(format!("query channel unread rows: {e}")))?;
+        let mut out = Vec::new();
+        for row in rows {
+            out.push(row.map_err(|e| Error::Storage(format!("decode channel unread row: {e}")))?);
+        }
+        Ok(out)
+    }
+
+    /// 推送落库（[`crate::push_ingest`]）算角标用：可见会话的未读、mute、未读 @，以及
+    /// `channel_pref` 镜像里的通知模式 / 收藏 / 低优先级。扩展进程打不开账号 kv，
+    /// 偏好只能从镜像读；tag 和文件夹与角标数无关，留空。
+    pub(crate) fn list_channel_unread_inputs(
+        &self,
+        uid: &str,
+        user_id: u64,
+    ) -> Result<Vec<crate::unread_badge::ChannelUnreadInput>> {
+        let conn = self.conn_for_user(uid)?;
+        let mut stmt = conn
+            .prepare(
+                "SELECT COALESCE(c.unread_count, 0), COALESCE(c.mute, 0),
+                        COALESCE(p.notification_mode, 0), COALESCE(p.favourite, 0),
+                        COALESCE(p.low_priority, 0),
+                        (SELECT COUNT(*) FROM mention m
+                          WHERE m.channel_id = c.channel_id AND m.channel_type = c.channel_type
+                            AND m.mentioned_user_id = ?1 AND m.is_read = 0)
+                 FROM channel c
+                 LEFT JOIN channel_pref p
+                    ON p.channel_id = c.channel_id AND p.channel_type = c.channel_type
+                 WHERE COALESCE(c.is_deleted, 0) = 0",
+            )
+            .map_err(|e| Error::Storage(format!("prepare channel unread inputs: {e}")))?;
+        let rows = stmt
+            .query_map(params![user_id as i64], |row| {
+                Ok(crate::unread_badge::ChannelUnreadInput {
+                    unread_count: row.get::<_, i32>(0)?.max(0) as u32,
+                    channel_muted: row.get::<_, i32>(1)? != 0,
+                    notification_mode: row.get::<_, i32>(2)?,
+                    favourite: row.get::<_, i32>(3)? != 0,
+                    low_priority: row.get::<_, i32>(4)? != 0,
+                    mention_count: row.get::<_, i64>(5)?.max(0) as u32,
+                    ..Default::default()
+                })
+            })
+            .map_err(|e| Error::Storage(format!("query channel unread inputs: {e}")))?;
+        let mut out = Vec::new();
+        for row in rows {
+            out.push(row.map_err(|e| Error::Storage(format!("decode channel unread input: {e}")))?);
+        }
+        Ok(out)
+    }
+
+    /// 推送落库（[`crate::push_ingest`]）之后推进会话行，对应 actor 里的
+    /// `update_channel_last_message`，但只用一条 upsert：扩展进程没有 actor 替它串行化，
+    /// 读-改-写会和主进程互相覆盖。
+    ///
+    /// 预览只在这条不比现有预览旧（按发送时间）时覆盖；会话行不存在就建一行，名字留空，
+    /// 等实体同步补上。返回推进后的未读数。
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn apply_push_channel_preview(
+        &self,
+        uid: &str,
+        channel_id: u64,
+        channel_type: i32,
+        message_id: u64,
+        content: &str,
+        timestamp_ms: i64,
+        bump_unread: bool,
+        peer_user_id: Option<u64>,
+    ) -> Result<i32> {
+        let conn = self.conn_for_user(uid)?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        conn.execute(
+            "INSERT INTO channel (
+                channel_id, channel_type, unread_count, last_msg_timestamp,
+                last_local_message_id, last_msg_content, peer_user_id, created_at, updated_at
+             ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?8)
+             ON CONFLICT(channel_id) DO UPDATE SET
+                unread_count = COALESCE(channel.unread_count, 0) + excluded.unread_count,
+                last_msg_timestamp = CASE
+                    WHEN excluded.last_msg_timestamp >= IFNULL(channel.last_msg_timestamp, 0)
+                    THEN excluded.last_msg_timestamp ELSE channel.last_msg_timestamp END,
+                last_local_message_id = CASE
+                    WHEN excluded.last_msg_timestamp >= IFNULL(channel.last_msg_timestamp, 0)
+                    THEN excluded.last_local_message_id ELSE channel.last_local_message_id END,
+                last_msg_content = CASE
+                    WHEN excluded.last_msg_timestamp >= IFNULL(channel.last_msg_timestamp, 0)
+                    THEN excluded.last_msg_content ELSE channel.last_msg_content END,
+                is_deleted = CASE
+                    WHEN excluded.last_msg_timestamp > IFNULL(channel.last_msg_timestamp, 0)
+                    THEN 0 ELSE channel.is_deleted END,
+                peer_user_id = COALESCE(channel.peer_user_id, excluded.peer_user_id),
+                updated_at = excluded.updated_at",
+            params![
+                channel_id as i64,
+                channel_type,
+                if bump_unread { 1 } else { 0 },
+                timestamp_ms,
+                message_id as i64,
+                content,
+                peer_user_id.map(|v| v as i64),
+                now_ms
+            ],
+        )
+        .map_err(|e| Error::Storage(format!("apply push channel preview: {e}")))?;
+        conn.query_row(
+            "SELECT COALESCE(unread_count, 0) FROM channel WHERE channel_id = ?1",
+            params![channel_id as i64],
+            |row| row.get::<_, i32>(0),
+        )
+        .map_err(|e| Error::Storage(format!("read push channel unread: {e}")))
+    }
+
+    /// 渲染推送通知要的会话与发送者信息。发送者显示名和群成员列表同一套回落顺序；
+    /// 静音与否和角标一样按 [`crate::unread_badge::channel_is_muted`] 算。
+    pub(crate) fn push_notification_context(
+        &self,
+        uid: &str,
+        channel_id: u64,
+        channel_type: i32,
+        sender_id: u64,
+    ) -> Result<PushNotificationContext> {
+        let conn = self.conn_for_user(uid)?;
+        let (group_alias, user_alias, nickname, username) = conn
+            .query_row(
+                "SELECT
+                    NULLIF(TRIM(gm.alias), ''), NULLIF(TRIM(u.alias), ''),
+                    NULLIF(TRIM(u.nickname), ''), NULLIF(TRIM(u.username), '')
+                 FROM (SELECT ?1 AS user_id) AS s
+                 LEFT JOIN \"user\" AS u ON u.user_id = s.user_id AND u.is_deleted = 0
+                 LEFT JOIN group_member AS gm
+                    ON ?3 = 2 AND gm.group_id = ?2 AND gm.user_id = s.user_id",
+                params![sender_id as i64, channel_id as i64, channel_type],
+                |row| {
+                    Ok((
+                        row.get::<_, Option<String>>(0)?,
+                        row.get::<_, Option<String>>(1)?,
+                        row.get::<_, Option<String>>(2)?,
+                        row.get::<_, Option<String>>(3)?,
+                    ))
+                },
+            )
+            .map_err(|e| Error::Storage(format!("query push sender name: {e}")))?;
+        let sender_name = resolve_group_member_display_name(
+            group_alias.as_deref(),
+            user_alias.as_deref(),
+            nickname.as_deref(),
+            username.as_deref(),
+            sender_id,
+        );
+        let channel = conn
+            .query_row(
+                "SELECT
+                    COALESCE(NULLIF(TRIM(c.channel_remark), ''), NULLIF(TRIM(c.channel_name), ''),
+                             NULLIF(TRIM(g.name), '')),
+                    COALESCE(c.mute, 0), COALESCE(c.unread_count, 0),
+                    COALESCE(p.notification_mode, 0)
+                 FROM channel AS c
+                 LEFT JOIN \"group\" AS g ON c.channel_type = 2 AND g.group_id = c.channel_id
+                 LEFT JOIN channel_pref AS p
+                    ON p.channel_id = c.channel_id AND p.channel_type = c.channel_type
+                 WHERE c.channel_id = ?1 AND c.channel_type = ?2",
+                params![channel_id as i64, channel_type],
+                |row| {
+                    Ok((
+                        row.get::<_, Option<String>>(0)?,
+                        row.get::<_, i32>(1)? != 0,
+                        row.get::<_, i32>(2)?,
+                        row.get::<_, i32>(3)?,
+                    ))
+                },
+            )
+            .optional()
+            .map_err(|e| Error::Storage(format!("query push channel context: {e}")))?;
+        let (channel_title, mute, channel_unread_count, notification_mode) = channel.unwrap_or((
+            None,
+            false,
+            0,
+            crate::unread_badge::notification_mode::DEFAULT,
+        ));
+        Ok(PushNotificationContext {
+            sender_name,
+            channel_title,
+            channel_muted: crate::unread_badge::channel_is_muted(notification_mode, mute),
+            notification_mode,
+            channel_unread_count,
+        })
+    }
+
+    pub fn save_current_uid(&self, uid: &str) -> Result<()> {
+        if let Some(scope) = &self.uid_scope {
+            // 检查和绑定在同一把 uid_scopes 锁下：分开做的话两个槽位可以同时通过检查，
+            // 再各自绑上同一个 uid。
+            let scopes = self
+                .uid_scopes
+                .lock()
+                .map_err(|_| Error::Storage("lock uid scopes failed".to_string()))?;
+            Self::check_uid_not_bound(&scopes, scope, uid)?;
+            *scope
+                .lock()
+                .map_err(|_| Error::Storage("lock uid scope failed".to_string()))? =
+                Some(uid.to_string());
+            return Ok(());
+        }
+        self.save_foreground_uid(uid)
+    }
+
+    /// 多账号时同一个 uid 只能绑在一个实例上：两个 actor 写同一份库、抢同一条长连接，
+    /// 会话和未读都会乱。只看其他 scope，本实例刷新自己的 token 不受影响。
+    ///
+    /// 这里只是提前失败；真正的绑定在 [`Self::save_current_uid`]，在锁内再查一次。
+    fn ensure_uid_not_open_elsewhere(&self, uid: &str) -> Result<()> {
+        let Some(own) = &self.uid_scope else {
+            return Ok(());
+        };
+        let scopes = self
+            .uid_scopes
+            .lock()
+            .map_err(|_| Error::Storage("lock uid scopes failed".to_string()))?;
+        Self::check_uid_not_bound(&scopes, own, uid)
+    }
+
+    /// 调用方持有 `uid_scopes` 的锁。
+    fn check_uid_not_bound(
+        scopes: &[Weak<Mutex<Option<String>>>],
+        own: &UidScope,
+        uid: &str,
+    ) -> Result<()> {
+        let taken = scopes
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|scope| !Arc::ptr_eq(scope, own))
+            .any(|scope| scope.lock().ok().and_then(|bound| bound.clone()).as_deref() == Some(uid));
+        if taken {
+            return Err(Error::InvalidState(format!(
+                "account {uid} is already open in another slot"
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn load_current_uid(&self) -> Result<Option<String>> {
+        if let Some(scope) = &self.uid_scope {
+            return scope
+                .lock()
+                .map(|uid| uid.clone())
+                .map_err(|_| Error::Storage("lock uid scope failed".to_string()));
+        }
+        self.load_foreground_uid()
+    }
+
+    /// `current_user` 文件：冷启动恢复哪个账号、账号列表里哪个是活跃的。
+    /// 多账号时只有前台账号写它。
+    pub(crate) fn save_foreground_uid(&self, uid: &str) -> Result<()> {
+        std::fs::write(self.current_user_file(), uid.as_bytes())
+            .map_err(|e| Error::Storage(format!("write current uid: {e}")))?;
+        Ok(())
+    }
+
+    pub(crate) fn load_foreground_uid(&self) -> Result<Option<String>> {
+        let path = self.current_user_file();
+        if !path.exists() {
+            return Ok(None);
+        }
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| Error::Storage(format!("read current uid: {e}")))?;
+        let uid = raw.trim();
+        if uid.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(uid.to_string()))
+        }
+    }
+
+    pub fn clear_current_uid(&self) -> Result<()> {
+        if let Some(scope) = &self.uid_scope {
+            *scope
+                .lock()
+                .map_err(|_| Error::Storage("lock uid scope failed".to_string()))? = None;
+            return Ok(());
+        }
+        self.clear_foreground_uid()
+    }
+
+    pub(crate) fn clear_foreground_uid(&self) -> Result<()> {
+        let path = self.current_user_file();
+        if path.exists() {
+            std::fs::remove_file(path)
+                .map_err(|e| Error::Storage(format!("clear current uid: {e}")))?;
+        }
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub fn wipe_user_full(&self, uid: &str) -> Result<()> {
+        if let Ok(mut guard) = self.account_dbs.lock() {
+            let _ = guard.remove(uid);
+        }
+        let paths = self.storage_paths(uid);
+        if paths.user_root.exists() {
+            std::fs::remove_dir_all(&paths.user_root)
+                .map_err(|e| Error::Storage(format!("remove user dir: {e}")))?;
+        }
+        let global_db = self.open_global_db()?;
+        let accounts = global_db
+            .open_tree(GLOBAL_TREE_ACCOUNTS)
+            .map_err(|e| Error::Storage(format!("open accounts tree: {e}")))?;
+        accounts
+            .remove(Self::k_acct_last_login(uid).as_bytes())
+            .map_err(|e| Error::Storage(format!("remove account last_login_at: {e}")))?;
+        accounts
+            .remove(Self::k_acct_created_at(uid).as_bytes())
+            .map_err(|e| Error::Storage(format!("remove account created_at: {e}")))?;
+        if let Some(active_uid) = get_string(&accounts, K_ACTIVE_UID)? {
+            if active_uid == uid {
+                accounts
+                    .remove(K_ACTIVE_UID)
+                    .map_err(|e| Error::Storage(format!("remove active uid: {e}")))?;
+            }
+        }
+        global_db
+            .flush()
+            .map_err(|e| Error::Storage(format!("flush global db: {e}")))?;
+        if self
+            .load_current_uid()?
+            .as_ref()
+            .map(|v| v == uid)
+            .unwrap_or(false)
+        {
+            self.clear_current_uid()?;
+        }
+        Ok(())
+    }
+
+    /// The send time to persist, in milliseconds.
+    ///
+    /// Guards the one thing a caller can get wrong here that no reader can
+    /// detect afterwards: a seconds-resolution value. The wire carries both —
+    /// `PushMessageRequest.timestamp` is a u32 of seconds (a millisecond epoch
+    /// does not fit in u32 at all), while history and sync carry milliseconds —
+    /// and a seconds value stored as milliseconds lands in 1970, which puts a
+    /// message received today before the entire conversation. The threshold is
+    /// 10^11 ms = 1973: every real millisecond timestamp is above it and every
+    /// plausible seconds one is below.
+    fn sent_at_ms(timestamp: i64, fallback_ms: i64) -> i64 {
+        const MIN_PLAUSIBLE_MS: i64 = 100_000_000_000;
+        match timestamp {
+            t if t >= MIN_PLAUSIBLE_MS => t,
+            t if t > 0 => t.saturating_mul(1_000),
+            // No usable time at all: local arrival is a worse answer than the
+            // real one, but a far better one than 1970.
+            _ => fallback_ms,
+        }
+    }
+
+    /// 仅 crate 内可见：测试要用外部连接打开这个加密库（例如装一个
+    /// 故意让事务失败的 trigger）。派生规则本身不变。
+    pub(crate) fn derive_encryption_key(uid: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(b"privchat_sdk_encryption_key_v1");
+        hasher.update(uid.as_bytes());
+        let result = hasher.finalize();
+        hex::encode(result)
+    }
+
+    pub fn kv_put(&self, uid: &str, key: &str, value: &[u8]) -> Result<()> {
+        let tree = self.account_tree(uid, ACCOUNT_TREE_KV)?;
+        tree.insert(key.as_bytes(), value)
+            .map_err(|e| Error::Storage(format!("sled kv put: {e}")))?;
+        Ok(())
+    }
+
+    pub fn kv_get(&self, uid: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let tree = self.account_tree(uid, ACCOUNT_TREE_KV)?;
+        let v = tree
+            .get(key.as_bytes())
+            .map_err(|e| Error::Storage(format!("sled kv get: {e}")))?;
+        Ok(v.map(|x| x.to_vec()))
+    }
+
+    pub fn kv_delete(&self, uid: &str, key: &str) -> Result<()> {
+        let tree = self.account_tree(uid, ACCOUNT_TREE_KV)?;
+        tree.remove(key.as_bytes())
+            .map_err(|e| Error::Storage(format!("sled kv delete: {e}")))?;
+        Ok(())
+    }
+
+    pub fn kv_scan_prefix(&self, uid: &str, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let tree = self.account_tree(uid, ACCOUNT_TREE_KV)?;
+        let mut out = Vec::new();
+        for entry in tree.scan_prefix(prefix.as_bytes()) {
+            let (key, value) =
+                entry.map_err(|e| Error::Storage(format!("sled kv scan prefix: {e}")))?;
+            out.push((String::from_utf8_lossy(&key).into_owned(), value.to_vec()));
+        }
+        Ok(out)
+    }
+
+    fn pending_timeline_mutation_key(mutation: &PendingTimelineMutation) -> String {
+        format!(
+            "{PENDING_TIMELINE_MUTATION_PREFIX}:{}:{:020}:{:020}:{:020}",
+            mutation.channel_type,
+            mutation.channel_id,
+            mutation.target_server_message_id,
+            mutation.event_id
+        )
+    }
+
+    fn pending_timeline_mutation_prefix(
+        channel_id: u64,
+        channel_type: i32,
+        target_server_message_id: u64,
+    ) -> String {
+        format!(
+            "{PENDING_TIMELINE_MUTATION_PREFIX}:{channel_type}:{channel_id:020}:{target_server_message_id:020}:"
+        )
+    }
+
+    fn encode_pending_timeline_mutation(mutation: &PendingTimelineMutation) -> Vec<u8> {
+        let mut value = Vec::with_capacity(17 + mutation.canonical_event.len());
+        value.push(1);
+        value.extend_from_slice(&mutation.event_id.to_be_bytes());
+        value.extend_from_slice(&mutation.pts.to_be_bytes());
+        value.extend_from_slice(&mutation.canonical_event);
+        value
+    }
+
+    fn decode_pending_timeline_mutation(
+        channel_id: u64,
+        channel_type: i32,
+        target_server_message_id: u64,
+        value: &[u8],
+    ) -> Result<PendingTimelineMutation> {
+        if value.len() < 18 || value[0] != 1 {
+            return Err(Error::Storage(
+                "invalid pending timeline mutation record".to_string(),
+            ));
+        }
+        let event_id = u64::from_be_bytes(value[1..9].try_into().map_err(|_| {
+            Error::Storage("invalid pending timeline mutation event_id".to_string())
+        })?);
+        let pts =
+            u64::from_be_bytes(value[9..17].try_into().map_err(|_| {
+                Error::Storage("invalid pending timeline mutation pts".to_string())
+            })?);
+        Ok(PendingTimelineMutation {
+            channel_id,
+            channel_type,
+            target_server_message_id,
+            event_id,
+            pts,
+            canonical_event: value[17..].to_vec(),
+        })
+    }
+
+    pub fn put_pending_timeline_mutation(
+        &self,
+        uid: &str,
+        mutation: &PendingTimelineMutation,
+    ) -> Result<()> {
+        let tree = self.account_tree(uid, ACCOUNT_TREE_KV)?;
+        tree.insert(
+            Self::pending_timeline_mutation_key(mutation).as_bytes(),
+            Self::encode_pending_timeline_mutation(mutation),
+        )
+        .map_err(|e| Error::Storage(format!("put pending timeline mutation: {e}")))?;
+        tree.flush()
+            .map_err(|e| Error::Storage(format!("flush pending timeline mutation: {e}")))?;
+        Ok(())
+    }
+
+    pub fn list_pending_timeline_mutations(
+        &self,
+        uid: &str,
+        channel_id: u64,
+        channel_type: i32,
+        target_server_message_id: u64,
+    ) -> Result<Vec<PendingTimelineMutation>> {
+        let tree = self.account_tree(uid, ACCOUNT_TREE_KV)?;
+        let prefix = Self::pending_timeline_mutation_prefix(
+            channel_id,
+            channel_type,
+            target_server_message_id,
+        );
+        let mut rows = Vec::new();
+        let mut corrupt_keys = Vec::new();
+        for entry in tree.scan_prefix(prefix.as_bytes()) {
+            let (key, value) = entry
+                .map_err(|e| Error::Storage(format!("scan pending timeline mutation: {e}")))?;
+            match Self::decode_pending_timeline_mutation(
+                channel_id,
+                channel_type,
+                target_server_message_id,
+                value.as_ref(),
+            ) {
+                Ok(row) => rows.push(row),
+                Err(_) => corrupt_keys.push(key),
+            }
+        }
+        for key in corrupt_keys {
+            let _ = tree.remove(key);
+        }
+        rows.sort_by_key(|row| (row.pts, row.event_id));
+        Ok(rows)
+    }
+
+    pub fn delete_pending_timeline_mutation(
+        &self,
+        uid: &str,
+        mutation: &PendingTimelineMutation,
+    ) -> Result<()> {
+        let tree = self.account_tree(uid, ACCOUNT_TREE_KV)?;
+        tree.remove(Self::pending_timeline_mutation_key(mutation).as_bytes())
+            .map_err(|e| Error::Storage(format!("delete pending timeline mutation: {e}")))?;
+        tree.flush()
+            .map_err(|e| Error::Storage(format!("flush pending timeline mutation delete: {e}")))?;
+        Ok(())
+    }
+
+    fn open_db(path: &Path) -> Result<sled::Db> {
+        sled::open(path)
+            .map_err(|e| Error::Storage(format!("open sled db {}: {e}", path.display())))
+    }
+
+    /// 旧 sled 出站队列的读写口 —— **只给测试造旧数据用**。
+    ///
+    /// 生产代码里已经没有这条路了：命令都在 SQLite `outbox`，升级后唯一还会
+    /// 碰 sled 队列的是 `migrate_sled_queues_into_outbox`，而它直接开 db 文件，
+    /// 不经过这里。把这几个方法留在 `LocalStore` 的公开表面上，等于给新代码留
+    /// 了一条「再写一次旧队列」的入口——那正是一个数据库两套真相的老问题。
+    #[cfg(test)]
+    pub(crate) fn test_seed_legacy_queue(
+        &self,
+        uid: &str,
+        kind: LegacyQueueKind,
+        message_id: u64,
+        payload: &[u8],
+    ) -> Result<()> {
+        let paths = self.ensure_user_storage(uid)?;
+        let db = Self::open_db(self.legacy_queue_path(&paths, kind))?;
+        db.insert(message_id.to_be_bytes(), payload)
+            .map_err(|e| Error::Storage(format!("seed legacy queue: {e}")))?;
+        db.flush()
+            .map_err(|e| Error::Storage(format!("seed legacy queue flush: {e}")))?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn test_legacy_queue_len(&self, uid: &str, kind: LegacyQueueKind) -> Result<usize> {
+        let paths = self.ensure_user_storage(uid)?;
+        let path = self.legacy_queue_path(&paths, kind);
+        if !path.exists() {
+            return Ok(0);
+        }
+        Ok(Self::open_db(path)?.len())
+    }
+
+    #[cfg(test)]
+    fn legacy_queue_path<'a>(&self, paths: &'a StoragePaths, kind: LegacyQueueKind) -> &'a Path {
+        match kind {
+            LegacyQueueKind::Normal => paths.normal_queue_path.as_path(),
+            LegacyQueueKind::File => paths
+                .file_queue_paths
+                .first()
+                .expect("legacy file queue path"),
+        }
+    }
+}
+
+/// 旧队列种类，仅测试用（见 `test_seed_legacy_queue`）。
+#[cfg(test)]
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum LegacyQueueKind {
+    Normal,
+    File,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        get_string, resolve_group_member_display_name, LegacyQueueKind, LocalStore,
+        GLOBAL_TREE_ACCOUNTS, K_ACTIVE_UID,
+    };
+    use crate::channel_query::{
+        ChannelFolderInput, ChannelListFilter, ChannelListQuery, ChannelPrefRow, ChannelSortKey,
+        ChannelVisibility,
+    };
+    use crate::live_location::{LiveLocationCommand, LiveLocationPosition, LiveLocationUpdate};
+    use crate::message_pin::{MessagePinCommand, RemotePin};
+    use crate::poll::{PollTallyUpdate, PollVoteCommand};
+    use crate::sticker::{RemoteStickerInstall, Sticker, StickerInstallCommand, StickerPackage};
+    use crate::{
+        Error, LoginResult, MessageRevisionInput, NewMessage, PendingTimelineMutation,
+        UpsertChannelExtraInput, UpsertChannelInput, UpsertGroupInput, UpsertRemoteMessageInput,
+        UpsertUserInput,
+    };
+    use rand::RngCore;
+    use rusqlite::params;
+    use std::path::PathBuf;
+
+    fn test_store() -> LocalStore {
+        let mut rand_bytes = [0u8; 6];
+        rand::thread_rng().fill_bytes(&mut rand_bytes);
+        let dir = PathBuf::from(format!(
+            "/tmp/privchat-rust-test-{}-{}",
+            chrono::Utc::now().timestamp_micros(),
+            hex::encode(rand_bytes)
+        ));
+        LocalStore::open_at(dir).expect("open test store")
+    }
+
+    /// 退出登录不是注销账号：凭证清掉了，bootstrap 水位必须还读得出来。
+    ///
+    /// 这条水位决定下次登录走增量还是全量。它一直单独存在 META 树里、`clear_session`
+    /// 也从不删它，可唯一的读法要求 access_token 还在——于是退出后它就"消失"了，
+    /// 同一个账号重新登录被当成没同步过，750 人的群连同 761 个会话重跑一遍全量，
+    /// 用户盯着「数据初始化中」十几秒。真机实测 15.9s，修完 0.5s。
+    #[test]
+    fn logging_out_keeps_the_bootstrap_watermark() {
+        let store = test_store();
+        let uid = "100000156";
+        store
+            .save_login(
+                uid,
+                &LoginResult {
+                    user_id: 100_000_156,
+                    token: "token".to_string(),
+                    device_id: "device".to_string(),
+                    refresh_token: None,
+                    expires_at: 0,
+                },
+            )
+            .expect("save login");
+        store
+            .set_bootstrap_completed(uid, true)
+            .expect("mark bootstrap completed");
+
+        store.clear_session(uid).expect("logout clears credentials");
+
+        // 凭证没了（这正是退出登录该做的）……
+        assert!(
+            store.load_session(uid).expect("load session").is_none(),
+            "logout must drop the credentials",
+        );
+        // ……但水位还在，所以再登录只做增量。
+        assert!(
+            store
+                .load_bootstrap_completed(uid)
+                .expect("load bootstrap watermark"),
+            "logout must not look like a fresh account",
+        );
+    }
+
+    /// DM 标题**任何情况下不得回退到 uid**
+    /// （spec CLIENT_GLOBAL_STATE_AND_IDENTITY_STORE_SPEC §27.5）。
+    ///
+    /// 2026-08-08 生产反馈：邀请码注册的新用户进主界面，和邀请人的会话标题是一串
+    /// `100000007`。链路是——补全链绑定邀请码时服务端才建好友和 DM，欢迎消息先于对端
+    /// user 实体到达，本地会话行由这条消息就地建出来，名字位置被填进了 uid。
+    /// 服务端其实是按约定发空串的（"宁可显示加载中也不显示一串数字"），是客户端自己
+    /// 一层层把 uid 补了回去。
+    #[test]
+    fn a_dm_without_a_known_peer_shows_no_name_rather_than_the_uid() {
+        let store = test_store();
+        let uid = "100000200";
+        // 对端 user 实体还没同步到本地——这正是新注册用户进主界面那一刻的状态。
+        store
+            .upsert_channel(
+                uid,
+                &UpsertChannelInput {
+                    channel_id: 4381,
+                    channel_type: 1,
+                    channel_name: String::new(),
+                    channel_remark: String::new(),
+                    avatar: String::new(),
+                    unread_count: 1,
+                    top: 0,
+                    mute: 0,
+                    last_msg_timestamp: 1,
+                    last_local_message_id: 1,
+                    last_msg_content: "hi".to_string(),
+                    version: 1,
+                    peer_user_id: Some(100_000_007),
+                },
+            )
+            .expect("upsert channel");
+
+        let listed = store.list_channels(uid, 10, 0).expect("list channels");
+        let dm = listed.iter().find(|c| c.channel_id == 4381).expect("dm row");
+        assert_eq!(dm.channel_name, "", "名字未知时留空，绝不返回 uid");
+        assert!(
+            !dm.channel_name.contains("100000007"),
+            "对端 uid 绝不能出现在标题里，实际='{}'",
+            dm.channel_name
+        );
+    }
+
+    /// 对端 user 实体一到，标题立刻是真名——不需要重启，也不需要额外的全量同步。
+    #[test]
+    fn the_dm_title_becomes_the_real_name_once_the_peer_lands() {
+        let store = test_store();
+        let uid = "100000200";
+        store
+            .upsert_channel(
+                uid,
+                &UpsertChannelInput {
+                    channel_id: 4381,
+                    channel_type: 1,
+                    channel_name: String::new(),
+                    channel_remark: String::new(),
+                    avatar: String::new(),
+                    unread_count: 1,
+                    top: 0,
+                    mute: 0,
+                    last_msg_timestamp: 1,
+                    last_local_message_id: 1,
+                    last_msg_content: "hi".to_string(),
+                    version: 1,
+                    peer_user_id: Some(100_000_007),
+                },
+            )
+            .expect("upsert channel");
+        store
+            .upsert_user(
+                uid,
+                &UpsertUserInput {
+                    user_id: 100_000_007,
+                    username: Some("fujie95".to_string()),
+                    nickname: Some("福姐九五".to_string()),
+                    alias: None,
+                    avatar: String::new(),
+                    user_type: 0,
+                    is_deleted: false,
+                    channel_id: String::new(),
+                    version: 1,
+                    updated_at: 1,
+                },
+            )
+            .expect("upsert user");
+
+        let listed = store.list_channels(uid, 10, 0).expect("list channels");
+        let dm = listed.iter().find(|c| c.channel_id == 4381).expect("dm row");
+        assert_eq!(dm.channel_name, "福姐九五");
+    }
+
+    /// 存量脏数据：老版本把对端 uid 写进了 `channel_name`。
+    /// 它只能用来**反推对端身份**去 JOIN，绝不能当名字显示。
+    #[test]
+    fn a_legacy_row_with_a_uid_in_the_name_column_still_never_shows_it() {
+        let store = test_store();
+        let uid = "100000200";
+        store
+            .upsert_channel(
+                uid,
+                &UpsertChannelInput {
+                    channel_id: 4382,
+                    channel_type: 1,
+                    // 老版本的写法：uid 当名字
+                    channel_name: "100000007".to_string(),
+                    channel_remark: String::new(),
+                    avatar: String::new(),
+                    unread_count: 0,
+                    top: 0,
+                    mute: 0,
+                    last_msg_timestamp: 1,
+                    last_local_message_id: 1,
+                    last_msg_content: String::new(),
+                    version: 1,
+                    peer_user_id: None,
+                },
+            )
+            .expect("upsert channel");
+
+        let before = store.list_channels(uid, 10, 0).expect("list channels");
+        let dm = before.iter().find(|c| c.channel_id == 4382).expect("dm row");
+        assert_eq!(dm.channel_name, "", "存量 uid 名字当成「没有名字」");
+
+        // 但它仍然要能反推出对端，等 user 实体到了标题就对了。
+        store
+            .upsert_user(
+                uid,
+                &UpsertUserInput {
+                    user_id: 100_000_007,
+                    username: Some("fujie95".to_string()),
+                    nickname: Some("福姐九五".to_string()),
+                    alias: None,
+                    avatar: String::new(),
+                    user_type: 0,
+                    is_deleted: false,
+                    channel_id: String::new(),
+                    version: 1,
+                    updated_at: 1,
+                },
+            )
+            .expect("upsert user");
+        let after = store.list_channels(uid, 10, 0).expect("list channels");
+        let dm = after.iter().find(|c| c.channel_id == 4382).expect("dm row");
+        assert_eq!(dm.channel_name, "福姐九五", "存量行也要能自愈");
+    }
+
+    #[test]
+    fn group_member_display_name_uses_the_frozen_priority() {
+        assert_eq!(
+            resolve_group_member_display_name(
+                Some(" group alias "),
+                Some("user alias"),
+                Some("nickname"),
+                Some("username"),
+                7,
+            ),
+            "group alias"
+        );
+        assert_eq!(
+            resolve_group_member_display_name(
+                None,
+                Some("user alias"),
+                Some("nickname"),
+                Some("username"),
+                7,
+            ),
+            "user alias"
+        );
+        assert_eq!(
+            resolve_group_member_display_name(None, None, Some("nickname"), None, 7),
+            "nickname"
+        );
+        assert_eq!(
+            resolve_group_member_display_name(None, None, None, Some("username"), 7),
+            "username"
+        );
+        assert_eq!(
+            resolve_group_member_display_name(None, None, None, None, 7),
+            "7"
+        );
+    }
+
+    #[test]
+    fn force_set_user_avatar_cache_bypasses_avatar_gate() {
+        // CLIENT_GLOBAL_STATE §4.3 P2：自己上传头像后，SDK user 行 avatar 列可能还没同步到
+        // 新 url ⇒ 门控版 set_user_avatar_cache 不写；force 版必须强制写三列。
+        let store = test_store();
+        let uid = "30001";
+        let user_id = 42u64;
+        store
+            .upsert_user(
+                uid,
+                &crate::UpsertUserInput {
+                    user_id,
+                    username: Some("self".to_string()),
+                    nickname: Some("Self".to_string()),
+                    alias: None,
+                    avatar: "https://cdn/old.png".to_string(),
+                    user_type: 0,
+                    is_deleted: false,
+                    channel_id: "c-self".to_string(),
+                    version: 1,
+                    updated_at: 1,
+                },
+            )
+            .expect("upsert user");
+
+        // 门控版：avatar 列 != new_url ⇒ 不写（这正是自己上传后 ensure 失效的根因）。
+        let gated = store
+            .set_user_avatar_cache(
+                uid,
+                user_id,
+                "https://cdn/new.png",
+                "/root/avatars/users/42.img",
+            )
+            .expect("gated set");
+        assert!(!gated, "gated set 应因 avatar 列不匹配而不写");
+
+        // 强制版：显式命令，avatar / avatar_local_path / avatar_cached_url 三列一起对齐。
+        let forced = store
+            .force_set_user_avatar_cache(
+                uid,
+                user_id,
+                "https://cdn/new.png",
+                "/root/avatars/users/42.img",
+            )
+            .expect("force set");
+        assert!(forced, "force set 应写入");
+
+        let cache = store
+            .get_user_avatar_cache(uid, user_id)
+            .expect("get cache")
+            .expect("row exists");
+        assert_eq!(cache.avatar_cached_url, "https://cdn/new.png");
+        assert_eq!(cache.avatar_local_path, "/root/avatars/users/42.img");
+        let user = store
+            .get_user_by_id(uid, user_id)
+            .expect("get user")
+            .expect("user exists");
+        assert_eq!(user.avatar, "https://cdn/new.png");
+    }
+
+    #[test]
+    fn account_kv_path_uses_dot_kv_suffix() {
+        let store = test_store();
+        let paths = store.storage_paths("20001");
+        assert_eq!(
+            paths.kv_path.file_name().and_then(|v| v.to_str()),
+            Some("account.kv")
+        );
+    }
+
+    #[test]
+    fn db_path_migrates_messages_db_to_privchat_db() {
+        let store = test_store();
+        let uid = "20000";
+        let paths = store.storage_paths(uid);
+        std::fs::create_dir_all(&paths.user_root).expect("create user root");
+        let legacy = paths.user_root.join("messages.db");
+        let conn = rusqlite::Connection::open(&legacy).expect("open legacy db");
+        let key = LocalStore::derive_encryption_key(uid);
+        conn.pragma_update(None, "key", &key)
+            .expect("set legacy db key");
+        conn.execute("CREATE TABLE t(id INTEGER PRIMARY KEY)", [])
+            .expect("init legacy db");
+        drop(conn);
+
+        let out = store.ensure_user_storage(uid).expect("ensure user storage");
+        assert_eq!(
+            out.db_path.file_name().and_then(|v| v.to_str()),
+            Some("privchat.db")
+        );
+        assert!(out.db_path.exists());
+        assert!(!legacy.exists());
+    }
+
+    #[test]
+    fn session_roundtrip_uses_encrypted_account_store() {
+        let store = test_store();
+        let uid = "20002";
+        let login = LoginResult {
+            user_id: uid.parse().expect("uid"),
+            token: "token-secret-value".to_string(),
+            device_id: "device-a".to_string(),
+            refresh_token: Some("refresh-secret-value".to_string()),
+            expires_at: 0,
+        };
+        store.save_login(uid, &login).expect("save login");
+        let snap = store
+            .load_session(uid)
+            .expect("load session")
+            .expect("session exists");
+        assert_eq!(snap.user_id, login.user_id);
+        assert_eq!(snap.token, login.token);
+        assert_eq!(snap.device_id, login.device_id);
+
+        let account_db = store.open_account_db(uid).expect("open account db");
+        let auth = account_db.open_tree("auth").expect("open auth tree");
+        let token_enc = auth.get("access_token_enc").expect("get access enc");
+        assert!(token_enc.is_some());
+        let plain = token_enc.expect("access enc exists");
+        assert_ne!(plain.as_ref(), login.token.as_bytes());
+    }
+
+    // R8.7-impl — see privchat-sdk/docs/ACCOUNT_ACTIVE_AUDIT.md.
+    //
+    // `list_local_accounts` derives the active uid from `current_user_file`
+    // (single source of truth), not the legacy `K_ACTIVE_UID` sled key.
+    // These three tests lock that contract down:
+    //
+    //   1. After `save_login`, list reports the logged-in uid as active.
+    //   2. After `save_current_uid` (the path `set_current_uid` uses), list
+    //      reports the newly-current uid as active.
+    //   3. When the file says B but sled K_ACTIVE_UID still says A (the
+    //      drift scenario this round fixes), list trusts the file.
+    #[test]
+    fn list_local_accounts_after_save_login_reports_active() {
+        let store = test_store();
+        let uid = "30001";
+        let login = LoginResult {
+            user_id: uid.parse().expect("uid"),
+            token: "t".to_string(),
+            device_id: "d".to_string(),
+            refresh_token: None,
+            expires_at: 0,
+        };
+        store.save_login(uid, &login).expect("save login");
+        let (active, entries) = store.list_local_accounts().expect("list");
+        assert_eq!(
+            active.as_deref(),
+            Some(uid),
+            "active uid should match login"
+        );
+        assert!(
+            entries.iter().any(|e| e.uid == uid),
+            "logged-in uid should appear in entries"
+        );
+    }
+
+    #[test]
+    fn list_local_accounts_follows_save_current_uid() {
+        let store = test_store();
+        // Two accounts, both fully logged in so both have entries.
+        let uid_a = "30010";
+        let uid_b = "30011";
+        for uid in [uid_a, uid_b] {
+            let login = LoginResult {
+                user_id: uid.parse().expect("uid"),
+                token: format!("t-{uid}"),
+                device_id: format!("d-{uid}"),
+                refresh_token: None,
+                expires_at: 0,
+            };
+            store.save_login(uid, &login).expect("save login");
+        }
+        // After both save_login calls, K_ACTIVE_UID=B and file=B (each
+        // save_login overwrites both). Switch the file pointer to A.
+        store.save_current_uid(uid_a).expect("save current uid");
+        let (active, entries) = store.list_local_accounts().expect("list");
+        assert_eq!(
+            active.as_deref(),
+            Some(uid_a),
+            "active should track the file, not the most-recent save_login"
+        );
+        assert_eq!(entries.len(), 2, "both accounts should remain listed");
+    }
+
+    #[test]
+    fn list_local_accounts_prefers_file_when_sled_drifts() {
+        let store = test_store();
+        let uid_a = "30020";
+        let uid_b = "30021";
+        for uid in [uid_a, uid_b] {
+            let login = LoginResult {
+                user_id: uid.parse().expect("uid"),
+                token: format!("t-{uid}"),
+                device_id: format!("d-{uid}"),
+                refresh_token: None,
+                expires_at: 0,
+            };
+            store.save_login(uid, &login).expect("save login");
+        }
+
+        // Construct an explicit drift: force the sled K_ACTIVE_UID back to
+        // A while leaving the file at B (state after second save_login).
+        // This mirrors the bug shape the audit identified — a `set_current_uid`-
+        // like path that updated only one of the two stores.
+        let global_db = store.open_global_db().expect("open global");
+        let accounts = global_db
+            .open_tree(GLOBAL_TREE_ACCOUNTS)
+            .expect("open accounts tree");
+        accounts
+            .insert(K_ACTIVE_UID, uid_a.as_bytes())
+            .expect("force K_ACTIVE_UID=A");
+        global_db.flush().expect("flush sled drift");
+
+        // Re-verify the drift is in place: sled says A, file should say B.
+        let sled_active = get_string(&accounts, K_ACTIVE_UID).expect("read sled");
+        assert_eq!(sled_active.as_deref(), Some(uid_a));
+        let file_active = store.load_current_uid().expect("read file");
+        assert_eq!(file_active.as_deref(), Some(uid_b));
+
+        // The contract: list trusts the file.
+        let (active, _) = store.list_local_accounts().expect("list");
+        assert_eq!(
+            active.as_deref(),
+            Some(uid_b),
+            "drift must be resolved in favor of the file (current_user_file)"
+        );
+    }
+
+    #[test]
+    fn scoped_stores_keep_their_own_current_uid() {
+        let base = test_store();
+        base.save_foreground_uid("30030").expect("seed foreground");
+        let (alice, alice_scope) = base.scoped(None).expect("scope alice");
+        let (bob, _) = base.scoped(Some("30032".to_string())).expect("scope bob");
+        for (store, uid) in [(&alice, "30031"), (&bob, "30032")] {
+            let login = LoginResult {
+                user_id: uid.parse().expect("uid"),
+                token: format!("t-{uid}"),
+                device_id: format!("d-{uid}"),
+                refresh_token: None,
+                expires_at: 0,
+            };
+            store.save_login(uid, &login).expect("save login");
+        }
+
+        assert_eq!(alice.load_current_uid().unwrap().as_deref(), Some("30031"));
+        assert_eq!(alice_scope.lock().unwrap().as_deref(), Some("30031"));
+        assert_eq!(bob.load_current_uid().unwrap().as_deref(), Some("30032"));
+        // 登录只改自己的 scope，前台（文件）不动。
+        assert_eq!(base.load_current_uid().unwrap().as_deref(), Some("30030"));
+        let (active, entries) = bob.list_local_accounts().expect("list");
+        assert_eq!(active.as_deref(), Some("30030"));
+        assert_eq!(entries.len(), 2);
+
+        // 同一个 uid 不能再绑到另一个实例上。
+        let dup_login = LoginResult {
+            user_id: 30032,
+            token: "t-dup".to_string(),
+            device_id: "d-dup".to_string(),
+            refresh_token: None,
+            expires_at: 0,
+        };
+        assert!(matches!(
+            alice.save_login("30032", &dup_login),
+            Err(Error::InvalidState(_))
+        ));
+        assert!(alice.save_current_uid("30032").is_err());
+        assert_eq!(alice.load_current_uid().unwrap().as_deref(), Some("30031"));
+        // 带 uid 开 scope 也一样：已经绑在别处的 uid 直接拒绝。
+        assert!(matches!(
+            base.scoped(Some("30032".to_string())),
+            Err(Error::InvalidState(_))
+        ));
+
+        alice.clear_current_uid().expect("clear");
+        assert_eq!(alice.load_current_uid().unwrap(), None);
+        assert_eq!(bob.load_current_uid().unwrap().as_deref(), Some("30032"));
+        assert_eq!(base.load_current_uid().unwrap().as_deref(), Some("30030"));
+    }
+
+    /// 几个槽位同时绑同一个 uid：检查和绑定在一把锁下，只能有一个成功。
+    #[test]
+    fn concurrent_binds_of_one_uid_leave_a_single_owner() {
+        let base = test_store();
+        let scoped: Vec<_> = (0..8)
+            .map(|_| base.scoped(None).expect("scope"))
+            .collect();
+        let barrier = std::sync::Barrier::new(scoped.len());
+        let bound = std::thread::scope(|s| {
+            let handles: Vec<_> = scoped
+                .iter()
+                .map(|(store, _)| {
+                    let barrier = &barrier;
+                    s.spawn(move || {
+                        barrier.wait();
+                        store.save_current_uid("30040").is_ok()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("bind thread"))
+                .filter(|ok| *ok)
+                .count()
+        });
+        assert_eq!(bound, 1);
+        let owners = scoped
+            .iter()
+            .filter(|(_, scope)| scope.lock().unwrap().as_deref() == Some("30040"))
+            .count();
+        assert_eq!(owners, 1);
+    }
+
+    #[test]
+    fn load_session_migrates_legacy_sqlite_row() {
+        let store = test_store();
+        let uid = "20003";
+        let conn = store.conn_for_user(uid).expect("open user db");
+        conn.execute(
+            "INSERT OR REPLACE INTO auth_session(id, user_id, token, device_id, bootstrap_completed, updated_at)
+             VALUES(1, ?1, ?2, ?3, 1, ?4)",
+            params![
+                uid.parse::<u64>().expect("uid parse"),
+                "legacy-token",
+                "legacy-device",
+                chrono::Utc::now().timestamp_millis()
+            ],
+        )
+        .expect("insert legacy session");
+
+        let snap = store
+            .load_session(uid)
+            .expect("load session")
+            .expect("session exists");
+        assert_eq!(snap.token, "legacy-token");
+        assert!(snap.bootstrap_completed);
+
+        let remain: i64 = conn
+            .query_row("SELECT COUNT(1) FROM auth_session", [], |r| r.get(0))
+            .expect("count auth_session");
+        assert_eq!(remain, 0);
+    }
+
+    #[test]
+    fn message_lifecycle_uses_message_id_pk() {
+        let store = test_store();
+        let uid = "10003";
+        let input = NewMessage {
+            channel_id: 100,
+            channel_type: 1,
+            from_uid: 200,
+            message_type: 1,
+            content: "hello".to_string(),
+            searchable_word: "hello".to_string(),
+            setting: 0,
+            extra: "{}".to_string(),
+            mime_type: None,
+            media_downloaded: false,
+            thumb_status: 0,
+        };
+
+        let message_id = store
+            .create_local_message(uid, &input, 0)
+            .expect("create local message");
+        let msg = store
             .get_message_by_id(uid, message_id)
             .expect("load message")
             .expect("message exists");
@@ -6091,6 +9043,46 @@ mod tests {
         );
     }
 
+    /// `before_local_id` 在 SQL 里过滤：游标之前的消息照样按 limit 凑满一页，
+    /// 不会因为先分页、后筛选而拿到短页或空页。
+    #[test]
+    fn list_messages_before_pages_after_filtering() {
+        let store = test_store();
+        let uid = "10003-list-before";
+        let mut ids = Vec::new();
+        for i in 0..6 {
+            let input = NewMessage {
+                channel_id: 101,
+                channel_type: 1,
+                from_uid: 200,
+                message_type: 1,
+                content: format!("m{i}"),
+                searchable_word: format!("m{i}"),
+                setting: 0,
+                extra: "{}".to_string(),
+                mime_type: None,
+                media_downloaded: false,
+                thumb_status: 0,
+            };
+            ids.push(
+                store
+                    .create_local_message_queued(uid, &input, 556_000 + i, "message", b"p", None)
+                    .expect("create"),
+            );
+        }
+        let cursor = ids[3];
+        let page = store
+            .list_messages_before(uid, 101, 1, Some(cursor), 2, 0)
+            .expect("page");
+        assert_eq!(page.len(), 2, "游标之前还有三条，整页应当凑满");
+        assert!(page.iter().all(|m| m.message_id < cursor));
+        let rest = store
+            .list_messages_before(uid, 101, 1, Some(cursor), 2, 2)
+            .expect("rest");
+        assert_eq!(rest.len(), 1);
+        assert!(rest[0].message_id < cursor);
+    }
+
     /// 升级迁移：真实跑一遍 refinery，验证重复 server_message_id 的附属数据
     /// 被正确合并/改指，而不是随重复行一起消失，也不因唯一索引冲突而炸掉。
     ///
@@ -7938,6 +10930,320 @@ mod tests {
         assert_eq!(page[0].channel_id, 9001);
     }
 
+    #[test]
+    fn query_channels_filters_sorts_and_pages_in_sql() {
+        let store = test_store();
+        let uid = "10012";
+        let upsert = |channel_id: u64, name: &str, ts: i64, unread: i32, top: i32, mute: i32| {
+            store
+                .upsert_channel(
+                    uid,
+                    &UpsertChannelInput {
+                        channel_id,
+                        channel_type: 2,
+                        channel_name: name.to_string(),
+                        channel_remark: String::new(),
+                        avatar: String::new(),
+                        unread_count: unread,
+                        top,
+                        mute,
+                        last_msg_timestamp: ts,
+                        last_local_message_id: 0,
+                        last_msg_content: "hi".to_string(),
+                        version: 1,
+                        peer_user_id: None,
+                    },
+                )
+                .expect("upsert channel");
+        };
+        upsert(1, "alpha", 1_000, 0, 0, 0);
+        upsert(2, "bravo", 3_000, 5, 0, 0);
+        upsert(3, "charlie", 2_000, 2, 1, 0);
+        upsert(4, "delta", 4_000, 1, 0, 1);
+        store
+            .replace_channel_prefs(
+                uid,
+                &[
+                    ChannelPrefRow {
+                        channel_id: 1,
+                        channel_type: 2,
+                        notification_mode: crate::unread_badge::notification_mode::MENTIONS_ONLY,
+                        tags: vec!["work".to_string()],
+                        ..Default::default()
+                    },
+                    ChannelPrefRow {
+                        channel_id: 2,
+                        channel_type: 2,
+                        favourite: true,
+                        tags: vec!["work".to_string()],
+                        ..Default::default()
+                    },
+                ],
+            )
+            .expect("mirror prefs");
+        let ids = |query: ChannelListQuery| -> Vec<u64> {
+            store
+                .query_channels(uid, &query)
+                .expect("query channels")
+                .channels
+                .iter()
+                .map(|c| c.channel_id)
+                .collect()
+        };
+        let with_filter = |filter: ChannelListFilter| ChannelListQuery {
+            filter,
+            ..Default::default()
+        };
+
+        // 置顶优先、按最后活动时间，两条一页：游标接着上一页往下翻，不重不漏。
+        let first = store
+            .query_channels(
+                uid,
+                &ChannelListQuery {
+                    limit: 2,
+                    ..Default::default()
+                },
+            )
+            .expect("first page");
+        let first_ids: Vec<u64> = first.channels.iter().map(|c| c.channel_id).collect();
+        assert_eq!(first_ids, vec![3, 4]);
+        let second = ids(ChannelListQuery {
+            limit: 2,
+            after: first.next_cursor.clone(),
+            ..Default::default()
+        });
+        assert_eq!(second, vec![2, 1]);
+        assert!(first.next_cursor.is_some());
+
+        assert_eq!(
+            ids(ChannelListQuery {
+                sort: ChannelSortKey::Unread,
+                pinned_first: false,
+                ..Default::default()
+            }),
+            vec![2, 3, 4, 1]
+        );
+        assert_eq!(
+            ids(with_filter(ChannelListFilter {
+                unread_only: true,
+                muted: Some(false),
+                ..Default::default()
+            })),
+            vec![3, 2],
+            "mute 的会话不算未静音"
+        );
+        assert_eq!(
+            ids(with_filter(ChannelListFilter {
+                muted: Some(true),
+                ..Default::default()
+            })),
+            vec![4, 1],
+            "只提醒 @ 的会话也算静音"
+        );
+        assert_eq!(
+            ids(with_filter(ChannelListFilter {
+                tag: Some("work".to_string()),
+                ..Default::default()
+            })),
+            vec![2, 1]
+        );
+        assert_eq!(
+            ids(with_filter(ChannelListFilter {
+                keyword: Some("ALP".to_string()),
+                ..Default::default()
+            })),
+            vec![1]
+        );
+
+        // 文件夹 = 手选的会话 ∪ 满足文件夹条件的会话。
+        let folder = store
+            .upsert_channel_folder(
+                uid,
+                &ChannelFolderInput {
+                    name: "important".to_string(),
+                    filter: ChannelListFilter {
+                        favourite: Some(true),
+                        ..Default::default()
+                    },
+                    channels: vec![(4, 2)],
+                    ..Default::default()
+                },
+            )
+            .expect("create folder");
+        assert_eq!(
+            ids(with_filter(ChannelListFilter {
+                folder_id: Some(folder.folder_id),
+                ..Default::default()
+            })),
+            vec![4, 2]
+        );
+        assert_eq!(
+            store.list_channel_folders(uid).expect("folders"),
+            vec![folder.clone()]
+        );
+        assert!(store
+            .delete_channel_folder(uid, folder.folder_id)
+            .expect("delete folder"));
+        assert!(store.list_channel_folders(uid).expect("folders").is_empty());
+
+        store.set_channel_hidden(uid, 1, true).expect("hide");
+        assert_eq!(
+            ids(with_filter(ChannelListFilter {
+                visibility: ChannelVisibility::Hidden,
+                ..Default::default()
+            })),
+            vec![1]
+        );
+        assert!(!ids(ChannelListQuery::default()).contains(&1));
+    }
+
+    /// 未读筛选、未读排序和游标都按返回给调用方的（自愈后的）未读数算：channel 表里
+    /// 残留的未读不能让一个已读到底的会话留在「只看未读」里，也不能打乱翻页。
+    #[test]
+    fn query_channels_filter_sort_and_page_on_the_resolved_unread_count() {
+        let store = test_store();
+        let uid = "10014";
+        for (channel_id, unread_count, ts) in [
+            (11, 3, 1_000),
+            (12, 7, 2_000),
+            (13, 1, 3_000),
+            (14, 0, 4_000),
+        ] {
+            store
+                .upsert_channel(
+                    uid,
+                    &UpsertChannelInput {
+                        channel_id,
+                        channel_type: 2,
+                        channel_name: format!("room-{channel_id}"),
+                        channel_remark: String::new(),
+                        avatar: String::new(),
+                        unread_count,
+                        top: 0,
+                        mute: 0,
+                        last_msg_timestamp: ts,
+                        last_local_message_id: 0,
+                        last_msg_content: "hi".to_string(),
+                        version: 1,
+                        peer_user_id: None,
+                    },
+                )
+                .expect("upsert channel");
+        }
+        // 会话 12 早已读到最新一条，channel.unread_count 的 7 是残留。
+        store
+            .upsert_remote_message_with_result(
+                uid,
+                &UpsertRemoteMessageInput {
+                    server_message_id: 81_201,
+                    local_message_id: 0,
+                    channel_id: 12,
+                    channel_type: 2,
+                    timestamp: 2_000,
+                    from_uid: 20_001,
+                    message_type: 1,
+                    content: "{\"content\":\"read\"}".to_string(),
+                    status: 2,
+                    pts: 5,
+                    order_seq: 5,
+                    searchable_word: "read".to_string(),
+                    setting: 0,
+                    extra: "{}".to_string(),
+                    timestamp_precision: crate::canonical_inbound::TimePrecision::Milliseconds,
+                    mime_type: None,
+                    revoked: false,
+                },
+            )
+            .expect("insert read message");
+        store
+            .upsert_channel_extra(
+                uid,
+                &UpsertChannelExtraInput {
+                    channel_id: 12,
+                    channel_type: 2,
+                    browse_to: 5,
+                    keep_pts: 5,
+                    keep_offset_y: 0,
+                    draft: String::new(),
+                    draft_updated_at: 0,
+                },
+            )
+            .expect("seed keep pts");
+
+        let walk = |query: ChannelListQuery| -> Vec<(u64, i32)> {
+            let mut out = Vec::new();
+            let mut after = None;
+            loop {
+                let page = store
+                    .query_channels(
+                        uid,
+                        &ChannelListQuery {
+                            limit: 1,
+                            after,
+                            ..query.clone()
+                        },
+                    )
+                    .expect("query page");
+                out.extend(page.channels.iter().map(|c| (c.channel_id, c.unread_count)));
+                match page.next_cursor {
+                    Some(cursor) => after = Some(cursor),
+                    None => break,
+                }
+                assert!(out.len() <= 4, "cursor must not loop");
+            }
+            out
+        };
+
+        let unread = ChannelListQuery {
+            filter: ChannelListFilter {
+                unread_only: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(walk(unread), vec![(13, 1), (11, 3)]);
+
+        let by_unread = ChannelListQuery {
+            sort: ChannelSortKey::Unread,
+            pinned_first: false,
+            ..Default::default()
+        };
+        assert_eq!(walk(by_unread), vec![(11, 3), (13, 1), (14, 0), (12, 0)]);
+    }
+
+    /// 删除文件夹连同成员一起删；再删一次返回 false。
+    #[test]
+    fn deleting_a_channel_folder_removes_its_members() {
+        let store = test_store();
+        let uid = "10015";
+        let folder = store
+            .upsert_channel_folder(
+                uid,
+                &ChannelFolderInput {
+                    name: "work".to_string(),
+                    channels: vec![(1, 2), (2, 2)],
+                    ..Default::default()
+                },
+            )
+            .expect("create folder");
+        assert!(store
+            .delete_channel_folder(uid, folder.folder_id)
+            .expect("delete folder"));
+        let members: i64 = store
+            .conn_for_user(uid)
+            .expect("conn")
+            .query_row(
+                "SELECT COUNT(*) FROM channel_folder_member WHERE folder_id = ?1",
+                params![folder.folder_id as i64],
+                |r| r.get(0),
+            )
+            .expect("count members");
+        assert_eq!(members, 0);
+        assert!(!store
+            .delete_channel_folder(uid, folder.folder_id)
+            .expect("delete missing folder"));
+    }
+
     #[test]
     fn channel_reads_self_heal_stale_unread_when_materialized_projection_is_zero() {
         let store = test_store();
@@ -8067,550 +11373,1136 @@ mod tests {
         let store = test_store();
         let uid = "10010";
         store
-            .upsert_channel(
+            .upsert_channel(
+                uid,
+                &UpsertChannelInput {
+                    channel_id: 9202,
+                    channel_type: 2,
+                    channel_name: "group-9202".to_string(),
+                    channel_remark: String::new(),
+                    avatar: String::new(),
+                    unread_count: 0,
+                    top: 0,
+                    mute: 0,
+                    last_msg_timestamp: 111,
+                    last_local_message_id: 0,
+                    last_msg_content: "stale-synced-preview".to_string(),
+                    version: 2002,
+                    peer_user_id: None,
+                },
+            )
+            .expect("upsert initial channel");
+
+        let inserted = store
+            .upsert_remote_message_with_result(
+                uid,
+                &UpsertRemoteMessageInput {
+                    server_message_id: 88001,
+                    local_message_id: 0,
+                    channel_id: 9202,
+                    channel_type: 2,
+                    timestamp: 999888777,
+                    from_uid: 10086,
+                    message_type: 1,
+                    content: "{\"content\":\"fresh-local-message\"}".to_string(),
+                    status: 2,
+                    pts: 12,
+                    setting: 0,
+                    order_seq: 12,
+                    searchable_word: "fresh local message".to_string(),
+                    extra: "{}".to_string(),
+                    timestamp_precision: crate::canonical_inbound::TimePrecision::Milliseconds,
+                    mime_type: None,
+                    revoked: false,
+                },
+            )
+            .expect("insert local materialized message");
+        let latest = store
+            .list_messages(uid, 9202, 2, 10, 0)
+            .expect("list messages");
+        assert_eq!(latest.len(), 1);
+
+        let row = store
+            .get_channel_by_id(uid, 9202)
+            .expect("get channel")
+            .expect("channel exists");
+        assert_eq!(row.last_msg_timestamp, latest[0].created_at);
+        assert_eq!(
+            row.last_msg_content,
+            "{\"content\":\"fresh-local-message\"}"
+        );
+        assert_eq!(row.last_local_message_id, inserted.message_id);
+
+        let page = store.list_channels(uid, 20, 0).expect("list channels");
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].last_msg_timestamp, latest[0].created_at);
+        assert_eq!(
+            page[0].last_msg_content,
+            "{\"content\":\"fresh-local-message\"}"
+        );
+        assert_eq!(page[0].last_local_message_id, inserted.message_id);
+    }
+
+    #[test]
+    fn upsert_and_get_channel_extra() {
+        let store = test_store();
+        let uid = "10008";
+        store
+            .upsert_channel_extra(
+                uid,
+                &UpsertChannelExtraInput {
+                    channel_id: 9100,
+                    channel_type: 1,
+                    browse_to: 123,
+                    keep_pts: 456,
+                    keep_offset_y: 20,
+                    draft: "hello draft".to_string(),
+                    draft_updated_at: 9999,
+                },
+            )
+            .expect("upsert channel_extra");
+
+        let row = store
+            .get_channel_extra(uid, 9100, 1)
+            .expect("get channel_extra")
+            .expect("channel_extra exists");
+        assert_eq!(row.channel_id, 9100);
+        assert_eq!(row.browse_to, 123);
+        assert_eq!(row.keep_pts, 456);
+        assert_eq!(row.keep_offset_y, 20);
+        assert_eq!(row.draft, "hello draft");
+        assert_eq!(row.draft_updated_at, 9999);
+    }
+
+    #[test]
+    fn upsert_and_list_user_friend_group_entities() {
+        let store = test_store();
+        let uid = "10009";
+
+        store
+            .upsert_user(
+                uid,
+                &crate::UpsertUserInput {
+                    user_id: 20001,
+                    username: Some("alice".to_string()),
+                    nickname: Some("Alice".to_string()),
+                    alias: Some("A".to_string()),
+                    avatar: "avatar://alice".to_string(),
+                    user_type: 0,
+                    is_deleted: false,
+                    channel_id: "c-alice".to_string(),
+                    version: 1000,
+                    updated_at: 1000,
+                },
+            )
+            .expect("upsert user");
+        let user = store
+            .get_user_by_id(uid, 20001)
+            .expect("get user")
+            .expect("user exists");
+        assert_eq!(user.username.as_deref(), Some("alice"));
+        assert_eq!(user.version, 1000);
+        store
+            .upsert_user(
+                uid,
+                &crate::UpsertUserInput {
+                    user_id: 20001,
+                    username: Some("stale-alice".to_string()),
+                    nickname: Some("Stale Alice".to_string()),
+                    alias: Some("stale".to_string()),
+                    avatar: "avatar://stale".to_string(),
+                    user_type: 9,
+                    is_deleted: true,
+                    channel_id: "stale-channel".to_string(),
+                    version: 999,
+                    updated_at: 999,
+                },
+            )
+            .expect("upsert stale user");
+        let user = store
+            .get_user_by_id(uid, 20001)
+            .expect("get user after stale upsert")
+            .expect("user exists after stale upsert");
+        assert_eq!(user.username.as_deref(), Some("alice"));
+        assert_eq!(user.nickname.as_deref(), Some("Alice"));
+        assert_eq!(user.alias.as_deref(), Some("A"));
+        assert_eq!(user.version, 1000);
+
+        store
+            .upsert_friend(
+                uid,
+                &crate::UpsertFriendInput {
+                    user_id: 20001,
+                    tags: Some("work".to_string()),
+                    is_pinned: true,
+                    created_at: 1000,
+                    version: 1001,
+                    updated_at: 1001,
+                    status: 1,
+                    is_outgoing: None,
+                    request_message: None,
+                    request_source: None,
+                    request_source_id: None,
+                },
+            )
+            .expect("upsert friend");
+        let friends = store.list_friends(uid, 20, 0).expect("list friends");
+        assert_eq!(friends.len(), 1);
+        assert_eq!(friends[0].user_id, 20001);
+        assert!(friends[0].is_pinned);
+        assert_eq!(friends[0].version, 1001);
+        store
+            .upsert_friend(
+                uid,
+                &crate::UpsertFriendInput {
+                    user_id: 20001,
+                    tags: Some("stale".to_string()),
+                    is_pinned: false,
+                    created_at: 999,
+                    version: 1000,
+                    updated_at: 1000,
+                    status: 1,
+                    is_outgoing: None,
+                    request_message: None,
+                    request_source: None,
+                    request_source_id: None,
+                },
+            )
+            .expect("upsert stale friend");
+        let friends = store
+            .list_friends(uid, 20, 0)
+            .expect("list friends after stale upsert");
+        assert_eq!(friends.len(), 1);
+        assert_eq!(friends[0].tags.as_deref(), Some("work"));
+        assert!(friends[0].is_pinned);
+        assert_eq!(friends[0].version, 1001);
+        store.delete_friend(uid, 20001).expect("delete friend");
+        let friends = store
+            .list_friends(uid, 20, 0)
+            .expect("list friends after delete");
+        assert!(friends.is_empty());
+        store
+            .upsert_blacklist_entry(
+                uid,
+                &crate::UpsertBlacklistInput {
+                    blocked_user_id: 20001,
+                    created_at: 1003,
+                    updated_at: 1004,
+                },
+            )
+            .expect("upsert blacklist entry");
+        let blacklist = store
+            .list_blacklist_entries(uid, 20, 0)
+            .expect("list blacklist entries");
+        assert_eq!(blacklist.len(), 1);
+        assert_eq!(blacklist[0].blocked_user_id, 20001);
+        store
+            .delete_blacklist_entry(uid, 20001)
+            .expect("delete blacklist entry");
+        let blacklist = store
+            .list_blacklist_entries(uid, 20, 0)
+            .expect("list blacklist entries after delete");
+        assert!(blacklist.is_empty());
+
+        store
+            .upsert_group(
+                uid,
+                &crate::UpsertGroupInput {
+                    group_id: 30001,
+                    name: Some("group-a".to_string()),
+                    avatar: "avatar://group-a".to_string(),
+                    owner_id: Some(20001),
+                    is_dismissed: false,
+                    member_count: None,
+                    created_at: 1000,
+                    version: 1002,
+                    updated_at: 1002,
+                },
+            )
+            .expect("upsert group");
+        let group = store
+            .get_group_by_id(uid, 30001)
+            .expect("get group")
+            .expect("group exists");
+        assert_eq!(group.name.as_deref(), Some("group-a"));
+        assert_eq!(group.version, 1002);
+        store
+            .upsert_group(
                 uid,
-                &UpsertChannelInput {
-                    channel_id: 9202,
-                    channel_type: 2,
-                    channel_name: "group-9202".to_string(),
-                    channel_remark: String::new(),
-                    avatar: String::new(),
-                    unread_count: 0,
-                    top: 0,
-                    mute: 0,
-                    last_msg_timestamp: 111,
-                    last_local_message_id: 0,
-                    last_msg_content: "stale-synced-preview".to_string(),
-                    version: 2002,
-                    peer_user_id: None,
+                &crate::UpsertGroupInput {
+                    group_id: 30001,
+                    name: Some("stale-group".to_string()),
+                    avatar: "avatar://stale-group".to_string(),
+                    owner_id: Some(99999),
+                    is_dismissed: true,
+                    member_count: None,
+                    created_at: 999,
+                    version: 1001,
+                    updated_at: 1001,
                 },
             )
-            .expect("upsert initial channel");
+            .expect("upsert stale group");
+        let group = store
+            .get_group_by_id(uid, 30001)
+            .expect("get group after stale upsert")
+            .expect("group exists after stale upsert");
+        assert_eq!(group.name.as_deref(), Some("group-a"));
+        assert_eq!(group.owner_id, Some(20001));
+        assert!(!group.is_dismissed);
+        assert_eq!(group.version, 1002);
 
-        let inserted = store
-            .upsert_remote_message_with_result(
+        store
+            .upsert_group_member(
                 uid,
-                &UpsertRemoteMessageInput {
-                    server_message_id: 88001,
-                    local_message_id: 0,
-                    channel_id: 9202,
-                    channel_type: 2,
-                    timestamp: 999888777,
-                    from_uid: 10086,
-                    message_type: 1,
-                    content: "{\"content\":\"fresh-local-message\"}".to_string(),
-                    status: 2,
-                    pts: 12,
-                    setting: 0,
-                    order_seq: 12,
-                    searchable_word: "fresh local message".to_string(),
-                    extra: "{}".to_string(),
-                    timestamp_precision: crate::canonical_inbound::TimePrecision::Milliseconds,
-                    mime_type: None,
-                    revoked: false,
+                &crate::UpsertGroupMemberInput {
+                    group_id: 30001,
+                    user_id: 20001,
+                    role: 0,
+                    status: 0,
+                    alias: Some("owner".to_string()),
+                    is_muted: false,
+                    joined_at: 1003,
+                    version: 1004,
+                    updated_at: 1004,
                 },
             )
-            .expect("insert local materialized message");
-        let latest = store
-            .list_messages(uid, 9202, 2, 10, 0)
-            .expect("list messages");
-        assert_eq!(latest.len(), 1);
-
-        let row = store
-            .get_channel_by_id(uid, 9202)
-            .expect("get channel")
-            .expect("channel exists");
-        assert_eq!(row.last_msg_timestamp, latest[0].created_at);
-        assert_eq!(
-            row.last_msg_content,
-            "{\"content\":\"fresh-local-message\"}"
-        );
-        assert_eq!(row.last_local_message_id, inserted.message_id);
-
-        let page = store.list_channels(uid, 20, 0).expect("list channels");
-        assert_eq!(page.len(), 1);
-        assert_eq!(page[0].last_msg_timestamp, latest[0].created_at);
-        assert_eq!(
-            page[0].last_msg_content,
-            "{\"content\":\"fresh-local-message\"}"
-        );
-        assert_eq!(page[0].last_local_message_id, inserted.message_id);
-    }
-
-    #[test]
-    fn upsert_and_get_channel_extra() {
-        let store = test_store();
-        let uid = "10008";
+            .expect("upsert group member");
+        let members = store
+            .list_group_members(uid, 30001, 20, 0)
+            .expect("list group members");
+        assert_eq!(members.len(), 1);
+        assert_eq!(members[0].user_id, 20001);
+        assert_eq!(members[0].display_name, "owner");
+        // delete_friend above removed this friend-only field; the public group
+        // projection must not resurrect or expose it.
+        assert_eq!(members[0].username, None);
+        assert_eq!(members[0].nickname.as_deref(), Some("Alice"));
+        assert_eq!(members[0].user_alias.as_deref(), Some("A"));
+        assert_eq!(members[0].avatar, "avatar://alice");
+        assert_eq!(members[0].version, 1004);
         store
-            .upsert_channel_extra(
+            .upsert_group_member(
                 uid,
-                &UpsertChannelExtraInput {
-                    channel_id: 9100,
-                    channel_type: 1,
-                    browse_to: 123,
-                    keep_pts: 456,
-                    keep_offset_y: 20,
-                    draft: "hello draft".to_string(),
-                    draft_updated_at: 9999,
+                &crate::UpsertGroupMemberInput {
+                    group_id: 30001,
+                    user_id: 20001,
+                    role: 2,
+                    status: 9,
+                    alias: Some("stale".to_string()),
+                    is_muted: true,
+                    joined_at: 1002,
+                    version: 1003,
+                    updated_at: 1003,
                 },
             )
-            .expect("upsert channel_extra");
-
-        let row = store
-            .get_channel_extra(uid, 9100, 1)
-            .expect("get channel_extra")
-            .expect("channel_extra exists");
-        assert_eq!(row.channel_id, 9100);
-        assert_eq!(row.browse_to, 123);
-        assert_eq!(row.keep_pts, 456);
-        assert_eq!(row.keep_offset_y, 20);
-        assert_eq!(row.draft, "hello draft");
-        assert_eq!(row.draft_updated_at, 9999);
-    }
-
-    #[test]
-    fn upsert_and_list_user_friend_group_entities() {
-        let store = test_store();
-        let uid = "10009";
+            .expect("upsert stale group member");
+        let members = store
+            .list_group_members(uid, 30001, 20, 0)
+            .expect("list group members after stale upsert");
+        assert_eq!(members.len(), 1);
+        assert_eq!(members[0].role, 0);
+        assert_eq!(members[0].status, 0);
+        assert_eq!(members[0].alias.as_deref(), Some("owner"));
+        assert_eq!(members[0].display_name, "owner");
+        assert!(!members[0].is_muted);
+        assert_eq!(members[0].version, 1004);
 
+        // The view is a live JOIN, not denormalized member data. A profile
+        // update changes the projected name without rewriting group_member.
         store
             .upsert_user(
                 uid,
                 &crate::UpsertUserInput {
                     user_id: 20001,
-                    username: Some("alice".to_string()),
-                    nickname: Some("Alice".to_string()),
-                    alias: Some("A".to_string()),
-                    avatar: "avatar://alice".to_string(),
+                    username: Some("alice-2".to_string()),
+                    nickname: Some("Alice 2".to_string()),
+                    alias: Some("A2".to_string()),
+                    avatar: "avatar://alice-2".to_string(),
                     user_type: 0,
                     is_deleted: false,
                     channel_id: "c-alice".to_string(),
-                    version: 1000,
-                    updated_at: 1000,
+                    version: 2000,
+                    updated_at: 2000,
                 },
             )
-            .expect("upsert user");
-        let user = store
-            .get_user_by_id(uid, 20001)
-            .expect("get user")
-            .expect("user exists");
-        assert_eq!(user.username.as_deref(), Some("alice"));
-        assert_eq!(user.version, 1000);
+            .expect("update user projection");
+        let members = store
+            .list_group_members(uid, 30001, 20, 0)
+            .expect("list group members after user update");
+        assert_eq!(members[0].display_name, "owner");
+        assert_eq!(members[0].nickname.as_deref(), Some("Alice 2"));
+        assert_eq!(members[0].avatar, "avatar://alice-2");
+        store
+            .delete_group_member(uid, 30001, 20001)
+            .expect("delete group member");
+        let members = store
+            .list_group_members(uid, 30001, 20, 0)
+            .expect("list group members after delete");
+        assert!(members.is_empty());
+
+        store
+            .upsert_channel_member(
+                uid,
+                &crate::UpsertChannelMemberInput {
+                    channel_id: 30001,
+                    channel_type: 2,
+                    member_uid: 20001,
+                    member_name: "alice".to_string(),
+                    member_remark: "owner".to_string(),
+                    member_avatar: "avatar://alice".to_string(),
+                    member_invite_uid: 20001,
+                    role: 1,
+                    status: 1,
+                    is_deleted: false,
+                    robot: 0,
+                    version: 1,
+                    created_at: 1005,
+                    updated_at: 1006,
+                    extra: "{}".to_string(),
+                    forbidden_expiration_time: 0,
+                    member_avatar_cache_key: "cache-alice".to_string(),
+                },
+            )
+            .expect("upsert channel member");
+        let ch_members = store
+            .list_channel_members(uid, 30001, 2, 20, 0)
+            .expect("list channel members");
+        assert_eq!(ch_members.len(), 1);
+        assert_eq!(ch_members[0].member_uid, 20001);
+        store
+            .delete_channel_member(uid, 30001, 2, 20001)
+            .expect("delete channel member");
+        let ch_members_after = store
+            .list_channel_members(uid, 30001, 2, 20, 0)
+            .expect("list channel members after delete");
+        assert!(ch_members_after.is_empty());
+    }
+
+    #[test]
+    fn batch_upsert_users_materializes_a_large_identity_page_in_one_call() {
+        let store = test_store();
+        let uid = "batch-users";
+        let inputs: Vec<_> = (1..=1_000)
+            .map(|user_id| crate::UpsertUserInput {
+                user_id,
+                username: Some(format!("user-{user_id}")),
+                nickname: Some(format!("User {user_id}")),
+                alias: None,
+                avatar: String::new(),
+                user_type: 0,
+                is_deleted: false,
+                channel_id: String::new(),
+                version: user_id as i64,
+                updated_at: user_id as i64,
+            })
+            .collect();
+
+        store
+            .batch_upsert_users(uid, &inputs)
+            .expect("batch upsert users");
+
+        let first = store
+            .get_user_by_id(uid, 1)
+            .expect("read first")
+            .expect("first exists");
+        let last = store
+            .get_user_by_id(uid, 1_000)
+            .expect("read last")
+            .expect("last exists");
+        assert_eq!(first.nickname.as_deref(), Some("User 1"));
+        assert_eq!(last.nickname.as_deref(), Some("User 1000"));
+    }
+
+    #[test]
+    fn reaction_mention_reminder_lifecycle() {
+        let store = test_store();
+        let uid = "10010";
+
         store
-            .upsert_user(
+            .upsert_message_reaction(
                 uid,
-                &crate::UpsertUserInput {
-                    user_id: 20001,
-                    username: Some("stale-alice".to_string()),
-                    nickname: Some("Stale Alice".to_string()),
-                    alias: Some("stale".to_string()),
-                    avatar: "avatar://stale".to_string(),
-                    user_type: 9,
-                    is_deleted: true,
-                    channel_id: "stale-channel".to_string(),
-                    version: 999,
-                    updated_at: 999,
+                &crate::UpsertMessageReactionInput {
+                    channel_id: 500,
+                    channel_type: 1,
+                    uid: 20001,
+                    name: "alice".to_string(),
+                    emoji: "👍".to_string(),
+                    message_id: 70001,
+                    seq: 1,
+                    is_deleted: false,
+                    created_at: 12345,
                 },
             )
-            .expect("upsert stale user");
-        let user = store
-            .get_user_by_id(uid, 20001)
-            .expect("get user after stale upsert")
-            .expect("user exists after stale upsert");
-        assert_eq!(user.username.as_deref(), Some("alice"));
-        assert_eq!(user.nickname.as_deref(), Some("Alice"));
-        assert_eq!(user.alias.as_deref(), Some("A"));
-        assert_eq!(user.version, 1000);
+            .expect("upsert reaction");
+        let reactions = store
+            .list_message_reactions(uid, 70001, 20, 0)
+            .expect("list reactions");
+        assert_eq!(reactions.len(), 1);
+        assert_eq!(reactions[0].emoji, "👍");
 
-        store
-            .upsert_friend(
+        let mention_id = store
+            .record_mention(
                 uid,
-                &crate::UpsertFriendInput {
-                    user_id: 20001,
-                    tags: Some("work".to_string()),
-                    is_pinned: true,
-                    created_at: 1000,
-                    version: 1001,
-                    updated_at: 1001,
-                    status: 1,
-                    is_outgoing: None,
-                    request_message: None,
-                    request_source: None,
-                    request_source_id: None,
+                &crate::MentionInput {
+                    message_id: 70001,
+                    channel_id: 500,
+                    channel_type: 1,
+                    mentioned_user_id: 20001,
+                    sender_id: 20002,
+                    is_mention_all: false,
+                    created_at: 20000,
                 },
             )
-            .expect("upsert friend");
-        let friends = store.list_friends(uid, 20, 0).expect("list friends");
-        assert_eq!(friends.len(), 1);
-        assert_eq!(friends[0].user_id, 20001);
-        assert!(friends[0].is_pinned);
-        assert_eq!(friends[0].version, 1001);
+            .expect("record mention");
+        assert!(mention_id > 0);
+        let unread = store
+            .get_unread_mention_count(uid, 500, 1, 20001)
+            .expect("get unread mention");
+        assert_eq!(unread, 1);
+        let ids = store
+            .list_unread_mention_message_ids(uid, 500, 1, 20001, 10)
+            .expect("list unread mention ids");
+        assert_eq!(ids, vec![70001]);
         store
-            .upsert_friend(
-                uid,
-                &crate::UpsertFriendInput {
-                    user_id: 20001,
-                    tags: Some("stale".to_string()),
-                    is_pinned: false,
-                    created_at: 999,
-                    version: 1000,
-                    updated_at: 1000,
-                    status: 1,
-                    is_outgoing: None,
-                    request_message: None,
-                    request_source: None,
-                    request_source_id: None,
-                },
-            )
-            .expect("upsert stale friend");
-        let friends = store
-            .list_friends(uid, 20, 0)
-            .expect("list friends after stale upsert");
-        assert_eq!(friends.len(), 1);
-        assert_eq!(friends[0].tags.as_deref(), Some("work"));
-        assert!(friends[0].is_pinned);
-        assert_eq!(friends[0].version, 1001);
-        store.delete_friend(uid, 20001).expect("delete friend");
-        let friends = store
-            .list_friends(uid, 20, 0)
-            .expect("list friends after delete");
-        assert!(friends.is_empty());
+            .mark_mention_read(uid, 70001, 20001)
+            .expect("mark mention read");
+        let unread_after = store
+            .get_unread_mention_count(uid, 500, 1, 20001)
+            .expect("get unread mention after");
+        assert_eq!(unread_after, 0);
+
         store
-            .upsert_blacklist_entry(
+            .upsert_reminder(
                 uid,
-                &crate::UpsertBlacklistInput {
-                    blocked_user_id: 20001,
-                    created_at: 1003,
-                    updated_at: 1004,
+                &crate::UpsertReminderInput {
+                    reminder_id: 80001,
+                    message_id: 70001,
+                    pts: 100,
+                    channel_id: 500,
+                    channel_type: 1,
+                    uid: 20001,
+                    reminder_type: 1,
+                    text: "todo".to_string(),
+                    data: "{}".to_string(),
+                    is_locate: false,
+                    version: 1,
+                    done: false,
+                    need_upload: true,
+                    publisher: Some(20002),
                 },
             )
-            .expect("upsert blacklist entry");
-        let blacklist = store
-            .list_blacklist_entries(uid, 20, 0)
-            .expect("list blacklist entries");
-        assert_eq!(blacklist.len(), 1);
-        assert_eq!(blacklist[0].blocked_user_id, 20001);
+            .expect("upsert reminder");
+        let reminders = store
+            .list_pending_reminders(uid, 20001, 20, 0)
+            .expect("list pending reminders");
+        assert_eq!(reminders.len(), 1);
+        assert_eq!(reminders[0].reminder_id, 80001);
         store
-            .delete_blacklist_entry(uid, 20001)
-            .expect("delete blacklist entry");
-        let blacklist = store
-            .list_blacklist_entries(uid, 20, 0)
-            .expect("list blacklist entries after delete");
-        assert!(blacklist.is_empty());
+            .mark_reminder_done(uid, 80001, true)
+            .expect("mark reminder done");
+        let reminders_after = store
+            .list_pending_reminders(uid, 20001, 20, 0)
+            .expect("list pending reminders after");
+        assert!(reminders_after.is_empty());
+    }
+
+    #[test]
+    fn update_local_message_id_and_status() {
+        let store = test_store();
+        let uid = "10004";
+        let input = NewMessage {
+            channel_id: 10,
+            channel_type: 1,
+            from_uid: 20,
+            message_type: 1,
+            content: "local-id".to_string(),
+            searchable_word: "local-id".to_string(),
+            setting: 0,
+            extra: "{}".to_string(),
+            mime_type: None,
+            media_downloaded: false,
+            thumb_status: 0,
+        };
+        let message_id = store
+            .create_local_message(uid, &input, 0)
+            .expect("create local message");
 
         store
-            .upsert_group(
-                uid,
-                &crate::UpsertGroupInput {
-                    group_id: 30001,
-                    name: Some("group-a".to_string()),
-                    avatar: "avatar://group-a".to_string(),
-                    owner_id: Some(20001),
-                    is_dismissed: false,
-                    member_count: None,
-                    created_at: 1000,
-                    version: 1002,
-                    updated_at: 1002,
-                },
+            .update_local_message_id(uid, message_id, 1234567890)
+            .expect("update local_message_id");
+        store
+            .update_message_status(uid, message_id, 2)
+            .expect("update status");
+
+        let conn = store.conn_for_user(uid).expect("open conn");
+        let row: (i64, i32) = conn
+            .query_row(
+                "SELECT local_message_id, status FROM message WHERE id = ?1",
+                params![message_id as i64],
+                |r| Ok((r.get(0)?, r.get(1)?)),
             )
-            .expect("upsert group");
-        let group = store
-            .get_group_by_id(uid, 30001)
-            .expect("get group")
-            .expect("group exists");
-        assert_eq!(group.name.as_deref(), Some("group-a"));
-        assert_eq!(group.version, 1002);
+            .expect("query message");
+        assert_eq!(row.0 as u64, 1234567890);
+        assert_eq!(row.1, 2);
+    }
+
+    #[test]
+    fn mark_message_delivered_returns_local_message_id_once() {
+        let store = test_store();
+        let uid = "10004-delivery";
+        let input = NewMessage {
+            channel_id: 10,
+            channel_type: 1,
+            from_uid: 20,
+            message_type: 1,
+            content: "delivery".to_string(),
+            searchable_word: "delivery".to_string(),
+            setting: 0,
+            extra: "{}".to_string(),
+            mime_type: None,
+            media_downloaded: false,
+            thumb_status: 0,
+        };
+        let message_id = store
+            .create_local_message(uid, &input, 0)
+            .expect("create local message");
         store
-            .upsert_group(
-                uid,
-                &crate::UpsertGroupInput {
-                    group_id: 30001,
-                    name: Some("stale-group".to_string()),
-                    avatar: "avatar://stale-group".to_string(),
-                    owner_id: Some(99999),
-                    is_dismissed: true,
-                    member_count: None,
-                    created_at: 999,
-                    version: 1001,
-                    updated_at: 1001,
-                },
-            )
-            .expect("upsert stale group");
-        let group = store
-            .get_group_by_id(uid, 30001)
-            .expect("get group after stale upsert")
-            .expect("group exists after stale upsert");
-        assert_eq!(group.name.as_deref(), Some("group-a"));
-        assert_eq!(group.owner_id, Some(20001));
-        assert!(!group.is_dismissed);
-        assert_eq!(group.version, 1002);
+            .mark_message_sent(uid, message_id, 9_001, 1)
+            .expect("assign server message id");
+
+        assert_eq!(
+            store
+                .mark_message_delivered(uid, 9_001, 123)
+                .expect("mark delivered"),
+            Some(message_id),
+        );
+        assert_eq!(
+            store
+                .mark_message_delivered(uid, 9_001, 456)
+                .expect("ignore duplicate receipt"),
+            None,
+        );
+        assert_eq!(
+            store
+                .mark_message_delivered(uid, 99_999, 456)
+                .expect("ignore unknown receipt"),
+            None,
+        );
+        assert!(
+            store
+                .get_message_by_id(uid, message_id)
+                .expect("read message")
+                .expect("message exists")
+                .delivered
+        );
+    }
+
+    #[test]
+    fn message_extra_revoke_edit_pin_lifecycle() {
+        let store = test_store();
+        let uid = "10011";
+        let input = NewMessage {
+            channel_id: 777,
+            channel_type: 1,
+            from_uid: 200,
+            message_type: 1,
+            content: "before-edit".to_string(),
+            searchable_word: "before-edit".to_string(),
+            setting: 0,
+            extra: "{}".to_string(),
+            mime_type: None,
+            media_downloaded: false,
+            thumb_status: 0,
+        };
+        let message_id = store
+            .create_local_message(uid, &input, 0)
+            .expect("create message");
 
         store
-            .upsert_group_member(
-                uid,
-                &crate::UpsertGroupMemberInput {
-                    group_id: 30001,
-                    user_id: 20001,
-                    role: 0,
-                    status: 0,
-                    alias: Some("owner".to_string()),
-                    is_muted: false,
-                    joined_at: 1003,
-                    version: 1004,
-                    updated_at: 1004,
-                },
-            )
-            .expect("upsert group member");
-        let members = store
-            .list_group_members(uid, 30001, 20, 0)
-            .expect("list group members");
-        assert_eq!(members.len(), 1);
-        assert_eq!(members[0].user_id, 20001);
-        assert_eq!(members[0].display_name, "owner");
-        // delete_friend above removed this friend-only field; the public group
-        // projection must not resurrect or expose it.
-        assert_eq!(members[0].username, None);
-        assert_eq!(members[0].nickname.as_deref(), Some("Alice"));
-        assert_eq!(members[0].user_alias.as_deref(), Some("A"));
-        assert_eq!(members[0].avatar, "avatar://alice");
-        assert_eq!(members[0].version, 1004);
+            .set_message_revoke(uid, message_id, true, Some(30001))
+            .expect("set revoke");
+        let revoked_row = store
+            .get_message_by_id(uid, message_id)
+            .expect("get revoked message")
+            .expect("message exists");
+        assert_eq!(revoked_row.message_type, 0);
+        assert_eq!(revoked_row.content, "消息已撤回");
+        assert!(revoked_row.revoked);
+        assert_eq!(revoked_row.revoked_by, Some(30001));
         store
-            .upsert_group_member(
-                uid,
-                &crate::UpsertGroupMemberInput {
-                    group_id: 30001,
-                    user_id: 20001,
-                    role: 2,
-                    status: 9,
-                    alias: Some("stale".to_string()),
-                    is_muted: true,
-                    joined_at: 1002,
-                    version: 1003,
-                    updated_at: 1003,
-                },
+            .edit_message(uid, message_id, "after-edit", 123)
+            .expect("edit message");
+        store
+            .set_message_pinned(uid, message_id, true)
+            .expect("set pinned");
+
+        let row = store
+            .get_message_by_id(uid, message_id)
+            .expect("get message")
+            .expect("message exists");
+        assert_eq!(row.content, "after-edit");
+
+        let extra = store
+            .get_message_extra(uid, message_id)
+            .expect("get message extra")
+            .expect("message extra exists");
+        assert!(extra.revoke);
+        assert_eq!(extra.revoker, Some(30001));
+        assert_eq!(extra.content_edit.as_deref(), Some("after-edit"));
+        assert_eq!(extra.edited_at, 123);
+        assert!(extra.is_pinned);
+    }
+
+    /// 升级前就编辑过的消息：迁移补出 version 0（原文已丢，占位）和 version 1，
+    /// created_at 按毫秒取自时间字段而不是 extra_version；之后的编辑接着记 version 2。
+    #[test]
+    fn the_revision_migration_backfills_the_documented_versions() {
+        use rusqlite::Connection;
+
+        let dir = std::env::temp_dir().join(format!(
+            "privchat-mig-revision-{}-{}",
+            std::process::id(),
+            chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        ));
+        std::fs::create_dir_all(&dir).expect("tmp dir");
+        let db_path = dir.join("legacy.db");
+        let mut conn = Connection::open(&db_path).expect("open");
+        super::embedded::migrations::runner()
+            .set_target(refinery::Target::Version(20_260_804_090_000))
+            .run(&mut conn)
+            .expect("migrate to the version right before message_revision");
+        conn.execute_batch(
+            "INSERT INTO message (id, server_message_id, channel_id, channel_type, from_uid,
+                                  type, content, status, created_at, updated_at,
+                                  searchable_word, local_message_id, setting, extra)
+             VALUES (1, 900, 100, 1, 200, 1, 'edited', 2, 1700000000000, 0, 'e', 1, 0, '{}');
+             INSERT INTO message_extra (message_id, channel_id, channel_type, content_edit,
+                                        edited_at, extra_version)
+             VALUES (1, 100, 1, 'edited', 1700000100, 42);",
+        )
+        .expect("seed an edited message");
+        super::embedded::migrations::runner()
+            .run(&mut conn)
+            .expect("upgrade");
+
+        let rows: Vec<(i64, String, i64, Option<i64>, i64)> = conn
+            .prepare(
+                "SELECT version, content, edited_at, editor_uid, created_at
+                 FROM message_revision WHERE message_id = 1 ORDER BY version",
             )
-            .expect("upsert stale group member");
-        let members = store
-            .list_group_members(uid, 30001, 20, 0)
-            .expect("list group members after stale upsert");
-        assert_eq!(members.len(), 1);
-        assert_eq!(members[0].role, 0);
-        assert_eq!(members[0].status, 0);
-        assert_eq!(members[0].alias.as_deref(), Some("owner"));
-        assert_eq!(members[0].display_name, "owner");
-        assert!(!members[0].is_muted);
-        assert_eq!(members[0].version, 1004);
+            .expect("prepare")
+            .query_map([], |r| {
+                Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?))
+            })
+            .expect("query")
+            .collect::<std::result::Result<_, _>>()
+            .expect("rows");
+        assert_eq!(rows.len(), 2);
+        let (sent_s, edited_s) = (1_700_000_000, 1_700_000_100);
+        assert_eq!(
+            rows[0],
+            (0, String::new(), sent_s, Some(200), sent_s * 1000)
+        );
+        assert_eq!(
+            rows[1],
+            (1, "edited".into(), edited_s, None, edited_s * 1000)
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
-        // The view is a live JOIN, not denormalized member data. A profile
-        // update changes the projected name without rewriting group_member.
+    /// 编辑按版本单调应用：乱序到达的旧版本不能盖掉新版本，但原文和每一版都留在历史里。
+    #[test]
+    fn message_revisions_are_monotonic_and_keep_the_original() {
+        let store = test_store();
+        let uid = "10012";
+        let input = NewMessage {
+            channel_id: 778,
+            channel_type: 1,
+            from_uid: 200,
+            message_type: 0,
+            content: "v0".to_string(),
+            searchable_word: "v0".to_string(),
+            setting: 0,
+            extra: "{}".to_string(),
+            mime_type: None,
+            media_downloaded: false,
+            thumb_status: 0,
+        };
+        let message_id = store
+            .create_local_message(uid, &input, 0)
+            .expect("create message");
+        let revision = |version: u64, content: &str| MessageRevisionInput {
+            version: Some(version),
+            content: content.to_string(),
+            edited_at: version as i32 * 10,
+            editor_uid: Some(200),
+        };
+
+        assert_eq!(
+            store
+                .apply_message_revision(uid, message_id, &revision(2, "v2"))
+                .expect("apply v2"),
+            Some(2)
+        );
+        // v1 晚到：丢弃，正文保持 v2。
+        assert_eq!(
+            store
+                .apply_message_revision(uid, message_id, &revision(1, "v1"))
+                .expect("apply stale v1"),
+            None
+        );
+        assert_eq!(
+            store
+                .apply_message_revision(uid, message_id, &revision(2, "v2-dup"))
+                .expect("apply duplicate v2"),
+            None
+        );
+        let row = store
+            .get_message_by_id(uid, message_id)
+            .expect("get message")
+            .expect("message exists");
+        assert_eq!(row.content, "v2");
+        assert_eq!(row.revision_count, 1);
+
+        // 本地编辑是待确认的一版：排在最后、版本号临时，编辑者是当前账号。
         store
-            .upsert_user(
-                uid,
-                &crate::UpsertUserInput {
-                    user_id: 20001,
-                    username: Some("alice-2".to_string()),
-                    nickname: Some("Alice 2".to_string()),
-                    alias: Some("A2".to_string()),
-                    avatar: "avatar://alice-2".to_string(),
-                    user_type: 0,
-                    is_deleted: false,
-                    channel_id: "c-alice".to_string(),
-                    version: 2000,
-                    updated_at: 2000,
-                },
-            )
-            .expect("update user projection");
-        let members = store
-            .list_group_members(uid, 30001, 20, 0)
-            .expect("list group members after user update");
-        assert_eq!(members[0].display_name, "owner");
-        assert_eq!(members[0].nickname.as_deref(), Some("Alice 2"));
-        assert_eq!(members[0].avatar, "avatar://alice-2");
+            .edit_message(uid, message_id, "v3", 30)
+            .expect("local edit");
+        let history = store
+            .list_message_revisions(uid, message_id)
+            .expect("list revisions");
+        let versions: Vec<(u64, &str, bool)> = history
+            .iter()
+            .map(|r| (r.version, r.content.as_str(), r.pending))
+            .collect();
+        assert_eq!(
+            versions,
+            vec![(0, "v0", false), (2, "v2", false), (3, "v3", true)]
+        );
+        assert_eq!(history[0].editor_uid, Some(200));
+        assert_eq!(history[2].editor_uid, Some(10012));
+        let listed = store
+            .list_messages(uid, 778, 1, 10, 0)
+            .expect("list messages");
+        assert_eq!(listed[0].revision_count, 2);
+        assert_eq!(
+            store
+                .get_message_extra(uid, message_id)
+                .expect("get extra")
+                .expect("extra exists")
+                .content_edit
+                .as_deref(),
+            Some("v3")
+        );
+    }
+
+    /// 本地编辑不占版本号：别的端先拿到的版本照常落下；服务端回来相同内容才算确认，
+    /// 确认前界面一直显示本地那一版。
+    #[test]
+    fn pending_local_edit_is_kept_apart_from_server_versions() {
+        let store = test_store();
+        let uid = "10012";
+        let input = NewMessage {
+            channel_id: 780,
+            channel_type: 1,
+            from_uid: 10012,
+            message_type: 0,
+            content: "v0".to_string(),
+            searchable_word: "v0".to_string(),
+            setting: 0,
+            extra: "{}".to_string(),
+            mime_type: None,
+            media_downloaded: false,
+            thumb_status: 0,
+        };
+        let message_id = store
+            .create_local_message(uid, &input, 0)
+            .expect("create message");
+        // 另一端的编辑发生在本地编辑之前（edited_at 更早），只是先到了服务端。
+        let revision = |version: u64, content: &str, editor: u64| MessageRevisionInput {
+            version: Some(version),
+            content: content.to_string(),
+            edited_at: version as i32 * 3,
+            editor_uid: Some(editor),
+        };
+
         store
-            .delete_group_member(uid, 30001, 20001)
-            .expect("delete group member");
-        let members = store
-            .list_group_members(uid, 30001, 20, 0)
-            .expect("list group members after delete");
-        assert!(members.is_empty());
+            .edit_message(uid, message_id, "mine", 5)
+            .expect("local edit");
+        // 再改一次覆盖上一份待确认的，不多出一版。
+        store
+            .edit_message(uid, message_id, "mine again", 6)
+            .expect("local edit again");
+        let history = store
+            .list_message_revisions(uid, message_id)
+            .expect("list revisions");
+        assert_eq!(history.len(), 2);
+        assert!(history[1].pending);
+        assert_eq!(history[1].version, 1);
+
+        // 另一端的编辑先到，拿走 version 1；本地那版仍待确认，界面不被盖掉。
+        assert_eq!(
+            store
+                .apply_message_revision(uid, message_id, &revision(1, "theirs", 10013))
+                .expect("apply other device edit"),
+            Some(1)
+        );
+        let row = store
+            .get_message_by_id(uid, message_id)
+            .expect("get message")
+            .expect("message exists");
+        assert_eq!(row.content, "mine again");
+        assert_eq!(row.revision_count, 2);
+        let history = store
+            .list_message_revisions(uid, message_id)
+            .expect("list revisions");
+        let versions: Vec<(u64, &str, bool)> = history
+            .iter()
+            .map(|r| (r.version, r.content.as_str(), r.pending))
+            .collect();
+        assert_eq!(
+            versions,
+            vec![
+                (0, "v0", false),
+                (1, "theirs", false),
+                (2, "mine again", true)
+            ]
+        );
+
+        // 服务端确认本地那版：用服务端的版本号，待确认的那份删掉。
+        assert_eq!(
+            store
+                .apply_message_revision(uid, message_id, &revision(2, "mine again", 10012))
+                .expect("confirm local edit"),
+            Some(2)
+        );
+        let history = store
+            .list_message_revisions(uid, message_id)
+            .expect("list revisions");
+        assert_eq!(history.len(), 3);
+        assert!(history.iter().all(|r| !r.pending));
+        let row = store
+            .get_message_by_id(uid, message_id)
+            .expect("get message")
+            .expect("message exists");
+        assert_eq!(row.content, "mine again");
+        assert_eq!(row.revision_count, 2);
 
         store
-            .upsert_channel_member(
-                uid,
-                &crate::UpsertChannelMemberInput {
-                    channel_id: 30001,
-                    channel_type: 2,
-                    member_uid: 20001,
-                    member_name: "alice".to_string(),
-                    member_remark: "owner".to_string(),
-                    member_avatar: "avatar://alice".to_string(),
-                    member_invite_uid: 20001,
-                    role: 1,
-                    status: 1,
-                    is_deleted: false,
-                    robot: 0,
-                    version: 1,
-                    created_at: 1005,
-                    updated_at: 1006,
-                    extra: "{}".to_string(),
-                    forbidden_expiration_time: 0,
-                    member_avatar_cache_key: "cache-alice".to_string(),
-                },
-            )
-            .expect("upsert channel member");
-        let ch_members = store
-            .list_channel_members(uid, 30001, 2, 20, 0)
-            .expect("list channel members");
-        assert_eq!(ch_members.len(), 1);
-        assert_eq!(ch_members[0].member_uid, 20001);
+            .delete_message_local(uid, message_id)
+            .expect("delete message");
         store
-            .delete_channel_member(uid, 30001, 2, 20001)
-            .expect("delete channel member");
-        let ch_members_after = store
-            .list_channel_members(uid, 30001, 2, 20, 0)
-            .expect("list channel members after delete");
-        assert!(ch_members_after.is_empty());
+            .edit_message(uid, message_id, "gone", 7)
+            .expect_err("edit of a deleted message fails");
     }
 
+    /// 不带版本号的老推送：同一份编辑重复同步只算一次。
     #[test]
-    fn batch_upsert_users_materializes_a_large_identity_page_in_one_call() {
+    fn versionless_edit_resync_does_not_add_revisions() {
         let store = test_store();
-        let uid = "batch-users";
-        let inputs: Vec<_> = (1..=1_000)
-            .map(|user_id| crate::UpsertUserInput {
-                user_id,
-                username: Some(format!("user-{user_id}")),
-                nickname: Some(format!("User {user_id}")),
-                alias: None,
-                avatar: String::new(),
-                user_type: 0,
-                is_deleted: false,
-                channel_id: String::new(),
-                version: user_id as i64,
-                updated_at: user_id as i64,
-            })
-            .collect();
+        let uid = "10012";
+        let input = NewMessage {
+            channel_id: 781,
+            channel_type: 1,
+            from_uid: 200,
+            message_type: 0,
+            content: "v0".to_string(),
+            searchable_word: "v0".to_string(),
+            setting: 0,
+            extra: "{}".to_string(),
+            mime_type: None,
+            media_downloaded: false,
+            thumb_status: 0,
+        };
+        let message_id = store
+            .create_local_message(uid, &input, 0)
+            .expect("create message");
+        let edit = MessageRevisionInput {
+            version: None,
+            content: "edited".to_string(),
+            edited_at: 100,
+            editor_uid: None,
+        };
 
-        store
-            .batch_upsert_users(uid, &inputs)
-            .expect("batch upsert users");
+        assert_eq!(
+            store
+                .apply_message_revision(uid, message_id, &edit)
+                .expect("apply edit"),
+            Some(1)
+        );
+        assert_eq!(
+            store
+                .apply_message_revision(uid, message_id, &edit)
+                .expect("re-sync edit"),
+            None
+        );
+        let row = store
+            .get_message_by_id(uid, message_id)
+            .expect("get message")
+            .expect("message exists");
+        assert_eq!(row.content, "edited");
+        assert_eq!(row.revision_count, 1);
+        assert_eq!(
+            store
+                .list_message_revisions(uid, message_id)
+                .expect("list revisions")
+                .len(),
+            2
+        );
 
-        let first = store
-            .get_user_by_id(uid, 1)
-            .expect("read first")
-            .expect("first exists");
-        let last = store
-            .get_user_by_id(uid, 1_000)
-            .expect("read last")
-            .expect("last exists");
-        assert_eq!(first.nickname.as_deref(), Some("User 1"));
-        assert_eq!(last.nickname.as_deref(), Some("User 1000"));
+        // 内容相同、时间不同是又一次编辑。
+        assert_eq!(
+            store
+                .apply_message_revision(
+                    uid,
+                    message_id,
+                    &MessageRevisionInput {
+                        edited_at: 200,
+                        ..edit.clone()
+                    },
+                )
+                .expect("apply later edit"),
+            Some(2)
+        );
     }
 
+    /// 待确认的本地编辑不会一直挡着：更新的服务端编辑顶掉它；服务端拒绝时退回服务端版本。
     #[test]
-    fn reaction_mention_reminder_lifecycle() {
+    fn pending_local_edit_is_superseded_or_discarded() {
         let store = test_store();
-        let uid = "10010";
+        let uid = "10012";
+        let input = NewMessage {
+            channel_id: 782,
+            channel_type: 1,
+            from_uid: 10012,
+            message_type: 0,
+            content: "v0".to_string(),
+            searchable_word: "v0".to_string(),
+            setting: 0,
+            extra: "{}".to_string(),
+            mime_type: None,
+            media_downloaded: false,
+            thumb_status: 0,
+        };
+        let message_id = store
+            .create_local_message(uid, &input, 0)
+            .expect("create message");
+        let revision = |version: u64, content: &str, edited_at: i32| MessageRevisionInput {
+            version: Some(version),
+            content: content.to_string(),
+            edited_at,
+            editor_uid: Some(10013),
+        };
+        let content = |store: &LocalStore| {
+            store
+                .get_message_by_id(uid, message_id)
+                .expect("get message")
+                .expect("message exists")
+                .content
+        };
+        let pending = |store: &LocalStore| {
+            store
+                .list_message_revisions(uid, message_id)
+                .expect("list revisions")
+                .iter()
+                .any(|r| r.pending)
+        };
 
+        // 另一端晚于本地编辑的那次编辑到了：顶掉待确认的那份。
         store
-            .upsert_message_reaction(
-                uid,
-                &crate::UpsertMessageReactionInput {
-                    channel_id: 500,
-                    channel_type: 1,
-                    uid: 20001,
-                    name: "alice".to_string(),
-                    emoji: "👍".to_string(),
-                    message_id: 70001,
-                    seq: 1,
-                    is_deleted: false,
-                    created_at: 12345,
-                },
-            )
-            .expect("upsert reaction");
-        let reactions = store
-            .list_message_reactions(uid, 70001, 20, 0)
-            .expect("list reactions");
-        assert_eq!(reactions.len(), 1);
-        assert_eq!(reactions[0].emoji, "👍");
+            .edit_message(uid, message_id, "mine", 10)
+            .expect("local edit");
+        store
+            .apply_message_revision(uid, message_id, &revision(1, "theirs", 20))
+            .expect("apply newer edit");
+        assert_eq!(content(&store), "theirs");
+        assert!(!pending(&store));
 
-        let mention_id = store
-            .record_mention(
-                uid,
-                &crate::MentionInput {
-                    message_id: 70001,
-                    channel_id: 500,
-                    channel_type: 1,
-                    mentioned_user_id: 20001,
-                    sender_id: 20002,
-                    is_mention_all: false,
-                    created_at: 20000,
-                },
-            )
-            .expect("record mention");
-        assert!(mention_id > 0);
-        let unread = store
-            .get_unread_mention_count(uid, 500, 1, 20001)
-            .expect("get unread mention");
-        assert_eq!(unread, 1);
-        let ids = store
-            .list_unread_mention_message_ids(uid, 500, 1, 20001, 10)
-            .expect("list unread mention ids");
-        assert_eq!(ids, vec![70001]);
+        // 版本越过本地编辑时看到的版本 + 1：即便时间更早，本地那份也已经落后了。
         store
-            .mark_mention_read(uid, 70001, 20001)
-            .expect("mark mention read");
-        let unread_after = store
-            .get_unread_mention_count(uid, 500, 1, 20001)
-            .expect("get unread mention after");
-        assert_eq!(unread_after, 0);
+            .edit_message(uid, message_id, "mine", 30)
+            .expect("local edit");
+        store
+            .apply_message_revision(uid, message_id, &revision(3, "later", 25))
+            .expect("apply version past the pending edit");
+        assert_eq!(content(&store), "later");
+        assert!(!pending(&store));
 
+        // 被拒：退回最新的服务端版本。
         store
-            .upsert_reminder(
-                uid,
-                &crate::UpsertReminderInput {
-                    reminder_id: 80001,
-                    message_id: 70001,
-                    pts: 100,
-                    channel_id: 500,
-                    channel_type: 1,
-                    uid: 20001,
-                    reminder_type: 1,
-                    text: "todo".to_string(),
-                    data: "{}".to_string(),
-                    is_locate: false,
-                    version: 1,
-                    done: false,
-                    need_upload: true,
-                    publisher: Some(20002),
-                },
-            )
-            .expect("upsert reminder");
-        let reminders = store
-            .list_pending_reminders(uid, 20001, 20, 0)
-            .expect("list pending reminders");
-        assert_eq!(reminders.len(), 1);
-        assert_eq!(reminders[0].reminder_id, 80001);
+            .edit_message(uid, message_id, "rejected", 40)
+            .expect("local edit");
+        assert_eq!(content(&store), "rejected");
+        assert!(store
+            .discard_pending_message_edit(uid, message_id)
+            .expect("discard pending edit"));
+        assert_eq!(content(&store), "later");
+        assert!(!pending(&store));
+        assert!(!store
+            .discard_pending_message_edit(uid, message_id)
+            .expect("nothing left to discard"));
+    }
+
+    /// 从没被服务端编辑过的消息，本地编辑被拒后回到原文、不再算已编辑。
+    #[test]
+    fn discarding_the_only_edit_restores_the_original() {
+        let store = test_store();
+        let uid = "10012";
+        let input = NewMessage {
+            channel_id: 783,
+            channel_type: 1,
+            from_uid: 10012,
+            message_type: 0,
+            content: "v0".to_string(),
+            searchable_word: "v0".to_string(),
+            setting: 0,
+            extra: "{}".to_string(),
+            mime_type: None,
+            media_downloaded: false,
+            thumb_status: 0,
+        };
+        let message_id = store
+            .create_local_message(uid, &input, 0)
+            .expect("create message");
         store
-            .mark_reminder_done(uid, 80001, true)
-            .expect("mark reminder done");
-        let reminders_after = store
-            .list_pending_reminders(uid, 20001, 20, 0)
-            .expect("list pending reminders after");
-        assert!(reminders_after.is_empty());
+            .edit_message(uid, message_id, "mine", 10)
+            .expect("local edit");
+        assert!(store
+            .discard_pending_message_edit(uid, message_id)
+            .expect("discard pending edit"));
+
+        let row = store
+            .get_message_by_id(uid, message_id)
+            .expect("get message")
+            .expect("message exists");
+        assert_eq!(row.content, "v0");
+        assert_eq!(row.revision_count, 0);
+        assert!(store
+            .list_message_revisions(uid, message_id)
+            .expect("list revisions")
+            .is_empty());
+        assert_eq!(
+            store
+                .get_message_extra(uid, message_id)
+                .expect("get extra")
+                .and_then(|extra| extra.content_edit),
+            None
+        );
     }
 
+    /// 投票：自己的选择先落本地并入队，同一投票只留最新一条命令；被拒退回确认过的选择；
+    /// 票数按版本单调。
     #[test]
-    fn update_local_message_id_and_status() {
+    fn poll_votes_coalesce_settle_and_tallies_are_monotonic() {
         let store = test_store();
-        let uid = "10004";
+        let uid = "10013";
         let input = NewMessage {
-            channel_id: 10,
-            channel_type: 1,
-            from_uid: 20,
-            message_type: 1,
-            content: "local-id".to_string(),
-            searchable_word: "local-id".to_string(),
+            channel_id: 779,
+            channel_type: 2,
+            from_uid: 200,
+            message_type: crate::POLL_MESSAGE_TYPE,
+            content: "lunch?".to_string(),
+            searchable_word: "lunch?".to_string(),
             setting: 0,
             extra: "{}".to_string(),
             mime_type: None,
@@ -8619,38 +12511,96 @@ mod tests {
         };
         let message_id = store
             .create_local_message(uid, &input, 0)
-            .expect("create local message");
+            .expect("create message");
+        let vote = |option_indexes: Vec<u32>| PollVoteCommand {
+            message_id,
+            server_message_id: 9001,
+            channel_id: 779,
+            channel_type: 2,
+            option_indexes,
+        };
+        let tally = || {
+            store
+                .get_message_by_id(uid, message_id)
+                .expect("get message")
+                .expect("message exists")
+                .poll
+                .expect("poll state")
+        };
 
         store
-            .update_local_message_id(uid, message_id, 1234567890)
-            .expect("update local_message_id");
+            .poll_record_vote(uid, "poll_vote:1", &vote(vec![0]))
+            .expect("vote 0");
         store
-            .update_message_status(uid, message_id, 2)
-            .expect("update status");
+            .poll_record_vote(uid, "poll_vote:2", &vote(vec![1]))
+            .expect("vote 1");
+        let queued = store
+            .outbox_peek_rpc(uid, 10, i64::MAX)
+            .expect("peek rpc outbox");
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].command_id, "poll_vote:2");
+        assert_eq!(tally().my_votes, vec![1]);
+        assert!(tally().vote_pending);
+        // 命令还在队列里：服务端旧的「我的选择」不盖掉界面上的。
+        let update = PollTallyUpdate {
+            counts: vec![3, 1],
+            voter_count: 4,
+            version: 5,
+            my_votes: Some(Vec::new()),
+            ..Default::default()
+        };
+        assert!(store
+            .apply_poll_update(uid, message_id, &update)
+            .expect("apply v5"));
+        assert_eq!(tally().my_votes, vec![1]);
 
-        let conn = store.conn_for_user(uid).expect("open conn");
-        let row: (i64, i32) = conn
-            .query_row(
-                "SELECT local_message_id, status FROM message WHERE id = ?1",
-                params![message_id as i64],
-                |r| Ok((r.get(0)?, r.get(1)?)),
-            )
-            .expect("query message");
-        assert_eq!(row.0 as u64, 1234567890);
-        assert_eq!(row.1, 2);
+        store
+            .poll_vote_settled(uid, queued[0].id, &vote(vec![1]), true, None)
+            .expect("settle accepted");
+        assert_eq!(tally().my_votes, vec![1]);
+        assert!(!tally().vote_pending);
+
+        store
+            .poll_record_vote(uid, "poll_vote:3", &vote(vec![0]))
+            .expect("vote 0 again");
+        let queued = store
+            .outbox_peek_rpc(uid, 10, i64::MAX)
+            .expect("peek rpc outbox");
+        store
+            .poll_vote_settled(uid, queued[0].id, &vote(vec![0]), false, None)
+            .expect("settle rejected");
+        assert_eq!(tally().my_votes, vec![1]);
+        assert!(store
+            .outbox_peek_rpc(uid, 10, i64::MAX)
+            .expect("peek rpc outbox")
+            .is_empty());
+
+        let stale = PollTallyUpdate {
+            counts: vec![1, 1],
+            voter_count: 2,
+            version: 4,
+            ..Default::default()
+        };
+        assert!(!store
+            .apply_poll_update(uid, message_id, &stale)
+            .expect("apply stale v4"));
+        assert_eq!(tally().counts, vec![3, 1]);
+        assert_eq!(tally().version, 5);
     }
 
+    /// 置顶：自己的置顶先进索引（pending），服务端列表不覆盖未发命令；确认后按服务端的
+    /// 置顶人 / 时间落定；之后服务端列表里没有了就删掉，`is_pinned` 跟着走。
     #[test]
-    fn mark_message_delivered_returns_local_message_id_once() {
+    fn pinned_messages_index_follows_commands_and_server_lists() {
         let store = test_store();
-        let uid = "10004-delivery";
+        let uid = "10014";
         let input = NewMessage {
-            channel_id: 10,
-            channel_type: 1,
-            from_uid: 20,
-            message_type: 1,
-            content: "delivery".to_string(),
-            searchable_word: "delivery".to_string(),
+            channel_id: 780,
+            channel_type: 2,
+            from_uid: 200,
+            message_type: 0,
+            content: "pin me".to_string(),
+            searchable_word: "pin me".to_string(),
             setting: 0,
             extra: "{}".to_string(),
             mime_type: None,
@@ -8659,49 +12609,290 @@ mod tests {
         };
         let message_id = store
             .create_local_message(uid, &input, 0)
-            .expect("create local message");
+            .expect("create message");
         store
-            .mark_message_sent(uid, message_id, 9_001, 1)
-            .expect("assign server message id");
+            .mark_message_sent(uid, message_id, 7_001, 1)
+            .expect("mark sent");
+        let command = MessagePinCommand {
+            message_id,
+            server_message_id: 7_001,
+            channel_id: 780,
+            channel_type: 2,
+            pinned: true,
+        };
+        let older = RemotePin {
+            server_message_id: 6_500,
+            pinned_by: Some(300),
+            pinned_at: 1_000,
+        };
 
+        store
+            .message_pin_record(uid, "message_pin:1", &command, Some(10014))
+            .expect("record pin");
+        // 服务端列表还不知道这次置顶：本地的保留。
+        assert!(store
+            .replace_channel_pins(uid, 780, 2, std::slice::from_ref(&older))
+            .expect("replace with server list"));
+        let pins = store
+            .list_pinned_messages(uid, 780, 2)
+            .expect("list pinned");
+        let listed: Vec<(u64, Option<u64>, bool)> = pins
+            .iter()
+            .map(|p| (p.server_message_id, p.message_id, p.pending))
+            .collect();
         assert_eq!(
-            store
-                .mark_message_delivered(uid, 9_001, 123)
-                .expect("mark delivered"),
-            Some(message_id),
+            listed,
+            vec![(7_001, Some(message_id), true), (6_500, None, false)]
         );
-        assert_eq!(
+        assert_eq!(pins[0].pinned_by, Some(10014));
+
+        let queued = store
+            .outbox_peek_rpc(uid, 10, i64::MAX)
+            .expect("peek rpc outbox");
+        let confirmed = RemotePin {
+            server_message_id: 7_001,
+            pinned_by: Some(10014),
+            pinned_at: 5_000,
+        };
+        store
+            .message_pin_settled(uid, queued[0].id, &command, Some(&confirmed))
+            .expect("settle pin");
+        let pins = store
+            .list_pinned_messages(uid, 780, 2)
+            .expect("list pinned");
+        assert_eq!(pins[0].pinned_at, 5_000);
+        assert!(!pins[0].pending);
+        assert!(
             store
-                .mark_message_delivered(uid, 9_001, 456)
-                .expect("ignore duplicate receipt"),
-            None,
+                .get_message_extra(uid, message_id)
+                .expect("get extra")
+                .expect("extra exists")
+                .is_pinned
         );
+
+        // 别人在别的端取消了置顶。
+        assert!(store
+            .replace_channel_pins(uid, 780, 2, &[older])
+            .expect("replace with server list"));
         assert_eq!(
             store
-                .mark_message_delivered(uid, 99_999, 456)
-                .expect("ignore unknown receipt"),
-            None,
+                .list_pinned_messages(uid, 780, 2)
+                .expect("list pinned")
+                .len(),
+            1
         );
         assert!(
+            !store
+                .get_message_extra(uid, message_id)
+                .expect("get extra")
+                .expect("extra exists")
+                .is_pinned
+        );
+    }
+
+    /// 装 / 卸先改本地再排命令；命令没发出去时同步不覆盖，被拒绝时退回。
+    #[test]
+    fn sticker_catalog_follows_commands_and_sync() {
+        let store = test_store();
+        let uid = "10015";
+        let sticker = |id: &str, package_id: &str| Sticker {
+            sticker_id: id.to_string(),
+            package_id: package_id.to_string(),
+            image_url: format!("https://cdn.example/{id}.webp"),
+            emoji: Some("😀".to_string()),
+            ..Default::default()
+        };
+        let package = |id: &str, stickers: Vec<Sticker>| StickerPackage {
+            package_id: id.to_string(),
+            name: id.to_string(),
+            sticker_count: stickers.len() as u64,
+            stickers,
+            ..Default::default()
+        };
+        store
+            .cache_sticker_packages(
+                uid,
+                &[
+                    package("cats", vec![sticker("c1", "cats"), sticker("c2", "cats")]),
+                    package("dogs", Vec::new()),
+                ],
+            )
+            .expect("cache packages");
+        assert!(store
+            .list_sticker_packages(uid, true)
+            .expect("list installed")
+            .is_empty());
+
+        let install = |package_id: &str, installed: bool| StickerInstallCommand {
+            package_id: package_id.to_string(),
+            installed,
+            previous: None,
+        };
+        store
+            .sticker_install_record(uid, "sticker_install:1", &install("cats", true))
+            .expect("install cats");
+        store
+            .sticker_install_record(uid, "sticker_install:2", &install("dogs", true))
+            .expect("install dogs");
+        // 本地还没见过的包也装得上（离线、从别人的消息里添加），元数据等拉详情。
+        store
+            .sticker_install_record(uid, "sticker_install:3", &install("birds", true))
+            .expect("install birds");
+        let installed = store.list_sticker_packages(uid, true).expect("list");
+        let ids: Vec<&str> = installed.iter().map(|p| p.package_id.as_str()).collect();
+        assert_eq!(ids, vec!["birds", "dogs", "cats"]);
+        assert!(installed.iter().all(|p| p.pending));
+        assert!(installed[0].stickers.is_empty());
+        assert_eq!(installed[2].stickers.len(), 2);
+
+        // 别的端卸了 cats，但本机的安装命令还没发出去：不覆盖。
+        let remote = RemoteStickerInstall {
+            package_id: "cats".to_string(),
+            installed: false,
+            installed_at: None,
+        };
+        assert!(store
+            .apply_remote_sticker_installs(uid, std::slice::from_ref(&remote))
+            .expect("apply remote")
+            .is_empty());
+
+        let queued = store.outbox_peek_rpc(uid, 10, i64::MAX).expect("peek");
+        assert_eq!(queued.len(), 3);
+        let queued_command = |i: usize| -> StickerInstallCommand {
+            serde_json::from_slice(&queued[i].payload).expect("decode command")
+        };
+        assert!(!store
+            .sticker_install_settled(uid, queued[0].id, &queued_command(0), true)
+            .expect("settle cats"));
+        // dogs、birds 被服务端拒绝：退回没装。
+        assert!(store
+            .sticker_install_settled(uid, queued[1].id, &queued_command(1), false)
+            .expect("settle dogs"));
+        assert!(store
+            .sticker_install_settled(uid, queued[2].id, &queued_command(2), false)
+            .expect("settle birds"));
+        assert_eq!(
             store
-                .get_message_by_id(uid, message_id)
-                .expect("read message")
-                .expect("message exists")
-                .delivered
+                .apply_remote_sticker_installs(uid, &[remote])
+                .expect("apply remote")
+                .len(),
+            1
         );
+        assert!(store
+            .list_sticker_packages(uid, true)
+            .expect("list")
+            .is_empty());
+
+        // 新的包列表不再含 c2，但它在最近使用里：留着，只是不算在包里。
+        store
+            .record_sticker_use(uid, &sticker("c2", "cats"), 1_000)
+            .expect("use c2");
+        store
+            .record_sticker_use(uid, &sticker("c1", "cats"), 2_000)
+            .expect("use c1");
+        store
+            .cache_sticker_packages(uid, &[package("cats", vec![sticker("c1", "cats")])])
+            .expect("recache cats");
+        let cats = store
+            .get_sticker_package(uid, "cats")
+            .expect("get cats")
+            .expect("cats cached");
+        assert_eq!(cats.stickers.len(), 1);
+        let recent: Vec<String> = store
+            .list_recent_stickers(uid)
+            .expect("recent")
+            .into_iter()
+            .map(|s| s.sticker_id)
+            .collect();
+        assert_eq!(recent, vec!["c1", "c2"]);
+
+        // 从消息里收藏一个不在任何包里的表情。
+        let foreign = sticker("x9", "elsewhere");
+        assert!(store
+            .set_sticker_favourite(uid, &foreign, true)
+            .expect("favourite"));
+        assert!(!store
+            .set_sticker_favourite(uid, &foreign, true)
+            .expect("favourite again"));
+        assert_eq!(
+            store.get_sticker(uid, "x9").expect("get sticker"),
+            Some(foreign.clone())
+        );
+        assert_eq!(
+            store.list_favourite_stickers(uid).expect("favourites"),
+            vec![foreign.clone()]
+        );
+        assert!(store
+            .set_sticker_favourite(uid, &foreign, false)
+            .expect("unfavourite"));
     }
 
+    /// 卸载被拒：包回到原来的位置、原来的安装时间，而不是当作新装的排到最前面。离线时
+    /// 装了又卸、卸了又装，退回的都是服务端最后认可的那一份。
     #[test]
-    fn message_extra_revoke_edit_pin_lifecycle() {
+    fn a_rejected_sticker_command_restores_the_previous_row() {
         let store = test_store();
-        let uid = "10011";
+        let uid = "10015";
+        let remote = |package_id: &str, installed_at: i64| RemoteStickerInstall {
+            package_id: package_id.to_string(),
+            installed: true,
+            installed_at: Some(installed_at),
+        };
+        store
+            .apply_remote_sticker_installs(uid, &[remote("cats", 100), remote("dogs", 200)])
+            .expect("sync installs");
+        let before = store.list_sticker_packages(uid, true).expect("list");
+        let ids: Vec<&str> = before.iter().map(|p| p.package_id.as_str()).collect();
+        assert_eq!(ids, vec!["dogs", "cats"]);
+
+        let command = |installed: bool| StickerInstallCommand {
+            package_id: "cats".to_string(),
+            installed,
+            previous: None,
+        };
+        store
+            .sticker_install_record(uid, "sticker_install:1", &command(false))
+            .expect("uninstall cats");
+        store
+            .sticker_install_record(uid, "sticker_install:2", &command(true))
+            .expect("reinstall cats");
+        store
+            .sticker_install_record(uid, "sticker_install:3", &command(false))
+            .expect("uninstall cats again");
+        let queued = store.outbox_peek_rpc(uid, 10, i64::MAX).expect("peek");
+        assert_eq!(queued.len(), 1);
+        let queued_command: StickerInstallCommand =
+            serde_json::from_slice(&queued[0].payload).expect("decode command");
+        assert!(store
+            .sticker_install_settled(uid, queued[0].id, &queued_command, false)
+            .expect("settle"));
+
+        let after = store.list_sticker_packages(uid, true).expect("list");
+        assert_eq!(
+            after
+                .iter()
+                .map(|p| (p.package_id.as_str(), p.sort_order, p.installed_at))
+                .collect::<Vec<_>>(),
+            before
+                .iter()
+                .map(|p| (p.package_id.as_str(), p.sort_order, p.installed_at))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    /// 实时位置：自己的更新改写同一行、按上次发出时间节流，排着的更新被新的和结束覆盖；
+    /// 别人的位置按版本单调应用，结束只进不退。
+    #[test]
+    fn live_location_updates_amend_one_row_and_are_throttled() {
+        let store = test_store();
+        let uid = "10015";
         let input = NewMessage {
-            channel_id: 777,
-            channel_type: 1,
-            from_uid: 200,
-            message_type: 1,
-            content: "before-edit".to_string(),
-            searchable_word: "before-edit".to_string(),
+            channel_id: 780,
+            channel_type: 2,
+            from_uid: 10015,
+            message_type: crate::LIVE_LOCATION_MESSAGE_TYPE,
+            content: String::new(),
+            searchable_word: String::new(),
             setting: 0,
             extra: "{}".to_string(),
             mime_type: None,
@@ -8711,40 +12902,221 @@ mod tests {
         let message_id = store
             .create_local_message(uid, &input, 0)
             .expect("create message");
+        let command = |latitude: Option<f64>| LiveLocationCommand {
+            message_id,
+            server_message_id: 9101,
+            channel_id: 780,
+            channel_type: 2,
+            position: latitude.map(|latitude| LiveLocationPosition {
+                latitude,
+                longitude: 121.5,
+                at: 1_000,
+                ..Default::default()
+            }),
+            stop: latitude.is_none(),
+        };
+        let state = || {
+            store
+                .get_message_by_id(uid, message_id)
+                .expect("get message")
+                .expect("message exists")
+                .live_location
+                .expect("live location state")
+        };
 
+        // 第一次更新：从没发出过，立即可发。
+        let next_at = store
+            .live_location_record(uid, "live_location:1", &command(Some(31.0)))
+            .expect("first update");
+        assert_eq!(next_at, 0);
+        let queued = store
+            .outbox_peek_rpc(uid, 10, i64::MAX)
+            .expect("peek rpc outbox");
+        assert_eq!(queued.len(), 1);
         store
-            .set_message_revoke(uid, message_id, true, Some(30001))
-            .expect("set revoke");
-        let revoked_row = store
-            .get_message_by_id(uid, message_id)
-            .expect("get revoked message")
-            .expect("message exists");
-        assert_eq!(revoked_row.message_type, 0);
-        assert_eq!(revoked_row.content, "消息已撤回");
-        assert!(revoked_row.revoked);
-        assert_eq!(revoked_row.revoked_by, Some(30001));
+            .live_location_settled(
+                uid,
+                queued[0].id,
+                &command(Some(31.0)),
+                LiveLocationSettle::Accepted,
+                None,
+            )
+            .expect("settle first update");
+        assert!(!state().update_pending);
+
+        // 单次更新被拒：只丢这一次，共享还在，之后的更新照常入队。
         store
-            .edit_message(uid, message_id, "after-edit", 123)
-            .expect("edit message");
+            .live_location_record(uid, "live_location:1b", &command(Some(31.05)))
+            .expect("update that will be rejected");
+        let queued = store
+            .outbox_peek_rpc(uid, 10, i64::MAX)
+            .expect("peek rpc outbox");
         store
-            .set_message_pinned(uid, message_id, true)
-            .expect("set pinned");
+            .live_location_settled(
+                uid,
+                queued[0].id,
+                &command(Some(31.05)),
+                LiveLocationSettle::Dropped,
+                None,
+            )
+            .expect("settle rejected update");
+        assert!(!state().stopped);
+        assert!(!state().update_pending);
 
-        let row = store
-            .get_message_by_id(uid, message_id)
-            .expect("get message")
+        // 紧接着的两次更新：推迟到节流窗口之后，只留最新一次。
+        let next_at = store
+            .live_location_record(uid, "live_location:2", &command(Some(31.1)))
+            .expect("throttled update");
+        assert!(next_at > chrono::Utc::now().timestamp_millis());
+        store
+            .live_location_record(uid, "live_location:3", &command(Some(31.2)))
+            .expect("newer throttled update");
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        assert!(store
+            .outbox_peek_rpc(uid, 10, now_ms)
+            .expect("peek rpc outbox")
+            .is_empty());
+        let queued = store
+            .outbox_peek_rpc(uid, 10, i64::MAX)
+            .expect("peek rpc outbox");
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].command_id, "live_location:3");
+        assert_eq!(state().position.map(|p| p.latitude), Some(31.2));
+        assert!(state().update_pending);
+
+        // 结束不节流，并替换排着的更新。
+        let next_at = store
+            .live_location_record(uid, "live_location:4", &command(None))
+            .expect("stop");
+        assert_eq!(next_at, 0);
+        let queued = store
+            .outbox_peek_rpc(uid, 10, now_ms)
+            .expect("peek rpc outbox");
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].command_id, "live_location:4");
+        assert!(state().stopped);
+        assert!(store
+            .live_location_record(uid, "live_location:5", &command(Some(31.3)))
+            .is_err());
+
+        // 服务端的位置：旧版本丢弃，结束不会被撤回。
+        let update = |version: u64, stopped: bool| LiveLocationUpdate {
+            position: Some(LiveLocationPosition {
+                latitude: 40.0,
+                longitude: 116.4,
+                at: 2_000,
+                ..Default::default()
+            }),
+            stopped,
+            version,
+            ..Default::default()
+        };
+        store
+            .live_location_settled(
+                uid,
+                queued[0].id,
+                &command(None),
+                LiveLocationSettle::Accepted,
+                None,
+            )
+            .expect("settle stop");
+        assert!(store
+            .apply_live_location_update(uid, message_id, &update(3, false))
+            .expect("apply v3"));
+        assert!(state().stopped);
+        assert_eq!(state().position.map(|p| p.latitude), Some(40.0));
+        assert!(!store
+            .apply_live_location_update(uid, message_id, &update(2, false))
+            .expect("apply stale v2"));
+        assert_eq!(state().version, 3);
+    }
+
+    /// 群「谁看过」由成员游标本地算：游标只进不退，发送者不算读者，没有游标的成员单列出来待回填。
+    #[test]
+    fn group_read_receipts_come_from_member_cursors() {
+        let store = test_store();
+        let uid = "900";
+        for user_id in [900u64, 901, 902, 903] {
+            store
+                .upsert_group_member(
+                    uid,
+                    &crate::UpsertGroupMemberInput {
+                        group_id: 55,
+                        user_id,
+                        role: 0,
+                        status: 0,
+                        alias: None,
+                        is_muted: false,
+                        joined_at: 1,
+                        version: 1,
+                        updated_at: 1,
+                    },
+                )
+                .expect("upsert group member");
+        }
+        let message_id = store
+            .upsert_remote_message_with_result(
+                uid,
+                &UpsertRemoteMessageInput {
+                    server_message_id: 5501,
+                    local_message_id: 0,
+                    channel_id: 55,
+                    channel_type: 2,
+                    timestamp: 1_700_000_000_000,
+                    timestamp_precision: crate::canonical_inbound::TimePrecision::Milliseconds,
+                    from_uid: 901,
+                    message_type: 0,
+                    content: "hi".to_string(),
+                    status: 2,
+                    pts: 7,
+                    setting: 0,
+                    order_seq: 7,
+                    searchable_word: String::new(),
+                    extra: "{}".to_string(),
+                    mime_type: None,
+                    revoked: false,
+                },
+            )
+            .expect("insert group message")
+            .message_id;
+
+        assert!(store.save_member_read_pts(uid, 55, 2, 902, 9).unwrap());
+        // 旧游标晚到不回退。
+        assert!(!store.save_member_read_pts(uid, 55, 2, 902, 3).unwrap());
+        // 发送者的游标不让他变成「读者」。
+        assert!(store.save_member_read_pts(uid, 55, 2, 901, 7).unwrap());
+
+        let receipts = store
+            .get_message_read_receipts(uid, message_id)
+            .expect("read receipts")
             .expect("message exists");
-        assert_eq!(row.content, "after-edit");
+        assert_eq!(receipts.pts, 7);
+        let readers: Vec<u64> = receipts.readers.iter().map(|r| r.reader_id).collect();
+        assert_eq!(readers, vec![902]);
+        // 903 没有游标；900 是自己，901 是发送者，都不需要回填。
+        assert_eq!(receipts.unknown_member_ids, vec![903]);
+
+        store.save_member_read_pts(uid, 55, 2, 903, 6).unwrap();
+        let receipts = store
+            .get_message_read_receipts(uid, message_id)
+            .expect("read receipts")
+            .expect("message exists");
+        assert_eq!(receipts.readers.len(), 1);
+        assert!(receipts.unknown_member_ids.is_empty());
 
-        let extra = store
-            .get_message_extra(uid, message_id)
-            .expect("get message extra")
-            .expect("message extra exists");
-        assert!(extra.revoke);
-        assert_eq!(extra.revoker, Some(30001));
-        assert_eq!(extra.content_edit.as_deref(), Some("after-edit"));
-        assert_eq!(extra.edited_at, 123);
-        assert!(extra.is_pinned);
+        // 不带会话也能按 server_message_id 直接定位。
+        assert_eq!(
+            store
+                .find_message_id_by_server_message_id(uid, 5501)
+                .expect("find by server id"),
+            Some(message_id)
+        );
+        // 删会话连成员游标一起清掉。
+        store.delete_channel_local(uid, 55).expect("delete channel");
+        assert!(store
+            .list_member_read_cursors(uid, 55, 2)
+            .expect("list cursors")
+            .is_empty());
     }
 
     /// P1-17 顺手根治：空名群（本地 upsert、group.name 为 NULL、无成员行）
@@ -0,0 +1,374 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 按账号的内容寻址 blob 库。
+//!
+//! 同一张图被转发进十个会话，以前就在十个消息目录里各下载、各存一份。现在明文按
+//! SHA-256 落进 blob 库，消息目录里的 `payload.*` 是指向它的**硬链接**：
+//!
+//! ```text
+//! {user_root}/blobs/sha256/{hh}/{sha}               明文
+//! {user_root}/blobs/sha256/{hh}/{sha}.sealed(.json) 这份明文的封装缓存（格式同 body.sealed）
+//! {user_root}/blobs/file_id/{file_id}               内容是 sha，下载前按 file_id 查
+//! ```
+//!
+//! 引用计数就是文件系统的链接数：库里一份 + 每个消息目录一份。删消息只删消息目录，
+//! [`collect_garbage`] 把链接数回到 1（只剩库里自己）的 blob 连同封装缓存、file_id
+//! 索引一起清掉。整号擦除直接删 `user_root`，库在它下面，不用单独处理。
+//!
+//! 🔴 消息目录里的正文是共享的 inode：任何地方都**不能原地写**它，要写先 unlink。
+//! 原地写一个硬链接等于同时改了所有转发过它的消息。
+//!
+//! 全部 best-effort：库里的东西丢了、坏了，最多退回照常下载 / 照常封装。
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use sha2::{Digest, Sha256};
+
+fn store_root(user_root: &Path) -> PathBuf {
+    user_root.join("blobs")
+}
+
+fn blob_path(user_root: &Path, sha256_hex: &str) -> PathBuf {
+    store_root(user_root)
+        .join("sha256")
+        .join(&sha256_hex[..2])
+        .join(sha256_hex)
+}
+
+fn file_id_path(user_root: &Path, file_id: u64) -> PathBuf {
+    store_root(user_root)
+        .join("file_id")
+        .join(file_id.to_string())
+}
+
+/// blob 对应的封装缓存：`{sha}.sealed` + `{sha}.sealed.json`，和消息目录里的
+/// `body.sealed` 同一套格式，`seal_once` 直接认。
+fn sealed_path(blob: &Path) -> PathBuf {
+    blob.with_extension("sealed")
+}
+
+fn is_sha256_hex(s: &str) -> bool {
+    s.len() == 64
+        && s.bytes()
+            .all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase())
+}
+
+/// 封装缓存算数的条件与读侧一致：密文和提交标记都在。
+fn sealed_committed(cache: &Path) -> bool {
+    cache.is_file() && cache.with_extension("sealed.json").is_file()
+}
+
+fn same_inode(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+/// 流式算文件的 SHA-256，不把整份读进内存。
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// 把 `dest` 换成 `src` 的硬链接：先链到临时名再改名盖过去，中途崩了 `dest` 仍是完整的。
+fn replace_with_link(src: &Path, dest: &Path) -> io::Result<()> {
+    let mut tmp_name = dest.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".blob.tmp");
+    let tmp = dest.with_file_name(tmp_name);
+    let _ = fs::remove_file(&tmp);
+    fs::hard_link(src, &tmp)?;
+    fs::rename(&tmp, dest).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+/// 把一封装缓存对（密文 + `.sealed.json`）链过去。标记最后链，与写缓存同序。
+fn link_sealed_pair(src: &Path, dest: &Path) -> bool {
+    let dest_meta = dest.with_extension("sealed.json");
+    let _ = fs::remove_file(&dest_meta);
+    if replace_with_link(src, dest).is_err() {
+        return false;
+    }
+    replace_with_link(&src.with_extension("sealed.json"), &dest_meta).is_ok()
+}
+
+/// 把消息目录里刚落好的明文 `file` 收进库，返回它的 sha。
+///
+/// - 库里还没有这份内容：`file` 链进库。
+/// - 已经有了：`file` 换成库里那份的硬链接，自己那份字节随之释放。
+/// - `file_id` 给了就登记索引，之后同一个 file_id 的下载直接链，不走网络。
+/// - `sealed_cache` 是这份明文已提交的封装缓存（`body.sealed`）；库里还没有时链一份进库，
+///   之后从别的路径拿到同样的明文再发送，也能复用这份密文秒传。
+pub fn adopt(
+    user_root: &Path,
+    file: &Path,
+    file_id: Option<u64>,
+    sealed_cache: Option<&Path>,
+) -> io::Result<String> {
+    let sha = hash_file(file)?;
+    let blob = blob_path(user_root, &sha);
+    if let Some(parent) = blob.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file_meta = fs::metadata(file)?;
+    match fs::metadata(&blob) {
+        Ok(blob_meta) if same_inode(&file_meta, &blob_meta) => {}
+        // 长度都对不上说明库里那份坏了（内容寻址，不该发生）：以刚落好的这份为准。
+        Ok(blob_meta) if blob_meta.len() != file_meta.len() => replace_with_link(file, &blob)?,
+        Ok(_) => replace_with_link(&blob, file)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => fs::hard_link(file, &blob)?,
+        Err(e) => return Err(e),
+    }
+
+    if let Some(file_id) = file_id {
+        let index = file_id_path(user_root, file_id);
+        if let Some(parent) = index.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = index.with_extension("tmp");
+        fs::write(&tmp, &sha).and_then(|_| fs::rename(&tmp, &index))?;
+    }
+
+    if let Some(cache) = sealed_cache.filter(|c| sealed_committed(c)) {
+        let store_sealed = sealed_path(&blob);
+        if !sealed_committed(&store_sealed) {
+            link_sealed_pair(cache, &store_sealed);
+        }
+    }
+    Ok(sha)
+}
+
+/// 库里有 `file_id` 对应的内容时，把它硬链到 `dest`（一并把库里的封装缓存链成 `dest`
+/// 旁边的 `body.sealed`，转发照样能秒传），返回 true。没有就返回 false，调用方照常下载。
+pub fn link_by_file_id(user_root: &Path, file_id: u64, dest: &Path) -> bool {
+    let Ok(sha) = fs::read_to_string(file_id_path(user_root, file_id)) else {
+        return false;
+    };
+    let sha = sha.trim();
+    if !is_sha256_hex(sha) {
+        return false;
+    }
+    let blob = blob_path(user_root, sha);
+    let Some(dir) = dest.parent() else {
+        return false;
+    };
+    if !blob.is_file() || fs::create_dir_all(dir).is_err() {
+        return false;
+    }
+    if replace_with_link(&blob, dest).is_err() {
+        return false;
+    }
+    let cache = dir.join(crate::media_download::sealed_cache_name(dest));
+    if !sealed_committed(&cache) {
+        link_sealed_for(user_root, sha, &cache);
+    }
+    true
+}
+
+/// 发送侧：明文是 `sha256_hex` 的内容库里有已提交的封装缓存时，链到 `dest_cache`
+/// （`body.sealed` 那个位置），返回 true。之后 `seal_once` 会照常校验再复用。
+pub fn link_sealed_for(user_root: &Path, sha256_hex: &str, dest_cache: &Path) -> bool {
+    if !is_sha256_hex(sha256_hex) {
+        return false;
+    }
+    let store_sealed = sealed_path(&blob_path(user_root, sha256_hex));
+    sealed_committed(&store_sealed) && link_sealed_pair(&store_sealed, dest_cache)
+}
+
+/// 回收：链接数回到 1 的 blob（已经没有消息目录引用它）连同封装缓存删掉；库里的封装
+/// 缓存超过 `sealed_max_age` 也删（与消息目录里的 `prune_sealed_caches` 同一个窗口）；
+/// 指向已删 blob 的 file_id 索引一并清掉。返回删掉的 blob 数。
+pub fn collect_garbage(user_root: &Path, sealed_max_age: Duration) -> usize {
+    let store = store_root(user_root);
+    let now = SystemTime::now();
+    let mut removed = 0usize;
+    if let Ok(shards) = fs::read_dir(store.join("sha256")) {
+        for shard in shards.flatten() {
+            let Ok(entries) = fs::read_dir(shard.path()) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let name = entry.file_name();
+                let Some(name) = name.to_str() else {
+                    continue;
+                };
+                if is_sha256_hex(name) {
+                    if fs::metadata(&path).is_ok_and(|m| m.nlink() <= 1) {
+                        let sealed = sealed_path(&path);
+                        let _ = fs::remove_file(sealed.with_extension("sealed.json"));
+                        let _ = fs::remove_file(&sealed);
+                        let _ = fs::remove_file(&path);
+                        removed += 1;
+                    }
+                    continue;
+                }
+                let Some(sha) = name.strip_suffix(".sealed") else {
+                    // `.sealed.json` 跟着 `.sealed` 走；改名中途留下的 `.blob.tmp` 直接清。
+                    if name.ends_with(".tmp") {
+                        let _ = fs::remove_file(&path);
+                    }
+                    continue;
+                };
+                let orphaned = !shard.path().join(sha).exists();
+                let expired = entry
+                    .metadata()
+                    .ok()
+                    .and_then(|m| m.modified().ok())
+                    .and_then(|t| now.duration_since(t).ok())
+                    .is_some_and(|age| age > sealed_max_age);
+                if orphaned || expired {
+                    let _ = fs::remove_file(path.with_extension("sealed.json"));
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+    }
+    if let Ok(entries) = fs::read_dir(store.join("file_id")) {
+        for entry in entries.flatten() {
+            let live = fs::read_to_string(entry.path()).is_ok_and(|sha| {
+                let sha = sha.trim();
+                is_sha256_hex(sha) && blob_path(user_root, sha).is_file()
+            });
+            if !live {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tmp_root() -> PathBuf {
+        static SEQ: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "privchat-blob-{}-{}-{}",
+            std::process::id(),
+            chrono::Utc::now().timestamp_micros(),
+            SEQ.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).expect("create root");
+        dir
+    }
+
+    fn message_file(root: &Path, message_id: i64, bytes: &[u8]) -> PathBuf {
+        let dir = root
+            .join("files")
+            .join("202610")
+            .join(message_id.to_string());
+        fs::create_dir_all(&dir).expect("message dir");
+        let file = dir.join("payload.png");
+        fs::write(&file, bytes).expect("payload");
+        file
+    }
+
+    fn commit_sealed(cache: &Path, blob: &[u8]) {
+        fs::write(cache, blob).expect("sealed");
+        fs::write(cache.with_extension("sealed.json"), b"{}").expect("sealed meta");
+    }
+
+    #[test]
+    fn the_same_content_in_two_messages_is_stored_once() {
+        let root = tmp_root();
+        let a = message_file(&root, 1, b"forwarded sticker");
+        let b = message_file(&root, 2, b"forwarded sticker");
+        let sha = adopt(&root, &a, Some(11), None).expect("adopt a");
+        assert_eq!(adopt(&root, &b, Some(12), None).expect("adopt b"), sha);
+
+        let (ma, mb) = (fs::metadata(&a).unwrap(), fs::metadata(&b).unwrap());
+        assert!(same_inode(&ma, &mb), "两条消息指向同一份字节");
+        assert_eq!(ma.nlink(), 3, "库里一份 + 两个消息目录");
+        assert_eq!(fs::read(&b).unwrap(), b"forwarded sticker");
+    }
+
+    #[test]
+    fn a_known_file_id_is_linked_instead_of_downloaded() {
+        let root = tmp_root();
+        let a = message_file(&root, 1, b"photo");
+        let cache = a.with_file_name("body.sealed");
+        commit_sealed(&cache, b"ciphertext");
+        adopt(&root, &a, Some(77), Some(&cache)).expect("adopt");
+
+        let dest = root
+            .join("files")
+            .join("202610")
+            .join("3")
+            .join("payload.png");
+        assert!(
+            !link_by_file_id(&root, 78, &dest),
+            "没见过的 file_id 照常下载"
+        );
+        assert!(link_by_file_id(&root, 77, &dest));
+        assert_eq!(fs::read(&dest).unwrap(), b"photo");
+        assert_eq!(
+            fs::read(dest.with_file_name("body.sealed")).unwrap(),
+            b"ciphertext",
+            "转发新消息也能直接拿密文秒传"
+        );
+    }
+
+    #[test]
+    fn resending_the_same_plaintext_finds_its_sealed_cache() {
+        let root = tmp_root();
+        let a = message_file(&root, 1, b"video bytes");
+        let cache = a.with_file_name("body.sealed");
+        commit_sealed(&cache, b"sealed video");
+        let sha = adopt(&root, &a, None, Some(&cache)).expect("adopt");
+
+        let dest = root.join("outbound-body.sealed");
+        assert!(link_sealed_for(&root, &sha, &dest));
+        assert_eq!(fs::read(&dest).unwrap(), b"sealed video");
+        assert!(dest.with_extension("sealed.json").is_file());
+        assert!(!link_sealed_for(
+            &root,
+            &"0".repeat(64),
+            &dest.with_file_name("x.sealed")
+        ));
+    }
+
+    #[test]
+    fn a_blob_is_collected_once_no_message_references_it() {
+        let root = tmp_root();
+        let a = message_file(&root, 1, b"shared");
+        let b = message_file(&root, 2, b"shared");
+        let cache = a.with_file_name("body.sealed");
+        commit_sealed(&cache, b"sealed");
+        let sha = adopt(&root, &a, Some(5), Some(&cache)).expect("adopt a");
+        adopt(&root, &b, Some(6), None).expect("adopt b");
+        let blob = blob_path(&root, &sha);
+
+        fs::remove_dir_all(a.parent().unwrap()).unwrap();
+        assert_eq!(collect_garbage(&root, Duration::from_secs(3600)), 0);
+        assert!(blob.is_file(), "还有一条消息在用");
+
+        fs::remove_dir_all(b.parent().unwrap()).unwrap();
+        assert_eq!(collect_garbage(&root, Duration::from_secs(3600)), 1);
+        assert!(!blob.exists());
+        assert!(!sealed_path(&blob).exists());
+        assert!(!file_id_path(&root, 5).exists());
+        assert!(!file_id_path(&root, 6).exists());
+    }
+
+    #[test]
+    fn expired_store_sealed_caches_are_dropped_but_the_blob_stays() {
+        let root = tmp_root();
+        let a = message_file(&root, 1, b"kept");
+        let cache = a.with_file_name("body.sealed");
+        commit_sealed(&cache, b"sealed");
+        let sha = adopt(&root, &a, None, Some(&cache)).expect("adopt");
+
+        assert_eq!(collect_garbage(&root, Duration::ZERO), 0);
+        let blob = blob_path(&root, &sha);
+        assert!(blob.is_file());
+        assert!(!sealed_committed(&sealed_path(&blob)));
+    }
+}
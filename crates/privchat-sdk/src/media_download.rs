@@ -12,7 +12,7 @@ use std::collections::HashMap;
 use std::fs::{self, OpenOptions};
 use std::future::Future;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
@@ -21,7 +21,8 @@ use reqwest::StatusCode;
 use tokio::sync::{oneshot, Notify, Semaphore};
 use tokio::task::JoinHandle;
 
-use crate::{MediaDownloadState, PrivchatSdk, ResolvedFileDownload, SdkEvent};
+use crate::bandwidth::{BandwidthLimiter, TransferDirection};
+use crate::{metrics, MediaDownloadState, PrivchatSdk, ResolvedFileDownload, SdkEvent};
 use privchat_protocol::ErrorCode;
 
 /// Progress events are throttled to this interval.
@@ -38,6 +39,8 @@ const MAX_BACKGROUND_TRACKED_DOWNLOADS: usize = 480;
 pub(crate) enum MediaKind {
     Payload,
     Thumbnail,
+    /// One sticker pack's assets; `message_id` is [`crate::sticker`]'s pack task id.
+    StickerAssets,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -77,6 +80,15 @@ impl MediaTaskKey {
             media_kind: MediaKind::Thumbnail,
         }
     }
+
+    pub(crate) fn sticker_assets(owner_uid: String, session_epoch: u64, package_id: &str) -> Self {
+        Self {
+            owner_uid,
+            session_epoch,
+            message_id: crate::sticker::prefetch_task_id(package_id),
+            media_kind: MediaKind::StickerAssets,
+        }
+    }
 }
 
 /// Account/session-scoped receiver media coordinator.
@@ -90,6 +102,8 @@ pub struct DownloadManager {
 
 struct ManagerInner {
     entries: Mutex<HashMap<MediaTaskKey, HandleEntry>>,
+    /// 交给系统后台传输的下载，按 transfer id 存。见 [`DownloadManager::hand_off`]。
+    handed_off: Mutex<HashMap<String, HandedOffDownload>>,
     active_slots: Arc<Semaphore>,
     background_slots: Arc<Semaphore>,
     next_task_id: AtomicU64,
@@ -102,6 +116,94 @@ struct HandleEntry {
     cancelled: Arc<AtomicBool>,
     pause_notify: Arc<Notify>,
     task: JoinHandle<()>,
+    /// `start_with_ticket` 起的前台下载才有；`submit` 的 job 不可交接。
+    source: Option<DownloadSource>,
+}
+
+impl HandleEntry {
+    fn user_initiated(&self) -> bool {
+        self.source.is_some()
+    }
+}
+
+/// What a payload download needs to be picked up again by someone else.
+#[derive(Debug, Clone)]
+struct DownloadSource {
+    ticket: ResolvedFileDownload,
+    target_dir: PathBuf,
+    payload_filename: String,
+}
+
+/// A payload download that was stopped in-process and handed to the platform's
+/// background-transfer service (iOS `URLSession` background session, Android
+/// `WorkManager`…). The `.part` stays where it is; the platform appends the rest
+/// of the object to it and then calls back through
+/// `PrivchatSdk::import_completed_transfer`.
+#[derive(Debug, Clone)]
+pub(crate) struct HandedOffDownload {
+    pub key: MediaTaskKey,
+    source: DownloadSource,
+}
+
+impl HandedOffDownload {
+    pub(crate) fn transfer_id(&self) -> String {
+        crate::background_transfer::download_transfer_id(self.key.message_id)
+    }
+
+    fn part_path(&self) -> PathBuf {
+        self.source
+            .target_dir
+            .join(format!("{}.part", self.source.payload_filename))
+    }
+
+    pub(crate) fn final_path(&self) -> PathBuf {
+        self.source.target_dir.join(&self.source.payload_filename)
+    }
+
+    /// 交给平台的描述：从 `.part` 现有长度续拉到对象结尾，追加进 `.part`。
+    ///
+    /// `.part` 比对象还长的（服务端换了对象）丢掉从 0 开始；正好一样长的给出空区间，
+    /// 平台什么都不用拉，直接导入即可。
+    pub(crate) fn descriptor(&self) -> crate::background_transfer::BackgroundTransfer {
+        let ticket = &self.source.ticket;
+        let part_path = self.part_path();
+        let mut range_start = fs::metadata(&part_path).map(|m| m.len()).unwrap_or(0);
+        if ticket
+            .expected_size
+            .is_some_and(|expected| range_start > expected)
+        {
+            let _ = fs::remove_file(&part_path);
+            range_start = 0;
+        }
+        let mut headers = Vec::new();
+        if range_start > 0 {
+            headers.push(("Range".to_string(), format!("bytes={range_start}-")));
+        }
+        crate::background_transfer::BackgroundTransfer {
+            transfer_id: self.transfer_id(),
+            direction: TransferDirection::Download,
+            message_id: self.key.message_id,
+            method: "GET".to_string(),
+            url: ticket.url.clone(),
+            headers,
+            range_start,
+            range_end: ticket.expected_size,
+            path: part_path,
+            expected_size: ticket.expected_size,
+            expected_sha256: ticket.expected_sha256.clone(),
+        }
+    }
+
+    /// 平台拉完之后：校验整份 `.part` 并解密成成品，与进程内下载完成走同一个
+    /// [`finalize_part`]。校验不过的 `.part` 已被删掉，重新发起下载会从头拉。
+    pub(crate) fn complete(&self) -> Result<PathBuf, FinalizeError> {
+        let final_path = self.final_path();
+        if final_path.exists() {
+            return Ok(final_path);
+        }
+        finalize_part(&self.source.ticket, &self.part_path(), &final_path)?;
+        Ok(final_path)
+    }
 }
 
 /// Remove a tracked task regardless of how its future exits. The task id
@@ -123,6 +225,7 @@ impl DownloadManager {
         Self {
             inner: Arc::new(ManagerInner {
                 entries: Mutex::new(HashMap::new()),
+                handed_off: Mutex::new(HashMap::new()),
                 active_slots: Arc::new(Semaphore::new(MAX_ACTIVE_DOWNLOADS)),
                 background_slots: Arc::new(Semaphore::new(MAX_BACKGROUND_DOWNLOADS)),
                 next_task_id: AtomicU64::new(1),
@@ -200,6 +303,7 @@ impl DownloadManager {
                 cancelled: Arc::new(AtomicBool::new(false)),
                 pause_notify: Arc::new(Notify::new()),
                 task,
+                source: None,
             },
         );
         drop(entries);
@@ -218,6 +322,11 @@ impl DownloadManager {
                 .expect("download manager poisoned");
             guard.drain().map(|(_, entry)| entry).collect::<Vec<_>>()
         };
+        self.inner
+            .handed_off
+            .lock()
+            .expect("download manager poisoned")
+            .clear();
         for entry in entries {
             entry.cancelled.store(true, Ordering::Release);
             entry.pause_notify.notify_waiters();
@@ -225,6 +334,144 @@ impl DownloadManager {
         }
     }
 
+    /// Whether `key` currently has a queued or running task.
+    pub(crate) fn is_tracked(&self, key: &MediaTaskKey) -> bool {
+        self.inner
+            .entries
+            .lock()
+            .expect("download manager poisoned")
+            .contains_key(key)
+    }
+
+    /// Whether `key` is a download the user started (rather than background work
+    /// submitted on their behalf).
+    pub(crate) fn is_user_initiated(&self, key: &MediaTaskKey) -> bool {
+        self.inner
+            .entries
+            .lock()
+            .expect("download manager poisoned")
+            .get(key)
+            .is_some_and(HandleEntry::user_initiated)
+    }
+
+    /// Drop one background task silently: no event, `.part` left for a later
+    /// resume. Used to park background work the current network no longer
+    /// allows; unlike [`cancel`](Self::cancel), the UI never asked for it, so
+    /// there is no state to report. A download the user started under the same
+    /// key is left alone.
+    pub(crate) fn abort_background(&self, key: &MediaTaskKey) -> bool {
+        let entry = {
+            let mut guard = self
+                .inner
+                .entries
+                .lock()
+                .expect("download manager poisoned");
+            if guard.get(key).is_some_and(HandleEntry::user_initiated) {
+                return false;
+            }
+            guard.remove(key)
+        };
+        let Some(entry) = entry else {
+            return false;
+        };
+        entry.cancelled.store(true, Ordering::Release);
+        entry.pause_notify.notify_waiters();
+        entry.task.abort();
+        true
+    }
+
+    /// 停掉进程内的全部前台正文下载，交给平台的后台传输服务。
+    ///
+    /// 任务先中止并**等它真正退出**再返回——之后平台往同一个 `.part` 追加，不能
+    /// 还有一个进程内的写者。返回当前所有已交接的下载（含之前交接过、还没导入的），
+    /// 所以重复调用是幂等的。缩略图等 `submit` 的 job 不交接：它们很小，回到前台
+    /// 重新跑就是了。
+    pub(crate) async fn hand_off(&self) -> Vec<HandedOffDownload> {
+        let entries = {
+            let mut guard = self
+                .inner
+                .entries
+                .lock()
+                .expect("download manager poisoned");
+            let keys = guard
+                .iter()
+                .filter(|(_, entry)| entry.source.is_some())
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>();
+            keys.into_iter()
+                .filter_map(|key| guard.remove(&key).map(|entry| (key, entry)))
+                .collect::<Vec<_>>()
+        };
+        let mut moved = Vec::with_capacity(entries.len());
+        for (key, entry) in entries {
+            entry.cancelled.store(true, Ordering::Release);
+            entry.pause_notify.notify_waiters();
+            entry.task.abort();
+            let _ = entry.task.await;
+            if let Some(source) = entry.source {
+                moved.push(HandedOffDownload { key, source });
+            }
+        }
+        let mut handed_off = self
+            .inner
+            .handed_off
+            .lock()
+            .expect("download manager poisoned");
+        for download in moved {
+            handed_off.insert(download.transfer_id(), download);
+        }
+        handed_off.values().cloned().collect()
+    }
+
+    /// 取走一个已交接的下载（导入时用）。没有这个 id 返回 `None`。
+    pub(crate) fn take_handed_off(&self, transfer_id: &str) -> Option<HandedOffDownload> {
+        self.inner
+            .handed_off
+            .lock()
+            .expect("download manager poisoned")
+            .remove(transfer_id)
+    }
+
+    /// 回到前台：已交接、还没导入的下载收回来，在进程内从 `.part` 续传。
+    /// 平台已经拉满但没来得及导入的，`run_download` 会直接校验收尾。
+    pub(crate) async fn reclaim_handed_off(&self, sdk: &PrivchatSdk) -> usize {
+        let downloads = self
+            .inner
+            .handed_off
+            .lock()
+            .expect("download manager poisoned")
+            .drain()
+            .map(|(_, download)| download)
+            .collect::<Vec<_>>();
+        let mut restarted = 0;
+        for download in downloads {
+            let message_id = download.key.message_id;
+            let DownloadSource {
+                ticket,
+                target_dir,
+                payload_filename,
+            } = download.source;
+            match self
+                .start_with_ticket(
+                    sdk.clone(),
+                    download.key,
+                    ticket,
+                    target_dir,
+                    payload_filename,
+                )
+                .await
+            {
+                Ok(()) => restarted += 1,
+                Err(e) => {
+                    eprintln!(
+                        "[SDK.media] reclaim handed-off download message_id={message_id}: {e}"
+                    )
+                }
+            }
+        }
+        restarted
+    }
+
     #[cfg(test)]
     pub(crate) fn tracked_count(&self) -> usize {
         self.inner
@@ -257,7 +504,8 @@ impl DownloadManager {
     }
 
     /// Start (or no-op restart if already Downloading/Paused) a download from a
-    /// resolved ticket (`url` + `encryption_version` + optional `cek`).
+    /// resolved ticket (`url` + `encryption_version` + optional `cek`). Background
+    /// work queued under the same key (an auto-download) is replaced by this one.
     /// - `target_dir` must already exist.
     /// - `payload_filename` is `payload.<ext>`.
     /// - On completion, a v1 ticket's `.part` blob is AES-GCM decrypted before
@@ -270,6 +518,26 @@ impl DownloadManager {
         target_dir: PathBuf,
         payload_filename: String,
     ) -> Result<(), String> {
+        // 同一条消息正挂着后台 job（自动下载）：用户点了就以这次为准。先让后台 job
+        // 真正退出再起前台任务——两边写的是同一个 `.part`，前台从它停下的地方接着拉。
+        let superseded = {
+            let mut guard = self
+                .inner
+                .entries
+                .lock()
+                .expect("download manager poisoned");
+            match guard.get(&key).map(HandleEntry::user_initiated) {
+                Some(true) => return Ok(()),
+                Some(false) => guard.remove(&key),
+                None => None,
+            }
+        };
+        if let Some(entry) = superseded {
+            entry.cancelled.store(true, Ordering::Release);
+            entry.pause_notify.notify_waiters();
+            entry.task.abort();
+            let _ = entry.task.await;
+        }
         let mut guard = self
             .inner
             .entries
@@ -281,6 +549,19 @@ impl DownloadManager {
         if guard.len() >= MAX_TRACKED_DOWNLOADS {
             return Err("receiver download queue is full".to_string());
         }
+        // 交给过平台又在前台重新发起：以这次为准，平台那边的结果不再导入。
+        self.inner
+            .handed_off
+            .lock()
+            .expect("download manager poisoned")
+            .remove(&crate::background_transfer::download_transfer_id(
+                key.message_id,
+            ));
+        let source = DownloadSource {
+            ticket: ticket.clone(),
+            target_dir: target_dir.clone(),
+            payload_filename: payload_filename.clone(),
+        };
 
         let paused = Arc::new(AtomicBool::new(false));
         let cancelled = Arc::new(AtomicBool::new(false));
@@ -340,6 +621,7 @@ impl DownloadManager {
                 cancelled,
                 pause_notify,
                 task,
+                source: Some(source),
             },
         );
         drop(guard);
@@ -446,12 +728,13 @@ async fn run_download(
     pause_notify: Arc<Notify>,
 ) {
     let message_id = key.message_id;
-    let download_url = ticket.url;
+    let download_url = ticket.url.clone();
     let final_path = target_dir.join(&payload_filename);
     let part_path = target_dir.join(format!("{payload_filename}.part"));
 
     // If the final file already exists, short-circuit success (caller may race).
-    if final_path.exists() {
+    // 同一个 file_id 已在别的消息里下载过的，从 blob 库链过来，同样算完成。
+    if final_path.exists() || link_from_blob_store(&ticket, &final_path) {
         let path_str = final_path.to_string_lossy().to_string();
         emit(
             &sdk,
@@ -463,139 +746,134 @@ async fn run_download(
         return;
     }
 
-    // Resume from .part if present.
-    let start_offset = fs::metadata(&part_path).map(|m| m.len()).unwrap_or(0);
-
-    // Issue the request.
-    let client = reqwest::Client::new();
-    let mut builder = client.get(&download_url);
-    if start_offset > 0 {
-        builder = builder.header("Range", format!("bytes={start_offset}-"));
+    // 平台后台传输已经把 `.part` 拉满、进程却在导入前被杀了：直接校验收尾，不再请求。
+    // 校验不过的 `.part` 已被删掉，照常从头下。
+    if part_is_complete(&ticket, &part_path) {
+        match finalize_part(&ticket, &part_path, &final_path) {
+            Ok(()) => {
+                finish(&sdk, &manager, &key, &final_path).await;
+                return;
+            }
+            Err(FinalizeError::Integrity(e)) => {
+                eprintln!("[SDK.media] complete part rejected message_id={message_id}: {e}");
+            }
+            Err(FinalizeError::Io(e)) => {
+                fail(&sdk, &manager, &key, ErrorCode::InternalError as u32, e).await;
+                return;
+            }
+        }
     }
-    let resp = match builder.send().await {
-        Ok(r) => r,
-        Err(e) => {
+
+    let mut integrity_retried = false;
+    loop {
+        // Resume from .part if present.
+        let start_offset = resume_offset(&ticket, &part_path);
+
+        // Issue the request.
+        let client = reqwest::Client::new();
+        let mut builder = client.get(&download_url);
+        if start_offset > 0 {
+            builder = builder.header("Range", format!("bytes={start_offset}-"));
+        }
+        let resp = match builder.send().await {
+            Ok(r) => r,
+            Err(e) => {
+                fail(
+                    &sdk,
+                    &manager,
+                    &key,
+                    ErrorCode::NetworkError as u32,
+                    format!("send: {e}"),
+                )
+                .await;
+                return;
+            }
+        };
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let body = resp.text().await.unwrap_or_default();
             fail(
                 &sdk,
                 &manager,
                 &key,
                 ErrorCode::NetworkError as u32,
-                format!("send: {e}"),
+                format!("status={status} body={body}"),
             )
             .await;
             return;
         }
-    };
-    if !resp.status().is_success() {
-        let status = resp.status();
-        let body = resp.text().await.unwrap_or_default();
-        fail(
+
+        let got_range = resp.status() == StatusCode::PARTIAL_CONTENT;
+        let mut offset = if got_range { start_offset } else { 0 };
+        // content_length on 206 is the remaining length; on 200 it is the full length.
+        let total = resp.content_length().map(|len| len + offset);
+
+        // Open the .part file: append if resuming via Range; truncate otherwise.
+        let file_result = if got_range && start_offset > 0 {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&part_path)
+        } else {
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&part_path)
+        };
+        let mut file = match file_result {
+            Ok(f) => f,
+            Err(e) => {
+                fail(
+                    &sdk,
+                    &manager,
+                    &key,
+                    ErrorCode::InternalError as u32,
+                    format!("open part: {e}"),
+                )
+                .await;
+                return;
+            }
+        };
+
+        emit(
             &sdk,
             &manager,
             &key,
-            ErrorCode::NetworkError as u32,
-            format!("status={status} body={body}"),
+            MediaDownloadState::Downloading {
+                bytes: offset,
+                total,
+            },
         )
         .await;
-        return;
-    }
-
-    let got_range = resp.status() == StatusCode::PARTIAL_CONTENT;
-    let mut offset = if got_range { start_offset } else { 0 };
-    // content_length on 206 is the remaining length; on 200 it is the full length.
-    let total = resp.content_length().map(|len| len + offset);
-
-    // Open the .part file: append if resuming via Range; truncate otherwise.
-    let file_result = if got_range && start_offset > 0 {
-        OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&part_path)
-    } else {
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&part_path)
-    };
-    let mut file = match file_result {
-        Ok(f) => f,
-        Err(e) => {
-            fail(
-                &sdk,
-                &manager,
-                &key,
-                ErrorCode::InternalError as u32,
-                format!("open part: {e}"),
-            )
-            .await;
-            return;
-        }
-    };
-
-    emit(
-        &sdk,
-        &manager,
-        &key,
-        MediaDownloadState::Downloading {
-            bytes: offset,
-            total,
-        },
-    )
-    .await;
 
-    let mut last_emit = Instant::now();
-    let mut resp = resp;
-    loop {
-        if cancelled.load(Ordering::Acquire) {
-            return;
-        }
-        // Pause wait loop — does not block chunk ownership.
-        while paused.load(Ordering::Acquire) {
-            emit(
-                &sdk,
-                &manager,
-                &key,
-                MediaDownloadState::Paused {
-                    bytes: offset,
-                    total,
-                },
-            )
-            .await;
-            pause_notify.notified().await;
+        let mut last_emit = Instant::now();
+        // Throughput is measured over the bytes this attempt actually fetched, so a
+        // resume that only pulls the tail does not look artificially fast.
+        let transfer_started = Instant::now();
+        let mut received: u64 = 0;
+        let mut resp = resp;
+        loop {
             if cancelled.load(Ordering::Acquire) {
                 return;
             }
-            if !paused.load(Ordering::Acquire) {
+            // Pause wait loop — does not block chunk ownership.
+            while paused.load(Ordering::Acquire) {
                 emit(
                     &sdk,
                     &manager,
                     &key,
-                    MediaDownloadState::Downloading {
+                    MediaDownloadState::Paused {
                         bytes: offset,
                         total,
                     },
                 )
                 .await;
-                last_emit = Instant::now();
-            }
-        }
-
-        match resp.chunk().await {
-            Ok(Some(bytes)) => {
-                if let Err(e) = file.write_all(&bytes) {
-                    fail(
-                        &sdk,
-                        &manager,
-                        &key,
-                        ErrorCode::InternalError as u32,
-                        format!("write: {e}"),
-                    )
-                    .await;
+                pause_notify.notified().await;
+                if cancelled.load(Ordering::Acquire) {
                     return;
                 }
-                offset += bytes.len() as u64;
-                if last_emit.elapsed() >= PROGRESS_EMIT_INTERVAL {
+                if !paused.load(Ordering::Acquire) {
                     emit(
                         &sdk,
                         &manager,
@@ -609,140 +887,477 @@ async fn run_download(
                     last_emit = Instant::now();
                 }
             }
-            Ok(None) => break,
-            Err(e) => {
-                fail(
-                    &sdk,
-                    &manager,
-                    &key,
-                    ErrorCode::NetworkError as u32,
-                    format!("chunk: {e}"),
-                )
-                .await;
-                return;
+
+            match resp.chunk().await {
+                Ok(Some(bytes)) => {
+                    if let Err(e) = file.write_all(&bytes) {
+                        fail(
+                            &sdk,
+                            &manager,
+                            &key,
+                            ErrorCode::InternalError as u32,
+                            format!("write: {e}"),
+                        )
+                        .await;
+                        return;
+                    }
+                    offset += bytes.len() as u64;
+                    received += bytes.len() as u64;
+                    sdk.metrics.inc_counter(
+                        metrics::MEDIA_DOWNLOAD_BYTES_TOTAL,
+                        &[],
+                        bytes.len() as u64,
+                    );
+                    sdk.bandwidth
+                        .acquire(TransferDirection::Download, bytes.len() as u64)
+                        .await;
+                    if last_emit.elapsed() >= PROGRESS_EMIT_INTERVAL {
+                        emit(
+                            &sdk,
+                            &manager,
+                            &key,
+                            MediaDownloadState::Downloading {
+                                bytes: offset,
+                                total,
+                            },
+                        )
+                        .await;
+                        last_emit = Instant::now();
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    fail(
+                        &sdk,
+                        &manager,
+                        &key,
+                        ErrorCode::NetworkError as u32,
+                        format!("chunk: {e}"),
+                    )
+                    .await;
+                    return;
+                }
             }
         }
-    }
-
-    if let Err(e) = file.sync_all() {
-        fail(
-            &sdk,
-            &manager,
-            &key,
-            ErrorCode::InternalError as u32,
-            format!("sync: {e}"),
-        )
-        .await;
-        return;
-    }
-    drop(file);
 
-    // Finalize: v0 (legacy plaintext) renames the .part as-is; v1 reads the full
-    // blob (nonce||ciphertext||tag), AES-GCM decrypts it, and writes the plaintext
-    // as the final file. A decrypt failure deletes the .part (it is unusable and
-    // must NOT masquerade as resumable data) and surfaces as a hard failure — we
-    // never fall back to writing the encrypted bytes.
-    if ticket.encryption_version == 0 {
-        if let Err(e) = fs::rename(&part_path, &final_path) {
+        if let Err(e) = file.sync_all() {
             fail(
                 &sdk,
                 &manager,
                 &key,
                 ErrorCode::InternalError as u32,
-                format!("rename: {e}"),
+                format!("sync: {e}"),
             )
             .await;
             return;
         }
-    } else {
-        let blob = match fs::read(&part_path) {
-            Ok(b) => b,
-            Err(e) => {
+        drop(file);
+        let elapsed = transfer_started.elapsed().as_secs_f64();
+        if received > 0 && elapsed > 0.0 {
+            sdk.metrics.observe(
+                metrics::MEDIA_DOWNLOAD_THROUGHPUT,
+                &[],
+                metrics::THROUGHPUT_BUCKETS,
+                received as f64 / elapsed,
+            );
+        }
+
+        match finalize_part(&ticket, &part_path, &final_path) {
+            Ok(()) => break,
+            // `.part` 已经丢掉了：从头再拉一遍。续传接歪（服务端换了对象、中间层截断）
+            // 的那种，重拉一次就好；还不对才算失败。
+            Err(FinalizeError::Integrity(e)) if !integrity_retried => {
+                eprintln!(
+                    "[SDK.media] integrity check failed message_id={message_id}, restarting: {e}"
+                );
+                integrity_retried = true;
+            }
+            Err(FinalizeError::Integrity(e)) => {
                 fail(
                     &sdk,
                     &manager,
                     &key,
-                    ErrorCode::InternalError as u32,
-                    format!("read part for decrypt: {e}"),
+                    crate::error_codes::MEDIA_INTEGRITY_MISMATCH,
+                    e,
                 )
                 .await;
                 return;
             }
-        };
-        let plaintext = match crate::attachment_crypto::decrypt_downloaded_attachment_bytes(
-            ticket.encryption_version,
-            ticket.cek.as_deref(),
-            &blob,
-        ) {
-            Ok(p) => p,
-            Err(e) => {
-                let _ = fs::remove_file(&part_path);
-                fail(
-                    &sdk,
-                    &manager,
-                    &key,
-                    ErrorCode::InternalError as u32,
-                    format!("decrypt attachment: {e}"),
-                )
-                .await;
+            Err(FinalizeError::Io(e)) => {
+                fail(&sdk, &manager, &key, ErrorCode::InternalError as u32, e).await;
                 return;
             }
-        };
-        let decrypted_part = final_path.with_extension("decrypted.part");
-        let write_result = (|| -> std::io::Result<()> {
-            let mut output = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .truncate(true)
-                .open(&decrypted_part)?;
-            output.write_all(&plaintext)?;
-            output.sync_all()?;
-            drop(output);
-            fs::rename(&decrypted_part, &final_path)
-        })();
-        if let Err(e) = write_result {
-            let _ = fs::remove_file(&decrypted_part);
-            fail(
-                &sdk,
-                &manager,
-                &key,
-                ErrorCode::InternalError as u32,
-                format!("write decrypted: {e}"),
-            )
-            .await;
-            return;
-        }
-        // 🔴 密文留一份。转发这份附件时可以直接拿它去预检并秒传——重新加密会
-        // 产出另一串字节，那按定义就是另一个物理文件，白传一遍。
-        //
-        // 落成 `seal_once` 认得的那套格式（blob + 同名 .sealed.json），发送侧
-        // 因此不需要任何「这是转发」的判断：它读到有效缓存就复用，读不到就照常封装。
-        //
-        // 明文成品照常保留给播放器/预览。两份都在，磁盘是双倍——所以有保留窗口，
-        // 见 `prune_sealed_caches`。
-        if let Some(cek) = ticket.cek.as_deref() {
-            if let Some(dir) = final_path.parent() {
-                write_sealed_cache(dir, sealed_cache_name(&final_path), &blob, cek);
-            }
         }
-        let _ = fs::remove_file(&part_path);
     }
 
-    if let Err(e) = sdk.update_media_downloaded_scoped(&key, true).await {
+    finish(&sdk, &manager, &key, &final_path).await;
+}
+
+/// 成品已落盘：置下载标记、发 `Done`。
+async fn finish(
+    sdk: &PrivchatSdk,
+    manager: &DownloadManager,
+    key: &MediaTaskKey,
+    final_path: &Path,
+) {
+    if let Err(e) = sdk.update_media_downloaded_scoped(key, true).await {
         // File is on disk; DB flag will be fixed on the next bootstrap/scan.
-        eprintln!("[SDK.media] update_media_downloaded failed message_id={message_id}: {e}");
+        eprintln!(
+            "[SDK.media] update_media_downloaded failed message_id={}: {e}",
+            key.message_id
+        );
     }
 
     let path_str = final_path.to_string_lossy().to_string();
     emit(
-        &sdk,
-        &manager,
-        &key,
+        sdk,
+        manager,
+        key,
         MediaDownloadState::Done { path: path_str },
     )
     .await;
 }
 
+/// Why [`finalize_part`] could not produce the final file.
+///
+/// `Integrity` means the downloaded bytes themselves are bad — size or SHA-256 differ
+/// from what `file/get_url` promised, or decryption failed authentication. The `.part`
+/// has already been deleted and a fresh download from offset 0 may succeed. `Io` is a
+/// local filesystem problem (reading the `.part`, writing the plaintext) that
+/// re-downloading will not fix; the `.part` is kept, so a retry finalizes it again
+/// without fetching a byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum FinalizeError {
+    Integrity(String),
+    Io(String),
+}
+
+impl std::fmt::Display for FinalizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Integrity(e) | Self::Io(e) => f.write_str(e),
+        }
+    }
+}
+
+/// Check downloaded bytes against the size / SHA-256 the server gave in the ticket.
+///
+/// The digest describes the stored object (plaintext for v0, ciphertext for v1/v2), so
+/// this runs on the raw `.part` before decryption. It is the only check a v0 file gets;
+/// for v1/v2 it also catches a bad Range splice before we spend time decrypting it.
+pub(crate) fn verify_stored_bytes(
+    ticket: &ResolvedFileDownload,
+    bytes: &[u8],
+) -> Result<(), String> {
+    use sha2::Digest as _;
+    if let Some(expected) = ticket.expected_size {
+        if bytes.len() as u64 != expected {
+            return Err(format!(
+                "size mismatch: got {} expected {expected}",
+                bytes.len()
+            ));
+        }
+    }
+    if let Some(expected) = ticket.expected_sha256.as_deref() {
+        let actual = hex::encode(sha2::Sha256::digest(bytes));
+        if actual != expected {
+            return Err(format!("sha256 mismatch: got {actual} expected {expected}"));
+        }
+    }
+    Ok(())
+}
+
+/// [`verify_stored_bytes`] for a file on disk, hashed as a stream.
+fn verify_stored_file(ticket: &ResolvedFileDownload, path: &Path) -> Result<(), String> {
+    use sha2::Digest as _;
+    if let Some(expected) = ticket.expected_size {
+        let len = fs::metadata(path)
+            .map_err(|e| format!("stat part: {e}"))?
+            .len();
+        if len != expected {
+            return Err(format!("size mismatch: got {len} expected {expected}"));
+        }
+    }
+    if let Some(expected) = ticket.expected_sha256.as_deref() {
+        let mut hasher = sha2::Sha256::new();
+        fs::File::open(path)
+            .and_then(|mut f| std::io::copy(&mut f, &mut hasher))
+            .map_err(|e| format!("hash part: {e}"))?;
+        let actual = hex::encode(hasher.finalize());
+        if actual != expected {
+            return Err(format!("sha256 mismatch: got {actual} expected {expected}"));
+        }
+    }
+    Ok(())
+}
+
+/// Where to resume from. A `.part` longer than the stored object cannot be extended
+/// with a Range request — the server answers 416 and the download would fail forever —
+/// so it is discarded and the fetch starts over. One exactly as long as the object is
+/// kept: it is complete, and callers check [`part_is_complete`] first and finalize it
+/// instead of fetching.
+fn resume_offset(ticket: &ResolvedFileDownload, part_path: &Path) -> u64 {
+    let len = fs::metadata(part_path).map(|m| m.len()).unwrap_or(0);
+    match ticket.expected_size {
+        Some(expected) if len > expected => {
+            let _ = fs::remove_file(part_path);
+            0
+        }
+        _ => len,
+    }
+}
+
+/// Whether the `.part` already holds the whole stored object (a background transfer
+/// finished it while the process was gone). Only decidable when the ticket has a size.
+fn part_is_complete(ticket: &ResolvedFileDownload, part_path: &Path) -> bool {
+    ticket
+        .expected_size
+        .is_some_and(|expected| fs::metadata(part_path).is_ok_and(|m| m.len() == expected))
+}
+
+/// Turn a fully downloaded `.part` into the final file.
+///
+/// The `.part` is first checked against the ticket's expected size / SHA-256 (see
+/// [`verify_stored_bytes`]). v0 (legacy plaintext) then renames the .part as-is. v1/v2
+/// decrypt the .part into a sibling `.decrypted.part` and rename that over the final
+/// path: v2 streams segment by segment (one segment in memory, each authenticated on
+/// its own), v1 still has to be read whole because its single GCM tag covers the entire
+/// blob. A verification or decrypt failure deletes the .part (it is unusable and must
+/// NOT masquerade as resumable data) and surfaces as [`FinalizeError::Integrity`] — we
+/// never fall back to writing the encrypted bytes. A local read / write error during
+/// decryption is [`FinalizeError::Io`] and leaves the .part alone.
+pub(crate) fn finalize_part(
+    ticket: &ResolvedFileDownload,
+    part_path: &Path,
+    final_path: &Path,
+) -> Result<(), FinalizeError> {
+    if let Err(e) = verify_stored_file(ticket, part_path) {
+        let _ = fs::remove_file(part_path);
+        return Err(FinalizeError::Integrity(e));
+    }
+    if ticket.encryption_version == 0 {
+        fs::rename(part_path, final_path).map_err(|e| FinalizeError::Io(format!("rename: {e}")))?;
+        adopt_into_blob_store(ticket, final_path);
+        return Ok(());
+    }
+    let input = fs::File::open(part_path)
+        .map(std::io::BufReader::new)
+        .map_err(|e| FinalizeError::Io(format!("read part for decrypt: {e}")))?;
+    let decrypted_part = final_path.with_extension("decrypted.part");
+    let output = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&decrypted_part)
+        .map_err(|e| FinalizeError::Io(format!("write decrypted: {e}")))?;
+    let mut writer = std::io::BufWriter::new(output);
+    if let Err(e) = decrypt_part_into(
+        ticket.encryption_version,
+        ticket.cek.as_deref(),
+        input,
+        &mut writer,
+    ) {
+        drop(writer);
+        let _ = fs::remove_file(&decrypted_part);
+        if matches!(e, FinalizeError::Integrity(_)) {
+            let _ = fs::remove_file(part_path);
+        }
+        return Err(e);
+    }
+    let write_result = writer
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .and_then(|output| output.sync_all())
+        .and_then(|_| fs::rename(&decrypted_part, final_path));
+    if let Err(e) = write_result {
+        let _ = fs::remove_file(&decrypted_part);
+        return Err(FinalizeError::Io(format!("write decrypted: {e}")));
+    }
+    // 🔴 密文留一份。转发这份附件时可以直接拿它去预检并秒传——重新加密会
+    // 产出另一串字节，那按定义就是另一个物理文件，白传一遍。
+    //
+    // 落成 `seal_once` 认得的那套格式（blob + 同名 .sealed.json），发送侧
+    // 因此不需要任何「这是转发」的判断：它读到有效缓存就复用，读不到就照常封装。
+    //
+    // 明文成品照常保留给播放器/预览。两份都在，磁盘是双倍——所以有保留窗口，
+    // 见 `prune_sealed_caches`。`.part` 本身就是那份密文，直接改名过去。
+    if let (Some(cek), Some(dir)) = (ticket.cek.as_deref(), final_path.parent()) {
+        adopt_sealed_cache(
+            dir,
+            sealed_cache_name(final_path),
+            part_path,
+            cek,
+            ticket.encryption_version,
+        );
+    }
+    let _ = fs::remove_file(part_path);
+    adopt_into_blob_store(ticket, final_path);
+    Ok(())
+}
+
+/// 解密 `.part` 写进 `sink`，把失败分成两种。
+///
+/// `decrypt_downloaded_attachment_stream` 的失败只有一个字符串，分不出是密文认证不过，
+/// 还是 `.part` 读不出、盘写满了。两端各套一层 [`IoTrap`] 记下本地 I/O 错误：有的就是
+/// [`FinalizeError::Io`]（字节没错，`.part` 得留着），没有才是 [`FinalizeError::Integrity`]。
+fn decrypt_part_into<R: std::io::Read, W: Write>(
+    encryption_version: i32,
+    cek: Option<&str>,
+    input: R,
+    sink: W,
+) -> Result<(), FinalizeError> {
+    let mut input = IoTrap::new(input);
+    let mut sink = IoTrap::new(sink);
+    let Err(e) = crate::attachment_crypto::decrypt_downloaded_attachment_stream(
+        encryption_version,
+        cek,
+        &mut input,
+        &mut sink,
+    ) else {
+        return Ok(());
+    };
+    match input.error.or(sink.error) {
+        Some(io) => Err(FinalizeError::Io(format!("decrypt attachment: {io}"))),
+        None => Err(FinalizeError::Integrity(format!("decrypt attachment: {e}"))),
+    }
+}
+
+/// 记下经过它的第一个 I/O 错误（`Interrupted` 会被重试，不算）。
+struct IoTrap<T> {
+    inner: T,
+    error: Option<std::io::Error>,
+}
+
+impl<T> IoTrap<T> {
+    fn new(inner: T) -> Self {
+        Self { inner, error: None }
+    }
+
+    fn record<V>(&mut self, result: std::io::Result<V>) -> std::io::Result<V> {
+        if let Err(e) = &result {
+            if self.error.is_none() && e.kind() != std::io::ErrorKind::Interrupted {
+                self.error = Some(std::io::Error::new(e.kind(), e.to_string()));
+            }
+        }
+        result
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for IoTrap<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let result = self.inner.read(buf);
+        self.record(result)
+    }
+}
+
+impl<W: Write> Write for IoTrap<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let result = self.inner.write(buf);
+        self.record(result)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let result = self.inner.flush();
+        self.record(result)
+    }
+}
+
+/// 同一个 file_id 本账号下载过（别的消息里转发过同一份），直接从 blob 库链过来。
+fn link_from_blob_store(ticket: &ResolvedFileDownload, final_path: &Path) -> bool {
+    let (Some(file_id), Some(dir)) = (ticket.file_id, final_path.parent()) else {
+        return false;
+    };
+    crate::media_store::user_root_of_message_dir(dir)
+        .is_some_and(|user_root| crate::media_blob::link_by_file_id(user_root, file_id, final_path))
+}
+
+/// 下载完的正文收进账号的 blob 库（见 [`crate::media_blob`]）：同样的内容之前有过就
+/// 换成指向那份的硬链接，并登记 file_id 索引。不在规范消息目录里的（legacy 布局）不收。
+pub(crate) fn adopt_into_blob_store(ticket: &ResolvedFileDownload, final_path: &Path) {
+    let Some(dir) = final_path.parent() else {
+        return;
+    };
+    let Some(user_root) = crate::media_store::user_root_of_message_dir(dir) else {
+        return;
+    };
+    let cache = dir.join(sealed_cache_name(final_path));
+    if let Err(e) = crate::media_blob::adopt(user_root, final_path, ticket.file_id, Some(&cache)) {
+        eprintln!("[SDK.media] blob store adopt failed: {e}");
+    }
+}
+
+/// Download a payload without progress events or pause/cancel handles.
+///
+/// Used by policy-driven auto-download, which runs inside a [`DownloadManager::submit`]
+/// job on the actor side and has no `PrivchatSdk` to emit through. Same `.part`
+/// Range resume and [`finalize_part`] as [`run_download`], so a job that is aborted
+/// (network no longer allowed) and resubmitted later picks up where it stopped,
+/// and a foreground `start_message_media_download*` can take over the same `.part`.
+/// An integrity failure restarts from scratch once, like [`run_download`].
+pub(crate) async fn download_detached(
+    ticket: &ResolvedFileDownload,
+    target_dir: &Path,
+    payload_filename: &str,
+    metrics: &metrics::MetricsRegistry,
+    bandwidth: &BandwidthLimiter,
+) -> Result<PathBuf, String> {
+    let final_path = target_dir.join(payload_filename);
+    let part_path = target_dir.join(format!("{payload_filename}.part"));
+    if final_path.exists() || link_from_blob_store(ticket, &final_path) {
+        return Ok(final_path);
+    }
+    let mut integrity_retried = false;
+    loop {
+        if !part_is_complete(ticket, &part_path) {
+            fetch_part_detached(ticket, &part_path, metrics, bandwidth).await?;
+        }
+        match finalize_part(ticket, &part_path, &final_path) {
+            Ok(()) => return Ok(final_path),
+            Err(FinalizeError::Integrity(e)) if !integrity_retried => {
+                eprintln!("[SDK.media] integrity check failed, restarting: {e}");
+                integrity_retried = true;
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+async fn fetch_part_detached(
+    ticket: &ResolvedFileDownload,
+    part_path: &Path,
+    metrics: &metrics::MetricsRegistry,
+    bandwidth: &BandwidthLimiter,
+) -> Result<(), String> {
+    let start_offset = resume_offset(ticket, part_path);
+    let mut builder = reqwest::Client::new().get(&ticket.url);
+    if start_offset > 0 {
+        builder = builder.header("Range", format!("bytes={start_offset}-"));
+    }
+    let mut resp = builder.send().await.map_err(|e| format!("send: {e}"))?;
+    if !resp.status().is_success() {
+        return Err(format!("status={}", resp.status()));
+    }
+    let resumed = resp.status() == StatusCode::PARTIAL_CONTENT && start_offset > 0;
+    let mut file = if resumed {
+        OpenOptions::new().append(true).open(part_path)
+    } else {
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(part_path)
+    }
+    .map_err(|e| format!("open part: {e}"))?;
+    while let Some(bytes) = resp.chunk().await.map_err(|e| format!("chunk: {e}"))? {
+        file.write_all(&bytes).map_err(|e| format!("write: {e}"))?;
+        metrics.inc_counter(metrics::MEDIA_DOWNLOAD_BYTES_TOTAL, &[], bytes.len() as u64);
+        bandwidth
+            .acquire(TransferDirection::Download, bytes.len() as u64)
+            .await;
+    }
+    file.sync_all().map_err(|e| format!("sync: {e}"))
+}
+
 /// 清掉过期的封装缓存。
 ///
 /// 明文成品和密文各留一份，磁盘就是双倍——视频尤其明显。转发通常发生在收到之后
@@ -790,7 +1405,7 @@ pub fn prune_sealed_caches(user_root: &std::path::Path, max_age: std::time::Dura
 /// 🔴 主文件和缩略图落在**同一个消息目录**里，共用一个名字的话，后下载完的那个
 /// 会把先下载完的覆盖掉——通常是缩略图覆盖主文件，于是转发主文件时缓存里躺着的
 /// 是缩略图的密文，摘要对不上，悄悄退回整传。没有任何报错。
-fn sealed_cache_name(final_path: &std::path::Path) -> &'static str {
+pub(crate) fn sealed_cache_name(final_path: &std::path::Path) -> &'static str {
     if final_path.file_name().and_then(|name| name.to_str())
         == Some(crate::media_store::THUMB_FILENAME)
     {
@@ -803,8 +1418,8 @@ fn sealed_cache_name(final_path: &std::path::Path) -> &'static str {
 /// 把刚下载到的密文存成发送侧能直接复用的封装缓存。
 ///
 /// 格式与 `State::seal_once` 写出来的完全一致：`body.sealed` +
-/// `body.sealed.json`（`{cek, sha256}`）。一致是刻意的——发送侧读缓存时会重算
-/// 摘要并解密验证 CEK，所以这里写坏了不会被误用，只会退回照常封装。
+/// `body.sealed.json`（`{cek, sha256, encryption_version}`）。一致是刻意的——发送侧
+/// 读缓存时会重算摘要并解密验证 CEK，所以这里写坏了不会被误用，只会退回照常封装。
 ///
 /// best-effort：写失败不影响下载本身，只是这次转发省不掉上传。
 /// 把密文和它的 CEK 留在文件旁边，供"同一份内容再发一次"时原样上传。
@@ -813,6 +1428,7 @@ pub fn write_sealed_cache(
     cache_name: &str,
     blob: &[u8],
     cek: &str,
+    encryption_version: i32,
 ) {
     use sha2::Digest as _;
     let cache = dir.join(cache_name);
@@ -830,19 +1446,55 @@ pub fn write_sealed_cache(
     }
     let mut hasher = <sha2::Sha256 as sha2::Digest>::new();
     hasher.update(blob);
+    commit_sealed_cache(&cache, &hex::encode(hasher.finalize()), cek, encryption_version);
+}
+
+/// 同 [`write_sealed_cache`]，但密文已经在磁盘上（下载好的 `.part`）：改名过去，
+/// 不把整份 blob 读进内存——分段格式的大视频就是为了不这样做。摘要边读边算。
+///
+/// 成功后 `blob_path` 就不在了；失败时它原样留着，由调用方照常清理。
+pub fn adopt_sealed_cache(
+    dir: &std::path::Path,
+    cache_name: &str,
+    blob_path: &std::path::Path,
+    cek: &str,
+    encryption_version: i32,
+) {
+    use sha2::Digest as _;
+    let cache = dir.join(cache_name);
+    let _ = fs::remove_file(cache.with_extension("sealed.json"));
+
+    let mut hasher = <sha2::Sha256 as sha2::Digest>::new();
+    let hashed = fs::File::open(blob_path)
+        .and_then(|mut f| std::io::copy(&mut f, &mut hasher))
+        .is_ok();
+    if !hashed || fs::rename(blob_path, &cache).is_err() {
+        return;
+    }
+    commit_sealed_cache(&cache, &hex::encode(hasher.finalize()), cek, encryption_version);
+}
+
+/// 立提交标记。标记立不起来 = 缓存不算数（读侧要求两者都在），把 blob 也清掉，
+/// 免得白占磁盘。
+fn commit_sealed_cache(
+    cache: &std::path::Path,
+    sha256_hex: &str,
+    cek: &str,
+    encryption_version: i32,
+) {
+    let meta_path = cache.with_extension("sealed.json");
     let meta = serde_json::json!({
         "cek": cek,
-        "sha256": hex::encode(hasher.finalize()),
+        "sha256": sha256_hex,
+        "encryption_version": encryption_version,
     });
     let meta_tmp = meta_path.with_extension("tmp");
     if fs::write(&meta_tmp, meta.to_string())
         .and_then(|_| fs::rename(&meta_tmp, &meta_path))
         .is_err()
     {
-        // 标记没立起来 = 缓存不算数（读侧要求两者都在），把 blob 也清掉，
-        // 免得白占磁盘。
         let _ = fs::remove_file(&meta_tmp);
-        let _ = fs::remove_file(&cache);
+        let _ = fs::remove_file(cache);
     }
 }
 
@@ -1007,8 +1659,8 @@ mod sealed_cache_tests {
         let dir = message_dir(&root);
         let blob = b"nonce-and-ciphertext-and-tag".to_vec();
 
-        write_sealed_cache(&dir, "body.sealed", &blob, "cek-under-test");
-        write_sealed_cache(&dir, "thumb.sealed", b"thumb-blob", "thumb-cek");
+        write_sealed_cache(&dir, "body.sealed", &blob, "cek-under-test", 1);
+        write_sealed_cache(&dir, "thumb.sealed", b"thumb-blob", "thumb-cek", 1);
 
         let cache = dir.join("body.sealed");
         assert_eq!(fs::read(&cache).expect("blob"), blob);
@@ -1017,6 +1669,7 @@ mod sealed_cache_tests {
         )
         .expect("parse meta");
         assert_eq!(meta["cek"], "cek-under-test");
+        assert_eq!(meta["encryption_version"], 1);
         // 摘要必须是**真实字节**的摘要——写错了发送侧会当成损坏缓存丢弃，
         // 表现就是转发永远省不掉上传，而且没有任何报错。
         use sha2::Digest as _;
@@ -1027,6 +1680,31 @@ mod sealed_cache_tests {
         assert!(dir.join("thumb.sealed.json").exists());
     }
 
+    /// 下载好的 `.part` 直接改名成缓存：不经内存，摘要和版本照样写对。
+    #[test]
+    fn a_downloaded_part_is_adopted_as_the_sealed_cache() {
+        let root = tmp_root();
+        let dir = message_dir(&root);
+        let plaintext = vec![7u8; 100 * 1024];
+        let (blob, cek) =
+            crate::attachment_crypto::encrypt_attachment_v2(&plaintext).expect("seal v2");
+        let part = dir.join("payload.mp4.part");
+        fs::write(&part, &blob).expect("write part");
+
+        adopt_sealed_cache(&dir, "body.sealed", &part, &cek, 2);
+
+        assert!(!part.exists(), ".part 已经改名成缓存了");
+        let cache = dir.join("body.sealed");
+        assert_eq!(fs::read(&cache).expect("blob"), blob);
+        let meta: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string(cache.with_extension("sealed.json")).expect("meta"),
+        )
+        .expect("parse meta");
+        assert_eq!(meta["encryption_version"], 2, "🔴 版本丢了，发送侧会按 v1 验证然后丢弃");
+        use sha2::Digest as _;
+        assert_eq!(meta["sha256"], hex::encode(sha2::Sha256::digest(&blob)));
+    }
+
     /// 主文件和缩略图必须占不同的缓存名。
     ///
     /// 两者下载到同一个消息目录。共用一个名字的话后完成的覆盖先完成的，而且完全
@@ -1060,7 +1738,7 @@ mod sealed_cache_tests {
         let stale = root.join("files").join("202608").join("2");
         for dir in [&fresh, &stale] {
             fs::create_dir_all(dir).expect("create dir");
-            write_sealed_cache(dir, "body.sealed", b"blob", "cek");
+            write_sealed_cache(dir, "body.sealed", b"blob", "cek", 1);
             fs::write(dir.join("payload.png"), b"plaintext").expect("write plaintext");
             fs::write(dir.join("thumb.webp"), b"thumb").expect("write thumb");
         }
@@ -1084,3 +1762,289 @@ mod sealed_cache_tests {
         }
     }
 }
+
+#[cfg(test)]
+mod integrity_tests {
+    use super::*;
+
+    fn tmp_dir() -> PathBuf {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "privchat-integrity-{}-{}-{}",
+            std::process::id(),
+            chrono::Utc::now().timestamp_micros(),
+            SEQ.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).expect("create dir");
+        dir
+    }
+
+    fn ticket_for(bytes: &[u8]) -> ResolvedFileDownload {
+        use sha2::Digest as _;
+        ResolvedFileDownload {
+            expected_size: Some(bytes.len() as u64),
+            expected_sha256: Some(hex::encode(sha2::Sha256::digest(bytes))),
+            ..ResolvedFileDownload::legacy_url("http://x".to_string())
+        }
+    }
+
+    #[test]
+    fn a_ticket_without_digests_accepts_anything() {
+        let ticket = ResolvedFileDownload::legacy_url("http://x".to_string());
+        assert_eq!(verify_stored_bytes(&ticket, b"whatever"), Ok(()));
+    }
+
+    #[test]
+    fn size_and_digest_are_both_checked() {
+        let ticket = ticket_for(b"hello");
+        assert_eq!(verify_stored_bytes(&ticket, b"hello"), Ok(()));
+        assert!(verify_stored_bytes(&ticket, b"hell").is_err());
+        // 同样长度、内容不同：只有摘要能发现。
+        assert!(verify_stored_bytes(&ticket, b"jello").is_err());
+    }
+
+    /// 🔴 legacy 明文没有 GCM 兜底：续传接歪的 `.part` 以前会原样改名成成品。
+    #[test]
+    fn a_corrupt_plaintext_part_is_discarded_not_published() {
+        let dir = tmp_dir();
+        let part = dir.join("payload.png.part");
+        let final_path = dir.join("payload.png");
+        fs::write(&part, b"hellX").expect("part");
+
+        let result = finalize_part(&ticket_for(b"hello"), &part, &final_path);
+
+        assert!(matches!(result, Err(FinalizeError::Integrity(_))));
+        assert!(!part.exists(), "坏掉的 .part 不能留着被当成续传起点");
+        assert!(!final_path.exists());
+
+        fs::write(&part, b"hello").expect("part");
+        assert_eq!(
+            finalize_part(&ticket_for(b"hello"), &part, &final_path),
+            Ok(())
+        );
+        assert_eq!(fs::read(&final_path).expect("final"), b"hello");
+    }
+
+    /// 比存储对象还长的 `.part` 再发 Range 只会拿到 416，永远下不完；正好一样长的是
+    /// 已经拉满的，留着收尾，不能当成续不上的删掉。
+    #[test]
+    fn a_part_that_cannot_be_extended_restarts_from_zero() {
+        let dir = tmp_dir();
+        let part = dir.join("payload.bin.part");
+        let ticket = ticket_for(b"0123456789");
+
+        fs::write(&part, b"0123").expect("part");
+        assert_eq!(resume_offset(&ticket, &part), 4);
+
+        fs::write(&part, b"0123456789").expect("part");
+        assert_eq!(resume_offset(&ticket, &part), 10);
+        assert!(part_is_complete(&ticket, &part));
+
+        fs::write(&part, b"0123456789!").expect("part");
+        assert_eq!(resume_offset(&ticket, &part), 0);
+        assert!(!part.exists());
+    }
+}
+
+#[cfg(test)]
+mod handoff_tests {
+    use super::*;
+
+    fn tmp_dir() -> PathBuf {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "privchat-handoff-{}-{}-{}",
+            std::process::id(),
+            chrono::Utc::now().timestamp_micros(),
+            SEQ.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).expect("create dir");
+        dir
+    }
+
+    fn ticket_for(bytes: &[u8]) -> ResolvedFileDownload {
+        use sha2::Digest as _;
+        ResolvedFileDownload {
+            expected_size: Some(bytes.len() as u64),
+            expected_sha256: Some(hex::encode(sha2::Sha256::digest(bytes))),
+            ..ResolvedFileDownload::legacy_url("http://x/blob".to_string())
+        }
+    }
+
+    /// 模拟一条进行中的前台下载：任务永远等着，交接时必须被中止。
+    fn track_running(manager: &DownloadManager, message_id: u64, source: DownloadSource) {
+        let task = tokio::spawn(std::future::pending::<()>());
+        manager.inner.entries.lock().unwrap().insert(
+            MediaTaskKey::payload("10001".to_string(), 1, message_id),
+            HandleEntry {
+                task_id: message_id,
+                state: MediaDownloadState::Downloading {
+                    bytes: 0,
+                    total: None,
+                },
+                paused: Arc::new(AtomicBool::new(false)),
+                cancelled: Arc::new(AtomicBool::new(false)),
+                pause_notify: Arc::new(Notify::new()),
+                task,
+                source: Some(source),
+            },
+        );
+    }
+
+    /// Linux 上模拟一次完整交接：导出 → 「平台」按描述把剩下的字节追加进 `.part` → 导入。
+    #[tokio::test]
+    async fn a_handed_off_download_resumes_from_the_part_and_finalizes_on_import() {
+        let dir = tmp_dir();
+        let body = b"0123456789abcdef";
+        fs::write(dir.join("payload.bin.part"), &body[..6]).unwrap();
+        let manager = DownloadManager::new();
+        track_running(
+            &manager,
+            5,
+            DownloadSource {
+                ticket: ticket_for(body),
+                target_dir: dir.clone(),
+                payload_filename: "payload.bin".to_string(),
+            },
+        );
+
+        let handed = manager.hand_off().await;
+        assert_eq!(manager.tracked_count(), 0);
+        assert_eq!(handed.len(), 1);
+        let descriptor = handed[0].descriptor();
+        assert_eq!(descriptor.transfer_id, "dl:5");
+        assert_eq!(
+            (descriptor.range_start, descriptor.range_end),
+            (6, Some(16))
+        );
+        assert_eq!(
+            descriptor.headers,
+            vec![("Range".to_string(), "bytes=6-".to_string())]
+        );
+        // 重复导出是同一批。
+        assert_eq!(manager.hand_off().await.len(), 1);
+
+        let mut part = OpenOptions::new()
+            .append(true)
+            .open(&descriptor.path)
+            .unwrap();
+        part.write_all(&body[descriptor.range_start as usize..])
+            .unwrap();
+        drop(part);
+
+        let download = manager.take_handed_off(&descriptor.transfer_id).unwrap();
+        let path = download.complete().expect("verified");
+        assert_eq!(fs::read(&path).unwrap(), body);
+        assert!(!descriptor.path.exists());
+        assert!(manager.take_handed_off(&descriptor.transfer_id).is_none());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    /// 写明文失败（盘满）不是密文的错：不能当成完整性问题把 `.part` 删掉。
+    #[test]
+    fn a_failing_sink_is_an_io_error_and_bad_ciphertext_is_integrity() {
+        struct DiskFull;
+        impl Write for DiskFull {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("no space left"))
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let (blob, cek) =
+            crate::attachment_crypto::encrypt_attachment_v2(b"some plaintext").expect("seal v2");
+
+        assert!(matches!(
+            decrypt_part_into(2, Some(&cek), blob.as_slice(), DiskFull),
+            Err(FinalizeError::Io(_))
+        ));
+
+        let mut tampered = blob.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0xff;
+        assert!(matches!(
+            decrypt_part_into(2, Some(&cek), tampered.as_slice(), Vec::new()),
+            Err(FinalizeError::Integrity(_))
+        ));
+
+        let mut plain = Vec::new();
+        decrypt_part_into(2, Some(&cek), blob.as_slice(), &mut plain).expect("decrypts");
+        assert_eq!(plain, b"some plaintext");
+    }
+
+    #[tokio::test]
+    async fn a_corrupt_background_result_is_rejected_and_discarded() {
+        let dir = tmp_dir();
+        let manager = DownloadManager::new();
+        track_running(
+            &manager,
+            6,
+            DownloadSource {
+                ticket: ticket_for(b"expected-body"),
+                target_dir: dir.clone(),
+                payload_filename: "payload.bin".to_string(),
+            },
+        );
+        let download = manager.hand_off().await.remove(0);
+        let descriptor = download.descriptor();
+        assert_eq!(descriptor.range_start, 0);
+        assert!(descriptor.headers.is_empty());
+        fs::write(&descriptor.path, b"tampered-body").unwrap();
+
+        assert!(matches!(
+            download.complete(),
+            Err(FinalizeError::Integrity(_))
+        ));
+        assert!(!descriptor.path.exists());
+        assert!(!download.final_path().exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn a_network_change_never_aborts_a_download_the_user_started() {
+        let dir = tmp_dir();
+        let manager = DownloadManager::new();
+        let key = MediaTaskKey::payload("10001".to_string(), 1, 7);
+
+        // 自动下载（后台 job）：网络不再放行就摘掉。
+        assert!(manager.submit(
+            key.clone(),
+            DownloadPriority::Background,
+            std::future::pending(),
+        ));
+        assert!(!manager.is_user_initiated(&key));
+        assert!(manager.abort_background(&key));
+        assert!(!manager.is_tracked(&key));
+
+        // 用户点开后同一个 key 上是前台下载：留着。
+        track_running(
+            &manager,
+            7,
+            DownloadSource {
+                ticket: ticket_for(b"body"),
+                target_dir: dir.clone(),
+                payload_filename: "payload.bin".to_string(),
+            },
+        );
+        assert!(manager.is_user_initiated(&key));
+        assert!(!manager.abort_background(&key));
+        assert!(manager.is_tracked(&key));
+        manager.cancel_all_scoped();
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn a_part_that_already_holds_the_whole_object_is_recognised() {
+        let dir = tmp_dir();
+        let part = dir.join("payload.bin.part");
+        let ticket = ticket_for(b"abc");
+        fs::write(&part, b"ab").unwrap();
+        assert!(!part_is_complete(&ticket, &part));
+        fs::write(&part, b"abc").unwrap();
+        assert!(part_is_complete(&ticket, &part));
+        let unsized_ticket = ResolvedFileDownload::legacy_url("http://x".to_string());
+        assert!(!part_is_complete(&unsized_ticket, &part));
+        let _ = fs::remove_dir_all(dir);
+    }
+}
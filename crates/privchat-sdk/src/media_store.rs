@@ -21,6 +21,17 @@ pub fn get_message_dir(user_root: &Path, message_id: i64, created_at_ms: i64) ->
         .join(message_id.to_string())
 }
 
+/// Inverse of [`get_message_dir`]: the `user_root` a message media directory lives under.
+/// Returns None for anything not shaped like `{user_root}/files/{yyyymm}/{message_id}/`
+/// (legacy directories without the yyyymm layer included).
+pub fn user_root_of_message_dir(dir: &Path) -> Option<&Path> {
+    let files = dir.parent()?.parent()?;
+    if files.file_name()? != "files" {
+        return None;
+    }
+    files.parent()
+}
+
 /// Calculate the canonical media directory for a message from global root.
 /// Spec: {root}/users/{uid}/files/{yyyymm}/{message_id}/
 pub fn get_canonical_message_dir(
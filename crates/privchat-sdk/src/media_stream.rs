@@ -0,0 +1,1263 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 本地回环流媒体代理：加密附件边下边播。
+//!
+//! `media_download::run_download` 要等整份 blob 落盘、解密完才出成品，视频和语音
+//! 在那之前放不了。这里在 `127.0.0.1` 上起一个最小 HTTP/1.1 服务，播放器拿到
+//! `http://127.0.0.1:{port}/media/{message_id}?token=...`，按 Range 要哪段，代理就去
+//! 签名 URL 拉对应的那几块密文、当场解密、回明文。
+//!
+//! - v2（分段）：块 = 一个密文段，随机访问，拉到哪段解哪段。
+//! - v0（明文）：块 = 固定 [`PLAIN_BLOCK_SIZE`]，原样转发。
+//! - v1（整份 GCM）：一个标签盖住整份，没有随机访问可言——先整份拉完解密，再从成品出。
+//!
+//! 拉到的密文按块写进 `{payload}.stream`（按 blob 总长预分配，块位图在内存）。所有块
+//! 到齐就按 `run_download` 的收尾落成品到 `media_store` 的正常路径，之后的请求直接
+//! 读成品。`.stream` 与下载器的 `.part` 刻意不共用：`.part` 靠文件长度续传，
+//! 一个中间有洞的文件会被它当成「前 N 字节已到」。
+//!
+//! 🔴 只绑回环地址，且 URL 带每次启动随机生成的 token：同机其他 App 也能连
+//! 127.0.0.1，没有 token 就等于把解密后的附件对整台设备开放。
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+
+use reqwest::StatusCode;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::attachment_crypto::{
+    self, SegmentedCipher, SegmentedHeader, SegmentedLayout, ENCRYPTION_VERSION_PLAIN,
+    ENCRYPTION_VERSION_SEGMENTED, ENCRYPTION_VERSION_WHOLE, V2_HEADER_LEN,
+};
+use crate::bandwidth::{BandwidthLimiter, TransferDirection};
+use crate::media_download::{self, FinalizeError, MediaTaskKey};
+use crate::metrics::{self, MetricsRegistry};
+use crate::ResolvedFileDownload;
+
+/// 明文（v0）附件的缓存块大小。
+pub const PLAIN_BLOCK_SIZE: u64 = 256 * 1024;
+/// 一次持锁最多出多少明文。长响应分窗写出，别的请求（播放器拖动进度条时常见）
+/// 不必等前一个整段写完。
+const RESPONSE_WINDOW: u64 = 1024 * 1024;
+/// 请求头上限。播放器的请求头很短，超过这个数只可能是别的东西连错了端口。
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// 代理诊断日志开关，默认关闭。设 `PRIVCHAT_MEDIA_STREAM_LOG=1` 开启。
+/// 播放器拖动进度条时断连、重连是常态，不该往宿主的 stderr 里刷。
+fn stream_logs_enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| std::env::var("PRIVCHAT_MEDIA_STREAM_LOG").ok().as_deref() == Some("1"))
+}
+
+/// 某条消息的流媒体来源：下载票据 + 成品该落的位置。
+pub(crate) struct StreamSource {
+    pub key: MediaTaskKey,
+    pub ticket: ResolvedFileDownload,
+    pub final_path: PathBuf,
+    pub mime: String,
+}
+
+/// 成品落盘后的回调：标记已下载、通知 UI。由 `PrivchatSdk` 提供。
+pub(crate) type CompletionHook = Arc<dyn Fn(MediaTaskKey, PathBuf) + Send + Sync>;
+
+/// 跑着的代理。drop 即停：accept 循环连同它名下所有连接一起 abort。
+pub(crate) struct MediaStreamServer {
+    port: u16,
+    token: String,
+    shared: Arc<Shared>,
+    task: JoinHandle<()>,
+}
+
+struct Shared {
+    token: String,
+    entries: Mutex<HashMap<u64, Arc<StreamEntry>>>,
+    origin: Origin,
+    on_complete: CompletionHook,
+}
+
+/// 拉源站用的客户端。收到的字节和 `DownloadManager` 的下载一样过限速器、记进
+/// 下载字节数：边下边播也是下载，不能绕开限速和按网络类型的记账。
+struct Origin {
+    client: reqwest::Client,
+    bandwidth: BandwidthLimiter,
+    metrics: Arc<MetricsRegistry>,
+}
+
+impl Origin {
+    /// 收到 `bytes` 字节之后付账。
+    async fn received(&self, bytes: u64) {
+        self.metrics
+            .inc_counter(metrics::MEDIA_DOWNLOAD_BYTES_TOTAL, &[], bytes);
+        self.bandwidth
+            .acquire(TransferDirection::Download, bytes)
+            .await;
+    }
+
+    /// 边读边付账地读完整个响应体。
+    async fn read_body(&self, resp: reqwest::Response) -> Result<Vec<u8>, String> {
+        let body = self
+            .bandwidth
+            .read_body(resp)
+            .await
+            .map_err(|e| format!("body: {e}"))?;
+        self.metrics
+            .inc_counter(metrics::MEDIA_DOWNLOAD_BYTES_TOTAL, &[], body.len() as u64);
+        Ok(body)
+    }
+}
+
+impl Shared {
+    /// 登记表只在锁内做 HashMap 增删，不会半途 panic 留下坏数据；
+    /// 中毒了照常取内部值，不让一个连接的 panic 把整个代理带走。
+    fn entries(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Arc<StreamEntry>>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+struct StreamEntry {
+    key: MediaTaskKey,
+    mime: String,
+    /// 块位图、票据、块几何。🔴 只在本地读写时持有，拉网络期间绝不持有：
+    /// 换票据、读已到的块都不该排在一次慢请求后面。
+    state: tokio::sync::Mutex<StreamState>,
+    /// 同一条消息同一时刻只有一个请求在拉源站，免得两个连接重复拉同一段。
+    fetch_gate: tokio::sync::Mutex<()>,
+}
+
+impl MediaStreamServer {
+    /// 绑定 `127.0.0.1` 的随机端口并开始接连接。
+    ///
+    /// 端口用 std 同步绑定，这样调用方不需要 Tokio 上下文就能拿到端口；
+    /// accept 循环跑在 `runtime` 上（UniFFI 的 async 桥没有 reactor）。
+    pub(crate) fn start(
+        runtime: &tokio::runtime::Handle,
+        bandwidth: BandwidthLimiter,
+        metrics: Arc<MetricsRegistry>,
+        on_complete: CompletionHook,
+    ) -> std::io::Result<Self> {
+        let std_listener = std::net::TcpListener::bind(("127.0.0.1", 0))?;
+        std_listener.set_nonblocking(true)?;
+        let port = std_listener.local_addr()?.port();
+        let token = {
+            use rand::RngCore as _;
+            let mut raw = [0u8; 16];
+            rand::thread_rng().fill_bytes(&mut raw);
+            hex::encode(raw)
+        };
+        let shared = Arc::new(Shared {
+            token: token.clone(),
+            entries: Mutex::new(HashMap::new()),
+            origin: Origin {
+                client: reqwest::Client::new(),
+                bandwidth,
+                metrics,
+            },
+            on_complete,
+        });
+        let accept_shared = shared.clone();
+        let task = runtime.spawn(async move {
+            let listener = match tokio::net::TcpListener::from_std(std_listener) {
+                Ok(l) => l,
+                Err(e) => {
+                    if stream_logs_enabled() {
+                        eprintln!("[SDK.media_stream] listener setup failed: {e}");
+                    }
+                    return;
+                }
+            };
+            // 连接挂在 JoinSet 上：accept 任务被 abort 时 JoinSet 随之 drop，
+            // 正在回包、正在拉源站的连接一并 abort，不会在代理停了之后还读着附件。
+            let mut connections = JoinSet::new();
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => {
+                        if let Ok((sock, _)) = accepted {
+                            connections.spawn(serve_connection(sock, accept_shared.clone()));
+                        }
+                    }
+                    Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                }
+            }
+        });
+        Ok(Self {
+            port,
+            token,
+            shared,
+            task,
+        })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// 登记（或刷新）一条消息，返回给播放器的 URL。
+    ///
+    /// 同一个 key 再登记只换票据——签名 URL 会过期，长视频看到一半重新要一次 URL
+    /// 是正常用法，已经拉到的块照样有效。key 的账号/会话和已登记的不同时，旧的
+    /// 全部丢掉：换了账号，上一个账号的附件不该还能从这个端口读出来。
+    pub(crate) async fn register(&self, source: StreamSource) -> String {
+        let message_id = source.key.message_id;
+        let existing = {
+            let mut entries = self.shared.entries();
+            entries.retain(|_, e| {
+                e.key.owner_uid == source.key.owner_uid
+                    && e.key.session_epoch == source.key.session_epoch
+            });
+            match entries.get(&message_id) {
+                Some(entry) if entry.key == source.key => Some(entry.clone()),
+                _ => {
+                    entries.insert(
+                        message_id,
+                        Arc::new(StreamEntry {
+                            key: source.key.clone(),
+                            mime: source.mime.clone(),
+                            state: tokio::sync::Mutex::new(StreamState::new(
+                                source.ticket.clone(),
+                                source.final_path.clone(),
+                            )),
+                            fetch_gate: tokio::sync::Mutex::new(()),
+                        }),
+                    );
+                    None
+                }
+            }
+        };
+        if let Some(entry) = existing {
+            entry.state.lock().await.ticket = source.ticket;
+        }
+        self.url_for(message_id)
+    }
+
+    pub(crate) fn url_for(&self, message_id: u64) -> String {
+        format!(
+            "http://127.0.0.1:{}/media/{message_id}?token={}",
+            self.port, self.token
+        )
+    }
+}
+
+impl Drop for MediaStreamServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// 票据按版本换算出来的块几何。
+enum Plan {
+    /// v0：按 [`PLAIN_BLOCK_SIZE`] 切块，原样转发。
+    Plain { len: u64 },
+    /// v2：块 = 一个密文段。
+    Segmented {
+        layout: SegmentedLayout,
+        cipher: Box<SegmentedCipher>,
+    },
+    /// 成品已在 `final_path`，直接读它。
+    Done { len: u64 },
+}
+
+impl Plan {
+    fn plaintext_len(&self) -> u64 {
+        match self {
+            Plan::Plain { len } | Plan::Done { len } => *len,
+            Plan::Segmented { layout, .. } => layout.plaintext_len,
+        }
+    }
+
+    fn block_count(&self) -> u64 {
+        match self {
+            Plan::Plain { len } => len.div_ceil(PLAIN_BLOCK_SIZE),
+            Plan::Segmented { layout, .. } => layout.segment_count(),
+            Plan::Done { .. } => 0,
+        }
+    }
+
+    /// 第 `index` 块在 blob（也就是 `.stream`）里的区间 `[start, end)`。
+    fn blob_range(&self, index: u64) -> (u64, u64) {
+        match self {
+            Plan::Segmented { layout, .. } => layout.ciphertext_range(index),
+            _ => self.plaintext_range(index),
+        }
+    }
+
+    /// 第 `index` 块的明文区间 `[start, end)`。
+    fn plaintext_range(&self, index: u64) -> (u64, u64) {
+        match self {
+            Plan::Segmented { layout, .. } => layout.plaintext_range(index),
+            _ => {
+                let start = index * PLAIN_BLOCK_SIZE;
+                (start, (start + PLAIN_BLOCK_SIZE).min(self.plaintext_len()))
+            }
+        }
+    }
+
+    /// 覆盖明文 `[start, end)` 的块号区间 `[first, last]`。调用方保证 `start < end`。
+    fn blocks_for(&self, start: u64, end: u64) -> (u64, u64) {
+        match self {
+            Plan::Segmented { layout, .. } => layout.segments_for(start, end),
+            _ => (start / PLAIN_BLOCK_SIZE, (end - 1) / PLAIN_BLOCK_SIZE),
+        }
+    }
+}
+
+/// 探测源站的结果：要么得到块几何，要么（v1）成品已经整份落了盘。
+enum Probed {
+    Plan(Plan),
+    Whole(u64),
+}
+
+/// 一次 Range 拉块的参数，从 `StreamState` 里抄出来，拉的时候不必持锁。
+struct BlockFetch {
+    url: String,
+    cache_path: PathBuf,
+    first: u64,
+    last: u64,
+    from: u64,
+    to: u64,
+    blob_len: u64,
+}
+
+impl StreamEntry {
+    /// 确定块几何，返回明文总长。第一次调用时探测 blob 总长（v2 顺带拿 header）。
+    /// 返回的 bool 表示这一次让成品落了盘。
+    ///
+    /// 🔴 探测用 `GET` + `Range`，不用 `HEAD`：签名 URL 通常只对 GET 签名，HEAD 会 403。
+    async fn prepare(&self, origin: &Origin) -> Result<(u64, bool), String> {
+        if let Some(len) = self.state.lock().await.prepared_len() {
+            return Ok((len, false));
+        }
+        let gate = self.fetch_gate.lock().await;
+        let (ticket, final_path) = {
+            let mut state = self.state.lock().await;
+            if let Some(len) = state.prepared_len() {
+                return Ok((len, false));
+            }
+            (state.ticket.clone(), state.final_path.clone())
+        };
+        let probed = probe(origin, &ticket, &final_path).await?;
+        let mut state = self.state.lock().await;
+        match probed {
+            Probed::Whole(len) => {
+                state.plan = Some(Plan::Done { len });
+                Ok((len, true))
+            }
+            Probed::Plan(plan) => {
+                let len = plan.plaintext_len();
+                state.install(plan)?;
+                drop(state);
+                let finished = self.finish_if_complete(&gate).await?;
+                Ok((len, finished))
+            }
+        }
+    }
+
+    /// 读明文 `[start, end)`，缺的块先去拉。返回的 bool 表示这一次读让成品落了盘。
+    async fn read(&self, origin: &Origin, start: u64, end: u64) -> Result<(Vec<u8>, bool), String> {
+        loop {
+            let read = {
+                let mut state = self.state.lock().await;
+                // 正在落成品时 `.stream` 可能正被改名：排到 gate 后面等它落完。
+                if !state.finishing && state.missing_run(start, end)?.is_none() {
+                    Some((state.read_present(start, end)?, state.is_complete()))
+                } else {
+                    None
+                }
+            };
+            if let Some((out, complete)) = read {
+                if !complete {
+                    return Ok((out, false));
+                }
+                let gate = self.fetch_gate.lock().await;
+                let finished = self.finish_if_complete(&gate).await?;
+                return Ok((out, finished));
+            }
+            let _gate = self.fetch_gate.lock().await;
+            // 排队期间别的请求可能已经把这一段拉回来了。
+            let job = {
+                let state = self.state.lock().await;
+                match state.missing_run(start, end)? {
+                    Some((first, last)) => state.block_fetch(first, last)?,
+                    None => continue,
+                }
+            };
+            let whole = fetch_blocks(origin, &job).await?;
+            self.state.lock().await.mark_present(&job, whole);
+        }
+    }
+
+    /// 所有块都到了就落成品，走下载器同一个 [`media_download::finalize_part`]：先按票据
+    /// 的大小 / SHA-256 校验整份 blob（v0 没有别的校验，v2 也靠它发现拼错的块），再
+    /// v0 改名、v2 整份流式解密，密文留作转发用的封装缓存。
+    ///
+    /// 🔴 整份校验 / 解密不持 `state`：把活抄出来、置上 `finishing`，在 blocking 线程池
+    /// 上做完再回锁发布。持着 `fetch_gate` 做，期间没人拉块，读请求看到 `finishing`
+    /// 也排到 gate 后面。
+    ///
+    /// 校验不过的 `.stream` 已被删掉：块几何一并作废，下一个请求重新探测、从头拉。
+    async fn finish_if_complete(
+        &self,
+        _gate: &tokio::sync::MutexGuard<'_, ()>,
+    ) -> Result<bool, String> {
+        let Some((len, ticket, cache_path, final_path)) = self.state.lock().await.begin_finish()
+        else {
+            return Ok(false);
+        };
+        let finalized = tokio::task::spawn_blocking(move || {
+            media_download::finalize_part(&ticket, &cache_path, &final_path)
+        })
+        .await;
+        let mut state = self.state.lock().await;
+        state.finishing = false;
+        match finalized.map_err(|e| format!("finish stream: {e}"))? {
+            Ok(()) => {}
+            Err(FinalizeError::Integrity(e)) => {
+                state.plan = None;
+                state.present = Vec::new();
+                return Err(e);
+            }
+            Err(FinalizeError::Io(e)) => return Err(e),
+        }
+        state.plan = Some(Plan::Done { len });
+        state.present = Vec::new();
+        Ok(true)
+    }
+}
+
+struct StreamState {
+    ticket: ResolvedFileDownload,
+    final_path: PathBuf,
+    cache_path: PathBuf,
+    plan: Option<Plan>,
+    present: Vec<bool>,
+    /// 正在落成品（见 [`StreamEntry::finish_if_complete`]）。
+    finishing: bool,
+}
+
+impl StreamState {
+    fn new(ticket: ResolvedFileDownload, final_path: PathBuf) -> Self {
+        let mut cache_name = final_path.file_name().unwrap_or_default().to_os_string();
+        cache_name.push(".stream");
+        let cache_path = final_path.with_file_name(cache_name);
+        Self {
+            ticket,
+            final_path,
+            cache_path,
+            plan: None,
+            present: Vec::new(),
+            finishing: false,
+        }
+    }
+
+    /// 块几何已定（或成品已在）时返回明文总长；还要探测源站时返回 None。
+    fn prepared_len(&mut self) -> Option<u64> {
+        if let Some(plan) = &self.plan {
+            return Some(plan.plaintext_len());
+        }
+        let len = fs::metadata(&self.final_path).ok()?.len();
+        self.plan = Some(Plan::Done { len });
+        Some(len)
+    }
+
+    /// 按探测到的块几何预分配 `.stream`，块位图全部置空。
+    fn install(&mut self, plan: Plan) -> Result<(), String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.cache_path)
+            .map_err(|e| format!("open stream cache: {e}"))?;
+        file.set_len(plan_blob_len(&plan))
+            .map_err(|e| format!("size stream cache: {e}"))?;
+        if let Plan::Segmented { cipher, .. } = &plan {
+            // header 不属于任何块，但完整 blob 里少不了它：落成品、留密文缓存都要。
+            let mut file = file;
+            file.write_all(&cipher.header().encode())
+                .map_err(|e| format!("write stream cache: {e}"))?;
+        }
+        self.present = vec![false; plan.block_count() as usize];
+        self.plan = Some(plan);
+        Ok(())
+    }
+
+    /// 明文 `[start, end)` 里第一段连续缺失的块 `[first, last]`；都到了（或成品已在）返回 None。
+    /// 连续缺的块并成一次 Range 请求。
+    fn missing_run(&self, start: u64, end: u64) -> Result<Option<(u64, u64)>, String> {
+        let plan = self.plan.as_ref().ok_or("stream not prepared")?;
+        if let Plan::Done { .. } = plan {
+            return Ok(None);
+        }
+        let (first, last) = plan.blocks_for(start, end);
+        let Some(index) = (first..=last).find(|&i| !self.present[i as usize]) else {
+            return Ok(None);
+        };
+        let mut run_end = index;
+        while run_end < last && !self.present[run_end as usize + 1] {
+            run_end += 1;
+        }
+        Ok(Some((index, run_end)))
+    }
+
+    fn block_fetch(&self, first: u64, last: u64) -> Result<BlockFetch, String> {
+        let plan = self.plan.as_ref().ok_or("stream not prepared")?;
+        let (from, _) = plan.blob_range(first);
+        let (_, to) = plan.blob_range(last);
+        Ok(BlockFetch {
+            url: self.ticket.url.clone(),
+            cache_path: self.cache_path.clone(),
+            first,
+            last,
+            from,
+            to,
+            blob_len: plan_blob_len(plan),
+        })
+    }
+
+    fn mark_present(&mut self, job: &BlockFetch, whole: bool) {
+        if !matches!(self.plan, Some(Plan::Plain { .. } | Plan::Segmented { .. })) {
+            return;
+        }
+        let (first, last) = if whole {
+            (0, self.present.len() as u64 - 1)
+        } else {
+            (job.first, job.last)
+        };
+        for index in first..=last {
+            self.present[index as usize] = true;
+        }
+    }
+
+    /// 读明文 `[start, end)`。调用方保证覆盖它的块都已到齐（[`Self::missing_run`] 为 None）。
+    fn read_present(&mut self, start: u64, end: u64) -> Result<Vec<u8>, String> {
+        let plan = self.plan.as_ref().ok_or("stream not prepared")?;
+        if let Plan::Done { .. } = plan {
+            let mut file =
+                fs::File::open(&self.final_path).map_err(|e| format!("open final: {e}"))?;
+            file.seek(SeekFrom::Start(start))
+                .map_err(|e| format!("seek final: {e}"))?;
+            let mut out = vec![0u8; (end - start) as usize];
+            file.read_exact(&mut out)
+                .map_err(|e| format!("read final: {e}"))?;
+            return Ok(out);
+        }
+
+        let (first, last) = plan.blocks_for(start, end);
+        let mut file =
+            fs::File::open(&self.cache_path).map_err(|e| format!("open stream cache: {e}"))?;
+        let mut out = Vec::with_capacity((end - start) as usize);
+        let mut corrupt = None;
+        for index in first..=last {
+            let (blob_start, blob_end) = plan.blob_range(index);
+            let mut raw = vec![0u8; (blob_end - blob_start) as usize];
+            file.seek(SeekFrom::Start(blob_start))
+                .and_then(|_| file.read_exact(&mut raw))
+                .map_err(|e| format!("read stream cache: {e}"))?;
+            let block = match plan {
+                Plan::Segmented { layout, cipher } => {
+                    let is_last = index + 1 == layout.segment_count();
+                    match cipher.open_segment(index, is_last, &raw) {
+                        Ok(block) => block,
+                        Err(e) => {
+                            corrupt = Some((index, e));
+                            break;
+                        }
+                    }
+                }
+                _ => raw,
+            };
+            let (plain_start, _) = plan.plaintext_range(index);
+            let from = start.saturating_sub(plain_start) as usize;
+            let to = ((end - plain_start) as usize).min(block.len());
+            if from < to {
+                out.extend_from_slice(&block[from..to]);
+            }
+        }
+        drop(file);
+        if let Some((index, e)) = corrupt {
+            // 缓存里这一块是坏的：作废，下次重新拉，而不是一直回同一个错。
+            self.present[index as usize] = false;
+            return Err(e);
+        }
+        Ok(out)
+    }
+
+    /// 块全到了、还没落成品。
+    fn is_complete(&self) -> bool {
+        matches!(self.plan, Some(Plan::Plain { .. } | Plan::Segmented { .. }))
+            && self.present.iter().all(|p| *p)
+    }
+
+    /// 该落成品时置上 `finishing`，抄出落成品要的东西：明文长度、票据、`.stream`、成品路径。
+    fn begin_finish(&mut self) -> Option<(u64, ResolvedFileDownload, PathBuf, PathBuf)> {
+        if self.finishing || !self.is_complete() {
+            return None;
+        }
+        let len = self.plan.as_ref()?.plaintext_len();
+        self.finishing = true;
+        Some((
+            len,
+            self.ticket.clone(),
+            self.cache_path.clone(),
+            self.final_path.clone(),
+        ))
+    }
+}
+
+fn plan_blob_len(plan: &Plan) -> u64 {
+    match plan {
+        Plan::Segmented { layout, .. } => layout.blob_len(),
+        _ => plan.plaintext_len(),
+    }
+}
+
+/// 按票据版本探测源站。不持任何锁。
+async fn probe(
+    origin: &Origin,
+    ticket: &ResolvedFileDownload,
+    final_path: &Path,
+) -> Result<Probed, String> {
+    let plan = match ticket.encryption_version {
+        ENCRYPTION_VERSION_PLAIN => {
+            let (_, total) = fetch_probe(origin, &ticket.url, 1).await?;
+            Plan::Plain { len: total }
+        }
+        ENCRYPTION_VERSION_SEGMENTED => {
+            let (head, total) = fetch_probe(origin, &ticket.url, V2_HEADER_LEN as u64).await?;
+            let header = SegmentedHeader::parse(&head)?;
+            let layout = SegmentedLayout::from_blob_len(header.segment_size, total)?;
+            let cek = ticket
+                .cek
+                .as_deref()
+                .ok_or_else(|| "segmented attachment requires cek".to_string())?;
+            let cipher = Box::new(SegmentedCipher::new(cek, header)?);
+            Plan::Segmented { layout, cipher }
+        }
+        ENCRYPTION_VERSION_WHOLE => {
+            return fetch_whole(origin, ticket, final_path)
+                .await
+                .map(Probed::Whole);
+        }
+        other => return Err(format!("unsupported encryption_version={other}")),
+    };
+    Ok(Probed::Plan(plan))
+}
+
+/// v1：整份拉完、整份解密、落成品。解密和落盘在 blocking 线程池上做。
+async fn fetch_whole(
+    origin: &Origin,
+    ticket: &ResolvedFileDownload,
+    final_path: &Path,
+) -> Result<u64, String> {
+    let resp = origin
+        .client
+        .get(&ticket.url)
+        .send()
+        .await
+        .map_err(|e| format!("send: {e}"))?;
+    if !resp.status().is_success() {
+        return Err(format!("status={}", resp.status()));
+    }
+    let blob = origin.read_body(resp).await?;
+    let ticket = ticket.clone();
+    let final_path = final_path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        media_download::verify_stored_bytes(&ticket, &blob)?;
+        let plaintext = attachment_crypto::decrypt_downloaded_attachment_bytes(
+            ENCRYPTION_VERSION_WHOLE,
+            ticket.cek.as_deref(),
+            &blob,
+        )?;
+        let tmp = final_path.with_extension("decrypted.part");
+        fs::write(&tmp, &plaintext)
+            .and_then(|_| fs::rename(&tmp, &final_path))
+            .map_err(|e| {
+                let _ = fs::remove_file(&tmp);
+                format!("write decrypted: {e}")
+            })?;
+        if let (Some(cek), Some(dir)) = (ticket.cek.as_deref(), final_path.parent()) {
+            media_download::write_sealed_cache(
+                dir,
+                media_download::sealed_cache_name(&final_path),
+                &blob,
+                cek,
+                ENCRYPTION_VERSION_WHOLE,
+            );
+        }
+        media_download::adopt_into_blob_store(&ticket, &final_path);
+        Ok(plaintext.len() as u64)
+    })
+    .await
+    .map_err(|e| format!("decrypt whole: {e}"))?
+}
+
+/// 按 `job` 拉块写进 `.stream`。返回 true 表示源站不认 Range、回了整份，全部块一次到齐。
+async fn fetch_blocks(origin: &Origin, job: &BlockFetch) -> Result<bool, String> {
+    let mut resp = origin
+        .client
+        .get(&job.url)
+        .header("Range", format!("bytes={}-{}", job.from, job.to - 1))
+        .send()
+        .await
+        .map_err(|e| format!("send: {e}"))?;
+    // 源站不认 Range 时回 200 + 整份：那就整份收下。
+    let (mut offset, whole) = match resp.status() {
+        StatusCode::PARTIAL_CONTENT => (job.from, false),
+        StatusCode::OK => (0, true),
+        status => return Err(format!("status={status}")),
+    };
+    let expected_end = if whole { job.blob_len } else { job.to };
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(&job.cache_path)
+        .map_err(|e| format!("open stream cache: {e}"))?;
+    file.seek(SeekFrom::Start(offset))
+        .map_err(|e| format!("seek stream cache: {e}"))?;
+    while let Some(chunk) = resp.chunk().await.map_err(|e| format!("chunk: {e}"))? {
+        origin.received(chunk.len() as u64).await;
+        let room = expected_end.saturating_sub(offset) as usize;
+        let take = chunk.len().min(room);
+        file.write_all(&chunk[..take])
+            .map_err(|e| format!("write stream cache: {e}"))?;
+        offset += take as u64;
+    }
+    if offset < expected_end {
+        return Err(format!(
+            "short body: got up to {offset}, want {expected_end}"
+        ));
+    }
+    Ok(whole)
+}
+
+/// 拉 blob 开头 `len` 字节，同时从 `Content-Range` 拿总长。
+async fn fetch_probe(origin: &Origin, url: &str, len: u64) -> Result<(Vec<u8>, u64), String> {
+    let resp = origin
+        .client
+        .get(url)
+        .header("Range", format!("bytes=0-{}", len - 1))
+        .send()
+        .await
+        .map_err(|e| format!("send: {e}"))?;
+    match resp.status() {
+        StatusCode::PARTIAL_CONTENT => {
+            let total = resp
+                .headers()
+                .get(reqwest::header::CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.rsplit_once('/'))
+                .and_then(|(_, total)| total.trim().parse::<u64>().ok())
+                .ok_or_else(|| "missing or unknown Content-Range total".to_string())?;
+            let body = origin.read_body(resp).await?;
+            Ok((body, total))
+        }
+        // 空文件对 `bytes=0-0` 只能回 416；它的总长就是 0。
+        StatusCode::RANGE_NOT_SATISFIABLE => Ok((Vec::new(), 0)),
+        StatusCode::OK => {
+            // 不认 Range 的源站：回的是整份，总长就是 body 长度。探测只要开头。
+            let total = resp.content_length();
+            let mut body = origin.read_body(resp).await?;
+            let total = total.unwrap_or(body.len() as u64);
+            body.truncate(len as usize);
+            Ok((body, total))
+        }
+        status => Err(format!("status={status}")),
+    }
+}
+
+/// 解析单段 `Range: bytes=...`，返回明文区间 `[start, end)`。不可满足（含多段）返回 None。
+pub fn parse_range(value: &str, total: u64) -> Option<(u64, u64)> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    // 播放器不发多段 Range；支持它要回 multipart，不值得。
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    if first.is_empty() {
+        // 后缀形式：最后 n 字节。
+        let n: u64 = last.parse().ok()?;
+        if n == 0 || total == 0 {
+            return None;
+        }
+        return Some((total - n.min(total), total));
+    }
+    let start: u64 = first.parse().ok()?;
+    if start >= total {
+        return None;
+    }
+    let end = if last.is_empty() {
+        total
+    } else {
+        last.parse::<u64>().ok()?.checked_add(1)?.min(total)
+    };
+    (start < end).then_some((start, end))
+}
+
+struct Request {
+    method: String,
+    target: String,
+    headers: HashMap<String, String>,
+}
+
+async fn read_request_head(sock: &mut TcpStream) -> Option<Request> {
+    let mut buf = Vec::new();
+    let mut tmp = [0u8; 1024];
+    loop {
+        let n = sock.read(&mut tmp).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&tmp[..n]);
+        if buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+        if buf.len() > MAX_REQUEST_HEAD {
+            return None;
+        }
+    }
+    let text = String::from_utf8_lossy(&buf);
+    let mut lines = text.split("\r\n");
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?.to_string();
+    let headers = lines
+        .take_while(|l| !l.is_empty())
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().to_string()))
+        .collect();
+    Some(Request {
+        method,
+        target,
+        headers,
+    })
+}
+
+/// `/media/{message_id}?token=...` → message_id。token 不对与消息不存在同样回 404，
+/// 不告诉探测者哪一个错了。
+fn route(target: &str, token: &str) -> Option<u64> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let message_id = path.strip_prefix("/media/")?.parse::<u64>().ok()?;
+    let presented = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))?;
+    tokens_match(presented, token).then_some(message_id)
+}
+
+/// 定长比较：逐字节异或累加，不在第一个不同的字节处提前返回，
+/// 免得探测者按响应耗时一位一位猜出 token。长度是公开的（固定 32 位十六进制）。
+fn tokens_match(presented: &str, token: &str) -> bool {
+    presented.len() == token.len()
+        && presented
+            .bytes()
+            .zip(token.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn write_status(sock: &mut TcpStream, status: &str, extra: &str) {
+    let head =
+        format!("HTTP/1.1 {status}\r\n{extra}Content-Length: 0\r\nConnection: close\r\n\r\n");
+    let _ = sock.write_all(head.as_bytes()).await;
+}
+
+/// 一个连接只处理一个请求，回完即关。播放器对 `Connection: close` 的处理都很成熟，
+/// 省掉 keep-alive 的状态机。
+async fn serve_connection(mut sock: TcpStream, shared: Arc<Shared>) {
+    let Some(req) = read_request_head(&mut sock).await else {
+        return;
+    };
+    let head_only = match req.method.as_str() {
+        "GET" => false,
+        "HEAD" => true,
+        _ => {
+            write_status(&mut sock, "405 Method Not Allowed", "Allow: GET, HEAD\r\n").await;
+            return;
+        }
+    };
+    let entry = route(&req.target, &shared.token).and_then(|id| shared.entries().get(&id).cloned());
+    let Some(entry) = entry else {
+        write_status(&mut sock, "404 Not Found", "").await;
+        return;
+    };
+
+    let prepared = entry.prepare(&shared.origin).await;
+    let total = match prepared {
+        Ok((total, finished)) => {
+            if finished {
+                notify_complete(&shared, &entry).await;
+            }
+            total
+        }
+        Err(e) => {
+            if stream_logs_enabled() {
+                eprintln!(
+                    "[SDK.media_stream] prepare failed message_id={}: {e}",
+                    entry.key.message_id
+                );
+            }
+            write_status(&mut sock, "502 Bad Gateway", "").await;
+            return;
+        }
+    };
+
+    let (start, end, status) = match req.headers.get("range") {
+        Some(range) => match parse_range(range, total) {
+            Some((start, end)) => (start, end, "206 Partial Content"),
+            None => {
+                let extra = format!("Content-Range: bytes */{total}\r\n");
+                write_status(&mut sock, "416 Range Not Satisfiable", &extra).await;
+                return;
+            }
+        },
+        None => (0, total, "200 OK"),
+    };
+    let mut head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {}\r\nAccept-Ranges: bytes\r\nContent-Length: {}\r\n",
+        entry.mime,
+        end - start
+    );
+    if status.starts_with("206") {
+        head.push_str(&format!(
+            "Content-Range: bytes {start}-{}/{total}\r\n",
+            end - 1
+        ));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    if sock.write_all(head.as_bytes()).await.is_err() || head_only {
+        return;
+    }
+
+    let mut pos = start;
+    while pos < end {
+        let window_end = (pos + RESPONSE_WINDOW).min(end);
+        let read = entry.read(&shared.origin, pos, window_end).await;
+        let bytes = match read {
+            Ok((bytes, finished)) => {
+                if finished {
+                    notify_complete(&shared, &entry).await;
+                }
+                bytes
+            }
+            Err(e) => {
+                // 头已经发出去了，只能断开；播放器会按 Range 重新要。
+                if stream_logs_enabled() {
+                    eprintln!(
+                        "[SDK.media_stream] read failed message_id={} range={pos}-{window_end}: {e}",
+                        entry.key.message_id
+                    );
+                }
+                return;
+            }
+        };
+        if sock.write_all(&bytes).await.is_err() {
+            // 播放器拖动进度条会直接关掉旧连接，这是常态。
+            return;
+        }
+        pos = window_end;
+    }
+}
+
+async fn notify_complete(shared: &Shared, entry: &StreamEntry) {
+    let final_path = entry.state.lock().await.final_path.clone();
+    (shared.on_complete)(entry.key.clone(), final_path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_follow_rfc_7233_single_part_forms() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 100)));
+        assert_eq!(parse_range("bytes=900-", 1000), Some((900, 1000)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 1000)));
+        // 末端越界按总长夹住，后缀超长等于整份。
+        assert_eq!(parse_range("bytes=990-5000", 1000), Some((990, 1000)));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some((0, 1000)));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_are_rejected() {
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=5-3", 1000), None);
+        assert_eq!(parse_range("bytes=-0", 1000), None);
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+        assert_eq!(parse_range("bytes=0-", 0), None);
+    }
+
+    #[test]
+    fn only_the_right_token_reaches_a_message() {
+        assert_eq!(route("/media/42?token=abc", "abc"), Some(42));
+        assert_eq!(route("/media/42?x=1&token=abc", "abc"), Some(42));
+        assert_eq!(route("/media/42?token=abd", "abc"), None);
+        assert_eq!(route("/media/42", "abc"), None);
+        assert_eq!(route("/files/42?token=abc", "abc"), None);
+        assert_eq!(route("/media/x?token=abc", "abc"), None);
+    }
+
+    #[test]
+    fn plain_blocks_cover_the_requested_range() {
+        let plan = Plan::Plain {
+            len: PLAIN_BLOCK_SIZE * 2 + 10,
+        };
+        assert_eq!(plan.block_count(), 3);
+        assert_eq!(plan.blocks_for(0, 1), (0, 0));
+        assert_eq!(
+            plan.blocks_for(PLAIN_BLOCK_SIZE - 1, PLAIN_BLOCK_SIZE + 1),
+            (0, 1)
+        );
+        assert_eq!(
+            plan.plaintext_range(2),
+            (PLAIN_BLOCK_SIZE * 2, PLAIN_BLOCK_SIZE * 2 + 10)
+        );
+    }
+
+    /// 最小源站：按 Range 回 blob 的一段，记下每次收到的 Range。
+    async fn origin(blob: Vec<u8>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut sock, _)) = listener.accept().await else {
+                    return;
+                };
+                let Some(req) = read_request_head(&mut sock).await else {
+                    continue;
+                };
+                let range = req.headers.get("range").cloned().unwrap_or_default();
+                log.lock().unwrap().push(range.clone());
+                let total = blob.len() as u64;
+                let (start, end) = parse_range(&range, total).expect("origin range");
+                let head = format!(
+                    "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {start}-{}/{total}\r\nConnection: close\r\n\r\n",
+                    end - start,
+                    end - 1
+                );
+                let _ = sock.write_all(head.as_bytes()).await;
+                let _ = sock.write_all(&blob[start as usize..end as usize]).await;
+            }
+        });
+        (format!("http://{addr}/blob"), seen)
+    }
+
+    async fn get(url: &str, range: Option<&str>) -> (u16, Vec<u8>) {
+        let mut req = reqwest::Client::new().get(url);
+        if let Some(range) = range {
+            req = req.header("Range", range);
+        }
+        let resp = req.send().await.expect("send");
+        let status = resp.status().as_u16();
+        (status, resp.bytes().await.expect("body").to_vec())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn segmented_media_plays_by_range_and_lands_in_the_normal_path() {
+        let plaintext: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let mut blob = Vec::new();
+        let sealed = attachment_crypto::encrypt_attachment_stream_with_segment_size(
+            &plaintext[..],
+            &mut blob,
+            64 * 1024,
+        )
+        .expect("seal");
+        let blob_len = blob.len() as u64;
+        let (url, seen) = origin(blob).await;
+
+        let dir = tempfile::tempdir().expect("tempdir");
+        let final_path = dir.path().join("payload.mp4");
+        let completed = Arc::new(Mutex::new(Vec::new()));
+        let done = completed.clone();
+        let bandwidth = BandwidthLimiter::default();
+        let server = MediaStreamServer::start(
+            &tokio::runtime::Handle::current(),
+            bandwidth.clone(),
+            Arc::new(MetricsRegistry::new()),
+            Arc::new(move |key: MediaTaskKey, path| {
+                done.lock().unwrap().push((key.message_id, path));
+            }),
+        )
+        .expect("start");
+        let play_url = server
+            .register(StreamSource {
+                key: MediaTaskKey::payload("u".to_string(), 1, 7),
+                ticket: ResolvedFileDownload {
+                    url,
+                    encryption_version: ENCRYPTION_VERSION_SEGMENTED,
+                    cek: Some(sealed.cek.clone()),
+                    file_id: None,
+                    expected_size: None,
+                    expected_sha256: None,
+                },
+                final_path: final_path.clone(),
+                mime: "video/mp4".to_string(),
+            })
+            .await;
+
+        // 跨段边界的一小段：只拉覆盖它的那两段，不拉整份。
+        let (status, body) = get(&play_url, Some("bytes=65530-65545")).await;
+        assert_eq!(status, 206);
+        assert_eq!(body, plaintext[65530..65546]);
+        assert!(!final_path.exists());
+        assert!(
+            seen.lock().unwrap().len() <= 2,
+            "probe + one run of segments"
+        );
+
+        // 整份读一遍：剩下的块补齐，成品落盘，回调触发。
+        let (status, body) = get(&play_url, None).await;
+        assert_eq!(status, 200);
+        assert_eq!(body, plaintext);
+        assert_eq!(fs::read(&final_path).expect("final"), plaintext);
+        assert_eq!(
+            completed.lock().unwrap().as_slice(),
+            &[(7, final_path.clone())]
+        );
+        assert!(dir.path().join("body.sealed").exists());
+        // 拉源站的字节和普通下载一样记账。
+        let downloaded: u64 = bandwidth
+            .bytes_by_network()
+            .iter()
+            .map(|b| b.downloaded)
+            .sum();
+        assert!(downloaded >= blob_len);
+
+        // 之后的请求直接读成品，不再碰源站。
+        let before = seen.lock().unwrap().len();
+        let (status, body) = get(&play_url, Some("bytes=-10")).await;
+        assert_eq!(status, 206);
+        assert_eq!(body, plaintext[plaintext.len() - 10..]);
+        assert_eq!(seen.lock().unwrap().len(), before);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn a_wrong_token_or_an_unsatisfiable_range_is_refused() {
+        let (url, _) = origin(vec![1u8; 1000]).await;
+        let dir = tempfile::tempdir().expect("tempdir");
+        let server = MediaStreamServer::start(
+            &tokio::runtime::Handle::current(),
+            BandwidthLimiter::default(),
+            Arc::new(MetricsRegistry::new()),
+            Arc::new(|_: MediaTaskKey, _| {}),
+        )
+        .expect("start");
+        let play_url = server
+            .register(StreamSource {
+                key: MediaTaskKey::payload("u".to_string(), 1, 9),
+                ticket: ResolvedFileDownload::legacy_url(url),
+                final_path: dir.path().join("payload.bin"),
+                mime: "application/octet-stream".to_string(),
+            })
+            .await;
+
+        let forged = format!("http://127.0.0.1:{}/media/9?token=nope", server.port());
+        assert_eq!(get(&forged, None).await.0, 404);
+        assert_eq!(get(&play_url, Some("bytes=1000-")).await.0, 416);
+        let (status, body) = get(&play_url, Some("bytes=10-19")).await;
+        assert_eq!(status, 206);
+        assert_eq!(body, vec![1u8; 10]);
+    }
+
+    /// 到齐的块和下载器一样先过服务端给的大小 / SHA-256：对不上的不落成品，
+    /// 缓存作废，换回对的票据后从头拉一遍就好。
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn a_stream_that_fails_the_server_digest_is_not_published() {
+        use sha2::Digest as _;
+        let blob = vec![7u8; 1000];
+        let (url, _) = origin(blob.clone()).await;
+        let dir = tempfile::tempdir().expect("tempdir");
+        let final_path = dir.path().join("payload.bin");
+        let server = MediaStreamServer::start(
+            &tokio::runtime::Handle::current(),
+            BandwidthLimiter::default(),
+            Arc::new(MetricsRegistry::new()),
+            Arc::new(|_: MediaTaskKey, _| {}),
+        )
+        .expect("start");
+        let source = |sha256: &[u8]| StreamSource {
+            key: MediaTaskKey::payload("u".to_string(), 1, 11),
+            ticket: ResolvedFileDownload {
+                expected_size: Some(blob.len() as u64),
+                expected_sha256: Some(hex::encode(sha2::Sha256::digest(sha256))),
+                ..ResolvedFileDownload::legacy_url(url.clone())
+            },
+            final_path: final_path.clone(),
+            mime: "application/octet-stream".to_string(),
+        };
+
+        let play_url = server.register(source(b"something else")).await;
+        // 头已经发出去了，校验失败只能断开连接。
+        if let Ok(resp) = reqwest::get(&play_url).await {
+            let _ = resp.bytes().await;
+        }
+        assert!(!final_path.exists());
+        assert!(!dir.path().join("payload.bin.stream").exists());
+
+        server.register(source(&blob)).await;
+        let (status, body) = get(&play_url, None).await;
+        assert_eq!(status, 200);
+        assert_eq!(body, blob);
+        assert_eq!(fs::read(&final_path).expect("final"), blob);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn dropping_the_server_closes_open_connections() {
+        let server = MediaStreamServer::start(
+            &tokio::runtime::Handle::current(),
+            BandwidthLimiter::default(),
+            Arc::new(MetricsRegistry::new()),
+            Arc::new(|_: MediaTaskKey, _| {}),
+        )
+        .expect("start");
+        // 连上但不发请求头：连接任务停在读请求头上。
+        let mut sock = TcpStream::connect(("127.0.0.1", server.port()))
+            .await
+            .expect("connect");
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        drop(server);
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(std::time::Duration::from_secs(2), sock.read(&mut buf))
+            .await
+            .expect("connection outlived the server");
+        assert!(matches!(read, Ok(0) | Err(_)));
+    }
+
+    #[test]
+    fn the_stream_cache_never_shares_the_downloaders_part_file() {
+        let state = StreamState::new(
+            ResolvedFileDownload::legacy_url("http://x".to_string()),
+            PathBuf::from("/tmp/m/payload.mp4"),
+        );
+        assert_eq!(state.cache_path, PathBuf::from("/tmp/m/payload.mp4.stream"));
+    }
+
+    #[test]
+    fn a_complete_stream_is_handed_out_for_finishing_once() {
+        let mut state = StreamState::new(
+            ResolvedFileDownload::legacy_url("http://x".to_string()),
+            PathBuf::from("/tmp/m/payload.bin"),
+        );
+        state.plan = Some(Plan::Plain { len: 10 });
+        state.present = vec![true, false];
+        assert!(state.begin_finish().is_none());
+
+        state.present = vec![true, true];
+        let (len, _, cache_path, _) = state.begin_finish().expect("complete");
+        assert_eq!(len, 10);
+        assert_eq!(cache_path, PathBuf::from("/tmp/m/payload.bin.stream"));
+        // 落成品期间（锁已放开）第二个读请求不会再领一次。
+        assert!(state.finishing);
+        assert!(state.begin_finish().is_none());
+    }
+}
@@ -2,6 +2,11 @@ use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::live_location::{
+    self, live_location_view, LiveLocationView, LIVE_LOCATION_MESSAGE_TYPE,
+};
+use crate::poll::{definition_from_message, poll_view, PollView, POLL_MESSAGE_TYPE};
+use crate::sticker::{sticker_from_message, Sticker, STICKER_MESSAGE_TYPE};
 use crate::StoredMessage;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -60,6 +65,19 @@ pub struct MessageContentProjection {
     pub money_amount_text: Option<String>,
     pub money_scene: Option<String>,
     pub money_type: Option<i32>,
+    /// 编辑次数；> 0 时 UI 显示「已编辑」，历史经 `list_message_revisions` 取。
+    #[serde(default)]
+    pub revision_count: u32,
+    /// 投票消息：定义 + 本地票数与自己的选择。
+    #[serde(default)]
+    pub poll: Option<PollView>,
+    /// 实时位置消息：最新位置、结束与过时状态（按投影时的当前时间算）。
+    /// `latitude` / `longitude` 同样是最新位置，只认静态位置的渲染器也能画。
+    #[serde(default)]
+    pub live_location: Option<LiveLocationView>,
+    /// 表情消息引用的表情（`local_path` 不填，本地图经表情目录取）。
+    #[serde(default)]
+    pub sticker: Option<Sticker>,
 }
 
 pub fn project_stored_message(message: &StoredMessage) -> MessageContentProjection {
@@ -77,6 +95,7 @@ pub fn project_stored_message(message: &StoredMessage) -> MessageContentProjecti
         } else {
             mentions
         },
+        revision_count: message.revision_count,
         ..Default::default()
     };
     body.entities = scan_entities(&body.text, &body.mentioned_user_ids);
@@ -134,6 +153,42 @@ pub fn project_stored_message(message: &StoredMessage) -> MessageContentProjecti
             .clone()
             .or_else(|| body.money_summary.clone())
             .unwrap_or_default();
+    } else if message.message_type == LIVE_LOCATION_MESSAGE_TYPE {
+        body.live_location = live_location::definition_from_message(message).map(|definition| {
+            live_location_view(
+                definition,
+                message.live_location.as_ref(),
+                chrono::Utc::now().timestamp_millis(),
+            )
+        });
+        if let Some(view) = &body.live_location {
+            body.latitude = Some(view.latitude);
+            body.longitude = Some(view.longitude);
+            body.coordinate_system = view.coordinate_system.clone();
+        }
+        body.text.clear();
+        body.entities.clear();
+    } else if message.message_type == POLL_MESSAGE_TYPE {
+        body.poll = definition_from_message(message)
+            .map(|definition| poll_view(definition, message.poll.as_ref()));
+        body.text = body
+            .poll
+            .as_ref()
+            .map(|poll| poll.question.clone())
+            .unwrap_or_default();
+        body.entities.clear();
+    } else if message.message_type == STICKER_MESSAGE_TYPE {
+        body.sticker = sticker_from_message(message);
+        if let Some(sticker) = &body.sticker {
+            body.width = Some(sticker.width as i32).filter(|&w| w > 0);
+            body.height = Some(sticker.height as i32).filter(|&h| h > 0);
+        }
+        body.text = body
+            .sticker
+            .as_ref()
+            .map(Sticker::display_text)
+            .unwrap_or_default();
+        body.entities.clear();
     } else if message.message_type == 0 {
         if let Some(value) = string_at(&sources, &["content", "text"]) {
             body.text = value;
@@ -189,6 +244,8 @@ fn kind_name(value: i32) -> &'static str {
         10 => "forward",
         11 => "red_packet",
         12 => "money_transfer",
+        POLL_MESSAGE_TYPE => "poll",
+        LIVE_LOCATION_MESSAGE_TYPE => "live_location",
         _ => "unknown",
     }
 }
@@ -443,6 +500,9 @@ mod caption_projection_tests {
             thumb_status: 1,
             delivered: false,
             pts: None,
+            revision_count: 0,
+            poll: None,
+            live_location: None,
         }
     }
 
@@ -455,6 +515,76 @@ mod caption_projection_tests {
         assert_eq!(body.text, "周末爬山");
     }
 
+    #[test]
+    fn revision_count_is_projected() {
+        let mut m = attachment(r#"{"file_name":"a.jpg","caption":"改过两次"}"#);
+        m.revision_count = 2;
+        assert_eq!(project_stored_message(&m).revision_count, 2);
+    }
+
+    #[test]
+    fn a_poll_projects_its_definition_and_local_tally() {
+        let mut m = received(
+            "午饭吃什么",
+            r#"{"content":"午饭吃什么","metadata":{"question":"午饭吃什么","options":["面","饭"],"multi_select":false,"anonymous":true}}"#,
+        );
+        m.message_type = POLL_MESSAGE_TYPE;
+        m.poll = Some(crate::poll::PollTally {
+            counts: vec![3, 1],
+            voter_count: 4,
+            my_votes: vec![0],
+            ..Default::default()
+        });
+        let body = project_stored_message(&m);
+        assert_eq!(body.kind, "poll");
+        assert_eq!(body.text, "午饭吃什么");
+        let poll = body.poll.expect("poll view");
+        assert!(poll.anonymous);
+        assert_eq!(poll.voter_count, 4);
+        assert_eq!(poll.options[0].vote_count, 3);
+        assert!(poll.options[0].voted && !poll.options[1].voted);
+    }
+
+    #[test]
+    fn a_sticker_projects_the_referenced_sticker() {
+        let mut m = received(
+            "开心",
+            r#"{"content":"开心","metadata":{"sticker_id":"s1","package_id":"cats","image_url":"https://cdn/s1.webp","alt_text":"开心","width":256,"height":256}}"#,
+        );
+        m.message_type = STICKER_MESSAGE_TYPE;
+        let body = project_stored_message(&m);
+        assert_eq!(body.kind, "sticker");
+        assert_eq!(body.text, "开心");
+        assert_eq!(body.width, Some(256));
+        assert_eq!(body.sticker.expect("sticker").sticker_id, "s1");
+    }
+
+    #[test]
+    fn a_live_location_projects_its_latest_position() {
+        let now = chrono::Utc::now().timestamp_millis();
+        let extra = format!(
+            r#"{{"content":"","metadata":{{"latitude":31.2,"longitude":121.5,"started_at":{now},"expires_at":{}}}}}"#,
+            now + 60_000
+        );
+        let mut m = received("", &extra);
+        m.message_type = LIVE_LOCATION_MESSAGE_TYPE;
+        m.live_location = Some(crate::live_location::LiveLocationState {
+            position: Some(crate::live_location::LiveLocationPosition {
+                latitude: 31.25,
+                longitude: 121.55,
+                at: now,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let body = project_stored_message(&m);
+        assert_eq!(body.kind, "live_location");
+        assert_eq!(body.latitude, Some(31.25));
+        let view = body.live_location.expect("live location view");
+        assert!(!view.ended && !view.stale);
+        assert_eq!(view.started_at, now);
+    }
+
     /// 没有说明时仍然不能把附件 JSON 泄露成正文。
     #[test]
     fn without_a_caption_the_json_is_not_exposed() {
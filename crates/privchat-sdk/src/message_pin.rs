@@ -0,0 +1,66 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 会话的置顶消息。
+//!
+//! 本地 `message_pin` 表按 `(channel_type, channel_id, server_message_id)` 记一个会话当前
+//! 置顶了哪些消息、谁置顶的、什么时候。用服务端消息 ID 做键，是因为置顶的消息可能很早，
+//! 本地还没有那条消息行；有的话 `message_extra.is_pinned` 同步维护，时间线照旧用它。
+//!
+//! 数据来源：
+//!
+//! - 服务端置顶列表（`routes::message::PIN_LIST`）：[`crate::PrivchatSdk::refresh_pinned_messages`]，
+//!   群会话的 `sync_channel` 也会拉一次，整会话替换；
+//! - 同步 / 推送里 `message_extra` 实体的 `is_pinned`（带 `pinned_at` / `pinned_by` 时一并记下）；
+//! - 自己置顶 / 取消置顶：本地先改，再作为 outbox 里的 RPC 类命令发出
+//!   （`coalesce_key` = `message_pin:{message_id}`），离线时反复操作只留最后一次。
+//!
+//! 命令还没发出去的消息，服务端列表和同步都不覆盖它的本地状态；命令被服务端拒绝时，
+//! 重拉一次该会话的服务端列表作准。目前服务端只支持群消息置顶（`group_id` = `channel_id`）。
+
+use serde::{Deserialize, Serialize};
+
+pub(crate) const PIN_COMMAND: &str = "message_pin";
+
+/// 同一条消息的未发置顶命令互相覆盖。
+pub(crate) fn pin_coalesce_key(message_id: u64) -> String {
+    format!("{PIN_COMMAND}:{message_id}")
+}
+
+/// 一条置顶，[`crate::PrivchatSdk::list_pinned_messages`] 按 `pinned_at` 倒序返回。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PinnedMessage {
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub server_message_id: u64,
+    /// 本地消息行；置顶的消息还没同步到本地时为空。
+    pub message_id: Option<u64>,
+    /// 置顶人；老服务端的同步数据里可能没有。
+    pub pinned_by: Option<u64>,
+    /// 置顶人显示名，回落顺序同群成员列表（群昵称、备注、昵称、用户名）。
+    pub pinned_by_name: Option<String>,
+    /// 置顶时间（毫秒）。
+    pub pinned_at: i64,
+    /// 自己的置顶还在出站队列里，服务端尚未确认。
+    pub pending: bool,
+}
+
+/// 服务端给的一条置顶（置顶列表的一项、置顶 RPC 的响应）。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RemotePin {
+    pub server_message_id: u64,
+    pub pinned_by: Option<u64>,
+    pub pinned_at: i64,
+}
+
+/// outbox 里置顶命令的 payload。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct MessagePinCommand {
+    pub message_id: u64,
+    pub server_message_id: u64,
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub pinned: bool,
+}
@@ -0,0 +1,512 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 统一指标注册表。
+//!
+//! 之前计数散在各处：`AttachmentTransferStats`、`CANONICAL_*` 两个全局原子量、
+//! `ReceivePipelineStats`（只活在一次 sync 的局部变量里），FFI 层还有自己的
+//! presence/typing 统计。排查「为什么慢」时没有一个地方能一次看全。
+//!
+//! 这里只有三种形态：
+//!
+//! - **counter**：单调递增（RPC 失败数、重连尝试数、下载字节数……）；
+//! - **gauge**：当前值（outbox 深度、最老一条的等待时长）；
+//! - **histogram**：固定桶的分布（每个 route 的 RPC 延迟、每种 [`SyncRunKind`] 的
+//!   同步耗时、下载吞吐）。
+//!
+//! 指标名和 label 都是代码里写死的常量，不接受宿主传入——label 基数失控是指标系统
+//! 最常见的事故，route 已经是上限了。
+//!
+//! [`MetricsSnapshot`] 是纯数据，FFI 直接映射；Prometheus 文本格式渲染在
+//! `prometheus` feature 后面，只有桌面端/daemon 需要。
+//!
+//! [`SyncRunKind`]: crate::sync_coordinator::SyncRunKind
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+/// RPC 往返耗时（秒），label `route`。
+pub const RPC_DURATION_SECONDS: &str = "privchat_rpc_duration_seconds";
+/// RPC 失败次数，label `route` / `kind`（`transport` | `server` | `auth` | `decode`）。
+pub const RPC_ERRORS_TOTAL: &str = "privchat_rpc_errors_total";
+/// 自动重连尝试次数，label `outcome`（`ok` | `fail`）。
+pub const RECONNECT_ATTEMPTS_TOTAL: &str = "privchat_reconnect_attempts_total";
+/// outbox 里还没送达的命令数。
+pub const OUTBOX_DEPTH: &str = "privchat_outbox_depth";
+/// outbox 里最老一条已经等了多久（秒）；空队列为 0。
+pub const OUTBOX_OLDEST_AGE_SECONDS: &str = "privchat_outbox_oldest_age_seconds";
+/// 一轮同步的耗时（秒），label `kind`（`bootstrap` | `resume`）/ `outcome`（`ok` | `error`）。
+pub const SYNC_DURATION_SECONDS: &str = "privchat_sync_duration_seconds";
+/// 接收管线入队的条目数。
+pub const RECEIVE_QUEUED_ITEMS_TOTAL: &str = "privchat_receive_queued_items_total";
+/// 接收管线按去重键丢掉的重复条目数。
+pub const RECEIVE_DROPPED_DUPLICATES_TOTAL: &str = "privchat_receive_dropped_duplicates_total";
+/// 媒体下载收到的字节数（含续传片段）。
+pub const MEDIA_DOWNLOAD_BYTES_TOTAL: &str = "privchat_media_download_bytes_total";
+/// 单次媒体下载的平均吞吐（字节/秒），只统计成功完成的下载。
+pub const MEDIA_DOWNLOAD_THROUGHPUT: &str = "privchat_media_download_throughput_bytes_per_second";
+/// 见 [`crate::AttachmentTransferStats`]，label `kind`（`claim` | `body` | `thumbnail`）。
+pub const ATTACHMENT_TRANSFERS_TOTAL: &str = "privchat_attachment_transfers_total";
+/// canonical 与 legacy 投影不一致的次数。
+pub const CANONICAL_LEGACY_MISMATCH_TOTAL: &str = "privchat_canonical_legacy_mismatch_total";
+/// canonical payload 解码失败次数。
+pub const CANONICAL_DECODE_ERRORS_TOTAL: &str = "privchat_canonical_decode_errors_total";
+/// presence 缓存里的用户数，label `state`（`online` | `offline`）。
+pub const PRESENCE_CACHED_USERS: &str = "privchat_presence_cached_users";
+
+/// RPC 延迟桶（秒）。
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+/// 同步耗时桶（秒）。首装 bootstrap 几十秒是正常的，所以上限放到 5 分钟。
+pub const SYNC_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+/// 下载吞吐桶（字节/秒）：16 KiB/s 到 64 MiB/s，×4 递增。
+pub const THROUGHPUT_BUCKETS: &[f64] = &[
+    16_384.0,
+    65_536.0,
+    262_144.0,
+    1_048_576.0,
+    4_194_304.0,
+    16_777_216.0,
+    67_108_864.0,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+/// 每个指标的类型与说明。渲染器靠它输出 `# HELP` / `# TYPE`。
+const DEFINITIONS: &[(&str, MetricKind, &str)] = &[
+    (
+        RPC_DURATION_SECONDS,
+        MetricKind::Histogram,
+        "RPC round-trip latency in seconds.",
+    ),
+    (
+        RPC_ERRORS_TOTAL,
+        MetricKind::Counter,
+        "RPC calls that failed, by route and failure kind.",
+    ),
+    (
+        RECONNECT_ATTEMPTS_TOTAL,
+        MetricKind::Counter,
+        "Automatic reconnect attempts, by outcome.",
+    ),
+    (
+        OUTBOX_DEPTH,
+        MetricKind::Gauge,
+        "Commands waiting in the outbox.",
+    ),
+    (
+        OUTBOX_OLDEST_AGE_SECONDS,
+        MetricKind::Gauge,
+        "Age of the oldest outbox command in seconds.",
+    ),
+    (
+        SYNC_DURATION_SECONDS,
+        MetricKind::Histogram,
+        "Sync run duration in seconds, by run kind and outcome.",
+    ),
+    (
+        RECEIVE_QUEUED_ITEMS_TOTAL,
+        MetricKind::Counter,
+        "Sync entity items queued by the receive pipeline.",
+    ),
+    (
+        RECEIVE_DROPPED_DUPLICATES_TOTAL,
+        MetricKind::Counter,
+        "Sync entity items dropped by the receive pipeline as duplicates.",
+    ),
+    (
+        MEDIA_DOWNLOAD_BYTES_TOTAL,
+        MetricKind::Counter,
+        "Bytes received by media downloads.",
+    ),
+    (
+        MEDIA_DOWNLOAD_THROUGHPUT,
+        MetricKind::Histogram,
+        "Average throughput of completed media downloads in bytes per second.",
+    ),
+    (
+        ATTACHMENT_TRANSFERS_TOTAL,
+        MetricKind::Counter,
+        "Attachment transfers, by kind (instant claim, body upload, thumbnail upload).",
+    ),
+    (
+        CANONICAL_LEGACY_MISMATCH_TOTAL,
+        MetricKind::Counter,
+        "Canonical and legacy projections that disagreed.",
+    ),
+    (
+        CANONICAL_DECODE_ERRORS_TOTAL,
+        MetricKind::Counter,
+        "Canonical payloads that failed to decode.",
+    ),
+    (
+        PRESENCE_CACHED_USERS,
+        MetricKind::Gauge,
+        "Users in the presence cache, by online state.",
+    ),
+];
+
+/// 指标说明；未登记的名字返回空串。
+pub fn metric_help(name: &str) -> &'static str {
+    DEFINITIONS
+        .iter()
+        .find(|(n, _, _)| *n == name)
+        .map(|(_, _, help)| *help)
+        .unwrap_or("")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetricLabel {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CounterSample {
+    pub name: String,
+    pub labels: Vec<MetricLabel>,
+    pub value: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GaugeSample {
+    pub name: String,
+    pub labels: Vec<MetricLabel>,
+    pub value: f64,
+}
+
+/// 一个桶：`value <= upper_bound` 的累计观测数（Prometheus `le` 语义）。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistogramBucket {
+    pub upper_bound: f64,
+    pub cumulative_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistogramSample {
+    pub name: String,
+    pub labels: Vec<MetricLabel>,
+    /// 不含 `+Inf`；`+Inf` 桶就是 `count`。
+    pub buckets: Vec<HistogramBucket>,
+    pub count: u64,
+    pub sum: f64,
+}
+
+/// [`crate::PrivchatSdk::metrics_snapshot`] 的返回值。各列表按 (name, labels) 排序，
+/// 两次快照可以逐项对比。
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    pub captured_at_ms: i64,
+    pub counters: Vec<CounterSample>,
+    pub gauges: Vec<GaugeSample>,
+    pub histograms: Vec<HistogramSample>,
+}
+
+type SeriesKey = (&'static str, Vec<(&'static str, String)>);
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+#[derive(Debug, Default)]
+struct Series {
+    counters: BTreeMap<SeriesKey, u64>,
+    gauges: BTreeMap<SeriesKey, f64>,
+    histograms: BTreeMap<SeriesKey, Histogram>,
+}
+
+/// 进程内的指标注册表，`PrivchatSdk` 和 actor 共享同一个 `Arc`。
+///
+/// 一把锁足够：写入点都在 RPC/同步/下载这种毫秒级操作的尾巴上，不在热循环里。
+#[derive(Debug, Default)]
+pub struct MetricsRegistry {
+    series: Mutex<Series>,
+}
+
+fn series_key(name: &'static str, labels: &[(&'static str, &str)]) -> SeriesKey {
+    (
+        name,
+        labels
+            .iter()
+            .map(|(k, v)| (*k, (*v).to_string()))
+            .collect(),
+    )
+}
+
+fn sample_labels(labels: &[(&'static str, String)]) -> Vec<MetricLabel> {
+    labels
+        .iter()
+        .map(|(name, value)| MetricLabel {
+            name: (*name).to_string(),
+            value: value.clone(),
+        })
+        .collect()
+}
+
+impl MetricsRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn inc_counter(&self, name: &'static str, labels: &[(&'static str, &str)], by: u64) {
+        let mut series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        let value = series.counters.entry(series_key(name, labels)).or_insert(0);
+        *value = value.saturating_add(by);
+    }
+
+    /// 把外部维护的单调计数（全局原子量、附件计数）镜像进来。只在快照前调用。
+    pub(crate) fn mirror_counter(
+        &self,
+        name: &'static str,
+        labels: &[(&'static str, &str)],
+        value: u64,
+    ) {
+        let mut series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        series.counters.insert(series_key(name, labels), value);
+    }
+
+    pub(crate) fn set_gauge(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        let mut series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        series.gauges.insert(series_key(name, labels), value);
+    }
+
+    /// 记一次观测。`bounds` 必须升序；同一条序列第一次观测时定下的桶就是它以后的桶。
+    pub(crate) fn observe(
+        &self,
+        name: &'static str,
+        labels: &[(&'static str, &str)],
+        bounds: &'static [f64],
+        value: f64,
+    ) {
+        if !value.is_finite() {
+            return;
+        }
+        let mut series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        let histogram = series
+            .histograms
+            .entry(series_key(name, labels))
+            .or_insert_with(|| Histogram {
+                bounds,
+                counts: vec![0; bounds.len()],
+                count: 0,
+                sum: 0.0,
+            });
+        if let Some(idx) = histogram.bounds.iter().position(|b| value <= *b) {
+            histogram.counts[idx] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += value;
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        let counters = series
+            .counters
+            .iter()
+            .map(|((name, labels), value)| CounterSample {
+                name: (*name).to_string(),
+                labels: sample_labels(labels),
+                value: *value,
+            })
+            .collect();
+        let gauges = series
+            .gauges
+            .iter()
+            .map(|((name, labels), value)| GaugeSample {
+                name: (*name).to_string(),
+                labels: sample_labels(labels),
+                value: *value,
+            })
+            .collect();
+        let histograms = series
+            .histograms
+            .iter()
+            .map(|((name, labels), h)| {
+                let mut cumulative = 0u64;
+                let buckets = h
+                    .bounds
+                    .iter()
+                    .zip(&h.counts)
+                    .map(|(bound, count)| {
+                        cumulative += count;
+                        HistogramBucket {
+                            upper_bound: *bound,
+                            cumulative_count: cumulative,
+                        }
+                    })
+                    .collect();
+                HistogramSample {
+                    name: (*name).to_string(),
+                    labels: sample_labels(labels),
+                    buckets,
+                    count: h.count,
+                    sum: h.sum,
+                }
+            })
+            .collect();
+        MetricsSnapshot {
+            captured_at_ms: chrono::Utc::now().timestamp_millis(),
+            counters,
+            gauges,
+            histograms,
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(feature = "prometheus")]
+fn render_labels(labels: &[MetricLabel], extra: Option<(&str, String)>) -> String {
+    let mut parts: Vec<String> = labels
+        .iter()
+        .map(|l| format!("{}=\"{}\"", l.name, escape_label_value(&l.value)))
+        .collect();
+    if let Some((name, value)) = extra {
+        parts.push(format!("{name}=\"{value}\""));
+    }
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", parts.join(","))
+    }
+}
+
+#[cfg(feature = "prometheus")]
+fn render_header(out: &mut String, last: &mut Option<String>, name: &str, kind: &str) {
+    use std::fmt::Write;
+    if last.as_deref() == Some(name) {
+        return;
+    }
+    let help = metric_help(name);
+    if !help.is_empty() {
+        let _ = writeln!(out, "# HELP {name} {help}");
+    }
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    *last = Some(name.to_string());
+}
+
+/// 渲染成 Prometheus text exposition format（0.0.4）。
+///
+/// 只给桌面端/daemon 用：移动端没有 scrape 方，直接读 [`MetricsSnapshot`] 就够了。
+#[cfg(feature = "prometheus")]
+pub fn render_prometheus(snapshot: &MetricsSnapshot) -> String {
+    use std::fmt::Write;
+    let mut out = String::new();
+    let mut last = None;
+    for c in &snapshot.counters {
+        render_header(&mut out, &mut last, &c.name, "counter");
+        let _ = writeln!(out, "{}{} {}", c.name, render_labels(&c.labels, None), c.value);
+    }
+    for g in &snapshot.gauges {
+        render_header(&mut out, &mut last, &g.name, "gauge");
+        let _ = writeln!(out, "{}{} {}", g.name, render_labels(&g.labels, None), g.value);
+    }
+    for h in &snapshot.histograms {
+        render_header(&mut out, &mut last, &h.name, "histogram");
+        for bucket in &h.buckets {
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                h.name,
+                render_labels(&h.labels, Some(("le", bucket.upper_bound.to_string()))),
+                bucket.cumulative_count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{} {}",
+            h.name,
+            render_labels(&h.labels, Some(("le", "+Inf".to_string()))),
+            h.count
+        );
+        let _ = writeln!(out, "{}_sum{} {}", h.name, render_labels(&h.labels, None), h.sum);
+        let _ = writeln!(out, "{}_count{} {}", h.name, render_labels(&h.labels, None), h.count);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_accumulate_per_label_set() {
+        let registry = MetricsRegistry::new();
+        registry.inc_counter(RPC_ERRORS_TOTAL, &[("route", "a"), ("kind", "server")], 1);
+        registry.inc_counter(RPC_ERRORS_TOTAL, &[("route", "a"), ("kind", "server")], 2);
+        registry.inc_counter(RPC_ERRORS_TOTAL, &[("route", "b"), ("kind", "server")], 1);
+
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot.counters.len(), 2);
+        assert_eq!(snapshot.counters[0].labels[0].value, "a");
+        assert_eq!(snapshot.counters[0].value, 3);
+        assert_eq!(snapshot.counters[1].value, 1);
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative_and_overflow_only_counts() {
+        let registry = MetricsRegistry::new();
+        for v in [0.003, 0.04, 0.04, 99.0] {
+            registry.observe(RPC_DURATION_SECONDS, &[("route", "r")], LATENCY_BUCKETS, v);
+        }
+        registry.observe(RPC_DURATION_SECONDS, &[("route", "r")], LATENCY_BUCKETS, f64::NAN);
+
+        let h = &registry.snapshot().histograms[0];
+        assert_eq!(h.count, 4);
+        assert_eq!(h.buckets[0].cumulative_count, 1); // le=0.005
+        assert_eq!(h.buckets[3].cumulative_count, 3); // le=0.05
+        assert_eq!(h.buckets.last().unwrap().cumulative_count, 3); // 99s 只进 +Inf
+        assert!((h.sum - 99.083).abs() < 1e-9);
+    }
+
+    #[test]
+    fn mirrored_counters_and_gauges_overwrite() {
+        let registry = MetricsRegistry::new();
+        registry.mirror_counter(CANONICAL_DECODE_ERRORS_TOTAL, &[], 5);
+        registry.mirror_counter(CANONICAL_DECODE_ERRORS_TOTAL, &[], 7);
+        registry.set_gauge(OUTBOX_DEPTH, &[], 3.0);
+        registry.set_gauge(OUTBOX_DEPTH, &[], 0.0);
+
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot.counters[0].value, 7);
+        assert_eq!(snapshot.gauges[0].value, 0.0);
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn prometheus_text_has_one_header_per_family() {
+        let registry = MetricsRegistry::new();
+        registry.inc_counter(RPC_ERRORS_TOTAL, &[("route", "a\"b"), ("kind", "server")], 1);
+        registry.inc_counter(RPC_ERRORS_TOTAL, &[("route", "c"), ("kind", "auth")], 1);
+        registry.observe(SYNC_DURATION_SECONDS, &[("kind", "resume")], SYNC_BUCKETS, 0.2);
+
+        let text = render_prometheus(&registry.snapshot());
+        assert_eq!(text.matches("# TYPE privchat_rpc_errors_total counter").count(), 1);
+        assert!(text.contains("privchat_rpc_errors_total{route=\"a\\\"b\",kind=\"server\"} 1"));
+        assert!(text.contains(
+            "privchat_sync_duration_seconds_bucket{kind=\"resume\",le=\"0.25\"} 1"
+        ));
+        assert!(text.contains("privchat_sync_duration_seconds_bucket{kind=\"resume\",le=\"+Inf\"} 1"));
+        assert!(text.contains("privchat_sync_duration_seconds_count{kind=\"resume\"} 1"));
+    }
+}
@@ -0,0 +1,359 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 投票消息。
+//!
+//! 投票本身是一条普通消息（`message_type` = [`POLL_MESSAGE_TYPE`]）：题目、选项、单选 / 多选、
+//! 匿名、截止时间放在消息 metadata 里（[`PollDefinition`]），随消息发出、同步，发出后不变。
+//! 会变的部分在本地 `poll_state` 表里，按消息行关联（[`PollTally`]）：
+//!
+//! - 各选项票数、投票人数、是否已截止——**只认服务端给的数**。来源是投票 RPC 的响应、
+//!   `routes::message_poll::GET`、以及推送 / 同步里的 `message_poll` 实体，带版本号的按版本单调应用；
+//! - 自己投了哪几项——本地先记下（`vote_pending`），再由出站队列发出。
+//!
+//! 票数不做乐观加减：乐观计数一旦和服务端对不上（别人同时在投、命令被拒），就没有可靠的
+//! 办法退回正确值；界面用 `voted` + `vote_pending` 表达「我选了、还在发」就够了。
+//!
+//! 投票 / 撤票是 outbox 里的 RPC 类命令（`message_id` 为空，`coalesce_key` =
+//! `poll_vote:{message_id}`）：离线时连改几次只留最后一次，重连后由出站队列按退避发出；
+//! 记下选择与入队在同一事务里。
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use privchat_protocol::message::ContentMessageType;
+use privchat_protocol::rpc::routes;
+
+use crate::{Error, Result, StoredMessage};
+
+/// 投票消息的 `message_type`。
+pub const POLL_MESSAGE_TYPE: i32 = ContentMessageType::Poll as i32;
+/// 一个投票最多几个选项。
+pub const MAX_POLL_OPTIONS: usize = 10;
+
+pub(crate) const VOTE_COMMAND: &str = "poll_vote";
+pub(crate) const ROUTE_VOTE: &str = routes::message_poll::VOTE;
+pub(crate) const ROUTE_GET: &str = routes::message_poll::GET;
+
+/// 同一个投票的未发投票命令互相覆盖。
+pub(crate) fn vote_coalesce_key(message_id: u64) -> String {
+    format!("{VOTE_COMMAND}:{message_id}")
+}
+
+/// 投票的定义，即消息 metadata 的形状。
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PollDefinition {
+    pub question: String,
+    pub options: Vec<String>,
+    #[serde(default)]
+    pub multi_select: bool,
+    #[serde(default)]
+    pub anonymous: bool,
+    /// 截止时间（毫秒）；`None` 表示发起人手动结束前一直开放。
+    #[serde(default)]
+    pub close_at: Option<i64>,
+}
+
+impl PollDefinition {
+    /// 校验并规整一个新投票：题目非空，2..=[`MAX_POLL_OPTIONS`] 个互不相同的非空选项。
+    pub(crate) fn normalized(
+        question: &str,
+        options: &[String],
+        multi_select: bool,
+        anonymous: bool,
+        close_at: Option<i64>,
+    ) -> Result<Self> {
+        let question = question.trim();
+        if question.is_empty() {
+            return Err(Error::InvalidArgument("poll question is empty".to_string()));
+        }
+        let options: Vec<String> = options.iter().map(|o| o.trim().to_string()).collect();
+        if options.len() < 2 || options.len() > MAX_POLL_OPTIONS {
+            return Err(Error::InvalidArgument(format!(
+                "a poll needs 2 to {MAX_POLL_OPTIONS} options, got {}",
+                options.len()
+            )));
+        }
+        for (index, option) in options.iter().enumerate() {
+            if option.is_empty() {
+                return Err(Error::InvalidArgument(format!(
+                    "poll option {index} is empty"
+                )));
+            }
+            if options[..index].contains(option) {
+                return Err(Error::InvalidArgument(format!(
+                    "poll option {index} duplicates an earlier option"
+                )));
+            }
+        }
+        Ok(Self {
+            question: question.to_string(),
+            options,
+            multi_select,
+            anonymous,
+            close_at: close_at.filter(|&at| at > 0),
+        })
+    }
+
+    /// 从消息 metadata 里认出投票定义；缺题目或选项就不是。
+    pub(crate) fn from_value(value: &Value) -> Option<Self> {
+        let obj = value.as_object()?;
+        let question = obj.get("question")?.as_str()?.to_string();
+        let options: Vec<String> = obj
+            .get("options")?
+            .as_array()?
+            .iter()
+            .filter_map(|o| o.as_str().map(str::to_string))
+            .collect();
+        if options.is_empty() {
+            return None;
+        }
+        Some(Self {
+            question,
+            options,
+            multi_select: obj
+                .get("multi_select")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            anonymous: obj
+                .get("anonymous")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            close_at: obj.get("close_at").and_then(Value::as_i64),
+        })
+    }
+
+    pub fn is_past_close(&self, now_ms: i64) -> bool {
+        self.close_at.is_some_and(|at| at <= now_ms)
+    }
+
+    /// 校验一次选择并规整成升序去重的下标。空选择表示撤票。
+    pub(crate) fn validate_choice(&self, option_indexes: &[u32]) -> Result<Vec<u32>> {
+        let mut choice = option_indexes.to_vec();
+        choice.sort_unstable();
+        choice.dedup();
+        if let Some(&bad) = choice.iter().find(|&&i| i as usize >= self.options.len()) {
+            return Err(Error::InvalidArgument(format!(
+                "poll option {bad} out of range (0..{})",
+                self.options.len()
+            )));
+        }
+        if !self.multi_select && choice.len() > 1 {
+            return Err(Error::InvalidArgument(
+                "single-choice poll accepts one option".to_string(),
+            ));
+        }
+        Ok(choice)
+    }
+}
+
+/// 投票消息的定义：在消息信封的 metadata 里（`extra`），老数据可能直接在 `content` 里。
+pub(crate) fn definition_from_message(message: &StoredMessage) -> Option<PollDefinition> {
+    if message.message_type != POLL_MESSAGE_TYPE {
+        return None;
+    }
+    [message.extra.as_str(), message.content.as_str()]
+        .iter()
+        .find_map(|raw| {
+            let value: Value = serde_json::from_str(raw).ok()?;
+            value
+                .get("metadata")
+                .and_then(PollDefinition::from_value)
+                .or_else(|| PollDefinition::from_value(&value))
+        })
+}
+
+/// 投票在本地的可变状态。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PollTally {
+    /// 各选项票数，按选项下标；比选项少的部分按 0 计。
+    pub counts: Vec<u32>,
+    pub voter_count: u32,
+    /// 自己当前的选择（可能还在出站队列里）。
+    pub my_votes: Vec<u32>,
+    /// `my_votes` 还没被服务端确认。
+    pub vote_pending: bool,
+    pub closed: bool,
+    /// 服务端票数版本；0 表示服务端没给版本。
+    pub version: u64,
+}
+
+/// 服务端下发的一次票数：投票 RPC 的响应、`routes::message_poll::GET`、推送 / 同步的 `message_poll`。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PollTallyUpdate {
+    #[serde(default)]
+    pub counts: Vec<u32>,
+    #[serde(default)]
+    pub voter_count: u32,
+    #[serde(default)]
+    pub closed: bool,
+    #[serde(default)]
+    pub version: u64,
+    /// 自己的选择。只有发给自己的响应里有；别人投票触发的推送里没有。
+    #[serde(default)]
+    pub my_votes: Option<Vec<u32>>,
+}
+
+impl PollTallyUpdate {
+    /// 从同步实体的 payload 里取票数；没有票数字段就不是一次票数更新。
+    pub(crate) fn from_payload(payload: &Value) -> Option<Self> {
+        let counts = ["counts", "option_counts", "tallies"]
+            .iter()
+            .find_map(|key| payload.get(*key).and_then(Value::as_array))?
+            .iter()
+            .map(|v| v.as_u64().unwrap_or(0).min(u32::MAX as u64) as u32)
+            .collect();
+        let u64_field = |keys: &[&str]| keys.iter().find_map(|k| payload.get(*k)?.as_u64());
+        Some(Self {
+            counts,
+            voter_count: u64_field(&["voter_count", "total_voters"]).unwrap_or(0) as u32,
+            closed: payload
+                .get("closed")
+                .or_else(|| payload.get("is_closed"))
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            version: u64_field(&["version", "tally_version"]).unwrap_or(0),
+            my_votes: payload.get("my_votes").and_then(Value::as_array).map(|a| {
+                a.iter()
+                    .filter_map(|v| v.as_u64().map(|i| i as u32))
+                    .collect()
+            }),
+        })
+    }
+}
+
+/// outbox 里一条投票命令的 payload。`option_indexes` 为空表示撤票。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct PollVoteCommand {
+    /// 本地消息行 id。
+    pub message_id: u64,
+    pub server_message_id: u64,
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub option_indexes: Vec<u32>,
+}
+
+/// `routes::message_poll::VOTE` 请求。`command_id` 供服务端去重，重试不变。
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct PollVoteRequest {
+    pub server_message_id: u64,
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub option_indexes: Vec<u32>,
+    pub command_id: String,
+}
+
+/// `routes::message_poll::GET` 请求。
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct PollGetRequest {
+    pub server_message_id: u64,
+    pub channel_id: u64,
+    pub channel_type: i32,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PollOptionView {
+    pub text: String,
+    pub vote_count: u32,
+    /// 自己选了这一项。
+    pub voted: bool,
+}
+
+/// 投影给 UI 的投票：定义 + 本地票数。
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PollView {
+    pub question: String,
+    pub options: Vec<PollOptionView>,
+    pub multi_select: bool,
+    pub anonymous: bool,
+    pub close_at: Option<i64>,
+    /// 服务端说已截止。`close_at` 已过而服务端还没下发时由 UI 自己比较。
+    pub closed: bool,
+    pub voter_count: u32,
+    pub vote_pending: bool,
+}
+
+pub(crate) fn poll_view(definition: PollDefinition, tally: Option<&PollTally>) -> PollView {
+    let empty = PollTally::default();
+    let tally = tally.unwrap_or(&empty);
+    let options = definition
+        .options
+        .into_iter()
+        .enumerate()
+        .map(|(index, text)| PollOptionView {
+            text,
+            vote_count: tally.counts.get(index).copied().unwrap_or(0),
+            voted: tally.my_votes.contains(&(index as u32)),
+        })
+        .collect();
+    PollView {
+        question: definition.question,
+        options,
+        multi_select: definition.multi_select,
+        anonymous: definition.anonymous,
+        close_at: definition.close_at,
+        closed: tally.closed,
+        voter_count: tally.voter_count,
+        vote_pending: tally.vote_pending,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn definitions_are_validated_and_trimmed() {
+        let poll =
+            PollDefinition::normalized(" lunch? ", &options(&["a ", "b"]), false, false, None)
+                .expect("valid poll");
+        assert_eq!(poll.question, "lunch?");
+        assert_eq!(poll.options, options(&["a", "b"]));
+        assert!(PollDefinition::normalized("q", &options(&["a"]), false, false, None).is_err());
+        assert!(
+            PollDefinition::normalized("q", &options(&["a", "a"]), false, false, None).is_err()
+        );
+        assert!(
+            PollDefinition::normalized(" ", &options(&["a", "b"]), false, false, None).is_err()
+        );
+    }
+
+    #[test]
+    fn single_choice_polls_reject_several_options() {
+        let poll = PollDefinition::normalized("q", &options(&["a", "b", "c"]), false, false, None)
+            .unwrap();
+        assert_eq!(poll.validate_choice(&[1, 1]).unwrap(), vec![1]);
+        assert!(poll.validate_choice(&[0, 2]).is_err());
+        assert!(poll.validate_choice(&[3]).is_err());
+        assert!(poll.validate_choice(&[]).unwrap().is_empty());
+        let multi = PollDefinition {
+            multi_select: true,
+            ..poll
+        };
+        assert_eq!(multi.validate_choice(&[2, 0]).unwrap(), vec![0, 2]);
+    }
+
+    #[test]
+    fn view_pads_missing_counts_and_marks_own_votes() {
+        let poll =
+            PollDefinition::normalized("q", &options(&["a", "b", "c"]), true, false, None).unwrap();
+        let tally = PollTally {
+            counts: vec![4, 1],
+            voter_count: 5,
+            my_votes: vec![1],
+            vote_pending: true,
+            ..Default::default()
+        };
+        let view = poll_view(poll, Some(&tally));
+        let counts: Vec<u32> = view.options.iter().map(|o| o.vote_count).collect();
+        assert_eq!(counts, vec![4, 1, 0]);
+        assert!(view.options[1].voted && !view.options[0].voted);
+        assert!(view.vote_pending);
+    }
+}
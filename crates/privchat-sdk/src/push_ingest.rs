@@ -0,0 +1,485 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 系统推送的轻量落库入口。
+//!
+//! 通知扩展（iOS Notification Service Extension）、Android 的后台 worker 只有几十 MB
+//! 内存和几秒钟：为了渲染一条通知把 `PrivchatSdk` 整个拉起来——actor、transport、
+//! 重连、bootstrap 同步——既装不下也跑不完。[`PushIngestor`] 只打开账号的本地 SQLite：
+//!
+//! - 不连网，不起 actor，不碰 sled（账号 kv 由主进程独占加锁，扩展进程打不开）；
+//! - 推送 payload 走与长连接 push 同一个 [`CanonicalInboundMessage::from_push`] 适配器和
+//!   同一条投影，主进程之后从 sync 再收到这条消息时按 `server_message_id` 去重，不会
+//!   多出一行，也不会把未读再加一次；
+//! - 返回一条可以直接渲染的 [`PushNotification`]：发送者显示名的回落顺序与群成员列表
+//!   一致，正文按消息类型给预览，带会话的免打扰状态（和角标同一条规则）和这条是否
+//!   @ 了自己；[`NotificationPrivacy`] 决定哪些字段留空。
+//!
+//! 隐私模式下被隐藏的字段是 `None`，不是某句写死的占位文案——占位要跟宿主的语言走。
+
+use std::path::PathBuf;
+
+use privchat_protocol::PushMessageRequest;
+use serde::{Deserialize, Serialize};
+
+use crate::canonical_inbound::CanonicalInboundMessage;
+use crate::local_store::LocalStore;
+use crate::message_content::{project_stored_message, MessageContentProjection};
+use crate::unread_badge::notification_mode;
+use crate::{Result, State};
+
+/// 通知里露出多少内容。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NotificationPrivacy {
+    /// 会话名、发送者、正文预览都显示。
+    #[default]
+    Full,
+    /// 显示会话名和发送者，不显示正文和消息类型。
+    HideContent,
+    /// 什么都不显示，宿主只提示「有新消息」。
+    HideAll,
+}
+
+/// 推送里携带的一条消息，字段与长连接的 `PushMessageRequest` 一一对应。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushPayload {
+    pub server_message_id: u64,
+    pub local_message_id: u64,
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub from_uid: u64,
+    pub message_type: i32,
+    /// 与 `PushMessageRequest.payload` 同一份字节（消息 envelope）。
+    pub payload: Vec<u8>,
+    pub message_seq: i64,
+    /// **秒**，与长连接 push 同单位。
+    pub timestamp: i64,
+    /// 撤回。
+    pub deleted: bool,
+}
+
+impl From<&PushMessageRequest> for PushPayload {
+    fn from(push: &PushMessageRequest) -> Self {
+        Self {
+            server_message_id: push.server_message_id,
+            local_message_id: push.local_message_id,
+            channel_id: push.channel_id,
+            channel_type: i32::from(push.channel_type),
+            from_uid: push.from_uid,
+            message_type: i32::try_from(push.message_type).unwrap_or(0),
+            payload: push.payload.clone(),
+            message_seq: i64::from(push.message_seq),
+            timestamp: i64::from(push.timestamp),
+            deleted: push.deleted,
+        }
+    }
+}
+
+/// 一条可以直接交给系统通知中心的通知。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushNotification {
+    /// 本地 `message.id`，点通知跳转用。
+    pub message_id: u64,
+    pub server_message_id: u64,
+    pub channel_id: u64,
+    pub channel_type: i32,
+    /// 私聊是发送者显示名，群是群名（本地还没有群实体时为 `None`）。
+    /// [`NotificationPrivacy::HideAll`] 下为 `None`。
+    pub title: Option<String>,
+    /// [`NotificationPrivacy::HideAll`] 下为 `None`。
+    pub sender_name: Option<String>,
+    /// 正文预览：文本消息是正文，附件是说明文字（文件名、链接标题……），没有说明时为
+    /// `None`。不含「[图片]」这类类型占位——宿主按 `message_kind` 用自己的语言拼上。
+    /// 只有 [`NotificationPrivacy::Full`] 才有。
+    pub body: Option<String>,
+    /// `text` / `image` / `voice` …，宿主据此换本地化的类型文案；只有
+    /// [`NotificationPrivacy::Full`] 才有。
+    pub message_kind: Option<String>,
+    /// 会话是否静音：通知模式优先，`DEFAULT` 时跟随会话的 `mute`，见
+    /// [`crate::unread_badge::channel_is_muted`]。静音的会话仍然落库、计未读。
+    pub channel_muted: bool,
+    /// 会话通知模式，取值见 [`crate::unread_badge::notification_mode`]。
+    pub notification_mode: i32,
+    /// 这条消息 @ 了当前账号。
+    pub mentions_me: bool,
+    /// 自己在另一台设备上发的消息：落库，但不弹通知。
+    pub from_self: bool,
+    /// 这条消息落库之后该会话的未读数。
+    pub channel_unread_count: i32,
+    /// 角标数，可直接作为通知扩展里的角标。与主进程的 `UnreadAggregate::badge_count`
+    /// 同一条规则（通知模式、mute、低优先级、未读 @），见 [`crate::unread_badge`]。
+    pub badge_count: i32,
+}
+
+impl PushNotification {
+    /// 是否应当弹出/响铃：静音的会话（mute、只提醒 @）只在 @ 了自己时提醒，
+    /// 不提醒模式下一律不响。
+    pub fn should_alert(&self) -> bool {
+        if self.from_self || self.notification_mode == notification_mode::NONE {
+            return false;
+        }
+        !self.channel_muted || self.mentions_me
+    }
+}
+
+/// 落库并渲染通知时需要的会话/发送者信息。
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PushNotificationContext {
+    pub sender_name: String,
+    pub channel_title: Option<String>,
+    pub channel_muted: bool,
+    pub notification_mode: i32,
+    pub channel_unread_count: i32,
+}
+
+/// 不连网、不起 actor 的推送落库入口。见模块文档。
+pub struct PushIngestor {
+    store: LocalStore,
+    uid: String,
+}
+
+impl PushIngestor {
+    /// 打开 `data_dir`（与 `PrivchatConfig.data_dir` 相同）下 `uid` 账号的本地库。
+    pub fn open(data_dir: impl Into<PathBuf>, uid: &str) -> Result<Self> {
+        let store = LocalStore::open_at(data_dir.into())?;
+        store.ensure_user_storage(uid)?;
+        Ok(Self {
+            store,
+            uid: uid.to_string(),
+        })
+    }
+
+    /// 落一条推送，返回要展示的通知。
+    ///
+    /// 返回 `None`：这条消息本地已经有了（主进程或上一条推送先到），或者是撤回/无效推送——
+    /// 都不该再弹一次。撤回推送会顺手把本地那条标成已撤回。
+    pub fn ingest(
+        &self,
+        push: &PushPayload,
+        privacy: NotificationPrivacy,
+    ) -> Result<Option<PushNotification>> {
+        if push.server_message_id == 0 || push.channel_id == 0 {
+            return Ok(None);
+        }
+        let uid = self.uid.as_str();
+        if push.deleted {
+            self.store.set_message_revoke_by_server_message_id(
+                uid,
+                push.server_message_id,
+                true,
+                None,
+            )?;
+            return Ok(None);
+        }
+        let (content, extra) = State::payload_bytes_to_message_content_and_extra(&push.payload);
+        let canonical = CanonicalInboundMessage::from_push(
+            push.server_message_id,
+            push.local_message_id,
+            push.channel_id,
+            push.channel_type,
+            push.from_uid,
+            push.message_type,
+            content,
+            extra.unwrap_or_default(),
+            push.message_seq,
+            push.timestamp,
+        );
+        let mime_type = State::extract_mime_type_from_json(&canonical.content, &canonical.extra);
+        let upserted = self
+            .store
+            .upsert_remote_message_with_result(uid, &canonical.to_upsert_input(2, mime_type))?;
+        if !upserted.inserted_new {
+            return Ok(None);
+        }
+
+        let current_user_id = uid.parse::<u64>().ok();
+        let from_self = current_user_id == Some(canonical.from_uid);
+        let channel_type = if canonical.channel_type == 0 {
+            1
+        } else {
+            canonical.channel_type
+        };
+        // 与 actor 路径同一条规则：已读游标之前的迟到消息不算未读。
+        let pts = u64::try_from(canonical.pts).unwrap_or_default();
+        let after_read_cursor = self
+            .store
+            .get_channel_extra(uid, canonical.channel_id, channel_type)?
+            .map(|extra| pts > extra.keep_pts)
+            .unwrap_or(true);
+        let peer_user_id = (channel_type == 1 && !from_self).then_some(canonical.from_uid);
+        self.store.apply_push_channel_preview(
+            uid,
+            canonical.channel_id,
+            channel_type,
+            upserted.message_id,
+            &canonical.content,
+            canonical.sent_at_ms,
+            !from_self && after_read_cursor,
+            peer_user_id,
+        )?;
+
+        let context = self.store.push_notification_context(
+            uid,
+            canonical.channel_id,
+            channel_type,
+            canonical.from_uid,
+        )?;
+        let projection = self
+            .store
+            .get_message_by_id(uid, upserted.message_id)?
+            .map(|message| project_stored_message(&message))
+            .unwrap_or_default();
+        let title = if channel_type == 1 {
+            Some(context.sender_name.clone())
+        } else {
+            context.channel_title.clone()
+        };
+        let mentions_me =
+            current_user_id.is_some_and(|user_id| projection.mentioned_user_ids.contains(&user_id));
+        let (title, sender_name, body, message_kind) = match privacy {
+            NotificationPrivacy::Full => (
+                title,
+                Some(context.sender_name),
+                preview_text(&projection),
+                Some(projection.kind),
+            ),
+            NotificationPrivacy::HideContent => (title, Some(context.sender_name), None, None),
+            NotificationPrivacy::HideAll => (None, None, None, None),
+        };
+        Ok(Some(PushNotification {
+            message_id: upserted.message_id,
+            server_message_id: canonical.server_message_id,
+            channel_id: canonical.channel_id,
+            channel_type,
+            title,
+            sender_name,
+            body,
+            message_kind,
+            channel_muted: context.channel_muted,
+            notification_mode: context.notification_mode,
+            mentions_me,
+            from_self,
+            channel_unread_count: context.channel_unread_count,
+            badge_count: self.badge_count(uid, current_user_id)?,
+        }))
+    }
+
+    /// 与 actor 里的未读聚合同一条规则算角标；uid 不是数字时没有 @ 可数，只按未读算。
+    fn badge_count(&self, uid: &str, current_user_id: Option<u64>) -> Result<i32> {
+        let inputs = self
+            .store
+            .list_channel_unread_inputs(uid, current_user_id.unwrap_or_default())?;
+        let aggregate = crate::unread_badge::aggregate_unread(inputs, Vec::new());
+        Ok(i32::try_from(aggregate.badge_count).unwrap_or(i32::MAX))
+    }
+}
+
+/// 通知正文预览：文本原样，附件取说明文字。类型占位不在这里拼——占位要跟宿主的语言走。
+fn preview_text(projection: &MessageContentProjection) -> Option<String> {
+    let text = projection.text.trim();
+    if !text.is_empty() {
+        return Some(text.to_string());
+    }
+    match projection.kind.as_str() {
+        "file" => projection.file_name.as_deref(),
+        "link" => projection.link_title.as_deref(),
+        "location" => projection.location_name.as_deref(),
+        "contact" => projection.contact_name.as_deref(),
+        _ => None,
+    }
+    .map(str::trim)
+    .filter(|s| !s.is_empty())
+    .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UpsertUserInput;
+
+    fn test_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "privchat-push-ingest-{name}-{}-{}",
+            std::process::id(),
+            chrono::Utc::now().timestamp_micros()
+        ))
+    }
+
+    fn text_push(server_message_id: u64, text: &str) -> PushPayload {
+        PushPayload {
+            server_message_id,
+            channel_id: 7001,
+            channel_type: 1,
+            from_uid: 2002,
+            message_type: 0,
+            payload: serde_json::to_vec(&serde_json::json!({ "content": text })).unwrap(),
+            message_seq: server_message_id as i64,
+            timestamp: 1_700_000_000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn push_is_ingested_once_and_counts_unread() {
+        let dir = test_dir("once");
+        let ingestor = PushIngestor::open(&dir, "1001").expect("open ingestor");
+        ingestor
+            .store
+            .upsert_user(
+                "1001",
+                &UpsertUserInput {
+                    user_id: 2002,
+                    username: Some("bob".to_string()),
+                    nickname: Some("Bob".to_string()),
+                    alias: None,
+                    avatar: String::new(),
+                    user_type: 0,
+                    is_deleted: false,
+                    channel_id: String::new(),
+                    version: 1,
+                    updated_at: 1,
+                },
+            )
+            .expect("seed sender");
+
+        let first = ingestor
+            .ingest(&text_push(9001, "hello"), NotificationPrivacy::Full)
+            .expect("ingest")
+            .expect("new message renders a notification");
+        assert_eq!(first.title.as_deref(), Some("Bob"));
+        assert_eq!(first.sender_name.as_deref(), Some("Bob"));
+        assert_eq!(first.body.as_deref(), Some("hello"));
+        assert_eq!(first.channel_unread_count, 1);
+        assert!(first.should_alert());
+
+        let replay = ingestor
+            .ingest(&text_push(9001, "hello"), NotificationPrivacy::Full)
+            .expect("ingest replay");
+        assert!(
+            replay.is_none(),
+            "same server_message_id must not notify twice"
+        );
+
+        let hidden = ingestor
+            .ingest(&text_push(9002, "secret"), NotificationPrivacy::HideContent)
+            .expect("ingest")
+            .expect("notification");
+        assert_eq!(hidden.sender_name.as_deref(), Some("Bob"));
+        assert_eq!(hidden.body, None);
+        assert_eq!(hidden.message_kind, None);
+        assert_eq!(hidden.channel_unread_count, 2);
+        assert_eq!(hidden.badge_count, 2);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn badge_count_follows_the_channel_notification_mode() {
+        let dir = test_dir("badge");
+        let ingestor = PushIngestor::open(&dir, "1001").expect("open ingestor");
+        let first = ingestor
+            .ingest(&text_push(9101, "one"), NotificationPrivacy::Full)
+            .expect("ingest")
+            .expect("notification");
+        assert_eq!(first.badge_count, 1);
+
+        // 只提醒 @：会话照样计未读，但没有 @ 就不进角标——和主进程的聚合一致。
+        ingestor
+            .store
+            .upsert_channel_pref(
+                "1001",
+                &crate::channel_query::ChannelPrefRow {
+                    channel_id: 7001,
+                    channel_type: 1,
+                    notification_mode: crate::unread_badge::notification_mode::MENTIONS_ONLY,
+                    favourite: false,
+                    low_priority: false,
+                    tags: Vec::new(),
+                },
+            )
+            .expect("seed pref");
+        let second = ingestor
+            .ingest(&text_push(9102, "two"), NotificationPrivacy::Full)
+            .expect("ingest")
+            .expect("notification");
+        assert_eq!(second.channel_unread_count, 2);
+        assert_eq!(second.badge_count, 0);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    fn set_mode(ingestor: &PushIngestor, mode: i32) {
+        ingestor
+            .store
+            .upsert_channel_pref(
+                "1001",
+                &crate::channel_query::ChannelPrefRow {
+                    channel_id: 7001,
+                    channel_type: 1,
+                    notification_mode: mode,
+                    favourite: false,
+                    low_priority: false,
+                    tags: Vec::new(),
+                },
+            )
+            .expect("seed pref");
+    }
+
+    fn mention_push(server_message_id: u64, text: &str) -> PushPayload {
+        PushPayload {
+            payload: serde_json::to_vec(&serde_json::json!({
+                "content": text,
+                "mentioned_user_ids": [1001],
+            }))
+            .unwrap(),
+            ..text_push(server_message_id, text)
+        }
+    }
+
+    #[test]
+    fn alerts_follow_the_channel_notification_mode() {
+        let dir = test_dir("alert");
+        let ingestor = PushIngestor::open(&dir, "1001").expect("open ingestor");
+        let ingest = |push: PushPayload| {
+            ingestor
+                .ingest(&push, NotificationPrivacy::Full)
+                .expect("ingest")
+                .expect("notification")
+        };
+        assert!(ingest(text_push(9201, "first")).should_alert());
+
+        // 不提醒：会话行的 mute 仍是 0，照样不响，和同一条通知里的角标一致。
+        set_mode(&ingestor, notification_mode::NONE);
+        let silent = ingest(text_push(9202, "quiet"));
+        assert!(silent.channel_muted);
+        assert!(!silent.should_alert());
+        assert_eq!(silent.badge_count, 0);
+        assert!(!ingest(mention_push(9203, "@me")).should_alert());
+
+        // 只提醒 @：没 @ 自己不响，@ 了才响。
+        set_mode(&ingestor, notification_mode::MENTIONS_ONLY);
+        assert!(!ingest(text_push(9204, "chatter")).should_alert());
+        let mentioned = ingest(mention_push(9205, "@me"));
+        assert!(mentioned.mentions_me);
+        assert!(mentioned.should_alert());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn attachment_preview_leaves_the_type_label_to_the_host() {
+        let projection = MessageContentProjection {
+            kind: "file".to_string(),
+            file_name: Some("report.pdf".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(preview_text(&projection).as_deref(), Some("report.pdf"));
+        let projection = MessageContentProjection {
+            kind: "image".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(preview_text(&projection), None);
+    }
+}
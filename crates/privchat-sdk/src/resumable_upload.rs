@@ -30,13 +30,18 @@
 //!
 //! 3. **失败立刻减半，成功缓慢增长。** 网络变差是突然的，变好是渐进的；两个方向用
 //!    同样的步长，要么恢复太慢，要么在临界点上反复抖。
+//!
+//! 4. **探测完再并发。** 好链路上大文件是被延迟卡住的，不是被带宽：同时在路上的
+//!    分片最多 `max_parallel_parts` 个。探测片只有一个；一失败就退回单片，直到
+//!    重新测出吞吐。
 
 use std::time::{Duration, Instant};
 
 use crate::{Error, Result};
 
 /// 分片方案。服务端只冻结 `base_unit`（寻址网格）；其余是客户端的传输决策
-/// （RESUMABLE_UPLOAD_SPEC §5：首片 64KiB 探测、单次上限 2MiB、并发 1）。
+/// （RESUMABLE_UPLOAD_SPEC §5：首片 64KiB 探测、单次上限 2MiB、并发见
+/// [`DEFAULT_MAX_PARALLEL_PARTS`]）。
 #[derive(Debug, Clone, Copy)]
 pub struct UploadPlan {
     pub base_unit: u32,
@@ -55,11 +60,17 @@ impl UploadPlan {
             initial_request_size: base_unit,
             max_request_size: (2 * 1024 * 1024).max(base_unit),
             session_threshold: base_unit as u64,
-            max_parallel_parts: 1,
+            max_parallel_parts: DEFAULT_MAX_PARALLEL_PARTS,
         }
     }
 }
 
+/// 同时在路上的分片上限。
+///
+/// 服务端按区间记账，乱序到达本来就合法；再多并发收益就很小了，而每一路都要在
+/// 内存里攥着一整片密文。
+pub const DEFAULT_MAX_PARALLEL_PARTS: u8 = 4;
+
 /// 上传进度：`uploaded / total`。
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct UploadProgress {
@@ -103,18 +114,33 @@ impl ChunkSizer {
         self.next
     }
 
+    /// 测过吞吐没有。没测过（或者刚失败过）就只该有一片在路上。
+    pub fn has_estimate(&self) -> bool {
+        self.rate.is_some()
+    }
+
     /// 一片成功了：按实测吞吐调整。
     pub fn on_success(&mut self, bytes: u32, elapsed: Duration) {
+        self.on_success_shared(bytes, elapsed, 1);
+    }
+
+    /// 同上，但这片是和另外几片**一起**传的：`concurrent` 是它落地时在路上的分片数
+    /// （含它自己）。
+    ///
+    /// 🔴 估的是**整条链路**的吞吐，不是单片的。几片并发时每片只分到一份带宽，
+    /// 拿单片速率去定大小，并发数一变（探测时 1 路、之后 4 路）估计就全错了。
+    pub fn on_success_shared(&mut self, bytes: u32, elapsed: Duration, concurrent: usize) {
         let secs = elapsed.as_secs_f64().max(0.001);
-        let sample = bytes as f64 / secs;
+        let sample = bytes as f64 / secs * concurrent.max(1) as f64;
         // EWMA：单次抖动不该让下一片直接跳一个数量级。
         self.rate = Some(match self.rate {
             Some(prev) => prev * 0.7 + sample * 0.3,
             None => sample,
         });
 
-        // 目标：一片大约传 1 秒。太小则请求开销占比过高，太大则一次失败要重传很多。
-        let target = self.rate.unwrap_or(sample);
+        // 目标：每一路一片大约传 1 秒。太小则请求开销占比过高，太大则一次失败要重传很多。
+        let lanes = self.plan.max_parallel_parts.max(1) as f64;
+        let target = self.rate.unwrap_or(sample) / lanes;
         // 🔴 每步最多涨 4 倍。一次异常快的采样（比如命中了某层缓存）不该把下一片
         // 直接顶到上限——那一片一旦超时，代价是整片重来。
         let capped = (target as u64).min(bytes as u64 * 4);
@@ -263,6 +289,8 @@ pub struct ResumableUpload {
     sizer: ChunkSizer,
     gaps: Vec<Gap>,
     confirmed: u64,
+    /// 已经发出去、还没有结论的分片（[`ResumableUpload::claim_part`] 发的号）。
+    in_flight: Vec<Gap>,
 }
 
 impl ResumableUpload {
@@ -272,6 +300,7 @@ impl ResumableUpload {
             sizer: ChunkSizer::new(plan),
             gaps: gaps_from_confirmed(confirmed, total),
             confirmed: confirmed.iter().map(|(_, len)| len).sum(),
+            in_flight: Vec::new(),
         }
     }
 
@@ -291,10 +320,14 @@ impl ResumableUpload {
             sizer: ChunkSizer::new(plan),
             gaps,
             confirmed: total.saturating_sub(missing_total),
+            in_flight: Vec::new(),
         }
     }
 
     /// 以服务端的 `missing` 为准重算缺口。
+    ///
+    /// 在路上的分片不动：它们是真实发出去的请求，结论回来时照常记账。调用方最好
+    /// 先等它们落地再对齐，否则刚拿到的 `missing` 马上就过时了。
     pub fn resync_missing(&mut self, missing: &[(u64, u64)]) {
         let fresh = Self::from_missing(self.total, self.sizer.plan, missing);
         self.gaps = fresh.gaps;
@@ -312,18 +345,59 @@ impl ResumableUpload {
         self.gaps.is_empty()
     }
 
-    /// 下一片该发哪一段。
+    /// 还没确认的缺口（交给后台传输时导出它们）。
+    pub fn gaps(&self) -> &[Gap] {
+        &self.gaps
+    }
+
+    /// 下一片该发哪一段（一次只发一片的调用方用）。
     pub fn next_chunk(&self) -> Option<Gap> {
         let gap = self.gaps.first()?;
         let pieces = split_gap(*gap, self.sizer.next_size(), self.total);
         pieces.into_iter().next()
     }
 
-    /// 这一片成功了。
+    /// 并发调用方用：领一片**还没人在传**的，记为在路上。`None` = 通道满了，或者
+    /// 剩下的缺口都已经有分片在路上——等一片落地再来领。
+    ///
+    /// 还没测出吞吐时只放一片（探测）；失败之后同理，直到重新测出来。
+    pub fn claim_part(&mut self) -> Option<Gap> {
+        let lanes = if self.sizer.has_estimate() {
+            self.sizer.plan.max_parallel_parts.max(1) as usize
+        } else {
+            1
+        };
+        if self.in_flight.len() >= lanes {
+            return None;
+        }
+        let mut taken = self.confirmed_ranges();
+        taken.extend(self.in_flight.iter().map(|g| (g.offset, g.len)));
+        let gap = gaps_from_confirmed(&taken, self.total).into_iter().next()?;
+        let part = split_gap(gap, self.sizer.next_size(), self.total)
+            .into_iter()
+            .next()?;
+        self.in_flight.push(part);
+        Some(part)
+    }
+
+    /// 在路上的分片数。
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// 这一片成功了（服务端确认过）。只有这里推进进度。
+    ///
+    /// 并发时分片乱序落地：缺口按「已确认区间 + 这一片」整体重算，不假设它正好
+    /// 接在第一个缺口的开头。
     pub fn on_chunk_ok(&mut self, chunk: Gap, elapsed: Duration) {
-        self.sizer.on_success(chunk.len as u32, elapsed);
-        self.consume(chunk);
-        self.confirmed = (self.confirmed + chunk.len).min(self.total);
+        let concurrent = self.in_flight.len().max(1);
+        self.in_flight.retain(|g| *g != chunk);
+        self.sizer
+            .on_success_shared(chunk.len as u32, elapsed, concurrent);
+        let mut confirmed = self.confirmed_ranges();
+        confirmed.push((chunk.offset, chunk.len));
+        self.gaps = gaps_from_confirmed(&confirmed, self.total);
+        self.confirmed = self.total - self.gaps.iter().map(|g| g.len).sum::<u64>();
     }
 
     /// 这一片失败了：下一片减半，缺口不动（原样重来）。
@@ -331,6 +405,12 @@ impl ResumableUpload {
         self.sizer.on_failure();
     }
 
+    /// 并发版：同上，并把这一片还回去，下次 [`ResumableUpload::claim_part`] 会重新发它。
+    pub fn on_part_failed(&mut self, part: Gap) {
+        self.in_flight.retain(|g| *g != part);
+        self.on_chunk_failed();
+    }
+
     /// 服务端告诉我们真实的已确认区间——**以它为准**重算缺口。
     ///
     /// 本地记账只是乐观估计；一旦与服务端不一致（重试、并发、跨设备），必须听服务端的。
@@ -339,19 +419,20 @@ impl ResumableUpload {
         self.confirmed = confirmed.iter().map(|(_, len)| len).sum();
     }
 
-    fn consume(&mut self, chunk: Gap) {
-        let Some(first) = self.gaps.first_mut() else {
-            return;
-        };
-        if chunk.offset != first.offset {
-            return;
+    /// 缺口的补集：已确认的区间。
+    fn confirmed_ranges(&self) -> Vec<(u64, u64)> {
+        let mut ranges = Vec::with_capacity(self.gaps.len() + 1);
+        let mut cursor = 0u64;
+        for gap in &self.gaps {
+            if gap.offset > cursor {
+                ranges.push((cursor, gap.offset - cursor));
+            }
+            cursor = cursor.max(gap.offset + gap.len);
         }
-        if chunk.len >= first.len {
-            self.gaps.remove(0);
-        } else {
-            first.offset += chunk.len;
-            first.len -= chunk.len;
+        if cursor < self.total {
+            ranges.push((cursor, self.total - cursor));
         }
+        ranges
     }
 
     /// 当前分片大小（测试与日志用）。
@@ -558,6 +639,95 @@ mod tests {
         assert_eq!(up.progress().uploaded, 0, "失败不能算进度");
     }
 
+    fn parallel_plan() -> UploadPlan {
+        UploadPlan {
+            max_parallel_parts: 4,
+            ..plan()
+        }
+    }
+
+    /// 先探一片；测出吞吐之后才放满通道，而且同时在路上的分片互不重叠。
+    #[test]
+    fn parts_fan_out_only_after_the_probe() {
+        let total = 64 * 1024 * 40;
+        let mut up = ResumableUpload::new(total, parallel_plan(), &[]);
+        let probe = up.claim_part().expect("probe");
+        assert!(up.claim_part().is_none(), "探测片回来之前不该再发");
+
+        up.on_chunk_ok(probe, Duration::from_millis(200));
+        let mut parts = Vec::new();
+        while let Some(part) = up.claim_part() {
+            parts.push(part);
+        }
+        assert_eq!(parts.len(), 4, "通道该放满");
+        assert_eq!(up.in_flight(), 4);
+        for (i, a) in parts.iter().enumerate() {
+            for b in &parts[i + 1..] {
+                assert!(
+                    a.offset + a.len <= b.offset || b.offset + b.len <= a.offset,
+                    "🔴 {a:?} 与 {b:?} 重叠：同一段字节传两遍"
+                );
+            }
+        }
+    }
+
+    /// 🔴 乱序落地：后发的先确认，缺口必须恰好剩下先发的那片。
+    #[test]
+    fn out_of_order_completions_leave_exactly_the_unconfirmed_parts() {
+        let total = 64 * 1024 * 8;
+        let mut up = ResumableUpload::new(total, parallel_plan(), &[]);
+        let probe = up.claim_part().unwrap();
+        up.on_chunk_ok(probe, Duration::from_secs(1));
+        let a = up.claim_part().unwrap();
+        let b = up.claim_part().unwrap();
+        assert!(b.offset > a.offset);
+
+        up.on_chunk_ok(b, Duration::from_millis(500));
+        assert_eq!(
+            up.progress().uploaded,
+            probe.len + b.len,
+            "进度只算服务端确认过的"
+        );
+        up.on_chunk_ok(a, Duration::from_millis(500));
+        assert_eq!(up.progress().uploaded, probe.len + a.len + b.len);
+
+        let mut sent = probe.len + a.len + b.len;
+        while let Some(part) = up.claim_part() {
+            sent += part.len;
+            up.on_chunk_ok(part, Duration::from_millis(500));
+        }
+        assert!(up.is_done());
+        assert_eq!(sent, total, "多一个字节都是白传，少一个永远 complete 不了");
+    }
+
+    /// 失败的那片还回去、重新发；失败后退回单片，重新测过吞吐再放开。
+    #[test]
+    fn a_failed_part_is_handed_out_again_and_the_lanes_close() {
+        let total = 64 * 1024 * 40;
+        let mut up = ResumableUpload::new(total, parallel_plan(), &[]);
+        let probe = up.claim_part().unwrap();
+        up.on_chunk_ok(probe, Duration::from_millis(200));
+        let a = up.claim_part().unwrap();
+        let b = up.claim_part().unwrap();
+
+        up.on_part_failed(a);
+        assert!(
+            up.claim_part().is_none(),
+            "刚失败过：b 还在路上，不该再加一路"
+        );
+        up.on_chunk_ok(b, Duration::from_millis(200));
+        let again = up.claim_part().expect("retry");
+        assert_eq!(again.offset, a.offset, "失败的那段必须重新发");
+    }
+
+    /// 聚合吞吐：四路各自 1 秒传完一片，说明链路是单路的四倍，但每路的片大小不该变。
+    #[test]
+    fn the_sizer_reasons_about_the_whole_link() {
+        let mut sizer = ChunkSizer::new(parallel_plan());
+        sizer.on_success_shared(256 * 1024, Duration::from_secs(1), 4);
+        assert_eq!(sizer.next_size(), 256 * 1024, "每路一片一秒：大小刚好");
+    }
+
     /// 服务端说了算：resync 之后按服务端的区间重算。
     #[test]
     fn resync_takes_the_servers_word_for_it() {
@@ -0,0 +1,441 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 表情包：本地目录、资源预取、发送。
+//!
+//! 服务端原来只有两个只读接口（`routes::sticker::PACKAGE_LIST` / `PACKAGE_DETAIL`），以前 FFI
+//! 原样透传，什么都不缓存，也没有发送入口。现在按账号存进本地库：
+//!
+//! - 包和表情的元数据（`sticker_package` / `sticker`）：列表、详情接口拉到的都落库，
+//!   离线也能打开表情面板；
+//! - 装了哪些包、按什么顺序：`installed` / `sort_order`，**顺序只在本机**；
+//! - 最近使用（最多 [`RECENT_LIMIT`] 个）和收藏，只在本机；
+//! - 已安装包的表情图以后台优先级交给 `DownloadManager` 预取到
+//!   `{user_root}/stickers/{package_id}/`，省流量生效时不预取，卸载时整个目录删掉。
+//!
+//! 装 / 卸是多端同步的：本地先改，再作为 outbox 里的 RPC 类命令发出
+//! （`routes::sticker::PACKAGE_INSTALL` / `PACKAGE_UNINSTALL`，`coalesce_key` =
+//! `sticker_install:{package_id}`，离线时反复操作只留最后一次）；别的设备上的改动经
+//! `routes::entity::STICKER_PACKAGE` 实体族下来（引导同步、断线恢复、实体失效推送）。
+//! 命令还没发出去的包，同步不覆盖它的本地状态；命令被服务端拒绝时按命令里记下的原样
+//! 退回（[`StickerInstallCommand::previous`]）。还没有这两个路由的服务端回的是「路由
+//! 不存在」，那不是拒绝：装 / 卸只留在本机，不退回（[`route_unavailable`]）。
+//! 本地还没见过的包也能装，详情由预取任务拉。
+//!
+//! 表情消息只引用表情（`message_type` = Sticker，metadata 是一个 [`Sticker`]），不上传文件。
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Error, StoredMessage};
+use privchat_protocol::message::ContentMessageType;
+use privchat_protocol::rpc::routes;
+use privchat_protocol::rpc::StickerPackageInfo;
+use privchat_protocol::ErrorCode;
+
+pub(crate) const INSTALL_COMMAND: &str = "sticker_install";
+pub(crate) const ROUTE_INSTALL: &str = routes::sticker::PACKAGE_INSTALL;
+pub(crate) const ROUTE_UNINSTALL: &str = routes::sticker::PACKAGE_UNINSTALL;
+/// 已安装包的实体族。payload 见 [`StickerPackageSyncPayload`]。
+pub(crate) const SYNC_ENTITY_TYPE: &str = routes::entity::STICKER_PACKAGE;
+
+/// 最近使用保留的个数。
+pub const RECENT_LIMIT: usize = 30;
+
+/// 表情图预取到 `{user_root}/` 下的这个目录。
+const ASSET_DIR: &str = "stickers";
+
+/// 同一个包的未发装 / 卸命令互相覆盖。
+pub(crate) fn install_coalesce_key(package_id: &str) -> String {
+    format!("{INSTALL_COMMAND}:{package_id}")
+}
+
+/// 服务端没有装 / 卸路由（还没升级）。这不是对这次操作的拒绝，本地状态照留。
+pub(crate) fn route_unavailable(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::Server { code, .. } if *code == ErrorCode::RouteNotFound as u32
+    )
+}
+
+/// 表情消息的 `message_type`。
+pub const STICKER_MESSAGE_TYPE: i32 = ContentMessageType::Sticker as i32;
+
+/// 一个表情。也是表情消息 metadata 的形状。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sticker {
+    pub sticker_id: String,
+    pub package_id: String,
+    pub image_url: String,
+    #[serde(default)]
+    pub alt_text: String,
+    #[serde(default)]
+    pub emoji: Option<String>,
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub height: u32,
+    #[serde(default)]
+    pub mime_type: String,
+    /// 预取到本地的图片；还没下载时为 `None`。只在本机有意义，不进消息。
+    #[serde(skip)]
+    pub local_path: Option<String>,
+}
+
+impl Sticker {
+    /// 表情消息的正文（会话预览、搜索）：描述文字，没有就用 emoji。
+    pub(crate) fn display_text(&self) -> String {
+        let alt = self.alt_text.trim();
+        if !alt.is_empty() {
+            return alt.to_string();
+        }
+        self.emoji
+            .as_deref()
+            .map(str::trim)
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        let sticker: Self = serde_json::from_value(value.clone()).ok()?;
+        (!sticker.sticker_id.is_empty()).then_some(sticker)
+    }
+}
+
+/// 一个表情包：服务端给的元数据，加上本账号的安装状态。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StickerPackage {
+    pub package_id: String,
+    pub name: String,
+    pub thumbnail_url: String,
+    pub author: String,
+    pub description: String,
+    pub sticker_count: u64,
+    /// 包内顺序。只拉过列表、还没拉过详情的包是空的。
+    pub stickers: Vec<Sticker>,
+    pub installed: bool,
+    /// 已安装包的顺序，小的在前。
+    pub sort_order: i64,
+    /// 安装时间（毫秒），没装为 0。
+    pub installed_at: i64,
+    /// 装 / 卸还在出站队列里，服务端尚未确认。
+    pub pending: bool,
+}
+
+/// 服务端的包信息。安装状态不在里面，由本地库补。
+impl From<StickerPackageInfo> for StickerPackage {
+    fn from(info: StickerPackageInfo) -> Self {
+        Self {
+            package_id: info.package_id,
+            name: info.name,
+            thumbnail_url: info.thumbnail_url,
+            author: info.author,
+            description: info.description,
+            sticker_count: info.sticker_count as u64,
+            stickers: info
+                .stickers
+                .unwrap_or_default()
+                .into_iter()
+                .map(|s| Sticker {
+                    sticker_id: s.sticker_id,
+                    package_id: s.package_id,
+                    image_url: s.image_url,
+                    alt_text: s.alt_text,
+                    emoji: s.emoji,
+                    width: s.width,
+                    height: s.height,
+                    mime_type: s.mime_type,
+                    local_path: None,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+/// outbox 里装 / 卸命令的 payload。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct StickerInstallCommand {
+    pub package_id: String,
+    pub installed: bool,
+    /// 服务端拒绝时退回的状态：入队前这个包在本机的样子。替换掉同一个包还没发出的旧命令
+    /// 时沿用旧命令的，退回的是服务端最后认可的那一份。入队时由本地库填，老版本入队的
+    /// 命令没有。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<StickerInstallState>,
+}
+
+/// 一个包在本机的安装状态。本地库里还没有这个包时是没装（`Default`）。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct StickerInstallState {
+    pub installed: bool,
+    pub sort_order: i64,
+    pub installed_at: i64,
+}
+
+/// `sticker/package/install` / `uninstall` 请求。`command_id` 供服务端去重，重试不变。
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct StickerInstallRequest {
+    pub package_id: String,
+    pub command_id: String,
+}
+
+/// `sticker_package` 实体的 payload。实体 ID 就是包 ID；删除（`deleted`）等于卸载。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub(crate) struct StickerPackageSyncPayload {
+    pub package_id: Option<String>,
+    pub installed: Option<bool>,
+    pub installed_at: Option<i64>,
+}
+
+/// 一条同步下来的装 / 卸。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RemoteStickerInstall {
+    pub package_id: String,
+    pub installed: bool,
+    pub installed_at: Option<i64>,
+}
+
+impl RemoteStickerInstall {
+    pub(crate) fn from_sync_item(entity_id: &str, deleted: bool, payload: &Value) -> Option<Self> {
+        let parsed: StickerPackageSyncPayload =
+            serde_json::from_value(payload.clone()).unwrap_or_default();
+        let package_id = parsed
+            .package_id
+            .filter(|id| !id.trim().is_empty())
+            .unwrap_or_else(|| entity_id.to_string());
+        if package_id.trim().is_empty() {
+            return None;
+        }
+        Some(Self {
+            package_id,
+            installed: !deleted && parsed.installed.unwrap_or(true),
+            installed_at: parsed.installed_at,
+        })
+    }
+}
+
+/// 表情消息引用的表情：在消息信封的 metadata 里（`extra`），老数据可能直接在 `content` 里。
+pub fn sticker_from_message(message: &StoredMessage) -> Option<Sticker> {
+    if message.message_type != STICKER_MESSAGE_TYPE {
+        return None;
+    }
+    [message.extra.as_str(), message.content.as_str()]
+        .iter()
+        .find_map(|raw| {
+            let value: Value = serde_json::from_str(raw).ok()?;
+            value
+                .get("metadata")
+                .and_then(Sticker::from_value)
+                .or_else(|| Sticker::from_value(&value))
+        })
+}
+
+/// 一个包的表情图目录：`{user_root}/stickers/{package_id}/`。卸载时整个删掉。
+pub(crate) fn package_dir(user_root: &Path, package_id: &str) -> PathBuf {
+    user_root.join(ASSET_DIR).join(path_component(package_id))
+}
+
+/// 一个表情图在本地的位置：`{user_root}/stickers/{package_id}/{sticker_id}.{ext}`。
+pub(crate) fn asset_path(user_root: &Path, sticker: &Sticker) -> PathBuf {
+    package_dir(user_root, &sticker.package_id).join(format!(
+        "{}.{}",
+        path_component(&sticker.sticker_id),
+        asset_extension(&sticker.mime_type, &sticker.image_url)
+    ))
+}
+
+/// 存在且非空才算下载过。
+pub(crate) fn local_asset(user_root: &Path, sticker: &Sticker) -> Option<String> {
+    let path = asset_path(user_root, sticker);
+    std::fs::metadata(&path)
+        .is_ok_and(|m| m.len() > 0)
+        .then(|| path.to_string_lossy().to_string())
+}
+
+/// 包 ID 映射到 `DownloadManager` 的任务 ID（它按 `u64` 去重）。撞上了只是两个包的
+/// 预取互相排队，不会下错文件。
+pub(crate) fn prefetch_task_id(package_id: &str) -> u64 {
+    fnv1a(package_id)
+}
+
+/// FNV-1a：跨进程、跨版本稳定（`DefaultHasher` 不保证）。
+fn fnv1a(value: &str) -> u64 {
+    value.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// 服务端的 ID 直接当文件名用；带了路径分隔符之类的字符就换成它的哈希。
+fn path_component(id: &str) -> String {
+    let safe = !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if safe {
+        id.to_string()
+    } else {
+        format!("{:016x}", fnv1a(id))
+    }
+}
+
+fn asset_extension(mime_type: &str, url: &str) -> &'static str {
+    match mime_type.trim().to_ascii_lowercase().as_str() {
+        "image/png" | "image/apng" => return "png",
+        "image/gif" => return "gif",
+        "image/webp" => return "webp",
+        "image/jpeg" | "image/jpg" => return "jpg",
+        "application/json" | "application/x-tgsticker" => return "json",
+        _ => {}
+    }
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    match path
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("png") => "png",
+        Some("gif") => "gif",
+        Some("jpg" | "jpeg") => "jpg",
+        Some("json") => "json",
+        _ => "webp",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sticker(sticker_id: &str, package_id: &str) -> Sticker {
+        Sticker {
+            sticker_id: sticker_id.to_string(),
+            package_id: package_id.to_string(),
+            image_url: "https://cdn.example/s/1.webp?v=2".to_string(),
+            alt_text: " 微笑 ".to_string(),
+            emoji: Some("🙂".to_string()),
+            width: 512,
+            height: 512,
+            mime_type: String::new(),
+            local_path: Some("/tmp/ignored".to_string()),
+        }
+    }
+
+    #[test]
+    fn asset_paths_stay_inside_the_package_directory() {
+        let root = Path::new("/data/u1");
+        let plain = asset_path(root, &sticker("s_01", "pack-a"));
+        assert_eq!(plain, Path::new("/data/u1/stickers/pack-a/s_01.webp"));
+
+        let hostile = asset_path(root, &sticker("../../etc/passwd", "a/b"));
+        assert_eq!(
+            hostile.parent().unwrap().parent().unwrap(),
+            root.join("stickers")
+        );
+        let name = hostile.file_name().unwrap().to_string_lossy().to_string();
+        assert!(!name.contains('/') && !name.starts_with('.'), "{name}");
+        // 同一个 ID 每次都映射到同一个文件。
+        assert_eq!(
+            hostile,
+            asset_path(root, &sticker("../../etc/passwd", "a/b"))
+        );
+
+        let mut gif = sticker("s_02", "pack-a");
+        gif.mime_type = "image/GIF".to_string();
+        assert_eq!(asset_extension(&gif.mime_type, &gif.image_url), "gif");
+        assert_eq!(asset_extension("", "https://cdn/x.PNG#frag"), "png");
+    }
+
+    #[test]
+    fn sticker_metadata_round_trips_through_a_message_without_the_local_path() {
+        let original = sticker("s_01", "pack-a");
+        let metadata = serde_json::to_value(&original).unwrap();
+        assert!(metadata.get("local_path").is_none());
+        let message = StoredMessage {
+            message_id: 1,
+            server_message_id: Some(9),
+            local_message_id: None,
+            channel_id: 10,
+            channel_type: 1,
+            from_uid: 7,
+            message_type: STICKER_MESSAGE_TYPE,
+            content: "微笑".to_string(),
+            status: 0,
+            created_at: 0,
+            updated_at: 0,
+            extra: serde_json::json!({ "content": "微笑", "metadata": metadata }).to_string(),
+            revoked: false,
+            revoked_by: None,
+            mime_type: None,
+            media_downloaded: false,
+            thumb_status: 0,
+            delivered: false,
+            pts: None,
+            revision_count: 0,
+            poll: None,
+            live_location: None,
+        };
+        let decoded = sticker_from_message(&message).expect("sticker metadata");
+        assert_eq!(decoded.sticker_id, "s_01");
+        assert_eq!(decoded.local_path, None);
+        assert_eq!(decoded.display_text(), "微笑");
+
+        let not_a_sticker = StoredMessage {
+            message_type: 0,
+            ..message
+        };
+        assert_eq!(sticker_from_message(&not_a_sticker), None);
+    }
+
+    #[test]
+    fn only_a_missing_route_keeps_the_install_local() {
+        let missing_route = Error::Server {
+            code: ErrorCode::RouteNotFound as u32,
+            message: "route not found".to_string(),
+        };
+        assert!(route_unavailable(&missing_route));
+        let rejected = Error::Server {
+            code: ErrorCode::ResourceNotFound as u32,
+            message: "package not found".to_string(),
+        };
+        assert!(!route_unavailable(&rejected));
+        assert!(!route_unavailable(&Error::NotConnected));
+    }
+
+    #[test]
+    fn sync_items_fall_back_to_the_entity_id_and_treat_deletion_as_uninstall() {
+        let installed =
+            RemoteStickerInstall::from_sync_item("pack-a", false, &serde_json::json!({}))
+                .expect("install");
+        assert_eq!(installed.package_id, "pack-a");
+        assert!(installed.installed);
+
+        let removed = RemoteStickerInstall::from_sync_item(
+            "ignored",
+            true,
+            &serde_json::json!({ "package_id": "pack-b", "installed": true }),
+        )
+        .expect("uninstall");
+        assert_eq!(removed.package_id, "pack-b");
+        assert!(!removed.installed);
+
+        let explicit = RemoteStickerInstall::from_sync_item(
+            "pack-c",
+            false,
+            &serde_json::json!({ "installed": false, "installed_at": 5 }),
+        )
+        .expect("explicit uninstall");
+        assert!(!explicit.installed);
+        assert_eq!(explicit.installed_at, Some(5));
+
+        assert_eq!(
+            RemoteStickerInstall::from_sync_item("", false, &serde_json::json!({})),
+            None
+        );
+    }
+}
@@ -20,9 +20,20 @@ use std::thread;
 
 use tokio::sync::oneshot;
 
-use crate::local_store::{LocalAccountEntry, LocalStore, StoragePaths, UserAvatarCacheRow};
+use crate::channel_query::{
+    ChannelFolder, ChannelFolderInput, ChannelListPage, ChannelListQuery, ChannelPrefRow,
+};
+use crate::live_location::{LiveLocationCommand, LiveLocationSettle, LiveLocationUpdate};
+use crate::local_store::{
+    LocalAccountEntry, LocalStore, OutboxRpcCommand, StoragePaths, UserAvatarCacheRow,
+};
+use crate::message_pin::{MessagePinCommand, PinnedMessage, RemotePin};
+use crate::poll::{PollTallyUpdate, PollVoteCommand};
+use crate::sticker::{RemoteStickerInstall, Sticker, StickerInstallCommand, StickerPackage};
+use crate::unread_badge::ChannelUnreadRow;
 use crate::{
-    Error, LoginResult, MentionInput, NewMessage, PendingTimelineMutation, Result, SessionSnapshot,
+    Error, LoginResult, MemberReadCursor, MentionInput, MessageReadReceipts, MessageRevision,
+    MessageRevisionInput, NewMessage, PendingTimelineMutation, Result, SessionSnapshot,
     StoredBlacklistEntry, StoredChannel, StoredChannelExtra, StoredChannelMember, StoredFriend,
     StoredGroup, StoredGroupMember, StoredMessage, StoredMessageExtra, StoredMessageReaction,
     StoredReminder, StoredUser, UnreadMentionCount, UpsertBlacklistInput, UpsertChannelExtraInput,
@@ -117,6 +128,14 @@ enum StorageCmd {
         offset: usize,
         resp: oneshot::Sender<Result<Vec<StoredMessage>>>,
     },
+    ListMessagesBefore {
+        channel_id: u64,
+        channel_type: i32,
+        before_local_id: u64,
+        limit: usize,
+        offset: usize,
+        resp: oneshot::Sender<Result<Vec<StoredMessage>>>,
+    },
     ListMessagesAround {
         channel_id: u64,
         channel_type: i32,
@@ -143,6 +162,29 @@ enum StorageCmd {
         offset: usize,
         resp: oneshot::Sender<Result<Vec<StoredChannel>>>,
     },
+    QueryChannels {
+        query: ChannelListQuery,
+        resp: oneshot::Sender<Result<ChannelListPage>>,
+    },
+    UpsertChannelPref {
+        pref: ChannelPrefRow,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    ReplaceChannelPrefs {
+        prefs: Vec<ChannelPrefRow>,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    UpsertChannelFolder {
+        input: ChannelFolderInput,
+        resp: oneshot::Sender<Result<ChannelFolder>>,
+    },
+    DeleteChannelFolder {
+        folder_id: u64,
+        resp: oneshot::Sender<Result<bool>>,
+    },
+    ListChannelFolders {
+        resp: oneshot::Sender<Result<Vec<ChannelFolder>>>,
+    },
     ListChannelIdentifiersAfter {
         after_channel_id: u64,
         after_channel_type: i32,
@@ -198,6 +240,9 @@ enum StorageCmd {
         last_error: String,
         resp: oneshot::Sender<Result<()>>,
     },
+    OutboxStats {
+        resp: oneshot::Sender<Result<(u64, Option<i64>)>>,
+    },
     OutboxDrop {
         local_message_id: u64,
         resp: oneshot::Sender<Result<()>>,
@@ -212,6 +257,136 @@ enum StorageCmd {
         server_message_id: u64,
         resp: oneshot::Sender<Result<()>>,
     },
+    OutboxPeekRpc {
+        limit: usize,
+        now_ms: i64,
+        resp: oneshot::Sender<Result<Vec<OutboxRpcCommand>>>,
+    },
+    OutboxRpcBumpRetry {
+        outbox_id: i64,
+        next_attempt_at: i64,
+        last_error: String,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    OutboxRpcDrop {
+        outbox_id: i64,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    ApplyPollUpdate {
+        message_id: u64,
+        update: PollTallyUpdate,
+        resp: oneshot::Sender<Result<bool>>,
+    },
+    PollRecordVote {
+        command_id: String,
+        command: PollVoteCommand,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    PollVoteSettled {
+        outbox_id: i64,
+        command: PollVoteCommand,
+        accepted: bool,
+        update: Option<PollTallyUpdate>,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    ApplyLiveLocationUpdate {
+        message_id: u64,
+        update: LiveLocationUpdate,
+        resp: oneshot::Sender<Result<bool>>,
+    },
+    LiveLocationRecord {
+        command_id: String,
+        command: LiveLocationCommand,
+        resp: oneshot::Sender<Result<i64>>,
+    },
+    LiveLocationSettled {
+        outbox_id: i64,
+        command: LiveLocationCommand,
+        outcome: LiveLocationSettle,
+        update: Option<LiveLocationUpdate>,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    MessagePinRecord {
+        command_id: String,
+        command: MessagePinCommand,
+        pinned_by: Option<u64>,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    MessagePinSettled {
+        outbox_id: i64,
+        command: MessagePinCommand,
+        confirmed: Option<RemotePin>,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    ApplyRemotePin {
+        channel_id: u64,
+        channel_type: i32,
+        pin: RemotePin,
+        pinned: bool,
+        resp: oneshot::Sender<Result<bool>>,
+    },
+    ReplaceChannelPins {
+        channel_id: u64,
+        channel_type: i32,
+        pins: Vec<RemotePin>,
+        resp: oneshot::Sender<Result<bool>>,
+    },
+    ListPinnedMessages {
+        channel_id: u64,
+        channel_type: i32,
+        resp: oneshot::Sender<Result<Vec<PinnedMessage>>>,
+    },
+    CacheStickerPackages {
+        packages: Vec<StickerPackage>,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    GetStickerPackage {
+        package_id: String,
+        resp: oneshot::Sender<Result<Option<StickerPackage>>>,
+    },
+    ListStickerPackages {
+        installed_only: bool,
+        resp: oneshot::Sender<Result<Vec<StickerPackage>>>,
+    },
+    StickerInstallRecord {
+        command_id: String,
+        command: StickerInstallCommand,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    StickerInstallSettled {
+        outbox_id: i64,
+        command: StickerInstallCommand,
+        accepted: bool,
+        resp: oneshot::Sender<Result<bool>>,
+    },
+    ApplyRemoteStickerInstalls {
+        installs: Vec<RemoteStickerInstall>,
+        resp: oneshot::Sender<Result<Vec<RemoteStickerInstall>>>,
+    },
+    ReorderStickerPackages {
+        package_ids: Vec<String>,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    GetSticker {
+        sticker_id: String,
+        resp: oneshot::Sender<Result<Option<Sticker>>>,
+    },
+    RecordStickerUse {
+        sticker: Sticker,
+        used_at: i64,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    ListRecentStickers {
+        resp: oneshot::Sender<Result<Vec<Sticker>>>,
+    },
+    SetStickerFavourite {
+        sticker: Sticker,
+        favourite: bool,
+        resp: oneshot::Sender<Result<bool>>,
+    },
+    ListFavouriteStickers {
+        resp: oneshot::Sender<Result<Vec<Sticker>>>,
+    },
     UpdateLocalMessageId {
         message_id: u64,
         local_message_id: u64,
@@ -232,6 +407,10 @@ enum StorageCmd {
         server_message_id: u64,
         resp: oneshot::Sender<Result<Option<u64>>>,
     },
+    FindMessageIdByServerMessageId {
+        server_message_id: u64,
+        resp: oneshot::Sender<Result<Option<u64>>>,
+    },
     UpdateMessageStatus {
         message_id: u64,
         status: i32,
@@ -303,6 +482,19 @@ enum StorageCmd {
         edited_at: i32,
         resp: oneshot::Sender<Result<()>>,
     },
+    ApplyMessageRevision {
+        message_id: u64,
+        input: MessageRevisionInput,
+        resp: oneshot::Sender<Result<Option<u64>>>,
+    },
+    DiscardPendingMessageEdit {
+        message_id: u64,
+        resp: oneshot::Sender<Result<bool>>,
+    },
+    ListMessageRevisions {
+        message_id: u64,
+        resp: oneshot::Sender<Result<Vec<MessageRevision>>>,
+    },
     SetMessagePinned {
         message_id: u64,
         is_pinned: bool,
@@ -334,6 +526,22 @@ enum StorageCmd {
         channel_type: i32,
         resp: oneshot::Sender<Result<Option<u64>>>,
     },
+    SaveMemberReadPts {
+        channel_id: u64,
+        channel_type: i32,
+        reader_id: u64,
+        read_pts: u64,
+        resp: oneshot::Sender<Result<bool>>,
+    },
+    ListMemberReadCursors {
+        channel_id: u64,
+        channel_type: i32,
+        resp: oneshot::Sender<Result<Vec<MemberReadCursor>>>,
+    },
+    GetMessageReadReceipts {
+        message_id: u64,
+        resp: oneshot::Sender<Result<Option<MessageReadReceipts>>>,
+    },
     GetChannelUnreadCount {
         channel_id: u64,
         channel_type: i32,
@@ -348,6 +556,9 @@ enum StorageCmd {
         exclude_muted: bool,
         resp: oneshot::Sender<Result<i32>>,
     },
+    ListChannelUnreadRows {
+        resp: oneshot::Sender<Result<Vec<ChannelUnreadRow>>>,
+    },
     UpsertUser {
         input: UpsertUserInput,
         resp: oneshot::Sender<Result<()>>,
@@ -549,6 +760,10 @@ enum StorageCmd {
         key: String,
         resp: oneshot::Sender<Result<()>>,
     },
+    KvScanPrefix {
+        prefix: String,
+        resp: oneshot::Sender<Result<Vec<(String, Vec<u8>)>>>,
+    },
     GetStoragePaths {
         resp: oneshot::Sender<Result<StoragePaths>>,
     },
@@ -566,17 +781,15 @@ pub struct StorageHandle {
 
 impl StorageHandle {
     pub fn start() -> Result<Self> {
-        let store = LocalStore::open_default()?;
-        let (tx, rx) = mpsc::channel::<StorageCmd>();
-        thread::Builder::new()
-            .name("privchat-db-actor".to_string())
-            .spawn(move || run_loop(store, rx))
-            .map_err(|e| Error::Storage(format!("spawn db actor: {e}")))?;
-        Ok(Self { tx })
+        Self::start_with(LocalStore::open_default()?)
     }
 
     pub fn start_at(base_dir: std::path::PathBuf) -> Result<Self> {
-        let store = LocalStore::open_at(base_dir)?;
+        Self::start_with(LocalStore::open_at(base_dir)?)
+    }
+
+    /// 在已经打开的 store 上起 actor 线程（多账号时传 [`LocalStore::scoped`] 的副本）。
+    pub fn start_with(store: LocalStore) -> Result<Self> {
         let (tx, rx) = mpsc::channel::<StorageCmd>();
         thread::Builder::new()
             .name("privchat-db-actor".to_string())
@@ -807,6 +1020,28 @@ impl StorageHandle {
         resp_rx.await.map_err(|_| Error::ActorClosed)?
     }
 
+    pub async fn list_messages_before(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+        before_local_id: u64,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<StoredMessage>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::ListMessagesBefore {
+                channel_id,
+                channel_type,
+                before_local_id,
+                limit,
+                offset,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
     /// 以 anchor 为轴的本地上下文窗口（显示排序，spec §5 跳转渲染原语）
     pub async fn list_messages_around(
         &self,
@@ -876,6 +1111,69 @@ impl StorageHandle {
         resp_rx.await.map_err(|_| Error::ActorClosed)?
     }
 
+    pub async fn query_channels(&self, query: ChannelListQuery) -> Result<ChannelListPage> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::QueryChannels {
+                query,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub(crate) async fn upsert_channel_pref(&self, pref: ChannelPrefRow) -> Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::UpsertChannelPref {
+                pref,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub(crate) async fn replace_channel_prefs(&self, prefs: Vec<ChannelPrefRow>) -> Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::ReplaceChannelPrefs {
+                prefs,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn upsert_channel_folder(&self, input: ChannelFolderInput) -> Result<ChannelFolder> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::UpsertChannelFolder {
+                input,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn delete_channel_folder(&self, folder_id: u64) -> Result<bool> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::DeleteChannelFolder {
+                folder_id,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn list_channel_folders(&self) -> Result<Vec<ChannelFolder>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::ListChannelFolders { resp: resp_tx })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
     pub async fn list_channel_identifiers_after(
         &self,
         after_channel_id: u64,
@@ -1004,6 +1302,15 @@ impl StorageHandle {
         resp_rx.await.map_err(|_| Error::ActorClosed)?
     }
 
+    /// `(pending 条数, 最老一条的 created_at 毫秒)`
+    pub async fn outbox_stats(&self) -> Result<(u64, Option<i64>)> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::OutboxStats { resp: resp_tx })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
     /// 确认送达：更新消息 + 删除 outbox 行，同一事务。失败即整体回滚。
     pub async fn outbox_ack_sent(
         &self,
@@ -1082,6 +1389,398 @@ impl StorageHandle {
         resp_rx.await.map_err(|_| Error::ActorClosed)?
     }
 
+    pub async fn outbox_peek_rpc(
+        &self,
+        limit: usize,
+        now_ms: i64,
+    ) -> Result<Vec<OutboxRpcCommand>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::OutboxPeekRpc {
+                limit,
+                now_ms,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn outbox_rpc_bump_retry(
+        &self,
+        outbox_id: i64,
+        next_attempt_at: i64,
+        last_error: &str,
+    ) -> Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::OutboxRpcBumpRetry {
+                outbox_id,
+                next_attempt_at,
+                last_error: last_error.to_string(),
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn outbox_rpc_drop(&self, outbox_id: i64) -> Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::OutboxRpcDrop {
+                outbox_id,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    /// 应用服务端票数；返回本地状态是否有变化。
+    pub async fn apply_poll_update(
+        &self,
+        message_id: u64,
+        update: PollTallyUpdate,
+    ) -> Result<bool> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::ApplyPollUpdate {
+                message_id,
+                update,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    /// 记下自己的选择 + 入队投票命令，同一事务。
+    pub async fn poll_record_vote(
+        &self,
+        command_id: String,
+        command: PollVoteCommand,
+    ) -> Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::PollRecordVote {
+                command_id,
+                command,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    /// 投票命令的结果：删除命令 + 收尾本地选择，同一事务。
+    pub async fn poll_vote_settled(
+        &self,
+        outbox_id: i64,
+        command: PollVoteCommand,
+        accepted: bool,
+        update: Option<PollTallyUpdate>,
+    ) -> Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::PollVoteSettled {
+                outbox_id,
+                command,
+                accepted,
+                update,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    /// 应用服务端下发的实时位置；返回本地状态是否有变化。
+    pub async fn apply_live_location_update(
+        &self,
+        message_id: u64,
+        update: LiveLocationUpdate,
+    ) -> Result<bool> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::ApplyLiveLocationUpdate {
+                message_id,
+                update,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    /// 记下自己的位置 / 结束 + 入队共享命令，同一事务；返回命令的 `next_attempt_at`。
+    pub async fn live_location_record(
+        &self,
+        command_id: String,
+        command: LiveLocationCommand,
+    ) -> Result<i64> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::LiveLocationRecord {
+                command_id,
+                command,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    /// 共享命令的结果：删除命令 + 收尾本地状态，同一事务。
+    pub async fn live_location_settled(
+        &self,
+        outbox_id: i64,
+        command: LiveLocationCommand,
+        outcome: LiveLocationSettle,
+        update: Option<LiveLocationUpdate>,
+    ) -> Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::LiveLocationSettled {
+                outbox_id,
+                command,
+                outcome,
+                update,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    /// 改本地置顶索引 + 入队置顶命令，同一事务。
+    pub async fn message_pin_record(
+        &self,
+        command_id: String,
+        command: MessagePinCommand,
+        pinned_by: Option<u64>,
+    ) -> Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::MessagePinRecord {
+                command_id,
+                command,
+                pinned_by,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    /// 置顶命令的结果：删除命令，服务端接受时落定置顶人 / 时间，同一事务。
+    pub async fn message_pin_settled(
+        &self,
+        outbox_id: i64,
+        command: MessagePinCommand,
+        confirmed: Option<RemotePin>,
+    ) -> Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::MessagePinSettled {
+                outbox_id,
+                command,
+                confirmed,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn apply_remote_pin(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+        pin: RemotePin,
+        pinned: bool,
+    ) -> Result<bool> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::ApplyRemotePin {
+                channel_id,
+                channel_type,
+                pin,
+                pinned,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn replace_channel_pins(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+        pins: Vec<RemotePin>,
+    ) -> Result<bool> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::ReplaceChannelPins {
+                channel_id,
+                channel_type,
+                pins,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn list_pinned_messages(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+    ) -> Result<Vec<PinnedMessage>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::ListPinnedMessages {
+                channel_id,
+                channel_type,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn cache_sticker_packages(&self, packages: Vec<StickerPackage>) -> Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::CacheStickerPackages {
+                packages,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn get_sticker_package(&self, package_id: String) -> Result<Option<StickerPackage>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::GetStickerPackage {
+                package_id,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn list_sticker_packages(&self, installed_only: bool) -> Result<Vec<StickerPackage>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::ListStickerPackages {
+                installed_only,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    /// 改本地安装状态 + 入队装 / 卸命令，同一事务。
+    pub async fn sticker_install_record(
+        &self,
+        command_id: String,
+        command: StickerInstallCommand,
+    ) -> Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::StickerInstallRecord {
+                command_id,
+                command,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    /// 装 / 卸命令的结果：删除命令，被拒绝时退回原状态，同一事务。
+    pub async fn sticker_install_settled(
+        &self,
+        outbox_id: i64,
+        command: StickerInstallCommand,
+        accepted: bool,
+    ) -> Result<bool> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::StickerInstallSettled {
+                outbox_id,
+                command,
+                accepted,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn apply_remote_sticker_installs(
+        &self,
+        installs: Vec<RemoteStickerInstall>,
+    ) -> Result<Vec<RemoteStickerInstall>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::ApplyRemoteStickerInstalls {
+                installs,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn reorder_sticker_packages(&self, package_ids: Vec<String>) -> Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::ReorderStickerPackages {
+                package_ids,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn get_sticker(&self, sticker_id: String) -> Result<Option<Sticker>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::GetSticker {
+                sticker_id,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn record_sticker_use(&self, sticker: Sticker, used_at: i64) -> Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::RecordStickerUse {
+                sticker,
+                used_at,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn list_recent_stickers(&self) -> Result<Vec<Sticker>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::ListRecentStickers { resp: resp_tx })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn set_sticker_favourite(&self, sticker: Sticker, favourite: bool) -> Result<bool> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::SetStickerFavourite {
+                sticker,
+                favourite,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn list_favourite_stickers(&self) -> Result<Vec<Sticker>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::ListFavouriteStickers { resp: resp_tx })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
     pub async fn update_message_content(&self, message_id: u64, content: &str) -> Result<()> {
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
@@ -1121,17 +1820,31 @@ impl StorageHandle {
         resp_rx.await.map_err(|_| Error::ActorClosed)?
     }
 
-    pub async fn get_message_id_by_server_message_id(
+    pub async fn get_message_id_by_server_message_id(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+        server_message_id: u64,
+    ) -> Result<Option<u64>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::GetMessageIdByServerMessageId {
+                channel_id,
+                channel_type,
+                server_message_id,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn find_message_id_by_server_message_id(
         &self,
-        channel_id: u64,
-        channel_type: i32,
         server_message_id: u64,
     ) -> Result<Option<u64>> {
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
-            .send(StorageCmd::GetMessageIdByServerMessageId {
-                channel_id,
-                channel_type,
+            .send(StorageCmd::FindMessageIdByServerMessageId {
                 server_message_id,
                 resp: resp_tx,
             })
@@ -1318,6 +2031,44 @@ impl StorageHandle {
         resp_rx.await.map_err(|_| Error::ActorClosed)?
     }
 
+    pub async fn apply_message_revision(
+        &self,
+        message_id: u64,
+        input: MessageRevisionInput,
+    ) -> Result<Option<u64>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::ApplyMessageRevision {
+                message_id,
+                input,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn discard_pending_message_edit(&self, message_id: u64) -> Result<bool> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::DiscardPendingMessageEdit {
+                message_id,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn list_message_revisions(&self, message_id: u64) -> Result<Vec<MessageRevision>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::ListMessageRevisions {
+                message_id,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
     pub async fn set_message_pinned(&self, message_id: u64, is_pinned: bool) -> Result<()> {
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
@@ -1393,6 +2144,56 @@ impl StorageHandle {
         resp_rx.await.map_err(|_| Error::ActorClosed)?
     }
 
+    pub async fn save_member_read_pts(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+        reader_id: u64,
+        read_pts: u64,
+    ) -> Result<bool> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::SaveMemberReadPts {
+                channel_id,
+                channel_type,
+                reader_id,
+                read_pts,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn list_member_read_cursors(
+        &self,
+        channel_id: u64,
+        channel_type: i32,
+    ) -> Result<Vec<MemberReadCursor>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::ListMemberReadCursors {
+                channel_id,
+                channel_type,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
+    pub async fn get_message_read_receipts(
+        &self,
+        message_id: u64,
+    ) -> Result<Option<MessageReadReceipts>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::GetMessageReadReceipts {
+                message_id,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
     pub async fn get_peer_read_pts(
         &self,
         channel_id: u64,
@@ -1452,6 +2253,14 @@ impl StorageHandle {
         resp_rx.await.map_err(|_| Error::ActorClosed)?
     }
 
+    pub(crate) async fn list_channel_unread_rows(&self) -> Result<Vec<ChannelUnreadRow>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::ListChannelUnreadRows { resp: resp_tx })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
     pub async fn upsert_user(&self, input: UpsertUserInput) -> Result<()> {
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
@@ -2018,6 +2827,17 @@ impl StorageHandle {
         resp_rx.await.map_err(|_| Error::ActorClosed)?
     }
 
+    pub async fn kv_scan_prefix(&self, prefix: String) -> Result<Vec<(String, Vec<u8>)>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.tx
+            .send(StorageCmd::KvScanPrefix {
+                prefix,
+                resp: resp_tx,
+            })
+            .map_err(|_| Error::ActorClosed)?;
+        resp_rx.await.map_err(|_| Error::ActorClosed)?
+    }
+
     pub async fn get_storage_paths(&self) -> Result<StoragePaths> {
         let (resp_tx, resp_rx) = oneshot::channel();
         self.tx
@@ -2227,6 +3047,23 @@ fn handle_single_cmd(store: &LocalStore, cmd: StorageCmd) {
                 offset
             ));
         }
+        StorageCmd::ListMessagesBefore {
+            channel_id,
+            channel_type,
+            before_local_id,
+            limit,
+            offset,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store.list_messages_before(
+                &uid,
+                channel_id,
+                channel_type,
+                Some(before_local_id),
+                limit,
+                offset
+            ));
+        }
         StorageCmd::ListMessagesAround {
             channel_id,
             channel_type,
@@ -2268,6 +3105,24 @@ fn handle_single_cmd(store: &LocalStore, cmd: StorageCmd) {
         } => {
             with_uid!(resp, |uid| store.list_channels(&uid, limit, offset));
         }
+        StorageCmd::QueryChannels { query, resp } => {
+            with_uid!(resp, |uid| store.query_channels(&uid, &query));
+        }
+        StorageCmd::UpsertChannelPref { pref, resp } => {
+            with_uid!(resp, |uid| store.upsert_channel_pref(&uid, &pref));
+        }
+        StorageCmd::ReplaceChannelPrefs { prefs, resp } => {
+            with_uid!(resp, |uid| store.replace_channel_prefs(&uid, &prefs));
+        }
+        StorageCmd::UpsertChannelFolder { input, resp } => {
+            with_uid!(resp, |uid| store.upsert_channel_folder(&uid, &input));
+        }
+        StorageCmd::DeleteChannelFolder { folder_id, resp } => {
+            with_uid!(resp, |uid| store.delete_channel_folder(&uid, folder_id));
+        }
+        StorageCmd::ListChannelFolders { resp } => {
+            with_uid!(resp, |uid| store.list_channel_folders(&uid));
+        }
         StorageCmd::UpsertChannelExtra { input, resp } => {
             with_uid!(resp, |uid| store.upsert_channel_extra(&uid, &input));
         }
@@ -2342,6 +3197,9 @@ fn handle_single_cmd(store: &LocalStore, cmd: StorageCmd) {
                 route_key.as_deref()
             ));
         }
+        StorageCmd::OutboxStats { resp } => {
+            with_uid!(resp, |uid| store.outbox_stats(&uid));
+        }
         StorageCmd::OutboxPeek {
             command_type,
             limit,
@@ -2409,6 +3267,228 @@ fn handle_single_cmd(store: &LocalStore, cmd: StorageCmd) {
                 server_message_id
             ));
         }
+        StorageCmd::OutboxPeekRpc {
+            limit,
+            now_ms,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store.outbox_peek_rpc(&uid, limit, now_ms));
+        }
+        StorageCmd::OutboxRpcBumpRetry {
+            outbox_id,
+            next_attempt_at,
+            last_error,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store.outbox_rpc_bump_retry(
+                &uid,
+                outbox_id,
+                next_attempt_at,
+                &last_error
+            ));
+        }
+        StorageCmd::OutboxRpcDrop { outbox_id, resp } => {
+            with_uid!(resp, |uid| store.outbox_rpc_drop(&uid, outbox_id));
+        }
+        StorageCmd::ApplyPollUpdate {
+            message_id,
+            update,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store
+                .apply_poll_update(&uid, message_id, &update));
+        }
+        StorageCmd::PollRecordVote {
+            command_id,
+            command,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store.poll_record_vote(
+                &uid,
+                &command_id,
+                &command
+            ));
+        }
+        StorageCmd::PollVoteSettled {
+            outbox_id,
+            command,
+            accepted,
+            update,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store.poll_vote_settled(
+                &uid,
+                outbox_id,
+                &command,
+                accepted,
+                update.as_ref()
+            ));
+        }
+        StorageCmd::ApplyLiveLocationUpdate {
+            message_id,
+            update,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store
+                .apply_live_location_update(&uid, message_id, &update));
+        }
+        StorageCmd::LiveLocationRecord {
+            command_id,
+            command,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store.live_location_record(
+                &uid,
+                &command_id,
+                &command
+            ));
+        }
+        StorageCmd::LiveLocationSettled {
+            outbox_id,
+            command,
+            outcome,
+            update,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store.live_location_settled(
+                &uid,
+                outbox_id,
+                &command,
+                outcome,
+                update.as_ref()
+            ));
+        }
+        StorageCmd::MessagePinRecord {
+            command_id,
+            command,
+            pinned_by,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store.message_pin_record(
+                &uid,
+                &command_id,
+                &command,
+                pinned_by
+            ));
+        }
+        StorageCmd::MessagePinSettled {
+            outbox_id,
+            command,
+            confirmed,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store.message_pin_settled(
+                &uid,
+                outbox_id,
+                &command,
+                confirmed.as_ref()
+            ));
+        }
+        StorageCmd::ApplyRemotePin {
+            channel_id,
+            channel_type,
+            pin,
+            pinned,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store.apply_remote_pin(
+                &uid,
+                channel_id,
+                channel_type,
+                &pin,
+                pinned
+            ));
+        }
+        StorageCmd::ReplaceChannelPins {
+            channel_id,
+            channel_type,
+            pins,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store.replace_channel_pins(
+                &uid,
+                channel_id,
+                channel_type,
+                &pins
+            ));
+        }
+        StorageCmd::ListPinnedMessages {
+            channel_id,
+            channel_type,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store.list_pinned_messages(
+                &uid,
+                channel_id,
+                channel_type
+            ));
+        }
+        StorageCmd::CacheStickerPackages { packages, resp } => {
+            with_uid!(resp, |uid| store.cache_sticker_packages(&uid, &packages));
+        }
+        StorageCmd::GetStickerPackage { package_id, resp } => {
+            with_uid!(resp, |uid| store.get_sticker_package(&uid, &package_id));
+        }
+        StorageCmd::ListStickerPackages {
+            installed_only,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store
+                .list_sticker_packages(&uid, installed_only));
+        }
+        StorageCmd::StickerInstallRecord {
+            command_id,
+            command,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store.sticker_install_record(
+                &uid,
+                &command_id,
+                &command
+            ));
+        }
+        StorageCmd::StickerInstallSettled {
+            outbox_id,
+            command,
+            accepted,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store
+                .sticker_install_settled(&uid, outbox_id, &command, accepted));
+        }
+        StorageCmd::ApplyRemoteStickerInstalls { installs, resp } => {
+            with_uid!(resp, |uid| store
+                .apply_remote_sticker_installs(&uid, &installs));
+        }
+        StorageCmd::ReorderStickerPackages { package_ids, resp } => {
+            with_uid!(resp, |uid| store
+                .reorder_sticker_packages(&uid, &package_ids));
+        }
+        StorageCmd::GetSticker { sticker_id, resp } => {
+            with_uid!(resp, |uid| store.get_sticker(&uid, &sticker_id));
+        }
+        StorageCmd::RecordStickerUse {
+            sticker,
+            used_at,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store
+                .record_sticker_use(&uid, &sticker, used_at));
+        }
+        StorageCmd::ListRecentStickers { resp } => {
+            with_uid!(resp, |uid| store.list_recent_stickers(&uid));
+        }
+        StorageCmd::SetStickerFavourite {
+            sticker,
+            favourite,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store
+                .set_sticker_favourite(&uid, &sticker, favourite));
+        }
+        StorageCmd::ListFavouriteStickers { resp } => {
+            with_uid!(resp, |uid| store.list_favourite_stickers(&uid));
+        }
         StorageCmd::UpdateLocalMessageId {
             message_id,
             local_message_id,
@@ -2444,6 +3524,13 @@ fn handle_single_cmd(store: &LocalStore, cmd: StorageCmd) {
                 server_message_id
             ));
         }
+        StorageCmd::FindMessageIdByServerMessageId {
+            server_message_id,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store
+                .find_message_id_by_server_message_id(&uid, server_message_id));
+        }
         StorageCmd::UpdateMessageStatus {
             message_id,
             status,
@@ -2563,6 +3650,20 @@ fn handle_single_cmd(store: &LocalStore, cmd: StorageCmd) {
             with_uid!(resp, |uid| store
                 .edit_message(&uid, message_id, &content, edited_at));
         }
+        StorageCmd::ApplyMessageRevision {
+            message_id,
+            input,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store
+                .apply_message_revision(&uid, message_id, &input));
+        }
+        StorageCmd::DiscardPendingMessageEdit { message_id, resp } => {
+            with_uid!(resp, |uid| store.discard_pending_message_edit(&uid, message_id));
+        }
+        StorageCmd::ListMessageRevisions { message_id, resp } => {
+            with_uid!(resp, |uid| store.list_message_revisions(&uid, message_id));
+        }
         StorageCmd::SetMessagePinned {
             message_id,
             is_pinned,
@@ -2611,6 +3712,36 @@ fn handle_single_cmd(store: &LocalStore, cmd: StorageCmd) {
                 peer_read_pts
             ));
         }
+        StorageCmd::SaveMemberReadPts {
+            channel_id,
+            channel_type,
+            reader_id,
+            read_pts,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store.save_member_read_pts(
+                &uid,
+                channel_id,
+                channel_type,
+                reader_id,
+                read_pts
+            ));
+        }
+        StorageCmd::ListMemberReadCursors {
+            channel_id,
+            channel_type,
+            resp,
+        } => {
+            with_uid!(resp, |uid| store.list_member_read_cursors(
+                &uid,
+                channel_id,
+                channel_type
+            ));
+        }
+        StorageCmd::GetMessageReadReceipts { message_id, resp } => {
+            with_uid!(resp, |uid| store
+                .get_message_read_receipts(&uid, message_id));
+        }
         StorageCmd::GetPeerReadPts {
             channel_id,
             channel_type,
@@ -2651,6 +3782,9 @@ fn handle_single_cmd(store: &LocalStore, cmd: StorageCmd) {
             with_uid!(resp, |uid| store
                 .get_total_unread_count(&uid, exclude_muted));
         }
+        StorageCmd::ListChannelUnreadRows { resp } => {
+            with_uid!(resp, |uid| store.list_channel_unread_rows(&uid));
+        }
         StorageCmd::UpsertUser { input, resp } => {
             with_uid!(resp, |uid| store.upsert_user(&uid, &input));
         }
@@ -2941,6 +4075,9 @@ fn handle_single_cmd(store: &LocalStore, cmd: StorageCmd) {
         StorageCmd::KvDelete { key, resp } => {
             with_uid!(resp, |uid| store.kv_delete(&uid, &key));
         }
+        StorageCmd::KvScanPrefix { prefix, resp } => {
+            with_uid!(resp, |uid| store.kv_scan_prefix(&uid, &prefix));
+        }
         StorageCmd::GetStoragePaths { resp } => {
             with_uid!(resp, |uid| store.ensure_user_storage(&uid));
         }
@@ -0,0 +1,448 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 入站帧录制与回放：复现同步类 bug（重复行、卡「发送中」、投影损坏）。
+//!
+//! 这些 bug 只看日志几乎无法复现——日志里没有帧本身，而决定结果的恰恰是帧的**内容和
+//! 次序**。录制器挂在 actor 上，按到达次序记下三样东西：
+//!
+//! - `Command::InboundFrame` 的原始帧（`biz_type` + payload）；
+//! - RPC 应答（route + code + data）；
+//! - 命令流（**只记命令名**，不记参数——参数里有密码、token、消息正文）。
+//!
+//! 文件格式：`MAGIC(8B) || { len(u32 BE) || nonce(12B) || ct || tag(16B) }*`。
+//! 每条记录独立 AES-256-GCM 加密，AAD 是记录序号，所以中间删条、调换次序都会解密失败。
+//! 密钥只在 [`TraceRecorder::start`] 时返回一次（base64url，与附件 CEK 同口径），
+//! **绝不落盘、绝不进日志**：trace 文件本身可以随 bug 报告上传，密钥走另一条通道。
+//!
+//! 回放（[`replay_trace`]）把帧喂给一个**全新**的 `PrivchatSdk`（空库、无 transport），
+//! 宿主/测试据 [`TraceReplayReport`] 里的事件序列和库内容做断言。RPC 应答只作为上下文
+//! 保留：没有 transport 的实例不会发请求，也就没有地方消费它们。
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::attachment_crypto::{CEK_LEN, NONCE_LEN, TAG_LEN};
+use crate::{Error, PrivchatSdk, Result, SequencedSdkEvent};
+
+pub const TRACE_MAGIC: &[u8; 8] = b"PCTRACE1";
+/// 单条记录的上限。超过它的只可能是文件损坏——正常的一帧远小于此。
+const MAX_RECORD_LEN: usize = 64 * 1024 * 1024;
+/// 被脱敏的 RPC 应答里，data 替换成这串字节。
+const REDACTED: &[u8] = b"<redacted>";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TraceRecord {
+    /// 永远是第一条。`uid` 决定回放时数据落在哪个账号目录下。
+    Header { uid: Option<String>, sdk_version: String },
+    Command { name: String },
+    InboundFrame { biz_type: u8, data: Vec<u8> },
+    RpcResponse {
+        route: String,
+        code: i64,
+        data: Option<Vec<u8>>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub seq: u64,
+    /// UTC 毫秒时间戳。
+    pub at_ms: i64,
+    pub record: TraceRecord,
+}
+
+/// 这些 route 的应答里有凭证（token / 签名 URL / CEK），只保留 route 和 code。
+fn rpc_route_is_sensitive(route: &str) -> bool {
+    use privchat_protocol::rpc::routes;
+    matches!(
+        route,
+        routes::auth::LOGIN
+            | routes::auth::REFRESH
+            | routes::account_user::REGISTER
+            | routes::qr_login::CREATE_SCENE
+            | routes::file::GET_URL
+            | routes::file::REQUEST_UPLOAD_TOKEN
+            | routes::file::REQUEST_CHUNKED_UPLOAD_TOKEN
+    )
+}
+
+fn cipher_from_key(key_b64: &str) -> Result<Aes256Gcm> {
+    let key = URL_SAFE_NO_PAD
+        .decode(key_b64.as_bytes())
+        .map_err(|_| Error::InvalidState("trace key is not valid base64url".to_string()))?;
+    if key.len() != CEK_LEN {
+        return Err(Error::InvalidState(format!(
+            "trace key must be {} bytes, got {}",
+            CEK_LEN,
+            key.len()
+        )));
+    }
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+}
+
+/// 正在录制的 trace。由 actor 独占（`State::trace_recorder`），不跨线程共享。
+pub(crate) struct TraceRecorder {
+    writer: BufWriter<File>,
+    cipher: Aes256Gcm,
+    next_seq: u64,
+}
+
+impl TraceRecorder {
+    /// 新建 trace 文件并写入 header。返回 `(recorder, key_b64)`。
+    pub(crate) fn start(path: &Path, uid: Option<String>) -> Result<(Self, String)> {
+        let mut key = [0u8; CEK_LEN];
+        rand::thread_rng().fill_bytes(&mut key);
+        let key_b64 = URL_SAFE_NO_PAD.encode(key);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| Error::Storage(format!("create trace dir: {e}")))?;
+        }
+        let file =
+            File::create(path).map_err(|e| Error::Storage(format!("create trace file: {e}")))?;
+        let mut recorder = Self {
+            writer: BufWriter::new(file),
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+            next_seq: 0,
+        };
+        recorder
+            .writer
+            .write_all(TRACE_MAGIC)
+            .map_err(|e| Error::Storage(format!("write trace magic: {e}")))?;
+        recorder.append(TraceRecord::Header {
+            uid,
+            sdk_version: env!("CARGO_PKG_VERSION").to_string(),
+        })?;
+        Ok((recorder, key_b64))
+    }
+
+    pub(crate) fn record_command(&mut self, name: &'static str) {
+        self.append_best_effort(TraceRecord::Command {
+            name: name.to_string(),
+        });
+    }
+
+    pub(crate) fn record_inbound_frame(&mut self, biz_type: u8, data: &[u8]) {
+        self.append_best_effort(TraceRecord::InboundFrame {
+            biz_type,
+            data: data.to_vec(),
+        });
+    }
+
+    pub(crate) fn record_rpc_response(&mut self, route: &str, code: i64, data: Option<&[u8]>) {
+        let data = if rpc_route_is_sensitive(route) {
+            data.map(|_| REDACTED.to_vec())
+        } else {
+            data.map(<[u8]>::to_vec)
+        };
+        self.append_best_effort(TraceRecord::RpcResponse {
+            route: route.to_string(),
+            code,
+            data,
+        });
+    }
+
+    /// 刷盘并关闭。返回写入的记录数（含 header）。
+    pub(crate) fn finish(mut self) -> Result<u64> {
+        self.writer
+            .flush()
+            .map_err(|e| Error::Storage(format!("flush trace: {e}")))?;
+        Ok(self.next_seq)
+    }
+
+    /// 录制是诊断手段：写失败只打日志，绝不让它反过来影响收发。
+    fn append_best_effort(&mut self, record: TraceRecord) {
+        if let Err(e) = self.append(record) {
+            eprintln!("[SDK.trace] append failed: {e}");
+        }
+    }
+
+    fn append(&mut self, record: TraceRecord) -> Result<()> {
+        let seq = self.next_seq;
+        let entry = TraceEntry {
+            seq,
+            at_ms: chrono::Utc::now().timestamp_millis(),
+            record,
+        };
+        let plain = serde_json::to_vec(&entry)
+            .map_err(|e| Error::Serialization(format!("encode trace entry: {e}")))?;
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let aad = seq.to_be_bytes();
+        let sealed = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &plain,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| Error::Serialization("trace entry encrypt failed".to_string()))?;
+        let len = (NONCE_LEN + sealed.len()) as u32;
+        self.writer
+            .write_all(&len.to_be_bytes())
+            .and_then(|_| self.writer.write_all(&nonce))
+            .and_then(|_| self.writer.write_all(&sealed))
+            .map_err(|e| Error::Storage(format!("write trace entry: {e}")))?;
+        self.next_seq += 1;
+        Ok(())
+    }
+}
+
+/// 读出并解密整份 trace。任何一条认证失败（错 key / 篡改 / 删条 / 调换次序）整份拒绝。
+///
+/// 末尾半条记录（录制中进程崩溃）不算错：丢掉它，返回前面完整的部分。
+pub fn read_trace(path: &Path, key_b64: &str) -> Result<Vec<TraceEntry>> {
+    let cipher = cipher_from_key(key_b64)?;
+    let mut raw = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut raw))
+        .map_err(|e| Error::Storage(format!("read trace file: {e}")))?;
+    if raw.len() < TRACE_MAGIC.len() || &raw[..TRACE_MAGIC.len()] != TRACE_MAGIC {
+        return Err(Error::Serialization("not a privchat trace file".to_string()));
+    }
+    let mut cursor = TRACE_MAGIC.len();
+    let mut entries = Vec::new();
+    while cursor + 4 <= raw.len() {
+        let len = u32::from_be_bytes(raw[cursor..cursor + 4].try_into().expect("4 bytes")) as usize;
+        if !(NONCE_LEN + TAG_LEN..=MAX_RECORD_LEN).contains(&len) {
+            return Err(Error::Serialization(format!("invalid trace record length {len}")));
+        }
+        let start = cursor + 4;
+        if start + len > raw.len() {
+            break;
+        }
+        let (nonce, sealed) = raw[start..start + len].split_at(NONCE_LEN);
+        let seq = entries.len() as u64;
+        let aad = seq.to_be_bytes();
+        let plain = cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: sealed,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| Error::Serialization(format!("trace record {seq} auth failed")))?;
+        let entry: TraceEntry = serde_json::from_slice(&plain)
+            .map_err(|e| Error::Serialization(format!("decode trace entry {seq}: {e}")))?;
+        entries.push(entry);
+        cursor = start + len;
+    }
+    match entries.first().map(|e| &e.record) {
+        Some(TraceRecord::Header { .. }) => Ok(entries),
+        _ => Err(Error::Serialization("trace has no header".to_string())),
+    }
+}
+
+/// 一次回放的结果。
+#[derive(Debug, Clone)]
+pub struct TraceReplayReport {
+    pub uid: Option<String>,
+    /// 喂进去的入站帧数。
+    pub frames_replayed: usize,
+    /// `handle_inbound_frame` 报告落库的条数之和。
+    pub items_applied: usize,
+    /// 回放期间 SDK 发出的全部事件（按 sequence_id 升序）。
+    pub events: Vec<SequencedSdkEvent>,
+}
+
+/// 把 trace 喂给一个全新的 `sdk`。
+///
+/// `sdk` 必须没有活跃会话（从未 connect）；header 里的 uid 会被设为当前账号。
+/// 帧按录制次序逐条同步投递，每条都等 actor 处理完再投下一条，所以事件次序与线上一致。
+pub async fn replay_trace(sdk: &PrivchatSdk, entries: &[TraceEntry]) -> Result<TraceReplayReport> {
+    let uid = match entries.first().map(|e| &e.record) {
+        Some(TraceRecord::Header { uid, .. }) => uid.clone(),
+        _ => return Err(Error::InvalidState("trace has no header".to_string())),
+    };
+    let uid_for_replay = uid
+        .clone()
+        .ok_or_else(|| Error::InvalidState("trace was recorded without an account".to_string()))?;
+    sdk.begin_trace_replay(uid_for_replay).await?;
+    let start_seq = sdk.last_event_sequence_id();
+    let mut frames_replayed = 0usize;
+    let mut items_applied = 0usize;
+    for entry in entries {
+        if let TraceRecord::InboundFrame { biz_type, data } = &entry.record {
+            items_applied += sdk.replay_inbound_frame(*biz_type, data.clone()).await?;
+            frames_replayed += 1;
+        }
+    }
+    Ok(TraceReplayReport {
+        uid,
+        frames_replayed,
+        items_applied,
+        events: sdk.events_since(start_seq, sdk.event_history_limit()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_trace_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "privchat-trace-{name}-{}-{}.bin",
+            std::process::id(),
+            chrono::Utc::now().timestamp_micros()
+        ))
+    }
+
+    #[test]
+    fn recorded_entries_round_trip_in_order() {
+        let path = temp_trace_path("roundtrip");
+        let (mut recorder, key) =
+            TraceRecorder::start(&path, Some("10001".to_string())).expect("start");
+        recorder.record_command("SyncEntities");
+        recorder.record_inbound_frame(7, b"frame-bytes");
+        recorder.record_rpc_response("entity/sync_entities", 0, Some(b"{}"));
+        assert_eq!(recorder.finish().expect("finish"), 4);
+
+        let entries = read_trace(&path, &key).expect("read");
+        assert_eq!(entries.len(), 4);
+        assert!(entries.iter().enumerate().all(|(i, e)| e.seq == i as u64));
+        assert_eq!(
+            entries[2].record,
+            TraceRecord::InboundFrame {
+                biz_type: 7,
+                data: b"frame-bytes".to_vec()
+            }
+        );
+        let _ = std::fs::remove_file(path);
+    }
+
+    /// 凭证类应答只留 route/code：trace 文件是要随 bug 报告外传的。
+    #[test]
+    fn sensitive_rpc_responses_are_redacted() {
+        use privchat_protocol::rpc::routes;
+        let path = temp_trace_path("redact");
+        let (mut recorder, key) = TraceRecorder::start(&path, None).expect("start");
+        recorder.record_rpc_response(routes::auth::LOGIN, 0, Some(b"{\"token\":\"secret\"}"));
+        recorder.record_rpc_response(routes::file::GET_URL, 0, Some(b"{\"cek\":\"k\"}"));
+        recorder.finish().expect("finish");
+
+        let entries = read_trace(&path, &key).expect("read");
+        for entry in &entries[1..] {
+            match &entry.record {
+                TraceRecord::RpcResponse { data, .. } => {
+                    assert_eq!(data.as_deref(), Some(REDACTED));
+                }
+                other => panic!("unexpected record {other:?}"),
+            }
+        }
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn wrong_key_and_reordered_records_are_rejected() {
+        let path = temp_trace_path("tamper");
+        let (mut recorder, key) = TraceRecorder::start(&path, None).expect("start");
+        recorder.record_command("A");
+        recorder.record_command("B");
+        recorder.finish().expect("finish");
+
+        let other_key = URL_SAFE_NO_PAD.encode([7u8; CEK_LEN]);
+        assert!(read_trace(&path, &other_key).is_err());
+
+        // 调换第 2、3 条：每条自身完好，但 AAD 里的序号对不上。
+        let raw = std::fs::read(&path).expect("read raw");
+        let mut records = Vec::new();
+        let mut cursor = TRACE_MAGIC.len();
+        while cursor < raw.len() {
+            let len = u32::from_be_bytes(raw[cursor..cursor + 4].try_into().unwrap()) as usize;
+            records.push(raw[cursor..cursor + 4 + len].to_vec());
+            cursor += 4 + len;
+        }
+        records.swap(1, 2);
+        let mut swapped = TRACE_MAGIC.to_vec();
+        for r in records {
+            swapped.extend_from_slice(&r);
+        }
+        std::fs::write(&path, swapped).expect("write swapped");
+        assert!(read_trace(&path, &key).is_err());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn a_torn_tail_keeps_the_complete_prefix() {
+        let path = temp_trace_path("torn");
+        let (mut recorder, key) = TraceRecorder::start(&path, None).expect("start");
+        recorder.record_inbound_frame(1, b"x");
+        recorder.finish().expect("finish");
+        let mut raw = std::fs::read(&path).expect("read raw");
+        raw.truncate(raw.len() - 3);
+        std::fs::write(&path, raw).expect("write torn");
+
+        let entries = read_trace(&path, &key).expect("prefix survives");
+        assert_eq!(entries.len(), 1);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn replaying_a_push_frame_rebuilds_the_message_and_its_events() {
+        let path = temp_trace_path("replay");
+        let push = privchat_protocol::PushMessageRequest {
+            server_message_id: 905_001,
+            message_seq: 1,
+            channel_id: 95_001,
+            channel_type: 2,
+            from_uid: 20_001,
+            message_type: privchat_protocol::ContentMessageType::Text.as_u32(),
+            timestamp: 1_710_000_000,
+            payload: privchat_protocol::encode_message(
+                &privchat_protocol::MessagePayloadEnvelope {
+                    content: "replayed".to_string(),
+                    ..Default::default()
+                },
+            )
+            .expect("encode payload"),
+            ..Default::default()
+        };
+        let (mut recorder, key) =
+            TraceRecorder::start(&path, Some("10001".to_string())).expect("start");
+        recorder.record_inbound_frame(
+            u8::from(privchat_protocol::MessageType::PushMessageRequest),
+            &privchat_protocol::encode_message(&push).expect("encode push"),
+        );
+        recorder.finish().expect("finish");
+
+        let data_dir = std::env::temp_dir().join(format!(
+            "privchat-trace-replay-{}-{}",
+            std::process::id(),
+            chrono::Utc::now().timestamp_micros()
+        ));
+        let mut config = crate::PrivchatConfig::default();
+        config.data_dir = data_dir.display().to_string();
+        let sdk = PrivchatSdk::new(config);
+
+        let entries = read_trace(&path, &key).expect("read");
+        let report = replay_trace(&sdk, &entries).await.expect("replay");
+        assert_eq!(report.uid.as_deref(), Some("10001"));
+        assert_eq!(report.frames_replayed, 1);
+        assert_eq!(report.items_applied, 1);
+        assert!(report
+            .events
+            .iter()
+            .any(|e| matches!(e.event, crate::SdkEvent::TimelineUpdated { .. })));
+
+        let messages = sdk.list_messages(95_001, 2, 10, 0).await.expect("list");
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].server_message_id, Some(905_001));
+
+        let _ = sdk.shutdown().await;
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+}
@@ -0,0 +1,410 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 角标 / 未读聚合。
+//!
+//! `get_total_unread_count` 只是把 `channel.unread_count` 加起来：会话的 `mute` 和账号 kv
+//! 里的会话偏好（`notification_mode` / `favourite` / `low_priority` / `tags`）一概不看，
+//! 宿主想要一个和通知设置一致的桌面角标，只能把整个会话列表拉过 FFI 再自己算一遍。
+//! 这里把规则收在一处：
+//!
+//! | 会话状态 | 进角标的数 |
+//! |---|---|
+//! | [`notification_mode::ALL`] | 全部未读 |
+//! | [`notification_mode::DEFAULT`]，未 mute、非低优先级 | 全部未读 |
+//! | [`notification_mode::DEFAULT`]，mute 或低优先级 | 未读 @ |
+//! | [`notification_mode::MENTIONS_ONLY`] | 未读 @ |
+//! | [`notification_mode::NONE`] | 0 |
+//!
+//! 不认识的取值按 [`notification_mode::DEFAULT`] 算。除了按 tag，还按用户自建的会话
+//! 文件夹（[`crate::channel_query::ChannelFolder`]）各出一份合计。
+//!
+//! 未读 @ 按 `min(@ 数, 未读数)` 计：会话整体读过之后 mention 行未必同步标已读，
+//! 残留的 @ 不该让一个已读会话继续挂在角标上。
+//!
+//! 本模块是纯计算，不碰存储；取数和「只在结果变化时发
+//! [`SdkEvent::BadgeChanged`](crate::SdkEvent::BadgeChanged)」在 actor 里。
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// 会话通知模式（会话偏好里的 `notification_mode`）。FFI 上导出为
+/// `ChannelNotificationMode`，宿主不直接写这些整数。
+pub mod notification_mode {
+    /// 跟随会话自身的 `mute`：mute 或低优先级时只有 @ 进角标。
+    pub const DEFAULT: i32 = 0;
+    /// 所有消息都进角标，覆盖 `mute` 和低优先级。
+    pub const ALL: i32 = 1;
+    /// 只有 @ 进角标。
+    pub const MENTIONS_ONLY: i32 = 2;
+    /// 不提醒，@ 也不进角标（仍计入 `muted_mention_count`）。
+    pub const NONE: i32 = 3;
+}
+
+/// 会话算不算静音：偏好里的 `notification_mode` 优先，[`notification_mode::DEFAULT`]
+/// 时跟随 `channel.mute`。角标、会话列表的 `muted` 筛选和自动下载的
+/// `skip_muted_channels` 共用这一条规则。
+pub(crate) fn channel_is_muted(notification_mode: i32, channel_muted: bool) -> bool {
+    match notification_mode {
+        notification_mode::ALL => false,
+        notification_mode::MENTIONS_ONLY | notification_mode::NONE => true,
+        _ => channel_muted,
+    }
+}
+
+/// 某个 tag 下的未读合计。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnreadTagTotal {
+    pub tag: String,
+    /// 带这个 tag 的会话未读之和，不看通知模式。
+    pub unread_count: u32,
+    /// 带这个 tag 的会话对角标的贡献。
+    pub badge_count: u32,
+}
+
+/// 某个会话文件夹里的未读合计。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnreadFolderTotal {
+    pub folder_id: u64,
+    pub name: String,
+    /// 文件夹里（手选的和按条件收录的）会话未读之和，不看通知模式。
+    pub unread_count: u32,
+    /// 文件夹里的会话对角标的贡献。
+    pub badge_count: u32,
+}
+
+/// 一次未读聚合的结果。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnreadAggregate {
+    /// 桌面/launcher 角标应显示的数。
+    pub badge_count: u32,
+    /// 可见会话的未读之和，不看任何偏好。
+    pub total_unread: u32,
+    /// 对角标有贡献的会话数（「N 个会话有新消息」）。
+    pub badge_channel_count: u32,
+    /// 静音会话（mute / 只提醒 @ / 不提醒）里的未读之和。
+    pub muted_unread: u32,
+    /// 静音会话里的未读 @ 数：界面可以在静音图标旁单独标出来。
+    pub muted_mention_count: u32,
+    /// 收藏会话的未读之和。
+    pub favourite_unread: u32,
+    /// 低优先级会话的未读之和。
+    pub low_priority_unread: u32,
+    /// 按 tag 名排序。可见会话上出现过的 tag 都列出，没有未读的计数为 0，界面据此稳定渲染分组。
+    pub tags: Vec<UnreadTagTotal>,
+    /// 按文件夹的 `sort_order` 排序，每个文件夹都列出。文件夹是各账号自己的，多账号的
+    /// 总和（[`merge_unread`]）里没有这一项。
+    #[serde(default)]
+    pub folders: Vec<UnreadFolderTotal>,
+}
+
+/// 单个会话参与聚合所需的全部输入。
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ChannelUnreadInput {
+    pub unread_count: u32,
+    pub mention_count: u32,
+    /// `channel.mute`（服务端 / 会话设置下发的静音）。
+    pub channel_muted: bool,
+    pub notification_mode: i32,
+    pub favourite: bool,
+    pub low_priority: bool,
+    pub tags: Vec<String>,
+    /// 收录这个会话的文件夹。
+    pub folder_ids: Vec<u64>,
+}
+
+/// `channel` 表里聚合用的那几列；隐藏（`is_deleted`）的会话不在其中。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ChannelUnreadRow {
+    pub channel_id: u64,
+    pub channel_type: i32,
+    pub unread_count: i32,
+    pub mute: bool,
+}
+
+impl ChannelUnreadInput {
+    fn is_muted(&self) -> bool {
+        channel_is_muted(self.notification_mode, self.channel_muted)
+    }
+
+    fn badge_contribution(&self) -> u32 {
+        let mentions = self.mention_count.min(self.unread_count);
+        match self.notification_mode {
+            notification_mode::ALL => self.unread_count,
+            notification_mode::MENTIONS_ONLY => mentions,
+            notification_mode::NONE => 0,
+            _ if self.channel_muted || self.low_priority => mentions,
+            _ => self.unread_count,
+        }
+    }
+}
+
+/// `folders` 是当前账号的全部文件夹（计数为 0），按要输出的顺序；会话经
+/// [`ChannelUnreadInput::folder_ids`] 记到对应的那一项上。
+pub(crate) fn aggregate_unread<I>(channels: I, folders: Vec<UnreadFolderTotal>) -> UnreadAggregate
+where
+    I: IntoIterator<Item = ChannelUnreadInput>,
+{
+    let mut out = UnreadAggregate {
+        folders,
+        ..Default::default()
+    };
+    let mut tags: BTreeMap<String, UnreadTagTotal> = BTreeMap::new();
+    for channel in channels {
+        let unread = channel.unread_count;
+        let badge = channel.badge_contribution();
+        out.total_unread = out.total_unread.saturating_add(unread);
+        out.badge_count = out.badge_count.saturating_add(badge);
+        if badge > 0 {
+            out.badge_channel_count += 1;
+        }
+        if channel.is_muted() {
+            out.muted_unread = out.muted_unread.saturating_add(unread);
+            out.muted_mention_count = out
+                .muted_mention_count
+                .saturating_add(channel.mention_count.min(unread));
+        }
+        if channel.favourite {
+            out.favourite_unread = out.favourite_unread.saturating_add(unread);
+        }
+        if channel.low_priority {
+            out.low_priority_unread = out.low_priority_unread.saturating_add(unread);
+        }
+        let mut seen: Vec<&str> = Vec::with_capacity(channel.tags.len());
+        for tag in &channel.tags {
+            if tag.is_empty() || seen.contains(&tag.as_str()) {
+                continue;
+            }
+            seen.push(tag);
+            let total = tags.entry(tag.clone()).or_insert_with(|| UnreadTagTotal {
+                tag: tag.clone(),
+                ..Default::default()
+            });
+            total.unread_count = total.unread_count.saturating_add(unread);
+            total.badge_count = total.badge_count.saturating_add(badge);
+        }
+        for folder in out
+            .folders
+            .iter_mut()
+            .filter(|folder| channel.folder_ids.contains(&folder.folder_id))
+        {
+            folder.unread_count = folder.unread_count.saturating_add(unread);
+            folder.badge_count = folder.badge_count.saturating_add(badge);
+        }
+    }
+    out.tags = tags.into_values().collect();
+    out
+}
+
+/// 把几个账号各自的聚合加成一个（多账号时的总角标）。tag 按名字合并，仍按名字排序。
+pub(crate) fn merge_unread<'a, I>(aggregates: I) -> UnreadAggregate
+where
+    I: IntoIterator<Item = &'a UnreadAggregate>,
+{
+    let mut out = UnreadAggregate::default();
+    let mut tags: BTreeMap<String, UnreadTagTotal> = BTreeMap::new();
+    for agg in aggregates {
+        out.badge_count = out.badge_count.saturating_add(agg.badge_count);
+        out.total_unread = out.total_unread.saturating_add(agg.total_unread);
+        out.badge_channel_count = out
+            .badge_channel_count
+            .saturating_add(agg.badge_channel_count);
+        out.muted_unread = out.muted_unread.saturating_add(agg.muted_unread);
+        out.muted_mention_count = out
+            .muted_mention_count
+            .saturating_add(agg.muted_mention_count);
+        out.favourite_unread = out.favourite_unread.saturating_add(agg.favourite_unread);
+        out.low_priority_unread = out
+            .low_priority_unread
+            .saturating_add(agg.low_priority_unread);
+        for tag in &agg.tags {
+            let total = tags
+                .entry(tag.tag.clone())
+                .or_insert_with(|| UnreadTagTotal {
+                    tag: tag.tag.clone(),
+                    ..Default::default()
+                });
+            total.unread_count = total.unread_count.saturating_add(tag.unread_count);
+            total.badge_count = total.badge_count.saturating_add(tag.badge_count);
+        }
+    }
+    out.tags = tags.into_values().collect();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(unread: u32, mentions: u32) -> ChannelUnreadInput {
+        ChannelUnreadInput {
+            unread_count: unread,
+            mention_count: mentions,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn mute_and_low_priority_limit_the_badge_to_mentions() {
+        let muted = ChannelUnreadInput {
+            channel_muted: true,
+            ..channel(10, 2)
+        };
+        let low_priority = ChannelUnreadInput {
+            low_priority: true,
+            ..channel(6, 1)
+        };
+        let muted_quiet = ChannelUnreadInput {
+            channel_muted: true,
+            ..channel(9, 0)
+        };
+        let agg = aggregate_unread(
+            vec![channel(5, 0), muted, low_priority, muted_quiet],
+            Vec::new(),
+        );
+        assert_eq!(agg.badge_count, 5 + 2 + 1);
+        assert_eq!(agg.total_unread, 5 + 10 + 6 + 9);
+        assert_eq!(agg.badge_channel_count, 3);
+        assert_eq!(agg.muted_unread, 10 + 9);
+        assert_eq!(agg.muted_mention_count, 2);
+        assert_eq!(agg.low_priority_unread, 6);
+    }
+
+    #[test]
+    fn notification_modes_override_mute_and_low_priority() {
+        let muted_but_all = ChannelUnreadInput {
+            channel_muted: true,
+            low_priority: true,
+            notification_mode: notification_mode::ALL,
+            ..channel(4, 0)
+        };
+        let mentions_only = ChannelUnreadInput {
+            notification_mode: notification_mode::MENTIONS_ONLY,
+            ..channel(7, 1)
+        };
+        let silent = ChannelUnreadInput {
+            notification_mode: notification_mode::NONE,
+            ..channel(9, 3)
+        };
+        let unknown_mode = ChannelUnreadInput {
+            notification_mode: 42,
+            channel_muted: true,
+            ..channel(6, 2)
+        };
+        let agg = aggregate_unread(
+            vec![muted_but_all, mentions_only, silent, unknown_mode],
+            Vec::new(),
+        );
+        assert_eq!(agg.badge_count, 4 + 1 + 2);
+        assert_eq!(agg.badge_channel_count, 3);
+        assert_eq!(agg.muted_unread, 7 + 9 + 6);
+        assert_eq!(agg.muted_mention_count, 1 + 3 + 2);
+    }
+
+    #[test]
+    fn folder_totals_keep_folder_order_and_list_empty_folders() {
+        let folder = |folder_id: u64, name: &str| UnreadFolderTotal {
+            folder_id,
+            name: name.into(),
+            ..Default::default()
+        };
+        let in_both = ChannelUnreadInput {
+            folder_ids: vec![2, 1],
+            ..channel(3, 0)
+        };
+        let muted_in_work = ChannelUnreadInput {
+            folder_ids: vec![2],
+            channel_muted: true,
+            ..channel(5, 1)
+        };
+        let agg = aggregate_unread(
+            vec![in_both, muted_in_work, channel(4, 0)],
+            vec![folder(2, "work"), folder(1, "family"), folder(3, "empty")],
+        );
+        let folders: Vec<_> = agg
+            .folders
+            .iter()
+            .map(|f| (f.folder_id, f.unread_count, f.badge_count))
+            .collect();
+        assert_eq!(folders, vec![(2, 8, 4), (1, 3, 3), (3, 0, 0)]);
+        assert!(merge_unread([&agg]).folders.is_empty());
+    }
+
+    #[test]
+    fn stale_mentions_do_not_outlive_unread() {
+        let read_but_mentioned = ChannelUnreadInput {
+            channel_muted: true,
+            ..channel(0, 3)
+        };
+        let agg = aggregate_unread(vec![read_but_mentioned], Vec::new());
+        assert_eq!(agg, UnreadAggregate::default());
+    }
+
+    #[test]
+    fn tag_totals_are_sorted_and_deduplicated() {
+        let work = ChannelUnreadInput {
+            tags: vec!["work".into(), "work".into(), "".into()],
+            favourite: true,
+            ..channel(3, 0)
+        };
+        let muted_work = ChannelUnreadInput {
+            tags: vec!["work".into(), "family".into()],
+            channel_muted: true,
+            ..channel(2, 1)
+        };
+        let agg = aggregate_unread(vec![work, muted_work], Vec::new());
+        assert_eq!(agg.favourite_unread, 3);
+        assert_eq!(
+            agg.tags,
+            vec![
+                UnreadTagTotal {
+                    tag: "family".into(),
+                    unread_count: 2,
+                    badge_count: 1,
+                },
+                UnreadTagTotal {
+                    tag: "work".into(),
+                    unread_count: 5,
+                    badge_count: 4,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn merged_accounts_add_up_and_share_tags() {
+        let alice = aggregate_unread(
+            vec![ChannelUnreadInput {
+                tags: vec!["work".into()],
+                ..channel(3, 0)
+            }],
+            Vec::new(),
+        );
+        let bob = aggregate_unread(
+            vec![
+                ChannelUnreadInput {
+                    tags: vec!["work".into(), "family".into()],
+                    channel_muted: true,
+                    ..channel(2, 1)
+                },
+                channel(4, 0),
+            ],
+            Vec::new(),
+        );
+        let total = merge_unread([&alice, &bob]);
+        assert_eq!(total.badge_count, 3 + 1 + 4);
+        assert_eq!(total.total_unread, 3 + 2 + 4);
+        assert_eq!(total.badge_channel_count, 3);
+        assert_eq!(total.muted_mention_count, 1);
+        let tags: Vec<_> = total
+            .tags
+            .iter()
+            .map(|t| (t.tag.as_str(), t.unread_count, t.badge_count))
+            .collect();
+        assert_eq!(tags, vec![("family", 2, 1), ("work", 5, 4)]);
+        assert_eq!(merge_unread(&Vec::new()), UnreadAggregate::default());
+    }
+}
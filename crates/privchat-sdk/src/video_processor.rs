@@ -0,0 +1,377 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 内置视频处理：给没有宿主层的桌面端 / CLI（daemon）用。
+//!
+//! 移动端由宿主实现 [`VideoProcessHook`]，或者应答 `SdkEvent::MediaJobRequested`。
+//! Linux 桌面和 `privchatd` 没有这一层，视频缩略图只能落到 `thumb_status = 3`。
+//! 开 `video-processor` feature 之后，没注册回调时 SDK 用这里的实现顶上，走的是
+//! **同一个** `MediaProcessOp::Thumbnail` / `Compress` 约定：
+//!
+//! - `Thumbnail`：抽首帧写成 JPEG，SDK 照常转 WebP；
+//! - `Compress`：默认不做（见下）；
+//! - 另外探测时长和尺寸（[`probe`]），填进消息的 `duration` / `width` / `height`——
+//!   宿主在 `extra` 里带了的以宿主为准。
+//!
+//! 解码交给系统里的 `ffmpeg` / `ffprobe`（`PRIVCHAT_FFMPEG` / `PRIVCHAT_FFPROBE` 可以
+//! 指定路径），不链接任何编解码库：容器和编码格式覆盖面就是 ffmpeg 的，SDK 的构建
+//! 不因此多一个 C 依赖。找不到 `ffmpeg` 时当作没有内置实现，行为和不开 feature 一样。
+//!
+//! 🔴 回调在 actor 里同步执行。抽一帧是毫秒级的事；压缩一段长视频可能要几分钟，
+//! 期间 actor 什么都不做，所以压缩必须显式打开：
+//! `sdk.set_video_process_hook(video_processor::hook(VideoProcessorOptions { compress: Some(..) }))`。
+//!
+//! 没开 feature 时这个模块只剩纯函数，[`probe`] 恒为 `None`。
+//!
+//! [`VideoProcessHook`]: crate::VideoProcessHook
+
+// 没开 feature 时，命令行拼装和输出解析只有测试在用。
+#![cfg_attr(not(feature = "video-processor"), allow(dead_code))]
+
+use std::ffi::OsString;
+use std::path::Path;
+
+/// 探测到的视频信息。哪一项拿不到就是 `None`。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VideoProbe {
+    /// 秒，四舍五入，非零时至少 1（与语音时长的约定一致）。
+    pub duration_secs: Option<u32>,
+    /// 显示尺寸：带 90° / 270° 旋转的（手机竖拍）已经宽高互换。
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// 内置处理器的配置。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VideoProcessorOptions {
+    /// `None` = 不压缩，`Compress` 一律回 `Ok(false)`（原文件照发）。
+    pub compress: Option<CompressOptions>,
+}
+
+/// H.264 + AAC 重新编码。只对 mp4 / m4v / mov 做，其它容器原样发。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressOptions {
+    /// 长边上限（像素），不放大。
+    pub max_edge: u32,
+    /// x264 CRF，越大越小越糊。
+    pub crf: u8,
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        Self {
+            max_edge: 1280,
+            crf: 28,
+        }
+    }
+}
+
+/// 解析 `ffprobe -of json` 的输出（[`probe_args`] 要的那几项）。
+fn parse_probe(json: &str) -> Option<VideoProbe> {
+    let value: serde_json::Value = serde_json::from_str(json).ok()?;
+    let stream = value["streams"].as_array()?.first()?;
+    let dim = |key: &str| stream[key].as_u64().filter(|v| *v > 0).map(|v| v as u32);
+    let (mut width, mut height) = (dim("width"), dim("height"));
+    // 旋转有两种写法：老的 `tags.rotate`（字符串），新的 display matrix side data。
+    let rotation = stream["side_data_list"]
+        .as_array()
+        .and_then(|list| list.iter().find_map(|d| d["rotation"].as_i64()))
+        .or_else(|| stream["tags"]["rotate"].as_str()?.parse::<i64>().ok())
+        .unwrap_or(0);
+    if rotation.rem_euclid(180) == 90 {
+        std::mem::swap(&mut width, &mut height);
+    }
+    let duration_secs = value["format"]["duration"]
+        .as_str()
+        .and_then(|d| d.parse::<f64>().ok())
+        .filter(|d| d.is_finite() && *d > 0.0)
+        .map(|d| (d.round() as u32).max(1));
+    Some(VideoProbe {
+        duration_secs,
+        width,
+        height,
+    })
+}
+
+fn probe_args(path: &Path) -> Vec<OsString> {
+    let mut args: Vec<OsString> = [
+        "-v",
+        "error",
+        "-select_streams",
+        "v:0",
+        "-show_entries",
+        "stream=width,height:stream_tags=rotate:stream_side_data=rotation:format=duration",
+        "-of",
+        "json",
+    ]
+    .into_iter()
+    .map(OsString::from)
+    .collect();
+    args.push(path.as_os_str().to_owned());
+    args
+}
+
+fn first_frame_args(source: &Path, output: &Path) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec!["-nostdin".into(), "-v".into(), "error".into(), "-y".into()];
+    args.push("-i".into());
+    args.push(source.as_os_str().to_owned());
+    for arg in ["-frames:v", "1", "-q:v", "3"] {
+        args.push(arg.into());
+    }
+    args.push(output.as_os_str().to_owned());
+    args
+}
+
+/// 长边缩到 `max_edge` 以内、不放大；另一边按比例取偶数（x264 要求）。
+fn scale_filter(max_edge: u32) -> String {
+    format!("scale='if(gte(iw,ih),min({max_edge},iw),-2)':'if(gte(iw,ih),-2,min({max_edge},ih))'")
+}
+
+fn compress_args(source: &Path, output: &Path, options: CompressOptions) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec!["-nostdin".into(), "-v".into(), "error".into(), "-y".into()];
+    args.push("-i".into());
+    args.push(source.as_os_str().to_owned());
+    let crf = options.crf.to_string();
+    let filter = scale_filter(options.max_edge);
+    for arg in [
+        "-map_metadata",
+        "0",
+        "-vf",
+        &filter,
+        "-c:v",
+        "libx264",
+        "-preset",
+        "veryfast",
+        "-crf",
+        &crf,
+        "-pix_fmt",
+        "yuv420p",
+        "-c:a",
+        "aac",
+        "-b:a",
+        "128k",
+        "-movflags",
+        "+faststart",
+    ] {
+        args.push(arg.into());
+    }
+    args.push(output.as_os_str().to_owned());
+    args
+}
+
+/// 只有这几种容器压完还能用原来的扩展名和 MIME。
+fn compressible(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| matches!(e.to_ascii_lowercase().as_str(), "mp4" | "m4v" | "mov"))
+}
+
+/// 时长和尺寸。没开 feature、没有 `ffprobe`、或者文件不是视频时为 `None`。
+pub fn probe(path: &Path) -> Option<VideoProbe> {
+    #[cfg(feature = "video-processor")]
+    {
+        let output = std::process::Command::new(tools::ffprobe())
+            .args(probe_args(path))
+            .stdin(std::process::Stdio::null())
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        parse_probe(std::str::from_utf8(&output.stdout).ok()?)
+    }
+    #[cfg(not(feature = "video-processor"))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+#[cfg(feature = "video-processor")]
+mod tools {
+    use std::ffi::OsString;
+    use std::sync::OnceLock;
+
+    pub(super) fn ffmpeg() -> OsString {
+        std::env::var_os("PRIVCHAT_FFMPEG").unwrap_or_else(|| "ffmpeg".into())
+    }
+
+    pub(super) fn ffprobe() -> OsString {
+        std::env::var_os("PRIVCHAT_FFPROBE").unwrap_or_else(|| "ffprobe".into())
+    }
+
+    /// `ffmpeg` 跑得起来。只查一次：进程生命周期内装上 / 卸掉 ffmpeg 不在考虑之内。
+    pub(super) fn available() -> bool {
+        static AVAILABLE: OnceLock<bool> = OnceLock::new();
+        *AVAILABLE.get_or_init(|| {
+            std::process::Command::new(ffmpeg())
+                .arg("-version")
+                .stdin(std::process::Stdio::null())
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .status()
+                .is_ok_and(|s| s.success())
+        })
+    }
+
+    pub(super) fn run_ffmpeg(args: Vec<OsString>) -> Result<(), String> {
+        let output = std::process::Command::new(ffmpeg())
+            .args(args)
+            .stdin(std::process::Stdio::null())
+            .output()
+            .map_err(|e| format!("spawn ffmpeg: {e}"))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(format!(
+                "ffmpeg exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        }
+    }
+}
+
+/// 抽首帧写成 JPEG。`Ok(false)` = 没抽出来（没有视频流），SDK 按没有缩略图处理。
+#[cfg(feature = "video-processor")]
+pub fn extract_first_frame(source: &Path, output: &Path) -> Result<bool, String> {
+    tools::run_ffmpeg(first_frame_args(source, output))?;
+    Ok(std::fs::metadata(output).is_ok_and(|m| m.len() > 0))
+}
+
+/// 重新编码 `source`，**比原来小才**替换 `dest`（可以是同一个文件）。
+///
+/// 先写到旁边的临时文件再改名过去：失败或不划算时 `dest` 保持原样，这是
+/// [`VideoProcessHook`](crate::VideoProcessHook) 约定的。
+#[cfg(feature = "video-processor")]
+pub fn compress(source: &Path, dest: &Path, options: CompressOptions) -> Result<bool, String> {
+    if !compressible(dest) {
+        return Ok(false);
+    }
+    let ext = dest
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("mp4")
+        .to_string();
+    let tmp = dest.with_extension(format!("compress.{ext}"));
+    let result = tools::run_ffmpeg(compress_args(source, &tmp, options)).and_then(|()| {
+        let before = std::fs::metadata(source).map_err(|e| format!("stat source: {e}"))?;
+        let after = std::fs::metadata(&tmp).map_err(|e| format!("stat output: {e}"))?;
+        if after.len() == 0 || after.len() >= before.len() {
+            return Ok(false);
+        }
+        std::fs::rename(&tmp, dest).map_err(|e| format!("replace with compressed: {e}"))?;
+        Ok(true)
+    });
+    let _ = std::fs::remove_file(&tmp);
+    result
+}
+
+/// 按 `options` 造一个 [`VideoProcessHook`](crate::VideoProcessHook)，可以直接交给
+/// `set_video_process_hook`。找不到 `ffmpeg` 时返回 `None`。
+///
+/// ffmpeg 处理失败只记日志、回 `Ok(false)`：宿主回调报错会让整条消息发送失败，
+/// 而 ffmpeg 认不出的文件照样应该能原样发出去（没有缩略图而已）。
+#[cfg(feature = "video-processor")]
+pub fn hook(options: VideoProcessorOptions) -> Option<crate::VideoProcessHook> {
+    if !tools::available() {
+        return None;
+    }
+    Some(std::sync::Arc::new(
+        move |op: crate::MediaProcessOp, source: &Path, _meta: &Path, output: &Path| {
+            let result = match op {
+                crate::MediaProcessOp::Thumbnail => extract_first_frame(source, output),
+                crate::MediaProcessOp::Compress => match options.compress {
+                    Some(compress_options) => compress(source, output, compress_options),
+                    None => Ok(false),
+                },
+            };
+            Ok(result.unwrap_or_else(|e| {
+                tracing::warn!("built-in video processor {:?} failed: {}", op, e);
+                false
+            }))
+        },
+    ))
+}
+
+/// 没注册回调时用的那个：只抽帧、不压缩。
+#[cfg(feature = "video-processor")]
+pub(crate) fn default_hook() -> Option<crate::VideoProcessHook> {
+    static DEFAULT: std::sync::OnceLock<Option<crate::VideoProcessHook>> =
+        std::sync::OnceLock::new();
+    DEFAULT
+        .get_or_init(|| hook(VideoProcessorOptions::default()))
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_output_is_read_with_rotation_and_rounded_duration() {
+        let json = r#"{
+            "streams": [{"width": 1920, "height": 1080, "side_data_list": [{"rotation": -90}]}],
+            "format": {"duration": "12.600000"}
+        }"#;
+        assert_eq!(
+            parse_probe(json),
+            Some(VideoProbe {
+                duration_secs: Some(13),
+                width: Some(1080),
+                height: Some(1920),
+            })
+        );
+    }
+
+    #[test]
+    fn the_legacy_rotate_tag_and_missing_fields_are_handled() {
+        let json = r#"{"streams": [{"width": 640, "height": 480, "tags": {"rotate": "270"}}],
+                      "format": {"duration": "0.2"}}"#;
+        assert_eq!(
+            parse_probe(json),
+            Some(VideoProbe {
+                duration_secs: Some(1),
+                width: Some(480),
+                height: Some(640),
+            })
+        );
+        let upright = r#"{"streams": [{"width": 640, "height": 480, "tags": {"rotate": "180"}}],
+                         "format": {}}"#;
+        assert_eq!(
+            parse_probe(upright),
+            Some(VideoProbe {
+                duration_secs: None,
+                width: Some(640),
+                height: Some(480),
+            })
+        );
+        // 没有视频流（纯音频文件）。
+        assert_eq!(
+            parse_probe(r#"{"streams": [], "format": {"duration": "3"}}"#),
+            None
+        );
+    }
+
+    #[test]
+    fn compression_keeps_the_container_and_never_upscales() {
+        assert!(compressible(Path::new("payload.MP4")));
+        assert!(compressible(Path::new("payload.mov")));
+        assert!(!compressible(Path::new("payload.webm")));
+        let filter = scale_filter(1280);
+        assert!(filter.contains("min(1280,iw)") && filter.contains("min(1280,ih)"));
+        let args = compress_args(
+            Path::new("in.mp4"),
+            Path::new("out.mp4"),
+            CompressOptions::default(),
+        );
+        let args = args
+            .iter()
+            .map(|a| a.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(args.last().map(String::as_str), Some("out.mp4"));
+        assert!(args.windows(2).any(|w| w == ["-crf", "28"]));
+    }
+}
@@ -2,8 +2,10 @@
 // 放 tests/ 集成测试：链接 lib 非-test 构建，绕开 lib 内不相关的 #[cfg(test)] fixture。
 
 use privchat_sdk::attachment_crypto::{
-    decrypt_attachment, decrypt_downloaded_attachment_bytes, encrypt_attachment, MIN_BLOB_LEN,
-    NONCE_LEN,
+    decrypt_attachment, decrypt_attachment_range, decrypt_attachment_stream, decrypt_attachment_v2,
+    decrypt_downloaded_attachment_bytes, decrypt_downloaded_attachment_stream, encrypt_attachment,
+    encrypt_attachment_stream_with_segment_size, encrypt_attachment_v2, SegmentedLayout,
+    MIN_BLOB_LEN, NONCE_LEN, TAG_LEN, V2_HEADER_LEN, V2_MIN_SEGMENT_SIZE,
 };
 
 #[test]
@@ -94,3 +96,171 @@ fn download_v1_wrong_cek_fails() {
     let (_b2, other) = encrypt_attachment(b"y").unwrap();
     assert!(decrypt_downloaded_attachment_bytes(1, Some(&other), &blob).is_err());
 }
+
+// ---- v2：定长分段 ----
+
+const SEG: u32 = V2_MIN_SEGMENT_SIZE;
+
+fn sample(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+fn seal_v2(plain: &[u8]) -> (Vec<u8>, String) {
+    let mut blob = Vec::new();
+    let sealed = encrypt_attachment_stream_with_segment_size(plain, &mut blob, SEG).unwrap();
+    assert_eq!(sealed.blob_len, blob.len() as u64);
+    assert_eq!(sealed.plaintext_len, plain.len() as u64);
+    (blob, sealed.cek)
+}
+
+/// 边界长度全部走一遍：空、不足一段、恰好整段（要补空末段）、整段多一字节。
+#[test]
+fn v2_stream_roundtrip_at_segment_boundaries() {
+    let seg = SEG as usize;
+    for len in [0, 1, seg - 1, seg, seg + 1, 3 * seg, 3 * seg + 7] {
+        let plain = sample(len);
+        let (blob, cek) = seal_v2(&plain);
+        let layout = SegmentedLayout::from_blob_len(SEG, blob.len() as u64).unwrap();
+        assert_eq!(layout.plaintext_len, len as u64, "len={len}");
+        assert_eq!(layout.blob_len(), blob.len() as u64, "len={len}");
+
+        let mut out = Vec::new();
+        let written = decrypt_attachment_stream(&blob[..], &mut out, &cek).unwrap();
+        assert_eq!(written, len as u64);
+        assert_eq!(out, plain, "len={len}");
+    }
+}
+
+#[test]
+fn v2_in_memory_roundtrip_uses_the_default_segment_size() {
+    let plain = sample(200 * 1024);
+    let (blob, cek) = encrypt_attachment_v2(&plain).unwrap();
+    assert_eq!(decrypt_attachment_v2(&blob, &cek).unwrap(), plain);
+    assert!(
+        decrypt_attachment(&blob, &cek).is_err(),
+        "v2 blob 不能被当成 v1 解开"
+    );
+}
+
+/// 从段边界截掉末段：剩下的每一段都完好，但最后一段不带末段标记，必须被识破。
+#[test]
+fn v2_truncation_at_a_segment_boundary_fails() {
+    let plain = sample(3 * SEG as usize + 10);
+    let (blob, cek) = seal_v2(&plain);
+    let layout = SegmentedLayout::from_blob_len(SEG, blob.len() as u64).unwrap();
+    let (last_start, _) = layout.ciphertext_range(layout.segment_count() - 1);
+    let truncated = &blob[..last_start as usize];
+    assert!(decrypt_attachment_v2(truncated, &cek).is_err());
+
+    // 再少一段同理：截断后剩下的最后一段刚好是满段，依然不是末段。
+    let (prev_start, _) = layout.ciphertext_range(layout.segment_count() - 2);
+    assert!(decrypt_attachment_v2(&blob[..prev_start as usize], &cek).is_err());
+}
+
+#[test]
+fn v2_reordered_segments_fail() {
+    let plain = sample(3 * SEG as usize + 10);
+    let (blob, cek) = seal_v2(&plain);
+    let layout = SegmentedLayout::from_blob_len(SEG, blob.len() as u64).unwrap();
+    let (a0, a1) = layout.ciphertext_range(0);
+    let (b0, b1) = layout.ciphertext_range(1);
+    let mut swapped = blob.clone();
+    swapped[a0 as usize..a1 as usize].copy_from_slice(&blob[b0 as usize..b1 as usize]);
+    swapped[b0 as usize..b1 as usize].copy_from_slice(&blob[a0 as usize..a1 as usize]);
+    assert!(decrypt_attachment_v2(&swapped, &cek).is_err());
+}
+
+#[test]
+fn v2_tampered_header_or_segment_fails() {
+    let plain = sample(2 * SEG as usize);
+    let (blob, cek) = seal_v2(&plain);
+
+    let mut bad_salt = blob.clone();
+    bad_salt[V2_HEADER_LEN - 1] ^= 0x01;
+    assert!(decrypt_attachment_v2(&bad_salt, &cek).is_err());
+
+    let mut bad_ct = blob.clone();
+    bad_ct[V2_HEADER_LEN + 5] ^= 0x01;
+    assert!(decrypt_attachment_v2(&bad_ct, &cek).is_err());
+
+    let (_other, other_cek) = seal_v2(b"x");
+    assert!(decrypt_attachment_v2(&blob, &other_cek).is_err());
+}
+
+#[test]
+fn v2_random_access_matches_the_full_plaintext() {
+    let seg = SEG as u64;
+    let plain = sample(4 * SEG as usize + 123);
+    let (blob, cek) = seal_v2(&plain);
+    let total = plain.len() as u64;
+    for (start, end) in [
+        (0, 1),
+        (0, total),
+        (seg - 3, seg + 3),
+        (2 * seg, 3 * seg),
+        (4 * seg + 100, total),
+        (total - 1, total + 500),
+        (total, total + 1),
+    ] {
+        let mut cursor = std::io::Cursor::new(&blob);
+        let got = decrypt_attachment_range(&mut cursor, &cek, start, end).unwrap();
+        let want = &plain[start.min(total) as usize..end.min(total) as usize];
+        assert_eq!(got, want, "range {start}..{end}");
+    }
+}
+
+/// 随机读也要认证：被篡改的那段落在读取范围里就必须报错。
+#[test]
+fn v2_random_access_authenticates_the_segments_it_reads() {
+    let plain = sample(3 * SEG as usize);
+    let (mut blob, cek) = seal_v2(&plain);
+    let layout = SegmentedLayout::from_blob_len(SEG, blob.len() as u64).unwrap();
+    let (s1, _) = layout.ciphertext_range(1);
+    blob[s1 as usize] ^= 0x01;
+
+    let seg = SEG as u64;
+    let mut cursor = std::io::Cursor::new(&blob);
+    assert!(decrypt_attachment_range(&mut cursor, &cek, 0, seg).is_ok());
+    assert!(decrypt_attachment_range(&mut cursor, &cek, seg + 1, seg + 2).is_err());
+}
+
+#[test]
+fn v2_impossible_blob_length_is_rejected() {
+    // 一个满段、没有末段：余数落在 [S, S+16)，没有哪种明文长度会产出这样的 blob。
+    let len = (V2_HEADER_LEN + SEG as usize + TAG_LEN) as u64;
+    assert!(SegmentedLayout::from_blob_len(SEG, len).is_err());
+    assert!(SegmentedLayout::from_blob_len(SEG, (V2_HEADER_LEN + TAG_LEN - 1) as u64).is_err());
+}
+
+#[test]
+fn download_v2_roundtrip_and_missing_cek() {
+    let plain = sample(5000);
+    let (blob, cek) = seal_v2(&plain);
+    assert_eq!(
+        decrypt_downloaded_attachment_bytes(2, Some(&cek), &blob).unwrap(),
+        plain
+    );
+    assert!(decrypt_downloaded_attachment_bytes(2, None, &blob).is_err());
+    assert!(decrypt_downloaded_attachment_bytes(3, Some(&cek), &blob).is_err());
+}
+
+#[test]
+fn download_stream_handles_every_version() {
+    let plain = sample(5000);
+
+    let mut out = Vec::new();
+    decrypt_downloaded_attachment_stream(0, None, &plain[..], &mut out).unwrap();
+    assert_eq!(out, plain);
+
+    let (v1_blob, v1_cek) = encrypt_attachment(&plain).unwrap();
+    let mut out = Vec::new();
+    decrypt_downloaded_attachment_stream(1, Some(&v1_cek), &v1_blob[..], &mut out).unwrap();
+    assert_eq!(out, plain);
+
+    let (v2_blob, v2_cek) = seal_v2(&plain);
+    let mut out = Vec::new();
+    decrypt_downloaded_attachment_stream(2, Some(&v2_cek), &v2_blob[..], &mut out).unwrap();
+    assert_eq!(out, plain);
+
+    assert!(decrypt_downloaded_attachment_stream(2, None, &v2_blob[..], &mut Vec::new()).is_err());
+}
@@ -381,3 +381,73 @@ async fn resuming_puts_only_the_missing_bytes_on_the_wire() {
         .unwrap();
     assert_eq!(first_offset, already, "必须从缺口开始，不是从 0");
 }
+
+/// 并发：几片同时在路上、乱序落地，服务端收到的字节照样拼回原文，而且一个字节不重复。
+#[tokio::test]
+async fn parallel_parts_reassemble_into_the_blob_without_overlap() {
+    let total = 64 * 1024 * 12 + 321;
+    let server = MockServer::start(total as u64).await;
+    let blob: Vec<u8> = (0..total).map(|i| (i % 241) as u8).collect();
+    let base = server
+        .upload_url()
+        .trim_end_matches('/')
+        .trim_end_matches("/upload")
+        .to_string();
+    let client = reqwest::Client::new();
+
+    let plan = privchat_sdk::resumable_upload::UploadPlan {
+        base_unit: 64 * 1024,
+        initial_request_size: 64 * 1024,
+        max_request_size: 2 * 1024 * 1024,
+        session_threshold: 64 * 1024,
+        max_parallel_parts: 4,
+    };
+    let mut up = privchat_sdk::resumable_upload::ResumableUpload::from_missing(
+        total as u64,
+        plan,
+        &[(0, total as u64)],
+    );
+
+    let mut in_flight = tokio::task::JoinSet::new();
+    let mut peak = 0usize;
+    loop {
+        while let Some(part) = up.claim_part() {
+            let piece = blob[part.offset as usize..(part.offset + part.len) as usize].to_vec();
+            let (client, base) = (client.clone(), base.clone());
+            in_flight.spawn(async move {
+                let resp = client
+                    .put(format!("{base}/chunk?offset={}", part.offset))
+                    .header("X-Upload-Token", "tok")
+                    .header("X-Chunk-SHA256", sha256_hex(&piece))
+                    .body(piece)
+                    .send()
+                    .await
+                    .expect("chunk");
+                assert!(resp.status().is_success());
+                part
+            });
+        }
+        peak = peak.max(up.in_flight());
+        let Some(done) = in_flight.join_next().await else {
+            break;
+        };
+        up.on_chunk_ok(done.expect("join"), std::time::Duration::from_millis(20));
+    }
+    assert!(up.is_done());
+    assert!(peak > 1, "探测之后应该并发起来");
+    assert!(peak <= 4, "不得超过 max_parallel_parts");
+
+    let chunks: Vec<_> = server
+        .requests()
+        .into_iter()
+        .filter(|r| r.path.starts_with("/api/app/files/chunk"))
+        .collect();
+    let sent: usize = chunks.iter().map(|r| r.body.len()).sum();
+    assert_eq!(sent, total, "🔴 并发不能让任何一段传两遍");
+    let mut reassembled = vec![0u8; total];
+    for r in &chunks {
+        let offset: usize = r.path.split("offset=").nth(1).unwrap().parse().unwrap();
+        reassembled[offset..offset + r.body.len()].copy_from_slice(&r.body);
+    }
+    assert_eq!(reassembled, blob, "服务端收到的字节拼不回原文");
+}
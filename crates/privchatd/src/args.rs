@@ -0,0 +1,129 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 命令行参数。选项就这么几个，手写解析，不为此引参数库。
+
+use std::path::PathBuf;
+
+use privchat_sdk::client_service::ClientServiceConfig;
+use privchat_sdk::{parse_server_url, ServerEndpoint};
+
+pub const USAGE: &str = "\
+usage: privchatd --data-dir <DIR> --server <URL> [--server <URL> ...]
+                 [--socket <PATH>] [--device-id <ID>] [--timeout-secs <N>]
+
+  --data-dir      account data directory; one daemon per directory
+  --server        quic://host:port, tcp://host:port, ws(s)://host:port/path
+  --socket        Unix socket to listen on (default: <data-dir>/privchatd.sock)
+  --device-id     device id used when a login request leaves it empty
+  --timeout-secs  connection timeout (default: 10)";
+
+#[derive(Debug, Clone)]
+pub struct DaemonArgs {
+    pub data_dir: PathBuf,
+    pub socket_path: PathBuf,
+    pub endpoints: Vec<ServerEndpoint>,
+    pub device_id: String,
+    pub connection_timeout_secs: u32,
+}
+
+impl DaemonArgs {
+    /// `args` 不含程序名。出错时返回的字符串可以直接打给用户。
+    pub fn parse<I, S>(args: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut data_dir = None;
+        let mut socket_path = None;
+        let mut endpoints = Vec::new();
+        let mut device_id = String::new();
+        let mut connection_timeout_secs = 10u32;
+
+        let mut it = args.into_iter().map(Into::into);
+        while let Some(flag) = it.next() {
+            let mut value = |name: &str| {
+                it.next()
+                    .ok_or_else(|| format!("{name} needs a value\n\n{USAGE}"))
+            };
+            match flag.as_str() {
+                "--data-dir" => data_dir = Some(PathBuf::from(value("--data-dir")?)),
+                "--socket" => socket_path = Some(PathBuf::from(value("--socket")?)),
+                "--server" => {
+                    let url = value("--server")?;
+                    let endpoint = parse_server_url(&url)
+                        .ok_or_else(|| format!("unsupported server url {url:?}"))?;
+                    endpoints.push(endpoint);
+                }
+                "--device-id" => device_id = value("--device-id")?,
+                "--timeout-secs" => {
+                    let raw = value("--timeout-secs")?;
+                    connection_timeout_secs = raw
+                        .parse()
+                        .map_err(|_| format!("--timeout-secs: not a number: {raw:?}"))?;
+                }
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                other => return Err(format!("unknown argument {other:?}\n\n{USAGE}")),
+            }
+        }
+
+        let data_dir = data_dir.ok_or_else(|| format!("--data-dir is required\n\n{USAGE}"))?;
+        if endpoints.is_empty() {
+            return Err(format!("at least one --server is required\n\n{USAGE}"));
+        }
+        let socket_path =
+            socket_path.unwrap_or_else(|| privchat_sdk_rpc::default_socket_path(&data_dir));
+        Ok(Self {
+            data_dir,
+            socket_path,
+            endpoints,
+            device_id,
+            connection_timeout_secs,
+        })
+    }
+
+    pub fn service_config(&self) -> ClientServiceConfig {
+        ClientServiceConfig {
+            endpoints: self.endpoints.clone(),
+            connection_timeout_secs: self.connection_timeout_secs,
+            data_dir: self.data_dir.clone(),
+            device_id: self.device_id.clone(),
+            account_hint: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_defaults_into_the_data_dir() {
+        let args = DaemonArgs::parse([
+            "--data-dir",
+            "/tmp/acct",
+            "--server",
+            "quic://im.example.com:9001",
+        ])
+        .unwrap();
+        assert_eq!(args.socket_path, PathBuf::from("/tmp/acct/privchatd.sock"));
+        assert_eq!(args.endpoints.len(), 1);
+        assert_eq!(args.endpoints[0].host, "im.example.com");
+        assert_eq!(args.connection_timeout_secs, 10);
+    }
+
+    #[test]
+    fn missing_server_and_bad_urls_are_errors() {
+        assert!(DaemonArgs::parse(["--data-dir", "/tmp/acct"]).is_err());
+        assert!(DaemonArgs::parse([
+            "--data-dir",
+            "/tmp/acct",
+            "--server",
+            "http://im.example.com"
+        ])
+        .is_err());
+        assert!(DaemonArgs::parse(["--server"]).is_err());
+    }
+}
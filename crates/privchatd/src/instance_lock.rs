@@ -0,0 +1,93 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! 每个 data_dir 单实例。
+//!
+//! 用 `flock` 而不是「pid 文件 + 探活」：进程崩了内核会自动放锁，不存在残留锁要人工清。
+//! 锁文件里写上持有者 pid，只用于排障时看一眼。
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+pub const LOCK_FILE_NAME: &str = "privchatd.lock";
+
+#[derive(Debug, thiserror::Error)]
+pub enum LockError {
+    #[error("another privchatd is already serving {0}")]
+    AlreadyRunning(PathBuf),
+    #[error("lock file {path}: {source}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+/// 持有期间同一 data_dir 上的其它 daemon 起不来。drop 即释放。
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    pub fn acquire(data_dir: &Path) -> Result<Self, LockError> {
+        let path = data_dir.join(LOCK_FILE_NAME);
+        let io_err = |source| LockError::Io {
+            path: path.clone(),
+            source,
+        };
+        std::fs::create_dir_all(data_dir).map_err(io_err)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .map_err(io_err)?;
+        // SAFETY: fd 来自上面刚打开、仍由 `file` 持有的文件。
+        let rc = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if rc != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::WouldBlock {
+                return Err(LockError::AlreadyRunning(data_dir.to_path_buf()));
+            }
+            return Err(io_err(err));
+        }
+        file.set_len(0).map_err(io_err)?;
+        writeln!(file, "{}", std::process::id()).map_err(io_err)?;
+        Ok(Self { _file: file, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_instance_on_the_same_data_dir_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = InstanceLock::acquire(dir.path()).unwrap();
+        let err = InstanceLock::acquire(dir.path()).unwrap_err();
+        assert!(matches!(err, LockError::AlreadyRunning(_)));
+
+        drop(first);
+        InstanceLock::acquire(dir.path()).expect("lock is released on drop");
+    }
+
+    #[test]
+    fn different_data_dirs_do_not_contend() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let _la = InstanceLock::acquire(a.path()).unwrap();
+        let _lb = InstanceLock::acquire(b.path()).unwrap();
+    }
+}
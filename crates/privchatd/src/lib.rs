@@ -0,0 +1,18 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! `privchatd`：一个账号一个常驻进程，把 `ClientService` 通过 Unix socket 上的
+//! 控制 RPC（`privchat-sdk-rpc`）暴露给桌面端和 CLI。
+//!
+//! 只依赖 facade（`docs/CLIENT_SERVICE_FACADE_SPEC.md` §3），不碰 UniFFI。
+//! 同一个 data_dir 只允许一个实例：启动先拿 [`instance_lock::InstanceLock`]，拿不到直接退出。
+
+pub mod args;
+pub mod instance_lock;
+pub mod server;
+
+pub use args::DaemonArgs;
+pub use instance_lock::InstanceLock;
+pub use server::{Daemon, StopHandle};
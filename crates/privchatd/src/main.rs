@@ -0,0 +1,67 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+use std::process::ExitCode;
+
+use privchat_sdk::client_service::ClientService;
+use privchatd::{Daemon, DaemonArgs, InstanceLock};
+use tokio::signal::unix::{signal, SignalKind};
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = match DaemonArgs::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(msg) => {
+            eprintln!("{msg}");
+            return ExitCode::from(2);
+        }
+    };
+
+    // 先拿锁再碰 socket：拿不到说明同一 data_dir 已有实例，它的 socket 不能动。
+    let _lock = match InstanceLock::acquire(&args.data_dir) {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("privchatd: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let service = match ClientService::new(args.service_config()) {
+        Ok(service) => service,
+        Err(e) => {
+            eprintln!("privchatd: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let daemon = Daemon::new(service.clone(), args.socket_path.clone());
+    let stop = daemon.stop_handle();
+    // systemd / launchd / `kill` 停服务发的是 SIGTERM，只等 Ctrl-C 的话会话不会落盘就被杀掉。
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            eprintln!("privchatd: install SIGTERM handler: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let signal_service = service.clone();
+    tokio::spawn(async move {
+        let received = tokio::select! {
+            r = tokio::signal::ctrl_c() => r.is_ok(),
+            r = terminate.recv() => r.is_some(),
+        };
+        if received {
+            let _ = signal_service.shutdown().await;
+            stop.stop();
+        }
+    });
+
+    if let Err(e) = daemon.serve().await {
+        eprintln!("privchatd: {}: {e}", args.socket_path.display());
+        let _ = service.shutdown().await;
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,426 @@
+// Copyright 2024 Shanghai Boyu Information Technology Co., Ltd.
+// https://privchat.dev
+//
+// Licensed under the Apache License, Version 2.0 (the "License").
+
+//! Unix socket 上的 RPC 服务端。
+//!
+//! 每条连接一个会话：请求按到达顺序逐个处理（要并发就多开连接），应答和事件经同一个
+//! 写任务串行写出。事件订阅的游标**归会话**（spec §7）：daemon 不存在全局游标，
+//! 每个订阅从客户端给的 `cursor` 起，沿 `events_since` 往后推。
+
+use std::collections::HashMap;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+
+use privchat_sdk::client_service::{
+    AuthenticateRequest, ClientService, ListChannelsRequest, ListMessagesRequest, LoginRequest,
+    MarkReadByLocalMessageIdRequest, MarkReadToPtsRequest, RegisterRequest, SendTextRequest,
+    SendTypingRequest,
+};
+use privchat_sdk_rpc::wire::{
+    codes, methods, read_frame, write_frame, EventFrame, EventsSinceParams, GetChannelParams,
+    HelloParams, HelloResult, RpcError, RpcRequest, RpcResponse, ServerFrame,
+    SubscribeEventsParams, SubscribeEventsResult, UnsubscribeEventsParams, DEFAULT_ACCOUNT,
+    PROTOCOL_VERSION,
+};
+
+/// 每次从历史里捞多少条往外推。
+const EVENT_PAGE: u64 = 128;
+/// 单个会话写队列深度。客户端读得太慢时写任务会背压事件泵，不会无限堆内存。
+const OUTBOUND_QUEUE: usize = 256;
+
+/// 让 [`Daemon::serve`] 退出。可克隆，可跨任务。
+#[derive(Clone)]
+pub struct StopHandle {
+    tx: watch::Sender<bool>,
+}
+
+impl StopHandle {
+    pub fn stop(&self) {
+        let _ = self.tx.send(true);
+    }
+}
+
+pub struct Daemon {
+    service: ClientService,
+    socket_path: PathBuf,
+    stop_tx: watch::Sender<bool>,
+}
+
+impl Daemon {
+    pub fn new(service: ClientService, socket_path: PathBuf) -> Self {
+        let (stop_tx, _) = watch::channel(false);
+        Self {
+            service,
+            socket_path,
+            stop_tx,
+        }
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle {
+            tx: self.stop_tx.clone(),
+        }
+    }
+
+    /// 绑定 socket 并服务到 [`StopHandle::stop`] 或收到 `sdk.lifecycle.shutdown`。
+    ///
+    /// 实例锁只管 data_dir，别的 data_dir 的实例可能用着同一个 `--socket`：
+    /// 旧文件只有在确认是没人应答的 socket 时才删（见 [`clear_stale_socket`]）。
+    pub async fn serve(self) -> std::io::Result<()> {
+        clear_stale_socket(&self.socket_path)?;
+        // 只有本用户能连：socket 背后就是这个账号的全部能力。
+        let listener = bind_private(&self.socket_path)?;
+        std::fs::set_permissions(&self.socket_path, std::fs::Permissions::from_mode(0o600))?;
+        tracing::info!("privchatd: listening on {}", self.socket_path.display());
+
+        let mut stop_rx = self.stop_tx.subscribe();
+        let mut sessions: Vec<JoinHandle<()>> = Vec::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, _) = match accepted {
+                        Ok(pair) => pair,
+                        Err(e) => {
+                            tracing::warn!("privchatd: accept failed: {e}");
+                            continue;
+                        }
+                    };
+                    sessions.retain(|h| !h.is_finished());
+                    let session = Session::new(self.service.clone(), self.stop_handle());
+                    sessions.push(tokio::spawn(session.run(stream)));
+                }
+                _ = stop_rx.wait_for(|stopped| *stopped) => break,
+            }
+        }
+        for session in sessions {
+            session.abort();
+        }
+        let _ = std::fs::remove_file(&self.socket_path);
+        tracing::info!("privchatd: stopped");
+        Ok(())
+    }
+}
+
+/// 删掉上一个崩掉的实例留下的 socket 文件。
+///
+/// 不是 socket 的文件不碰；还能连上的 socket 说明另一个 daemon 正在用，返回
+/// `AddrInUse`，不能把它的入口删了。
+fn clear_stale_socket(path: &Path) -> std::io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AddrInUse,
+            format!("another daemon is listening on {}", path.display()),
+        ));
+    }
+    std::fs::remove_file(path)
+}
+
+/// 在 `umask 077` 下 bind：socket 文件一出现就只有本用户能连。先 bind 再 chmod 的话，
+/// 中间那一段别的用户能抢先连上来。
+///
+/// umask 是进程级的，bind 完立刻恢复；这期间别的线程新建的文件 / 目录只是权限更紧
+/// （0600 / 0700），照样能用。
+fn bind_private(path: &Path) -> std::io::Result<UnixListener> {
+    // SAFETY: umask 只改本进程的文件创建掩码，没有内存方面的前置条件。
+    let previous = unsafe { libc::umask(0o077) };
+    let bound = UnixListener::bind(path);
+    // SAFETY: 同上。
+    unsafe { libc::umask(previous) };
+    bound
+}
+
+struct Session {
+    service: ClientService,
+    stop: StopHandle,
+    greeted: bool,
+    subscriptions: HashMap<u64, JoinHandle<()>>,
+}
+
+impl Session {
+    fn new(service: ClientService, stop: StopHandle) -> Self {
+        Self {
+            service,
+            stop,
+            greeted: false,
+            subscriptions: HashMap::new(),
+        }
+    }
+
+    async fn run(mut self, stream: UnixStream) {
+        let (mut reader, mut writer) = stream.into_split();
+        let (out_tx, mut out_rx) = mpsc::channel::<ServerFrame>(OUTBOUND_QUEUE);
+        let writer_task = tokio::spawn(async move {
+            while let Some(frame) = out_rx.recv().await {
+                if let Err(e) = write_frame(&mut writer, &frame).await {
+                    tracing::debug!("privchatd: session write failed: {e}");
+                    break;
+                }
+            }
+        });
+
+        loop {
+            let request = match read_frame::<_, RpcRequest>(&mut reader).await {
+                Ok(Some(req)) => req,
+                Ok(None) => break,
+                Err(e) => {
+                    // 帧坏了就没法再对齐边界，回一条错误后断开。
+                    let err = RpcError::new(codes::PROTOCOL_ERROR, e.to_string());
+                    let _ = out_tx
+                        .send(ServerFrame::Response(RpcResponse::err(0, err)))
+                        .await;
+                    break;
+                }
+            };
+            let id = request.id;
+            let shutting_down = request.method == methods::LIFECYCLE_SHUTDOWN;
+            let response = match self.handle(request, &out_tx).await {
+                Ok(value) => RpcResponse::ok(id, value),
+                Err(err) => RpcResponse::err(id, err),
+            };
+            let ok = response.error.is_none();
+            if out_tx.send(ServerFrame::Response(response)).await.is_err() {
+                break;
+            }
+            if shutting_down && ok {
+                self.stop.stop();
+                break;
+            }
+        }
+
+        for (_, pump) in self.subscriptions.drain() {
+            pump.abort();
+        }
+        drop(out_tx);
+        let _ = writer_task.await;
+    }
+
+    async fn handle(
+        &mut self,
+        request: RpcRequest,
+        out: &mpsc::Sender<ServerFrame>,
+    ) -> Result<serde_json::Value, RpcError> {
+        if let Some(account) = request.account.as_deref() {
+            if account != DEFAULT_ACCOUNT {
+                return Err(RpcError::new(
+                    codes::UNSUPPORTED_ACCOUNT,
+                    format!("account {account:?} is not served; v1 only serves \"default\""),
+                ));
+            }
+        }
+        if request.method == methods::RPC_HELLO {
+            let hello: HelloParams = params(request.params)?;
+            if hello.protocol_version != PROTOCOL_VERSION {
+                return Err(RpcError::new(
+                    codes::VERSION_MISMATCH,
+                    format!(
+                        "client speaks protocol {}, daemon speaks {PROTOCOL_VERSION}",
+                        hello.protocol_version
+                    ),
+                ));
+            }
+            tracing::debug!("privchatd: hello from {:?}", hello.client_name);
+            self.greeted = true;
+            return result(Ok(HelloResult {
+                protocol_version: PROTOCOL_VERSION,
+                daemon_version: env!("CARGO_PKG_VERSION").to_string(),
+                methods: methods::ALL.iter().map(|m| m.to_string()).collect(),
+            }));
+        }
+        if !self.greeted {
+            return Err(RpcError::new(
+                codes::PROTOCOL_ERROR,
+                format!("{} called before {}", request.method, methods::RPC_HELLO),
+            ));
+        }
+
+        let svc = &self.service;
+        let p = request.params;
+        match request.method.as_str() {
+            methods::SESSION_CONNECT => result(svc.connect().await),
+            methods::SESSION_DISCONNECT => result(svc.disconnect().await),
+            methods::SESSION_CONNECTION_STATE => result(svc.connection_state().await),
+            methods::SESSION_LOGIN => result(svc.login(params::<LoginRequest>(p)?).await),
+            methods::SESSION_REGISTER => result(svc.register(params::<RegisterRequest>(p)?).await),
+            methods::SESSION_AUTHENTICATE => {
+                result(svc.authenticate(params::<AuthenticateRequest>(p)?).await)
+            }
+            methods::SESSION_LOGOUT => result(svc.logout().await),
+            methods::SESSION_SNAPSHOT => result(svc.session_snapshot().await),
+            methods::LIFECYCLE_SHUTDOWN => result(svc.shutdown().await),
+            methods::CHANNELS_LIST => {
+                result(svc.list_channels(params::<ListChannelsRequest>(p)?).await)
+            }
+            methods::CHANNELS_GET => {
+                let req: GetChannelParams = params(p)?;
+                result(svc.get_channel(req.channel_id).await)
+            }
+            methods::MESSAGES_LIST => {
+                result(svc.list_messages(params::<ListMessagesRequest>(p)?).await)
+            }
+            methods::MESSAGES_SEND_TEXT => {
+                result(svc.send_text(params::<SendTextRequest>(p)?).await)
+            }
+            methods::READ_MARK_TO_PTS => result(
+                svc.mark_read_to_pts(params::<MarkReadToPtsRequest>(p)?)
+                    .await,
+            ),
+            methods::READ_MARK_BY_LOCAL_ID => result(
+                svc.mark_read_by_local_message_id(params::<MarkReadByLocalMessageIdRequest>(p)?)
+                    .await,
+            ),
+            methods::TYPING_SEND => result(svc.send_typing(params::<SendTypingRequest>(p)?).await),
+            methods::EVENTS_LAST_SEQUENCE_ID => result(Ok(svc.last_event_sequence_id())),
+            methods::EVENTS_SINCE => {
+                let req: EventsSinceParams = params(p)?;
+                result(Ok(svc.events_since(req.cursor, req.limit)))
+            }
+            methods::EVENTS_SUBSCRIBE => {
+                let req: SubscribeEventsParams = params(p)?;
+                self.subscribe(req, out.clone())
+            }
+            methods::EVENTS_UNSUBSCRIBE => {
+                let req: UnsubscribeEventsParams = params(p)?;
+                if let Some(pump) = self.subscriptions.remove(&req.subscription_id) {
+                    pump.abort();
+                }
+                result(Ok(()))
+            }
+            other => Err(RpcError::new(
+                codes::METHOD_NOT_IMPLEMENTED,
+                format!("method {other} is not implemented"),
+            )),
+        }
+    }
+
+    fn subscribe(
+        &mut self,
+        req: SubscribeEventsParams,
+        out: mpsc::Sender<ServerFrame>,
+    ) -> Result<serde_json::Value, RpcError> {
+        if self.subscriptions.contains_key(&req.subscription_id) {
+            return Err(RpcError::new(
+                codes::PROTOCOL_ERROR,
+                format!("subscription {} already exists", req.subscription_id),
+            ));
+        }
+        // 先订阅广播再读历史：两步之间产生的事件会同时出现在历史里和唤醒里，
+        // 泵按游标去重，不会漏也不会重。
+        let wake = self.service.subscribe_events().into_receiver();
+        let info = SubscribeEventsResult {
+            last_sequence_id: self.service.last_event_sequence_id(),
+            oldest_retained_sequence_id: self
+                .service
+                .events_since(0, 1)
+                .first()
+                .map(|e| e.sequence_id)
+                .unwrap_or(0),
+        };
+        let pump = tokio::spawn(pump_events(
+            self.service.clone(),
+            wake,
+            out,
+            req.subscription_id,
+            req.cursor,
+        ));
+        self.subscriptions.insert(req.subscription_id, pump);
+        result(Ok(info))
+    }
+}
+
+/// 把 `cursor` 之后的事件推给一个订阅。广播只当「有新东西了」的信号用，
+/// 事件本身一律从 `events_since` 取——这样才拿得到 `sequence_id`，`Lagged` 也不丢。
+async fn pump_events(
+    service: ClientService,
+    mut wake: broadcast::Receiver<privchat_sdk::SdkEvent>,
+    out: mpsc::Sender<ServerFrame>,
+    subscription_id: u64,
+    mut cursor: u64,
+) {
+    loop {
+        loop {
+            let batch = service.events_since(cursor, EVENT_PAGE);
+            if batch.is_empty() {
+                break;
+            }
+            for event in batch {
+                cursor = event.sequence_id;
+                let frame = ServerFrame::Event(EventFrame {
+                    subscription_id,
+                    event,
+                });
+                if out.send(frame).await.is_err() {
+                    return;
+                }
+            }
+        }
+        match wake.recv().await {
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+fn params<T: DeserializeOwned>(value: serde_json::Value) -> Result<T, RpcError> {
+    serde_json::from_value(value).map_err(|e| RpcError::new(codes::INVALID_PARAMS, e.to_string()))
+}
+
+fn result<T: Serialize>(
+    out: Result<T, privchat_sdk::Error>,
+) -> Result<serde_json::Value, RpcError> {
+    let value = out.map_err(RpcError::from_sdk)?;
+    serde_json::to_value(value).map_err(|e| RpcError::new(codes::PROTOCOL_ERROR, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn the_socket_is_private_from_the_moment_it_is_bound() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("privchatd.sock");
+        let _listener = bind_private(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o077, 0, "mode {mode:o} lets other users connect");
+    }
+
+    #[tokio::test]
+    async fn only_a_dead_socket_is_cleared() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("privchatd.sock");
+        clear_stale_socket(&path).unwrap();
+
+        let listener = bind_private(&path).unwrap();
+        let err = clear_stale_socket(&path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+        assert!(path.exists(), "a live socket was removed");
+
+        // 监听端没了、文件还在：上一个实例崩掉的样子。
+        drop(listener);
+        clear_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+
+        std::fs::write(&path, b"not a socket").unwrap();
+        assert!(clear_stale_socket(&path).is_err());
+        assert!(path.exists());
+    }
+}
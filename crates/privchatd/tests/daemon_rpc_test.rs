@@ -0,0 +1,203 @@
+// daemon 端到端：真起一个 `Daemon` 监听临时 socket，用 `privchat-sdk-rpc` 的客户端去连。
+// 不连 IM 服务端——这里验的是 RPC 层本身：握手、错误过线、事件订阅的游标语义、停机。
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use privchat_sdk::client_service::{
+    ClientService, ClientServiceConfig, MarkReadToPtsRequest, SdkError,
+};
+use privchat_sdk::{parse_server_url, SdkEvent};
+use privchat_sdk_rpc::wire::{read_frame, write_frame, RpcRequest, ServerFrame, DEFAULT_ACCOUNT};
+use privchat_sdk_rpc::{
+    codes, default_socket_path, methods, ClientError, EventSubscription, RpcClient,
+};
+use privchatd::Daemon;
+use tokio::net::UnixStream;
+use tokio::task::JoinHandle;
+
+struct Running {
+    _dir: tempfile::TempDir,
+    socket: PathBuf,
+    service: ClientService,
+    serve: JoinHandle<std::io::Result<()>>,
+}
+
+fn start() -> Running {
+    let dir = tempfile::tempdir().unwrap();
+    let service = ClientService::new(ClientServiceConfig {
+        endpoints: vec![parse_server_url("tcp://127.0.0.1:9").unwrap()],
+        connection_timeout_secs: 1,
+        data_dir: dir.path().to_path_buf(),
+        device_id: "daemon-test".into(),
+        account_hint: None,
+    })
+    .unwrap();
+    let socket = default_socket_path(dir.path());
+    let daemon = Daemon::new(service.clone(), socket.clone());
+    let serve = tokio::spawn(daemon.serve());
+    Running {
+        _dir: dir,
+        socket,
+        service,
+        serve,
+    }
+}
+
+async fn open(running: &Running) -> RpcClient {
+    for _ in 0..50 {
+        if let Ok(client) = RpcClient::open(&running.socket, "daemon-test").await {
+            return client;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("daemon never came up on {}", running.socket.display());
+}
+
+async fn next_sequence_id(sub: &mut EventSubscription) -> u64 {
+    tokio::time::timeout(Duration::from_secs(5), sub.recv())
+        .await
+        .expect("event within 5s")
+        .expect("stream open")
+        .sequence_id
+}
+
+async fn raw_call(socket: &Path, request: RpcRequest) -> ServerFrame {
+    let mut stream = UnixStream::connect(socket).await.unwrap();
+    write_frame(&mut stream, &request).await.unwrap();
+    read_frame(&mut stream).await.unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn hello_reports_the_method_set_and_sdk_errors_come_back_typed() {
+    let running = start();
+    let client = open(&running).await;
+
+    assert_eq!(
+        client.hello().protocol_version,
+        privchat_sdk_rpc::PROTOCOL_VERSION
+    );
+    assert!(client
+        .hello()
+        .methods
+        .iter()
+        .any(|m| m == methods::READ_MARK_BY_LOCAL_ID));
+
+    assert!(client.session_snapshot().await.unwrap().is_none());
+
+    let err = client
+        .mark_read_to_pts(MarkReadToPtsRequest {
+            channel_id: 1,
+            read_pts: 0,
+        })
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ClientError::Sdk(SdkError::InvalidArgument(_))
+    ));
+
+    let err = client
+        .call::<_, serde_json::Value>("sdk.nope", &())
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), codes::METHOD_NOT_IMPLEMENTED);
+
+    running.service.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn hello_is_required_and_only_the_default_account_is_served() {
+    let running = start();
+    // 等 socket 起来。
+    drop(open(&running).await);
+
+    let frame = raw_call(
+        &running.socket,
+        RpcRequest {
+            id: 1,
+            method: methods::SESSION_SNAPSHOT.into(),
+            account: None,
+            params: serde_json::Value::Null,
+        },
+    )
+    .await;
+    let ServerFrame::Response(resp) = frame else {
+        panic!("expected a response");
+    };
+    assert_eq!(resp.error.unwrap().code, codes::PROTOCOL_ERROR);
+
+    let frame = raw_call(
+        &running.socket,
+        RpcRequest {
+            id: 2,
+            method: methods::RPC_HELLO.into(),
+            account: Some("work".into()),
+            params: serde_json::json!({ "protocol_version": privchat_sdk_rpc::PROTOCOL_VERSION }),
+        },
+    )
+    .await;
+    let ServerFrame::Response(resp) = frame else {
+        panic!("expected a response");
+    };
+    assert_eq!(resp.error.unwrap().code, codes::UNSUPPORTED_ACCOUNT);
+
+    let frame = raw_call(
+        &running.socket,
+        RpcRequest {
+            id: 3,
+            method: methods::RPC_HELLO.into(),
+            account: Some(DEFAULT_ACCOUNT.into()),
+            params: serde_json::json!({ "protocol_version": 999 }),
+        },
+    )
+    .await;
+    let ServerFrame::Response(resp) = frame else {
+        panic!("expected a response");
+    };
+    assert_eq!(resp.error.unwrap().code, codes::VERSION_MISMATCH);
+
+    running.service.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn each_subscription_resumes_from_its_own_cursor() {
+    let running = start();
+    let client = open(&running).await;
+    let sdk = running.service.sdk();
+
+    let base = running.service.last_event_sequence_id();
+    sdk.emit_event(SdkEvent::ResumeSyncStarted);
+    sdk.emit_event(SdkEvent::ResumeSyncStarted);
+
+    // 一个从头订，一个从第一条之后订：历史各按各的游标补。
+    let mut from_start = client.subscribe_events(base).await.unwrap();
+    let mut from_second = client.subscribe_events(base + 1).await.unwrap();
+    assert!(!from_start.missed_events());
+
+    // 订阅之后产生的事件两边都能收到。
+    sdk.emit_event(SdkEvent::ResumeSyncStarted);
+
+    assert_eq!(next_sequence_id(&mut from_start).await, base + 1);
+    assert_eq!(next_sequence_id(&mut from_start).await, base + 2);
+    assert_eq!(next_sequence_id(&mut from_start).await, base + 3);
+    assert_eq!(next_sequence_id(&mut from_second).await, base + 2);
+    assert_eq!(next_sequence_id(&mut from_second).await, base + 3);
+    assert_eq!(from_second.cursor(), base + 3);
+
+    running.service.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_over_rpc_stops_the_daemon_and_removes_the_socket() {
+    let running = start();
+    let client = open(&running).await;
+
+    client.shutdown().await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), running.serve)
+        .await
+        .expect("daemon stops")
+        .unwrap()
+        .unwrap();
+    assert!(!running.socket.exists());
+}